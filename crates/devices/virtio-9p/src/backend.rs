@@ -0,0 +1,299 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Filesystem backend abstraction.
+//!
+//! This module provides the [`Filesystem`](trait.Filesystem.html) interface, which the
+//! [`Server`](../server/struct.Server.html) uses to carry out the 9P2000.L operations. The server
+//! decodes the messages and keeps track of the fids, while the filesystem works with nodes, which
+//! are the backend specific references to files. The
+//! [`PassthroughFs`](../passthrough/struct.PassthroughFs.html) implementation exposes a host
+//! directory.
+//!
+//! Errors are reported as `io::Error` values, and their raw OS error codes are forwarded to the
+//! driver (which expects Linux `errno` values).
+
+use std::fmt::Debug;
+use std::io;
+
+use crate::defs::{P9_QTDIR, P9_QTFILE, P9_QTSYMLINK};
+
+/// The server's unique identification for a file.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Qid {
+    /// The type of the file (`P9_QT*`).
+    pub ty: u8,
+    /// The version of the file.
+    pub version: u32,
+    /// A number which is unique among the files of the filesystem (i.e. the inode number).
+    pub path: u64,
+}
+
+impl Qid {
+    /// Creates a qid for a file.
+    ///
+    /// # Arguments
+    /// * `mode` - The mode of the file, which determines the qid type.
+    /// * `path` - The unique number of the file.
+    pub fn from_mode(mode: u32, path: u64) -> Self {
+        let ty = match mode & libc::S_IFMT {
+            libc::S_IFDIR => P9_QTDIR,
+            libc::S_IFLNK => P9_QTSYMLINK,
+            _ => P9_QTFILE,
+        };
+        Qid {
+            ty,
+            version: 0,
+            path,
+        }
+    }
+}
+
+/// The attributes of a file, as reported by `Rgetattr` messages.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Attr {
+    /// The qid of the file.
+    pub qid: Qid,
+    /// The type and permissions of the file.
+    pub mode: u32,
+    /// The owner.
+    pub uid: u32,
+    /// The group.
+    pub gid: u32,
+    /// The number of hard links.
+    pub nlink: u64,
+    /// The device identifier (for device nodes).
+    pub rdev: u64,
+    /// The size, in bytes.
+    pub size: u64,
+    /// The block size for I/O.
+    pub blksize: u64,
+    /// The number of 512 byte blocks.
+    pub blocks: u64,
+    /// The last access time, as seconds and nanoseconds.
+    pub atime: (u64, u64),
+    /// The last modification time, as seconds and nanoseconds.
+    pub mtime: (u64, u64),
+    /// The last status change time, as seconds and nanoseconds.
+    pub ctime: (u64, u64),
+}
+
+/// The attributes to change, as requested by `Tsetattr` messages.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SetAttr {
+    /// The attributes to change (`P9_SETATTR_*`).
+    pub valid: u32,
+    /// The new permissions.
+    pub mode: u32,
+    /// The new owner.
+    pub uid: u32,
+    /// The new group.
+    pub gid: u32,
+    /// The new size.
+    pub size: u64,
+    /// The new access time, as seconds and nanoseconds.
+    pub atime: (u64, u64),
+    /// The new modification time, as seconds and nanoseconds.
+    pub mtime: (u64, u64),
+}
+
+/// Filesystem information, as reported by `Rstatfs` messages.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StatFs {
+    /// The type of the filesystem.
+    pub ty: u32,
+    /// The block size.
+    pub bsize: u32,
+    /// The total number of blocks.
+    pub blocks: u64,
+    /// The number of free blocks.
+    pub bfree: u64,
+    /// The number of blocks available to unprivileged users.
+    pub bavail: u64,
+    /// The total number of files.
+    pub files: u64,
+    /// The number of free files.
+    pub ffree: u64,
+    /// The filesystem identifier.
+    pub fsid: u64,
+    /// The maximum length of file names.
+    pub namelen: u32,
+}
+
+/// A directory entry, as reported by `Rreaddir` messages.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DirEntry {
+    /// The qid of the file.
+    pub qid: Qid,
+    /// The offset of the next entry, which the driver passes back to continue reading.
+    pub offset: u64,
+    /// The type of the file (`DT_*`).
+    pub ty: u8,
+    /// The name of the file.
+    pub name: Vec<u8>,
+}
+
+/// The filesystem operations behind the 9P2000.L messages.
+///
+/// The names passed to the methods are single path components: the server rejects names which
+/// are empty or contain slashes or NUL bytes, and passes `..` only to `walk`. The open flags
+/// are translated to the host values.
+pub trait Filesystem {
+    /// A reference to a file, which the server associates with a fid.
+    type Node: Debug;
+
+    /// Returns the root of the filesystem.
+    ///
+    /// # Arguments
+    /// * `aname` - The name of the filesystem the driver selected, which is usually empty.
+    fn attach(&mut self, aname: &[u8]) -> io::Result<Self::Node>;
+
+    /// Looks up an entry of a directory, where `..` stands for the parent directory (which is
+    /// the root itself for the root).
+    ///
+    /// # Arguments
+    /// * `dir` - The directory.
+    /// * `name` - The name of the entry.
+    fn walk(&mut self, dir: &Self::Node, name: &[u8]) -> io::Result<Self::Node>;
+
+    /// Returns a new reference to the same file.
+    ///
+    /// # Arguments
+    /// * `node` - The file.
+    fn clone_node(&mut self, node: &Self::Node) -> io::Result<Self::Node>;
+
+    /// Returns the attributes of a file.
+    ///
+    /// # Arguments
+    /// * `node` - The file.
+    fn getattr(&mut self, node: &Self::Node) -> io::Result<Attr>;
+
+    /// Changes the attributes of a file.
+    ///
+    /// # Arguments
+    /// * `node` - The file.
+    /// * `attr` - The attributes to change.
+    fn setattr(&mut self, node: &Self::Node, attr: &SetAttr) -> io::Result<()>;
+
+    /// Opens a file for I/O.
+    ///
+    /// # Arguments
+    /// * `node` - The file.
+    /// * `flags` - The open flags.
+    fn open(&mut self, node: &mut Self::Node, flags: i32) -> io::Result<()>;
+
+    /// Creates and opens a regular file, and returns it.
+    ///
+    /// # Arguments
+    /// * `dir` - The parent directory.
+    /// * `name` - The name of the new file.
+    /// * `flags` - The open flags.
+    /// * `mode` - The permissions of the new file.
+    fn create(
+        &mut self,
+        dir: &Self::Node,
+        name: &[u8],
+        flags: i32,
+        mode: u32,
+    ) -> io::Result<Self::Node>;
+
+    /// Reads from an open file, and returns the number of bytes read.
+    ///
+    /// # Arguments
+    /// * `node` - The open file.
+    /// * `offset` - The offset in the file.
+    /// * `buf` - The buffer to read into.
+    fn read(&mut self, node: &mut Self::Node, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Writes to an open file, and returns the number of bytes written.
+    ///
+    /// # Arguments
+    /// * `node` - The open file.
+    /// * `offset` - The offset in the file.
+    /// * `data` - The data to write.
+    fn write(&mut self, node: &mut Self::Node, offset: u64, data: &[u8]) -> io::Result<usize>;
+
+    /// Returns the entries of an open directory which come after `offset` (zero stands for the
+    /// first entry). The server sends as many of them as fit in the reply.
+    ///
+    /// # Arguments
+    /// * `node` - The open directory.
+    /// * `offset` - The offset of the first entry, from a previous `DirEntry`.
+    fn readdir(&mut self, node: &mut Self::Node, offset: u64) -> io::Result<Vec<DirEntry>>;
+
+    /// Flushes the data of an open file to the underlying storage.
+    ///
+    /// # Arguments
+    /// * `node` - The open file.
+    /// * `datasync` - Whether only the data (and not the metadata) has to be flushed.
+    fn fsync(&mut self, node: &mut Self::Node, datasync: bool) -> io::Result<()>;
+
+    /// Creates a directory, and returns its qid.
+    ///
+    /// # Arguments
+    /// * `dir` - The parent directory.
+    /// * `name` - The name of the new directory.
+    /// * `mode` - The permissions of the new directory.
+    fn mkdir(&mut self, dir: &Self::Node, name: &[u8], mode: u32) -> io::Result<Qid>;
+
+    /// Creates a symbolic link, and returns its qid.
+    ///
+    /// # Arguments
+    /// * `dir` - The parent directory.
+    /// * `name` - The name of the link.
+    /// * `target` - The target of the link.
+    fn symlink(&mut self, dir: &Self::Node, name: &[u8], target: &[u8]) -> io::Result<Qid>;
+
+    /// Creates a device node, a FIFO or a socket, and returns its qid.
+    ///
+    /// # Arguments
+    /// * `dir` - The parent directory.
+    /// * `name` - The name of the new file.
+    /// * `mode` - The type and permissions of the new file.
+    /// * `rdev` - The device identifier (for device nodes).
+    fn mknod(&mut self, dir: &Self::Node, name: &[u8], mode: u32, rdev: u64) -> io::Result<Qid>;
+
+    /// Creates a hard link to a file.
+    ///
+    /// # Arguments
+    /// * `dir` - The directory of the new link.
+    /// * `node` - The file.
+    /// * `name` - The name of the new link.
+    fn link(&mut self, dir: &Self::Node, node: &Self::Node, name: &[u8]) -> io::Result<()>;
+
+    /// Returns the target of a symbolic link.
+    ///
+    /// # Arguments
+    /// * `node` - The symbolic link.
+    fn readlink(&mut self, node: &Self::Node) -> io::Result<Vec<u8>>;
+
+    /// Removes a directory entry.
+    ///
+    /// # Arguments
+    /// * `dir` - The directory.
+    /// * `name` - The name of the entry.
+    /// * `remove_dir` - Whether the entry is an (empty) directory.
+    fn unlink(&mut self, dir: &Self::Node, name: &[u8], remove_dir: bool) -> io::Result<()>;
+
+    /// Renames a directory entry.
+    ///
+    /// # Arguments
+    /// * `old_dir` - The directory of the entry.
+    /// * `old_name` - The name of the entry.
+    /// * `new_dir` - The new directory of the entry.
+    /// * `new_name` - The new name of the entry.
+    fn rename(
+        &mut self,
+        old_dir: &Self::Node,
+        old_name: &[u8],
+        new_dir: &Self::Node,
+        new_name: &[u8],
+    ) -> io::Result<()>;
+
+    /// Returns information about the filesystem which holds a file.
+    ///
+    /// # Arguments
+    /// * `node` - The file.
+    fn statfs(&mut self, node: &Self::Node) -> io::Result<StatFs>;
+}
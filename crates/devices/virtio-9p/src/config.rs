@@ -0,0 +1,72 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Virtio 9p device configuration space abstraction.
+//!
+//! This module provides the [`ConfigSpace`](struct.ConfigSpace.html) abstraction, which mirrors
+//! the `virtio_9p_config` structure from the virtio specification. It holds the tag the guest
+//! uses to mount the filesystem. Unlike the other devices, the size of the configuration space
+//! depends on the length of the tag, so the structure is serialized explicitly.
+
+/// The maximum length of the mount tag.
+pub const MAX_TAG_LEN: usize = u16::MAX as usize;
+
+/// The 9p device configuration space, which consists of the length of the tag (as a little
+/// endian `u16`) followed by the tag itself (which is not NUL-terminated).
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigSpace {
+    tag: String,
+}
+
+impl ConfigSpace {
+    /// The offset of the `tag_len` field.
+    pub const TAG_LEN_OFFSET: usize = 0;
+    /// The offset of the `tag` field.
+    pub const TAG_OFFSET: usize = 2;
+
+    /// Creates a new `ConfigSpace`, or returns `None` when the tag is empty or longer than
+    /// `MAX_TAG_LEN` bytes.
+    ///
+    /// # Arguments
+    /// * `tag` - The name of the filesystem.
+    pub fn new(tag: &str) -> Option<Self> {
+        if tag.is_empty() || tag.len() > MAX_TAG_LEN {
+            return None;
+        }
+        Some(ConfigSpace {
+            tag: tag.to_owned(),
+        })
+    }
+
+    /// Returns the mount tag.
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+}
+
+impl From<ConfigSpace> for Vec<u8> {
+    fn from(config: ConfigSpace) -> Self {
+        // The length was validated when the configuration space was created.
+        let mut bytes = (config.tag.len() as u16).to_le_bytes().to_vec();
+        bytes.extend_from_slice(config.tag.as_bytes());
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_space() {
+        assert!(ConfigSpace::new("").is_none());
+        assert!(ConfigSpace::new(&"a".repeat(MAX_TAG_LEN + 1)).is_none());
+        assert!(ConfigSpace::new(&"a".repeat(MAX_TAG_LEN)).is_some());
+
+        let config = ConfigSpace::new("share").unwrap();
+        assert_eq!(config.tag(), "share");
+        let bytes: Vec<u8> = config.into();
+        assert_eq!(bytes, b"\x05\x00share");
+    }
+}
@@ -0,0 +1,156 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+// TODO: Move the generic device type definition to the vm-virtio crate proper.
+/// The virtio device type of 9p devices.
+pub const VIRTIO_ID_9P: u32 = 9;
+
+/// The device exposes a mount tag through the configuration space (from `linux/virtio_9p.h`).
+pub const VIRTIO_9P_MOUNT_TAG: u64 = 0;
+
+/// The index of the request queue.
+pub const REQUEST_QUEUE: u16 = 0;
+
+/// The default maximum size of the request queue.
+pub const DEFAULT_QUEUE_SIZE: u16 = 128;
+
+/// The protocol version supported by the device.
+pub const P9_PROTOCOL_VERSION: &[u8] = b"9P2000.L";
+/// The version returned when the protocol requested by the driver is not supported.
+pub const P9_VERSION_UNKNOWN: &[u8] = b"unknown";
+/// The tag of `Tversion` messages.
+pub const P9_NOTAG: u16 = 0xffff;
+/// The fid value which stands for no fid (i.e. the `afid` of `Tattach` messages).
+pub const P9_NOFID: u32 = 0xffff_ffff;
+/// The maximum number of names in a `Twalk` message.
+pub const P9_MAXWELEM: usize = 16;
+
+// Message types (from `net/9p/protocol.h` and `include/net/9p/9p.h`). The reply to a `T`
+// message has the type of the message plus one.
+/// Error reply.
+pub const P9_RLERROR: u8 = 7;
+/// Get filesystem information.
+pub const P9_TSTATFS: u8 = 8;
+/// Open a file.
+pub const P9_TLOPEN: u8 = 12;
+/// Create and open a file.
+pub const P9_TLCREATE: u8 = 14;
+/// Create a symbolic link.
+pub const P9_TSYMLINK: u8 = 16;
+/// Create a device node.
+pub const P9_TMKNOD: u8 = 18;
+/// Rename a file (superseded by `Trenameat`).
+pub const P9_TRENAME: u8 = 20;
+/// Read the target of a symbolic link.
+pub const P9_TREADLINK: u8 = 22;
+/// Get file attributes.
+pub const P9_TGETATTR: u8 = 24;
+/// Set file attributes.
+pub const P9_TSETATTR: u8 = 26;
+/// Prepare to read an extended attribute.
+pub const P9_TXATTRWALK: u8 = 30;
+/// Prepare to set an extended attribute.
+pub const P9_TXATTRCREATE: u8 = 32;
+/// Read directory entries.
+pub const P9_TREADDIR: u8 = 40;
+/// Flush cached file data.
+pub const P9_TFSYNC: u8 = 50;
+/// Acquire or release a POSIX record lock.
+pub const P9_TLOCK: u8 = 52;
+/// Test for the existence of a POSIX record lock.
+pub const P9_TGETLOCK: u8 = 54;
+/// Create a hard link.
+pub const P9_TLINK: u8 = 70;
+/// Create a directory.
+pub const P9_TMKDIR: u8 = 72;
+/// Rename a directory entry.
+pub const P9_TRENAMEAT: u8 = 74;
+/// Remove a directory entry.
+pub const P9_TUNLINKAT: u8 = 76;
+/// Negotiate the protocol version and the message size.
+pub const P9_TVERSION: u8 = 100;
+/// Authenticate a user.
+pub const P9_TAUTH: u8 = 102;
+/// Attach to the root of a filesystem.
+pub const P9_TATTACH: u8 = 104;
+/// Abort a pending message.
+pub const P9_TFLUSH: u8 = 108;
+/// Walk a directory hierarchy.
+pub const P9_TWALK: u8 = 110;
+/// Read from a file.
+pub const P9_TREAD: u8 = 116;
+/// Write to a file.
+pub const P9_TWRITE: u8 = 118;
+/// Forget a fid.
+pub const P9_TCLUNK: u8 = 120;
+/// Remove a file (superseded by `Tunlinkat`).
+pub const P9_TREMOVE: u8 = 122;
+
+// Qid types.
+/// The file is a directory.
+pub const P9_QTDIR: u8 = 0x80;
+/// The file is a symbolic link.
+pub const P9_QTSYMLINK: u8 = 0x02;
+/// The file is a regular file (or any other kind of file).
+pub const P9_QTFILE: u8 = 0x00;
+
+// `Tgetattr` request mask bits.
+/// The basic attributes, i.e. the ones from `stat`.
+pub const P9_GETATTR_BASIC: u64 = 0x0000_07ff;
+
+// `Tsetattr` valid bits.
+/// Set the mode.
+pub const P9_SETATTR_MODE: u32 = 0x0000_0001;
+/// Set the owner.
+pub const P9_SETATTR_UID: u32 = 0x0000_0002;
+/// Set the group.
+pub const P9_SETATTR_GID: u32 = 0x0000_0004;
+/// Set the size.
+pub const P9_SETATTR_SIZE: u32 = 0x0000_0008;
+/// Set the access time.
+pub const P9_SETATTR_ATIME: u32 = 0x0000_0010;
+/// Set the modification time.
+pub const P9_SETATTR_MTIME: u32 = 0x0000_0020;
+/// Update the change time.
+pub const P9_SETATTR_CTIME: u32 = 0x0000_0040;
+/// Set the access time to the value from the message (instead of the current time).
+pub const P9_SETATTR_ATIME_SET: u32 = 0x0000_0080;
+/// Set the modification time to the value from the message (instead of the current time).
+pub const P9_SETATTR_MTIME_SET: u32 = 0x0000_0100;
+
+// Open flags, which have the values used by Linux on x86 (from `include/net/9p/9p.h`).
+/// The access mode bits.
+pub const P9_ACCMODE: u32 = 0o3;
+/// Read only access.
+pub const P9_RDONLY: u32 = 0o0;
+/// Write only access.
+pub const P9_WRONLY: u32 = 0o1;
+/// Read and write access.
+pub const P9_RDWR: u32 = 0o2;
+/// Create the file if it doesn't exist.
+pub const P9_CREATE: u32 = 0o100;
+/// Fail if the file exists.
+pub const P9_EXCL: u32 = 0o200;
+/// Truncate the file.
+pub const P9_TRUNC: u32 = 0o1000;
+/// Append to the file.
+pub const P9_APPEND: u32 = 0o2000;
+/// Non-blocking access.
+pub const P9_NONBLOCK: u32 = 0o4000;
+/// Synchronized data writes.
+pub const P9_DSYNC: u32 = 0o10000;
+/// Fail unless the file is a directory.
+pub const P9_DIRECTORY: u32 = 0o200000;
+/// Don't follow symbolic links.
+pub const P9_NOFOLLOW: u32 = 0o400000;
+/// Don't update the access time.
+pub const P9_NOATIME: u32 = 0o1000000;
+/// Synchronized writes.
+pub const P9_SYNC: u32 = 0o4000000;
+
+/// Remove a directory instead of a file (the `flags` of `Tunlinkat` messages).
+pub const P9_AT_REMOVEDIR: u32 = 0x200;
+
+/// The filesystem type reported by `Rstatfs` (from `include/uapi/linux/magic.h`).
+pub const V9FS_MAGIC: u32 = 0x0102_1997;
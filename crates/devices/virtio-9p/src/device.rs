@@ -0,0 +1,483 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A reference virtio 9p device implementation.
+//!
+//! This module provides the following abstractions:
+//!
+//! - [`P9`](struct.P9.html) which ties together the generic device building blocks
+//!   (`VirtioConfig` and the `VirtioDevice`/`VirtioMmioDevice` interfaces) with the 9p specific
+//!   ones (the configuration space and the 9P2000.L [`Server`](../server/struct.Server.html)).
+//! - [`P9Builder`](struct.P9Builder.html) which configures and creates a `P9` device.
+//!
+//! The device has a single request queue. Each buffer holds a 9P2000.L message in its
+//! device-readable part, and the reply goes to its device-writable part. This makes the device
+//! a simpler alternative to virtio-fs for sharing host directories, at the cost of performance
+//! (i.e. there's no DAX window, and all the requests go through a single queue).
+//!
+//! The device doesn't register any events by itself: the VMM is expected to rely on the
+//! `VirtioMmioDevice::queue_notify` implementation (or call `P9::process_request_queue`
+//! directly) when the driver notifies the queue.
+
+use std::fmt::{self, Display};
+use std::result;
+
+use log::{error, warn};
+
+use vm_memory::GuestAddressSpace;
+
+use virtio_device::{
+    SignalUsedQueue, VirtioConfig, VirtioDeviceActions, VirtioDeviceCommon, VirtioMmioDevice,
+};
+use virtio_queue::areas::{self, areas_len, read_areas, write_areas, Area};
+use virtio_queue::{self, Queue};
+
+use crate::backend::Filesystem;
+use crate::config::ConfigSpace;
+use crate::defs::{REQUEST_QUEUE, VIRTIO_9P_MOUNT_TAG};
+use crate::protocol::{Response, HEADER_LEN};
+use crate::server::{Server, DEFAULT_MAX_MSIZE, MIN_MSIZE};
+
+pub use crate::defs::{DEFAULT_QUEUE_SIZE, VIRTIO_ID_9P};
+
+// TODO: Move the generic feature definitions to the vm-virtio crate proper.
+const VIRTIO_F_VERSION_1: u64 = 32;
+const VIRTIO_F_RING_EVENT_IDX: u64 = 29;
+
+/// 9p device errors.
+#[derive(Debug)]
+pub enum Error {
+    /// The device was already activated.
+    AlreadyActivated,
+    /// The message size limit is too small.
+    InvalidMaxMsize(u32),
+    /// The queues are not configured properly.
+    InvalidQueues,
+    /// The queue index is not valid, or the device is not activated.
+    InvalidQueueIndex(u16),
+    /// The tag is empty, or too long.
+    InvalidTag(String),
+    /// Failed to access a queue.
+    Queue(virtio_queue::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            AlreadyActivated => write!(f, "the device is already activated"),
+            InvalidMaxMsize(msize) => write!(f, "invalid message size limit {}", msize),
+            InvalidQueues => write!(f, "invalid queue configuration"),
+            InvalidQueueIndex(index) => write!(f, "invalid or inactive queue {}", index),
+            InvalidTag(ref tag) => write!(f, "invalid mount tag \"{}\"", tag),
+            Queue(ref err) => write!(f, "failed to access the queue: {}", err),
+        }
+    }
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Configures and builds a `P9` device.
+///
+/// # Example
+///
+/// ```rust
+/// # use std::sync::Arc;
+/// # use virtio_9p::device::P9Builder;
+/// # use virtio_9p::passthrough::PassthroughFs;
+/// # use vm_memory::{GuestAddress, GuestMemoryMmap};
+/// # use vmm_sys_util::eventfd::EventFd;
+/// let mem = Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+/// let fs = PassthroughFs::new("/tmp").unwrap();
+///
+/// let p9 = P9Builder::new(mem, "share", fs, EventFd::new(0).unwrap())
+///     .with_max_msize(64 << 10)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug)]
+pub struct P9Builder<M: GuestAddressSpace, F: Filesystem, S: SignalUsedQueue> {
+    mem: M,
+    tag: String,
+    fs: F,
+    driver_notify: S,
+    queue_size: u16,
+    max_msize: u32,
+}
+
+impl<M, F, S> P9Builder<M, F, S>
+where
+    M: GuestAddressSpace + Clone,
+    F: Filesystem,
+    S: SignalUsedQueue,
+{
+    /// Creates a new `P9Builder`.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    /// * `tag` - The name the guest uses to mount the filesystem.
+    /// * `fs` - The filesystem.
+    /// * `driver_notify` - The object used for notifying the driver about used buffers.
+    pub fn new(mem: M, tag: &str, fs: F, driver_notify: S) -> Self {
+        P9Builder {
+            mem,
+            tag: tag.to_owned(),
+            fs,
+            driver_notify,
+            queue_size: DEFAULT_QUEUE_SIZE,
+            max_msize: DEFAULT_MAX_MSIZE,
+        }
+    }
+
+    /// Sets the maximum size of the request queue.
+    ///
+    /// # Arguments
+    /// * `queue_size` - The maximum queue size.
+    pub fn with_queue_size(mut self, queue_size: u16) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Sets the limit for the message size, which the driver can lower when negotiating the
+    /// protocol version (`DEFAULT_MAX_MSIZE` by default).
+    ///
+    /// # Arguments
+    /// * `max_msize` - The limit, in bytes.
+    pub fn with_max_msize(mut self, max_msize: u32) -> Self {
+        self.max_msize = max_msize;
+        self
+    }
+
+    /// Builds the `P9` device.
+    pub fn build(self) -> Result<P9<M, F, S>> {
+        let config_space = ConfigSpace::new(&self.tag).ok_or(Error::InvalidTag(self.tag))?;
+        if self.max_msize < MIN_MSIZE {
+            return Err(Error::InvalidMaxMsize(self.max_msize));
+        }
+
+        let device_features =
+            (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_RING_EVENT_IDX) | (1 << VIRTIO_9P_MOUNT_TAG);
+        let queues = vec![Queue::new(self.mem, self.queue_size)];
+
+        Ok(P9 {
+            cfg: VirtioConfig::new(device_features, queues, config_space.into()),
+            server: Server::new(self.fs, self.max_msize),
+            driver_notify: self.driver_notify,
+            max_msize: self.max_msize,
+        })
+    }
+}
+
+/// A virtio 9p device.
+#[derive(Debug, VirtioDeviceCommon)]
+#[virtio(device_type = VIRTIO_ID_9P)]
+pub struct P9<M: GuestAddressSpace, F: Filesystem, S: SignalUsedQueue> {
+    #[virtio(config)]
+    cfg: VirtioConfig<M>,
+    server: Server<F>,
+    driver_notify: S,
+    max_msize: u32,
+}
+
+impl<M, F, S> P9<M, F, S>
+where
+    M: GuestAddressSpace,
+    F: Filesystem,
+    S: SignalUsedQueue,
+{
+    /// Returns whether the device is activated.
+    pub fn is_activated(&self) -> bool {
+        self.cfg.device_activated
+    }
+
+    /// Returns a reference to the server.
+    pub fn server(&self) -> &Server<F> {
+        &self.server
+    }
+
+    /// Processes the messages from the request queue. This has to be called when the driver
+    /// notifies the queue.
+    pub fn process_request_queue(&mut self) -> Result<()> {
+        if !self.cfg.device_activated {
+            return Err(Error::InvalidQueueIndex(REQUEST_QUEUE));
+        }
+        let index = usize::from(REQUEST_QUEUE);
+        while let Some(mut chain) = self.cfg.queues[index].iter()?.next() {
+            let len = match areas::split_chain(&mut chain) {
+                Ok((readable, writable)) => {
+                    self.handle_message(chain.memory(), &readable, &writable)
+                }
+                Err(e) => {
+                    warn!("invalid 9p request: {}", e);
+                    0
+                }
+            };
+
+            let queue = &mut self.cfg.queues[index];
+            queue.add_used(chain.head_index(), len)?;
+            if queue.needs_notification()? {
+                self.cfg.signal_used_queue();
+                self.driver_notify.signal_used_queue(REQUEST_QUEUE);
+            }
+        }
+        Ok(())
+    }
+
+    // Handles the message from the device-readable areas, and returns the length of the reply
+    // written to the device-writable areas.
+    fn handle_message(&mut self, mem: &M::M, readable: &[Area], writable: &[Area]) -> u32 {
+        // Messages which are larger than the limit are not valid, so there's no point in reading
+        // more than that.
+        let mut msg = vec![0u8; areas_len(readable).min(self.max_msize as usize)];
+        if let Err(e) = read_areas(mem, readable, 0, &mut msg) {
+            warn!("failed to read 9p message: {}", e);
+            return 0;
+        }
+
+        let mut reply = match self.server.handle_message(&msg) {
+            Some(reply) => reply,
+            None => return 0,
+        };
+        if reply.len() > areas_len(writable) {
+            // The tag comes right before the end of the header.
+            let tag = u16::from_le_bytes([reply[HEADER_LEN - 2], reply[HEADER_LEN - 1]]);
+            reply = Response::Lerror(libc::EMSGSIZE as u32).encode(tag);
+        }
+
+        match write_areas(mem, writable, 0, &reply) {
+            // The reply is bounded by the message size.
+            Ok(()) => reply.len() as u32,
+            Err(e) => {
+                warn!("failed to write 9p reply: {}", e);
+                0
+            }
+        }
+    }
+}
+
+impl<M, F, S> VirtioDeviceActions for P9<M, F, S>
+where
+    M: GuestAddressSpace,
+    F: Filesystem,
+    S: SignalUsedQueue,
+{
+    type E = Error;
+
+    fn activate(&mut self) -> Result<()> {
+        if self.cfg.device_activated {
+            return Err(Error::AlreadyActivated);
+        }
+        if !self.cfg.queues.iter().all(Queue::is_valid) {
+            return Err(Error::InvalidQueues);
+        }
+        self.cfg.device_activated = true;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        // The fids are dropped, which releases the host resources behind them.
+        self.server.reset();
+
+        let cfg = &mut self.cfg;
+        cfg.driver_features = 0;
+        cfg.device_features_select = 0;
+        cfg.driver_features_select = 0;
+        cfg.device_status = 0;
+        cfg.queue_select = 0;
+        cfg.queues.iter_mut().for_each(Queue::reset);
+        cfg.device_activated = false;
+        cfg.reset_interrupt_status();
+        Ok(())
+    }
+}
+
+impl<M, F, S> VirtioMmioDevice<M> for P9<M, F, S>
+where
+    M: GuestAddressSpace + 'static,
+    F: Filesystem,
+    S: SignalUsedQueue,
+{
+    fn queue_notify(&mut self, val: u32) {
+        // Queue indices always fit in an `u16`.
+        let result = match val as u16 {
+            REQUEST_QUEUE => self.process_request_queue(),
+            index => Err(Error::InvalidQueueIndex(index)),
+        };
+        if let Err(e) = result {
+            error!("failed to process queue {}: {}", val, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::slice;
+    use std::sync::Arc;
+
+    use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap};
+    use vmm_sys_util::eventfd::EventFd;
+    use vmm_sys_util::tempdir::TempDir;
+
+    use virtio_device::mock::activate;
+    use virtio_device::VirtioDevice;
+    use virtio_queue::mock::{AttackPattern, VirtQueue};
+    use virtio_queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    use crate::defs::*;
+    use crate::passthrough::PassthroughFs;
+
+    type Mem = Arc<GuestMemoryMmap>;
+    type TestP9 = P9<Mem, PassthroughFs, EventFd>;
+
+    fn builder(mem: &Mem, dir: &TempDir, tag: &str) -> P9Builder<Mem, PassthroughFs, EventFd> {
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let fs = PassthroughFs::new(dir.as_path()).unwrap();
+        P9Builder::new(mem.clone(), tag, fs, evt).with_queue_size(16)
+    }
+
+    fn used_len(mem: &GuestMemoryMmap, vq: &VirtQueue, index: u64) -> u32 {
+        // The used ring starts after the flags and the index, and each element holds the head
+        // index followed by the length.
+        let addr = vq.used_start().unchecked_add(4 + index * 8 + 4);
+        mem.read_obj(addr).unwrap()
+    }
+
+    // Sends a message through the request queue, and returns the reply.
+    fn send(
+        p9: &mut TestP9,
+        mem: &GuestMemoryMmap,
+        vq: &VirtQueue,
+        msg: &[u8],
+        reply_len: u32,
+    ) -> Vec<u8> {
+        let avail = vq.avail.idx().load();
+        let head = (avail * 2) % vq.size();
+        mem.write_slice(msg, GuestAddress(0x1_0000)).unwrap();
+        vq.dtable(head)
+            .set(0x1_0000, msg.len() as u32, VIRTQ_DESC_F_NEXT, head + 1);
+        vq.dtable(head + 1)
+            .set(0x2_0000, reply_len, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring(avail % vq.size()).store(head);
+        vq.avail.idx().store(avail + 1);
+
+        p9.queue_notify(u32::from(REQUEST_QUEUE));
+        assert_eq!(vq.used.idx().load(), avail + 1);
+        let mut reply = vec![0u8; used_len(mem, vq, u64::from(avail % vq.size())) as usize];
+        mem.read_slice(&mut reply, GuestAddress(0x2_0000)).unwrap();
+        reply
+    }
+
+    fn message(ty: u8, tag: u16, body: &[u8]) -> Vec<u8> {
+        let mut msg = ((HEADER_LEN + body.len()) as u32).to_le_bytes().to_vec();
+        msg.push(ty);
+        msg.extend_from_slice(&tag.to_le_bytes());
+        msg.extend_from_slice(body);
+        msg
+    }
+
+    #[test]
+    fn test_build() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let dir = TempDir::new_with_prefix("/tmp/p9").unwrap();
+
+        let p9 = builder(&mem, &dir, "share").build().unwrap();
+        assert_eq!(VirtioDevice::device_type(&p9), VIRTIO_ID_9P);
+        assert_eq!(p9.num_queues(), 1);
+        assert_ne!(p9.device_features() & (1 << VIRTIO_9P_MOUNT_TAG), 0);
+        let mut config = [0u8; 7];
+        p9.read_config(0, &mut config);
+        assert_eq!(&config, b"\x05\x00share");
+
+        assert!(matches!(
+            builder(&mem, &dir, "").build(),
+            Err(Error::InvalidTag(_))
+        ));
+        assert!(matches!(
+            builder(&mem, &dir, "share")
+                .with_max_msize(MIN_MSIZE - 1)
+                .build(),
+            Err(Error::InvalidMaxMsize(_))
+        ));
+    }
+
+    #[test]
+    fn test_requests() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let dir = TempDir::new_with_prefix("/tmp/p9").unwrap();
+        let mut p9 = builder(&mem, &dir, "share").build().unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+
+        assert!(matches!(
+            p9.process_request_queue(),
+            Err(Error::InvalidQueueIndex(REQUEST_QUEUE))
+        ));
+        activate(&mut p9, slice::from_ref(&vq), 0);
+        assert!(p9.is_activated());
+
+        let mut body = 8192u32.to_le_bytes().to_vec();
+        body.extend_from_slice(b"\x08\x009P2000.L");
+        let reply = send(
+            &mut p9,
+            &mem,
+            &vq,
+            &message(P9_TVERSION, P9_NOTAG, &body),
+            64,
+        );
+        assert_eq!(reply, message(P9_TVERSION + 1, P9_NOTAG, &body));
+        assert_eq!(p9.server().msize(), 8192);
+
+        let mut body = vec![1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0];
+        body.extend_from_slice(&[0; 4]);
+        let reply = send(&mut p9, &mem, &vq, &message(P9_TATTACH, 1, &body), 64);
+        assert_eq!(reply.len(), HEADER_LEN + 13);
+        assert_eq!(reply[4], P9_TATTACH + 1);
+        assert_eq!(reply[HEADER_LEN], P9_QTDIR);
+        assert_eq!(p9.server().num_fids(), 1);
+
+        // The reply doesn't fit in the buffer.
+        let body = [1, 0, 0, 0, 0xff, 0x07, 0, 0, 0, 0, 0, 0];
+        let reply = send(&mut p9, &mem, &vq, &message(P9_TGETATTR, 2, &body), 32);
+        assert_eq!(reply, Response::Lerror(libc::EMSGSIZE as u32).encode(2));
+
+        // The fids are dropped on reset.
+        p9.ack_device_status(0);
+        assert!(!p9.is_activated());
+        assert_eq!(p9.server().num_fids(), 0);
+    }
+
+    #[test]
+    fn test_attack_patterns() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let dir = TempDir::new_with_prefix("/tmp/p9").unwrap();
+
+        // The malicious chains are consumed like any other request, except for the ones with an
+        // out of range head index, which can't be added to the used ring.
+        for &pattern in AttackPattern::ALL.iter() {
+            let mut p9 = builder(&mem, &dir, "share").build().unwrap();
+            let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+            activate(&mut p9, slice::from_ref(&vq), 0);
+            vq.add_attack(pattern, 0, GuestAddress(0x1_0000));
+            p9.queue_notify(u32::from(REQUEST_QUEUE));
+            assert_eq!(
+                vq.used.idx().load(),
+                u16::from(pattern != AttackPattern::HeadOutOfRange),
+                "{:?}",
+                pattern
+            );
+        }
+    }
+}
@@ -0,0 +1,29 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A crate that provides 9p device specific components as described
+//! by the virtio specification.
+
+#![deny(missing_docs)]
+
+/// Contains the interface between the 9p server and the filesystem implementation.
+pub mod backend;
+
+/// Contains the 9p device configuration space abstraction.
+pub mod config;
+
+/// Contains virtio 9p constant definitions.
+pub mod defs;
+
+/// Contains a reference virtio 9p device implementation.
+pub mod device;
+
+/// Contains a filesystem implementation which exposes a host directory.
+pub mod passthrough;
+
+/// Contains the 9P2000.L message abstractions.
+pub mod protocol;
+
+/// Contains the 9P2000.L server, which handles the messages on top of a filesystem.
+pub mod server;
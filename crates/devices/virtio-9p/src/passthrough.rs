@@ -0,0 +1,591 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Passthrough filesystem.
+//!
+//! This module provides the [`PassthroughFs`](struct.PassthroughFs.html) implementation of the
+//! [`Filesystem`](../backend/trait.Filesystem.html) interface, which exposes a host directory
+//! to the guest.
+//!
+//! Nodes hold `O_PATH` file descriptors, and names are always resolved relative to the
+//! descriptor of the parent directory without following symbolic links, so walks can't leave
+//! the shared directory (`..` stops at the root). Operations which need a regular file
+//! descriptor reopen the node through `/proc/self/fd`, which is why they are refused for
+//! symbolic links. The operations are carried out with the credentials of the VMM, so the
+//! ownership of new files is not changed to match the driver's request.
+//!
+//! Directory offsets are entry indices, so entries which are added or removed while the
+//! driver reads a directory can cause other entries to be skipped or repeated.
+
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, FileTypeExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+
+use crate::backend::{Attr, DirEntry, Filesystem, Qid, SetAttr, StatFs};
+use crate::defs::*;
+
+// The flags of the `O_PATH` descriptors which back the nodes.
+const PATH_FLAGS: i32 = libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+// The maximum length of symbolic link targets.
+const MAX_LINK_LEN: usize = libc::PATH_MAX as usize;
+
+/// A file from the shared directory.
+#[derive(Debug)]
+pub struct Node {
+    // The `O_PATH` descriptor of the file.
+    path: File,
+    // The descriptor used for I/O, once the file is opened.
+    file: Option<File>,
+}
+
+/// A filesystem which exposes a host directory.
+#[derive(Debug)]
+pub struct PassthroughFs {
+    root: File,
+    // The device and inode numbers of the root, which tell when `..` has to stop.
+    root_id: (u64, u64),
+}
+
+// Converts the return value of a libc call to a `Result`.
+fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn cstring(name: &[u8]) -> io::Result<CString> {
+    CString::new(name).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))
+}
+
+// Returns the path of a descriptor in `/proc/self/fd`.
+fn proc_path(file: &File) -> String {
+    format!("/proc/self/fd/{}", file.as_raw_fd())
+}
+
+fn openat(dir: &File, name: &[u8], flags: i32, mode: u32) -> io::Result<File> {
+    let name = cstring(name)?;
+    // Safe because the name is a valid C string, and we check the return value.
+    let fd = cvt(unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags, mode) })?;
+    // Safe because we own the new descriptor.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+// Returns the attributes of the file `name` from `dir`, or of `dir` itself when `name` is
+// empty, without following symbolic links.
+fn stat_at(dir: &File, name: &[u8]) -> io::Result<libc::stat> {
+    let name = cstring(name)?;
+    let mut st = MaybeUninit::<libc::stat>::zeroed();
+    // Safe because the name is a valid C string, the kernel only writes a `stat` structure to
+    // `st`, and we check the return value.
+    cvt(unsafe {
+        libc::fstatat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            st.as_mut_ptr(),
+            libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
+        )
+    })?;
+    // Safe because the kernel initialized the structure.
+    Ok(unsafe { st.assume_init() })
+}
+
+// The types of some `stat` fields depend on the architecture.
+#[allow(clippy::unnecessary_cast)]
+fn attr_from_stat(st: &libc::stat) -> Attr {
+    Attr {
+        qid: Qid::from_mode(st.st_mode, st.st_ino),
+        mode: st.st_mode,
+        uid: st.st_uid,
+        gid: st.st_gid,
+        nlink: st.st_nlink as u64,
+        rdev: st.st_rdev,
+        size: st.st_size as u64,
+        blksize: st.st_blksize as u64,
+        blocks: st.st_blocks as u64,
+        atime: (st.st_atime as u64, st.st_atime_nsec as u64),
+        mtime: (st.st_mtime as u64, st.st_mtime_nsec as u64),
+        ctime: (st.st_ctime as u64, st.st_ctime_nsec as u64),
+    }
+}
+
+fn qid_at(dir: &File, name: &[u8]) -> io::Result<Qid> {
+    stat_at(dir, name).map(|st| attr_from_stat(&st).qid)
+}
+
+// Fails with `err` when the node is a symbolic link, which can't be reopened through
+// `/proc/self/fd` without following it.
+fn check_not_symlink(node: &Node, err: i32) -> io::Result<()> {
+    if stat_at(&node.path, b"")?.st_mode & libc::S_IFMT == libc::S_IFLNK {
+        return Err(io::Error::from_raw_os_error(err));
+    }
+    Ok(())
+}
+
+fn open_file(node: &Node) -> io::Result<&File> {
+    node.file
+        .as_ref()
+        .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))
+}
+
+// Returns the timestamp `utimensat` uses for a `Tsetattr` time.
+fn timespec(attr: &SetAttr, valid: u32, set: u32, (sec, nsec): (u64, u64)) -> libc::timespec {
+    let (tv_sec, tv_nsec) = if attr.valid & valid == 0 {
+        (0, libc::UTIME_OMIT)
+    } else if attr.valid & set == 0 {
+        (0, libc::UTIME_NOW)
+    } else {
+        (sec as libc::time_t, nsec as libc::c_long)
+    };
+    libc::timespec { tv_sec, tv_nsec }
+}
+
+fn dir_entry_type(ty: fs::FileType) -> (u8, u32) {
+    if ty.is_dir() {
+        (libc::DT_DIR, libc::S_IFDIR)
+    } else if ty.is_symlink() {
+        (libc::DT_LNK, libc::S_IFLNK)
+    } else if ty.is_file() {
+        (libc::DT_REG, libc::S_IFREG)
+    } else if ty.is_block_device() {
+        (libc::DT_BLK, libc::S_IFBLK)
+    } else if ty.is_char_device() {
+        (libc::DT_CHR, libc::S_IFCHR)
+    } else if ty.is_fifo() {
+        (libc::DT_FIFO, libc::S_IFIFO)
+    } else if ty.is_socket() {
+        (libc::DT_SOCK, libc::S_IFSOCK)
+    } else {
+        (libc::DT_UNKNOWN, 0)
+    }
+}
+
+impl PassthroughFs {
+    /// Creates a new `PassthroughFs`.
+    ///
+    /// # Arguments
+    /// * `root` - The shared directory.
+    pub fn new<P: AsRef<Path>>(root: P) -> io::Result<Self> {
+        let root = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_DIRECTORY)
+            .open(root)?;
+        let st = stat_at(&root, b"")?;
+        Ok(PassthroughFs {
+            root,
+            root_id: (st.st_dev, st.st_ino),
+        })
+    }
+}
+
+impl Filesystem for PassthroughFs {
+    type Node = Node;
+
+    fn attach(&mut self, _aname: &[u8]) -> io::Result<Node> {
+        // There's a single filesystem, so the name is not relevant.
+        Ok(Node {
+            path: self.root.try_clone()?,
+            file: None,
+        })
+    }
+
+    fn walk(&mut self, dir: &Node, name: &[u8]) -> io::Result<Node> {
+        if name == b".." {
+            let st = stat_at(&dir.path, b"")?;
+            if (st.st_dev, st.st_ino) == self.root_id {
+                return self.attach(b"");
+            }
+        }
+        Ok(Node {
+            path: openat(&dir.path, name, PATH_FLAGS, 0)?,
+            file: None,
+        })
+    }
+
+    fn clone_node(&mut self, node: &Node) -> io::Result<Node> {
+        Ok(Node {
+            path: node.path.try_clone()?,
+            file: None,
+        })
+    }
+
+    fn getattr(&mut self, node: &Node) -> io::Result<Attr> {
+        stat_at(&node.path, b"").map(|st| attr_from_stat(&st))
+    }
+
+    fn setattr(&mut self, node: &Node, attr: &SetAttr) -> io::Result<()> {
+        let valid = attr.valid;
+        if valid & (P9_SETATTR_MODE | P9_SETATTR_SIZE | P9_SETATTR_ATIME | P9_SETATTR_MTIME) != 0 {
+            check_not_symlink(node, libc::EOPNOTSUPP)?;
+        }
+
+        if valid & P9_SETATTR_MODE != 0 {
+            fs::set_permissions(
+                proc_path(&node.path),
+                Permissions::from_mode(attr.mode & 0o7777),
+            )?;
+        }
+        if valid & (P9_SETATTR_UID | P9_SETATTR_GID) != 0 {
+            // `u32::MAX` (i.e. -1) leaves the owner or the group unchanged.
+            let uid = if valid & P9_SETATTR_UID != 0 {
+                attr.uid
+            } else {
+                u32::MAX
+            };
+            let gid = if valid & P9_SETATTR_GID != 0 {
+                attr.gid
+            } else {
+                u32::MAX
+            };
+            // Safe because the path is a valid C string, and we check the return value.
+            cvt(unsafe {
+                libc::fchownat(
+                    node.path.as_raw_fd(),
+                    b"\0".as_ptr() as *const libc::c_char,
+                    uid,
+                    gid,
+                    libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
+                )
+            })?;
+        }
+        if valid & P9_SETATTR_SIZE != 0 {
+            OpenOptions::new()
+                .write(true)
+                .open(proc_path(&node.path))?
+                .set_len(attr.size)?;
+        }
+        if valid & (P9_SETATTR_ATIME | P9_SETATTR_MTIME) != 0 {
+            let times = [
+                timespec(attr, P9_SETATTR_ATIME, P9_SETATTR_ATIME_SET, attr.atime),
+                timespec(attr, P9_SETATTR_MTIME, P9_SETATTR_MTIME_SET, attr.mtime),
+            ];
+            let path = cstring(proc_path(&node.path).as_bytes())?;
+            // Safe because the path is a valid C string, `times` holds two timestamps, and we
+            // check the return value.
+            cvt(unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) })?;
+        }
+        Ok(())
+    }
+
+    fn open(&mut self, node: &mut Node, flags: i32) -> io::Result<()> {
+        check_not_symlink(node, libc::ELOOP)?;
+        let accmode = flags & libc::O_ACCMODE;
+        // The node is already resolved, and `/proc/self/fd` entries are symbolic links.
+        let file = OpenOptions::new()
+            .read(accmode != libc::O_WRONLY)
+            .write(accmode != libc::O_RDONLY)
+            .custom_flags(flags & !(libc::O_ACCMODE | libc::O_NOFOLLOW | libc::O_CREAT))
+            .open(proc_path(&node.path))?;
+        node.file = Some(file);
+        Ok(())
+    }
+
+    fn create(&mut self, dir: &Node, name: &[u8], flags: i32, mode: u32) -> io::Result<Node> {
+        let file = openat(
+            &dir.path,
+            name,
+            flags | libc::O_CREAT | libc::O_NOFOLLOW,
+            mode & 0o7777,
+        )?;
+        Ok(Node {
+            path: openat(&dir.path, name, PATH_FLAGS, 0)?,
+            file: Some(file),
+        })
+    }
+
+    fn read(&mut self, node: &mut Node, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        open_file(node)?.read_at(buf, offset)
+    }
+
+    fn write(&mut self, node: &mut Node, offset: u64, data: &[u8]) -> io::Result<usize> {
+        open_file(node)?.write_at(data, offset)
+    }
+
+    fn readdir(&mut self, node: &mut Node, offset: u64) -> io::Result<Vec<DirEntry>> {
+        let dir = open_file(node)?;
+        let mut entries = Vec::new();
+        for (index, entry) in fs::read_dir(proc_path(dir))?.enumerate() {
+            if (index as u64) < offset {
+                continue;
+            }
+            let entry = entry?;
+            let (ty, mode) = dir_entry_type(entry.file_type()?);
+            entries.push(DirEntry {
+                qid: Qid::from_mode(mode, std::os::unix::fs::DirEntryExt::ino(&entry)),
+                offset: index as u64 + 1,
+                ty,
+                name: entry.file_name().as_bytes().to_vec(),
+            });
+        }
+        Ok(entries)
+    }
+
+    fn fsync(&mut self, node: &mut Node, datasync: bool) -> io::Result<()> {
+        let file = open_file(node)?;
+        if datasync {
+            file.sync_data()
+        } else {
+            file.sync_all()
+        }
+    }
+
+    fn mkdir(&mut self, dir: &Node, name: &[u8], mode: u32) -> io::Result<Qid> {
+        let cname = cstring(name)?;
+        // Safe because the name is a valid C string, and we check the return value.
+        cvt(unsafe { libc::mkdirat(dir.path.as_raw_fd(), cname.as_ptr(), mode & 0o7777) })?;
+        qid_at(&dir.path, name)
+    }
+
+    fn symlink(&mut self, dir: &Node, name: &[u8], target: &[u8]) -> io::Result<Qid> {
+        let cname = cstring(name)?;
+        let target = cstring(target)?;
+        // Safe because both strings are valid C strings, and we check the return value.
+        cvt(unsafe { libc::symlinkat(target.as_ptr(), dir.path.as_raw_fd(), cname.as_ptr()) })?;
+        qid_at(&dir.path, name)
+    }
+
+    fn mknod(&mut self, dir: &Node, name: &[u8], mode: u32, rdev: u64) -> io::Result<Qid> {
+        let cname = cstring(name)?;
+        // Safe because the name is a valid C string, and we check the return value.
+        cvt(unsafe { libc::mknodat(dir.path.as_raw_fd(), cname.as_ptr(), mode, rdev) })?;
+        qid_at(&dir.path, name)
+    }
+
+    fn link(&mut self, dir: &Node, node: &Node, name: &[u8]) -> io::Result<()> {
+        check_not_symlink(node, libc::EPERM)?;
+        let cname = cstring(name)?;
+        let path = cstring(proc_path(&node.path).as_bytes())?;
+        // Safe because both paths are valid C strings, and we check the return value.
+        cvt(unsafe {
+            libc::linkat(
+                libc::AT_FDCWD,
+                path.as_ptr(),
+                dir.path.as_raw_fd(),
+                cname.as_ptr(),
+                libc::AT_SYMLINK_FOLLOW,
+            )
+        })?;
+        Ok(())
+    }
+
+    fn readlink(&mut self, node: &Node) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; MAX_LINK_LEN];
+        // Safe because the kernel writes at most `buf.len()` bytes, and we check the return
+        // value.
+        let len = unsafe {
+            libc::readlinkat(
+                node.path.as_raw_fd(),
+                b"\0".as_ptr() as *const libc::c_char,
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buf.truncate(len as usize);
+        Ok(buf)
+    }
+
+    fn unlink(&mut self, dir: &Node, name: &[u8], remove_dir: bool) -> io::Result<()> {
+        let cname = cstring(name)?;
+        let flags = if remove_dir { libc::AT_REMOVEDIR } else { 0 };
+        // Safe because the name is a valid C string, and we check the return value.
+        cvt(unsafe { libc::unlinkat(dir.path.as_raw_fd(), cname.as_ptr(), flags) })?;
+        Ok(())
+    }
+
+    fn rename(
+        &mut self,
+        old_dir: &Node,
+        old_name: &[u8],
+        new_dir: &Node,
+        new_name: &[u8],
+    ) -> io::Result<()> {
+        let old_name = cstring(old_name)?;
+        let new_name = cstring(new_name)?;
+        // Safe because both names are valid C strings, and we check the return value.
+        cvt(unsafe {
+            libc::renameat(
+                old_dir.path.as_raw_fd(),
+                old_name.as_ptr(),
+                new_dir.path.as_raw_fd(),
+                new_name.as_ptr(),
+            )
+        })?;
+        Ok(())
+    }
+
+    fn statfs(&mut self, node: &Node) -> io::Result<StatFs> {
+        let mut st = MaybeUninit::<libc::statvfs>::zeroed();
+        // Safe because the kernel only writes a `statvfs` structure to `st`, and we check the
+        // return value.
+        cvt(unsafe { libc::fstatvfs(node.path.as_raw_fd(), st.as_mut_ptr()) })?;
+        // Safe because the kernel initialized the structure.
+        let st = unsafe { st.assume_init() };
+        Ok(StatFs {
+            ty: V9FS_MAGIC,
+            bsize: st.f_bsize as u32,
+            blocks: st.f_blocks,
+            bfree: st.f_bfree,
+            bavail: st.f_bavail,
+            files: st.f_files,
+            ffree: st.f_ffree,
+            fsid: st.f_fsid,
+            namelen: st.f_namemax as u32,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vmm_sys_util::tempdir::TempDir;
+
+    fn errno(e: io::Error) -> i32 {
+        e.raw_os_error().unwrap()
+    }
+
+    #[test]
+    fn test_files() {
+        let dir = TempDir::new_with_prefix("/tmp/p9").unwrap();
+        let mut fs = PassthroughFs::new(dir.as_path()).unwrap();
+        let root = fs.attach(b"").unwrap();
+        let root_attr = fs.getattr(&root).unwrap();
+        assert_eq!(root_attr.qid.ty, P9_QTDIR);
+
+        let mut file = fs
+            .create(&root, b"file", libc::O_RDWR | libc::O_CLOEXEC, 0o600)
+            .unwrap();
+        assert_eq!(fs.write(&mut file, 2, b"abc").unwrap(), 3);
+        let mut buf = [0xffu8; 8];
+        assert_eq!(fs.read(&mut file, 0, &mut buf).unwrap(), 5);
+        assert_eq!(buf[..5], [0, 0, b'a', b'b', b'c']);
+        fs.fsync(&mut file, false).unwrap();
+        assert_eq!(
+            errno(
+                fs.create(&root, b"file", libc::O_RDWR | libc::O_EXCL, 0o600)
+                    .unwrap_err()
+            ),
+            libc::EEXIST
+        );
+
+        let mut node = fs.walk(&root, b"file").unwrap();
+        let attr = fs.getattr(&node).unwrap();
+        assert_eq!(attr.qid.ty, P9_QTFILE);
+        assert_eq!(attr.size, 5);
+        assert_eq!(attr.mode & 0o777, 0o600);
+        // The node is not open yet.
+        assert_eq!(
+            errno(fs.read(&mut node, 0, &mut buf).unwrap_err()),
+            libc::EBADF
+        );
+        fs.open(&mut node, libc::O_RDONLY).unwrap();
+        assert_eq!(fs.read(&mut node, 3, &mut buf).unwrap(), 2);
+        assert_eq!(
+            errno(fs.write(&mut node, 0, b"x").unwrap_err()),
+            libc::EBADF
+        );
+
+        let attr = SetAttr {
+            valid: P9_SETATTR_MODE | P9_SETATTR_SIZE | P9_SETATTR_MTIME | P9_SETATTR_MTIME_SET,
+            mode: 0o640,
+            size: 1,
+            mtime: (1000, 5),
+            ..Default::default()
+        };
+        fs.setattr(&node, &attr).unwrap();
+        let attr = fs.getattr(&node).unwrap();
+        assert_eq!(attr.mode & 0o777, 0o640);
+        assert_eq!(attr.size, 1);
+        assert_eq!(attr.mtime, (1000, 5));
+
+        fs.link(&root, &node, b"hardlink").unwrap();
+        assert_eq!(fs.getattr(&node).unwrap().nlink, 2);
+        fs.rename(&root, b"hardlink", &root, b"renamed").unwrap();
+        let renamed = fs.walk(&root, b"renamed").unwrap();
+        assert_eq!(fs.getattr(&renamed).unwrap().qid, attr.qid);
+        fs.unlink(&root, b"renamed", false).unwrap();
+        assert_eq!(errno(fs.walk(&root, b"renamed").unwrap_err()), libc::ENOENT);
+
+        let st = fs.statfs(&root).unwrap();
+        assert_eq!(st.ty, V9FS_MAGIC);
+        assert_ne!(st.bsize, 0);
+    }
+
+    #[test]
+    fn test_directories() {
+        let dir = TempDir::new_with_prefix("/tmp/p9").unwrap();
+        let mut fs = PassthroughFs::new(dir.as_path()).unwrap();
+        let root = fs.attach(b"").unwrap();
+        let root_qid = fs.getattr(&root).unwrap().qid;
+
+        let qid = fs.mkdir(&root, b"dir", 0o755).unwrap();
+        assert_eq!(qid.ty, P9_QTDIR);
+        let sub = fs.walk(&root, b"dir").unwrap();
+        assert_eq!(fs.getattr(&sub).unwrap().qid, qid);
+        // `..` goes up, but not past the root.
+        let up = fs.walk(&sub, b"..").unwrap();
+        assert_eq!(fs.getattr(&up).unwrap().qid, root_qid);
+        let up = fs.walk(&root, b"..").unwrap();
+        assert_eq!(fs.getattr(&up).unwrap().qid, root_qid);
+
+        fs.mknod(&sub, b"fifo", libc::S_IFIFO | 0o600, 0).unwrap();
+        let mut node = fs.clone_node(&sub).unwrap();
+        fs.open(&mut node, libc::O_RDONLY | libc::O_DIRECTORY)
+            .unwrap();
+        let entries = fs.readdir(&mut node, 0).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, b"fifo");
+        assert_eq!(entries[0].ty, libc::DT_FIFO);
+        assert!(fs.readdir(&mut node, entries[0].offset).unwrap().is_empty());
+
+        assert_eq!(
+            errno(fs.unlink(&root, b"dir", true).unwrap_err()),
+            libc::ENOTEMPTY
+        );
+        fs.unlink(&sub, b"fifo", false).unwrap();
+        fs.unlink(&root, b"dir", true).unwrap();
+    }
+
+    #[test]
+    fn test_symlinks() {
+        let dir = TempDir::new_with_prefix("/tmp/p9").unwrap();
+        let mut fs = PassthroughFs::new(dir.as_path()).unwrap();
+        let root = fs.attach(b"").unwrap();
+
+        let qid = fs.symlink(&root, b"link", b"/etc").unwrap();
+        assert_eq!(qid.ty, P9_QTSYMLINK);
+        let mut link = fs.walk(&root, b"link").unwrap();
+        assert_eq!(fs.readlink(&link).unwrap(), b"/etc");
+
+        // Symbolic links are never followed.
+        assert_eq!(errno(fs.walk(&link, b"passwd").unwrap_err()), libc::ENOTDIR);
+        assert_eq!(
+            errno(fs.open(&mut link, libc::O_RDONLY).unwrap_err()),
+            libc::ELOOP
+        );
+        let attr = SetAttr {
+            valid: P9_SETATTR_MODE,
+            mode: 0o777,
+            ..Default::default()
+        };
+        assert_eq!(
+            errno(fs.setattr(&link, &attr).unwrap_err()),
+            libc::EOPNOTSUPP
+        );
+        assert_eq!(
+            errno(fs.link(&root, &link, b"other").unwrap_err()),
+            libc::EPERM
+        );
+        assert_eq!(errno(fs.readlink(&root).unwrap_err()), libc::ENOENT);
+    }
+}
@@ -0,0 +1,776 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! 9P2000.L message abstractions.
+//!
+//! Every message starts with a header, which holds the size of the whole message (as an `u32`),
+//! the type of the message (as an `u8`), and the tag which pairs requests with their replies (as
+//! an `u16`). The body of the message follows. All integers are little endian, and strings
+//! (i.e. names) are made of an `u16` length followed by the bytes of the string.
+//!
+//! This module provides the decoding of the [`Request`](enum.Request.html) messages (the `T`
+//! messages) supported by the device, and the encoding of the [`Response`](enum.Response.html)
+//! messages (the `R` messages).
+
+use std::convert::TryInto;
+use std::fmt::{self, Display};
+use std::mem::size_of;
+use std::result;
+
+use crate::backend::{Attr, DirEntry, Qid, SetAttr, StatFs};
+use crate::defs::*;
+
+/// The size of the message header.
+pub const HEADER_LEN: usize = 7;
+/// The size of an encoded qid.
+pub const QID_LEN: usize = 13;
+
+/// Message decoding errors.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The message is shorter than its contents.
+    TooShort,
+    /// The size from the header doesn't match the buffer.
+    InvalidSize(u32),
+    /// `Twalk` message with too many names.
+    TooManyNames(u16),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            TooShort => write!(f, "the message is too short"),
+            InvalidSize(size) => write!(f, "invalid message size {}", size),
+            TooManyNames(num) => write!(f, "too many names to walk: {}", num),
+        }
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// A request from the driver.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    /// `Tversion`: negotiate the protocol version and the message size.
+    Version {
+        /// The maximum message size the driver supports.
+        msize: u32,
+        /// The protocol version.
+        version: Vec<u8>,
+    },
+    /// `Tattach`: associate a fid with the root of the filesystem.
+    Attach {
+        /// The new fid.
+        fid: u32,
+        /// The authentication fid (`P9_NOFID`, since authentication is not supported).
+        afid: u32,
+        /// The user name.
+        uname: Vec<u8>,
+        /// The name of the filesystem.
+        aname: Vec<u8>,
+        /// The numeric user identifier.
+        n_uname: u32,
+    },
+    /// `Tflush`: abort a pending request.
+    Flush {
+        /// The tag of the request.
+        oldtag: u16,
+    },
+    /// `Twalk`: associate a new fid with the file reached by walking names from a fid.
+    Walk {
+        /// The starting fid.
+        fid: u32,
+        /// The new fid.
+        newfid: u32,
+        /// The names to walk.
+        names: Vec<Vec<u8>>,
+    },
+    /// `Tread`: read from an open file.
+    Read {
+        /// The open fid.
+        fid: u32,
+        /// The offset in the file.
+        offset: u64,
+        /// The number of bytes to read.
+        count: u32,
+    },
+    /// `Twrite`: write to an open file.
+    Write {
+        /// The open fid.
+        fid: u32,
+        /// The offset in the file.
+        offset: u64,
+        /// The data to write.
+        data: Vec<u8>,
+    },
+    /// `Tclunk`: forget a fid.
+    Clunk {
+        /// The fid.
+        fid: u32,
+    },
+    /// `Tstatfs`: get filesystem information.
+    Statfs {
+        /// A fid of a file from the filesystem.
+        fid: u32,
+    },
+    /// `Tlopen`: open a file.
+    Lopen {
+        /// The fid of the file.
+        fid: u32,
+        /// The open flags (`P9_*`).
+        flags: u32,
+    },
+    /// `Tlcreate`: create and open a file, which the fid of the parent directory then refers
+    /// to.
+    Lcreate {
+        /// The fid of the parent directory.
+        fid: u32,
+        /// The name of the new file.
+        name: Vec<u8>,
+        /// The open flags (`P9_*`).
+        flags: u32,
+        /// The permissions of the new file.
+        mode: u32,
+        /// The group of the new file.
+        gid: u32,
+    },
+    /// `Tsymlink`: create a symbolic link.
+    Symlink {
+        /// The fid of the parent directory.
+        fid: u32,
+        /// The name of the link.
+        name: Vec<u8>,
+        /// The target of the link.
+        target: Vec<u8>,
+        /// The group of the link.
+        gid: u32,
+    },
+    /// `Tmknod`: create a device node, a FIFO or a socket.
+    Mknod {
+        /// The fid of the parent directory.
+        dfid: u32,
+        /// The name of the new file.
+        name: Vec<u8>,
+        /// The type and permissions of the new file.
+        mode: u32,
+        /// The major device number.
+        major: u32,
+        /// The minor device number.
+        minor: u32,
+        /// The group of the new file.
+        gid: u32,
+    },
+    /// `Treadlink`: read the target of a symbolic link.
+    Readlink {
+        /// The fid of the link.
+        fid: u32,
+    },
+    /// `Tgetattr`: get the attributes of a file.
+    Getattr {
+        /// The fid of the file.
+        fid: u32,
+        /// The attributes the driver is interested in (`P9_GETATTR_*`).
+        request_mask: u64,
+    },
+    /// `Tsetattr`: set the attributes of a file.
+    Setattr {
+        /// The fid of the file.
+        fid: u32,
+        /// The attributes.
+        attr: SetAttr,
+    },
+    /// `Treaddir`: read directory entries.
+    Readdir {
+        /// The fid of the open directory.
+        fid: u32,
+        /// The offset of the first entry.
+        offset: u64,
+        /// The maximum size of the entries.
+        count: u32,
+    },
+    /// `Tfsync`: flush cached file data.
+    Fsync {
+        /// The fid of the open file.
+        fid: u32,
+        /// Whether only the data has to be flushed.
+        datasync: u32,
+    },
+    /// `Tlink`: create a hard link.
+    Link {
+        /// The fid of the directory of the link.
+        dfid: u32,
+        /// The fid of the file.
+        fid: u32,
+        /// The name of the link.
+        name: Vec<u8>,
+    },
+    /// `Tmkdir`: create a directory.
+    Mkdir {
+        /// The fid of the parent directory.
+        dfid: u32,
+        /// The name of the new directory.
+        name: Vec<u8>,
+        /// The permissions of the new directory.
+        mode: u32,
+        /// The group of the new directory.
+        gid: u32,
+    },
+    /// `Trenameat`: rename a directory entry.
+    Renameat {
+        /// The fid of the directory of the entry.
+        olddirfid: u32,
+        /// The name of the entry.
+        oldname: Vec<u8>,
+        /// The fid of the new directory of the entry.
+        newdirfid: u32,
+        /// The new name of the entry.
+        newname: Vec<u8>,
+    },
+    /// `Tunlinkat`: remove a directory entry.
+    Unlinkat {
+        /// The fid of the directory of the entry.
+        dirfid: u32,
+        /// The name of the entry.
+        name: Vec<u8>,
+        /// The flags (`P9_AT_REMOVEDIR`).
+        flags: u32,
+    },
+    /// A message which is not supported by the device.
+    Unsupported(u8),
+}
+
+/// A reply to the driver.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    /// `Rlerror`: the request failed with a Linux `errno` value.
+    Lerror(u32),
+    /// `Rversion`.
+    Version {
+        /// The negotiated message size.
+        msize: u32,
+        /// The protocol version, or `unknown`.
+        version: Vec<u8>,
+    },
+    /// `Rattach`, with the qid of the root.
+    Attach(Qid),
+    /// `Rflush`.
+    Flush,
+    /// `Rwalk`, with the qids of the walked names.
+    Walk(Vec<Qid>),
+    /// `Rread`, with the data.
+    Read(Vec<u8>),
+    /// `Rwrite`, with the number of bytes written.
+    Write(u32),
+    /// `Rclunk`.
+    Clunk,
+    /// `Rstatfs`.
+    Statfs(StatFs),
+    /// `Rlopen`.
+    Lopen {
+        /// The qid of the file.
+        qid: Qid,
+        /// The maximum size of atomic I/O operations (zero when unknown).
+        iounit: u32,
+    },
+    /// `Rlcreate`.
+    Lcreate {
+        /// The qid of the new file.
+        qid: Qid,
+        /// The maximum size of atomic I/O operations (zero when unknown).
+        iounit: u32,
+    },
+    /// `Rsymlink`, with the qid of the link.
+    Symlink(Qid),
+    /// `Rmknod`, with the qid of the new file.
+    Mknod(Qid),
+    /// `Rreadlink`, with the target of the link.
+    Readlink(Vec<u8>),
+    /// `Rgetattr`.
+    Getattr {
+        /// The valid attributes (`P9_GETATTR_*`).
+        valid: u64,
+        /// The attributes.
+        attr: Attr,
+    },
+    /// `Rsetattr`.
+    Setattr,
+    /// `Rreaddir`, with the directory entries.
+    Readdir(Vec<DirEntry>),
+    /// `Rfsync`.
+    Fsync,
+    /// `Rlink`.
+    Link,
+    /// `Rmkdir`, with the qid of the new directory.
+    Mkdir(Qid),
+    /// `Rrenameat`.
+    Renameat,
+    /// `Runlinkat`.
+    Unlinkat,
+}
+
+// Decodes the fields of a message.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(Error::TooShort);
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        // The slice has the right length.
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>> {
+        let len = self.u16()?;
+        Ok(self.bytes(usize::from(len))?.to_vec())
+    }
+}
+
+// Encodes the fields of a message.
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Strings longer than `u16::MAX` bytes are truncated, but the ones the device sends come
+    // from the filesystem, whose names (and symbolic link targets) are shorter.
+    fn string(&mut self, value: &[u8]) {
+        let len = value.len().min(usize::from(u16::MAX));
+        self.u16(len as u16);
+        self.buf.extend_from_slice(&value[..len]);
+    }
+
+    fn qid(&mut self, qid: &Qid) {
+        self.u8(qid.ty);
+        self.u32(qid.version);
+        self.u64(qid.path);
+    }
+}
+
+/// Returns the size of an encoded directory entry.
+///
+/// # Arguments
+/// * `entry` - The directory entry.
+pub fn dir_entry_len(entry: &DirEntry) -> usize {
+    QID_LEN + size_of::<u64>() + size_of::<u8>() + size_of::<u16>() + entry.name.len()
+}
+
+impl Request {
+    /// Decodes a message, and returns its tag along with the request.
+    ///
+    /// # Arguments
+    /// * `buf` - The message, which can be followed by other bytes.
+    pub fn decode(buf: &[u8]) -> Result<(u16, Request)> {
+        let mut r = Reader { buf };
+        let size = r.u32()?;
+        if (size as usize) < HEADER_LEN || size as usize > buf.len() {
+            return Err(Error::InvalidSize(size));
+        }
+        r.buf = &buf[size_of::<u32>()..size as usize];
+        let ty = r.u8()?;
+        let tag = r.u16()?;
+
+        let req = match ty {
+            P9_TVERSION => Request::Version {
+                msize: r.u32()?,
+                version: r.string()?,
+            },
+            P9_TATTACH => Request::Attach {
+                fid: r.u32()?,
+                afid: r.u32()?,
+                uname: r.string()?,
+                aname: r.string()?,
+                n_uname: r.u32()?,
+            },
+            P9_TFLUSH => Request::Flush { oldtag: r.u16()? },
+            P9_TWALK => {
+                let fid = r.u32()?;
+                let newfid = r.u32()?;
+                let num = r.u16()?;
+                if usize::from(num) > P9_MAXWELEM {
+                    return Err(Error::TooManyNames(num));
+                }
+                let names = (0..num).map(|_| r.string()).collect::<Result<_>>()?;
+                Request::Walk { fid, newfid, names }
+            }
+            P9_TREAD => Request::Read {
+                fid: r.u32()?,
+                offset: r.u64()?,
+                count: r.u32()?,
+            },
+            P9_TWRITE => {
+                let fid = r.u32()?;
+                let offset = r.u64()?;
+                let count = r.u32()?;
+                let data = r.bytes(count as usize)?.to_vec();
+                Request::Write { fid, offset, data }
+            }
+            P9_TCLUNK => Request::Clunk { fid: r.u32()? },
+            P9_TSTATFS => Request::Statfs { fid: r.u32()? },
+            P9_TLOPEN => Request::Lopen {
+                fid: r.u32()?,
+                flags: r.u32()?,
+            },
+            P9_TLCREATE => Request::Lcreate {
+                fid: r.u32()?,
+                name: r.string()?,
+                flags: r.u32()?,
+                mode: r.u32()?,
+                gid: r.u32()?,
+            },
+            P9_TSYMLINK => Request::Symlink {
+                fid: r.u32()?,
+                name: r.string()?,
+                target: r.string()?,
+                gid: r.u32()?,
+            },
+            P9_TMKNOD => Request::Mknod {
+                dfid: r.u32()?,
+                name: r.string()?,
+                mode: r.u32()?,
+                major: r.u32()?,
+                minor: r.u32()?,
+                gid: r.u32()?,
+            },
+            P9_TREADLINK => Request::Readlink { fid: r.u32()? },
+            P9_TGETATTR => Request::Getattr {
+                fid: r.u32()?,
+                request_mask: r.u64()?,
+            },
+            P9_TSETATTR => Request::Setattr {
+                fid: r.u32()?,
+                attr: SetAttr {
+                    valid: r.u32()?,
+                    mode: r.u32()?,
+                    uid: r.u32()?,
+                    gid: r.u32()?,
+                    size: r.u64()?,
+                    atime: (r.u64()?, r.u64()?),
+                    mtime: (r.u64()?, r.u64()?),
+                },
+            },
+            P9_TREADDIR => Request::Readdir {
+                fid: r.u32()?,
+                offset: r.u64()?,
+                count: r.u32()?,
+            },
+            P9_TFSYNC => Request::Fsync {
+                fid: r.u32()?,
+                datasync: r.u32()?,
+            },
+            P9_TLINK => Request::Link {
+                dfid: r.u32()?,
+                fid: r.u32()?,
+                name: r.string()?,
+            },
+            P9_TMKDIR => Request::Mkdir {
+                dfid: r.u32()?,
+                name: r.string()?,
+                mode: r.u32()?,
+                gid: r.u32()?,
+            },
+            P9_TRENAMEAT => Request::Renameat {
+                olddirfid: r.u32()?,
+                oldname: r.string()?,
+                newdirfid: r.u32()?,
+                newname: r.string()?,
+            },
+            P9_TUNLINKAT => Request::Unlinkat {
+                dirfid: r.u32()?,
+                name: r.string()?,
+                flags: r.u32()?,
+            },
+            ty => Request::Unsupported(ty),
+        };
+        Ok((tag, req))
+    }
+}
+
+impl Response {
+    // Returns the message type of the response.
+    fn message_type(&self) -> u8 {
+        let request_type = match self {
+            Response::Lerror(_) => return P9_RLERROR,
+            Response::Version { .. } => P9_TVERSION,
+            Response::Attach(_) => P9_TATTACH,
+            Response::Flush => P9_TFLUSH,
+            Response::Walk(_) => P9_TWALK,
+            Response::Read(_) => P9_TREAD,
+            Response::Write(_) => P9_TWRITE,
+            Response::Clunk => P9_TCLUNK,
+            Response::Statfs(_) => P9_TSTATFS,
+            Response::Lopen { .. } => P9_TLOPEN,
+            Response::Lcreate { .. } => P9_TLCREATE,
+            Response::Symlink(_) => P9_TSYMLINK,
+            Response::Mknod(_) => P9_TMKNOD,
+            Response::Readlink(_) => P9_TREADLINK,
+            Response::Getattr { .. } => P9_TGETATTR,
+            Response::Setattr => P9_TSETATTR,
+            Response::Readdir(_) => P9_TREADDIR,
+            Response::Fsync => P9_TFSYNC,
+            Response::Link => P9_TLINK,
+            Response::Mkdir(_) => P9_TMKDIR,
+            Response::Renameat => P9_TRENAMEAT,
+            Response::Unlinkat => P9_TUNLINKAT,
+        };
+        request_type + 1
+    }
+
+    /// Encodes the response as a message.
+    ///
+    /// # Arguments
+    /// * `tag` - The tag of the request.
+    pub fn encode(&self, tag: u16) -> Vec<u8> {
+        let mut w = Writer {
+            buf: Vec::with_capacity(HEADER_LEN),
+        };
+        // The size is filled in at the end.
+        w.u32(0);
+        w.u8(self.message_type());
+        w.u16(tag);
+
+        match self {
+            Response::Lerror(ecode) => w.u32(*ecode),
+            Response::Version { msize, version } => {
+                w.u32(*msize);
+                w.string(version);
+            }
+            Response::Attach(qid)
+            | Response::Symlink(qid)
+            | Response::Mknod(qid)
+            | Response::Mkdir(qid) => w.qid(qid),
+            Response::Walk(qids) => {
+                // There's at most `P9_MAXWELEM` qids.
+                w.u16(qids.len() as u16);
+                qids.iter().for_each(|qid| w.qid(qid));
+            }
+            Response::Read(data) => {
+                // The data is bounded by the message size.
+                w.u32(data.len() as u32);
+                w.buf.extend_from_slice(data);
+            }
+            Response::Write(count) => w.u32(*count),
+            Response::Statfs(st) => {
+                w.u32(st.ty);
+                w.u32(st.bsize);
+                w.u64(st.blocks);
+                w.u64(st.bfree);
+                w.u64(st.bavail);
+                w.u64(st.files);
+                w.u64(st.ffree);
+                w.u64(st.fsid);
+                w.u32(st.namelen);
+            }
+            Response::Lopen { qid, iounit } | Response::Lcreate { qid, iounit } => {
+                w.qid(qid);
+                w.u32(*iounit);
+            }
+            Response::Readlink(target) => w.string(target),
+            Response::Getattr { valid, attr } => {
+                w.u64(*valid);
+                w.qid(&attr.qid);
+                w.u32(attr.mode);
+                w.u32(attr.uid);
+                w.u32(attr.gid);
+                w.u64(attr.nlink);
+                w.u64(attr.rdev);
+                w.u64(attr.size);
+                w.u64(attr.blksize);
+                w.u64(attr.blocks);
+                for &(sec, nsec) in [attr.atime, attr.mtime, attr.ctime].iter() {
+                    w.u64(sec);
+                    w.u64(nsec);
+                }
+                // The birth time, the generation and the data version are not reported.
+                (0..4).for_each(|_| w.u64(0));
+            }
+            Response::Readdir(entries) => {
+                let len: usize = entries.iter().map(dir_entry_len).sum();
+                // The entries are bounded by the message size.
+                w.u32(len as u32);
+                for entry in entries {
+                    w.qid(&entry.qid);
+                    w.u64(entry.offset);
+                    w.u8(entry.ty);
+                    w.string(&entry.name);
+                }
+            }
+            Response::Flush
+            | Response::Clunk
+            | Response::Setattr
+            | Response::Fsync
+            | Response::Link
+            | Response::Renameat
+            | Response::Unlinkat => {}
+        }
+
+        // Messages are bounded by the negotiated message size, which is an `u32`.
+        let size = w.buf.len() as u32;
+        w.buf[..4].copy_from_slice(&size.to_le_bytes());
+        w.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(ty: u8, tag: u16, body: &[u8]) -> Vec<u8> {
+        let mut buf = ((HEADER_LEN + body.len()) as u32).to_le_bytes().to_vec();
+        buf.push(ty);
+        buf.extend_from_slice(&tag.to_le_bytes());
+        buf.extend_from_slice(body);
+        buf
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut body = 8192u32.to_le_bytes().to_vec();
+        body.extend_from_slice(b"\x08\x009P2000.L");
+        let msg = message(P9_TVERSION, P9_NOTAG, &body);
+        assert_eq!(
+            Request::decode(&msg).unwrap(),
+            (
+                P9_NOTAG,
+                Request::Version {
+                    msize: 8192,
+                    version: b"9P2000.L".to_vec()
+                }
+            )
+        );
+        // The string is cut short.
+        assert_eq!(
+            Request::decode(&msg[..msg.len() - 1]),
+            Err(Error::InvalidSize(msg.len() as u32))
+        );
+        let mut short = message(P9_TVERSION, 1, &body[..body.len() - 1]);
+        short.push(0);
+        assert_eq!(Request::decode(&short), Err(Error::TooShort));
+        // Trailing bytes are ignored.
+        let mut long = msg.clone();
+        long.extend_from_slice(&[0; 10]);
+        assert!(Request::decode(&long).is_ok());
+
+        let mut body = vec![1, 0, 0, 0, 2, 0, 0, 0, 2, 0];
+        body.extend_from_slice(b"\x01\x00a\x02\x00bc");
+        assert_eq!(
+            Request::decode(&message(P9_TWALK, 5, &body)).unwrap(),
+            (
+                5,
+                Request::Walk {
+                    fid: 1,
+                    newfid: 2,
+                    names: vec![b"a".to_vec(), b"bc".to_vec()]
+                }
+            )
+        );
+        let body = [1, 0, 0, 0, 2, 0, 0, 0, 17, 0];
+        assert_eq!(
+            Request::decode(&message(P9_TWALK, 5, &body)),
+            Err(Error::TooManyNames(17))
+        );
+
+        let mut body = vec![3, 0, 0, 0];
+        body.extend_from_slice(&10u64.to_le_bytes());
+        body.extend_from_slice(&[2, 0, 0, 0, 0xaa, 0xbb]);
+        assert_eq!(
+            Request::decode(&message(P9_TWRITE, 1, &body)).unwrap().1,
+            Request::Write {
+                fid: 3,
+                offset: 10,
+                data: vec![0xaa, 0xbb]
+            }
+        );
+
+        assert_eq!(
+            Request::decode(&message(P9_TXATTRWALK, 1, &[])).unwrap().1,
+            Request::Unsupported(P9_TXATTRWALK)
+        );
+        assert_eq!(Request::decode(&[7, 0, 0]), Err(Error::TooShort));
+        assert_eq!(
+            Request::decode(&message(P9_TCLUNK, 1, &[])[..6]),
+            Err(Error::InvalidSize(7))
+        );
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(
+            Response::Lerror(2).encode(3),
+            message(P9_RLERROR, 3, &[2, 0, 0, 0])
+        );
+        assert_eq!(Response::Clunk.encode(1), message(P9_TCLUNK + 1, 1, &[]));
+
+        let qid = Qid {
+            ty: P9_QTDIR,
+            version: 1,
+            path: 2,
+        };
+        assert_eq!(
+            Response::Walk(vec![qid]).encode(1),
+            message(
+                P9_TWALK + 1,
+                1,
+                &[1, 0, P9_QTDIR, 1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0]
+            )
+        );
+
+        let entry = DirEntry {
+            qid,
+            offset: 1,
+            ty: libc::DT_DIR,
+            name: b"dir".to_vec(),
+        };
+        assert_eq!(dir_entry_len(&entry), 27);
+        let msg = Response::Readdir(vec![entry.clone(), entry]).encode(1);
+        assert_eq!(msg.len(), HEADER_LEN + 4 + 54);
+        assert_eq!(msg[HEADER_LEN..HEADER_LEN + 4], [54, 0, 0, 0]);
+
+        let msg = Response::Getattr {
+            valid: P9_GETATTR_BASIC,
+            attr: Attr::default(),
+        }
+        .encode(1);
+        assert_eq!(msg.len(), HEADER_LEN + 8 + QID_LEN + 3 * 4 + 15 * 8);
+    }
+}
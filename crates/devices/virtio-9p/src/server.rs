@@ -0,0 +1,729 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! 9P2000.L server.
+//!
+//! This module provides the [`Server`](struct.Server.html) abstraction, which handles the
+//! requests from the driver on top of a [`Filesystem`](../backend/trait.Filesystem.html). The
+//! server negotiates the message size, and keeps track of the fids, which are the identifiers
+//! the driver chooses for the files it works with. Requests are handled synchronously, so
+//! `Tflush` has nothing to abort.
+
+use std::collections::BTreeMap;
+use std::io;
+
+use log::warn;
+
+use crate::backend::Filesystem;
+use crate::defs::*;
+use crate::protocol::{dir_entry_len, Request, Response, HEADER_LEN};
+
+/// The default limit for the message size.
+pub const DEFAULT_MAX_MSIZE: u32 = 128 << 10;
+/// The smallest message size the server accepts.
+pub const MIN_MSIZE: u32 = 4096;
+/// The maximum number of fids which are in use at the same time.
+pub const MAX_FIDS: usize = 4096;
+
+// The size of the header of `Rread` and `Rreaddir` messages, which is followed by the data.
+const IO_HEADER_LEN: u32 = HEADER_LEN as u32 + 4;
+
+// Returns the `errno` value which is reported to the driver for an error.
+fn errno(e: &io::Error) -> u32 {
+    // Error codes are positive.
+    e.raw_os_error().unwrap_or(libc::EIO) as u32
+}
+
+fn error(errno: i32) -> io::Error {
+    io::Error::from_raw_os_error(errno)
+}
+
+// Checks that a name is a single path component (`..` is only valid when walking).
+fn check_name(name: &[u8], allow_dotdot: bool) -> io::Result<()> {
+    if name.is_empty()
+        || name == b"."
+        || (name == b".." && !allow_dotdot)
+        || name.iter().any(|&b| b == b'/' || b == 0)
+    {
+        return Err(error(libc::EINVAL));
+    }
+    Ok(())
+}
+
+// Translates protocol open flags to host open flags.
+fn open_flags(flags: u32) -> i32 {
+    const FLAGS: [(u32, i32); 10] = [
+        (P9_CREATE, libc::O_CREAT),
+        (P9_EXCL, libc::O_EXCL),
+        (P9_TRUNC, libc::O_TRUNC),
+        (P9_APPEND, libc::O_APPEND),
+        (P9_NONBLOCK, libc::O_NONBLOCK),
+        (P9_DSYNC, libc::O_DSYNC),
+        (P9_DIRECTORY, libc::O_DIRECTORY),
+        (P9_NOFOLLOW, libc::O_NOFOLLOW),
+        (P9_NOATIME, libc::O_NOATIME),
+        (P9_SYNC, libc::O_SYNC),
+    ];
+
+    let mut host_flags = match flags & P9_ACCMODE {
+        P9_WRONLY => libc::O_WRONLY,
+        P9_RDWR => libc::O_RDWR,
+        _ => libc::O_RDONLY,
+    };
+    for &(flag, host_flag) in FLAGS.iter() {
+        if flags & flag != 0 {
+            host_flags |= host_flag;
+        }
+    }
+    host_flags | libc::O_CLOEXEC
+}
+
+// Returns the node associated with a fid.
+fn node<N>(fids: &BTreeMap<u32, N>, fid: u32) -> io::Result<&N> {
+    fids.get(&fid).ok_or_else(|| error(libc::EBADF))
+}
+
+fn node_mut<N>(fids: &mut BTreeMap<u32, N>, fid: u32) -> io::Result<&mut N> {
+    fids.get_mut(&fid).ok_or_else(|| error(libc::EBADF))
+}
+
+/// A 9P2000.L server.
+#[derive(Debug)]
+pub struct Server<F: Filesystem> {
+    fs: F,
+    fids: BTreeMap<u32, F::Node>,
+    msize: u32,
+    max_msize: u32,
+}
+
+impl<F: Filesystem> Server<F> {
+    /// Creates a new `Server`.
+    ///
+    /// # Arguments
+    /// * `fs` - The filesystem.
+    /// * `max_msize` - The limit for the message size.
+    pub fn new(fs: F, max_msize: u32) -> Self {
+        Server {
+            fs,
+            fids: BTreeMap::new(),
+            msize: max_msize,
+            max_msize,
+        }
+    }
+
+    /// Returns a reference to the filesystem.
+    pub fn filesystem(&self) -> &F {
+        &self.fs
+    }
+
+    /// Returns the negotiated message size.
+    pub fn msize(&self) -> u32 {
+        self.msize
+    }
+
+    /// Returns the number of fids in use.
+    pub fn num_fids(&self) -> usize {
+        self.fids.len()
+    }
+
+    /// Forgets all the fids, and restores the message size to its limit.
+    pub fn reset(&mut self) {
+        self.fids.clear();
+        self.msize = self.max_msize;
+    }
+
+    /// Handles a message, and returns the reply. Returns `None` when the message is malformed
+    /// and doesn't even have a tag to reply to.
+    ///
+    /// # Arguments
+    /// * `msg` - The message.
+    pub fn handle_message(&mut self, msg: &[u8]) -> Option<Vec<u8>> {
+        match Request::decode(msg) {
+            Ok((tag, req)) => {
+                let resp = self
+                    .handle_request(req)
+                    .unwrap_or_else(|e| Response::Lerror(errno(&e)));
+                Some(resp.encode(tag))
+            }
+            Err(e) => {
+                warn!("invalid 9p message: {}", e);
+                let tag = msg.get(5..HEADER_LEN)?;
+                let tag = u16::from_le_bytes([tag[0], tag[1]]);
+                Some(Response::Lerror(libc::EINVAL as u32).encode(tag))
+            }
+        }
+    }
+
+    /// Handles a request, and returns the reply.
+    ///
+    /// # Arguments
+    /// * `req` - The request.
+    pub fn handle_request(&mut self, req: Request) -> io::Result<Response> {
+        match req {
+            Request::Version { msize, version } => self.version(msize, version),
+            Request::Attach {
+                fid, afid, aname, ..
+            } => {
+                if afid != P9_NOFID {
+                    // Authentication is not supported.
+                    return Err(error(libc::EOPNOTSUPP));
+                }
+                self.check_new_fid(fid)?;
+                let node = self.fs.attach(&aname)?;
+                let qid = self.fs.getattr(&node)?.qid;
+                self.fids.insert(fid, node);
+                Ok(Response::Attach(qid))
+            }
+            Request::Flush { .. } => Ok(Response::Flush),
+            Request::Walk { fid, newfid, names } => self.walk(fid, newfid, &names),
+            Request::Read { fid, offset, count } => {
+                let count = count.min(self.msize - IO_HEADER_LEN);
+                let mut buf = vec![0u8; count as usize];
+                let len = self
+                    .fs
+                    .read(node_mut(&mut self.fids, fid)?, offset, &mut buf)?;
+                buf.truncate(len);
+                Ok(Response::Read(buf))
+            }
+            Request::Write { fid, offset, data } => {
+                let len = self
+                    .fs
+                    .write(node_mut(&mut self.fids, fid)?, offset, &data)?;
+                // The data came from a message, so its length fits in an `u32`.
+                Ok(Response::Write(len as u32))
+            }
+            Request::Clunk { fid } => {
+                self.fids.remove(&fid).ok_or_else(|| error(libc::EBADF))?;
+                Ok(Response::Clunk)
+            }
+            Request::Statfs { fid } => {
+                let st = self.fs.statfs(node(&self.fids, fid)?)?;
+                Ok(Response::Statfs(st))
+            }
+            Request::Lopen { fid, flags } => {
+                let node = node_mut(&mut self.fids, fid)?;
+                // Files are created through `Tlcreate`.
+                self.fs.open(node, open_flags(flags & !P9_CREATE))?;
+                let qid = self.fs.getattr(node)?.qid;
+                Ok(Response::Lopen { qid, iounit: 0 })
+            }
+            Request::Lcreate {
+                fid,
+                name,
+                flags,
+                mode,
+                ..
+            } => {
+                check_name(&name, false)?;
+                let dir = node(&self.fids, fid)?;
+                let node = self
+                    .fs
+                    .create(dir, &name, open_flags(flags | P9_CREATE), mode)?;
+                let qid = self.fs.getattr(&node)?.qid;
+                // The fid now refers to the new file.
+                self.fids.insert(fid, node);
+                Ok(Response::Lcreate { qid, iounit: 0 })
+            }
+            Request::Symlink {
+                fid, name, target, ..
+            } => {
+                check_name(&name, false)?;
+                let qid = self.fs.symlink(node(&self.fids, fid)?, &name, &target)?;
+                Ok(Response::Symlink(qid))
+            }
+            Request::Mknod {
+                dfid,
+                name,
+                mode,
+                major,
+                minor,
+                ..
+            } => {
+                check_name(&name, false)?;
+                let rdev = libc::makedev(major, minor);
+                let qid = self.fs.mknod(node(&self.fids, dfid)?, &name, mode, rdev)?;
+                Ok(Response::Mknod(qid))
+            }
+            Request::Readlink { fid } => {
+                let target = self.fs.readlink(node(&self.fids, fid)?)?;
+                Ok(Response::Readlink(target))
+            }
+            Request::Getattr { fid, .. } => {
+                let attr = self.fs.getattr(node(&self.fids, fid)?)?;
+                Ok(Response::Getattr {
+                    valid: P9_GETATTR_BASIC,
+                    attr,
+                })
+            }
+            Request::Setattr { fid, attr } => {
+                self.fs.setattr(node(&self.fids, fid)?, &attr)?;
+                Ok(Response::Setattr)
+            }
+            Request::Readdir { fid, offset, count } => {
+                let count = count.min(self.msize - IO_HEADER_LEN) as usize;
+                let entries = self.fs.readdir(node_mut(&mut self.fids, fid)?, offset)?;
+                let mut len = 0;
+                let entries = entries
+                    .into_iter()
+                    .take_while(|entry| {
+                        len += dir_entry_len(entry);
+                        len <= count
+                    })
+                    .collect();
+                Ok(Response::Readdir(entries))
+            }
+            Request::Fsync { fid, datasync } => {
+                self.fs
+                    .fsync(node_mut(&mut self.fids, fid)?, datasync != 0)?;
+                Ok(Response::Fsync)
+            }
+            Request::Link { dfid, fid, name } => {
+                check_name(&name, false)?;
+                self.fs
+                    .link(node(&self.fids, dfid)?, node(&self.fids, fid)?, &name)?;
+                Ok(Response::Link)
+            }
+            Request::Mkdir {
+                dfid, name, mode, ..
+            } => {
+                check_name(&name, false)?;
+                let qid = self.fs.mkdir(node(&self.fids, dfid)?, &name, mode)?;
+                Ok(Response::Mkdir(qid))
+            }
+            Request::Renameat {
+                olddirfid,
+                oldname,
+                newdirfid,
+                newname,
+            } => {
+                check_name(&oldname, false)?;
+                check_name(&newname, false)?;
+                self.fs.rename(
+                    node(&self.fids, olddirfid)?,
+                    &oldname,
+                    node(&self.fids, newdirfid)?,
+                    &newname,
+                )?;
+                Ok(Response::Renameat)
+            }
+            Request::Unlinkat {
+                dirfid,
+                name,
+                flags,
+            } => {
+                check_name(&name, false)?;
+                if flags & !P9_AT_REMOVEDIR != 0 {
+                    return Err(error(libc::EINVAL));
+                }
+                self.fs.unlink(
+                    node(&self.fids, dirfid)?,
+                    &name,
+                    flags & P9_AT_REMOVEDIR != 0,
+                )?;
+                Ok(Response::Unlinkat)
+            }
+            Request::Unsupported(_) => Err(error(libc::EOPNOTSUPP)),
+        }
+    }
+
+    fn version(&mut self, msize: u32, version: Vec<u8>) -> io::Result<Response> {
+        if msize < MIN_MSIZE {
+            return Err(error(libc::EINVAL));
+        }
+        // A new session starts, so the fids from the previous one are no longer valid.
+        self.fids.clear();
+        self.msize = msize.min(self.max_msize);
+        let version = if version == P9_PROTOCOL_VERSION {
+            version
+        } else {
+            P9_VERSION_UNKNOWN.to_vec()
+        };
+        Ok(Response::Version {
+            msize: self.msize,
+            version,
+        })
+    }
+
+    fn check_new_fid(&self, fid: u32) -> io::Result<()> {
+        if fid == P9_NOFID || self.fids.contains_key(&fid) {
+            return Err(error(libc::EBADF));
+        }
+        if self.fids.len() >= MAX_FIDS {
+            return Err(error(libc::EMFILE));
+        }
+        Ok(())
+    }
+
+    fn walk(&mut self, fid: u32, newfid: u32, names: &[Vec<u8>]) -> io::Result<Response> {
+        if newfid != fid {
+            self.check_new_fid(newfid)?;
+        }
+        names.iter().try_for_each(|name| check_name(name, true))?;
+
+        let start = node(&self.fids, fid)?;
+        let mut qids = Vec::with_capacity(names.len());
+        let mut current = None;
+        for name in names {
+            let dir = current.as_ref().unwrap_or(start);
+            let fs = &mut self.fs;
+            match fs
+                .walk(dir, name)
+                .and_then(|next| Ok((fs.getattr(&next)?.qid, next)))
+            {
+                Ok((qid, next)) => {
+                    qids.push(qid);
+                    current = Some(next);
+                }
+                // Only a failure to walk the first name is an error. Otherwise, the qids of
+                // the walked names are returned, and the new fid is not created.
+                Err(e) if qids.is_empty() => return Err(e),
+                Err(_) => return Ok(Response::Walk(qids)),
+            }
+        }
+
+        let node = match current {
+            Some(node) => node,
+            None => self.fs.clone_node(start)?,
+        };
+        self.fids.insert(newfid, node);
+        Ok(Response::Walk(qids))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vmm_sys_util::tempdir::TempDir;
+
+    use crate::backend::{Qid, SetAttr, StatFs};
+    use crate::passthrough::PassthroughFs;
+
+    fn server(dir: &TempDir) -> Server<PassthroughFs> {
+        let mut server = Server::new(PassthroughFs::new(dir.as_path()).unwrap(), 8192);
+        server
+            .handle_request(Request::Version {
+                msize: 65536,
+                version: P9_PROTOCOL_VERSION.to_vec(),
+            })
+            .unwrap();
+        assert_eq!(server.msize(), 8192);
+        server
+            .handle_request(Request::Attach {
+                fid: 1,
+                afid: P9_NOFID,
+                uname: Vec::new(),
+                aname: Vec::new(),
+                n_uname: 0,
+            })
+            .unwrap();
+        server
+    }
+
+    fn errno(result: io::Result<Response>) -> i32 {
+        result.unwrap_err().raw_os_error().unwrap()
+    }
+
+    fn walk(server: &mut Server<PassthroughFs>, fid: u32, newfid: u32, names: &[&str]) -> Vec<Qid> {
+        let names = names.iter().map(|name| name.as_bytes().to_vec()).collect();
+        match server.handle_request(Request::Walk { fid, newfid, names }) {
+            Ok(Response::Walk(qids)) => qids,
+            resp => panic!("unexpected walk response {:?}", resp),
+        }
+    }
+
+    #[test]
+    fn test_version_attach() {
+        let dir = TempDir::new_with_prefix("/tmp/p9").unwrap();
+        let mut server = server(&dir);
+        assert_eq!(server.num_fids(), 1);
+
+        let resp = server.handle_request(Request::Version {
+            msize: 4096,
+            version: b"9P2000.u".to_vec(),
+        });
+        assert_eq!(
+            resp.unwrap(),
+            Response::Version {
+                msize: 4096,
+                version: P9_VERSION_UNKNOWN.to_vec()
+            }
+        );
+        // The fids are gone.
+        assert_eq!(server.num_fids(), 0);
+        let resp = server.handle_request(Request::Version {
+            msize: MIN_MSIZE - 1,
+            version: P9_PROTOCOL_VERSION.to_vec(),
+        });
+        assert_eq!(errno(resp), libc::EINVAL);
+
+        let attach = |fid, afid| Request::Attach {
+            fid,
+            afid,
+            uname: Vec::new(),
+            aname: Vec::new(),
+            n_uname: 0,
+        };
+        assert!(matches!(
+            server.handle_request(attach(1, P9_NOFID)),
+            Ok(Response::Attach(Qid { ty: P9_QTDIR, .. }))
+        ));
+        assert_eq!(
+            errno(server.handle_request(attach(1, P9_NOFID))),
+            libc::EBADF
+        );
+        assert_eq!(errno(server.handle_request(attach(2, 3))), libc::EOPNOTSUPP);
+
+        assert_eq!(
+            server.handle_request(Request::Clunk { fid: 1 }).unwrap(),
+            Response::Clunk
+        );
+        assert_eq!(
+            errno(server.handle_request(Request::Clunk { fid: 1 })),
+            libc::EBADF
+        );
+
+        server.reset();
+        assert_eq!(server.msize(), 8192);
+    }
+
+    #[test]
+    fn test_walk() {
+        let dir = TempDir::new_with_prefix("/tmp/p9").unwrap();
+        std::fs::create_dir_all(dir.as_path().join("a/b")).unwrap();
+        let mut server = server(&dir);
+
+        // Walking no names clones the fid.
+        assert!(walk(&mut server, 1, 2, &[]).is_empty());
+        assert_eq!(server.num_fids(), 2);
+        let qids = walk(&mut server, 1, 3, &["a", "b", ".."]);
+        assert_eq!(qids.len(), 3);
+        assert_eq!(qids[0], qids[2]);
+        assert_eq!(server.num_fids(), 3);
+        // The fid can be replaced by the result of the walk.
+        assert_eq!(walk(&mut server, 3, 3, &["b"]).len(), 1);
+        assert_eq!(server.num_fids(), 3);
+
+        // Partial walks don't create the new fid.
+        assert_eq!(walk(&mut server, 1, 4, &["a", "missing", "c"]).len(), 1);
+        assert_eq!(server.num_fids(), 3);
+        let resp = server.handle_request(Request::Walk {
+            fid: 1,
+            newfid: 4,
+            names: vec![b"missing".to_vec()],
+        });
+        assert_eq!(errno(resp), libc::ENOENT);
+
+        // The new fid is in use, the fid doesn't exist, or the names are not valid.
+        let tests = [
+            (1, 2, &b"a"[..], libc::EBADF),
+            (5, 6, b"a", libc::EBADF),
+            (1, 4, b"a/b", libc::EINVAL),
+            (1, 4, b".", libc::EINVAL),
+        ];
+        for &(fid, newfid, name, expected) in tests.iter() {
+            let resp = server.handle_request(Request::Walk {
+                fid,
+                newfid,
+                names: vec![name.to_vec()],
+            });
+            assert_eq!(errno(resp), expected);
+        }
+    }
+
+    #[test]
+    fn test_files() {
+        let dir = TempDir::new_with_prefix("/tmp/p9").unwrap();
+        let mut server = server(&dir);
+
+        walk(&mut server, 1, 2, &[]);
+        let resp = server
+            .handle_request(Request::Lcreate {
+                fid: 2,
+                name: b"file".to_vec(),
+                flags: P9_RDWR,
+                mode: 0o644,
+                gid: 0,
+            })
+            .unwrap();
+        let qid = match resp {
+            Response::Lcreate { qid, .. } => qid,
+            resp => panic!("unexpected lcreate response {:?}", resp),
+        };
+        assert_eq!(qid.ty, P9_QTFILE);
+
+        let data = vec![0xaau8; 10000];
+        let resp = server.handle_request(Request::Write {
+            fid: 2,
+            offset: 0,
+            data: data.clone(),
+        });
+        assert_eq!(resp.unwrap(), Response::Write(10000));
+        // Reads are bounded by the message size.
+        let resp = server.handle_request(Request::Read {
+            fid: 2,
+            offset: 0,
+            count: 10000,
+        });
+        assert_eq!(resp.unwrap(), Response::Read(vec![0xaa; 8192 - 11]));
+
+        walk(&mut server, 1, 3, &["file"]);
+        let resp = server.handle_request(Request::Lopen {
+            fid: 3,
+            flags: P9_RDONLY,
+        });
+        assert_eq!(resp.unwrap(), Response::Lopen { qid, iounit: 0 });
+        let resp = server.handle_request(Request::Read {
+            fid: 3,
+            offset: 9998,
+            count: 10,
+        });
+        assert_eq!(resp.unwrap(), Response::Read(vec![0xaa; 2]));
+
+        let resp = server.handle_request(Request::Setattr {
+            fid: 3,
+            attr: SetAttr {
+                valid: P9_SETATTR_SIZE,
+                size: 1,
+                ..Default::default()
+            },
+        });
+        assert_eq!(resp.unwrap(), Response::Setattr);
+        match server.handle_request(Request::Getattr {
+            fid: 3,
+            request_mask: P9_GETATTR_BASIC,
+        }) {
+            Ok(Response::Getattr { valid, attr }) => {
+                assert_eq!(valid, P9_GETATTR_BASIC);
+                assert_eq!(attr.qid, qid);
+                assert_eq!(attr.size, 1);
+            }
+            resp => panic!("unexpected getattr response {:?}", resp),
+        }
+
+        let resp = server.handle_request(Request::Fsync {
+            fid: 2,
+            datasync: 1,
+        });
+        assert_eq!(resp.unwrap(), Response::Fsync);
+        let resp = server.handle_request(Request::Lcreate {
+            fid: 1,
+            name: b"..".to_vec(),
+            flags: P9_RDWR,
+            mode: 0o644,
+            gid: 0,
+        });
+        assert_eq!(errno(resp), libc::EINVAL);
+        assert_eq!(
+            errno(server.handle_request(Request::Unsupported(P9_TXATTRWALK))),
+            libc::EOPNOTSUPP
+        );
+    }
+
+    #[test]
+    fn test_directories() {
+        let dir = TempDir::new_with_prefix("/tmp/p9").unwrap();
+        let mut server = server(&dir);
+
+        for name in ["one", "two", "three"].iter() {
+            let resp = server.handle_request(Request::Mkdir {
+                dfid: 1,
+                name: name.as_bytes().to_vec(),
+                mode: 0o755,
+                gid: 0,
+            });
+            assert!(matches!(
+                resp,
+                Ok(Response::Mkdir(Qid { ty: P9_QTDIR, .. }))
+            ));
+        }
+        let resp = server.handle_request(Request::Symlink {
+            fid: 1,
+            name: b"link".to_vec(),
+            target: b"one".to_vec(),
+            gid: 0,
+        });
+        assert!(matches!(resp, Ok(Response::Symlink(_))));
+        walk(&mut server, 1, 2, &["link"]);
+        let resp = server.handle_request(Request::Readlink { fid: 2 });
+        assert_eq!(resp.unwrap(), Response::Readlink(b"one".to_vec()));
+
+        let resp = server.handle_request(Request::Renameat {
+            olddirfid: 1,
+            oldname: b"two".to_vec(),
+            newdirfid: 1,
+            newname: b"four".to_vec(),
+        });
+        assert_eq!(resp.unwrap(), Response::Renameat);
+        let resp = server.handle_request(Request::Unlinkat {
+            dirfid: 1,
+            name: b"three".to_vec(),
+            flags: P9_AT_REMOVEDIR,
+        });
+        assert_eq!(resp.unwrap(), Response::Unlinkat);
+        let resp = server.handle_request(Request::Unlinkat {
+            dirfid: 1,
+            name: b"one".to_vec(),
+            flags: 1,
+        });
+        assert_eq!(errno(resp), libc::EINVAL);
+
+        walk(&mut server, 1, 3, &[]);
+        server
+            .handle_request(Request::Lopen {
+                fid: 3,
+                flags: P9_RDONLY | P9_DIRECTORY,
+            })
+            .unwrap();
+        let readdir = |server: &mut Server<PassthroughFs>, offset, count| match server
+            .handle_request(Request::Readdir {
+                fid: 3,
+                offset,
+                count,
+            }) {
+            Ok(Response::Readdir(entries)) => entries,
+            resp => panic!("unexpected readdir response {:?}", resp),
+        };
+        let mut names: Vec<_> = readdir(&mut server, 0, 4096)
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![b"four".to_vec(), b"link".to_vec(), b"one".to_vec()]
+        );
+
+        // The entries which don't fit are left for the next request.
+        let first = readdir(&mut server, 0, 30);
+        assert_eq!(first.len(), 1);
+        let rest = readdir(&mut server, first[0].offset, 4096);
+        assert_eq!(rest.len(), 2);
+
+        let resp = server.handle_request(Request::Statfs { fid: 1 });
+        assert!(matches!(
+            resp,
+            Ok(Response::Statfs(StatFs { ty: V9FS_MAGIC, .. }))
+        ));
+    }
+
+    #[test]
+    fn test_handle_message() {
+        let dir = TempDir::new_with_prefix("/tmp/p9").unwrap();
+        let mut server = server(&dir);
+
+        let mut msg = vec![11, 0, 0, 0, P9_TCLUNK, 5, 0, 1, 0, 0, 0];
+        assert_eq!(
+            server.handle_message(&msg).unwrap(),
+            Response::Clunk.encode(5)
+        );
+        // Malformed messages are rejected, as long as they have a tag.
+        msg[0] = 10;
+        assert_eq!(
+            server.handle_message(&msg[..10]).unwrap(),
+            Response::Lerror(libc::EINVAL as u32).encode(5)
+        );
+        assert!(server.handle_message(&msg[..6]).is_none());
+    }
+}
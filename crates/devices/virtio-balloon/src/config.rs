@@ -0,0 +1,81 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Virtio balloon device configuration space abstraction.
+//!
+//! This module provides the [`ConfigSpace`](struct.ConfigSpace.html) abstraction, which mirrors
+//! the `virtio_balloon_config` structure from the virtio specification. The device sets the
+//! number of pages it wants the driver to hand over (`num_pages`), and the driver reports the
+//! number of pages it actually handed over (`actual`). The device also uses the configuration
+//! space to start and stop free page hinting (`free_page_hint_cmd_id`).
+
+use std::mem::{offset_of, size_of};
+
+use vm_memory::ByteValued;
+
+/// The balloon device configuration space layout, as defined by the virtio specification.
+///
+/// All fields are expected to hold little endian values, which is always the case on the
+/// platforms that are currently supported (`x86_64` and `aarch64`).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C, packed)]
+pub struct ConfigSpace {
+    /// The number of pages the device wants in the balloon, which is only written by the
+    /// device.
+    pub num_pages: u32,
+    /// The number of pages in the balloon, which is only written by the driver.
+    pub actual: u32,
+    /// The free page hinting command identifier (valid when `VIRTIO_BALLOON_F_FREE_PAGE_HINT`
+    /// is negotiated), which is only written by the device.
+    pub free_page_hint_cmd_id: u32,
+    /// The value the driver fills free pages with (valid when `VIRTIO_BALLOON_F_PAGE_POISON` is
+    /// negotiated), which is only written by the driver.
+    pub poison_val: u32,
+}
+
+// Safe because ConfigSpace only contains plain data, and there's no padding between (or after)
+// the fields since the structure is packed.
+unsafe impl ByteValued for ConfigSpace {}
+
+impl ConfigSpace {
+    /// The size of the balloon device configuration space.
+    pub const LEN: usize = size_of::<ConfigSpace>();
+    /// The offset of the `num_pages` field.
+    pub const NUM_PAGES_OFFSET: usize = offset_of!(ConfigSpace, num_pages);
+    /// The offset of the `actual` field.
+    pub const ACTUAL_OFFSET: usize = offset_of!(ConfigSpace, actual);
+    /// The offset of the `free_page_hint_cmd_id` field.
+    pub const FREE_PAGE_HINT_CMD_ID_OFFSET: usize = offset_of!(ConfigSpace, free_page_hint_cmd_id);
+    /// The offset of the `poison_val` field.
+    pub const POISON_VAL_OFFSET: usize = offset_of!(ConfigSpace, poison_val);
+}
+
+impl From<ConfigSpace> for Vec<u8> {
+    fn from(config: ConfigSpace) -> Self {
+        config.as_slice().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_space() {
+        assert_eq!(ConfigSpace::LEN, 16);
+        assert_eq!(ConfigSpace::NUM_PAGES_OFFSET, 0);
+        assert_eq!(ConfigSpace::ACTUAL_OFFSET, 4);
+        assert_eq!(ConfigSpace::FREE_PAGE_HINT_CMD_ID_OFFSET, 8);
+        assert_eq!(ConfigSpace::POISON_VAL_OFFSET, 12);
+
+        let config = ConfigSpace {
+            num_pages: 0x0102_0304,
+            actual: 0x10,
+            free_page_hint_cmd_id: 2,
+            ..Default::default()
+        };
+        let bytes: Vec<u8> = config.into();
+        assert_eq!(bytes, [4, 3, 2, 1, 0x10, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0]);
+    }
+}
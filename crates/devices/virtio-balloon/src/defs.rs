@@ -0,0 +1,68 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+// TODO: Move the generic device type definition to the vm-virtio crate proper.
+/// The virtio device type of balloon devices.
+pub const VIRTIO_ID_BALLOON: u32 = 5;
+
+/// The default (and maximum) size of the queues.
+pub const DEFAULT_QUEUE_SIZE: u16 = 256;
+
+// Feature bits.
+/// Host has to be told before pages from the balloon are used.
+pub const VIRTIO_BALLOON_F_MUST_TELL_HOST: u64 = 0;
+/// A virtqueue for reporting guest memory statistics is present.
+pub const VIRTIO_BALLOON_F_STATS_VQ: u64 = 1;
+/// Deflate the balloon on guest out of memory condition.
+pub const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u64 = 2;
+/// A virtqueue for hinting free pages is present.
+pub const VIRTIO_BALLOON_F_FREE_PAGE_HINT: u64 = 3;
+/// Guest is using page poisoning, and reports the poison value in `poison_val`.
+pub const VIRTIO_BALLOON_F_PAGE_POISON: u64 = 4;
+/// A virtqueue for reporting free pages is present.
+pub const VIRTIO_BALLOON_F_REPORTING: u64 = 5;
+
+// Queue indices.
+/// The index of the inflate queue.
+pub const INFLATE_QUEUE: u16 = 0;
+/// The index of the deflate queue.
+pub const DEFLATE_QUEUE: u16 = 1;
+/// The index of the statistics queue, when `VIRTIO_BALLOON_F_STATS_VQ` is negotiated. The free
+/// page hinting and reporting queues come next (when their features are negotiated), so their
+/// indices depend on the negotiated features.
+pub const STATS_QUEUE: u16 = 2;
+
+// Free page hinting command identifiers.
+/// Asks the driver to stop hinting free pages, and is sent by the driver when it's done.
+pub const VIRTIO_BALLOON_CMD_ID_STOP: u32 = 0;
+/// Allows the driver to use the hinted free pages again.
+pub const VIRTIO_BALLOON_CMD_ID_DONE: u32 = 1;
+
+/// The page frame numbers exchanged through the inflate and deflate queues always refer to
+/// 4 KiB pages, regardless of the page size used by the driver.
+pub const VIRTIO_BALLOON_PFN_SHIFT: u64 = 12;
+/// The size of the pages referred to by the page frame numbers.
+pub const VIRTIO_BALLOON_PAGE_SIZE: u64 = 1 << VIRTIO_BALLOON_PFN_SHIFT;
+
+// Memory statistics tags.
+/// Amount of memory swapped in (in bytes).
+pub const VIRTIO_BALLOON_S_SWAP_IN: u16 = 0;
+/// Amount of memory swapped out to disk (in bytes).
+pub const VIRTIO_BALLOON_S_SWAP_OUT: u16 = 1;
+/// Number of major page faults that have occurred.
+pub const VIRTIO_BALLOON_S_MAJFLT: u16 = 2;
+/// Number of minor page faults that have occurred.
+pub const VIRTIO_BALLOON_S_MINFLT: u16 = 3;
+/// Amount of memory not being used for any purpose (in bytes).
+pub const VIRTIO_BALLOON_S_MEMFREE: u16 = 4;
+/// Total amount of memory available (in bytes).
+pub const VIRTIO_BALLOON_S_MEMTOT: u16 = 5;
+/// An estimate of how much memory is available for starting new applications (in bytes).
+pub const VIRTIO_BALLOON_S_AVAIL: u16 = 6;
+/// Amount of memory used for disk caches (in bytes).
+pub const VIRTIO_BALLOON_S_CACHES: u16 = 7;
+/// Number of successful hugetlb page allocations.
+pub const VIRTIO_BALLOON_S_HTLB_PGALLOC: u16 = 8;
+/// Number of failed hugetlb page allocations.
+pub const VIRTIO_BALLOON_S_HTLB_PGFAIL: u16 = 9;
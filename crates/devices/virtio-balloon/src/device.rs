@@ -0,0 +1,1337 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A reference virtio balloon device implementation.
+//!
+//! This module provides the following abstractions:
+//!
+//! - [`Balloon`](struct.Balloon.html) which ties together the generic device building blocks
+//!   (`VirtioConfig` and the `VirtioDevice`/`VirtioMmioDevice` interfaces) with the balloon
+//!   specific ones (the configuration space, the memory statistics and the
+//!   [`MemoryRelease`](../release/trait.MemoryRelease.html) interface).
+//! - [`BalloonBuilder`](struct.BalloonBuilder.html) which configures and creates a `Balloon`
+//!   device.
+//!
+//! The VMM sets the number of pages it wants the driver to hand over with
+//! [`Balloon::set_num_pages`](struct.Balloon.html#method.set_num_pages), which raises a
+//! configuration change interrupt. The driver then inflates (or deflates) the balloon by
+//! sending arrays of page frame numbers through the inflate (or deflate) queue, and the memory
+//! of the inflated pages is released by the `MemoryRelease` implementation. The driver reports
+//! the number of pages in the balloon through the `actual` configuration space field (see
+//! [`Balloon::actual`](struct.Balloon.html#method.actual)).
+//!
+//! When deflate on OOM is enabled, the driver can deflate the balloon below the number of
+//! pages requested by the device when the guest runs out of memory. The device tracks the
+//! number of pages in the balloon from the inflate and deflate requests, and calls the
+//! [`MemoryPressure`](../pressure/trait.MemoryPressure.html) implementation set by the VMM
+//! when that happens, which can lower the target accordingly.
+//!
+//! When the statistics queue is enabled, the driver hands over a buffer filled with memory
+//! statistics, which the device keeps until the VMM asks for fresh ones with
+//! [`Balloon::request_stats`](struct.Balloon.html#method.request_stats). The driver then fills
+//! the buffer again, and makes it available through the statistics queue.
+//!
+//! When free page reporting is enabled, the driver sends ranges of free guest memory through
+//! the reporting queue at runtime, and doesn't use them until the device returns the buffers,
+//! so their memory can be released right away. When free page hinting is enabled, the VMM
+//! starts a hinting session (i.e. before migrating the guest) with
+//! [`Balloon::start_free_page_hinting`](struct.Balloon.html#method.start_free_page_hinting),
+//! which publishes a new command identifier in the configuration space. The driver then
+//! acknowledges the command identifier and sends ranges of free guest memory through the free
+//! page queue, until it runs out of free pages, or the VMM stops the session. The driver keeps
+//! the hinted pages until the VMM calls
+//! [`Balloon::finish_free_page_hinting`](struct.Balloon.html#method.finish_free_page_hinting).
+//!
+//! The device doesn't register any events by itself: the VMM is expected to rely on the
+//! `VirtioMmioDevice::queue_notify` implementation (or call the `process_*` methods directly)
+//! when the driver notifies a queue.
+
+use std::convert::TryInto;
+use std::fmt::{self, Display};
+use std::io;
+use std::result;
+
+use log::{error, warn};
+
+use vm_memory::{Bytes, GuestAddress, GuestAddressSpace, GuestMemoryError};
+
+use virtio_device::{
+    set_interrupt_status, SignalConfigChange, SignalUsedQueue, VirtioConfig, VirtioDeviceActions,
+    VirtioDeviceCommon, VirtioMmioDevice, VIRTIO_MMIO_INT_VRING,
+};
+use virtio_queue::{self, Descriptor, DescriptorChain, Queue};
+
+use crate::config::ConfigSpace;
+use crate::defs::{
+    DEFLATE_QUEUE, INFLATE_QUEUE, STATS_QUEUE, VIRTIO_BALLOON_CMD_ID_DONE,
+    VIRTIO_BALLOON_CMD_ID_STOP, VIRTIO_BALLOON_F_DEFLATE_ON_OOM, VIRTIO_BALLOON_F_FREE_PAGE_HINT,
+    VIRTIO_BALLOON_F_MUST_TELL_HOST, VIRTIO_BALLOON_F_REPORTING, VIRTIO_BALLOON_F_STATS_VQ,
+    VIRTIO_BALLOON_PAGE_SIZE, VIRTIO_BALLOON_PFN_SHIFT,
+};
+use crate::pressure::MemoryPressure;
+use crate::release::MemoryRelease;
+use crate::stats::MemoryStats;
+
+pub use crate::defs::{DEFAULT_QUEUE_SIZE, VIRTIO_ID_BALLOON};
+
+// TODO: Move the generic feature definitions to the vm-virtio crate proper.
+const VIRTIO_F_VERSION_1: u64 = 32;
+const VIRTIO_F_RING_EVENT_IDX: u64 = 29;
+
+// The largest buffer accepted from the driver. The Linux driver sends at most 256 page frame
+// numbers at once.
+const MAX_BUFFER_LEN: usize = 0x1_0000;
+
+// The features which enable the optional queues. The queues follow the inflate and deflate
+// queues in this order, and only the ones which are negotiated get an index.
+const OPTIONAL_QUEUE_FEATURES: [u64; 3] = [
+    VIRTIO_BALLOON_F_STATS_VQ,
+    VIRTIO_BALLOON_F_FREE_PAGE_HINT,
+    VIRTIO_BALLOON_F_REPORTING,
+];
+
+/// Balloon device errors.
+#[derive(Debug)]
+pub enum Error {
+    /// The device was already activated.
+    AlreadyActivated,
+    /// The buffer is larger than what the device accepts.
+    BufferTooLarge,
+    /// The feature was not negotiated, or the device is not activated.
+    FeatureNotNegotiated(u64),
+    /// Invalid memory access.
+    GuestMemory(GuestMemoryError),
+    /// The queues are not configured properly.
+    InvalidQueues,
+    /// The queue index is not valid, or the device is not activated.
+    InvalidQueueIndex(u16),
+    /// Failed to access a queue.
+    Queue(virtio_queue::Error),
+    /// Failed to release or reclaim guest memory.
+    Release(io::Error),
+    /// Read only descriptor in a buffer sent by the driver.
+    UnexpectedReadOnlyDescriptor,
+    /// Write only descriptor in a buffer sent by the driver.
+    UnexpectedWriteOnlyDescriptor,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            AlreadyActivated => write!(f, "the device is already activated"),
+            BufferTooLarge => write!(f, "buffer too large"),
+            FeatureNotNegotiated(feature) => {
+                write!(f, "feature {} not negotiated or inactive device", feature)
+            }
+            GuestMemory(ref err) => write!(f, "error accessing guest memory: {}", err),
+            InvalidQueues => write!(f, "invalid queue configuration"),
+            InvalidQueueIndex(index) => write!(f, "invalid or inactive queue {}", index),
+            Queue(ref err) => write!(f, "failed to access the queue: {}", err),
+            Release(ref err) => write!(f, "failed to release guest memory: {}", err),
+            UnexpectedReadOnlyDescriptor => write!(f, "unexpected read only descriptor"),
+            UnexpectedWriteOnlyDescriptor => write!(f, "unexpected write only descriptor"),
+        }
+    }
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Configures and builds a `Balloon` device.
+///
+/// # Example
+///
+/// ```rust
+/// # use std::sync::Arc;
+/// # use virtio_balloon::device::BalloonBuilder;
+/// # use virtio_balloon::release::MadviseRelease;
+/// # use vm_memory::{GuestAddress, GuestMemoryMmap};
+/// # use vmm_sys_util::eventfd::EventFd;
+/// let mem = Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+/// let release = MadviseRelease::new(mem.clone());
+///
+/// let balloon = BalloonBuilder::new(mem, release, EventFd::new(0).unwrap())
+///     .with_stats_queue(true)
+///     .with_deflate_on_oom(true)
+///     .build();
+/// ```
+#[derive(Debug)]
+pub struct BalloonBuilder<M: GuestAddressSpace, R: MemoryRelease, S: SignalUsedQueue> {
+    mem: M,
+    release: R,
+    driver_notify: S,
+    queue_size: u16,
+    num_pages: u32,
+    features: u64,
+    pressure: Option<Box<dyn MemoryPressure>>,
+}
+
+impl<M, R, S> BalloonBuilder<M, R, S>
+where
+    M: GuestAddressSpace + Clone,
+    R: MemoryRelease,
+    S: SignalUsedQueue,
+{
+    /// Creates a new `BalloonBuilder` for an initially empty balloon.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    /// * `release` - The object used for releasing the memory of the inflated pages.
+    /// * `driver_notify` - The object used for notifying the driver about used buffers.
+    pub fn new(mem: M, release: R, driver_notify: S) -> Self {
+        BalloonBuilder {
+            mem,
+            release,
+            driver_notify,
+            queue_size: DEFAULT_QUEUE_SIZE,
+            num_pages: 0,
+            features: 0,
+            pressure: None,
+        }
+    }
+
+    /// Sets the maximum size of the queues.
+    ///
+    /// # Arguments
+    /// * `queue_size` - The maximum queue size.
+    pub fn with_queue_size(mut self, queue_size: u16) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Sets the number of pages the driver is initially asked to hand over.
+    ///
+    /// # Arguments
+    /// * `num_pages` - The number of 4 KiB pages.
+    pub fn with_num_pages(mut self, num_pages: u32) -> Self {
+        self.num_pages = num_pages;
+        self
+    }
+
+    fn with_feature(mut self, feature_pos: u64, enabled: bool) -> Self {
+        if enabled {
+            self.features |= 1 << feature_pos;
+        } else {
+            self.features &= !(1 << feature_pos);
+        }
+        self
+    }
+
+    /// Offers the statistics queue (`VIRTIO_BALLOON_F_STATS_VQ`), through which the driver
+    /// reports guest memory statistics.
+    ///
+    /// # Arguments
+    /// * `stats` - Whether the statistics queue is offered.
+    pub fn with_stats_queue(self, stats: bool) -> Self {
+        self.with_feature(VIRTIO_BALLOON_F_STATS_VQ, stats)
+    }
+
+    /// Allows the driver to deflate the balloon when the guest runs out of memory
+    /// (`VIRTIO_BALLOON_F_DEFLATE_ON_OOM`), even if that goes below the requested number of
+    /// pages.
+    ///
+    /// # Arguments
+    /// * `deflate_on_oom` - Whether the driver can deflate the balloon on its own.
+    pub fn with_deflate_on_oom(self, deflate_on_oom: bool) -> Self {
+        self.with_feature(VIRTIO_BALLOON_F_DEFLATE_ON_OOM, deflate_on_oom)
+    }
+
+    /// Sets the object which is called when the driver deflates the balloon below the target
+    /// because of guest memory pressure (see
+    /// [`with_deflate_on_oom`](#method.with_deflate_on_oom)).
+    ///
+    /// # Arguments
+    /// * `pressure` - The memory pressure handler, i.e. `LowerTarget` or a callback.
+    pub fn with_memory_pressure<P: MemoryPressure + 'static>(mut self, pressure: P) -> Self {
+        self.pressure = Some(Box::new(pressure));
+        self
+    }
+
+    /// Requires the driver to wait until the device processed the deflate requests before
+    /// using the deflated pages (`VIRTIO_BALLOON_F_MUST_TELL_HOST`), which matters when the
+    /// `MemoryRelease` implementation has to reclaim them first.
+    ///
+    /// # Arguments
+    /// * `must_tell_host` - Whether the driver has to wait for the deflate requests.
+    pub fn with_must_tell_host(self, must_tell_host: bool) -> Self {
+        self.with_feature(VIRTIO_BALLOON_F_MUST_TELL_HOST, must_tell_host)
+    }
+
+    /// Offers the free page hinting queue (`VIRTIO_BALLOON_F_FREE_PAGE_HINT`), through which
+    /// the driver hints free pages on request, i.e. so they are skipped during migration.
+    ///
+    /// # Arguments
+    /// * `free_page_hinting` - Whether the free page hinting queue is offered.
+    pub fn with_free_page_hinting(self, free_page_hinting: bool) -> Self {
+        self.with_feature(VIRTIO_BALLOON_F_FREE_PAGE_HINT, free_page_hinting)
+    }
+
+    /// Offers the free page reporting queue (`VIRTIO_BALLOON_F_REPORTING`), through which the
+    /// driver reports free pages at runtime, such that their memory is released.
+    ///
+    /// # Arguments
+    /// * `free_page_reporting` - Whether the free page reporting queue is offered.
+    pub fn with_free_page_reporting(self, free_page_reporting: bool) -> Self {
+        self.with_feature(VIRTIO_BALLOON_F_REPORTING, free_page_reporting)
+    }
+
+    /// Builds the `Balloon` device.
+    pub fn build(self) -> Balloon<M, R, S> {
+        let device_features =
+            self.features | (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_RING_EVENT_IDX);
+        let num_queues = 2 + OPTIONAL_QUEUE_FEATURES
+            .iter()
+            .filter(|&&feature| device_features & (1 << feature) != 0)
+            .count();
+        let queues = (0..num_queues)
+            .map(|_| Queue::new(self.mem.clone(), self.queue_size))
+            .collect();
+        let config_space = ConfigSpace {
+            num_pages: self.num_pages,
+            ..Default::default()
+        };
+
+        Balloon {
+            cfg: VirtioConfig::new(device_features, queues, config_space.into()),
+            release: self.release,
+            driver_notify: self.driver_notify,
+            pages: 0,
+            pressure: self.pressure,
+            stats: None,
+            stats_head: None,
+            hint_cmd_id: VIRTIO_BALLOON_CMD_ID_DONE,
+            hinting: None,
+            hinted: None,
+        }
+    }
+}
+
+/// A virtio balloon device.
+#[derive(Debug, VirtioDeviceCommon)]
+#[virtio(device_type = VIRTIO_ID_BALLOON)]
+pub struct Balloon<M: GuestAddressSpace, R: MemoryRelease, S: SignalUsedQueue> {
+    #[virtio(config)]
+    cfg: VirtioConfig<M>,
+    release: R,
+    driver_notify: S,
+    // The number of pages in the balloon, according to the inflate and deflate requests.
+    pages: u32,
+    // The object called when the driver deflates the balloon below the target, if any.
+    pressure: Option<Box<dyn MemoryPressure>>,
+    // The latest statistics reported by the driver.
+    stats: Option<MemoryStats>,
+    // The head index of the statistics buffer held by the device, if any.
+    stats_head: Option<u16>,
+    // The latest free page hinting command identifier issued by the device.
+    hint_cmd_id: u32,
+    // The command identifier the driver is currently hinting free pages for, if any.
+    hinting: Option<u32>,
+    // The latest command identifier the driver finished hinting free pages for, if any.
+    hinted: Option<u32>,
+}
+
+impl<M, R, S> Balloon<M, R, S>
+where
+    M: GuestAddressSpace,
+    R: MemoryRelease,
+    S: SignalUsedQueue,
+{
+    /// Returns whether the device is activated.
+    pub fn is_activated(&self) -> bool {
+        self.cfg.device_activated
+    }
+
+    /// Returns the number of pages the driver is asked to hand over.
+    pub fn num_pages(&self) -> u32 {
+        self.config_field(ConfigSpace::NUM_PAGES_OFFSET)
+    }
+
+    /// Returns the number of pages in the balloon, as reported by the driver.
+    pub fn actual(&self) -> u32 {
+        self.config_field(ConfigSpace::ACTUAL_OFFSET)
+    }
+
+    /// Returns the number of pages in the balloon, according to the inflate and deflate requests
+    /// processed since the device was activated. Unlike [`actual`](#method.actual), this is
+    /// updated as soon as the requests are processed.
+    pub fn inflated_pages(&self) -> u32 {
+        self.pages
+    }
+
+    /// Sets the object which is called when the driver deflates the balloon below the target
+    /// because of guest memory pressure, or removes it if `pressure` is `None`. The handler is
+    /// preserved across device resets.
+    ///
+    /// # Arguments
+    /// * `pressure` - The memory pressure handler, i.e. `LowerTarget` or a callback.
+    pub fn set_memory_pressure(&mut self, pressure: Option<Box<dyn MemoryPressure>>) {
+        self.pressure = pressure;
+    }
+
+    // Returns the configuration space field at `offset`.
+    fn config_field(&self, offset: usize) -> u32 {
+        self.cfg.config_space[offset..offset + 4]
+            .try_into()
+            .map_or(0, u32::from_le_bytes)
+    }
+
+    fn set_config_field(&mut self, offset: usize, value: u32) {
+        self.cfg.config_space[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Returns the latest memory statistics reported by the driver, if any.
+    pub fn stats(&self) -> Option<&MemoryStats> {
+        self.stats.as_ref()
+    }
+
+    /// Releases the memory of the pages sent by the driver through the inflate queue. This has
+    /// to be called when the driver notifies the inflate queue.
+    pub fn process_inflate_queue(&mut self) -> Result<()> {
+        self.process_pfn_queue(INFLATE_QUEUE)
+    }
+
+    /// Reclaims the memory of the pages sent by the driver through the deflate queue. This has
+    /// to be called when the driver notifies the deflate queue.
+    pub fn process_deflate_queue(&mut self) -> Result<()> {
+        self.process_pfn_queue(DEFLATE_QUEUE)
+    }
+
+    // Processes the page frame number arrays from the inflate or deflate queue.
+    fn process_pfn_queue(&mut self, index: u16) -> Result<()> {
+        if !self.cfg.device_activated {
+            return Err(Error::InvalidQueueIndex(index));
+        }
+        let queue = &mut self.cfg.queues[usize::from(index)];
+        let release = &mut self.release;
+        let mut pages = self.pages;
+        while let Some(mut chain) = queue.iter()?.next() {
+            let result = read_buffer(&mut chain).and_then(|buffer| {
+                // The driver considers the pages handed over (or taken back) even if their
+                // memory can't be released (or reclaimed). The buffer length is bounded by
+                // `MAX_BUFFER_LEN`, so the count fits in an `u32`.
+                let count = (buffer.len() / 4) as u32;
+                pages = if index == INFLATE_QUEUE {
+                    pages.saturating_add(count)
+                } else {
+                    pages.saturating_sub(count)
+                };
+                for (addr, len) in page_ranges(&buffer) {
+                    if index == INFLATE_QUEUE {
+                        release.release(addr, len)
+                    } else {
+                        release.reclaim(addr, len)
+                    }
+                    .map_err(Error::Release)?;
+                }
+                Ok(())
+            });
+            if let Err(e) = result {
+                warn!("failed to process balloon queue {}: {}", index, e);
+            }
+
+            // The driver doesn't expect anything to be written to the buffers.
+            queue.add_used(chain.head_index(), 0)?;
+            if queue.needs_notification()? {
+                set_interrupt_status(&self.cfg.interrupt_status, VIRTIO_MMIO_INT_VRING);
+                self.driver_notify.signal_used_queue(index);
+            }
+        }
+
+        let target = self.num_pages();
+        let below_target = pages < self.pages && pages < target;
+        self.pages = pages;
+        if below_target {
+            self.handle_pressure(target);
+        }
+        Ok(())
+    }
+
+    // Handles the driver deflating the balloon below the target.
+    fn handle_pressure(&mut self, target: u32) {
+        if self.cfg.driver_features & (1 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM) == 0 {
+            warn!(
+                "the driver deflated the balloon to {} pages, below the target of {} pages",
+                self.pages, target
+            );
+            return;
+        }
+        let new_target = match self.pressure.as_mut() {
+            Some(pressure) => pressure.deflated(target, self.pages),
+            None => None,
+        };
+        // The driver has nothing to do when the target doesn't exceed the number of pages in
+        // the balloon, so the configuration generation changes, but no interrupt is raised.
+        if let Some(new_target) = new_target.filter(|&new_target| new_target < target) {
+            let new_target = new_target.max(self.pages);
+            self.set_config_field(ConfigSpace::NUM_PAGES_OFFSET, new_target);
+            self.cfg.config_generation = self.cfg.config_generation.wrapping_add(1);
+        }
+    }
+
+    /// Picks up the memory statistics buffer made available by the driver. This has to be
+    /// called when the driver notifies the statistics queue.
+    pub fn process_stats_queue(&mut self) -> Result<()> {
+        if !self.cfg.device_activated || !self.has_stats_queue() {
+            return Err(Error::InvalidQueueIndex(STATS_QUEUE));
+        }
+        loop {
+            let queue = &mut self.cfg.queues[usize::from(STATS_QUEUE)];
+            let mut chain = match queue.iter()?.next() {
+                Some(chain) => chain,
+                None => return Ok(()),
+            };
+            // The driver is not supposed to provide another buffer before the device used the
+            // previous one, but in case it does, the previous one is returned.
+            if self.stats_head.is_some() {
+                self.complete_stats()?;
+            }
+            match read_buffer(&mut chain) {
+                Ok(buffer) => self.stats = Some(MemoryStats::from_bytes(&buffer)),
+                Err(e) => warn!("failed to read memory statistics: {}", e),
+            }
+            self.stats_head = Some(chain.head_index());
+        }
+    }
+
+    /// Asks the driver for fresh memory statistics, by returning the statistics buffer. The
+    /// updated statistics are available after the driver notifies the statistics queue again.
+    /// Returns `false` if the device doesn't hold a statistics buffer.
+    pub fn request_stats(&mut self) -> Result<bool> {
+        if !self.cfg.device_activated || !self.has_stats_queue() {
+            return Err(Error::InvalidQueueIndex(STATS_QUEUE));
+        }
+        if self.stats_head.is_none() {
+            return Ok(false);
+        }
+        self.complete_stats()?;
+        Ok(true)
+    }
+
+    // Returns the statistics buffer to the driver.
+    fn complete_stats(&mut self) -> Result<()> {
+        if let Some(head) = self.stats_head.take() {
+            let queue = &mut self.cfg.queues[usize::from(STATS_QUEUE)];
+            queue.add_used(head, 0)?;
+            if queue.needs_notification()? {
+                self.cfg.signal_used_queue();
+                self.driver_notify.signal_used_queue(STATS_QUEUE);
+            }
+        }
+        Ok(())
+    }
+
+    fn has_stats_queue(&self) -> bool {
+        self.cfg.driver_features & (1 << VIRTIO_BALLOON_F_STATS_VQ) != 0
+    }
+
+    // Returns the index of the queue enabled by `feature`, if the feature was negotiated.
+    fn optional_queue(&self, feature: u64) -> Option<u16> {
+        let negotiated = |feature: u64| self.cfg.driver_features & (1 << feature) != 0;
+        if !negotiated(feature) {
+            return None;
+        }
+        let preceding = OPTIONAL_QUEUE_FEATURES
+            .iter()
+            .take_while(|&&f| f != feature)
+            .filter(|&&f| negotiated(f))
+            .count();
+        // There are only a few optional queues.
+        Some(2 + preceding as u16)
+    }
+
+    // Returns the index of the queue enabled by `feature`, if the device is activated and the
+    // feature was negotiated.
+    fn active_queue(&self, feature: u64) -> Result<u16> {
+        self.optional_queue(feature)
+            .filter(|_| self.cfg.device_activated)
+            .ok_or(Error::FeatureNotNegotiated(feature))
+    }
+
+    /// Returns the free page hinting command identifier currently published in the
+    /// configuration space.
+    pub fn free_page_hint_cmd_id(&self) -> u32 {
+        self.config_field(ConfigSpace::FREE_PAGE_HINT_CMD_ID_OFFSET)
+    }
+
+    /// Returns whether the driver finished hinting free pages for the current command, either
+    /// because it ran out of free pages, or because the hinting was stopped.
+    pub fn is_free_page_hinting_done(&self) -> bool {
+        self.hinted == Some(self.hint_cmd_id)
+    }
+
+    /// Processes the command identifiers and the free page ranges sent by the driver through
+    /// the free page hinting queue. This has to be called when the driver notifies the free
+    /// page hinting queue.
+    pub fn process_free_page_queue(&mut self) -> Result<()> {
+        let index = self.active_queue(VIRTIO_BALLOON_F_FREE_PAGE_HINT)?;
+        let cmd_id = self.free_page_hint_cmd_id();
+        let queue = &mut self.cfg.queues[usize::from(index)];
+        let release = &mut self.release;
+        let hinting = &mut self.hinting;
+        let hinted = &mut self.hinted;
+        while let Some(mut chain) = queue.iter()?.next() {
+            let descriptors: Vec<_> = chain.by_ref().collect();
+            let result = match descriptors.first() {
+                // The driver acknowledges a command identifier with a device-readable buffer.
+                Some(desc) if !desc.is_write_only() => chain
+                    .memory()
+                    .read_obj::<u32>(desc.addr())
+                    .map(|id| match u32::from_le(id) {
+                        VIRTIO_BALLOON_CMD_ID_STOP => *hinted = hinting.take().or(*hinted),
+                        id if id == cmd_id => *hinting = Some(id),
+                        // The driver acknowledged a previous command identifier.
+                        _ => (),
+                    })
+                    .map_err(Error::GuestMemory),
+                // Free pages are sent through device-writable buffers, and they are ignored
+                // when they don't belong to the current command.
+                _ => write_only_ranges(&descriptors).and_then(|ranges| {
+                    if *hinting != Some(cmd_id) {
+                        return Ok(());
+                    }
+                    for (addr, len) in ranges {
+                        release.hint(cmd_id, addr, len).map_err(Error::Release)?;
+                    }
+                    Ok(())
+                }),
+            };
+            if let Err(e) = result {
+                warn!("failed to process free page hints: {}", e);
+            }
+
+            // The driver doesn't expect anything to be written to the buffers.
+            queue.add_used(chain.head_index(), 0)?;
+            if queue.needs_notification()? {
+                set_interrupt_status(&self.cfg.interrupt_status, VIRTIO_MMIO_INT_VRING);
+                self.driver_notify.signal_used_queue(index);
+            }
+        }
+        Ok(())
+    }
+
+    /// Releases the memory of the free page ranges sent by the driver through the free page
+    /// reporting queue. This has to be called when the driver notifies the reporting queue.
+    pub fn process_reporting_queue(&mut self) -> Result<()> {
+        let index = self.active_queue(VIRTIO_BALLOON_F_REPORTING)?;
+        let queue = &mut self.cfg.queues[usize::from(index)];
+        let release = &mut self.release;
+        while let Some(mut chain) = queue.iter()?.next() {
+            let descriptors: Vec<_> = chain.by_ref().collect();
+            let result = write_only_ranges(&descriptors).and_then(|ranges| {
+                for (addr, len) in ranges {
+                    release.report(addr, len).map_err(Error::Release)?;
+                }
+                Ok(())
+            });
+            if let Err(e) = result {
+                warn!("failed to process free page reports: {}", e);
+            }
+
+            // The driver doesn't expect anything to be written to the buffers.
+            queue.add_used(chain.head_index(), 0)?;
+            if queue.needs_notification()? {
+                set_interrupt_status(&self.cfg.interrupt_status, VIRTIO_MMIO_INT_VRING);
+                self.driver_notify.signal_used_queue(index);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<M, R, S> Balloon<M, R, S>
+where
+    M: GuestAddressSpace,
+    R: MemoryRelease,
+    S: SignalUsedQueue + SignalConfigChange,
+{
+    /// Sets the number of pages the driver is asked to hand over, which inflates (or deflates)
+    /// the balloon.
+    ///
+    /// The `num_pages` field of the configuration space and the configuration generation are
+    /// updated, and a configuration change interrupt is raised when the device is activated,
+    /// such that the driver can pick up the new target. The target is preserved across device
+    /// resets.
+    ///
+    /// # Arguments
+    /// * `num_pages` - The number of 4 KiB pages.
+    pub fn set_num_pages(&mut self, num_pages: u32) {
+        if self.num_pages() == num_pages {
+            return;
+        }
+        self.update_config_field(ConfigSpace::NUM_PAGES_OFFSET, num_pages);
+    }
+
+    /// Asks the driver to hint its free pages, by publishing a new free page hinting command
+    /// identifier, which is returned. The hinted ranges are passed to
+    /// [`MemoryRelease::hint`](../release/trait.MemoryRelease.html#method.hint) along with the
+    /// command identifier.
+    pub fn start_free_page_hinting(&mut self) -> Result<u32> {
+        self.active_queue(VIRTIO_BALLOON_F_FREE_PAGE_HINT)?;
+        // The first two command identifiers are reserved.
+        self.hint_cmd_id = match self.hint_cmd_id.wrapping_add(1) {
+            cmd_id if cmd_id > VIRTIO_BALLOON_CMD_ID_DONE => cmd_id,
+            _ => VIRTIO_BALLOON_CMD_ID_DONE + 1,
+        };
+        self.hinting = None;
+        self.hinted = None;
+        self.update_config_field(ConfigSpace::FREE_PAGE_HINT_CMD_ID_OFFSET, self.hint_cmd_id);
+        Ok(self.hint_cmd_id)
+    }
+
+    /// Asks the driver to stop hinting free pages. The driver keeps the pages hinted so far
+    /// until [`finish_free_page_hinting`](#method.finish_free_page_hinting) is called.
+    pub fn stop_free_page_hinting(&mut self) {
+        self.update_config_field(
+            ConfigSpace::FREE_PAGE_HINT_CMD_ID_OFFSET,
+            VIRTIO_BALLOON_CMD_ID_STOP,
+        );
+    }
+
+    /// Allows the driver to use the hinted pages again, i.e. once the migration completed.
+    pub fn finish_free_page_hinting(&mut self) {
+        self.update_config_field(
+            ConfigSpace::FREE_PAGE_HINT_CMD_ID_OFFSET,
+            VIRTIO_BALLOON_CMD_ID_DONE,
+        );
+    }
+
+    // Updates a configuration space field, and notifies the driver about the change when the
+    // device is activated.
+    fn update_config_field(&mut self, offset: usize, value: u32) {
+        self.set_config_field(offset, value);
+        self.cfg.config_generation = self.cfg.config_generation.wrapping_add(1);
+
+        if self.cfg.device_activated {
+            self.cfg.signal_config_change();
+            self.driver_notify.signal_config_change();
+        }
+    }
+}
+
+// Reads the contents of a buffer sent by the driver, which can only contain device-readable
+// descriptors.
+fn read_buffer<M: GuestAddressSpace>(chain: &mut DescriptorChain<M>) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let descriptors: Vec<_> = chain.by_ref().collect();
+    for desc in descriptors {
+        if desc.is_write_only() {
+            return Err(Error::UnexpectedWriteOnlyDescriptor);
+        }
+        let start = bytes.len();
+        let end = start + desc.len() as usize;
+        if end > MAX_BUFFER_LEN {
+            return Err(Error::BufferTooLarge);
+        }
+        bytes.resize(end, 0);
+        chain
+            .memory()
+            .read_slice(&mut bytes[start..end], desc.addr())
+            .map_err(Error::GuestMemory)?;
+    }
+    Ok(bytes)
+}
+
+// Returns the guest memory ranges covered by the descriptors of a buffer sent by the driver,
+// which can only contain device-writable descriptors.
+fn write_only_ranges(descriptors: &[Descriptor]) -> Result<Vec<(GuestAddress, u64)>> {
+    descriptors
+        .iter()
+        .map(|desc| {
+            if desc.is_write_only() {
+                Ok((desc.addr(), u64::from(desc.len())))
+            } else {
+                Err(Error::UnexpectedReadOnlyDescriptor)
+            }
+        })
+        .collect()
+}
+
+// Converts an array of little endian page frame numbers to guest memory ranges, merging the
+// consecutive pages. A trailing partial page frame number is ignored.
+fn page_ranges(buffer: &[u8]) -> Vec<(GuestAddress, u64)> {
+    let mut ranges: Vec<(GuestAddress, u64)> = Vec::new();
+    for chunk in buffer.chunks_exact(4) {
+        // The chunk always has 4 bytes.
+        let pfn = u32::from_le_bytes(chunk.try_into().unwrap());
+        let addr = u64::from(pfn) << VIRTIO_BALLOON_PFN_SHIFT;
+        match ranges.last_mut() {
+            Some((start, len)) if start.0 + *len == addr => *len += VIRTIO_BALLOON_PAGE_SIZE,
+            _ => ranges.push((GuestAddress(addr), VIRTIO_BALLOON_PAGE_SIZE)),
+        }
+    }
+    ranges
+}
+
+impl<M, R, S> VirtioDeviceActions for Balloon<M, R, S>
+where
+    M: GuestAddressSpace,
+    R: MemoryRelease,
+    S: SignalUsedQueue,
+{
+    type E = Error;
+
+    fn activate(&mut self) -> Result<()> {
+        if self.cfg.device_activated {
+            return Err(Error::AlreadyActivated);
+        }
+        let num_queues = 2 + OPTIONAL_QUEUE_FEATURES
+            .iter()
+            .filter(|&&feature| self.optional_queue(feature).is_some())
+            .count();
+        if !self.cfg.queues[..num_queues].iter().all(Queue::is_valid) {
+            return Err(Error::InvalidQueues);
+        }
+        self.cfg.device_activated = true;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.pages = 0;
+        self.stats = None;
+        self.stats_head = None;
+        self.hinting = None;
+        self.hinted = None;
+        // The driver starts over with an empty balloon, and no free page hinting session.
+        self.set_config_field(ConfigSpace::ACTUAL_OFFSET, 0);
+        self.set_config_field(
+            ConfigSpace::FREE_PAGE_HINT_CMD_ID_OFFSET,
+            VIRTIO_BALLOON_CMD_ID_STOP,
+        );
+
+        let cfg = &mut self.cfg;
+        cfg.driver_features = 0;
+        cfg.device_features_select = 0;
+        cfg.driver_features_select = 0;
+        cfg.device_status = 0;
+        cfg.queue_select = 0;
+        cfg.queues.iter_mut().for_each(Queue::reset);
+        cfg.device_activated = false;
+        cfg.reset_interrupt_status();
+        Ok(())
+    }
+}
+
+impl<M, R, S> VirtioMmioDevice<M> for Balloon<M, R, S>
+where
+    M: GuestAddressSpace + 'static,
+    R: MemoryRelease,
+    S: SignalUsedQueue,
+{
+    fn queue_notify(&mut self, val: u32) {
+        // Queue indices always fit in an `u16`.
+        let index = val as u16;
+        let result = match index {
+            INFLATE_QUEUE => self.process_inflate_queue(),
+            DEFLATE_QUEUE => self.process_deflate_queue(),
+            STATS_QUEUE if self.has_stats_queue() => self.process_stats_queue(),
+            _ if self.optional_queue(VIRTIO_BALLOON_F_FREE_PAGE_HINT) == Some(index) => {
+                self.process_free_page_queue()
+            }
+            _ if self.optional_queue(VIRTIO_BALLOON_F_REPORTING) == Some(index) => {
+                self.process_reporting_queue()
+            }
+            _ => Err(Error::InvalidQueueIndex(index)),
+        };
+        if let Err(e) = result {
+            error!("failed to process queue {}: {}", val, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use vm_memory::{Address, GuestMemoryMmap};
+    use vmm_sys_util::eventfd::EventFd;
+
+    use virtio_device::mock::{activate, adjacent_regions};
+    use virtio_device::{VirtioDevice, VIRTIO_MMIO_INT_CONFIG};
+    use virtio_queue::mock::{AttackPattern, VirtQueue};
+    use virtio_queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    use crate::defs::VIRTIO_BALLOON_S_MEMFREE;
+    use crate::pressure::LowerTarget;
+    use crate::stats::BalloonStat;
+
+    type Mem = Arc<GuestMemoryMmap>;
+
+    // Records the released, reclaimed, reported and hinted ranges.
+    #[derive(Debug, Default)]
+    struct TestRelease {
+        released: Vec<(GuestAddress, u64)>,
+        reclaimed: Vec<(GuestAddress, u64)>,
+        reported: Vec<(GuestAddress, u64)>,
+        hinted: Vec<(u32, GuestAddress, u64)>,
+    }
+
+    impl MemoryRelease for TestRelease {
+        fn release(&mut self, addr: GuestAddress, len: u64) -> io::Result<()> {
+            self.released.push((addr, len));
+            Ok(())
+        }
+
+        fn reclaim(&mut self, addr: GuestAddress, len: u64) -> io::Result<()> {
+            self.reclaimed.push((addr, len));
+            Ok(())
+        }
+
+        fn report(&mut self, addr: GuestAddress, len: u64) -> io::Result<()> {
+            self.reported.push((addr, len));
+            Ok(())
+        }
+
+        fn hint(&mut self, cmd_id: u32, addr: GuestAddress, len: u64) -> io::Result<()> {
+            self.hinted.push((cmd_id, addr, len));
+            Ok(())
+        }
+    }
+
+    fn balloon(mem: &Mem, stats: bool) -> Balloon<Mem, TestRelease, EventFd> {
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        BalloonBuilder::new(mem.clone(), TestRelease::default(), evt)
+            .with_queue_size(16)
+            .with_stats_queue(stats)
+            .build()
+    }
+
+    fn virt_queues(mem: &GuestMemoryMmap, num_queues: u64) -> Vec<VirtQueue<'_>> {
+        (0..num_queues)
+            .map(|i| VirtQueue::new(GuestAddress(i * 0x1000), mem, 16))
+            .collect()
+    }
+
+    // Makes a buffer holding `pfns` available in `vq`, using the descriptor `index`.
+    fn add_pfns(mem: &GuestMemoryMmap, vq: &VirtQueue, index: u16, pfns: &[u32]) {
+        let addr = GuestAddress(0x1_0000 + u64::from(index) * 0x1000);
+        let bytes: Vec<u8> = pfns.iter().flat_map(|pfn| pfn.to_le_bytes()).collect();
+        mem.write_slice(&bytes, addr).unwrap();
+        vq.dtable(index).set(addr.0, bytes.len() as u32, 0, 0);
+        let avail = vq.avail.idx().load();
+        vq.avail.ring(avail).store(index);
+        vq.avail.idx().store(avail + 1);
+    }
+
+    // Makes a buffer with a single descriptor available in `vq`, using the descriptor `index`.
+    fn add_buffer(vq: &VirtQueue, index: u16, addr: u64, len: u32, flags: u16) {
+        vq.dtable(index).set(addr, len, flags, 0);
+        let avail = vq.avail.idx().load();
+        vq.avail.ring(avail).store(index);
+        vq.avail.idx().store(avail + 1);
+    }
+
+    #[test]
+    fn test_build() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+
+        let b = balloon(&mem, false);
+        assert_eq!(VirtioDevice::device_type(&b), VIRTIO_ID_BALLOON);
+        assert_eq!(b.num_queues(), 2);
+        assert_ne!(b.device_features() & (1 << VIRTIO_F_VERSION_1), 0);
+        assert_eq!(b.device_features() & (1 << VIRTIO_BALLOON_F_STATS_VQ), 0);
+        assert_eq!(b.num_pages(), 0);
+        assert_eq!(b.actual(), 0);
+
+        let b = BalloonBuilder::new(
+            mem.clone(),
+            TestRelease::default(),
+            EventFd::new(0).unwrap(),
+        )
+        .with_num_pages(0x100)
+        .with_stats_queue(true)
+        .with_deflate_on_oom(true)
+        .with_must_tell_host(true)
+        .with_free_page_hinting(true)
+        .with_free_page_reporting(true)
+        .build();
+        assert_eq!(b.num_queues(), 5);
+        for &feature in [
+            VIRTIO_BALLOON_F_STATS_VQ,
+            VIRTIO_BALLOON_F_DEFLATE_ON_OOM,
+            VIRTIO_BALLOON_F_MUST_TELL_HOST,
+            VIRTIO_BALLOON_F_FREE_PAGE_HINT,
+            VIRTIO_BALLOON_F_REPORTING,
+        ]
+        .iter()
+        {
+            assert_ne!(b.device_features() & (1 << feature), 0);
+        }
+        let mut num_pages = [0u8; 4];
+        b.read_config(ConfigSpace::NUM_PAGES_OFFSET, &mut num_pages);
+        assert_eq!(u32::from_le_bytes(num_pages), 0x100);
+    }
+
+    #[test]
+    fn test_inflate_deflate() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let vqs = virt_queues(&mem, 2);
+        let mut b = balloon(&mem, false);
+        assert!(matches!(
+            b.process_inflate_queue(),
+            Err(Error::InvalidQueueIndex(INFLATE_QUEUE))
+        ));
+
+        activate(&mut b, &vqs, 0);
+        assert!(b.is_activated());
+
+        // Consecutive pages are merged.
+        add_pfns(&mem, &vqs[0], 0, &[0x10, 0x11, 0x12, 0x20, 0x13]);
+        // A malformed buffer, which is returned to the driver without releasing anything.
+        vqs[0].dtable(1).set(0x2_0000, 4, VIRTQ_DESC_F_WRITE, 0);
+        vqs[0].avail.ring(1).store(1);
+        vqs[0].avail.idx().store(2);
+        b.queue_notify(u32::from(INFLATE_QUEUE));
+        assert_eq!(
+            b.release.released,
+            vec![
+                (GuestAddress(0x1_0000), 0x3000),
+                (GuestAddress(0x2_0000), 0x1000),
+                (GuestAddress(0x1_3000), 0x1000)
+            ]
+        );
+        assert_eq!(vqs[0].used.idx().load(), 2);
+        assert!(b.release.reclaimed.is_empty());
+
+        // A page frame number split across two descriptors.
+        let addr = GuestAddress(0x3_0000);
+        mem.write_slice(&0x30u32.to_le_bytes(), addr).unwrap();
+        vqs[1].dtable(0).set(addr.0, 2, VIRTQ_DESC_F_NEXT, 1);
+        vqs[1].dtable(1).set(addr.unchecked_add(2).0, 2, 0, 0);
+        vqs[1].avail.ring(0).store(0);
+        vqs[1].avail.idx().store(1);
+        b.queue_notify(u32::from(DEFLATE_QUEUE));
+        assert_eq!(b.release.reclaimed, vec![(GuestAddress(0x3_0000), 0x1000)]);
+        assert_eq!(vqs[1].used.idx().load(), 1);
+
+        // The driver reports the number of pages in the balloon.
+        b.write_config(ConfigSpace::ACTUAL_OFFSET, &4u32.to_le_bytes());
+        assert_eq!(b.actual(), 4);
+        VirtioDeviceActions::reset(&mut b).unwrap();
+        assert!(!b.is_activated());
+        assert_eq!(b.actual(), 0);
+    }
+
+    #[test]
+    fn test_num_pages() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let vqs = virt_queues(&mem, 2);
+        let mut b = balloon(&mem, false);
+
+        // No interrupt is raised before the device is activated.
+        b.set_num_pages(0x10);
+        assert_eq!(b.num_pages(), 0x10);
+        assert_eq!(b.config_generation(), 1);
+        assert_eq!(b.interrupt_status().load(Ordering::SeqCst), 0);
+        assert!(b.driver_notify.read().is_err());
+
+        activate(&mut b, &vqs, 0);
+        b.set_num_pages(0x20);
+        assert_eq!(b.config_generation(), 2);
+        assert_eq!(
+            b.interrupt_status().load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_CONFIG
+        );
+        assert_eq!(b.driver_notify.read().unwrap(), 1);
+        let mut num_pages = [0u8; 4];
+        b.read_config(ConfigSpace::NUM_PAGES_OFFSET, &mut num_pages);
+        assert_eq!(u32::from_le_bytes(num_pages), 0x20);
+
+        // Setting the same target doesn't notify the driver again.
+        b.set_num_pages(0x20);
+        assert_eq!(b.config_generation(), 2);
+
+        // The target is preserved across resets.
+        VirtioDeviceActions::reset(&mut b).unwrap();
+        assert_eq!(b.num_pages(), 0x20);
+    }
+
+    #[test]
+    fn test_deflate_on_oom() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let vqs = virt_queues(&mem, 2);
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let mut b = BalloonBuilder::new(mem.clone(), TestRelease::default(), evt)
+            .with_queue_size(16)
+            .with_num_pages(8)
+            .with_deflate_on_oom(true)
+            .with_memory_pressure(LowerTarget)
+            .build();
+        activate(&mut b, &vqs, 0);
+
+        add_pfns(
+            &mem,
+            &vqs[0],
+            0,
+            &[0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17],
+        );
+        b.process_inflate_queue().unwrap();
+        assert_eq!(b.inflated_pages(), 8);
+
+        // Deflating the balloon down to the target doesn't count as memory pressure.
+        b.set_num_pages(4);
+        assert_eq!(b.config_generation(), 1);
+        add_pfns(&mem, &vqs[1], 0, &[0x14, 0x15, 0x16, 0x17]);
+        b.process_deflate_queue().unwrap();
+        assert_eq!(b.inflated_pages(), 4);
+        assert_eq!(b.num_pages(), 4);
+        assert_eq!(b.config_generation(), 1);
+        assert_eq!(b.driver_notify.read().unwrap(), 3);
+
+        // The guest runs out of memory, and the target is lowered without notifying the driver.
+        add_pfns(&mem, &vqs[1], 1, &[0x12, 0x13]);
+        b.process_deflate_queue().unwrap();
+        assert_eq!(b.inflated_pages(), 2);
+        assert_eq!(b.num_pages(), 2);
+        assert_eq!(b.config_generation(), 2);
+        assert!(b.driver_notify.read().is_err());
+
+        // The target can't be lowered below the number of pages in the balloon.
+        b.set_memory_pressure(Some(Box::new(|target, pages| {
+            assert_eq!((target, pages), (2, 1));
+            Some(0)
+        })));
+        add_pfns(&mem, &vqs[1], 2, &[0x11]);
+        b.process_deflate_queue().unwrap();
+        assert_eq!(b.num_pages(), 1);
+
+        // The target is kept when the handler doesn't provide a new one.
+        b.set_memory_pressure(Some(Box::new(|_, _| None)));
+        add_pfns(&mem, &vqs[1], 3, &[0x10]);
+        b.process_deflate_queue().unwrap();
+        assert_eq!(b.inflated_pages(), 0);
+        assert_eq!(b.num_pages(), 1);
+        assert_eq!(b.config_generation(), 3);
+
+        VirtioDeviceActions::reset(&mut b).unwrap();
+        assert_eq!(b.inflated_pages(), 0);
+
+        // The handler is not called when the feature was not negotiated.
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let mut b = BalloonBuilder::new(mem.clone(), TestRelease::default(), evt)
+            .with_queue_size(16)
+            .with_num_pages(2)
+            .with_memory_pressure(LowerTarget)
+            .build();
+        let vqs = virt_queues(&mem, 2);
+        activate(&mut b, &vqs, 0);
+        add_pfns(&mem, &vqs[0], 0, &[0x10, 0x11]);
+        add_pfns(&mem, &vqs[1], 0, &[0x10]);
+        b.queue_notify(u32::from(INFLATE_QUEUE));
+        b.queue_notify(u32::from(DEFLATE_QUEUE));
+        assert_eq!(b.inflated_pages(), 1);
+        assert_eq!(b.num_pages(), 2);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let vqs = virt_queues(&mem, 3);
+        let mut b = balloon(&mem, false);
+        activate(&mut b, &vqs[..2], 0);
+        // The statistics queue was not negotiated.
+        assert!(matches!(
+            b.request_stats(),
+            Err(Error::InvalidQueueIndex(STATS_QUEUE))
+        ));
+
+        let mut b = balloon(&mem, true);
+        activate(&mut b, &vqs, 0);
+        assert!(b.stats().is_none());
+        assert!(!b.request_stats().unwrap());
+
+        let vq = &vqs[usize::from(STATS_QUEUE)];
+        let addr = GuestAddress(0x4_0000);
+        let stat = BalloonStat {
+            tag: VIRTIO_BALLOON_S_MEMFREE,
+            val: 0x1234,
+        };
+        mem.write_obj(stat, addr).unwrap();
+        vq.dtable(0).set(addr.0, BalloonStat::LEN as u32, 0, 0);
+        vq.avail.ring(0).store(0);
+        vq.avail.idx().store(1);
+        b.queue_notify(u32::from(STATS_QUEUE));
+        assert_eq!(b.stats().unwrap().free_memory, Some(0x1234));
+        // The buffer is held until fresh statistics are requested.
+        assert_eq!(vq.used.idx().load(), 0);
+
+        assert!(b.request_stats().unwrap());
+        assert_eq!(vq.used.idx().load(), 1);
+        assert!(!b.request_stats().unwrap());
+
+        // The driver refills the buffer.
+        mem.write_obj(
+            BalloonStat {
+                tag: VIRTIO_BALLOON_S_MEMFREE,
+                val: 0x5678,
+            },
+            addr,
+        )
+        .unwrap();
+        vq.avail.ring(1).store(0);
+        vq.avail.idx().store(2);
+        b.process_stats_queue().unwrap();
+        assert_eq!(b.stats().unwrap().free_memory, Some(0x5678));
+
+        VirtioDeviceActions::reset(&mut b).unwrap();
+        assert!(b.stats().is_none());
+    }
+
+    #[test]
+    fn test_free_page_reporting() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let vqs = virt_queues(&mem, 3);
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let mut b = BalloonBuilder::new(mem.clone(), TestRelease::default(), evt)
+            .with_queue_size(16)
+            .with_free_page_reporting(true)
+            .build();
+        assert_eq!(b.num_queues(), 3);
+        assert!(matches!(
+            b.process_reporting_queue(),
+            Err(Error::FeatureNotNegotiated(VIRTIO_BALLOON_F_REPORTING))
+        ));
+
+        activate(&mut b, &vqs, 0);
+        // The reporting queue takes the first optional queue index.
+        assert_eq!(b.optional_queue(VIRTIO_BALLOON_F_REPORTING), Some(2));
+        assert_eq!(b.optional_queue(VIRTIO_BALLOON_F_STATS_VQ), None);
+
+        add_buffer(&vqs[2], 0, 0x8_0000, 0x20_0000, VIRTQ_DESC_F_WRITE);
+        // A device-readable buffer is returned without reporting anything.
+        add_buffer(&vqs[2], 1, 0x9_0000, 0x1000, 0);
+        b.queue_notify(2);
+        assert_eq!(
+            b.release.reported,
+            vec![(GuestAddress(0x8_0000), 0x20_0000)]
+        );
+        assert_eq!(vqs[2].used.idx().load(), 2);
+        assert_eq!(b.driver_notify.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_free_page_hinting() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let vqs = virt_queues(&mem, 4);
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let mut b = BalloonBuilder::new(mem.clone(), TestRelease::default(), evt)
+            .with_queue_size(16)
+            .with_free_page_hinting(true)
+            .with_free_page_reporting(true)
+            .build();
+        assert!(matches!(
+            b.start_free_page_hinting(),
+            Err(Error::FeatureNotNegotiated(VIRTIO_BALLOON_F_FREE_PAGE_HINT))
+        ));
+
+        activate(&mut b, &vqs, 0);
+        assert_eq!(b.optional_queue(VIRTIO_BALLOON_F_FREE_PAGE_HINT), Some(2));
+        assert_eq!(b.optional_queue(VIRTIO_BALLOON_F_REPORTING), Some(3));
+        assert_eq!(b.free_page_hint_cmd_id(), VIRTIO_BALLOON_CMD_ID_STOP);
+
+        let cmd_id = b.start_free_page_hinting().unwrap();
+        assert_eq!(cmd_id, 2);
+        assert_eq!(b.free_page_hint_cmd_id(), cmd_id);
+        assert_eq!(
+            b.interrupt_status().load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_CONFIG
+        );
+        assert_eq!(b.driver_notify.read().unwrap(), 1);
+
+        let vq = &vqs[2];
+        // Hints sent before the command identifier is acknowledged are ignored.
+        add_buffer(vq, 0, 0x8_0000, 0x1000, VIRTQ_DESC_F_WRITE);
+        // The driver acknowledges the command identifier, and sends hints.
+        mem.write_obj(cmd_id.to_le(), GuestAddress(0x2_0000))
+            .unwrap();
+        add_buffer(vq, 1, 0x2_0000, 4, 0);
+        add_buffer(vq, 2, 0x9_0000, 0x40_0000, VIRTQ_DESC_F_WRITE);
+        b.process_free_page_queue().unwrap();
+        assert_eq!(
+            b.release.hinted,
+            vec![(cmd_id, GuestAddress(0x9_0000), 0x40_0000)]
+        );
+        assert_eq!(vq.used.idx().load(), 3);
+        assert!(!b.is_free_page_hinting_done());
+
+        // The VMM stops the hinting, and the driver acknowledges it.
+        b.stop_free_page_hinting();
+        assert_eq!(b.free_page_hint_cmd_id(), VIRTIO_BALLOON_CMD_ID_STOP);
+        mem.write_obj(VIRTIO_BALLOON_CMD_ID_STOP.to_le(), GuestAddress(0x2_0000))
+            .unwrap();
+        add_buffer(vq, 3, 0x2_0000, 4, 0);
+        add_buffer(vq, 4, 0xa_0000, 0x1000, VIRTQ_DESC_F_WRITE);
+        b.queue_notify(2);
+        assert_eq!(b.release.hinted.len(), 1);
+        assert!(b.is_free_page_hinting_done());
+
+        b.finish_free_page_hinting();
+        assert_eq!(b.free_page_hint_cmd_id(), VIRTIO_BALLOON_CMD_ID_DONE);
+        assert!(b.is_free_page_hinting_done());
+
+        // A new session gets a new command identifier, which survives resets.
+        assert_eq!(b.start_free_page_hinting().unwrap(), 3);
+        assert!(!b.is_free_page_hinting_done());
+        VirtioDeviceActions::reset(&mut b).unwrap();
+        assert_eq!(b.free_page_hint_cmd_id(), VIRTIO_BALLOON_CMD_ID_STOP);
+        b.hint_cmd_id = u32::MAX;
+        activate(&mut b, &vqs, 0);
+        assert_eq!(b.start_free_page_hinting().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_page_ranges() {
+        assert!(page_ranges(&[]).is_empty());
+        assert!(page_ranges(&[1, 2, 3]).is_empty());
+        assert_eq!(
+            page_ranges(&[1, 0, 0, 0, 2, 0, 0, 0, 0xff]),
+            vec![(GuestAddress(0x1000), 0x2000)]
+        );
+        // The largest page frame number doesn't overflow.
+        assert_eq!(
+            page_ranges(&[0xff; 4]),
+            vec![(GuestAddress(0xffff_ffff << 12), 0x1000)]
+        );
+    }
+
+    #[test]
+    fn test_attack_patterns() {
+        let mem = adjacent_regions(0x10_0000);
+        for &pattern in AttackPattern::ALL.iter() {
+            let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+            vq.add_attack(pattern, 0, GuestAddress(0x1_0000));
+            let mut chain = vq.create_queue(&mem).iter().unwrap().next().unwrap();
+            // The chains which end early are empty buffers, and a loop repeats the same buffer
+            // up to the queue size.
+            assert_eq!(
+                read_buffer(&mut chain).is_ok(),
+                [
+                    AttackPattern::SelfReference,
+                    AttackPattern::IndirectSelfReference,
+                    AttackPattern::HeadOutOfRange
+                ]
+                .contains(&pattern),
+                "{:?}",
+                pattern
+            );
+        }
+    }
+}
@@ -0,0 +1,26 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A crate that provides balloon device specific components as described
+//! by the virtio specification.
+
+#![deny(missing_docs)]
+
+/// Contains the balloon device configuration space abstraction.
+pub mod config;
+
+/// Contains virtio balloon constant definitions.
+pub mod defs;
+
+/// Contains a reference virtio balloon device implementation.
+pub mod device;
+
+/// Contains the interface used for reacting to guest memory pressure.
+pub mod pressure;
+
+/// Contains the interface used for releasing the memory handed over by the driver.
+pub mod release;
+
+/// Contains the memory statistics reported by the driver.
+pub mod stats;
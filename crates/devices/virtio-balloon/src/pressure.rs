@@ -0,0 +1,58 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Guest memory pressure abstractions.
+//!
+//! When `VIRTIO_BALLOON_F_DEFLATE_ON_OOM` is negotiated, the driver takes pages back from the
+//! balloon when the guest runs out of memory, even if that goes below the number of pages
+//! requested by the device. The balloon device detects this from the deflate requests, and
+//! calls the [`MemoryPressure`](trait.MemoryPressure.html) implementation provided by the VMM,
+//! which can lower the target, such that the driver doesn't inflate the balloon back as soon as
+//! it gets the chance.
+//!
+//! The interface is implemented for all the `FnMut(u32, u32) -> Option<u32>` closures, so a
+//! callback can be used directly, and [`LowerTarget`](struct.LowerTarget.html) implements the
+//! common policy of matching the target to the number of pages left in the balloon.
+
+use std::fmt;
+
+/// Reacts to the driver deflating the balloon because of guest memory pressure.
+pub trait MemoryPressure: Send {
+    /// Called after the driver deflated the balloon below the target, and returns the new
+    /// target, or `None` to keep the current one. The target can only be lowered down to the
+    /// number of pages left in the balloon, and the driver is not notified about the change,
+    /// since the balloon already holds at least as many pages.
+    ///
+    /// # Arguments
+    /// * `target` - The number of pages the driver is asked to hand over.
+    /// * `pages` - The number of pages left in the balloon.
+    fn deflated(&mut self, target: u32, pages: u32) -> Option<u32>;
+}
+
+impl<F> MemoryPressure for F
+where
+    F: FnMut(u32, u32) -> Option<u32> + Send,
+{
+    fn deflated(&mut self, target: u32, pages: u32) -> Option<u32> {
+        self(target, pages)
+    }
+}
+
+// Allows the devices which hold a pressure handler to derive `Debug`.
+impl fmt::Debug for dyn MemoryPressure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MemoryPressure")
+    }
+}
+
+/// Lowers the target to the number of pages left in the balloon, such that the pages taken
+/// back by the driver are not requested again until the VMM sets a new target.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LowerTarget;
+
+impl MemoryPressure for LowerTarget {
+    fn deflated(&mut self, _target: u32, pages: u32) -> Option<u32> {
+        Some(pages)
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Guest memory release abstractions.
+//!
+//! This module provides the following abstractions:
+//!
+//! - [`MemoryRelease`](trait.MemoryRelease.html) which is called by the balloon device with
+//!   the guest memory ranges handed over by the driver (when the balloon is inflated), with the
+//!   ranges the driver takes back (when the balloon is deflated), and with the free page ranges
+//!   reported or hinted by the driver.
+//! - [`MadviseRelease`](struct.MadviseRelease.html) which releases the host memory backing the
+//!   ranges with `madvise(MADV_DONTNEED)`.
+
+use std::io;
+
+use vm_memory::{
+    Address, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryRegion, MemoryRegionAddress,
+};
+
+/// Releases the guest memory handed over by the driver of a balloon device.
+pub trait MemoryRelease {
+    /// Releases the memory of the range which starts at `addr`, and spans `len` bytes. The
+    /// driver no longer uses the range, so its contents can be discarded.
+    ///
+    /// # Arguments
+    /// * `addr` - The guest physical address of the range, which is page aligned.
+    /// * `len` - The length of the range, which is a multiple of the page size.
+    fn release(&mut self, addr: GuestAddress, len: u64) -> io::Result<()>;
+
+    /// Prepares the range which starts at `addr`, and spans `len` bytes, to be used again by the
+    /// driver. Released memory which is backed by anonymous mappings is populated again on
+    /// access, so nothing is done by default.
+    ///
+    /// # Arguments
+    /// * `addr` - The guest physical address of the range, which is page aligned.
+    /// * `len` - The length of the range, which is a multiple of the page size.
+    fn reclaim(&mut self, _addr: GuestAddress, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Handles the range of free pages which starts at `addr`, and spans `len` bytes, reported
+    /// by the driver (when `VIRTIO_BALLOON_F_REPORTING` is negotiated). The driver doesn't use
+    /// the range until the device is done with it, so it's released by default.
+    ///
+    /// # Arguments
+    /// * `addr` - The guest physical address of the range.
+    /// * `len` - The length of the range.
+    fn report(&mut self, addr: GuestAddress, len: u64) -> io::Result<()> {
+        self.release(addr, len)
+    }
+
+    /// Handles the range of free pages which starts at `addr`, and spans `len` bytes, hinted by
+    /// the driver in response to the free page hinting command `cmd_id` (when
+    /// `VIRTIO_BALLOON_F_FREE_PAGE_HINT` is negotiated), i.e. so the range is skipped while
+    /// migrating the guest memory. The contents of the range are not guaranteed to be discarded
+    /// by the driver, so nothing is done by default.
+    ///
+    /// # Arguments
+    /// * `cmd_id` - The free page hinting command identifier.
+    /// * `addr` - The guest physical address of the range.
+    /// * `len` - The length of the range.
+    fn hint(&mut self, _cmd_id: u32, _addr: GuestAddress, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Releases the host memory backing the guest memory ranges with `madvise(MADV_DONTNEED)`.
+///
+/// Private anonymous mappings read as zeroes after being released. Shared mappings (i.e. backed
+/// by a file or by shared memory) are not freed by `MADV_DONTNEED`, so they require a different
+/// `MemoryRelease` implementation (i.e. based on `MADV_REMOVE`). When the host page size is
+/// larger than the 4 KiB balloon pages, only the host pages which are fully covered by a range
+/// are released.
+#[derive(Clone, Debug)]
+pub struct MadviseRelease<M: GuestAddressSpace> {
+    mem: M,
+    page_size: u64,
+}
+
+impl<M: GuestAddressSpace> MadviseRelease<M> {
+    /// Creates a new `MadviseRelease` for the specified guest memory.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    pub fn new(mem: M) -> Self {
+        // Safe because `sysconf` doesn't access any memory.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        MadviseRelease {
+            mem,
+            // The page size is always positive.
+            page_size: page_size as u64,
+        }
+    }
+}
+
+impl<M: GuestAddressSpace> MemoryRelease for MadviseRelease<M> {
+    fn release(&mut self, addr: GuestAddress, len: u64) -> io::Result<()> {
+        let invalid_range = || io::Error::from(io::ErrorKind::InvalidInput);
+        let mem = self.mem.memory();
+        let end = addr.checked_add(len).ok_or_else(invalid_range)?;
+
+        // The range can span multiple regions, which are released separately.
+        let mut addr = addr;
+        while addr < end {
+            let region = mem.find_region(addr).ok_or_else(invalid_range)?;
+            let offset = region.to_region_addr(addr).ok_or_else(invalid_range)?;
+            let region_end = region.start_addr().unchecked_add(region.len());
+            let chunk_end = if end < region_end { end } else { region_end };
+
+            // Only whole host pages can be released.
+            let start = offset.raw_value();
+            let first_page = start.div_ceil(self.page_size) * self.page_size;
+            let last_page = (start + (chunk_end.raw_value() - addr.raw_value())) / self.page_size
+                * self.page_size;
+            if first_page < last_page {
+                let host_addr = region
+                    .get_host_address(MemoryRegionAddress(first_page))
+                    .map_err(|_| invalid_range())?;
+                // Safe because the range is contained by the region mapping, and the guest
+                // memory contents are allowed to change underneath the VMM.
+                let ret = unsafe {
+                    libc::madvise(
+                        host_addr as *mut libc::c_void,
+                        (last_page - first_page) as usize,
+                        libc::MADV_DONTNEED,
+                    )
+                };
+                if ret < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            addr = chunk_end;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::{Bytes, GuestMemoryMmap};
+
+    #[test]
+    fn test_madvise_release() {
+        let mem = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x10_0000),
+            (GuestAddress(0x10_0000), 0x10_0000),
+        ])
+        .unwrap();
+        let mut release = MadviseRelease::new(&mem);
+        let page_size = release.page_size;
+
+        mem.write_slice(&vec![0xaa; 0x20_0000], GuestAddress(0))
+            .unwrap();
+        // The range spans both regions.
+        let addr = GuestAddress(0x10_0000 - 4 * page_size);
+        release.release(addr, 8 * page_size).unwrap();
+
+        let mut data = vec![0xff; 8 * page_size as usize];
+        mem.read_slice(&mut data, addr).unwrap();
+        assert!(data.iter().all(|&b| b == 0));
+        let mut byte = 0u8;
+        mem.read_slice(std::slice::from_mut(&mut byte), addr.unchecked_sub(1))
+            .unwrap();
+        assert_eq!(byte, 0xaa);
+        mem.read_slice(
+            std::slice::from_mut(&mut byte),
+            addr.unchecked_add(8 * page_size),
+        )
+        .unwrap();
+        assert_eq!(byte, 0xaa);
+
+        // Partially covered host pages are left alone.
+        release.release(GuestAddress(1), page_size - 1).unwrap();
+        mem.read_slice(std::slice::from_mut(&mut byte), GuestAddress(1))
+            .unwrap();
+        assert_eq!(byte, 0xaa);
+
+        assert!(release.release(GuestAddress(0x20_0000), page_size).is_err());
+        assert!(release
+            .release(GuestAddress(0x1f_f000), 2 * page_size)
+            .is_err());
+        assert!(release.reclaim(GuestAddress(0), page_size).is_ok());
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Balloon memory statistics abstraction.
+//!
+//! When `VIRTIO_BALLOON_F_STATS_VQ` is negotiated, the driver reports guest memory statistics
+//! through the statistics queue, as an array of tagged values. This module provides the
+//! [`MemoryStats`](struct.MemoryStats.html) abstraction, which holds the parsed values.
+
+use std::mem::size_of;
+
+use vm_memory::ByteValued;
+
+use crate::defs::{
+    VIRTIO_BALLOON_S_AVAIL, VIRTIO_BALLOON_S_CACHES, VIRTIO_BALLOON_S_HTLB_PGALLOC,
+    VIRTIO_BALLOON_S_HTLB_PGFAIL, VIRTIO_BALLOON_S_MAJFLT, VIRTIO_BALLOON_S_MEMFREE,
+    VIRTIO_BALLOON_S_MEMTOT, VIRTIO_BALLOON_S_MINFLT, VIRTIO_BALLOON_S_SWAP_IN,
+    VIRTIO_BALLOON_S_SWAP_OUT,
+};
+
+/// A single statistic, as defined by the virtio specification (`virtio_balloon_stat`).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C, packed)]
+pub struct BalloonStat {
+    /// The statistic tag (`VIRTIO_BALLOON_S_*`).
+    pub tag: u16,
+    /// The statistic value.
+    pub val: u64,
+}
+
+// Safe because BalloonStat only contains plain data, and there's no padding between (or after)
+// the fields since the structure is packed.
+unsafe impl ByteValued for BalloonStat {}
+
+impl BalloonStat {
+    /// The size of a statistic.
+    pub const LEN: usize = size_of::<BalloonStat>();
+}
+
+/// The guest memory statistics reported by the driver. The statistics which are not supported
+/// by the driver are `None`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MemoryStats {
+    /// Amount of memory swapped in (in bytes).
+    pub swap_in: Option<u64>,
+    /// Amount of memory swapped out to disk (in bytes).
+    pub swap_out: Option<u64>,
+    /// Number of major page faults that have occurred.
+    pub major_faults: Option<u64>,
+    /// Number of minor page faults that have occurred.
+    pub minor_faults: Option<u64>,
+    /// Amount of memory not being used for any purpose (in bytes).
+    pub free_memory: Option<u64>,
+    /// Total amount of memory available (in bytes).
+    pub total_memory: Option<u64>,
+    /// An estimate of how much memory is available for starting new applications (in bytes).
+    pub available_memory: Option<u64>,
+    /// Amount of memory used for disk caches (in bytes).
+    pub disk_caches: Option<u64>,
+    /// Number of successful hugetlb page allocations.
+    pub hugetlb_allocations: Option<u64>,
+    /// Number of failed hugetlb page allocations.
+    pub hugetlb_failures: Option<u64>,
+}
+
+impl MemoryStats {
+    /// Parses the statistics from the contents of a statistics buffer. Unknown tags, and
+    /// trailing bytes which don't make a whole statistic, are ignored.
+    ///
+    /// # Arguments
+    /// * `bytes` - The array of `virtio_balloon_stat` structures written by the driver.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut stats = MemoryStats::default();
+        for chunk in bytes.chunks_exact(BalloonStat::LEN) {
+            let mut stat = BalloonStat::default();
+            stat.as_mut_slice().copy_from_slice(chunk);
+            stats.update(stat);
+        }
+        stats
+    }
+
+    // Sets the field which corresponds to the tag of `stat`.
+    fn update(&mut self, stat: BalloonStat) {
+        let field = match stat.tag {
+            VIRTIO_BALLOON_S_SWAP_IN => &mut self.swap_in,
+            VIRTIO_BALLOON_S_SWAP_OUT => &mut self.swap_out,
+            VIRTIO_BALLOON_S_MAJFLT => &mut self.major_faults,
+            VIRTIO_BALLOON_S_MINFLT => &mut self.minor_faults,
+            VIRTIO_BALLOON_S_MEMFREE => &mut self.free_memory,
+            VIRTIO_BALLOON_S_MEMTOT => &mut self.total_memory,
+            VIRTIO_BALLOON_S_AVAIL => &mut self.available_memory,
+            VIRTIO_BALLOON_S_CACHES => &mut self.disk_caches,
+            VIRTIO_BALLOON_S_HTLB_PGALLOC => &mut self.hugetlb_allocations,
+            VIRTIO_BALLOON_S_HTLB_PGFAIL => &mut self.hugetlb_failures,
+            _ => return,
+        };
+        *field = Some(stat.val);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_bytes() {
+        assert_eq!(BalloonStat::LEN, 10);
+        assert_eq!(MemoryStats::from_bytes(&[]), MemoryStats::default());
+
+        let mut bytes = Vec::new();
+        for &(tag, val) in [
+            (VIRTIO_BALLOON_S_MEMFREE, 0x1000),
+            (VIRTIO_BALLOON_S_MEMTOT, 0x10_0000),
+            // Unknown tags are ignored.
+            (0xff, 1),
+            (VIRTIO_BALLOON_S_HTLB_PGFAIL, 3),
+        ]
+        .iter()
+        {
+            bytes.extend_from_slice(BalloonStat { tag, val }.as_slice());
+        }
+        // A truncated statistic.
+        bytes.extend_from_slice(&[VIRTIO_BALLOON_S_SWAP_IN as u8, 0, 1]);
+
+        let stats = MemoryStats::from_bytes(&bytes);
+        assert_eq!(
+            stats,
+            MemoryStats {
+                free_memory: Some(0x1000),
+                total_memory: Some(0x10_0000),
+                hugetlb_failures: Some(3),
+                ..Default::default()
+            }
+        );
+    }
+}
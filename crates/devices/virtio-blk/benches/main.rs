@@ -0,0 +1,21 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+extern crate criterion;
+
+mod request;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use request::benchmark_request;
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(200).measurement_time(std::time::Duration::from_secs(20));
+    targets = benchmark_request
+}
+
+criterion_main! {
+    benches,
+}
@@ -0,0 +1,64 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use criterion::{black_box, BatchSize, Criterion};
+use virtio_blk::defs::{SECTOR_SIZE, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT};
+use virtio_blk::request::Request;
+use virtio_queue::mock::DescriptorChainBuilder;
+use virtio_queue::{Descriptor, DescriptorChain, VIRTQ_DESC_F_WRITE};
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+// Where the request header, the data buffers and the status byte are placed in guest memory.
+const HEADER_ADDR: u64 = 0x10_0000;
+const DATA_ADDR: u64 = 0x20_0000;
+const STATUS_ADDR: u64 = 0x30_0000;
+
+// Writes a block request with `num_data` data descriptors of one sector each as the only
+// available chain of a queue, and returns the chain.
+fn request_chain(
+    mem: &GuestMemoryMmap,
+    request_type: u32,
+    num_data: u16,
+) -> DescriptorChain<&GuestMemoryMmap> {
+    let data_flags = if request_type == VIRTIO_BLK_T_IN {
+        VIRTQ_DESC_F_WRITE
+    } else {
+        0
+    };
+
+    // The request header is made of the request type, a reserved field and the sector.
+    mem.write_obj(request_type, GuestAddress(HEADER_ADDR))
+        .unwrap();
+    mem.write_obj(0u64, GuestAddress(HEADER_ADDR + 8)).unwrap();
+
+    let mut descs = vec![Descriptor::new(HEADER_ADDR, 16, 0, 0)];
+    descs.extend((0..num_data).map(|i| {
+        let addr = DATA_ADDR + u64::from(i) * SECTOR_SIZE;
+        Descriptor::new(addr, SECTOR_SIZE as u32, data_flags, 0)
+    }));
+    descs.push(Descriptor::new(STATUS_ADDR, 1, VIRTQ_DESC_F_WRITE, 0));
+
+    DescriptorChainBuilder::new(GuestAddress(0), mem, 256).build(&descs)
+}
+
+pub fn benchmark_request(c: &mut Criterion) {
+    let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0x0), 0x100_0000)]).unwrap();
+
+    for (name, request_type) in [("in", VIRTIO_BLK_T_IN), ("out", VIRTIO_BLK_T_OUT)]
+        .iter()
+        .copied()
+    {
+        for num_data in [1u16, 64].iter().copied() {
+            c.bench_function(
+                &format!("parse {} request ({} data descriptors)", name, num_data),
+                |b| {
+                    b.iter_batched(
+                        || request_chain(&mem, request_type, num_data),
+                        |mut chain| Request::parse(black_box(&mut chain)).unwrap(),
+                        BatchSize::SmallInput,
+                    )
+                },
+            );
+        }
+    }
+}
@@ -0,0 +1,953 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A minimal KVM based VMM which boots a Linux kernel with a virtio-blk MMIO device.
+//!
+//! ```text
+//! cargo run -p virtio-blk --features backend-stdio --example mmio_vmm -- \
+//!     --kernel bzImage --initrd initrd.cpio --image disk.raw [--read-only] \
+//!     [--mem-size MiB] [--cmdline ARGS] [--expect TEXT]
+//! ```
+//!
+//! The VMM creates a single vCPU, loads the kernel (a `bzImage`, started through the 64-bit
+//! boot protocol) and the initrd in guest memory, and emulates the serial console and the
+//! block device. The device is described to the guest on the kernel command line
+//! (`virtio_mmio.device=4K@0xd0000000:5`), so the kernel needs `CONFIG_VIRTIO_MMIO` and
+//! `CONFIG_VIRTIO_MMIO_CMDLINE_DEVICES`, and shows the disk as `/dev/vda`.
+//!
+//! The driver notifications reach the device as MMIO exits, which are handled on the vCPU
+//! thread through `VirtioMmioDevice::queue_notify`, while the interrupts are delivered by KVM
+//! through the irqfd registered with `VirtioDeviceEvents::reattach`.
+//!
+//! The VMM stops when the guest reboots (i.e. `reboot -f` with the default `reboot=k`, or a
+//! kernel panic). When `--expect` is given, it only exits successfully if the guest printed
+//! `TEXT` on the console, which makes the example usable as an integration test, for instance
+//! with an initrd whose `/init` is:
+//!
+//! ```text
+//! #!/bin/sh
+//! mount -t devtmpfs dev /dev
+//! head -c 16 /dev/vda
+//! reboot -f
+//! ```
+
+#[cfg(target_arch = "x86_64")]
+mod vmm {
+    use std::convert::TryInto;
+    use std::env;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, Write};
+    use std::os::raw::c_int;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::process;
+    use std::ptr;
+    use std::sync::Arc;
+
+    use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+    use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+    use vmm_sys_util::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ref, ioctl_with_val};
+    use vmm_sys_util::{ioctl_io_nr, ioctl_ior_nr, ioctl_iow_nr, ioctl_iowr_nr};
+
+    use virtio_blk::device::{Block, BlockBuilder};
+    use virtio_blk::shared_file::{CacheMode, SharedFile};
+    use virtio_device::{EventsContext, VirtioDeviceEvents, VirtioMmioDevice};
+
+    const USAGE: &str = "usage: mmio_vmm --kernel <path> --initrd <path> --image <path> \
+                         [--read-only] [--mem-size <MiB>] [--cmdline <args>] [--expect <text>]";
+
+    const DEFAULT_CMDLINE: &str = "console=ttyS0 reboot=k panic=1 pci=off acpi=off noapic";
+
+    // The guest physical memory layout.
+    const GDT_START: u64 = 0x500;
+    const IDT_START: u64 = 0x520;
+    const ZERO_PAGE_START: u64 = 0x7000;
+    const BOOT_STACK_POINTER: u64 = 0x8ff0;
+    const PML4_START: u64 = 0x9000;
+    const PDPT_START: u64 = 0xa000;
+    const PD_START: u64 = 0xb000;
+    const CMDLINE_START: u64 = 0x20000;
+    const CMDLINE_MAX_SIZE: usize = 0x1000;
+    const EBDA_START: u64 = 0x9fc00;
+    const HIGH_MEMORY_START: u64 = 0x10_0000;
+    // The identity mapping set up for the kernel entry covers the first GiB.
+    const MAX_MEM_SIZE: u64 = 1 << 30;
+    const TSS_ADDRESS: u64 = 0xfffb_d000;
+
+    const MMIO_BASE: u64 = 0xd000_0000;
+    const MMIO_LEN: u64 = 0x1000;
+    const BLOCK_IRQ: u32 = 5;
+    const SERIAL_IRQ: u32 = 4;
+
+    const SERIAL_PORT: u16 = 0x3f8;
+    const I8042_COMMAND_PORT: u16 = 0x64;
+    const I8042_RESET_CMD: u8 = 0xfe;
+
+    // The `boot_params` (zero page) fields, as offsets in the page.
+    const E820_ENTRIES_OFFSET: usize = 0x1e8;
+    const SETUP_SECTS_OFFSET: usize = 0x1f1;
+    const HEADER_MAGIC_OFFSET: usize = 0x202;
+    const TYPE_OF_LOADER_OFFSET: usize = 0x210;
+    const RAMDISK_IMAGE_OFFSET: usize = 0x218;
+    const RAMDISK_SIZE_OFFSET: usize = 0x21c;
+    const CMD_LINE_PTR_OFFSET: usize = 0x228;
+    const INITRD_ADDR_MAX_OFFSET: usize = 0x22c;
+    const XLOADFLAGS_OFFSET: usize = 0x236;
+    const E820_TABLE_OFFSET: usize = 0x2d0;
+    const HEADER_MAGIC: &[u8] = b"HdrS";
+    const XLF_KERNEL_64: u16 = 0x1;
+    const E820_RAM: u32 = 1;
+    // The 64-bit entry point is at this offset in the protected mode kernel.
+    const STARTUP_64_OFFSET: u64 = 0x200;
+
+    const X86_CR0_PE: u64 = 0x1;
+    const X86_CR0_PG: u64 = 0x8000_0000;
+    const X86_CR4_PAE: u64 = 0x20;
+    const EFER_LME: u64 = 0x100;
+    const EFER_LMA: u64 = 0x400;
+
+    const KVMIO: u32 = 0xae;
+    const KVM_API_VERSION: c_int = 12;
+    const KVM_MAX_CPUID_ENTRIES: usize = 256;
+    const KVM_EXIT_IO: u32 = 2;
+    const KVM_EXIT_HLT: u32 = 5;
+    const KVM_EXIT_MMIO: u32 = 6;
+    const KVM_EXIT_SHUTDOWN: u32 = 8;
+    const KVM_EXIT_IO_OUT: u8 = 1;
+
+    // The subset of the KVM uapi structures used by the VMM.
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct KvmUserspaceMemoryRegion {
+        slot: u32,
+        flags: u32,
+        guest_phys_addr: u64,
+        memory_size: u64,
+        userspace_addr: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct KvmPitConfig {
+        flags: u32,
+        pad: [u32; 15],
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct KvmIrqfd {
+        fd: u32,
+        gsi: u32,
+        flags: u32,
+        resamplefd: u32,
+        pad: [u8; 16],
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct KvmCpuidEntry2 {
+        function: u32,
+        index: u32,
+        flags: u32,
+        eax: u32,
+        ebx: u32,
+        ecx: u32,
+        edx: u32,
+        padding: [u32; 3],
+    }
+
+    // The header of `struct kvm_cpuid2`, whose size is encoded in the ioctl numbers.
+    #[repr(C)]
+    struct KvmCpuid2Header {
+        nent: u32,
+        padding: u32,
+    }
+
+    #[repr(C)]
+    struct KvmCpuid2 {
+        nent: u32,
+        padding: u32,
+        entries: [KvmCpuidEntry2; KVM_MAX_CPUID_ENTRIES],
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct KvmRegs {
+        rax: u64,
+        rbx: u64,
+        rcx: u64,
+        rdx: u64,
+        rsi: u64,
+        rdi: u64,
+        rsp: u64,
+        rbp: u64,
+        r8: u64,
+        r9: u64,
+        r10: u64,
+        r11: u64,
+        r12: u64,
+        r13: u64,
+        r14: u64,
+        r15: u64,
+        rip: u64,
+        rflags: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct KvmSegment {
+        base: u64,
+        limit: u32,
+        selector: u16,
+        type_: u8,
+        present: u8,
+        dpl: u8,
+        db: u8,
+        s: u8,
+        l: u8,
+        g: u8,
+        avl: u8,
+        unusable: u8,
+        padding: u8,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct KvmDtable {
+        base: u64,
+        limit: u16,
+        padding: [u16; 3],
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct KvmSregs {
+        cs: KvmSegment,
+        ds: KvmSegment,
+        es: KvmSegment,
+        fs: KvmSegment,
+        gs: KvmSegment,
+        ss: KvmSegment,
+        tr: KvmSegment,
+        ldt: KvmSegment,
+        gdt: KvmDtable,
+        idt: KvmDtable,
+        cr0: u64,
+        cr2: u64,
+        cr3: u64,
+        cr4: u64,
+        cr8: u64,
+        efer: u64,
+        apic_base: u64,
+        interrupt_bitmap: [u64; 4],
+    }
+
+    // The fixed part of `struct kvm_run`, followed by the exit information.
+    #[repr(C)]
+    struct KvmRun {
+        request_interrupt_window: u8,
+        immediate_exit: u8,
+        padding1: [u8; 6],
+        exit_reason: u32,
+        ready_for_interrupt_injection: u8,
+        if_flag: u8,
+        flags: u16,
+        cr8: u64,
+        apic_base: u64,
+        exit: KvmRunExit,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct KvmRunIo {
+        direction: u8,
+        size: u8,
+        port: u16,
+        count: u32,
+        data_offset: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct KvmRunMmio {
+        phys_addr: u64,
+        data: [u8; 8],
+        len: u32,
+        is_write: u8,
+    }
+
+    #[repr(C)]
+    union KvmRunExit {
+        io: KvmRunIo,
+        mmio: KvmRunMmio,
+        padding: [u8; 256],
+    }
+
+    #[repr(C)]
+    struct KvmIoeventfd {
+        datamatch: u64,
+        addr: u64,
+        len: u32,
+        fd: i32,
+        flags: u32,
+        pad: [u8; 36],
+    }
+
+    ioctl_io_nr!(KVM_GET_API_VERSION, KVMIO, 0x00);
+    ioctl_io_nr!(KVM_CREATE_VM, KVMIO, 0x01);
+    ioctl_io_nr!(KVM_GET_VCPU_MMAP_SIZE, KVMIO, 0x04);
+    ioctl_iowr_nr!(KVM_GET_SUPPORTED_CPUID, KVMIO, 0x05, KvmCpuid2Header);
+    ioctl_io_nr!(KVM_CREATE_VCPU, KVMIO, 0x41);
+    ioctl_iow_nr!(
+        KVM_SET_USER_MEMORY_REGION,
+        KVMIO,
+        0x46,
+        KvmUserspaceMemoryRegion
+    );
+    ioctl_io_nr!(KVM_SET_TSS_ADDR, KVMIO, 0x47);
+    ioctl_io_nr!(KVM_CREATE_IRQCHIP, KVMIO, 0x60);
+    ioctl_iow_nr!(KVM_IRQFD, KVMIO, 0x76, KvmIrqfd);
+    ioctl_iow_nr!(KVM_CREATE_PIT2, KVMIO, 0x77, KvmPitConfig);
+    ioctl_iow_nr!(KVM_IOEVENTFD, KVMIO, 0x79, KvmIoeventfd);
+    ioctl_io_nr!(KVM_RUN, KVMIO, 0x80);
+    ioctl_iow_nr!(KVM_SET_REGS, KVMIO, 0x82, KvmRegs);
+    ioctl_ior_nr!(KVM_GET_SREGS, KVMIO, 0x83, KvmSregs);
+    ioctl_iow_nr!(KVM_SET_SREGS, KVMIO, 0x84, KvmSregs);
+    ioctl_iow_nr!(KVM_SET_CPUID2, KVMIO, 0x90, KvmCpuid2Header);
+
+    // Turns the return value of an ioctl into a `Result`.
+    fn check(ret: c_int) -> io::Result<c_int> {
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret)
+        }
+    }
+
+    // The command line options of the VMM.
+    struct Options {
+        kernel: String,
+        initrd: String,
+        image: String,
+        read_only: bool,
+        mem_size: u64,
+        cmdline: String,
+        expect: Option<String>,
+    }
+
+    fn parse_options() -> Result<Options, String> {
+        let (mut kernel, mut initrd, mut image) = (None, None, None);
+        let mut options = Options {
+            kernel: String::new(),
+            initrd: String::new(),
+            image: String::new(),
+            read_only: false,
+            mem_size: 256 << 20,
+            cmdline: DEFAULT_CMDLINE.to_owned(),
+            expect: None,
+        };
+
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--kernel" => kernel = args.next(),
+                "--initrd" => initrd = args.next(),
+                "--image" => image = args.next(),
+                "--read-only" => options.read_only = true,
+                "--mem-size" => {
+                    options.mem_size = args
+                        .next()
+                        .and_then(|size| size.parse::<u64>().ok())
+                        .map(|size| size << 20)
+                        .filter(|size| (HIGH_MEMORY_START * 2..=MAX_MEM_SIZE).contains(size))
+                        .ok_or("invalid memory size")?;
+                }
+                "--cmdline" => options.cmdline = args.next().ok_or("missing command line")?,
+                "--expect" => options.expect = args.next(),
+                _ => return Err(format!("unknown argument {}", arg)),
+            }
+        }
+        options.kernel = kernel.ok_or("missing kernel path")?;
+        options.initrd = initrd.ok_or("missing initrd path")?;
+        options.image = image.ok_or("missing image path")?;
+        Ok(options)
+    }
+
+    // A minimal 8250 UART, which is enough for the guest console.
+    struct Serial {
+        // The registers, indexed by their offset from the base port.
+        regs: [u8; 8],
+        divisor: [u8; 2],
+        // Whether the "transmitter holding register empty" interrupt is pending.
+        thr_empty: bool,
+        irq: EventFd,
+        output: Vec<u8>,
+    }
+
+    const UART_IER: usize = 1;
+    const UART_IIR: usize = 2;
+    const UART_LCR: usize = 3;
+    const UART_MCR: usize = 4;
+    const UART_LSR: usize = 5;
+    const UART_MSR: usize = 6;
+    const UART_IER_THRI: u8 = 0x02;
+    const UART_IIR_NO_INT: u8 = 0x01;
+    const UART_IIR_THRI: u8 = 0x02;
+    const UART_LCR_DLAB: u8 = 0x80;
+    const UART_MCR_LOOP: u8 = 0x10;
+    // THR and the transmitter are always empty.
+    const UART_LSR_IDLE: u8 = 0x60;
+    // DCD, DSR and CTS are always set.
+    const UART_MSR_IDLE: u8 = 0xb0;
+
+    impl Serial {
+        fn new(irq: EventFd) -> Self {
+            Serial {
+                regs: [0; 8],
+                divisor: [0; 2],
+                thr_empty: false,
+                irq,
+                output: Vec::new(),
+            }
+        }
+
+        fn dlab(&self) -> bool {
+            self.regs[UART_LCR] & UART_LCR_DLAB != 0
+        }
+
+        fn signal_thr_empty(&mut self) {
+            if self.regs[UART_IER] & UART_IER_THRI != 0 {
+                self.thr_empty = true;
+                if let Err(e) = self.irq.write(1) {
+                    eprintln!("failed to inject the serial interrupt: {}", e);
+                }
+            }
+        }
+
+        fn read(&mut self, offset: usize) -> u8 {
+            match offset {
+                0 | 1 if self.dlab() => self.divisor[offset],
+                // There's no input.
+                0 => 0,
+                UART_IIR if self.thr_empty => {
+                    self.thr_empty = false;
+                    UART_IIR_THRI
+                }
+                UART_IIR => UART_IIR_NO_INT,
+                UART_LSR => UART_LSR_IDLE,
+                UART_MSR if self.regs[UART_MCR] & UART_MCR_LOOP != 0 => {
+                    // In loopback mode, DTR, RTS, OUT1 and OUT2 drive DSR, CTS, RI and DCD.
+                    let mcr = self.regs[UART_MCR];
+                    ((mcr & 0x01) << 5) | ((mcr & 0x02) << 3) | ((mcr & 0x0c) << 4)
+                }
+                UART_MSR => UART_MSR_IDLE,
+                _ => self.regs[offset],
+            }
+        }
+
+        fn write(&mut self, offset: usize, value: u8) {
+            match offset {
+                0 | 1 if self.dlab() => self.divisor[offset] = value,
+                0 => {
+                    self.output.push(value);
+                    let mut stdout = io::stdout();
+                    // The console output is best effort.
+                    let _ = stdout.write_all(&[value]).and_then(|_| stdout.flush());
+                    self.signal_thr_empty();
+                }
+                UART_IER => {
+                    self.regs[UART_IER] = value & 0x0f;
+                    self.signal_thr_empty();
+                }
+                // The FIFO control register is ignored, the UART has no FIFOs.
+                UART_IIR => {}
+                _ => self.regs[offset] = value,
+            }
+        }
+    }
+
+    type BlockDevice = Block<Arc<GuestMemoryMmap>, SharedFile, EventFd>;
+
+    struct Vm {
+        fd: File,
+    }
+
+    impl Vm {
+        fn new(kvm: &File) -> io::Result<Self> {
+            // Safe because the ioctl doesn't take any arguments, and we check the return value.
+            let ret = unsafe { ioctl(kvm, KVM_GET_API_VERSION()) };
+            if check(ret)? != KVM_API_VERSION {
+                return Err(io::Error::other("unsupported KVM API version"));
+            }
+            // Safe because the ioctl doesn't take any arguments, and we check the return value.
+            let fd = check(unsafe { ioctl(kvm, KVM_CREATE_VM()) })?;
+            // Safe because we own the new file descriptor.
+            let vm = Vm {
+                fd: unsafe { File::from_raw_fd(fd) },
+            };
+
+            // Safe because the ioctls only take integer arguments, or objects which live for
+            // the duration of the call, and we check the return values.
+            unsafe {
+                check(ioctl_with_val(&vm.fd, KVM_SET_TSS_ADDR(), TSS_ADDRESS))?;
+                check(ioctl(&vm.fd, KVM_CREATE_IRQCHIP()))?;
+                check(ioctl_with_ref(
+                    &vm.fd,
+                    KVM_CREATE_PIT2(),
+                    &KvmPitConfig::default(),
+                ))?;
+            }
+            Ok(vm)
+        }
+
+        fn set_memory(&self, mem: &GuestMemoryMmap) -> io::Result<()> {
+            for (slot, region) in mem.iter().enumerate() {
+                let userspace_addr = mem
+                    .get_host_address(region.start_addr())
+                    .map_err(io::Error::other)?;
+                let region = KvmUserspaceMemoryRegion {
+                    slot: slot as u32,
+                    flags: 0,
+                    guest_phys_addr: region.start_addr().0,
+                    memory_size: region.len(),
+                    userspace_addr: userspace_addr as u64,
+                };
+                // Safe because the memory region stays mapped for the lifetime of the VM, and
+                // we check the return value.
+                check(unsafe { ioctl_with_ref(&self.fd, KVM_SET_USER_MEMORY_REGION(), &region) })?;
+            }
+            Ok(())
+        }
+
+        fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> io::Result<()> {
+            let irqfd = KvmIrqfd {
+                fd: fd.as_raw_fd() as u32,
+                gsi,
+                ..Default::default()
+            };
+            // Safe because the argument lives for the duration of the call, and we check the
+            // return value.
+            check(unsafe { ioctl_with_ref(&self.fd, KVM_IRQFD(), &irqfd) }).map(|_| ())
+        }
+    }
+
+    // The block device is the only one registering `EventFd`s through the context.
+    impl EventsContext for Vm {
+        type E = io::Error;
+
+        fn register_ioeventfd(&mut self, queue: u16, fd: &EventFd) -> io::Result<()> {
+            const KVM_IOEVENTFD_FLAG_DATAMATCH: u32 = 1;
+            // Offset of the `QueueNotify` register.
+            const QUEUE_NOTIFY_OFFSET: u64 = 0x50;
+
+            let ioeventfd = KvmIoeventfd {
+                datamatch: u64::from(queue),
+                addr: MMIO_BASE + QUEUE_NOTIFY_OFFSET,
+                len: 4,
+                fd: fd.as_raw_fd(),
+                flags: KVM_IOEVENTFD_FLAG_DATAMATCH,
+                pad: [0; 36],
+            };
+            // Safe because the argument lives for the duration of the call, and we check the
+            // return value.
+            check(unsafe { ioctl_with_ref(&self.fd, KVM_IOEVENTFD(), &ioeventfd) }).map(|_| ())
+        }
+
+        fn register_irqfd(&mut self, fd: &EventFd) -> io::Result<()> {
+            Vm::register_irqfd(self, fd, BLOCK_IRQ)
+        }
+    }
+
+    struct Vcpu {
+        fd: File,
+        run: *mut KvmRun,
+    }
+
+    impl Vcpu {
+        fn new(kvm: &File, vm: &Vm) -> io::Result<Self> {
+            // Safe because the ioctls only take integer arguments, and we check the return
+            // values.
+            let fd = check(unsafe { ioctl_with_val(&vm.fd, KVM_CREATE_VCPU(), 0) })?;
+            // Safe because we own the new file descriptor.
+            let fd = unsafe { File::from_raw_fd(fd) };
+            let run_size = check(unsafe { ioctl(kvm, KVM_GET_VCPU_MMAP_SIZE()) })? as usize;
+            // Safe because we map a new shared region, and check the result. The mapping is
+            // never unmapped, since the process exits along with the vCPU.
+            let run = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    run_size,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED,
+                    fd.as_raw_fd(),
+                    0,
+                )
+            };
+            if run == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+
+            let vcpu = Vcpu {
+                fd,
+                run: run as *mut KvmRun,
+            };
+            vcpu.set_cpuid(kvm)?;
+            Ok(vcpu)
+        }
+
+        fn set_cpuid(&self, kvm: &File) -> io::Result<()> {
+            let mut cpuid = Box::new(KvmCpuid2 {
+                nent: KVM_MAX_CPUID_ENTRIES as u32,
+                padding: 0,
+                entries: [KvmCpuidEntry2::default(); KVM_MAX_CPUID_ENTRIES],
+            });
+            // Safe because the kernel doesn't write more than `nent` entries, and we check the
+            // return values.
+            unsafe {
+                check(ioctl_with_mut_ref(
+                    kvm,
+                    KVM_GET_SUPPORTED_CPUID(),
+                    &mut *cpuid,
+                ))?;
+                check(ioctl_with_ref(&self.fd, KVM_SET_CPUID2(), &*cpuid))?;
+            }
+            Ok(())
+        }
+
+        // Sets up the vCPU to start at the 64-bit entry point of the kernel.
+        fn setup_long_mode(&self, mem: &GuestMemoryMmap, entry: u64) -> io::Result<()> {
+            let write_u64 = |value: u64, addr: u64| {
+                mem.write_obj(value, GuestAddress(addr))
+                    .map_err(io::Error::other)
+            };
+
+            // Identity map the first GiB with 2 MiB pages.
+            write_u64(PDPT_START | 0x03, PML4_START)?;
+            write_u64(PD_START | 0x03, PDPT_START)?;
+            for i in 0..512 {
+                write_u64((i << 21) | 0x83, PD_START + i * 8)?;
+            }
+
+            // The null, code, data and TSS descriptors, with the kernel expecting the code and
+            // data selectors at 0x10 and 0x18.
+            let gdt = [
+                0,
+                0,
+                0x00af_9b00_0000_ffff,
+                0x00cf_9300_0000_ffff,
+                0x008f_8b00_0000_ffff,
+            ];
+            for (i, entry) in gdt.iter().enumerate() {
+                write_u64(*entry, GDT_START + i as u64 * 8)?;
+            }
+            write_u64(0, IDT_START)?;
+
+            let mut sregs = KvmSregs::default();
+            // Safe because the kernel only writes the argument, and we check the return value.
+            check(unsafe { ioctl_with_mut_ref(&self.fd, KVM_GET_SREGS(), &mut sregs) })?;
+            let segment = |selector: u16, type_: u8| KvmSegment {
+                base: 0,
+                limit: 0xf_ffff,
+                selector,
+                type_,
+                present: 1,
+                dpl: 0,
+                db: u8::from(type_ == 0x3),
+                s: u8::from(selector != 0x20),
+                l: u8::from(type_ == 0xb && selector == 0x10),
+                g: 1,
+                ..Default::default()
+            };
+            let data = segment(0x18, 0x3);
+            sregs.cs = segment(0x10, 0xb);
+            sregs.ds = data;
+            sregs.es = data;
+            sregs.fs = data;
+            sregs.gs = data;
+            sregs.ss = data;
+            sregs.tr = segment(0x20, 0xb);
+            sregs.gdt.base = GDT_START;
+            sregs.gdt.limit = (gdt.len() * 8 - 1) as u16;
+            sregs.idt.base = IDT_START;
+            sregs.idt.limit = 7;
+            sregs.cr3 = PML4_START;
+            sregs.cr4 |= X86_CR4_PAE;
+            sregs.cr0 |= X86_CR0_PE | X86_CR0_PG;
+            sregs.efer |= EFER_LME | EFER_LMA;
+
+            let regs = KvmRegs {
+                rflags: 0x2,
+                rip: entry,
+                rsp: BOOT_STACK_POINTER,
+                rbp: BOOT_STACK_POINTER,
+                rsi: ZERO_PAGE_START,
+                ..Default::default()
+            };
+            // Safe because the arguments live for the duration of the calls, and we check the
+            // return values.
+            unsafe {
+                check(ioctl_with_ref(&self.fd, KVM_SET_SREGS(), &sregs))?;
+                check(ioctl_with_ref(&self.fd, KVM_SET_REGS(), &regs))?;
+            }
+            Ok(())
+        }
+
+        // Runs the vCPU until the guest reboots.
+        fn run(&self, serial: &mut Serial, block: &mut BlockDevice) -> io::Result<()> {
+            loop {
+                // Safe because the ioctl doesn't take any arguments, and we check the return
+                // value.
+                if let Err(e) = check(unsafe { ioctl(&self.fd, KVM_RUN()) }) {
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(e);
+                }
+
+                // Safe because `kvm_run` stays mapped, and the kernel doesn't change it until
+                // the next `KVM_RUN`.
+                let run = unsafe { &mut *self.run };
+                match run.exit_reason {
+                    KVM_EXIT_IO => {
+                        // Safe because the kernel filled in the `io` member for this exit.
+                        let exit = unsafe { run.exit.io };
+                        let len = usize::from(exit.size) * exit.count as usize;
+                        // Safe because the data lies in the `kvm_run` mapping.
+                        let data = unsafe {
+                            std::slice::from_raw_parts_mut(
+                                (self.run as *mut u8).add(exit.data_offset as usize),
+                                len,
+                            )
+                        };
+                        if exit.direction == KVM_EXIT_IO_OUT {
+                            if handle_io_out(serial, exit.port, data) {
+                                return Ok(());
+                            }
+                        } else {
+                            handle_io_in(serial, exit.port, data);
+                        }
+                    }
+                    KVM_EXIT_MMIO => {
+                        // Safe because the kernel filled in the `mmio` member for this exit.
+                        let exit = unsafe { &mut run.exit.mmio };
+                        let len = (exit.len as usize).min(exit.data.len());
+                        let data = &mut exit.data[..len];
+                        match exit.phys_addr.checked_sub(MMIO_BASE) {
+                            Some(offset) if offset < MMIO_LEN => {
+                                if exit.is_write != 0 {
+                                    block.write(offset, data);
+                                } else {
+                                    block.read(offset, data);
+                                }
+                            }
+                            _ if exit.is_write == 0 => data.iter_mut().for_each(|b| *b = 0xff),
+                            _ => {}
+                        }
+                    }
+                    KVM_EXIT_HLT | KVM_EXIT_SHUTDOWN => return Ok(()),
+                    reason => {
+                        return Err(io::Error::other(format!(
+                            "unexpected vcpu exit reason {}",
+                            reason
+                        )))
+                    }
+                }
+            }
+        }
+    }
+
+    // Handles a port write, and returns whether the guest requested a reset.
+    fn handle_io_out(serial: &mut Serial, port: u16, data: &[u8]) -> bool {
+        match port {
+            SERIAL_PORT..=0x3ff => {
+                for value in data {
+                    serial.write(usize::from(port - SERIAL_PORT), *value);
+                }
+                false
+            }
+            I8042_COMMAND_PORT => data.first() == Some(&I8042_RESET_CMD),
+            _ => false,
+        }
+    }
+
+    fn handle_io_in(serial: &mut Serial, port: u16, data: &mut [u8]) {
+        for value in data.iter_mut() {
+            *value = match port {
+                SERIAL_PORT..=0x3ff => serial.read(usize::from(port - SERIAL_PORT)),
+                // The controller is always ready for the reset command.
+                I8042_COMMAND_PORT => 0,
+                _ => 0xff,
+            };
+        }
+    }
+
+    // Loads the `bzImage` at `kernel` and the initrd, and fills in the zero page. Returns the
+    // kernel entry point.
+    fn load_kernel(
+        mem: &GuestMemoryMmap,
+        mem_size: u64,
+        kernel: &[u8],
+        initrd: &[u8],
+        cmdline: &str,
+    ) -> Result<u64, String> {
+        let invalid = || "invalid bzImage".to_owned();
+        let header_end = kernel
+            .get(HEADER_MAGIC_OFFSET + 1)
+            .map(|len| HEADER_MAGIC_OFFSET + 2 + usize::from(*len))
+            .filter(|end| *end <= kernel.len())
+            .ok_or_else(invalid)?;
+        if &kernel[HEADER_MAGIC_OFFSET..HEADER_MAGIC_OFFSET + 4] != HEADER_MAGIC {
+            return Err(invalid());
+        }
+        let xloadflags = u16::from_le_bytes(
+            kernel[XLOADFLAGS_OFFSET..XLOADFLAGS_OFFSET + 2]
+                .try_into()
+                .unwrap(),
+        );
+        if xloadflags & XLF_KERNEL_64 == 0 {
+            return Err("the kernel doesn't support the 64-bit boot protocol".to_owned());
+        }
+
+        // The setup code is skipped, only the protected mode kernel is loaded.
+        let setup_sects = match kernel[SETUP_SECTS_OFFSET] {
+            0 => 4,
+            sects => usize::from(sects),
+        };
+        let kernel_start = (setup_sects + 1) * 512;
+        let protected_mode = kernel.get(kernel_start..).ok_or_else(invalid)?;
+        let guest_err = |e: vm_memory::GuestMemoryError| e.to_string();
+        mem.write_slice(protected_mode, GuestAddress(HIGH_MEMORY_START))
+            .map_err(guest_err)?;
+
+        let mut zero_page = vec![0u8; 0x1000];
+        zero_page[SETUP_SECTS_OFFSET..header_end]
+            .copy_from_slice(&kernel[SETUP_SECTS_OFFSET..header_end]);
+        zero_page[TYPE_OF_LOADER_OFFSET] = 0xff;
+
+        // The initrd goes at the end of the memory, below the highest address the kernel
+        // supports.
+        let initrd_addr_max = u32::from_le_bytes(
+            zero_page[INITRD_ADDR_MAX_OFFSET..INITRD_ADDR_MAX_OFFSET + 4]
+                .try_into()
+                .unwrap(),
+        );
+        let initrd_end = mem_size.min(u64::from(initrd_addr_max) + 1);
+        let initrd_start = initrd_end
+            .checked_sub(initrd.len() as u64)
+            .map(|start| start & !0xfff)
+            .filter(|start| *start >= HIGH_MEMORY_START + protected_mode.len() as u64)
+            .ok_or("the initrd doesn't fit in guest memory")?;
+        mem.write_slice(initrd, GuestAddress(initrd_start))
+            .map_err(guest_err)?;
+        zero_page[RAMDISK_IMAGE_OFFSET..RAMDISK_IMAGE_OFFSET + 4]
+            .copy_from_slice(&(initrd_start as u32).to_le_bytes());
+        zero_page[RAMDISK_SIZE_OFFSET..RAMDISK_SIZE_OFFSET + 4]
+            .copy_from_slice(&(initrd.len() as u32).to_le_bytes());
+
+        let cmdline = format!(
+            "{} virtio_mmio.device=4K@0x{:x}:{}\0",
+            cmdline, MMIO_BASE, BLOCK_IRQ
+        );
+        if cmdline.len() > CMDLINE_MAX_SIZE {
+            return Err("the kernel command line is too long".to_owned());
+        }
+        mem.write_slice(cmdline.as_bytes(), GuestAddress(CMDLINE_START))
+            .map_err(guest_err)?;
+        zero_page[CMD_LINE_PTR_OFFSET..CMD_LINE_PTR_OFFSET + 4]
+            .copy_from_slice(&(CMDLINE_START as u32).to_le_bytes());
+
+        // The low memory up to the EBDA, and everything above 1 MiB, are usable.
+        let e820 = [
+            (0, EBDA_START),
+            (HIGH_MEMORY_START, mem_size - HIGH_MEMORY_START),
+        ];
+        zero_page[E820_ENTRIES_OFFSET] = e820.len() as u8;
+        for (i, (addr, size)) in e820.iter().enumerate() {
+            let entry = E820_TABLE_OFFSET + i * 20;
+            zero_page[entry..entry + 8].copy_from_slice(&addr.to_le_bytes());
+            zero_page[entry + 8..entry + 16].copy_from_slice(&size.to_le_bytes());
+            zero_page[entry + 16..entry + 20].copy_from_slice(&E820_RAM.to_le_bytes());
+        }
+        mem.write_slice(&zero_page, GuestAddress(ZERO_PAGE_START))
+            .map_err(guest_err)?;
+
+        Ok(HIGH_MEMORY_START + STARTUP_64_OFFSET)
+    }
+
+    fn setup(options: &Options) -> Result<(Vm, Vcpu, Serial, BlockDevice), String> {
+        let kernel = fs::read(&options.kernel)
+            .map_err(|e| format!("failed to read {}: {}", options.kernel, e))?;
+        let initrd = fs::read(&options.initrd)
+            .map_err(|e| format!("failed to read {}: {}", options.initrd, e))?;
+        let file = SharedFile::open(&options.image, options.read_only, CacheMode::Cached)
+            .map_err(|e| format!("failed to open {}: {}", options.image, e))?;
+
+        let kvm = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/kvm")
+            .map_err(|e| format!("failed to open /dev/kvm: {}", e))?;
+        let kvm_err = |e: io::Error| format!("failed to set up the VM: {}", e);
+        let mut vm = Vm::new(&kvm).map_err(kvm_err)?;
+
+        let mem = Arc::new(
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), options.mem_size as usize)])
+                .map_err(|e| format!("failed to allocate guest memory: {}", e))?,
+        );
+        vm.set_memory(&mem).map_err(kvm_err)?;
+        let entry = load_kernel(&mem, options.mem_size, &kernel, &initrd, &options.cmdline)?;
+
+        let vcpu = Vcpu::new(&kvm, &vm).map_err(kvm_err)?;
+        vcpu.setup_long_mode(&mem, entry).map_err(kvm_err)?;
+
+        let serial_irq = EventFd::new(EFD_NONBLOCK).map_err(kvm_err)?;
+        vm.register_irqfd(&serial_irq, SERIAL_IRQ)
+            .map_err(kvm_err)?;
+        let serial = Serial::new(serial_irq);
+
+        let block_irq = EventFd::new(EFD_NONBLOCK).map_err(kvm_err)?;
+        let block = BlockBuilder::new(mem, file, block_irq)
+            .with_read_only(options.read_only)
+            .build()
+            .map_err(|e| format!("failed to create the device: {}", e))?;
+        block.reattach(&mut vm).map_err(kvm_err)?;
+
+        Ok((vm, vcpu, serial, block))
+    }
+
+    pub fn main() {
+        let options = parse_options().unwrap_or_else(|e| {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(1);
+        });
+        // The VM file descriptor is kept open while the guest runs.
+        let (_vm, vcpu, mut serial, mut block) = setup(&options).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        });
+
+        if let Err(e) = vcpu.run(&mut serial, &mut block) {
+            eprintln!("failed to run the guest: {}", e);
+            process::exit(1);
+        }
+        if let Some(expect) = options.expect {
+            let output = String::from_utf8_lossy(&serial.output);
+            if !output.contains(expect.as_str()) {
+                eprintln!("the guest didn't print {:?}", expect);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn main() {
+    vmm::main();
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn main() {
+    eprintln!("mmio_vmm only supports x86_64 hosts");
+    std::process::exit(1);
+}
@@ -0,0 +1,92 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A vhost-user-blk daemon which serves a raw image to any VMM that supports vhost-user.
+//!
+//! ```text
+//! cargo run -p virtio-blk --features vhost-user-backend --example vhost_user_blk -- \
+//!     --socket /tmp/vhost-user-blk.sock --image disk.raw [--read-only] [--direct] \
+//!     [--num-queues N]
+//! ```
+
+use std::env;
+use std::process;
+
+use virtio_blk::shared_file::{CacheMode, SharedFile};
+use virtio_blk::vhost_user_backend::VhostUserBlkBuilder;
+use virtio_device::vhost_user_backend::VhostUserListener;
+
+const USAGE: &str = "usage: vhost_user_blk --socket <path> --image <path> [--read-only] \
+                     [--direct] [--num-queues <count>]";
+
+// The command line options of the daemon.
+struct Options {
+    socket: String,
+    image: String,
+    read_only: bool,
+    cache_mode: CacheMode,
+    num_queues: u16,
+}
+
+fn parse_options() -> Result<Options, String> {
+    let (mut socket, mut image) = (None, None);
+    let mut options = Options {
+        socket: String::new(),
+        image: String::new(),
+        read_only: false,
+        cache_mode: CacheMode::Cached,
+        num_queues: 1,
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--socket" => socket = args.next(),
+            "--image" => image = args.next(),
+            "--read-only" => options.read_only = true,
+            "--direct" => options.cache_mode = CacheMode::Direct,
+            "--num-queues" => {
+                options.num_queues = args
+                    .next()
+                    .and_then(|count| count.parse().ok())
+                    .ok_or("invalid number of queues")?;
+            }
+            _ => return Err(format!("unknown argument {}", arg)),
+        }
+    }
+    options.socket = socket.ok_or("missing socket path")?;
+    options.image = image.ok_or("missing image path")?;
+    Ok(options)
+}
+
+fn main() {
+    let options = parse_options().unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, USAGE);
+        process::exit(1);
+    });
+
+    let file = SharedFile::open(&options.image, options.read_only, options.cache_mode)
+        .unwrap_or_else(|e| {
+            eprintln!("failed to open {}: {}", options.image, e);
+            process::exit(1);
+        });
+    let blk = VhostUserBlkBuilder::new(file)
+        .with_num_queues(options.num_queues)
+        .with_read_only(options.read_only)
+        .with_writeback(true)
+        .build()
+        .unwrap_or_else(|e| {
+            eprintln!("failed to create the device: {}", e);
+            process::exit(1);
+        });
+
+    let listener = VhostUserListener::new(&options.socket).unwrap_or_else(|e| {
+        eprintln!("failed to listen on {}: {}", options.socket, e);
+        process::exit(1);
+    });
+    if let Err(e) = blk.serve(&listener) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
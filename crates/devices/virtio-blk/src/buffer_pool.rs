@@ -0,0 +1,246 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A pool of aligned buffers for bouncing request data.
+//!
+//! The data of a request can't always be transferred between the backend and the guest buffers
+//! directly: the files opened with `O_DIRECT` require buffers aligned to the logical block size
+//! of the storage, and the guest buffers which span multiple memory regions can't be passed to
+//! a single vectored I/O call. The data of such requests goes through an intermediate (bounce)
+//! buffer instead.
+//!
+//! [`BufferPool`](struct.BufferPool.html) allocates a fixed number of bounce buffers upfront,
+//! with the requested alignment, and touches all their pages, so the data path doesn't allocate
+//! memory or take page faults. The pool can be cloned and shared between the executors of
+//! multiple queues, and each buffer goes back to the pool when the
+//! [`PooledBuffer`](struct.PooledBuffer.html) which holds it is dropped.
+
+use std::alloc::{self, Layout};
+use std::fmt;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::ptr::{self, NonNull};
+use std::slice;
+use std::sync::{Arc, Mutex};
+
+// A heap allocation with a custom alignment.
+struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+// Safe because the buffer owns its allocation, which is only accessed through `&self` or
+// `&mut self`.
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    fn new(layout: Layout, page_size: usize) -> io::Result<Self> {
+        // Safe because the layout has a non-zero size, which the caller checked.
+        let ptr = NonNull::new(unsafe { alloc::alloc_zeroed(layout) })
+            .ok_or_else(|| io::Error::from(io::ErrorKind::OutOfMemory))?;
+        // The zeroed pages might not be mapped yet; write to each of them, so they don't have
+        // to be faulted in while processing requests.
+        for offset in (0..layout.size()).step_by(page_size) {
+            // Safe because `offset` is within the allocation.
+            unsafe { ptr::write_volatile(ptr.as_ptr().add(offset), 0) };
+        }
+        Ok(AlignedBuffer { ptr, layout })
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // Safe because the allocation is `layout.size()` bytes long, and initialized.
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // Safe because the allocation is `layout.size()` bytes long, and initialized.
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // Safe because the allocation was made with the same layout.
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) };
+    }
+}
+
+/// A fixed set of pre-allocated buffers which share the same length and alignment.
+#[derive(Clone)]
+pub struct BufferPool {
+    /// The buffers which are not in use.
+    free: Arc<Mutex<Vec<AlignedBuffer>>>,
+    /// The layout of each buffer.
+    layout: Layout,
+}
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("buffer_len", &self.buffer_len())
+            .field("alignment", &self.alignment())
+            .field("available", &self.available())
+            .finish()
+    }
+}
+
+impl BufferPool {
+    /// Allocates `count` buffers of `buffer_len` bytes, which start at an address that's a
+    /// multiple of `alignment`.
+    ///
+    /// # Arguments
+    /// * `count` - The number of buffers, which bounds the number of requests that can be
+    ///   bounced at the same time.
+    /// * `buffer_len` - The length of each buffer, which bounds the data length of the requests
+    ///   that can be bounced.
+    /// * `alignment` - The alignment of the buffers, which has to be a power of two (i.e. the
+    ///   logical block size for `O_DIRECT`).
+    pub fn new(count: usize, buffer_len: usize, alignment: usize) -> io::Result<Self> {
+        if buffer_len == 0 {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let layout = Layout::from_size_align(buffer_len, alignment)
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        // Safe because `sysconf` has no side effects.
+        let page_size = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+            page_size if page_size > 0 => page_size as usize,
+            _ => return Err(io::Error::last_os_error()),
+        };
+        let free = (0..count)
+            .map(|_| AlignedBuffer::new(layout, page_size))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(BufferPool {
+            free: Arc::new(Mutex::new(free)),
+            layout,
+        })
+    }
+
+    /// Returns the length of each buffer.
+    pub fn buffer_len(&self) -> usize {
+        self.layout.size()
+    }
+
+    /// Returns the alignment of the buffers.
+    pub fn alignment(&self) -> usize {
+        self.layout.align()
+    }
+
+    /// Returns the number of buffers which are not in use.
+    pub fn available(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+
+    /// Takes a buffer from the pool, or returns `None` if all of them are in use. The buffer
+    /// goes back to the pool when the returned object is dropped.
+    pub fn get(&self) -> Option<PooledBuffer> {
+        let buffer = self.free.lock().unwrap().pop()?;
+        Some(PooledBuffer {
+            buffer: Some(buffer),
+            free: self.free.clone(),
+        })
+    }
+}
+
+/// A buffer taken from a [`BufferPool`](struct.BufferPool.html), which dereferences to its
+/// bytes. The contents are whatever the previous user left in it.
+pub struct PooledBuffer {
+    /// The buffer, which is only `None` while it's returned to the pool.
+    buffer: Option<AlignedBuffer>,
+    /// The buffers of the pool which are not in use.
+    free: Arc<Mutex<Vec<AlignedBuffer>>>,
+}
+
+impl fmt::Debug for PooledBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PooledBuffer")
+            .field("ptr", &self.as_ptr())
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // The buffer is only taken out when `self` is dropped.
+        self.buffer.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.buffer.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            // A poisoned lock means the pool is going away anyway.
+            if let Ok(mut free) = self.free.lock() {
+                free.push(buffer);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_buffer_pool() {
+        assert_eq!(
+            BufferPool::new(1, 0, 512).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            BufferPool::new(1, 0x1000, 3).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+
+        let pool = BufferPool::new(2, 0x3000, 0x1000).unwrap();
+        assert_eq!(pool.buffer_len(), 0x3000);
+        assert_eq!(pool.alignment(), 0x1000);
+        assert_eq!(pool.available(), 2);
+
+        let mut first = pool.get().unwrap();
+        assert_eq!(first.len(), 0x3000);
+        assert_eq!(first.as_ptr() as usize % 0x1000, 0);
+        assert!(first.iter().all(|&b| b == 0));
+        first[0x2fff] = 0xaa;
+
+        // The clones share the buffers.
+        let second = thread::spawn({
+            let pool = pool.clone();
+            move || pool.get().unwrap()
+        })
+        .join()
+        .unwrap();
+        assert_ne!(first.as_ptr(), second.as_ptr());
+        assert_eq!(pool.available(), 0);
+        assert!(pool.get().is_none());
+
+        // The buffers are reused as they are.
+        let ptr = first.as_ptr();
+        drop(first);
+        assert_eq!(pool.available(), 1);
+        let first = pool.get().unwrap();
+        assert_eq!(first.as_ptr(), ptr);
+        assert_eq!(first[0x2fff], 0xaa);
+
+        drop((first, second));
+        assert_eq!(pool.available(), 2);
+    }
+}
@@ -0,0 +1,622 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Virtio block device configuration space abstraction.
+//!
+//! This module provides the following abstractions:
+//!
+//! - [`ConfigSpace`](struct.ConfigSpace.html) which mirrors the `virtio_blk_config` structure
+//!   from the virtio specification, and can be converted to the byte representation expected by
+//!   `VirtioConfig`.
+//! - [`ConfigBuilder`](struct.ConfigBuilder.html) which populates a `ConfigSpace` and keeps
+//!   track of the feature bits that have to be advertised by the device so the driver actually
+//!   looks at the fields that were set.
+
+use std::convert::TryFrom;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io;
+use std::mem::{offset_of, size_of};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::result;
+
+use vm_memory::ByteValued;
+use vmm_sys_util::ioctl::ioctl_with_mut_ref;
+
+use crate::defs::{
+    SECTOR_SIZE, VIRTIO_BLK_F_BLK_SIZE, VIRTIO_BLK_F_CONFIG_WCE, VIRTIO_BLK_F_DISCARD,
+    VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_MQ, VIRTIO_BLK_F_SEG_MAX, VIRTIO_BLK_F_SIZE_MAX,
+    VIRTIO_BLK_F_TOPOLOGY, VIRTIO_BLK_F_WRITE_ZEROES,
+};
+
+// The request header and the status descriptors are always present in a chain, so at most
+// `queue_size - 2` descriptors can point to data buffers.
+pub(crate) const NON_DATA_DESCRIPTORS: u16 = 2;
+
+// Block device ioctls used for retrieving the I/O topology (see `include/uapi/linux/fs.h`).
+mod blk_ioctls {
+    use vmm_sys_util::ioctl_io_nr;
+
+    const BLK_IOCTL_TYPE: u32 = 0x12;
+    ioctl_io_nr!(BLKIOMIN, BLK_IOCTL_TYPE, 120);
+    ioctl_io_nr!(BLKIOOPT, BLK_IOCTL_TYPE, 121);
+    ioctl_io_nr!(BLKALIGNOFF, BLK_IOCTL_TYPE, 122);
+    ioctl_io_nr!(BLKPBSZGET, BLK_IOCTL_TYPE, 123);
+}
+use blk_ioctls::{BLKALIGNOFF, BLKIOMIN, BLKIOOPT, BLKPBSZGET};
+
+/// Block configuration space building errors.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The block size is not a power of 2, or is smaller than the sector size.
+    InvalidBlockSize(u32),
+    /// The discard limits are not valid.
+    InvalidDiscardLimits,
+    /// The number of request queues is 0.
+    InvalidNumQueues,
+    /// The alignment offset doesn't fit in a physical block.
+    InvalidTopology,
+    /// The queue size is too small to fit a data descriptor.
+    InvalidQueueSize(u16),
+    /// The write zeroes limits are not valid.
+    InvalidWriteZeroesLimits,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            InvalidBlockSize(size) => write!(f, "invalid block size: {}", size),
+            InvalidDiscardLimits => write!(f, "invalid discard limits"),
+            InvalidNumQueues => write!(f, "the number of request queues can't be 0"),
+            InvalidTopology => write!(f, "invalid alignment offset for the physical block size"),
+            InvalidQueueSize(size) => write!(f, "invalid queue size: {}", size),
+            InvalidWriteZeroesLimits => write!(f, "invalid write zeroes limits"),
+        }
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// The (legacy) geometry of the block device, valid when `VIRTIO_BLK_F_GEOMETRY` is negotiated.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C, packed)]
+pub struct Geometry {
+    /// Number of cylinders.
+    pub cylinders: u16,
+    /// Number of heads.
+    pub heads: u8,
+    /// Number of sectors.
+    pub sectors: u8,
+}
+
+/// Information about the optimal I/O alignment, valid when `VIRTIO_BLK_F_TOPOLOGY` is
+/// negotiated.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C, packed)]
+pub struct Topology {
+    /// Number of logical blocks per physical block (log2).
+    pub physical_block_exp: u8,
+    /// Offset of first aligned logical block.
+    pub alignment_offset: u8,
+    /// Suggested minimum I/O size in blocks.
+    pub min_io_size: u16,
+    /// Optimal (suggested maximum) I/O size in blocks.
+    pub opt_io_size: u32,
+}
+
+impl Topology {
+    /// Derives the topology of a device with the specified logical block size from its backing
+    /// file.
+    ///
+    /// When `file` is a block device, the values are retrieved via the `BLKPBSZGET`,
+    /// `BLKALIGNOFF`, `BLKIOMIN` and `BLKIOOPT` ioctls. For regular files, the preferred I/O
+    /// block size of the host file system is used as both the physical block size and the
+    /// minimum I/O size.
+    ///
+    /// # Arguments
+    /// * `file` - The block device backing file.
+    /// * `blk_size` - The logical block size exposed to the driver.
+    pub fn from_file(file: &File, blk_size: u32) -> io::Result<Self> {
+        let metadata = file.metadata()?;
+        let (physical_block_size, alignment_offset, min_io_size, opt_io_size) =
+            if metadata.file_type().is_block_device() {
+                (
+                    blk_ioctl(file, BLKPBSZGET())?,
+                    blk_ioctl(file, BLKALIGNOFF())?,
+                    blk_ioctl(file, BLKIOMIN())?,
+                    blk_ioctl(file, BLKIOOPT())?,
+                )
+            } else {
+                let blksize = u32::try_from(metadata.blksize()).unwrap_or(0);
+                (blksize, 0, blksize, 0)
+            };
+
+        let blk_size = blk_size.max(1);
+        let blocks_per_physical_block = (physical_block_size / blk_size).max(1);
+        Ok(Topology {
+            // A non power of 2 value is rounded down to the closest power of 2.
+            physical_block_exp: (u32::BITS - 1 - blocks_per_physical_block.leading_zeros()) as u8,
+            alignment_offset: u8::try_from(alignment_offset / blk_size).unwrap_or(0),
+            min_io_size: u16::try_from(min_io_size / blk_size).unwrap_or(u16::MAX),
+            opt_io_size: opt_io_size / blk_size,
+        })
+    }
+}
+
+// Runs a block device ioctl which returns an integer value.
+fn blk_ioctl(file: &File, req: std::os::raw::c_ulong) -> io::Result<u32> {
+    let mut value: std::os::raw::c_uint = 0;
+    // Safe because the kernel only writes an integer to `value`, and we check the return value.
+    let ret = unsafe { ioctl_with_mut_ref(file, req, &mut value) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+/// The block device configuration space layout, as defined by the virtio specification.
+///
+/// All fields are expected to hold little endian values, which is always the case on the
+/// platforms that are currently supported (`x86_64` and `aarch64`).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C, packed)]
+pub struct ConfigSpace {
+    /// The capacity of the device (expressed in 512-byte sectors).
+    pub capacity: u64,
+    /// The maximum segment size (valid when `VIRTIO_BLK_F_SIZE_MAX` is negotiated).
+    pub size_max: u32,
+    /// The maximum number of segments in a request (valid when `VIRTIO_BLK_F_SEG_MAX` is
+    /// negotiated).
+    pub seg_max: u32,
+    /// The device geometry (valid when `VIRTIO_BLK_F_GEOMETRY` is negotiated).
+    pub geometry: Geometry,
+    /// The block size of the device (valid when `VIRTIO_BLK_F_BLK_SIZE` is negotiated).
+    pub blk_size: u32,
+    /// The device topology (valid when `VIRTIO_BLK_F_TOPOLOGY` is negotiated).
+    pub topology: Topology,
+    /// Writeback mode (valid when `VIRTIO_BLK_F_CONFIG_WCE` is negotiated).
+    pub writeback: u8,
+    /// Reserved.
+    pub unused0: u8,
+    /// The number of request queues (valid when `VIRTIO_BLK_F_MQ` is negotiated).
+    pub num_queues: u16,
+    /// The maximum discard sectors for one segment (valid when `VIRTIO_BLK_F_DISCARD` is
+    /// negotiated).
+    pub max_discard_sectors: u32,
+    /// The maximum number of discard segments in a discard command (valid when
+    /// `VIRTIO_BLK_F_DISCARD` is negotiated).
+    pub max_discard_seg: u32,
+    /// The alignment (in sectors) required when splitting a discard command (valid when
+    /// `VIRTIO_BLK_F_DISCARD` is negotiated).
+    pub discard_sector_alignment: u32,
+    /// The maximum number of write zeroes sectors in one segment (valid when
+    /// `VIRTIO_BLK_F_WRITE_ZEROES` is negotiated).
+    pub max_write_zeroes_sectors: u32,
+    /// The maximum number of segments in a write zeroes command (valid when
+    /// `VIRTIO_BLK_F_WRITE_ZEROES` is negotiated).
+    pub max_write_zeroes_seg: u32,
+    /// Set to 1 if a write zeroes request with the unmap flag set can result in deallocating
+    /// one or more of the sectors (valid when `VIRTIO_BLK_F_WRITE_ZEROES` is negotiated).
+    pub write_zeroes_may_unmap: u8,
+    /// Reserved.
+    pub unused1: [u8; 3],
+}
+
+// Safe because ConfigSpace only contains plain data, and there's no padding between (or after)
+// the fields since the structure is packed.
+unsafe impl ByteValued for ConfigSpace {}
+
+impl ConfigSpace {
+    /// The size of the block device configuration space.
+    pub const LEN: usize = size_of::<ConfigSpace>();
+    /// The offset of the `capacity` field.
+    pub const CAPACITY_OFFSET: usize = offset_of!(ConfigSpace, capacity);
+    /// The offset of the `writeback` field, which is the only one the driver can write.
+    pub const WRITEBACK_OFFSET: usize = offset_of!(ConfigSpace, writeback);
+
+    /// Returns whether the configuration space reports a writeback cache.
+    pub fn is_writeback(&self) -> bool {
+        self.writeback != 0
+    }
+}
+
+impl From<ConfigSpace> for Vec<u8> {
+    fn from(config: ConfigSpace) -> Self {
+        config.as_slice().to_vec()
+    }
+}
+
+/// Builds the configuration space of a block device.
+///
+/// # Example
+///
+/// ```rust
+/// # use virtio_blk::config::ConfigBuilder;
+/// # use virtio_blk::defs::{VIRTIO_BLK_F_BLK_SIZE, VIRTIO_BLK_F_SEG_MAX};
+/// let builder = ConfigBuilder::new(0x1000)
+///     .with_queue_size(256)
+///     .with_blk_size(4096);
+///
+/// // The features which have to be advertised by the device, based on what was configured.
+/// assert_eq!(
+///     builder.features(),
+///     (1 << VIRTIO_BLK_F_SEG_MAX) | (1 << VIRTIO_BLK_F_BLK_SIZE)
+/// );
+///
+/// let config_space: Vec<u8> = builder.build().unwrap().into();
+/// ```
+#[derive(Clone, Debug)]
+pub struct ConfigBuilder {
+    config: ConfigSpace,
+    features: u64,
+}
+
+impl ConfigBuilder {
+    /// Creates a new `ConfigBuilder` for a device with the specified capacity.
+    ///
+    /// # Arguments
+    /// * `capacity` - The number of 512-byte sectors of the device (usually obtained from the
+    ///   backend, e.g. via `StdIoBackend::num_sectors`).
+    pub fn new(capacity: u64) -> Self {
+        ConfigBuilder {
+            config: ConfigSpace {
+                capacity,
+                ..Default::default()
+            },
+            features: 0,
+        }
+    }
+
+    /// Returns the feature bits which have to be offered by the device for the configuration
+    /// fields set so far to be taken into account by the driver.
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    fn set_feature(&mut self, feature_pos: u64) {
+        self.features |= 1 << feature_pos;
+    }
+
+    /// Sets the maximum size of any single data segment.
+    ///
+    /// # Arguments
+    /// * `size_max` - The maximum segment size, in bytes.
+    pub fn with_size_max(mut self, size_max: u32) -> Self {
+        self.config.size_max = size_max;
+        self.set_feature(VIRTIO_BLK_F_SIZE_MAX);
+        self
+    }
+
+    /// Sets the maximum number of data segments based on the size of the request queue(s).
+    ///
+    /// # Arguments
+    /// * `queue_size` - The maximum size of the request queue(s).
+    pub fn with_queue_size(mut self, queue_size: u16) -> Self {
+        // An invalid queue size will be reported by `build`.
+        self.config.seg_max = u32::from(queue_size.saturating_sub(NON_DATA_DESCRIPTORS));
+        self.set_feature(VIRTIO_BLK_F_SEG_MAX);
+        self
+    }
+
+    /// Sets the logical block size of the device.
+    ///
+    /// # Arguments
+    /// * `blk_size` - The block size, which must be a power of 2 greater or equal to 512.
+    pub fn with_blk_size(mut self, blk_size: u32) -> Self {
+        self.config.blk_size = blk_size;
+        self.set_feature(VIRTIO_BLK_F_BLK_SIZE);
+        self
+    }
+
+    /// Sets the number of logical blocks per physical block.
+    ///
+    /// # Arguments
+    /// * `physical_block_exp` - The log2 of the number of logical blocks per physical block.
+    pub fn with_physical_block_exp(mut self, physical_block_exp: u8) -> Self {
+        self.config.topology.physical_block_exp = physical_block_exp;
+        self.set_feature(VIRTIO_BLK_F_TOPOLOGY);
+        self
+    }
+
+    /// Sets the I/O topology of the device (usually obtained via `Topology::from_file`).
+    ///
+    /// # Arguments
+    /// * `topology` - The physical block size and the I/O size hints, in logical blocks.
+    pub fn with_topology(mut self, topology: Topology) -> Self {
+        self.config.topology = topology;
+        self.set_feature(VIRTIO_BLK_F_TOPOLOGY);
+        self
+    }
+
+    /// Sets the number of request queues of the device.
+    ///
+    /// # Arguments
+    /// * `num_queues` - The number of request queues.
+    pub fn with_num_queues(mut self, num_queues: u16) -> Self {
+        self.config.num_queues = num_queues;
+        self.set_feature(VIRTIO_BLK_F_MQ);
+        self
+    }
+
+    /// Sets the limits of the discard command.
+    ///
+    /// # Arguments
+    /// * `max_sectors` - The maximum number of sectors in a discard segment.
+    /// * `max_seg` - The maximum number of segments in a discard request.
+    /// * `sector_alignment` - The alignment (in sectors) used when splitting discard requests.
+    pub fn with_discard(mut self, max_sectors: u32, max_seg: u32, sector_alignment: u32) -> Self {
+        self.config.max_discard_sectors = max_sectors;
+        self.config.max_discard_seg = max_seg;
+        self.config.discard_sector_alignment = sector_alignment;
+        self.set_feature(VIRTIO_BLK_F_DISCARD);
+        self
+    }
+
+    /// Sets the limits of the write zeroes command.
+    ///
+    /// # Arguments
+    /// * `max_sectors` - The maximum number of sectors in a write zeroes segment.
+    /// * `max_seg` - The maximum number of segments in a write zeroes request.
+    /// * `may_unmap` - Whether write zeroes requests with the unmap flag set can deallocate
+    ///   sectors.
+    pub fn with_write_zeroes(mut self, max_sectors: u32, max_seg: u32, may_unmap: bool) -> Self {
+        self.config.max_write_zeroes_sectors = max_sectors;
+        self.config.max_write_zeroes_seg = max_seg;
+        self.config.write_zeroes_may_unmap = may_unmap as u8;
+        self.set_feature(VIRTIO_BLK_F_WRITE_ZEROES);
+        self
+    }
+
+    /// Sets the initial cache mode of the device, which the driver can then toggle by writing
+    /// the `writeback` field.
+    ///
+    /// A writeback cache requires flush support, so `VIRTIO_BLK_F_FLUSH` is advertised as well.
+    ///
+    /// # Arguments
+    /// * `writeback` - Whether the device starts in writeback (as opposed to writethrough) mode.
+    pub fn with_writeback(mut self, writeback: bool) -> Self {
+        self.config.writeback = writeback as u8;
+        self.set_feature(VIRTIO_BLK_F_FLUSH);
+        self.set_feature(VIRTIO_BLK_F_CONFIG_WCE);
+        self
+    }
+
+    fn has_feature(&self, feature_pos: u64) -> bool {
+        (self.features & (1u64 << feature_pos)) != 0
+    }
+
+    /// Validates the configuration and returns the resulting `ConfigSpace`.
+    pub fn build(self) -> Result<ConfigSpace> {
+        let config = self.config;
+
+        if self.has_feature(VIRTIO_BLK_F_SEG_MAX) && config.seg_max == 0 {
+            return Err(Error::InvalidQueueSize(
+                config.seg_max as u16 + NON_DATA_DESCRIPTORS,
+            ));
+        }
+
+        let blk_size = config.blk_size;
+        if self.has_feature(VIRTIO_BLK_F_BLK_SIZE)
+            && (!blk_size.is_power_of_two() || u64::from(blk_size) < SECTOR_SIZE)
+        {
+            return Err(Error::InvalidBlockSize(blk_size));
+        }
+
+        let topology = config.topology;
+        if self.has_feature(VIRTIO_BLK_F_TOPOLOGY)
+            && 1u32
+                .checked_shl(u32::from(topology.physical_block_exp))
+                .map_or(true, |blocks| {
+                    u32::from(topology.alignment_offset) >= blocks
+                })
+        {
+            return Err(Error::InvalidTopology);
+        }
+
+        if self.has_feature(VIRTIO_BLK_F_MQ) && config.num_queues == 0 {
+            return Err(Error::InvalidNumQueues);
+        }
+
+        if self.has_feature(VIRTIO_BLK_F_DISCARD)
+            && (config.max_discard_sectors == 0 || config.max_discard_seg == 0)
+        {
+            return Err(Error::InvalidDiscardLimits);
+        }
+
+        if self.has_feature(VIRTIO_BLK_F_WRITE_ZEROES)
+            && (config.max_write_zeroes_sectors == 0 || config.max_write_zeroes_seg == 0)
+        {
+            return Err(Error::InvalidWriteZeroesLimits);
+        }
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_config_space_layout() {
+        assert_eq!(ConfigSpace::LEN, 60);
+        assert_eq!(offset_of!(ConfigSpace, capacity), 0);
+        assert_eq!(ConfigSpace::CAPACITY_OFFSET, 0);
+        assert_eq!(offset_of!(ConfigSpace, size_max), 8);
+        assert_eq!(offset_of!(ConfigSpace, seg_max), 12);
+        assert_eq!(offset_of!(ConfigSpace, geometry), 16);
+        assert_eq!(offset_of!(ConfigSpace, blk_size), 20);
+        assert_eq!(offset_of!(ConfigSpace, topology), 24);
+        assert_eq!(offset_of!(ConfigSpace, writeback), 32);
+        assert_eq!(ConfigSpace::WRITEBACK_OFFSET, 32);
+        assert_eq!(offset_of!(ConfigSpace, num_queues), 34);
+        assert_eq!(offset_of!(ConfigSpace, max_discard_sectors), 36);
+        assert_eq!(offset_of!(ConfigSpace, max_discard_seg), 40);
+        assert_eq!(offset_of!(ConfigSpace, discard_sector_alignment), 44);
+        assert_eq!(offset_of!(ConfigSpace, max_write_zeroes_sectors), 48);
+        assert_eq!(offset_of!(ConfigSpace, max_write_zeroes_seg), 52);
+        assert_eq!(offset_of!(ConfigSpace, write_zeroes_may_unmap), 56);
+    }
+
+    #[test]
+    fn test_config_builder() {
+        let builder = ConfigBuilder::new(0x1000);
+        assert_eq!(builder.features(), 0);
+        let config = builder.build().unwrap();
+        assert_eq!(
+            config,
+            ConfigSpace {
+                capacity: 0x1000,
+                ..Default::default()
+            }
+        );
+
+        let builder = ConfigBuilder::new(0x2000)
+            .with_size_max(0x1000)
+            .with_queue_size(128)
+            .with_blk_size(4096)
+            .with_physical_block_exp(1)
+            .with_num_queues(4)
+            .with_discard(0x100, 1, 8)
+            .with_write_zeroes(0x200, 2, true)
+            .with_writeback(true);
+        assert_eq!(
+            builder.features(),
+            (1 << VIRTIO_BLK_F_SIZE_MAX)
+                | (1 << VIRTIO_BLK_F_SEG_MAX)
+                | (1 << VIRTIO_BLK_F_BLK_SIZE)
+                | (1 << VIRTIO_BLK_F_FLUSH)
+                | (1 << VIRTIO_BLK_F_TOPOLOGY)
+                | (1 << VIRTIO_BLK_F_CONFIG_WCE)
+                | (1 << VIRTIO_BLK_F_MQ)
+                | (1 << VIRTIO_BLK_F_DISCARD)
+                | (1 << VIRTIO_BLK_F_WRITE_ZEROES)
+        );
+
+        let config = builder.build().unwrap();
+        assert_eq!(
+            config,
+            ConfigSpace {
+                capacity: 0x2000,
+                size_max: 0x1000,
+                seg_max: 126,
+                blk_size: 4096,
+                topology: Topology {
+                    physical_block_exp: 1,
+                    ..Default::default()
+                },
+                writeback: 1,
+                num_queues: 4,
+                max_discard_sectors: 0x100,
+                max_discard_seg: 1,
+                discard_sector_alignment: 8,
+                max_write_zeroes_sectors: 0x200,
+                max_write_zeroes_seg: 2,
+                write_zeroes_may_unmap: 1,
+                ..Default::default()
+            }
+        );
+
+        assert!(config.is_writeback());
+        let bytes: Vec<u8> = config.into();
+        assert_eq!(bytes.len(), ConfigSpace::LEN);
+        assert_eq!(bytes[..8], 0x2000u64.to_le_bytes());
+        assert_eq!(bytes[ConfigSpace::WRITEBACK_OFFSET], 1);
+        assert_eq!(bytes[34..36], 4u16.to_le_bytes());
+    }
+
+    #[test]
+    fn test_config_builder_errors() {
+        assert_eq!(
+            ConfigBuilder::new(1)
+                .with_queue_size(2)
+                .build()
+                .unwrap_err(),
+            Error::InvalidQueueSize(2)
+        );
+        assert_eq!(
+            ConfigBuilder::new(1)
+                .with_blk_size(256)
+                .build()
+                .unwrap_err(),
+            Error::InvalidBlockSize(256)
+        );
+        assert_eq!(
+            ConfigBuilder::new(1)
+                .with_blk_size(1000)
+                .build()
+                .unwrap_err(),
+            Error::InvalidBlockSize(1000)
+        );
+        assert_eq!(
+            ConfigBuilder::new(1)
+                .with_num_queues(0)
+                .build()
+                .unwrap_err(),
+            Error::InvalidNumQueues
+        );
+        assert_eq!(
+            ConfigBuilder::new(1)
+                .with_topology(Topology {
+                    physical_block_exp: 3,
+                    alignment_offset: 8,
+                    ..Default::default()
+                })
+                .build()
+                .unwrap_err(),
+            Error::InvalidTopology
+        );
+        assert_eq!(
+            ConfigBuilder::new(1)
+                .with_physical_block_exp(32)
+                .build()
+                .unwrap_err(),
+            Error::InvalidTopology
+        );
+        assert_eq!(
+            ConfigBuilder::new(1)
+                .with_discard(0, 1, 0)
+                .build()
+                .unwrap_err(),
+            Error::InvalidDiscardLimits
+        );
+        assert_eq!(
+            ConfigBuilder::new(1)
+                .with_write_zeroes(1, 0, false)
+                .build()
+                .unwrap_err(),
+            Error::InvalidWriteZeroesLimits
+        );
+    }
+
+    #[test]
+    fn test_topology_from_file() {
+        let f = TempFile::new().unwrap().into_file();
+        let blksize = f.metadata().unwrap().blksize() as u32;
+
+        let topology = Topology::from_file(&f, SECTOR_SIZE as u32).unwrap();
+        let min_io_size = topology.min_io_size;
+        assert_eq!(u32::from(min_io_size), blksize / SECTOR_SIZE as u32);
+        assert_eq!(
+            1u32 << topology.physical_block_exp,
+            (blksize / SECTOR_SIZE as u32).max(1)
+        );
+        assert_eq!(topology.alignment_offset, 0);
+        let opt_io_size = topology.opt_io_size;
+        assert_eq!(opt_io_size, 0);
+
+        // A logical block size larger than the host one results in 1 block per physical block.
+        let topology = Topology::from_file(&f, blksize * 2).unwrap();
+        assert_eq!(topology.physical_block_exp, 0);
+
+        let builder = ConfigBuilder::new(1).with_topology(topology);
+        assert_eq!(builder.features(), 1 << VIRTIO_BLK_F_TOPOLOGY);
+        assert_eq!(builder.build().unwrap().topology, topology);
+    }
+}
@@ -0,0 +1,423 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A copy-on-write block device backend, which layers a writable overlay file on top of a
+//! read-only base image.
+//!
+//! [`CowFile`](struct.CowFile.html) splits the disk in clusters of `CLUSTER_SIZE` bytes. A
+//! cluster is read from the base image until it's written for the first time, when it's copied
+//! to the overlay file and marked as allocated in a bitmap. From then on, the cluster is only
+//! accessed in the overlay. This way, multiple VMs can be started from the same template image
+//! without copying it, each one with its own (initially empty) overlay.
+//!
+//! The overlay file holds the clusters at the same offsets as the base image (so it's sparse),
+//! followed by the allocation bitmap, which has one bit for each cluster. An empty overlay file
+//! is initialized when the `CowFile` is created, while an existing one is reopened with the
+//! clusters it already contains.
+//!
+//! Like [`SharedFile`](../shared_file/struct.SharedFile.html), a `CowFile` can be cloned for
+//! each request queue, with all the handles sharing the same files and bitmap.
+
+use std::cmp::min;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Mutex};
+
+use vmm_sys_util::file_traits::FileSync;
+use vmm_sys_util::write_zeroes::{PunchHole, WriteZeroesAt};
+
+use crate::defs::VIRTIO_BLK_ID_BYTES;
+use crate::stdio_executor::Backend;
+
+/// The size of the copy-on-write unit.
+pub const CLUSTER_SIZE: u64 = 0x10000;
+
+// Maximum size of the buffer used to write zeroes.
+const ZEROES_BUF_SIZE: usize = 0x10000;
+
+/// A copy-on-write backend, which reads the clusters that were never written from a base image
+/// and keeps all the changes in an overlay file.
+///
+/// # Example
+///
+/// ```rust
+/// # use virtio_blk::cow_file::CowFile;
+/// # use virtio_blk::stdio_executor::StdIoBackend;
+/// # use vmm_sys_util::tempfile::TempFile;
+/// let base = TempFile::new().unwrap().into_file();
+/// base.set_len(0x10_0000).unwrap();
+/// let overlay = TempFile::new().unwrap().into_file();
+///
+/// let cow = CowFile::new(base, overlay).unwrap();
+/// let executor = StdIoBackend::new(cow, 0).unwrap();
+/// assert_eq!(executor.num_sectors(), 0x800);
+/// ```
+#[derive(Clone, Debug)]
+pub struct CowFile {
+    base: Arc<File>,
+    overlay: Arc<File>,
+    // One bit for each cluster, which is set if the cluster was copied to the overlay.
+    bitmap: Arc<Mutex<Vec<u8>>>,
+    disk_size: u64,
+    offset: u64,
+}
+
+impl CowFile {
+    /// Creates a new `CowFile` with the cursor at offset 0.
+    ///
+    /// The size of the disk is the size of `base`. If `overlay` is empty, it's initialized for
+    /// `base`, otherwise its size has to match the one of the base image, and the clusters which
+    /// it already contains are used.
+    ///
+    /// # Arguments
+    /// * `base` - The base image, which is only read.
+    /// * `overlay` - The file which holds the changes, which has to be writable.
+    pub fn new(base: File, overlay: File) -> io::Result<Self> {
+        let disk_size = base.metadata()?.len();
+        let bitmap_len = Self::bitmap_len(disk_size);
+        let bitmap = match overlay.metadata()?.len() {
+            0 => {
+                overlay.set_len(disk_size + bitmap_len as u64)?;
+                vec![0u8; bitmap_len]
+            }
+            len if len == disk_size + bitmap_len as u64 => {
+                let mut bitmap = vec![0u8; bitmap_len];
+                overlay.read_exact_at(&mut bitmap, disk_size)?;
+                bitmap
+            }
+            _ => return Err(io::Error::from(io::ErrorKind::InvalidData)),
+        };
+
+        Ok(CowFile {
+            base: Arc::new(base),
+            overlay: Arc::new(overlay),
+            bitmap: Arc::new(Mutex::new(bitmap)),
+            disk_size,
+            offset: 0,
+        })
+    }
+
+    /// Returns the number of clusters that were copied to the overlay.
+    pub fn allocated_clusters(&self) -> u64 {
+        let bitmap = self.bitmap.lock().unwrap();
+        bitmap.iter().map(|byte| u64::from(byte.count_ones())).sum()
+    }
+
+    // Returns the size of the bitmap for a disk of `disk_size` bytes.
+    fn bitmap_len(disk_size: u64) -> usize {
+        // The cast is safe since the result is much smaller than the size of the disk.
+        (disk_size.div_ceil(CLUSTER_SIZE).div_ceil(8)) as usize
+    }
+
+    fn is_allocated(bitmap: &[u8], cluster: u64) -> bool {
+        bitmap[(cluster / 8) as usize] & (1 << (cluster % 8)) != 0
+    }
+
+    // Marks `cluster` as allocated, and saves the change in the overlay. The cluster data has to
+    // be written before.
+    fn allocate(&self, bitmap: &mut [u8], cluster: u64) -> io::Result<()> {
+        let index = (cluster / 8) as usize;
+        let byte = bitmap[index] | (1 << (cluster % 8));
+        self.overlay
+            .write_all_at(&[byte], self.disk_size + index as u64)?;
+        bitmap[index] = byte;
+        Ok(())
+    }
+
+    // Reads at `offset` into `buf`, which doesn't cross a cluster boundary.
+    fn read_cluster_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let cluster = offset / CLUSTER_SIZE;
+        let allocated = Self::is_allocated(&self.bitmap.lock().unwrap(), cluster);
+        let file = if allocated { &self.overlay } else { &self.base };
+        file.read_exact_at(buf, offset)
+    }
+
+    // Writes `buf` at `offset`, which doesn't cross a cluster boundary.
+    fn write_cluster_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let cluster = offset / CLUSTER_SIZE;
+        let mut bitmap = self.bitmap.lock().unwrap();
+        if Self::is_allocated(&bitmap, cluster) {
+            drop(bitmap);
+            return self.overlay.write_all_at(buf, offset);
+        }
+
+        // The bitmap stays locked during the copy, such that a cluster is never copied twice.
+        let cluster_start = cluster * CLUSTER_SIZE;
+        let cluster_len = min(CLUSTER_SIZE, self.disk_size - cluster_start) as usize;
+        if buf.len() == cluster_len {
+            self.overlay.write_all_at(buf, offset)?;
+        } else {
+            let mut data = vec![0u8; cluster_len];
+            self.base.read_exact_at(&mut data, cluster_start)?;
+            let start = (offset - cluster_start) as usize;
+            data[start..start + buf.len()].copy_from_slice(buf);
+            self.overlay.write_all_at(&data, cluster_start)?;
+        }
+        self.allocate(&mut bitmap, cluster)
+    }
+
+    // Returns the length of the access at `offset` which fits in the disk and in the current
+    // cluster, capped at `len`.
+    fn chunk_len(&self, offset: u64, len: usize) -> usize {
+        let cluster_end = (offset / CLUSTER_SIZE + 1) * CLUSTER_SIZE;
+        // The cast is safe since the value is at most `len`.
+        min(len as u64, min(cluster_end, self.disk_size) - offset) as usize
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            if pos >= self.disk_size {
+                break;
+            }
+            let count = self.chunk_len(pos, buf.len() - done);
+            self.read_cluster_at(&mut buf[done..done + count], pos)?;
+            done += count;
+        }
+        Ok(done)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            // The size of the disk is fixed, so there's no writing past the end.
+            if pos >= self.disk_size {
+                break;
+            }
+            let count = self.chunk_len(pos, buf.len() - done);
+            self.write_cluster_at(&buf[done..done + count], pos)?;
+            done += count;
+        }
+        Ok(done)
+    }
+
+    // Advances the cursor with `count` bytes.
+    fn advance(&mut self, count: usize) -> io::Result<()> {
+        self.offset = self
+            .offset
+            .checked_add(count as u64)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        Ok(())
+    }
+}
+
+impl Read for CowFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.read_at(buf, self.offset)?;
+        self.advance(count)?;
+        Ok(count)
+    }
+}
+
+impl Write for CowFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.write_at(buf, self.offset)?;
+        self.advance(count)?;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Writes go straight to the overlay, so there's nothing to flush here.
+        Ok(())
+    }
+}
+
+impl Seek for CowFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => {
+                self.offset = offset;
+                return Ok(offset);
+            }
+            SeekFrom::Current(delta) => (self.offset, delta),
+            SeekFrom::End(delta) => (self.disk_size, delta),
+        };
+
+        let offset = if delta >= 0 {
+            base.checked_add(delta as u64)
+        } else {
+            base.checked_sub(delta.unsigned_abs())
+        }
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+        self.offset = offset;
+        Ok(offset)
+    }
+}
+
+impl FileSync for CowFile {
+    fn fsync(&mut self) -> io::Result<()> {
+        self.overlay.sync_all()
+    }
+}
+
+impl PunchHole for CowFile {
+    fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        // Deallocating the clusters would expose the base image again, so the range is zeroed
+        // in the overlay instead.
+        let length =
+            usize::try_from(length).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        self.write_zeroes_at(offset, length).map(|_| ())
+    }
+}
+
+impl WriteZeroesAt for CowFile {
+    fn write_zeroes_at(&mut self, offset: u64, length: usize) -> io::Result<usize> {
+        let buf = vec![0u8; min(length, ZEROES_BUF_SIZE)];
+        let mut written = 0;
+        while written < length {
+            let count = min(length - written, buf.len());
+            match self.write_at(&buf[..count], offset + written as u64)? {
+                0 => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                count => written += count,
+            }
+        }
+        Ok(length)
+    }
+}
+
+impl Backend for CowFile {
+    fn image_id(&self) -> Option<[u8; VIRTIO_BLK_ID_BYTES]> {
+        // Each clone of a template image has its own overlay.
+        self.overlay.image_id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+    use vmm_sys_util::tempfile::TempFile;
+
+    use crate::defs::SECTOR_SIZE;
+    use crate::request::{Request, RequestType};
+    use crate::stdio_executor::StdIoBackend;
+
+    const DISK_SIZE: u64 = 4 * CLUSTER_SIZE + 0x1000;
+
+    fn base_image() -> File {
+        let base = TempFile::new().unwrap().into_file();
+        let pattern = (0..DISK_SIZE).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        base.write_all_at(&pattern, 0).unwrap();
+        base
+    }
+
+    #[test]
+    fn test_cow_file() {
+        let base = base_image();
+        let overlay = TempFile::new().unwrap();
+        let mut cow = CowFile::new(
+            base.try_clone().unwrap(),
+            overlay.as_file().try_clone().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(overlay.as_file().metadata().unwrap().len(), DISK_SIZE + 1);
+        assert_eq!(cow.allocated_clusters(), 0);
+        assert_eq!(cow.seek(SeekFrom::End(0)).unwrap(), DISK_SIZE);
+
+        // Everything is read from the base image at first.
+        let mut expected = vec![0u8; DISK_SIZE as usize];
+        base.read_exact_at(&mut expected, 0).unwrap();
+        let mut buf = vec![0u8; DISK_SIZE as usize];
+        cow.seek(SeekFrom::Start(0)).unwrap();
+        cow.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, expected);
+        // There's nothing past the end of the disk.
+        assert_eq!(cow.read(&mut buf).unwrap(), 0);
+        assert_eq!(cow.write(&buf).unwrap(), 0);
+
+        // A write which spans two clusters copies both of them, while the base is unchanged.
+        let offset = CLUSTER_SIZE - 0x200;
+        cow.seek(SeekFrom::Start(offset)).unwrap();
+        cow.write_all(&[0xaa; 0x400]).unwrap();
+        expected[offset as usize..offset as usize + 0x400].copy_from_slice(&[0xaa; 0x400]);
+        assert_eq!(cow.allocated_clusters(), 2);
+
+        // A whole cluster write, and the partial cluster at the end of the disk.
+        cow.seek(SeekFrom::Start(3 * CLUSTER_SIZE)).unwrap();
+        cow.write_all(&vec![0xbb; CLUSTER_SIZE as usize + 0x1000])
+            .unwrap();
+        expected[3 * CLUSTER_SIZE as usize..]
+            .copy_from_slice(&[0xbb; CLUSTER_SIZE as usize + 0x1000]);
+        assert_eq!(cow.allocated_clusters(), 4);
+
+        cow.write_zeroes_at(0x100, 0x100).unwrap();
+        cow.punch_hole(2 * CLUSTER_SIZE, 0x200).unwrap();
+        expected[0x100..0x200].copy_from_slice(&[0u8; 0x100]);
+        expected[2 * CLUSTER_SIZE as usize..2 * CLUSTER_SIZE as usize + 0x200]
+            .copy_from_slice(&[0u8; 0x200]);
+        assert_eq!(cow.allocated_clusters(), 5);
+
+        cow.seek(SeekFrom::Start(0)).unwrap();
+        cow.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, expected);
+        let mut base_data = vec![0u8; DISK_SIZE as usize];
+        base.read_exact_at(&mut base_data, 0).unwrap();
+        assert!(base_data
+            .iter()
+            .enumerate()
+            .all(|(i, &b)| b == (i % 251) as u8));
+        cow.fsync().unwrap();
+
+        // The overlay can be reopened.
+        let mut cow = CowFile::new(base, overlay.into_file()).unwrap();
+        assert_eq!(cow.allocated_clusters(), 5);
+        cow.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, expected);
+
+        // The overlay doesn't match the base image.
+        let other = TempFile::new().unwrap().into_file();
+        other.set_len(0x1000).unwrap();
+        assert_eq!(
+            CowFile::new(base_image(), other).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_cow_requests() {
+        let base = base_image();
+        let cow = CowFile::new(base, TempFile::new().unwrap().into_file()).unwrap();
+        assert!(cow.image_id().is_some());
+        let mut executor = StdIoBackend::new(cow.clone(), 0).unwrap();
+        assert_eq!(executor.num_sectors(), DISK_SIZE / SECTOR_SIZE);
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        mem.write_slice(&[0xcc; 0x400], GuestAddress(0x1000))
+            .unwrap();
+        let request = Request::new(
+            RequestType::Out,
+            vec![(GuestAddress(0x1000), 0x400)],
+            1,
+            GuestAddress(0x8_0000),
+        );
+        executor.execute(&mem, &request).unwrap();
+
+        // The changes are visible through the other handles.
+        let mut executor = StdIoBackend::new(cow, 0).unwrap();
+        let request = Request::new(
+            RequestType::In,
+            vec![(GuestAddress(0x2000), 0x800)],
+            0,
+            GuestAddress(0x8_0000),
+        );
+        executor.execute(&mem, &request).unwrap();
+        let mut buf = [0u8; 0x800];
+        mem.read_slice(&mut buf, GuestAddress(0x2000)).unwrap();
+        assert!(buf[..0x200]
+            .iter()
+            .enumerate()
+            .all(|(i, &b)| b == (i % 251) as u8));
+        assert_eq!(buf[0x200..0x600], [0xcc; 0x400]);
+        assert!(buf[0x600..]
+            .iter()
+            .enumerate()
+            .all(|(i, &b)| b == ((i + 0x600) % 251) as u8));
+    }
+}
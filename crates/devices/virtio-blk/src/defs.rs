@@ -2,6 +2,13 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
+// TODO: Move the generic device type definition to the vm-virtio crate proper.
+/// The virtio device type of block devices.
+pub const VIRTIO_ID_BLOCK: u32 = 2;
+
+/// The default (and maximum) size of the request queues.
+pub const DEFAULT_QUEUE_SIZE: u16 = 256;
+
 // Request types.
 /// Read request.
 pub const VIRTIO_BLK_T_IN: u32 = 0;
@@ -15,20 +22,46 @@ pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
 // unimplemented. See commit b342d29 from [virtio-spec](https://github.com/oasis-tcs/virtio-spec).
 /// Get device ID request.
 pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
+/// Get device lifetime request.
+pub const VIRTIO_BLK_T_GET_LIFETIME: u32 = 10;
 /// Discard request.
 pub const VIRTIO_BLK_T_DISCARD: u32 = 11;
 /// Write zeroes request.
 pub const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
 
 // Feature bits.
+/// Maximum size of any single segment is in `size_max`.
+pub const VIRTIO_BLK_F_SIZE_MAX: u64 = 1;
+/// Maximum number of segments in a request is in `seg_max`.
+pub const VIRTIO_BLK_F_SEG_MAX: u64 = 2;
 /// Read-only device.
 pub const VIRTIO_BLK_F_RO: u64 = 5;
+/// Block size of disk is in `blk_size`.
+pub const VIRTIO_BLK_F_BLK_SIZE: u64 = 6;
 /// Flush command supported.
 pub const VIRTIO_BLK_F_FLUSH: u64 = 9;
+/// Device exports information on optimal I/O alignment.
+pub const VIRTIO_BLK_F_TOPOLOGY: u64 = 10;
+/// Device can toggle its cache between writeback and writethrough modes.
+pub const VIRTIO_BLK_F_CONFIG_WCE: u64 = 11;
+/// Device supports multiple request queues.
+pub const VIRTIO_BLK_F_MQ: u64 = 12;
 /// Discard command supported.
 pub const VIRTIO_BLK_F_DISCARD: u64 = 13;
 /// Write zeroes command supported.
 pub const VIRTIO_BLK_F_WRITE_ZEROES: u64 = 14;
+/// Device supports providing storage lifetime information.
+pub const VIRTIO_BLK_F_LIFETIME: u64 = 15;
+
+// Pre-EOL values of the lifetime information.
+/// The wear state of the device is unknown.
+pub const VIRTIO_BLK_PRE_EOL_INFO_UNDEFINED: u16 = 0;
+/// Less than 80% of the reserved blocks are consumed.
+pub const VIRTIO_BLK_PRE_EOL_INFO_NORMAL: u16 = 1;
+/// 80% of the reserved blocks are consumed.
+pub const VIRTIO_BLK_PRE_EOL_INFO_WARNING: u16 = 2;
+/// 90% of the reserved blocks are consumed.
+pub const VIRTIO_BLK_PRE_EOL_INFO_URGENT: u16 = 3;
 
 /// Length of block device id.
 pub const VIRTIO_BLK_ID_BYTES: usize = 20;
@@ -0,0 +1,2007 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A reference virtio block device implementation.
+//!
+//! This module provides the following abstractions:
+//!
+//! - [`Block`](struct.Block.html) which ties together the generic device building blocks
+//!   (`VirtioConfig` and the `VirtioDevice`/`VirtioMmioDevice` interfaces) with the block
+//!   specific ones (the configuration space, the request executor and the queue handler). It
+//!   uses one [`InorderQueueHandler`](../queue_handler/struct.InorderQueueHandler.html) for each
+//!   request queue, all of them sharing the same backend (usually a
+//!   [`SharedFile`](../shared_file/struct.SharedFile.html)).
+//! - [`BlockBuilder`](struct.BlockBuilder.html) which configures and creates a `Block` device.
+//!
+//! The device doesn't register any events by itself. The VMM is expected to call
+//! [`Block::process_queue`](struct.Block.html#method.process_queue) when the driver notifies a
+//! queue (i.e. when the associated ioeventfd is triggered), or to rely on the
+//! `VirtioMmioDevice::queue_notify` implementation, which does the same thing.
+//!
+//! The backing file can be grown at runtime with [`Block::resize`](struct.Block.html#method.resize),
+//! which also notifies the driver about the new capacity.
+//!
+//! Before taking a snapshot of the VM, the device can be quiesced with
+//! [`Block::drain`](struct.Block.html#method.drain), which leaves a crash-consistent disk state
+//! in the backing file until [`Block::resume`](struct.Block.html#method.resume) is called (the
+//! device also implements `VirtioDevicePause`, which can skip flushing the backing file). The
+//! state of the device is then saved with `VirtioDevicePersist::save`, and a device which
+//! continues from it is created with `VirtioDevicePersist::restore`, given the same backing
+//! file.
+//!
+//! The requests can also be executed by an [`AsyncBackend`](../queue_handler/trait.AsyncBackend.html)
+//! for each queue (see [`BlockBuilder::with_async_backend`](struct.BlockBuilder.html#method.with_async_backend)),
+//! in which case the VMM also calls
+//! [`Block::process_completions`](struct.Block.html#method.process_completions) when the
+//! completion channel of a queue becomes readable. The outstanding requests are cancelled when
+//! the driver resets the device, and waited for when the device is drained.
+
+use std::convert::TryInto;
+use std::fmt::{self, Display};
+use std::sync::atomic::AtomicU8;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{io, result};
+
+use log::{error, warn};
+
+use vm_memory::GuestAddressSpace;
+
+use virtio_device::affinity::QueueAffinity;
+use virtio_device::completion_channel::{self, CompletionReceiver, CompletionSender};
+use virtio_device::persist::{
+    self, StateCodec, VersionedState, VirtioDevicePersist, VirtioDeviceState,
+};
+use virtio_device::{
+    set_interrupt_status, EventsContext, SignalConfigChange, SignalUsedQueue, VirtioConfig,
+    VirtioDeviceActions, VirtioDeviceCommon, VirtioDeviceEvents, VirtioDevicePause,
+    VirtioMmioDevice, VIRTIO_MMIO_INT_VRING,
+};
+use virtio_queue::{DirtyTracker, InvalidQueueReason, Queue};
+
+use crate::config::{self, ConfigBuilder, ConfigSpace};
+use crate::defs::{
+    SECTOR_SHIFT, SECTOR_SIZE, VIRTIO_BLK_F_CONFIG_WCE, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_LIFETIME,
+    VIRTIO_BLK_ID_BYTES,
+};
+use crate::queue_handler::{self, AsyncBackend, InorderQueueHandler};
+use crate::request::Lifetime;
+use crate::stdio_executor::{self, Backend, StdIoBackend};
+
+pub use crate::defs::{DEFAULT_QUEUE_SIZE, VIRTIO_ID_BLOCK};
+
+// TODO: Move the generic feature definitions to the vm-virtio crate proper.
+const VIRTIO_F_VERSION_1: u64 = 32;
+const VIRTIO_F_RING_EVENT_IDX: u64 = 29;
+// How long the asynchronous backends get to stop the outstanding requests on reset, or to
+// complete them when the device is drained.
+const INFLIGHT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Block device errors.
+#[derive(Debug)]
+pub enum Error {
+    /// The device was already activated.
+    AlreadyActivated,
+    /// Failed to create an asynchronous backend.
+    AsyncBackend(io::Error),
+    /// Failed to create a completion channel.
+    CompletionChannel(completion_channel::Error),
+    /// Failed to build the configuration space.
+    Config(config::Error),
+    /// Failed to set up the request executor.
+    Executor(stdio_executor::Error),
+    /// Failed to flush the backend.
+    Flush(io::Error),
+    /// The queues are not configured properly.
+    InvalidQueues,
+    /// The queue index is not valid, or the device is not activated.
+    InvalidQueueIndex(u16),
+    /// A queue of an activated device is not valid anymore after restoring its state.
+    InvalidRestoredQueue(u16, InvalidQueueReason),
+    /// The new disk size is not a multiple of the sector size, or is smaller than the current one.
+    InvalidSize(u64),
+    /// Failed to process a request queue.
+    QueueHandler(queue_handler::Error),
+    /// Failed to resize the backend.
+    Resize(io::Error),
+    /// The saved state has a version the device doesn't support.
+    UnsupportedStateVersion(u16),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            AlreadyActivated => write!(f, "the device is already activated"),
+            AsyncBackend(ref err) => write!(f, "failed to create the async backend: {}", err),
+            CompletionChannel(ref err) => {
+                write!(f, "failed to create the completion channel: {}", err)
+            }
+            Config(ref err) => write!(f, "invalid configuration space: {}", err),
+            Executor(ref err) => write!(f, "failed to set up the request executor: {}", err),
+            Flush(ref err) => write!(f, "failed to flush the backend: {}", err),
+            InvalidQueues => write!(f, "invalid queue configuration"),
+            InvalidQueueIndex(index) => write!(f, "invalid or inactive queue {}", index),
+            InvalidRestoredQueue(index, reason) => {
+                write!(f, "invalid restored queue {}: {}", index, reason)
+            }
+            InvalidSize(size) => write!(f, "invalid disk size {}", size),
+            QueueHandler(ref err) => write!(f, "failed to process the queue: {}", err),
+            Resize(ref err) => write!(f, "failed to resize the backend: {}", err),
+            UnsupportedStateVersion(version) => {
+                write!(f, "unsupported device state version {}", version)
+            }
+        }
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+// Signals the driver on behalf of a queue handler, after updating the interrupt status.
+#[derive(Debug)]
+struct QueueSignal<S: SignalUsedQueue> {
+    interrupt_status: Arc<AtomicU8>,
+    driver_notify: Arc<S>,
+}
+
+impl<S: SignalUsedQueue> SignalUsedQueue for QueueSignal<S> {
+    fn signal_used_queue(&self, index: u16) {
+        set_interrupt_status(&self.interrupt_status, VIRTIO_MMIO_INT_VRING);
+        self.driver_notify.signal_used_queue(index);
+    }
+}
+
+/// Creates the asynchronous backend of each request queue of a `Block` device.
+pub trait AsyncBackendFactory: fmt::Debug + Send + Sync {
+    /// Creates the backend of a request queue, when the device is activated.
+    ///
+    /// # Arguments
+    /// * `queue_index` - The index of the request queue.
+    /// * `completions` - The sending end of the channel the backend sends the completions
+    ///   through.
+    fn create(
+        &self,
+        queue_index: u16,
+        completions: CompletionSender,
+    ) -> io::Result<Box<dyn AsyncBackend>>;
+}
+
+/// Configures and builds a `Block` device.
+///
+/// # Example
+///
+/// ```rust
+/// # use std::sync::Arc;
+/// # use virtio_blk::device::BlockBuilder;
+/// # use virtio_blk::shared_file::SharedFile;
+/// # use vm_memory::{GuestAddress, GuestMemoryMmap};
+/// # use vmm_sys_util::eventfd::EventFd;
+/// # use vmm_sys_util::tempfile::TempFile;
+/// let mem = Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+/// let file = TempFile::new().unwrap().into_file();
+/// file.set_len(0x10_0000).unwrap();
+///
+/// let block = BlockBuilder::new(mem, SharedFile::new(file), EventFd::new(0).unwrap())
+///     .with_num_queues(2)
+///     .with_writeback(true)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug)]
+pub struct BlockBuilder<M: GuestAddressSpace, B: Backend + Clone, S: SignalUsedQueue> {
+    mem: M,
+    backend: B,
+    driver_notify: S,
+    num_queues: u16,
+    queue_size: u16,
+    read_only: bool,
+    writeback: Option<bool>,
+    device_id: Option<[u8; VIRTIO_BLK_ID_BYTES]>,
+    lifetime: Option<Lifetime>,
+    queue_affinity: QueueAffinity,
+    async_backend: Option<Arc<dyn AsyncBackendFactory>>,
+    max_pending: Option<usize>,
+}
+
+impl<M, B, S> BlockBuilder<M, B, S>
+where
+    M: GuestAddressSpace + Clone,
+    B: Backend + Clone,
+    S: SignalUsedQueue,
+{
+    /// Creates a new `BlockBuilder` for a device with a single request queue.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    /// * `backend` - The block device backend, which is cloned for each request queue.
+    /// * `driver_notify` - The object used for notifying the driver about used buffers.
+    pub fn new(mem: M, backend: B, driver_notify: S) -> Self {
+        BlockBuilder {
+            mem,
+            backend,
+            driver_notify,
+            num_queues: 1,
+            queue_size: DEFAULT_QUEUE_SIZE,
+            read_only: false,
+            writeback: None,
+            device_id: None,
+            lifetime: None,
+            queue_affinity: QueueAffinity::new(),
+            async_backend: None,
+            max_pending: None,
+        }
+    }
+
+    /// Sets the number of request queues.
+    ///
+    /// # Arguments
+    /// * `num_queues` - The number of request queues.
+    pub fn with_num_queues(mut self, num_queues: u16) -> Self {
+        self.num_queues = num_queues;
+        self
+    }
+
+    /// Sets the maximum size of the request queues.
+    ///
+    /// # Arguments
+    /// * `queue_size` - The maximum queue size.
+    pub fn with_queue_size(mut self, queue_size: u16) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Marks the device as read-only.
+    ///
+    /// # Arguments
+    /// * `read_only` - Whether the device is read-only.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Lets the driver toggle the cache mode of the device.
+    ///
+    /// # Arguments
+    /// * `writeback` - Whether the device starts in writeback mode.
+    pub fn with_writeback(mut self, writeback: bool) -> Self {
+        self.writeback = Some(writeback);
+        self
+    }
+
+    /// Sets the device id string, which is returned to the driver for `VIRTIO_BLK_T_GET_ID`
+    /// requests. By default, the id is derived from the backend with `Backend::image_id`.
+    ///
+    /// # Arguments
+    /// * `device_id` - The block device id.
+    pub fn with_device_id(mut self, device_id: [u8; VIRTIO_BLK_ID_BYTES]) -> Self {
+        self.device_id = Some(device_id);
+        self
+    }
+
+    /// Sets the lifetime information which is returned to the driver for
+    /// `VIRTIO_BLK_T_GET_LIFETIME` requests, unless the backend reports its own values with
+    /// `Backend::lifetime`. `VIRTIO_BLK_F_LIFETIME` is offered when either of them is available.
+    ///
+    /// # Arguments
+    /// * `lifetime` - The static wear estimates of the device.
+    pub fn with_lifetime(mut self, lifetime: Lifetime) -> Self {
+        self.lifetime = Some(lifetime);
+        self
+    }
+
+    /// Places the workers of the request queues on host CPUs (see `Block::queue_affinity`).
+    ///
+    /// # Arguments
+    /// * `queue_affinity` - The host CPU of each request queue.
+    pub fn with_queue_affinity(mut self, queue_affinity: QueueAffinity) -> Self {
+        self.queue_affinity = queue_affinity;
+        self
+    }
+
+    /// Executes the requests with asynchronous backends, which are created by `async_backend`
+    /// for each request queue when the device is activated, instead of the queue handlers.
+    ///
+    /// # Arguments
+    /// * `async_backend` - The factory of the asynchronous backends.
+    pub fn with_async_backend(mut self, async_backend: Arc<dyn AsyncBackendFactory>) -> Self {
+        self.async_backend = Some(async_backend);
+        self
+    }
+
+    /// Bounds the number of requests in flight on each request queue. The queue is not
+    /// consumed anymore when the bound is reached, until the asynchronous backend completes
+    /// some of the requests (see `InorderQueueHandler::with_max_pending`).
+    ///
+    /// # Arguments
+    /// * `max_pending` - The maximum number of requests in flight on each queue.
+    pub fn with_max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = Some(max_pending);
+        self
+    }
+
+    /// Builds the `Block` device.
+    pub fn build(self) -> Result<Block<M, B, S>> {
+        let num_queues = self.num_queues.max(1);
+        if self.queue_affinity.num_queues() > num_queues {
+            return Err(Error::InvalidQueueIndex(
+                self.queue_affinity.num_queues() - 1,
+            ));
+        }
+
+        let disk = StdIoBackend::new(self.backend.clone(), 0)
+            .map_err(Error::Executor)?
+            .with_read_only(self.read_only);
+        let num_sectors = disk.num_sectors();
+
+        let mut config = ConfigBuilder::new(num_sectors).with_queue_size(self.queue_size);
+        if self.num_queues > 1 {
+            config = config.with_num_queues(self.num_queues);
+        }
+        if let Some(writeback) = self.writeback {
+            config = config.with_writeback(writeback);
+        }
+
+        let mut device_features = config.features()
+            | disk.device_features()
+            | (1 << VIRTIO_F_VERSION_1)
+            | (1 << VIRTIO_F_RING_EVENT_IDX)
+            | (1 << VIRTIO_BLK_F_FLUSH);
+        if self.lifetime.is_some() || self.backend.lifetime().is_some() {
+            device_features |= 1 << VIRTIO_BLK_F_LIFETIME;
+        }
+
+        let config_space: Vec<u8> = config.build().map_err(Error::Config)?.into();
+        let queues = (0..num_queues)
+            .map(|_| Queue::new(self.mem.clone(), self.queue_size))
+            .collect();
+
+        let device_id = self.device_id.or_else(|| self.backend.image_id());
+
+        Ok(Block {
+            cfg: VirtioConfig::new(device_features, queues, config_space.clone()),
+            initial_config_space: config_space,
+            backend: self.backend,
+            read_only: self.read_only,
+            device_id,
+            lifetime: self.lifetime,
+            driver_notify: Arc::new(self.driver_notify),
+            handlers: Vec::new(),
+            drained: false,
+            dirty_tracker: None,
+            queue_affinity: self.queue_affinity,
+            async_backend: self.async_backend,
+            max_pending: self.max_pending,
+        })
+    }
+}
+
+/// The block specific state of a `Block` device.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockState {
+    /// The configuration space that's restored on reset.
+    pub initial_config_space: Vec<u8>,
+    /// Whether the device is read-only.
+    pub read_only: bool,
+    /// The device id string.
+    pub device_id: Option<[u8; VIRTIO_BLK_ID_BYTES]>,
+    /// The static wear estimates of the device.
+    pub lifetime: Option<Lifetime>,
+    /// Whether request processing is stopped by `drain`.
+    pub drained: bool,
+    /// The head indices of the requests which were popped from each request queue, but not
+    /// completed. They are resubmitted the next time the queue is processed.
+    pub inflight: Vec<Vec<u16>>,
+}
+
+impl StateCodec for Lifetime {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.pre_eol_info.encode(buf);
+        self.device_lifetime_est_typ_a.encode(buf);
+        self.device_lifetime_est_typ_b.encode(buf);
+    }
+
+    fn decode(buf: &mut &[u8]) -> Option<Self> {
+        Some(Lifetime {
+            pre_eol_info: StateCodec::decode(buf)?,
+            device_lifetime_est_typ_a: StateCodec::decode(buf)?,
+            device_lifetime_est_typ_b: StateCodec::decode(buf)?,
+        })
+    }
+}
+
+impl StateCodec for BlockState {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.initial_config_space.encode(buf);
+        self.read_only.encode(buf);
+        self.device_id.encode(buf);
+        self.lifetime.encode(buf);
+        self.drained.encode(buf);
+        self.inflight.encode(buf);
+    }
+
+    fn decode(buf: &mut &[u8]) -> Option<Self> {
+        Some(BlockState {
+            initial_config_space: StateCodec::decode(buf)?,
+            read_only: StateCodec::decode(buf)?,
+            device_id: StateCodec::decode(buf)?,
+            lifetime: StateCodec::decode(buf)?,
+            drained: StateCodec::decode(buf)?,
+            inflight: StateCodec::decode(buf)?,
+        })
+    }
+}
+
+impl VersionedState for BlockState {
+    const VERSION: u16 = 2;
+
+    fn decode_version(version: u16, buf: &mut &[u8]) -> persist::Result<Self> {
+        match version {
+            1 => BlockStateV1::decode(buf).map(BlockState::from),
+            Self::VERSION => BlockState::decode(buf),
+            _ => return Err(persist::Error::UnsupportedVersion(version)),
+        }
+        .ok_or(persist::Error::InvalidPayload)
+    }
+
+    fn encode_version(&self, version: u16, buf: &mut Vec<u8>) -> persist::Result<()> {
+        match version {
+            // The requests which are in flight would be lost.
+            1 if self.inflight.iter().all(Vec::is_empty) => BlockStateV1 {
+                initial_config_space: self.initial_config_space.clone(),
+                read_only: self.read_only,
+                device_id: self.device_id,
+                lifetime: self.lifetime,
+                drained: self.drained,
+            }
+            .encode(buf),
+            Self::VERSION => self.encode(buf),
+            _ => return Err(persist::Error::UnsupportedVersion(version)),
+        }
+        Ok(())
+    }
+}
+
+/// The block specific state of a `Block` device, before the in-flight requests were tracked
+/// (version 1).
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockStateV1 {
+    /// The configuration space that's restored on reset.
+    pub initial_config_space: Vec<u8>,
+    /// Whether the device is read-only.
+    pub read_only: bool,
+    /// The device id string.
+    pub device_id: Option<[u8; VIRTIO_BLK_ID_BYTES]>,
+    /// The static wear estimates of the device.
+    pub lifetime: Option<Lifetime>,
+    /// Whether request processing is stopped by `drain`.
+    pub drained: bool,
+}
+
+impl From<BlockStateV1> for BlockState {
+    fn from(state: BlockStateV1) -> Self {
+        BlockState {
+            initial_config_space: state.initial_config_space,
+            read_only: state.read_only,
+            device_id: state.device_id,
+            lifetime: state.lifetime,
+            drained: state.drained,
+            // No requests were left in flight by the older releases.
+            inflight: Vec::new(),
+        }
+    }
+}
+
+impl StateCodec for BlockStateV1 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.initial_config_space.encode(buf);
+        self.read_only.encode(buf);
+        self.device_id.encode(buf);
+        self.lifetime.encode(buf);
+        self.drained.encode(buf);
+    }
+
+    fn decode(buf: &mut &[u8]) -> Option<Self> {
+        Some(BlockStateV1 {
+            initial_config_space: StateCodec::decode(buf)?,
+            read_only: StateCodec::decode(buf)?,
+            device_id: StateCodec::decode(buf)?,
+            lifetime: StateCodec::decode(buf)?,
+            drained: StateCodec::decode(buf)?,
+        })
+    }
+}
+
+/// The resources a `Block` device is restored with, which are not part of its state.
+#[derive(Debug)]
+pub struct BlockConstructorArgs<M, B, S> {
+    /// The guest memory.
+    pub mem: M,
+    /// The block device backend, which holds the disk contents at the time the state was saved.
+    pub backend: B,
+    /// The object used for notifying the driver about used buffers.
+    pub driver_notify: S,
+    /// The factory of the asynchronous backends, if any (see
+    /// `BlockBuilder::with_async_backend`).
+    pub async_backend: Option<Arc<dyn AsyncBackendFactory>>,
+    /// The maximum number of requests in flight on each request queue, if any (see
+    /// `BlockBuilder::with_max_pending`).
+    pub max_pending: Option<usize>,
+}
+
+/// A virtio block device.
+#[derive(Debug, VirtioDeviceCommon)]
+#[virtio(device_type = VIRTIO_ID_BLOCK)]
+pub struct Block<M: GuestAddressSpace, B: Backend + Clone, S: SignalUsedQueue> {
+    #[virtio(config)]
+    cfg: VirtioConfig<M>,
+    // The configuration space that's restored on reset.
+    initial_config_space: Vec<u8>,
+    backend: B,
+    read_only: bool,
+    device_id: Option<[u8; VIRTIO_BLK_ID_BYTES]>,
+    lifetime: Option<Lifetime>,
+    driver_notify: Arc<S>,
+    // One handler for each request queue, which are available while the device is activated.
+    handlers: Vec<InorderQueueHandler<M, B, QueueSignal<S>>>,
+    // Whether request processing is stopped by `drain`.
+    drained: bool,
+    // Tracks the writes of the device to guest memory, if any.
+    dirty_tracker: Option<Arc<dyn DirtyTracker>>,
+    // The host CPUs the request queues are processed on.
+    queue_affinity: QueueAffinity,
+    // Creates the asynchronous backends of the handlers, if any.
+    async_backend: Option<Arc<dyn AsyncBackendFactory>>,
+    // The maximum number of requests in flight on each request queue, if any.
+    max_pending: Option<usize>,
+}
+
+impl<M, B, S> Block<M, B, S>
+where
+    M: GuestAddressSpace,
+    B: Backend + Clone,
+    S: SignalUsedQueue,
+{
+    /// Returns whether the device is activated.
+    pub fn is_activated(&self) -> bool {
+        self.cfg.device_activated
+    }
+
+    /// Returns the device id string, if any.
+    pub fn device_id(&self) -> Option<[u8; VIRTIO_BLK_ID_BYTES]> {
+        self.device_id
+    }
+
+    /// Returns the host CPUs the request queues have to be processed on. When the VMM processes
+    /// each queue from a separate worker thread, the worker of queue `index` is expected to
+    /// call `queue_affinity().pin(index)` before processing it.
+    pub fn queue_affinity(&self) -> &QueueAffinity {
+        &self.queue_affinity
+    }
+
+    /// Changes the host CPUs the request queues have to be processed on (i.e. for a device
+    /// which was restored from a saved state).
+    ///
+    /// # Arguments
+    /// * `queue_affinity` - The host CPU of each request queue.
+    pub fn set_queue_affinity(&mut self, queue_affinity: QueueAffinity) -> Result<()> {
+        let num_queues = self.cfg.queues.len() as u16;
+        if queue_affinity.num_queues() > num_queues {
+            return Err(Error::InvalidQueueIndex(queue_affinity.num_queues() - 1));
+        }
+        self.queue_affinity = queue_affinity;
+        Ok(())
+    }
+
+    /// Returns the capacity of the device (expressed in 512-byte sectors).
+    pub fn capacity(&self) -> u64 {
+        let offset = ConfigSpace::CAPACITY_OFFSET;
+        self.cfg.config_space[offset..offset + 8]
+            .try_into()
+            .map_or(0, u64::from_le_bytes)
+    }
+
+    /// Processes the requests available in the queue with the specified index. This has to be
+    /// called when the driver notifies the device about the queue.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the request queue.
+    pub fn process_queue(&mut self, index: u16) -> Result<()> {
+        // The driver toggles the cache mode by writing the configuration space, so the handler
+        // is brought up to date before processing new requests.
+        let writeback = self.writeback();
+        let handler = self
+            .handlers
+            .get_mut(usize::from(index))
+            .ok_or(Error::InvalidQueueIndex(index))?;
+        // The requests made available while the device is drained are processed on `resume`.
+        if self.drained {
+            return Ok(());
+        }
+        handler
+            .disk_mut()
+            .set_writeback(writeback)
+            .map_err(Error::Executor)?;
+        handler.process_queue().map_err(Error::QueueHandler)
+    }
+
+    /// Adds the requests completed by the asynchronous backend of the queue with the specified
+    /// index to the used ring, and resumes the processing of the queue if it was stopped by
+    /// the bound on the requests in flight. This has to be called when the file descriptor
+    /// returned by `completion_receiver` for the queue becomes readable.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the request queue.
+    pub fn process_completions(&mut self, index: u16) -> Result<()> {
+        let handler = self
+            .handlers
+            .get_mut(usize::from(index))
+            .ok_or(Error::InvalidQueueIndex(index))?;
+        // The backends don't execute anything while the device is drained, and the completions
+        // which arrive late are received on `resume`.
+        if self.drained {
+            return Ok(());
+        }
+        handler.process_completions().map_err(Error::QueueHandler)
+    }
+
+    /// Returns the receiving end of the completion channel of the queue with the specified
+    /// index, if the requests are executed by an asynchronous backend (i.e. for registering
+    /// its file descriptor with an event loop). The channels are created when the device is
+    /// activated.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the request queue.
+    pub fn completion_receiver(&self, index: u16) -> Option<&CompletionReceiver> {
+        self.handlers
+            .get(usize::from(index))
+            .and_then(InorderQueueHandler::completion_receiver)
+    }
+
+    /// Quiesces the device, i.e. before taking a snapshot of the VM or migrating it.
+    ///
+    /// The device stops consuming new requests from the queues, and the backend is flushed once
+    /// all the in-flight requests are completed. When this method returns successfully, the
+    /// backing file holds a crash-consistent state of the disk, which doesn't change until
+    /// [`resume`](struct.Block.html#method.resume) is called. Driver notifications received in
+    /// the meantime are not lost, the corresponding requests are processed on `resume`.
+    ///
+    /// This is the same as calling `VirtioDevicePause::pause` with `flush` set.
+    pub fn drain(&mut self) -> Result<()> {
+        VirtioDevicePause::pause(self, true)
+    }
+
+    /// Returns whether the device is drained.
+    pub fn is_drained(&self) -> bool {
+        self.drained
+    }
+
+    /// Resumes request processing after a `drain`, and processes the requests which were made
+    /// available in the meantime.
+    pub fn resume(&mut self) -> Result<()> {
+        self.drained = false;
+        // The number of queues always fits in an `u16`.
+        for index in 0..self.handlers.len() as u16 {
+            self.process_queue(index)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the object which tracks the writes of the device to guest memory (i.e. to the used
+    /// rings and to the request buffers), such that the written pages are transferred again
+    /// during live migration. The tracker applies to the running handlers, and to the ones
+    /// created when the device is activated again.
+    ///
+    /// # Arguments
+    /// * `dirty_tracker` - The tracker, or `None` to stop tracking writes.
+    pub fn set_dirty_tracker(&mut self, dirty_tracker: Option<Arc<dyn DirtyTracker>>) {
+        for handler in self.handlers.iter_mut() {
+            handler.queue_mut().set_dirty_tracker(dirty_tracker.clone());
+            handler.disk_mut().set_dirty_tracker(dirty_tracker.clone());
+        }
+        self.dirty_tracker = dirty_tracker;
+    }
+
+    // Returns the cache mode from the configuration space.
+    fn writeback(&self) -> bool {
+        self.cfg
+            .config_space
+            .get(ConfigSpace::WRITEBACK_OFFSET)
+            .is_some_and(|&v| v != 0)
+    }
+
+    // Creates the handlers of all the request queues.
+    fn create_handlers(&self) -> Result<Vec<InorderQueueHandler<M, B, QueueSignal<S>>>>
+    where
+        M: Clone,
+    {
+        self.cfg
+            .queues
+            .iter()
+            .enumerate()
+            // The number of queues always fits in an `u16`.
+            .map(|(i, queue)| self.create_handler(i as u16, queue.clone()))
+            .collect()
+    }
+
+    fn create_handler(
+        &self,
+        index: u16,
+        mut queue: Queue<M>,
+    ) -> Result<InorderQueueHandler<M, B, QueueSignal<S>>> {
+        let mut disk = StdIoBackend::new(self.backend.clone(), self.cfg.driver_features)
+            .map_err(Error::Executor)?
+            .with_read_only(self.read_only);
+        queue.set_dirty_tracker(self.dirty_tracker.clone());
+        disk.set_dirty_tracker(self.dirty_tracker.clone());
+        if let Some(device_id) = self.device_id {
+            disk = disk.with_device_id(device_id);
+        }
+        if let Some(lifetime) = self.lifetime {
+            disk = disk.with_lifetime(lifetime);
+        }
+        if self.cfg.driver_features & (1 << VIRTIO_BLK_F_CONFIG_WCE) != 0 {
+            disk.set_writeback(self.writeback())
+                .map_err(Error::Executor)?;
+        }
+
+        let signal = QueueSignal {
+            interrupt_status: self.cfg.interrupt_status.clone(),
+            driver_notify: self.driver_notify.clone(),
+        };
+        let queue_size = queue.actual_size();
+        let mut handler = InorderQueueHandler::new(queue, disk, signal).with_queue_index(index);
+        if let Some(max_pending) = self.max_pending {
+            handler = handler.with_max_pending(max_pending);
+        }
+        match self.async_backend.as_ref() {
+            Some(async_backend) => {
+                // Each head index is in flight at most once, so the channel never fills up.
+                let (sender, receiver) = completion_channel::channel(usize::from(queue_size))
+                    .map_err(Error::CompletionChannel)?;
+                let backend = async_backend
+                    .create(index, sender)
+                    .map_err(Error::AsyncBackend)?;
+                Ok(handler.with_async_backend(backend, receiver))
+            }
+            None => Ok(handler),
+        }
+    }
+}
+
+impl<M, B, S> Block<M, B, S>
+where
+    M: GuestAddressSpace,
+    B: Backend + Clone,
+    S: SignalUsedQueue + SignalConfigChange,
+{
+    /// Grows the backend to `size` bytes while the device is running.
+    ///
+    /// The `capacity` field of the configuration space and the configuration generation are
+    /// updated, and a configuration change interrupt is raised when the device is activated,
+    /// such that the driver can pick up the new capacity.
+    ///
+    /// # Arguments
+    /// * `size` - The new size of the backend, which has to be a multiple of the sector size,
+    ///   and can't be smaller than the current one.
+    pub fn resize(&mut self, size: u64) -> Result<()> {
+        if size % SECTOR_SIZE != 0 || size >> SECTOR_SHIFT < self.capacity() {
+            return Err(Error::InvalidSize(size));
+        }
+        self.backend.set_size(size).map_err(Error::Resize)?;
+        for handler in self.handlers.iter_mut() {
+            handler
+                .disk_mut()
+                .update_num_sectors()
+                .map_err(Error::Executor)?;
+        }
+
+        // The new capacity also survives device resets.
+        let capacity = (size >> SECTOR_SHIFT).to_le_bytes();
+        let offset = ConfigSpace::CAPACITY_OFFSET;
+        self.cfg.config_space[offset..offset + capacity.len()].copy_from_slice(&capacity);
+        self.initial_config_space[offset..offset + capacity.len()].copy_from_slice(&capacity);
+        self.cfg.config_generation = self.cfg.config_generation.wrapping_add(1);
+
+        if self.cfg.device_activated {
+            self.cfg.signal_config_change();
+            self.driver_notify.signal_config_change();
+        }
+        Ok(())
+    }
+}
+
+impl<M, B, S> VirtioDeviceActions for Block<M, B, S>
+where
+    M: GuestAddressSpace + Clone,
+    B: Backend + Clone,
+    S: SignalUsedQueue,
+{
+    type E = Error;
+
+    fn activate(&mut self) -> Result<()> {
+        if self.cfg.device_activated {
+            return Err(Error::AlreadyActivated);
+        }
+        if !self.cfg.queues_valid() {
+            return Err(Error::InvalidQueues);
+        }
+
+        self.handlers = self.create_handlers()?;
+        self.cfg.device_activated = true;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        // The requests which are still in flight (i.e. executed by an asynchronous backend, or
+        // left behind by a failure) must neither be completed, nor resubmitted, after the
+        // queues are reset. The reset goes on when a backend doesn't stop them in time, since
+        // the driver can't be told to retry it.
+        for (index, handler) in self.handlers.iter_mut().enumerate() {
+            match handler.cancel_inflight(INFLIGHT_TIMEOUT) {
+                Ok(0) => {}
+                Ok(count) => warn!("cancelled {} requests of queue {} on reset", count, index),
+                Err(e) => error!("failed to cancel the requests of queue {}: {}", index, e),
+            }
+        }
+        self.handlers.clear();
+
+        let cfg = &mut self.cfg;
+        cfg.driver_features = 0;
+        cfg.device_features_select = 0;
+        cfg.driver_features_select = 0;
+        cfg.device_status = 0;
+        cfg.queue_select = 0;
+        cfg.queues.iter_mut().for_each(Queue::reset);
+        cfg.config_space = self.initial_config_space.clone();
+        cfg.device_activated = false;
+        cfg.reset_interrupt_status();
+        Ok(())
+    }
+}
+
+impl<M, B, S> VirtioDevicePause for Block<M, B, S>
+where
+    M: GuestAddressSpace,
+    B: Backend + Clone,
+    S: SignalUsedQueue,
+{
+    type E = Error;
+
+    fn pause(&mut self, flush: bool) -> Result<()> {
+        self.drained = true;
+        // The requests executed by the handlers themselves are completed when `process_queue`
+        // returns, while the asynchronous backends are waited for. The handlers share the
+        // backend, which is flushed only once.
+        for handler in self.handlers.iter_mut() {
+            handler
+                .wait_for_completions(INFLIGHT_TIMEOUT)
+                .map_err(Error::QueueHandler)?;
+        }
+        if flush {
+            self.backend.fsync().map_err(Error::Flush)?;
+        }
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        Block::resume(self)
+    }
+
+    fn is_paused(&self) -> bool {
+        self.drained
+    }
+}
+
+impl<M, B, S> VirtioDeviceEvents for Block<M, B, S>
+where
+    M: GuestAddressSpace,
+    B: Backend + Clone,
+    S: SignalUsedQueue,
+{
+    fn reattach<C: EventsContext>(&self, events_ctx: &mut C) -> result::Result<(), C::E> {
+        // The driver notifications reach the device through `queue_notify`, so only the
+        // interrupt has to be registered.
+        match self.driver_notify.irqfd() {
+            Some(irqfd) => events_ctx.register_irqfd(irqfd),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<M, B, S> VirtioDevicePersist for Block<M, B, S>
+where
+    M: GuestAddressSpace + Clone,
+    B: Backend + Clone,
+    S: SignalUsedQueue,
+{
+    type State = BlockState;
+    type ConstructorArgs = BlockConstructorArgs<M, B, S>;
+    type E = Error;
+
+    const STATE_VERSION: u16 = BlockState::VERSION;
+
+    fn save(&self) -> VirtioDeviceState<BlockState> {
+        // The handlers process the queues on copies of them, which hold the current ring
+        // indices.
+        let mut config = self.cfg.state();
+        for (queue, handler) in config.queues.iter_mut().zip(self.handlers.iter()) {
+            *queue = handler.queue().state();
+        }
+        VirtioDeviceState {
+            version: Self::STATE_VERSION,
+            config,
+            device: BlockState {
+                initial_config_space: self.initial_config_space.clone(),
+                read_only: self.read_only,
+                device_id: self.device_id,
+                lifetime: self.lifetime,
+                drained: self.drained,
+                inflight: self
+                    .handlers
+                    .iter()
+                    .map(InorderQueueHandler::inflight)
+                    .collect(),
+            },
+        }
+    }
+
+    fn restore(
+        args: BlockConstructorArgs<M, B, S>,
+        state: &VirtioDeviceState<BlockState>,
+    ) -> Result<Self> {
+        if state.version != Self::STATE_VERSION {
+            return Err(Error::UnsupportedStateVersion(state.version));
+        }
+        let mut block = Block {
+            cfg: VirtioConfig::from_state(args.mem, &state.config),
+            initial_config_space: state.device.initial_config_space.clone(),
+            backend: args.backend,
+            read_only: state.device.read_only,
+            device_id: state.device.device_id,
+            lifetime: state.device.lifetime,
+            driver_notify: Arc::new(args.driver_notify),
+            handlers: Vec::new(),
+            drained: state.device.drained,
+            dirty_tracker: None,
+            queue_affinity: QueueAffinity::new(),
+            async_backend: args.async_backend,
+            max_pending: args.max_pending,
+        };
+        if block.cfg.device_activated {
+            // The guest memory layout or the queue configuration may not match anymore (i.e.
+            // when the state comes from a different host), so we fail the restore instead of
+            // having the handlers access memory the driver never set up.
+            for (index, queue) in block.cfg.queues.iter().enumerate() {
+                queue
+                    .validate()
+                    .map_err(|reason| Error::InvalidRestoredQueue(index as u16, reason))?;
+            }
+            block.handlers = block.create_handlers()?;
+            for (handler, inflight) in block.handlers.iter_mut().zip(&state.device.inflight) {
+                handler.set_inflight(inflight.clone());
+            }
+        }
+        Ok(block)
+    }
+}
+
+impl<M, B, S> VirtioMmioDevice<M> for Block<M, B, S>
+where
+    M: GuestAddressSpace + Clone + 'static,
+    B: Backend + Clone,
+    S: SignalUsedQueue,
+{
+    fn queue_notify(&mut self, val: u32) {
+        // Queue indices always fit in an `u16`.
+        if let Err(e) = self.process_queue(val as u16) {
+            error!("failed to process queue {}: {}", val, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::mem::offset_of;
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::sync::atomic::Ordering;
+
+    use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap};
+    use vmm_sys_util::eventfd::EventFd;
+    use vmm_sys_util::tempfile::TempFile;
+
+    use virtio_device::mock::{activate, check_golden, hex_dump, MmioDriver};
+    use virtio_device::status::{ACKNOWLEDGE, DRIVER, DRIVER_OK, FEATURES_OK};
+    use virtio_device::{VirtioDevice, VIRTIO_MMIO_INT_CONFIG};
+    use virtio_queue::mock::VirtQueue;
+    use virtio_queue::{Descriptor, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    use crate::defs::{
+        SECTOR_SIZE, VIRTIO_BLK_F_MQ, VIRTIO_BLK_F_RO, VIRTIO_BLK_F_SEG_MAX, VIRTIO_BLK_S_OK,
+        VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT,
+    };
+    use crate::queue_handler::tests::{TestAsyncBackend, TestAsyncState};
+    use crate::shared_file::SharedFile;
+    use crate::stdio_executor::tests::TestTracker;
+
+    type Mem = Arc<GuestMemoryMmap>;
+
+    fn block(mem: &Mem, num_queues: u16) -> Block<Mem, SharedFile, EventFd> {
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x10_0000).unwrap();
+        BlockBuilder::new(mem.clone(), SharedFile::new(file), EventFd::new(0).unwrap())
+            .with_num_queues(num_queues)
+            .with_queue_size(16)
+            .with_writeback(true)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_build() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let block = block(&mem, 2);
+
+        assert_eq!(VirtioDevice::device_type(&block), VIRTIO_ID_BLOCK);
+        assert_eq!(block.num_queues(), 2);
+        assert_eq!(block.queue(0).unwrap().max_size(), 16);
+        assert!(!block.is_activated());
+
+        let features = block.device_features();
+        for &feature in [
+            VIRTIO_F_VERSION_1,
+            VIRTIO_BLK_F_FLUSH,
+            VIRTIO_BLK_F_CONFIG_WCE,
+            VIRTIO_BLK_F_MQ,
+            VIRTIO_BLK_F_SEG_MAX,
+        ]
+        .iter()
+        {
+            assert_ne!(features & (1 << feature), 0);
+        }
+        assert_eq!(features & (1 << VIRTIO_BLK_F_RO), 0);
+        assert_eq!(features & (1 << VIRTIO_BLK_F_LIFETIME), 0);
+
+        let mut capacity = [0u8; 8];
+        block.read_config(0, &mut capacity);
+        assert_eq!(u64::from_le_bytes(capacity), 0x10_0000 / SECTOR_SIZE);
+        assert!(block.writeback());
+        assert_eq!(block.device_id(), block.backend.image_id());
+        assert!(block.device_id().is_some());
+
+        let device_id = [b'a'; VIRTIO_BLK_ID_BYTES];
+        let other = BlockBuilder::new(mem.clone(), block.backend.clone(), EventFd::new(0).unwrap())
+            .with_device_id(device_id)
+            .build()
+            .unwrap();
+        assert_eq!(other.device_id(), Some(device_id));
+
+        let lifetime = Lifetime {
+            pre_eol_info: 1,
+            device_lifetime_est_typ_a: 2,
+            device_lifetime_est_typ_b: 3,
+        };
+        let other = BlockBuilder::new(mem.clone(), block.backend.clone(), EventFd::new(0).unwrap())
+            .with_lifetime(lifetime)
+            .build()
+            .unwrap();
+        assert_ne!(other.device_features() & (1 << VIRTIO_BLK_F_LIFETIME), 0);
+        assert_eq!(other.lifetime, Some(lifetime));
+
+        assert!(
+            BlockBuilder::new(mem.clone(), block.backend.clone(), EventFd::new(0).unwrap())
+                .with_queue_size(1)
+                .build()
+                .is_err()
+        );
+
+        // The workers of the queues which exist can be placed on host CPUs.
+        assert_eq!(block.queue_affinity(), &QueueAffinity::new());
+        let affinity = QueueAffinity::new().with_cpu(1, 0);
+        let mut other =
+            BlockBuilder::new(mem.clone(), block.backend.clone(), EventFd::new(0).unwrap())
+                .with_num_queues(2)
+                .with_queue_affinity(affinity.clone())
+                .build()
+                .unwrap();
+        assert_eq!(other.queue_affinity().cpu(1), Some(0));
+        assert!(matches!(
+            other.set_queue_affinity(affinity.clone().with_cpu(2, 0)),
+            Err(Error::InvalidQueueIndex(2))
+        ));
+        other.set_queue_affinity(QueueAffinity::new()).unwrap();
+        assert_eq!(other.queue_affinity().cpu(1), None);
+        assert!(matches!(
+            BlockBuilder::new(mem.clone(), block.backend.clone(), EventFd::new(0).unwrap())
+                .with_queue_affinity(affinity)
+                .build(),
+            Err(Error::InvalidQueueIndex(1))
+        ));
+    }
+
+    #[test]
+    fn test_activate_reset() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let mut block = block(&mem, 2);
+
+        // The queues are not configured yet.
+        assert!(matches!(
+            VirtioDeviceActions::activate(&mut block),
+            Err(Error::InvalidQueues)
+        ));
+        assert!(matches!(
+            block.process_queue(0),
+            Err(Error::InvalidQueueIndex(0))
+        ));
+
+        let vqs = [
+            VirtQueue::new(GuestAddress(0), &mem, 16),
+            VirtQueue::new(GuestAddress(0x1000), &mem, 16),
+        ];
+        activate(&mut block, &vqs, 0);
+        assert!(block.is_activated());
+        assert_eq!(
+            block.device_status(),
+            ACKNOWLEDGE | DRIVER | FEATURES_OK | DRIVER_OK
+        );
+        assert!(matches!(
+            VirtioDeviceActions::activate(&mut block),
+            Err(Error::AlreadyActivated)
+        ));
+
+        // Add a write request to the second queue.
+        let vq = &vqs[1];
+        mem.write_obj(VIRTIO_BLK_T_OUT, GuestAddress(0x1_0000))
+            .unwrap();
+        mem.write_obj(1u64, GuestAddress(0x1_0008)).unwrap();
+        mem.write_slice(&[0xaa; SECTOR_SIZE as usize], GuestAddress(0x2_0000))
+            .unwrap();
+        vq.dtable(0).set(0x1_0000, 0x10, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1)
+            .set(0x2_0000, SECTOR_SIZE as u32, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable(2).set(0x3_0000, 1, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring(0).store(0);
+        vq.avail.idx().store(1);
+
+        // The driver disables the writeback cache before sending the request.
+        block.write_config(ConfigSpace::WRITEBACK_OFFSET, &[0]);
+        block.queue_notify(1);
+        assert_eq!(vq.used.idx().load(), 1);
+        assert_eq!(vqs[0].used.idx().load(), 0);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x3_0000)).unwrap(),
+            VIRTIO_BLK_S_OK
+        );
+        assert!(!block.handlers[1].disk().is_writeback());
+        assert_eq!(
+            block.interrupt_status().load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_VRING
+        );
+        assert_eq!(block.driver_notify.read().unwrap(), 1);
+
+        let mut buf = [0u8; SECTOR_SIZE as usize];
+        block
+            .backend
+            .file()
+            .read_exact_at(&mut buf, SECTOR_SIZE)
+            .unwrap();
+        assert_eq!(buf, [0xaa; SECTOR_SIZE as usize]);
+
+        // The driver resets the device.
+        block.ack_device_status(0);
+        assert!(!block.is_activated());
+        assert_eq!(block.device_status(), 0);
+        assert_eq!(block.driver_features(), 0);
+        assert_eq!(block.interrupt_status().load(Ordering::SeqCst), 0);
+        assert!(!block.queue(1).unwrap().ready);
+        assert!(block.writeback());
+        assert!(matches!(
+            block.process_queue(1),
+            Err(Error::InvalidQueueIndex(1))
+        ));
+
+        // The device can be initialized again.
+        activate(&mut block, &vqs, 0);
+        assert!(block.is_activated());
+    }
+
+    #[test]
+    fn test_drain() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let mut block = block(&mem, 1);
+        let vqs = [VirtQueue::new(GuestAddress(0), &mem, 16)];
+        activate(&mut block, &vqs, 0);
+
+        block.drain().unwrap();
+        assert!(block.is_drained());
+
+        // The request is left in the available ring while the device is drained.
+        let vq = &vqs[0];
+        mem.write_obj(VIRTIO_BLK_T_OUT, GuestAddress(0x1_0000))
+            .unwrap();
+        mem.write_obj(1u64, GuestAddress(0x1_0008)).unwrap();
+        mem.write_slice(&[0xaa; SECTOR_SIZE as usize], GuestAddress(0x2_0000))
+            .unwrap();
+        vq.dtable(0).set(0x1_0000, 0x10, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1)
+            .set(0x2_0000, SECTOR_SIZE as u32, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable(2).set(0x3_0000, 1, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring(0).store(0);
+        vq.avail.idx().store(1);
+        block.queue_notify(0);
+        assert_eq!(vq.used.idx().load(), 0);
+
+        let mut buf = [0u8; SECTOR_SIZE as usize];
+        block
+            .backend
+            .file()
+            .read_exact_at(&mut buf, SECTOR_SIZE)
+            .unwrap();
+        assert_eq!(buf, [0u8; SECTOR_SIZE as usize]);
+
+        block.resume().unwrap();
+        assert!(!block.is_drained());
+        assert_eq!(vq.used.idx().load(), 1);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x3_0000)).unwrap(),
+            VIRTIO_BLK_S_OK
+        );
+        block
+            .backend
+            .file()
+            .read_exact_at(&mut buf, SECTOR_SIZE)
+            .unwrap();
+        assert_eq!(buf, [0xaa; SECTOR_SIZE as usize]);
+    }
+
+    #[test]
+    fn test_mmio_driver() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let mut driver = MmioDriver::new(block(&mem, 2), &mem, GuestAddress(0));
+        let features = driver.initialize(u64::MAX, 2, 16).unwrap();
+        assert_ne!(features & (1 << VIRTIO_BLK_F_MQ), 0);
+        assert!(driver.device().is_activated());
+
+        let mut capacity = [0u8; 8];
+        driver.read_config(0, &mut capacity);
+        assert_eq!(u64::from_le_bytes(capacity), 0x10_0000 / SECTOR_SIZE);
+
+        // Write a sector through the second queue.
+        mem.write_obj(VIRTIO_BLK_T_OUT, GuestAddress(0x1_0000))
+            .unwrap();
+        mem.write_obj(1u64, GuestAddress(0x1_0008)).unwrap();
+        mem.write_slice(&[0xaa; SECTOR_SIZE as usize], GuestAddress(0x2_0000))
+            .unwrap();
+        let head = driver
+            .add_buffers(
+                1,
+                &[
+                    Descriptor::new(0x1_0000, 0x10, 0, 0),
+                    Descriptor::new(0x2_0000, SECTOR_SIZE as u32, 0, 0),
+                    Descriptor::new(0x3_0000, 1, VIRTQ_DESC_F_WRITE, 0),
+                ],
+            )
+            .unwrap();
+        driver.kick(1);
+        assert_eq!(driver.ack_interrupt(), u32::from(VIRTIO_MMIO_INT_VRING));
+        assert_eq!(driver.pop_used(1), Some((head, 1)));
+        assert_eq!(driver.pop_used(0), None);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x3_0000)).unwrap(),
+            VIRTIO_BLK_S_OK
+        );
+
+        // Read it back through the first queue.
+        mem.write_obj(VIRTIO_BLK_T_IN, GuestAddress(0x1_0000))
+            .unwrap();
+        let head = driver
+            .add_buffers(
+                0,
+                &[
+                    Descriptor::new(0x1_0000, 0x10, 0, 0),
+                    Descriptor::new(0x4_0000, SECTOR_SIZE as u32, VIRTQ_DESC_F_WRITE, 0),
+                    Descriptor::new(0x3_0000, 1, VIRTQ_DESC_F_WRITE, 0),
+                ],
+            )
+            .unwrap();
+        driver.kick(0);
+        assert_eq!(driver.ack_interrupt(), u32::from(VIRTIO_MMIO_INT_VRING));
+        assert_eq!(driver.pop_used(0), Some((head, SECTOR_SIZE as u32 + 1)));
+        let mut buf = [0u8; SECTOR_SIZE as usize];
+        mem.read_slice(&mut buf, GuestAddress(0x4_0000)).unwrap();
+        assert_eq!(buf, [0xaa; SECTOR_SIZE as usize]);
+        assert_eq!(driver.device().driver_notify.read().unwrap(), 2);
+
+        // The driver resets the device.
+        driver.reset();
+        assert!(!driver.device().is_activated());
+        // The device can be initialized again, but it needs all its queues.
+        assert!(driver.initialize(u64::MAX, 1, 16).is_err());
+        assert!(driver.initialize(u64::MAX, 2, 16).is_ok());
+        assert!(driver.device().is_activated());
+    }
+
+    #[test]
+    fn test_multiqueue() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+
+        // A single request queue doesn't need `VIRTIO_BLK_F_MQ`.
+        assert_eq!(block(&mem, 1).device_features() & (1 << VIRTIO_BLK_F_MQ), 0);
+
+        let mut driver = MmioDriver::new(block(&mem, 4), &mem, GuestAddress(0));
+        let mut num_queues = [0u8; 2];
+        driver.read_config(offset_of!(ConfigSpace, num_queues) as u64, &mut num_queues);
+        assert_eq!(u16::from_le_bytes(num_queues), 4);
+        let features = driver.initialize(u64::MAX, 4, 16).unwrap();
+        assert_ne!(features & (1 << VIRTIO_BLK_F_MQ), 0);
+        assert_eq!(driver.device().handlers.len(), 4);
+
+        // Each queue writes its own sector, and only its used ring is updated.
+        for queue in 0..4u16 {
+            let base = 0x2_0000 + u64::from(queue) * 0x1_0000;
+            mem.write_obj(VIRTIO_BLK_T_OUT, GuestAddress(base)).unwrap();
+            mem.write_obj(u64::from(queue), GuestAddress(base + 8))
+                .unwrap();
+            mem.write_slice(
+                &[queue as u8 + 1; SECTOR_SIZE as usize],
+                GuestAddress(base + 0x1000),
+            )
+            .unwrap();
+            let head = driver
+                .add_buffers(
+                    queue,
+                    &[
+                        Descriptor::new(base, 0x10, 0, 0),
+                        Descriptor::new(base + 0x1000, SECTOR_SIZE as u32, 0, 0),
+                        Descriptor::new(base + 0x2000, 1, VIRTQ_DESC_F_WRITE, 0),
+                    ],
+                )
+                .unwrap();
+            driver.kick(queue);
+            assert_eq!(driver.ack_interrupt(), u32::from(VIRTIO_MMIO_INT_VRING));
+            for other in 0..4u16 {
+                let expected = Some((head, 1)).filter(|_| other == queue);
+                assert_eq!(driver.pop_used(other), expected);
+            }
+            assert_eq!(
+                mem.read_obj::<u8>(GuestAddress(base + 0x2000)).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+        }
+
+        // All the queues share the same backend.
+        let mut buf = [0u8; SECTOR_SIZE as usize];
+        for sector in 0..4u64 {
+            driver
+                .device()
+                .backend
+                .file()
+                .read_exact_at(&mut buf, sector * SECTOR_SIZE)
+                .unwrap();
+            assert_eq!(buf, [sector as u8 + 1; SECTOR_SIZE as usize]);
+        }
+        assert!(matches!(
+            driver.device_mut().process_queue(4),
+            Err(Error::InvalidQueueIndex(4))
+        ));
+    }
+
+    // Records the `EventFd`s registered by a device.
+    #[derive(Default)]
+    struct TestEventsCtx {
+        ioeventfds: Vec<(u16, RawFd)>,
+        irqfds: Vec<RawFd>,
+    }
+
+    impl EventsContext for TestEventsCtx {
+        type E = ();
+
+        fn register_ioeventfd(&mut self, queue: u16, fd: &EventFd) -> result::Result<(), ()> {
+            self.ioeventfds.push((queue, fd.as_raw_fd()));
+            Ok(())
+        }
+
+        fn register_irqfd(&mut self, fd: &EventFd) -> result::Result<(), ()> {
+            self.irqfds.push(fd.as_raw_fd());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_reattach() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let block = block(&mem, 1);
+        let mut events_ctx = TestEventsCtx::default();
+        block.reattach(&mut events_ctx).unwrap();
+        assert!(events_ctx.ioeventfds.is_empty());
+        assert_eq!(events_ctx.irqfds, [block.driver_notify.as_raw_fd()]);
+    }
+
+    #[test]
+    fn test_pause() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let mut block = block(&mem, 2);
+        let vqs = [
+            VirtQueue::new(GuestAddress(0), &mem, 16),
+            VirtQueue::new(GuestAddress(0x4000), &mem, 16),
+        ];
+        activate(&mut block, &vqs, 0);
+
+        VirtioDevicePause::pause(&mut block, false).unwrap();
+        assert!(block.is_paused());
+        assert!(block.is_drained());
+
+        // Requests are made available on both queues while the device is paused.
+        for (i, vq) in vqs.iter().enumerate() {
+            let base = 0x1_0000 * (i as u64 + 1);
+            mem.write_obj(VIRTIO_BLK_T_OUT, GuestAddress(base)).unwrap();
+            mem.write_obj(i as u64, GuestAddress(base + 8)).unwrap();
+            vq.dtable(0).set(base, 0x10, VIRTQ_DESC_F_NEXT, 1);
+            vq.dtable(1)
+                .set(base + 0x1000, SECTOR_SIZE as u32, VIRTQ_DESC_F_NEXT, 2);
+            vq.dtable(2).set(base + 0x2000, 1, VIRTQ_DESC_F_WRITE, 0);
+            vq.avail.ring(0).store(0);
+            vq.avail.idx().store(1);
+            block.queue_notify(i as u32);
+            assert_eq!(vq.used.idx().load(), 0);
+        }
+
+        VirtioDevicePause::resume(&mut block).unwrap();
+        assert!(!block.is_paused());
+        for vq in vqs.iter() {
+            assert_eq!(vq.used.idx().load(), 1);
+        }
+    }
+
+    #[test]
+    fn test_dirty_tracker() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let mut block = block(&mem, 1);
+        let tracker = Arc::new(TestTracker::default());
+        // The tracker is passed to the handlers created on activation.
+        block.set_dirty_tracker(Some(tracker.clone()));
+        let vqs = [VirtQueue::new(GuestAddress(0), &mem, 16)];
+        activate(&mut block, &vqs, 0);
+        assert!(block.handlers[0].queue().dirty_tracker().is_some());
+
+        let vq = &vqs[0];
+        mem.write_obj(VIRTIO_BLK_T_OUT, GuestAddress(0x1_0000))
+            .unwrap();
+        mem.write_obj(1u64, GuestAddress(0x1_0008)).unwrap();
+        vq.dtable(0).set(0x1_0000, 0x10, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1)
+            .set(0x2_0000, SECTOR_SIZE as u32, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable(2).set(0x3_0000, 1, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring(0).store(0);
+        vq.avail.idx().store(1);
+        block.queue_notify(0);
+        assert_eq!(vq.used.idx().load(), 1);
+
+        let ranges = tracker.ranges.lock().unwrap().drain(..).collect::<Vec<_>>();
+        let used = vq.used_start();
+        for range in [
+            (GuestAddress(0x3_0000), 1),
+            (used.unchecked_add(4), 8),
+            (used.unchecked_add(2), 2),
+        ]
+        .iter()
+        {
+            assert!(ranges.contains(range));
+        }
+
+        block.set_dirty_tracker(None);
+        assert!(block.handlers[0].queue().dirty_tracker().is_none());
+        vq.avail.idx().store(2);
+        vq.avail.ring(1).store(0);
+        block.queue_notify(0);
+        assert_eq!(vq.used.idx().load(), 2);
+        assert!(tracker.ranges.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_persist() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let mut block = block(&mem, 1);
+        let restore = |block: &Block<Mem, SharedFile, EventFd>,
+                       state: &VirtioDeviceState<BlockState>| {
+            let args = BlockConstructorArgs {
+                mem: mem.clone(),
+                backend: block.backend.clone(),
+                driver_notify: EventFd::new(0).unwrap(),
+                async_backend: None,
+                max_pending: None,
+            };
+            Block::restore(args, state)
+        };
+
+        // An inactive device is restored as such.
+        let state = block.save();
+        assert_eq!(state.version, 2);
+        assert!(!state.config.device_activated);
+        let restored = restore(&block, &state).unwrap();
+        assert!(!restored.is_activated());
+        assert_eq!(restored.save(), state);
+
+        let vqs = [VirtQueue::new(GuestAddress(0), &mem, 16)];
+        activate(&mut block, &vqs, 0);
+        let vq = &vqs[0];
+        let add_request = |index: u16, sector: u64| {
+            let header = 0x1_0000 + u64::from(index) * 0x100;
+            mem.write_obj(VIRTIO_BLK_T_OUT, GuestAddress(header))
+                .unwrap();
+            mem.write_obj(sector, GuestAddress(header + 8)).unwrap();
+            mem.write_obj(0xffu8, GuestAddress(header + 0x10)).unwrap();
+            let desc = index * 3;
+            vq.dtable(desc)
+                .set(header, 0x10, VIRTQ_DESC_F_NEXT, desc + 1);
+            vq.dtable(desc + 1)
+                .set(0x2_0000, SECTOR_SIZE as u32, VIRTQ_DESC_F_NEXT, desc + 2);
+            vq.dtable(desc + 2)
+                .set(header + 0x10, 1, VIRTQ_DESC_F_WRITE, 0);
+            vq.avail.ring(index).store(desc);
+            vq.avail.idx().store(index + 1);
+        };
+        mem.write_slice(&[0xaa; SECTOR_SIZE as usize], GuestAddress(0x2_0000))
+            .unwrap();
+        add_request(0, 1);
+        block.process_queue(0).unwrap();
+        assert_eq!(vq.used.idx().load(), 1);
+
+        block.drain().unwrap();
+        let state = block.save();
+        assert!(state.config.device_activated);
+        assert_eq!(state.config.queues[0].next_avail, 1);
+        assert_eq!(state.config.queues[0].next_used, 1);
+        assert!(state.device.drained);
+        assert_eq!(state.device.device_id, block.device_id());
+        // The state goes through a migration stream unchanged.
+        let mut stream = Vec::new();
+        persist::save_to(&mut stream, &state).unwrap();
+        assert_eq!(persist::load_from(&mut &stream[..]).unwrap(), state);
+
+        // The restored device continues from the next request once it's resumed.
+        let mut restored = restore(&block, &state).unwrap();
+        assert!(restored.is_activated());
+        assert!(restored.is_drained());
+        assert_eq!(restored.save(), state);
+        add_request(1, 2);
+        restored.process_queue(0).unwrap();
+        assert_eq!(vq.used.idx().load(), 1);
+        restored.resume().unwrap();
+        assert_eq!(vq.used.idx().load(), 2);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x1_0110)).unwrap(),
+            VIRTIO_BLK_S_OK
+        );
+        let mut buf = [0u8; SECTOR_SIZE as usize];
+        block
+            .backend
+            .file()
+            .read_exact_at(&mut buf, 2 * SECTOR_SIZE)
+            .unwrap();
+        assert_eq!(buf, [0xaa; SECTOR_SIZE as usize]);
+
+        let state = VirtioDeviceState {
+            version: 3,
+            ..state
+        };
+        assert!(matches!(
+            restore(&block, &state),
+            Err(Error::UnsupportedStateVersion(3))
+        ));
+
+        // Activated devices are only restored when their queues are still valid.
+        let mut state = VirtioDeviceState {
+            version: 2,
+            ..state
+        };
+        state.config.queues[0].used_ring = GuestAddress(0x10_0000 - 4);
+        assert!(matches!(
+            restore(&block, &state),
+            Err(Error::InvalidRestoredQueue(
+                0,
+                InvalidQueueReason::UsedRingOutOfBounds(_, _)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_persist_inflight() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let mut block = block(&mem, 1);
+        let vqs = [VirtQueue::new(GuestAddress(0), &mem, 16)];
+        activate(&mut block, &vqs, 0);
+
+        let vq = &vqs[0];
+        mem.write_obj(VIRTIO_BLK_T_OUT, GuestAddress(0x1_0000))
+            .unwrap();
+        mem.write_obj(1u64, GuestAddress(0x1_0008)).unwrap();
+        mem.write_slice(&[0xaa; SECTOR_SIZE as usize], GuestAddress(0x2_0000))
+            .unwrap();
+        vq.dtable(0).set(0x1_0000, 0x10, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1)
+            .set(0x2_0000, SECTOR_SIZE as u32, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable(2).set(0x3_0000, 1, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring(0).store(0);
+        vq.avail.idx().store(1);
+
+        // The request is popped, but it can't be added to the used ring.
+        block.handlers[0].queue_mut().used_ring = GuestAddress(0x10_0000 - 4);
+        assert!(block.process_queue(0).is_err());
+        block.drain().unwrap();
+        let mut state = block.save();
+        assert_eq!(state.device.inflight, [[0]]);
+        assert_eq!(state.config.queues[0].next_avail, 1);
+        assert_eq!(state.config.queues[0].next_used, 0);
+
+        // The restored device resubmits the request.
+        state.config.queues[0].used_ring = vq.used_start();
+        let args = BlockConstructorArgs {
+            mem: mem.clone(),
+            backend: block.backend.clone(),
+            driver_notify: EventFd::new(0).unwrap(),
+            async_backend: None,
+            max_pending: None,
+        };
+        let mut restored = Block::restore(args, &state).unwrap();
+        assert_eq!(restored.save(), state);
+        restored.resume().unwrap();
+        assert_eq!(vq.used.idx().load(), 1);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x3_0000)).unwrap(),
+            VIRTIO_BLK_S_OK
+        );
+        assert!(restored.save().device.inflight[0].is_empty());
+    }
+
+    // Creates `TestAsyncBackend`s, and keeps the sending end of their completion channels.
+    #[derive(Debug, Default)]
+    struct TestAsyncFactory {
+        state: Arc<TestAsyncState>,
+        senders: std::sync::Mutex<Vec<CompletionSender>>,
+    }
+
+    impl AsyncBackendFactory for TestAsyncFactory {
+        fn create(
+            &self,
+            _queue_index: u16,
+            completions: CompletionSender,
+        ) -> io::Result<Box<dyn AsyncBackend>> {
+            self.senders.lock().unwrap().push(completions);
+            Ok(Box::new(TestAsyncBackend {
+                state: self.state.clone(),
+                stops: true,
+            }))
+        }
+    }
+
+    // Makes a write request to sector 1 available in `vq`.
+    fn add_out_request(mem: &Mem, vq: &VirtQueue) {
+        mem.write_obj(VIRTIO_BLK_T_OUT, GuestAddress(0x1_0000))
+            .unwrap();
+        mem.write_obj(1u64, GuestAddress(0x1_0008)).unwrap();
+        vq.dtable(0).set(0x1_0000, 0x10, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1)
+            .set(0x2_0000, SECTOR_SIZE as u32, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable(2).set(0x3_0000, 1, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring(0).store(0);
+        vq.avail.idx().store(1);
+    }
+
+    #[test]
+    fn test_reset_inflight() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x10_0000).unwrap();
+        let factory = Arc::new(TestAsyncFactory::default());
+        let mut block =
+            BlockBuilder::new(mem.clone(), SharedFile::new(file), EventFd::new(0).unwrap())
+                .with_queue_size(16)
+                .with_async_backend(factory.clone())
+                .build()
+                .unwrap();
+        assert!(block.completion_receiver(0).is_none());
+        let vqs = [VirtQueue::new(GuestAddress(0), &mem, 16)];
+        activate(&mut block, &vqs, 0);
+        assert!(block.completion_receiver(0).is_some());
+
+        // The request is outstanding when the driver resets the device.
+        add_out_request(&mem, &vqs[0]);
+        block.queue_notify(0);
+        assert_eq!(*factory.state.submitted.lock().unwrap(), [0]);
+        assert_eq!(block.save().device.inflight, [[0]]);
+        block.ack_device_status(0);
+        assert!(factory.state.cancelled.load(Ordering::SeqCst));
+
+        // Its completion is discarded, and it's not resubmitted after the device is
+        // initialized again.
+        factory.senders.lock().unwrap()[0].send(0, 1).unwrap();
+        vqs[0].avail.idx().store(0);
+        activate(&mut block, &vqs, 0);
+        block.process_queue(0).unwrap();
+        block.process_completions(0).unwrap();
+        assert_eq!(vqs[0].used.idx().load(), 0);
+        assert!(block.save().device.inflight[0].is_empty());
+        assert_eq!(factory.state.submitted.lock().unwrap().len(), 1);
+        assert_eq!(block.interrupt_status().load(Ordering::SeqCst), 0);
+
+        // The new requests complete through the channel of the new backend.
+        vqs[0].avail.idx().store(1);
+        block.process_queue(0).unwrap();
+        factory.senders.lock().unwrap()[1].send(0, 1).unwrap();
+        block.process_completions(0).unwrap();
+        assert_eq!(vqs[0].used.idx().load(), 1);
+        assert_eq!(
+            block.interrupt_status().load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_VRING
+        );
+
+        // The requests left behind by a failure are not resubmitted after a reset either.
+        let mut sync_block = self::block(&mem, 1);
+        let vqs = [VirtQueue::new(GuestAddress(0), &mem, 16)];
+        activate(&mut sync_block, &vqs, 0);
+        add_out_request(&mem, &vqs[0]);
+        sync_block.handlers[0].queue_mut().used_ring = GuestAddress(0x10_0000 - 4);
+        assert!(sync_block.process_queue(0).is_err());
+        assert_eq!(sync_block.save().device.inflight, [[0]]);
+        sync_block.ack_device_status(0);
+        vqs[0].avail.idx().store(0);
+        activate(&mut sync_block, &vqs, 0);
+        sync_block.process_queue(0).unwrap();
+        assert_eq!(vqs[0].used.idx().load(), 0);
+        assert!(sync_block.save().device.inflight[0].is_empty());
+    }
+
+    #[test]
+    fn test_max_pending() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x10_0000).unwrap();
+        let factory = Arc::new(TestAsyncFactory::default());
+        let mut block =
+            BlockBuilder::new(mem.clone(), SharedFile::new(file), EventFd::new(0).unwrap())
+                .with_queue_size(16)
+                .with_async_backend(factory.clone())
+                .with_max_pending(1)
+                .build()
+                .unwrap();
+        let vqs = [VirtQueue::new(GuestAddress(0), &mem, 16)];
+        activate(&mut block, &vqs, 0);
+
+        // Two requests which use the same buffers.
+        add_out_request(&mem, &vqs[0]);
+        vqs[0].avail.ring(1).store(0);
+        vqs[0].avail.idx().store(2);
+        block.queue_notify(0);
+        assert_eq!(*factory.state.submitted.lock().unwrap(), [0]);
+        assert!(block.handlers[0].is_backlogged());
+
+        // The second one is submitted once the first one completes.
+        let sender = factory.senders.lock().unwrap()[0].clone();
+        sender.send(0, 1).unwrap();
+        block.process_completions(0).unwrap();
+        assert_eq!(vqs[0].used.idx().load(), 1);
+        assert_eq!(*factory.state.submitted.lock().unwrap(), [0, 0]);
+
+        assert!(!block.handlers[0].is_backlogged());
+        sender.send(0, 1).unwrap();
+        block.process_completions(0).unwrap();
+        assert_eq!(vqs[0].used.idx().load(), 2);
+    }
+
+    #[test]
+    fn test_drain_async() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x10_0000).unwrap();
+        let factory = Arc::new(TestAsyncFactory::default());
+        let mut block =
+            BlockBuilder::new(mem.clone(), SharedFile::new(file), EventFd::new(0).unwrap())
+                .with_queue_size(16)
+                .with_async_backend(factory.clone())
+                .build()
+                .unwrap();
+        let vqs = [VirtQueue::new(GuestAddress(0), &mem, 16)];
+        activate(&mut block, &vqs, 0);
+        add_out_request(&mem, &vqs[0]);
+        block.queue_notify(0);
+
+        // Draining waits for the request the backend is executing.
+        let sender = factory.senders.lock().unwrap()[0].clone();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(50));
+                sender.send(0, 1).unwrap();
+            });
+            block.drain().unwrap();
+        });
+        assert_eq!(vqs[0].used.idx().load(), 1);
+        assert!(block.save().device.inflight[0].is_empty());
+        assert!(!factory.state.cancelled.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_state_versions() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let mut block = block(&mem, 1);
+        let vqs = [VirtQueue::new(GuestAddress(0), &mem, 16)];
+        activate(&mut block, &vqs, 0);
+        block.drain().unwrap();
+        let state = block.save();
+
+        // A state written by a release which doesn't track in-flight requests.
+        let old_state = VirtioDeviceState {
+            version: 1,
+            config: state.config.clone(),
+            device: BlockStateV1 {
+                initial_config_space: state.device.initial_config_space.clone(),
+                read_only: state.device.read_only,
+                device_id: state.device.device_id,
+                lifetime: state.device.lifetime,
+                drained: state.device.drained,
+            },
+        };
+        let mut stream = Vec::new();
+        persist::save_to(&mut stream, &old_state).unwrap();
+        // The old layout doesn't match the current one.
+        assert!(matches!(
+            persist::load_from::<_, BlockState>(&mut &stream[..]),
+            Err(persist::Error::InvalidPayload)
+        ));
+        let upgraded = persist::load_from_versioned::<_, BlockState>(&mut &stream[..]).unwrap();
+        assert_eq!(upgraded.version, 2);
+        assert!(upgraded.device.inflight.is_empty());
+        let args = BlockConstructorArgs {
+            mem: mem.clone(),
+            backend: block.backend.clone(),
+            driver_notify: EventFd::new(0).unwrap(),
+            async_backend: None,
+            max_pending: None,
+        };
+        let restored = Block::restore(args, &upgraded).unwrap();
+        assert!(restored.is_activated());
+        assert!(restored.is_drained());
+
+        // The current state can be written for the older release.
+        let mut downgraded = Vec::new();
+        persist::save_to_version(&mut downgraded, &state, 1).unwrap();
+        assert_eq!(downgraded, stream);
+        assert!(matches!(
+            persist::save_to_version(&mut Vec::new(), &state, 3),
+            Err(persist::Error::UnsupportedVersion(3))
+        ));
+        // Unless requests are in flight.
+        let mut inflight = state.clone();
+        inflight.device.inflight = vec![vec![3]];
+        assert!(matches!(
+            persist::save_to_version(&mut Vec::new(), &inflight, 1),
+            Err(persist::Error::UnsupportedVersion(1))
+        ));
+    }
+
+    #[test]
+    fn test_resize() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let mut block = block(&mem, 1);
+        let capacity = 0x10_0000 / SECTOR_SIZE;
+        assert_eq!(block.capacity(), capacity);
+
+        // The device can only grow, in sector increments.
+        assert!(matches!(
+            block.resize(0x10_0100),
+            Err(Error::InvalidSize(0x10_0100))
+        ));
+        assert!(matches!(
+            block.resize(0x8_0000),
+            Err(Error::InvalidSize(0x8_0000))
+        ));
+
+        // There's no interrupt before activation.
+        block.resize(0x20_0000).unwrap();
+        assert_eq!(block.capacity(), 2 * capacity);
+        assert_eq!(block.config_generation(), 1);
+        assert_eq!(block.interrupt_status().load(Ordering::SeqCst), 0);
+
+        let vqs = [VirtQueue::new(GuestAddress(0), &mem, 16)];
+        activate(&mut block, &vqs, 0);
+        block.resize(0x30_0000).unwrap();
+        assert_eq!(block.backend.file().metadata().unwrap().len(), 0x30_0000);
+        assert_eq!(block.config_generation(), 2);
+        assert_eq!(
+            block.interrupt_status().load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_CONFIG
+        );
+        assert_eq!(block.driver_notify.read().unwrap(), 1);
+        let mut buf = [0u8; 8];
+        block.read_config(ConfigSpace::CAPACITY_OFFSET, &mut buf);
+        assert_eq!(u64::from_le_bytes(buf), 3 * capacity);
+
+        // Requests can access the new sectors right away.
+        let vq = &vqs[0];
+        mem.write_obj(VIRTIO_BLK_T_OUT, GuestAddress(0x1_0000))
+            .unwrap();
+        mem.write_obj(3 * capacity - 1, GuestAddress(0x1_0008))
+            .unwrap();
+        vq.dtable(0).set(0x1_0000, 0x10, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1)
+            .set(0x2_0000, SECTOR_SIZE as u32, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable(2).set(0x3_0000, 1, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring(0).store(0);
+        vq.avail.idx().store(1);
+        block.process_queue(0).unwrap();
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x3_0000)).unwrap(),
+            VIRTIO_BLK_S_OK
+        );
+
+        // The capacity is preserved across resets.
+        block.ack_device_status(0);
+        assert_eq!(block.capacity(), 3 * capacity);
+    }
+
+    #[test]
+    fn test_golden() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let mut driver = MmioDriver::new(block(&mem, 2), &mem, GuestAddress(0));
+        driver.initialize(u64::MAX, 2, 16).unwrap();
+        check_golden(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/golden/mmio_registers.txt"),
+            &driver.register_dump(ConfigSpace::LEN),
+        );
+
+        let mut device_id = [0u8; VIRTIO_BLK_ID_BYTES];
+        device_id[..6].copy_from_slice(b"golden");
+        let mut state = VirtioDeviceState {
+            version: BlockState::VERSION,
+            config: driver.device().cfg.state(),
+            device: BlockState {
+                initial_config_space: driver.device().initial_config_space.clone(),
+                read_only: true,
+                device_id: Some(device_id),
+                lifetime: Some(Lifetime {
+                    pre_eol_info: 1,
+                    device_lifetime_est_typ_a: 2,
+                    device_lifetime_est_typ_b: 3,
+                }),
+                drained: true,
+                inflight: vec![vec![3, 7], Vec::new()],
+            },
+        };
+        let mut stream = Vec::new();
+        persist::save_to(&mut stream, &state).unwrap();
+        check_golden(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/golden/state_v2.txt"),
+            &hex_dump(&stream),
+        );
+
+        // The layout of the older release, which can't hold the in-flight requests.
+        state.device.inflight.clear();
+        stream.clear();
+        persist::save_to_version(&mut stream, &state, 1).unwrap();
+        check_golden(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/golden/state_v1.txt"),
+            &hex_dump(&stream),
+        );
+    }
+}
@@ -10,11 +10,52 @@
 /// Contains virtio block constant definitions.
 pub mod defs;
 
+/// Contains the virtio block configuration space layout and builder.
+pub mod config;
+
 /// Contains block request parsing abstraction.
 pub mod request;
 
+/// Contains the hooks used for reporting block device metrics.
+pub mod metrics;
+
+/// Contains a token bucket based rate limiter for block requests.
+pub use virtio_device::rate_limiter;
+
 /// Contains a block request execution abstraction that is based on
 /// [`std::io::Read`](https://doc.rust-lang.org/std/io/trait.Read.html)
 /// and [`std::io::Write`](https://doc.rust-lang.org/std/io/trait.Write.html).
 #[cfg(feature = "backend-stdio")]
 pub mod stdio_executor;
+
+/// Contains a pool of aligned buffers, which are used for bouncing the request data.
+#[cfg(feature = "backend-stdio")]
+pub mod buffer_pool;
+
+/// Contains a block device backing file abstraction which can be shared between the executors
+/// of multiple request queues.
+#[cfg(feature = "backend-stdio")]
+pub mod shared_file;
+
+/// Contains a copy-on-write block device backend, which keeps the changes to a read-only base
+/// image in an overlay file.
+#[cfg(feature = "backend-stdio")]
+pub mod cow_file;
+
+/// Contains a block request queue handler which processes requests in order.
+#[cfg(feature = "backend-stdio")]
+pub mod queue_handler;
+
+/// Contains a reference virtio block device implementation.
+#[cfg(feature = "backend-stdio")]
+pub mod device;
+
+/// Contains a virtio block device frontend which forwards the request queues to an external
+/// vhost-user-blk backend.
+#[cfg(feature = "vhost-user")]
+pub mod vhost_user;
+
+/// Contains a vhost-user-blk backend, which serves a block device backing file to the VMMs
+/// that support vhost-user.
+#[cfg(feature = "vhost-user-backend")]
+pub mod vhost_user_backend;
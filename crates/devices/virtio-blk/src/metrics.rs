@@ -0,0 +1,112 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Block device metrics hooks.
+//!
+//! The block device components don't keep any metrics themselves. Instead, they report the
+//! relevant events through the [`BlockMetrics`](trait.BlockMetrics.html) trait, which the VMM can
+//! implement on top of its own metrics system. All methods have no-op default implementations,
+//! so implementers only have to provide the ones they're interested in.
+
+use std::fmt::Debug;
+
+/// Hooks for the events that are relevant from the block device metrics point of view.
+///
+/// The methods take `&self` because the same object is usually shared between the executors and
+/// handlers of multiple queues, so implementations are expected to rely on atomics or other
+/// means of interior mutability.
+pub trait BlockMetrics: Debug + Send + Sync {
+    /// A descriptor chain could not be parsed as a valid request.
+    fn invalid_request(&self) {}
+
+    /// The execution of a request failed.
+    fn execute_fail(&self) {}
+
+    /// A flush request was executed successfully.
+    fn flush(&self) {}
+
+    /// `bytes` were read from the disk by an `In` request.
+    fn read_bytes(&self, _bytes: u64) {}
+
+    /// `bytes` were written to the disk by an `Out` request.
+    fn write_bytes(&self, _bytes: u64) {}
+
+    /// Request processing was throttled by the rate limiter.
+    fn rate_limiter_throttled(&self) {}
+}
+
+/// A `BlockMetrics` implementation which ignores all events.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopMetrics;
+
+impl BlockMetrics for NoopMetrics {}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    // Keeps track of all the reported events.
+    #[derive(Debug, Default)]
+    pub(crate) struct TestMetrics {
+        pub invalid_requests: AtomicU64,
+        pub execute_fails: AtomicU64,
+        pub flushes: AtomicU64,
+        pub read_bytes: AtomicU64,
+        pub write_bytes: AtomicU64,
+        pub throttled: AtomicU64,
+    }
+
+    impl BlockMetrics for TestMetrics {
+        fn invalid_request(&self) {
+            self.invalid_requests.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn execute_fail(&self) {
+            self.execute_fails.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn flush(&self) {
+            self.flushes.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn read_bytes(&self, bytes: u64) {
+            self.read_bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+
+        fn write_bytes(&self, bytes: u64) {
+            self.write_bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+
+        fn rate_limiter_throttled(&self) {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn report_events(metrics: &dyn BlockMetrics) {
+        metrics.invalid_request();
+        metrics.execute_fail();
+        metrics.flush();
+        metrics.read_bytes(0x200);
+        metrics.write_bytes(0x400);
+        metrics.rate_limiter_throttled();
+    }
+
+    #[test]
+    fn test_metrics() {
+        // The default implementations don't do anything.
+        report_events(&NoopMetrics);
+
+        let metrics = TestMetrics::default();
+        report_events(&metrics);
+        report_events(&metrics);
+        assert_eq!(metrics.invalid_requests.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.execute_fails.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.flushes.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.read_bytes.load(Ordering::Relaxed), 0x400);
+        assert_eq!(metrics.write_bytes.load(Ordering::Relaxed), 0x800);
+        assert_eq!(metrics.throttled.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,1444 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A block request queue processing abstraction.
+//!
+//! This module provides the following abstraction:
+//!
+//! - [`InorderQueueHandler`](struct.InorderQueueHandler.html) which consumes the descriptor
+//!   chains from a request queue, executes the associated requests in order using a
+//!   [`StdIoBackend`](../stdio_executor/struct.StdIoBackend.html), and notifies the driver
+//!   about the used buffers via a `SignalUsedQueue` implementation. Request processing can
+//!   optionally be throttled with a [`RateLimiter`](../rate_limiter/struct.RateLimiter.html),
+//!   and adjacent requests can optionally be merged into a single backend operation. The number
+//!   of requests handled for each driver notification can be bounded as well, so a driver which
+//!   submits requests faster than the backend completes them can't monopolize the event loop.
+//!   The number of requests in flight can be bounded too, in which case the handler stops
+//!   consuming the available ring (with the queue notifications disabled) until the backend
+//!   completes some of them, instead of buffering an unbounded backlog.
+//!   The requests which were popped from the queue, but not completed, are tracked, so they can
+//!   be resubmitted after the device is restored from a snapshot. The completions can optionally
+//!   be batched as well, such that the driver is notified at most once per iteration. Under
+//!   sustained load, the handler can optionally keep polling the available ring for a while
+//!   before enabling the queue notifications again, which saves most of the driver kicks (and
+//!   the corresponding VM exits) at the cost of some CPU time.
+//! - [`AsyncBackend`](trait.AsyncBackend.html), which the handler can hand the requests over
+//!   to instead of executing them on its own thread. The completions come back through a
+//!   [`completion channel`](../../virtio_device/completion_channel/index.html), and the
+//!   outstanding requests are cancelled with
+//!   [`cancel_inflight`](struct.InorderQueueHandler.html#method.cancel_inflight) before the
+//!   queue is reset.
+
+use std::fmt::{self, Display};
+use std::hint;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use std::{io, result};
+
+use log::warn;
+
+use vm_memory::GuestAddressSpace;
+
+use virtio_device::completion::CompletionBatcher;
+use virtio_device::completion_channel::{self, CompletionReceiver};
+use virtio_device::SignalUsedQueue;
+use virtio_queue::{self, DescriptorChain, Queue};
+
+use crate::rate_limiter::RateLimiter;
+use crate::request::Request;
+use crate::stdio_executor::{Backend, ProcessReqError, StdIoBackend};
+
+/// Errors encountered while processing a request queue.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to receive the completions of the asynchronous backend.
+    CompletionChannel(completion_channel::Error),
+    /// Failed to process a request.
+    ProcessRequest(ProcessReqError),
+    /// Failed to access the queue.
+    Queue(virtio_queue::Error),
+    /// Failed to handle a rate limiter event.
+    RateLimiter(io::Error),
+    /// Failed to submit a request to the asynchronous backend.
+    Submit(io::Error),
+    /// The asynchronous backend didn't complete or stop the in-flight requests in time.
+    Timeout,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            CompletionChannel(ref err) => write!(f, "failed to receive completions: {}", err),
+            ProcessRequest(ref err) => write!(f, "failed to process request: {}", err),
+            Queue(ref err) => write!(f, "failed to access the queue: {}", err),
+            RateLimiter(ref err) => write!(f, "failed to handle rate limiter event: {}", err),
+            Submit(ref err) => write!(f, "failed to submit request: {}", err),
+            Timeout => write!(f, "timed out waiting for the in-flight requests"),
+        }
+    }
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+// The maximum number of requests that are merged into a single backend operation.
+const MAX_MERGED_REQUESTS: usize = 32;
+
+/// A backend which executes the requests of a queue asynchronously (i.e. on a pool of I/O
+/// threads, or with `io_uring`), instead of the thread which processes the queue.
+///
+/// The backend writes the data and the status of each request to guest memory, and then sends
+/// the used length of its descriptor chain, tagged with the generation of the request, through
+/// the `CompletionSender` of the channel the handler receives the completions from (see
+/// `InorderQueueHandler::with_async_backend` and `CompletionSender::send_tagged`).
+pub trait AsyncBackend: fmt::Debug + Send {
+    /// Starts executing a request.
+    ///
+    /// # Arguments
+    /// * `head_index` - The head index of the descriptor chain of the request, which is sent
+    ///   back with its completion.
+    /// * `generation` - The generation of the request, which is sent back with its completion
+    ///   as the tag. It changes whenever the in-flight requests are cancelled, so the late
+    ///   completions of the cancelled requests aren't mistaken for the ones of the new requests
+    ///   which reuse their head indices.
+    /// * `request` - The request.
+    fn submit(&mut self, head_index: u16, generation: u16, request: Request) -> io::Result<()>;
+
+    /// Aborts the requests which were submitted, but not completed, and waits for the ones
+    /// which are already executing. Returns whether all of them stopped accessing the guest
+    /// memory within `timeout`. The completions of the aborted requests don't have to be sent.
+    ///
+    /// # Arguments
+    /// * `timeout` - How long to wait for the executing requests.
+    fn cancel(&mut self, timeout: Duration) -> bool;
+}
+
+/// Processes the requests of a block device queue in the order they're made available by the
+/// driver.
+///
+/// Each available descriptor chain is parsed into a `Request`, which is then executed by the
+/// `StdIoBackend`. The status of the request is written to guest memory, and the chain is added
+/// to the used ring. Chains that can't be parsed are added to the used ring with a length of 0,
+/// since there's no reliable way of reporting their status. The driver is notified via
+/// `driver_notify` whenever the queue requires it.
+///
+/// # Example
+///
+/// ```rust
+/// # use virtio_blk::queue_handler::InorderQueueHandler;
+/// # use virtio_blk::stdio_executor::StdIoBackend;
+/// # use virtio_queue::Queue;
+/// # use vm_memory::{GuestAddress, GuestMemoryMmap};
+/// # use vmm_sys_util::eventfd::EventFd;
+/// # use vmm_sys_util::tempfile::TempFile;
+/// let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+/// let disk = StdIoBackend::new(TempFile::new().unwrap().into_file(), 0).unwrap();
+/// let mut handler =
+///     InorderQueueHandler::new(Queue::new(&mem, 256), disk, EventFd::new(0).unwrap());
+///
+/// // When the driver notifies the device about available buffers:
+/// handler.process_queue().unwrap();
+/// ```
+#[derive(Debug)]
+pub struct InorderQueueHandler<M: GuestAddressSpace, B: Backend, S: SignalUsedQueue> {
+    /// The request queue.
+    queue: Queue<M>,
+    /// The index of the request queue, which is passed to `driver_notify`.
+    queue_index: u16,
+    /// The executor used for the requests.
+    disk: StdIoBackend<B>,
+    /// The object used for notifying the driver about used buffers.
+    driver_notify: S,
+    /// The optional rate limiter for the requests.
+    rate_limiter: Option<RateLimiter>,
+    /// Whether adjacent requests are merged.
+    merge_requests: bool,
+    /// The maximum number of requests handled by a `process_queue` call, if any.
+    request_budget: Option<usize>,
+    /// Whether `process_queue` stopped because the request budget was exhausted.
+    paused: bool,
+    /// The maximum number of requests in flight, if any.
+    max_pending: Option<usize>,
+    /// Whether `process_queue` stopped because `max_pending` requests are in flight.
+    backlogged: bool,
+    /// The head indices of the chains popped from the available ring, which were not added to
+    /// the used ring yet, in the order they were popped.
+    inflight: Vec<u16>,
+    /// Accumulates the completions of each iteration, if they are batched.
+    completions: Option<CompletionBatcher>,
+    /// How long the available ring is polled before enabling the notifications, if at all.
+    poll_time: Option<Duration>,
+    /// The backend which executes the requests asynchronously, if any, and the channel its
+    /// completions are received from.
+    async_backend: Option<(Box<dyn AsyncBackend>, CompletionReceiver)>,
+    /// The head indices of the requests executed by the asynchronous backend, which were not
+    /// completed yet.
+    submitted: Vec<u16>,
+    /// The generation of the requests submitted to the asynchronous backend, which is
+    /// incremented whenever the in-flight requests are cancelled.
+    generation: u16,
+}
+
+impl<M: GuestAddressSpace, B: Backend, S: SignalUsedQueue> InorderQueueHandler<M, B, S> {
+    /// Creates a new `InorderQueueHandler` for the queue with index 0.
+    ///
+    /// # Arguments
+    /// * `queue` - The request queue.
+    /// * `disk` - The executor used for the requests.
+    /// * `driver_notify` - The object used for notifying the driver.
+    pub fn new(queue: Queue<M>, disk: StdIoBackend<B>, driver_notify: S) -> Self {
+        InorderQueueHandler {
+            queue,
+            queue_index: 0,
+            disk,
+            driver_notify,
+            rate_limiter: None,
+            merge_requests: false,
+            request_budget: None,
+            paused: false,
+            max_pending: None,
+            backlogged: false,
+            inflight: Vec::new(),
+            completions: None,
+            poll_time: None,
+            async_backend: None,
+            submitted: Vec::new(),
+            generation: 0,
+        }
+    }
+
+    /// Sets the index of the request queue, which is relevant when the device has multiple
+    /// request queues.
+    ///
+    /// # Arguments
+    /// * `queue_index` - The index of the request queue.
+    pub fn with_queue_index(mut self, queue_index: u16) -> Self {
+        self.queue_index = queue_index;
+        self
+    }
+
+    /// Throttles request processing with `rate_limiter`.
+    ///
+    /// Each request consumes one operation and its data length from the rate limiter budget.
+    /// When the budget is exhausted, the processing stops until the rate limiter file descriptor
+    /// becomes readable, at which point `process_rate_limiter_event` has to be called.
+    ///
+    /// # Arguments
+    /// * `rate_limiter` - The rate limiter used for the requests.
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Enables or disables request merging.
+    ///
+    /// With request merging, consecutive `In` or `Out` requests from the same batch of available
+    /// buffers which access adjacent sectors are executed with a single backend operation. Each
+    /// request is still completed with its own used length and status. This reduces the number
+    /// of backend operations (and system calls) for sequential workloads.
+    ///
+    /// # Arguments
+    /// * `merge_requests` - Whether adjacent requests are merged.
+    pub fn with_request_merging(mut self, merge_requests: bool) -> Self {
+        self.merge_requests = merge_requests;
+        self
+    }
+
+    /// Bounds the number of requests handled by each `process_queue` call.
+    ///
+    /// When the budget is exhausted, the remaining requests are left in the available ring and
+    /// the queue notifications stay disabled, so the driver doesn't keep kicking the device.
+    /// `is_paused` returns `true` in this case, and the VMM has to call `process_queue` again
+    /// (i.e. on the next iteration of its event loop) without waiting for a driver notification.
+    ///
+    /// # Arguments
+    /// * `request_budget` - The maximum number of requests handled at once (at least 1).
+    pub fn with_request_budget(mut self, request_budget: usize) -> Self {
+        self.request_budget = Some(request_budget.max(1));
+        self
+    }
+
+    /// Enables completion batching: the chains completed by an iteration of `process_queue` are
+    /// added to the used ring at once, and the driver is notified at most once per iteration.
+    ///
+    /// # Arguments
+    /// * `max_latency` - The maximum time a completion is held back within an iteration, if
+    ///   any.
+    pub fn with_completion_batching(mut self, max_latency: Option<Duration>) -> Self {
+        let completions = CompletionBatcher::new();
+        self.completions = Some(match max_latency {
+            Some(max_latency) => completions.with_max_latency(max_latency),
+            None => completions,
+        });
+        self
+    }
+
+    /// Enables adaptive polling: once the available ring is drained, it's polled for up to
+    /// `poll_time` before the queue notifications are enabled again, and the processing
+    /// continues right away when the driver makes more buffers available in the meantime. This
+    /// saves the driver notifications (and the associated VM exits) under sustained load, at the
+    /// cost of spinning on the CPU for up to `poll_time` after each burst of requests.
+    ///
+    /// # Arguments
+    /// * `poll_time` - How long the available ring is polled (i.e. a few tens of microseconds).
+    pub fn with_poll_time(mut self, poll_time: Duration) -> Self {
+        self.poll_time = Some(poll_time).filter(|poll_time| !poll_time.is_zero());
+        self
+    }
+
+    /// Hands the requests over to `backend`, which executes them asynchronously. Its
+    /// completions are received from `completions`, whose file descriptor has to be registered
+    /// with the event loop of the handler, which calls `process_completions` when it becomes
+    /// readable. Request merging doesn't apply to the asynchronous backends.
+    ///
+    /// # Arguments
+    /// * `backend` - The asynchronous backend.
+    /// * `completions` - The receiving end of the channel the backend sends the completions
+    ///   through. Its capacity has to be at least the size of the queue.
+    pub fn with_async_backend(
+        mut self,
+        backend: Box<dyn AsyncBackend>,
+        completions: CompletionReceiver,
+    ) -> Self {
+        self.async_backend = Some((backend, completions));
+        self
+    }
+
+    /// Returns the receiving end of the completion channel of the asynchronous backend, if any
+    /// (i.e. for registering its file descriptor with an event loop).
+    pub fn completion_receiver(&self) -> Option<&CompletionReceiver> {
+        self.async_backend
+            .as_ref()
+            .map(|(_, completions)| completions)
+    }
+
+    /// Bounds the number of requests which were popped from the queue, but not completed.
+    ///
+    /// When the bound is reached, the handler stops consuming the available ring, and the
+    /// queue notifications stay disabled, so the driver doesn't keep kicking the device while
+    /// the backend catches up. `is_backlogged` returns `true` in this case, and the processing
+    /// resumes once `process_completions` receives enough completions. This is meant for the
+    /// asynchronous backends; for the requests executed by the handler itself, it only bounds
+    /// the number of requests which are merged at once.
+    ///
+    /// # Arguments
+    /// * `max_pending` - The maximum number of requests in flight (at least 1).
+    pub fn with_max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = Some(max_pending.max(1));
+        self
+    }
+
+    /// Returns whether request processing is paused because the request budget was exhausted,
+    /// in which case `process_queue` has to be called again.
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Returns whether request processing is paused because the maximum number of requests are
+    /// in flight, in which case it's resumed by `process_completions`.
+    pub fn is_backlogged(&self) -> bool {
+        self.backlogged
+    }
+
+    /// Returns the head indices of the descriptor chains which were popped from the available
+    /// ring, but not added to the used ring yet (i.e. because processing failed midway, or
+    /// because the asynchronous backend didn't complete them yet). They have to be saved along
+    /// with the queue state when taking a snapshot.
+    pub fn inflight(&self) -> Vec<u16> {
+        self.submitted
+            .iter()
+            .chain(&self.inflight)
+            .copied()
+            .collect()
+    }
+
+    /// Sets the head indices of the descriptor chains which were popped from the available ring,
+    /// but not completed (i.e. when restoring a snapshot). The corresponding requests are
+    /// resubmitted by the next `process_queue` call, before the available ones.
+    ///
+    /// # Arguments
+    /// * `inflight` - The head indices, in the order the chains were popped.
+    pub fn set_inflight(&mut self, inflight: Vec<u16>) {
+        self.inflight = inflight;
+    }
+
+    /// Returns a reference to the rate limiter, if any (i.e. for registering its file
+    /// descriptor with an event loop).
+    pub fn rate_limiter(&self) -> Option<&RateLimiter> {
+        self.rate_limiter.as_ref()
+    }
+
+    /// Returns a reference to the request queue.
+    pub fn queue(&self) -> &Queue<M> {
+        &self.queue
+    }
+
+    /// Returns a mutable reference to the request queue.
+    pub fn queue_mut(&mut self) -> &mut Queue<M> {
+        &mut self.queue
+    }
+
+    /// Returns a reference to the request executor.
+    pub fn disk(&self) -> &StdIoBackend<B> {
+        &self.disk
+    }
+
+    /// Returns a mutable reference to the request executor (i.e. for switching the cache mode).
+    pub fn disk_mut(&mut self) -> &mut StdIoBackend<B> {
+        &mut self.disk
+    }
+
+    /// Processes the available requests, until there are no more, or the rate limiter budget or
+    /// the request budget is exhausted. This has to be called when the driver notifies the
+    /// device about the queue, and while the processing is paused (see `is_paused`).
+    pub fn process_queue(&mut self) -> Result<()> {
+        // The batched completions are published on every path out of the processing.
+        let result = self.process_available();
+        result.and(self.flush_completions())
+    }
+
+    fn process_available(&mut self) -> Result<()> {
+        // The chains and requests which are waiting to be merged with the next ones.
+        let mut chains = Vec::new();
+        let mut requests = Vec::new();
+        let mut budget = self.request_budget.unwrap_or(usize::MAX);
+        self.paused = false;
+        self.backlogged = false;
+        self.receive_completions()?;
+        self.process_inflight()?;
+
+        loop {
+            self.queue.disable_notification()?;
+
+            loop {
+                if budget == 0 {
+                    // Notifications stay disabled, the VMM resumes the processing.
+                    self.paused = true;
+                    return self.complete_requests(&mut chains, &mut requests);
+                }
+                let mut chain = match self.queue.iter()?.next() {
+                    Some(chain) => chain,
+                    None => break,
+                };
+                if self.is_full() {
+                    // Executing the requests which wait to be merged makes room for more.
+                    self.complete_requests(&mut chains, &mut requests)?;
+                    if self.is_full() {
+                        // Put the chain back; notifications stay disabled until
+                        // `process_completions` resumes the processing.
+                        self.queue.go_to_previous_position();
+                        self.backlogged = true;
+                        return Ok(());
+                    }
+                }
+                budget -= 1;
+                self.inflight.push(chain.head_index());
+
+                let request = match Request::parse(&mut chain) {
+                    Ok(request) => request,
+                    Err(e) => {
+                        warn!("failed to parse block request: {}", e);
+                        self.complete_requests(&mut chains, &mut requests)?;
+                        let len = self
+                            .disk
+                            .process_invalid_request(chain.memory(), &e)
+                            .map_err(Error::ProcessRequest)?;
+                        self.add_used(chain.head_index(), len)?;
+                        continue;
+                    }
+                };
+
+                if let Some(rate_limiter) = self.rate_limiter.as_mut() {
+                    if !rate_limiter.consume(1, request.total_data_len()) {
+                        self.disk.metrics().rate_limiter_throttled();
+                        // Put the chain back; it will be processed once the rate limiter
+                        // allows it. Notifications stay disabled until then.
+                        self.queue.go_to_previous_position();
+                        self.inflight.pop();
+                        return self.complete_requests(&mut chains, &mut requests);
+                    }
+                }
+
+                if self.async_backend.is_some() {
+                    self.submit(chain.head_index(), request)?;
+                    continue;
+                }
+
+                let mergeable = self.merge_requests
+                    && requests.len() < MAX_MERGED_REQUESTS
+                    && requests
+                        .last()
+                        .is_some_and(|last: &Request| last.is_mergeable_with(&request));
+                if !mergeable {
+                    self.complete_requests(&mut chains, &mut requests)?;
+                }
+                chains.push(chain);
+                requests.push(request);
+            }
+
+            self.complete_requests(&mut chains, &mut requests)?;
+            self.flush_completions()?;
+            if self.poll_available()? {
+                continue;
+            }
+            if !self.queue.enable_notification()? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Returns whether the maximum number of requests are in flight.
+    fn is_full(&self) -> bool {
+        self.max_pending
+            .is_some_and(|max_pending| self.submitted.len() + self.inflight.len() >= max_pending)
+    }
+
+    // Polls the available ring until the driver makes more buffers available, or the poll time
+    // expires. Returns whether there are buffers to process.
+    fn poll_available(&mut self) -> Result<bool> {
+        let poll_time = match self.poll_time {
+            Some(poll_time) => poll_time,
+            None => return Ok(false),
+        };
+        let start = Instant::now();
+        loop {
+            let avail_idx = self.queue.avail_idx(Ordering::Acquire)?;
+            if avail_idx.0 != self.queue.next_avail() {
+                return Ok(true);
+            }
+            if start.elapsed() >= poll_time {
+                return Ok(false);
+            }
+            hint::spin_loop();
+        }
+    }
+
+    // Resubmits the requests which were popped, but not completed, one at a time.
+    fn process_inflight(&mut self) -> Result<()> {
+        // `add_used` removes the completed chains from the list.
+        while let Some(&head_index) = self.inflight.first() {
+            let mut chain = match self.queue.chain_at(head_index) {
+                Ok(chain) => chain,
+                Err(e) => {
+                    warn!("dropping in-flight block request {}: {}", head_index, e);
+                    self.inflight.remove(0);
+                    continue;
+                }
+            };
+            match Request::parse(&mut chain) {
+                Ok(request) if self.async_backend.is_some() => self.submit(head_index, request)?,
+                Ok(request) => self.complete_requests(&mut vec![chain], &mut vec![request])?,
+                Err(e) => {
+                    warn!("failed to parse block request: {}", e);
+                    let len = self
+                        .disk
+                        .process_invalid_request(chain.memory(), &e)
+                        .map_err(Error::ProcessRequest)?;
+                    self.add_used(head_index, len)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Hands a request over to the asynchronous backend. The chain stays in flight when the
+    // submission fails, so it's resubmitted by the next `process_queue` call.
+    fn submit(&mut self, head_index: u16, request: Request) -> Result<()> {
+        if let Some((backend, _)) = self.async_backend.as_mut() {
+            backend
+                .submit(head_index, self.generation, request)
+                .map_err(Error::Submit)?;
+            if let Some(pos) = self.inflight.iter().position(|&head| head == head_index) {
+                self.inflight.remove(pos);
+            }
+            self.submitted.push(head_index);
+        }
+        Ok(())
+    }
+
+    // Adds the completions received from the asynchronous backend, if any, to the used ring.
+    fn receive_completions(&mut self) -> Result<()> {
+        let completions = match self.async_backend.as_mut() {
+            Some((_, completions)) => completions,
+            None => return Ok(()),
+        };
+        completions
+            .clear_wakeup()
+            .map_err(Error::CompletionChannel)?;
+        let mut received = Vec::new();
+        while let Some(completion) = completions.try_recv_tagged() {
+            received.push(completion);
+        }
+        for (head_index, len, generation) in received {
+            // I.e. the late completion of a request which was cancelled, whose head index may
+            // have been reused by a new request in the meantime.
+            if generation != self.generation {
+                warn!(
+                    "dropping completion of cancelled block request {}",
+                    head_index
+                );
+                continue;
+            }
+            if !self.submitted.contains(&head_index) {
+                warn!(
+                    "dropping completion of unknown block request {}",
+                    head_index
+                );
+                continue;
+            }
+            self.add_used(head_index, len)?;
+        }
+        self.flush_completions()
+    }
+
+    /// Adds the requests completed by the asynchronous backend to the used ring, and notifies
+    /// the driver if needed. The available requests are processed as well when the processing
+    /// is paused by the backlog (see `is_backlogged`). This has to be called when the file
+    /// descriptor of the `completion_receiver` becomes readable.
+    pub fn process_completions(&mut self) -> Result<()> {
+        self.receive_completions()?;
+        if self.backlogged && !self.is_full() {
+            return self.process_queue();
+        }
+        Ok(())
+    }
+
+    /// Waits for the asynchronous backend to complete the requests it's executing (i.e. before
+    /// flushing the backend), and adds them to the used ring. No new requests are submitted.
+    /// `Error::Timeout` is returned when they don't complete within `timeout`.
+    ///
+    /// # Arguments
+    /// * `timeout` - How long to wait for the completions.
+    pub fn wait_for_completions(&mut self, timeout: Duration) -> Result<()> {
+        let start = Instant::now();
+        loop {
+            self.receive_completions()?;
+            let completions = match self.async_backend.as_ref() {
+                Some((_, completions)) if !self.submitted.is_empty() => completions,
+                _ => return Ok(()),
+            };
+            let remaining = timeout.checked_sub(start.elapsed()).ok_or(Error::Timeout)?;
+            completions
+                .wait(remaining)
+                .map_err(Error::CompletionChannel)?;
+        }
+    }
+
+    /// Cancels the requests which were popped from the queue, but not completed, before the
+    /// queue is reset. The asynchronous backend aborts the ones it's executing (or waits for
+    /// them), and their completions are discarded, so nothing is added to the used ring and
+    /// none of them is resubmitted afterwards. Returns the number of cancelled requests.
+    ///
+    /// The requests are forgotten even when the backend doesn't stop them within `timeout`,
+    /// in which case `Error::Timeout` is returned, since they may still access the guest
+    /// memory. Their completions are dropped whenever they arrive, since the requests which are
+    /// submitted afterwards belong to a new generation.
+    ///
+    /// # Arguments
+    /// * `timeout` - How long to wait for the requests which are already executing.
+    pub fn cancel_inflight(&mut self, timeout: Duration) -> Result<usize> {
+        let cancelled = self.submitted.len() + self.inflight.len();
+        self.generation = self.generation.wrapping_add(1);
+        self.submitted.clear();
+        self.inflight.clear();
+        self.paused = false;
+        self.backlogged = false;
+
+        if let Some((backend, completions)) = self.async_backend.as_mut() {
+            let stopped = backend.cancel(timeout);
+            completions
+                .clear_wakeup()
+                .map_err(Error::CompletionChannel)?;
+            while completions.try_recv().is_some() {}
+            if !stopped {
+                return Err(Error::Timeout);
+            }
+        }
+        Ok(cancelled)
+    }
+
+    // Executes the pending `requests` and adds the corresponding `chains` to the used ring.
+    fn complete_requests(
+        &mut self,
+        chains: &mut Vec<DescriptorChain<M>>,
+        requests: &mut Vec<Request>,
+    ) -> Result<()> {
+        let lens = match chains.first() {
+            Some(chain) => self
+                .disk
+                .process_merged_requests(chain.memory(), requests)
+                .map_err(Error::ProcessRequest)?,
+            None => return Ok(()),
+        };
+        for (chain, len) in chains.drain(..).zip(lens) {
+            self.add_used(chain.head_index(), len)?;
+        }
+        requests.clear();
+        Ok(())
+    }
+
+    // Adds a chain to the used ring (or to the batch of completions), and notifies the driver
+    // if needed.
+    fn add_used(&mut self, head_index: u16, len: u32) -> Result<()> {
+        let notify = match self.completions.as_mut() {
+            Some(completions) => completions.add_used(&mut self.queue, head_index, len)?,
+            None => {
+                self.queue.add_used(head_index, len)?;
+                self.queue.needs_notification()?
+            }
+        };
+        if let Some(pos) = self.inflight.iter().position(|&head| head == head_index) {
+            self.inflight.remove(pos);
+        } else if let Some(pos) = self.submitted.iter().position(|&head| head == head_index) {
+            self.submitted.remove(pos);
+        }
+        if notify {
+            self.driver_notify.signal_used_queue(self.queue_index);
+        }
+        Ok(())
+    }
+
+    // Publishes the batched completions, if any, and notifies the driver if needed.
+    fn flush_completions(&mut self) -> Result<()> {
+        if let Some(completions) = self.completions.as_mut() {
+            if completions.flush(&mut self.queue)? {
+                self.driver_notify.signal_used_queue(self.queue_index);
+            }
+        }
+        Ok(())
+    }
+
+    /// Resumes request processing after the rate limiter timer expired. This has to be called
+    /// when the rate limiter file descriptor becomes readable.
+    pub fn process_rate_limiter_event(&mut self) -> Result<()> {
+        if let Some(rate_limiter) = self.rate_limiter.as_mut() {
+            rate_limiter.event_handler().map_err(Error::RateLimiter)?;
+        }
+        self.process_queue()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+    use vmm_sys_util::eventfd::EventFd;
+    use vmm_sys_util::file_traits::FileSync;
+    use vmm_sys_util::write_zeroes::{PunchHole, WriteZeroesAt};
+
+    use virtio_device::completion_channel::channel;
+    use virtio_queue::mock::VirtQueue;
+    use virtio_queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    use crate::defs::{SECTOR_SIZE, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_T_OUT};
+    use crate::metrics::tests::TestMetrics;
+    use crate::rate_limiter::TokenBucket;
+    use crate::stdio_executor::tests::TestTracker;
+
+    const HEADER_ADDR: u64 = 0x1_0000;
+    const DATA_ADDR: u64 = 0x2_0000;
+    const STATUS_ADDR: u64 = 0x3_0000;
+    const DISK_SIZE: usize = 0x1000;
+
+    // A backend which keeps the disk contents in memory.
+    #[derive(Debug)]
+    struct MemBackend(Cursor<Vec<u8>>);
+
+    impl MemBackend {
+        fn new(len: usize) -> Self {
+            MemBackend(Cursor::new(vec![0; len]))
+        }
+
+        fn sector(&self, sector: u64) -> &[u8] {
+            let start = (sector * SECTOR_SIZE) as usize;
+            &self.0.get_ref()[start..start + SECTOR_SIZE as usize]
+        }
+    }
+
+    impl Read for MemBackend {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for MemBackend {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for MemBackend {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    impl FileSync for MemBackend {
+        fn fsync(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl PunchHole for MemBackend {
+        fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+            self.write_zeroes_at(offset, length as usize).map(|_| ())
+        }
+    }
+
+    impl WriteZeroesAt for MemBackend {
+        fn write_zeroes_at(&mut self, offset: u64, length: usize) -> io::Result<usize> {
+            let start = offset as usize;
+            self.0.get_mut()[start..start + length]
+                .iter_mut()
+                .for_each(|b| *b = 0);
+            Ok(length)
+        }
+    }
+
+    impl Backend for MemBackend {}
+
+    // Records the indices of the signalled queues.
+    #[derive(Debug, Default)]
+    struct TestSignal(RefCell<Vec<u16>>);
+
+    impl SignalUsedQueue for TestSignal {
+        fn signal_used_queue(&self, index: u16) {
+            self.0.borrow_mut().push(index);
+        }
+    }
+
+    // The requests submitted to a `TestAsyncBackend` (and their generations), and whether it
+    // was cancelled.
+    #[derive(Debug, Default)]
+    pub(crate) struct TestAsyncState {
+        pub submitted: Mutex<Vec<u16>>,
+        pub generations: Mutex<Vec<u16>>,
+        pub cancelled: AtomicBool,
+    }
+
+    // Records the submitted requests, which the tests complete through the completion channel.
+    #[derive(Debug)]
+    pub(crate) struct TestAsyncBackend {
+        pub state: Arc<TestAsyncState>,
+        // Whether `cancel` stops the requests in time.
+        pub stops: bool,
+    }
+
+    impl AsyncBackend for TestAsyncBackend {
+        fn submit(
+            &mut self,
+            head_index: u16,
+            generation: u16,
+            _request: Request,
+        ) -> io::Result<()> {
+            self.state.submitted.lock().unwrap().push(head_index);
+            self.state.generations.lock().unwrap().push(generation);
+            Ok(())
+        }
+
+        fn cancel(&mut self, _timeout: Duration) -> bool {
+            self.state.cancelled.store(true, Ordering::SeqCst);
+            self.stops
+        }
+    }
+
+    // Adds `count` write requests (one sector each, to consecutive sectors) to the queue.
+    fn add_out_requests(vq: &VirtQueue, mem: &GuestMemoryMmap, count: u16) {
+        for i in 0..count {
+            let header = GuestAddress(HEADER_ADDR + u64::from(i) * 0x10);
+            mem.write_obj(VIRTIO_BLK_T_OUT, header).unwrap();
+            mem.write_obj(u64::from(i), GuestAddress(header.0 + 8))
+                .unwrap();
+            mem.write_slice(
+                &[i as u8 + 1; SECTOR_SIZE as usize],
+                GuestAddress(DATA_ADDR + u64::from(i) * SECTOR_SIZE),
+            )
+            .unwrap();
+
+            let idx = 3 * i;
+            vq.dtable(idx)
+                .set(header.0, 0x10, VIRTQ_DESC_F_NEXT, idx + 1);
+            vq.dtable(idx + 1).set(
+                DATA_ADDR + u64::from(i) * SECTOR_SIZE,
+                SECTOR_SIZE as u32,
+                VIRTQ_DESC_F_NEXT,
+                idx + 2,
+            );
+            vq.dtable(idx + 2)
+                .set(STATUS_ADDR + u64::from(i), 1, VIRTQ_DESC_F_WRITE, 0);
+            vq.avail.ring(i).store(idx);
+        }
+        vq.avail.idx().store(count);
+    }
+
+    // Returns the (head index, length) pair from position `i` of the used ring.
+    fn used_elem(vq: &VirtQueue, mem: &GuestMemoryMmap, i: u16) -> (u32, u32) {
+        // Each used element consists of the head index and the used length (`u32`s).
+        let elem = vq.used_start().0 + 4 + 8 * u64::from(i);
+        (
+            mem.read_obj(GuestAddress(elem)).unwrap(),
+            mem.read_obj(GuestAddress(elem + 4)).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_process_queue() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        add_out_requests(&vq, &mem, 3);
+        // Make the header of the last request device-writable, so it can't be parsed.
+        vq.dtable(6)
+            .flags()
+            .store(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+
+        let metrics = Arc::new(TestMetrics::default());
+        let disk = StdIoBackend::new(MemBackend::new(DISK_SIZE), 0)
+            .unwrap()
+            .with_metrics(metrics.clone());
+
+        let mut handler =
+            InorderQueueHandler::new(vq.create_queue(&mem), disk, TestSignal::default())
+                .with_queue_index(2);
+        assert!(handler.rate_limiter().is_none());
+        handler.process_queue().unwrap();
+
+        assert_eq!(vq.used.idx().load(), 3);
+        assert_eq!(handler.queue().next_avail(), 3);
+        // Only the status byte is written to memory for valid requests.
+        assert_eq!(used_elem(&vq, &mem, 0), (0, 1));
+        assert_eq!(used_elem(&vq, &mem, 1), (3, 1));
+        assert_eq!(used_elem(&vq, &mem, 2), (6, 0));
+        for i in 0..2 {
+            assert_eq!(
+                mem.read_obj::<u8>(GuestAddress(STATUS_ADDR + i)).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+            assert!(handler
+                .disk()
+                .inner()
+                .sector(i)
+                .iter()
+                .all(|&b| b == i as u8 + 1));
+        }
+        assert!(handler.disk().inner().sector(2).iter().all(|&b| b == 0));
+
+        // The driver is notified after each used buffer, since `EVENT_IDX` is not enabled.
+        assert_eq!(*handler.driver_notify.0.borrow(), vec![2, 2, 2]);
+
+        assert_eq!(metrics.invalid_requests.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.write_bytes.load(Ordering::Relaxed), 2 * SECTOR_SIZE);
+
+        // Nothing happens when there are no new requests.
+        handler.process_queue().unwrap();
+        assert_eq!(vq.used.idx().load(), 3);
+        assert_eq!(handler.driver_notify.0.borrow().len(), 3);
+    }
+
+    #[test]
+    fn test_inflight() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        add_out_requests(&vq, &mem, 2);
+
+        let disk = StdIoBackend::new(MemBackend::new(DISK_SIZE), 0).unwrap();
+        let mut handler =
+            InorderQueueHandler::new(vq.create_queue(&mem), disk, TestSignal::default())
+                .with_request_merging(true);
+        // Only the flags and the index of the used ring are accessible, so the merged requests
+        // are executed, but they can't be added to the used ring.
+        handler.queue_mut().used_ring = GuestAddress(0x10_0000 - 4);
+        assert!(matches!(handler.process_queue(), Err(Error::Queue(_))));
+        assert_eq!(handler.queue().next_avail(), 2);
+        assert_eq!(handler.inflight(), [0, 3]);
+
+        // The requests are resubmitted, i.e. after a restore.
+        let mut queue = vq.create_queue(&mem);
+        queue.set_next_avail(2);
+        let disk = StdIoBackend::new(MemBackend::new(DISK_SIZE), 0).unwrap();
+        let mut handler = InorderQueueHandler::new(queue, disk, TestSignal::default());
+        handler.set_inflight(vec![0, 3, 16]);
+        add_out_requests(&vq, &mem, 3);
+        handler.process_queue().unwrap();
+        assert!(handler.inflight().is_empty());
+        assert_eq!(vq.used.idx().load(), 3);
+        // The invalid head index is dropped, and the new request comes last.
+        assert_eq!(used_elem(&vq, &mem, 0), (0, 1));
+        assert_eq!(used_elem(&vq, &mem, 1), (3, 1));
+        assert_eq!(used_elem(&vq, &mem, 2), (6, 1));
+        for i in 0..3 {
+            assert!(handler
+                .disk()
+                .inner()
+                .sector(i)
+                .iter()
+                .all(|&b| b == i as u8 + 1));
+        }
+    }
+
+    #[test]
+    fn test_request_merging() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        add_out_requests(&vq, &mem, 5);
+        // Make the header of the third request device-writable, so it can't be parsed. The
+        // requests before and after it can't be merged.
+        vq.dtable(6)
+            .flags()
+            .store(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+        // The last request has a partial sector of data, so only its status is written.
+        vq.dtable(13).len().store(0x100);
+
+        let metrics = Arc::new(TestMetrics::default());
+        let disk = StdIoBackend::new(MemBackend::new(DISK_SIZE), 0)
+            .unwrap()
+            .with_metrics(metrics.clone());
+
+        let mut handler =
+            InorderQueueHandler::new(vq.create_queue(&mem), disk, TestSignal::default())
+                .with_request_merging(true);
+        handler.process_queue().unwrap();
+
+        // Each request is completed with its own used length and status.
+        assert_eq!(vq.used.idx().load(), 5);
+        assert_eq!(used_elem(&vq, &mem, 0), (0, 1));
+        assert_eq!(used_elem(&vq, &mem, 1), (3, 1));
+        assert_eq!(used_elem(&vq, &mem, 2), (6, 0));
+        assert_eq!(used_elem(&vq, &mem, 3), (9, 1));
+        assert_eq!(used_elem(&vq, &mem, 4), (12, 1));
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(STATUS_ADDR + 4)).unwrap(),
+            VIRTIO_BLK_S_IOERR
+        );
+        for i in [0, 1, 3].iter() {
+            assert_eq!(
+                mem.read_obj::<u8>(GuestAddress(STATUS_ADDR + i)).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+            assert!(handler
+                .disk()
+                .inner()
+                .sector(*i)
+                .iter()
+                .all(|&b| b == *i as u8 + 1));
+        }
+        assert!(handler.disk().inner().sector(2).iter().all(|&b| b == 0));
+        assert!(handler.disk().inner().sector(4).iter().all(|&b| b == 0));
+
+        assert_eq!(*handler.driver_notify.0.borrow(), vec![0; 5]);
+        assert_eq!(metrics.invalid_requests.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.write_bytes.load(Ordering::Relaxed), 3 * SECTOR_SIZE);
+    }
+
+    #[test]
+    fn test_dirty_tracker() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        add_out_requests(&vq, &mem, 3);
+        // The second request has a partial sector of data, so only its status is written. The
+        // header of the last one is device-writable, so nothing is written for it.
+        vq.dtable(4).len().store(0x100);
+        vq.dtable(6)
+            .flags()
+            .store(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+
+        let tracker = Arc::new(TestTracker::default());
+        let mut handler = InorderQueueHandler::new(
+            vq.create_queue(&mem),
+            StdIoBackend::new(MemBackend::new(DISK_SIZE), 0).unwrap(),
+            TestSignal::default(),
+        );
+        handler.disk_mut().set_dirty_tracker(Some(tracker.clone()));
+        handler.process_queue().unwrap();
+
+        assert_eq!(used_elem(&vq, &mem, 1), (3, 1));
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(STATUS_ADDR + 1)).unwrap(),
+            VIRTIO_BLK_S_IOERR
+        );
+        // The status byte of the invalid request is tracked as well.
+        assert_eq!(
+            *tracker.ranges.lock().unwrap(),
+            [
+                (GuestAddress(STATUS_ADDR), 1),
+                (GuestAddress(STATUS_ADDR + 1), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_request_budget() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        add_out_requests(&vq, &mem, 5);
+
+        let disk = StdIoBackend::new(MemBackend::new(DISK_SIZE), 0).unwrap();
+        let mut handler =
+            InorderQueueHandler::new(vq.create_queue(&mem), disk, TestSignal::default())
+                .with_request_budget(2);
+        assert!(!handler.is_paused());
+
+        // The remaining requests are left in the available ring.
+        for &used in [2, 4].iter() {
+            handler.process_queue().unwrap();
+            assert!(handler.is_paused());
+            assert_eq!(vq.used.idx().load(), used);
+            assert_eq!(handler.queue().next_avail(), used);
+        }
+
+        handler.process_queue().unwrap();
+        assert!(!handler.is_paused());
+        assert_eq!(vq.used.idx().load(), 5);
+        for i in 0..5 {
+            assert_eq!(
+                mem.read_obj::<u8>(GuestAddress(STATUS_ADDR + i)).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+        }
+        assert_eq!(handler.driver_notify.0.borrow().len(), 5);
+    }
+
+    #[test]
+    fn test_completion_batching() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        add_out_requests(&vq, &mem, 4);
+
+        let disk = StdIoBackend::new(MemBackend::new(DISK_SIZE), 0).unwrap();
+        let mut handler =
+            InorderQueueHandler::new(vq.create_queue(&mem), disk, TestSignal::default())
+                .with_queue_index(1)
+                .with_completion_batching(None);
+        handler.process_queue().unwrap();
+        assert_eq!(vq.used.idx().load(), 4);
+        assert!(handler.inflight().is_empty());
+        // The driver is notified once for the whole batch.
+        assert_eq!(*handler.driver_notify.0.borrow(), vec![1]);
+
+        // The batch is also published when the request budget stops the processing.
+        let disk = StdIoBackend::new(MemBackend::new(DISK_SIZE), 0).unwrap();
+        let mut handler =
+            InorderQueueHandler::new(vq.create_queue(&mem), disk, TestSignal::default())
+                .with_request_budget(3)
+                .with_completion_batching(Some(Duration::from_secs(10)));
+        add_out_requests(&vq, &mem, 5);
+        handler.process_queue().unwrap();
+        assert!(handler.is_paused());
+        assert_eq!(vq.used.idx().load(), 3);
+        handler.process_queue().unwrap();
+        assert_eq!(vq.used.idx().load(), 5);
+        assert_eq!(handler.driver_notify.0.borrow().len(), 2);
+        for i in 0..5 {
+            assert_eq!(
+                mem.read_obj::<u8>(GuestAddress(STATUS_ADDR + i)).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+        }
+    }
+
+    #[test]
+    fn test_async_backend() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        add_out_requests(&vq, &mem, 3);
+
+        let state = Arc::new(TestAsyncState::default());
+        let backend = TestAsyncBackend {
+            state: state.clone(),
+            stops: true,
+        };
+        let (sender, receiver) = channel(16).unwrap();
+        let disk = StdIoBackend::new(MemBackend::new(DISK_SIZE), 0).unwrap();
+        let mut handler =
+            InorderQueueHandler::new(vq.create_queue(&mem), disk, TestSignal::default())
+                .with_request_merging(true)
+                .with_async_backend(Box::new(backend), receiver);
+        assert!(handler.completion_receiver().is_some());
+
+        // The requests are handed over to the backend, and nothing is written to the disk.
+        handler.process_queue().unwrap();
+        assert_eq!(*state.submitted.lock().unwrap(), [0, 3, 6]);
+        assert_eq!(handler.inflight(), [0, 3, 6]);
+        assert_eq!(vq.used.idx().load(), 0);
+        assert!(handler.disk().inner().sector(0).iter().all(|&b| b == 0));
+
+        // The completions are added to the used ring in the order they arrive, and the ones of
+        // unknown requests are dropped.
+        sender.send(3, 1).unwrap();
+        sender.send(9, 1).unwrap();
+        handler.process_completions().unwrap();
+        assert_eq!(vq.used.idx().load(), 1);
+        assert_eq!(used_elem(&vq, &mem, 0), (3, 1));
+        assert_eq!(handler.inflight(), [0, 6]);
+        assert_eq!(*handler.driver_notify.0.borrow(), vec![0]);
+
+        assert!(matches!(
+            handler.wait_for_completions(Duration::from_millis(10)),
+            Err(Error::Timeout)
+        ));
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                sender.send(6, 1).unwrap();
+                sender.send(0, 1).unwrap();
+            });
+            handler
+                .wait_for_completions(Duration::from_secs(10))
+                .unwrap();
+        });
+        assert_eq!(vq.used.idx().load(), 3);
+        assert_eq!(used_elem(&vq, &mem, 1), (6, 1));
+        assert_eq!(used_elem(&vq, &mem, 2), (0, 1));
+        assert!(handler.inflight().is_empty());
+        // Nothing is waited for without in-flight requests.
+        handler.wait_for_completions(Duration::ZERO).unwrap();
+    }
+
+    #[test]
+    fn test_max_pending() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        add_out_requests(&vq, &mem, 4);
+
+        let state = Arc::new(TestAsyncState::default());
+        let backend = TestAsyncBackend {
+            state: state.clone(),
+            stops: true,
+        };
+        let (sender, receiver) = channel(16).unwrap();
+        let disk = StdIoBackend::new(MemBackend::new(DISK_SIZE), 0).unwrap();
+        let mut handler =
+            InorderQueueHandler::new(vq.create_queue(&mem), disk, TestSignal::default())
+                .with_async_backend(Box::new(backend), receiver)
+                .with_max_pending(2);
+        assert!(!handler.is_backlogged());
+
+        // The other requests are left in the available ring, with the notifications disabled.
+        handler.process_queue().unwrap();
+        assert!(handler.is_backlogged());
+        assert!(!handler.is_paused());
+        assert_eq!(*state.submitted.lock().unwrap(), [0, 3]);
+        assert_eq!(handler.queue().next_avail(), 2);
+        assert_ne!(vq.used.flags().load(), 0);
+
+        // A driver notification doesn't change anything while the backlog is full.
+        handler.process_queue().unwrap();
+        assert!(handler.is_backlogged());
+        assert_eq!(handler.queue().next_avail(), 2);
+
+        // Each completion makes room for another request.
+        sender.send(0, 1).unwrap();
+        handler.process_completions().unwrap();
+        assert!(handler.is_backlogged());
+        assert_eq!(vq.used.idx().load(), 1);
+        assert_eq!(*state.submitted.lock().unwrap(), [0, 3, 6]);
+
+        // The processing goes on normally once the backlog drains.
+        sender.send(3, 1).unwrap();
+        sender.send(6, 1).unwrap();
+        handler.process_completions().unwrap();
+        assert!(!handler.is_backlogged());
+        assert_eq!(vq.used.idx().load(), 3);
+        assert_eq!(*state.submitted.lock().unwrap(), [0, 3, 6, 9]);
+        assert_eq!(vq.used.flags().load(), 0);
+        sender.send(9, 1).unwrap();
+        handler.process_completions().unwrap();
+        assert_eq!(vq.used.idx().load(), 4);
+        assert!(handler.inflight().is_empty());
+
+        // The requests executed by the handler itself never fill the backlog.
+        let disk = StdIoBackend::new(MemBackend::new(DISK_SIZE), 0).unwrap();
+        let mut handler =
+            InorderQueueHandler::new(vq.create_queue(&mem), disk, TestSignal::default())
+                .with_request_merging(true)
+                .with_max_pending(2);
+        add_out_requests(&vq, &mem, 5);
+        handler.process_queue().unwrap();
+        assert!(!handler.is_backlogged());
+        assert_eq!(vq.used.idx().load(), 5);
+        for i in 0..5 {
+            assert_eq!(
+                mem.read_obj::<u8>(GuestAddress(STATUS_ADDR + i)).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+        }
+    }
+
+    #[test]
+    fn test_cancel_inflight() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        add_out_requests(&vq, &mem, 2);
+
+        let state = Arc::new(TestAsyncState::default());
+        let backend = TestAsyncBackend {
+            state: state.clone(),
+            stops: true,
+        };
+        let (sender, receiver) = channel(16).unwrap();
+        let disk = StdIoBackend::new(MemBackend::new(DISK_SIZE), 0).unwrap();
+        let mut handler =
+            InorderQueueHandler::new(vq.create_queue(&mem), disk, TestSignal::default())
+                .with_async_backend(Box::new(backend), receiver);
+        handler.process_queue().unwrap();
+        assert_eq!(handler.inflight(), [0, 3]);
+
+        // A request completes right before the reset, and the other one right after it.
+        sender.send(0, 1).unwrap();
+        assert_eq!(handler.cancel_inflight(Duration::from_secs(1)).unwrap(), 2);
+        assert!(state.cancelled.load(Ordering::SeqCst));
+        assert!(handler.inflight().is_empty());
+        sender.send(3, 1).unwrap();
+
+        // Neither of them is completed, nor resubmitted.
+        handler.process_queue().unwrap();
+        handler.process_completions().unwrap();
+        assert_eq!(vq.used.idx().load(), 0);
+        assert_eq!(*state.submitted.lock().unwrap(), [0, 3]);
+        assert!(handler.driver_notify.0.borrow().is_empty());
+
+        // The requests are forgotten when the backend doesn't stop them in time.
+        let state = Arc::new(TestAsyncState::default());
+        let backend = TestAsyncBackend {
+            state: state.clone(),
+            stops: false,
+        };
+        let (sender, receiver) = channel(16).unwrap();
+        let disk = StdIoBackend::new(MemBackend::new(DISK_SIZE), 0).unwrap();
+        let mut handler =
+            InorderQueueHandler::new(vq.create_queue(&mem), disk, TestSignal::default())
+                .with_async_backend(Box::new(backend), receiver);
+        handler.process_queue().unwrap();
+        assert!(matches!(
+            handler.cancel_inflight(Duration::ZERO),
+            Err(Error::Timeout)
+        ));
+        assert!(handler.inflight().is_empty());
+
+        // The driver reuses the head index of a cancelled request, and the late completion of
+        // the cancelled request arrives before the one of the new request.
+        vq.avail.ring(2).store(0);
+        vq.avail.idx().store(3);
+        handler.process_queue().unwrap();
+        assert_eq!(*state.submitted.lock().unwrap(), [0, 3, 0]);
+        assert_eq!(*state.generations.lock().unwrap(), [0, 0, 1]);
+        assert_eq!(handler.inflight(), [0]);
+        sender.send_tagged(0, 1, 0).unwrap();
+        handler.process_completions().unwrap();
+        assert_eq!(vq.used.idx().load(), 0);
+        assert_eq!(handler.inflight(), [0]);
+        sender.send_tagged(0, 1, 1).unwrap();
+        handler.process_completions().unwrap();
+        assert_eq!(vq.used.idx().load(), 1);
+        assert_eq!(used_elem(&vq, &mem, 0), (0, 1));
+        assert!(handler.inflight().is_empty());
+
+        // The requests left behind by a failure aren't resubmitted either.
+        vq.avail.idx().store(2);
+        vq.used.idx().store(0);
+        let disk = StdIoBackend::new(MemBackend::new(DISK_SIZE), 0).unwrap();
+        let mut handler =
+            InorderQueueHandler::new(vq.create_queue(&mem), disk, TestSignal::default());
+        handler.queue_mut().used_ring = GuestAddress(0x10_0000 - 4);
+        assert!(handler.process_queue().is_err());
+        assert_eq!(handler.inflight(), [0, 3]);
+        assert_eq!(handler.cancel_inflight(Duration::ZERO).unwrap(), 2);
+        handler.queue_mut().used_ring = vq.used_start();
+        handler.process_queue().unwrap();
+        assert_eq!(vq.used.idx().load(), 0);
+        assert!(handler.disk().inner().sector(1).iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_poll_time() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        add_out_requests(&vq, &mem, 4);
+        vq.avail.idx().store(2);
+
+        let disk = StdIoBackend::new(MemBackend::new(DISK_SIZE), 0).unwrap();
+        let mut handler =
+            InorderQueueHandler::new(vq.create_queue(&mem), disk, TestSignal::default())
+                .with_poll_time(Duration::from_secs(1));
+        let avail_idx = GuestAddress(vq.avail_start().0 + 2);
+        thread::scope(|s| {
+            // Make the other two requests available while the handler polls the ring.
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                mem.write_obj(4u16, avail_idx).unwrap();
+            });
+            handler.process_queue().unwrap();
+        });
+        assert_eq!(vq.used.idx().load(), 4);
+        for i in 0..4 {
+            assert_eq!(
+                mem.read_obj::<u8>(GuestAddress(STATUS_ADDR + i)).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+        }
+
+        // A zero poll time disables polling.
+        let handler = handler.with_poll_time(Duration::ZERO);
+        assert!(handler.poll_time.is_none());
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        add_out_requests(&vq, &mem, 2);
+
+        let metrics = Arc::new(TestMetrics::default());
+        let disk = StdIoBackend::new(MemBackend::new(DISK_SIZE), 0)
+            .unwrap()
+            .with_metrics(metrics.clone());
+        let driver_notify = EventFd::new(0).unwrap();
+
+        // One operation every 100 ms.
+        let rate_limiter =
+            RateLimiter::new(TokenBucket::new(1, Duration::from_millis(100)), None).unwrap();
+        let mut handler = InorderQueueHandler::new(vq.create_queue(&mem), disk, driver_notify)
+            .with_rate_limiter(rate_limiter);
+
+        // The second request is pushed back to the available ring.
+        handler.process_queue().unwrap();
+        assert_eq!(vq.used.idx().load(), 1);
+        assert_eq!(handler.queue().next_avail(), 1);
+        assert!(handler.rate_limiter().unwrap().is_blocked());
+        assert_eq!(handler.driver_notify.read().unwrap(), 1);
+
+        // Processing the queue again while throttled doesn't consume anything.
+        handler.process_queue().unwrap();
+        assert_eq!(vq.used.idx().load(), 1);
+        assert_eq!(handler.queue().next_avail(), 1);
+        assert_eq!(metrics.throttled.load(Ordering::Relaxed), 2);
+
+        // The request is processed once the timer expires.
+        handler.process_rate_limiter_event().unwrap();
+        assert_eq!(vq.used.idx().load(), 2);
+        assert_eq!(handler.queue().next_avail(), 2);
+        assert!(!handler.rate_limiter().unwrap().is_blocked());
+        assert_eq!(handler.driver_notify.read().unwrap(), 1);
+    }
+}
@@ -23,17 +23,23 @@
 //! However, we expect the `Request` interface won't change even if we switch to the general
 //! approach.
 
+use std::cmp::min;
 use std::fmt::{self, Display};
+use std::mem;
 use std::result;
 
+use crate::config::NON_DATA_DESCRIPTORS;
 use crate::defs::{
-    VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN,
-    VIRTIO_BLK_T_OUT, VIRTIO_BLK_T_WRITE_ZEROES,
+    DEFAULT_QUEUE_SIZE, SECTOR_SIZE, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP,
+    VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_GET_LIFETIME,
+    VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT, VIRTIO_BLK_T_WRITE_ZEROES,
 };
 
+use smallvec::SmallVec;
 use virtio_queue::{Descriptor, DescriptorChain};
 use vm_memory::{
-    ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryError,
+    Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryError,
+    GuestMemoryRegion, VolatileSlice,
 };
 
 /// Block request parsing errors.
@@ -45,8 +51,17 @@ pub enum Error {
     DescriptorLengthTooSmall,
     /// Invalid memory access.
     GuestMemory(GuestMemoryError),
+    /// The total data length of a request is not a non-zero multiple of the sector size (for
+    /// reads and writes), or of the segment size (for discard and write zeroes requests). The
+    /// address is the one of the status byte (see `status_addr`).
+    InvalidDataLength(GuestAddress),
     /// Invalid sector value for a flush request.
     InvalidFlushSector,
+    /// The request accesses sectors beyond the capacity of the device.
+    SectorOutOfRange,
+    /// A discard or write zeroes request has more segments than allowed. The address is the one
+    /// of the status byte (see `status_addr`).
+    TooManySegments(GuestAddress),
     /// Read only descriptor that protocol says to write to.
     UnexpectedReadOnlyDescriptor,
     /// Write only descriptor that protocol says to read from.
@@ -61,13 +76,30 @@ impl Display for Error {
             DescriptorChainTooShort => write!(f, "descriptor chain too short"),
             DescriptorLengthTooSmall => write!(f, "descriptor length too small"),
             GuestMemory(ref err) => write!(f, "error accessing guest memory: {}", err),
+            InvalidDataLength(_) => write!(f, "invalid data length of request"),
             InvalidFlushSector => write!(f, "invalid sector in flush request, it should be 0"),
+            SectorOutOfRange => write!(f, "request sectors exceed the device capacity"),
+            TooManySegments(_) => write!(f, "too many discard/write zeroes segments"),
             UnexpectedReadOnlyDescriptor => write!(f, "unexpected read only descriptor"),
             UnexpectedWriteOnlyDescriptor => write!(f, "unexpected write only descriptor"),
         }
     }
 }
 
+impl Error {
+    /// Returns the address where the `VIRTIO_BLK_S_IOERR` status has to be written for the
+    /// request, if the error is reported to the driver. This is the case when the descriptors
+    /// of the request are valid, but its data isn't, so the status is written by the caller
+    /// (i.e. with `StdIoBackend::process_invalid_request`), and the request is completed
+    /// without being executed.
+    pub fn status_addr(&self) -> Option<GuestAddress> {
+        match *self {
+            Error::InvalidDataLength(addr) | Error::TooManySegments(addr) => Some(addr),
+            _ => None,
+        }
+    }
+}
+
 /// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
 pub type Result<T> = result::Result<T, Error>;
 
@@ -82,6 +114,8 @@ pub enum RequestType {
     Flush,
     /// Get device ID request.
     GetDeviceID,
+    /// Get device lifetime request.
+    GetLifetime,
     /// Discard request.
     Discard,
     /// Write zeroes request.
@@ -97,6 +131,7 @@ impl From<u32> for RequestType {
             VIRTIO_BLK_T_OUT => RequestType::Out,
             VIRTIO_BLK_T_FLUSH => RequestType::Flush,
             VIRTIO_BLK_T_GET_ID => RequestType::GetDeviceID,
+            VIRTIO_BLK_T_GET_LIFETIME => RequestType::GetLifetime,
             VIRTIO_BLK_T_DISCARD => RequestType::Discard,
             VIRTIO_BLK_T_WRITE_ZEROES => RequestType::WriteZeroes,
             t => RequestType::Unsupported(t),
@@ -104,6 +139,80 @@ impl From<u32> for RequestType {
     }
 }
 
+/// Status of a request, as reported by the device to the driver.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    /// The request was executed successfully.
+    Ok,
+    /// The request execution failed.
+    IoErr,
+    /// The request is not supported by the device.
+    Unsupp,
+}
+
+impl From<Status> for u8 {
+    fn from(status: Status) -> Self {
+        match status {
+            Status::Ok => VIRTIO_BLK_S_OK,
+            Status::IoErr => VIRTIO_BLK_S_IOERR,
+            Status::Unsupp => VIRTIO_BLK_S_UNSUPP,
+        }
+    }
+}
+
+/// Describes a range of sectors targeted by a discard or write zeroes request (the
+/// `virtio_blk_discard_write_zeroes` structure from the virtio specification). The data buffers
+/// of such requests consist of one or more segments.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct DiscardWriteZeroes {
+    /// The first sector of the range.
+    pub sector: u64,
+    /// The number of sectors in the range.
+    pub num_sectors: u32,
+    /// The segment flags.
+    pub flags: u32,
+}
+
+impl DiscardWriteZeroes {
+    /// The least significant bit from `flags` set -> the targeted range should be unmapped
+    /// (only valid for write zeroes requests).
+    pub const UNMAP: u32 = 1;
+    /// The size of a segment in guest memory.
+    pub const LEN: u64 = mem::size_of::<DiscardWriteZeroes>() as u64;
+}
+
+// Safe because DiscardWriteZeroes contains only plain data.
+unsafe impl ByteValued for DiscardWriteZeroes {}
+
+/// The wear estimates of the device media (the `virtio_blk_lifetime` structure from the virtio
+/// specification), which are returned for `VIRTIO_BLK_T_GET_LIFETIME` requests. The values
+/// follow the eMMC and UFS standards.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct Lifetime {
+    /// The consumption of the reserved blocks (one of the `VIRTIO_BLK_PRE_EOL_INFO_*` values).
+    pub pre_eol_info: u16,
+    /// The estimated wear of the SLC cells, in 10% increments (from 0x01 for 0-10% of the
+    /// lifetime used, to 0x0b for exceeded lifetime), or 0 if unknown.
+    pub device_lifetime_est_typ_a: u16,
+    /// The estimated wear of the MLC cells, in 10% increments, or 0 if unknown.
+    pub device_lifetime_est_typ_b: u16,
+}
+
+impl Lifetime {
+    /// The size of the lifetime information in guest memory.
+    pub const LEN: u64 = mem::size_of::<Lifetime>() as u64;
+}
+
+// Safe because Lifetime contains only plain data.
+unsafe impl ByteValued for Lifetime {}
+
+// The maximum number of segments that are parsed for a discard or write zeroes request. This
+// matches the limit of the Linux block layer, and keeps malicious drivers from making the
+// device allocate memory for a huge number of segments.
+const MAX_SEGMENTS: u64 = 256;
+
 /// Block request header.
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C)]
@@ -113,14 +222,27 @@ struct RequestHeader {
     sector: u64,
 }
 
+/// The number of data segments of a request which are stored inline, i.e. without any heap
+/// allocation, when the request is parsed and executed. It's the `seg_max` advertised for
+/// queues of `DEFAULT_QUEUE_SIZE` descriptors, so only merged requests (or devices with larger
+/// queues) can go beyond it.
+pub const INLINE_SEGMENTS: usize = (DEFAULT_QUEUE_SIZE - NON_DATA_DESCRIPTORS) as usize;
+
+/// A buffer which holds one element per data segment of a request (i.e. the data buffers, or
+/// the host memory slices they resolve to), and only falls back to the heap when there are more
+/// than `INLINE_SEGMENTS` of them.
+pub type SegmentBuf<T> = SmallVec<[T; INLINE_SEGMENTS]>;
+
 /// Stores the necessary information for further execution of a block request.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Request {
     /// The type of the request.
     request_type: RequestType,
     /// Stores the (address, data length) pairs where the data descriptors
     /// point to.
-    data: Vec<(GuestAddress, u32)>,
+    data: SegmentBuf<(GuestAddress, u32)>,
+    /// The segments of a discard or write zeroes request, as read from the data buffers.
+    segments: Vec<DiscardWriteZeroes>,
     /// The offset (multiplied by 512) where the read or write is to occur.
     sector: u64,
     /// The address where the device should write the request status.
@@ -141,6 +263,45 @@ impl Request {
         &self.data
     }
 
+    /// Returns the segments of a discard or write zeroes request, which are read from the data
+    /// buffers when the request is parsed. The slice is empty for the other request types.
+    pub fn segments(&self) -> &[DiscardWriteZeroes] {
+        &self.segments
+    }
+
+    /// Resolves the request data buffers to host memory slices, which can be used for vectored
+    /// I/O directly on guest memory.
+    ///
+    /// The slices are returned in the order of the data descriptors. A buffer that spans
+    /// multiple guest memory regions is split at the region boundaries.
+    ///
+    /// # Arguments
+    /// * `mem` - A reference to the guest memory.
+    pub fn data_slices<'a, M: GuestMemory>(
+        &self,
+        mem: &'a M,
+    ) -> result::Result<SegmentBuf<VolatileSlice<'a>>, GuestMemoryError> {
+        let mut slices = SegmentBuf::new();
+        for &(mut addr, len) in self.data.iter() {
+            let mut remaining = len as usize;
+            while remaining > 0 {
+                let (region, region_addr) = mem
+                    .to_region_addr(addr)
+                    .ok_or(GuestMemoryError::InvalidGuestAddress(addr))?;
+                // `region_addr` is within the region, so there's at least one byte left in it.
+                let count = min(remaining as u64, region.len() - region_addr.raw_value()) as usize;
+                slices.push(region.get_slice(region_addr, count)?);
+                remaining -= count;
+                if remaining > 0 {
+                    addr = addr
+                        .checked_add(count as u64)
+                        .ok_or(GuestMemoryError::InvalidGuestAddress(addr))?;
+                }
+            }
+        }
+        Ok(slices)
+    }
+
     /// Returns the sector.
     pub fn sector(&self) -> u64 {
         self.sector
@@ -151,6 +312,19 @@ impl Request {
         self.status_addr
     }
 
+    /// Writes the `status` byte of the request at `status_addr`.
+    ///
+    /// # Arguments
+    /// * `mem` - A reference to the guest memory.
+    /// * `status` - The outcome of the request execution.
+    pub fn write_status<M: GuestMemory>(
+        &self,
+        mem: &M,
+        status: Status,
+    ) -> result::Result<(), GuestMemoryError> {
+        mem.write_obj(u8::from(status), self.status_addr)
+    }
+
     /// Returns the total length of request data.
     pub fn total_data_len(&self) -> u64 {
         // The maximum queue size is 32768 (2^15), which is the maximum  possible descriptor chain
@@ -158,6 +332,132 @@ impl Request {
         self.data.iter().map(|x| x.1 as u64).sum()
     }
 
+    /// Checks that the sectors accessed by the request are within the device capacity.
+    ///
+    /// Only `In` and `Out` requests describe their sector range in the header, so the check is a
+    /// no-op for the other request types (the ranges of discard and write zeroes segments can
+    /// only be validated while reading the segments from memory).
+    ///
+    /// # Arguments
+    /// * `num_sectors` - The capacity of the device, in 512-byte sectors.
+    pub fn check_capacity(&self, num_sectors: u64) -> Result<()> {
+        if self.request_type != RequestType::In && self.request_type != RequestType::Out {
+            return Ok(());
+        }
+        // A partial sector at the end still needs the whole sector to be accessible.
+        let sectors_count = self.total_data_len().div_ceil(SECTOR_SIZE);
+        match self.sector.checked_add(sectors_count) {
+            Some(end) if end <= num_sectors => Ok(()),
+            _ => Err(Error::SectorOutOfRange),
+        }
+    }
+
+    /// Checks that a discard or write zeroes request has at most `max_segments` segments
+    /// (i.e. the `max_discard_seg` or `max_write_zeroes_seg` value from the configuration
+    /// space).
+    ///
+    /// # Arguments
+    /// * `max_segments` - The maximum number of segments allowed for the request type.
+    pub fn check_segments(&self, max_segments: u32) -> Result<()> {
+        if self.segments.len() as u64 > u64::from(max_segments) {
+            return Err(Error::TooManySegments(self.status_addr));
+        }
+        Ok(())
+    }
+
+    /// Returns whether `next` can be merged into this request, i.e. whether both are `In` or
+    /// `Out` requests which access adjacent sector ranges, such that they can be executed with
+    /// a single backend operation.
+    ///
+    /// # Arguments
+    /// * `next` - The request which follows this one.
+    pub fn is_mergeable_with(&self, next: &Request) -> bool {
+        let len = self.total_data_len();
+        (self.request_type == RequestType::In || self.request_type == RequestType::Out)
+            && next.request_type == self.request_type
+            && len % SECTOR_SIZE == 0
+            && self.sector.checked_add(len / SECTOR_SIZE) == Some(next.sector)
+    }
+
+    /// Merges `requests` into a single request, which accesses all their sectors and data
+    /// buffers. Returns `None` if the requests are not adjacent, as defined by
+    /// [`is_mergeable_with`](struct.Request.html#method.is_mergeable_with).
+    ///
+    /// The status address of the merged request is the one of the first request, but the status
+    /// of each request has to be written separately.
+    ///
+    /// # Arguments
+    /// * `requests` - The requests to merge, in sector order.
+    pub fn merge(requests: &[Request]) -> Option<Request> {
+        let first = requests.first()?;
+        if !requests.windows(2).all(|w| w[0].is_mergeable_with(&w[1])) {
+            return None;
+        }
+        Some(Request {
+            request_type: first.request_type,
+            data: requests
+                .iter()
+                .flat_map(|request| request.data.iter().copied())
+                .collect(),
+            segments: Vec::new(),
+            sector: first.sector,
+            status_addr: first.status_addr,
+        })
+    }
+
+    // Reads the discard or write zeroes segments from the data buffers. A segment can be split
+    // between several buffers, so only the total data length has to be a multiple of the
+    // segment size.
+    fn read_segments<M: GuestMemory>(&mut self, mem: &M) -> Result<()> {
+        let len = self.total_data_len();
+        if len == 0 || len % DiscardWriteZeroes::LEN != 0 {
+            return Err(Error::InvalidDataLength(self.status_addr));
+        }
+        if len / DiscardWriteZeroes::LEN > MAX_SEGMENTS {
+            return Err(Error::TooManySegments(self.status_addr));
+        }
+
+        let mut segments = Vec::with_capacity((len / DiscardWriteZeroes::LEN) as usize);
+        let mut segment = DiscardWriteZeroes::default();
+        // The number of bytes of `segment` which were read so far.
+        let mut filled = 0;
+        for &(addr, len) in self.data.iter() {
+            let len = len as usize;
+            let mut offset = 0;
+            while offset < len {
+                let count = min(DiscardWriteZeroes::LEN as usize - filled, len - offset);
+                let addr = addr.checked_add(offset as u64).ok_or(Error::GuestMemory(
+                    GuestMemoryError::InvalidGuestAddress(addr),
+                ))?;
+                mem.read_slice(&mut segment.as_mut_slice()[filled..filled + count], addr)
+                    .map_err(Error::GuestMemory)?;
+                filled += count;
+                offset += count;
+                if filled == DiscardWriteZeroes::LEN as usize {
+                    segments.push(segment);
+                    filled = 0;
+                }
+            }
+        }
+        self.segments = segments;
+        Ok(())
+    }
+
+    // Validates the data of a request whose status descriptor is known to be valid.
+    fn check_data<M: GuestMemory>(&mut self, mem: &M) -> Result<()> {
+        match self.request_type {
+            RequestType::In | RequestType::Out => {
+                let len = self.total_data_len();
+                if len == 0 || len % SECTOR_SIZE != 0 {
+                    return Err(Error::InvalidDataLength(self.status_addr));
+                }
+                Ok(())
+            }
+            RequestType::Discard | RequestType::WriteZeroes => self.read_segments(mem),
+            _ => Ok(()),
+        }
+    }
+
     // Checks that a descriptor meets the minimal requirements for a valid status descriptor.
     fn check_status_desc<M: GuestMemory>(mem: &M, desc: Descriptor) -> Result<()> {
         // The status MUST always be writable.
@@ -199,9 +499,19 @@ impl Request {
     /// expected header descriptor (the chain head) is device-readable and the expected status
     /// descriptor (the chain tail) is device-writable.
     ///
+    /// The total data length of `In` and `Out` requests has to be a non-zero multiple of the
+    /// sector size. The data of `Discard` and `WriteZeroes` requests is parsed into
+    /// [`segments`](struct.Request.html#method.segments), so its total length has to be a
+    /// non-zero multiple of the segment size. If the data is invalid, an error which holds the
+    /// address of the status byte is returned (see
+    /// [`Error::status_addr`](enum.Error.html#method.status_addr)), such that the caller can
+    /// report the `VIRTIO_BLK_S_IOERR` status without passing the request to a backend. This is
+    /// the only place where the data length is validated, backends rely on it. Parsing never
+    /// writes to guest memory.
+    ///
     /// # Arguments
     /// * `desc_chain` - A mutable reference to the descriptor chain that should point to the
-    ///                  buffers of a virtio block request.
+    ///   buffers of a virtio block request.
     pub fn parse<M: GuestAddressSpace>(desc_chain: &mut DescriptorChain<M>) -> Result<Request> {
         let chain_head = desc_chain.next().ok_or(Error::DescriptorChainTooShort)?;
         // The head contains the request type which MUST be readable.
@@ -220,7 +530,8 @@ impl Request {
 
         let mut request = Request {
             request_type: RequestType::from(request_header.request_type),
-            data: Vec::new(),
+            data: SegmentBuf::new(),
+            segments: Vec::new(),
             sector: request_header.sector,
             status_addr: GuestAddress(0),
         };
@@ -238,6 +549,8 @@ impl Request {
         Request::check_status_desc::<<M>::M>(desc_chain.memory(), status_desc)?;
 
         request.status_addr = status_desc.addr();
+        request.check_data(desc_chain.memory())?;
+
         Ok(request)
     }
 }
@@ -246,10 +559,11 @@ impl Request {
 mod tests {
     use super::*;
 
-    use vm_memory::{Address, GuestMemoryMmap};
+    use vm_memory::GuestMemoryMmap;
 
-    use virtio_queue::test_utils::VirtQueue;
-    use virtio_queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+    use virtio_device::mock::adjacent_regions;
+    use virtio_queue::mock::{AttackPattern, DescriptorChainBuilder};
+    use virtio_queue::VIRTQ_DESC_F_WRITE;
 
     impl PartialEq for Error {
         fn eq(&self, other: &Self) -> bool {
@@ -260,7 +574,10 @@ mod tests {
                 (GuestMemory(ref e), GuestMemory(ref other_e)) => {
                     format!("{}", e).eq(&format!("{}", other_e))
                 }
+                (InvalidDataLength(addr), InvalidDataLength(other_addr)) => addr == other_addr,
                 (InvalidFlushSector, InvalidFlushSector) => true,
+                (SectorOutOfRange, SectorOutOfRange) => true,
+                (TooManySegments(addr), TooManySegments(other_addr)) => addr == other_addr,
                 (UnexpectedReadOnlyDescriptor, UnexpectedReadOnlyDescriptor) => true,
                 (UnexpectedWriteOnlyDescriptor, UnexpectedWriteOnlyDescriptor) => true,
                 _ => false,
@@ -269,6 +586,7 @@ mod tests {
     }
 
     impl Request {
+        /// Builds a `Request` directly from its components, bypassing `parse`.
         pub fn new(
             request_type: RequestType,
             data: Vec<(GuestAddress, u32)>,
@@ -277,67 +595,35 @@ mod tests {
         ) -> Self {
             Request {
                 request_type,
-                data,
+                data: data.into_iter().collect(),
+                segments: Vec::new(),
                 sector,
                 status_addr,
             }
         }
+
+        /// Reads the discard or write zeroes segments from the data buffers, as `parse` does.
+        pub fn with_segments<M: GuestMemory>(mut self, mem: &M) -> Result<Self> {
+            self.read_segments(mem)?;
+            Ok(self)
+        }
     }
 
-    // Helper method that writes a descriptor chain to a `GuestMemoryMmap` object and returns
-    // the associated `DescriptorChain` object. `descs` represents a slice of `Descriptor` objects
-    // which are used to populate the chain. This method ensures the next flags and values are
-    // set properly for the desired chain, but keeps the other characteristics of the input
-    // descriptors (`addr`, `len`, other flags).
-    // The queue/descriptor chain related information is written in memory starting with
-    // address 0. The `addr` fields of the input descriptors should start at a sufficiently
+    // Writes a descriptor chain made of `descs` to `mem`, and returns it. The queue is placed
+    // at address 0, so the `addr` fields of the descriptors should start at a sufficiently
     // greater location (i.e. 1MiB, or `0x10_0000`).
     fn build_desc_chain<'a>(
         mem: &'a GuestMemoryMmap,
         descs: &[Descriptor],
     ) -> DescriptorChain<&'a GuestMemoryMmap> {
-        // Support a max of 16 descriptors for now.
-        let vq = VirtQueue::new(GuestAddress(0), mem, 16);
-        for (idx, desc) in descs.iter().enumerate() {
-            let i = idx as u16;
-            vq.dtable(i).addr().store(desc.addr().0);
-            vq.dtable(i).len().store(desc.len());
-
-            if idx == descs.len() - 1 {
-                // Clear the NEXT flag if it was set. The value of the next field of the
-                // Descriptor doesn't matter at this point.
-                vq.dtable(i)
-                    .flags()
-                    .store(desc.flags() & !VIRTQ_DESC_F_NEXT);
-            } else {
-                // Ensure the next flag is set.
-                vq.dtable(i).flags().store(desc.flags() | VIRTQ_DESC_F_NEXT);
-                // Ensure we are referring the following descriptor. This ignores
-                // any value is actually present in `desc.next`.
-                vq.dtable(i).next().store(i + 1);
-            }
-        }
-
-        // Put the descriptor index 0 in the first available ring position.
-        mem.write_obj(0u16, vq.avail_start().unchecked_add(4))
-            .unwrap();
-
-        // Set `avail_idx` to 1.
-        mem.write_obj(1u16, vq.avail_start().unchecked_add(2))
-            .unwrap();
-
-        vq.create_queue(mem)
-            .iter()
-            .unwrap()
-            .next()
-            .expect("failed to build desc chain")
+        DescriptorChainBuilder::new(GuestAddress(0), mem, 16).build(descs)
     }
 
     #[test]
     fn test_parse_request() {
         let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000_0000)]).unwrap();
         // The `build_desc_chain` function will populate the `NEXT` related flags and field.
-        let v = vec![
+        let v = [
             // A device-writable request header descriptor.
             Descriptor::new(0x10_0000, 0x100, VIRTQ_DESC_F_WRITE, 0),
             Descriptor::new(0x20_0000, 0x100, VIRTQ_DESC_F_WRITE, 0),
@@ -358,7 +644,7 @@ mod tests {
             Error::UnexpectedWriteOnlyDescriptor
         );
 
-        let v = vec![
+        let v = [
             Descriptor::new(0x10_0000, 0x100, 0, 0),
             Descriptor::new(0x20_0000, 0x100, VIRTQ_DESC_F_WRITE, 0),
             // A device-readable request status descriptor.
@@ -372,7 +658,7 @@ mod tests {
             Error::UnexpectedReadOnlyDescriptor
         );
 
-        let v = vec![
+        let v = [
             Descriptor::new(0x10_0000, 0x100, 0, 0),
             Descriptor::new(0x20_0000, 0x100, VIRTQ_DESC_F_WRITE, 0),
             // Status descriptor with len = 0.
@@ -384,7 +670,7 @@ mod tests {
             Error::DescriptorLengthTooSmall
         );
 
-        let v = vec![
+        let v = [
             Descriptor::new(0x10_0000, 0x100, 0, 0),
             Descriptor::new(0x20_0000, 0x100, 0, 0),
             Descriptor::new(0x30_0000, 0x100, VIRTQ_DESC_F_WRITE, 0),
@@ -416,7 +702,7 @@ mod tests {
         );
 
         // Invalid status address.
-        let v = vec![
+        let v = [
             Descriptor::new(0x10_0000, 0x100, 0, 0),
             Descriptor::new(0x20_0000, 0x100, VIRTQ_DESC_F_WRITE, 0),
             Descriptor::new(0x30_0000, 0x200, VIRTQ_DESC_F_WRITE, 0),
@@ -440,8 +726,8 @@ mod tests {
             )))
         );
 
-        // Valid descriptor chain for OUT.
-        let v = vec![
+        // The data length of an OUT request has to be a multiple of the sector size.
+        let v = [
             Descriptor::new(0x10_0000, 0x100, 0, 0),
             Descriptor::new(0x20_0000, 0x100, VIRTQ_DESC_F_WRITE, 0),
             Descriptor::new(0x30_0000, 0x200, VIRTQ_DESC_F_WRITE, 0),
@@ -452,6 +738,42 @@ mod tests {
             _reserved: 0,
             sector: 2,
         };
+        mem.write_obj::<RequestHeader>(req_header, GuestAddress(0x10_0000))
+            .unwrap();
+        mem.write_obj::<u8>(0xff, GuestAddress(0x40_0000)).unwrap();
+
+        let mut chain = build_desc_chain(&mem, &v[..4]);
+        let err = Request::parse(&mut chain).unwrap_err();
+        assert_eq!(err, Error::InvalidDataLength(GuestAddress(0x40_0000)));
+        assert_eq!(err.status_addr(), Some(GuestAddress(0x40_0000)));
+        // Parsing doesn't write the status, that's up to the caller.
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x40_0000)).unwrap(), 0xff);
+
+        // An IN request without any data is invalid as well.
+        let v = [
+            Descriptor::new(0x10_0000, 0x100, 0, 0),
+            Descriptor::new(0x40_0000, 0x100, VIRTQ_DESC_F_WRITE, 0),
+        ];
+        mem.write_obj::<u32>(VIRTIO_BLK_T_IN, GuestAddress(0x10_0000))
+            .unwrap();
+        let mut chain = build_desc_chain(&mem, &v[..2]);
+        assert_eq!(
+            Request::parse(&mut chain).unwrap_err(),
+            Error::InvalidDataLength(GuestAddress(0x40_0000))
+        );
+
+        // Valid descriptor chain for OUT.
+        let v = [
+            Descriptor::new(0x10_0000, 0x100, 0, 0),
+            Descriptor::new(0x20_0000, 0x200, VIRTQ_DESC_F_WRITE, 0),
+            Descriptor::new(0x30_0000, 0x200, VIRTQ_DESC_F_WRITE, 0),
+            Descriptor::new(0x40_0000, 0x100, VIRTQ_DESC_F_WRITE, 0),
+        ];
+        let req_header = RequestHeader {
+            request_type: VIRTIO_BLK_T_OUT,
+            _reserved: 0,
+            sector: 2,
+        };
         mem.write_obj::<RequestHeader>(req_header, GuestAddress(0x10_0000))
             .unwrap();
 
@@ -461,15 +783,18 @@ mod tests {
         let expected_request = Request {
             request_type: RequestType::Out,
             data: vec![
-                (GuestAddress(0x20_0000), 0x100),
+                (GuestAddress(0x20_0000), 0x200),
                 (GuestAddress(0x30_0000), 0x200),
-            ],
+            ]
+            .into_iter()
+            .collect(),
+            segments: Vec::new(),
             sector: 2,
             status_addr: GuestAddress(0x40_0000),
         };
         assert_eq!(request, expected_request);
         assert_eq!(request.status_addr(), GuestAddress(0x40_0000));
-        assert_eq!(request.total_data_len(), 0x100 + 0x200);
+        assert_eq!(request.total_data_len(), 0x200 + 0x200);
 
         // Request header with unsupported request type.
         let req_header = RequestHeader {
@@ -486,7 +811,7 @@ mod tests {
         assert_eq!(request.request_type(), RequestType::Unsupported(2));
 
         // Valid descriptor chain for FLUSH.
-        let v = vec![
+        let v = [
             Descriptor::new(0x10_0000, 0x100, 0, 0),
             Descriptor::new(0x40_0000, 0x100, VIRTQ_DESC_F_WRITE, 0),
         ];
@@ -501,4 +826,279 @@ mod tests {
         let mut chain = build_desc_chain(&mem, &v[..2]);
         assert!(Request::parse(&mut chain).is_ok());
     }
+
+    #[test]
+    fn test_data_buffers() {
+        let buffers: Vec<_> = (0..INLINE_SEGMENTS as u64)
+            .map(|i| (GuestAddress(0x1000 * i), 0x200))
+            .collect();
+
+        // The data buffers of a request within the advertised `seg_max` are stored inline.
+        let request = Request::new(RequestType::Out, buffers.clone(), 0, GuestAddress(0));
+        assert_eq!(request.data(), &buffers[..]);
+        assert!(!request.data.spilled());
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000_0000)]).unwrap();
+        assert!(!request.data_slices(&mem).unwrap().spilled());
+
+        // Merged requests can go beyond it.
+        let next = Request::new(
+            RequestType::Out,
+            vec![(GuestAddress(0x1000_0000 - 0x200), 0x200)],
+            request.total_data_len() / SECTOR_SIZE,
+            GuestAddress(0),
+        );
+        let merged = Request::merge(&[request, next]).unwrap();
+        assert_eq!(merged.data().len(), INLINE_SEGMENTS + 1);
+        assert!(merged.data.spilled());
+
+        // Parsing a chain with several data descriptors.
+        let mut v = vec![Descriptor::new(0x10_0000, 0x100, 0, 0)];
+        v.extend(
+            buffers[..8]
+                .iter()
+                .map(|&(addr, len)| Descriptor::new(0x20_0000 + addr.0, len, 0, 0)),
+        );
+        v.push(Descriptor::new(0x40_0000, 0x100, VIRTQ_DESC_F_WRITE, 0));
+        mem.write_obj::<u32>(VIRTIO_BLK_T_OUT, GuestAddress(0x10_0000))
+            .unwrap();
+        let mut chain = build_desc_chain(&mem, &v);
+        let request = Request::parse(&mut chain).unwrap();
+        assert_eq!(request.data().len(), 8);
+        assert_eq!(request.data()[7].0, GuestAddress(0x20_7000));
+        assert_eq!(request.total_data_len(), 0x200 * 8);
+    }
+
+    #[test]
+    fn test_segments() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000_0000)]).unwrap();
+        let segments = [
+            DiscardWriteZeroes {
+                sector: 1,
+                num_sectors: 2,
+                flags: 0,
+            },
+            DiscardWriteZeroes {
+                sector: 8,
+                num_sectors: 4,
+                flags: DiscardWriteZeroes::UNMAP,
+            },
+        ];
+        mem.write_obj(segments[0], GuestAddress(0x20_0000)).unwrap();
+        mem.write_obj(segments[1], GuestAddress(0x20_0010)).unwrap();
+
+        // The segments are split between several data descriptors.
+        let v = [
+            Descriptor::new(0x10_0000, 0x100, 0, 0),
+            Descriptor::new(0x20_0000, 4, 0, 0),
+            Descriptor::new(0x20_0004, 0x14, 0, 0),
+            Descriptor::new(0x20_0018, 8, 0, 0),
+            Descriptor::new(0x40_0000, 0x100, VIRTQ_DESC_F_WRITE, 0),
+        ];
+        mem.write_obj::<u32>(VIRTIO_BLK_T_WRITE_ZEROES, GuestAddress(0x10_0000))
+            .unwrap();
+        let mut chain = build_desc_chain(&mem, &v);
+        let request = Request::parse(&mut chain).unwrap();
+        assert_eq!(request.segments(), &segments);
+        request.check_segments(2).unwrap();
+        assert_eq!(
+            request.check_segments(1).unwrap_err(),
+            Error::TooManySegments(GuestAddress(0x40_0000))
+        );
+
+        // Only the total length has to be a multiple of the segment size.
+        mem.write_obj::<u32>(VIRTIO_BLK_T_DISCARD, GuestAddress(0x10_0000))
+            .unwrap();
+        mem.write_obj::<u8>(0xff, GuestAddress(0x40_0000)).unwrap();
+        let mut chain = build_desc_chain(&mem, &[v[0], v[1], v[2], v[4]]);
+        assert_eq!(
+            Request::parse(&mut chain).unwrap_err(),
+            Error::InvalidDataLength(GuestAddress(0x40_0000))
+        );
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x40_0000)).unwrap(), 0xff);
+
+        let request = |data| Request::new(RequestType::Discard, data, 0, GuestAddress(0));
+        assert_eq!(
+            request(vec![]).with_segments(&mem).unwrap_err(),
+            Error::InvalidDataLength(GuestAddress(0))
+        );
+        let len = (MAX_SEGMENTS + 1) * DiscardWriteZeroes::LEN;
+        assert_eq!(
+            request(vec![(GuestAddress(0x20_0000), len as u32)])
+                .with_segments(&mem)
+                .unwrap_err(),
+            Error::TooManySegments(GuestAddress(0))
+        );
+        assert_eq!(
+            request(vec![(
+                GuestAddress(0x1000_0000),
+                DiscardWriteZeroes::LEN as u32
+            )])
+            .with_segments(&mem)
+            .unwrap_err(),
+            Error::GuestMemory(GuestMemoryError::InvalidGuestAddress(GuestAddress(
+                0x1000_0000
+            )))
+        );
+
+        // Other request types don't have segments.
+        let request = Request::new(
+            RequestType::Out,
+            vec![(GuestAddress(0x20_0000), 0x200)],
+            0,
+            GuestAddress(0),
+        );
+        assert!(request.segments().is_empty());
+        request.check_segments(0).unwrap();
+    }
+
+    #[test]
+    fn test_check_capacity() {
+        let data = vec![(GuestAddress(0x1000), 0x200), (GuestAddress(0x2000), 0x400)];
+        for &request_type in [RequestType::In, RequestType::Out].iter() {
+            let request = Request::new(request_type, data.clone(), 5, GuestAddress(0x3000));
+            request.check_capacity(8).unwrap();
+            assert_eq!(
+                request.check_capacity(7).unwrap_err(),
+                Error::SectorOutOfRange
+            );
+
+            // The end of the range overflows.
+            let request = Request::new(request_type, data.clone(), u64::MAX, GuestAddress(0));
+            assert_eq!(
+                request.check_capacity(u64::MAX).unwrap_err(),
+                Error::SectorOutOfRange
+            );
+
+            // Partial sectors are taken into account as well.
+            let request = Request::new(
+                request_type,
+                vec![(GuestAddress(0x1000), 0x201)],
+                6,
+                GuestAddress(0x3000),
+            );
+            assert_eq!(
+                request.check_capacity(7).unwrap_err(),
+                Error::SectorOutOfRange
+            );
+        }
+
+        // Requests without a sector range are not checked.
+        let request = Request::new(RequestType::Flush, vec![], 0, GuestAddress(0x3000));
+        request.check_capacity(0).unwrap();
+        let request = Request::new(RequestType::Discard, data, 100, GuestAddress(0x3000));
+        request.check_capacity(0).unwrap();
+    }
+
+    #[test]
+    fn test_write_status() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let request = Request::new(RequestType::In, vec![], 0, GuestAddress(0x100));
+
+        for &(status, value) in [
+            (Status::Ok, VIRTIO_BLK_S_OK),
+            (Status::IoErr, VIRTIO_BLK_S_IOERR),
+            (Status::Unsupp, VIRTIO_BLK_S_UNSUPP),
+        ]
+        .iter()
+        {
+            assert_eq!(u8::from(status), value);
+            request.write_status(&mem, status).unwrap();
+            assert_eq!(mem.read_obj::<u8>(GuestAddress(0x100)).unwrap(), value);
+        }
+
+        let request = Request::new(RequestType::In, vec![], 0, GuestAddress(0x1000));
+        assert!(request.write_status(&mem, Status::Ok).is_err());
+    }
+
+    #[test]
+    fn test_data_slices() {
+        let mem = adjacent_regions(0x1000);
+        let data = vec![
+            (GuestAddress(0x100), 0x200),
+            (GuestAddress(0xf00), 0x400),
+            (GuestAddress(0x1800), 0),
+        ];
+        let request = Request::new(RequestType::In, data, 0, GuestAddress(0x1f00));
+
+        let slices = request.data_slices(&mem).unwrap();
+        // The second buffer is split at the region boundary and the empty one is skipped.
+        assert_eq!(
+            slices.iter().map(|s| s.len()).collect::<Vec<_>>(),
+            vec![0x200, 0x100, 0x300]
+        );
+        slices[1].write_obj(0xaau8, 0xff).unwrap();
+        slices[2].write_obj(0xbbu8, 0).unwrap();
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0xfff)).unwrap(), 0xaa);
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x1000)).unwrap(), 0xbb);
+
+        // Buffers must be completely backed by guest memory.
+        let request = Request::new(
+            RequestType::In,
+            vec![(GuestAddress(0x1f00), 0x200)],
+            0,
+            GuestAddress(0x100),
+        );
+        assert!(matches!(
+            request.data_slices(&mem).unwrap_err(),
+            GuestMemoryError::InvalidGuestAddress(GuestAddress(0x2000))
+        ));
+    }
+
+    #[test]
+    fn test_merge() {
+        let request = |request_type, sector, len| {
+            Request::new(
+                request_type,
+                vec![(GuestAddress(0x1000 * sector), len)],
+                sector,
+                GuestAddress(0x100 + sector),
+            )
+        };
+
+        let first = request(RequestType::Out, 1, 0x400);
+        assert!(first.is_mergeable_with(&request(RequestType::Out, 3, 0x200)));
+        // Not adjacent.
+        assert!(!first.is_mergeable_with(&request(RequestType::Out, 4, 0x200)));
+        assert!(!first.is_mergeable_with(&request(RequestType::Out, 1, 0x200)));
+        // Different types.
+        assert!(!first.is_mergeable_with(&request(RequestType::In, 3, 0x200)));
+        // Only reads and writes are merged.
+        let flush = request(RequestType::Flush, 0, 0);
+        assert!(!flush.is_mergeable_with(&request(RequestType::Flush, 0, 0)));
+        // Partial sectors.
+        let partial = request(RequestType::In, 1, 0x100);
+        assert!(!partial.is_mergeable_with(&request(RequestType::In, 2, 0x200)));
+
+        let requests = [
+            request(RequestType::In, 1, 0x200),
+            request(RequestType::In, 2, 0x400),
+            request(RequestType::In, 4, 0x200),
+        ];
+        let merged = Request::merge(&requests).unwrap();
+        assert_eq!(merged.request_type(), RequestType::In);
+        assert_eq!(merged.sector(), 1);
+        assert_eq!(merged.total_data_len(), 0x800);
+        assert_eq!(
+            merged.data(),
+            &[
+                (GuestAddress(0x1000), 0x200),
+                (GuestAddress(0x2000), 0x400),
+                (GuestAddress(0x4000), 0x200)
+            ]
+        );
+        assert_eq!(merged.status_addr(), GuestAddress(0x101));
+
+        assert!(Request::merge(&requests[..0]).is_none());
+        assert!(Request::merge(&[requests[0].clone(), requests[2].clone()]).is_none());
+    }
+
+    #[test]
+    fn test_parse_attack_patterns() {
+        let mem = adjacent_regions(0x10_0000);
+        for &pattern in AttackPattern::ALL.iter() {
+            let mut chain =
+                DescriptorChainBuilder::new(GuestAddress(0), &mem, 16).build_attack(pattern);
+            assert!(Request::parse(&mut chain).is_err(), "{:?}", pattern);
+        }
+    }
 }
@@ -0,0 +1,971 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A block device backing file which can be shared between multiple request queues.
+//!
+//! The [`StdIoBackend`](../stdio_executor/struct.StdIoBackend.html) executor relies on the
+//! `Read`, `Write` and `Seek` interfaces of its backend, which means that sharing the same
+//! `File` between executors that run on different threads (i.e. one for each request queue
+//! when `VIRTIO_BLK_F_MQ` is negotiated) is racy, because all `File` handles which refer to
+//! the same open file description share the file offset.
+//!
+//! [`SharedFile`](struct.SharedFile.html) addresses this by keeping a private cursor for each
+//! handle, and by using positioned I/O operations (i.e. `pread`/`pwrite`) on the shared file,
+//! such that every queue worker can own an independent `StdIoBackend<SharedFile>`.
+//!
+//! A `SharedFile` can also be opened with [`CacheMode::Direct`](enum.CacheMode.html) (i.e. the
+//! equivalent of `cache=none`), in which case the host page cache is bypassed using `O_DIRECT`.
+//! Direct accesses have to be aligned to the [`alignment`](struct.SharedFile.html#method.alignment)
+//! of the file, so the VMM should expose it as the block size of the device. Guest buffers which
+//! are not aligned in memory are transferred via bounce buffers that are reused between requests.
+//!
+//! For sparse images, [`with_sparse_reads`](struct.SharedFile.html#method.with_sparse_reads)
+//! enables the detection of holes (using `SEEK_DATA`/`SEEK_HOLE`), such that reads of the
+//! unallocated ranges are satisfied by zero-filling the guest buffers instead of accessing the
+//! file.
+
+use std::alloc::{self, Layout};
+use std::cmp::{max, min};
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr::NonNull;
+use std::slice;
+use std::sync::{Arc, Mutex};
+
+use vm_memory::VolatileSlice;
+
+use vmm_sys_util::fallocate::{fallocate, FallocateMode};
+use vmm_sys_util::file_traits::FileSync;
+use vmm_sys_util::write_zeroes::{PunchHole, WriteZeroesAt};
+
+use crate::defs::{SECTOR_SIZE, VIRTIO_BLK_ID_BYTES};
+use crate::request::SegmentBuf;
+use crate::stdio_executor::{read_exact_vectored_at, write_all_vectored_at, Backend};
+
+// Maximum size of the buffer used to write zeroes when `fallocate` is not supported.
+const ZEROES_BUF_SIZE: usize = 0x10000;
+// Minimum size of the bounce buffers used for direct I/O.
+const BOUNCE_BUF_SIZE: usize = 0x10000;
+// Maximum number of idle bounce buffers that are kept around for reuse.
+const MAX_POOLED_BOUNCE_BUFS: usize = 8;
+
+/// The host page cache policy for the accesses to a `SharedFile`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Accesses go through the host page cache.
+    Cached,
+    /// The host page cache is bypassed by opening the file with `O_DIRECT`.
+    Direct,
+}
+
+// A zeroed heap buffer with a custom alignment, as required by `O_DIRECT` accesses.
+#[derive(Debug)]
+struct AlignedBuf {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+// Safe because `AlignedBuf` owns the memory it points to.
+unsafe impl Send for AlignedBuf {}
+
+impl AlignedBuf {
+    // `size` has to be non-zero and `align` has to be a power of two.
+    fn new(size: usize, align: usize) -> io::Result<Self> {
+        let layout = Layout::from_size_align(size, align)
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        // Safe because the layout has a non-zero size.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        Ok(AlignedBuf { ptr, layout })
+    }
+
+    fn len(&self) -> usize {
+        self.layout.size()
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // Safe because the buffer is valid for `len` bytes and we hold a mutable reference.
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len()) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        // Safe because the buffer was allocated with the same layout.
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) };
+    }
+}
+
+// The bounce buffers used for the direct accesses with unaligned guest buffers, which are shared
+// by all the handles of a `SharedFile`.
+#[derive(Debug)]
+struct BouncePool {
+    alignment: usize,
+    buf_size: usize,
+    bufs: Mutex<Vec<AlignedBuf>>,
+}
+
+impl BouncePool {
+    // `alignment` has to be a power of two.
+    fn new(alignment: usize) -> Self {
+        BouncePool {
+            alignment,
+            // Both values are powers of two, so the size is a multiple of the alignment.
+            buf_size: max(BOUNCE_BUF_SIZE, alignment),
+            bufs: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn is_aligned(&self, value: u64) -> bool {
+        value % self.alignment as u64 == 0
+    }
+
+    // Runs `f` with a bounce buffer, which is returned to the pool afterwards.
+    fn with_buf<T, F>(&self, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&mut [u8]) -> io::Result<T>,
+    {
+        let buf = self.bufs.lock().unwrap().pop();
+        let mut buf = match buf {
+            Some(buf) => buf,
+            None => AlignedBuf::new(self.buf_size, self.alignment)?,
+        };
+        let result = f(buf.as_mut_slice());
+        let mut bufs = self.bufs.lock().unwrap();
+        if bufs.len() < MAX_POOLED_BOUNCE_BUFS {
+            bufs.push(buf);
+        }
+        result
+    }
+}
+
+// Returns the `len` bytes which start at `start` in the area formed by concatenating `bufs`.
+fn sub_bufs<'a>(
+    bufs: &[VolatileSlice<'a>],
+    start: usize,
+    len: usize,
+) -> SegmentBuf<VolatileSlice<'a>> {
+    let end = start + len;
+    let mut sub_bufs = SegmentBuf::new();
+    let mut buf_start = 0;
+    for buf in bufs {
+        let buf_end = buf_start + buf.len();
+        if buf_start < end && start < buf_end {
+            let from = start.saturating_sub(buf_start);
+            let to = min(end, buf_end) - buf_start;
+            // The range is within the bounds of `buf`, so this doesn't fail.
+            if let Ok(sub_buf) = buf.subslice(from, to - from) {
+                sub_bufs.push(sub_buf);
+            }
+        }
+        buf_start = buf_end;
+    }
+    sub_bufs
+}
+
+// Converts the errors of out of bounds volatile accesses.
+fn volatile_error(e: vm_memory::VolatileMemoryError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
+
+/// A handle to a file that can be shared between multiple threads, which keeps its own
+/// cursor and uses positioned I/O for all data accesses.
+///
+/// Cloning a `SharedFile` returns a new handle to the same underlying file, with a cursor
+/// that's independent from the one of the original handle.
+///
+/// # Example
+///
+/// ```rust
+/// # use virtio_blk::shared_file::SharedFile;
+/// # use virtio_blk::stdio_executor::StdIoBackend;
+/// # use vmm_sys_util::tempfile::TempFile;
+/// let file = TempFile::new().unwrap().into_file();
+/// file.set_len(0x1000).unwrap();
+///
+/// let shared = SharedFile::new(file);
+/// // One executor for each request queue.
+/// let executors = (0..4)
+///     .map(|_| StdIoBackend::new(shared.clone(), 0).unwrap())
+///     .collect::<Vec<_>>();
+/// ```
+#[derive(Clone, Debug)]
+pub struct SharedFile {
+    file: Arc<File>,
+    offset: u64,
+    // The bounce buffers for direct accesses, or `None` if the file uses the host page cache.
+    direct: Option<Arc<BouncePool>>,
+    // Whether the vectored reads look for holes in the file.
+    sparse_reads: bool,
+}
+
+impl SharedFile {
+    /// Creates a new `SharedFile` based on `file`, with the cursor at offset 0.
+    ///
+    /// # Arguments
+    /// * `file` - The block device backing file.
+    pub fn new(file: File) -> Self {
+        SharedFile {
+            file: Arc::new(file),
+            offset: 0,
+            direct: None,
+            sparse_reads: false,
+        }
+    }
+
+    /// Opens the file at `path` as a `SharedFile`, with the cursor at offset 0.
+    ///
+    /// With `CacheMode::Direct`, the file is opened with `O_DIRECT`, and all the data accesses
+    /// have to be aligned to [`alignment`](struct.SharedFile.html#method.alignment) bytes, both
+    /// for the offset and the length. Otherwise, they fail with `io::ErrorKind::InvalidInput`.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the block device backing file.
+    /// * `read_only` - Whether the file is opened for reading only.
+    /// * `cache_mode` - The host page cache policy.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        read_only: bool,
+        cache_mode: CacheMode,
+    ) -> io::Result<Self> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(!read_only);
+        if cache_mode == CacheMode::Direct {
+            options.custom_flags(libc::O_DIRECT);
+        }
+        let file = options.open(path)?;
+
+        let direct = match cache_mode {
+            CacheMode::Cached => None,
+            CacheMode::Direct => {
+                // The preferred I/O block size is a multiple of the logical block size of the
+                // underlying device, so it's a safe alignment for direct accesses.
+                let alignment = max(file.metadata()?.blksize(), SECTOR_SIZE);
+                if !alignment.is_power_of_two() {
+                    return Err(io::Error::from(io::ErrorKind::InvalidInput));
+                }
+                Some(Arc::new(BouncePool::new(alignment as usize)))
+            }
+        };
+        Ok(SharedFile {
+            file: Arc::new(file),
+            offset: 0,
+            direct,
+            sparse_reads: false,
+        })
+    }
+
+    /// Enables or disables the hole detection for the vectored reads, which are used by the
+    /// executor for the `In` requests.
+    ///
+    /// With sparse reads, the unallocated ranges of the file are not read, and the corresponding
+    /// guest buffers are filled with zeroes instead. This saves I/O for sparse images (e.g. for
+    /// freshly created disks), at the cost of one or two more system calls for each read.
+    ///
+    /// # Arguments
+    /// * `sparse_reads` - Whether to look for holes when reading.
+    pub fn with_sparse_reads(mut self, sparse_reads: bool) -> Self {
+        self.sparse_reads = sparse_reads;
+        self
+    }
+
+    /// Returns a reference to the underlying file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Returns the host page cache policy of the file.
+    pub fn cache_mode(&self) -> CacheMode {
+        match self.direct {
+            Some(_) => CacheMode::Direct,
+            None => CacheMode::Cached,
+        }
+    }
+
+    /// Returns the alignment required for the data accesses, which is 1 when the file uses the
+    /// host page cache.
+    pub fn alignment(&self) -> u64 {
+        self.direct.as_ref().map_or(1, |pool| pool.alignment as u64)
+    }
+
+    // Reads at `offset` into `buf`. The direct accesses are done via a bounce buffer, so neither
+    // `offset` nor `buf` have to be aligned.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let pool = match self.direct {
+            Some(ref pool) => pool,
+            None => return self.file.read_at(buf, offset),
+        };
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let skip = (offset % pool.alignment as u64) as usize;
+        pool.with_buf(|bounce| {
+            let len = min(
+                (skip + buf.len()).next_multiple_of(pool.alignment),
+                bounce.len(),
+            );
+            let count = self
+                .file
+                .read_at(&mut bounce[..len], offset - skip as u64)?;
+            let count = min(count.saturating_sub(skip), buf.len());
+            buf[..count].copy_from_slice(&bounce[skip..skip + count]);
+            Ok(count)
+        })
+    }
+
+    // Writes `buf` at `offset`. The direct accesses are done via a bounce buffer, so `buf` can
+    // be anywhere in memory, but `offset` and the written length have to be aligned.
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let pool = match self.direct {
+            Some(ref pool) => pool,
+            None => return self.file.write_at(buf, offset),
+        };
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let len = buf.len() - buf.len() % pool.alignment;
+        if !pool.is_aligned(offset) || len == 0 {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        pool.with_buf(|bounce| {
+            let len = min(len, bounce.len());
+            bounce[..len].copy_from_slice(&buf[..len]);
+            self.file.write_at(&bounce[..len], offset)
+        })
+    }
+
+    // Checks that a direct vectored access can be done, and returns whether the guest buffers
+    // can be used without bouncing.
+    fn check_direct_access(
+        pool: &BouncePool,
+        bufs: &[VolatileSlice],
+        offset: u64,
+    ) -> io::Result<bool> {
+        let len = bufs.iter().map(|buf| buf.len() as u64).sum::<u64>();
+        if !pool.is_aligned(offset) || !pool.is_aligned(len) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        Ok(bufs
+            .iter()
+            .all(|buf| pool.is_aligned(buf.as_ptr() as u64) && pool.is_aligned(buf.len() as u64)))
+    }
+
+    // Returns whether `value` is properly aligned for the data accesses.
+    fn is_aligned(&self, value: u64) -> bool {
+        self.direct
+            .as_ref()
+            .map_or(true, |pool| pool.is_aligned(value))
+    }
+
+    // Returns the result of `lseek` with `whence` (i.e. `SEEK_DATA` or `SEEK_HOLE`) at `offset`,
+    // or `None` if there's no such area after `offset`.
+    fn seek_extent(&self, offset: u64, whence: libc::c_int) -> io::Result<Option<u64>> {
+        let offset = libc::off_t::try_from(offset)
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        // Safe because this doesn't access any memory. The file offset is changed, but all the
+        // accesses of a `SharedFile` are positioned, so it's not used anywhere.
+        let ret = unsafe { libc::lseek(self.file.as_raw_fd(), offset, whence) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::ENXIO) => Ok(None),
+                _ => Err(err),
+            };
+        }
+        // The cast is safe since `ret` is positive.
+        Ok(Some(ret as u64))
+    }
+
+    // Returns the end (capped at `end`) of the file area which starts at `offset`, and whether
+    // the area is a hole.
+    fn next_extent(&self, offset: u64, end: u64) -> io::Result<(u64, bool)> {
+        let (extent_end, is_hole) = match self.seek_extent(offset, libc::SEEK_DATA) {
+            Ok(Some(data)) if data > offset => (data, true),
+            Ok(Some(_)) => {
+                // There's always a hole at the end of the file.
+                let hole = self.seek_extent(offset, libc::SEEK_HOLE)?;
+                (hole.unwrap_or(end), false)
+            }
+            // Either there's only a hole after `offset`, or `offset` is past the end of the
+            // file. Reading beyond the end has to fail, so it's considered data.
+            Ok(None) => match self.file.metadata()?.len() {
+                len if len > offset => (len, true),
+                _ => (end, false),
+            },
+            // The filesystem doesn't support looking for holes.
+            Err(_) => (end, false),
+        };
+        let extent_end = min(extent_end, end);
+        // Holes are always aligned to the filesystem block size, but check anyway so that the
+        // data accesses don't end up being unaligned.
+        let is_hole = is_hole && self.is_aligned(offset) && self.is_aligned(extent_end);
+        Ok((extent_end, is_hole))
+    }
+
+    // Fills `bufs` with the data at `offset`, filling the areas which correspond to holes with
+    // zeroes when sparse reads are enabled.
+    fn read_bufs_at(&self, bufs: &[VolatileSlice], offset: u64) -> io::Result<()> {
+        if !self.sparse_reads {
+            return self.read_data_at(bufs, offset);
+        }
+
+        let len = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+        let end = offset
+            .checked_add(len as u64)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let mut pos = offset;
+        while pos < end {
+            let (extent_end, is_hole) = self.next_extent(pos, end)?;
+            // The casts are safe since both values are smaller than `len`.
+            let bufs = sub_bufs(bufs, (pos - offset) as usize, (extent_end - pos) as usize);
+            if is_hole {
+                let zeroes = [0u8; 0x1000];
+                for buf in bufs {
+                    let mut done = 0;
+                    while done < buf.len() {
+                        let count = min(zeroes.len(), buf.len() - done);
+                        buf.subslice(done, count)
+                            .map_err(volatile_error)?
+                            .copy_from(&zeroes[..count]);
+                        done += count;
+                    }
+                }
+            } else {
+                self.read_data_at(&bufs, pos)?;
+            }
+            pos = extent_end;
+        }
+        Ok(())
+    }
+
+    // Fills `bufs` with the data at `offset`, using bounce buffers for direct accesses when the
+    // guest buffers are not aligned.
+    fn read_data_at(&self, bufs: &[VolatileSlice], mut offset: u64) -> io::Result<()> {
+        let pool = match self.direct {
+            Some(ref pool) if !Self::check_direct_access(pool, bufs, offset)? => pool,
+            _ => return read_exact_vectored_at(self.file.as_raw_fd(), bufs, offset),
+        };
+
+        let mut remaining = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+        pool.with_buf(|bounce| {
+            // The range of `bounce` which hasn't been copied to the guest buffers yet.
+            let (mut start, mut end) = (0, 0);
+            for buf in bufs {
+                let mut done = 0;
+                while done < buf.len() {
+                    if start == end {
+                        // Both `remaining` and the size of `bounce` are aligned.
+                        end = min(remaining, bounce.len());
+                        self.file.read_exact_at(&mut bounce[..end], offset)?;
+                        offset += end as u64;
+                        remaining -= end;
+                        start = 0;
+                    }
+                    let count = min(end - start, buf.len() - done);
+                    buf.subslice(done, count)
+                        .map_err(volatile_error)?
+                        .copy_from(&bounce[start..start + count]);
+                    start += count;
+                    done += count;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    // Writes the data from `bufs` at `offset`, using bounce buffers for direct accesses when the
+    // guest buffers are not aligned.
+    fn write_bufs_at(&self, bufs: &[VolatileSlice], mut offset: u64) -> io::Result<()> {
+        let pool = match self.direct {
+            Some(ref pool) if !Self::check_direct_access(pool, bufs, offset)? => pool,
+            _ => return write_all_vectored_at(self.file.as_raw_fd(), bufs, offset),
+        };
+
+        pool.with_buf(|bounce| {
+            // The number of bytes gathered in `bounce`.
+            let mut len = 0;
+            for buf in bufs {
+                let mut done = 0;
+                while done < buf.len() {
+                    let count = min(bounce.len() - len, buf.len() - done);
+                    buf.subslice(done, count)
+                        .map_err(volatile_error)?
+                        .copy_to(&mut bounce[len..len + count]);
+                    len += count;
+                    done += count;
+                    if len == bounce.len() {
+                        self.file.write_all_at(bounce, offset)?;
+                        offset += len as u64;
+                        len = 0;
+                    }
+                }
+            }
+            // The total length is aligned, so what's left in `bounce` is aligned as well.
+            self.file.write_all_at(&bounce[..len], offset)
+        })
+    }
+
+    // Advances the cursor with `count` bytes.
+    fn advance(&mut self, count: usize) -> io::Result<()> {
+        self.offset = self
+            .offset
+            .checked_add(count as u64)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        Ok(())
+    }
+}
+
+impl Read for SharedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.read_at(buf, self.offset)?;
+        self.advance(count)?;
+        Ok(count)
+    }
+}
+
+impl Write for SharedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.write_at(buf, self.offset)?;
+        self.advance(count)?;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Writes go straight to the file, so there's nothing to flush here.
+        Ok(())
+    }
+}
+
+impl Seek for SharedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => {
+                self.offset = offset;
+                return Ok(offset);
+            }
+            SeekFrom::Current(delta) => (self.offset, delta),
+            SeekFrom::End(delta) => (self.file.metadata()?.len(), delta),
+        };
+
+        let offset = if delta >= 0 {
+            base.checked_add(delta as u64)
+        } else {
+            base.checked_sub(delta.unsigned_abs())
+        }
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+        self.offset = offset;
+        Ok(offset)
+    }
+}
+
+impl FileSync for SharedFile {
+    fn fsync(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+}
+
+impl PunchHole for SharedFile {
+    fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        fallocate(&*self.file, FallocateMode::PunchHole, true, offset, length)
+            .map_err(io::Error::from)
+    }
+}
+
+impl WriteZeroesAt for SharedFile {
+    fn write_zeroes_at(&mut self, offset: u64, length: usize) -> io::Result<usize> {
+        // Try to use `fallocate` first, as it's more efficient than actually writing zeroes.
+        if fallocate(
+            &*self.file,
+            FallocateMode::ZeroRange,
+            true,
+            offset,
+            length as u64,
+        )
+        .is_ok()
+        {
+            return Ok(length);
+        }
+
+        let buf = vec![0u8; min(length, ZEROES_BUF_SIZE)];
+        let mut written = 0;
+        while written < length {
+            let count = min(length - written, buf.len());
+            written += self.write_at(&buf[..count], offset + written as u64)?;
+        }
+        Ok(length)
+    }
+}
+
+impl Backend for SharedFile {
+    fn read_exact_vectored_at(
+        &mut self,
+        bufs: &[VolatileSlice],
+        offset: u64,
+    ) -> Option<io::Result<()>> {
+        Some(self.read_bufs_at(bufs, offset))
+    }
+
+    fn write_all_vectored_at(
+        &mut self,
+        bufs: &[VolatileSlice],
+        offset: u64,
+    ) -> Option<io::Result<()>> {
+        Some(self.write_bufs_at(bufs, offset))
+    }
+
+    fn set_size(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+
+    fn image_id(&self) -> Option<[u8; VIRTIO_BLK_ID_BYTES]> {
+        self.file.image_id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+    use vmm_sys_util::tempfile::TempFile;
+
+    use crate::defs::SECTOR_SIZE;
+    use crate::request::{Request, RequestType};
+    use crate::stdio_executor::StdIoBackend;
+
+    #[test]
+    fn test_shared_file_cursors() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+
+        let mut a = SharedFile::new(f);
+        let mut b = a.clone();
+
+        a.write_all(&[1u8; 0x10]).unwrap();
+        assert_eq!(a.stream_position().unwrap(), 0x10);
+        // The cursor of `b` is not affected by operations on `a`.
+        assert_eq!(b.stream_position().unwrap(), 0);
+
+        let mut buf = [0u8; 0x20];
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[..0x10], [1u8; 0x10]);
+        assert_eq!(buf[0x10..], [0u8; 0x10]);
+
+        assert_eq!(b.seek(SeekFrom::End(-0x10)).unwrap(), 0xff0);
+        assert_eq!(b.seek(SeekFrom::Current(-0xf0)).unwrap(), 0xf00);
+        assert!(b.seek(SeekFrom::Current(-0x1000)).is_err());
+        assert_eq!(b.stream_position().unwrap(), 0xf00);
+        assert_eq!(b.seek(SeekFrom::Start(0x2000)).unwrap(), 0x2000);
+        // Reading beyond the end of the file doesn't return any data.
+        assert_eq!(b.read(&mut buf).unwrap(), 0);
+
+        a.write_zeroes_at(0x8, 0x4).unwrap();
+        a.seek(SeekFrom::Start(0)).unwrap();
+        a.read_exact(&mut buf[..0x10]).unwrap();
+        assert_eq!(buf[..0x8], [1u8; 0x8]);
+        assert_eq!(buf[0x8..0xc], [0u8; 0x4]);
+        assert_eq!(buf[0xc..0x10], [1u8; 0x4]);
+
+        a.punch_hole(0, 0x10).unwrap();
+        a.seek(SeekFrom::Start(0)).unwrap();
+        a.read_exact(&mut buf[..0x10]).unwrap();
+        assert_eq!(buf[..0x10], [0u8; 0x10]);
+
+        a.fsync().unwrap();
+    }
+
+    #[test]
+    fn test_multiple_queue_workers() {
+        const NUM_QUEUES: u64 = 4;
+        const SECTORS_PER_QUEUE: u64 = 8;
+
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(NUM_QUEUES * SECTORS_PER_QUEUE * SECTOR_SIZE)
+            .unwrap();
+        let shared = SharedFile::new(f);
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        for i in 0..NUM_QUEUES {
+            mem.write_slice(
+                &[i as u8 + 1; SECTOR_SIZE as usize],
+                GuestAddress(i * SECTOR_SIZE),
+            )
+            .unwrap();
+        }
+
+        // Each worker writes to its own disk area, using an independent executor.
+        thread::scope(|s| {
+            for i in 0..NUM_QUEUES {
+                let mut executor = StdIoBackend::new(shared.clone(), 0).unwrap();
+                let mem = &mem;
+                s.spawn(move || {
+                    for j in 0..SECTORS_PER_QUEUE {
+                        let request = Request::new(
+                            RequestType::Out,
+                            vec![(GuestAddress(i * SECTOR_SIZE), SECTOR_SIZE as u32)],
+                            i * SECTORS_PER_QUEUE + j,
+                            GuestAddress(0x8_0000),
+                        );
+                        executor.execute(mem, &request).unwrap();
+                    }
+                });
+            }
+        });
+
+        let mut buf = vec![0u8; (SECTORS_PER_QUEUE * SECTOR_SIZE) as usize];
+        for i in 0..NUM_QUEUES {
+            shared
+                .file()
+                .read_exact_at(&mut buf, i * SECTORS_PER_QUEUE * SECTOR_SIZE)
+                .unwrap();
+            assert!(buf.iter().all(|&b| b == i as u8 + 1));
+        }
+    }
+
+    #[test]
+    fn test_direct_access_alignment() {
+        const ALIGNMENT: usize = 0x200;
+
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x2_0000).unwrap();
+        // The file isn't actually opened with `O_DIRECT`, so that the bounce buffer handling
+        // can be checked regardless of the filesystem support.
+        let mut file = SharedFile {
+            file: Arc::new(f),
+            offset: 0,
+            direct: Some(Arc::new(BouncePool::new(ALIGNMENT))),
+            sparse_reads: false,
+        };
+        assert_eq!(file.cache_mode(), CacheMode::Direct);
+        assert_eq!(file.alignment(), ALIGNMENT as u64);
+        assert_eq!(
+            SharedFile::new(file.file().try_clone().unwrap()).alignment(),
+            1
+        );
+
+        // Writes have to be aligned.
+        let data = (0..0x400).map(|i| i as u8).collect::<Vec<_>>();
+        file.seek(SeekFrom::Start(0x100)).unwrap();
+        assert_eq!(
+            file.write(&data).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        file.seek(SeekFrom::Start(0x200)).unwrap();
+        assert_eq!(
+            file.write(&data[..0x100]).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        // Only the aligned part of the buffer is written.
+        assert_eq!(file.write(&data[..0x300]).unwrap(), 0x200);
+        file.write_all(&data[0x200..]).unwrap();
+
+        // Reads don't have to be aligned.
+        let mut buf = [0u8; 0x80];
+        file.seek(SeekFrom::Start(0x280)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[..], data[0x80..0x100]);
+
+        // Vectored accesses with unaligned buffers go through the bounce buffers, which are
+        // reused afterwards.
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x4_0000)]).unwrap();
+        let pattern = (0..0x1_0400).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        mem.write_slice(&pattern, GuestAddress(0x100)).unwrap();
+        let request = Request::new(
+            RequestType::Out,
+            vec![
+                (GuestAddress(0x100), 0x1_0000),
+                (GuestAddress(0x1_0100), 0x400),
+            ],
+            0,
+            GuestAddress(0),
+        );
+        let bufs = request.data_slices(&mem).unwrap();
+        file.write_all_vectored_at(&bufs, 0x400).unwrap().unwrap();
+        assert_eq!(file.direct.as_ref().unwrap().bufs.lock().unwrap().len(), 1);
+
+        let request = Request::new(
+            RequestType::In,
+            vec![
+                (GuestAddress(0x2_0080), 0x380),
+                (GuestAddress(0x2_1000), 0x1_0080),
+            ],
+            0,
+            GuestAddress(0),
+        );
+        let bufs = request.data_slices(&mem).unwrap();
+        file.read_exact_vectored_at(&bufs, 0x400).unwrap().unwrap();
+        let mut buf = vec![0u8; 0x1_0400];
+        mem.read_slice(&mut buf[..0x380], GuestAddress(0x2_0080))
+            .unwrap();
+        mem.read_slice(&mut buf[0x380..], GuestAddress(0x2_1000))
+            .unwrap();
+        assert_eq!(buf, pattern);
+        assert_eq!(file.direct.as_ref().unwrap().bufs.lock().unwrap().len(), 1);
+
+        // Aligned buffers are used directly.
+        let request = Request::new(
+            RequestType::In,
+            vec![(GuestAddress(0x3_0000), 0x400)],
+            0,
+            GuestAddress(0),
+        );
+        let bufs = request.data_slices(&mem).unwrap();
+        file.read_exact_vectored_at(&bufs, 0x400).unwrap().unwrap();
+        mem.read_slice(&mut buf[..0x400], GuestAddress(0x3_0000))
+            .unwrap();
+        assert_eq!(buf[..0x400], pattern[..0x400]);
+
+        // The file range has to be aligned, regardless of the buffers.
+        assert_eq!(
+            file.read_exact_vectored_at(&bufs, 0x100)
+                .unwrap()
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+        let bufs = [bufs[0].subslice(0, 0x300).unwrap()];
+        assert_eq!(
+            file.write_all_vectored_at(&bufs, 0)
+                .unwrap()
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn test_open_direct() {
+        let path = TempFile::new_in(Path::new(".")).unwrap();
+        path.as_file().set_len(0x1_0000).unwrap();
+
+        let file = SharedFile::open(path.as_path(), true, CacheMode::Cached).unwrap();
+        assert_eq!(file.cache_mode(), CacheMode::Cached);
+        assert!(file.file().write_at(&[0u8], 0).is_err());
+
+        let file = match SharedFile::open(path.as_path(), false, CacheMode::Direct) {
+            Ok(file) => file,
+            // Not all filesystems support `O_DIRECT`.
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return,
+            Err(e) => panic!("failed to open file: {}", e),
+        };
+        assert_eq!(file.cache_mode(), CacheMode::Direct);
+        let alignment = file.alignment();
+        assert!(alignment.is_power_of_two() && alignment >= SECTOR_SIZE);
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        mem.write_slice(&[0xaa; 0x2000], GuestAddress(0x100))
+            .unwrap();
+        let mut executor = StdIoBackend::new(file, 0).unwrap();
+
+        // An unaligned guest buffer.
+        let request = Request::new(
+            RequestType::Out,
+            vec![(GuestAddress(0x100), alignment as u32)],
+            0,
+            GuestAddress(0x8_0000),
+        );
+        executor.execute(&mem, &request).unwrap();
+        // An aligned guest buffer.
+        let request = Request::new(
+            RequestType::In,
+            vec![(GuestAddress(0x4000), alignment as u32)],
+            0,
+            GuestAddress(0x8_0000),
+        );
+        executor.execute(&mem, &request).unwrap();
+        let mut buf = vec![0u8; alignment as usize];
+        mem.read_slice(&mut buf, GuestAddress(0x4000)).unwrap();
+        assert!(buf.iter().all(|&b| b == 0xaa));
+
+        // Requests which are not aligned to the block size fail.
+        if alignment > SECTOR_SIZE {
+            let request = Request::new(
+                RequestType::In,
+                vec![(GuestAddress(0x4000), SECTOR_SIZE as u32)],
+                1,
+                GuestAddress(0x8_0000),
+            );
+            assert!(executor.execute(&mem, &request).is_err());
+        }
+    }
+
+    #[test]
+    fn test_sparse_reads() {
+        const DISK_SIZE: u64 = 0x10_0000;
+
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(DISK_SIZE).unwrap();
+        f.write_all_at(&[0xaa; 0x1000], 0x4_0000).unwrap();
+        let mut file = SharedFile::new(f).with_sparse_reads(true);
+
+        // Holes are detected, unless the filesystem doesn't support it.
+        let (end, is_hole) = file.next_extent(0, DISK_SIZE).unwrap();
+        if is_hole {
+            assert_eq!(end, 0x4_0000);
+            assert_eq!(
+                file.next_extent(0x4_0000, DISK_SIZE).unwrap(),
+                (0x4_1000, false)
+            );
+            assert_eq!(
+                file.next_extent(0x4_1000, 0x8_0000).unwrap(),
+                (0x8_0000, true)
+            );
+        }
+        // Past the end of the file.
+        assert_eq!(
+            file.next_extent(DISK_SIZE, DISK_SIZE + 0x1000).unwrap(),
+            (DISK_SIZE + 0x1000, false)
+        );
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let read = |file: &mut SharedFile, offset: u64| {
+            mem.write_slice(&[0xff; 0x3000], GuestAddress(0x1000))
+                .unwrap();
+            let request = Request::new(
+                RequestType::In,
+                vec![
+                    (GuestAddress(0x1000), 0x1800),
+                    (GuestAddress(0x2800), 0x1800),
+                ],
+                0,
+                GuestAddress(0),
+            );
+            let bufs = request.data_slices(&mem).unwrap();
+            let result = file.read_exact_vectored_at(&bufs, offset).unwrap();
+            let mut buf = vec![0u8; 0x3000];
+            mem.read_slice(&mut buf, GuestAddress(0x1000)).unwrap();
+            result.map(|_| buf)
+        };
+
+        // Mixed holes and data.
+        let buf = read(&mut file, 0x3_f000).unwrap();
+        assert!(buf[..0x1000].iter().all(|&b| b == 0));
+        assert!(buf[0x1000..0x2000].iter().all(|&b| b == 0xaa));
+        assert!(buf[0x2000..].iter().all(|&b| b == 0));
+        // Only holes, up to the end of the file.
+        let buf = read(&mut file, DISK_SIZE - 0x3000).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+        // Reading beyond the end of the file still fails.
+        assert_eq!(
+            read(&mut file, DISK_SIZE - 0x1000).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        // The same data is returned without sparse reads.
+        let mut file = file.with_sparse_reads(false);
+        let buf = read(&mut file, 0x3_f000).unwrap();
+        assert!(buf[..0x1000].iter().all(|&b| b == 0));
+        assert!(buf[0x1000..0x2000].iter().all(|&b| b == 0xaa));
+        assert!(buf[0x2000..].iter().all(|&b| b == 0));
+    }
+}
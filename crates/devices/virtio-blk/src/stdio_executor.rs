@@ -23,50 +23,394 @@
 //! For more complex executors, that need asynchronous dispatch of requests for example, we can
 //! add separate modules for those abstractions as well.
 
+use std::cmp::min;
+use std::convert::TryFrom;
 use std::fmt::{self, Display};
+use std::fs::{File, Metadata};
 use std::io::{Read, Seek, SeekFrom, Write};
-use std::{io, mem, result};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::{io, result};
 
 use log::{error, warn};
 
-use vm_memory::{Address, ByteValued, Bytes, GuestMemory, GuestMemoryError};
+use virtio_queue::DirtyTracker;
+use vm_memory::{
+    Address, ByteValued, Bytes, GuestMemory, GuestMemoryError, GuestMemoryRegion, VolatileSlice,
+};
 use vmm_sys_util::file_traits::FileSync;
 use vmm_sys_util::write_zeroes::{PunchHole, WriteZeroesAt};
 
-use crate::defs::VIRTIO_BLK_T_GET_ID;
+use crate::buffer_pool::BufferPool;
 use crate::defs::{
-    SECTOR_SHIFT, SECTOR_SIZE, VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_RO,
-    VIRTIO_BLK_F_WRITE_ZEROES, VIRTIO_BLK_ID_BYTES, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK,
-    VIRTIO_BLK_S_UNSUPP, VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_WRITE_ZEROES,
+    SECTOR_SHIFT, SECTOR_SIZE, VIRTIO_BLK_F_CONFIG_WCE, VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH,
+    VIRTIO_BLK_F_LIFETIME, VIRTIO_BLK_F_RO, VIRTIO_BLK_F_WRITE_ZEROES, VIRTIO_BLK_ID_BYTES,
+    VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_WRITE_ZEROES,
+};
+use crate::defs::{VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_GET_LIFETIME};
+use crate::metrics::{BlockMetrics, NoopMetrics};
+use crate::request::{
+    self, DiscardWriteZeroes, Lifetime, Request, RequestType, SegmentBuf, Status,
 };
-use crate::request::{Request, RequestType};
+
+// The maximum number of buffers that can be passed to a single `preadv`/`pwritev` call.
+const MAX_IOVECS: usize = libc::UIO_MAXIOV as usize;
 
 /// Trait that keeps as supertraits the ones that are necessary for the `StdIoBackend` abstraction
 /// used for the virtio block request execution.
-pub trait Backend: Read + Write + Seek + FileSync + PunchHole + WriteZeroesAt {}
-
-impl<B: Read + Write + Seek + FileSync + PunchHole + WriteZeroesAt> Backend for B {}
-
-/// One or more `DiscardWriteZeroes` structs are used to describe the data for
-/// discard or write zeroes command.
-#[derive(Copy, Clone, Debug, Default)]
-#[repr(C)]
-struct DiscardWriteZeroes {
-    sector: u64,
-    num_sectors: u32,
-    flags: u32,
+///
+/// The vectored I/O methods are optional; their default implementations return `None`, in which
+/// case the executor falls back to the `Read` and `Write` interfaces.
+pub trait Backend: Read + Write + Seek + FileSync + PunchHole + WriteZeroesAt {
+    /// Fills `bufs` with the data starting at `offset`, without using or changing the cursor of
+    /// the backend. Returns `None` if positioned vectored I/O is not supported.
+    ///
+    /// # Arguments
+    /// * `bufs` - The memory buffers to fill, in order.
+    /// * `offset` - The offset in the backend where the data starts.
+    fn read_exact_vectored_at(
+        &mut self,
+        _bufs: &[VolatileSlice],
+        _offset: u64,
+    ) -> Option<io::Result<()>> {
+        None
+    }
+
+    /// Writes the data from `bufs` starting at `offset`, without using or changing the cursor of
+    /// the backend. Returns `None` if positioned vectored I/O is not supported.
+    ///
+    /// # Arguments
+    /// * `bufs` - The memory buffers to write, in order.
+    /// * `offset` - The offset in the backend where the data is written.
+    fn write_all_vectored_at(
+        &mut self,
+        _bufs: &[VolatileSlice],
+        _offset: u64,
+    ) -> Option<io::Result<()>> {
+        None
+    }
+
+    /// Copies `len` bytes starting at `offset` to the file referred by `fd`, at `fd_offset`,
+    /// without passing the data through userspace. Returns `None` if such copies are not
+    /// supported, in which case nothing was copied.
+    ///
+    /// # Arguments
+    /// * `offset` - The offset in the backend where the data starts.
+    /// * `fd` - The file descriptor of the file to copy the data to.
+    /// * `fd_offset` - The offset in the file where the data is copied.
+    /// * `len` - The number of bytes to copy.
+    fn copy_to_fd_at(
+        &mut self,
+        _offset: u64,
+        _fd: RawFd,
+        _fd_offset: u64,
+        _len: usize,
+    ) -> Option<io::Result<()>> {
+        None
+    }
+
+    /// Copies `len` bytes from the file referred by `fd`, starting at `fd_offset`, to `offset`
+    /// in the backend, without passing the data through userspace. Returns `None` if such copies
+    /// are not supported, in which case nothing was copied.
+    ///
+    /// # Arguments
+    /// * `fd` - The file descriptor of the file to copy the data from.
+    /// * `fd_offset` - The offset in the file where the data starts.
+    /// * `offset` - The offset in the backend where the data is copied.
+    /// * `len` - The number of bytes to copy.
+    fn copy_from_fd_at(
+        &mut self,
+        _fd: RawFd,
+        _fd_offset: u64,
+        _offset: u64,
+        _len: usize,
+    ) -> Option<io::Result<()>> {
+        None
+    }
+
+    /// Changes the size of the backend to `len` bytes. Backends which can't be resized return
+    /// an `io::ErrorKind::Unsupported` error, which is also the default.
+    ///
+    /// # Arguments
+    /// * `len` - The new size, in bytes.
+    fn set_size(&mut self, _len: u64) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    /// Returns a stable identifier of the backend, which can be used as the device id string
+    /// (i.e. the disk serial). Returns `None` by default.
+    fn image_id(&self) -> Option<[u8; VIRTIO_BLK_ID_BYTES]> {
+        None
+    }
+
+    /// Returns the current wear estimates of the media behind the backend (i.e. of an eMMC or
+    /// UFS device which is passed through to the guest). Returns `None` by default.
+    fn lifetime(&self) -> Option<Lifetime> {
+        None
+    }
+}
+
+impl Backend for File {
+    fn read_exact_vectored_at(
+        &mut self,
+        bufs: &[VolatileSlice],
+        offset: u64,
+    ) -> Option<io::Result<()>> {
+        Some(read_exact_vectored_at(self.as_raw_fd(), bufs, offset))
+    }
+
+    fn write_all_vectored_at(
+        &mut self,
+        bufs: &[VolatileSlice],
+        offset: u64,
+    ) -> Option<io::Result<()>> {
+        Some(write_all_vectored_at(self.as_raw_fd(), bufs, offset))
+    }
+
+    fn copy_to_fd_at(
+        &mut self,
+        offset: u64,
+        fd: RawFd,
+        fd_offset: u64,
+        len: usize,
+    ) -> Option<io::Result<()>> {
+        copy_file_range_at(self.as_raw_fd(), offset, fd, fd_offset, len)
+    }
+
+    fn copy_from_fd_at(
+        &mut self,
+        fd: RawFd,
+        fd_offset: u64,
+        offset: u64,
+        len: usize,
+    ) -> Option<io::Result<()>> {
+        copy_file_range_at(fd, fd_offset, self.as_raw_fd(), offset, len)
+    }
+
+    fn set_size(&mut self, len: u64) -> io::Result<()> {
+        self.set_len(len)
+    }
+
+    fn image_id(&self) -> Option<[u8; VIRTIO_BLK_ID_BYTES]> {
+        self.metadata().ok().map(|metadata| image_id(&metadata))
+    }
+}
+
+/// Derives the device id string from the `metadata` of the backing file.
+///
+/// The id is built from the device numbers and the inode number of the file, so it stays the
+/// same across VM boots as long as the backing file is not replaced. The guest exposes it as the
+/// disk serial, which means that it can be used to build persistent device paths (such as the
+/// ones from `/dev/disk/by-id` on Linux). It consists of fixed width hex fields which fill all
+/// the `VIRTIO_BLK_ID_BYTES`: 4 digits for the device numbers, folded into 16 bits, followed by
+/// the 16 digits of the inode number.
+///
+/// # Arguments
+/// * `metadata` - The metadata of the block device backing file.
+pub fn image_id(metadata: &Metadata) -> [u8; VIRTIO_BLK_ID_BYTES] {
+    file_id(metadata.dev(), metadata.rdev(), metadata.ino())
+}
+
+// Builds the id of the file with inode number `ino` on the device `dev` (`rdev` is the device
+// the file represents, if it's a special file). The inode number is kept whole, since it tells
+// apart the files of the same file system, which is the common case.
+fn file_id(dev: u64, rdev: u64, ino: u64) -> [u8; VIRTIO_BLK_ID_BYTES] {
+    let fold = |n: u64| (n ^ (n >> 16) ^ (n >> 32) ^ (n >> 48)) as u16;
+    let id = format!("{:04x}{:016x}", fold(dev) ^ fold(rdev).rotate_left(8), ino);
+    let mut device_id = [0u8; VIRTIO_BLK_ID_BYTES];
+    device_id.copy_from_slice(id.as_bytes());
+    device_id
+}
+
+// Converts `bufs` to the `iovec`s expected by `preadv`/`pwritev`, skipping the empty ones.
+fn to_iovecs(bufs: &[VolatileSlice]) -> SegmentBuf<libc::iovec> {
+    bufs.iter()
+        .filter(|buf| !buf.is_empty())
+        .map(|buf| libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        })
+        .collect()
+}
+
+// Calls `op` with the `iovecs` which haven't been completely transferred yet, and with the
+// corresponding file offset, until all the data is transferred. `op` returns the number of
+// bytes transferred by a single call, where 0 means that no progress can be made anymore.
+fn vectored_io_at<F>(
+    mut iovecs: SegmentBuf<libc::iovec>,
+    mut offset: u64,
+    eof_error: io::ErrorKind,
+    mut op: F,
+) -> io::Result<()>
+where
+    F: FnMut(&[libc::iovec], libc::off_t) -> isize,
+{
+    let mut first = 0;
+    while first < iovecs.len() {
+        let last = min(first + MAX_IOVECS, iovecs.len());
+        let file_offset = libc::off_t::try_from(offset)
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let ret = op(&iovecs[first..last], file_offset);
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        if ret == 0 {
+            return Err(io::Error::from(eof_error));
+        }
+
+        // The cast is safe since `ret` is positive.
+        let mut count = ret as usize;
+        offset += count as u64;
+        // `count` is at most the length of the pending buffers, so we can't go past the end.
+        while count > 0 {
+            let iov = &mut iovecs[first];
+            if count < iov.iov_len {
+                // Safe because `count` is within the buffer described by `iov`.
+                iov.iov_base = unsafe { (iov.iov_base as *mut u8).add(count) } as *mut _;
+                iov.iov_len -= count;
+                break;
+            }
+            count -= iov.iov_len;
+            first += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Fills `bufs` with the data found at `offset` in the file referred by `fd`, using as few
+/// `preadv` calls as possible.
+///
+/// # Arguments
+/// * `fd` - The file descriptor of the file to read from.
+/// * `bufs` - The memory buffers to fill, in order.
+/// * `offset` - The offset in the file where the data starts.
+pub(crate) fn read_exact_vectored_at(
+    fd: RawFd,
+    bufs: &[VolatileSlice],
+    offset: u64,
+) -> io::Result<()> {
+    vectored_io_at(
+        to_iovecs(bufs),
+        offset,
+        io::ErrorKind::UnexpectedEof,
+        |iovecs, offset| {
+            // Safe because the `iovecs` describe valid memory buffers, and the number of buffers
+            // is at most `MAX_IOVECS`, which fits in a `c_int`.
+            unsafe { libc::preadv(fd, iovecs.as_ptr(), iovecs.len() as libc::c_int, offset) }
+        },
+    )
+}
+
+/// Writes the data from `bufs` at `offset` in the file referred by `fd`, using as few `pwritev`
+/// calls as possible.
+///
+/// # Arguments
+/// * `fd` - The file descriptor of the file to write to.
+/// * `bufs` - The memory buffers to write, in order.
+/// * `offset` - The offset in the file where the data is written.
+pub(crate) fn write_all_vectored_at(
+    fd: RawFd,
+    bufs: &[VolatileSlice],
+    offset: u64,
+) -> io::Result<()> {
+    vectored_io_at(
+        to_iovecs(bufs),
+        offset,
+        io::ErrorKind::WriteZero,
+        |iovecs, offset| {
+            // Safe because the `iovecs` describe valid memory buffers, and the number of buffers
+            // is at most `MAX_IOVECS`, which fits in a `c_int`.
+            unsafe { libc::pwritev(fd, iovecs.as_ptr(), iovecs.len() as libc::c_int, offset) }
+        },
+    )
 }
 
-impl DiscardWriteZeroes {
-    // The least significant bit from `flags` set -> the targeted range should be unmapped
-    // (only valid for write zeroes command).
-    const UNMAP: u32 = 1;
-    // Size of DiscardWriteZeroes struct.
-    const LEN: u64 = mem::size_of::<DiscardWriteZeroes>() as u64;
+/// Copies `len` bytes from `offset_in` in the file referred by `fd_in` to `offset_out` in the
+/// file referred by `fd_out`, using as few `copy_file_range` calls as possible. Returns `None` if
+/// the kernel can't copy data between the two files (i.e. when they are on different file
+/// systems), in which case nothing was copied.
+///
+/// # Arguments
+/// * `fd_in` - The file descriptor of the file to copy from.
+/// * `offset_in` - The offset in the source file where the data starts.
+/// * `fd_out` - The file descriptor of the file to copy to.
+/// * `offset_out` - The offset in the destination file where the data is copied.
+/// * `len` - The number of bytes to copy.
+pub(crate) fn copy_file_range_at(
+    fd_in: RawFd,
+    offset_in: u64,
+    fd_out: RawFd,
+    offset_out: u64,
+    len: usize,
+) -> Option<io::Result<()>> {
+    let (mut off_in, mut off_out) = match (
+        libc::loff_t::try_from(offset_in),
+        libc::loff_t::try_from(offset_out),
+    ) {
+        (Ok(off_in), Ok(off_out)) => (off_in, off_out),
+        _ => return Some(Err(io::Error::from(io::ErrorKind::InvalidInput))),
+    };
+    let mut copied = 0;
+    while copied < len {
+        // Safe because the offsets are valid for writes, and the kernel checks the rest of the
+        // arguments. Both offsets are advanced by the number of copied bytes.
+        let ret = unsafe {
+            libc::copy_file_range(fd_in, &mut off_in, fd_out, &mut off_out, len - copied, 0)
+        };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::EXDEV)
+                | Some(libc::ENOSYS)
+                | Some(libc::EOPNOTSUPP)
+                | Some(libc::EINVAL)
+                    if copied == 0 =>
+                {
+                    return None
+                }
+                _ => return Some(Err(err)),
+            }
+        }
+        if ret == 0 {
+            return Some(Err(io::Error::from(io::ErrorKind::UnexpectedEof)));
+        }
+        // The cast is safe since `ret` is positive.
+        copied += ret as usize;
+    }
+    Some(Ok(()))
 }
 
-// Safe because DiscardWriteZeroes contains only plain data.
-unsafe impl ByteValued for DiscardWriteZeroes {}
+// Resolves the data buffers of `request` to ranges of the files which back the guest memory, as
+// `(fd, offset, len)`. Returns `None` if a buffer is not contained in a single file backed
+// region.
+fn file_ranges<M: GuestMemory>(
+    mem: &M,
+    request: &Request,
+) -> Option<SegmentBuf<(RawFd, u64, usize)>> {
+    request
+        .data()
+        .iter()
+        .map(|&(addr, len)| {
+            let (region, region_addr) = mem.to_region_addr(addr)?;
+            let file_offset = region.file_offset()?;
+            region_addr
+                .raw_value()
+                .checked_add(u64::from(len))
+                .filter(|&end| end <= region.len())?;
+            Some((
+                file_offset.file().as_raw_fd(),
+                file_offset.start().checked_add(region_addr.raw_value())?,
+                len as usize,
+            ))
+        })
+        .collect()
+}
 
 /// Errors encountered during request execution.
 #[derive(Debug)]
@@ -88,7 +432,7 @@ pub enum Error {
     /// Error during read request execution.
     // The `u32` represents the number of bytes written to memory until the error occurred.
     Read(GuestMemoryError, u32),
-    /// Can't execute an operation other than `read` on a read-only device.
+    /// Can't execute an operation that modifies the disk on a read-only device.
     ReadOnly,
     /// Error during write request execution.
     Write(GuestMemoryError),
@@ -98,21 +442,21 @@ pub enum Error {
     Unsupported(u32),
 }
 
-impl Error {
-    fn status(&self) -> u8 {
-        match self {
-            Error::DiscardWriteZeroes(_) => VIRTIO_BLK_S_IOERR,
-            Error::Flush(_) => VIRTIO_BLK_S_IOERR,
-            Error::GuestMemory(_) => VIRTIO_BLK_S_IOERR,
-            Error::InvalidAccess => VIRTIO_BLK_S_IOERR,
-            Error::InvalidFlags => VIRTIO_BLK_S_UNSUPP,
-            Error::InvalidDataLength => VIRTIO_BLK_S_IOERR,
-            Error::Overflow => VIRTIO_BLK_S_IOERR,
-            Error::Read(_, _) => VIRTIO_BLK_S_IOERR,
-            Error::ReadOnly => VIRTIO_BLK_S_IOERR,
-            Error::Write(_) => VIRTIO_BLK_S_IOERR,
-            Error::Seek(_) => VIRTIO_BLK_S_IOERR,
-            Error::Unsupported(_) => VIRTIO_BLK_S_UNSUPP,
+impl From<&Error> for Status {
+    fn from(e: &Error) -> Self {
+        match e {
+            Error::DiscardWriteZeroes(_) => Status::IoErr,
+            Error::Flush(_) => Status::IoErr,
+            Error::GuestMemory(_) => Status::IoErr,
+            Error::InvalidAccess => Status::IoErr,
+            Error::InvalidFlags => Status::Unsupp,
+            Error::InvalidDataLength => Status::IoErr,
+            Error::Overflow => Status::IoErr,
+            Error::Read(_, _) => Status::IoErr,
+            Error::ReadOnly => Status::IoErr,
+            Error::Write(_) => Status::IoErr,
+            Error::Seek(_) => Status::IoErr,
+            Error::Unsupported(_) => Status::Unsupp,
         }
     }
 }
@@ -132,10 +476,7 @@ impl Display for Error {
             InvalidFlags => write!(f, "invalid flags for discard/write zeroes request"),
             Overflow => write!(f, "overflow when computing memory address"),
             Read(ref err, _) => write!(f, "error during read request execution: {}", err),
-            ReadOnly => write!(
-                f,
-                "can't execute an operation other than `read` on a read-only device"
-            ),
+            ReadOnly => write!(f, "can't execute a write operation on a read-only device"),
             Write(ref err) => write!(f, "error during write request execution: {}", err),
             Seek(ref err) => write!(f, "file seek execution failed: {}", err),
             Unsupported(t) => write!(f, "can't execute unsupported request {}", t),
@@ -152,6 +493,17 @@ pub enum ProcessReqError {
     Overflow,
 }
 
+impl Display for ProcessReqError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ProcessReqError::*;
+
+        match self {
+            GuestMemory(ref err) => write!(f, "error writing the request status: {}", err),
+            Overflow => write!(f, "overflow when computing the used length"),
+        }
+    }
+}
+
 impl From<vm_memory::GuestMemoryError> for ProcessReqError {
     fn from(e: vm_memory::GuestMemoryError) -> Self {
         ProcessReqError::GuestMemory(e)
@@ -185,6 +537,28 @@ pub struct StdIoBackend<B: Backend> {
     /// The device id string, which is a NUL-padded ASCII string up to 20 bytes long.
     /// If the string is 20 bytes long, then there is no NUL terminator.
     device_id: Option<[u8; VIRTIO_BLK_ID_BYTES]>,
+    /// The lifetime information that's reported when `inner` doesn't provide any.
+    lifetime: Option<Lifetime>,
+    /// Whether the backend rejects requests that would modify `inner`, regardless of the
+    /// negotiated features.
+    read_only: bool,
+    /// Whether the device cache is in writeback mode. In writethrough mode, every request that
+    /// modifies `inner` is flushed before being completed.
+    writeback: bool,
+    /// The maximum number of segments in a discard request.
+    max_discard_seg: u32,
+    /// The maximum number of segments in a write zeroes request.
+    max_write_zeroes_seg: u32,
+    /// The hooks used for reporting request execution events.
+    metrics: Arc<dyn BlockMetrics>,
+    /// Tracks the request buffers written to memory, if any.
+    dirty_tracker: Option<Arc<dyn DirtyTracker>>,
+    /// Whether the data of `In` and `Out` requests is copied in the kernel, between `inner` and
+    /// the files backing the guest memory, when possible.
+    zero_copy: bool,
+    /// The bounce buffers used for the data of `In` and `Out` requests which can't be
+    /// transferred directly, if any.
+    buffer_pool: Option<BufferPool>,
 }
 
 impl<B: Backend> StdIoBackend<B> {
@@ -194,6 +568,28 @@ impl<B: Backend> StdIoBackend<B> {
     /// * `inner` - The block device backend.
     /// * `features` - The features that were negotiated between driver and device.
     pub fn new(mut inner: B, features: u64) -> Result<Self> {
+        let num_sectors = Self::disk_sectors(&mut inner)?;
+
+        Ok(Self {
+            inner,
+            num_sectors,
+            features,
+            device_id: None,
+            lifetime: None,
+            read_only: false,
+            // Without flush support, the driver has no way of persisting cached writes.
+            writeback: features & (1 << VIRTIO_BLK_F_FLUSH) != 0,
+            max_discard_seg: u32::MAX,
+            max_write_zeroes_seg: u32::MAX,
+            metrics: Arc::new(NoopMetrics),
+            dirty_tracker: None,
+            zero_copy: false,
+            buffer_pool: None,
+        })
+    }
+
+    // Returns the number of sectors of `inner`.
+    fn disk_sectors(inner: &mut B) -> Result<u64> {
         let disk_size = inner.seek(SeekFrom::End(0)).map_err(Error::Seek)?;
         // This check makes sense only if VIRTIO_BLK_F_BLK_SIZE feature is
         // unsupported, which might be okay to assume for now.
@@ -206,33 +602,173 @@ impl<B: Backend> StdIoBackend<B> {
                 disk_size, SECTOR_SIZE
             );
         }
-
-        Ok(Self {
-            inner,
-            num_sectors: disk_size >> SECTOR_SHIFT,
-            features,
-            device_id: None,
-        })
+        Ok(disk_size >> SECTOR_SHIFT)
     }
 
     /// Sets the `device_id`.
     ///
     /// # Arguments
     /// * `device_id` - The block device id. On Linux guests, this information can be read from
-    ///                 `/sys/block/<device>/serial`.
+    ///   `/sys/block/<device>/serial`.
     pub fn with_device_id(mut self, device_id: [u8; VIRTIO_BLK_ID_BYTES]) -> Self {
         self.device_id = Some(device_id);
         self
     }
 
+    /// Sets the lifetime information which is returned for `VIRTIO_BLK_T_GET_LIFETIME` requests
+    /// when `inner` doesn't report any (see `Backend::lifetime`).
+    ///
+    /// # Arguments
+    /// * `lifetime` - The static wear estimates of the block device.
+    pub fn with_lifetime(mut self, lifetime: Lifetime) -> Self {
+        self.lifetime = Some(lifetime);
+        self
+    }
+
+    /// Marks the backend as read-only.
+    ///
+    /// A read-only backend rejects `Out`, `Flush`, `Discard` and `WriteZeroes` requests with
+    /// `VIRTIO_BLK_S_IOERR` without accessing `inner`, even if the driver did not acknowledge
+    /// `VIRTIO_BLK_F_RO`, which is reported by `device_features` for such backends.
+    ///
+    /// # Arguments
+    /// * `read_only` - Whether the block device is read-only.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Sets the maximum number of segments in discard and write zeroes requests, as advertised
+    /// in the `max_discard_seg` and `max_write_zeroes_seg` configuration space fields. Requests
+    /// with more segments are rejected with `VIRTIO_BLK_S_IOERR`. By default, the number of
+    /// segments is only limited while parsing the requests.
+    ///
+    /// # Arguments
+    /// * `max_discard_seg` - The maximum number of segments in a discard request.
+    /// * `max_write_zeroes_seg` - The maximum number of segments in a write zeroes request.
+    pub fn with_max_segments(mut self, max_discard_seg: u32, max_write_zeroes_seg: u32) -> Self {
+        self.max_discard_seg = max_discard_seg;
+        self.max_write_zeroes_seg = max_write_zeroes_seg;
+        self
+    }
+
+    /// Sets the hooks used for reporting request execution events, which are ignored by default.
+    ///
+    /// # Arguments
+    /// * `metrics` - The metrics implementation, which can be shared with other executors and
+    ///   queue handlers.
+    pub fn with_metrics(mut self, metrics: Arc<dyn BlockMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Enables copying the data of `In` and `Out` requests in the kernel, between the backend
+    /// and the files backing the guest memory (i.e. memfd regions), without passing it through
+    /// userspace. This only helps for large transfers, and only works when the backend
+    /// supports it (see `Backend::copy_to_fd_at`). The requests whose buffers are not file
+    /// backed are executed as usual, and the option is turned off the first time the backend
+    /// can't do such a copy.
+    ///
+    /// # Arguments
+    /// * `zero_copy` - Whether the data is copied in the kernel when possible.
+    pub fn with_zero_copy(mut self, zero_copy: bool) -> Self {
+        self.zero_copy = zero_copy;
+        self
+    }
+
+    /// Sets the pool of bounce buffers for the data of `In` and `Out` requests. The data goes
+    /// through a bounce buffer when the guest buffers are not aligned to the alignment of the
+    /// pool (which backends opened with `O_DIRECT` need), and when the backend doesn't support
+    /// vectored I/O. The requests which don't fit in a buffer, or which are executed while all
+    /// the buffers are in use, access the guest buffers one at a time instead.
+    ///
+    /// # Arguments
+    /// * `buffer_pool` - The bounce buffers, which can be shared with other executors.
+    pub fn with_buffer_pool(mut self, buffer_pool: BufferPool) -> Self {
+        self.buffer_pool = Some(buffer_pool);
+        self
+    }
+
+    /// Returns the hooks used for reporting request execution events.
+    pub fn metrics(&self) -> &Arc<dyn BlockMetrics> {
+        &self.metrics
+    }
+
+    /// Sets the object which tracks the writes of the processed requests to guest memory (i.e.
+    /// the data of `In` requests and the status bytes), for live migration.
+    ///
+    /// # Arguments
+    /// * `dirty_tracker` - The tracker, which can be shared with the queues of the device, or
+    ///   `None` to stop tracking writes.
+    pub fn set_dirty_tracker(&mut self, dirty_tracker: Option<Arc<dyn DirtyTracker>>) {
+        self.dirty_tracker = dirty_tracker;
+    }
+
+    /// Returns the features the backend requires the device to offer, i.e. `VIRTIO_BLK_F_RO`
+    /// for read-only backends.
+    pub fn device_features(&self) -> u64 {
+        if self.read_only {
+            1 << VIRTIO_BLK_F_RO
+        } else {
+            0
+        }
+    }
+
+    /// Returns whether requests which modify the backing file are rejected.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only || self.has_feature(VIRTIO_BLK_F_RO)
+    }
+
+    /// Returns whether the device cache is in writeback mode.
+    pub fn is_writeback(&self) -> bool {
+        self.writeback
+    }
+
+    /// Switches the device cache between writeback and writethrough modes.
+    ///
+    /// This has to be called when the driver writes the `writeback` field of the configuration
+    /// space (i.e. at `ConfigSpace::WRITEBACK_OFFSET`). Pending writes are flushed on a
+    /// transition to writethrough mode. The mode can only be changed when `VIRTIO_BLK_F_FLUSH`
+    /// and `VIRTIO_BLK_F_CONFIG_WCE` were negotiated, otherwise the call has no effect.
+    ///
+    /// # Arguments
+    /// * `writeback` - Whether the cache has to be in writeback mode.
+    pub fn set_writeback(&mut self, writeback: bool) -> Result<()> {
+        if !self.has_feature(VIRTIO_BLK_F_FLUSH) || !self.has_feature(VIRTIO_BLK_F_CONFIG_WCE) {
+            return Ok(());
+        }
+        if self.writeback && !writeback {
+            self.inner.fsync().map_err(Error::Flush)?;
+        }
+        self.writeback = writeback;
+        Ok(())
+    }
+
     fn has_feature(&self, feature_pos: u64) -> bool {
         (self.features & (1u64 << feature_pos)) != 0
     }
 
-    fn num_sectors(&self) -> u64 {
+    /// Returns a reference to the block device backend.
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// Returns the number of 512-byte sectors of the backend, which is the capacity that has
+    /// to be exposed in the device configuration space.
+    pub fn num_sectors(&self) -> u64 {
         self.num_sectors
     }
 
+    /// Updates the number of sectors after the backend was resized, and returns the new value.
+    ///
+    /// The capacity is cached when the `StdIoBackend` is created, so this has to be called
+    /// whenever the size of the backend changes, otherwise requests which access the new
+    /// sectors are rejected.
+    pub fn update_num_sectors(&mut self) -> Result<u64> {
+        self.num_sectors = Self::disk_sectors(&mut self.inner)?;
+        Ok(self.num_sectors)
+    }
+
     /// Processes the `request` execution result, writes its status in memory and returns the used
     /// length (i.e. the total number of bytes written into the memory buffer, including the status
     /// byte).
@@ -246,21 +782,128 @@ impl<B: Backend> StdIoBackend<B> {
         request: &Request,
     ) -> result::Result<u32, ProcessReqError> {
         let (status, length) = match self.execute(mem, request) {
-            Ok(length) => (VIRTIO_BLK_S_OK, length),
+            Ok(length) => {
+                self.report_success(request, length);
+                (Status::Ok, length)
+            }
             Err(e) => {
                 error!("failed executing block request: {}", e);
+                self.metrics.execute_fail();
                 match e {
-                    Error::Read(_, bytes_to_mem) => (e.status(), bytes_to_mem),
-                    _ => (e.status(), 0),
+                    Error::Read(_, bytes_to_mem) => (Status::from(&e), bytes_to_mem),
+                    _ => (Status::from(&e), 0),
                 }
             }
         };
-        mem.write_obj(status, request.status_addr())?;
+        request.write_status(mem, status)?;
+        self.mark_dirty(request, length);
         // Adding +1 here for the status byte. `length` should not be u32::MAX since it is expected
         // to be a multiple of SECTOR_SIZE, but using `checked_add` here for safety.
         length.checked_add(1).ok_or(ProcessReqError::Overflow)
     }
 
+    /// Executes adjacent `In` or `Out` requests with a single backend operation, writes the
+    /// status of each one in memory, and returns their used lengths (as `process_request` does).
+    ///
+    /// The requests are expected to be mergeable, as checked by
+    /// [`Request::is_mergeable_with`](../request/struct.Request.html#method.is_mergeable_with).
+    /// Otherwise, or if the merged execution fails, the requests are processed one by one, such
+    /// that each of them gets its own status.
+    ///
+    /// # Arguments
+    /// * `mem` - A reference to the guest memory.
+    /// * `requests` - The requests to execute, in sector order.
+    pub fn process_merged_requests<M: GuestMemory>(
+        &mut self,
+        mem: &M,
+        requests: &[Request],
+    ) -> result::Result<Vec<u32>, ProcessReqError> {
+        if requests.len() > 1 {
+            if let Some(merged) = Request::merge(requests) {
+                match self.execute(mem, &merged) {
+                    Ok(_) => {
+                        return requests
+                            .iter()
+                            .map(|request| {
+                                let length = match request.request_type() {
+                                    // The total length of the merged request fits in an u32,
+                                    // so this cast is safe.
+                                    RequestType::In => request.total_data_len() as u32,
+                                    _ => 0,
+                                };
+                                self.report_success(request, length);
+                                request.write_status(mem, Status::Ok)?;
+                                self.mark_dirty(request, length);
+                                length.checked_add(1).ok_or(ProcessReqError::Overflow)
+                            })
+                            .collect();
+                    }
+                    Err(e) => warn!(
+                        "failed executing merged block requests, retrying separately: {}",
+                        e
+                    ),
+                }
+            }
+        }
+        requests
+            .iter()
+            .map(|request| self.process_request(mem, request))
+            .collect()
+    }
+
+    /// Completes a request which couldn't be parsed: reports it to the metrics and, when the
+    /// parsing error carries a status address, writes the `VIRTIO_BLK_S_IOERR` status there.
+    /// Returns the used length of the request (i.e. 1 if the status was written, 0 otherwise).
+    ///
+    /// # Arguments
+    /// * `mem` - A reference to the guest memory.
+    /// * `error` - The error returned by [`Request::parse`](../request/struct.Request.html#method.parse).
+    pub fn process_invalid_request<M: GuestMemory>(
+        &mut self,
+        mem: &M,
+        error: &request::Error,
+    ) -> result::Result<u32, ProcessReqError> {
+        self.metrics.invalid_request();
+        match error.status_addr() {
+            Some(status_addr) => {
+                mem.write_obj(u8::from(Status::IoErr), status_addr)?;
+                if let Some(tracker) = self.dirty_tracker.as_ref() {
+                    tracker.mark_dirty(status_addr, 1);
+                }
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+
+    // Reports the successful execution of `request`, which wrote `length` bytes to memory.
+    fn report_success(&self, request: &Request, length: u32) {
+        match request.request_type() {
+            RequestType::In => self.metrics.read_bytes(u64::from(length)),
+            RequestType::Out => self.metrics.write_bytes(request.total_data_len()),
+            RequestType::Flush => self.metrics.flush(),
+            _ => {}
+        }
+    }
+
+    // Reports the first `length` bytes of the data buffers of `request` and its status byte,
+    // which were written to memory, to the dirty tracker.
+    fn mark_dirty(&self, request: &Request, mut length: u32) {
+        let tracker = match self.dirty_tracker.as_ref() {
+            Some(tracker) => tracker,
+            None => return,
+        };
+        for &(data_addr, data_len) in request.data() {
+            if length == 0 {
+                break;
+            }
+            let written = min(data_len, length);
+            tracker.mark_dirty(data_addr, written as usize);
+            length -= written;
+        }
+        tracker.mark_dirty(request.status_addr(), 1);
+    }
+
     fn check_access(&self, mut sectors_count: u64, sector: u64) -> Result<()> {
         sectors_count = sectors_count
             .checked_add(sector)
@@ -272,7 +915,15 @@ impl<B: Backend> StdIoBackend<B> {
     }
 
     fn check_request(&self, request_type: RequestType) -> Result<()> {
-        if self.has_feature(VIRTIO_BLK_F_RO) && request_type != RequestType::In {
+        if self.is_read_only()
+            && matches!(
+                request_type,
+                RequestType::Out
+                    | RequestType::Flush
+                    | RequestType::Discard
+                    | RequestType::WriteZeroes
+            )
+        {
             return Err(Error::ReadOnly);
         }
         match request_type {
@@ -285,6 +936,9 @@ impl<B: Backend> StdIoBackend<B> {
             RequestType::WriteZeroes if !self.has_feature(VIRTIO_BLK_F_WRITE_ZEROES) => {
                 Err(Error::Unsupported(VIRTIO_BLK_T_WRITE_ZEROES))
             }
+            RequestType::GetLifetime if !self.has_feature(VIRTIO_BLK_F_LIFETIME) => {
+                Err(Error::Unsupported(VIRTIO_BLK_T_GET_LIFETIME))
+            }
             _ => Ok(()),
         }
     }
@@ -292,10 +946,28 @@ impl<B: Backend> StdIoBackend<B> {
     /// Executes `request` Request on `B` and `mem` and returns the number of bytes that were
     /// written into the memory buffer during execution (status byte not included).
     ///
+    /// The data length of `In` and `Out` requests is not checked here, as it is already validated
+    /// by [`Request::parse`](../request/struct.Request.html#method.parse).
+    ///
     /// # Arguments
     /// * `mem` - A reference to the guest memory.
     /// * `request` - The request to execute.
     pub fn execute<M: GuestMemory>(&mut self, mem: &M, request: &Request) -> Result<u32> {
+        let request_type = request.request_type();
+        // Validate the request before touching the backend in any way.
+        self.check_request(request_type)?;
+        request
+            .check_capacity(self.num_sectors)
+            .map_err(|_| Error::InvalidAccess)?;
+        let max_segments = match request_type {
+            RequestType::Discard => self.max_discard_seg,
+            RequestType::WriteZeroes => self.max_write_zeroes_seg,
+            _ => u32::MAX,
+        };
+        request
+            .check_segments(max_segments)
+            .map_err(|_| Error::InvalidDataLength)?;
+
         let offset = request
             .sector()
             .checked_shl(u32::from(SECTOR_SHIFT))
@@ -306,48 +978,71 @@ impl<B: Backend> StdIoBackend<B> {
         // This will count the number of bytes written by the device to the memory. It must fit in
         // an u32 for further writing in the used ring.
         let mut bytes_to_mem: u32 = 0;
-        let request_type = request.request_type();
-        self.check_request(request_type)?;
 
         let total_len = request.total_data_len();
 
-        if (request_type == RequestType::In || request_type == RequestType::Out)
-            && (total_len % SECTOR_SIZE != 0)
-        {
-            return Err(Error::InvalidDataLength);
-        }
-
         match request_type {
             RequestType::In => {
-                self.check_access(total_len / SECTOR_SIZE, request.sector())?;
                 // Total data length should fit in an u32 for further writing in the used ring.
                 if total_len > u32::MAX as u64 {
                     return Err(Error::InvalidDataLength);
                 }
-                for (data_addr, data_len) in request.data() {
-                    mem.read_exact_from(*data_addr, &mut self.inner, *data_len as usize)
-                        .map_err(|e| {
-                            if let GuestMemoryError::PartialBuffer {
-                                completed,
-                                expected: _,
-                            } = e
-                            {
-                                // The `as u32` cast is safe, since completed < data_len (which is
-                                // an u32).
-                                bytes_to_mem += completed as u32
-                            }
-                            Error::Read(e, bytes_to_mem)
-                        })?;
-                    // This can not overflow since we checked right before the loop that `total_len`
-                    // fits in an u32.
-                    bytes_to_mem += data_len;
+                // Read directly into the guest buffers when the backend supports it. Buffers
+                // that can't be resolved to host memory are handled (and reported) below.
+                let vectored = self.copy_data(mem, request, offset).or_else(|| {
+                    self.data_slices(mem, request)
+                        .and_then(|bufs| self.inner.read_exact_vectored_at(&bufs, offset))
+                });
+                match vectored {
+                    Some(result) => {
+                        // We don't know how much data made it to memory before the error, so
+                        // we don't report any.
+                        result.map_err(|e| Error::Read(GuestMemoryError::IOError(e), 0))?;
+                        // The cast is safe since we checked that `total_len` fits in an u32.
+                        bytes_to_mem = total_len as u32;
+                    }
+                    None if self.bounce_data(mem, request).transpose()?.is_some() => {
+                        // The cast is safe since we checked that `total_len` fits in an u32.
+                        bytes_to_mem = total_len as u32;
+                    }
+                    None => {
+                        for (data_addr, data_len) in request.data() {
+                            mem.read_exact_from(*data_addr, &mut self.inner, *data_len as usize)
+                                .map_err(|e| {
+                                    if let GuestMemoryError::PartialBuffer {
+                                        completed,
+                                        expected: _,
+                                    } = e
+                                    {
+                                        // The `as u32` cast is safe, since completed < data_len
+                                        // (which is an u32).
+                                        bytes_to_mem += completed as u32
+                                    }
+                                    Error::Read(e, bytes_to_mem)
+                                })?;
+                            // This can not overflow since we checked right before the loop that
+                            // `total_len` fits in an u32.
+                            bytes_to_mem += data_len;
+                        }
+                    }
                 }
             }
             RequestType::Out => {
-                self.check_access(total_len / SECTOR_SIZE, request.sector())?;
-                for (data_addr, data_len) in request.data() {
-                    mem.write_all_to(*data_addr, &mut self.inner, *data_len as usize)
-                        .map_err(Error::Write)?;
+                let vectored = self.copy_data(mem, request, offset).or_else(|| {
+                    self.data_slices(mem, request)
+                        .and_then(|bufs| self.inner.write_all_vectored_at(&bufs, offset))
+                });
+                match vectored {
+                    Some(result) => {
+                        result.map_err(|e| Error::Write(GuestMemoryError::IOError(e)))?
+                    }
+                    None if self.bounce_data(mem, request).transpose()?.is_some() => {}
+                    None => {
+                        for (data_addr, data_len) in request.data() {
+                            mem.write_all_to(*data_addr, &mut self.inner, *data_len as usize)
+                                .map_err(Error::Write)?;
+                        }
+                    }
                 }
             }
             RequestType::Flush => return self.inner.fsync().map(|_| 0).map_err(Error::Flush),
@@ -360,62 +1055,153 @@ impl<B: Backend> StdIoBackend<B> {
                 if total_len != VIRTIO_BLK_ID_BYTES as u64 {
                     return Err(Error::InvalidDataLength);
                 }
-                for (data_addr, data_len) in request.data() {
-                    // The device_id accesses are safe because we checked that the total data length
-                    // is VIRTIO_BLK_ID_BYTES, which is the size of the id as well.
-                    mem.read_exact_from(
-                        *data_addr,
-                        &mut &device_id[bytes_to_mem as usize..(*data_len + bytes_to_mem) as usize],
-                        *data_len as usize,
-                    )
-                    .map_err(|e| {
-                        if let GuestMemoryError::PartialBuffer {
-                            completed,
-                            expected: _,
-                        } = e
-                        {
-                            // The `as u32` cast is safe, since completed < data_len (which is an
-                            // u32).
-                            bytes_to_mem += completed as u32
-                        }
-                        Error::Read(e, bytes_to_mem)
-                    })?;
-                    // This can not overflow since total data length = VIRTIO_BLK_ID_BYTES for sure
-                    // at this point.
-                    bytes_to_mem += data_len;
+                bytes_to_mem = Self::write_data(mem, request, &device_id)?;
+            }
+            RequestType::GetLifetime => {
+                // The values reported by the backend take precedence over the static ones.
+                let lifetime = self
+                    .inner
+                    .lifetime()
+                    .or(self.lifetime)
+                    .ok_or(Error::Unsupported(VIRTIO_BLK_T_GET_LIFETIME))?;
+                if total_len != Lifetime::LEN {
+                    return Err(Error::InvalidDataLength);
                 }
+                bytes_to_mem = Self::write_data(mem, request, lifetime.as_slice())?;
             }
             RequestType::Discard | RequestType::WriteZeroes => {
-                for (data_addr, data_len) in request.data() {
-                    // We support for now only data descriptors with the `len` field = multiple of
-                    // the size of `virtio_blk_discard_write_zeroes` segment. The specification,
-                    // however, requires that only `total_len` be such multiple (a segment can be
-                    // divided between several descriptors). Once we switch to a more general
-                    // approach regarding how we store and parse the device buffers, we'll fix this
-                    // too.
-                    if *data_len as u64 % DiscardWriteZeroes::LEN != 0 {
-                        return Err(Error::InvalidDataLength);
-                    }
-                    let mut available_bytes = *data_len as u64;
-                    let mut crt_addr = *data_addr;
-                    crt_addr
-                        .checked_add(*data_len as u64)
-                        .ok_or(Error::Overflow)?;
-
-                    while available_bytes >= DiscardWriteZeroes::LEN {
-                        let segment = mem.read_obj(crt_addr).map_err(Error::GuestMemory)?;
-                        self.handle_discard_write_zeroes(&segment, request.request_type())?;
-                        // Using `unchecked_add` here, since the overflow is not possible at this
-                        // point (it is checked right before the current loop) and `read_obj` fails
-                        // if the memory access is invalid.
-                        crt_addr = crt_addr.unchecked_add(DiscardWriteZeroes::LEN);
-                        available_bytes -= DiscardWriteZeroes::LEN;
-                    }
+                // The segments were read from memory when the request was parsed.
+                for segment in request.segments() {
+                    self.handle_discard_write_zeroes(segment, request_type)?;
                 }
             }
             RequestType::Unsupported(t) => return Err(Error::Unsupported(t)),
         };
 
+        // In writethrough mode, the data has to reach the disk before the request is completed.
+        if !self.writeback
+            && matches!(
+                request_type,
+                RequestType::Out | RequestType::Discard | RequestType::WriteZeroes
+            )
+        {
+            self.inner.fsync().map_err(Error::Flush)?;
+        }
+
+        Ok(bytes_to_mem)
+    }
+
+    // Copies the data of an `In` or `Out` request between `inner`, starting at `offset`, and the
+    // files backing the guest buffers, if zero copy is enabled. Returns `None` if the data can't
+    // be copied this way, in which case the whole request has to be executed as usual (some of
+    // the buffers might have been copied already, but doing it again is harmless).
+    fn copy_data<M: GuestMemory>(
+        &mut self,
+        mem: &M,
+        request: &Request,
+        mut offset: u64,
+    ) -> Option<io::Result<()>> {
+        if !self.zero_copy {
+            return None;
+        }
+        for (fd, fd_offset, len) in file_ranges(mem, request)? {
+            let result = match request.request_type() {
+                RequestType::In => self.inner.copy_to_fd_at(offset, fd, fd_offset, len),
+                _ => self.inner.copy_from_fd_at(fd, fd_offset, offset, len),
+            };
+            match result {
+                Some(Ok(())) => offset += len as u64,
+                Some(Err(e)) => return Some(Err(e)),
+                None => {
+                    // The backend won't be able to do it for the next requests either.
+                    self.zero_copy = false;
+                    return None;
+                }
+            }
+        }
+        Some(Ok(()))
+    }
+
+    // Resolves the data buffers of `request` to host memory slices for vectored I/O. Returns
+    // `None` if some buffer can't be resolved, or isn't aligned to the alignment of the buffer
+    // pool.
+    fn data_slices<'a, M: GuestMemory>(
+        &self,
+        mem: &'a M,
+        request: &Request,
+    ) -> Option<SegmentBuf<VolatileSlice<'a>>> {
+        let bufs = request.data_slices(mem).ok()?;
+        let alignment = self.buffer_pool.as_ref().map_or(1, BufferPool::alignment);
+        bufs.iter()
+            .all(|buf| (buf.as_ptr() as usize | buf.len()) % alignment == 0)
+            .then_some(bufs)
+    }
+
+    // Transfers the data of an `In` or `Out` request between `inner` (at its current position)
+    // and the guest buffers through a bounce buffer. Returns `None` if there's no bounce buffer
+    // available, or if the data doesn't fit in one.
+    fn bounce_data<M: GuestMemory>(&mut self, mem: &M, request: &Request) -> Option<Result<()>> {
+        let mut buf = self.buffer_pool.as_ref()?.get()?;
+        let total_len = usize::try_from(request.total_data_len()).ok()?;
+        let buf = buf.get_mut(..total_len)?;
+
+        if request.request_type() == RequestType::In {
+            // We don't know how much data made it to memory before an error, so we don't report
+            // any.
+            if let Err(e) = self.inner.read_exact(buf) {
+                return Some(Err(Error::Read(GuestMemoryError::IOError(e), 0)));
+            }
+            let mut data = &buf[..];
+            for &(data_addr, data_len) in request.data() {
+                let (chunk, rest) = data.split_at(data_len as usize);
+                if let Err(e) = mem.write_slice(chunk, data_addr) {
+                    return Some(Err(Error::Read(e, 0)));
+                }
+                data = rest;
+            }
+        } else {
+            let mut data = &mut buf[..];
+            for &(data_addr, data_len) in request.data() {
+                let (chunk, rest) = data.split_at_mut(data_len as usize);
+                if let Err(e) = mem.read_slice(chunk, data_addr) {
+                    return Some(Err(Error::Write(e)));
+                }
+                data = rest;
+            }
+            if let Err(e) = self.inner.write_all(buf) {
+                return Some(Err(Error::Write(GuestMemoryError::IOError(e))));
+            }
+        }
+        Some(Ok(()))
+    }
+
+    // Copies `data` to the data buffers of `request`, whose total length has to be equal to the
+    // length of `data`. Returns the number of bytes written to memory.
+    fn write_data<M: GuestMemory>(mem: &M, request: &Request, data: &[u8]) -> Result<u32> {
+        let mut bytes_to_mem: u32 = 0;
+        for (data_addr, data_len) in request.data() {
+            // The data accesses are safe because the caller checked that the total data length
+            // is equal to the length of `data`.
+            mem.read_exact_from(
+                *data_addr,
+                &mut &data[bytes_to_mem as usize..(*data_len + bytes_to_mem) as usize],
+                *data_len as usize,
+            )
+            .map_err(|e| {
+                if let GuestMemoryError::PartialBuffer {
+                    completed,
+                    expected: _,
+                } = e
+                {
+                    // The `as u32` cast is safe, since completed < data_len (which is an u32).
+                    bytes_to_mem += completed as u32
+                }
+                Error::Read(e, bytes_to_mem)
+            })?;
+            // This can not overflow since the total data length is equal to the (small) length
+            // of `data`.
+            bytes_to_mem += data_len;
+        }
         Ok(bytes_to_mem)
     }
 
@@ -471,16 +1257,40 @@ impl<B: Backend> StdIoBackend<B> {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
+    use std::fs::File;
+    use std::os::unix::fs::FileExt;
+    use std::sync::atomic::Ordering;
+    use std::sync::Mutex;
+
+    use crate::defs::{
+        VIRTIO_BLK_PRE_EOL_INFO_NORMAL, VIRTIO_BLK_PRE_EOL_INFO_URGENT, VIRTIO_BLK_S_IOERR,
+        VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP,
+    };
+    use crate::metrics::tests::TestMetrics;
+    use virtio_device::mock::adjacent_regions;
+    use virtio_queue::mock::{Access, FaultyMemory};
     use vm_memory::guest_memory::Error::{InvalidGuestAddress, PartialBuffer};
-    use vm_memory::{GuestAddress, GuestMemoryMmap};
+    use vm_memory::{FileOffset, GuestAddress, GuestMemoryMmap};
     use vmm_sys_util::tempfile::TempFile;
 
-    impl PartialEq for Error {
-        fn eq(&self, other: &Self) -> bool {
-            use self::Error::*;
+    // Keeps track of all the ranges written to memory.
+    #[derive(Debug, Default)]
+    pub(crate) struct TestTracker {
+        pub ranges: Mutex<Vec<(GuestAddress, usize)>>,
+    }
+
+    impl DirtyTracker for TestTracker {
+        fn mark_dirty(&self, addr: GuestAddress, len: usize) {
+            self.ranges.lock().unwrap().push((addr, len));
+        }
+    }
+
+    impl PartialEq for Error {
+        fn eq(&self, other: &Self) -> bool {
+            use self::Error::*;
             match (self, other) {
                 (DiscardWriteZeroes(ref e), DiscardWriteZeroes(ref other_e)) => {
                     format!("{}", e).eq(&format!("{}", other_e))
@@ -640,28 +1450,6 @@ mod tests {
             Error::InvalidAccess
         );
 
-        // Invalid data length for IN/OUT request.
-        let out_req = Request::new(
-            RequestType::Out,
-            vec![(GuestAddress(0x100), 0x400), (GuestAddress(0x500), 0x100)],
-            2,
-            GuestAddress(0x200),
-        );
-        assert_eq!(
-            req_exec.execute(&mem, &out_req).unwrap_err(),
-            Error::InvalidDataLength
-        );
-        let in_req = Request::new(
-            RequestType::In,
-            vec![(GuestAddress(0x100), 0x201)],
-            2,
-            GuestAddress(0x200),
-        );
-        assert_eq!(
-            req_exec.execute(&mem, &in_req).unwrap_err(),
-            Error::InvalidDataLength
-        );
-
         // It's ok to have partial data lengths that are not multiple of 512 bytes as long as their
         // sum is a multiple.
         let in_req = Request::new(
@@ -807,7 +1595,9 @@ mod tests {
             ],
             2,
             GuestAddress(0x2000),
-        );
+        )
+        .with_segments(&mem)
+        .unwrap();
 
         // 0 bytes should've been written in memory.
         assert_eq!(req_exec.execute(&mem, &wr_zeroes_req).unwrap(), 0x00);
@@ -866,7 +1656,9 @@ mod tests {
             vec![(GuestAddress(0x1000), DiscardWriteZeroes::LEN as u32)],
             7,
             GuestAddress(0x2000),
-        );
+        )
+        .with_segments(&mem)
+        .unwrap();
 
         // 0 bytes should've been written in memory.
         assert_eq!(req_exec.execute(&mem, &discard_req).unwrap(), 0x00);
@@ -894,7 +1686,9 @@ mod tests {
             vec![(GuestAddress(0x1000), DiscardWriteZeroes::LEN as u32)],
             7,
             GuestAddress(0x2000),
-        );
+        )
+        .with_segments(&mem)
+        .unwrap();
 
         req_exec.inner.seek(SeekFrom::Start(0x800)).unwrap();
         let mut v = vec![0x00; 0x200];
@@ -930,46 +1724,26 @@ mod tests {
         );
         req_exec.features = (1 << VIRTIO_BLK_F_DISCARD) | (1 << VIRTIO_BLK_F_WRITE_ZEROES);
 
-        // Test discard request with invalid data length.
-        let discard_req = Request::new(
-            RequestType::Discard,
-            vec![
-                (GuestAddress(0x5000), DiscardWriteZeroes::LEN as u32 / 2),
-                (GuestAddress(0x1000), DiscardWriteZeroes::LEN as u32 / 2),
-            ],
-            7,
-            GuestAddress(0x2000),
-        );
-        assert_eq!(
-            req_exec.execute(&mem, &discard_req).unwrap_err(),
-            Error::InvalidDataLength
-        );
-
+        // Test discard request with too many segments.
+        for &addr in [0x1000, 0x1010].iter() {
+            mem.write_obj::<DiscardWriteZeroes>(discard_req.segments()[0], GuestAddress(addr))
+                .unwrap();
+        }
         let discard_req = Request::new(
             RequestType::Discard,
-            vec![(GuestAddress(0x1000), DiscardWriteZeroes::LEN as u32 - 1)],
+            vec![(GuestAddress(0x1000), 2 * DiscardWriteZeroes::LEN as u32)],
             7,
             GuestAddress(0x2000),
-        );
+        )
+        .with_segments(&mem)
+        .unwrap();
+        req_exec = req_exec.with_max_segments(1, 1);
         assert_eq!(
             req_exec.execute(&mem, &discard_req).unwrap_err(),
             Error::InvalidDataLength
         );
-
-        // Test discard request with an invalid memory access that would cause an overflow.
-        let discard_req = Request::new(
-            RequestType::Discard,
-            vec![(
-                GuestAddress(u64::MAX - DiscardWriteZeroes::LEN),
-                2 * DiscardWriteZeroes::LEN as u32,
-            )],
-            7,
-            GuestAddress(0x2000),
-        );
-        assert_eq!(
-            req_exec.execute(&mem, &discard_req).unwrap_err(),
-            Error::Overflow
-        );
+        req_exec = req_exec.with_max_segments(2, 1);
+        assert_eq!(req_exec.execute(&mem, &discard_req).unwrap(), 0);
 
         // Test discard request with invalid sectors.
         let discard_req = DiscardWriteZeroes {
@@ -985,7 +1759,9 @@ mod tests {
             vec![(GuestAddress(0x1000), DiscardWriteZeroes::LEN as u32)],
             7,
             GuestAddress(0x2000),
-        );
+        )
+        .with_segments(&mem)
+        .unwrap();
         assert_eq!(
             req_exec.execute(&mem, &discard_req).unwrap_err(),
             Error::InvalidAccess
@@ -1005,7 +1781,9 @@ mod tests {
             vec![(GuestAddress(0x1000), DiscardWriteZeroes::LEN as u32)],
             7,
             GuestAddress(0x2000),
-        );
+        )
+        .with_segments(&mem)
+        .unwrap();
         assert_eq!(
             req_exec.execute(&mem, &discard_req).unwrap_err(),
             Error::InvalidFlags
@@ -1025,24 +1803,247 @@ mod tests {
             vec![(GuestAddress(0x1000), DiscardWriteZeroes::LEN as u32)],
             7,
             GuestAddress(0x2000),
-        );
+        )
+        .with_segments(&mem)
+        .unwrap();
         assert_eq!(
             req_exec.execute(&mem, &wr_zeroes_req).unwrap_err(),
             Error::InvalidFlags
         );
+    }
 
-        // Invalid data address.
-        let wr_zeroes_req = Request::new(
-            RequestType::WriteZeroes,
-            vec![(GuestAddress(0x1100_0000), DiscardWriteZeroes::LEN as u32)],
-            7,
+    #[test]
+    fn test_read_only() {
+        const NON_ZERO_VALUE: u8 = 0x55;
+
+        let mut f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+        f.seek(SeekFrom::Start(0x200)).unwrap();
+        f.write_all(&[NON_ZERO_VALUE; 0x200]).unwrap();
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000_0000)]).unwrap();
+        let features = (1 << VIRTIO_BLK_F_DISCARD) | (1 << VIRTIO_BLK_F_WRITE_ZEROES);
+        let mut req_exec = StdIoBackend::new(f, features).unwrap().with_read_only(true);
+        assert!(req_exec.is_read_only());
+        assert_eq!(req_exec.device_features(), 1 << VIRTIO_BLK_F_RO);
+
+        mem.write_slice(&[NON_ZERO_VALUE + 1; 0x200], GuestAddress(0x100))
+            .unwrap();
+        let segment = DiscardWriteZeroes {
+            sector: 1,
+            num_sectors: 1,
+            flags: 0,
+        };
+        mem.write_obj(segment, GuestAddress(0x1000)).unwrap();
+
+        let requests = [
+            Request::new(
+                RequestType::Out,
+                vec![(GuestAddress(0x100), 0x200)],
+                1,
+                GuestAddress(0x2000),
+            ),
+            Request::new(RequestType::Flush, vec![], 0, GuestAddress(0x2000)),
+            Request::new(
+                RequestType::Discard,
+                vec![(GuestAddress(0x1000), DiscardWriteZeroes::LEN as u32)],
+                0,
+                GuestAddress(0x2000),
+            )
+            .with_segments(&mem)
+            .unwrap(),
+            Request::new(
+                RequestType::WriteZeroes,
+                vec![(GuestAddress(0x1000), DiscardWriteZeroes::LEN as u32)],
+                0,
+                GuestAddress(0x2000),
+            )
+            .with_segments(&mem)
+            .unwrap(),
+        ];
+
+        // Move the file cursor somewhere else, so we can check it's not altered below.
+        req_exec.inner.seek(SeekFrom::Start(0x10)).unwrap();
+        for request in requests.iter() {
+            assert_eq!(
+                req_exec.execute(&mem, request).unwrap_err(),
+                Error::ReadOnly
+            );
+            assert_eq!(req_exec.process_request(&mem, request).unwrap(), 1);
+            assert_eq!(
+                mem.read_obj::<u8>(GuestAddress(0x2000)).unwrap(),
+                VIRTIO_BLK_S_IOERR
+            );
+        }
+        assert_eq!(req_exec.inner.stream_position().unwrap(), 0x10);
+
+        // The contents of the file are left untouched.
+        let mut v = vec![0x00; 0x200];
+        req_exec.inner.seek(SeekFrom::Start(0x200)).unwrap();
+        req_exec.inner.read_exact(&mut v).unwrap();
+        assert_eq!(v, vec![NON_ZERO_VALUE; 0x200]);
+
+        // Reads are still allowed.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![(GuestAddress(0x100), 0x200)],
+            1,
             GuestAddress(0x2000),
         );
+        assert_eq!(req_exec.execute(&mem, &in_req).unwrap(), 0x200);
+        let mut v = vec![0x00; 0x200];
+        mem.read_slice(&mut v, GuestAddress(0x100)).unwrap();
+        assert_eq!(v, vec![NON_ZERO_VALUE; 0x200]);
 
+        // The same restrictions apply when the driver acknowledged `VIRTIO_BLK_F_RO`.
+        req_exec = req_exec.with_read_only(false);
+        assert_eq!(req_exec.device_features(), 0);
+        req_exec.features = 1 << VIRTIO_BLK_F_RO;
+        assert!(req_exec.is_read_only());
         assert_eq!(
-            req_exec.execute(&mem, &wr_zeroes_req).unwrap_err(),
-            Error::GuestMemory(InvalidGuestAddress(GuestAddress(0x1100_0000)))
+            req_exec.execute(&mem, &requests[0]).unwrap_err(),
+            Error::ReadOnly
+        );
+
+        req_exec.features = 0;
+        assert!(!req_exec.is_read_only());
+        assert_eq!(req_exec.execute(&mem, &requests[0]).unwrap(), 0);
+    }
+
+    // A backend which keeps track of the number of `fsync` calls.
+    struct SyncCounter {
+        file: File,
+        syncs: u32,
+    }
+
+    impl Read for SyncCounter {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.file.read(buf)
+        }
+    }
+
+    impl Write for SyncCounter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.file.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    impl Seek for SyncCounter {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.file.seek(pos)
+        }
+    }
+
+    impl FileSync for SyncCounter {
+        fn fsync(&mut self) -> io::Result<()> {
+            self.syncs += 1;
+            self.file.fsync()
+        }
+    }
+
+    impl PunchHole for SyncCounter {
+        fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+            self.file.punch_hole(offset, length)
+        }
+    }
+
+    impl WriteZeroesAt for SyncCounter {
+        fn write_zeroes_at(&mut self, offset: u64, length: usize) -> io::Result<usize> {
+            self.file.write_zeroes_at(offset, length)
+        }
+    }
+
+    impl Backend for SyncCounter {}
+
+    #[test]
+    fn test_writeback() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+        let backend = SyncCounter { file: f, syncs: 0 };
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![(GuestAddress(0x100), 0x200)],
+            1,
+            GuestAddress(0x2000),
         );
+        let in_req = Request::new(
+            RequestType::In,
+            vec![(GuestAddress(0x100), 0x200)],
+            1,
+            GuestAddress(0x2000),
+        );
+
+        // Without flush support, the device is in writethrough mode and can't be switched.
+        let mut req_exec = StdIoBackend::new(backend, 1 << VIRTIO_BLK_F_CONFIG_WCE).unwrap();
+        assert!(!req_exec.is_writeback());
+        req_exec.set_writeback(true).unwrap();
+        assert!(!req_exec.is_writeback());
+        req_exec.execute(&mem, &out_req).unwrap();
+        assert_eq!(req_exec.inner.syncs, 1);
+        req_exec.execute(&mem, &in_req).unwrap();
+        assert_eq!(req_exec.inner.syncs, 1);
+
+        // Without `VIRTIO_BLK_F_CONFIG_WCE`, the device stays in writeback mode.
+        let mut req_exec = StdIoBackend::new(req_exec.inner, 1 << VIRTIO_BLK_F_FLUSH).unwrap();
+        assert!(req_exec.is_writeback());
+        req_exec.set_writeback(false).unwrap();
+        assert!(req_exec.is_writeback());
+        req_exec.execute(&mem, &out_req).unwrap();
+        assert_eq!(req_exec.inner.syncs, 1);
+
+        let features = (1 << VIRTIO_BLK_F_FLUSH) | (1 << VIRTIO_BLK_F_CONFIG_WCE);
+        let mut req_exec = StdIoBackend::new(req_exec.inner, features).unwrap();
+        assert!(req_exec.is_writeback());
+        req_exec.execute(&mem, &out_req).unwrap();
+        assert_eq!(req_exec.inner.syncs, 1);
+
+        // Switching to writethrough flushes the cached writes.
+        req_exec.set_writeback(false).unwrap();
+        assert!(!req_exec.is_writeback());
+        assert_eq!(req_exec.inner.syncs, 2);
+        req_exec.set_writeback(false).unwrap();
+        assert_eq!(req_exec.inner.syncs, 2);
+        req_exec.execute(&mem, &out_req).unwrap();
+        assert_eq!(req_exec.inner.syncs, 3);
+
+        // Switching back to writeback doesn't require a flush.
+        req_exec.set_writeback(true).unwrap();
+        assert!(req_exec.is_writeback());
+        req_exec.execute(&mem, &out_req).unwrap();
+        assert_eq!(req_exec.inner.syncs, 3);
+    }
+
+    #[test]
+    fn test_image_id() {
+        let file = TempFile::new().unwrap();
+        let f = file.as_file();
+        let metadata = f.metadata().unwrap();
+        let id = f.image_id().unwrap();
+        assert_eq!(id, image_id(&metadata));
+        // The id is stable, and it's the same for all the handles of the same file.
+        assert_eq!(File::open(file.as_path()).unwrap().image_id().unwrap(), id);
+        assert_ne!(TempFile::new().unwrap().as_file().image_id().unwrap(), id);
+
+        assert_eq!(id, file_id(metadata.dev(), metadata.rdev(), metadata.ino()));
+
+        // The fields have a fixed width, so concatenating them doesn't lead to collisions.
+        assert_eq!(&file_id(1, 0, 12), b"0001000000000000000c");
+        assert_eq!(&file_id(10, 1, 2), b"010a0000000000000002");
+        // The inode number is never truncated.
+        assert_eq!(&file_id(u64::MAX, 0, u64::MAX), b"0000ffffffffffffffff");
+        assert_ne!(file_id(0x801, 0, u64::MAX), file_id(0x801, 0, u64::MAX - 1));
+
+        // The default implementation doesn't provide an id.
+        let backend = SyncCounter {
+            file: TempFile::new().unwrap().into_file(),
+            syncs: 0,
+        };
+        assert!(backend.image_id().is_none());
     }
 
     #[test]
@@ -1123,6 +2124,559 @@ mod tests {
         assert_eq!(buf, dev_id[8..VIRTIO_BLK_ID_BYTES]);
     }
 
+    // A backend which reports the wear of the underlying media.
+    struct WornFile(File);
+
+    impl Read for WornFile {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for WornFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl Seek for WornFile {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    impl FileSync for WornFile {
+        fn fsync(&mut self) -> io::Result<()> {
+            self.0.fsync()
+        }
+    }
+
+    impl PunchHole for WornFile {
+        fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+            self.0.punch_hole(offset, length)
+        }
+    }
+
+    impl WriteZeroesAt for WornFile {
+        fn write_zeroes_at(&mut self, offset: u64, length: usize) -> io::Result<usize> {
+            self.0.write_zeroes_at(offset, length)
+        }
+    }
+
+    impl Backend for WornFile {
+        fn lifetime(&self) -> Option<Lifetime> {
+            Some(Lifetime {
+                pre_eol_info: VIRTIO_BLK_PRE_EOL_INFO_URGENT,
+                device_lifetime_est_typ_a: 0x0a,
+                device_lifetime_est_typ_b: 0x0b,
+            })
+        }
+    }
+
+    #[test]
+    fn test_get_lifetime() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000_0000)]).unwrap();
+        let lifetime = Lifetime {
+            pre_eol_info: VIRTIO_BLK_PRE_EOL_INFO_NORMAL,
+            device_lifetime_est_typ_a: 0x01,
+            device_lifetime_est_typ_b: 0x02,
+        };
+        let get_lifetime_req = Request::new(
+            RequestType::GetLifetime,
+            vec![(GuestAddress(0x100), 2), (GuestAddress(0x200), 4)],
+            0,
+            GuestAddress(0x300),
+        );
+
+        // The feature was not negotiated.
+        let mut req_exec = StdIoBackend::new(f.try_clone().unwrap(), 0)
+            .unwrap()
+            .with_lifetime(lifetime);
+        assert_eq!(
+            req_exec.execute(&mem, &get_lifetime_req).unwrap_err(),
+            Error::Unsupported(VIRTIO_BLK_T_GET_LIFETIME)
+        );
+
+        // Neither the backend nor the executor provide the lifetime information.
+        let mut req_exec =
+            StdIoBackend::new(f.try_clone().unwrap(), 1 << VIRTIO_BLK_F_LIFETIME).unwrap();
+        assert_eq!(
+            req_exec.execute(&mem, &get_lifetime_req).unwrap_err(),
+            Error::Unsupported(VIRTIO_BLK_T_GET_LIFETIME)
+        );
+
+        let mut req_exec = req_exec.with_lifetime(lifetime);
+        let invalid_req = Request::new(
+            RequestType::GetLifetime,
+            vec![(GuestAddress(0x100), 4)],
+            0,
+            GuestAddress(0x300),
+        );
+        assert_eq!(
+            req_exec.execute(&mem, &invalid_req).unwrap_err(),
+            Error::InvalidDataLength
+        );
+
+        assert_eq!(
+            req_exec.execute(&mem, &get_lifetime_req).unwrap(),
+            Lifetime::LEN as u32
+        );
+        assert_eq!(
+            mem.read_obj::<u16>(GuestAddress(0x100)).unwrap(),
+            VIRTIO_BLK_PRE_EOL_INFO_NORMAL
+        );
+        assert_eq!(mem.read_obj::<u16>(GuestAddress(0x200)).unwrap(), 0x01);
+        assert_eq!(mem.read_obj::<u16>(GuestAddress(0x202)).unwrap(), 0x02);
+
+        // The values reported by the backend are preferred.
+        let mut req_exec = StdIoBackend::new(WornFile(f), 1 << VIRTIO_BLK_F_LIFETIME)
+            .unwrap()
+            .with_lifetime(lifetime);
+        assert_eq!(
+            req_exec.execute(&mem, &get_lifetime_req).unwrap(),
+            Lifetime::LEN as u32
+        );
+        assert_eq!(
+            mem.read_obj::<u16>(GuestAddress(0x100)).unwrap(),
+            VIRTIO_BLK_PRE_EOL_INFO_URGENT
+        );
+        assert_eq!(mem.read_obj::<u16>(GuestAddress(0x200)).unwrap(), 0x0a);
+        assert_eq!(mem.read_obj::<u16>(GuestAddress(0x202)).unwrap(), 0x0b);
+    }
+
+    #[test]
+    fn test_metrics() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+
+        let metrics = Arc::new(TestMetrics::default());
+        let mut req_exec = StdIoBackend::new(f, 1 << VIRTIO_BLK_F_FLUSH)
+            .unwrap()
+            .with_metrics(metrics.clone());
+
+        let requests = [
+            Request::new(
+                RequestType::Out,
+                vec![(GuestAddress(0x100), 0x400)],
+                0,
+                GuestAddress(0x2000),
+            ),
+            Request::new(
+                RequestType::In,
+                vec![(GuestAddress(0x100), 0x200), (GuestAddress(0x400), 0x200)],
+                2,
+                GuestAddress(0x2000),
+            ),
+            Request::new(RequestType::Flush, vec![], 0, GuestAddress(0x2000)),
+            // Out of range.
+            Request::new(
+                RequestType::In,
+                vec![(GuestAddress(0x100), 0x200)],
+                8,
+                GuestAddress(0x2000),
+            ),
+            // Not negotiated.
+            Request::new(RequestType::Discard, vec![], 0, GuestAddress(0x2000)),
+        ];
+        for request in requests.iter() {
+            req_exec.process_request(&mem, request).unwrap();
+        }
+
+        assert_eq!(metrics.write_bytes.load(Ordering::Relaxed), 0x400);
+        assert_eq!(metrics.read_bytes.load(Ordering::Relaxed), 0x400);
+        assert_eq!(metrics.flushes.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.execute_fails.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.invalid_requests.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_vectored_io() {
+        const DISK_SIZE: u64 = 0x1000;
+
+        let f = TempFile::new().unwrap().into_file();
+        let pattern = (0..DISK_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        f.write_all_at(&pattern, 0).unwrap();
+        let mem = adjacent_regions(0x1000);
+        let data = vec![(GuestAddress(0xf00), 0x200), (GuestAddress(0x1800), 0x200)];
+
+        // The data is read directly into the guest buffers.
+        let mut req_exec = StdIoBackend::new(f.try_clone().unwrap(), 0).unwrap();
+        let request = Request::new(RequestType::In, data.clone(), 1, GuestAddress(0x1f00));
+        assert_eq!(req_exec.execute(&mem, &request).unwrap(), 0x400);
+        let mut buf = vec![0u8; 0x200];
+        mem.read_slice(&mut buf, GuestAddress(0xf00)).unwrap();
+        assert_eq!(buf, pattern[0x200..0x400]);
+        mem.read_slice(&mut buf, GuestAddress(0x1800)).unwrap();
+        assert_eq!(buf, pattern[0x400..0x600]);
+
+        // And written back at a different offset.
+        let request = Request::new(RequestType::Out, data.clone(), 4, GuestAddress(0x1f00));
+        assert_eq!(req_exec.execute(&mem, &request).unwrap(), 0);
+        let mut disk = vec![0u8; 0x400];
+        f.read_exact_at(&mut disk, 0x800).unwrap();
+        assert_eq!(disk, pattern[0x200..0x600]);
+
+        // Reading past the end of the file fails.
+        let request = Request::new(RequestType::In, data, 7, GuestAddress(0x1f00));
+        req_exec.num_sectors = 16;
+        assert!(matches!(
+            req_exec.execute(&mem, &request).unwrap_err(),
+            Error::Read(GuestMemoryError::IOError(_), 0)
+        ));
+
+        // The transfer is resumed after short reads/writes, including in the middle of a buffer,
+        // and the buffers are split between calls when there are too many of them.
+        let bufs = vec![0u8; 2 * (MAX_IOVECS + 2)];
+        let iovecs = bufs
+            .chunks(2)
+            .map(|b| libc::iovec {
+                iov_base: b.as_ptr() as *mut libc::c_void,
+                iov_len: b.len(),
+            })
+            .collect::<SegmentBuf<_>>();
+        let mut calls = Vec::new();
+        vectored_io_at(
+            iovecs.clone(),
+            0x10,
+            io::ErrorKind::UnexpectedEof,
+            |iovecs, offset| {
+                calls.push((offset, iovecs.len(), iovecs[0].iov_len));
+                let len = iovecs.iter().map(|iov| iov.iov_len).sum::<usize>();
+                min(len, 0x301) as isize
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            calls,
+            vec![(0x10, MAX_IOVECS, 2), (0x311, 642, 1), (0x612, 257, 2)]
+        );
+
+        let err = vectored_io_at(iovecs, 0, io::ErrorKind::WriteZero, |_, offset| {
+            if offset == 0 {
+                1
+            } else {
+                0
+            }
+        })
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+    }
+
+    #[test]
+    fn test_buffer_pool() {
+        const DISK_SIZE: u64 = 0x1000;
+
+        let mut f = TempFile::new().unwrap().into_file();
+        let pattern = (0..DISK_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        f.write_all_at(&pattern, 0).unwrap();
+        let mem = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x1000),
+            (GuestAddress(0x1000), 0x1000),
+        ])
+        .unwrap();
+        let pool = BufferPool::new(1, 0x400, 0x200).unwrap();
+        let mut req_exec = StdIoBackend::new(f.try_clone().unwrap(), 0)
+            .unwrap()
+            .with_buffer_pool(pool.clone());
+
+        // The unaligned buffers are filled through the bounce buffer, which moves the cursor.
+        let data = vec![(GuestAddress(0xf00), 0x200), (GuestAddress(0x1900), 0x200)];
+        let request = Request::new(RequestType::In, data.clone(), 1, GuestAddress(0x1f00));
+        assert_eq!(req_exec.execute(&mem, &request).unwrap(), 0x400);
+        assert_eq!(f.stream_position().unwrap(), 0x600);
+        assert_eq!(pool.available(), 1);
+        let mut buf = vec![0u8; 0x200];
+        mem.read_slice(&mut buf, GuestAddress(0xf00)).unwrap();
+        assert_eq!(buf, pattern[0x200..0x400]);
+        mem.read_slice(&mut buf, GuestAddress(0x1900)).unwrap();
+        assert_eq!(buf, pattern[0x400..0x600]);
+
+        let request = Request::new(RequestType::Out, data, 4, GuestAddress(0x1f00));
+        assert_eq!(req_exec.execute(&mem, &request).unwrap(), 0);
+        assert_eq!(f.stream_position().unwrap(), 0xc00);
+        let mut disk = vec![0u8; 0x400];
+        f.read_exact_at(&mut disk, 0x800).unwrap();
+        assert_eq!(disk, pattern[0x200..0x600]);
+
+        // The aligned buffers are still accessed directly.
+        let data = vec![(GuestAddress(0x1000), 0x200)];
+        let request = Request::new(RequestType::In, data, 0, GuestAddress(0x1f00));
+        assert_eq!(req_exec.execute(&mem, &request).unwrap(), 0x200);
+        assert_eq!(f.stream_position().unwrap(), 0);
+
+        // Without a bounce buffer, the guest buffers are accessed one at a time.
+        let data = vec![(GuestAddress(0x100), 0x600)];
+        let request = Request::new(RequestType::In, data, 0, GuestAddress(0x1f00));
+        assert_eq!(req_exec.execute(&mem, &request).unwrap(), 0x600);
+        let mut buf = vec![0u8; 0x600];
+        mem.read_slice(&mut buf, GuestAddress(0x100)).unwrap();
+        assert_eq!(buf, pattern[..0x600]);
+
+        let data = vec![(GuestAddress(0x100), 0x200)];
+        let request = Request::new(RequestType::In, data, 3, GuestAddress(0x1f00));
+        let _buf = pool.get().unwrap();
+        assert_eq!(req_exec.execute(&mem, &request).unwrap(), 0x200);
+        let mut buf = vec![0u8; 0x200];
+        mem.read_slice(&mut buf, GuestAddress(0x100)).unwrap();
+        assert_eq!(buf, pattern[0x600..0x800]);
+    }
+
+    #[test]
+    fn test_zero_copy() {
+        const DISK_SIZE: u64 = 0x1000;
+
+        let f = TempFile::new().unwrap().into_file();
+        let pattern = (0..DISK_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        f.write_all_at(&pattern, 0).unwrap();
+        // A file backed region, followed by an anonymous one.
+        let mem_file = TempFile::new().unwrap().into_file();
+        mem_file.set_len(0x1000).unwrap();
+        let mem = GuestMemoryMmap::from_ranges_with_files(&[
+            (
+                GuestAddress(0),
+                0x1000,
+                Some(FileOffset::new(mem_file.try_clone().unwrap(), 0)),
+            ),
+            (GuestAddress(0x1000), 0x1000, None),
+        ])
+        .unwrap();
+        let data = vec![(GuestAddress(0x200), 0x200), (GuestAddress(0x600), 0x200)];
+
+        // Only the buffers which are contained in a file backed region are resolved.
+        let request = Request::new(RequestType::In, data.clone(), 1, GuestAddress(0x1f00));
+        let fd = mem
+            .find_region(GuestAddress(0))
+            .and_then(|region| region.file_offset())
+            .unwrap()
+            .file()
+            .as_raw_fd();
+        assert_eq!(
+            file_ranges(&mem, &request).unwrap()[..],
+            [(fd, 0x200, 0x200), (fd, 0x600, 0x200)]
+        );
+        for data in [(GuestAddress(0x1200), 0x200), (GuestAddress(0xf00), 0x200)].iter() {
+            let request = Request::new(RequestType::In, vec![*data], 1, GuestAddress(0x1f00));
+            assert!(file_ranges(&mem, &request).is_none());
+        }
+
+        // The data is copied straight to the file backing the guest buffers.
+        let mut req_exec = StdIoBackend::new(f.try_clone().unwrap(), 0)
+            .unwrap()
+            .with_zero_copy(true);
+        assert_eq!(req_exec.execute(&mem, &request).unwrap(), 0x400);
+        assert!(req_exec.zero_copy);
+        let mut buf = vec![0u8; 0x200];
+        mem_file.read_exact_at(&mut buf, 0x200).unwrap();
+        assert_eq!(buf, pattern[0x200..0x400]);
+        mem.read_slice(&mut buf, GuestAddress(0x600)).unwrap();
+        assert_eq!(buf, pattern[0x400..0x600]);
+
+        // And back to the disk, at a different offset.
+        let request = Request::new(RequestType::Out, data, 4, GuestAddress(0x1f00));
+        assert_eq!(req_exec.execute(&mem, &request).unwrap(), 0);
+        let mut disk = vec![0u8; 0x400];
+        f.read_exact_at(&mut disk, 0x800).unwrap();
+        assert_eq!(disk, pattern[0x200..0x600]);
+
+        // The requests whose buffers are not file backed are executed as usual.
+        let data = vec![(GuestAddress(0x1200), 0x200)];
+        let request = Request::new(RequestType::In, data, 0, GuestAddress(0x1f00));
+        assert_eq!(req_exec.execute(&mem, &request).unwrap(), 0x200);
+        mem.read_slice(&mut buf, GuestAddress(0x1200)).unwrap();
+        assert_eq!(buf, pattern[..0x200]);
+        assert!(req_exec.zero_copy);
+
+        // Copying past the end of the file fails.
+        assert_eq!(
+            copy_file_range_at(f.as_raw_fd(), DISK_SIZE - 0x100, fd, 0, 0x200)
+                .unwrap()
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn test_process_merged_requests() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x2000).unwrap();
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let backend = SyncCounter { file: f, syncs: 0 };
+        // Writethrough mode, so every write is followed by a flush.
+        let mut req_exec = StdIoBackend::new(backend, 0).unwrap();
+
+        let request = |request_type, sector: u64| {
+            Request::new(
+                request_type,
+                vec![(GuestAddress(0x1000 * (sector + 1)), SECTOR_SIZE as u32)],
+                sector,
+                GuestAddress(0x100 + sector),
+            )
+        };
+        let status = |sector: u64| mem.read_obj::<u8>(GuestAddress(0x100 + sector)).unwrap();
+
+        for sector in 0..3 {
+            mem.write_slice(
+                &[sector as u8 + 1; SECTOR_SIZE as usize],
+                GuestAddress(0x1000 * (sector + 1)),
+            )
+            .unwrap();
+        }
+        let writes = (0..3)
+            .map(|sector| request(RequestType::Out, sector))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            req_exec.process_merged_requests(&mem, &writes).unwrap(),
+            vec![1, 1, 1]
+        );
+        // The requests were executed together.
+        assert_eq!(req_exec.inner.syncs, 1);
+        let mut buf = [0u8; 3 * SECTOR_SIZE as usize];
+        req_exec.inner.file.read_exact_at(&mut buf, 0).unwrap();
+        for sector in 0..3 {
+            assert_eq!(status(sector), VIRTIO_BLK_S_OK);
+            let start = sector as usize * SECTOR_SIZE as usize;
+            assert!(buf[start..start + SECTOR_SIZE as usize]
+                .iter()
+                .all(|&b| b == sector as u8 + 1));
+        }
+
+        let reads = [request(RequestType::In, 4), request(RequestType::In, 5)];
+        assert_eq!(
+            req_exec.process_merged_requests(&mem, &reads).unwrap(),
+            vec![0x201, 0x201]
+        );
+        assert_eq!(status(4), VIRTIO_BLK_S_OK);
+        assert_eq!(status(5), VIRTIO_BLK_S_OK);
+
+        // The merged request fails, so each request gets its own status.
+        let reads = [request(RequestType::In, 15), request(RequestType::In, 16)];
+        assert_eq!(
+            req_exec.process_merged_requests(&mem, &reads).unwrap(),
+            vec![0x201, 1]
+        );
+        assert_eq!(status(15), VIRTIO_BLK_S_OK);
+        assert_eq!(status(16), VIRTIO_BLK_S_IOERR);
+
+        // Requests which can't be merged are executed separately.
+        let writes = [request(RequestType::Out, 0), request(RequestType::Out, 2)];
+        assert_eq!(
+            req_exec.process_merged_requests(&mem, &writes).unwrap(),
+            vec![1, 1]
+        );
+        assert_eq!(req_exec.inner.syncs, 3);
+    }
+
+    #[test]
+    fn test_dirty_tracker() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x2000).unwrap();
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let tracker = Arc::new(TestTracker::default());
+        let mut req_exec = StdIoBackend::new(f, 0).unwrap();
+        req_exec.set_dirty_tracker(Some(tracker.clone()));
+
+        // Only the status byte is written for `Out` requests.
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![(GuestAddress(0x1000), 0x200)],
+            0,
+            GuestAddress(0x100),
+        );
+        assert_eq!(req_exec.process_request(&mem, &out_req).unwrap(), 1);
+        assert_eq!(
+            tracker.ranges.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            [(GuestAddress(0x100), 1)]
+        );
+
+        let in_req = Request::new(
+            RequestType::In,
+            vec![(GuestAddress(0x1000), 0x400), (GuestAddress(0x3000), 0x200)],
+            0,
+            GuestAddress(0x101),
+        );
+        assert_eq!(req_exec.process_request(&mem, &in_req).unwrap(), 0x601);
+        assert_eq!(
+            tracker.ranges.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            [
+                (GuestAddress(0x1000), 0x400),
+                (GuestAddress(0x3000), 0x200),
+                (GuestAddress(0x101), 1)
+            ]
+        );
+
+        // Only the part of the data which was read before the error is tracked.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![
+                (GuestAddress(0x1000), 0x200),
+                (GuestAddress(0xf_ff00), 0x200),
+            ],
+            0,
+            GuestAddress(0x102),
+        );
+        assert_eq!(req_exec.process_request(&mem, &in_req).unwrap(), 0x301);
+        assert_eq!(
+            tracker.ranges.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            [
+                (GuestAddress(0x1000), 0x200),
+                (GuestAddress(0xf_ff00), 0x100),
+                (GuestAddress(0x102), 1)
+            ]
+        );
+
+        let reads = [
+            Request::new(
+                RequestType::In,
+                vec![(GuestAddress(0x1000), 0x200)],
+                0,
+                GuestAddress(0x103),
+            ),
+            Request::new(
+                RequestType::In,
+                vec![(GuestAddress(0x2000), 0x200)],
+                1,
+                GuestAddress(0x104),
+            ),
+        ];
+        assert_eq!(
+            req_exec.process_merged_requests(&mem, &reads).unwrap(),
+            vec![0x201, 0x201]
+        );
+        assert_eq!(
+            tracker.ranges.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            [
+                (GuestAddress(0x1000), 0x200),
+                (GuestAddress(0x103), 1),
+                (GuestAddress(0x2000), 0x200),
+                (GuestAddress(0x104), 1)
+            ]
+        );
+
+        // The status of a request with invalid data is tracked, while nothing is written for
+        // other parsing errors.
+        let invalid = request::Error::InvalidDataLength(GuestAddress(0x105));
+        assert_eq!(req_exec.process_invalid_request(&mem, &invalid).unwrap(), 1);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x105)).unwrap(),
+            VIRTIO_BLK_S_IOERR
+        );
+        let invalid = request::Error::DescriptorChainTooShort;
+        assert_eq!(req_exec.process_invalid_request(&mem, &invalid).unwrap(), 0);
+        assert_eq!(
+            tracker.ranges.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            [(GuestAddress(0x105), 1)]
+        );
+    }
+
     #[test]
     fn test_process_request() {
         let f = TempFile::new().unwrap().into_file();
@@ -1189,13 +2743,12 @@ mod tests {
             .unwrap();
         let discard_req = Request::new(
             RequestType::Discard,
-            vec![(
-                GuestAddress(0x1000),
-                mem::size_of::<DiscardWriteZeroes>() as u32,
-            )],
+            vec![(GuestAddress(0x1000), DiscardWriteZeroes::LEN as u32)],
             7,
             GuestAddress(0x2000),
-        );
+        )
+        .with_segments(&mem)
+        .unwrap();
         assert_eq!(req_exec.process_request(&mem, &discard_req).unwrap(), 1);
         assert_eq!(
             mem.read_obj::<u8>(GuestAddress(0x2000)).unwrap(),
@@ -1253,4 +2806,54 @@ mod tests {
             VIRTIO_BLK_S_IOERR
         );
     }
+
+    #[test]
+    fn test_memory_faults() {
+        let f = TempFile::new().unwrap().into_file();
+        f.write_all_at(&[0xaa; 0x400], 0).unwrap();
+        let mem = FaultyMemory::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let data = vec![(GuestAddress(0x1000), 0x200), (GuestAddress(0x2000), 0x200)];
+        let mut req_exec = StdIoBackend::new(f, 0).unwrap();
+
+        // The data can't be written to the second buffer; only the first one is reported.
+        mem.fail_range(GuestAddress(0x2000), 0x200, Access::Write);
+        let in_req = Request::new(RequestType::In, data.clone(), 0, GuestAddress(0x3000));
+        assert!(matches!(
+            req_exec.execute(&mem, &in_req).unwrap_err(),
+            Error::Read(GuestMemoryError::IOError(_), 0x200)
+        ));
+        assert_eq!(req_exec.process_request(&mem, &in_req).unwrap(), 0x201);
+        assert_eq!(
+            mem.memory().read_obj::<u8>(GuestAddress(0x3000)).unwrap(),
+            VIRTIO_BLK_S_IOERR
+        );
+        let mut buf = [0u8; 0x200];
+        mem.memory()
+            .read_slice(&mut buf, GuestAddress(0x1000))
+            .unwrap();
+        assert_eq!(buf, [0xaa; 0x200]);
+        mem.clear();
+
+        // The data can't be read from the guest buffers.
+        mem.fail_range(GuestAddress(0x1000), 1, Access::Read);
+        let out_req = Request::new(RequestType::Out, data, 0, GuestAddress(0x3000));
+        assert!(matches!(
+            req_exec.execute(&mem, &out_req).unwrap_err(),
+            Error::Write(GuestMemoryError::IOError(_))
+        ));
+        mem.clear();
+
+        // The status can't be written.
+        mem.fail_range(GuestAddress(0x3000), 1, Access::Write);
+        assert!(matches!(
+            req_exec.process_request(&mem, &out_req).unwrap_err(),
+            ProcessReqError::GuestMemory(GuestMemoryError::IOError(_))
+        ));
+        mem.clear();
+        assert_eq!(req_exec.process_request(&mem, &out_req).unwrap(), 1);
+        assert_eq!(
+            mem.memory().read_obj::<u8>(GuestAddress(0x3000)).unwrap(),
+            VIRTIO_BLK_S_OK
+        );
+    }
 }
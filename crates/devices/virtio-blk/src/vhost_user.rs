@@ -0,0 +1,979 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A virtio block device frontend for external vhost-user-blk backends.
+//!
+//! This module provides the following abstractions:
+//!
+//! - [`VhostUserBlock`](struct.VhostUserBlock.html) which implements the `VirtioDevice` and
+//!   `VirtioMmioDevice` interfaces, but doesn't process any requests by itself. The request
+//!   queues and the configuration space are forwarded over a Unix socket to a vhost-user-blk
+//!   backend (such as SPDK or qemu-storage-daemon), which accesses the guest memory directly.
+//! - [`VhostUserBlockBuilder`](struct.VhostUserBlockBuilder.html) which negotiates the
+//!   vhost-user protocol features with the backend and creates a `VhostUserBlock` device.
+//!
+//! The configuration space is fetched from the backend when the device is created and on reset,
+//! and driver writes are forwarded to the backend, so the backend has to support the
+//! `VHOST_USER_PROTOCOL_F_CONFIG` protocol feature. When the device is activated, the guest
+//! memory regions and the queue configuration are sent to the backend. The backend has to map
+//! the guest memory, so all the regions must be backed by files (i.e. created with
+//! `GuestMemoryMmap::from_ranges_with_files`).
+//!
+//! The backend is notified through one kick `EventFd` for each queue. The VMM is expected to
+//! register them as ioeventfds (see
+//! [`VhostUserBlock::kick_eventfd`](struct.VhostUserBlock.html#method.kick_eventfd)), or to
+//! rely on the `VirtioMmioDevice::queue_notify` implementation, which triggers them as well.
+//! The backend signals used buffers through one call `EventFd` for each queue, and the VMM
+//! has to call
+//! [`VhostUserBlock::process_call_event`](struct.VhostUserBlock.html#method.process_call_event)
+//! when one of them becomes readable, so the interrupt status is updated before the driver is
+//! notified.
+//!
+//! When the backend is restarted (i.e. after a crash), the VMM can
+//! [`reconnect`](struct.VhostUserBlock.html#method.reconnect) the device to the new backend,
+//! which resumes processing the queues without any involvement from the driver. The requests
+//! which were in flight are submitted again, using the in-flight descriptors area when the
+//! backend supports `VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD`.
+
+use std::borrow::{Borrow, BorrowMut};
+use std::cmp;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::result;
+use std::sync::atomic::AtomicU8;
+use std::sync::Arc;
+
+use log::error;
+use vm_memory::GuestAddressSpace;
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+use virtio_device::vhost_user::{self, Inflight, VhostUserFrontend};
+use virtio_device::{
+    EventsContext, SignalConfigChange, SignalUsedQueue, VirtioConfig, VirtioDevice,
+    VirtioDeviceEvents, VirtioMmioDevice,
+};
+use virtio_queue::Queue;
+
+use crate::config::ConfigSpace;
+use crate::defs::{DEFAULT_QUEUE_SIZE, VIRTIO_ID_BLOCK};
+
+pub use virtio_device::vhost_user::{
+    VHOST_USER_F_PROTOCOL_FEATURES, VHOST_USER_PROTOCOL_F_CONFIG,
+    VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD, VHOST_USER_PROTOCOL_F_MQ,
+    VHOST_USER_PROTOCOL_F_REPLY_ACK,
+};
+
+/// vhost-user block device errors.
+#[derive(Debug)]
+pub enum Error {
+    /// The device was already activated.
+    AlreadyActivated,
+    /// Failed to create or use an `EventFd`.
+    EventFd(io::Error),
+    /// The queues are not configured properly.
+    InvalidQueues,
+    /// The queue index is not valid.
+    InvalidQueueIndex(u16),
+    /// Failed to communicate with the backend.
+    VhostUser(vhost_user::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            AlreadyActivated => write!(f, "the device is already activated"),
+            EventFd(ref err) => write!(f, "eventfd error: {}", err),
+            InvalidQueues => write!(f, "invalid queue configuration"),
+            InvalidQueueIndex(index) => write!(f, "invalid queue {}", index),
+            VhostUser(ref err) => write!(f, "vhost-user error: {}", err),
+        }
+    }
+}
+
+impl From<vhost_user::Error> for Error {
+    fn from(e: vhost_user::Error) -> Self {
+        Error::VhostUser(e)
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Configures and builds a `VhostUserBlock` device.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # use std::os::unix::net::UnixStream;
+/// # use std::sync::Arc;
+/// # use virtio_blk::vhost_user::VhostUserBlockBuilder;
+/// # use vm_memory::{FileOffset, GuestAddress, GuestMemoryMmap};
+/// # use vmm_sys_util::eventfd::EventFd;
+/// # use vmm_sys_util::tempfile::TempFile;
+/// // The guest memory has to be shared with the backend.
+/// let file = TempFile::new().unwrap().into_file();
+/// file.set_len(0x10_0000).unwrap();
+/// let mem = Arc::new(
+///     GuestMemoryMmap::from_ranges_with_files(&[(
+///         GuestAddress(0),
+///         0x10_0000,
+///         Some(FileOffset::new(file, 0)),
+///     )])
+///     .unwrap(),
+/// );
+///
+/// let stream = UnixStream::connect("/tmp/vhost-user-blk.sock").unwrap();
+/// let block = VhostUserBlockBuilder::new(mem, stream, EventFd::new(0).unwrap())
+///     .with_num_queues(2)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug)]
+pub struct VhostUserBlockBuilder<M: GuestAddressSpace, S: SignalUsedQueue> {
+    mem: M,
+    stream: UnixStream,
+    driver_notify: S,
+    num_queues: u16,
+    queue_size: u16,
+}
+
+impl<M, S> VhostUserBlockBuilder<M, S>
+where
+    M: GuestAddressSpace + Clone,
+    S: SignalUsedQueue,
+{
+    /// Creates a new `VhostUserBlockBuilder` for a device with a single request queue.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory, whose regions have to be backed by files.
+    /// * `stream` - The socket connected to the vhost-user backend.
+    /// * `driver_notify` - The object used for notifying the driver about used buffers.
+    pub fn new(mem: M, stream: UnixStream, driver_notify: S) -> Self {
+        VhostUserBlockBuilder {
+            mem,
+            stream,
+            driver_notify,
+            num_queues: 1,
+            queue_size: DEFAULT_QUEUE_SIZE,
+        }
+    }
+
+    /// Sets the number of request queues.
+    ///
+    /// # Arguments
+    /// * `num_queues` - The number of request queues.
+    pub fn with_num_queues(mut self, num_queues: u16) -> Self {
+        self.num_queues = num_queues;
+        self
+    }
+
+    /// Sets the maximum size of the request queues.
+    ///
+    /// # Arguments
+    /// * `queue_size` - The maximum queue size.
+    pub fn with_queue_size(mut self, queue_size: u16) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Negotiates the protocol features with the backend, fetches the configuration space and
+    /// builds the `VhostUserBlock` device.
+    pub fn build(self) -> Result<VhostUserBlock<M, S>> {
+        let mut frontend = VhostUserFrontend::new(self.stream)?;
+        frontend.require_protocol_feature(VHOST_USER_PROTOCOL_F_CONFIG)?;
+        let num_queues = self.num_queues.max(1);
+        frontend.check_queue_num(num_queues)?;
+
+        let config_space = frontend.get_config(ConfigSpace::LEN)?;
+        let (mem, queue_size) = (self.mem, self.queue_size);
+        let queues = (0..num_queues)
+            .map(|_| Queue::new(mem.clone(), queue_size))
+            .collect();
+        let new_eventfds = || {
+            (0..num_queues)
+                .map(|_| EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd))
+                .collect::<Result<Vec<_>>>()
+        };
+
+        Ok(VhostUserBlock {
+            cfg: VirtioConfig::new(frontend.features(), queues, config_space),
+            mem,
+            frontend,
+            kick_evts: new_eventfds()?,
+            call_evts: new_eventfds()?,
+            inflight: None,
+            driver_notify: self.driver_notify,
+        })
+    }
+}
+
+/// A virtio block device whose requests are processed by a vhost-user backend.
+#[derive(Debug)]
+pub struct VhostUserBlock<M: GuestAddressSpace, S: SignalUsedQueue> {
+    cfg: VirtioConfig<M>,
+    mem: M,
+    frontend: VhostUserFrontend,
+    // Used by the driver (or the VMM) to notify the backend about available buffers.
+    kick_evts: Vec<EventFd>,
+    // Used by the backend to notify the VMM about used buffers.
+    call_evts: Vec<EventFd>,
+    // The area where the backend records the in-flight requests, which is passed to the
+    // backend again when reconnecting.
+    inflight: Option<(Inflight, File)>,
+    driver_notify: S,
+}
+
+impl<M, S> VhostUserBlock<M, S>
+where
+    M: GuestAddressSpace,
+    S: SignalUsedQueue,
+{
+    /// Returns whether the device is activated.
+    pub fn is_activated(&self) -> bool {
+        self.cfg.device_activated
+    }
+
+    /// Returns the `EventFd` which notifies the backend about the buffers made available in the
+    /// queue with the specified index. It can be registered as an ioeventfd for the queue.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the request queue.
+    pub fn kick_eventfd(&self, index: u16) -> Option<&EventFd> {
+        self.kick_evts.get(usize::from(index))
+    }
+
+    /// Returns the `EventFd` which the backend uses for signaling used buffers in the queue with
+    /// the specified index.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the request queue.
+    pub fn call_eventfd(&self, index: u16) -> Option<&EventFd> {
+        self.call_evts.get(usize::from(index))
+    }
+
+    /// Updates the interrupt status and notifies the driver after the backend signaled used
+    /// buffers. This has to be called when the call `EventFd` of the queue becomes readable.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the request queue.
+    pub fn process_call_event(&self, index: u16) -> Result<()> {
+        let call_evt = self
+            .call_eventfd(index)
+            .ok_or(Error::InvalidQueueIndex(index))?;
+        match call_evt.read() {
+            Ok(_) => {}
+            // Spurious wakeup, the event was already consumed.
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(Error::EventFd(e)),
+        }
+        self.cfg.signal_used_queue();
+        self.driver_notify.signal_used_queue(index);
+        Ok(())
+    }
+
+    /// Returns the file descriptor of the socket connected to the backend, which can be
+    /// monitored for hangups (see
+    /// [`is_backend_disconnected`](struct.VhostUserBlock.html#method.is_backend_disconnected)).
+    pub fn backend_fd(&self) -> RawFd {
+        self.frontend.as_raw_fd()
+    }
+
+    /// Returns whether the backend closed the connection (i.e. because it crashed or it was
+    /// restarted).
+    pub fn is_backend_disconnected(&self) -> bool {
+        self.frontend.is_disconnected()
+    }
+
+    /// Connects the device to a new backend, after the previous one was restarted. The new
+    /// backend has to support the features negotiated with the previous one. The cache mode
+    /// selected by the driver is restored, and the queues are resumed if the device is
+    /// activated, so the guest doesn't notice the restart.
+    ///
+    /// # Arguments
+    /// * `stream` - The socket connected to the new backend.
+    pub fn reconnect(&mut self, stream: UnixStream) -> Result<()> {
+        self.frontend.reconnect(stream)?;
+        let writeback = ConfigSpace::WRITEBACK_OFFSET;
+        self.frontend
+            .set_config(writeback, &self.cfg.config_space[writeback..=writeback])?;
+        if !self.cfg.device_activated {
+            return Ok(());
+        }
+
+        if let Some((inflight, file)) = self.inflight.as_ref() {
+            self.frontend.set_inflight_fd(inflight, file)?;
+        }
+        let mem = self.mem.memory();
+        self.frontend.resume(
+            &*mem,
+            self.cfg.driver_features,
+            &self.cfg.queues,
+            &self.kick_evts,
+            &self.call_evts,
+        )?;
+        Ok(())
+    }
+
+    // Sends the guest memory and queue configuration to the backend, and starts the queues.
+    fn setup_backend(&mut self) -> Result<()> {
+        if self.frontend.protocol_features() & (1 << VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD) != 0 {
+            let queue_size = self.cfg.queues.iter().map(Queue::actual_size).max();
+            self.inflight = Some(
+                self.frontend
+                    .get_inflight_fd(self.num_queues(), queue_size.unwrap_or(0))?,
+            );
+        }
+        let mem = self.mem.memory();
+        self.frontend.start(
+            &*mem,
+            self.cfg.driver_features,
+            &self.cfg.queues,
+            &self.kick_evts,
+            &self.call_evts,
+        )?;
+        Ok(())
+    }
+
+    // Stops the queues of the backend.
+    fn stop_backend(&mut self) -> Result<()> {
+        self.inflight = None;
+        // The number of queues always fits in an `u16`.
+        self.frontend.stop(self.cfg.queues.len() as u16)?;
+        Ok(())
+    }
+}
+
+impl<M, S> VhostUserBlock<M, S>
+where
+    M: GuestAddressSpace,
+    S: SignalUsedQueue + SignalConfigChange,
+{
+    /// Fetches the configuration space from the backend again, i.e. after the backend image
+    /// was resized. If it changed, the configuration generation is updated, and a configuration
+    /// change interrupt is raised when the device is activated.
+    pub fn refresh_config(&mut self) -> Result<()> {
+        let config_space = self.frontend.get_config(ConfigSpace::LEN)?;
+        if config_space == self.cfg.config_space {
+            return Ok(());
+        }
+        self.cfg.config_space = config_space;
+        self.cfg.config_generation = self.cfg.config_generation.wrapping_add(1);
+
+        if self.cfg.device_activated {
+            self.cfg.signal_config_change();
+            self.driver_notify.signal_config_change();
+        }
+        Ok(())
+    }
+}
+
+impl<M, S> Borrow<VirtioConfig<M>> for VhostUserBlock<M, S>
+where
+    M: GuestAddressSpace,
+    S: SignalUsedQueue,
+{
+    fn borrow(&self) -> &VirtioConfig<M> {
+        &self.cfg
+    }
+}
+
+impl<M, S> BorrowMut<VirtioConfig<M>> for VhostUserBlock<M, S>
+where
+    M: GuestAddressSpace,
+    S: SignalUsedQueue,
+{
+    fn borrow_mut(&mut self) -> &mut VirtioConfig<M> {
+        &mut self.cfg
+    }
+}
+
+// `VirtioDevice` is implemented directly (rather than through `VirtioDeviceActions`), since the
+// configuration space writes have to be forwarded to the backend.
+impl<M, S> VirtioDevice<M> for VhostUserBlock<M, S>
+where
+    M: GuestAddressSpace,
+    S: SignalUsedQueue,
+{
+    type E = Error;
+
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn num_queues(&self) -> u16 {
+        // The number of queues always fits in an `u16`.
+        self.cfg.queues.len() as u16
+    }
+
+    fn queue(&self, index: u16) -> Option<&Queue<M>> {
+        self.cfg.queues.get(usize::from(index))
+    }
+
+    fn queue_mut(&mut self, index: u16) -> Option<&mut Queue<M>> {
+        self.cfg.queues.get_mut(usize::from(index))
+    }
+
+    fn device_features(&self) -> u64 {
+        self.cfg.device_features
+    }
+
+    fn driver_features(&self) -> u64 {
+        self.cfg.driver_features
+    }
+
+    fn set_driver_features(&mut self, page: u32, value: u32) {
+        let features = self.cfg.driver_features;
+        let v = u64::from(value);
+        self.cfg.driver_features = match page {
+            0 => ((features >> 32) << 32) + v,
+            1 => ((features << 32) >> 32) + (v << 32),
+            // Accessing an unknown page has no effect.
+            _ => features,
+        }
+    }
+
+    fn device_status(&self) -> u8 {
+        self.cfg.device_status
+    }
+
+    fn set_device_status(&mut self, status: u8) {
+        self.cfg.device_status = status;
+    }
+
+    fn activate(&mut self) -> Result<()> {
+        if self.cfg.device_activated {
+            return Err(Error::AlreadyActivated);
+        }
+        if !self.cfg.queues_valid() {
+            return Err(Error::InvalidQueues);
+        }
+
+        self.setup_backend()?;
+        self.cfg.device_activated = true;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        // The device is reset even if the backend fails, and the error is reported afterwards.
+        let stopped = if self.cfg.device_activated {
+            self.stop_backend()
+        } else {
+            Ok(())
+        };
+
+        let cfg = &mut self.cfg;
+        cfg.driver_features = 0;
+        cfg.device_features_select = 0;
+        cfg.driver_features_select = 0;
+        cfg.device_status = 0;
+        cfg.queue_select = 0;
+        cfg.queues.iter_mut().for_each(Queue::reset);
+        cfg.device_activated = false;
+        cfg.reset_interrupt_status();
+
+        stopped?;
+        // The driver might have changed the configuration space (i.e. the cache mode), so the
+        // backend is the source of truth here.
+        self.cfg.config_space = self.frontend.get_config(ConfigSpace::LEN)?;
+        Ok(())
+    }
+
+    fn interrupt_status(&self) -> &Arc<AtomicU8> {
+        &self.cfg.interrupt_status
+    }
+
+    fn config_generation(&self) -> u8 {
+        self.cfg.config_generation
+    }
+
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        let config_space = &self.cfg.config_space;
+        if offset >= config_space.len() {
+            error!("Failed to read from config space");
+            return;
+        }
+        let end = cmp::min(offset.saturating_add(data.len()), config_space.len());
+        data[..end - offset].copy_from_slice(&config_space[offset..end]);
+    }
+
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        let config_space = &mut self.cfg.config_space;
+        if offset >= config_space.len() {
+            error!("Failed to write to config space");
+            return;
+        }
+        let end = cmp::min(offset.saturating_add(data.len()), config_space.len());
+        config_space[offset..end].copy_from_slice(&data[..end - offset]);
+
+        if let Err(e) = self
+            .frontend
+            .set_config(offset, &self.cfg.config_space[offset..end])
+        {
+            error!("failed to write the backend config space: {}", e);
+        }
+    }
+}
+
+impl<M, S> VirtioDeviceEvents for VhostUserBlock<M, S>
+where
+    M: GuestAddressSpace,
+    S: SignalUsedQueue,
+{
+    fn reattach<C: EventsContext>(&self, events_ctx: &mut C) -> result::Result<(), C::E> {
+        // The call `EventFd`s are read by the VMM (see `process_call_event`), which then
+        // notifies the driver.
+        for (index, kick_evt) in self.kick_evts.iter().enumerate() {
+            // The number of queues always fits in an `u16`.
+            events_ctx.register_ioeventfd(index as u16, kick_evt)?;
+        }
+        match self.driver_notify.irqfd() {
+            Some(irqfd) => events_ctx.register_irqfd(irqfd),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<M, S> VirtioMmioDevice<M> for VhostUserBlock<M, S>
+where
+    M: GuestAddressSpace + 'static,
+    S: SignalUsedQueue,
+{
+    fn queue_notify(&mut self, val: u32) {
+        // Queue indices always fit in an `u16`.
+        match self.kick_eventfd(val as u16) {
+            Some(kick_evt) => {
+                if let Err(e) = kick_evt.write(1) {
+                    error!("failed to kick queue {}: {}", val, e);
+                }
+            }
+            None => error!("invalid queue {}", val),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::mem::size_of;
+    use std::net::Shutdown;
+    use std::sync::atomic::Ordering;
+    use std::sync::Mutex;
+    use std::thread::{self, JoinHandle};
+
+    use vm_memory::{ByteValued, FileOffset, GuestAddress, GuestMemory, GuestMemoryMmap};
+    use vmm_sys_util::sock_ctrl_msg::ScmSocket;
+    use vmm_sys_util::tempfile::TempFile;
+
+    use virtio_device::mock::{activate, recv_backend_message, BackendMessage};
+    use virtio_device::vhost_user::{
+        ConfigHeader, Header, VringState, SUPPORTED_PROTOCOL_FEATURES, VHOST_USER_GET_CONFIG,
+        VHOST_USER_GET_FEATURES, VHOST_USER_GET_INFLIGHT_FD, VHOST_USER_GET_PROTOCOL_FEATURES,
+        VHOST_USER_GET_QUEUE_NUM, VHOST_USER_GET_VRING_BASE, VHOST_USER_NEED_REPLY,
+        VHOST_USER_REPLY, VHOST_USER_SET_CONFIG, VHOST_USER_SET_FEATURES,
+        VHOST_USER_SET_INFLIGHT_FD, VHOST_USER_SET_MEM_TABLE, VHOST_USER_SET_OWNER,
+        VHOST_USER_SET_PROTOCOL_FEATURES, VHOST_USER_SET_VRING_ADDR, VHOST_USER_SET_VRING_BASE,
+        VHOST_USER_SET_VRING_CALL, VHOST_USER_SET_VRING_KICK, VHOST_USER_SET_VRING_NUM,
+        VHOST_USER_VERSION,
+    };
+    use virtio_device::{VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING};
+    use virtio_queue::mock::VirtQueue;
+
+    use crate::config::ConfigBuilder;
+
+    type Mem = Arc<GuestMemoryMmap>;
+
+    const BACKEND_FEATURES: u64 = (1 << 32) | (1 << VHOST_USER_F_PROTOCOL_FEATURES) | (1 << 9);
+
+    fn send_reply(stream: &mut UnixStream, request: u32, payload: &[u8]) {
+        let header = Header {
+            request,
+            flags: VHOST_USER_VERSION | VHOST_USER_REPLY,
+            size: payload.len() as u32,
+        };
+        stream.write_all(header.as_slice()).unwrap();
+        stream.write_all(payload).unwrap();
+    }
+
+    // Replies with the in-flight descriptors area, which is backed by a temporary file.
+    fn send_inflight_reply(stream: &mut UnixStream, message: &BackendMessage) {
+        let mut inflight = Inflight::default();
+        inflight.as_mut_slice().copy_from_slice(&message.payload);
+        inflight.mmap_size = 0x1000;
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(inflight.mmap_size).unwrap();
+
+        let header = Header {
+            request: message.header.request,
+            flags: VHOST_USER_VERSION | VHOST_USER_REPLY,
+            size: size_of::<Inflight>() as u32,
+        };
+        let mut reply = header.as_slice().to_vec();
+        reply.extend_from_slice(inflight.as_slice());
+        let sent = stream
+            .send_with_fds(&[&reply[..]], &[file.as_raw_fd()])
+            .unwrap();
+        stream.write_all(&reply[sent..]).unwrap();
+    }
+
+    // Spawns a fake vhost-user-blk backend, which answers the frontend requests and records
+    // all of them.
+    fn spawn_backend(
+        mut stream: UnixStream,
+        protocol_features: u64,
+        config: Arc<Mutex<Vec<u8>>>,
+    ) -> JoinHandle<Vec<BackendMessage>> {
+        thread::spawn(move || {
+            let mut messages = Vec::new();
+            while let Some(message) = recv_backend_message(&mut stream) {
+                let request = message.header.request;
+                match request {
+                    VHOST_USER_GET_FEATURES => {
+                        send_reply(&mut stream, request, BACKEND_FEATURES.as_slice())
+                    }
+                    VHOST_USER_GET_PROTOCOL_FEATURES => {
+                        send_reply(&mut stream, request, protocol_features.as_slice())
+                    }
+                    VHOST_USER_GET_QUEUE_NUM => send_reply(&mut stream, request, 2u64.as_slice()),
+                    VHOST_USER_GET_CONFIG => {
+                        let mut reply = message.payload[..size_of::<ConfigHeader>()].to_vec();
+                        reply.extend_from_slice(&config.lock().unwrap());
+                        send_reply(&mut stream, request, &reply);
+                    }
+                    VHOST_USER_SET_CONFIG => {
+                        let offset = message.obj::<u32>(0) as usize;
+                        let data = &message.payload[size_of::<ConfigHeader>()..];
+                        config.lock().unwrap()[offset..offset + data.len()].copy_from_slice(data);
+                    }
+                    VHOST_USER_GET_VRING_BASE => {
+                        let state = VringState {
+                            index: message.obj::<u32>(0),
+                            num: 3,
+                        };
+                        send_reply(&mut stream, request, state.as_slice());
+                    }
+                    VHOST_USER_GET_INFLIGHT_FD => send_inflight_reply(&mut stream, &message),
+                    _ => {}
+                }
+                if message.header.flags & VHOST_USER_NEED_REPLY != 0 {
+                    send_reply(&mut stream, request, 0u64.as_slice());
+                }
+                messages.push(message);
+            }
+            messages
+        })
+    }
+
+    fn config_space(capacity: u64) -> Vec<u8> {
+        ConfigBuilder::new(capacity)
+            .with_writeback(true)
+            .build()
+            .unwrap()
+            .into()
+    }
+
+    fn shared_mem() -> Mem {
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x10_0000).unwrap();
+        Arc::new(
+            GuestMemoryMmap::from_ranges_with_files(&[(
+                GuestAddress(0),
+                0x10_0000,
+                Some(FileOffset::new(file, 0)),
+            )])
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_build() {
+        let mem = shared_mem();
+
+        // The backend has to support the configuration space accesses.
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let config = Arc::new(Mutex::new(config_space(0x800)));
+        let handle = spawn_backend(backend, 1 << VHOST_USER_PROTOCOL_F_MQ, config.clone());
+        assert!(matches!(
+            VhostUserBlockBuilder::new(mem.clone(), frontend, EventFd::new(0).unwrap()).build(),
+            Err(Error::VhostUser(vhost_user::Error::MissingProtocolFeature(
+                VHOST_USER_PROTOCOL_F_CONFIG
+            )))
+        ));
+        handle.join().unwrap();
+
+        // Multiple queues require `VHOST_USER_PROTOCOL_F_MQ`.
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let handle = spawn_backend(backend, 1 << VHOST_USER_PROTOCOL_F_CONFIG, config.clone());
+        assert!(matches!(
+            VhostUserBlockBuilder::new(mem.clone(), frontend, EventFd::new(0).unwrap())
+                .with_num_queues(2)
+                .build(),
+            Err(Error::VhostUser(vhost_user::Error::MissingProtocolFeature(
+                VHOST_USER_PROTOCOL_F_MQ
+            )))
+        ));
+        handle.join().unwrap();
+
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let handle = spawn_backend(backend, SUPPORTED_PROTOCOL_FEATURES, config.clone());
+        assert!(matches!(
+            VhostUserBlockBuilder::new(mem.clone(), frontend, EventFd::new(0).unwrap())
+                .with_num_queues(3)
+                .build(),
+            Err(Error::VhostUser(vhost_user::Error::TooManyQueues(3)))
+        ));
+        handle.join().unwrap();
+
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let handle = spawn_backend(backend, SUPPORTED_PROTOCOL_FEATURES, config);
+        let block = VhostUserBlockBuilder::new(mem, frontend, EventFd::new(0).unwrap())
+            .with_num_queues(2)
+            .with_queue_size(16)
+            .build()
+            .unwrap();
+        assert_eq!(VirtioDevice::device_type(&block), VIRTIO_ID_BLOCK);
+        assert_eq!(block.num_queues(), 2);
+        assert_eq!(block.queue(1).unwrap().max_size(), 16);
+        assert_eq!(
+            block.device_features(),
+            BACKEND_FEATURES & !(1 << VHOST_USER_F_PROTOCOL_FEATURES)
+        );
+        let mut capacity = [0u8; 8];
+        block.read_config(0, &mut capacity);
+        assert_eq!(u64::from_le_bytes(capacity), 0x800);
+        assert!(block.kick_eventfd(1).is_some());
+        assert!(block.call_eventfd(2).is_none());
+        drop(block);
+
+        let requests: Vec<u32> = handle
+            .join()
+            .unwrap()
+            .iter()
+            .map(|m| m.header.request)
+            .collect();
+        assert_eq!(
+            requests,
+            vec![
+                VHOST_USER_SET_OWNER,
+                VHOST_USER_GET_FEATURES,
+                VHOST_USER_GET_PROTOCOL_FEATURES,
+                VHOST_USER_SET_PROTOCOL_FEATURES,
+                VHOST_USER_GET_QUEUE_NUM,
+                VHOST_USER_GET_CONFIG,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_activate_reset() {
+        let mem = shared_mem();
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let config = Arc::new(Mutex::new(config_space(0x800)));
+        let handle = spawn_backend(backend, SUPPORTED_PROTOCOL_FEATURES, config.clone());
+        let mut block = VhostUserBlockBuilder::new(mem.clone(), frontend, EventFd::new(0).unwrap())
+            .with_queue_size(16)
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            VirtioDevice::activate(&mut block),
+            Err(Error::InvalidQueues)
+        ));
+
+        let vqs = [VirtQueue::new(GuestAddress(0x1000), &mem, 16)];
+        activate(&mut block, &vqs, 0);
+        assert!(block.is_activated());
+
+        // Configuration space writes reach the backend.
+        block.write_config(ConfigSpace::WRITEBACK_OFFSET, &[0]);
+        let mut writeback = [1u8];
+        block.read_config(ConfigSpace::WRITEBACK_OFFSET, &mut writeback);
+        assert_eq!(writeback[0], 0);
+
+        // Queue notifications are forwarded through the kick eventfd.
+        block.queue_notify(0);
+        assert_eq!(block.kick_eventfd(0).unwrap().read().unwrap(), 1);
+
+        // The backend signals used buffers through the call eventfd.
+        block.process_call_event(0).unwrap();
+        assert_eq!(block.interrupt_status().load(Ordering::SeqCst), 0);
+        block.call_eventfd(0).unwrap().write(1).unwrap();
+        block.process_call_event(0).unwrap();
+        assert_eq!(
+            block.interrupt_status().load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_VRING
+        );
+        assert_eq!(block.driver_notify.read().unwrap(), 1);
+        assert!(matches!(
+            block.process_call_event(1),
+            Err(Error::InvalidQueueIndex(1))
+        ));
+
+        // The backend grew the disk.
+        *config.lock().unwrap() = config_space(0x1000);
+        block.refresh_config().unwrap();
+        assert_eq!(block.config_generation(), 1);
+        assert_ne!(
+            block.interrupt_status().load(Ordering::SeqCst) & VIRTIO_MMIO_INT_CONFIG,
+            0
+        );
+        block.refresh_config().unwrap();
+        assert_eq!(block.config_generation(), 1);
+
+        block.ack_device_status(0);
+        assert!(!block.is_activated());
+        assert_eq!(block.device_status(), 0);
+        drop(block);
+
+        let messages = handle.join().unwrap();
+        let find = |request| {
+            messages
+                .iter()
+                .find(|m| m.header.request == request)
+                .unwrap()
+        };
+
+        let features = find(VHOST_USER_SET_FEATURES);
+        assert_eq!(
+            features.obj::<u64>(0),
+            BACKEND_FEATURES | (1 << VHOST_USER_F_PROTOCOL_FEATURES)
+        );
+
+        let mem_table = find(VHOST_USER_SET_MEM_TABLE);
+        assert_eq!(mem_table.fds.len(), 1);
+        assert_eq!(mem_table.obj::<u32>(0), 1);
+        // The region follows the number of regions and the padding.
+        assert_eq!(mem_table.obj::<u64>(8), 0);
+        assert_eq!(mem_table.obj::<u64>(16), 0x10_0000);
+        assert_eq!(
+            mem_table.obj::<u64>(24),
+            mem.get_host_address(GuestAddress(0)).unwrap() as u64
+        );
+
+        let vring_addr = find(VHOST_USER_SET_VRING_ADDR);
+        assert_eq!(
+            vring_addr.obj::<u64>(8),
+            mem.get_host_address(vqs[0].dtable_start()).unwrap() as u64
+        );
+        assert_eq!(
+            vring_addr.obj::<u64>(16),
+            mem.get_host_address(vqs[0].used_start()).unwrap() as u64
+        );
+        assert_eq!(
+            vring_addr.obj::<u64>(24),
+            mem.get_host_address(vqs[0].avail_start()).unwrap() as u64
+        );
+        assert_eq!(find(VHOST_USER_SET_VRING_NUM).obj::<u32>(4), 16);
+        assert_eq!(find(VHOST_USER_SET_VRING_KICK).fds.len(), 1);
+        assert_eq!(find(VHOST_USER_SET_VRING_CALL).fds.len(), 1);
+
+        let set_config = find(VHOST_USER_SET_CONFIG);
+        assert_eq!(
+            set_config.obj::<u32>(0) as usize,
+            ConfigSpace::WRITEBACK_OFFSET
+        );
+        assert_eq!(set_config.obj::<u32>(4), 1);
+
+        // The queue is stopped on reset.
+        assert!(messages
+            .iter()
+            .any(|m| m.header.request == VHOST_USER_GET_VRING_BASE));
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let mem = shared_mem();
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        // Shutting down the socket of the backend stops it, like a crash would.
+        let crash = backend.try_clone().unwrap();
+        let config = Arc::new(Mutex::new(config_space(0x800)));
+        let handle = spawn_backend(backend, SUPPORTED_PROTOCOL_FEATURES, config);
+        let mut block = VhostUserBlockBuilder::new(mem.clone(), frontend, EventFd::new(0).unwrap())
+            .with_queue_size(16)
+            .build()
+            .unwrap();
+        let vqs = [VirtQueue::new(GuestAddress(0x1000), &mem, 16)];
+        activate(&mut block, &vqs, 0);
+        block.write_config(ConfigSpace::WRITEBACK_OFFSET, &[0]);
+        assert!(!block.is_backend_disconnected());
+
+        crash.shutdown(Shutdown::Both).unwrap();
+        let messages = handle.join().unwrap();
+        assert!(block.is_backend_disconnected());
+        // The in-flight descriptors area was set up when the device was activated.
+        let get_inflight = messages
+            .iter()
+            .find(|m| m.header.request == VHOST_USER_GET_INFLIGHT_FD)
+            .unwrap();
+        // The number of queues and their size follow the size and the offset of the area.
+        assert_eq!(get_inflight.obj::<u32>(16), (16 << 16) | 1);
+        // The backend used two buffers before it stopped.
+        vqs[0].used.idx().store(2);
+
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let config = Arc::new(Mutex::new(config_space(0x800)));
+        let handle = spawn_backend(backend, SUPPORTED_PROTOCOL_FEATURES, config.clone());
+        block.reconnect(frontend).unwrap();
+        assert!(!block.is_backend_disconnected());
+        assert!(block.is_activated());
+        // The cache mode selected by the driver is restored.
+        assert_eq!(config.lock().unwrap()[ConfigSpace::WRITEBACK_OFFSET], 0);
+        // The queue is kicked, in case the driver made buffers available in the meantime.
+        assert_eq!(block.kick_eventfd(0).unwrap().read().unwrap(), 1);
+        drop(block);
+
+        let messages = handle.join().unwrap();
+        let find = |request| {
+            messages
+                .iter()
+                .find(|m| m.header.request == request)
+                .unwrap()
+        };
+        let set_inflight = find(VHOST_USER_SET_INFLIGHT_FD);
+        assert_eq!(set_inflight.fds.len(), 1);
+        assert_eq!(set_inflight.obj::<u64>(0), 0x1000);
+        assert_eq!(find(VHOST_USER_SET_MEM_TABLE).fds.len(), 1);
+        // The queue continues after the last used buffer.
+        assert_eq!(find(VHOST_USER_SET_VRING_BASE).obj::<u32>(4), 2);
+        assert_eq!(find(VHOST_USER_SET_VRING_KICK).fds.len(), 1);
+        assert_eq!(find(VHOST_USER_SET_VRING_CALL).fds.len(), 1);
+    }
+
+    #[test]
+    fn test_unshared_memory() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let config = Arc::new(Mutex::new(config_space(0x800)));
+        let handle = spawn_backend(backend, SUPPORTED_PROTOCOL_FEATURES, config);
+        let mut block = VhostUserBlockBuilder::new(mem.clone(), frontend, EventFd::new(0).unwrap())
+            .with_queue_size(16)
+            .build()
+            .unwrap();
+
+        let vq = VirtQueue::new(GuestAddress(0x1000), &mem, 16);
+        let queue = block.queue_mut(0).unwrap();
+        queue.size = vq.size();
+        queue.desc_table = vq.dtable_start();
+        queue.avail_ring = vq.avail_start();
+        queue.used_ring = vq.used_start();
+        queue.ready = true;
+
+        assert!(matches!(
+            VirtioDevice::activate(&mut block),
+            Err(Error::VhostUser(vhost_user::Error::UnsharedMemoryRegion(
+                GuestAddress(0)
+            )))
+        ));
+        assert!(!block.is_activated());
+        drop(block);
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,643 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A vhost-user-blk backend, which serves a block device backing file to the VMMs that support
+//! vhost-user.
+//!
+//! This module provides the following abstractions:
+//!
+//! - [`VhostUserBlk`](struct.VhostUserBlk.html) which implements the
+//!   [`VhostUserBackend`](../../virtio_device/vhost_user_backend/trait.VhostUserBackend.html)
+//!   interface. The requests of each queue are executed in order by a
+//!   [`StdIoBackend`](../stdio_executor/struct.StdIoBackend.html), and the configuration space
+//!   is exposed to the frontend through `VHOST_USER_PROTOCOL_F_CONFIG`. The in-flight requests
+//!   are recorded when the frontend supports `VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD`, so they
+//!   are resubmitted when the daemon is restarted.
+//! - [`VhostUserBlkBuilder`](struct.VhostUserBlkBuilder.html) which configures and creates a
+//!   `VhostUserBlk` backend.
+//!
+//! The backend is driven by a
+//! [`VhostUserDaemon`](../../virtio_device/vhost_user_backend/struct.VhostUserDaemon.html),
+//! which handles the requests of a single frontend. A ready-made daemon that serves the
+//! frontends connecting to a Unix socket, one at a time, is available with
+//! [`VhostUserBlk::serve`](struct.VhostUserBlk.html#method.serve) (see also the
+//! `vhost_user_blk` example).
+
+use std::fmt::{self, Display};
+use std::result;
+
+use log::{error, warn};
+
+use virtio_device::vhost_user::{
+    VHOST_USER_PROTOCOL_F_CONFIG, VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD,
+};
+use virtio_device::vhost_user_backend::{
+    self, InflightQueue, VhostUserBackend, VhostUserDaemon, VhostUserListener, VringMemory,
+};
+use virtio_queue::{self, Queue};
+
+use crate::config::{self, ConfigBuilder, ConfigSpace};
+use crate::defs::{
+    DEFAULT_QUEUE_SIZE, VIRTIO_BLK_F_CONFIG_WCE, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_ID_BYTES,
+};
+use crate::request::Request;
+use crate::stdio_executor::{self, Backend, StdIoBackend};
+
+// TODO: Move the generic feature definitions to the vm-virtio crate proper.
+const VIRTIO_F_VERSION_1: u64 = 32;
+const VIRTIO_F_RING_EVENT_IDX: u64 = 29;
+
+/// vhost-user block backend errors.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to build the configuration space.
+    Config(config::Error),
+    /// Failed to create the request executor.
+    Executor(stdio_executor::Error),
+    /// The number of queues is zero.
+    InvalidNumQueues,
+    /// Failed to communicate with a frontend.
+    VhostUser(vhost_user_backend::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Config(ref err) => write!(f, "failed to build the configuration space: {}", err),
+            Executor(ref err) => write!(f, "failed to create the request executor: {}", err),
+            InvalidNumQueues => write!(f, "the device needs at least one queue"),
+            VhostUser(ref err) => write!(f, "vhost-user error: {}", err),
+        }
+    }
+}
+
+impl From<vhost_user_backend::Error> for Error {
+    fn from(e: vhost_user_backend::Error) -> Self {
+        Error::VhostUser(e)
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Configures and builds a `VhostUserBlk` backend.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # use virtio_blk::shared_file::{CacheMode, SharedFile};
+/// # use virtio_blk::vhost_user_backend::VhostUserBlkBuilder;
+/// # use virtio_device::vhost_user_backend::VhostUserListener;
+/// let file = SharedFile::open("/var/lib/images/disk.raw", false, CacheMode::Cached).unwrap();
+/// let blk = VhostUserBlkBuilder::new(file)
+///     .with_num_queues(2)
+///     .build()
+///     .unwrap();
+///
+/// let listener = VhostUserListener::new("/tmp/vhost-user-blk.sock").unwrap();
+/// blk.serve(&listener).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct VhostUserBlkBuilder<B: Backend + Clone> {
+    backend: B,
+    num_queues: u16,
+    queue_size: u16,
+    read_only: bool,
+    writeback: Option<bool>,
+    device_id: Option<[u8; VIRTIO_BLK_ID_BYTES]>,
+}
+
+impl<B: Backend + Clone> VhostUserBlkBuilder<B> {
+    /// Creates a new `VhostUserBlkBuilder`.
+    ///
+    /// # Arguments
+    /// * `backend` - The block device backing file, which is cloned for each request queue
+    ///   (usually a [`SharedFile`](../shared_file/struct.SharedFile.html)).
+    pub fn new(backend: B) -> Self {
+        VhostUserBlkBuilder {
+            backend,
+            num_queues: 1,
+            queue_size: DEFAULT_QUEUE_SIZE,
+            read_only: false,
+            writeback: None,
+            device_id: None,
+        }
+    }
+
+    /// Sets the number of request queues.
+    ///
+    /// # Arguments
+    /// * `num_queues` - The number of request queues.
+    pub fn with_num_queues(mut self, num_queues: u16) -> Self {
+        self.num_queues = num_queues;
+        self
+    }
+
+    /// Sets the maximum size of the request queues.
+    ///
+    /// # Arguments
+    /// * `queue_size` - The maximum queue size.
+    pub fn with_queue_size(mut self, queue_size: u16) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Marks the device as read-only.
+    ///
+    /// # Arguments
+    /// * `read_only` - Whether the device is read-only.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Lets the driver toggle the cache mode of the device.
+    ///
+    /// # Arguments
+    /// * `writeback` - Whether the device starts in writeback mode.
+    pub fn with_writeback(mut self, writeback: bool) -> Self {
+        self.writeback = Some(writeback);
+        self
+    }
+
+    /// Sets the device id string, which is returned to the driver for `VIRTIO_BLK_T_GET_ID`
+    /// requests. By default, the id is derived from the backend with `Backend::image_id`.
+    ///
+    /// # Arguments
+    /// * `device_id` - The block device id.
+    pub fn with_device_id(mut self, device_id: [u8; VIRTIO_BLK_ID_BYTES]) -> Self {
+        self.device_id = Some(device_id);
+        self
+    }
+
+    /// Builds the `VhostUserBlk` backend.
+    pub fn build(self) -> Result<VhostUserBlk<B>> {
+        if self.num_queues == 0 {
+            return Err(Error::InvalidNumQueues);
+        }
+        let disk = StdIoBackend::new(self.backend.clone(), 0)
+            .map_err(Error::Executor)?
+            .with_read_only(self.read_only);
+        let num_sectors = disk.num_sectors();
+
+        let mut config = ConfigBuilder::new(num_sectors).with_queue_size(self.queue_size);
+        if self.num_queues > 1 {
+            config = config.with_num_queues(self.num_queues);
+        }
+        if let Some(writeback) = self.writeback {
+            config = config.with_writeback(writeback);
+        }
+
+        let features = config.features()
+            | disk.device_features()
+            | (1 << VIRTIO_F_VERSION_1)
+            | (1 << VIRTIO_F_RING_EVENT_IDX)
+            | (1 << VIRTIO_BLK_F_FLUSH);
+        let config_space: Vec<u8> = config.build().map_err(Error::Config)?.into();
+        let device_id = self.device_id.or_else(|| self.backend.image_id());
+
+        Ok(VhostUserBlk {
+            backend: self.backend,
+            features,
+            num_queues: self.num_queues,
+            queue_size: self.queue_size,
+            read_only: self.read_only,
+            device_id,
+            config_space: config_space.clone(),
+            initial_config_space: config_space,
+            disks: Vec::new(),
+        })
+    }
+}
+
+/// A vhost-user-blk backend, which executes the requests on a block device backing file.
+#[derive(Debug)]
+pub struct VhostUserBlk<B: Backend + Clone> {
+    backend: B,
+    features: u64,
+    num_queues: u16,
+    queue_size: u16,
+    read_only: bool,
+    device_id: Option<[u8; VIRTIO_BLK_ID_BYTES]>,
+    config_space: Vec<u8>,
+    // The configuration space that's restored for each frontend.
+    initial_config_space: Vec<u8>,
+    // One executor for each request queue, which are created when the features are set.
+    disks: Vec<StdIoBackend<B>>,
+}
+
+impl<B: Backend + Clone> VhostUserBlk<B> {
+    /// Returns the device id string, if any.
+    pub fn device_id(&self) -> Option<[u8; VIRTIO_BLK_ID_BYTES]> {
+        self.device_id
+    }
+
+    /// Returns whether the device cache is in writeback mode.
+    pub fn writeback(&self) -> bool {
+        self.config_space
+            .get(ConfigSpace::WRITEBACK_OFFSET)
+            .is_some_and(|&v| v != 0)
+    }
+
+    /// Serves the frontends which connect through `listener`, one at a time. Every frontend
+    /// starts with the initial device state, and the errors of a connection are logged before
+    /// the next frontend is accepted. This only returns when accepting a connection fails.
+    ///
+    /// # Arguments
+    /// * `listener` - The listener used for accepting the frontends.
+    pub fn serve(&self, listener: &VhostUserListener) -> Result<()> {
+        loop {
+            let stream = listener.accept()?;
+            let mut daemon = VhostUserDaemon::new(stream, self.fresh_copy())?;
+            if let Err(e) = daemon.run() {
+                error!("vhost-user-blk frontend error: {}", e);
+            }
+        }
+    }
+
+    // Returns a copy of the backend, in the state it had before any frontend connected.
+    fn fresh_copy(&self) -> Self {
+        VhostUserBlk {
+            backend: self.backend.clone(),
+            features: self.features,
+            num_queues: self.num_queues,
+            queue_size: self.queue_size,
+            read_only: self.read_only,
+            device_id: self.device_id,
+            config_space: self.initial_config_space.clone(),
+            initial_config_space: self.initial_config_space.clone(),
+            disks: Vec::new(),
+        }
+    }
+
+    fn create_disk(&self, features: u64) -> stdio_executor::Result<StdIoBackend<B>> {
+        let mut disk =
+            StdIoBackend::new(self.backend.clone(), features)?.with_read_only(self.read_only);
+        if let Some(device_id) = self.device_id {
+            disk = disk.with_device_id(device_id);
+        }
+        if features & (1 << VIRTIO_BLK_F_CONFIG_WCE) != 0 {
+            disk.set_writeback(self.writeback())?;
+        }
+        Ok(disk)
+    }
+
+    // Executes the available requests, and returns whether the driver has to be notified.
+    fn process_requests(
+        disk: &mut StdIoBackend<B>,
+        queue: &mut InflightQueue,
+    ) -> result::Result<bool, virtio_queue::Error> {
+        let mut used = false;
+        loop {
+            queue.queue_mut().disable_notification()?;
+
+            loop {
+                let mut chain = match queue.pop()? {
+                    Some(chain) => chain,
+                    None => break,
+                };
+                let len = match Request::parse(&mut chain) {
+                    Ok(request) => disk
+                        .process_request(chain.memory(), &request)
+                        .unwrap_or_else(|e| {
+                            error!("failed to process block request: {}", e);
+                            0
+                        }),
+                    Err(e) => {
+                        warn!("failed to parse block request: {}", e);
+                        disk.process_invalid_request(chain.memory(), &e)
+                            .unwrap_or_else(|e| {
+                                error!("failed to process invalid block request: {}", e);
+                                0
+                            })
+                    }
+                };
+                queue.add_used(chain.head_index(), len)?;
+                used = true;
+            }
+
+            if !queue.queue_mut().enable_notification()? {
+                break;
+            }
+        }
+        Ok(used && queue.queue_mut().needs_notification()?)
+    }
+}
+
+impl<B: Backend + Clone> VhostUserBackend for VhostUserBlk<B> {
+    fn num_queues(&self) -> u16 {
+        self.num_queues
+    }
+
+    fn max_queue_size(&self) -> u16 {
+        self.queue_size
+    }
+
+    fn features(&self) -> u64 {
+        self.features
+    }
+
+    fn set_features(&mut self, features: u64) {
+        let disks = (0..self.num_queues)
+            .map(|_| self.create_disk(features))
+            .collect::<stdio_executor::Result<Vec<_>>>();
+        self.disks = disks.unwrap_or_else(|e| {
+            // The queues are left without executors, so their requests are not processed.
+            error!("failed to create the request executors: {}", e);
+            Vec::new()
+        });
+    }
+
+    fn protocol_features(&self) -> u64 {
+        (1 << VHOST_USER_PROTOCOL_F_CONFIG) | (1 << VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD)
+    }
+
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        let end = offset.saturating_add(data.len());
+        match self.config_space.get(offset..end) {
+            Some(src) => data.copy_from_slice(src),
+            None => warn!("invalid configuration space read at offset {}", offset),
+        }
+    }
+
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        // The cache mode is the only writable field.
+        if offset != ConfigSpace::WRITEBACK_OFFSET || data.len() != 1 {
+            warn!("invalid configuration space write at offset {}", offset);
+            return;
+        }
+        self.config_space[offset] = data[0];
+        let writeback = self.writeback();
+        for disk in self.disks.iter_mut() {
+            if let Err(e) = disk.set_writeback(writeback) {
+                error!("failed to switch the cache mode: {}", e);
+            }
+        }
+    }
+
+    fn process_queue(&mut self, index: u16, queue: &mut Queue<VringMemory>) -> bool {
+        self.process_queue_inflight(index, &mut InflightQueue::new(queue))
+    }
+
+    fn process_queue_inflight(&mut self, index: u16, queue: &mut InflightQueue) -> bool {
+        let disk = match self.disks.get_mut(usize::from(index)) {
+            Some(disk) => disk,
+            None => {
+                warn!("block queue {} doesn't have a request executor", index);
+                return false;
+            }
+        };
+        Self::process_requests(disk, queue).unwrap_or_else(|e| {
+            error!("failed to process block queue {}: {}", index, e);
+            false
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::net::UnixStream;
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+
+    use vm_memory::{Address, Bytes, FileOffset, GuestAddress, GuestMemoryMmap};
+    use vmm_sys_util::eventfd::EventFd;
+    use vmm_sys_util::tempfile::TempFile;
+
+    use virtio_device::vhost_user::VhostUserFrontend;
+    use virtio_queue::mock::VirtQueue;
+    use virtio_queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    use crate::defs::{
+        SECTOR_SIZE, VIRTIO_BLK_F_MQ, VIRTIO_BLK_F_RO, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK,
+        VIRTIO_BLK_T_OUT,
+    };
+    use crate::shared_file::SharedFile;
+
+    type Mem = Arc<GuestMemoryMmap>;
+
+    fn shared_mem() -> Mem {
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x10_0000).unwrap();
+        Arc::new(
+            GuestMemoryMmap::from_ranges_with_files(&[(
+                GuestAddress(0),
+                0x10_0000,
+                Some(FileOffset::new(file, 0)),
+            )])
+            .unwrap(),
+        )
+    }
+
+    fn disk() -> SharedFile {
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x10_0000).unwrap();
+        SharedFile::new(file)
+    }
+
+    fn spawn_daemon(
+        stream: UnixStream,
+        blk: VhostUserBlk<SharedFile>,
+    ) -> JoinHandle<VhostUserBlk<SharedFile>> {
+        thread::spawn(move || {
+            let mut daemon = VhostUserDaemon::new(stream, blk).unwrap();
+            daemon.run().unwrap();
+            daemon.backend().fresh_copy()
+        })
+    }
+
+    // Adds a request which writes one sector of `0xaa` bytes at sector 1 to the queue.
+    fn add_write_request(mem: &Mem, vq: &VirtQueue, idx: u16) {
+        mem.write_obj(VIRTIO_BLK_T_OUT, GuestAddress(0x1_0000))
+            .unwrap();
+        mem.write_obj(1u64, GuestAddress(0x1_0008)).unwrap();
+        mem.write_slice(&[0xaa; SECTOR_SIZE as usize], GuestAddress(0x2_0000))
+            .unwrap();
+        mem.write_obj(0xffu8, GuestAddress(0x3_0000)).unwrap();
+        vq.dtable(0).set(0x1_0000, 0x10, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1)
+            .set(0x2_0000, SECTOR_SIZE as u32, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable(2).set(0x3_0000, 1, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring(idx).store(0);
+        vq.avail.idx().store(idx + 1);
+    }
+
+    #[test]
+    fn test_build() {
+        assert!(matches!(
+            VhostUserBlkBuilder::new(disk()).with_num_queues(0).build(),
+            Err(Error::InvalidNumQueues)
+        ));
+        assert!(matches!(
+            VhostUserBlkBuilder::new(disk()).with_queue_size(1).build(),
+            Err(Error::Config(_))
+        ));
+
+        let backend = disk();
+        let blk = VhostUserBlkBuilder::new(backend.clone())
+            .with_num_queues(2)
+            .with_queue_size(16)
+            .with_writeback(true)
+            .with_read_only(true)
+            .build()
+            .unwrap();
+        assert_eq!(blk.num_queues(), 2);
+        assert_eq!(blk.max_queue_size(), 16);
+        for &feature in [
+            VIRTIO_F_VERSION_1,
+            VIRTIO_F_RING_EVENT_IDX,
+            VIRTIO_BLK_F_FLUSH,
+            VIRTIO_BLK_F_CONFIG_WCE,
+            VIRTIO_BLK_F_MQ,
+            VIRTIO_BLK_F_RO,
+        ]
+        .iter()
+        {
+            assert_ne!(blk.features() & (1 << feature), 0);
+        }
+        assert_eq!(blk.device_id(), backend.image_id());
+        assert!(blk.writeback());
+
+        let mut capacity = [0u8; 8];
+        blk.read_config(ConfigSpace::CAPACITY_OFFSET, &mut capacity);
+        assert_eq!(u64::from_le_bytes(capacity), 0x10_0000 / SECTOR_SIZE);
+        // Out of bounds reads are ignored.
+        let mut data = [0xffu8; 4];
+        blk.read_config(ConfigSpace::LEN - 2, &mut data);
+        assert_eq!(data, [0xff; 4]);
+
+        let device_id = [b'a'; VIRTIO_BLK_ID_BYTES];
+        let blk = VhostUserBlkBuilder::new(backend)
+            .with_device_id(device_id)
+            .build()
+            .unwrap();
+        assert_eq!(blk.device_id(), Some(device_id));
+        assert_eq!(blk.features() & (1 << VIRTIO_BLK_F_RO), 0);
+    }
+
+    #[test]
+    fn test_daemon() {
+        let mem = shared_mem();
+        let backend = disk();
+        let blk = VhostUserBlkBuilder::new(backend.clone())
+            .with_num_queues(2)
+            .with_queue_size(16)
+            .with_writeback(true)
+            .build()
+            .unwrap();
+        let (stream, daemon_stream) = UnixStream::pair().unwrap();
+        let handle = spawn_daemon(daemon_stream, blk);
+
+        let mut frontend = VhostUserFrontend::new(stream).unwrap();
+        frontend.check_queue_num(2).unwrap();
+        // The requests are recorded while they are in flight.
+        let (inflight, _file) = frontend.get_inflight_fd(2, 16).unwrap();
+        assert_eq!(inflight.num_queues, 2);
+        let config = frontend.get_config(ConfigSpace::LEN).unwrap();
+        assert_eq!(config[ConfigSpace::WRITEBACK_OFFSET], 1);
+        // The driver switches to writethrough mode.
+        frontend
+            .set_config(ConfigSpace::WRITEBACK_OFFSET, &[0])
+            .unwrap();
+        let config = frontend.get_config(ConfigSpace::LEN).unwrap();
+        assert_eq!(config[ConfigSpace::WRITEBACK_OFFSET], 0);
+
+        let vqs = [
+            VirtQueue::new(GuestAddress(0), &mem, 16),
+            VirtQueue::new(GuestAddress(0x1000), &mem, 16),
+        ];
+        let queues: Vec<_> = vqs
+            .iter()
+            .map(|vq| {
+                let mut queue = Queue::new(mem.clone(), 16);
+                queue.size = vq.size();
+                queue.desc_table = vq.dtable_start();
+                queue.avail_ring = vq.avail_start();
+                queue.used_ring = vq.used_start();
+                queue.ready = true;
+                queue
+            })
+            .collect();
+        // The call eventfds are blocking, so the test can wait for the used buffers.
+        let kick_evts: Vec<_> = (0..2).map(|_| EventFd::new(0).unwrap()).collect();
+        let call_evts: Vec<_> = (0..2).map(|_| EventFd::new(0).unwrap()).collect();
+        let features = frontend.features() & !(1 << VIRTIO_F_RING_EVENT_IDX);
+        frontend
+            .start(&*mem, features, &queues, &kick_evts, &call_evts)
+            .unwrap();
+
+        add_write_request(&mem, &vqs[1], 0);
+        kick_evts[1].write(1).unwrap();
+        assert_eq!(call_evts[1].read().unwrap(), 1);
+        assert_eq!(vqs[1].used.idx().load(), 1);
+        // Only the status byte is written to memory.
+        let used_len = vqs[1].used_start().unchecked_add(8);
+        assert_eq!(mem.read_obj::<u32>(used_len).unwrap(), 1);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x3_0000)).unwrap(),
+            VIRTIO_BLK_S_OK
+        );
+        let mut buf = [0u8; SECTOR_SIZE as usize];
+        backend.file().read_exact_at(&mut buf, SECTOR_SIZE).unwrap();
+        assert_eq!(buf, [0xaa; SECTOR_SIZE as usize]);
+
+        frontend.stop(2).unwrap();
+        drop(frontend);
+        // The next frontend starts with the initial configuration space.
+        let blk = handle.join().unwrap();
+        assert!(blk.writeback());
+    }
+
+    #[test]
+    fn test_read_only() {
+        let mem = shared_mem();
+        let backend = disk();
+        let blk = VhostUserBlkBuilder::new(backend.clone())
+            .with_queue_size(16)
+            .with_read_only(true)
+            .build()
+            .unwrap();
+        let (stream, daemon_stream) = UnixStream::pair().unwrap();
+        let handle = spawn_daemon(daemon_stream, blk);
+
+        let mut frontend = VhostUserFrontend::new(stream).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let mut queue = Queue::new(mem.clone(), 16);
+        queue.size = vq.size();
+        queue.desc_table = vq.dtable_start();
+        queue.avail_ring = vq.avail_start();
+        queue.used_ring = vq.used_start();
+        queue.ready = true;
+        let (kick_evt, call_evt) = (EventFd::new(0).unwrap(), EventFd::new(0).unwrap());
+        let features = frontend.features() & !(1 << VIRTIO_F_RING_EVENT_IDX);
+        frontend
+            .start(
+                &*mem,
+                features,
+                &[queue],
+                &[kick_evt.try_clone().unwrap()],
+                &[call_evt.try_clone().unwrap()],
+            )
+            .unwrap();
+
+        add_write_request(&mem, &vq, 0);
+        kick_evt.write(1).unwrap();
+        assert_eq!(call_evt.read().unwrap(), 1);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x3_0000)).unwrap(),
+            VIRTIO_BLK_S_IOERR
+        );
+        let mut buf = [0u8; SECTOR_SIZE as usize];
+        backend.file().read_exact_at(&mut buf, SECTOR_SIZE).unwrap();
+        assert_eq!(buf, [0; SECTOR_SIZE as usize]);
+
+        drop(frontend);
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,49 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Can backend abstraction.
+//!
+//! This module provides the [`CanBackend`](trait.CanBackend.html) interface, which connects the
+//! device to a CAN controller (or to a bus simulation). The device validates the frames sent by
+//! the driver, and enforces the controller mode requested through the control queue, such that
+//! backends only have to move frames to and from the bus. The
+//! [`SocketCan`](../socketcan/struct.SocketCan.html) backend is built on top of the Linux
+//! SocketCAN interface.
+
+use std::io;
+
+use crate::frame::CanFrame;
+
+/// An event which is reported by a backend.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CanEvent {
+    /// A frame was received from the bus.
+    Frame(CanFrame),
+    /// The controller went bus-off, which means it no longer takes part in the bus traffic
+    /// until it's restarted by the driver.
+    BusOff,
+}
+
+/// The interface between the can device and the CAN controller.
+pub trait CanBackend {
+    /// Returns whether CAN FD frames are supported.
+    fn supports_fd(&self) -> bool;
+
+    /// Starts the controller, when requested by the driver.
+    fn start(&mut self) -> io::Result<()>;
+
+    /// Stops the controller, when requested by the driver (or when the device is reset).
+    fn stop(&mut self) -> io::Result<()>;
+
+    /// Sends a frame on the bus.
+    ///
+    /// # Arguments
+    /// * `frame` - The frame.
+    fn send(&mut self, frame: &CanFrame) -> io::Result<()>;
+
+    /// Returns the next event, or `None` when there are no pending events. This must not block,
+    /// since the device only calls it when the VMM reports that the backend has pending events
+    /// (or when the driver makes more receive buffers available).
+    fn recv(&mut self) -> io::Result<Option<CanEvent>>;
+}
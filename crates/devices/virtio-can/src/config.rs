@@ -0,0 +1,60 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Virtio can device configuration space abstraction.
+//!
+//! This module provides the [`ConfigSpace`](struct.ConfigSpace.html) abstraction, which mirrors
+//! the `virtio_can_config` structure from the draft virtio specification. It only holds the
+//! status of the CAN controller, which the device updates when the controller goes bus-off.
+
+use std::mem::{offset_of, size_of};
+
+use vm_memory::ByteValued;
+
+/// The can device configuration space layout, as defined by the draft virtio specification.
+///
+/// All fields are expected to hold little endian values, which is always the case on the
+/// platforms that are currently supported (`x86_64` and `aarch64`).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C, packed)]
+pub struct ConfigSpace {
+    /// The controller status (`VIRTIO_CAN_S_CTRL_BUSOFF`).
+    pub status: u16,
+}
+
+// Safe because ConfigSpace only contains plain data, and there's no padding between (or after)
+// the fields since the structure is packed.
+unsafe impl ByteValued for ConfigSpace {}
+
+impl ConfigSpace {
+    /// The size of the can device configuration space.
+    pub const LEN: usize = size_of::<ConfigSpace>();
+    /// The offset of the `status` field.
+    pub const STATUS_OFFSET: usize = offset_of!(ConfigSpace, status);
+}
+
+impl From<ConfigSpace> for Vec<u8> {
+    fn from(config: ConfigSpace) -> Self {
+        config.as_slice().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::defs::VIRTIO_CAN_S_CTRL_BUSOFF;
+
+    #[test]
+    fn test_config_space() {
+        assert_eq!(ConfigSpace::LEN, 2);
+        assert_eq!(ConfigSpace::STATUS_OFFSET, 0);
+
+        let config = ConfigSpace {
+            status: VIRTIO_CAN_S_CTRL_BUSOFF,
+        };
+        let bytes: Vec<u8> = config.into();
+        assert_eq!(bytes, [1, 0]);
+    }
+}
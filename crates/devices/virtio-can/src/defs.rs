@@ -0,0 +1,66 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+// TODO: Move the generic device type definition to the vm-virtio crate proper.
+/// The virtio device type of can devices.
+pub const VIRTIO_ID_CAN: u32 = 36;
+
+/// The index of the transmit queue.
+pub const TX_QUEUE: u16 = 0;
+/// The index of the receive queue.
+pub const RX_QUEUE: u16 = 1;
+/// The index of the control queue.
+pub const CONTROL_QUEUE: u16 = 2;
+/// The number of queues.
+pub const NUM_QUEUES: usize = 3;
+
+/// The default size of the queues.
+pub const DEFAULT_QUEUE_SIZE: u16 = 64;
+
+// Feature bits (from the draft `linux/virtio_can.h`).
+/// The device supports classic CAN frames.
+pub const VIRTIO_CAN_F_CAN_CLASSIC: u64 = 0;
+/// The device supports CAN FD frames.
+pub const VIRTIO_CAN_F_CAN_FD: u64 = 1;
+/// The device uses the transmit buffers only after the frames were sent on the bus.
+pub const VIRTIO_CAN_F_LATE_TX_ACK: u64 = 2;
+/// The device supports remote transmission request frames.
+pub const VIRTIO_CAN_F_RTR_FRAMES: u64 = 3;
+
+// Controller status.
+/// The controller is in the bus-off state.
+pub const VIRTIO_CAN_S_CTRL_BUSOFF: u16 = 1;
+
+// Message types.
+/// A frame sent through the transmit queue.
+pub const VIRTIO_CAN_TX: u16 = 0x0001;
+/// A frame received through the receive queue.
+pub const VIRTIO_CAN_RX: u16 = 0x0101;
+/// Starts the controller.
+pub const VIRTIO_CAN_SET_CTRL_MODE_START: u16 = 0x0201;
+/// Stops the controller.
+pub const VIRTIO_CAN_SET_CTRL_MODE_STOP: u16 = 0x0202;
+
+// Request results.
+/// The request was successful.
+pub const VIRTIO_CAN_RESULT_OK: u8 = 0;
+/// The request failed.
+pub const VIRTIO_CAN_RESULT_NOT_OK: u8 = 1;
+
+// Frame flags.
+/// The frame has a 29 bit (extended) identifier.
+pub const VIRTIO_CAN_FLAGS_EXTENDED: u32 = 0x8000;
+/// The frame is a CAN FD frame.
+pub const VIRTIO_CAN_FLAGS_FD: u32 = 0x4000;
+/// The frame is a remote transmission request.
+pub const VIRTIO_CAN_FLAGS_RTR: u32 = 0x2000;
+
+/// The maximum length of the data of classic CAN frames.
+pub const CAN_MAX_DLEN: usize = 8;
+/// The maximum length of the data of CAN FD frames.
+pub const CANFD_MAX_DLEN: usize = 64;
+/// The largest standard (11 bit) identifier.
+pub const CAN_SFF_MASK: u32 = 0x7ff;
+/// The largest extended (29 bit) identifier.
+pub const CAN_EFF_MASK: u32 = 0x1fff_ffff;
@@ -0,0 +1,871 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A reference virtio can device implementation.
+//!
+//! This module provides the following abstractions:
+//!
+//! - [`Can`](struct.Can.html) which ties together the generic device building blocks
+//!   (`VirtioConfig` and the `VirtioDevice`/`VirtioMmioDevice` interfaces) with the can
+//!   specific ones (the configuration space, the frames and the
+//!   [`CanBackend`](../backend/trait.CanBackend.html) interface).
+//! - [`CanBuilder`](struct.CanBuilder.html) which configures and creates a `Can` device.
+//!
+//! The device has a transmit queue, a receive queue and a control queue, through which the
+//! driver starts and stops the controller. Frames are only exchanged while the controller is
+//! started, and the frames received in the meantime are discarded. When the backend reports
+//! that the controller went bus-off, the device stops the controller, and sets the
+//! `VIRTIO_CAN_S_CTRL_BUSOFF` status bit until the driver starts it again.
+//!
+//! Classic frames and remote transmission requests are always offered, while CAN FD frames are
+//! offered when the backend supports them. Transmit buffers are used as soon as the backend
+//! accepted the frames (i.e. `VIRTIO_CAN_F_LATE_TX_ACK` is not offered).
+//!
+//! The device doesn't register any events by itself: the VMM is expected to rely on the
+//! `VirtioMmioDevice::queue_notify` implementation (or call the `process_*` methods directly)
+//! when the driver notifies a queue, and to call `process_rx_queue` when the backend has
+//! pending events.
+
+use std::cmp::min;
+use std::fmt::{self, Display};
+use std::io;
+use std::result;
+
+use log::{error, warn};
+
+use vm_memory::{ByteValued, GuestAddressSpace};
+
+use virtio_device::{
+    SignalConfigChange, SignalUsedQueue, VirtioConfig, VirtioDeviceActions, VirtioDeviceCommon,
+    VirtioMmioDevice,
+};
+use virtio_queue::areas::{self, areas_len, read_areas, read_obj, write_areas, Area};
+use virtio_queue::{self, Queue};
+
+use crate::backend::{CanBackend, CanEvent};
+use crate::config::ConfigSpace;
+use crate::defs::*;
+use crate::frame::{CanFrame, CanMessage};
+
+pub use crate::defs::{DEFAULT_QUEUE_SIZE, VIRTIO_ID_CAN};
+
+// TODO: Move the generic feature definitions to the vm-virtio crate proper.
+const VIRTIO_F_VERSION_1: u64 = 32;
+const VIRTIO_F_RING_EVENT_IDX: u64 = 29;
+
+/// Can device errors.
+#[derive(Debug)]
+pub enum Error {
+    /// The device was already activated.
+    AlreadyActivated,
+    /// Failed to receive events from the backend.
+    Backend(io::Error),
+    /// The queues are not configured properly.
+    InvalidQueues,
+    /// The queue index is not valid, or the device is not activated.
+    InvalidQueueIndex(u16),
+    /// Failed to access a queue.
+    Queue(virtio_queue::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            AlreadyActivated => write!(f, "the device is already activated"),
+            Backend(ref err) => write!(f, "failed to receive events from the backend: {}", err),
+            InvalidQueues => write!(f, "invalid queue configuration"),
+            InvalidQueueIndex(index) => write!(f, "invalid or inactive queue {}", index),
+            Queue(ref err) => write!(f, "failed to access the queue: {}", err),
+        }
+    }
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+// The outcome of a request, which is either successful or has a `VIRTIO_CAN_RESULT_*` result.
+type Status = result::Result<(), u8>;
+
+// Returns the result of a request which doesn't fit in its descriptor chain.
+fn not_ok(e: areas::Error) -> u8 {
+    warn!("invalid can request: {}", e);
+    VIRTIO_CAN_RESULT_NOT_OK
+}
+
+/// Configures and builds a `Can` device.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # use std::sync::Arc;
+/// # use virtio_can::device::CanBuilder;
+/// # use virtio_can::socketcan::SocketCan;
+/// # use vm_memory::{GuestAddress, GuestMemoryMmap};
+/// # use vmm_sys_util::eventfd::EventFd;
+/// let mem = Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+///
+/// let backend = SocketCan::new("vcan0").unwrap();
+/// let can = CanBuilder::new(mem, backend, EventFd::new(0).unwrap())
+///     .with_queue_size(128)
+///     .build();
+/// ```
+#[derive(Debug)]
+pub struct CanBuilder<M, B, S>
+where
+    M: GuestAddressSpace,
+    B: CanBackend,
+    S: SignalUsedQueue + SignalConfigChange,
+{
+    mem: M,
+    backend: B,
+    driver_notify: S,
+    queue_size: u16,
+}
+
+impl<M, B, S> CanBuilder<M, B, S>
+where
+    M: GuestAddressSpace + Clone,
+    B: CanBackend,
+    S: SignalUsedQueue + SignalConfigChange,
+{
+    /// Creates a new `CanBuilder`.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    /// * `backend` - The connection to the CAN controller.
+    /// * `driver_notify` - The object used for notifying the driver about used buffers and
+    ///   configuration space changes.
+    pub fn new(mem: M, backend: B, driver_notify: S) -> Self {
+        CanBuilder {
+            mem,
+            backend,
+            driver_notify,
+            queue_size: DEFAULT_QUEUE_SIZE,
+        }
+    }
+
+    /// Sets the maximum size of the queues.
+    ///
+    /// # Arguments
+    /// * `queue_size` - The maximum queue size.
+    pub fn with_queue_size(mut self, queue_size: u16) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Builds the `Can` device.
+    pub fn build(self) -> Can<M, B, S> {
+        let mut device_features = (1 << VIRTIO_F_VERSION_1)
+            | (1 << VIRTIO_F_RING_EVENT_IDX)
+            | (1 << VIRTIO_CAN_F_CAN_CLASSIC)
+            | (1 << VIRTIO_CAN_F_RTR_FRAMES);
+        if self.backend.supports_fd() {
+            device_features |= 1 << VIRTIO_CAN_F_CAN_FD;
+        }
+        let queues = (0..NUM_QUEUES)
+            .map(|_| Queue::new(self.mem.clone(), self.queue_size))
+            .collect();
+
+        Can {
+            cfg: VirtioConfig::new(device_features, queues, ConfigSpace::default().into()),
+            backend: self.backend,
+            driver_notify: self.driver_notify,
+            started: false,
+            pending: None,
+        }
+    }
+}
+
+/// A virtio can device.
+#[derive(Debug, VirtioDeviceCommon)]
+#[virtio(device_type = VIRTIO_ID_CAN)]
+pub struct Can<M, B, S>
+where
+    M: GuestAddressSpace,
+    B: CanBackend,
+    S: SignalUsedQueue + SignalConfigChange,
+{
+    #[virtio(config)]
+    cfg: VirtioConfig<M>,
+    backend: B,
+    driver_notify: S,
+    // Whether the controller was started by the driver.
+    started: bool,
+    // A received frame which is waiting for a receive buffer.
+    pending: Option<CanFrame>,
+}
+
+impl<M, B, S> Can<M, B, S>
+where
+    M: GuestAddressSpace,
+    B: CanBackend,
+    S: SignalUsedQueue + SignalConfigChange,
+{
+    /// Returns whether the device is activated.
+    pub fn is_activated(&self) -> bool {
+        self.cfg.device_activated
+    }
+
+    /// Returns a reference to the backend.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Returns whether the controller is started.
+    pub fn is_started(&self) -> bool {
+        self.started
+    }
+
+    /// Returns whether the controller is in the bus-off state.
+    pub fn is_bus_off(&self) -> bool {
+        self.status() & VIRTIO_CAN_S_CTRL_BUSOFF != 0
+    }
+
+    /// Processes the frames from the transmit queue. This has to be called when the driver
+    /// notifies the transmit queue.
+    pub fn process_tx_queue(&mut self) -> Result<()> {
+        self.process_requests(TX_QUEUE)
+    }
+
+    /// Processes the requests from the control queue. This has to be called when the driver
+    /// notifies the control queue.
+    pub fn process_control_queue(&mut self) -> Result<()> {
+        self.process_requests(CONTROL_QUEUE)
+    }
+
+    /// Processes the events of the backend, and passes the received frames to the driver for as
+    /// long as there are receive buffers. The events are processed in order, so the backend
+    /// isn't polled while a frame is waiting for a buffer. This has to be called when the
+    /// backend has pending events, and when the driver notifies the receive queue.
+    pub fn process_rx_queue(&mut self) -> Result<()> {
+        self.check_activated(RX_QUEUE)?;
+        loop {
+            let frame = match self.pending {
+                Some(frame) => frame,
+                None => {
+                    match self.backend.recv().map_err(Error::Backend)? {
+                        Some(CanEvent::Frame(frame)) if self.accepts(&frame) => {
+                            self.pending = Some(frame);
+                        }
+                        // Frames which are received while the controller is stopped, or which
+                        // the driver doesn't support, are discarded.
+                        Some(CanEvent::Frame(_)) => {}
+                        Some(CanEvent::BusOff) => self.bus_off(),
+                        None => return Ok(()),
+                    }
+                    continue;
+                }
+            };
+
+            let mut chain = match self.cfg.queues[usize::from(RX_QUEUE)].iter()?.next() {
+                Some(chain) => chain,
+                None => return Ok(()),
+            };
+            let msg = frame.to_message(VIRTIO_CAN_RX);
+            let len = frame.message_len();
+            let result = areas::split_chain(&mut chain).and_then(|(_, writable)| {
+                write_areas(chain.memory(), &writable, 0, &msg.as_slice()[..len])
+            });
+            let used_len = match result {
+                Ok(()) => {
+                    self.pending = None;
+                    // The message length is at most `CanMessage::LEN`.
+                    len as u32
+                }
+                Err(e) => {
+                    // The frame is kept for the next buffer.
+                    warn!("invalid can receive buffer: {}", e);
+                    0
+                }
+            };
+            self.add_used(RX_QUEUE, chain.head_index(), used_len)?;
+        }
+    }
+
+    fn check_activated(&self, index: u16) -> Result<()> {
+        if !self.cfg.device_activated {
+            return Err(Error::InvalidQueueIndex(index));
+        }
+        Ok(())
+    }
+
+    fn process_requests(&mut self, index: u16) -> Result<()> {
+        self.check_activated(index)?;
+        while let Some(mut chain) = self.cfg.queues[usize::from(index)].iter()?.next() {
+            let len = match areas::split_chain(&mut chain) {
+                Ok((readable, writable)) => {
+                    let status = if index == TX_QUEUE {
+                        self.handle_tx(chain.memory(), &readable)
+                    } else {
+                        self.handle_control(chain.memory(), &readable)
+                    };
+                    let result = match status {
+                        Ok(()) => VIRTIO_CAN_RESULT_OK,
+                        Err(result) => result,
+                    };
+                    match write_areas(chain.memory(), &writable, 0, &[result]) {
+                        Ok(()) => 1,
+                        Err(e) => {
+                            warn!("invalid can request: {}", e);
+                            0
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!("invalid can request: {}", e);
+                    0
+                }
+            };
+            self.add_used(index, chain.head_index(), len)?;
+        }
+        Ok(())
+    }
+
+    fn add_used(&mut self, index: u16, head_index: u16, len: u32) -> Result<()> {
+        let queue = &mut self.cfg.queues[usize::from(index)];
+        queue.add_used(head_index, len)?;
+        if queue.needs_notification()? {
+            self.cfg.signal_used_queue();
+            self.driver_notify.signal_used_queue(index);
+        }
+        Ok(())
+    }
+
+    // Sends a frame from the transmit queue.
+    fn handle_tx(&mut self, mem: &M::M, readable: &[Area]) -> Status {
+        // The message only holds the data of the frame, which is not present at all for remote
+        // transmission requests.
+        let len = min(areas_len(readable), CanMessage::LEN);
+        if len < CanMessage::HEADER_LEN {
+            warn!("can message is too short");
+            return Err(VIRTIO_CAN_RESULT_NOT_OK);
+        }
+        let mut msg = CanMessage::default();
+        read_areas(mem, readable, 0, &mut msg.as_mut_slice()[..len]).map_err(not_ok)?;
+
+        if msg.msg_type != VIRTIO_CAN_TX {
+            warn!("unknown can transmit message type {:#x}", msg.msg_type);
+            return Err(VIRTIO_CAN_RESULT_NOT_OK);
+        }
+        let frame = CanFrame::from_message(&msg).map_err(|e| {
+            warn!("invalid can frame: {}", e);
+            VIRTIO_CAN_RESULT_NOT_OK
+        })?;
+        if frame.message_len() > len {
+            warn!("can message is too short");
+            return Err(VIRTIO_CAN_RESULT_NOT_OK);
+        }
+        if !self.accepts(&frame) {
+            return Err(VIRTIO_CAN_RESULT_NOT_OK);
+        }
+        self.backend.send(&frame).map_err(|e| {
+            warn!("failed to send can frame: {}", e);
+            VIRTIO_CAN_RESULT_NOT_OK
+        })
+    }
+
+    // Changes the mode of the controller.
+    fn handle_control(&mut self, mem: &M::M, readable: &[Area]) -> Status {
+        let msg_type = read_obj::<_, u16>(mem, readable, 0).map_err(not_ok)?;
+        match msg_type {
+            VIRTIO_CAN_SET_CTRL_MODE_START => {
+                self.backend.start().map_err(|e| {
+                    warn!("failed to start the can controller: {}", e);
+                    VIRTIO_CAN_RESULT_NOT_OK
+                })?;
+                self.started = true;
+                self.set_status(0);
+            }
+            VIRTIO_CAN_SET_CTRL_MODE_STOP => {
+                self.backend.stop().map_err(|e| {
+                    warn!("failed to stop the can controller: {}", e);
+                    VIRTIO_CAN_RESULT_NOT_OK
+                })?;
+                self.started = false;
+                self.pending = None;
+            }
+            _ => {
+                warn!("unknown can control message type {:#x}", msg_type);
+                return Err(VIRTIO_CAN_RESULT_NOT_OK);
+            }
+        }
+        Ok(())
+    }
+
+    // Returns whether a frame can be exchanged with the driver, based on the state of the
+    // controller and on the negotiated features.
+    fn accepts(&self, frame: &CanFrame) -> bool {
+        let feature = if frame.is_fd() {
+            VIRTIO_CAN_F_CAN_FD
+        } else if frame.is_remote() {
+            VIRTIO_CAN_F_RTR_FRAMES
+        } else {
+            VIRTIO_CAN_F_CAN_CLASSIC
+        };
+        self.started && self.cfg.driver_features & (1 << feature) != 0
+    }
+
+    fn bus_off(&mut self) {
+        self.started = false;
+        self.pending = None;
+        self.set_status(VIRTIO_CAN_S_CTRL_BUSOFF);
+    }
+
+    fn status(&self) -> u16 {
+        let offset = ConfigSpace::STATUS_OFFSET;
+        u16::from_le_bytes([
+            self.cfg.config_space[offset],
+            self.cfg.config_space[offset + 1],
+        ])
+    }
+
+    // Updates the controller status, and notifies the driver about the change when the device
+    // is activated.
+    fn set_status(&mut self, status: u16) {
+        if self.status() == status {
+            return;
+        }
+        let offset = ConfigSpace::STATUS_OFFSET;
+        self.cfg.config_space[offset..offset + 2].copy_from_slice(&status.to_le_bytes());
+        self.cfg.config_generation = self.cfg.config_generation.wrapping_add(1);
+
+        if self.cfg.device_activated {
+            self.cfg.signal_config_change();
+            self.driver_notify.signal_config_change();
+        }
+    }
+}
+
+impl<M, B, S> VirtioDeviceActions for Can<M, B, S>
+where
+    M: GuestAddressSpace,
+    B: CanBackend,
+    S: SignalUsedQueue + SignalConfigChange,
+{
+    type E = Error;
+
+    fn activate(&mut self) -> Result<()> {
+        if self.cfg.device_activated {
+            return Err(Error::AlreadyActivated);
+        }
+        if !self.cfg.queues.iter().all(Queue::is_valid) {
+            return Err(Error::InvalidQueues);
+        }
+        self.cfg.device_activated = true;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        if let Err(e) = self.backend.stop() {
+            warn!("failed to stop the can controller: {}", e);
+        }
+        self.started = false;
+        self.pending = None;
+
+        let cfg = &mut self.cfg;
+        cfg.driver_features = 0;
+        cfg.device_features_select = 0;
+        cfg.driver_features_select = 0;
+        cfg.device_status = 0;
+        cfg.queue_select = 0;
+        cfg.queues.iter_mut().for_each(Queue::reset);
+        cfg.device_activated = false;
+        cfg.reset_interrupt_status();
+        cfg.config_space = ConfigSpace::default().into();
+        Ok(())
+    }
+}
+
+impl<M, B, S> VirtioMmioDevice<M> for Can<M, B, S>
+where
+    M: GuestAddressSpace + 'static,
+    B: CanBackend,
+    S: SignalUsedQueue + SignalConfigChange,
+{
+    fn queue_notify(&mut self, val: u32) {
+        // Queue indices always fit in an `u16`.
+        let result = match val as u16 {
+            TX_QUEUE => self.process_tx_queue(),
+            RX_QUEUE => self.process_rx_queue(),
+            CONTROL_QUEUE => self.process_control_queue(),
+            index => Err(Error::InvalidQueueIndex(index)),
+        };
+        if let Err(e) = result {
+            error!("failed to process queue {}: {}", val, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::VecDeque;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap};
+    use vmm_sys_util::eventfd::EventFd;
+
+    use virtio_device::mock::activate;
+    use virtio_device::{VirtioDevice, VIRTIO_MMIO_INT_CONFIG};
+    use virtio_queue::mock::{AttackPattern, VirtQueue};
+    use virtio_queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    type Mem = Arc<GuestMemoryMmap>;
+
+    // Records the sent frames, and returns the queued events.
+    #[derive(Debug, Default)]
+    struct TestBackend {
+        fd: bool,
+        running: bool,
+        sent: Vec<CanFrame>,
+        events: VecDeque<CanEvent>,
+    }
+
+    impl CanBackend for TestBackend {
+        fn supports_fd(&self) -> bool {
+            self.fd
+        }
+
+        fn start(&mut self) -> io::Result<()> {
+            self.running = true;
+            Ok(())
+        }
+
+        fn stop(&mut self) -> io::Result<()> {
+            self.running = false;
+            Ok(())
+        }
+
+        fn send(&mut self, frame: &CanFrame) -> io::Result<()> {
+            if frame.id() == 0 {
+                return Err(io::Error::from_raw_os_error(libc::ENOBUFS));
+            }
+            self.sent.push(*frame);
+            Ok(())
+        }
+
+        fn recv(&mut self) -> io::Result<Option<CanEvent>> {
+            Ok(self.events.pop_front())
+        }
+    }
+
+    type TestCan = Can<Mem, TestBackend, EventFd>;
+
+    fn setup(fd: bool) -> (Mem, TestCan) {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let backend = TestBackend {
+            fd,
+            ..Default::default()
+        };
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let can = CanBuilder::new(mem.clone(), backend, evt)
+            .with_queue_size(16)
+            .build();
+        (mem, can)
+    }
+
+    fn used_len(mem: &GuestMemoryMmap, vq: &VirtQueue, index: u64) -> u32 {
+        // The used ring starts after the flags and the index, and each element holds the head
+        // index followed by the length.
+        let addr = vq.used_start().unchecked_add(4 + index * 8 + 4);
+        mem.read_obj(addr).unwrap()
+    }
+
+    // Sends a request through a queue, and returns the result written by the device.
+    fn send(
+        can: &mut TestCan,
+        mem: &GuestMemoryMmap,
+        vq: &VirtQueue,
+        index: u16,
+        req: &[u8],
+    ) -> u8 {
+        let avail = vq.avail.idx().load();
+        let head = (avail * 2) % vq.size();
+        mem.write_slice(req, GuestAddress(0x1_0000)).unwrap();
+        mem.write_obj(0xffu8, GuestAddress(0x2_0000)).unwrap();
+        vq.dtable(head)
+            .set(0x1_0000, req.len() as u32, VIRTQ_DESC_F_NEXT, head + 1);
+        vq.dtable(head + 1).set(0x2_0000, 1, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring(avail % vq.size()).store(head);
+        vq.avail.idx().store(avail + 1);
+
+        can.queue_notify(u32::from(index));
+        assert_eq!(vq.used.idx().load(), avail + 1);
+        assert_eq!(used_len(mem, vq, u64::from(avail % vq.size())), 1);
+        mem.read_obj(GuestAddress(0x2_0000)).unwrap()
+    }
+
+    fn control(can: &mut TestCan, mem: &GuestMemoryMmap, vq: &VirtQueue, msg_type: u16) -> u8 {
+        send(can, mem, vq, CONTROL_QUEUE, &msg_type.to_le_bytes())
+    }
+
+    fn transmit(can: &mut TestCan, mem: &GuestMemoryMmap, vq: &VirtQueue, frame: &CanFrame) -> u8 {
+        let msg = frame.to_message(VIRTIO_CAN_TX);
+        send(
+            can,
+            mem,
+            vq,
+            TX_QUEUE,
+            &msg.as_slice()[..frame.message_len()],
+        )
+    }
+
+    // Makes `count` receive buffers of `len` bytes available, starting at `0x3_0000`.
+    fn add_rx_buffers(vq: &VirtQueue, count: u16, len: u32) {
+        for _ in 0..count {
+            let avail = vq.avail.idx().load();
+            let head = avail % vq.size();
+            let addr = 0x3_0000 + u64::from(head) * 0x100;
+            vq.dtable(head).set(addr, len, VIRTQ_DESC_F_WRITE, 0);
+            vq.avail.ring(head).store(head);
+            vq.avail.idx().store(avail + 1);
+        }
+    }
+
+    // Returns the frame received in the buffer which corresponds to the used element `index`.
+    fn received(mem: &GuestMemoryMmap, vq: &VirtQueue, index: u16) -> CanFrame {
+        let len = used_len(mem, vq, u64::from(index)) as usize;
+        let mut msg = CanMessage::default();
+        mem.read_slice(
+            &mut msg.as_mut_slice()[..len],
+            GuestAddress(0x3_0000 + u64::from(index) * 0x100),
+        )
+        .unwrap();
+        assert_eq!(msg.msg_type, VIRTIO_CAN_RX);
+        let frame = CanFrame::from_message(&msg).unwrap();
+        assert_eq!(frame.message_len(), len);
+        frame
+    }
+
+    #[test]
+    fn test_build() {
+        let (_, can) = setup(false);
+        assert_eq!(VirtioDevice::device_type(&can), VIRTIO_ID_CAN);
+        assert_eq!(
+            can.device_features(),
+            (1 << VIRTIO_F_VERSION_1)
+                | (1 << VIRTIO_F_RING_EVENT_IDX)
+                | (1 << VIRTIO_CAN_F_CAN_CLASSIC)
+                | (1 << VIRTIO_CAN_F_RTR_FRAMES)
+        );
+        assert_eq!(can.cfg.queues.len(), NUM_QUEUES);
+        assert!(!can.is_activated() && !can.is_started() && !can.is_bus_off());
+
+        let (_, can) = setup(true);
+        assert_ne!(can.device_features() & (1 << VIRTIO_CAN_F_CAN_FD), 0);
+    }
+
+    #[test]
+    fn test_transmit() {
+        let (mem, mut can) = setup(true);
+        let vqs: Vec<_> = (0..NUM_QUEUES as u64)
+            .map(|i| VirtQueue::new(GuestAddress(i * 0x1000), &mem, 16))
+            .collect();
+        let frame = CanFrame::new(0x10, false, &[1, 2]).unwrap();
+
+        // The queues can't be processed before the device is activated.
+        assert!(matches!(
+            can.process_tx_queue(),
+            Err(Error::InvalidQueueIndex(TX_QUEUE))
+        ));
+
+        activate(&mut can, &vqs, 1 << VIRTIO_CAN_F_RTR_FRAMES);
+        assert!(can.is_activated());
+
+        // Frames are rejected while the controller is stopped.
+        let tx = &vqs[usize::from(TX_QUEUE)];
+        let ctrl = &vqs[usize::from(CONTROL_QUEUE)];
+        assert_eq!(
+            transmit(&mut can, &mem, tx, &frame),
+            VIRTIO_CAN_RESULT_NOT_OK
+        );
+        assert_eq!(
+            control(&mut can, &mem, ctrl, VIRTIO_CAN_SET_CTRL_MODE_START),
+            VIRTIO_CAN_RESULT_OK
+        );
+        assert!(can.is_started() && can.backend().running);
+
+        let fd_frame = CanFrame::new_fd(0x1234, true, &[0xaa; 64]).unwrap();
+        assert_eq!(transmit(&mut can, &mem, tx, &frame), VIRTIO_CAN_RESULT_OK);
+        assert_eq!(
+            transmit(&mut can, &mem, tx, &fd_frame),
+            VIRTIO_CAN_RESULT_OK
+        );
+        assert_eq!(can.backend().sent, [frame, fd_frame]);
+
+        // Remote transmission requests were not negotiated.
+        let remote = CanFrame::new_remote(0x10, false, 2).unwrap();
+        assert_eq!(
+            transmit(&mut can, &mem, tx, &remote),
+            VIRTIO_CAN_RESULT_NOT_OK
+        );
+
+        // Backend failures, invalid frames, unknown message types and truncated messages.
+        let lost = CanFrame::new(0, false, &[]).unwrap();
+        assert_eq!(
+            transmit(&mut can, &mem, tx, &lost),
+            VIRTIO_CAN_RESULT_NOT_OK
+        );
+        let mut msg = frame.to_message(VIRTIO_CAN_TX);
+        msg.can_id = CAN_SFF_MASK + 1;
+        assert_eq!(
+            send(&mut can, &mem, tx, TX_QUEUE, &msg.as_slice()[..18]),
+            VIRTIO_CAN_RESULT_NOT_OK
+        );
+        msg = frame.to_message(VIRTIO_CAN_RX);
+        assert_eq!(
+            send(&mut can, &mem, tx, TX_QUEUE, &msg.as_slice()[..18]),
+            VIRTIO_CAN_RESULT_NOT_OK
+        );
+        msg = frame.to_message(VIRTIO_CAN_TX);
+        assert_eq!(
+            send(&mut can, &mem, tx, TX_QUEUE, &msg.as_slice()[..17]),
+            VIRTIO_CAN_RESULT_NOT_OK
+        );
+        assert_eq!(can.backend().sent.len(), 2);
+
+        assert_eq!(
+            control(&mut can, &mem, ctrl, 0x0300),
+            VIRTIO_CAN_RESULT_NOT_OK
+        );
+        assert_eq!(
+            control(&mut can, &mem, ctrl, VIRTIO_CAN_SET_CTRL_MODE_STOP),
+            VIRTIO_CAN_RESULT_OK
+        );
+        assert!(!can.is_started() && !can.backend().running);
+        assert_eq!(
+            transmit(&mut can, &mem, tx, &frame),
+            VIRTIO_CAN_RESULT_NOT_OK
+        );
+    }
+
+    #[test]
+    fn test_receive() {
+        let (mem, mut can) = setup(false);
+        let vqs: Vec<_> = (0..NUM_QUEUES as u64)
+            .map(|i| VirtQueue::new(GuestAddress(i * 0x1000), &mem, 16))
+            .collect();
+        activate(&mut can, &vqs, 0);
+        let rx = &vqs[usize::from(RX_QUEUE)];
+        let ctrl = &vqs[usize::from(CONTROL_QUEUE)];
+
+        let frames = [
+            CanFrame::new(0x1, false, &[1]).unwrap(),
+            CanFrame::new_remote(0x2, true, 8).unwrap(),
+            CanFrame::new(0x3, false, &[3; 8]).unwrap(),
+        ];
+
+        // Frames received while the controller is stopped are discarded.
+        can.backend.events.push_back(CanEvent::Frame(frames[0]));
+        add_rx_buffers(rx, 2, CanMessage::LEN as u32);
+        can.process_rx_queue().unwrap();
+        assert!(can.backend.events.is_empty());
+        assert_eq!(rx.used.idx().load(), 0);
+
+        control(&mut can, &mem, ctrl, VIRTIO_CAN_SET_CTRL_MODE_START);
+        // CAN FD frames were not negotiated, so they are discarded too.
+        let fd_frame = CanFrame::new_fd(0x4, false, &[]).unwrap();
+        can.backend.events.push_back(CanEvent::Frame(fd_frame));
+        can.backend
+            .events
+            .extend(frames.iter().map(|f| CanEvent::Frame(*f)));
+        can.process_rx_queue().unwrap();
+        assert_eq!(rx.used.idx().load(), 2);
+        assert_eq!(received(&mem, rx, 0), frames[0]);
+        assert_eq!(received(&mem, rx, 1), frames[1]);
+        assert_eq!(used_len(&mem, rx, 1) as usize, CanMessage::HEADER_LEN);
+
+        // The last frame waits for a buffer, and a buffer which is too short is skipped.
+        assert!(can.backend.events.is_empty());
+        add_rx_buffers(rx, 1, 8);
+        add_rx_buffers(rx, 1, CanMessage::LEN as u32);
+        can.queue_notify(u32::from(RX_QUEUE));
+        assert_eq!(rx.used.idx().load(), 4);
+        assert_eq!(used_len(&mem, rx, 2), 0);
+        assert_eq!(received(&mem, rx, 3), frames[2]);
+    }
+
+    #[test]
+    fn test_bus_off() {
+        let (mem, mut can) = setup(false);
+        let vqs: Vec<_> = (0..NUM_QUEUES as u64)
+            .map(|i| VirtQueue::new(GuestAddress(i * 0x1000), &mem, 16))
+            .collect();
+        activate(&mut can, &vqs, 0);
+        let ctrl = &vqs[usize::from(CONTROL_QUEUE)];
+        control(&mut can, &mem, ctrl, VIRTIO_CAN_SET_CTRL_MODE_START);
+        can.interrupt_status().store(0, Ordering::SeqCst);
+        can.driver_notify.read().unwrap();
+
+        let frame = CanFrame::new(0x1, false, &[1]).unwrap();
+        can.backend.events.push_back(CanEvent::BusOff);
+        can.backend.events.push_back(CanEvent::Frame(frame));
+        can.process_rx_queue().unwrap();
+        assert!(can.is_bus_off() && !can.is_started());
+        assert_eq!(can.config_generation(), 1);
+        assert_eq!(
+            can.interrupt_status().load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_CONFIG
+        );
+        assert_eq!(can.driver_notify.read().unwrap(), 1);
+        let mut status = [0u8; 2];
+        can.read_config(ConfigSpace::STATUS_OFFSET, &mut status);
+        assert_eq!(u16::from_le_bytes(status), VIRTIO_CAN_S_CTRL_BUSOFF);
+
+        // The frame received after the bus-off event was discarded, and the driver restarts the
+        // controller.
+        add_rx_buffers(&vqs[usize::from(RX_QUEUE)], 1, CanMessage::LEN as u32);
+        can.process_rx_queue().unwrap();
+        assert_eq!(vqs[usize::from(RX_QUEUE)].used.idx().load(), 0);
+        control(&mut can, &mem, ctrl, VIRTIO_CAN_SET_CTRL_MODE_START);
+        assert!(!can.is_bus_off() && can.is_started());
+        assert_eq!(can.config_generation(), 2);
+
+        // The state is cleared when the device is reset.
+        can.backend.events.push_back(CanEvent::BusOff);
+        can.process_rx_queue().unwrap();
+        can.ack_device_status(0);
+        assert!(!can.is_activated() && !can.is_bus_off() && !can.backend().running);
+        assert!(matches!(
+            can.process_rx_queue(),
+            Err(Error::InvalidQueueIndex(RX_QUEUE))
+        ));
+    }
+
+    #[test]
+    fn test_attack_patterns() {
+        // The malicious chains are consumed like any other request, on both the transmit and
+        // the control queue, except for the ones with an out of range head index, which can't
+        // be added to the used ring.
+        for &pattern in AttackPattern::ALL.iter() {
+            let (mem, mut can) = setup(true);
+            let vqs: Vec<_> = (0..NUM_QUEUES as u64)
+                .map(|i| VirtQueue::new(GuestAddress(i * 0x1000), &mem, 16))
+                .collect();
+            activate(&mut can, &vqs, 0);
+            for &index in [TX_QUEUE, CONTROL_QUEUE].iter() {
+                let vq = &vqs[usize::from(index)];
+                vq.add_attack(pattern, 0, GuestAddress(0x1_0000));
+                can.queue_notify(u32::from(index));
+                assert_eq!(
+                    vq.used.idx().load(),
+                    u16::from(pattern != AttackPattern::HeadOutOfRange),
+                    "{:?}",
+                    pattern
+                );
+            }
+        }
+    }
+}
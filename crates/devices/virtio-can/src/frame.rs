@@ -0,0 +1,364 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! CAN frame abstraction.
+//!
+//! This module provides the [`CanFrame`](struct.CanFrame.html) abstraction, which always holds
+//! a valid classic CAN or CAN FD frame, and the [`CanMessage`](struct.CanMessage.html) structure,
+//! which mirrors the `virtio_can_tx_out` and `virtio_can_rx` structures (they have the same
+//! layout) from the draft virtio specification.
+
+use std::fmt::{self, Display};
+use std::mem::size_of;
+use std::result;
+
+use vm_memory::ByteValued;
+
+use crate::defs::{
+    CANFD_MAX_DLEN, CAN_EFF_MASK, CAN_MAX_DLEN, CAN_SFF_MASK, VIRTIO_CAN_FLAGS_EXTENDED,
+    VIRTIO_CAN_FLAGS_FD, VIRTIO_CAN_FLAGS_RTR,
+};
+
+/// Frame errors.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The identifier doesn't fit in 11 (or 29, for extended identifiers) bits.
+    InvalidId(u32),
+    /// The length is not valid for the kind of frame.
+    InvalidLength(usize),
+    /// Unknown or conflicting flags.
+    InvalidFlags(u32),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            InvalidId(id) => write!(f, "invalid identifier {:#x}", id),
+            InvalidLength(len) => write!(f, "invalid frame length {}", len),
+            InvalidFlags(flags) => write!(f, "invalid frame flags {:#x}", flags),
+        }
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// The layout of the messages which carry frames through the transmit and receive queues.
+///
+/// Only the first `length` bytes of `sdu` are transferred for data frames, and none of them for
+/// remote transmission requests.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(C)]
+pub struct CanMessage {
+    /// The message type (`VIRTIO_CAN_TX` or `VIRTIO_CAN_RX`).
+    pub msg_type: u16,
+    /// The length of the data.
+    pub length: u16,
+    /// Reserved for the data length codes of classic frames which are larger than 8.
+    pub reserved_classic_dlc: u8,
+    /// Padding.
+    pub padding: u8,
+    /// Reserved for the priority of CAN XL frames.
+    pub reserved_xl_priority: u16,
+    /// The frame flags (`VIRTIO_CAN_FLAGS_*`).
+    pub flags: u32,
+    /// The identifier.
+    pub can_id: u32,
+    /// The data.
+    pub sdu: [u8; CANFD_MAX_DLEN],
+}
+
+impl Default for CanMessage {
+    fn default() -> Self {
+        CanMessage {
+            msg_type: 0,
+            length: 0,
+            reserved_classic_dlc: 0,
+            padding: 0,
+            reserved_xl_priority: 0,
+            flags: 0,
+            can_id: 0,
+            sdu: [0; CANFD_MAX_DLEN],
+        }
+    }
+}
+
+// Safe because CanMessage only contains plain data, and there's no padding between (or after)
+// the fields.
+unsafe impl ByteValued for CanMessage {}
+
+impl CanMessage {
+    /// The size of a message.
+    pub const LEN: usize = size_of::<CanMessage>();
+    /// The size of the part of a message which precedes the data.
+    pub const HEADER_LEN: usize = Self::LEN - CANFD_MAX_DLEN;
+}
+
+// Returns whether `len` is one of the lengths which can be encoded by the data length code of
+// CAN FD frames.
+fn is_valid_fd_len(len: usize) -> bool {
+    len <= CAN_MAX_DLEN || matches!(len, 12 | 16 | 20 | 24 | 32 | 48 | 64)
+}
+
+/// A classic CAN or CAN FD frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CanFrame {
+    id: u32,
+    flags: u32,
+    len: usize,
+    data: [u8; CANFD_MAX_DLEN],
+}
+
+impl CanFrame {
+    // Checks the parameters which are common to all the kinds of frames.
+    fn build(id: u32, extended: bool, flags: u32, len: usize, data: &[u8]) -> Result<Self> {
+        let max_id = if extended { CAN_EFF_MASK } else { CAN_SFF_MASK };
+        if id > max_id {
+            return Err(Error::InvalidId(id));
+        }
+
+        let mut frame = CanFrame {
+            id,
+            flags: if extended {
+                flags | VIRTIO_CAN_FLAGS_EXTENDED
+            } else {
+                flags
+            },
+            len,
+            data: [0; CANFD_MAX_DLEN],
+        };
+        frame.data[..data.len()].copy_from_slice(data);
+        Ok(frame)
+    }
+
+    /// Creates a classic data frame.
+    ///
+    /// # Arguments
+    /// * `id` - The identifier.
+    /// * `extended` - Whether the identifier is an extended (29 bit) one.
+    /// * `data` - The data, which is at most 8 bytes long.
+    pub fn new(id: u32, extended: bool, data: &[u8]) -> Result<Self> {
+        if data.len() > CAN_MAX_DLEN {
+            return Err(Error::InvalidLength(data.len()));
+        }
+        Self::build(id, extended, 0, data.len(), data)
+    }
+
+    /// Creates a CAN FD frame.
+    ///
+    /// # Arguments
+    /// * `id` - The identifier.
+    /// * `extended` - Whether the identifier is an extended (29 bit) one.
+    /// * `data` - The data, which has one of the lengths supported by CAN FD (0 to 8, 12, 16,
+    ///   20, 24, 32, 48 or 64 bytes).
+    pub fn new_fd(id: u32, extended: bool, data: &[u8]) -> Result<Self> {
+        if !is_valid_fd_len(data.len()) {
+            return Err(Error::InvalidLength(data.len()));
+        }
+        Self::build(id, extended, VIRTIO_CAN_FLAGS_FD, data.len(), data)
+    }
+
+    /// Creates a remote transmission request, which is a classic frame without data.
+    ///
+    /// # Arguments
+    /// * `id` - The identifier.
+    /// * `extended` - Whether the identifier is an extended (29 bit) one.
+    /// * `len` - The length of the requested data, which is at most 8.
+    pub fn new_remote(id: u32, extended: bool, len: usize) -> Result<Self> {
+        if len > CAN_MAX_DLEN {
+            return Err(Error::InvalidLength(len));
+        }
+        Self::build(id, extended, VIRTIO_CAN_FLAGS_RTR, len, &[])
+    }
+
+    /// Creates a frame from a message, which holds at least `CanMessage::HEADER_LEN` bytes,
+    /// followed by the data.
+    ///
+    /// # Arguments
+    /// * `msg` - The message.
+    pub fn from_message(msg: &CanMessage) -> Result<Self> {
+        let flags = msg.flags;
+        let extended = flags & VIRTIO_CAN_FLAGS_EXTENDED != 0;
+        let len = usize::from(msg.length);
+        match flags & !VIRTIO_CAN_FLAGS_EXTENDED {
+            0 | VIRTIO_CAN_FLAGS_FD if len > CANFD_MAX_DLEN => Err(Error::InvalidLength(len)),
+            0 => Self::new(msg.can_id, extended, &msg.sdu[..len]),
+            VIRTIO_CAN_FLAGS_FD => Self::new_fd(msg.can_id, extended, &msg.sdu[..len]),
+            VIRTIO_CAN_FLAGS_RTR => Self::new_remote(msg.can_id, extended, len),
+            _ => Err(Error::InvalidFlags(flags)),
+        }
+    }
+
+    /// Returns the message which carries the frame.
+    ///
+    /// # Arguments
+    /// * `msg_type` - The message type (`VIRTIO_CAN_TX` or `VIRTIO_CAN_RX`).
+    pub fn to_message(&self, msg_type: u16) -> CanMessage {
+        CanMessage {
+            msg_type,
+            // The length is at most 64.
+            length: self.len as u16,
+            flags: self.flags,
+            can_id: self.id,
+            sdu: self.data,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the identifier.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Returns the frame flags (`VIRTIO_CAN_FLAGS_*`).
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    /// Returns whether the identifier is an extended (29 bit) one.
+    pub fn is_extended(&self) -> bool {
+        self.flags & VIRTIO_CAN_FLAGS_EXTENDED != 0
+    }
+
+    /// Returns whether this is a CAN FD frame.
+    pub fn is_fd(&self) -> bool {
+        self.flags & VIRTIO_CAN_FLAGS_FD != 0
+    }
+
+    /// Returns whether this is a remote transmission request.
+    pub fn is_remote(&self) -> bool {
+        self.flags & VIRTIO_CAN_FLAGS_RTR != 0
+    }
+
+    /// Returns the length of the frame, which is the length of the requested data for remote
+    /// transmission requests.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the length of the frame is zero.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the data, which is empty for remote transmission requests.
+    pub fn data(&self) -> &[u8] {
+        if self.is_remote() {
+            &[]
+        } else {
+            &self.data[..self.len]
+        }
+    }
+
+    /// Returns the number of bytes of a message which carries the frame.
+    pub fn message_len(&self) -> usize {
+        CanMessage::HEADER_LEN + self.data().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::defs::{VIRTIO_CAN_RX, VIRTIO_CAN_TX};
+
+    #[test]
+    fn test_message_layout() {
+        assert_eq!(CanMessage::LEN, 80);
+        assert_eq!(CanMessage::HEADER_LEN, 16);
+
+        let frame = CanFrame::new(0x123, false, &[1, 2, 3]).unwrap();
+        let msg = frame.to_message(VIRTIO_CAN_RX);
+        let bytes = msg.as_slice();
+        assert_eq!(bytes[..12], [1, 1, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(bytes[12..16], 0x123u32.to_le_bytes());
+        assert_eq!(bytes[16..19], [1, 2, 3]);
+        assert_eq!(frame.message_len(), 19);
+    }
+
+    #[test]
+    fn test_frames() {
+        let frame = CanFrame::new(CAN_SFF_MASK, false, &[0xaa; 8]).unwrap();
+        assert_eq!(frame.id(), CAN_SFF_MASK);
+        assert!(!frame.is_extended() && !frame.is_fd() && !frame.is_remote());
+        assert_eq!(frame.data(), [0xaa; 8]);
+        assert_eq!(
+            CanFrame::new(CAN_SFF_MASK + 1, false, &[]).unwrap_err(),
+            Error::InvalidId(CAN_SFF_MASK + 1)
+        );
+        assert_eq!(
+            CanFrame::new(0, false, &[0; 9]).unwrap_err(),
+            Error::InvalidLength(9)
+        );
+
+        let frame = CanFrame::new_fd(CAN_EFF_MASK, true, &[0x55; 48]).unwrap();
+        assert!(frame.is_extended() && frame.is_fd());
+        assert_eq!(
+            frame.flags(),
+            VIRTIO_CAN_FLAGS_EXTENDED | VIRTIO_CAN_FLAGS_FD
+        );
+        assert_eq!(frame.len(), 48);
+        assert_eq!(
+            CanFrame::new_fd(CAN_EFF_MASK + 1, true, &[]).unwrap_err(),
+            Error::InvalidId(CAN_EFF_MASK + 1)
+        );
+        assert_eq!(
+            CanFrame::new_fd(0, false, &[0; 10]).unwrap_err(),
+            Error::InvalidLength(10)
+        );
+
+        let frame = CanFrame::new_remote(0x10, false, 4).unwrap();
+        assert!(frame.is_remote());
+        assert_eq!(frame.len(), 4);
+        assert!(frame.data().is_empty());
+        assert_eq!(frame.message_len(), CanMessage::HEADER_LEN);
+    }
+
+    #[test]
+    fn test_from_message() {
+        let mut msg = CanMessage {
+            msg_type: VIRTIO_CAN_TX,
+            length: 2,
+            flags: VIRTIO_CAN_FLAGS_EXTENDED,
+            can_id: 0x1234_5678,
+            ..Default::default()
+        };
+        msg.sdu[..2].copy_from_slice(&[7, 8]);
+        let frame = CanFrame::from_message(&msg).unwrap();
+        assert_eq!(frame, CanFrame::new(0x1234_5678, true, &[7, 8]).unwrap());
+        assert_eq!(frame.to_message(VIRTIO_CAN_TX), msg);
+
+        msg.length = 64;
+        assert_eq!(
+            CanFrame::from_message(&msg).unwrap_err(),
+            Error::InvalidLength(64)
+        );
+        msg.flags |= VIRTIO_CAN_FLAGS_FD;
+        assert!(CanFrame::from_message(&msg).unwrap().is_fd());
+        msg.length = 65;
+        assert_eq!(
+            CanFrame::from_message(&msg).unwrap_err(),
+            Error::InvalidLength(65)
+        );
+
+        msg.length = 8;
+        msg.flags = VIRTIO_CAN_FLAGS_RTR;
+        msg.can_id = 1;
+        let frame = CanFrame::from_message(&msg).unwrap();
+        assert_eq!(frame, CanFrame::new_remote(1, false, 8).unwrap());
+
+        msg.flags = VIRTIO_CAN_FLAGS_RTR | VIRTIO_CAN_FLAGS_FD;
+        assert_eq!(
+            CanFrame::from_message(&msg).unwrap_err(),
+            Error::InvalidFlags(VIRTIO_CAN_FLAGS_RTR | VIRTIO_CAN_FLAGS_FD)
+        );
+        msg.flags = 1;
+        assert_eq!(
+            CanFrame::from_message(&msg).unwrap_err(),
+            Error::InvalidFlags(1)
+        );
+    }
+}
@@ -0,0 +1,26 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A crate that provides can device specific components as described by the (draft) virtio
+//! specification.
+
+#![deny(missing_docs)]
+
+/// Contains the interface between the can device and the CAN controller.
+pub mod backend;
+
+/// Contains the can device configuration space abstraction.
+pub mod config;
+
+/// Contains virtio can constant definitions.
+pub mod defs;
+
+/// Contains a reference virtio can device implementation.
+pub mod device;
+
+/// Contains the CAN frame abstraction, and the layout of the messages which carry the frames.
+pub mod frame;
+
+/// Contains a can backend built on top of the Linux SocketCAN interface.
+pub mod socketcan;
@@ -0,0 +1,338 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! SocketCAN backend.
+//!
+//! This module provides the [`SocketCan`](struct.SocketCan.html) backend, which sends and
+//! receives frames through a raw CAN socket bound to a host interface (i.e. a physical
+//! controller, or a `vcan` interface for prototyping). CAN FD frames are supported when the
+//! interface is configured for them.
+//!
+//! The state of the host interface (and of the controller behind it) is managed on the host,
+//! so starting and stopping the controller from the driver only gates the traffic of the
+//! device. Bus-off conditions are detected through the error frames of the interface.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use libc::{c_char, c_int, c_void, socklen_t};
+use vm_memory::ByteValued;
+
+use crate::backend::{CanBackend, CanEvent};
+use crate::defs::{CANFD_MAX_DLEN, CAN_EFF_MASK, CAN_MAX_DLEN, CAN_SFF_MASK};
+use crate::frame::CanFrame;
+
+// SocketCAN definitions (from `linux/can.h`, `linux/can/raw.h` and `linux/can/error.h`).
+const CAN_RAW: c_int = 1;
+const SOL_CAN_RAW: c_int = 100 + CAN_RAW;
+const CAN_RAW_ERR_FILTER: c_int = 2;
+const CAN_RAW_FD_FRAMES: c_int = 5;
+const CAN_EFF_FLAG: u32 = 0x8000_0000;
+const CAN_RTR_FLAG: u32 = 0x4000_0000;
+const CAN_ERR_FLAG: u32 = 0x2000_0000;
+const CAN_ERR_BUSOFF: u32 = 0x0000_0040;
+// The sizes of the `can_frame` and `canfd_frame` structures.
+const CAN_MTU: usize = 16;
+const CANFD_MTU: usize = 72;
+
+// Interface ioctl (from `linux/sockios.h`).
+const SIOCGIFMTU: libc::c_ulong = 0x8921;
+
+// The `sockaddr_can` structure, without the protocol specific addresses.
+#[repr(C)]
+struct SockaddrCan {
+    can_family: libc::sa_family_t,
+    can_ifindex: c_int,
+    can_addr: [u64; 2],
+}
+
+// The `ifreq` structure, with the MTU member of the request union.
+#[repr(C)]
+struct IfreqMtu {
+    ifr_name: [c_char; libc::IFNAMSIZ],
+    ifr_mtu: c_int,
+    padding: [u8; 20],
+}
+
+// The `canfd_frame` structure, which starts with the same fields as the `can_frame` one.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct RawFrame {
+    can_id: u32,
+    len: u8,
+    flags: u8,
+    res0: u8,
+    res1: u8,
+    data: [u8; CANFD_MAX_DLEN],
+}
+
+impl Default for RawFrame {
+    fn default() -> Self {
+        RawFrame {
+            can_id: 0,
+            len: 0,
+            flags: 0,
+            res0: 0,
+            res1: 0,
+            data: [0; CANFD_MAX_DLEN],
+        }
+    }
+}
+
+// Safe because RawFrame only contains plain data, and there's no padding between (or after)
+// the fields.
+unsafe impl ByteValued for RawFrame {}
+
+// Converts the return value of a libc call to a `Result`.
+fn cvt(ret: c_int) -> io::Result<c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+// Returns the raw frame which corresponds to `frame`, and the number of bytes to send.
+fn encode(frame: &CanFrame) -> (RawFrame, usize) {
+    let mut raw = RawFrame {
+        can_id: frame.id(),
+        // The length is at most 64.
+        len: frame.len() as u8,
+        ..Default::default()
+    };
+    if frame.is_extended() {
+        raw.can_id |= CAN_EFF_FLAG;
+    }
+    if frame.is_remote() {
+        raw.can_id |= CAN_RTR_FLAG;
+    }
+    raw.data[..frame.data().len()].copy_from_slice(frame.data());
+    let len = if frame.is_fd() { CANFD_MTU } else { CAN_MTU };
+    (raw, len)
+}
+
+// Returns the event which corresponds to the first `len` bytes of `raw`, or `None` for the
+// frames which are not relevant to the device.
+fn decode(raw: &RawFrame, len: usize) -> Option<CanEvent> {
+    if raw.can_id & CAN_ERR_FLAG != 0 {
+        // The error class is held by the identifier of error frames.
+        return if raw.can_id & CAN_ERR_BUSOFF != 0 {
+            Some(CanEvent::BusOff)
+        } else {
+            None
+        };
+    }
+
+    let extended = raw.can_id & CAN_EFF_FLAG != 0;
+    let id = raw.can_id & if extended { CAN_EFF_MASK } else { CAN_SFF_MASK };
+    let data_len = usize::from(raw.len);
+    let frame = match len {
+        CAN_MTU if raw.can_id & CAN_RTR_FLAG != 0 => {
+            CanFrame::new_remote(id, extended, data_len.min(CAN_MAX_DLEN))
+        }
+        CAN_MTU => CanFrame::new(id, extended, &raw.data[..data_len.min(CAN_MAX_DLEN)]),
+        CANFD_MTU => CanFrame::new_fd(id, extended, &raw.data[..data_len.min(CANFD_MAX_DLEN)]),
+        _ => return None,
+    };
+    frame.ok().map(CanEvent::Frame)
+}
+
+/// A can backend which uses a raw CAN socket bound to a host interface.
+#[derive(Debug)]
+pub struct SocketCan {
+    socket: File,
+    fd_frames: bool,
+}
+
+impl SocketCan {
+    /// Creates a new `SocketCan` backend.
+    ///
+    /// # Arguments
+    /// * `ifname` - The name of the host interface (e.g. `can0` or `vcan0`).
+    pub fn new(ifname: &str) -> io::Result<Self> {
+        let name = CString::new(ifname).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+        if name.as_bytes_with_nul().len() > libc::IFNAMSIZ {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        // Safe because the name is a valid C string, and we check the return value.
+        let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safe because we check the return value.
+        let fd = cvt(unsafe {
+            libc::socket(
+                libc::AF_CAN,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                CAN_RAW,
+            )
+        })?;
+        // Safe because we own the new descriptor.
+        let socket = unsafe { File::from_raw_fd(fd) };
+
+        let mut ifr = IfreqMtu {
+            ifr_name: [0; libc::IFNAMSIZ],
+            ifr_mtu: 0,
+            padding: [0; 20],
+        };
+        for (dst, src) in ifr.ifr_name.iter_mut().zip(name.as_bytes()) {
+            *dst = *src as c_char;
+        }
+        // Safe because the kernel only writes the MTU to the request, and we check the return
+        // value.
+        cvt(unsafe { libc::ioctl(fd, SIOCGIFMTU as _, &mut ifr) })?;
+
+        // CAN FD frames can only be exchanged with interfaces which are configured for them,
+        // and the socket option is missing on older kernels.
+        let fd_frames = ifr.ifr_mtu as usize == CANFD_MTU
+            && Self::set_option(&socket, CAN_RAW_FD_FRAMES, 1).is_ok();
+        Self::set_option(&socket, CAN_RAW_ERR_FILTER, CAN_ERR_BUSOFF)?;
+
+        let addr = SockaddrCan {
+            can_family: libc::AF_CAN as libc::sa_family_t,
+            // Interface indices are positive `int` values.
+            can_ifindex: ifindex as c_int,
+            can_addr: [0; 2],
+        };
+        // Safe because `addr` is a valid `sockaddr_can` structure of the given size, and we
+        // check the return value.
+        cvt(unsafe {
+            libc::bind(
+                fd,
+                &addr as *const SockaddrCan as *const libc::sockaddr,
+                size_of::<SockaddrCan>() as socklen_t,
+            )
+        })?;
+
+        Ok(SocketCan { socket, fd_frames })
+    }
+
+    fn set_option<T>(socket: &File, name: c_int, value: T) -> io::Result<()> {
+        // Safe because `value` is valid for the given size, and we check the return value.
+        cvt(unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                SOL_CAN_RAW,
+                name,
+                &value as *const T as *const c_void,
+                size_of::<T>() as socklen_t,
+            )
+        })
+        .map(|_| ())
+    }
+}
+
+impl AsRawFd for SocketCan {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl CanBackend for SocketCan {
+    fn supports_fd(&self) -> bool {
+        self.fd_frames
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn send(&mut self, frame: &CanFrame) -> io::Result<()> {
+        let (raw, len) = encode(frame);
+        let written = self.socket.write(&raw.as_slice()[..len])?;
+        if written != len {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "partial frame write",
+            ));
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self) -> io::Result<Option<CanEvent>> {
+        let mut raw = RawFrame::default();
+        loop {
+            match self.socket.read(raw.as_mut_slice()) {
+                Ok(len) => {
+                    if let Some(event) = decode(&raw, len) {
+                        return Ok(Some(event));
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layouts() {
+        assert_eq!(size_of::<RawFrame>(), CANFD_MTU);
+        assert_eq!(size_of::<SockaddrCan>(), 24);
+        assert_eq!(size_of::<IfreqMtu>(), 40);
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let frame = CanFrame::new(0x123, false, &[1, 2, 3]).unwrap();
+        let (raw, len) = encode(&frame);
+        assert_eq!(len, CAN_MTU);
+        assert_eq!(raw.can_id, 0x123);
+        assert_eq!(raw.as_slice()[4..11], [3, 0, 0, 0, 1, 2, 3]);
+        assert_eq!(decode(&raw, len), Some(CanEvent::Frame(frame)));
+
+        let frame = CanFrame::new_fd(0x1abc_def0, true, &[0xaa; 12]).unwrap();
+        let (raw, len) = encode(&frame);
+        assert_eq!(len, CANFD_MTU);
+        assert_eq!(raw.can_id, 0x1abc_def0 | CAN_EFF_FLAG);
+        assert_eq!(decode(&raw, len), Some(CanEvent::Frame(frame)));
+
+        let frame = CanFrame::new_remote(0x7ff, false, 8).unwrap();
+        let (raw, len) = encode(&frame);
+        assert_eq!(raw.can_id, 0x7ff | CAN_RTR_FLAG);
+        assert_eq!(raw.len, 8);
+        assert_eq!(decode(&raw, len), Some(CanEvent::Frame(frame)));
+
+        // Frames with unexpected sizes or invalid lengths are ignored.
+        assert_eq!(decode(&raw, 8), None);
+        let raw = RawFrame {
+            len: 10,
+            ..Default::default()
+        };
+        assert_eq!(decode(&raw, CANFD_MTU), None);
+
+        // Only bus-off error frames are reported.
+        let mut raw = RawFrame {
+            can_id: CAN_ERR_FLAG | CAN_ERR_BUSOFF,
+            ..Default::default()
+        };
+        assert_eq!(decode(&raw, CAN_MTU), Some(CanEvent::BusOff));
+        raw.can_id = CAN_ERR_FLAG | 0x4;
+        assert_eq!(decode(&raw, CAN_MTU), None);
+    }
+
+    #[test]
+    fn test_missing_interface() {
+        assert!(SocketCan::new("vcan-missing").is_err());
+        assert_eq!(
+            SocketCan::new("a-very-long-interface-name")
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EINVAL)
+        );
+    }
+}
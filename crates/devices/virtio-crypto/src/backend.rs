@@ -0,0 +1,137 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Crypto backend abstraction.
+//!
+//! This module provides the [`CryptoBackend`](trait.CryptoBackend.html) interface, which
+//! performs the cryptographic operations requested by the driver. The device keeps track of the
+//! sessions created by the driver, and the backend provides the state of each session (i.e. the
+//! key schedule), along with the operations. The
+//! [`SoftwareBackend`](../software/struct.SoftwareBackend.html) is built on top of the RustCrypto
+//! crates, and other backends can offload the operations to hardware.
+
+use std::fmt::{self, Display};
+use std::result;
+
+use crate::defs::{
+    VIRTIO_CRYPTO_BADMSG, VIRTIO_CRYPTO_ERR, VIRTIO_CRYPTO_KEY_REJECTED, VIRTIO_CRYPTO_NOTSUPP,
+};
+
+/// Crypto backend errors, which are reported to the driver.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The request parameters are not valid.
+    BadMessage,
+    /// The operation failed.
+    Failed,
+    /// The key is not valid.
+    KeyRejected,
+    /// The algorithm is not supported.
+    NotSupported,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            BadMessage => write!(f, "invalid request parameters"),
+            Failed => write!(f, "the operation failed"),
+            KeyRejected => write!(f, "invalid key"),
+            NotSupported => write!(f, "unsupported algorithm"),
+        }
+    }
+}
+
+impl Error {
+    /// Returns the request status which corresponds to the error.
+    pub fn status(&self) -> u8 {
+        match self {
+            Error::BadMessage => VIRTIO_CRYPTO_BADMSG,
+            Error::Failed => VIRTIO_CRYPTO_ERR,
+            Error::KeyRejected => VIRTIO_CRYPTO_KEY_REJECTED,
+            Error::NotSupported => VIRTIO_CRYPTO_NOTSUPP,
+        }
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// The algorithms supported by a backend, which are advertised through the configuration space.
+/// The algorithm bitmaps hold `1 << algorithm` values.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Capabilities {
+    /// The supported cipher algorithms (`VIRTIO_CRYPTO_CIPHER_*`).
+    pub cipher_algos: u64,
+    /// The supported hash algorithms (`VIRTIO_CRYPTO_HASH_*`).
+    pub hash_algos: u32,
+    /// The supported MAC algorithms (`VIRTIO_CRYPTO_MAC_*`).
+    pub mac_algos: u64,
+    /// The maximum length of cipher keys.
+    pub max_cipher_key_len: u32,
+    /// The maximum length of authentication keys.
+    pub max_auth_key_len: u32,
+}
+
+/// The parameters of a new session.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SessionParams {
+    /// A cipher session.
+    Cipher {
+        /// The cipher algorithm (`VIRTIO_CRYPTO_CIPHER_*`).
+        algo: u32,
+        /// The key.
+        key: Vec<u8>,
+        /// Whether the session encrypts (or decrypts) data.
+        encrypt: bool,
+    },
+    /// A hash session.
+    Hash {
+        /// The hash algorithm (`VIRTIO_CRYPTO_HASH_*`).
+        algo: u32,
+        /// The length of the results.
+        result_len: u32,
+    },
+    /// A MAC session.
+    Mac {
+        /// The MAC algorithm (`VIRTIO_CRYPTO_MAC_*`).
+        algo: u32,
+        /// The length of the results.
+        result_len: u32,
+        /// The authentication key.
+        key: Vec<u8>,
+    },
+}
+
+/// The provider of the cryptographic operations of the device.
+pub trait CryptoBackend {
+    /// The state of a session.
+    type Session;
+
+    /// Returns the algorithms supported by the backend.
+    fn capabilities(&self) -> Capabilities;
+
+    /// Creates a new session.
+    ///
+    /// # Arguments
+    /// * `params` - The parameters of the session.
+    fn create_session(&mut self, params: &SessionParams) -> Result<Self::Session>;
+
+    /// Encrypts or decrypts data in place, in the direction of the cipher session.
+    ///
+    /// # Arguments
+    /// * `session` - The cipher session.
+    /// * `iv` - The initialization vector.
+    /// * `data` - The source data, which is replaced with the destination data.
+    fn cipher(&mut self, session: &mut Self::Session, iv: &[u8], data: &mut [u8]) -> Result<()>;
+
+    /// Computes the hash or the MAC of data.
+    ///
+    /// # Arguments
+    /// * `session` - The hash or MAC session.
+    /// * `src` - The source data.
+    /// * `result` - The buffer for the result, which can be shorter than the full result.
+    fn digest(&mut self, session: &mut Self::Session, src: &[u8], result: &mut [u8]) -> Result<()>;
+}
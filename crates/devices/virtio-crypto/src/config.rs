@@ -0,0 +1,94 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Virtio crypto device configuration space abstraction.
+//!
+//! This module provides the [`ConfigSpace`](struct.ConfigSpace.html) abstraction, which mirrors
+//! the `virtio_crypto_config` structure from the virtio specification. It advertises the number
+//! of data queues, along with the services and algorithms supported by the device.
+
+use std::mem::{offset_of, size_of};
+
+use vm_memory::ByteValued;
+
+/// The crypto device configuration space layout, as defined by the virtio specification.
+///
+/// All fields are expected to hold little endian values, which is always the case on the
+/// platforms that are currently supported (`x86_64` and `aarch64`).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C, packed)]
+pub struct ConfigSpace {
+    /// The device status (`VIRTIO_CRYPTO_S_HW_READY`).
+    pub status: u32,
+    /// The number of data queues.
+    pub max_dataqueues: u32,
+    /// The supported services, as a bitmap of `1 << VIRTIO_CRYPTO_SERVICE_*` values.
+    pub crypto_services: u32,
+    /// The lower half of the supported cipher algorithms bitmap.
+    pub cipher_algo_l: u32,
+    /// The upper half of the supported cipher algorithms bitmap.
+    pub cipher_algo_h: u32,
+    /// The supported hash algorithms bitmap.
+    pub hash_algo: u32,
+    /// The lower half of the supported MAC algorithms bitmap.
+    pub mac_algo_l: u32,
+    /// The upper half of the supported MAC algorithms bitmap.
+    pub mac_algo_h: u32,
+    /// The supported AEAD algorithms bitmap.
+    pub aead_algo: u32,
+    /// The maximum length of cipher keys.
+    pub max_cipher_key_len: u32,
+    /// The maximum length of authentication keys.
+    pub max_auth_key_len: u32,
+    /// The supported asymmetric cipher algorithms bitmap.
+    pub akcipher_algo: u32,
+    /// The maximum amount of data in a request.
+    pub max_size: u64,
+}
+
+// Safe because ConfigSpace only contains plain data, and there's no padding between (or after)
+// the fields since the structure is packed.
+unsafe impl ByteValued for ConfigSpace {}
+
+impl ConfigSpace {
+    /// The size of the crypto device configuration space.
+    pub const LEN: usize = size_of::<ConfigSpace>();
+    /// The offset of the `status` field.
+    pub const STATUS_OFFSET: usize = offset_of!(ConfigSpace, status);
+    /// The offset of the `max_dataqueues` field.
+    pub const MAX_DATAQUEUES_OFFSET: usize = offset_of!(ConfigSpace, max_dataqueues);
+    /// The offset of the `crypto_services` field.
+    pub const CRYPTO_SERVICES_OFFSET: usize = offset_of!(ConfigSpace, crypto_services);
+    /// The offset of the `max_size` field.
+    pub const MAX_SIZE_OFFSET: usize = offset_of!(ConfigSpace, max_size);
+}
+
+impl From<ConfigSpace> for Vec<u8> {
+    fn from(config: ConfigSpace) -> Self {
+        config.as_slice().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_space() {
+        assert_eq!(ConfigSpace::LEN, 56);
+        assert_eq!(ConfigSpace::STATUS_OFFSET, 0);
+        assert_eq!(ConfigSpace::MAX_DATAQUEUES_OFFSET, 4);
+        assert_eq!(ConfigSpace::CRYPTO_SERVICES_OFFSET, 8);
+        assert_eq!(ConfigSpace::MAX_SIZE_OFFSET, 48);
+
+        let config = ConfigSpace {
+            max_dataqueues: 2,
+            max_size: 0x1000,
+            ..Default::default()
+        };
+        let bytes: Vec<u8> = config.into();
+        assert_eq!(bytes[4..8], [2, 0, 0, 0]);
+        assert_eq!(bytes[48..], [0, 0x10, 0, 0, 0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+// TODO: Move the generic device type definition to the vm-virtio crate proper.
+/// The virtio device type of crypto devices.
+pub const VIRTIO_ID_CRYPTO: u32 = 20;
+
+/// The default (and maximum) size of the queues.
+pub const DEFAULT_QUEUE_SIZE: u16 = 256;
+
+// Device status (from `linux/virtio_crypto.h`).
+/// The device is ready to process requests.
+pub const VIRTIO_CRYPTO_S_HW_READY: u32 = 1;
+
+// Services.
+/// Symmetric ciphers.
+pub const VIRTIO_CRYPTO_SERVICE_CIPHER: u32 = 0;
+/// Hash functions.
+pub const VIRTIO_CRYPTO_SERVICE_HASH: u32 = 1;
+/// Message authentication codes.
+pub const VIRTIO_CRYPTO_SERVICE_MAC: u32 = 2;
+/// Authenticated encryption with associated data.
+pub const VIRTIO_CRYPTO_SERVICE_AEAD: u32 = 3;
+
+// Cipher algorithms.
+/// AES in ECB mode.
+pub const VIRTIO_CRYPTO_CIPHER_AES_ECB: u32 = 2;
+/// AES in CBC mode.
+pub const VIRTIO_CRYPTO_CIPHER_AES_CBC: u32 = 3;
+/// AES in CTR mode.
+pub const VIRTIO_CRYPTO_CIPHER_AES_CTR: u32 = 4;
+
+// Hash algorithms.
+/// SHA-1.
+pub const VIRTIO_CRYPTO_HASH_SHA1: u32 = 2;
+/// SHA-224.
+pub const VIRTIO_CRYPTO_HASH_SHA_224: u32 = 3;
+/// SHA-256.
+pub const VIRTIO_CRYPTO_HASH_SHA_256: u32 = 4;
+/// SHA-384.
+pub const VIRTIO_CRYPTO_HASH_SHA_384: u32 = 5;
+/// SHA-512.
+pub const VIRTIO_CRYPTO_HASH_SHA_512: u32 = 6;
+
+// MAC algorithms.
+/// HMAC with SHA-1.
+pub const VIRTIO_CRYPTO_MAC_HMAC_SHA1: u32 = 2;
+/// HMAC with SHA-256.
+pub const VIRTIO_CRYPTO_MAC_HMAC_SHA_256: u32 = 4;
+/// HMAC with SHA-512.
+pub const VIRTIO_CRYPTO_MAC_HMAC_SHA_512: u32 = 6;
+
+// Control queue operations, which are `(service << 8) | op` values.
+/// Creates a cipher session.
+pub const VIRTIO_CRYPTO_CIPHER_CREATE_SESSION: u32 = 0x02;
+/// Destroys a cipher session.
+pub const VIRTIO_CRYPTO_CIPHER_DESTROY_SESSION: u32 = 0x03;
+/// Creates a hash session.
+pub const VIRTIO_CRYPTO_HASH_CREATE_SESSION: u32 = 0x102;
+/// Destroys a hash session.
+pub const VIRTIO_CRYPTO_HASH_DESTROY_SESSION: u32 = 0x103;
+/// Creates a MAC session.
+pub const VIRTIO_CRYPTO_MAC_CREATE_SESSION: u32 = 0x202;
+/// Destroys a MAC session.
+pub const VIRTIO_CRYPTO_MAC_DESTROY_SESSION: u32 = 0x203;
+/// Creates an AEAD session.
+pub const VIRTIO_CRYPTO_AEAD_CREATE_SESSION: u32 = 0x302;
+/// Destroys an AEAD session.
+pub const VIRTIO_CRYPTO_AEAD_DESTROY_SESSION: u32 = 0x303;
+
+// Data queue operations.
+/// Encrypts data with a cipher session.
+pub const VIRTIO_CRYPTO_CIPHER_ENCRYPT: u32 = 0x00;
+/// Decrypts data with a cipher session.
+pub const VIRTIO_CRYPTO_CIPHER_DECRYPT: u32 = 0x01;
+/// Hashes data with a hash session.
+pub const VIRTIO_CRYPTO_HASH: u32 = 0x100;
+/// Computes the MAC of data with a MAC session.
+pub const VIRTIO_CRYPTO_MAC: u32 = 0x200;
+
+// Symmetric operation types.
+/// Plain cipher operation.
+pub const VIRTIO_CRYPTO_SYM_OP_CIPHER: u32 = 1;
+
+// Cipher session directions.
+/// The session encrypts data.
+pub const VIRTIO_CRYPTO_OP_ENCRYPT: u32 = 1;
+/// The session decrypts data.
+pub const VIRTIO_CRYPTO_OP_DECRYPT: u32 = 2;
+
+// Request status values.
+/// Success.
+pub const VIRTIO_CRYPTO_OK: u8 = 0;
+/// Any failure not covered by the other values.
+pub const VIRTIO_CRYPTO_ERR: u8 = 1;
+/// Invalid request.
+pub const VIRTIO_CRYPTO_BADMSG: u8 = 2;
+/// Unsupported service or algorithm.
+pub const VIRTIO_CRYPTO_NOTSUPP: u8 = 3;
+/// Invalid session identifier.
+pub const VIRTIO_CRYPTO_INVSESS: u8 = 4;
+/// No space left for new sessions.
+pub const VIRTIO_CRYPTO_NOSPC: u8 = 5;
+/// The key was rejected.
+pub const VIRTIO_CRYPTO_KEY_REJECTED: u8 = 6;
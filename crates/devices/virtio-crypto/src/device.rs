@@ -0,0 +1,1066 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A reference virtio crypto device implementation.
+//!
+//! This module provides the following abstractions:
+//!
+//! - [`Crypto`](struct.Crypto.html) which ties together the generic device building blocks
+//!   (`VirtioConfig` and the `VirtioDevice`/`VirtioMmioDevice` interfaces) with the crypto
+//!   specific ones (the configuration space, the requests and the
+//!   [`CryptoBackend`](../backend/trait.CryptoBackend.html) interface).
+//! - [`CryptoBuilder`](struct.CryptoBuilder.html) which configures and creates a `Crypto`
+//!   device.
+//!
+//! The device has one or more data queues, followed by the control queue. The driver creates
+//! and destroys sessions through the control queue, and then submits cipher, hash and MAC
+//! requests for those sessions through the data queues. The cipher, hash and MAC services are
+//! supported, as long as the backend provides at least one algorithm for them.
+//!
+//! The device doesn't register any events by itself: the VMM is expected to rely on the
+//! `VirtioMmioDevice::queue_notify` implementation (or call the `process_*` methods directly)
+//! when the driver notifies a queue.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::result;
+
+use log::{error, warn};
+
+use vm_memory::{ByteValued, GuestAddressSpace};
+
+use virtio_device::{
+    SignalUsedQueue, VirtioConfig, VirtioDeviceActions, VirtioDeviceCommon, VirtioMmioDevice,
+};
+use virtio_queue::areas::{self, areas_len, read_areas, write_areas, Area};
+use virtio_queue::{self, Queue};
+
+use crate::backend::{self, CryptoBackend, SessionParams};
+use crate::config::ConfigSpace;
+use crate::defs::*;
+use crate::request::{
+    CipherPara, CipherSessionPara, CtrlHeader, HashPara, HashSessionPara, MacSessionPara, OpHeader,
+    SessionInput, REQ_LEN, SYM_CREATE_SESSION_OP_TYPE_OFFSET, SYM_DATA_OP_TYPE_OFFSET,
+};
+
+pub use crate::defs::{DEFAULT_QUEUE_SIZE, VIRTIO_ID_CRYPTO};
+
+// TODO: Move the generic feature definitions to the vm-virtio crate proper.
+const VIRTIO_F_VERSION_1: u64 = 32;
+const VIRTIO_F_RING_EVENT_IDX: u64 = 29;
+
+/// The default limit for the amount of data in a request.
+pub const DEFAULT_MAX_SIZE: u64 = 1 << 20;
+/// The maximum number of sessions which are open at the same time.
+pub const MAX_SESSIONS: usize = 1024;
+/// The maximum number of data queues.
+pub const MAX_DATA_QUEUES: u16 = 64;
+
+/// Crypto device errors.
+#[derive(Debug)]
+pub enum Error {
+    /// The device was already activated.
+    AlreadyActivated,
+    /// The queues are not configured properly.
+    InvalidQueues,
+    /// The queue index is not valid, or the device is not activated.
+    InvalidQueueIndex(u16),
+    /// The number of data queues is zero or too large.
+    InvalidNumDataQueues(u16),
+    /// Failed to access a queue.
+    Queue(virtio_queue::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            AlreadyActivated => write!(f, "the device is already activated"),
+            InvalidQueues => write!(f, "invalid queue configuration"),
+            InvalidQueueIndex(index) => write!(f, "invalid or inactive queue {}", index),
+            InvalidNumDataQueues(num) => write!(f, "invalid number of data queues {}", num),
+            Queue(ref err) => write!(f, "failed to access the queue: {}", err),
+        }
+    }
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+// The outcome of a request, which is either successful or has a `VIRTIO_CRYPTO_*` status.
+type Status<T> = result::Result<T, u8>;
+
+impl From<backend::Error> for u8 {
+    fn from(e: backend::Error) -> Self {
+        e.status()
+    }
+}
+
+// Returns the status of a request which doesn't fit in its descriptor chain.
+fn bad_msg(e: areas::Error) -> u8 {
+    warn!("invalid crypto request: {}", e);
+    VIRTIO_CRYPTO_BADMSG
+}
+
+// A session created by the driver.
+#[derive(Debug)]
+struct Session<T> {
+    // The service of the session (`VIRTIO_CRYPTO_SERVICE_*`).
+    service: u32,
+    // Whether a cipher session encrypts (or decrypts) data.
+    encrypt: bool,
+    state: T,
+}
+
+// Returns the service of a control or data request opcode.
+fn opcode_service(opcode: u32) -> u32 {
+    opcode >> 8
+}
+
+// Reads `len` bytes which start at `offset` in the concatenation of `areas`.
+fn read_bytes<M: vm_memory::GuestMemory>(
+    mem: &M,
+    areas: &[Area],
+    offset: usize,
+    len: usize,
+) -> Status<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    read_areas(mem, areas, offset, &mut buf).map_err(bad_msg)?;
+    Ok(buf)
+}
+
+// Reads an object which starts at `offset` in the concatenation of `areas`.
+fn read_obj<M: vm_memory::GuestMemory, T: ByteValued>(
+    mem: &M,
+    areas: &[Area],
+    offset: usize,
+) -> Status<T> {
+    areas::read_obj(mem, areas, offset).map_err(bad_msg)
+}
+
+/// Configures and builds a `Crypto` device.
+///
+/// # Example
+///
+/// ```rust
+/// # use std::sync::Arc;
+/// # use virtio_crypto::device::CryptoBuilder;
+/// # use virtio_crypto::software::SoftwareBackend;
+/// # use vm_memory::{GuestAddress, GuestMemoryMmap};
+/// # use vmm_sys_util::eventfd::EventFd;
+/// let mem = Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+///
+/// let crypto = CryptoBuilder::new(mem, SoftwareBackend::new(), EventFd::new(0).unwrap())
+///     .with_num_data_queues(2)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug)]
+pub struct CryptoBuilder<M: GuestAddressSpace, B: CryptoBackend, S: SignalUsedQueue> {
+    mem: M,
+    backend: B,
+    driver_notify: S,
+    num_data_queues: u16,
+    queue_size: u16,
+    max_size: u64,
+}
+
+impl<M, B, S> CryptoBuilder<M, B, S>
+where
+    M: GuestAddressSpace + Clone,
+    B: CryptoBackend,
+    S: SignalUsedQueue,
+{
+    /// Creates a new `CryptoBuilder`.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    /// * `backend` - The provider of the cryptographic operations.
+    /// * `driver_notify` - The object used for notifying the driver about used buffers.
+    pub fn new(mem: M, backend: B, driver_notify: S) -> Self {
+        CryptoBuilder {
+            mem,
+            backend,
+            driver_notify,
+            num_data_queues: 1,
+            queue_size: DEFAULT_QUEUE_SIZE,
+            max_size: DEFAULT_MAX_SIZE,
+        }
+    }
+
+    /// Sets the number of data queues (there's a single data queue by default).
+    ///
+    /// # Arguments
+    /// * `num_data_queues` - The number of data queues.
+    pub fn with_num_data_queues(mut self, num_data_queues: u16) -> Self {
+        self.num_data_queues = num_data_queues;
+        self
+    }
+
+    /// Sets the maximum size of the queues.
+    ///
+    /// # Arguments
+    /// * `queue_size` - The maximum queue size.
+    pub fn with_queue_size(mut self, queue_size: u16) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Sets the limit for the amount of data in a request, which is advertised to the driver.
+    ///
+    /// # Arguments
+    /// * `max_size` - The limit, in bytes.
+    pub fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Builds the `Crypto` device.
+    pub fn build(self) -> Result<Crypto<M, B, S>> {
+        if self.num_data_queues == 0 || self.num_data_queues > MAX_DATA_QUEUES {
+            return Err(Error::InvalidNumDataQueues(self.num_data_queues));
+        }
+
+        let device_features = (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_RING_EVENT_IDX);
+        let queues = (0..=self.num_data_queues)
+            .map(|_| Queue::new(self.mem.clone(), self.queue_size))
+            .collect();
+
+        let caps = self.backend.capabilities();
+        let mut services = 0;
+        if caps.cipher_algos != 0 {
+            services |= 1 << VIRTIO_CRYPTO_SERVICE_CIPHER;
+        }
+        if caps.hash_algos != 0 {
+            services |= 1 << VIRTIO_CRYPTO_SERVICE_HASH;
+        }
+        if caps.mac_algos != 0 {
+            services |= 1 << VIRTIO_CRYPTO_SERVICE_MAC;
+        }
+        let config_space = ConfigSpace {
+            status: VIRTIO_CRYPTO_S_HW_READY,
+            max_dataqueues: u32::from(self.num_data_queues),
+            crypto_services: services,
+            cipher_algo_l: caps.cipher_algos as u32,
+            cipher_algo_h: (caps.cipher_algos >> 32) as u32,
+            hash_algo: caps.hash_algos,
+            mac_algo_l: caps.mac_algos as u32,
+            mac_algo_h: (caps.mac_algos >> 32) as u32,
+            max_cipher_key_len: caps.max_cipher_key_len,
+            max_auth_key_len: caps.max_auth_key_len,
+            max_size: self.max_size,
+            ..Default::default()
+        };
+
+        Ok(Crypto {
+            cfg: VirtioConfig::new(device_features, queues, config_space.into()),
+            backend: self.backend,
+            driver_notify: self.driver_notify,
+            num_data_queues: self.num_data_queues,
+            max_size: self.max_size,
+            max_cipher_key_len: caps.max_cipher_key_len,
+            max_auth_key_len: caps.max_auth_key_len,
+            sessions: BTreeMap::new(),
+            next_session_id: 0,
+        })
+    }
+}
+
+/// A virtio crypto device.
+#[derive(Debug, VirtioDeviceCommon)]
+#[virtio(device_type = VIRTIO_ID_CRYPTO)]
+pub struct Crypto<M: GuestAddressSpace, B: CryptoBackend, S: SignalUsedQueue> {
+    #[virtio(config)]
+    cfg: VirtioConfig<M>,
+    backend: B,
+    driver_notify: S,
+    num_data_queues: u16,
+    max_size: u64,
+    max_cipher_key_len: u32,
+    max_auth_key_len: u32,
+    sessions: BTreeMap<u64, Session<B::Session>>,
+    next_session_id: u64,
+}
+
+impl<M, B, S> Crypto<M, B, S>
+where
+    M: GuestAddressSpace,
+    B: CryptoBackend,
+    S: SignalUsedQueue,
+{
+    /// Returns whether the device is activated.
+    pub fn is_activated(&self) -> bool {
+        self.cfg.device_activated
+    }
+
+    /// Returns a reference to the backend.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Returns the index of the control queue, which comes after the data queues.
+    pub fn control_queue_index(&self) -> u16 {
+        self.num_data_queues
+    }
+
+    /// Returns the number of open sessions.
+    pub fn num_sessions(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Processes the requests from the control queue. This has to be called when the driver
+    /// notifies the control queue.
+    pub fn process_control_queue(&mut self) -> Result<()> {
+        self.process_queue(self.num_data_queues)
+    }
+
+    /// Processes the requests from a data queue. This has to be called when the driver
+    /// notifies a data queue.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the data queue.
+    pub fn process_data_queue(&mut self, index: u16) -> Result<()> {
+        if index >= self.num_data_queues {
+            return Err(Error::InvalidQueueIndex(index));
+        }
+        self.process_queue(index)
+    }
+
+    fn process_queue(&mut self, index: u16) -> Result<()> {
+        if !self.cfg.device_activated {
+            return Err(Error::InvalidQueueIndex(index));
+        }
+        while let Some(mut chain) = self.cfg.queues[usize::from(index)].iter()?.next() {
+            let len = match areas::split_chain(&mut chain) {
+                Ok((readable, writable)) if index == self.num_data_queues => {
+                    self.handle_control_request(chain.memory(), &readable, &writable)
+                }
+                Ok((readable, writable)) => {
+                    self.handle_data_request(chain.memory(), &readable, &writable)
+                }
+                Err(e) => {
+                    warn!("invalid crypto request: {}", e);
+                    0
+                }
+            };
+
+            let queue = &mut self.cfg.queues[usize::from(index)];
+            queue.add_used(chain.head_index(), len)?;
+            if queue.needs_notification()? {
+                self.cfg.signal_used_queue();
+                self.driver_notify.signal_used_queue(index);
+            }
+        }
+        Ok(())
+    }
+
+    // Handles a control request, and returns the number of bytes written to the device-writable
+    // part of the buffer.
+    fn handle_control_request(&mut self, mem: &M::M, readable: &[Area], writable: &[Area]) -> u32 {
+        let hdr = match read_obj::<_, CtrlHeader>(mem, readable, 0) {
+            Ok(hdr) => hdr,
+            Err(e) => {
+                warn!("invalid crypto control request: {}", e);
+                return 0;
+            }
+        };
+
+        let result = match hdr.opcode {
+            VIRTIO_CRYPTO_CIPHER_CREATE_SESSION
+            | VIRTIO_CRYPTO_HASH_CREATE_SESSION
+            | VIRTIO_CRYPTO_MAC_CREATE_SESSION
+            | VIRTIO_CRYPTO_AEAD_CREATE_SESSION => {
+                let input = match self.create_session(mem, readable, &hdr) {
+                    Ok(session_id) => SessionInput {
+                        session_id,
+                        status: u32::from(VIRTIO_CRYPTO_OK),
+                        ..Default::default()
+                    },
+                    Err(status) => SessionInput {
+                        status: u32::from(status),
+                        ..Default::default()
+                    },
+                };
+                write_areas(mem, writable, 0, input.as_slice()).map(|_| input.as_slice().len())
+            }
+            opcode => {
+                let status = match opcode {
+                    VIRTIO_CRYPTO_CIPHER_DESTROY_SESSION
+                    | VIRTIO_CRYPTO_HASH_DESTROY_SESSION
+                    | VIRTIO_CRYPTO_MAC_DESTROY_SESSION
+                    | VIRTIO_CRYPTO_AEAD_DESTROY_SESSION => {
+                        self.destroy_session(mem, readable, opcode_service(opcode))
+                    }
+                    _ => {
+                        warn!("unsupported crypto control opcode 0x{:x}", opcode);
+                        Err(VIRTIO_CRYPTO_NOTSUPP)
+                    }
+                }
+                .map_or_else(|status| status, |_| VIRTIO_CRYPTO_OK);
+                write_areas(mem, writable, 0, &[status]).map(|_| 1)
+            }
+        };
+
+        // The length of the response is at most `size_of::<SessionInput>()`.
+        result.map_or_else(
+            |e| {
+                warn!("failed to write crypto control response: {}", e);
+                0
+            },
+            |len| len as u32,
+        )
+    }
+
+    fn create_session(&mut self, mem: &M::M, readable: &[Area], hdr: &CtrlHeader) -> Status<u64> {
+        if self.sessions.len() >= MAX_SESSIONS {
+            return Err(VIRTIO_CRYPTO_NOSPC);
+        }
+
+        let (encrypt, params) = match hdr.opcode {
+            VIRTIO_CRYPTO_CIPHER_CREATE_SESSION => {
+                // Algorithm chaining is not supported.
+                let op_type: u32 = read_obj(mem, readable, SYM_CREATE_SESSION_OP_TYPE_OFFSET)?;
+                if op_type != VIRTIO_CRYPTO_SYM_OP_CIPHER {
+                    return Err(VIRTIO_CRYPTO_NOTSUPP);
+                }
+                let para: CipherSessionPara = read_obj(mem, readable, CtrlHeader::LEN)?;
+                if para.keylen > self.max_cipher_key_len {
+                    return Err(VIRTIO_CRYPTO_KEY_REJECTED);
+                }
+                let encrypt = match para.op {
+                    VIRTIO_CRYPTO_OP_ENCRYPT => true,
+                    VIRTIO_CRYPTO_OP_DECRYPT => false,
+                    _ => return Err(VIRTIO_CRYPTO_BADMSG),
+                };
+                let key = read_bytes(mem, readable, REQ_LEN, para.keylen as usize)?;
+                let params = SessionParams::Cipher {
+                    algo: para.algo,
+                    key,
+                    encrypt,
+                };
+                (encrypt, params)
+            }
+            VIRTIO_CRYPTO_HASH_CREATE_SESSION => {
+                let para: HashSessionPara = read_obj(mem, readable, CtrlHeader::LEN)?;
+                let params = SessionParams::Hash {
+                    algo: para.algo,
+                    result_len: para.hash_result_len,
+                };
+                (false, params)
+            }
+            VIRTIO_CRYPTO_MAC_CREATE_SESSION => {
+                let para: MacSessionPara = read_obj(mem, readable, CtrlHeader::LEN)?;
+                if para.auth_key_len > self.max_auth_key_len {
+                    return Err(VIRTIO_CRYPTO_KEY_REJECTED);
+                }
+                let key = read_bytes(mem, readable, REQ_LEN, para.auth_key_len as usize)?;
+                let params = SessionParams::Mac {
+                    algo: para.algo,
+                    result_len: para.hash_result_len,
+                    key,
+                };
+                (false, params)
+            }
+            _ => return Err(VIRTIO_CRYPTO_NOTSUPP),
+        };
+
+        let state = self.backend.create_session(&params)?;
+        let session_id = self.next_session_id;
+        self.next_session_id += 1;
+        self.sessions.insert(
+            session_id,
+            Session {
+                service: opcode_service(hdr.opcode),
+                encrypt,
+                state,
+            },
+        );
+        Ok(session_id)
+    }
+
+    fn destroy_session(&mut self, mem: &M::M, readable: &[Area], service: u32) -> Status<()> {
+        let session_id: u64 = read_obj(mem, readable, CtrlHeader::LEN)?;
+        match self.sessions.get(&session_id) {
+            Some(session) if session.service == service => {
+                self.sessions.remove(&session_id);
+                Ok(())
+            }
+            _ => Err(VIRTIO_CRYPTO_INVSESS),
+        }
+    }
+
+    // Handles a data request, and returns the number of bytes written to the device-writable
+    // part of the buffer, which ends with the status of the request.
+    fn handle_data_request(&mut self, mem: &M::M, readable: &[Area], writable: &[Area]) -> u32 {
+        let writable_len = areas_len(writable);
+        if writable_len == 0 {
+            warn!("crypto data request without status");
+            return 0;
+        }
+        let status_offset = writable_len - 1;
+
+        let status = match self.execute_data_request(mem, readable) {
+            Ok(output) if output.len() > status_offset => VIRTIO_CRYPTO_BADMSG,
+            Ok(output) => write_areas(mem, writable, 0, &output)
+                .map_or(VIRTIO_CRYPTO_ERR, |_| VIRTIO_CRYPTO_OK),
+            Err(status) => status,
+        };
+
+        match write_areas(mem, writable, status_offset, &[status]) {
+            // The length of the descriptor chain fits in an `u32`.
+            Ok(()) => writable_len as u32,
+            Err(e) => {
+                warn!("failed to write crypto data response: {}", e);
+                0
+            }
+        }
+    }
+
+    // Executes a data request, and returns its output.
+    fn execute_data_request(&mut self, mem: &M::M, readable: &[Area]) -> Status<Vec<u8>> {
+        let hdr: OpHeader = read_obj(mem, readable, 0)?;
+        let session = self
+            .sessions
+            .get_mut(&{ hdr.session_id })
+            .ok_or(VIRTIO_CRYPTO_INVSESS)?;
+        if session.service != opcode_service(hdr.opcode) {
+            return Err(VIRTIO_CRYPTO_INVSESS);
+        }
+
+        match hdr.opcode {
+            VIRTIO_CRYPTO_CIPHER_ENCRYPT | VIRTIO_CRYPTO_CIPHER_DECRYPT => {
+                let op_type: u32 = read_obj(mem, readable, SYM_DATA_OP_TYPE_OFFSET)?;
+                if op_type != VIRTIO_CRYPTO_SYM_OP_CIPHER {
+                    return Err(VIRTIO_CRYPTO_NOTSUPP);
+                }
+                if session.encrypt != (hdr.opcode == VIRTIO_CRYPTO_CIPHER_ENCRYPT) {
+                    return Err(VIRTIO_CRYPTO_BADMSG);
+                }
+                let para: CipherPara = read_obj(mem, readable, OpHeader::LEN)?;
+                if u64::from(para.src_data_len) > self.max_size
+                    || para.dst_data_len != para.src_data_len
+                    || para.iv_len > self.max_cipher_key_len
+                {
+                    return Err(VIRTIO_CRYPTO_BADMSG);
+                }
+                let iv = read_bytes(mem, readable, REQ_LEN, para.iv_len as usize)?;
+                let mut data = read_bytes(
+                    mem,
+                    readable,
+                    REQ_LEN + iv.len(),
+                    para.src_data_len as usize,
+                )?;
+                self.backend.cipher(&mut session.state, &iv, &mut data)?;
+                Ok(data)
+            }
+            VIRTIO_CRYPTO_HASH | VIRTIO_CRYPTO_MAC => {
+                let para: HashPara = read_obj(mem, readable, OpHeader::LEN)?;
+                if u64::from(para.src_data_len) > self.max_size {
+                    return Err(VIRTIO_CRYPTO_BADMSG);
+                }
+                let src = read_bytes(mem, readable, REQ_LEN, para.src_data_len as usize)?;
+                let mut result = vec![0u8; para.hash_result_len as usize];
+                self.backend.digest(&mut session.state, &src, &mut result)?;
+                Ok(result)
+            }
+            opcode => {
+                warn!("unsupported crypto data opcode 0x{:x}", opcode);
+                Err(VIRTIO_CRYPTO_NOTSUPP)
+            }
+        }
+    }
+}
+
+impl<M, B, S> VirtioDeviceActions for Crypto<M, B, S>
+where
+    M: GuestAddressSpace,
+    B: CryptoBackend,
+    S: SignalUsedQueue,
+{
+    type E = Error;
+
+    fn activate(&mut self) -> Result<()> {
+        if self.cfg.device_activated {
+            return Err(Error::AlreadyActivated);
+        }
+        if !self.cfg.queues.iter().all(Queue::is_valid) {
+            return Err(Error::InvalidQueues);
+        }
+        self.cfg.device_activated = true;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.sessions.clear();
+
+        let cfg = &mut self.cfg;
+        cfg.driver_features = 0;
+        cfg.device_features_select = 0;
+        cfg.driver_features_select = 0;
+        cfg.device_status = 0;
+        cfg.queue_select = 0;
+        cfg.queues.iter_mut().for_each(Queue::reset);
+        cfg.device_activated = false;
+        cfg.reset_interrupt_status();
+        Ok(())
+    }
+}
+
+impl<M, B, S> VirtioMmioDevice<M> for Crypto<M, B, S>
+where
+    M: GuestAddressSpace + 'static,
+    B: CryptoBackend,
+    S: SignalUsedQueue,
+{
+    fn queue_notify(&mut self, val: u32) {
+        // Queue indices always fit in an `u16`.
+        let index = val as u16;
+        let result = if index == self.num_data_queues {
+            self.process_control_queue()
+        } else {
+            self.process_data_queue(index)
+        };
+        if let Err(e) = result {
+            error!("failed to process queue {}: {}", val, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap};
+    use vmm_sys_util::eventfd::EventFd;
+
+    use virtio_device::mock::activate;
+    use virtio_device::VirtioDevice;
+    use virtio_queue::mock::{AttackPattern, VirtQueue};
+    use virtio_queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    use crate::backend::{Capabilities, Error as BackendError};
+
+    type Mem = Arc<GuestMemoryMmap>;
+
+    // Xors the data with the key for ciphers, and sums the bytes of the data (and of the key)
+    // for digests.
+    #[derive(Debug, Default)]
+    struct TestBackend;
+
+    impl CryptoBackend for TestBackend {
+        type Session = SessionParams;
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities {
+                cipher_algos: 1 << VIRTIO_CRYPTO_CIPHER_AES_CTR,
+                hash_algos: 1 << VIRTIO_CRYPTO_HASH_SHA_256,
+                mac_algos: 0,
+                max_cipher_key_len: 32,
+                max_auth_key_len: 64,
+            }
+        }
+
+        fn create_session(&mut self, params: &SessionParams) -> backend::Result<SessionParams> {
+            match params {
+                SessionParams::Cipher { key, .. } if key.is_empty() => {
+                    Err(BackendError::KeyRejected)
+                }
+                _ => Ok(params.clone()),
+            }
+        }
+
+        fn cipher(
+            &mut self,
+            session: &mut SessionParams,
+            iv: &[u8],
+            data: &mut [u8],
+        ) -> backend::Result<()> {
+            match session {
+                SessionParams::Cipher { key, .. } => {
+                    for (i, b) in data.iter_mut().enumerate() {
+                        *b ^= key[i % key.len()] ^ iv.get(i).copied().unwrap_or(0);
+                    }
+                    Ok(())
+                }
+                _ => Err(BackendError::BadMessage),
+            }
+        }
+
+        fn digest(
+            &mut self,
+            session: &mut SessionParams,
+            src: &[u8],
+            result: &mut [u8],
+        ) -> backend::Result<()> {
+            let key: &[u8] = match session {
+                SessionParams::Hash { .. } => &[],
+                SessionParams::Mac { key, .. } => key,
+                _ => return Err(BackendError::BadMessage),
+            };
+            let sum = src
+                .iter()
+                .chain(key)
+                .fold(0u8, |acc, b| acc.wrapping_add(*b));
+            result.iter_mut().for_each(|b| *b = sum);
+            Ok(())
+        }
+    }
+
+    type TestCrypto = Crypto<Mem, TestBackend, EventFd>;
+
+    fn builder(mem: &Mem) -> CryptoBuilder<Mem, TestBackend, EventFd> {
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        CryptoBuilder::new(mem.clone(), TestBackend, evt).with_queue_size(16)
+    }
+
+    fn used_len(mem: &GuestMemoryMmap, vq: &VirtQueue, index: u64) -> u32 {
+        // The used ring starts after the flags and the index, and each element holds the head
+        // index followed by the length.
+        let addr = vq.used_start().unchecked_add(4 + index * 8 + 4);
+        mem.read_obj(addr).unwrap()
+    }
+
+    // Sends a request through a queue, and returns the bytes written by the device.
+    fn send(
+        crypto: &mut TestCrypto,
+        mem: &GuestMemoryMmap,
+        vq: &VirtQueue,
+        index: u16,
+        req: &[u8],
+        writable_len: u32,
+    ) -> Vec<u8> {
+        let avail = vq.avail.idx().load();
+        let head = (avail * 2) % vq.size();
+        mem.write_slice(req, GuestAddress(0x1_0000)).unwrap();
+        mem.write_slice(&vec![0xff; writable_len as usize], GuestAddress(0x2_0000))
+            .unwrap();
+        vq.dtable(head)
+            .set(0x1_0000, req.len() as u32, VIRTQ_DESC_F_NEXT, head + 1);
+        vq.dtable(head + 1)
+            .set(0x2_0000, writable_len, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring(avail % vq.size()).store(head);
+        vq.avail.idx().store(avail + 1);
+
+        crypto.queue_notify(u32::from(index));
+        assert_eq!(vq.used.idx().load(), avail + 1);
+        let len = used_len(mem, vq, u64::from(avail % vq.size()));
+        let mut buf = vec![0u8; len as usize];
+        mem.read_slice(&mut buf, GuestAddress(0x2_0000)).unwrap();
+        buf
+    }
+
+    // Returns the bytes of a request, with the fixed size part padded to `REQ_LEN` bytes.
+    fn request<H: ByteValued, P: ByteValued>(
+        hdr: &H,
+        para: &P,
+        op_type: u32,
+        extra: &[u8],
+    ) -> Vec<u8> {
+        let mut bytes = hdr.as_slice().to_vec();
+        bytes.extend_from_slice(para.as_slice());
+        bytes.resize(REQ_LEN - 8, 0);
+        bytes.extend_from_slice(&op_type.to_le_bytes());
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(extra);
+        bytes
+    }
+
+    fn ctrl_header(opcode: u32) -> CtrlHeader {
+        CtrlHeader {
+            opcode,
+            ..Default::default()
+        }
+    }
+
+    fn op_header(opcode: u32, session_id: u64) -> OpHeader {
+        OpHeader {
+            opcode,
+            session_id,
+            ..Default::default()
+        }
+    }
+
+    // Creates a session, and returns the output of the request.
+    fn create_session(
+        crypto: &mut TestCrypto,
+        mem: &GuestMemoryMmap,
+        vq: &VirtQueue,
+        req: &[u8],
+    ) -> SessionInput {
+        let out = send(crypto, mem, vq, 1, req, 16);
+        assert_eq!(out.len(), 16);
+        let mut input = SessionInput::default();
+        input.as_mut_slice().copy_from_slice(&out);
+        input
+    }
+
+    fn cipher_session_req(key: &[u8], op: u32) -> Vec<u8> {
+        let para = CipherSessionPara {
+            algo: VIRTIO_CRYPTO_CIPHER_AES_CTR,
+            keylen: key.len() as u32,
+            op,
+            padding: 0,
+        };
+        request(
+            &ctrl_header(VIRTIO_CRYPTO_CIPHER_CREATE_SESSION),
+            &para,
+            VIRTIO_CRYPTO_SYM_OP_CIPHER,
+            key,
+        )
+    }
+
+    fn cipher_req(opcode: u32, session_id: u64, iv: &[u8], src: &[u8]) -> Vec<u8> {
+        let para = CipherPara {
+            iv_len: iv.len() as u32,
+            src_data_len: src.len() as u32,
+            dst_data_len: src.len() as u32,
+            padding: 0,
+        };
+        let mut extra = iv.to_vec();
+        extra.extend_from_slice(src);
+        request(
+            &op_header(opcode, session_id),
+            &para,
+            VIRTIO_CRYPTO_SYM_OP_CIPHER,
+            &extra,
+        )
+    }
+
+    fn setup() -> (Mem, TestCrypto) {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let crypto = builder(&mem).build().unwrap();
+        (mem, crypto)
+    }
+
+    #[test]
+    fn test_build() {
+        let (mem, c) = setup();
+        assert_eq!(VirtioDevice::device_type(&c), VIRTIO_ID_CRYPTO);
+        assert_eq!(c.num_queues(), 2);
+        assert_eq!(c.control_queue_index(), 1);
+        assert_ne!(c.device_features() & (1 << VIRTIO_F_VERSION_1), 0);
+
+        let mut config = ConfigSpace::default();
+        c.read_config(0, config.as_mut_slice());
+        assert_eq!({ config.status }, VIRTIO_CRYPTO_S_HW_READY);
+        assert_eq!({ config.max_dataqueues }, 1);
+        assert_eq!(
+            { config.crypto_services },
+            (1 << VIRTIO_CRYPTO_SERVICE_CIPHER) | (1 << VIRTIO_CRYPTO_SERVICE_HASH)
+        );
+        assert_eq!({ config.cipher_algo_l }, 1 << VIRTIO_CRYPTO_CIPHER_AES_CTR);
+        assert_eq!({ config.max_size }, DEFAULT_MAX_SIZE);
+
+        let c = builder(&mem).with_num_data_queues(4).build().unwrap();
+        assert_eq!(c.num_queues(), 5);
+        assert_eq!(c.control_queue_index(), 4);
+
+        for &num in [0, MAX_DATA_QUEUES + 1].iter() {
+            assert!(matches!(
+                builder(&mem).with_num_data_queues(num).build(),
+                Err(Error::InvalidNumDataQueues(n)) if n == num
+            ));
+        }
+    }
+
+    #[test]
+    fn test_sessions() {
+        let (mem, mut c) = setup();
+        let vqs: Vec<_> = (0..2)
+            .map(|i| VirtQueue::new(GuestAddress(i * 0x1000), &mem, 16))
+            .collect();
+        let ctrl = &vqs[1];
+
+        assert!(matches!(
+            c.process_control_queue(),
+            Err(Error::InvalidQueueIndex(1))
+        ));
+        activate(&mut c, &vqs, 0);
+        assert!(c.is_activated());
+
+        let input = create_session(&mut c, &mem, ctrl, &cipher_session_req(&[1; 16], 1));
+        assert_eq!({ input.status }, u32::from(VIRTIO_CRYPTO_OK));
+        assert_eq!({ input.session_id }, 0);
+        let para = HashSessionPara {
+            algo: VIRTIO_CRYPTO_HASH_SHA_256,
+            hash_result_len: 32,
+        };
+        let req = request(
+            &ctrl_header(VIRTIO_CRYPTO_HASH_CREATE_SESSION),
+            &para,
+            0,
+            &[],
+        );
+        let input = create_session(&mut c, &mem, ctrl, &req);
+        assert_eq!({ input.status }, u32::from(VIRTIO_CRYPTO_OK));
+        assert_eq!({ input.session_id }, 1);
+        assert_eq!(c.num_sessions(), 2);
+
+        // The backend rejects empty keys.
+        let input = create_session(&mut c, &mem, ctrl, &cipher_session_req(&[], 1));
+        assert_eq!({ input.status }, u32::from(VIRTIO_CRYPTO_KEY_REJECTED));
+        // The key is longer than advertised.
+        let input = create_session(&mut c, &mem, ctrl, &cipher_session_req(&[1; 33], 1));
+        assert_eq!({ input.status }, u32::from(VIRTIO_CRYPTO_KEY_REJECTED));
+        // The key is missing from the request.
+        let mut req = cipher_session_req(&[1; 16], 1);
+        req.truncate(REQ_LEN + 8);
+        let input = create_session(&mut c, &mem, ctrl, &req);
+        assert_eq!({ input.status }, u32::from(VIRTIO_CRYPTO_BADMSG));
+        // Invalid direction.
+        let input = create_session(&mut c, &mem, ctrl, &cipher_session_req(&[1; 16], 3));
+        assert_eq!({ input.status }, u32::from(VIRTIO_CRYPTO_BADMSG));
+        // AEAD is not supported.
+        let req = request(
+            &ctrl_header(VIRTIO_CRYPTO_AEAD_CREATE_SESSION),
+            &para,
+            0,
+            &[],
+        );
+        let input = create_session(&mut c, &mem, ctrl, &req);
+        assert_eq!({ input.status }, u32::from(VIRTIO_CRYPTO_NOTSUPP));
+        assert_eq!(c.num_sessions(), 2);
+
+        let destroy =
+            |opcode: u32, session_id: u64| request(&ctrl_header(opcode), &session_id, 0, &[]);
+        // The session belongs to another service.
+        let out = send(
+            &mut c,
+            &mem,
+            ctrl,
+            1,
+            &destroy(VIRTIO_CRYPTO_HASH_DESTROY_SESSION, 0),
+            1,
+        );
+        assert_eq!(out, vec![VIRTIO_CRYPTO_INVSESS]);
+        let out = send(
+            &mut c,
+            &mem,
+            ctrl,
+            1,
+            &destroy(VIRTIO_CRYPTO_CIPHER_DESTROY_SESSION, 0),
+            1,
+        );
+        assert_eq!(out, vec![VIRTIO_CRYPTO_OK]);
+        let out = send(
+            &mut c,
+            &mem,
+            ctrl,
+            1,
+            &destroy(VIRTIO_CRYPTO_CIPHER_DESTROY_SESSION, 0),
+            1,
+        );
+        assert_eq!(out, vec![VIRTIO_CRYPTO_INVSESS]);
+        let out = send(&mut c, &mem, ctrl, 1, &destroy(0x999, 0), 1);
+        assert_eq!(out, vec![VIRTIO_CRYPTO_NOTSUPP]);
+        assert_eq!(c.num_sessions(), 1);
+
+        // The sessions are dropped on reset.
+        c.ack_device_status(0);
+        assert!(!c.is_activated());
+        assert_eq!(c.num_sessions(), 0);
+    }
+
+    #[test]
+    fn test_data_requests() {
+        let (mem, mut c) = setup();
+        let vqs: Vec<_> = (0..2)
+            .map(|i| VirtQueue::new(GuestAddress(i * 0x1000), &mem, 16))
+            .collect();
+        let (data, ctrl) = (&vqs[0], &vqs[1]);
+        activate(&mut c, &vqs, 0);
+
+        let key = [0x0f; 16];
+        let encrypt = create_session(&mut c, &mem, ctrl, &cipher_session_req(&key, 1)).session_id;
+        let decrypt = create_session(&mut c, &mem, ctrl, &cipher_session_req(&key, 2)).session_id;
+        let para = HashSessionPara {
+            algo: VIRTIO_CRYPTO_HASH_SHA_256,
+            hash_result_len: 4,
+        };
+        let req = request(
+            &ctrl_header(VIRTIO_CRYPTO_HASH_CREATE_SESSION),
+            &para,
+            0,
+            &[],
+        );
+        let hash = create_session(&mut c, &mem, ctrl, &req).session_id;
+
+        // The output is followed by the status.
+        let req = cipher_req(VIRTIO_CRYPTO_CIPHER_ENCRYPT, encrypt, &[0xf0], &[1, 2, 3]);
+        let out = send(&mut c, &mem, data, 0, &req, 4);
+        assert_eq!(out, vec![0xfe, 0x0d, 0x0c, VIRTIO_CRYPTO_OK]);
+        let req = cipher_req(VIRTIO_CRYPTO_CIPHER_DECRYPT, decrypt, &[0xf0], &out[..3]);
+        let out = send(&mut c, &mem, data, 0, &req, 4);
+        assert_eq!(out, vec![1, 2, 3, VIRTIO_CRYPTO_OK]);
+
+        // The direction of the request doesn't match the session.
+        let req = cipher_req(VIRTIO_CRYPTO_CIPHER_DECRYPT, encrypt, &[], &[1, 2, 3]);
+        let out = send(&mut c, &mem, data, 0, &req, 4);
+        assert_eq!(out[3], VIRTIO_CRYPTO_BADMSG);
+        // The output doesn't fit.
+        let req = cipher_req(VIRTIO_CRYPTO_CIPHER_ENCRYPT, encrypt, &[], &[1, 2, 3]);
+        let out = send(&mut c, &mem, data, 0, &req, 3);
+        assert_eq!(out[2], VIRTIO_CRYPTO_BADMSG);
+        // Invalid sessions.
+        let req = cipher_req(VIRTIO_CRYPTO_CIPHER_ENCRYPT, 100, &[], &[1, 2, 3]);
+        let out = send(&mut c, &mem, data, 0, &req, 4);
+        assert_eq!(out[3], VIRTIO_CRYPTO_INVSESS);
+        let req = cipher_req(VIRTIO_CRYPTO_CIPHER_ENCRYPT, hash, &[], &[1, 2, 3]);
+        let out = send(&mut c, &mem, data, 0, &req, 4);
+        assert_eq!(out[3], VIRTIO_CRYPTO_INVSESS);
+
+        let para = HashPara {
+            src_data_len: 3,
+            hash_result_len: 4,
+        };
+        let req = request(&op_header(VIRTIO_CRYPTO_HASH, hash), &para, 0, &[1, 2, 3]);
+        let out = send(&mut c, &mem, data, 0, &req, 5);
+        assert_eq!(out, vec![6, 6, 6, 6, VIRTIO_CRYPTO_OK]);
+
+        // The source data is missing.
+        let req = request(&op_header(VIRTIO_CRYPTO_HASH, hash), &para, 0, &[1]);
+        let out = send(&mut c, &mem, data, 0, &req, 5);
+        assert_eq!(out[4], VIRTIO_CRYPTO_BADMSG);
+
+        // Requests which go through the control queue are not valid data requests.
+        assert!(matches!(
+            c.process_data_queue(1),
+            Err(Error::InvalidQueueIndex(1))
+        ));
+    }
+
+    #[test]
+    fn test_attack_patterns() {
+        // The malicious chains are consumed like any other request, on both the data and the
+        // control queue, except for the ones with an out of range head index, which can't be
+        // added to the used ring.
+        for &pattern in AttackPattern::ALL.iter() {
+            let (mem, mut crypto) = setup();
+            let vqs: Vec<_> = (0..2)
+                .map(|i| VirtQueue::new(GuestAddress(i * 0x1000), &mem, 16))
+                .collect();
+            activate(&mut crypto, &vqs, 0);
+            for (index, vq) in vqs.iter().enumerate() {
+                vq.add_attack(pattern, 0, GuestAddress(0x1_0000));
+                crypto.queue_notify(index as u32);
+                assert_eq!(
+                    vq.used.idx().load(),
+                    u16::from(pattern != AttackPattern::HeadOutOfRange),
+                    "{:?}",
+                    pattern
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,27 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A crate that provides crypto device specific components as described
+//! by the virtio specification.
+
+#![deny(missing_docs)]
+
+/// Contains the interface between the crypto device and the provider of the operations.
+pub mod backend;
+
+/// Contains the crypto device configuration space abstraction.
+pub mod config;
+
+/// Contains virtio crypto constant definitions.
+pub mod defs;
+
+/// Contains a reference virtio crypto device implementation.
+pub mod device;
+
+/// Contains the layout of the crypto requests.
+pub mod request;
+
+/// Contains a crypto backend built on top of the RustCrypto crates.
+#[cfg(feature = "backend-software")]
+pub mod software;
@@ -0,0 +1,170 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Virtio crypto request layouts.
+//!
+//! Every request starts with a fixed size part: a header followed by an operation specific
+//! structure, which is padded to `REQ_LEN` bytes overall. The variable size inputs (i.e. keys,
+//! initialization vectors and source data) follow the fixed size part. The outputs go to the
+//! device-writable part of the buffer, which ends with the status of the request.
+//!
+//! The structures defined here mirror the ones from `linux/virtio_crypto.h`, without the
+//! padding.
+//!
+//! All fields are expected to hold little endian values, which is always the case on the
+//! platforms that are currently supported (`x86_64` and `aarch64`).
+
+use vm_memory::ByteValued;
+
+/// The size of the fixed size part of control and data requests.
+pub const REQ_LEN: usize = 72;
+/// The offset of the `op_type` field in symmetric session creation requests.
+pub const SYM_CREATE_SESSION_OP_TYPE_OFFSET: usize = CtrlHeader::LEN + 48;
+/// The offset of the `op_type` field in symmetric data requests.
+pub const SYM_DATA_OP_TYPE_OFFSET: usize = OpHeader::LEN + 40;
+
+/// The header of control requests (the `virtio_crypto_ctrl_header` structure).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct CtrlHeader {
+    /// The operation.
+    pub opcode: u32,
+    /// The algorithm.
+    pub algo: u32,
+    /// Operation specific flags.
+    pub flag: u32,
+    /// The data queue associated with the session.
+    pub queue_id: u32,
+}
+
+/// The parameters of cipher sessions (the `virtio_crypto_cipher_session_para` structure),
+/// which are followed by the key.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct CipherSessionPara {
+    /// The cipher algorithm.
+    pub algo: u32,
+    /// The length of the key.
+    pub keylen: u32,
+    /// The direction (`VIRTIO_CRYPTO_OP_ENCRYPT` or `VIRTIO_CRYPTO_OP_DECRYPT`).
+    pub op: u32,
+    /// Unused.
+    pub padding: u32,
+}
+
+/// The parameters of hash sessions (the `virtio_crypto_hash_session_para` structure).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct HashSessionPara {
+    /// The hash algorithm.
+    pub algo: u32,
+    /// The length of the results.
+    pub hash_result_len: u32,
+}
+
+/// The parameters of MAC sessions (the `virtio_crypto_mac_session_para` structure), which are
+/// followed by the key.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct MacSessionPara {
+    /// The MAC algorithm.
+    pub algo: u32,
+    /// The length of the results.
+    pub hash_result_len: u32,
+    /// The length of the key.
+    pub auth_key_len: u32,
+    /// Unused.
+    pub padding: u32,
+}
+
+/// The output of session creation requests (the `virtio_crypto_session_input` structure).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct SessionInput {
+    /// The identifier of the new session.
+    pub session_id: u64,
+    /// The status of the request.
+    pub status: u32,
+    /// Unused.
+    pub padding: u32,
+}
+
+/// The header of data requests (the `virtio_crypto_op_header` structure).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct OpHeader {
+    /// The operation.
+    pub opcode: u32,
+    /// The algorithm.
+    pub algo: u32,
+    /// The session which processes the request.
+    pub session_id: u64,
+    /// Operation specific flags.
+    pub flag: u32,
+    /// Unused.
+    pub padding: u32,
+}
+
+/// The parameters of cipher requests (the `virtio_crypto_cipher_para` structure), which are
+/// followed by the initialization vector and the source data.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct CipherPara {
+    /// The length of the initialization vector.
+    pub iv_len: u32,
+    /// The length of the source data.
+    pub src_data_len: u32,
+    /// The length of the destination data.
+    pub dst_data_len: u32,
+    /// Unused.
+    pub padding: u32,
+}
+
+/// The parameters of hash and MAC requests (the `virtio_crypto_hash_para` structure), which
+/// are followed by the source data.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct HashPara {
+    /// The length of the source data.
+    pub src_data_len: u32,
+    /// The length of the result.
+    pub hash_result_len: u32,
+}
+
+// Safe because the structures below only contain plain data, and there's no padding between
+// (or after) the fields.
+unsafe impl ByteValued for CtrlHeader {}
+unsafe impl ByteValued for CipherSessionPara {}
+unsafe impl ByteValued for HashSessionPara {}
+unsafe impl ByteValued for MacSessionPara {}
+unsafe impl ByteValued for SessionInput {}
+unsafe impl ByteValued for OpHeader {}
+unsafe impl ByteValued for CipherPara {}
+unsafe impl ByteValued for HashPara {}
+
+impl CtrlHeader {
+    /// The size of the control request header.
+    pub const LEN: usize = std::mem::size_of::<CtrlHeader>();
+}
+
+impl OpHeader {
+    /// The size of the data request header.
+    pub const LEN: usize = std::mem::size_of::<OpHeader>();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::mem::size_of;
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(CtrlHeader::LEN, 16);
+        assert_eq!(OpHeader::LEN, 24);
+        assert_eq!(size_of::<SessionInput>(), 16);
+        assert_eq!(SYM_CREATE_SESSION_OP_TYPE_OFFSET, 64);
+        assert_eq!(SYM_DATA_OP_TYPE_OFFSET, 64);
+    }
+}
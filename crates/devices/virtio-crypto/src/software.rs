@@ -0,0 +1,456 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Software crypto backend.
+//!
+//! This module provides the [`SoftwareBackend`](struct.SoftwareBackend.html), which implements
+//! the AES ciphers, the SHA hashes and HMAC on top of the RustCrypto crates.
+
+use aes::cipher::consts::U16;
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{
+    BlockCipher, BlockDecrypt, BlockDecryptMut, BlockEncrypt, BlockEncryptMut, BlockSizeUser,
+    KeyInit, KeyIvInit, StreamCipher,
+};
+use aes::{Aes128, Aes192, Aes256};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Digest, Sha224, Sha256, Sha384, Sha512};
+
+use crate::backend::{Capabilities, CryptoBackend, Error, Result, SessionParams};
+use crate::defs::{
+    VIRTIO_CRYPTO_CIPHER_AES_CBC, VIRTIO_CRYPTO_CIPHER_AES_CTR, VIRTIO_CRYPTO_CIPHER_AES_ECB,
+    VIRTIO_CRYPTO_HASH_SHA1, VIRTIO_CRYPTO_HASH_SHA_224, VIRTIO_CRYPTO_HASH_SHA_256,
+    VIRTIO_CRYPTO_HASH_SHA_384, VIRTIO_CRYPTO_HASH_SHA_512, VIRTIO_CRYPTO_MAC_HMAC_SHA1,
+    VIRTIO_CRYPTO_MAC_HMAC_SHA_256, VIRTIO_CRYPTO_MAC_HMAC_SHA_512,
+};
+
+// The AES block size.
+const AES_BLOCK_SIZE: usize = 16;
+// The maximum length of AES keys.
+const MAX_CIPHER_KEY_LEN: u32 = 32;
+// The maximum length of HMAC keys.
+const MAX_AUTH_KEY_LEN: u32 = 128;
+
+/// The state of a software backend session.
+#[derive(Clone, Debug)]
+pub enum SoftwareSession {
+    /// A cipher session.
+    Cipher {
+        /// The cipher algorithm.
+        algo: u32,
+        /// The key.
+        key: Vec<u8>,
+        /// Whether the session encrypts (or decrypts) data.
+        encrypt: bool,
+    },
+    /// A hash session.
+    Hash {
+        /// The hash algorithm.
+        algo: u32,
+    },
+    /// A MAC session.
+    Mac {
+        /// The MAC algorithm.
+        algo: u32,
+        /// The authentication key.
+        key: Vec<u8>,
+    },
+}
+
+/// A crypto backend which performs the operations in software.
+#[derive(Debug, Default)]
+pub struct SoftwareBackend;
+
+impl SoftwareBackend {
+    /// Creates a new software backend.
+    pub fn new() -> Self {
+        SoftwareBackend
+    }
+}
+
+// Returns the length of the results of a hash algorithm.
+fn hash_len(algo: u32) -> Option<usize> {
+    match algo {
+        VIRTIO_CRYPTO_HASH_SHA1 => Some(20),
+        VIRTIO_CRYPTO_HASH_SHA_224 => Some(28),
+        VIRTIO_CRYPTO_HASH_SHA_256 => Some(32),
+        VIRTIO_CRYPTO_HASH_SHA_384 => Some(48),
+        VIRTIO_CRYPTO_HASH_SHA_512 => Some(64),
+        _ => None,
+    }
+}
+
+// Returns the length of the results of a MAC algorithm.
+fn mac_len(algo: u32) -> Option<usize> {
+    match algo {
+        VIRTIO_CRYPTO_MAC_HMAC_SHA1 => Some(20),
+        VIRTIO_CRYPTO_MAC_HMAC_SHA_256 => Some(32),
+        VIRTIO_CRYPTO_MAC_HMAC_SHA_512 => Some(64),
+        _ => None,
+    }
+}
+
+fn hash(algo: u32, src: &[u8]) -> Result<Vec<u8>> {
+    Ok(match algo {
+        VIRTIO_CRYPTO_HASH_SHA1 => Sha1::digest(src).to_vec(),
+        VIRTIO_CRYPTO_HASH_SHA_224 => Sha224::digest(src).to_vec(),
+        VIRTIO_CRYPTO_HASH_SHA_256 => Sha256::digest(src).to_vec(),
+        VIRTIO_CRYPTO_HASH_SHA_384 => Sha384::digest(src).to_vec(),
+        VIRTIO_CRYPTO_HASH_SHA_512 => Sha512::digest(src).to_vec(),
+        _ => return Err(Error::NotSupported),
+    })
+}
+
+fn hmac_with<T: Mac + KeyInit>(key: &[u8], src: &[u8]) -> Result<Vec<u8>> {
+    let mut mac = <T as KeyInit>::new_from_slice(key).map_err(|_| Error::KeyRejected)?;
+    mac.update(src);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+fn hmac(algo: u32, key: &[u8], src: &[u8]) -> Result<Vec<u8>> {
+    match algo {
+        VIRTIO_CRYPTO_MAC_HMAC_SHA1 => hmac_with::<Hmac<Sha1>>(key, src),
+        VIRTIO_CRYPTO_MAC_HMAC_SHA_256 => hmac_with::<Hmac<Sha256>>(key, src),
+        VIRTIO_CRYPTO_MAC_HMAC_SHA_512 => hmac_with::<Hmac<Sha512>>(key, src),
+        _ => Err(Error::NotSupported),
+    }
+}
+
+fn aes_with<C>(algo: u32, key: &[u8], encrypt: bool, iv: &[u8], data: &mut [u8]) -> Result<()>
+where
+    C: BlockCipher + BlockEncrypt + BlockDecrypt + KeyInit + BlockSizeUser<BlockSize = U16>,
+{
+    // ECB and CBC do not use padding, so the data has to consist of whole blocks.
+    if algo != VIRTIO_CRYPTO_CIPHER_AES_CTR && data.len() % AES_BLOCK_SIZE != 0 {
+        return Err(Error::BadMessage);
+    }
+
+    let blocks = data
+        .chunks_exact_mut(AES_BLOCK_SIZE)
+        .map(GenericArray::from_mut_slice);
+
+    match algo {
+        VIRTIO_CRYPTO_CIPHER_AES_ECB => {
+            let cipher = C::new_from_slice(key).map_err(|_| Error::KeyRejected)?;
+            for block in blocks {
+                if encrypt {
+                    cipher.encrypt_block(block);
+                } else {
+                    cipher.decrypt_block(block);
+                }
+            }
+        }
+        VIRTIO_CRYPTO_CIPHER_AES_CBC => {
+            if encrypt {
+                let mut cipher =
+                    cbc::Encryptor::<C>::new_from_slices(key, iv).map_err(|_| Error::BadMessage)?;
+                blocks.for_each(|block| cipher.encrypt_block_mut(block));
+            } else {
+                let mut cipher =
+                    cbc::Decryptor::<C>::new_from_slices(key, iv).map_err(|_| Error::BadMessage)?;
+                blocks.for_each(|block| cipher.decrypt_block_mut(block));
+            }
+        }
+        VIRTIO_CRYPTO_CIPHER_AES_CTR => {
+            let mut cipher =
+                ctr::Ctr128BE::<C>::new_from_slices(key, iv).map_err(|_| Error::BadMessage)?;
+            cipher.apply_keystream(data);
+        }
+        _ => return Err(Error::NotSupported),
+    }
+
+    Ok(())
+}
+
+fn aes(algo: u32, key: &[u8], encrypt: bool, iv: &[u8], data: &mut [u8]) -> Result<()> {
+    match key.len() {
+        16 => aes_with::<Aes128>(algo, key, encrypt, iv, data),
+        24 => aes_with::<Aes192>(algo, key, encrypt, iv, data),
+        32 => aes_with::<Aes256>(algo, key, encrypt, iv, data),
+        _ => Err(Error::KeyRejected),
+    }
+}
+
+// Checks the requested length of the results against the length of the full results.
+fn check_result_len(result_len: u32, full_len: Option<usize>) -> Result<()> {
+    match full_len {
+        Some(len) if result_len as usize <= len => Ok(()),
+        Some(_) => Err(Error::BadMessage),
+        None => Err(Error::NotSupported),
+    }
+}
+
+impl CryptoBackend for SoftwareBackend {
+    type Session = SoftwareSession;
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            cipher_algos: (1 << VIRTIO_CRYPTO_CIPHER_AES_ECB)
+                | (1 << VIRTIO_CRYPTO_CIPHER_AES_CBC)
+                | (1 << VIRTIO_CRYPTO_CIPHER_AES_CTR),
+            hash_algos: (1 << VIRTIO_CRYPTO_HASH_SHA1)
+                | (1 << VIRTIO_CRYPTO_HASH_SHA_224)
+                | (1 << VIRTIO_CRYPTO_HASH_SHA_256)
+                | (1 << VIRTIO_CRYPTO_HASH_SHA_384)
+                | (1 << VIRTIO_CRYPTO_HASH_SHA_512),
+            mac_algos: (1 << VIRTIO_CRYPTO_MAC_HMAC_SHA1)
+                | (1 << VIRTIO_CRYPTO_MAC_HMAC_SHA_256)
+                | (1 << VIRTIO_CRYPTO_MAC_HMAC_SHA_512),
+            max_cipher_key_len: MAX_CIPHER_KEY_LEN,
+            max_auth_key_len: MAX_AUTH_KEY_LEN,
+        }
+    }
+
+    fn create_session(&mut self, params: &SessionParams) -> Result<Self::Session> {
+        match params {
+            SessionParams::Cipher { algo, key, encrypt } => {
+                match *algo {
+                    VIRTIO_CRYPTO_CIPHER_AES_ECB
+                    | VIRTIO_CRYPTO_CIPHER_AES_CBC
+                    | VIRTIO_CRYPTO_CIPHER_AES_CTR => {}
+                    _ => return Err(Error::NotSupported),
+                }
+                if ![16, 24, 32].contains(&key.len()) {
+                    return Err(Error::KeyRejected);
+                }
+                Ok(SoftwareSession::Cipher {
+                    algo: *algo,
+                    key: key.clone(),
+                    encrypt: *encrypt,
+                })
+            }
+            SessionParams::Hash { algo, result_len } => {
+                check_result_len(*result_len, hash_len(*algo))?;
+                Ok(SoftwareSession::Hash { algo: *algo })
+            }
+            SessionParams::Mac {
+                algo,
+                result_len,
+                key,
+            } => {
+                check_result_len(*result_len, mac_len(*algo))?;
+                if key.len() > MAX_AUTH_KEY_LEN as usize {
+                    return Err(Error::KeyRejected);
+                }
+                Ok(SoftwareSession::Mac {
+                    algo: *algo,
+                    key: key.clone(),
+                })
+            }
+        }
+    }
+
+    fn cipher(&mut self, session: &mut Self::Session, iv: &[u8], data: &mut [u8]) -> Result<()> {
+        match session {
+            SoftwareSession::Cipher { algo, key, encrypt } => aes(*algo, key, *encrypt, iv, data),
+            _ => Err(Error::BadMessage),
+        }
+    }
+
+    fn digest(&mut self, session: &mut Self::Session, src: &[u8], result: &mut [u8]) -> Result<()> {
+        let full = match session {
+            SoftwareSession::Hash { algo } => hash(*algo, src)?,
+            SoftwareSession::Mac { algo, key } => hmac(*algo, key, src)?,
+            _ => return Err(Error::BadMessage),
+        };
+
+        let len = result.len();
+        if len > full.len() {
+            return Err(Error::BadMessage);
+        }
+        result.copy_from_slice(&full[..len]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn cipher_session(
+        backend: &mut SoftwareBackend,
+        algo: u32,
+        key: &[u8],
+        encrypt: bool,
+    ) -> SoftwareSession {
+        backend
+            .create_session(&SessionParams::Cipher {
+                algo,
+                key: key.to_vec(),
+                encrypt,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_create_session() {
+        let mut backend = SoftwareBackend::new();
+
+        let params = SessionParams::Cipher {
+            algo: 100,
+            key: vec![0; 16],
+            encrypt: true,
+        };
+        assert_eq!(
+            backend.create_session(&params).unwrap_err(),
+            Error::NotSupported
+        );
+
+        let params = SessionParams::Cipher {
+            algo: VIRTIO_CRYPTO_CIPHER_AES_CBC,
+            key: vec![0; 15],
+            encrypt: true,
+        };
+        assert_eq!(
+            backend.create_session(&params).unwrap_err(),
+            Error::KeyRejected
+        );
+
+        let params = SessionParams::Hash {
+            algo: VIRTIO_CRYPTO_HASH_SHA_256,
+            result_len: 33,
+        };
+        assert_eq!(
+            backend.create_session(&params).unwrap_err(),
+            Error::BadMessage
+        );
+
+        let params = SessionParams::Mac {
+            algo: VIRTIO_CRYPTO_MAC_HMAC_SHA_256,
+            result_len: 32,
+            key: vec![0; 200],
+        };
+        assert_eq!(
+            backend.create_session(&params).unwrap_err(),
+            Error::KeyRejected
+        );
+    }
+
+    #[test]
+    fn test_aes() {
+        let mut backend = SoftwareBackend::new();
+
+        // Test vectors from NIST SP 800-38A (F.1.1, F.2.1 and F.5.1).
+        let key = hex("2b7e151628aed2a6abf7158809cf4f3c");
+        let plain = hex("6bc1bee22e409f96e93d7e117393172a");
+        let iv = hex("000102030405060708090a0b0c0d0e0f");
+        let ctr_iv = hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff");
+
+        let tests = [
+            (
+                VIRTIO_CRYPTO_CIPHER_AES_ECB,
+                &iv,
+                "3ad77bb40d7a3660a89ecaf32466ef97",
+            ),
+            (
+                VIRTIO_CRYPTO_CIPHER_AES_CBC,
+                &iv,
+                "7649abac8119b246cee98e9b12e9197d",
+            ),
+            (
+                VIRTIO_CRYPTO_CIPHER_AES_CTR,
+                &ctr_iv,
+                "874d6191b620e3261bef6864990db6ce",
+            ),
+        ];
+
+        for (algo, iv, expected) in tests.iter() {
+            let mut session = cipher_session(&mut backend, *algo, &key, true);
+            let mut data = plain.clone();
+            backend.cipher(&mut session, iv, &mut data).unwrap();
+            assert_eq!(data, hex(expected));
+
+            let mut session = cipher_session(&mut backend, *algo, &key, false);
+            backend.cipher(&mut session, iv, &mut data).unwrap();
+            assert_eq!(data, plain);
+        }
+
+        // ECB and CBC only handle whole blocks.
+        let mut session = cipher_session(&mut backend, VIRTIO_CRYPTO_CIPHER_AES_CBC, &key, true);
+        let mut data = vec![0; 15];
+        assert_eq!(
+            backend.cipher(&mut session, &iv, &mut data).unwrap_err(),
+            Error::BadMessage
+        );
+        // The IV has to be one block long.
+        let mut data = vec![0; 16];
+        assert_eq!(
+            backend
+                .cipher(&mut session, &iv[..8], &mut data)
+                .unwrap_err(),
+            Error::BadMessage
+        );
+        // CTR handles partial blocks.
+        let mut session = cipher_session(&mut backend, VIRTIO_CRYPTO_CIPHER_AES_CTR, &key, true);
+        let mut data = plain[..5].to_vec();
+        backend.cipher(&mut session, &ctr_iv, &mut data).unwrap();
+        assert_eq!(data, hex("874d6191b6"));
+    }
+
+    #[test]
+    fn test_digest() {
+        let mut backend = SoftwareBackend::new();
+
+        let mut session = backend
+            .create_session(&SessionParams::Hash {
+                algo: VIRTIO_CRYPTO_HASH_SHA_256,
+                result_len: 32,
+            })
+            .unwrap();
+        let mut result = [0u8; 32];
+        backend.digest(&mut session, b"abc", &mut result).unwrap();
+        assert_eq!(
+            result.to_vec(),
+            hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+
+        // Truncated results.
+        let mut result = [0u8; 4];
+        backend.digest(&mut session, b"abc", &mut result).unwrap();
+        assert_eq!(result.to_vec(), hex("ba7816bf"));
+
+        let mut result = [0u8; 33];
+        assert_eq!(
+            backend
+                .digest(&mut session, b"abc", &mut result)
+                .unwrap_err(),
+            Error::BadMessage
+        );
+
+        // Test case 2 from RFC 4231.
+        let mut session = backend
+            .create_session(&SessionParams::Mac {
+                algo: VIRTIO_CRYPTO_MAC_HMAC_SHA_256,
+                result_len: 32,
+                key: b"Jefe".to_vec(),
+            })
+            .unwrap();
+        let mut result = [0u8; 32];
+        backend
+            .digest(&mut session, b"what do ya want for nothing?", &mut result)
+            .unwrap();
+        assert_eq!(
+            result.to_vec(),
+            hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+        );
+
+        // Cipher sessions can't compute digests.
+        let mut session =
+            cipher_session(&mut backend, VIRTIO_CRYPTO_CIPHER_AES_CTR, &[0; 16], true);
+        assert_eq!(
+            backend
+                .digest(&mut session, b"abc", &mut result)
+                .unwrap_err(),
+            Error::BadMessage
+        );
+    }
+}
@@ -0,0 +1,27 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Fs backend abstraction.
+//!
+//! This module provides the [`FsBackend`](trait.FsBackend.html) interface, which connects the
+//! fs device to the filesystem implementation. The device only deals with the FUSE-over-virtio
+//! transport: it reads the FUSE requests from the queues, hands them over to the backend, and
+//! writes the replies back to the driver. Backends (i.e. a passthrough filesystem which serves
+//! a host directory) interpret the requests.
+
+use crate::fuse::{Reply, Request};
+
+/// The filesystem which serves the FUSE requests of the driver.
+pub trait FsBackend {
+    /// Handles a FUSE request, and returns the reply. Requests which don't expect a reply
+    /// (i.e. `FUSE_FORGET`) return `None`.
+    ///
+    /// # Arguments
+    /// * `req` - The request sent by the driver.
+    fn handle_request(&mut self, req: &Request) -> Option<Reply>;
+
+    /// Drops the state of the session, i.e. when the device is reset. The driver starts over
+    /// with a `FUSE_INIT` request.
+    fn reset(&mut self) {}
+}
@@ -0,0 +1,97 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Virtio fs device configuration space abstraction.
+//!
+//! This module provides the [`ConfigSpace`](struct.ConfigSpace.html) abstraction, which mirrors
+//! the `virtio_fs_config` structure from the virtio specification. It holds the tag the guest
+//! uses to mount the filesystem, and the number of request queues.
+
+use std::mem::{offset_of, size_of};
+
+use vm_memory::ByteValued;
+
+/// The maximum length of the filesystem tag.
+pub const TAG_LEN: usize = 36;
+
+/// The fs device configuration space layout, as defined by the virtio specification.
+///
+/// All fields are expected to hold little endian values, which is always the case on the
+/// platforms that are currently supported (`x86_64` and `aarch64`).
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[repr(C, packed)]
+pub struct ConfigSpace {
+    /// The UTF-8 name of the filesystem, padded with zeroes (it's not NUL-terminated when it
+    /// takes up all the bytes).
+    pub tag: [u8; TAG_LEN],
+    /// The number of request queues.
+    pub num_request_queues: u32,
+}
+
+// Safe because ConfigSpace only contains plain data, and there's no padding between (or after)
+// the fields since the structure is packed.
+unsafe impl ByteValued for ConfigSpace {}
+
+impl Default for ConfigSpace {
+    fn default() -> Self {
+        ConfigSpace {
+            tag: [0; TAG_LEN],
+            num_request_queues: 0,
+        }
+    }
+}
+
+impl ConfigSpace {
+    /// The size of the fs device configuration space.
+    pub const LEN: usize = size_of::<ConfigSpace>();
+    /// The offset of the `tag` field.
+    pub const TAG_OFFSET: usize = offset_of!(ConfigSpace, tag);
+    /// The offset of the `num_request_queues` field.
+    pub const NUM_REQUEST_QUEUES_OFFSET: usize = offset_of!(ConfigSpace, num_request_queues);
+
+    /// Creates a new `ConfigSpace`, or returns `None` when the tag is empty or longer than
+    /// `TAG_LEN` bytes.
+    ///
+    /// # Arguments
+    /// * `tag` - The name of the filesystem.
+    /// * `num_request_queues` - The number of request queues.
+    pub fn new(tag: &str, num_request_queues: u32) -> Option<Self> {
+        if tag.is_empty() || tag.len() > TAG_LEN {
+            return None;
+        }
+        let mut config = ConfigSpace {
+            num_request_queues,
+            ..Default::default()
+        };
+        config.tag[..tag.len()].copy_from_slice(tag.as_bytes());
+        Some(config)
+    }
+}
+
+impl From<ConfigSpace> for Vec<u8> {
+    fn from(config: ConfigSpace) -> Self {
+        config.as_slice().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_space() {
+        assert_eq!(ConfigSpace::LEN, 40);
+        assert_eq!(ConfigSpace::TAG_OFFSET, 0);
+        assert_eq!(ConfigSpace::NUM_REQUEST_QUEUES_OFFSET, 36);
+
+        assert!(ConfigSpace::new("", 1).is_none());
+        assert!(ConfigSpace::new(&"a".repeat(TAG_LEN + 1), 1).is_none());
+        assert!(ConfigSpace::new(&"a".repeat(TAG_LEN), 1).is_some());
+
+        let bytes: Vec<u8> = ConfigSpace::new("myfs", 2).unwrap().into();
+        assert_eq!(&bytes[..5], b"myfs\0");
+        assert!(bytes[4..TAG_LEN].iter().all(|&b| b == 0));
+        assert_eq!(bytes[TAG_LEN..], [2, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,35 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+// TODO: Move the generic device type definition to the vm-virtio crate proper.
+/// The virtio device type of fs devices.
+pub const VIRTIO_ID_FS: u32 = 26;
+
+/// The default (and maximum) size of the queues.
+pub const DEFAULT_QUEUE_SIZE: u16 = 256;
+
+// Queue indices.
+/// The index of the high priority queue, which carries `FUSE_INTERRUPT`, `FUSE_FORGET` and
+/// `FUSE_BATCH_FORGET` requests.
+pub const HIPRIO_QUEUE: u16 = 0;
+/// The index of the first request queue.
+pub const REQUEST_QUEUE_BASE: u16 = 1;
+
+// Shared memory region identifiers.
+/// The identifier of the DAX window, where the driver maps file contents.
+pub const VIRTIO_FS_SHMCAP_ID_CACHE: u8 = 0;
+
+// FUSE operations (from `linux/fuse.h`).
+/// Forget about an inode, which doesn't expect a reply.
+pub const FUSE_FORGET: u32 = 2;
+/// Initialize the session.
+pub const FUSE_INIT: u32 = 26;
+/// Interrupt a previous request, which doesn't expect a reply.
+pub const FUSE_INTERRUPT: u32 = 36;
+/// Forget about multiple inodes, which doesn't expect a reply.
+pub const FUSE_BATCH_FORGET: u32 = 42;
+/// Map a file range in the DAX window.
+pub const FUSE_SETUPMAPPING: u32 = 48;
+/// Remove mappings from the DAX window.
+pub const FUSE_REMOVEMAPPING: u32 = 49;
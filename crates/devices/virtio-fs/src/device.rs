@@ -0,0 +1,564 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A reference virtio fs device implementation.
+//!
+//! This module provides the following abstractions:
+//!
+//! - [`Fs`](struct.Fs.html) which ties together the generic device building blocks
+//!   (`VirtioConfig` and the `VirtioDevice`/`VirtioMmioDevice` interfaces) with the fs specific
+//!   ones (the configuration space, the FUSE messages and the
+//!   [`FsBackend`](../backend/trait.FsBackend.html) interface).
+//! - [`FsBuilder`](struct.FsBuilder.html) which configures and creates a `Fs` device.
+//!
+//! The device has a high priority queue followed by one or more request queues, which all carry
+//! FUSE requests. The requests are handed over to the backend, and the replies are written to
+//! the device-writable part of the request buffers. The device can also advertise a DAX window
+//! (as the `VIRTIO_FS_SHMCAP_ID_CACHE` shared memory region), where the backend maps file
+//! contents when serving `FUSE_SETUPMAPPING` requests. Setting up the guest memory behind the
+//! window is up to the VMM.
+//!
+//! The device doesn't register any events by itself: the VMM is expected to rely on the
+//! `VirtioMmioDevice::queue_notify` implementation (or call `Fs::process_queue` directly) when
+//! the driver notifies a queue.
+
+use std::fmt::{self, Display};
+use std::result;
+
+use log::{error, warn};
+
+use vm_memory::{Address, GuestAddress, GuestAddressSpace};
+
+use virtio_device::{
+    set_interrupt_status, SharedMemoryRegion, SignalUsedQueue, VirtioConfig, VirtioDeviceActions,
+    VirtioDeviceCommon, VirtioMmioDevice, VIRTIO_MMIO_INT_VRING,
+};
+use virtio_queue::{self, Queue};
+
+use crate::backend::FsBackend;
+use crate::config::ConfigSpace;
+use crate::defs::{REQUEST_QUEUE_BASE, VIRTIO_FS_SHMCAP_ID_CACHE};
+use crate::fuse::Request;
+
+pub use crate::defs::{DEFAULT_QUEUE_SIZE, VIRTIO_ID_FS};
+
+// TODO: Move the generic feature definitions to the vm-virtio crate proper.
+const VIRTIO_F_VERSION_1: u64 = 32;
+const VIRTIO_F_RING_EVENT_IDX: u64 = 29;
+
+/// Fs device errors.
+#[derive(Debug)]
+pub enum Error {
+    /// The device was already activated.
+    AlreadyActivated,
+    /// The DAX window is empty, or it doesn't fit in the guest physical address space.
+    InvalidDaxWindow(GuestAddress, u64),
+    /// The number of request queues is zero, or too large.
+    InvalidNumRequestQueues(u16),
+    /// The queues are not configured properly.
+    InvalidQueues,
+    /// The queue index is not valid, or the device is not activated.
+    InvalidQueueIndex(u16),
+    /// The tag is empty, or too long.
+    InvalidTag(String),
+    /// Failed to access a queue.
+    Queue(virtio_queue::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            AlreadyActivated => write!(f, "the device is already activated"),
+            InvalidDaxWindow(addr, len) => write!(
+                f,
+                "invalid DAX window at 0x{:x} with length 0x{:x}",
+                addr.0, len
+            ),
+            InvalidNumRequestQueues(num) => write!(f, "invalid number of request queues {}", num),
+            InvalidQueues => write!(f, "invalid queue configuration"),
+            InvalidQueueIndex(index) => write!(f, "invalid or inactive queue {}", index),
+            InvalidTag(ref tag) => write!(f, "invalid filesystem tag \"{}\"", tag),
+            Queue(ref err) => write!(f, "failed to access the queue: {}", err),
+        }
+    }
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Configures and builds a `Fs` device.
+///
+/// # Example
+///
+/// ```rust
+/// # use std::sync::Arc;
+/// # use virtio_fs::backend::FsBackend;
+/// # use virtio_fs::device::FsBuilder;
+/// # use virtio_fs::fuse::{Reply, Request};
+/// # use vm_memory::{GuestAddress, GuestMemoryMmap};
+/// # use vmm_sys_util::eventfd::EventFd;
+/// // A filesystem which doesn't implement any operation.
+/// struct NoFs;
+///
+/// impl FsBackend for NoFs {
+///     fn handle_request(&mut self, _req: &Request) -> Option<Reply> {
+///         Some(Reply::error(libc::ENOSYS))
+///     }
+/// }
+///
+/// let mem = Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+///
+/// let fs = FsBuilder::new(mem, "myfs", NoFs, EventFd::new(0).unwrap())
+///     .with_num_request_queues(2)
+///     .with_dax_window(GuestAddress(1 << 32), 1 << 30)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug)]
+pub struct FsBuilder<M: GuestAddressSpace, B: FsBackend, S: SignalUsedQueue> {
+    mem: M,
+    tag: String,
+    backend: B,
+    driver_notify: S,
+    num_request_queues: u16,
+    queue_size: u16,
+    dax_window: Option<(GuestAddress, u64)>,
+}
+
+impl<M, B, S> FsBuilder<M, B, S>
+where
+    M: GuestAddressSpace + Clone,
+    B: FsBackend,
+    S: SignalUsedQueue,
+{
+    /// Creates a new `FsBuilder`.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    /// * `tag` - The name the guest uses to mount the filesystem.
+    /// * `backend` - The filesystem which serves the requests.
+    /// * `driver_notify` - The object used for notifying the driver about used buffers.
+    pub fn new(mem: M, tag: &str, backend: B, driver_notify: S) -> Self {
+        FsBuilder {
+            mem,
+            tag: tag.to_owned(),
+            backend,
+            driver_notify,
+            num_request_queues: 1,
+            queue_size: DEFAULT_QUEUE_SIZE,
+            dax_window: None,
+        }
+    }
+
+    /// Sets the number of request queues (one by default).
+    ///
+    /// # Arguments
+    /// * `num_request_queues` - The number of request queues.
+    pub fn with_num_request_queues(mut self, num_request_queues: u16) -> Self {
+        self.num_request_queues = num_request_queues;
+        self
+    }
+
+    /// Sets the maximum size of the queues.
+    ///
+    /// # Arguments
+    /// * `queue_size` - The maximum queue size.
+    pub fn with_queue_size(mut self, queue_size: u16) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Advertises a DAX window to the driver.
+    ///
+    /// # Arguments
+    /// * `addr` - The guest physical address where the window starts.
+    /// * `len` - The length of the window.
+    pub fn with_dax_window(mut self, addr: GuestAddress, len: u64) -> Self {
+        self.dax_window = Some((addr, len));
+        self
+    }
+
+    /// Builds the `Fs` device.
+    pub fn build(self) -> Result<Fs<M, B, S>> {
+        // The high priority queue comes before the request queues.
+        if self.num_request_queues == 0 || self.num_request_queues == u16::MAX {
+            return Err(Error::InvalidNumRequestQueues(self.num_request_queues));
+        }
+        let config_space = ConfigSpace::new(&self.tag, u32::from(self.num_request_queues))
+            .ok_or_else(|| Error::InvalidTag(self.tag.clone()))?;
+
+        let device_features = (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_RING_EVENT_IDX);
+        let queues = (0..self.num_request_queues + REQUEST_QUEUE_BASE)
+            .map(|_| Queue::new(self.mem.clone(), self.queue_size))
+            .collect();
+        let mut cfg = VirtioConfig::new(device_features, queues, config_space.into());
+
+        if let Some((addr, len)) = self.dax_window {
+            if len == 0 || addr.checked_add(len - 1).is_none() {
+                return Err(Error::InvalidDaxWindow(addr, len));
+            }
+            cfg.shm_regions.push(SharedMemoryRegion {
+                id: VIRTIO_FS_SHMCAP_ID_CACHE,
+                addr,
+                len,
+            });
+        }
+
+        Ok(Fs {
+            cfg,
+            backend: self.backend,
+            driver_notify: self.driver_notify,
+        })
+    }
+}
+
+/// A virtio fs device.
+#[derive(Debug, VirtioDeviceCommon)]
+#[virtio(device_type = VIRTIO_ID_FS)]
+pub struct Fs<M: GuestAddressSpace, B: FsBackend, S: SignalUsedQueue> {
+    #[virtio(config)]
+    cfg: VirtioConfig<M>,
+    backend: B,
+    driver_notify: S,
+}
+
+impl<M, B, S> Fs<M, B, S>
+where
+    M: GuestAddressSpace,
+    B: FsBackend,
+    S: SignalUsedQueue,
+{
+    /// Returns whether the device is activated.
+    pub fn is_activated(&self) -> bool {
+        self.cfg.device_activated
+    }
+
+    /// Returns the DAX window advertised to the driver, if any.
+    pub fn dax_window(&self) -> Option<SharedMemoryRegion> {
+        self.cfg.shm_regions.first().copied()
+    }
+
+    /// Returns a reference to the backend.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Returns a mutable reference to the backend.
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    /// Hands over the FUSE requests from a queue to the backend, and writes back the replies.
+    /// This has to be called when the driver notifies the queue.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the high priority queue, or of a request queue.
+    pub fn process_queue(&mut self, index: u16) -> Result<()> {
+        if !self.cfg.device_activated {
+            return Err(Error::InvalidQueueIndex(index));
+        }
+        let queue = self
+            .cfg
+            .queues
+            .get_mut(usize::from(index))
+            .ok_or(Error::InvalidQueueIndex(index))?;
+        while let Some(mut chain) = queue.iter()?.next() {
+            let len = match Request::read_from_chain(&mut chain) {
+                Ok((req, buf)) => match self.backend.handle_request(&req) {
+                    Some(reply) => buf
+                        .write_reply(chain.memory(), req.hdr.unique, &reply)
+                        .unwrap_or_else(|e| {
+                            warn!("failed to write FUSE reply: {}", e);
+                            0
+                        }),
+                    None => 0,
+                },
+                Err(e) => {
+                    warn!("failed to read FUSE request: {}", e);
+                    0
+                }
+            };
+
+            queue.add_used(chain.head_index(), len)?;
+            if queue.needs_notification()? {
+                set_interrupt_status(&self.cfg.interrupt_status, VIRTIO_MMIO_INT_VRING);
+                self.driver_notify.signal_used_queue(index);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<M, B, S> VirtioDeviceActions for Fs<M, B, S>
+where
+    M: GuestAddressSpace,
+    B: FsBackend,
+    S: SignalUsedQueue,
+{
+    type E = Error;
+
+    fn activate(&mut self) -> Result<()> {
+        if self.cfg.device_activated {
+            return Err(Error::AlreadyActivated);
+        }
+        if !self.cfg.queues.iter().all(Queue::is_valid) {
+            return Err(Error::InvalidQueues);
+        }
+        self.cfg.device_activated = true;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        // The driver starts a new session.
+        self.backend.reset();
+
+        let cfg = &mut self.cfg;
+        cfg.driver_features = 0;
+        cfg.device_features_select = 0;
+        cfg.driver_features_select = 0;
+        cfg.device_status = 0;
+        cfg.queue_select = 0;
+        cfg.queues.iter_mut().for_each(Queue::reset);
+        cfg.device_activated = false;
+        cfg.reset_interrupt_status();
+        cfg.shm_select = 0;
+        Ok(())
+    }
+}
+
+impl<M, B, S> VirtioMmioDevice<M> for Fs<M, B, S>
+where
+    M: GuestAddressSpace + 'static,
+    B: FsBackend,
+    S: SignalUsedQueue,
+{
+    fn queue_notify(&mut self, val: u32) {
+        // Queue indices always fit in an `u16`.
+        if let Err(e) = self.process_queue(val as u16) {
+            error!("failed to process queue {}: {}", val, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use vm_memory::{Bytes, GuestMemoryMmap};
+    use vmm_sys_util::eventfd::EventFd;
+
+    use virtio_device::mock::activate;
+    use virtio_device::{VirtioDevice, WithDriverSelect};
+    use virtio_queue::mock::VirtQueue;
+    use virtio_queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    use crate::defs::{FUSE_FORGET, FUSE_INIT, HIPRIO_QUEUE};
+    use crate::fuse::{InHeader, OutHeader, Reply};
+
+    type Mem = Arc<GuestMemoryMmap>;
+
+    // Records the requests, and echoes their arguments.
+    #[derive(Debug, Default)]
+    struct TestBackend {
+        requests: Vec<Request>,
+        resets: usize,
+    }
+
+    impl FsBackend for TestBackend {
+        fn handle_request(&mut self, req: &Request) -> Option<Reply> {
+            self.requests.push(req.clone());
+            match req.hdr.opcode {
+                FUSE_FORGET => None,
+                FUSE_INIT => Some(Reply::ok(req.args.clone())),
+                _ => Some(Reply::error(libc::ENOSYS)),
+            }
+        }
+
+        fn reset(&mut self) {
+            self.resets += 1;
+        }
+    }
+
+    fn fs(mem: &Mem, num_request_queues: u16) -> Fs<Mem, TestBackend, EventFd> {
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        FsBuilder::new(mem.clone(), "myfs", TestBackend::default(), evt)
+            .with_num_request_queues(num_request_queues)
+            .with_queue_size(16)
+            .build()
+            .unwrap()
+    }
+
+    // Writes a request with `args_len` bytes of arguments at `addr`, and makes it available in
+    // `vq` using the descriptors starting at `index`. The reply goes to `reply_addr` when
+    // `reply_len` is not zero.
+    #[allow(clippy::too_many_arguments)]
+    fn add_request(
+        mem: &GuestMemoryMmap,
+        vq: &VirtQueue,
+        index: u16,
+        opcode: u32,
+        addr: u64,
+        args_len: usize,
+        reply_addr: u64,
+        reply_len: u32,
+    ) {
+        let len = InHeader::LEN + args_len;
+        let hdr = InHeader {
+            len: len as u32,
+            opcode,
+            unique: u64::from(index),
+            ..Default::default()
+        };
+        mem.write_obj(hdr, GuestAddress(addr)).unwrap();
+        mem.write_slice(
+            &vec![0xab; args_len],
+            GuestAddress(addr + InHeader::LEN as u64),
+        )
+        .unwrap();
+
+        if reply_len == 0 {
+            vq.dtable(index).set(addr, len as u32, 0, 0);
+        } else {
+            vq.dtable(index)
+                .set(addr, len as u32, VIRTQ_DESC_F_NEXT, index + 1);
+            vq.dtable(index + 1)
+                .set(reply_addr, reply_len, VIRTQ_DESC_F_WRITE, 0);
+        }
+        let avail = vq.avail.idx().load();
+        vq.avail.ring(avail).store(index);
+        vq.avail.idx().store(avail + 1);
+    }
+
+    // Returns the length of the used element `index` from `vq`.
+    fn used_len(mem: &GuestMemoryMmap, vq: &VirtQueue, index: u64) -> u32 {
+        // The used ring starts after the flags and the index, and each element holds the head
+        // index followed by the length.
+        let addr = vq.used_start().unchecked_add(4 + index * 8 + 4);
+        mem.read_obj(addr).unwrap()
+    }
+
+    #[test]
+    fn test_build() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let f = fs(&mem, 2);
+        assert_eq!(VirtioDevice::device_type(&f), VIRTIO_ID_FS);
+        assert_eq!(f.num_queues(), 3);
+        assert_ne!(f.device_features() & (1 << VIRTIO_F_VERSION_1), 0);
+        assert!(f.dax_window().is_none());
+        assert!(f.shm_regions().is_empty());
+
+        let mut tag = [0u8; 5];
+        f.read_config(ConfigSpace::TAG_OFFSET, &mut tag);
+        assert_eq!(&tag, b"myfs\0");
+        let mut num = [0u8; 4];
+        f.read_config(ConfigSpace::NUM_REQUEST_QUEUES_OFFSET, &mut num);
+        assert_eq!(u32::from_le_bytes(num), 2);
+
+        let builder = |tag: &str| {
+            let evt = EventFd::new(0).unwrap();
+            FsBuilder::new(mem.clone(), tag, TestBackend::default(), evt)
+        };
+        assert!(matches!(builder("").build(), Err(Error::InvalidTag(_))));
+        assert!(matches!(
+            builder("myfs").with_num_request_queues(0).build(),
+            Err(Error::InvalidNumRequestQueues(0))
+        ));
+        assert!(matches!(
+            builder("myfs")
+                .with_dax_window(GuestAddress(u64::MAX), 2)
+                .build(),
+            Err(Error::InvalidDaxWindow(_, 2))
+        ));
+        assert!(matches!(
+            builder("myfs")
+                .with_dax_window(GuestAddress(1 << 32), 0)
+                .build(),
+            Err(Error::InvalidDaxWindow(_, 0))
+        ));
+
+        let f = builder("myfs")
+            .with_dax_window(GuestAddress(1 << 32), 1 << 30)
+            .build()
+            .unwrap();
+        let window = SharedMemoryRegion {
+            id: VIRTIO_FS_SHMCAP_ID_CACHE,
+            addr: GuestAddress(1 << 32),
+            len: 1 << 30,
+        };
+        assert_eq!(f.dax_window(), Some(window));
+        assert_eq!(f.selected_shm_region(), Some(window));
+    }
+
+    #[test]
+    fn test_process_queue() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let vqs: Vec<_> = (0..3)
+            .map(|i| VirtQueue::new(GuestAddress(i * 0x1000), &mem, 16))
+            .collect();
+        let mut f = fs(&mem, 2);
+        assert!(matches!(
+            f.process_queue(HIPRIO_QUEUE),
+            Err(Error::InvalidQueueIndex(HIPRIO_QUEUE))
+        ));
+        activate(&mut f, &vqs, 0);
+        assert!(f.is_activated());
+        assert!(matches!(
+            f.process_queue(3),
+            Err(Error::InvalidQueueIndex(3))
+        ));
+
+        // Requests without replies go through the high priority queue.
+        add_request(&mem, &vqs[0], 0, FUSE_FORGET, 0x1_0000, 8, 0, 0);
+        f.queue_notify(u32::from(HIPRIO_QUEUE));
+        assert_eq!(vqs[0].used.idx().load(), 1);
+        assert_eq!(used_len(&mem, &vqs[0], 0), 0);
+        assert_eq!(f.backend().requests.len(), 1);
+        assert_eq!(f.driver_notify.read().unwrap(), 1);
+
+        // A successful request, a failed one, and one with a reply buffer that's too short.
+        add_request(&mem, &vqs[2], 0, FUSE_INIT, 0x2_0000, 0x10, 0x3_0000, 0x100);
+        add_request(&mem, &vqs[2], 2, 0xffff, 0x4_0000, 0, 0x5_0000, 0x100);
+        add_request(&mem, &vqs[2], 4, FUSE_INIT, 0x6_0000, 0x10, 0x7_0000, 0x10);
+        f.queue_notify(u32::from(REQUEST_QUEUE_BASE + 1));
+        assert_eq!(vqs[2].used.idx().load(), 3);
+        assert_eq!(f.backend().requests.len(), 4);
+
+        assert_eq!(used_len(&mem, &vqs[2], 0), 0x20);
+        let hdr: OutHeader = mem.read_obj(GuestAddress(0x3_0000)).unwrap();
+        assert_eq!(
+            hdr,
+            OutHeader {
+                len: 0x20,
+                error: 0,
+                unique: 0
+            }
+        );
+        let mut data = [0u8; 0x10];
+        mem.read_slice(&mut data, GuestAddress(0x3_0010)).unwrap();
+        assert_eq!(data, [0xab; 0x10]);
+
+        assert_eq!(used_len(&mem, &vqs[2], 1), OutHeader::LEN as u32);
+        let hdr: OutHeader = mem.read_obj(GuestAddress(0x5_0000)).unwrap();
+        assert_eq!(hdr.error, -libc::ENOSYS);
+        assert_eq!(hdr.unique, 2);
+
+        assert_eq!(used_len(&mem, &vqs[2], 2), 0);
+
+        VirtioDeviceActions::reset(&mut f).unwrap();
+        assert!(!f.is_activated());
+        assert_eq!(f.backend().resets, 1);
+    }
+}
@@ -0,0 +1,349 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! FUSE message abstractions.
+//!
+//! Every buffer which goes through the queues of a fs device holds a FUSE request, which is
+//! followed by the space for the reply. The device-readable descriptors of the chain hold the
+//! request, and the device-writable ones (which come after them) receive the reply. This module
+//! provides the following abstractions:
+//!
+//! - [`InHeader`](struct.InHeader.html) and [`OutHeader`](struct.OutHeader.html), which are the
+//!   `fuse_in_header` and `fuse_out_header` structures from `linux/fuse.h`.
+//! - [`Request`](struct.Request.html) which holds the request header and the arguments that
+//!   follow it, and can be read from a descriptor chain.
+//! - [`Reply`](struct.Reply.html) which holds the result of a request, and
+//!   [`ReplyBuffer`](struct.ReplyBuffer.html) which is the device-writable part of the chain.
+
+use std::fmt::{self, Display};
+use std::mem::size_of;
+use std::result;
+
+use vm_memory::{ByteValued, GuestAddressSpace, GuestMemory, GuestMemoryError};
+
+use virtio_queue::areas::{self, areas_len, read_areas, split_chain, write_areas, Area};
+use virtio_queue::DescriptorChain;
+
+/// FUSE message errors.
+#[derive(Debug)]
+pub enum Error {
+    /// The descriptor chain is too short to hold the message.
+    DescriptorChainTooShort,
+    /// Invalid memory access.
+    GuestMemory(GuestMemoryError),
+    /// The length from the request header is smaller than the header.
+    InvalidRequestLength(u32),
+    /// Read only descriptor after a write only one.
+    UnexpectedReadOnlyDescriptor,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            DescriptorChainTooShort => write!(f, "descriptor chain too short for the message"),
+            GuestMemory(ref err) => write!(f, "error accessing guest memory: {}", err),
+            InvalidRequestLength(len) => write!(f, "invalid request length: {} bytes", len),
+            UnexpectedReadOnlyDescriptor => write!(f, "unexpected read only descriptor"),
+        }
+    }
+}
+
+impl From<areas::Error> for Error {
+    fn from(e: areas::Error) -> Self {
+        match e {
+            areas::Error::TooShort => Error::DescriptorChainTooShort,
+            areas::Error::GuestMemory(e) => Error::GuestMemory(e),
+            areas::Error::UnexpectedReadOnlyDescriptor => Error::UnexpectedReadOnlyDescriptor,
+        }
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// The header of the FUSE requests (the `fuse_in_header` structure).
+///
+/// All fields are expected to hold little endian values, which is always the case on the
+/// platforms that are currently supported (`x86_64` and `aarch64`).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct InHeader {
+    /// The length of the request, including the header.
+    pub len: u32,
+    /// The operation.
+    pub opcode: u32,
+    /// The identifier of the request, which is echoed by the reply.
+    pub unique: u64,
+    /// The inode the request refers to.
+    pub nodeid: u64,
+    /// The user identifier of the caller.
+    pub uid: u32,
+    /// The group identifier of the caller.
+    pub gid: u32,
+    /// The process identifier of the caller.
+    pub pid: u32,
+    /// Unused.
+    pub padding: u32,
+}
+
+// Safe because InHeader only contains plain data, and there's no padding between (or after)
+// the fields.
+unsafe impl ByteValued for InHeader {}
+
+impl InHeader {
+    /// The size of the request header.
+    pub const LEN: usize = size_of::<InHeader>();
+}
+
+/// The header of the FUSE replies (the `fuse_out_header` structure).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct OutHeader {
+    /// The length of the reply, including the header.
+    pub len: u32,
+    /// Zero, or a negated `errno` value.
+    pub error: i32,
+    /// The identifier of the request.
+    pub unique: u64,
+}
+
+// Safe because OutHeader only contains plain data, and there's no padding between (or after)
+// the fields.
+unsafe impl ByteValued for OutHeader {}
+
+impl OutHeader {
+    /// The size of the reply header.
+    pub const LEN: usize = size_of::<OutHeader>();
+}
+
+/// A FUSE request, which consists of a header and the operation specific arguments.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Request {
+    /// The request header.
+    pub hdr: InHeader,
+    /// The arguments that follow the header.
+    pub args: Vec<u8>,
+}
+
+impl Request {
+    /// Reads a request from a descriptor chain, and returns it together with the
+    /// device-writable part of the chain, where the reply goes.
+    ///
+    /// # Arguments
+    /// * `chain` - The request descriptor chain.
+    pub fn read_from_chain<M: GuestAddressSpace>(
+        chain: &mut DescriptorChain<M>,
+    ) -> Result<(Self, ReplyBuffer)> {
+        let (readable, writable) = split_chain(chain)?;
+
+        let mut hdr = InHeader::default();
+        read_areas(chain.memory(), &readable, 0, hdr.as_mut_slice())?;
+        // The arguments are bounded by the length of the chain, which is checked before
+        // allocating the buffer.
+        let args_len = (hdr.len as usize)
+            .checked_sub(InHeader::LEN)
+            .ok_or(Error::InvalidRequestLength(hdr.len))?;
+        if areas_len(&readable) < hdr.len as usize {
+            return Err(Error::DescriptorChainTooShort);
+        }
+        let mut args = vec![0; args_len];
+        read_areas(chain.memory(), &readable, InHeader::LEN, &mut args)?;
+
+        Ok((Request { hdr, args }, ReplyBuffer { areas: writable }))
+    }
+}
+
+/// The reply to a FUSE request.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Reply {
+    /// Zero, or a negated `errno` value.
+    pub error: i32,
+    /// The operation specific data that follows the header, which is only present when the
+    /// request succeeds.
+    pub data: Vec<u8>,
+}
+
+impl Reply {
+    /// Creates a successful reply.
+    ///
+    /// # Arguments
+    /// * `data` - The data of the reply.
+    pub fn ok(data: Vec<u8>) -> Self {
+        Reply { error: 0, data }
+    }
+
+    /// Creates a reply for a failed request.
+    ///
+    /// # Arguments
+    /// * `errno` - The (positive) error number.
+    pub fn error(errno: i32) -> Self {
+        Reply {
+            error: -errno,
+            data: Vec::new(),
+        }
+    }
+}
+
+/// The device-writable part of a request descriptor chain, which receives the reply.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReplyBuffer {
+    areas: Vec<Area>,
+}
+
+impl ReplyBuffer {
+    /// Returns the size of the buffer.
+    pub fn len(&self) -> usize {
+        areas_len(&self.areas)
+    }
+
+    /// Returns whether the buffer is empty, i.e. for requests which don't expect a reply.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Writes the reply to a request, and returns the number of bytes written.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    /// * `unique` - The identifier of the request.
+    /// * `reply` - The reply.
+    pub fn write_reply<M: GuestMemory>(&self, mem: &M, unique: u64, reply: &Reply) -> Result<u32> {
+        let len = OutHeader::LEN + reply.data.len();
+        if len > self.len() {
+            return Err(Error::DescriptorChainTooShort);
+        }
+        let hdr = OutHeader {
+            // The length fits in an `u32` because it's bounded by the length of the chain.
+            len: len as u32,
+            error: reply.error,
+            unique,
+        };
+        write_areas(mem, &self.areas, 0, hdr.as_slice())?;
+        write_areas(mem, &self.areas, OutHeader::LEN, &reply.data)?;
+        Ok(len as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+    use virtio_device::mock::adjacent_regions;
+    use virtio_queue::mock::{AttackPattern, VirtQueue};
+    use virtio_queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    use crate::defs::FUSE_INIT;
+
+    fn header(args_len: usize) -> InHeader {
+        InHeader {
+            len: (InHeader::LEN + args_len) as u32,
+            opcode: FUSE_INIT,
+            unique: 7,
+            nodeid: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(InHeader::LEN, 40);
+        assert_eq!(OutHeader::LEN, 16);
+        assert_eq!(Reply::error(libc::ENOENT).error, -libc::ENOENT);
+    }
+
+    #[test]
+    fn test_request() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let mut queue = vq.create_queue(&mem);
+
+        mem.write_obj(header(8), GuestAddress(0x1_0000)).unwrap();
+        mem.write_slice(&[0xab; 8], GuestAddress(0x1_0000 + InHeader::LEN as u64))
+            .unwrap();
+
+        // The header is split across the first two descriptors, and the reply buffer spans
+        // two descriptors as well.
+        vq.dtable(0).set(0x1_0000, 20, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1).set(0x1_0014, 28, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable(2)
+            .set(0x2_0000, 10, VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT, 3);
+        vq.dtable(3).set(0x3_0000, 0x100, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring(0).store(0);
+        // A chain which is shorter than the request.
+        vq.dtable(4).set(0x1_0000, 44, 0, 0);
+        vq.avail.ring(1).store(4);
+        // A chain with a read only descriptor after a write only one.
+        vq.dtable(5)
+            .set(0x2_0000, 0x10, VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT, 6);
+        vq.dtable(6).set(0x1_0000, 48, 0, 0);
+        vq.avail.ring(2).store(5);
+        vq.avail.idx().store(3);
+
+        let mut iter = queue.iter().unwrap();
+        let (req, buf) = Request::read_from_chain(&mut iter.next().unwrap()).unwrap();
+        assert_eq!(req.hdr, header(8));
+        assert_eq!(req.args, [0xab; 8]);
+        assert_eq!(buf.len(), 0x10a);
+        assert!(matches!(
+            Request::read_from_chain(&mut iter.next().unwrap()),
+            Err(Error::DescriptorChainTooShort)
+        ));
+        assert!(matches!(
+            Request::read_from_chain(&mut iter.next().unwrap()),
+            Err(Error::UnexpectedReadOnlyDescriptor)
+        ));
+
+        let reply = Reply::ok(vec![0xcd; 0x20]);
+        assert_eq!(buf.write_reply(&mem, 7, &reply).unwrap(), 0x30);
+        let hdr: OutHeader = mem.read_obj(GuestAddress(0x2_0000)).unwrap();
+        assert_eq!(
+            hdr,
+            OutHeader {
+                len: 0x30,
+                error: 0,
+                unique: 7
+            }
+        );
+        let mut data = [0u8; 0x20];
+        mem.read_slice(&mut data, GuestAddress(0x3_0006)).unwrap();
+        assert_eq!(data, [0xcd; 0x20]);
+
+        let reply = Reply::ok(vec![0; 0x100]);
+        assert!(matches!(
+            buf.write_reply(&mem, 7, &reply),
+            Err(Error::DescriptorChainTooShort)
+        ));
+
+        // The header length is validated.
+        let mut hdr = header(0);
+        hdr.len = 8;
+        mem.write_obj(hdr, GuestAddress(0x1_0000)).unwrap();
+        vq.avail.ring(3).store(0);
+        vq.avail.idx().store(4);
+        let mut chain = queue.iter().unwrap().next().unwrap();
+        assert!(matches!(
+            Request::read_from_chain(&mut chain),
+            Err(Error::InvalidRequestLength(8))
+        ));
+    }
+
+    #[test]
+    fn test_attack_patterns() {
+        let mem = adjacent_regions(0x10_0000);
+        for &pattern in AttackPattern::ALL.iter() {
+            let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+            vq.add_attack(pattern, 0, GuestAddress(0x1_0000));
+            let mut chain = vq.create_queue(&mem).iter().unwrap().next().unwrap();
+            assert!(
+                Request::read_from_chain(&mut chain).is_err(),
+                "{:?}",
+                pattern
+            );
+        }
+    }
+}
@@ -0,0 +1,28 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A crate that provides fs device specific components as described
+//! by the virtio specification.
+
+#![deny(missing_docs)]
+
+/// Contains the interface between the fs device and the filesystem implementation.
+pub mod backend;
+
+/// Contains the fs device configuration space abstraction.
+pub mod config;
+
+/// Contains virtio fs constant definitions.
+pub mod defs;
+
+/// Contains a reference virtio fs device implementation.
+pub mod device;
+
+/// Contains the FUSE message abstractions.
+pub mod fuse;
+
+/// Contains a virtio fs device frontend which forwards the queues to an external vhost-user-fs
+/// backend, and maps its DAX window requests.
+#[cfg(feature = "vhost-user")]
+pub mod vhost_user;
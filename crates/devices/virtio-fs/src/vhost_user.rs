@@ -0,0 +1,1104 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A virtio fs device frontend for external vhost-user-fs backends.
+//!
+//! This module provides the following abstractions:
+//!
+//! - [`VhostUserFs`](struct.VhostUserFs.html) which implements the `VirtioDevice` and
+//!   `VirtioMmioDevice` interfaces, and forwards the queues over a Unix socket to a
+//!   vhost-user-fs backend (such as virtiofsd), which accesses the guest memory directly.
+//! - [`VhostUserFsBuilder`](struct.VhostUserFsBuilder.html) which configures and creates a
+//!   `VhostUserFs` device.
+//! - [`DaxCache`](struct.DaxCache.html) which holds the host mapping of the DAX window.
+//!
+//! The configuration space (the tag and the number of request queues) is provided by the
+//! device itself. When the device advertises a DAX window, the backend maps file ranges into
+//! it through the slave channel (with the `VHOST_USER_SLAVE_FS_*` requests), so it has to
+//! support `VHOST_USER_PROTOCOL_F_SLAVE_REQ` and `VHOST_USER_PROTOCOL_F_SLAVE_SEND_FD`. The
+//! device reserves the host address space of the window (see
+//! [`VhostUserFs::dax_cache`](struct.VhostUserFs.html#method.dax_cache)), and the VMM is
+//! expected to make it available to the guest at the address of the window (i.e. as a KVM
+//! memory slot). The VMM has to call
+//! [`VhostUserFs::process_slave_request`](struct.VhostUserFs.html#method.process_slave_request)
+//! when the slave channel becomes readable. The window is emptied when the device is reset.
+//!
+//! As for the other vhost-user devices, the kick `EventFd`s are expected to be registered as
+//! ioeventfds (or triggered by the `VirtioMmioDevice::queue_notify` implementation), and
+//! [`VhostUserFs::process_call_event`](struct.VhostUserFs.html#method.process_call_event) has
+//! to be called when one of the call `EventFd`s becomes readable.
+
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::ptr::null_mut;
+use std::result;
+
+use log::{error, warn};
+use vm_memory::{Address, ByteValued, GuestAddress, GuestAddressSpace};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+use virtio_device::vhost_user::{
+    self, SlaveChannel, SlaveRequest, VhostUserFrontend, SLAVE_PROTOCOL_FEATURES,
+    VHOST_USER_PROTOCOL_F_SLAVE_SEND_FD,
+};
+use virtio_device::{
+    SharedMemoryRegion, SignalUsedQueue, VirtioConfig, VirtioDeviceActions, VirtioDeviceCommon,
+    VirtioMmioDevice,
+};
+use virtio_queue::Queue;
+
+use crate::config::ConfigSpace;
+use crate::defs::{
+    DEFAULT_QUEUE_SIZE, REQUEST_QUEUE_BASE, VIRTIO_FS_SHMCAP_ID_CACHE, VIRTIO_ID_FS,
+};
+
+pub use virtio_device::vhost_user::{
+    VHOST_USER_F_PROTOCOL_FEATURES, VHOST_USER_PROTOCOL_F_MQ, VHOST_USER_PROTOCOL_F_REPLY_ACK,
+    VHOST_USER_PROTOCOL_F_SLAVE_REQ,
+};
+
+// The vhost-user-fs requests sent by the backend through the slave channel.
+/// Maps file ranges into the DAX window.
+pub const VHOST_USER_SLAVE_FS_MAP: u32 = 6;
+/// Removes mappings from the DAX window.
+pub const VHOST_USER_SLAVE_FS_UNMAP: u32 = 7;
+/// Writes back the changes made through the mappings of the DAX window.
+pub const VHOST_USER_SLAVE_FS_SYNC: u32 = 8;
+/// Reads or writes file ranges through guest memory (not supported).
+pub const VHOST_USER_SLAVE_FS_IO: u32 = 9;
+
+/// The number of ranges in a `FsSlaveMsg`.
+pub const VHOST_USER_FS_SLAVE_ENTRIES: usize = 8;
+/// The mapping is readable.
+pub const VHOST_USER_FS_FLAG_MAP_R: u64 = 1;
+/// The mapping is writable.
+pub const VHOST_USER_FS_FLAG_MAP_W: u64 = 2;
+
+/// vhost-user fs device errors.
+#[derive(Debug)]
+pub enum Error {
+    /// The device was already activated.
+    AlreadyActivated,
+    /// Failed to reserve the host address space of the DAX window.
+    DaxCache(io::Error),
+    /// Failed to create or use an `EventFd`.
+    EventFd(io::Error),
+    /// The DAX window is empty, or it doesn't fit in the guest physical address space.
+    InvalidDaxWindow(GuestAddress, u64),
+    /// The number of request queues is zero, or too large.
+    InvalidNumRequestQueues(u16),
+    /// The queues are not configured properly.
+    InvalidQueues,
+    /// The queue index is not valid.
+    InvalidQueueIndex(u16),
+    /// The tag is empty, or too long.
+    InvalidTag(String),
+    /// The device doesn't have a slave channel, since it doesn't advertise a DAX window.
+    NoSlaveChannel,
+    /// Failed to communicate with the backend.
+    VhostUser(vhost_user::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            AlreadyActivated => write!(f, "the device is already activated"),
+            DaxCache(ref err) => write!(f, "failed to reserve the DAX window: {}", err),
+            EventFd(ref err) => write!(f, "eventfd error: {}", err),
+            InvalidDaxWindow(addr, len) => write!(
+                f,
+                "invalid DAX window at 0x{:x} with length 0x{:x}",
+                addr.0, len
+            ),
+            InvalidNumRequestQueues(num) => write!(f, "invalid number of request queues {}", num),
+            InvalidQueues => write!(f, "invalid queue configuration"),
+            InvalidQueueIndex(index) => write!(f, "invalid queue {}", index),
+            InvalidTag(ref tag) => write!(f, "invalid filesystem tag \"{}\"", tag),
+            NoSlaveChannel => write!(f, "the device doesn't have a slave channel"),
+            VhostUser(ref err) => write!(f, "vhost-user error: {}", err),
+        }
+    }
+}
+
+impl From<vhost_user::Error> for Error {
+    fn from(e: vhost_user::Error) -> Self {
+        Error::VhostUser(e)
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// The payload of the `VHOST_USER_SLAVE_FS_*` requests, which describes up to
+/// `VHOST_USER_FS_SLAVE_ENTRIES` ranges. The ranges with a zero length are ignored.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct FsSlaveMsg {
+    /// The offsets of the ranges in the file sent along with the request.
+    pub fd_offset: [u64; VHOST_USER_FS_SLAVE_ENTRIES],
+    /// The offsets of the ranges in the DAX window.
+    pub cache_offset: [u64; VHOST_USER_FS_SLAVE_ENTRIES],
+    /// The lengths of the ranges.
+    pub len: [u64; VHOST_USER_FS_SLAVE_ENTRIES],
+    /// The `VHOST_USER_FS_FLAG_MAP_*` flags of the ranges.
+    pub flags: [u64; VHOST_USER_FS_SLAVE_ENTRIES],
+}
+
+// Safe because FsSlaveMsg contains only plain data.
+unsafe impl ByteValued for FsSlaveMsg {}
+
+/// The host mapping of the DAX window. The address space is reserved when the cache is
+/// created, and it's inaccessible until the backend maps file ranges into it.
+#[derive(Debug)]
+pub struct DaxCache {
+    addr: *mut u8,
+    size: u64,
+}
+
+// Safe because the cache owns its mapping, which is only changed through `mmap` calls.
+unsafe impl Send for DaxCache {}
+// Safe because the cache owns its mapping, which is only changed through `mmap` calls.
+unsafe impl Sync for DaxCache {}
+
+impl DaxCache {
+    /// Reserves the host address space of a DAX window.
+    ///
+    /// # Arguments
+    /// * `size` - The size of the DAX window.
+    pub fn new(size: u64) -> io::Result<Self> {
+        // Safe because a new anonymous mapping is created, and the return value is checked.
+        let addr = unsafe {
+            libc::mmap(
+                null_mut(),
+                size as usize,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(DaxCache {
+            addr: addr as *mut u8,
+            size,
+        })
+    }
+
+    /// Returns the host address where the DAX window starts.
+    pub fn host_addr(&self) -> *mut u8 {
+        self.addr
+    }
+
+    /// Returns the size of the DAX window.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // Returns the host address of a range of the window, or `EINVAL` if it doesn't fit.
+    fn range_addr(&self, offset: u64, len: u64) -> io::Result<*mut libc::c_void> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.size => {
+                // The offset is within the mapping, so the resulting pointer is valid.
+                Ok(self.addr.wrapping_add(offset as usize) as *mut libc::c_void)
+            }
+            _ => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+
+    /// Maps a file range into the window, replacing the previous mappings of that range. The
+    /// offsets and the length have to be page aligned.
+    ///
+    /// # Arguments
+    /// * `file` - The file whose contents are mapped.
+    /// * `fd_offset` - The offset of the range in the file.
+    /// * `cache_offset` - The offset of the range in the window.
+    /// * `len` - The length of the range.
+    /// * `flags` - The `VHOST_USER_FS_FLAG_MAP_*` flags of the mapping.
+    pub fn map(
+        &self,
+        file: &File,
+        fd_offset: u64,
+        cache_offset: u64,
+        len: u64,
+        flags: u64,
+    ) -> io::Result<()> {
+        let addr = self.range_addr(cache_offset, len)?;
+        let mut prot = 0;
+        if flags & VHOST_USER_FS_FLAG_MAP_R != 0 {
+            prot |= libc::PROT_READ;
+        }
+        if flags & VHOST_USER_FS_FLAG_MAP_W != 0 {
+            prot |= libc::PROT_WRITE;
+        }
+        // Safe because the range is within the mapping owned by the cache, and the return
+        // value is checked.
+        let ret = unsafe {
+            libc::mmap(
+                addr,
+                len as usize,
+                prot,
+                libc::MAP_SHARED | libc::MAP_FIXED,
+                file.as_raw_fd(),
+                fd_offset as libc::off_t,
+            )
+        };
+        if ret == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Removes the mappings of a range of the window, which becomes inaccessible again.
+    ///
+    /// # Arguments
+    /// * `cache_offset` - The offset of the range in the window.
+    /// * `len` - The length of the range.
+    pub fn unmap(&self, cache_offset: u64, len: u64) -> io::Result<()> {
+        let addr = self.range_addr(cache_offset, len)?;
+        // Safe because the range is within the mapping owned by the cache, and the return
+        // value is checked.
+        let ret = unsafe {
+            libc::mmap(
+                addr,
+                len as usize,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE | libc::MAP_FIXED,
+                -1,
+                0,
+            )
+        };
+        if ret == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Writes back the changes made through the mappings of a range of the window.
+    ///
+    /// # Arguments
+    /// * `cache_offset` - The offset of the range in the window.
+    /// * `len` - The length of the range.
+    pub fn sync(&self, cache_offset: u64, len: u64) -> io::Result<()> {
+        let addr = self.range_addr(cache_offset, len)?;
+        // Safe because the range is within the mapping owned by the cache, and the return
+        // value is checked.
+        if unsafe { libc::msync(addr, len as usize, libc::MS_SYNC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Executes a request received through the slave channel.
+    fn handle_request(&self, request: &SlaveRequest) -> io::Result<()> {
+        let msg = request
+            .payload_obj::<FsSlaveMsg>()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+        let ranges = (0..VHOST_USER_FS_SLAVE_ENTRIES).filter(|&i| msg.len[i] != 0);
+
+        match request.request {
+            VHOST_USER_SLAVE_FS_MAP => {
+                let file = request
+                    .files
+                    .first()
+                    .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))?;
+                for i in ranges {
+                    self.map(
+                        file,
+                        msg.fd_offset[i],
+                        msg.cache_offset[i],
+                        msg.len[i],
+                        msg.flags[i],
+                    )?;
+                }
+            }
+            VHOST_USER_SLAVE_FS_UNMAP => {
+                for i in ranges {
+                    // A length of `u64::MAX` stands for the rest of the window.
+                    let len = match msg.len[i] {
+                        u64::MAX => self.size.saturating_sub(msg.cache_offset[i]),
+                        len => len,
+                    };
+                    self.unmap(msg.cache_offset[i], len)?;
+                }
+            }
+            VHOST_USER_SLAVE_FS_SYNC => {
+                for i in ranges {
+                    self.sync(msg.cache_offset[i], msg.len[i])?;
+                }
+            }
+            _ => return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP)),
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DaxCache {
+    fn drop(&mut self) {
+        // Safe because the mapping is owned by the cache.
+        unsafe { libc::munmap(self.addr as *mut libc::c_void, self.size as usize) };
+    }
+}
+
+/// Configures and builds a `VhostUserFs` device.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # use std::os::unix::net::UnixStream;
+/// # use std::sync::Arc;
+/// # use virtio_fs::vhost_user::VhostUserFsBuilder;
+/// # use vm_memory::{FileOffset, GuestAddress, GuestMemoryMmap};
+/// # use vmm_sys_util::eventfd::EventFd;
+/// # use vmm_sys_util::tempfile::TempFile;
+/// // The guest memory has to be shared with the backend.
+/// let file = TempFile::new().unwrap().into_file();
+/// file.set_len(0x10_0000).unwrap();
+/// let mem = Arc::new(
+///     GuestMemoryMmap::from_ranges_with_files(&[(
+///         GuestAddress(0),
+///         0x10_0000,
+///         Some(FileOffset::new(file, 0)),
+///     )])
+///     .unwrap(),
+/// );
+///
+/// let stream = UnixStream::connect("/tmp/virtiofsd.sock").unwrap();
+/// let fs = VhostUserFsBuilder::new(mem, stream, "myfs", EventFd::new(0).unwrap())
+///     .with_dax_window(GuestAddress(1 << 32), 1 << 30)
+///     .build()
+///     .unwrap();
+/// // The host address space of the window has to be made available to the guest.
+/// let cache = fs.dax_cache().unwrap();
+/// ```
+#[derive(Debug)]
+pub struct VhostUserFsBuilder<M: GuestAddressSpace, S: SignalUsedQueue> {
+    mem: M,
+    stream: UnixStream,
+    tag: String,
+    driver_notify: S,
+    num_request_queues: u16,
+    queue_size: u16,
+    dax_window: Option<(GuestAddress, u64)>,
+}
+
+impl<M, S> VhostUserFsBuilder<M, S>
+where
+    M: GuestAddressSpace + Clone,
+    S: SignalUsedQueue,
+{
+    /// Creates a new `VhostUserFsBuilder`.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory, whose regions have to be backed by files.
+    /// * `stream` - The socket connected to the vhost-user backend.
+    /// * `tag` - The name the guest uses to mount the filesystem.
+    /// * `driver_notify` - The object used for notifying the driver about used buffers.
+    pub fn new(mem: M, stream: UnixStream, tag: &str, driver_notify: S) -> Self {
+        VhostUserFsBuilder {
+            mem,
+            stream,
+            tag: tag.to_owned(),
+            driver_notify,
+            num_request_queues: 1,
+            queue_size: DEFAULT_QUEUE_SIZE,
+            dax_window: None,
+        }
+    }
+
+    /// Sets the number of request queues (one by default).
+    ///
+    /// # Arguments
+    /// * `num_request_queues` - The number of request queues.
+    pub fn with_num_request_queues(mut self, num_request_queues: u16) -> Self {
+        self.num_request_queues = num_request_queues;
+        self
+    }
+
+    /// Sets the maximum size of the queues.
+    ///
+    /// # Arguments
+    /// * `queue_size` - The maximum queue size.
+    pub fn with_queue_size(mut self, queue_size: u16) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Advertises a DAX window to the driver, whose host address space is reserved by the
+    /// device.
+    ///
+    /// # Arguments
+    /// * `addr` - The guest physical address where the window starts.
+    /// * `len` - The length of the window.
+    pub fn with_dax_window(mut self, addr: GuestAddress, len: u64) -> Self {
+        self.dax_window = Some((addr, len));
+        self
+    }
+
+    /// Negotiates the protocol features with the backend, sets up the slave channel when
+    /// there's a DAX window, and builds the `VhostUserFs` device.
+    pub fn build(self) -> Result<VhostUserFs<M, S>> {
+        // The high priority queue comes before the request queues.
+        if self.num_request_queues == 0 || self.num_request_queues == u16::MAX {
+            return Err(Error::InvalidNumRequestQueues(self.num_request_queues));
+        }
+        let config_space = ConfigSpace::new(&self.tag, u32::from(self.num_request_queues))
+            .ok_or_else(|| Error::InvalidTag(self.tag.clone()))?;
+        if let Some((addr, len)) = self.dax_window {
+            if len == 0 || addr.checked_add(len - 1).is_none() {
+                return Err(Error::InvalidDaxWindow(addr, len));
+            }
+        }
+
+        let mut frontend = match self.dax_window {
+            Some(_) => {
+                VhostUserFrontend::new_with_protocol_features(self.stream, SLAVE_PROTOCOL_FEATURES)?
+            }
+            None => VhostUserFrontend::new(self.stream)?,
+        };
+        let num_queues = self.num_request_queues + REQUEST_QUEUE_BASE;
+        frontend.check_queue_num(num_queues)?;
+
+        let mut shm_regions = Vec::new();
+        let (mut dax_cache, mut slave) = (None, None);
+        if let Some((addr, len)) = self.dax_window {
+            frontend.require_protocol_feature(VHOST_USER_PROTOCOL_F_SLAVE_REQ)?;
+            frontend.require_protocol_feature(VHOST_USER_PROTOCOL_F_SLAVE_SEND_FD)?;
+            dax_cache = Some(DaxCache::new(len).map_err(Error::DaxCache)?);
+            slave = Some(frontend.create_slave_channel()?);
+            shm_regions.push(SharedMemoryRegion {
+                id: VIRTIO_FS_SHMCAP_ID_CACHE,
+                addr,
+                len,
+            });
+        }
+
+        let (mem, queue_size) = (self.mem, self.queue_size);
+        let queues = (0..num_queues)
+            .map(|_| Queue::new(mem.clone(), queue_size))
+            .collect();
+        let new_eventfds = || {
+            (0..num_queues)
+                .map(|_| EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd))
+                .collect::<Result<Vec<_>>>()
+        };
+        let mut cfg = VirtioConfig::new(frontend.features(), queues, config_space.into());
+        cfg.shm_regions = shm_regions;
+
+        Ok(VhostUserFs {
+            cfg,
+            mem,
+            frontend,
+            kick_evts: new_eventfds()?,
+            call_evts: new_eventfds()?,
+            driver_notify: self.driver_notify,
+            slave,
+            dax_cache,
+        })
+    }
+}
+
+/// A virtio fs device whose requests are processed by a vhost-user backend.
+#[derive(Debug, VirtioDeviceCommon)]
+#[virtio(device_type = VIRTIO_ID_FS)]
+pub struct VhostUserFs<M: GuestAddressSpace, S: SignalUsedQueue> {
+    #[virtio(config)]
+    cfg: VirtioConfig<M>,
+    mem: M,
+    frontend: VhostUserFrontend,
+    // Used by the driver (or the VMM) to notify the backend about available buffers.
+    kick_evts: Vec<EventFd>,
+    // Used by the backend to notify the VMM about used buffers.
+    call_evts: Vec<EventFd>,
+    driver_notify: S,
+    // The channel the backend uses for mapping file ranges into the DAX window.
+    slave: Option<SlaveChannel>,
+    dax_cache: Option<DaxCache>,
+}
+
+impl<M, S> VhostUserFs<M, S>
+where
+    M: GuestAddressSpace,
+    S: SignalUsedQueue,
+{
+    /// Returns whether the device is activated.
+    pub fn is_activated(&self) -> bool {
+        self.cfg.device_activated
+    }
+
+    /// Returns the DAX window advertised to the driver, if any.
+    pub fn dax_window(&self) -> Option<SharedMemoryRegion> {
+        self.cfg.shm_regions.first().copied()
+    }
+
+    /// Returns the host mapping of the DAX window, if any, which the VMM has to make available
+    /// to the guest at the address of the window.
+    pub fn dax_cache(&self) -> Option<&DaxCache> {
+        self.dax_cache.as_ref()
+    }
+
+    /// Returns the slave channel, if the device advertises a DAX window. The VMM is expected
+    /// to call [`process_slave_request`](#method.process_slave_request) when it becomes
+    /// readable.
+    pub fn slave_channel(&self) -> Option<&SlaveChannel> {
+        self.slave.as_ref()
+    }
+
+    /// Returns the `EventFd` which notifies the backend about the buffers made available in the
+    /// queue with the specified index. It can be registered as an ioeventfd for the queue.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the high priority queue, or of a request queue.
+    pub fn kick_eventfd(&self, index: u16) -> Option<&EventFd> {
+        self.kick_evts.get(usize::from(index))
+    }
+
+    /// Returns the `EventFd` which the backend uses for signaling used buffers in the queue with
+    /// the specified index.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the high priority queue, or of a request queue.
+    pub fn call_eventfd(&self, index: u16) -> Option<&EventFd> {
+        self.call_evts.get(usize::from(index))
+    }
+
+    /// Updates the interrupt status and notifies the driver after the backend signaled used
+    /// buffers. This has to be called when the call `EventFd` of the queue becomes readable.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the high priority queue, or of a request queue.
+    pub fn process_call_event(&self, index: u16) -> Result<()> {
+        let call_evt = self
+            .call_eventfd(index)
+            .ok_or(Error::InvalidQueueIndex(index))?;
+        match call_evt.read() {
+            Ok(_) => {}
+            // Spurious wakeup, the event was already consumed.
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(Error::EventFd(e)),
+        }
+        self.cfg.signal_used_queue();
+        self.driver_notify.signal_used_queue(index);
+        Ok(())
+    }
+
+    /// Receives a request from the backend through the slave channel, updates the DAX window
+    /// accordingly, and sends back the result, which is the negated `errno` value on failure.
+    /// This has to be called when the slave channel becomes readable.
+    pub fn process_slave_request(&mut self) -> Result<()> {
+        let (slave, dax_cache) = match (self.slave.as_mut(), self.dax_cache.as_ref()) {
+            (Some(slave), Some(dax_cache)) => (slave, dax_cache),
+            _ => return Err(Error::NoSlaveChannel),
+        };
+        let request = slave.recv_request()?;
+        let result = match dax_cache.handle_request(&request) {
+            Ok(()) => 0,
+            Err(e) => {
+                warn!(
+                    "failed to handle backend request {}: {}",
+                    request.request, e
+                );
+                -i64::from(e.raw_os_error().unwrap_or(libc::EIO)) as u64
+            }
+        };
+        slave.send_reply(&request, result)?;
+        Ok(())
+    }
+
+    // Sends the guest memory and queue configuration to the backend, and starts the queues.
+    fn setup_backend(&mut self) -> Result<()> {
+        let mem = self.mem.memory();
+        self.frontend.start(
+            &*mem,
+            self.cfg.driver_features,
+            &self.cfg.queues,
+            &self.kick_evts,
+            &self.call_evts,
+        )?;
+        Ok(())
+    }
+
+    // Stops the queues of the backend.
+    fn stop_backend(&mut self) -> Result<()> {
+        // The number of queues always fits in an `u16`.
+        self.frontend.stop(self.cfg.queues.len() as u16)?;
+        Ok(())
+    }
+}
+
+impl<M, S> VirtioDeviceActions for VhostUserFs<M, S>
+where
+    M: GuestAddressSpace,
+    S: SignalUsedQueue,
+{
+    type E = Error;
+
+    fn activate(&mut self) -> Result<()> {
+        if self.cfg.device_activated {
+            return Err(Error::AlreadyActivated);
+        }
+        if !self.cfg.queues.iter().all(Queue::is_valid) {
+            return Err(Error::InvalidQueues);
+        }
+
+        self.setup_backend()?;
+        self.cfg.device_activated = true;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        // The device is reset even if the backend fails, and the error is reported afterwards.
+        let stopped = if self.cfg.device_activated {
+            self.stop_backend()
+        } else {
+            Ok(())
+        };
+        // The driver starts a new session, with an empty DAX window.
+        if let Some(dax_cache) = self.dax_cache.as_ref() {
+            if let Err(e) = dax_cache.unmap(0, dax_cache.size()) {
+                error!("failed to empty the DAX window: {}", e);
+            }
+        }
+
+        let cfg = &mut self.cfg;
+        cfg.driver_features = 0;
+        cfg.device_features_select = 0;
+        cfg.driver_features_select = 0;
+        cfg.device_status = 0;
+        cfg.queue_select = 0;
+        cfg.queues.iter_mut().for_each(Queue::reset);
+        cfg.device_activated = false;
+        cfg.reset_interrupt_status();
+        cfg.shm_select = 0;
+        stopped
+    }
+}
+
+impl<M, S> VirtioMmioDevice<M> for VhostUserFs<M, S>
+where
+    M: GuestAddressSpace + 'static,
+    S: SignalUsedQueue,
+{
+    fn queue_notify(&mut self, val: u32) {
+        // Queue indices always fit in an `u16`.
+        match self.kick_eventfd(val as u16) {
+            Some(kick_evt) => {
+                if let Err(e) = kick_evt.write(1) {
+                    error!("failed to kick queue {}: {}", val, e);
+                }
+            }
+            None => error!("invalid queue {}", val),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::mem::size_of;
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+
+    use vm_memory::{FileOffset, GuestMemoryMmap};
+    use vmm_sys_util::sock_ctrl_msg::ScmSocket;
+    use vmm_sys_util::tempfile::TempFile;
+
+    use virtio_device::mock::{recv_backend_message, BackendMessage};
+    use virtio_device::status::{ACKNOWLEDGE, DRIVER, DRIVER_OK, FEATURES_OK};
+    use virtio_device::vhost_user::{
+        Header, VringState, SUPPORTED_PROTOCOL_FEATURES, VHOST_USER_GET_FEATURES,
+        VHOST_USER_GET_PROTOCOL_FEATURES, VHOST_USER_GET_QUEUE_NUM, VHOST_USER_GET_VRING_BASE,
+        VHOST_USER_NEED_REPLY, VHOST_USER_REPLY, VHOST_USER_SET_SLAVE_REQ_FD,
+        VHOST_USER_SET_VRING_KICK, VHOST_USER_VERSION,
+    };
+    use virtio_device::{VirtioDevice, WithDriverSelect};
+    use virtio_queue::mock::VirtQueue;
+
+    type Mem = Arc<GuestMemoryMmap>;
+    // The device, the fake backend and the receiving end of its slave channel.
+    type Built = (
+        Result<VhostUserFs<Mem, EventFd>>,
+        JoinHandle<Vec<u32>>,
+        Receiver<UnixStream>,
+    );
+
+    const VIRTIO_F_VERSION_1: u64 = 32;
+    const BACKEND_FEATURES: u64 = (1 << VIRTIO_F_VERSION_1) | (1 << VHOST_USER_F_PROTOCOL_FEATURES);
+    const DAX_ADDR: GuestAddress = GuestAddress(0x1_0000_0000);
+    const DAX_LEN: u64 = 0x10_0000;
+
+    fn send_reply(stream: &mut UnixStream, request: u32, payload: &[u8]) {
+        let header = Header {
+            request,
+            flags: VHOST_USER_VERSION | VHOST_USER_REPLY,
+            size: payload.len() as u32,
+        };
+        stream.write_all(header.as_slice()).unwrap();
+        stream.write_all(payload).unwrap();
+    }
+
+    // Spawns a fake vhost-user-fs backend with up to three queues, which answers the frontend
+    // requests, passes the backend end of the slave channel to `slave`, and records the
+    // requests.
+    fn spawn_backend(
+        mut stream: UnixStream,
+        protocol_features: u64,
+        slave: Sender<UnixStream>,
+    ) -> JoinHandle<Vec<u32>> {
+        thread::spawn(move || {
+            let mut requests = Vec::new();
+            while let Some(BackendMessage {
+                header,
+                payload,
+                mut fds,
+            }) = recv_backend_message(&mut stream)
+            {
+                let request = header.request;
+                match request {
+                    VHOST_USER_GET_FEATURES => {
+                        send_reply(&mut stream, request, BACKEND_FEATURES.as_slice())
+                    }
+                    VHOST_USER_GET_PROTOCOL_FEATURES => {
+                        send_reply(&mut stream, request, protocol_features.as_slice())
+                    }
+                    VHOST_USER_GET_QUEUE_NUM => send_reply(&mut stream, request, 3u64.as_slice()),
+                    VHOST_USER_GET_VRING_BASE => {
+                        let state = VringState {
+                            index: u32::from_le_bytes([
+                                payload[0], payload[1], payload[2], payload[3],
+                            ]),
+                            num: 0,
+                        };
+                        send_reply(&mut stream, request, state.as_slice());
+                    }
+                    VHOST_USER_SET_SLAVE_REQ_FD => {
+                        let fd = fds.remove(0).into_raw_fd();
+                        // Safe because the file descriptor is a socket owned by the backend.
+                        slave.send(unsafe { UnixStream::from_raw_fd(fd) }).unwrap();
+                    }
+                    _ => {}
+                }
+                if header.flags & VHOST_USER_NEED_REPLY != 0 {
+                    send_reply(&mut stream, request, 0u64.as_slice());
+                }
+                requests.push(request);
+            }
+            requests
+        })
+    }
+
+    // Sends a request through the slave channel, and returns the result sent by the frontend.
+    fn slave_request(
+        fs: &mut VhostUserFs<Mem, EventFd>,
+        slave: &mut UnixStream,
+        request: u32,
+        msg: &FsSlaveMsg,
+        file: Option<&File>,
+    ) -> u64 {
+        let header = Header {
+            request,
+            flags: VHOST_USER_VERSION | VHOST_USER_NEED_REPLY,
+            size: size_of::<FsSlaveMsg>() as u32,
+        };
+        let mut buf = header.as_slice().to_vec();
+        buf.extend_from_slice(msg.as_slice());
+        let fds: Vec<_> = file.iter().map(|f| f.as_raw_fd()).collect();
+        slave.send_with_fds(&[&buf[..]], &fds).unwrap();
+
+        fs.process_slave_request().unwrap();
+        let mut reply = Header::default();
+        slave.read_exact(reply.as_mut_slice()).unwrap();
+        assert_eq!(reply.request, request);
+        assert_eq!(reply.flags, VHOST_USER_VERSION | VHOST_USER_REPLY);
+        let mut result = 0u64;
+        slave.read_exact(result.as_mut_slice()).unwrap();
+        result
+    }
+
+    fn shared_mem() -> Mem {
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x10_0000).unwrap();
+        Arc::new(
+            GuestMemoryMmap::from_ranges_with_files(&[(
+                GuestAddress(0),
+                0x10_0000,
+                Some(FileOffset::new(file, 0)),
+            )])
+            .unwrap(),
+        )
+    }
+
+    fn build(
+        mem: &Mem,
+        builder: impl FnOnce(VhostUserFsBuilder<Mem, EventFd>) -> VhostUserFsBuilder<Mem, EventFd>,
+        protocol_features: u64,
+    ) -> Built {
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let (sender, receiver) = channel();
+        let handle = spawn_backend(backend, protocol_features, sender);
+        let fs = builder(VhostUserFsBuilder::new(
+            mem.clone(),
+            frontend,
+            "myfs",
+            EventFd::new(0).unwrap(),
+        ))
+        .build();
+        (fs, handle, receiver)
+    }
+
+    #[test]
+    fn test_build() {
+        let mem = shared_mem();
+
+        let (fs, handle, _) = build(&mem, |b| b.with_num_request_queues(0), 0);
+        assert!(matches!(fs, Err(Error::InvalidNumRequestQueues(0))));
+        handle.join().unwrap();
+
+        let (fs, handle, _) = build(&mem, |b| b.with_dax_window(DAX_ADDR, 0), 0);
+        assert!(matches!(fs, Err(Error::InvalidDaxWindow(DAX_ADDR, 0))));
+        handle.join().unwrap();
+
+        let (frontend, _backend) = UnixStream::pair().unwrap();
+        let fs =
+            VhostUserFsBuilder::new(mem.clone(), frontend, "", EventFd::new(0).unwrap()).build();
+        assert!(matches!(fs, Err(Error::InvalidTag(_))));
+
+        // Multiple request queues require `VHOST_USER_PROTOCOL_F_MQ`.
+        let (fs, handle, _) = build(&mem, |b| b.with_num_request_queues(2), 0);
+        assert!(matches!(
+            fs,
+            Err(Error::VhostUser(vhost_user::Error::MissingProtocolFeature(
+                VHOST_USER_PROTOCOL_F_MQ
+            )))
+        ));
+        handle.join().unwrap();
+
+        let (fs, handle, _) = build(
+            &mem,
+            |b| b.with_num_request_queues(3),
+            SUPPORTED_PROTOCOL_FEATURES,
+        );
+        assert!(matches!(
+            fs,
+            Err(Error::VhostUser(vhost_user::Error::TooManyQueues(4)))
+        ));
+        handle.join().unwrap();
+
+        // The DAX window requires the slave channel.
+        let (fs, handle, _) = build(
+            &mem,
+            |b| b.with_dax_window(DAX_ADDR, DAX_LEN),
+            SUPPORTED_PROTOCOL_FEATURES,
+        );
+        assert!(matches!(
+            fs,
+            Err(Error::VhostUser(vhost_user::Error::MissingProtocolFeature(
+                VHOST_USER_PROTOCOL_F_SLAVE_REQ
+            )))
+        ));
+        handle.join().unwrap();
+
+        let (fs, handle, _) = build(
+            &mem,
+            |b| b.with_num_request_queues(2).with_queue_size(16),
+            SUPPORTED_PROTOCOL_FEATURES | SLAVE_PROTOCOL_FEATURES,
+        );
+        let mut fs = fs.unwrap();
+        assert_eq!(VirtioDevice::device_type(&fs), VIRTIO_ID_FS);
+        assert_eq!(fs.num_queues(), 3);
+        assert_eq!(fs.queue(2).unwrap().max_size(), 16);
+        assert_eq!(fs.device_features(), 1 << VIRTIO_F_VERSION_1);
+        let expected: Vec<u8> = ConfigSpace::new("myfs", 2).unwrap().into();
+        let mut config = vec![0u8; expected.len()];
+        fs.read_config(0, &mut config);
+        assert_eq!(config, expected);
+        assert!(fs.kick_eventfd(2).is_some());
+        assert!(fs.call_eventfd(3).is_none());
+        assert!(fs.dax_window().is_none());
+        assert!(fs.dax_cache().is_none());
+        assert!(fs.slave_channel().is_none());
+        assert!(matches!(
+            fs.process_slave_request(),
+            Err(Error::NoSlaveChannel)
+        ));
+        drop(fs);
+        // The slave channel is only set up along with the DAX window.
+        assert!(!handle
+            .join()
+            .unwrap()
+            .contains(&VHOST_USER_SET_SLAVE_REQ_FD));
+    }
+
+    #[test]
+    fn test_dax_window() {
+        let mem = shared_mem();
+        let (fs, handle, receiver) = build(
+            &mem,
+            |b| b.with_queue_size(16).with_dax_window(DAX_ADDR, DAX_LEN),
+            SUPPORTED_PROTOCOL_FEATURES | SLAVE_PROTOCOL_FEATURES,
+        );
+        let mut fs = fs.unwrap();
+        let mut slave = receiver.recv().unwrap();
+        assert_eq!(
+            fs.dax_window(),
+            Some(SharedMemoryRegion {
+                id: VIRTIO_FS_SHMCAP_ID_CACHE,
+                addr: DAX_ADDR,
+                len: DAX_LEN,
+            })
+        );
+        let cache = fs.dax_cache().unwrap();
+        assert_eq!(cache.size(), DAX_LEN);
+        let host_addr = cache.host_addr();
+        assert!(fs.slave_channel().is_some());
+
+        let mut file = TempFile::new().unwrap().into_file();
+        file.set_len(0x2000).unwrap();
+        file.seek(SeekFrom::Start(0x1000)).unwrap();
+        file.write_all(b"hello").unwrap();
+
+        let mut msg = FsSlaveMsg::default();
+        msg.fd_offset[1] = 0x1000;
+        msg.cache_offset[1] = 0x2000;
+        msg.len[1] = 0x1000;
+        msg.flags[1] = VHOST_USER_FS_FLAG_MAP_R | VHOST_USER_FS_FLAG_MAP_W;
+        // The mapping requests come with a file.
+        assert_eq!(
+            slave_request(&mut fs, &mut slave, VHOST_USER_SLAVE_FS_MAP, &msg, None),
+            -i64::from(libc::EBADF) as u64
+        );
+        assert_eq!(
+            slave_request(
+                &mut fs,
+                &mut slave,
+                VHOST_USER_SLAVE_FS_MAP,
+                &msg,
+                Some(&file)
+            ),
+            0
+        );
+        // Safe because the range was just mapped by the backend.
+        let mapping = unsafe { std::slice::from_raw_parts_mut(host_addr.add(0x2000), 0x1000) };
+        assert_eq!(&mapping[..5], b"hello");
+
+        // The changes made through the window end up in the file.
+        mapping[..5].copy_from_slice(b"world");
+        assert_eq!(
+            slave_request(&mut fs, &mut slave, VHOST_USER_SLAVE_FS_SYNC, &msg, None),
+            0
+        );
+        let mut data = [0u8; 5];
+        file.seek(SeekFrom::Start(0x1000)).unwrap();
+        file.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"world");
+
+        // The ranges have to fit in the window.
+        let mut invalid = msg;
+        invalid.cache_offset[1] = DAX_LEN;
+        assert_eq!(
+            slave_request(
+                &mut fs,
+                &mut slave,
+                VHOST_USER_SLAVE_FS_UNMAP,
+                &invalid,
+                None
+            ),
+            -i64::from(libc::EINVAL) as u64
+        );
+        assert_eq!(
+            slave_request(
+                &mut fs,
+                &mut slave,
+                VHOST_USER_SLAVE_FS_IO,
+                &msg,
+                Some(&file)
+            ),
+            -i64::from(libc::EOPNOTSUPP) as u64
+        );
+        let mut unmap_all = FsSlaveMsg::default();
+        unmap_all.len[0] = u64::MAX;
+        assert_eq!(
+            slave_request(
+                &mut fs,
+                &mut slave,
+                VHOST_USER_SLAVE_FS_UNMAP,
+                &unmap_all,
+                None
+            ),
+            0
+        );
+
+        // The backend is started when the driver activates the device.
+        let vqs: Vec<_> = (0..2)
+            .map(|i| VirtQueue::new(GuestAddress(i * 0x1000), &mem, 16))
+            .collect();
+        fs.ack_device_status(ACKNOWLEDGE);
+        fs.ack_device_status(ACKNOWLEDGE | DRIVER);
+        fs.set_driver_features(1, 1);
+        fs.ack_device_status(ACKNOWLEDGE | DRIVER | FEATURES_OK);
+        for (i, vq) in vqs.iter().enumerate() {
+            fs.set_queue_select(i as u16);
+            let queue = fs.selected_queue_mut().unwrap();
+            queue.size = vq.size();
+            queue.desc_table = vq.dtable_start();
+            queue.avail_ring = vq.avail_start();
+            queue.used_ring = vq.used_start();
+            queue.ready = true;
+        }
+        fs.ack_device_status(ACKNOWLEDGE | DRIVER | FEATURES_OK | DRIVER_OK);
+        assert!(fs.is_activated());
+
+        fs.call_eventfd(1).unwrap().write(1).unwrap();
+        fs.process_call_event(1).unwrap();
+        assert_eq!(fs.interrupt_status().load(Ordering::SeqCst), 1);
+        assert_eq!(fs.driver_notify.read().unwrap(), 1);
+        fs.queue_notify(0);
+        assert_eq!(fs.kick_eventfd(0).unwrap().read().unwrap(), 1);
+
+        assert_eq!(
+            slave_request(
+                &mut fs,
+                &mut slave,
+                VHOST_USER_SLAVE_FS_MAP,
+                &msg,
+                Some(&file)
+            ),
+            0
+        );
+        fs.set_shm_select(1);
+        fs.ack_device_status(0);
+        assert!(!fs.is_activated());
+        assert_eq!(fs.shm_select(), 0);
+        assert_eq!(fs.interrupt_status().load(Ordering::SeqCst), 0);
+        drop(fs);
+
+        let requests = handle.join().unwrap();
+        assert!(requests.contains(&VHOST_USER_SET_SLAVE_REQ_FD));
+        assert_eq!(
+            requests
+                .iter()
+                .filter(|&&r| r == VHOST_USER_SET_VRING_KICK)
+                .count(),
+            2
+        );
+        assert_eq!(
+            requests
+                .iter()
+                .filter(|&&r| r == VHOST_USER_GET_VRING_BASE)
+                .count(),
+            2
+        );
+    }
+}
@@ -0,0 +1,63 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Gpu display backend abstraction.
+//!
+//! This module provides the [`DisplayBackend`](trait.DisplayBackend.html) interface, which
+//! connects the gpu device to the displays (i.e. the windows of a VNC server or of a graphical
+//! frontend). The device keeps the host copy of the resources, and tells the backend which
+//! resource is shown on each scanout, and when the contents change.
+
+use crate::protocol::Rect;
+use crate::resource::Resource2d;
+
+/// The displays behind the scanouts of the gpu device.
+pub trait DisplayBackend {
+    /// Starts showing an area of a resource on a scanout. The contents are provided by the
+    /// subsequent [`flush`](#tymethod.flush) calls.
+    ///
+    /// # Arguments
+    /// * `scanout_id` - The identifier of the scanout.
+    /// * `resource` - The displayed resource.
+    /// * `rect` - The displayed area of the resource.
+    fn enable_scanout(&mut self, scanout_id: u32, resource: &Resource2d, rect: Rect);
+
+    /// Stops showing anything on a scanout.
+    ///
+    /// # Arguments
+    /// * `scanout_id` - The identifier of the scanout.
+    fn disable_scanout(&mut self, scanout_id: u32);
+
+    /// Updates an area of a scanout.
+    ///
+    /// # Arguments
+    /// * `scanout_id` - The identifier of the scanout.
+    /// * `resource` - The displayed resource.
+    /// * `rect` - The updated area of the resource, which is within the displayed area.
+    fn flush(&mut self, scanout_id: u32, resource: &Resource2d, rect: Rect);
+
+    /// Sets the cursor image, or hides the cursor. Does nothing by default.
+    ///
+    /// # Arguments
+    /// * `scanout_id` - The identifier of the scanout which shows the cursor.
+    /// * `cursor` - The cursor image, or `None` to hide the cursor.
+    /// * `hot_x` - The horizontal position of the hot spot in the cursor image.
+    /// * `hot_y` - The vertical position of the hot spot in the cursor image.
+    fn update_cursor(
+        &mut self,
+        _scanout_id: u32,
+        _cursor: Option<&Resource2d>,
+        _hot_x: u32,
+        _hot_y: u32,
+    ) {
+    }
+
+    /// Moves the cursor. Does nothing by default.
+    ///
+    /// # Arguments
+    /// * `scanout_id` - The identifier of the scanout which shows the cursor.
+    /// * `x` - The horizontal position.
+    /// * `y` - The vertical position.
+    fn move_cursor(&mut self, _scanout_id: u32, _x: u32, _y: u32) {}
+}
@@ -0,0 +1,408 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Virtio gpu command parsing.
+//!
+//! Every buffer which goes through the control or cursor queues of a gpu device holds a
+//! command, which is followed by the space for the response. The device-readable descriptors of
+//! the chain hold the command, and the device-writable ones (which come after them) receive the
+//! response. This module provides the following abstractions:
+//!
+//! - [`Request`](struct.Request.html) which holds the header and the parsed
+//!   [`Command`](enum.Command.html), and can be read from a descriptor chain.
+//! - [`Response`](struct.Response.html) which holds the response type and data, and
+//!   [`ResponseBuffer`](struct.ResponseBuffer.html) which is the device-writable part of the
+//!   chain.
+
+use std::fmt::{self, Display};
+use std::mem::size_of;
+use std::result;
+
+use vm_memory::{ByteValued, GuestAddressSpace, GuestMemory, GuestMemoryError};
+
+use virtio_queue::areas::{self, areas_len, read_obj, split_chain, write_areas, Area};
+use virtio_queue::DescriptorChain;
+
+use crate::defs::*;
+use crate::protocol::{
+    CtrlHeader, MemEntry, ResourceAttachBacking, ResourceCreate2d, ResourceFlush, ResourceId,
+    RespDisplayInfo, SetScanout, TransferToHost2d, UpdateCursor,
+};
+
+/// The largest number of backing entries accepted for a resource.
+pub const MAX_BACKING_ENTRIES: u32 = 16 * 1024;
+
+/// Command parsing errors.
+#[derive(Debug)]
+pub enum Error {
+    /// The descriptor chain is too short to hold the command or the response.
+    DescriptorChainTooShort,
+    /// Invalid memory access.
+    GuestMemory(GuestMemoryError),
+    /// The command has too many backing entries.
+    TooManyEntries(u32),
+    /// Read only descriptor after a write only one.
+    UnexpectedReadOnlyDescriptor,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            DescriptorChainTooShort => write!(f, "descriptor chain too short for the command"),
+            GuestMemory(ref err) => write!(f, "error accessing guest memory: {}", err),
+            TooManyEntries(num) => write!(f, "too many backing entries: {}", num),
+            UnexpectedReadOnlyDescriptor => write!(f, "unexpected read only descriptor"),
+        }
+    }
+}
+
+impl From<areas::Error> for Error {
+    fn from(e: areas::Error) -> Self {
+        match e {
+            areas::Error::TooShort => Error::DescriptorChainTooShort,
+            areas::Error::GuestMemory(e) => Error::GuestMemory(e),
+            areas::Error::UnexpectedReadOnlyDescriptor => Error::UnexpectedReadOnlyDescriptor,
+        }
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// A gpu command.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// `VIRTIO_GPU_CMD_GET_DISPLAY_INFO`.
+    GetDisplayInfo,
+    /// `VIRTIO_GPU_CMD_RESOURCE_CREATE_2D`.
+    ResourceCreate2d(ResourceCreate2d),
+    /// `VIRTIO_GPU_CMD_RESOURCE_UNREF`.
+    ResourceUnref(ResourceId),
+    /// `VIRTIO_GPU_CMD_SET_SCANOUT`.
+    SetScanout(SetScanout),
+    /// `VIRTIO_GPU_CMD_RESOURCE_FLUSH`.
+    ResourceFlush(ResourceFlush),
+    /// `VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D`.
+    TransferToHost2d(TransferToHost2d),
+    /// `VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING`, with the backing entries.
+    ResourceAttachBacking(ResourceAttachBacking, Vec<MemEntry>),
+    /// `VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING`.
+    ResourceDetachBacking(ResourceId),
+    /// `VIRTIO_GPU_CMD_UPDATE_CURSOR`.
+    UpdateCursor(UpdateCursor),
+    /// `VIRTIO_GPU_CMD_MOVE_CURSOR`.
+    MoveCursor(UpdateCursor),
+    /// A command which is not supported by the device, with its type.
+    Unsupported(u32),
+}
+
+/// A command, together with its header.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Request {
+    /// The command header.
+    pub hdr: CtrlHeader,
+    /// The command.
+    pub cmd: Command,
+}
+
+impl Request {
+    /// Reads a request from a descriptor chain, and returns it together with the
+    /// device-writable part of the chain, where the response goes.
+    ///
+    /// # Arguments
+    /// * `chain` - The command descriptor chain.
+    pub fn read_from_chain<M: GuestAddressSpace>(
+        chain: &mut DescriptorChain<M>,
+    ) -> Result<(Self, ResponseBuffer)> {
+        let (readable, writable) = split_chain(chain)?;
+
+        let mem = chain.memory();
+        let hdr: CtrlHeader = read_obj(mem, &readable, 0)?;
+        let offset = CtrlHeader::LEN;
+        let cmd = match hdr.type_ {
+            VIRTIO_GPU_CMD_GET_DISPLAY_INFO => Command::GetDisplayInfo,
+            VIRTIO_GPU_CMD_RESOURCE_CREATE_2D => {
+                Command::ResourceCreate2d(read_obj(mem, &readable, offset)?)
+            }
+            VIRTIO_GPU_CMD_RESOURCE_UNREF => {
+                Command::ResourceUnref(read_obj(mem, &readable, offset)?)
+            }
+            VIRTIO_GPU_CMD_SET_SCANOUT => Command::SetScanout(read_obj(mem, &readable, offset)?),
+            VIRTIO_GPU_CMD_RESOURCE_FLUSH => {
+                Command::ResourceFlush(read_obj(mem, &readable, offset)?)
+            }
+            VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D => {
+                Command::TransferToHost2d(read_obj(mem, &readable, offset)?)
+            }
+            VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING => {
+                let attach: ResourceAttachBacking = read_obj(mem, &readable, offset)?;
+                if attach.nr_entries > MAX_BACKING_ENTRIES {
+                    return Err(Error::TooManyEntries(attach.nr_entries));
+                }
+                let offset = offset + size_of::<ResourceAttachBacking>();
+                let entries = (0..attach.nr_entries as usize)
+                    .map(|i| read_obj(mem, &readable, offset + i * size_of::<MemEntry>()))
+                    .collect::<result::Result<_, areas::Error>>()?;
+                Command::ResourceAttachBacking(attach, entries)
+            }
+            VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING => {
+                Command::ResourceDetachBacking(read_obj(mem, &readable, offset)?)
+            }
+            VIRTIO_GPU_CMD_UPDATE_CURSOR => {
+                Command::UpdateCursor(read_obj(mem, &readable, offset)?)
+            }
+            VIRTIO_GPU_CMD_MOVE_CURSOR => Command::MoveCursor(read_obj(mem, &readable, offset)?),
+            type_ => Command::Unsupported(type_),
+        };
+
+        Ok((Request { hdr, cmd }, ResponseBuffer { areas: writable }))
+    }
+}
+
+/// The response to a command.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response {
+    /// The response type (`VIRTIO_GPU_RESP_*`).
+    pub type_: u32,
+    /// The response specific data that follows the header.
+    pub data: Vec<u8>,
+}
+
+impl Response {
+    /// Creates a `VIRTIO_GPU_RESP_OK_NODATA` response.
+    pub fn ok() -> Self {
+        Response {
+            type_: VIRTIO_GPU_RESP_OK_NODATA,
+            data: Vec::new(),
+        }
+    }
+
+    /// Creates a `VIRTIO_GPU_RESP_OK_DISPLAY_INFO` response.
+    ///
+    /// # Arguments
+    /// * `info` - The modes of the scanouts.
+    pub fn display_info(info: &RespDisplayInfo) -> Self {
+        Response {
+            type_: VIRTIO_GPU_RESP_OK_DISPLAY_INFO,
+            data: info.as_slice().to_vec(),
+        }
+    }
+
+    /// Creates a response without any data, i.e. for a failed command.
+    ///
+    /// # Arguments
+    /// * `type_` - The response type (`VIRTIO_GPU_RESP_ERR_*`).
+    pub fn error(type_: u32) -> Self {
+        Response {
+            type_,
+            data: Vec::new(),
+        }
+    }
+}
+
+/// The device-writable part of a command descriptor chain, which receives the response.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResponseBuffer {
+    areas: Vec<Area>,
+}
+
+impl ResponseBuffer {
+    /// Returns the size of the buffer.
+    pub fn len(&self) -> usize {
+        areas_len(&self.areas)
+    }
+
+    /// Returns whether the buffer is empty, i.e. for cursor commands.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Writes the response to a command, and returns the number of bytes written. The fence
+    /// information of the command is echoed by the response, since the commands complete
+    /// before their response is written.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    /// * `req_hdr` - The header of the command.
+    /// * `resp` - The response.
+    pub fn write_response<M: GuestMemory>(
+        &self,
+        mem: &M,
+        req_hdr: &CtrlHeader,
+        resp: &Response,
+    ) -> Result<u32> {
+        let hdr = CtrlHeader {
+            type_: resp.type_,
+            flags: req_hdr.flags & VIRTIO_GPU_FLAG_FENCE,
+            fence_id: req_hdr.fence_id,
+            ctx_id: req_hdr.ctx_id,
+            padding: 0,
+        };
+        write_areas(mem, &self.areas, 0, hdr.as_slice())?;
+        write_areas(mem, &self.areas, CtrlHeader::LEN, &resp.data)?;
+        // The length is bounded by the size of `RespDisplayInfo`.
+        Ok((CtrlHeader::LEN + resp.data.len()) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+    use virtio_device::mock::adjacent_regions;
+    use virtio_queue::mock::{AttackPattern, VirtQueue};
+    use virtio_queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    use crate::protocol::Rect;
+
+    #[test]
+    fn test_request() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let mut queue = vq.create_queue(&mem);
+
+        let hdr = CtrlHeader {
+            type_: VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING,
+            flags: VIRTIO_GPU_FLAG_FENCE,
+            fence_id: 5,
+            ..Default::default()
+        };
+        let attach = ResourceAttachBacking {
+            resource_id: 1,
+            nr_entries: 2,
+        };
+        let entries = [
+            MemEntry {
+                addr: 0x8_0000,
+                length: 0x1000,
+                padding: 0,
+            },
+            MemEntry {
+                addr: 0x9_0000,
+                length: 0x2000,
+                padding: 0,
+            },
+        ];
+        let mut bytes = hdr.as_slice().to_vec();
+        bytes.extend_from_slice(attach.as_slice());
+        bytes.extend_from_slice(entries[0].as_slice());
+        bytes.extend_from_slice(entries[1].as_slice());
+        mem.write_slice(&bytes, GuestAddress(0x1_0000)).unwrap();
+
+        // The command is split across two descriptors.
+        vq.dtable(0).set(0x1_0000, 30, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1).set(0x1_001e, 34, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable(2).set(0x2_0000, 0x100, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring(0).store(0);
+        // A chain which is shorter than the backing entries.
+        vq.dtable(3).set(0x1_0000, 40, 0, 0);
+        vq.avail.ring(1).store(3);
+        vq.avail.idx().store(2);
+
+        let mut iter = queue.iter().unwrap();
+        let (req, buf) = Request::read_from_chain(&mut iter.next().unwrap()).unwrap();
+        assert_eq!(req.hdr, hdr);
+        assert_eq!(
+            req.cmd,
+            Command::ResourceAttachBacking(attach, entries.to_vec())
+        );
+        assert_eq!(buf.len(), 0x100);
+        assert!(matches!(
+            Request::read_from_chain(&mut iter.next().unwrap()),
+            Err(Error::DescriptorChainTooShort)
+        ));
+
+        assert_eq!(
+            buf.write_response(&mem, &hdr, &Response::ok()).unwrap(),
+            CtrlHeader::LEN as u32
+        );
+        let resp: CtrlHeader = mem.read_obj(GuestAddress(0x2_0000)).unwrap();
+        assert_eq!(resp.type_, VIRTIO_GPU_RESP_OK_NODATA);
+        assert_eq!(resp.flags, VIRTIO_GPU_FLAG_FENCE);
+        assert_eq!(resp.fence_id, 5);
+
+        // The display information doesn't fit in the buffer.
+        let info = Response::display_info(&RespDisplayInfo::default());
+        assert!(matches!(
+            buf.write_response(&mem, &hdr, &info),
+            Err(Error::DescriptorChainTooShort)
+        ));
+
+        // Unknown commands are reported as such, and too many entries are rejected.
+        let hdr = CtrlHeader {
+            type_: 0x0200,
+            ..Default::default()
+        };
+        mem.write_obj(hdr, GuestAddress(0x1_0000)).unwrap();
+        let attach = ResourceAttachBacking {
+            resource_id: 1,
+            nr_entries: MAX_BACKING_ENTRIES + 1,
+        };
+        let hdr = CtrlHeader {
+            type_: VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING,
+            ..Default::default()
+        };
+        mem.write_obj(hdr, GuestAddress(0x3_0000)).unwrap();
+        mem.write_obj(attach, GuestAddress(0x3_0018)).unwrap();
+        vq.dtable(4).set(0x3_0000, 32, 0, 0);
+        vq.avail.ring(2).store(0);
+        vq.avail.ring(3).store(4);
+        vq.avail.idx().store(4);
+
+        let mut iter = queue.iter().unwrap();
+        let (req, _) = Request::read_from_chain(&mut iter.next().unwrap()).unwrap();
+        assert_eq!(req.cmd, Command::Unsupported(0x0200));
+        assert!(matches!(
+            Request::read_from_chain(&mut iter.next().unwrap()),
+            Err(Error::TooManyEntries(n)) if n == MAX_BACKING_ENTRIES + 1
+        ));
+
+        // Commands which only hold a rectangle and an identifier.
+        let flush = ResourceFlush {
+            r: Rect {
+                x: 1,
+                y: 2,
+                width: 3,
+                height: 4,
+            },
+            resource_id: 7,
+            padding: 0,
+        };
+        let hdr = CtrlHeader {
+            type_: VIRTIO_GPU_CMD_RESOURCE_FLUSH,
+            ..Default::default()
+        };
+        mem.write_obj(hdr, GuestAddress(0x3_0000)).unwrap();
+        mem.write_obj(flush, GuestAddress(0x3_0018)).unwrap();
+        vq.dtable(4).set(0x3_0000, 48, 0, 0);
+        vq.avail.ring(4).store(4);
+        vq.avail.idx().store(5);
+        let mut chain = queue.iter().unwrap().next().unwrap();
+        let (req, buf) = Request::read_from_chain(&mut chain).unwrap();
+        assert_eq!(req.cmd, Command::ResourceFlush(flush));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_attack_patterns() {
+        let mem = adjacent_regions(0x10_0000);
+        for &pattern in AttackPattern::ALL.iter() {
+            let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+            vq.add_attack(pattern, 0, GuestAddress(0x1_0000));
+            let mut chain = vq.create_queue(&mem).iter().unwrap().next().unwrap();
+            // The descriptor table holds a valid command header, and the response goes to the
+            // rings.
+            assert_eq!(
+                Request::read_from_chain(&mut chain).is_ok(),
+                [AttackPattern::SelfReference, AttackPattern::RingAliasing].contains(&pattern),
+                "{:?}",
+                pattern
+            );
+        }
+    }
+}
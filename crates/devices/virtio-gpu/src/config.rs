@@ -0,0 +1,72 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Virtio gpu device configuration space abstraction.
+//!
+//! This module provides the [`ConfigSpace`](struct.ConfigSpace.html) abstraction, which mirrors
+//! the `virtio_gpu_config` structure from the virtio specification. The device signals events
+//! (i.e. display configuration changes) through the `events_read` field, and the driver
+//! acknowledges them by writing the same bits to `events_clear`.
+
+use std::mem::{offset_of, size_of};
+
+use vm_memory::ByteValued;
+
+/// The gpu device configuration space layout, as defined by the virtio specification.
+///
+/// All fields are expected to hold little endian values, which is always the case on the
+/// platforms that are currently supported (`x86_64` and `aarch64`).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C, packed)]
+pub struct ConfigSpace {
+    /// The pending events, which are only written by the device.
+    pub events_read: u32,
+    /// The events acknowledged by the driver, which are cleared from `events_read`.
+    pub events_clear: u32,
+    /// The number of scanouts supported by the device.
+    pub num_scanouts: u32,
+    /// Reserved.
+    pub reserved: u32,
+}
+
+// Safe because ConfigSpace only contains plain data, and there's no padding between (or after)
+// the fields since the structure is packed.
+unsafe impl ByteValued for ConfigSpace {}
+
+impl ConfigSpace {
+    /// The size of the gpu device configuration space.
+    pub const LEN: usize = size_of::<ConfigSpace>();
+    /// The offset of the `events_read` field.
+    pub const EVENTS_READ_OFFSET: usize = offset_of!(ConfigSpace, events_read);
+    /// The offset of the `events_clear` field.
+    pub const EVENTS_CLEAR_OFFSET: usize = offset_of!(ConfigSpace, events_clear);
+    /// The offset of the `num_scanouts` field.
+    pub const NUM_SCANOUTS_OFFSET: usize = offset_of!(ConfigSpace, num_scanouts);
+}
+
+impl From<ConfigSpace> for Vec<u8> {
+    fn from(config: ConfigSpace) -> Self {
+        config.as_slice().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_space() {
+        assert_eq!(ConfigSpace::LEN, 16);
+        assert_eq!(ConfigSpace::EVENTS_READ_OFFSET, 0);
+        assert_eq!(ConfigSpace::EVENTS_CLEAR_OFFSET, 4);
+        assert_eq!(ConfigSpace::NUM_SCANOUTS_OFFSET, 8);
+
+        let config = ConfigSpace {
+            num_scanouts: 2,
+            ..Default::default()
+        };
+        let bytes: Vec<u8> = config.into();
+        assert_eq!(bytes, [0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0]);
+    }
+}
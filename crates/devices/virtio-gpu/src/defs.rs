@@ -0,0 +1,87 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+// TODO: Move the generic device type definition to the vm-virtio crate proper.
+/// The virtio device type of gpu devices.
+pub const VIRTIO_ID_GPU: u32 = 16;
+
+/// The default (and maximum) size of the queues.
+pub const DEFAULT_QUEUE_SIZE: u16 = 256;
+
+// Queue indices.
+/// The index of the control queue.
+pub const CONTROL_QUEUE: u16 = 0;
+/// The index of the cursor queue.
+pub const CURSOR_QUEUE: u16 = 1;
+
+/// The maximum number of scanouts (from `linux/virtio_gpu.h`).
+pub const VIRTIO_GPU_MAX_SCANOUTS: usize = 16;
+
+// Configuration space events.
+/// The display configuration changed, so the driver has to fetch it again.
+pub const VIRTIO_GPU_EVENT_DISPLAY: u32 = 1;
+
+// Command header flags.
+/// The driver asks to be notified when the command completes, using the fence identifier.
+pub const VIRTIO_GPU_FLAG_FENCE: u32 = 1;
+
+// 2D commands (from `linux/virtio_gpu.h`).
+/// Returns the current output configuration.
+pub const VIRTIO_GPU_CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+/// Creates a 2D resource on the host.
+pub const VIRTIO_GPU_CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+/// Destroys a resource.
+pub const VIRTIO_GPU_CMD_RESOURCE_UNREF: u32 = 0x0102;
+/// Sets the scanout parameters for a single output.
+pub const VIRTIO_GPU_CMD_SET_SCANOUT: u32 = 0x0103;
+/// Flushes a scanout resource to the display.
+pub const VIRTIO_GPU_CMD_RESOURCE_FLUSH: u32 = 0x0104;
+/// Transfers data from guest memory to a host resource.
+pub const VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+/// Assigns backing pages to a resource.
+pub const VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+/// Detaches backing pages from a resource.
+pub const VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING: u32 = 0x0107;
+
+// Cursor commands.
+/// Sets the cursor image and position.
+pub const VIRTIO_GPU_CMD_UPDATE_CURSOR: u32 = 0x0300;
+/// Sets the cursor position.
+pub const VIRTIO_GPU_CMD_MOVE_CURSOR: u32 = 0x0301;
+
+// Success responses.
+/// Success without any data.
+pub const VIRTIO_GPU_RESP_OK_NODATA: u32 = 0x1100;
+/// Success, followed by the display information.
+pub const VIRTIO_GPU_RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+// Error responses.
+/// Unspecified error.
+pub const VIRTIO_GPU_RESP_ERR_UNSPEC: u32 = 0x1200;
+/// The host is out of memory.
+pub const VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY: u32 = 0x1201;
+/// Invalid scanout identifier.
+pub const VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID: u32 = 0x1202;
+/// Invalid resource identifier.
+pub const VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID: u32 = 0x1203;
+/// Invalid parameter.
+pub const VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER: u32 = 0x1205;
+
+// Pixel formats, which all use 4 bytes per pixel.
+/// Blue, green, red and alpha channels.
+pub const VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM: u32 = 1;
+/// Blue, green and red channels, followed by a padding byte.
+pub const VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM: u32 = 2;
+/// Alpha, red, green and blue channels.
+pub const VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM: u32 = 3;
+/// A padding byte, followed by the red, green and blue channels.
+pub const VIRTIO_GPU_FORMAT_X8R8G8B8_UNORM: u32 = 4;
+/// Red, green, blue and alpha channels.
+pub const VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM: u32 = 67;
+/// A padding byte, followed by the blue, green and red channels.
+pub const VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM: u32 = 68;
+/// Alpha, blue, green and red channels.
+pub const VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM: u32 = 121;
+/// Red, green and blue channels, followed by a padding byte.
+pub const VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM: u32 = 134;
@@ -0,0 +1,1089 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A reference virtio gpu device implementation, which supports the 2D command set.
+//!
+//! This module provides the following abstractions:
+//!
+//! - [`Gpu`](struct.Gpu.html) which ties together the generic device building blocks
+//!   (`VirtioConfig` and the `VirtioDevice`/`VirtioMmioDevice` interfaces) with the gpu specific
+//!   ones (the configuration space, the commands, the resources and the
+//!   [`DisplayBackend`](../backend/trait.DisplayBackend.html) interface).
+//! - [`GpuBuilder`](struct.GpuBuilder.html) which configures and creates a `Gpu` device.
+//!
+//! The driver creates resources on the host, attaches guest memory to them, and transfers the
+//! contents of that memory to the host copy of the resources. Then it shows resources on the
+//! scanouts, and flushes the updated areas, which the backend displays. The commands go through
+//! the control queue, and the cursor commands go through the cursor queue. All the commands
+//! complete before the response is written, so fences are signaled right away.
+//!
+//! The device doesn't register any events by itself: the VMM is expected to rely on the
+//! `VirtioMmioDevice::queue_notify` implementation (or call the `process_*` methods directly)
+//! when the driver notifies a queue.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt::{self, Display};
+use std::result;
+
+use log::{error, warn};
+
+use vm_memory::GuestAddressSpace;
+
+use virtio_device::{
+    SignalConfigChange, SignalUsedQueue, VirtioConfig, VirtioDeviceActions, VirtioDeviceCommon,
+    VirtioMmioDevice,
+};
+use virtio_queue::{self, Queue};
+
+use crate::backend::DisplayBackend;
+use crate::command::{Command, Request, Response};
+use crate::config::ConfigSpace;
+use crate::defs::*;
+use crate::protocol::{DisplayOne, Rect, RespDisplayInfo};
+use crate::resource::{self, is_supported_format, Resource2d};
+
+pub use crate::defs::{DEFAULT_QUEUE_SIZE, VIRTIO_ID_GPU};
+
+// TODO: Move the generic feature definitions to the vm-virtio crate proper.
+const VIRTIO_F_VERSION_1: u64 = 32;
+const VIRTIO_F_RING_EVENT_IDX: u64 = 29;
+
+/// The default size of the single scanout.
+pub const DEFAULT_SCANOUT_SIZE: (u32, u32) = (1024, 768);
+/// The default limit for the memory used by the host copies of the resources.
+pub const DEFAULT_MAX_HOST_MEMORY: usize = 256 << 20;
+
+/// Gpu device errors.
+#[derive(Debug)]
+pub enum Error {
+    /// The device was already activated.
+    AlreadyActivated,
+    /// The queues are not configured properly.
+    InvalidQueues,
+    /// The queue index is not valid, or the device is not activated.
+    InvalidQueueIndex(u16),
+    /// The scanout identifier is not valid.
+    InvalidScanout(u32),
+    /// The number of scanouts is zero or too large, or a scanout is empty.
+    InvalidScanouts,
+    /// Failed to access a queue.
+    Queue(virtio_queue::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            AlreadyActivated => write!(f, "the device is already activated"),
+            InvalidQueues => write!(f, "invalid queue configuration"),
+            InvalidQueueIndex(index) => write!(f, "invalid or inactive queue {}", index),
+            InvalidScanout(id) => write!(f, "invalid scanout {}", id),
+            InvalidScanouts => write!(f, "invalid scanout configuration"),
+            Queue(ref err) => write!(f, "failed to access the queue: {}", err),
+        }
+    }
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+// The state of a scanout.
+#[derive(Debug)]
+struct Scanout {
+    // The preferred size, which is advertised to the driver.
+    width: u32,
+    height: u32,
+    // The displayed resource (zero when the scanout is disabled), and the displayed area.
+    resource_id: u32,
+    rect: Rect,
+}
+
+/// Configures and builds a `Gpu` device.
+///
+/// # Example
+///
+/// ```rust
+/// # use std::sync::Arc;
+/// # use virtio_gpu::backend::DisplayBackend;
+/// # use virtio_gpu::device::GpuBuilder;
+/// # use virtio_gpu::protocol::Rect;
+/// # use virtio_gpu::resource::Resource2d;
+/// # use vm_memory::{GuestAddress, GuestMemoryMmap};
+/// # use vmm_sys_util::eventfd::EventFd;
+/// // A display which doesn't show anything.
+/// struct NoDisplay;
+///
+/// impl DisplayBackend for NoDisplay {
+///     fn enable_scanout(&mut self, _scanout_id: u32, _resource: &Resource2d, _rect: Rect) {}
+///     fn disable_scanout(&mut self, _scanout_id: u32) {}
+///     fn flush(&mut self, _scanout_id: u32, _resource: &Resource2d, _rect: Rect) {}
+/// }
+///
+/// let mem = Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+///
+/// let gpu = GpuBuilder::new(mem, NoDisplay, EventFd::new(0).unwrap())
+///     .with_scanouts(&[(1280, 720), (800, 600)])
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug)]
+pub struct GpuBuilder<M: GuestAddressSpace, B: DisplayBackend, S: SignalUsedQueue> {
+    mem: M,
+    backend: B,
+    driver_notify: S,
+    scanouts: Vec<(u32, u32)>,
+    max_host_memory: usize,
+    queue_size: u16,
+}
+
+impl<M, B, S> GpuBuilder<M, B, S>
+where
+    M: GuestAddressSpace + Clone,
+    B: DisplayBackend,
+    S: SignalUsedQueue,
+{
+    /// Creates a new `GpuBuilder`.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    /// * `backend` - The displays behind the scanouts.
+    /// * `driver_notify` - The object used for notifying the driver about used buffers.
+    pub fn new(mem: M, backend: B, driver_notify: S) -> Self {
+        GpuBuilder {
+            mem,
+            backend,
+            driver_notify,
+            scanouts: vec![DEFAULT_SCANOUT_SIZE],
+            max_host_memory: DEFAULT_MAX_HOST_MEMORY,
+            queue_size: DEFAULT_QUEUE_SIZE,
+        }
+    }
+
+    /// Sets the preferred sizes of the scanouts, which also determines their number (there's a
+    /// single `DEFAULT_SCANOUT_SIZE` scanout by default).
+    ///
+    /// # Arguments
+    /// * `scanouts` - The `(width, height)` pairs of the scanouts.
+    pub fn with_scanouts(mut self, scanouts: &[(u32, u32)]) -> Self {
+        self.scanouts = scanouts.to_vec();
+        self
+    }
+
+    /// Sets the limit for the memory used by the host copies of the resources.
+    ///
+    /// # Arguments
+    /// * `max_host_memory` - The limit, in bytes.
+    pub fn with_max_host_memory(mut self, max_host_memory: usize) -> Self {
+        self.max_host_memory = max_host_memory;
+        self
+    }
+
+    /// Sets the maximum size of the queues.
+    ///
+    /// # Arguments
+    /// * `queue_size` - The maximum queue size.
+    pub fn with_queue_size(mut self, queue_size: u16) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Builds the `Gpu` device.
+    pub fn build(self) -> Result<Gpu<M, B, S>> {
+        if self.scanouts.is_empty()
+            || self.scanouts.len() > VIRTIO_GPU_MAX_SCANOUTS
+            || self.scanouts.iter().any(|&(w, h)| w == 0 || h == 0)
+        {
+            return Err(Error::InvalidScanouts);
+        }
+
+        let device_features = (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_RING_EVENT_IDX);
+        let queues = (0..2)
+            .map(|_| Queue::new(self.mem.clone(), self.queue_size))
+            .collect();
+        let config_space = ConfigSpace {
+            // The number of scanouts is bounded by `VIRTIO_GPU_MAX_SCANOUTS`.
+            num_scanouts: self.scanouts.len() as u32,
+            ..Default::default()
+        };
+        let scanouts = self
+            .scanouts
+            .iter()
+            .map(|&(width, height)| Scanout {
+                width,
+                height,
+                resource_id: 0,
+                rect: Rect::default(),
+            })
+            .collect();
+
+        Ok(Gpu {
+            cfg: VirtioConfig::new(device_features, queues, config_space.into()),
+            backend: self.backend,
+            driver_notify: self.driver_notify,
+            scanouts,
+            resources: BTreeMap::new(),
+            host_memory: 0,
+            max_host_memory: self.max_host_memory,
+        })
+    }
+}
+
+/// A virtio gpu device.
+#[derive(Debug, VirtioDeviceCommon)]
+#[virtio(device_type = VIRTIO_ID_GPU)]
+pub struct Gpu<M: GuestAddressSpace, B: DisplayBackend, S: SignalUsedQueue> {
+    #[virtio(config)]
+    cfg: VirtioConfig<M>,
+    backend: B,
+    driver_notify: S,
+    scanouts: Vec<Scanout>,
+    resources: BTreeMap<u32, Resource2d>,
+    // The memory used by the host copies of the resources, and its limit.
+    host_memory: usize,
+    max_host_memory: usize,
+}
+
+impl<M, B, S> Gpu<M, B, S>
+where
+    M: GuestAddressSpace,
+    B: DisplayBackend,
+    S: SignalUsedQueue,
+{
+    /// Returns whether the device is activated.
+    pub fn is_activated(&self) -> bool {
+        self.cfg.device_activated
+    }
+
+    /// Returns a reference to the backend.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Returns a mutable reference to the backend.
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    /// Returns the resource with the specified identifier, if any.
+    ///
+    /// # Arguments
+    /// * `resource_id` - The identifier of the resource.
+    pub fn resource(&self, resource_id: u32) -> Option<&Resource2d> {
+        self.resources.get(&resource_id)
+    }
+
+    /// Processes the commands from the control queue. This has to be called when the driver
+    /// notifies the control queue.
+    pub fn process_control_queue(&mut self) -> Result<()> {
+        self.process_queue(CONTROL_QUEUE)
+    }
+
+    /// Processes the commands from the cursor queue. This has to be called when the driver
+    /// notifies the cursor queue.
+    pub fn process_cursor_queue(&mut self) -> Result<()> {
+        self.process_queue(CURSOR_QUEUE)
+    }
+
+    fn process_queue(&mut self, index: u16) -> Result<()> {
+        if !self.cfg.device_activated {
+            return Err(Error::InvalidQueueIndex(index));
+        }
+        while let Some(mut chain) = self.cfg.queues[usize::from(index)].iter()?.next() {
+            let len = match Request::read_from_chain(&mut chain) {
+                Ok((req, buf)) => {
+                    let resp = self.handle_command(chain.memory(), &req.cmd);
+                    // The driver doesn't expect responses to cursor commands.
+                    if buf.is_empty() {
+                        0
+                    } else {
+                        buf.write_response(chain.memory(), &req.hdr, &resp)
+                            .unwrap_or_else(|e| {
+                                warn!("failed to write gpu response: {}", e);
+                                0
+                            })
+                    }
+                }
+                Err(e) => {
+                    warn!("failed to read gpu command: {}", e);
+                    0
+                }
+            };
+
+            let queue = &mut self.cfg.queues[usize::from(index)];
+            queue.add_used(chain.head_index(), len)?;
+            if queue.needs_notification()? {
+                self.cfg.signal_used_queue();
+                self.driver_notify.signal_used_queue(index);
+            }
+        }
+        Ok(())
+    }
+
+    // Executes a command, and returns the response.
+    fn handle_command(&mut self, mem: &M::M, cmd: &Command) -> Response {
+        let result = match *cmd {
+            Command::GetDisplayInfo => return Response::display_info(&self.display_info()),
+            Command::ResourceCreate2d(c) => {
+                self.create_resource(c.resource_id, c.format, c.width, c.height)
+            }
+            Command::ResourceUnref(c) => self.unref_resource(c.resource_id),
+            Command::SetScanout(c) => self.set_scanout(c.scanout_id, c.resource_id, c.r),
+            Command::ResourceFlush(c) => self.flush_resource(c.resource_id, c.r),
+            Command::TransferToHost2d(c) => self
+                .resources
+                .get_mut(&{ c.resource_id })
+                .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)
+                .and_then(|res| {
+                    res.transfer_to_host(mem, &c.r, c.offset)
+                        .map_err(|e| match e {
+                            resource::Error::InvalidRect(_) => {
+                                VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER
+                            }
+                            e => {
+                                warn!("failed to transfer gpu resource: {}", e);
+                                VIRTIO_GPU_RESP_ERR_UNSPEC
+                            }
+                        })
+                }),
+            Command::ResourceAttachBacking(c, ref entries) => self
+                .resources
+                .get_mut(&{ c.resource_id })
+                .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)
+                .and_then(|res| {
+                    if res.has_backing() || entries.is_empty() {
+                        return Err(VIRTIO_GPU_RESP_ERR_UNSPEC);
+                    }
+                    res.attach_backing(entries);
+                    Ok(())
+                }),
+            Command::ResourceDetachBacking(c) => self
+                .resources
+                .get_mut(&{ c.resource_id })
+                .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)
+                .and_then(|res| {
+                    if !res.has_backing() {
+                        return Err(VIRTIO_GPU_RESP_ERR_UNSPEC);
+                    }
+                    res.detach_backing();
+                    Ok(())
+                }),
+            Command::UpdateCursor(c) => self.update_cursor(
+                c.pos.scanout_id,
+                c.resource_id,
+                (c.hot_x, c.hot_y),
+                (c.pos.x, c.pos.y),
+            ),
+            Command::MoveCursor(c) => {
+                if (c.pos.scanout_id as usize) < self.scanouts.len() {
+                    self.backend.move_cursor(c.pos.scanout_id, c.pos.x, c.pos.y);
+                    Ok(())
+                } else {
+                    Err(VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID)
+                }
+            }
+            Command::Unsupported(type_) => {
+                warn!("unsupported gpu command 0x{:x}", type_);
+                Err(VIRTIO_GPU_RESP_ERR_UNSPEC)
+            }
+        };
+
+        match result {
+            Ok(()) => Response::ok(),
+            Err(type_) => Response::error(type_),
+        }
+    }
+
+    fn display_info(&self) -> RespDisplayInfo {
+        let mut info = RespDisplayInfo::default();
+        for (mode, scanout) in info.pmodes.iter_mut().zip(self.scanouts.iter()) {
+            *mode = DisplayOne {
+                r: Rect {
+                    x: 0,
+                    y: 0,
+                    width: scanout.width,
+                    height: scanout.height,
+                },
+                enabled: 1,
+                flags: 0,
+            };
+        }
+        info
+    }
+
+    fn create_resource(
+        &mut self,
+        resource_id: u32,
+        format: u32,
+        width: u32,
+        height: u32,
+    ) -> result::Result<(), u32> {
+        if resource_id == 0 || self.resources.contains_key(&resource_id) {
+            return Err(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID);
+        }
+        if !is_supported_format(format) || width == 0 || height == 0 {
+            return Err(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+        }
+        let host_memory = Resource2d::size(width, height)
+            .and_then(|size| self.host_memory.checked_add(size))
+            .filter(|&host_memory| host_memory <= self.max_host_memory)
+            .ok_or(VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY)?;
+        // The size was validated above.
+        let res = Resource2d::new(resource_id, format, width, height)
+            .ok_or(VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY)?;
+        self.resources.insert(resource_id, res);
+        self.host_memory = host_memory;
+        Ok(())
+    }
+
+    fn unref_resource(&mut self, resource_id: u32) -> result::Result<(), u32> {
+        let res = self
+            .resources
+            .remove(&resource_id)
+            .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)?;
+        self.host_memory -= res.data().len();
+        // The resource is no longer displayed.
+        for (id, scanout) in self.scanouts.iter_mut().enumerate() {
+            if scanout.resource_id == resource_id {
+                scanout.resource_id = 0;
+                // The number of scanouts is bounded by `VIRTIO_GPU_MAX_SCANOUTS`.
+                self.backend.disable_scanout(id as u32);
+            }
+        }
+        Ok(())
+    }
+
+    fn set_scanout(
+        &mut self,
+        scanout_id: u32,
+        resource_id: u32,
+        rect: Rect,
+    ) -> result::Result<(), u32> {
+        let scanout = self
+            .scanouts
+            .get_mut(scanout_id as usize)
+            .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID)?;
+        if resource_id == 0 {
+            scanout.resource_id = 0;
+            self.backend.disable_scanout(scanout_id);
+            return Ok(());
+        }
+
+        let res = self
+            .resources
+            .get(&resource_id)
+            .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)?;
+        if rect.width == 0 || rect.height == 0 || !rect.fits(res.width(), res.height()) {
+            return Err(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+        }
+        scanout.resource_id = resource_id;
+        scanout.rect = rect;
+        self.backend.enable_scanout(scanout_id, res, rect);
+        Ok(())
+    }
+
+    fn flush_resource(&mut self, resource_id: u32, rect: Rect) -> result::Result<(), u32> {
+        let res = self
+            .resources
+            .get(&resource_id)
+            .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)?;
+        if !rect.fits(res.width(), res.height()) {
+            return Err(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+        }
+        for (id, scanout) in self.scanouts.iter().enumerate() {
+            if scanout.resource_id != resource_id {
+                continue;
+            }
+            if let Some(damage) = rect.intersection(&scanout.rect) {
+                // The number of scanouts is bounded by `VIRTIO_GPU_MAX_SCANOUTS`.
+                self.backend.flush(id as u32, res, damage);
+            }
+        }
+        Ok(())
+    }
+
+    fn update_cursor(
+        &mut self,
+        scanout_id: u32,
+        resource_id: u32,
+        (hot_x, hot_y): (u32, u32),
+        (x, y): (u32, u32),
+    ) -> result::Result<(), u32> {
+        if scanout_id as usize >= self.scanouts.len() {
+            return Err(VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID);
+        }
+        let cursor = match resource_id {
+            0 => None,
+            id => Some(
+                self.resources
+                    .get(&id)
+                    .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)?,
+            ),
+        };
+        self.backend.update_cursor(scanout_id, cursor, hot_x, hot_y);
+        self.backend.move_cursor(scanout_id, x, y);
+        Ok(())
+    }
+}
+
+impl<M, B, S> Gpu<M, B, S>
+where
+    M: GuestAddressSpace,
+    B: DisplayBackend,
+    S: SignalUsedQueue + SignalConfigChange,
+{
+    /// Changes the preferred size of a scanout (i.e. when the window of the display is
+    /// resized), and raises a `VIRTIO_GPU_EVENT_DISPLAY` event, such that the driver fetches the
+    /// display information again.
+    ///
+    /// # Arguments
+    /// * `scanout_id` - The identifier of the scanout.
+    /// * `width` - The new width.
+    /// * `height` - The new height.
+    pub fn resize_scanout(&mut self, scanout_id: u32, width: u32, height: u32) -> Result<()> {
+        let scanout = self
+            .scanouts
+            .get_mut(scanout_id as usize)
+            .ok_or(Error::InvalidScanout(scanout_id))?;
+        if width == 0 || height == 0 {
+            return Err(Error::InvalidScanouts);
+        }
+        scanout.width = width;
+        scanout.height = height;
+        self.raise_event(VIRTIO_GPU_EVENT_DISPLAY);
+        Ok(())
+    }
+
+    // Sets an event bit in the configuration space, and notifies the driver when the device is
+    // activated. The events acknowledged by the driver (through `events_clear`) are cleared
+    // first, because the configuration space writes of the driver are not intercepted.
+    fn raise_event(&mut self, event: u32) {
+        let events_clear = self.config_field(ConfigSpace::EVENTS_CLEAR_OFFSET);
+        let events_read = self.config_field(ConfigSpace::EVENTS_READ_OFFSET);
+        self.set_config_field(ConfigSpace::EVENTS_CLEAR_OFFSET, 0);
+        self.set_config_field(
+            ConfigSpace::EVENTS_READ_OFFSET,
+            (events_read & !events_clear) | event,
+        );
+        self.cfg.config_generation = self.cfg.config_generation.wrapping_add(1);
+
+        if self.cfg.device_activated {
+            self.cfg.signal_config_change();
+            self.driver_notify.signal_config_change();
+        }
+    }
+
+    fn config_field(&self, offset: usize) -> u32 {
+        self.cfg.config_space[offset..offset + 4]
+            .try_into()
+            .map_or(0, u32::from_le_bytes)
+    }
+
+    fn set_config_field(&mut self, offset: usize, value: u32) {
+        self.cfg.config_space[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+impl<M, B, S> VirtioDeviceActions for Gpu<M, B, S>
+where
+    M: GuestAddressSpace,
+    B: DisplayBackend,
+    S: SignalUsedQueue,
+{
+    type E = Error;
+
+    fn activate(&mut self) -> Result<()> {
+        if self.cfg.device_activated {
+            return Err(Error::AlreadyActivated);
+        }
+        if !self.cfg.queues.iter().all(Queue::is_valid) {
+            return Err(Error::InvalidQueues);
+        }
+        self.cfg.device_activated = true;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        // The resources are dropped, so nothing is displayed anymore.
+        for (id, scanout) in self.scanouts.iter_mut().enumerate() {
+            if scanout.resource_id != 0 {
+                scanout.resource_id = 0;
+                // The number of scanouts is bounded by `VIRTIO_GPU_MAX_SCANOUTS`.
+                self.backend.disable_scanout(id as u32);
+            }
+        }
+        self.resources.clear();
+        self.host_memory = 0;
+
+        let cfg = &mut self.cfg;
+        cfg.driver_features = 0;
+        cfg.device_features_select = 0;
+        cfg.driver_features_select = 0;
+        cfg.device_status = 0;
+        cfg.queue_select = 0;
+        cfg.queues.iter_mut().for_each(Queue::reset);
+        cfg.device_activated = false;
+        cfg.reset_interrupt_status();
+        Ok(())
+    }
+}
+
+impl<M, B, S> VirtioMmioDevice<M> for Gpu<M, B, S>
+where
+    M: GuestAddressSpace + 'static,
+    B: DisplayBackend,
+    S: SignalUsedQueue,
+{
+    fn queue_notify(&mut self, val: u32) {
+        // Queue indices always fit in an `u16`.
+        let result = match val as u16 {
+            CONTROL_QUEUE => self.process_control_queue(),
+            CURSOR_QUEUE => self.process_cursor_queue(),
+            index => Err(Error::InvalidQueueIndex(index)),
+        };
+        if let Err(e) = result {
+            error!("failed to process queue {}: {}", val, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryMmap};
+    use vmm_sys_util::eventfd::EventFd;
+
+    use virtio_device::mock::activate;
+    use virtio_device::{VirtioDevice, VIRTIO_MMIO_INT_CONFIG};
+    use virtio_queue::mock::VirtQueue;
+    use virtio_queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    use crate::protocol::{
+        CtrlHeader, CursorPos, MemEntry, ResourceAttachBacking, ResourceCreate2d, ResourceFlush,
+        ResourceId, SetScanout, TransferToHost2d, UpdateCursor,
+    };
+
+    type Mem = Arc<GuestMemoryMmap>;
+
+    // Records the calls from the device.
+    #[derive(Debug, Default)]
+    struct TestBackend {
+        enabled: Vec<(u32, Rect)>,
+        disabled: Vec<u32>,
+        // The scanout, the area, and the first pixel of the area.
+        flushed: Vec<(u32, Rect, [u8; 4])>,
+        cursors: Vec<(u32, Option<u32>, u32, u32)>,
+        moves: Vec<(u32, u32, u32)>,
+    }
+
+    impl DisplayBackend for TestBackend {
+        fn enable_scanout(&mut self, scanout_id: u32, _resource: &Resource2d, rect: Rect) {
+            self.enabled.push((scanout_id, rect));
+        }
+
+        fn disable_scanout(&mut self, scanout_id: u32) {
+            self.disabled.push(scanout_id);
+        }
+
+        fn flush(&mut self, scanout_id: u32, resource: &Resource2d, rect: Rect) {
+            let start = rect.y as usize * resource.stride() + rect.x as usize * 4;
+            let mut pixel = [0u8; 4];
+            pixel.copy_from_slice(&resource.data()[start..start + 4]);
+            self.flushed.push((scanout_id, rect, pixel));
+        }
+
+        fn update_cursor(
+            &mut self,
+            scanout_id: u32,
+            cursor: Option<&Resource2d>,
+            hot_x: u32,
+            hot_y: u32,
+        ) {
+            self.cursors
+                .push((scanout_id, cursor.map(Resource2d::id), hot_x, hot_y));
+        }
+
+        fn move_cursor(&mut self, scanout_id: u32, x: u32, y: u32) {
+            self.moves.push((scanout_id, x, y));
+        }
+    }
+
+    type TestGpu = Gpu<Mem, TestBackend, EventFd>;
+
+    fn builder(mem: &Mem) -> GpuBuilder<Mem, TestBackend, EventFd> {
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        GpuBuilder::new(mem.clone(), TestBackend::default(), evt)
+            .with_scanouts(&[(640, 480), (320, 200)])
+            .with_queue_size(16)
+    }
+
+    fn virt_queues(mem: &GuestMemoryMmap) -> Vec<VirtQueue<'_>> {
+        (0..2)
+            .map(|i| VirtQueue::new(GuestAddress(i * 0x1000), mem, 16))
+            .collect()
+    }
+
+    // Returns the bytes of a command.
+    fn command<T: ByteValued>(type_: u32, body: &T) -> Vec<u8> {
+        let hdr = CtrlHeader {
+            type_,
+            ..Default::default()
+        };
+        let mut bytes = hdr.as_slice().to_vec();
+        bytes.extend_from_slice(body.as_slice());
+        bytes
+    }
+
+    // Sends a command through the control queue, and returns the type of the response.
+    fn send(gpu: &mut TestGpu, mem: &GuestMemoryMmap, vq: &VirtQueue, cmd: &[u8]) -> u32 {
+        let avail = vq.avail.idx().load();
+        let index = (avail * 2) % vq.size();
+        mem.write_slice(cmd, GuestAddress(0x1_0000)).unwrap();
+        vq.dtable(index)
+            .set(0x1_0000, cmd.len() as u32, VIRTQ_DESC_F_NEXT, index + 1);
+        vq.dtable(index + 1)
+            .set(0x2_0000, 0x200, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring(avail % vq.size()).store(index);
+        vq.avail.idx().store(avail + 1);
+
+        gpu.queue_notify(u32::from(CONTROL_QUEUE));
+        assert_eq!(vq.used.idx().load(), avail + 1);
+        mem.read_obj::<CtrlHeader>(GuestAddress(0x2_0000))
+            .unwrap()
+            .type_
+    }
+
+    fn create(resource_id: u32, width: u32, height: u32) -> Vec<u8> {
+        let create = ResourceCreate2d {
+            resource_id,
+            format: VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM,
+            width,
+            height,
+        };
+        command(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D, &create)
+    }
+
+    fn rect(x: u32, y: u32, width: u32, height: u32) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn test_build() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let g = builder(&mem).build().unwrap();
+        assert_eq!(VirtioDevice::device_type(&g), VIRTIO_ID_GPU);
+        assert_eq!(g.num_queues(), 2);
+        assert_ne!(g.device_features() & (1 << VIRTIO_F_VERSION_1), 0);
+        let mut num = [0u8; 4];
+        g.read_config(ConfigSpace::NUM_SCANOUTS_OFFSET, &mut num);
+        assert_eq!(u32::from_le_bytes(num), 2);
+
+        for scanouts in [vec![], vec![(640, 0)], vec![(1, 1); 17]].iter() {
+            assert!(matches!(
+                builder(&mem).with_scanouts(scanouts).build(),
+                Err(Error::InvalidScanouts)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_2d_commands() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let vqs = virt_queues(&mem);
+        let mut g = builder(&mem).with_max_host_memory(0x8000).build().unwrap();
+        assert!(matches!(
+            g.process_control_queue(),
+            Err(Error::InvalidQueueIndex(CONTROL_QUEUE))
+        ));
+        activate(&mut g, &vqs, 0);
+        assert!(g.is_activated());
+        let vq = &vqs[0];
+
+        let cmd = command(VIRTIO_GPU_CMD_GET_DISPLAY_INFO, &[0u8; 0]);
+        assert_eq!(
+            send(&mut g, &mem, vq, &cmd),
+            VIRTIO_GPU_RESP_OK_DISPLAY_INFO
+        );
+        let info: RespDisplayInfo = mem
+            .read_obj(GuestAddress(0x2_0000 + CtrlHeader::LEN as u64))
+            .unwrap();
+        assert_eq!(info.pmodes[0].r, rect(0, 0, 640, 480));
+        assert_eq!(info.pmodes[1].r, rect(0, 0, 320, 200));
+        assert_eq!(info.pmodes[1].enabled, 1);
+        assert_eq!(info.pmodes[2].enabled, 0);
+
+        // Resource creation.
+        assert_eq!(
+            send(&mut g, &mem, vq, &create(1, 64, 64)),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        assert_eq!(g.resource(1).unwrap().width(), 64);
+        assert_eq!(
+            send(&mut g, &mem, vq, &create(1, 64, 64)),
+            VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID
+        );
+        assert_eq!(
+            send(&mut g, &mem, vq, &create(0, 64, 64)),
+            VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID
+        );
+        assert_eq!(
+            send(&mut g, &mem, vq, &create(2, 0, 64)),
+            VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER
+        );
+        // The host memory limit is 0x8000 bytes, and the first resource takes half of it.
+        assert_eq!(
+            send(&mut g, &mem, vq, &create(2, 64, 65)),
+            VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY
+        );
+        assert_eq!(
+            send(&mut g, &mem, vq, &create(2, 64, 64)),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+
+        // The guest image is filled with its row numbers, and lives in two backing entries.
+        for row in 0..64u64 {
+            mem.write_slice(&[row as u8; 256], GuestAddress(0x4_0000 + row * 256))
+                .unwrap();
+        }
+        let attach = ResourceAttachBacking {
+            resource_id: 1,
+            nr_entries: 2,
+        };
+        let mut cmd = command(VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING, &attach);
+        let entries = [
+            MemEntry {
+                addr: 0x4_0000,
+                length: 0x2000,
+                padding: 0,
+            },
+            MemEntry {
+                addr: 0x4_2000,
+                length: 0x2000,
+                padding: 0,
+            },
+        ];
+        cmd.extend_from_slice(entries[0].as_slice());
+        cmd.extend_from_slice(entries[1].as_slice());
+        assert_eq!(send(&mut g, &mem, vq, &cmd), VIRTIO_GPU_RESP_OK_NODATA);
+        assert_eq!(send(&mut g, &mem, vq, &cmd), VIRTIO_GPU_RESP_ERR_UNSPEC);
+
+        let transfer = TransferToHost2d {
+            r: rect(0, 0, 64, 64),
+            offset: 0,
+            resource_id: 1,
+            padding: 0,
+        };
+        let cmd = command(VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D, &transfer);
+        assert_eq!(send(&mut g, &mem, vq, &cmd), VIRTIO_GPU_RESP_OK_NODATA);
+        assert_eq!(g.resource(1).unwrap().data()[40 * 256], 40);
+        let cmd = command(
+            VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D,
+            &TransferToHost2d {
+                r: rect(1, 0, 64, 64),
+                ..transfer
+            },
+        );
+        assert_eq!(
+            send(&mut g, &mem, vq, &cmd),
+            VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER
+        );
+        // The second resource doesn't have any backing pages.
+        let cmd = command(
+            VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D,
+            &TransferToHost2d {
+                resource_id: 2,
+                ..transfer
+            },
+        );
+        assert_eq!(send(&mut g, &mem, vq, &cmd), VIRTIO_GPU_RESP_ERR_UNSPEC);
+
+        // Show the bottom half of the first resource on both scanouts.
+        for &scanout_id in [0, 1, 2].iter() {
+            let set = SetScanout {
+                r: rect(0, 32, 64, 32),
+                scanout_id,
+                resource_id: 1,
+            };
+            let expected = match scanout_id {
+                2 => VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID,
+                _ => VIRTIO_GPU_RESP_OK_NODATA,
+            };
+            let cmd = command(VIRTIO_GPU_CMD_SET_SCANOUT, &set);
+            assert_eq!(send(&mut g, &mem, vq, &cmd), expected);
+        }
+        assert_eq!(
+            g.backend().enabled,
+            [(0, rect(0, 32, 64, 32)), (1, rect(0, 32, 64, 32))]
+        );
+        let set = SetScanout {
+            r: rect(0, 0, 0, 0),
+            scanout_id: 1,
+            resource_id: 0,
+        };
+        let cmd = command(VIRTIO_GPU_CMD_SET_SCANOUT, &set);
+        assert_eq!(send(&mut g, &mem, vq, &cmd), VIRTIO_GPU_RESP_OK_NODATA);
+        assert_eq!(g.backend().disabled, [1]);
+
+        // Only the displayed part of the flushed area reaches the backend.
+        let flush = ResourceFlush {
+            r: rect(8, 16, 32, 32),
+            resource_id: 1,
+            padding: 0,
+        };
+        let cmd = command(VIRTIO_GPU_CMD_RESOURCE_FLUSH, &flush);
+        assert_eq!(send(&mut g, &mem, vq, &cmd), VIRTIO_GPU_RESP_OK_NODATA);
+        assert_eq!(g.backend().flushed, [(0, rect(8, 32, 32, 16), [32; 4])]);
+        let flush = ResourceFlush {
+            resource_id: 3,
+            ..flush
+        };
+        let cmd = command(VIRTIO_GPU_CMD_RESOURCE_FLUSH, &flush);
+        assert_eq!(
+            send(&mut g, &mem, vq, &cmd),
+            VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID
+        );
+
+        // Detaching the backing pages, and dropping the displayed resource.
+        let id = ResourceId {
+            resource_id: 1,
+            padding: 0,
+        };
+        let cmd = command(VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING, &id);
+        assert_eq!(send(&mut g, &mem, vq, &cmd), VIRTIO_GPU_RESP_OK_NODATA);
+        assert_eq!(send(&mut g, &mem, vq, &cmd), VIRTIO_GPU_RESP_ERR_UNSPEC);
+        let cmd = command(VIRTIO_GPU_CMD_RESOURCE_UNREF, &id);
+        assert_eq!(send(&mut g, &mem, vq, &cmd), VIRTIO_GPU_RESP_OK_NODATA);
+        assert!(g.resource(1).is_none());
+        assert_eq!(g.backend().disabled, [1, 0]);
+        assert_eq!(
+            send(&mut g, &mem, vq, &create(3, 64, 64)),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+
+        let cmd = command(0x0200, &[0u8; 0]);
+        assert_eq!(send(&mut g, &mem, vq, &cmd), VIRTIO_GPU_RESP_ERR_UNSPEC);
+
+        VirtioDeviceActions::reset(&mut g).unwrap();
+        assert!(!g.is_activated());
+        assert!(g.resource(2).is_none());
+    }
+
+    #[test]
+    fn test_cursor() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let vqs = virt_queues(&mem);
+        let mut g = builder(&mem).build().unwrap();
+        activate(&mut g, &vqs, 0);
+        assert_eq!(
+            send(&mut g, &mem, &vqs[0], &create(1, 64, 64)),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+
+        // The cursor commands don't have a response buffer.
+        let update = UpdateCursor {
+            pos: CursorPos {
+                scanout_id: 1,
+                x: 100,
+                y: 200,
+                padding: 0,
+            },
+            resource_id: 1,
+            hot_x: 3,
+            hot_y: 4,
+            padding: 0,
+        };
+        let commands = [
+            command(VIRTIO_GPU_CMD_UPDATE_CURSOR, &update),
+            command(
+                VIRTIO_GPU_CMD_MOVE_CURSOR,
+                &UpdateCursor {
+                    pos: CursorPos { x: 5, ..update.pos },
+                    ..update
+                },
+            ),
+            command(
+                VIRTIO_GPU_CMD_UPDATE_CURSOR,
+                &UpdateCursor {
+                    resource_id: 0,
+                    ..update
+                },
+            ),
+            // An invalid scanout.
+            command(
+                VIRTIO_GPU_CMD_MOVE_CURSOR,
+                &UpdateCursor {
+                    pos: CursorPos {
+                        scanout_id: 2,
+                        ..update.pos
+                    },
+                    ..update
+                },
+            ),
+        ];
+        let vq = &vqs[1];
+        for (i, cmd) in commands.iter().enumerate() {
+            let addr = 0x1_0000 + i as u64 * 0x100;
+            mem.write_slice(cmd, GuestAddress(addr)).unwrap();
+            vq.dtable(i as u16).set(addr, cmd.len() as u32, 0, 0);
+            vq.avail.ring(i as u16).store(i as u16);
+        }
+        vq.avail.idx().store(commands.len() as u16);
+        g.queue_notify(u32::from(CURSOR_QUEUE));
+        assert_eq!(vq.used.idx().load(), 4);
+        assert_eq!(g.backend().cursors, [(1, Some(1), 3, 4), (1, None, 3, 4)]);
+        assert_eq!(
+            g.backend().moves,
+            [(1, 100, 200), (1, 5, 200), (1, 100, 200)]
+        );
+        assert_eq!(g.driver_notify.read().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_resize_scanout() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let vqs = virt_queues(&mem);
+        let mut g = builder(&mem).build().unwrap();
+        activate(&mut g, &vqs, 0);
+
+        assert!(matches!(
+            g.resize_scanout(2, 800, 600),
+            Err(Error::InvalidScanout(2))
+        ));
+        g.resize_scanout(1, 800, 600).unwrap();
+        assert_eq!(g.config_field(ConfigSpace::EVENTS_READ_OFFSET), 1);
+        assert_eq!(g.config_generation(), 1);
+        assert_eq!(
+            g.interrupt_status().load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_CONFIG
+        );
+        assert_eq!(g.driver_notify.read().unwrap(), 1);
+        assert_eq!(g.display_info().pmodes[1].r, rect(0, 0, 800, 600));
+
+        // The driver acknowledges the event, which is cleared before raising the next one.
+        g.write_config(ConfigSpace::EVENTS_CLEAR_OFFSET, &1u32.to_le_bytes());
+        g.raise_event(2);
+        assert_eq!(g.config_field(ConfigSpace::EVENTS_READ_OFFSET), 2);
+        assert_eq!(g.config_field(ConfigSpace::EVENTS_CLEAR_OFFSET), 0);
+    }
+}
@@ -0,0 +1,29 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A crate that provides gpu device specific components as described
+//! by the virtio specification.
+
+#![deny(missing_docs)]
+
+/// Contains the interface between the gpu device and the display.
+pub mod backend;
+
+/// Contains the parsing of the commands sent by the driver.
+pub mod command;
+
+/// Contains the gpu device configuration space abstraction.
+pub mod config;
+
+/// Contains virtio gpu constant definitions.
+pub mod defs;
+
+/// Contains a reference virtio gpu device implementation.
+pub mod device;
+
+/// Contains the layout of the gpu commands and responses.
+pub mod protocol;
+
+/// Contains the 2D resource abstraction.
+pub mod resource;
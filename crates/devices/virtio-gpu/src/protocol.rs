@@ -0,0 +1,299 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Virtio gpu command and response layouts.
+//!
+//! Every command starts with a [`CtrlHeader`](struct.CtrlHeader.html), which is followed by the
+//! command specific structure defined here (the structures from `linux/virtio_gpu.h` without
+//! the leading header). Responses start with a header as well.
+//!
+//! All fields are expected to hold little endian values, which is always the case on the
+//! platforms that are currently supported (`x86_64` and `aarch64`).
+
+use std::cmp::{max, min};
+use std::mem::size_of;
+
+use vm_memory::ByteValued;
+
+use crate::defs::VIRTIO_GPU_MAX_SCANOUTS;
+
+/// The header of commands and responses (the `virtio_gpu_ctrl_hdr` structure).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct CtrlHeader {
+    /// The command or response type.
+    pub type_: u32,
+    /// The command flags (i.e. `VIRTIO_GPU_FLAG_FENCE`).
+    pub flags: u32,
+    /// The fence identifier, which is echoed by the response.
+    pub fence_id: u64,
+    /// The rendering context, which is not used by 2D commands.
+    pub ctx_id: u32,
+    /// Unused.
+    pub padding: u32,
+}
+
+/// A rectangle, in pixels.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct Rect {
+    /// The horizontal position of the top left corner.
+    pub x: u32,
+    /// The vertical position of the top left corner.
+    pub y: u32,
+    /// The width.
+    pub width: u32,
+    /// The height.
+    pub height: u32,
+}
+
+impl Rect {
+    /// Returns whether the rectangle fits in an image with the specified size.
+    ///
+    /// # Arguments
+    /// * `width` - The width of the image.
+    /// * `height` - The height of the image.
+    pub fn fits(&self, width: u32, height: u32) -> bool {
+        self.x.checked_add(self.width).is_some_and(|x| x <= width)
+            && self.y.checked_add(self.height).is_some_and(|y| y <= height)
+    }
+
+    /// Returns the intersection with another rectangle, if it's not empty.
+    ///
+    /// # Arguments
+    /// * `other` - The other rectangle.
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        let x = max(self.x, other.x);
+        let y = max(self.y, other.y);
+        let right = min(
+            u64::from(self.x) + u64::from(self.width),
+            u64::from(other.x) + u64::from(other.width),
+        );
+        let bottom = min(
+            u64::from(self.y) + u64::from(self.height),
+            u64::from(other.y) + u64::from(other.height),
+        );
+        if right <= u64::from(x) || bottom <= u64::from(y) {
+            return None;
+        }
+        // The sizes fit in an `u32` because they are smaller than the sizes of both rectangles.
+        Some(Rect {
+            x,
+            y,
+            width: (right - u64::from(x)) as u32,
+            height: (bottom - u64::from(y)) as u32,
+        })
+    }
+}
+
+/// The mode of a scanout (the `virtio_gpu_display_one` structure).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct DisplayOne {
+    /// The preferred position and size of the scanout.
+    pub r: Rect,
+    /// Whether the scanout is enabled.
+    pub enabled: u32,
+    /// Unused.
+    pub flags: u32,
+}
+
+/// The response to `VIRTIO_GPU_CMD_GET_DISPLAY_INFO` (the `virtio_gpu_resp_display_info`
+/// structure).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct RespDisplayInfo {
+    /// The modes of all the scanouts.
+    pub pmodes: [DisplayOne; VIRTIO_GPU_MAX_SCANOUTS],
+}
+
+/// The `VIRTIO_GPU_CMD_RESOURCE_CREATE_2D` command (the `virtio_gpu_resource_create_2d`
+/// structure).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct ResourceCreate2d {
+    /// The identifier of the new resource.
+    pub resource_id: u32,
+    /// The pixel format.
+    pub format: u32,
+    /// The width.
+    pub width: u32,
+    /// The height.
+    pub height: u32,
+}
+
+/// The `VIRTIO_GPU_CMD_RESOURCE_UNREF` and `VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING` commands
+/// (the `virtio_gpu_resource_unref` and `virtio_gpu_resource_detach_backing` structures).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct ResourceId {
+    /// The identifier of the resource.
+    pub resource_id: u32,
+    /// Unused.
+    pub padding: u32,
+}
+
+/// The `VIRTIO_GPU_CMD_SET_SCANOUT` command (the `virtio_gpu_set_scanout` structure).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct SetScanout {
+    /// The area of the resource which is displayed.
+    pub r: Rect,
+    /// The identifier of the scanout.
+    pub scanout_id: u32,
+    /// The identifier of the displayed resource, or zero to disable the scanout.
+    pub resource_id: u32,
+}
+
+/// The `VIRTIO_GPU_CMD_RESOURCE_FLUSH` command (the `virtio_gpu_resource_flush` structure).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct ResourceFlush {
+    /// The area of the resource which changed.
+    pub r: Rect,
+    /// The identifier of the resource.
+    pub resource_id: u32,
+    /// Unused.
+    pub padding: u32,
+}
+
+/// The `VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D` command (the `virtio_gpu_transfer_to_host_2d`
+/// structure).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct TransferToHost2d {
+    /// The area of the resource which is updated.
+    pub r: Rect,
+    /// The offset of the area in the backing pages.
+    pub offset: u64,
+    /// The identifier of the resource.
+    pub resource_id: u32,
+    /// Unused.
+    pub padding: u32,
+}
+
+/// The `VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING` command (the
+/// `virtio_gpu_resource_attach_backing` structure), which is followed by `nr_entries`
+/// [`MemEntry`](struct.MemEntry.html) structures.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct ResourceAttachBacking {
+    /// The identifier of the resource.
+    pub resource_id: u32,
+    /// The number of backing entries.
+    pub nr_entries: u32,
+}
+
+/// A backing entry of a resource (the `virtio_gpu_mem_entry` structure).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct MemEntry {
+    /// The guest physical address of the entry.
+    pub addr: u64,
+    /// The length of the entry.
+    pub length: u32,
+    /// Unused.
+    pub padding: u32,
+}
+
+/// The position of the cursor (the `virtio_gpu_cursor_pos` structure).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct CursorPos {
+    /// The identifier of the scanout which shows the cursor.
+    pub scanout_id: u32,
+    /// The horizontal position.
+    pub x: u32,
+    /// The vertical position.
+    pub y: u32,
+    /// Unused.
+    pub padding: u32,
+}
+
+/// The `VIRTIO_GPU_CMD_UPDATE_CURSOR` and `VIRTIO_GPU_CMD_MOVE_CURSOR` commands (the
+/// `virtio_gpu_update_cursor` structure).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct UpdateCursor {
+    /// The position of the cursor.
+    pub pos: CursorPos,
+    /// The identifier of the cursor image resource, or zero to hide the cursor (only used by
+    /// `VIRTIO_GPU_CMD_UPDATE_CURSOR`).
+    pub resource_id: u32,
+    /// The horizontal position of the hot spot in the cursor image.
+    pub hot_x: u32,
+    /// The vertical position of the hot spot in the cursor image.
+    pub hot_y: u32,
+    /// Unused.
+    pub padding: u32,
+}
+
+// Safe because the structures below only contain plain data, and there's no padding between
+// (or after) the fields.
+unsafe impl ByteValued for CtrlHeader {}
+unsafe impl ByteValued for Rect {}
+unsafe impl ByteValued for DisplayOne {}
+unsafe impl ByteValued for RespDisplayInfo {}
+unsafe impl ByteValued for ResourceCreate2d {}
+unsafe impl ByteValued for ResourceId {}
+unsafe impl ByteValued for SetScanout {}
+unsafe impl ByteValued for ResourceFlush {}
+unsafe impl ByteValued for TransferToHost2d {}
+unsafe impl ByteValued for ResourceAttachBacking {}
+unsafe impl ByteValued for MemEntry {}
+unsafe impl ByteValued for CursorPos {}
+unsafe impl ByteValued for UpdateCursor {}
+
+impl CtrlHeader {
+    /// The size of the header.
+    pub const LEN: usize = size_of::<CtrlHeader>();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(CtrlHeader::LEN, 24);
+        assert_eq!(size_of::<RespDisplayInfo>(), 384);
+        assert_eq!(size_of::<SetScanout>(), 24);
+        assert_eq!(size_of::<TransferToHost2d>(), 32);
+        assert_eq!(size_of::<MemEntry>(), 16);
+        assert_eq!(size_of::<UpdateCursor>(), 32);
+    }
+
+    #[test]
+    fn test_rect() {
+        let r = Rect {
+            x: 10,
+            y: 20,
+            width: 100,
+            height: 50,
+        };
+        assert!(r.fits(110, 70));
+        assert!(!r.fits(109, 70));
+        assert!(!r.fits(110, 69));
+        let r2 = Rect { x: u32::MAX, ..r };
+        assert!(!r2.fits(u32::MAX, u32::MAX));
+
+        let other = Rect {
+            x: 50,
+            y: 0,
+            width: 100,
+            height: 30,
+        };
+        assert_eq!(
+            r.intersection(&other),
+            Some(Rect {
+                x: 50,
+                y: 20,
+                width: 60,
+                height: 10
+            })
+        );
+        let other = Rect { x: 110, ..other };
+        assert_eq!(r.intersection(&other), None);
+    }
+}
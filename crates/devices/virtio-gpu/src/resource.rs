@@ -0,0 +1,288 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Virtio gpu 2D resource abstraction.
+//!
+//! A 2D resource is an image which lives on the host, and which the driver updates by
+//! transferring data from its backing pages in guest memory. This module provides the
+//! [`Resource2d`](struct.Resource2d.html) abstraction, which holds the host copy of the image.
+
+use std::convert::TryFrom;
+use std::fmt::{self, Display};
+use std::result;
+
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryError};
+
+use virtio_queue::areas::{self, read_areas, Area};
+
+use crate::defs::*;
+use crate::protocol::{MemEntry, Rect};
+
+/// The number of bytes per pixel, which is the same for all the supported formats.
+pub const BYTES_PER_PIXEL: usize = 4;
+
+/// Resource errors.
+#[derive(Debug)]
+pub enum Error {
+    /// The backing pages are too short for the transfer.
+    BackingTooShort,
+    /// Invalid memory access.
+    GuestMemory(GuestMemoryError),
+    /// The rectangle doesn't fit in the resource.
+    InvalidRect(Rect),
+    /// The resource doesn't have any backing pages.
+    NoBacking,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            BackingTooShort => write!(f, "backing pages too short for the transfer"),
+            GuestMemory(ref err) => write!(f, "error accessing guest memory: {}", err),
+            InvalidRect(r) => write!(
+                f,
+                "invalid rectangle {}x{} at ({}, {})",
+                r.width, r.height, r.x, r.y
+            ),
+            NoBacking => write!(f, "the resource has no backing pages"),
+        }
+    }
+}
+
+impl From<areas::Error> for Error {
+    fn from(e: areas::Error) -> Self {
+        match e {
+            // The backing pages aren't read through a descriptor chain, so the descriptor
+            // checks don't apply.
+            areas::Error::TooShort | areas::Error::UnexpectedReadOnlyDescriptor => {
+                Error::BackingTooShort
+            }
+            areas::Error::GuestMemory(e) => Error::GuestMemory(e),
+        }
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Returns whether a pixel format is supported.
+///
+/// # Arguments
+/// * `format` - The pixel format (`VIRTIO_GPU_FORMAT_*`).
+pub fn is_supported_format(format: u32) -> bool {
+    matches!(
+        format,
+        VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM
+            | VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM
+            | VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM
+            | VIRTIO_GPU_FORMAT_X8R8G8B8_UNORM
+            | VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM
+            | VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM
+            | VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM
+            | VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM
+    )
+}
+
+/// A 2D resource.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Resource2d {
+    id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+    backing: Vec<Area>,
+}
+
+impl Resource2d {
+    /// Returns the size of the host copy of an image, or `None` on overflow.
+    ///
+    /// # Arguments
+    /// * `width` - The width of the image.
+    /// * `height` - The height of the image.
+    pub fn size(width: u32, height: u32) -> Option<usize> {
+        (width as usize)
+            .checked_mul(height as usize)?
+            .checked_mul(BYTES_PER_PIXEL)
+    }
+
+    /// Creates a new `Resource2d`, which is initially black (or transparent).
+    ///
+    /// # Arguments
+    /// * `id` - The identifier of the resource.
+    /// * `format` - The pixel format.
+    /// * `width` - The width of the image.
+    /// * `height` - The height of the image.
+    pub fn new(id: u32, format: u32, width: u32, height: u32) -> Option<Self> {
+        Some(Resource2d {
+            id,
+            format,
+            width,
+            height,
+            data: vec![0; Self::size(width, height)?],
+            backing: Vec::new(),
+        })
+    }
+
+    /// Returns the identifier of the resource.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Returns the pixel format.
+    pub fn format(&self) -> u32 {
+        self.format
+    }
+
+    /// Returns the width of the image.
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// Returns the height of the image.
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Returns the length of a row of pixels, in bytes.
+    pub fn stride(&self) -> usize {
+        self.width as usize * BYTES_PER_PIXEL
+    }
+
+    /// Returns the host copy of the image, which holds `height` rows of `stride` bytes.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns whether the resource has backing pages.
+    pub fn has_backing(&self) -> bool {
+        !self.backing.is_empty()
+    }
+
+    /// Sets the backing pages of the resource.
+    ///
+    /// # Arguments
+    /// * `entries` - The guest memory areas which hold the backing pages, in order.
+    pub fn attach_backing(&mut self, entries: &[MemEntry]) {
+        self.backing = entries
+            .iter()
+            .map(|entry| (GuestAddress(entry.addr), entry.length as usize))
+            .collect();
+    }
+
+    /// Drops the backing pages of the resource.
+    pub fn detach_backing(&mut self) {
+        self.backing.clear();
+    }
+
+    /// Copies an area of the image from the backing pages to the host copy. The rows of the
+    /// area start at `offset` in the backing pages, and they are `stride` bytes apart.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    /// * `r` - The area of the image.
+    /// * `offset` - The offset of the area in the backing pages.
+    pub fn transfer_to_host<M: GuestMemory>(
+        &mut self,
+        mem: &M,
+        r: &Rect,
+        offset: u64,
+    ) -> Result<()> {
+        if !r.fits(self.width, self.height) {
+            return Err(Error::InvalidRect(*r));
+        }
+        if !self.has_backing() {
+            return Err(Error::NoBacking);
+        }
+
+        let stride = self.stride();
+        let row_len = r.width as usize * BYTES_PER_PIXEL;
+        for row in 0..r.height as usize {
+            let src = usize::try_from(offset)
+                .ok()
+                .and_then(|offset| offset.checked_add(row * stride))
+                .ok_or(Error::BackingTooShort)?;
+            // The destination is within the image, because the rectangle fits.
+            let dst = (r.y as usize + row) * stride + r.x as usize * BYTES_PER_PIXEL;
+            read_areas(mem, &self.backing, src, &mut self.data[dst..dst + row_len])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::{Bytes, GuestMemoryMmap};
+
+    #[test]
+    fn test_resource() {
+        assert!(is_supported_format(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM));
+        assert!(!is_supported_format(0));
+        assert_eq!(Resource2d::size(2, 3), Some(24));
+        assert_eq!(Resource2d::size(u32::MAX, u32::MAX), None);
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let mut res = Resource2d::new(1, VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM, 4, 4).unwrap();
+        assert_eq!(res.stride(), 16);
+        assert_eq!(res.data(), &[0; 64][..]);
+
+        let r = Rect {
+            x: 1,
+            y: 1,
+            width: 2,
+            height: 2,
+        };
+        assert!(matches!(
+            res.transfer_to_host(&mem, &r, 0),
+            Err(Error::NoBacking)
+        ));
+
+        // The guest image is split across two backing entries.
+        let image: Vec<u8> = (0..64).collect();
+        mem.write_slice(&image[..20], GuestAddress(0x1_0000))
+            .unwrap();
+        mem.write_slice(&image[20..], GuestAddress(0x2_0000))
+            .unwrap();
+        res.attach_backing(&[
+            MemEntry {
+                addr: 0x1_0000,
+                length: 20,
+                padding: 0,
+            },
+            MemEntry {
+                addr: 0x2_0000,
+                length: 44,
+                padding: 0,
+            },
+        ]);
+        assert!(res.has_backing());
+
+        let big = Rect { width: 4, ..r };
+        assert!(matches!(
+            res.transfer_to_host(&mem, &big, 0),
+            Err(Error::InvalidRect(_))
+        ));
+        // The offset of the area is the offset of its top left pixel.
+        res.transfer_to_host(&mem, &r, 20).unwrap();
+        for (i, &byte) in res.data().iter().enumerate() {
+            let (row, col) = (i / 16, i % 16 / 4);
+            if (1..3).contains(&row) && (1..3).contains(&col) {
+                assert_eq!(byte, i as u8);
+            } else {
+                assert_eq!(byte, 0);
+            }
+        }
+        assert!(matches!(
+            res.transfer_to_host(&mem, &r, 44),
+            Err(Error::BackingTooShort)
+        ));
+
+        res.detach_backing();
+        assert!(!res.has_backing());
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Packet capture abstractions.
+//!
+//! This module provides the following abstractions, which can be used for inspecting the
+//! traffic of a network device without any external tooling:
+//!
+//! - [`PacketCapture`](trait.PacketCapture.html) which is called by the queue pair handlers with
+//!   a copy of every Ethernet frame exchanged with the packet backend. It's implemented for all
+//!   the `FnMut(u16, Direction, &[u8])` closures, so a callback can be used directly.
+//! - [`PcapWriter`](struct.PcapWriter.html) which records the frames in the pcap file format,
+//!   so they can be analyzed with tools such as Wireshark or tcpdump.
+//!
+//! The frames are captured without their network header. Packets which are exchanged by a
+//! vhost-net backend don't go through the queue pair handlers, so they can't be captured.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+// The pcap file header fields (see https://wiki.wireshark.org/Development/LibpcapFileFormat).
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const PCAP_SNAPLEN: u32 = 262_144;
+const PCAP_LINKTYPE_ETHERNET: u32 = 1;
+
+/// The direction of a captured frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// The frame was received from the packet backend, and is delivered to the driver.
+    Rx,
+    /// The frame was sent by the driver to the packet backend.
+    Tx,
+}
+
+/// A sink for the frames which go through a network device.
+pub trait PacketCapture: Send {
+    /// Processes a copy of a frame.
+    ///
+    /// # Arguments
+    /// * `pair` - The index of the queue pair which handled the frame.
+    /// * `direction` - Whether the frame was received or transmitted.
+    /// * `frame` - The Ethernet frame, without the network header.
+    fn capture(&mut self, pair: u16, direction: Direction, frame: &[u8]);
+}
+
+impl<F> PacketCapture for F
+where
+    F: FnMut(u16, Direction, &[u8]) + Send,
+{
+    fn capture(&mut self, pair: u16, direction: Direction, frame: &[u8]) {
+        self(pair, direction, frame)
+    }
+}
+
+// Allows the handlers which hold a capture to derive `Debug`.
+impl fmt::Debug for dyn PacketCapture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PacketCapture")
+    }
+}
+
+/// A packet capture which can be shared by the handlers of all the queue pairs.
+pub type SharedPacketCapture = Arc<Mutex<dyn PacketCapture>>;
+
+/// Records the captured frames in the pcap file format.
+///
+/// The pcap format doesn't store the direction of the frames, nor the queue pair which handled
+/// them, so the frames of both directions from all the pairs end up in the same stream. Write
+/// errors are logged, and the affected frames are dropped from the capture.
+///
+/// # Example
+///
+/// ```rust
+/// # use std::sync::{Arc, Mutex};
+/// # use virtio_net::capture::{PcapWriter, SharedPacketCapture};
+/// let writer = PcapWriter::new(Vec::new()).unwrap();
+/// let capture: SharedPacketCapture = Arc::new(Mutex::new(writer));
+/// ```
+#[derive(Debug)]
+pub struct PcapWriter<W: Write + Send> {
+    writer: W,
+}
+
+impl<W: Write + Send> PcapWriter<W> {
+    /// Creates a new `PcapWriter`, and writes the pcap file header to `writer`.
+    ///
+    /// # Arguments
+    /// * `writer` - The destination of the capture, i.e. a file.
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        let mut hdr = Vec::with_capacity(24);
+        hdr.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+        hdr.extend_from_slice(&PCAP_VERSION_MAJOR.to_le_bytes());
+        hdr.extend_from_slice(&PCAP_VERSION_MINOR.to_le_bytes());
+        // The timezone offset and the timestamp accuracy, which are always 0.
+        hdr.extend_from_slice(&[0; 8]);
+        hdr.extend_from_slice(&PCAP_SNAPLEN.to_le_bytes());
+        hdr.extend_from_slice(&PCAP_LINKTYPE_ETHERNET.to_le_bytes());
+        writer.write_all(&hdr)?;
+        Ok(PcapWriter { writer })
+    }
+
+    /// Consumes the `PcapWriter` and returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    // Writes a packet record.
+    fn write_record(&mut self, frame: &[u8]) -> io::Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        // The frame length is bounded by the largest packet a handler exchanges, and the
+        // captured length by the snapshot length.
+        let len = frame.len().min(PCAP_SNAPLEN as usize);
+        let mut record = Vec::with_capacity(16 + len);
+        record.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&timestamp.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(len as u32).to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&frame[..len]);
+        self.writer.write_all(&record)
+    }
+}
+
+impl<W: Write + Send> PacketCapture for PcapWriter<W> {
+    fn capture(&mut self, _pair: u16, _direction: Direction, frame: &[u8]) {
+        if let Err(e) = self.write_record(frame) {
+            warn!("failed to write pcap record: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::TryInto;
+
+    fn u32_at(buf: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_pcap_writer() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        assert_eq!(writer.writer.len(), 24);
+        assert_eq!(u32_at(&writer.writer, 0), PCAP_MAGIC);
+        assert_eq!(&writer.writer[4..8], &[2, 0, 4, 0]);
+        assert_eq!(u32_at(&writer.writer, 16), PCAP_SNAPLEN);
+        assert_eq!(u32_at(&writer.writer, 20), PCAP_LINKTYPE_ETHERNET);
+
+        writer.capture(0, Direction::Rx, &[0xaa; 60]);
+        writer.capture(1, Direction::Tx, &[0xbb; 14]);
+
+        let buf = writer.into_inner();
+        assert_eq!(buf.len(), 24 + 16 + 60 + 16 + 14);
+        let record = &buf[24..];
+        assert_eq!(u32_at(record, 8), 60);
+        assert_eq!(u32_at(record, 12), 60);
+        assert_eq!(&record[16..76], &[0xaa; 60][..]);
+        let record = &record[76..];
+        assert_eq!(u32_at(record, 8), 14);
+        assert_eq!(u32_at(record, 12), 14);
+        assert_eq!(&record[16..], &[0xbb; 14][..]);
+    }
+
+    #[test]
+    fn test_callback() {
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let sink = frames.clone();
+        let capture: SharedPacketCapture =
+            Arc::new(Mutex::new(move |pair, direction, frame: &[u8]| {
+                sink.lock().unwrap().push((pair, direction, frame.to_vec()))
+            }));
+
+        capture
+            .lock()
+            .unwrap()
+            .capture(2, Direction::Tx, &[1, 2, 3]);
+        assert_eq!(
+            *frames.lock().unwrap(),
+            vec![(2, Direction::Tx, vec![1, 2, 3])]
+        );
+        assert!(format!("{:?}", capture).contains("PacketCapture"));
+    }
+}
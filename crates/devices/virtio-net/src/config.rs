@@ -0,0 +1,299 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Virtio network device configuration space abstraction.
+//!
+//! This module provides the following abstractions:
+//!
+//! - [`ConfigSpace`](struct.ConfigSpace.html) which mirrors the `virtio_net_config` structure
+//!   from the virtio specification, and can be converted to the byte representation expected by
+//!   `VirtioConfig`.
+//! - [`ConfigBuilder`](struct.ConfigBuilder.html) which populates a `ConfigSpace` and keeps
+//!   track of the feature bits that have to be advertised by the device so the driver actually
+//!   looks at the fields that were set.
+
+use std::fmt::{self, Display};
+use std::mem::{offset_of, size_of};
+use std::result;
+
+use vm_memory::ByteValued;
+
+use crate::defs::{
+    VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN, VIRTIO_NET_F_CTRL_VQ,
+    VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ, VIRTIO_NET_F_MTU, VIRTIO_NET_F_STATUS, VIRTIO_NET_S_LINK_UP,
+};
+
+/// The smallest MTU a device can advertise, which is the minimum IPv4 MTU.
+pub const MIN_MTU: u16 = 68;
+
+/// Network configuration space building errors.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The MAC address is a multicast address.
+    InvalidMac([u8; 6]),
+    /// The MTU is smaller than `MIN_MTU`.
+    InvalidMtu(u16),
+    /// The number of queue pairs is out of range.
+    InvalidQueuePairs(u16),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            InvalidMac(mac) => write!(
+                f,
+                "invalid MAC address {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+                mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+            ),
+            InvalidMtu(mtu) => write!(f, "invalid MTU {}", mtu),
+            InvalidQueuePairs(pairs) => write!(f, "invalid number of queue pairs {}", pairs),
+        }
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// The network device configuration space layout, as defined by the virtio specification.
+///
+/// All fields are expected to hold little endian values, which is always the case on the
+/// platforms that are currently supported (`x86_64` and `aarch64`).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C, packed)]
+pub struct ConfigSpace {
+    /// The MAC address of the device (valid when `VIRTIO_NET_F_MAC` is negotiated).
+    pub mac: [u8; 6],
+    /// The link status (valid when `VIRTIO_NET_F_STATUS` is negotiated).
+    pub status: u16,
+    /// The maximum number of receive/transmit queue pairs (valid when `VIRTIO_NET_F_MQ` is
+    /// negotiated).
+    pub max_virtqueue_pairs: u16,
+    /// The maximum MTU the driver should use (valid when `VIRTIO_NET_F_MTU` is negotiated).
+    pub mtu: u16,
+}
+
+// Safe because ConfigSpace only contains plain data, and there's no padding between (or after)
+// the fields since the structure is packed.
+unsafe impl ByteValued for ConfigSpace {}
+
+impl ConfigSpace {
+    /// The size of the network device configuration space.
+    pub const LEN: usize = size_of::<ConfigSpace>();
+    /// The offset of the `mac` field.
+    pub const MAC_OFFSET: usize = offset_of!(ConfigSpace, mac);
+    /// The offset of the `status` field.
+    pub const STATUS_OFFSET: usize = offset_of!(ConfigSpace, status);
+    /// The offset of the `max_virtqueue_pairs` field.
+    pub const MAX_VIRTQUEUE_PAIRS_OFFSET: usize = offset_of!(ConfigSpace, max_virtqueue_pairs);
+    /// The offset of the `mtu` field.
+    pub const MTU_OFFSET: usize = offset_of!(ConfigSpace, mtu);
+
+    /// Returns whether the configuration space reports the link as up.
+    pub fn is_link_up(&self) -> bool {
+        self.status & VIRTIO_NET_S_LINK_UP != 0
+    }
+}
+
+impl From<ConfigSpace> for Vec<u8> {
+    fn from(config: ConfigSpace) -> Self {
+        config.as_slice().to_vec()
+    }
+}
+
+/// Builds the configuration space of a network device.
+///
+/// # Example
+///
+/// ```rust
+/// # use virtio_net::config::ConfigBuilder;
+/// # use virtio_net::defs::{VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_MQ};
+/// let builder = ConfigBuilder::new().with_queue_pairs(4);
+///
+/// // The features which have to be advertised by the device, based on what was configured.
+/// assert_eq!(
+///     builder.features(),
+///     (1 << VIRTIO_NET_F_MQ) | (1 << VIRTIO_NET_F_CTRL_VQ)
+/// );
+///
+/// let config_space: Vec<u8> = builder.build().unwrap().into();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ConfigBuilder {
+    config: ConfigSpace,
+    features: u64,
+}
+
+impl ConfigBuilder {
+    /// Creates a new `ConfigBuilder` for a device with a single queue pair.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the feature bits which have to be offered by the device for the configuration
+    /// fields set so far to be taken into account by the driver.
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    fn set_feature(&mut self, feature_pos: u64) {
+        self.features |= 1 << feature_pos;
+    }
+
+    fn has_feature(&self, feature_pos: u64) -> bool {
+        (self.features & (1u64 << feature_pos)) != 0
+    }
+
+    /// Sets the MAC address of the device. Without it, the driver generates a random address.
+    ///
+    /// # Arguments
+    /// * `mac` - The unicast MAC address of the device.
+    pub fn with_mac(mut self, mac: [u8; 6]) -> Self {
+        self.config.mac = mac;
+        self.set_feature(VIRTIO_NET_F_MAC);
+        self
+    }
+
+    /// Reports the link status to the driver, which can then be changed at runtime. Without
+    /// it, the driver assumes the link is always up.
+    ///
+    /// # Arguments
+    /// * `link_up` - Whether the link is initially up.
+    pub fn with_link_status(mut self, link_up: bool) -> Self {
+        self.config.status = if link_up { VIRTIO_NET_S_LINK_UP } else { 0 };
+        self.set_feature(VIRTIO_NET_F_STATUS);
+        self
+    }
+
+    /// Sets the maximum number of receive/transmit queue pairs. The driver selects how many
+    /// of them it actually uses through the control queue, so `VIRTIO_NET_F_CTRL_VQ` is
+    /// required as well.
+    ///
+    /// # Arguments
+    /// * `pairs` - The number of queue pairs.
+    pub fn with_queue_pairs(mut self, pairs: u16) -> Self {
+        self.config.max_virtqueue_pairs = pairs;
+        self.set_feature(VIRTIO_NET_F_MQ);
+        self.set_feature(VIRTIO_NET_F_CTRL_VQ);
+        self
+    }
+
+    /// Sets the maximum MTU the driver should use, i.e. to enable jumbo frames. Without it, the
+    /// driver assumes the standard Ethernet MTU of 1500 bytes. The MTU should match the one of
+    /// the backend interface, since larger packets are dropped by the host.
+    ///
+    /// # Arguments
+    /// * `mtu` - The maximum MTU, which can't be smaller than `MIN_MTU`.
+    pub fn with_mtu(mut self, mtu: u16) -> Self {
+        self.config.mtu = mtu;
+        self.set_feature(VIRTIO_NET_F_MTU);
+        self
+    }
+
+    /// Validates the configuration and returns the resulting `ConfigSpace`.
+    pub fn build(self) -> Result<ConfigSpace> {
+        let config = self.config;
+
+        // The least significant bit of the first octet marks multicast addresses.
+        if self.has_feature(VIRTIO_NET_F_MAC) && config.mac[0] & 1 != 0 {
+            return Err(Error::InvalidMac(config.mac));
+        }
+
+        let pairs = config.max_virtqueue_pairs;
+        if self.has_feature(VIRTIO_NET_F_MQ)
+            && !(VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN..=VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX).contains(&pairs)
+        {
+            return Err(Error::InvalidQueuePairs(pairs));
+        }
+
+        if self.has_feature(VIRTIO_NET_F_MTU) && config.mtu < MIN_MTU {
+            return Err(Error::InvalidMtu(config.mtu));
+        }
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_space() {
+        assert_eq!(ConfigSpace::LEN, 12);
+        assert_eq!(ConfigSpace::MAX_VIRTQUEUE_PAIRS_OFFSET, 8);
+        assert_eq!(ConfigSpace::MTU_OFFSET, 10);
+
+        let config = ConfigSpace {
+            max_virtqueue_pairs: 0x0102,
+            ..Default::default()
+        };
+        let bytes: Vec<u8> = config.into();
+        assert_eq!(bytes.len(), ConfigSpace::LEN);
+        assert_eq!(bytes[8..10], [0x02, 0x01]);
+    }
+
+    #[test]
+    fn test_mac_status() {
+        let mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        let builder = ConfigBuilder::new().with_mac(mac).with_link_status(true);
+        assert_eq!(
+            builder.features(),
+            (1 << VIRTIO_NET_F_MAC) | (1 << VIRTIO_NET_F_STATUS)
+        );
+        let config = builder.build().unwrap();
+        assert_eq!(config.mac, mac);
+        assert!(config.is_link_up());
+
+        let bytes: Vec<u8> = config.into();
+        assert_eq!(bytes[ConfigSpace::MAC_OFFSET..][..6], mac);
+        assert_eq!(bytes[ConfigSpace::STATUS_OFFSET..][..2], [1, 0]);
+
+        let config = ConfigBuilder::new()
+            .with_link_status(false)
+            .build()
+            .unwrap();
+        assert!(!config.is_link_up());
+
+        let mac = [0x01, 0x00, 0x5e, 0x00, 0x00, 0x01];
+        assert_eq!(
+            ConfigBuilder::new().with_mac(mac).build(),
+            Err(Error::InvalidMac(mac))
+        );
+    }
+
+    #[test]
+    fn test_queue_pairs() {
+        let builder = ConfigBuilder::new();
+        assert_eq!(builder.features(), 0);
+        assert_eq!(builder.build().unwrap(), ConfigSpace::default());
+
+        let config = ConfigBuilder::new().with_queue_pairs(4).build().unwrap();
+        assert_eq!({ config.max_virtqueue_pairs }, 4);
+
+        assert_eq!(
+            ConfigBuilder::new().with_queue_pairs(0).build(),
+            Err(Error::InvalidQueuePairs(0))
+        );
+        assert_eq!(
+            ConfigBuilder::new().with_queue_pairs(0x8001).build(),
+            Err(Error::InvalidQueuePairs(0x8001))
+        );
+    }
+
+    #[test]
+    fn test_mtu() {
+        let builder = ConfigBuilder::new().with_mtu(9000);
+        assert_eq!(builder.features(), 1 << VIRTIO_NET_F_MTU);
+        let bytes: Vec<u8> = builder.build().unwrap().into();
+        assert_eq!(bytes[ConfigSpace::MTU_OFFSET..][..2], 9000u16.to_le_bytes());
+
+        assert!(ConfigBuilder::new().with_mtu(MIN_MTU).build().is_ok());
+        assert_eq!(
+            ConfigBuilder::new().with_mtu(MIN_MTU - 1).build(),
+            Err(Error::InvalidMtu(MIN_MTU - 1))
+        );
+    }
+}
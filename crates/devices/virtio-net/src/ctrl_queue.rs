@@ -0,0 +1,258 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Control queue request abstraction.
+//!
+//! The driver uses the control queue to send commands to the network device (i.e. to select
+//! the number of queue pairs it uses). Each command is made of a class, a command code and
+//! command specific data, which are all device-readable, followed by a device-writable ack
+//! byte. This module provides the [`CtrlRequest`](struct.CtrlRequest.html) abstraction, which
+//! parses such a descriptor chain.
+
+use std::fmt::{self, Display};
+use std::mem::size_of;
+use std::result;
+
+use vm_memory::{
+    ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryError,
+};
+
+use virtio_queue::DescriptorChain;
+
+// The maximum length of the command specific data (i.e. large enough for the MAC filtering
+// tables).
+const MAX_DATA_LEN: usize = 4096;
+
+/// Control request parsing errors.
+#[derive(Debug)]
+pub enum Error {
+    /// The descriptor chain is too short to hold the header and the ack byte.
+    DescriptorChainTooShort,
+    /// Invalid memory access.
+    GuestMemory(GuestMemoryError),
+    /// The command data is larger than what the device accepts.
+    RequestTooLarge,
+    /// Read only descriptor which follows a write only one.
+    UnexpectedReadOnlyDescriptor,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            DescriptorChainTooShort => write!(f, "descriptor chain too short for the request"),
+            GuestMemory(ref err) => write!(f, "error accessing guest memory: {}", err),
+            RequestTooLarge => write!(f, "request data too large"),
+            UnexpectedReadOnlyDescriptor => write!(f, "unexpected read only descriptor"),
+        }
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// The header of the control queue requests.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct CtrlHeader {
+    /// The command class (`VIRTIO_NET_CTRL_*`).
+    pub class: u8,
+    /// The command code within the class.
+    pub cmd: u8,
+}
+
+// Safe because CtrlHeader contains only plain data.
+unsafe impl ByteValued for CtrlHeader {}
+
+/// A request sent by the driver through the control queue.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CtrlRequest {
+    header: CtrlHeader,
+    data: Vec<u8>,
+    ack_addr: GuestAddress,
+}
+
+impl CtrlRequest {
+    /// Parses a request from a control queue descriptor chain.
+    ///
+    /// # Arguments
+    /// * `chain` - The control queue descriptor chain.
+    pub fn parse<M: GuestAddressSpace>(chain: &mut DescriptorChain<M>) -> Result<Self> {
+        let mut bytes = Vec::new();
+        let mut ack_addr = None;
+
+        let descriptors: Vec<_> = chain.by_ref().collect();
+        for desc in descriptors {
+            if !desc.is_write_only() {
+                if ack_addr.is_some() {
+                    return Err(Error::UnexpectedReadOnlyDescriptor);
+                }
+                let start = bytes.len();
+                let end = start + desc.len() as usize;
+                if end > size_of::<CtrlHeader>() + MAX_DATA_LEN {
+                    return Err(Error::RequestTooLarge);
+                }
+                bytes.resize(end, 0);
+                chain
+                    .memory()
+                    .read_slice(&mut bytes[start..end], desc.addr())
+                    .map_err(Error::GuestMemory)?;
+            } else if ack_addr.is_none() && desc.len() > 0 {
+                ack_addr = Some(desc.addr());
+            }
+        }
+
+        let ack_addr = ack_addr.ok_or(Error::DescriptorChainTooShort)?;
+        if bytes.len() < size_of::<CtrlHeader>() {
+            return Err(Error::DescriptorChainTooShort);
+        }
+        let mut header = CtrlHeader::default();
+        header
+            .as_mut_slice()
+            .copy_from_slice(&bytes[..size_of::<CtrlHeader>()]);
+
+        Ok(CtrlRequest {
+            header,
+            data: bytes.split_off(size_of::<CtrlHeader>()),
+            ack_addr,
+        })
+    }
+
+    /// Returns the command class.
+    pub fn class(&self) -> u8 {
+        self.header.class
+    }
+
+    /// Returns the command code.
+    pub fn cmd(&self) -> u8 {
+        self.header.cmd
+    }
+
+    /// Returns the command specific data.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns the command specific data as an object of type `T`, or `None` if the data
+    /// doesn't have the right size.
+    pub fn data_obj<T: ByteValued>(&self) -> Option<T> {
+        if self.data.len() != size_of::<T>() {
+            return None;
+        }
+        let mut obj = T::default();
+        obj.as_mut_slice().copy_from_slice(&self.data);
+        Some(obj)
+    }
+
+    /// Writes the ack byte of the request (`VIRTIO_NET_OK` or `VIRTIO_NET_ERR`) to guest memory.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    /// * `ack` - The result of the command.
+    pub fn complete<M: GuestMemory>(&self, mem: &M, ack: u8) -> Result<()> {
+        mem.write_obj(ack, self.ack_addr)
+            .map_err(Error::GuestMemory)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::GuestMemoryMmap;
+
+    use virtio_device::mock::adjacent_regions;
+    use virtio_queue::mock::{AttackPattern, VirtQueue};
+    use virtio_queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    use crate::defs::{VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET, VIRTIO_NET_OK};
+
+    #[test]
+    fn test_parse() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let mut q = vq.create_queue(&mem);
+
+        mem.write_obj(
+            CtrlHeader {
+                class: VIRTIO_NET_CTRL_MQ,
+                cmd: VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET,
+            },
+            GuestAddress(0x1000),
+        )
+        .unwrap();
+        mem.write_obj(4u16, GuestAddress(0x2000)).unwrap();
+        mem.write_obj(0xffu8, GuestAddress(0x3000)).unwrap();
+
+        vq.dtable(0).set(0x1000, 2, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1).set(0x2000, 2, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable(2).set(0x3000, 1, VIRTQ_DESC_F_WRITE, 0);
+        // A chain without the ack byte.
+        vq.dtable(3).set(0x1000, 2, 0, 0);
+        // A chain with a read only descriptor after the ack byte.
+        vq.dtable(4)
+            .set(0x3000, 1, VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT, 5);
+        vq.dtable(5).set(0x1000, 2, 0, 0);
+        // A chain which is too large.
+        vq.dtable(6).set(0x1000, 0x2000, VIRTQ_DESC_F_NEXT, 7);
+        vq.dtable(7).set(0x3000, 1, VIRTQ_DESC_F_WRITE, 0);
+        for (i, &head) in [0, 3, 4, 6].iter().enumerate() {
+            vq.avail.ring(i as u16).store(head);
+        }
+        vq.avail.idx().store(4);
+
+        let mut chain = q.iter().unwrap().next().unwrap();
+        let request = CtrlRequest::parse(&mut chain).unwrap();
+        assert_eq!(request.class(), VIRTIO_NET_CTRL_MQ);
+        assert_eq!(request.cmd(), VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET);
+        assert_eq!(request.data(), &[4, 0]);
+        assert_eq!(request.data_obj::<u16>(), Some(4));
+        assert_eq!(request.data_obj::<u32>(), None);
+        request.complete(&mem, VIRTIO_NET_OK).unwrap();
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x3000)).unwrap(),
+            VIRTIO_NET_OK
+        );
+
+        let mut chain = q.iter().unwrap().next().unwrap();
+        assert!(matches!(
+            CtrlRequest::parse(&mut chain),
+            Err(Error::DescriptorChainTooShort)
+        ));
+        let mut chain = q.iter().unwrap().next().unwrap();
+        assert!(matches!(
+            CtrlRequest::parse(&mut chain),
+            Err(Error::UnexpectedReadOnlyDescriptor)
+        ));
+        let mut chain = q.iter().unwrap().next().unwrap();
+        assert!(matches!(
+            CtrlRequest::parse(&mut chain),
+            Err(Error::RequestTooLarge)
+        ));
+    }
+
+    #[test]
+    fn test_parse_attack_patterns() {
+        let mem = adjacent_regions(0x10_0000);
+        for &pattern in AttackPattern::ALL.iter() {
+            let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+            vq.add_attack(pattern, 0, GuestAddress(0x1_0000));
+            let mut chain = vq.create_queue(&mem).iter().unwrap().next().unwrap();
+            // The chains which are cut short, or whose buffers are still in guest memory, are
+            // parsed (the device writes the ack to the used ring at worst).
+            assert_eq!(
+                CtrlRequest::parse(&mut chain).is_ok(),
+                [
+                    AttackPattern::NextOutOfRange,
+                    AttackPattern::RegionBoundary,
+                    AttackPattern::RingAliasing
+                ]
+                .contains(&pattern),
+                "{:?}",
+                pattern
+            );
+        }
+    }
+}
@@ -0,0 +1,94 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+// TODO: Move the generic device type definition to the vm-virtio crate proper.
+/// The virtio device type of network devices.
+pub const VIRTIO_ID_NET: u32 = 1;
+
+/// The default (and maximum) size of the receive and transmit queues.
+pub const DEFAULT_QUEUE_SIZE: u16 = 256;
+
+// Feature bits.
+/// Device handles packets with partial checksum.
+pub const VIRTIO_NET_F_CSUM: u64 = 0;
+/// Driver handles packets with partial checksum.
+pub const VIRTIO_NET_F_GUEST_CSUM: u64 = 1;
+/// Control channel offloads reconfiguration support.
+pub const VIRTIO_NET_F_CTRL_GUEST_OFFLOADS: u64 = 2;
+/// Maximum MTU is in `mtu`.
+pub const VIRTIO_NET_F_MTU: u64 = 3;
+/// Device has the MAC address in `mac`.
+pub const VIRTIO_NET_F_MAC: u64 = 5;
+/// Driver can receive TSOv4.
+pub const VIRTIO_NET_F_GUEST_TSO4: u64 = 7;
+/// Driver can receive TSOv6.
+pub const VIRTIO_NET_F_GUEST_TSO6: u64 = 8;
+/// Driver can receive TSO with ECN.
+pub const VIRTIO_NET_F_GUEST_ECN: u64 = 9;
+/// Driver can receive UFO.
+pub const VIRTIO_NET_F_GUEST_UFO: u64 = 10;
+/// Device can receive TSOv4.
+pub const VIRTIO_NET_F_HOST_TSO4: u64 = 11;
+/// Device can receive TSOv6.
+pub const VIRTIO_NET_F_HOST_TSO6: u64 = 12;
+/// Device can receive TSO with ECN.
+pub const VIRTIO_NET_F_HOST_ECN: u64 = 13;
+/// Device can receive UFO.
+pub const VIRTIO_NET_F_HOST_UFO: u64 = 14;
+/// Driver can merge receive buffers.
+pub const VIRTIO_NET_F_MRG_RXBUF: u64 = 15;
+/// Link status is in `status`.
+pub const VIRTIO_NET_F_STATUS: u64 = 16;
+/// Control channel is available.
+pub const VIRTIO_NET_F_CTRL_VQ: u64 = 17;
+/// Control channel RX mode support.
+pub const VIRTIO_NET_F_CTRL_RX: u64 = 18;
+/// Control channel VLAN filtering.
+pub const VIRTIO_NET_F_CTRL_VLAN: u64 = 19;
+/// Driver can send gratuitous packets.
+pub const VIRTIO_NET_F_GUEST_ANNOUNCE: u64 = 21;
+/// Device supports multiple queue pairs.
+pub const VIRTIO_NET_F_MQ: u64 = 22;
+/// Set MAC address through the control channel.
+pub const VIRTIO_NET_F_CTRL_MAC_ADDR: u64 = 23;
+
+// Link status bits.
+/// The link is up.
+pub const VIRTIO_NET_S_LINK_UP: u16 = 1;
+/// The driver has to send gratuitous packets to announce its location.
+pub const VIRTIO_NET_S_ANNOUNCE: u16 = 2;
+
+// Header flags.
+/// The packet needs a checksum, starting at `csum_start` and stored at `csum_offset`.
+pub const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+/// The packet checksum was validated.
+pub const VIRTIO_NET_HDR_F_DATA_VALID: u8 = 2;
+/// The header contains receive segment coalescing information.
+pub const VIRTIO_NET_HDR_F_RSC_INFO: u8 = 4;
+
+// Header GSO types.
+/// Not a GSO frame.
+pub const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+/// GSO frame, IPv4 TCP (TSO).
+pub const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+/// GSO frame, IPv4 UDP (UFO).
+pub const VIRTIO_NET_HDR_GSO_UDP: u8 = 3;
+/// GSO frame, IPv6 TCP.
+pub const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+/// TCP has the ECN bit set (combined with one of the other GSO types).
+pub const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+// Control queue command classes and commands.
+/// Ack value written by the device when a control command succeeded.
+pub const VIRTIO_NET_OK: u8 = 0;
+/// Ack value written by the device when a control command failed.
+pub const VIRTIO_NET_ERR: u8 = 1;
+/// Multiqueue control command class.
+pub const VIRTIO_NET_CTRL_MQ: u8 = 4;
+/// Sets the number of queue pairs used by the driver.
+pub const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+/// The minimum number of queue pairs.
+pub const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN: u16 = 1;
+/// The maximum number of queue pairs.
+pub const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX: u16 = 0x8000;
@@ -0,0 +1,1456 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A reference virtio network device implementation.
+//!
+//! This module provides the following abstractions:
+//!
+//! - [`Net`](struct.Net.html) which ties together the generic device building blocks
+//!   (`VirtioConfig` and the `VirtioDevice`/`VirtioMmioDevice` interfaces) with the network
+//!   specific ones (the configuration space, the control queue and the queue handlers). It uses
+//!   one [`QueuePairHandler`](../queue_handler/struct.QueuePairHandler.html) for each
+//!   receive/transmit queue pair, each of them backed by its own TAP device queue (see
+//!   [`Tap::open_queues`](../tap/struct.Tap.html#method.open_queues)) or by an AF_XDP socket
+//!   (see [`XdpSocket::open`](../xdp/struct.XdpSocket.html#method.open)).
+//! - [`NetBuilder`](struct.NetBuilder.html) which configures and creates a `Net` device.
+//!
+//! The queues are laid out as defined by the virtio specification: the receive queue of pair
+//! `n` has the index `2 * n`, its transmit queue has the index `2 * n + 1`, and the control
+//! queue (which is only present for multiqueue devices) comes after all the pairs. The driver
+//! starts by using a single pair, and enables the other ones through the control queue.
+//!
+//! The device always reports the link status to the driver, and the link can be brought down
+//! (and back up) at runtime with [`Net::set_link_up`](struct.Net.html#method.set_link_up), i.e.
+//! to simulate unplugging the network cable.
+//!
+//! The device doesn't register any events by itself. The pairs are independent of each other,
+//! so the VMM can process each of them from a separate worker: it is expected to call
+//! [`Net::process_rx`](struct.Net.html#method.process_rx) when the TAP device queue of a pair
+//! becomes readable, and to rely on the `VirtioMmioDevice::queue_notify` implementation (or call
+//! the `process_*` methods directly) when the driver notifies a queue. The workers can be
+//! placed on host CPUs with [`NetBuilder::with_pair_affinity`](struct.NetBuilder.html#method.with_pair_affinity).
+//!
+//! Alternatively, the queue pairs can be offloaded to the in-kernel vhost-net driver (see
+//! [`NetBuilder::with_vhost`](struct.NetBuilder.html#method.with_vhost)), in which case the
+//! VMM only processes the control queue. The kick `EventFd` of each queue (see
+//! [`Net::vhost`](struct.Net.html#method.vhost)) is expected to be registered as an
+//! ioeventfd, and [`Net::process_call_event`](struct.Net.html#method.process_call_event) has
+//! to be called when one of the call `EventFd`s becomes readable.
+//!
+//! Before taking a snapshot of the VM, the device can be quiesced with
+//! `VirtioDevicePause::pause`, which stops processing the queues (and detaches the TAP device
+//! queues from the vhost-net backends) until `VirtioDevicePause::resume` is called.
+
+use std::convert::{TryFrom, TryInto};
+use std::fmt::{self, Display};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::result;
+use std::sync::atomic::AtomicU8;
+use std::sync::Arc;
+
+use log::{error, warn};
+
+use vm_memory::GuestAddressSpace;
+
+use virtio_device::affinity::QueueAffinity;
+use virtio_device::rate_limiter::{RateLimiter, TokenBucket};
+use virtio_device::{
+    set_interrupt_status, EventsContext, SignalConfigChange, SignalUsedQueue, VirtioConfig,
+    VirtioDeviceActions, VirtioDeviceCommon, VirtioDeviceEvents, VirtioDevicePause,
+    VirtioMmioDevice, VIRTIO_MMIO_INT_VRING,
+};
+use virtio_queue::{self, Queue};
+
+use crate::capture::SharedPacketCapture;
+use crate::config::{self, ConfigBuilder, ConfigSpace};
+use crate::ctrl_queue::CtrlRequest;
+use crate::defs::{
+    VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET,
+    VIRTIO_NET_ERR, VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ,
+    VIRTIO_NET_F_MRG_RXBUF, VIRTIO_NET_OK, VIRTIO_NET_S_LINK_UP,
+};
+use crate::offload::{configure_tap, TapCapabilities};
+use crate::queue_handler::{self, QueuePairHandler};
+use crate::vhost::{self, VhostNet, VHOST_NET_F_VIRTIO_NET_HDR};
+
+pub use crate::defs::{DEFAULT_QUEUE_SIZE, VIRTIO_ID_NET};
+
+// TODO: Move the generic feature definitions to the vm-virtio crate proper.
+const VIRTIO_F_VERSION_1: u64 = 32;
+const VIRTIO_F_RING_EVENT_IDX: u64 = 29;
+
+/// Network device errors.
+#[derive(Debug)]
+pub enum Error {
+    /// The device was already activated.
+    AlreadyActivated,
+    /// Failed to build the configuration space.
+    Config(config::Error),
+    /// Failed to process the control queue.
+    CtrlQueue(virtio_queue::Error),
+    /// The queues are not configured properly.
+    InvalidQueues,
+    /// The queue index is not valid, or the device is not activated.
+    InvalidQueueIndex(u16),
+    /// The queue pair index is not valid, or the pair is not enabled.
+    InvalidQueuePair(u16),
+    /// Failed to process a queue pair.
+    QueueHandler(queue_handler::Error),
+    /// Failed to create a rate limiter.
+    RateLimiter(io::Error),
+    /// Failed to configure a TAP device queue.
+    Tap(io::Error),
+    /// Failed to use a vhost-net backend.
+    Vhost(vhost::Error),
+    /// The number of vhost-net backends doesn't match the number of TAP device queues.
+    VhostBackendCount(usize),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            AlreadyActivated => write!(f, "the device is already activated"),
+            Config(ref err) => write!(f, "invalid configuration space: {}", err),
+            CtrlQueue(ref err) => write!(f, "failed to process the control queue: {}", err),
+            InvalidQueues => write!(f, "invalid queue configuration"),
+            InvalidQueueIndex(index) => write!(f, "invalid or inactive queue {}", index),
+            InvalidQueuePair(index) => write!(f, "invalid or disabled queue pair {}", index),
+            QueueHandler(ref err) => write!(f, "failed to process the queue pair: {}", err),
+            RateLimiter(ref err) => write!(f, "failed to create a rate limiter: {}", err),
+            Tap(ref err) => write!(f, "failed to configure the TAP device: {}", err),
+            Vhost(ref err) => write!(f, "vhost-net backend error: {}", err),
+            VhostBackendCount(count) => write!(f, "invalid number of vhost-net backends {}", count),
+        }
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+// The operations and bandwidth buckets of a rate limiter.
+type RateLimit = (Option<TokenBucket>, Option<TokenBucket>);
+
+// Creates a rate limiter with the specified buckets, unless both of them are missing.
+fn rate_limiter(rate_limit: &RateLimit) -> Result<Option<RateLimiter>> {
+    match rate_limit {
+        (None, None) => Ok(None),
+        (ops, bandwidth) => RateLimiter::new(ops.clone(), bandwidth.clone())
+            .map(Some)
+            .map_err(Error::RateLimiter),
+    }
+}
+
+// Signals the driver on behalf of a queue handler, after updating the interrupt status.
+#[derive(Debug)]
+struct QueueSignal<S: SignalUsedQueue> {
+    interrupt_status: Arc<AtomicU8>,
+    driver_notify: Arc<S>,
+}
+
+impl<S: SignalUsedQueue> SignalUsedQueue for QueueSignal<S> {
+    fn signal_used_queue(&self, index: u16) {
+        set_interrupt_status(&self.interrupt_status, VIRTIO_MMIO_INT_VRING);
+        self.driver_notify.signal_used_queue(index);
+    }
+}
+
+/// Configures and builds a `Net` device.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # use std::sync::Arc;
+/// # use virtio_net::device::NetBuilder;
+/// # use virtio_net::offload::TapCapabilities;
+/// # use virtio_net::tap::Tap;
+/// # use vm_memory::{GuestAddress, GuestMemoryMmap};
+/// # use vmm_sys_util::eventfd::EventFd;
+/// let mem = Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+/// // One TAP device queue for each receive/transmit queue pair.
+/// let taps = Tap::open_queues("tap0", 4).unwrap();
+/// // The driver should use the same MTU as the TAP interface.
+/// let mtu = taps[0].mtu().unwrap();
+///
+/// let net = NetBuilder::new(mem, taps, EventFd::new(0).unwrap())
+///     .with_mac([0x52, 0x54, 0x00, 0x12, 0x34, 0x56])
+///     .with_mtu(mtu)
+///     .with_tap_capabilities(TapCapabilities {
+///         vnet_hdr: true,
+///         csum: true,
+///         ..Default::default()
+///     })
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug)]
+pub struct NetBuilder<M: GuestAddressSpace, T: Read + Write + AsRawFd, S: SignalUsedQueue> {
+    mem: M,
+    taps: Vec<T>,
+    driver_notify: S,
+    queue_size: u16,
+    capabilities: TapCapabilities,
+    mac: Option<[u8; 6]>,
+    mtu: Option<u16>,
+    vhost: Vec<VhostNet>,
+    rx_rate_limit: RateLimit,
+    tx_rate_limit: RateLimit,
+    pair_affinity: QueueAffinity,
+}
+
+impl<M, T, S> NetBuilder<M, T, S>
+where
+    M: GuestAddressSpace + Clone,
+    T: Read + Write + AsRawFd,
+    S: SignalUsedQueue,
+{
+    /// Creates a new `NetBuilder` with one receive/transmit queue pair for each TAP device queue.
+    /// When there's more than one pair, the device offers `VIRTIO_NET_F_MQ` and the control
+    /// queue.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    /// * `taps` - The TAP device queues, in non-blocking mode.
+    /// * `driver_notify` - The object used for notifying the driver about used buffers.
+    pub fn new(mem: M, taps: Vec<T>, driver_notify: S) -> Self {
+        NetBuilder {
+            mem,
+            taps,
+            driver_notify,
+            queue_size: DEFAULT_QUEUE_SIZE,
+            capabilities: TapCapabilities::default(),
+            mac: None,
+            mtu: None,
+            vhost: Vec::new(),
+            rx_rate_limit: (None, None),
+            tx_rate_limit: (None, None),
+            pair_affinity: QueueAffinity::new(),
+        }
+    }
+
+    /// Sets the maximum size of the receive and transmit queues.
+    ///
+    /// # Arguments
+    /// * `queue_size` - The maximum queue size.
+    pub fn with_queue_size(mut self, queue_size: u16) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Sets the capabilities of the TAP device queues, which determine the offloads offered to
+    /// the driver, and whether packets are exchanged along with their network header.
+    ///
+    /// # Arguments
+    /// * `capabilities` - The offloads supported by the TAP device.
+    pub fn with_tap_capabilities(mut self, capabilities: TapCapabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Sets the MAC address of the device. By default, the driver generates a random address.
+    ///
+    /// # Arguments
+    /// * `mac` - The unicast MAC address of the device.
+    pub fn with_mac(mut self, mac: [u8; 6]) -> Self {
+        self.mac = Some(mac);
+        self
+    }
+
+    /// Advertises the MTU of the backend interface to the driver (through `VIRTIO_NET_F_MTU`),
+    /// so it can use jumbo frames. By default, the driver assumes an MTU of 1500 bytes. The MTU
+    /// of a TAP interface is returned by [`Tap::mtu`](../tap/struct.Tap.html#method.mtu).
+    ///
+    /// # Arguments
+    /// * `mtu` - The MTU of the backend interface.
+    pub fn with_mtu(mut self, mtu: u16) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    /// Offloads the queue pairs to the in-kernel vhost-net driver. The features offered to the
+    /// driver are limited to the ones supported by the kernel.
+    ///
+    /// # Arguments
+    /// * `vhost` - One vhost-net backend for each TAP device queue.
+    pub fn with_vhost(mut self, vhost: Vec<VhostNet>) -> Self {
+        self.vhost = vhost;
+        self
+    }
+
+    /// Limits the rate of the packets received by the driver. Each queue pair gets its own rate
+    /// limiter with the specified buckets. The limits don't apply when the queue pairs are
+    /// offloaded to vhost-net.
+    ///
+    /// # Arguments
+    /// * `ops` - The bucket for the number of packets, or `None` for no limit.
+    /// * `bandwidth` - The bucket for the number of bytes, or `None` for no limit.
+    pub fn with_rx_rate_limit(
+        mut self,
+        ops: Option<TokenBucket>,
+        bandwidth: Option<TokenBucket>,
+    ) -> Self {
+        self.rx_rate_limit = (ops, bandwidth);
+        self
+    }
+
+    /// Limits the rate of the packets sent by the driver. Each queue pair gets its own rate
+    /// limiter with the specified buckets. The limits don't apply when the queue pairs are
+    /// offloaded to vhost-net.
+    ///
+    /// # Arguments
+    /// * `ops` - The bucket for the number of packets, or `None` for no limit.
+    /// * `bandwidth` - The bucket for the number of bytes, or `None` for no limit.
+    pub fn with_tx_rate_limit(
+        mut self,
+        ops: Option<TokenBucket>,
+        bandwidth: Option<TokenBucket>,
+    ) -> Self {
+        self.tx_rate_limit = (ops, bandwidth);
+        self
+    }
+
+    /// Places the workers of the queue pairs on host CPUs (see `Net::pair_affinity`).
+    ///
+    /// # Arguments
+    /// * `pair_affinity` - The host CPU of each queue pair, indexed by pair.
+    pub fn with_pair_affinity(mut self, pair_affinity: QueueAffinity) -> Self {
+        self.pair_affinity = pair_affinity;
+        self
+    }
+
+    /// Builds the `Net` device.
+    pub fn build(self) -> Result<Net<M, T, S>> {
+        let pairs = u16::try_from(self.taps.len())
+            .map_err(|_| Error::Config(config::Error::InvalidQueuePairs(u16::MAX)))?;
+        if self.pair_affinity.num_queues() > pairs {
+            return Err(Error::InvalidQueuePair(self.pair_affinity.num_queues() - 1));
+        }
+
+        // The link is initially up.
+        let mut config = ConfigBuilder::new().with_link_status(true);
+        if let Some(mac) = self.mac {
+            config = config.with_mac(mac);
+        }
+        if let Some(mtu) = self.mtu {
+            config = config.with_mtu(mtu);
+        }
+        if pairs != 1 {
+            config = config.with_queue_pairs(pairs);
+        }
+
+        let mut device_features = config.features()
+            | self.capabilities.features()
+            | (1 << VIRTIO_NET_F_MRG_RXBUF)
+            | (1 << VIRTIO_F_VERSION_1)
+            | (1 << VIRTIO_F_RING_EVENT_IDX);
+        let mut vhost_features = 0;
+        if let Some(vhost) = self.vhost.first() {
+            if self.vhost.len() != self.taps.len() {
+                return Err(Error::VhostBackendCount(self.vhost.len()));
+            }
+            vhost_features = vhost.features().map_err(Error::Vhost)?;
+            // The configuration space features and the offloads are still handled by the
+            // device and the TAP device respectively.
+            device_features &= vhost_features | config.features() | self.capabilities.features();
+        }
+
+        let config_space: Vec<u8> = config.build().map_err(Error::Config)?.into();
+        let mut num_queues = 2 * pairs;
+        if device_features & (1 << VIRTIO_NET_F_CTRL_VQ) != 0 {
+            num_queues += 1;
+        }
+        let queues = (0..num_queues)
+            .map(|_| Queue::new(self.mem.clone(), self.queue_size))
+            .collect();
+
+        Ok(Net {
+            cfg: VirtioConfig::new(device_features, queues, config_space),
+            mem: self.mem,
+            max_pairs: pairs,
+            taps: self.taps,
+            capabilities: self.capabilities,
+            driver_notify: Arc::new(self.driver_notify),
+            handlers: Vec::new(),
+            ctrl_queue: None,
+            active_pairs: 0,
+            vhost: self.vhost,
+            vhost_features,
+            rx_rate_limit: self.rx_rate_limit,
+            tx_rate_limit: self.tx_rate_limit,
+            capture: None,
+            paused: false,
+            pair_affinity: self.pair_affinity,
+        })
+    }
+}
+
+/// A virtio network device.
+#[derive(Debug, VirtioDeviceCommon)]
+#[virtio(device_type = VIRTIO_ID_NET)]
+pub struct Net<M: GuestAddressSpace, T: Read + Write + AsRawFd, S: SignalUsedQueue> {
+    #[virtio(config)]
+    cfg: VirtioConfig<M>,
+    mem: M,
+    max_pairs: u16,
+    // The TAP device queues which are not used by a handler.
+    taps: Vec<T>,
+    capabilities: TapCapabilities,
+    driver_notify: Arc<S>,
+    // One handler for each queue pair set up by the driver, which are available while the device
+    // is activated.
+    handlers: Vec<QueuePairHandler<M, T, QueueSignal<S>>>,
+    ctrl_queue: Option<Queue<M>>,
+    // The number of queue pairs enabled by the driver.
+    active_pairs: u16,
+    // The vhost-net backends of the queue pairs, if they are offloaded to the kernel.
+    vhost: Vec<VhostNet>,
+    // The features supported by the vhost-net backends.
+    vhost_features: u64,
+    rx_rate_limit: RateLimit,
+    tx_rate_limit: RateLimit,
+    // The packet capture passed to the handlers, if any.
+    capture: Option<SharedPacketCapture>,
+    // Whether queue processing is stopped by `pause`.
+    paused: bool,
+    // The host CPUs the queue pairs are processed on.
+    pair_affinity: QueueAffinity,
+}
+
+impl<M, T, S> Net<M, T, S>
+where
+    M: GuestAddressSpace,
+    T: Read + Write + AsRawFd,
+    S: SignalUsedQueue,
+{
+    /// Returns whether the device is activated.
+    pub fn is_activated(&self) -> bool {
+        self.cfg.device_activated
+    }
+
+    /// Returns the MAC address reported to the driver, if any.
+    pub fn mac(&self) -> Option<[u8; 6]> {
+        if self.cfg.device_features & (1 << VIRTIO_NET_F_MAC) == 0 {
+            return None;
+        }
+        let offset = ConfigSpace::MAC_OFFSET;
+        self.cfg.config_space[offset..offset + 6].try_into().ok()
+    }
+
+    /// Returns whether the link is up.
+    pub fn is_link_up(&self) -> bool {
+        self.status() & VIRTIO_NET_S_LINK_UP != 0
+    }
+
+    // Returns the `status` field of the configuration space.
+    fn status(&self) -> u16 {
+        let offset = ConfigSpace::STATUS_OFFSET;
+        self.cfg.config_space[offset..offset + 2]
+            .try_into()
+            .map_or(0, u16::from_le_bytes)
+    }
+
+    /// Returns the maximum number of receive/transmit queue pairs.
+    pub fn max_queue_pairs(&self) -> u16 {
+        self.max_pairs
+    }
+
+    /// Returns the number of queue pairs currently enabled by the driver.
+    pub fn active_queue_pairs(&self) -> u16 {
+        self.active_pairs
+    }
+
+    /// Returns the host CPUs the queue pairs have to be processed on, indexed by pair. When the
+    /// VMM processes each pair from a separate worker thread, the worker of pair `n` is expected
+    /// to call `pair_affinity().pin(n)` before processing it.
+    pub fn pair_affinity(&self) -> &QueueAffinity {
+        &self.pair_affinity
+    }
+
+    /// Returns the TAP device queue which backs the queue pair with the specified index (i.e.
+    /// for registering its file descriptor with an event loop).
+    ///
+    /// # Arguments
+    /// * `pair` - The index of the queue pair.
+    pub fn tap(&self, pair: u16) -> Option<&T> {
+        let pair = usize::from(pair);
+        match self.handlers.get(pair) {
+            Some(handler) => Some(handler.tap()),
+            None => self.taps.get(pair - self.handlers.len()),
+        }
+    }
+
+    /// Places the packets available on the TAP device queue of a pair in its receive queue.
+    /// This has to be called when the TAP device queue becomes readable, and when the driver
+    /// notifies the receive queue.
+    ///
+    /// # Arguments
+    /// * `pair` - The index of the queue pair.
+    pub fn process_rx(&mut self, pair: u16) -> Result<()> {
+        let paused = self.paused;
+        let handler = self.handler_mut(pair)?;
+        // The packets are placed in the receive queue on `resume`.
+        if paused {
+            return Ok(());
+        }
+        handler.process_rx().map_err(Error::QueueHandler)
+    }
+
+    /// Sends the packets from the transmit queue of a pair to its TAP device queue. This has
+    /// to be called when the driver notifies the transmit queue.
+    ///
+    /// # Arguments
+    /// * `pair` - The index of the queue pair.
+    pub fn process_tx(&mut self, pair: u16) -> Result<()> {
+        let paused = self.paused;
+        let handler = self.handler_mut(pair)?;
+        if paused {
+            return Ok(());
+        }
+        handler.process_tx().map_err(Error::QueueHandler)
+    }
+
+    /// Returns the vhost-net backend of the queue pair with the specified index, if the pairs
+    /// are offloaded to the kernel (i.e. for registering its kick `EventFd`s as ioeventfds).
+    ///
+    /// # Arguments
+    /// * `pair` - The index of the queue pair.
+    pub fn vhost(&self, pair: u16) -> Option<&VhostNet> {
+        self.vhost.get(usize::from(pair))
+    }
+
+    /// Updates the interrupt status and notifies the driver after a vhost-net backend signaled
+    /// used buffers. This has to be called when the call `EventFd` of the queue becomes
+    /// readable.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the receive or transmit queue.
+    pub fn process_call_event(&self, index: u16) -> Result<()> {
+        let call_evt = self
+            .vhost(index / 2)
+            .and_then(|vhost| vhost.call_eventfd(usize::from(index % 2)))
+            .ok_or(Error::InvalidQueueIndex(index))?;
+        match call_evt.read() {
+            Ok(_) => {}
+            // Spurious wakeup, the event was already consumed.
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(Error::Vhost(vhost::Error::EventFd(e))),
+        }
+        self.cfg.signal_used_queue();
+        self.driver_notify.signal_used_queue(index);
+        Ok(())
+    }
+
+    // Notifies the vhost-net backend about the buffers made available in a queue, for VMMs
+    // which don't register the kick `EventFd`s as ioeventfds.
+    fn kick_vhost(&self, index: u16) -> Result<()> {
+        let kick_evt = self
+            .vhost(index / 2)
+            .and_then(|vhost| vhost.kick_eventfd(usize::from(index % 2)))
+            .ok_or(Error::InvalidQueueIndex(index))?;
+        // The backends are kicked on `resume`.
+        if self.paused {
+            return Ok(());
+        }
+        kick_evt
+            .write(1)
+            .map_err(|e| Error::Vhost(vhost::Error::EventFd(e)))
+    }
+
+    // Attaches the TAP device queues to the vhost-net backends of the first `pairs` queue
+    // pairs, and detaches them from the other ones which were active.
+    fn set_vhost_pairs(&mut self, pairs: u16) -> vhost::Result<()> {
+        let (start, end) = if pairs < self.active_pairs {
+            (pairs, self.active_pairs)
+        } else {
+            (self.active_pairs, pairs)
+        };
+        for pair in usize::from(start)..usize::from(end) {
+            let tap = (pair < usize::from(pairs)).then(|| &self.taps[pair]);
+            self.vhost[pair].set_backend(tap)?;
+        }
+        self.active_pairs = pairs;
+        Ok(())
+    }
+
+    // Hands the first `pairs` queue pairs over to their vhost-net backends.
+    fn start_vhost(&self, pairs: u16) -> vhost::Result<()> {
+        let mut features = self.cfg.driver_features & self.vhost_features;
+        if !self.capabilities.vnet_hdr {
+            // The TAP device queues don't use network headers, so the kernel has to handle them.
+            features |= 1 << VHOST_NET_F_VIRTIO_NET_HDR;
+        }
+        let mem = self.mem.memory();
+        for (vhost, queues) in self.vhost[..usize::from(pairs)]
+            .iter()
+            .zip(self.cfg.queues.chunks(2))
+        {
+            vhost.setup(features, &queues[0], &queues[1], &*mem)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the receive rate limiter of the queue pair with the specified index, if any (i.e.
+    /// for registering its file descriptor with an event loop).
+    ///
+    /// # Arguments
+    /// * `pair` - The index of the queue pair.
+    pub fn rx_rate_limiter(&self, pair: u16) -> Option<&RateLimiter> {
+        self.handlers.get(usize::from(pair))?.rx_rate_limiter()
+    }
+
+    /// Returns the transmit rate limiter of the queue pair with the specified index, if any.
+    ///
+    /// # Arguments
+    /// * `pair` - The index of the queue pair.
+    pub fn tx_rate_limiter(&self, pair: u16) -> Option<&RateLimiter> {
+        self.handlers.get(usize::from(pair))?.tx_rate_limiter()
+    }
+
+    /// Resumes placing the received packets of a pair after its receive rate limiter timer
+    /// expired. This has to be called when the rate limiter file descriptor becomes readable.
+    ///
+    /// # Arguments
+    /// * `pair` - The index of the queue pair.
+    pub fn process_rx_rate_limiter_event(&mut self, pair: u16) -> Result<()> {
+        let paused = self.paused;
+        let handler = self.handler_mut(pair)?;
+        // The timer event is consumed, and the queue is processed on `resume`.
+        if paused {
+            return match handler.rx_rate_limiter_mut() {
+                Some(rate_limiter) => rate_limiter.event_handler().map_err(Error::RateLimiter),
+                None => Ok(()),
+            };
+        }
+        handler
+            .process_rx_rate_limiter_event()
+            .map_err(Error::QueueHandler)
+    }
+
+    /// Resumes sending the transmitted packets of a pair after its transmit rate limiter timer
+    /// expired. This has to be called when the rate limiter file descriptor becomes readable.
+    ///
+    /// # Arguments
+    /// * `pair` - The index of the queue pair.
+    pub fn process_tx_rate_limiter_event(&mut self, pair: u16) -> Result<()> {
+        let paused = self.paused;
+        let handler = self.handler_mut(pair)?;
+        if paused {
+            return match handler.tx_rate_limiter_mut() {
+                Some(rate_limiter) => rate_limiter.event_handler().map_err(Error::RateLimiter),
+                None => Ok(()),
+            };
+        }
+        handler
+            .process_tx_rate_limiter_event()
+            .map_err(Error::QueueHandler)
+    }
+
+    /// Copies the frames exchanged by all the queue pairs to `capture`, or stops copying them if
+    /// `capture` is `None`. The capture applies to the running pairs, and is preserved across
+    /// device resets. Frames exchanged by vhost-net backends can't be captured.
+    ///
+    /// # Arguments
+    /// * `capture` - The packet capture, i.e. a `PcapWriter` or a callback.
+    pub fn set_packet_capture(&mut self, capture: Option<SharedPacketCapture>) {
+        for handler in self.handlers.iter_mut() {
+            handler.set_packet_capture(capture.clone());
+        }
+        self.capture = capture;
+    }
+
+    fn handler_mut(&mut self, pair: u16) -> Result<&mut QueuePairHandler<M, T, QueueSignal<S>>> {
+        if pair >= self.active_pairs {
+            return Err(Error::InvalidQueuePair(pair));
+        }
+        self.handlers
+            .get_mut(usize::from(pair))
+            .ok_or(Error::InvalidQueuePair(pair))
+    }
+
+    // Returns the index of the control queue.
+    fn ctrl_queue_index(&self) -> u16 {
+        2 * self.max_pairs
+    }
+
+    /// Processes the commands available in the control queue. This has to be called when the
+    /// driver notifies the device about the control queue.
+    pub fn process_ctrl_queue(&mut self) -> Result<()> {
+        let index = self.ctrl_queue_index();
+        let mut queue = self
+            .ctrl_queue
+            .take()
+            .ok_or(Error::InvalidQueueIndex(index))?;
+        // The commands made available while the device is paused are processed on `resume`.
+        if self.paused {
+            self.ctrl_queue = Some(queue);
+            return Ok(());
+        }
+        let result = self.process_ctrl_requests(&mut queue);
+        self.ctrl_queue = Some(queue);
+        result.map_err(Error::CtrlQueue)
+    }
+
+    fn process_ctrl_requests(
+        &mut self,
+        queue: &mut Queue<M>,
+    ) -> result::Result<(), virtio_queue::Error> {
+        while let Some(mut chain) = queue.iter()?.next() {
+            let len = match CtrlRequest::parse(&mut chain) {
+                Ok(request) => {
+                    let ack = self.handle_ctrl_request(&request);
+                    match request.complete(chain.memory(), ack) {
+                        Ok(()) => 1,
+                        Err(e) => {
+                            warn!("failed to complete control request: {}", e);
+                            0
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!("failed to parse control request: {}", e);
+                    0
+                }
+            };
+            queue.add_used(chain.head_index(), len)?;
+            if queue.needs_notification()? {
+                self.cfg.signal_used_queue();
+                self.driver_notify
+                    .signal_used_queue(self.ctrl_queue_index());
+            }
+        }
+        Ok(())
+    }
+
+    // Executes a control command, and returns its ack value.
+    fn handle_ctrl_request(&mut self, request: &CtrlRequest) -> u8 {
+        match (request.class(), request.cmd()) {
+            (VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET) => {
+                match request.data_obj::<u16>() {
+                    Some(pairs) if self.set_active_pairs(pairs) => VIRTIO_NET_OK,
+                    _ => VIRTIO_NET_ERR,
+                }
+            }
+            (class, cmd) => {
+                warn!("unsupported control command {}:{}", class, cmd);
+                VIRTIO_NET_ERR
+            }
+        }
+    }
+
+    // Enables the first `pairs` queue pairs, and returns whether the value is valid.
+    fn set_active_pairs(&mut self, pairs: u16) -> bool {
+        // All the pairs are set up when `VIRTIO_NET_F_MQ` is negotiated.
+        if self.cfg.driver_features & (1 << VIRTIO_NET_F_MQ) == 0
+            || pairs < VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN
+            || pairs > self.max_pairs
+        {
+            return false;
+        }
+
+        if !self.vhost.is_empty() {
+            return match self.set_vhost_pairs(pairs) {
+                Ok(()) => true,
+                Err(e) => {
+                    error!("failed to enable {} queue pairs: {}", pairs, e);
+                    false
+                }
+            };
+        }
+
+        let previous = self.active_pairs;
+        self.active_pairs = pairs;
+        // Pick up the packets which arrived on the newly enabled pairs in the meantime.
+        for pair in previous..pairs {
+            if let Err(e) = self.process_rx(pair) {
+                error!("failed to process queue pair {}: {}", pair, e);
+            }
+        }
+        true
+    }
+
+    // Returns whether the queues used by the driver are valid. The driver sets up either the
+    // first queue pair, or all of them when `VIRTIO_NET_F_MQ` is negotiated, in which case
+    // the number of pairs with handlers is also returned.
+    fn used_pairs(&self) -> Option<u16> {
+        let has_feature = |feature: u64| self.cfg.driver_features & (1 << feature) != 0;
+        let pairs = if has_feature(VIRTIO_NET_F_MQ) {
+            self.max_pairs
+        } else {
+            1
+        };
+
+        let ctrl_queue = if has_feature(VIRTIO_NET_F_CTRL_VQ) {
+            self.cfg.queues.get(usize::from(self.ctrl_queue_index()))
+        } else {
+            None
+        };
+        self.cfg.queues[..2 * usize::from(pairs)]
+            .iter()
+            .chain(ctrl_queue)
+            .all(Queue::is_valid)
+            .then_some(pairs)
+    }
+}
+
+impl<M, T, S> Net<M, T, S>
+where
+    M: GuestAddressSpace,
+    T: Read + Write + AsRawFd,
+    S: SignalUsedQueue + SignalConfigChange,
+{
+    /// Brings the link up or down while the device is running, i.e. to simulate plugging or
+    /// unplugging the network cable.
+    ///
+    /// The `status` field of the configuration space and the configuration generation are
+    /// updated, and a configuration change interrupt is raised when the device is activated,
+    /// such that the driver can pick up the new link status. The link status is preserved
+    /// across device resets. Packets keep flowing through the queues regardless of the link
+    /// status, it's up to the driver to stop using the link while it's down.
+    ///
+    /// # Arguments
+    /// * `link_up` - Whether the link is up.
+    pub fn set_link_up(&mut self, link_up: bool) {
+        if self.is_link_up() == link_up {
+            return;
+        }
+
+        let status = if link_up {
+            self.status() | VIRTIO_NET_S_LINK_UP
+        } else {
+            self.status() & !VIRTIO_NET_S_LINK_UP
+        };
+        let offset = ConfigSpace::STATUS_OFFSET;
+        self.cfg.config_space[offset..offset + 2].copy_from_slice(&status.to_le_bytes());
+        self.cfg.config_generation = self.cfg.config_generation.wrapping_add(1);
+
+        if self.cfg.device_activated {
+            self.cfg.signal_config_change();
+            self.driver_notify.signal_config_change();
+        }
+    }
+}
+
+impl<M, T, S> VirtioDeviceActions for Net<M, T, S>
+where
+    M: GuestAddressSpace + Clone,
+    T: Read + Write + AsRawFd,
+    S: SignalUsedQueue,
+{
+    type E = Error;
+
+    fn activate(&mut self) -> Result<()> {
+        if self.cfg.device_activated {
+            return Err(Error::AlreadyActivated);
+        }
+        let pairs = self.used_pairs().ok_or(Error::InvalidQueues)?;
+
+        if self.capabilities.vnet_hdr {
+            for tap in self.taps[..usize::from(pairs)].iter() {
+                configure_tap(tap, self.cfg.driver_features).map_err(Error::Tap)?;
+            }
+        }
+
+        if self.vhost.is_empty() {
+            let cfg = &self.cfg;
+            let driver_notify = &self.driver_notify;
+            let vnet_hdr = self.capabilities.vnet_hdr;
+            let mrg_rxbuf = cfg.driver_features & (1 << VIRTIO_NET_F_MRG_RXBUF) != 0;
+            let (rx_rate_limit, tx_rate_limit) = (&self.rx_rate_limit, &self.tx_rate_limit);
+            let capture = &self.capture;
+            self.handlers = self
+                .taps
+                .drain(..usize::from(pairs))
+                .zip(cfg.queues.chunks(2))
+                .enumerate()
+                .map(|(i, (tap, queues))| {
+                    let signal = QueueSignal {
+                        interrupt_status: cfg.interrupt_status.clone(),
+                        driver_notify: driver_notify.clone(),
+                    };
+                    // The number of pairs always fits in an `u16`.
+                    let mut handler =
+                        QueuePairHandler::new(queues[0].clone(), queues[1].clone(), tap, signal)
+                            .with_pair_index(i as u16)
+                            .with_vnet_hdr(vnet_hdr)
+                            .with_mergeable_rx_buffers(mrg_rxbuf);
+                    handler.set_packet_capture(capture.clone());
+                    if let Some(rate_limiter) = rate_limiter(rx_rate_limit)? {
+                        handler = handler.with_rx_rate_limiter(rate_limiter);
+                    }
+                    if let Some(rate_limiter) = rate_limiter(tx_rate_limit)? {
+                        handler = handler.with_tx_rate_limiter(rate_limiter);
+                    }
+                    Ok(handler)
+                })
+                .collect::<Result<_>>()?;
+        } else {
+            self.start_vhost(pairs).map_err(Error::Vhost)?;
+        }
+
+        let cfg = &self.cfg;
+        if cfg.driver_features & (1 << VIRTIO_NET_F_CTRL_VQ) != 0 {
+            self.ctrl_queue = Some(cfg.queues[usize::from(self.ctrl_queue_index())].clone());
+        }
+
+        // Only the first pair is enabled until the driver asks for more. The vhost-net backend
+        // of a paused device is attached on `resume`.
+        if self.vhost.is_empty() || self.paused {
+            self.active_pairs = 1;
+        } else {
+            self.set_vhost_pairs(1).map_err(Error::Vhost)?;
+        }
+        self.cfg.device_activated = true;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        // The vhost-net backends stop processing the queues once the TAP device queues are
+        // detached.
+        let vhost_result = if self.vhost.is_empty() {
+            Ok(())
+        } else {
+            self.set_vhost_pairs(0)
+        };
+
+        // The TAP device queues are returned by the handlers, in the same order.
+        let mut taps: Vec<T> = self
+            .handlers
+            .drain(..)
+            .map(QueuePairHandler::into_tap)
+            .collect();
+        taps.append(&mut self.taps);
+        self.taps = taps;
+        self.ctrl_queue = None;
+        self.active_pairs = 0;
+
+        let cfg = &mut self.cfg;
+        cfg.driver_features = 0;
+        cfg.device_features_select = 0;
+        cfg.driver_features_select = 0;
+        cfg.device_status = 0;
+        cfg.queue_select = 0;
+        cfg.queues.iter_mut().for_each(Queue::reset);
+        cfg.device_activated = false;
+        cfg.reset_interrupt_status();
+        vhost_result.map_err(Error::Vhost)
+    }
+}
+
+impl<M, T, S> VirtioDevicePause for Net<M, T, S>
+where
+    M: GuestAddressSpace,
+    T: Read + Write + AsRawFd,
+    S: SignalUsedQueue,
+{
+    type E = Error;
+
+    // The TAP device queues don't buffer data on behalf of the device, so there's nothing to
+    // flush.
+    fn pause(&mut self, _flush: bool) -> Result<()> {
+        if self.paused {
+            return Ok(());
+        }
+        // The handlers process the queues synchronously, so nothing is in flight once they
+        // return. The vhost-net backends stop processing the queues (and complete the pending
+        // requests) once the TAP device queues are detached.
+        if !self.vhost.is_empty() {
+            for vhost in self.vhost[..usize::from(self.active_pairs)].iter() {
+                vhost.set_backend::<T>(None).map_err(Error::Vhost)?;
+            }
+        }
+        self.paused = true;
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        if !self.paused {
+            return Ok(());
+        }
+        self.paused = false;
+        if !self.vhost.is_empty() {
+            for (pair, vhost) in self.vhost[..usize::from(self.active_pairs)]
+                .iter()
+                .enumerate()
+            {
+                vhost
+                    .set_backend(Some(&self.taps[pair]))
+                    .map_err(Error::Vhost)?;
+            }
+            // The backends pick up the buffers made available in the meantime.
+            for index in 0..2 * self.active_pairs {
+                self.kick_vhost(index)?;
+            }
+        } else {
+            for pair in 0..self.active_pairs {
+                self.process_rx(pair)?;
+                self.process_tx(pair)?;
+            }
+        }
+        if self.ctrl_queue.is_some() {
+            self.process_ctrl_queue()?;
+        }
+        Ok(())
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused
+    }
+}
+
+impl<M, T, S> VirtioDeviceEvents for Net<M, T, S>
+where
+    M: GuestAddressSpace,
+    T: Read + Write + AsRawFd,
+    S: SignalUsedQueue,
+{
+    fn reattach<C: EventsContext>(&self, events_ctx: &mut C) -> result::Result<(), C::E> {
+        // Only the kick `EventFd`s of the vhost-net backends are registered as ioeventfds, the
+        // call `EventFd`s are read by the VMM (see `process_call_event`).
+        for (pair, vhost) in self.vhost.iter().enumerate() {
+            for queue in 0..2 {
+                if let Some(kick_evt) = vhost.kick_eventfd(queue) {
+                    // The number of queues always fits in an `u16`.
+                    events_ctx.register_ioeventfd((pair * 2 + queue) as u16, kick_evt)?;
+                }
+            }
+        }
+        match self.driver_notify.irqfd() {
+            Some(irqfd) => events_ctx.register_irqfd(irqfd),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<M, T, S> VirtioMmioDevice<M> for Net<M, T, S>
+where
+    M: GuestAddressSpace + Clone + 'static,
+    T: Read + Write + AsRawFd,
+    S: SignalUsedQueue,
+{
+    fn queue_notify(&mut self, val: u32) {
+        // Queue indices always fit in an `u16`.
+        let index = val as u16;
+        let result = if index == self.ctrl_queue_index() {
+            self.process_ctrl_queue()
+        } else if !self.vhost.is_empty() {
+            self.kick_vhost(index)
+        } else if index % 2 == 0 {
+            // The driver provided more receive buffers.
+            self.process_rx(index / 2)
+        } else {
+            self.process_tx(index / 2)
+        };
+        if let Err(e) = result {
+            error!("failed to process queue {}: {}", val, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::io::RawFd;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+    use vmm_sys_util::eventfd::EventFd;
+
+    use virtio_device::mock::activate;
+    use virtio_device::{VirtioDevice, VIRTIO_MMIO_INT_CONFIG};
+    use virtio_queue::mock::VirtQueue;
+    use virtio_queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    use crate::ctrl_queue::CtrlHeader;
+    use crate::defs::{VIRTIO_NET_F_MTU, VIRTIO_NET_F_STATUS};
+    use crate::header::VirtioNetHdrMrgRxbuf;
+    use crate::queue_handler::tests::TestTap;
+
+    type Mem = Arc<GuestMemoryMmap>;
+
+    impl AsRawFd for TestTap {
+        fn as_raw_fd(&self) -> RawFd {
+            -1
+        }
+    }
+
+    fn net(mem: &Mem, pairs: usize) -> Net<Mem, TestTap, EventFd> {
+        let taps = (0..pairs).map(|_| TestTap::default()).collect();
+        NetBuilder::new(mem.clone(), taps, EventFd::new(0).unwrap())
+            .with_queue_size(16)
+            .build()
+            .unwrap()
+    }
+
+    fn virt_queues(mem: &GuestMemoryMmap, num_queues: u64) -> Vec<VirtQueue<'_>> {
+        (0..num_queues)
+            .map(|i| VirtQueue::new(GuestAddress(i * 0x1000), mem, 16))
+            .collect()
+    }
+
+    #[test]
+    fn test_build() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+
+        let single = net(&mem, 1);
+        assert_eq!(VirtioDevice::device_type(&single), VIRTIO_ID_NET);
+        assert_eq!(single.num_queues(), 2);
+        assert_eq!(single.max_queue_pairs(), 1);
+        assert_eq!(single.device_features() & (1 << VIRTIO_NET_F_MQ), 0);
+        assert_ne!(single.device_features() & (1 << VIRTIO_F_VERSION_1), 0);
+        assert_ne!(single.device_features() & (1 << VIRTIO_NET_F_MRG_RXBUF), 0);
+        assert!(single.tap(0).is_some());
+        assert!(single.tap(1).is_none());
+        assert!(single.vhost(0).is_none());
+        assert!(matches!(
+            single.process_call_event(0),
+            Err(Error::InvalidQueueIndex(0))
+        ));
+        assert_ne!(single.device_features() & (1 << VIRTIO_NET_F_STATUS), 0);
+        assert!(single.is_link_up());
+        assert_eq!(single.mac(), None);
+        assert_eq!(single.device_features() & (1 << VIRTIO_NET_F_MAC), 0);
+
+        let mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        let with_mac = NetBuilder::new(
+            mem.clone(),
+            vec![TestTap::default()],
+            EventFd::new(0).unwrap(),
+        )
+        .with_mac(mac)
+        .build()
+        .unwrap();
+        assert_eq!(with_mac.mac(), Some(mac));
+        let mut config_mac = [0u8; 6];
+        with_mac.read_config(ConfigSpace::MAC_OFFSET, &mut config_mac);
+        assert_eq!(config_mac, mac);
+
+        assert_eq!(single.device_features() & (1 << VIRTIO_NET_F_MTU), 0);
+        let with_mtu = NetBuilder::new(
+            mem.clone(),
+            vec![TestTap::default()],
+            EventFd::new(0).unwrap(),
+        )
+        .with_mtu(9000)
+        .build()
+        .unwrap();
+        assert_ne!(with_mtu.device_features() & (1 << VIRTIO_NET_F_MTU), 0);
+        let mut mtu = [0u8; 2];
+        with_mtu.read_config(ConfigSpace::MTU_OFFSET, &mut mtu);
+        assert_eq!(u16::from_le_bytes(mtu), 9000);
+        assert!(matches!(
+            NetBuilder::new(
+                mem.clone(),
+                vec![TestTap::default()],
+                EventFd::new(0).unwrap()
+            )
+            .with_mtu(0)
+            .build(),
+            Err(Error::Config(config::Error::InvalidMtu(0)))
+        ));
+
+        let multi = net(&mem, 4);
+        assert_eq!(multi.num_queues(), 9);
+        assert_eq!(multi.max_queue_pairs(), 4);
+        let features = multi.device_features();
+        assert_ne!(features & (1 << VIRTIO_NET_F_MQ), 0);
+        assert_ne!(features & (1 << VIRTIO_NET_F_CTRL_VQ), 0);
+        let mut pairs = [0u8; 2];
+        multi.read_config(ConfigSpace::MAX_VIRTQUEUE_PAIRS_OFFSET, &mut pairs);
+        assert_eq!(u16::from_le_bytes(pairs), 4);
+
+        // The workers of the pairs which exist can be placed on host CPUs.
+        assert_eq!(multi.pair_affinity(), &QueueAffinity::new());
+        let taps = (0..2).map(|_| TestTap::default()).collect::<Vec<_>>();
+        let pinned = NetBuilder::new(mem.clone(), taps, EventFd::new(0).unwrap())
+            .with_pair_affinity(QueueAffinity::new().with_cpu(1, 0))
+            .build()
+            .unwrap();
+        assert_eq!(pinned.pair_affinity().cpu(1), Some(0));
+        assert!(matches!(
+            NetBuilder::new(
+                mem.clone(),
+                vec![TestTap::default()],
+                EventFd::new(0).unwrap()
+            )
+            .with_pair_affinity(QueueAffinity::new().with_cpu(1, 0))
+            .build(),
+            Err(Error::InvalidQueuePair(1))
+        ));
+
+        assert!(matches!(
+            NetBuilder::<Mem, TestTap, _>::new(mem.clone(), Vec::new(), EventFd::new(0).unwrap())
+                .build(),
+            Err(Error::Config(config::Error::InvalidQueuePairs(0)))
+        ));
+    }
+
+    #[test]
+    fn test_activate_reset() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let mut net = net(&mem, 1);
+
+        // The queues are not configured yet.
+        assert!(matches!(
+            VirtioDeviceActions::activate(&mut net),
+            Err(Error::InvalidQueues)
+        ));
+        assert!(matches!(net.process_tx(0), Err(Error::InvalidQueuePair(0))));
+
+        let vqs = virt_queues(&mem, 2);
+        activate(&mut net, &vqs, 0);
+        assert!(net.is_activated());
+        assert_eq!(net.active_queue_pairs(), 1);
+        assert!(matches!(
+            VirtioDeviceActions::activate(&mut net),
+            Err(Error::AlreadyActivated)
+        ));
+
+        // Send a packet.
+        let txq = &vqs[1];
+        mem.write_obj(VirtioNetHdrMrgRxbuf::default(), GuestAddress(0x1_0000))
+            .unwrap();
+        txq.dtable(0)
+            .set(0x1_0000, VirtioNetHdrMrgRxbuf::LEN as u32 + 0x40, 0, 0);
+        txq.avail.ring(0).store(0);
+        txq.avail.idx().store(1);
+        net.queue_notify(1);
+        assert_eq!(txq.used.idx().load(), 1);
+        // The TAP device doesn't use network headers.
+        assert_eq!(net.tap(0).unwrap().tx, vec![vec![0; 0x40]]);
+        assert_eq!(
+            net.interrupt_status().load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_VRING
+        );
+
+        // The driver resets the device, and the TAP device queue is preserved.
+        net.ack_device_status(0);
+        assert!(!net.is_activated());
+        assert_eq!(net.active_queue_pairs(), 0);
+        assert_eq!(net.tap(0).unwrap().tx.len(), 1);
+        assert!(matches!(net.process_tx(0), Err(Error::InvalidQueuePair(0))));
+
+        activate(&mut net, &vqs, 0);
+        assert!(net.is_activated());
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let mut net = NetBuilder::new(
+            mem.clone(),
+            vec![TestTap::default()],
+            EventFd::new(0).unwrap(),
+        )
+        .with_queue_size(16)
+        .with_tx_rate_limit(None, TokenBucket::new(0x1000, Duration::from_secs(1)))
+        .build()
+        .unwrap();
+        assert!(matches!(
+            net.process_tx_rate_limiter_event(0),
+            Err(Error::InvalidQueuePair(0))
+        ));
+
+        let vqs = virt_queues(&mem, 2);
+        activate(&mut net, &vqs, 0);
+        // Only the transmit direction is limited.
+        assert!(net.rx_rate_limiter(0).is_none());
+        assert!(net.tx_rate_limiter(0).is_some());
+        assert!(net.tx_rate_limiter(1).is_none());
+
+        // The rate limiters are created again when the device is activated after a reset.
+        VirtioDeviceActions::reset(&mut net).unwrap();
+        assert!(net.tx_rate_limiter(0).is_none());
+        activate(&mut net, &vqs, 0);
+        assert!(net.tx_rate_limiter(0).is_some());
+    }
+
+    #[test]
+    fn test_link_status() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let mut net = net(&mem, 1);
+
+        // There's no interrupt before activation.
+        net.set_link_up(false);
+        assert!(!net.is_link_up());
+        assert_eq!(net.config_generation(), 1);
+        assert_eq!(net.interrupt_status().load(Ordering::SeqCst), 0);
+
+        let vqs = virt_queues(&mem, 2);
+        activate(&mut net, &vqs, 0);
+        // Nothing changes if the link is already down.
+        net.set_link_up(false);
+        assert_eq!(net.config_generation(), 1);
+
+        net.set_link_up(true);
+        assert!(net.is_link_up());
+        assert_eq!(net.config_generation(), 2);
+        assert_eq!(
+            net.interrupt_status().load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_CONFIG
+        );
+        assert_eq!(net.driver_notify.read().unwrap(), 1);
+        let mut status = [0u8; 2];
+        net.read_config(ConfigSpace::STATUS_OFFSET, &mut status);
+        assert_eq!(u16::from_le_bytes(status), VIRTIO_NET_S_LINK_UP);
+
+        // The link status is preserved across resets.
+        net.set_link_up(false);
+        net.ack_device_status(0);
+        assert!(!net.is_link_up());
+    }
+
+    // Records the `EventFd`s registered by a device.
+    #[derive(Default)]
+    struct TestEventsCtx {
+        ioeventfds: Vec<(u16, RawFd)>,
+        irqfds: Vec<RawFd>,
+    }
+
+    impl EventsContext for TestEventsCtx {
+        type E = ();
+
+        fn register_ioeventfd(&mut self, queue: u16, fd: &EventFd) -> result::Result<(), ()> {
+            self.ioeventfds.push((queue, fd.as_raw_fd()));
+            Ok(())
+        }
+
+        fn register_irqfd(&mut self, fd: &EventFd) -> result::Result<(), ()> {
+            self.irqfds.push(fd.as_raw_fd());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_reattach() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let net = net(&mem, 1);
+        let mut events_ctx = TestEventsCtx::default();
+        net.reattach(&mut events_ctx).unwrap();
+        // The queues are processed by the device, so there are no kick `EventFd`s.
+        assert!(events_ctx.ioeventfds.is_empty());
+        assert_eq!(events_ctx.irqfds, [net.driver_notify.as_raw_fd()]);
+    }
+
+    #[test]
+    fn test_pause() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let mut net = net(&mem, 1);
+        let vqs = virt_queues(&mem, 2);
+        activate(&mut net, &vqs, 0);
+
+        net.pause(true).unwrap();
+        assert!(net.is_paused());
+
+        // Neither direction is processed while the device is paused.
+        net.handlers[0].tap_mut().rx.push_back(vec![0xdd; 0x20]);
+        let rxq = &vqs[0];
+        rxq.dtable(0).set(0x1_0000, 0x100, VIRTQ_DESC_F_WRITE, 0);
+        rxq.avail.ring(0).store(0);
+        rxq.avail.idx().store(1);
+        net.process_rx(0).unwrap();
+        net.queue_notify(0);
+        assert_eq!(rxq.used.idx().load(), 0);
+
+        let txq = &vqs[1];
+        mem.write_obj(VirtioNetHdrMrgRxbuf::default(), GuestAddress(0x2_0000))
+            .unwrap();
+        txq.dtable(0)
+            .set(0x2_0000, VirtioNetHdrMrgRxbuf::LEN as u32 + 0x40, 0, 0);
+        txq.avail.ring(0).store(0);
+        txq.avail.idx().store(1);
+        net.queue_notify(1);
+        assert_eq!(txq.used.idx().load(), 0);
+        assert!(net.tap(0).unwrap().tx.is_empty());
+        // Invalid pairs are still reported.
+        assert!(matches!(net.process_tx(1), Err(Error::InvalidQueuePair(1))));
+
+        net.resume().unwrap();
+        assert!(!net.is_paused());
+        assert_eq!(rxq.used.idx().load(), 1);
+        assert_eq!(txq.used.idx().load(), 1);
+        assert_eq!(net.tap(0).unwrap().tx, vec![vec![0; 0x40]]);
+    }
+
+    #[test]
+    fn test_multiqueue() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+
+        // Without `VIRTIO_NET_F_MQ`, only the first pair is used.
+        let mut single = net(&mem, 2);
+        let vqs = virt_queues(&mem, 2);
+        activate(
+            &mut single,
+            &vqs,
+            (1 << VIRTIO_NET_F_MQ) | (1 << VIRTIO_NET_F_CTRL_VQ),
+        );
+        assert!(single.is_activated());
+        assert_eq!(single.handlers.len(), 1);
+        assert!(single.tap(1).is_some());
+        assert!(matches!(
+            single.process_ctrl_queue(),
+            Err(Error::InvalidQueueIndex(4))
+        ));
+
+        let mut net = net(&mem, 2);
+        let vqs = virt_queues(&mem, 5);
+        activate(&mut net, &vqs, 0);
+        assert_eq!(net.handlers.len(), 2);
+        assert_eq!(net.active_queue_pairs(), 1);
+        assert!(matches!(net.process_rx(1), Err(Error::InvalidQueuePair(1))));
+
+        // A packet arrives on the second pair before it's enabled.
+        net.handlers[1].tap_mut().rx.push_back(vec![0xdd; 0x20]);
+        let rxq = &vqs[2];
+        rxq.dtable(0).set(0x1_0000, 0x100, VIRTQ_DESC_F_WRITE, 0);
+        rxq.avail.ring(0).store(0);
+        rxq.avail.idx().store(1);
+
+        // The driver enables both pairs, then asks for an invalid number of pairs.
+        let ctrlq = &vqs[4];
+        let header = CtrlHeader {
+            class: VIRTIO_NET_CTRL_MQ,
+            cmd: VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET,
+        };
+        mem.write_obj(header, GuestAddress(0x2_0000)).unwrap();
+        mem.write_obj(2u16, GuestAddress(0x2_0002)).unwrap();
+        mem.write_obj(3u16, GuestAddress(0x2_0004)).unwrap();
+        ctrlq.dtable(0).set(0x2_0000, 4, VIRTQ_DESC_F_NEXT, 1);
+        ctrlq.dtable(1).set(0x3_0000, 1, VIRTQ_DESC_F_WRITE, 0);
+        ctrlq.dtable(2).set(0x2_0000, 2, VIRTQ_DESC_F_NEXT, 3);
+        ctrlq.dtable(3).set(0x2_0004, 2, VIRTQ_DESC_F_NEXT, 4);
+        ctrlq.dtable(4).set(0x3_0001, 1, VIRTQ_DESC_F_WRITE, 0);
+        ctrlq.avail.ring(0).store(0);
+        ctrlq.avail.ring(1).store(2);
+        ctrlq.avail.idx().store(2);
+        net.queue_notify(4);
+
+        assert_eq!(ctrlq.used.idx().load(), 2);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x3_0000)).unwrap(),
+            VIRTIO_NET_OK
+        );
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x3_0001)).unwrap(),
+            VIRTIO_NET_ERR
+        );
+        assert_eq!(net.active_queue_pairs(), 2);
+        // The pending packet was received right away.
+        assert_eq!(rxq.used.idx().load(), 1);
+        let mut data = [0u8; 0x20];
+        mem.read_slice(&mut data, GuestAddress(0x1_000c)).unwrap();
+        assert_eq!(data, [0xdd; 0x20]);
+        net.process_tx(1).unwrap();
+    }
+}
@@ -0,0 +1,472 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Virtio network header abstractions.
+//!
+//! Every packet that goes through the receive or transmit queues of a network device is
+//! preceded by a header, which describes the checksum and segmentation offloads that apply to
+//! the packet. This module provides the following abstractions:
+//!
+//! - [`VirtioNetHdr`](struct.VirtioNetHdr.html) which is the `virtio_net_hdr` structure from the
+//!   virtio specification, along with the validation of the fields written by the driver.
+//! - [`VirtioNetHdrMrgRxbuf`](struct.VirtioNetHdrMrgRxbuf.html) which extends `VirtioNetHdr`
+//!   with the number of merged receive buffers. This is the header layout used by all the
+//!   devices which negotiate `VIRTIO_F_VERSION_1` or `VIRTIO_NET_F_MRG_RXBUF`.
+//! - [`NetHeader`](trait.NetHeader.html) which reads the header from the head of a transmit
+//!   descriptor chain, or writes it to the head of a receive descriptor chain. The header can
+//!   span multiple descriptors, and it can share a descriptor with the packet data.
+
+use std::cmp::min;
+use std::fmt::{self, Display};
+use std::mem::size_of;
+use std::result;
+
+use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryError};
+
+use virtio_queue::DescriptorChain;
+
+use crate::defs::{
+    VIRTIO_NET_HDR_F_DATA_VALID, VIRTIO_NET_HDR_F_NEEDS_CSUM, VIRTIO_NET_HDR_F_RSC_INFO,
+    VIRTIO_NET_HDR_GSO_ECN, VIRTIO_NET_HDR_GSO_NONE, VIRTIO_NET_HDR_GSO_TCPV4,
+    VIRTIO_NET_HDR_GSO_TCPV6, VIRTIO_NET_HDR_GSO_UDP,
+};
+
+/// Network header errors.
+#[derive(Debug)]
+pub enum Error {
+    /// The descriptor chain is too short to hold the header.
+    DescriptorChainTooShort,
+    /// Invalid memory access.
+    GuestMemory(GuestMemoryError),
+    /// The header has unknown flags set.
+    InvalidFlags(u8),
+    /// The segment size of a GSO packet is 0.
+    InvalidGsoSize,
+    /// The GSO type is unknown.
+    InvalidGsoType(u8),
+    /// The header length of a GSO packet is 0.
+    InvalidHeaderLength,
+    /// Read only descriptor in a receive chain.
+    UnexpectedReadOnlyDescriptor,
+    /// Write only descriptor in a transmit chain.
+    UnexpectedWriteOnlyDescriptor,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            DescriptorChainTooShort => write!(f, "descriptor chain too short for the header"),
+            GuestMemory(ref err) => write!(f, "error accessing guest memory: {}", err),
+            InvalidFlags(flags) => write!(f, "invalid header flags 0x{:x}", flags),
+            InvalidGsoSize => write!(f, "invalid GSO segment size"),
+            InvalidGsoType(gso_type) => write!(f, "invalid GSO type 0x{:x}", gso_type),
+            InvalidHeaderLength => write!(f, "invalid header length of GSO packet"),
+            UnexpectedReadOnlyDescriptor => write!(f, "unexpected read only descriptor"),
+            UnexpectedWriteOnlyDescriptor => write!(f, "unexpected write only descriptor"),
+        }
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// The header of the packets exchanged through the network device queues (the
+/// `virtio_net_hdr` structure from the virtio specification).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct VirtioNetHdr {
+    /// The header flags (`VIRTIO_NET_HDR_F_*`).
+    pub flags: u8,
+    /// The GSO type of the packet (`VIRTIO_NET_HDR_GSO_*`).
+    pub gso_type: u8,
+    /// The length of the headers which are replicated for each segment of a GSO packet.
+    pub hdr_len: u16,
+    /// The maximum size of the segments of a GSO packet (without the headers).
+    pub gso_size: u16,
+    /// The offset where the checksum computation starts.
+    pub csum_start: u16,
+    /// The offset after `csum_start` where the checksum is stored.
+    pub csum_offset: u16,
+}
+
+// Safe because VirtioNetHdr contains only plain data.
+unsafe impl ByteValued for VirtioNetHdr {}
+
+impl VirtioNetHdr {
+    /// The size of the header in guest memory.
+    pub const LEN: usize = size_of::<VirtioNetHdr>();
+
+    /// Returns whether the checksum of the packet has to be computed by the receiver.
+    pub fn needs_csum(&self) -> bool {
+        self.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0
+    }
+
+    /// Returns whether the packet has to be segmented by the receiver.
+    pub fn is_gso(&self) -> bool {
+        self.gso_type & !VIRTIO_NET_HDR_GSO_ECN != VIRTIO_NET_HDR_GSO_NONE
+    }
+
+    /// Checks that the header written by the driver describes a valid packet: the flags and the
+    /// GSO type are known, and GSO packets have non-zero header and segment sizes.
+    pub fn validate(&self) -> Result<()> {
+        let valid_flags =
+            VIRTIO_NET_HDR_F_NEEDS_CSUM | VIRTIO_NET_HDR_F_DATA_VALID | VIRTIO_NET_HDR_F_RSC_INFO;
+        if self.flags & !valid_flags != 0 {
+            return Err(Error::InvalidFlags(self.flags));
+        }
+
+        match self.gso_type & !VIRTIO_NET_HDR_GSO_ECN {
+            // The ECN bit only makes sense for TCP segmentation.
+            VIRTIO_NET_HDR_GSO_NONE if self.gso_type == VIRTIO_NET_HDR_GSO_NONE => Ok(()),
+            VIRTIO_NET_HDR_GSO_UDP if self.gso_type == VIRTIO_NET_HDR_GSO_UDP => {
+                self.validate_gso()
+            }
+            VIRTIO_NET_HDR_GSO_TCPV4 | VIRTIO_NET_HDR_GSO_TCPV6 => self.validate_gso(),
+            _ => Err(Error::InvalidGsoType(self.gso_type)),
+        }
+    }
+
+    fn validate_gso(&self) -> Result<()> {
+        if self.hdr_len == 0 {
+            return Err(Error::InvalidHeaderLength);
+        }
+        if self.gso_size == 0 {
+            return Err(Error::InvalidGsoSize);
+        }
+        Ok(())
+    }
+}
+
+/// The header of the packets exchanged through the network device queues when
+/// `VIRTIO_F_VERSION_1` or `VIRTIO_NET_F_MRG_RXBUF` is negotiated.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct VirtioNetHdrMrgRxbuf {
+    /// The common part of the header.
+    pub hdr: VirtioNetHdr,
+    /// The number of descriptor chains used for a received packet. This is only relevant for
+    /// the receive queues, and it is always 1 when `VIRTIO_NET_F_MRG_RXBUF` is not negotiated.
+    pub num_buffers: u16,
+}
+
+// Safe because VirtioNetHdrMrgRxbuf contains only plain data.
+unsafe impl ByteValued for VirtioNetHdrMrgRxbuf {}
+
+impl VirtioNetHdrMrgRxbuf {
+    /// The size of the header in guest memory.
+    pub const LEN: usize = size_of::<VirtioNetHdrMrgRxbuf>();
+
+    /// Creates a new `VirtioNetHdrMrgRxbuf`.
+    ///
+    /// # Arguments
+    /// * `hdr` - The common part of the header.
+    /// * `num_buffers` - The number of descriptor chains used for the packet.
+    pub fn new(hdr: VirtioNetHdr, num_buffers: u16) -> Self {
+        VirtioNetHdrMrgRxbuf { hdr, num_buffers }
+    }
+}
+
+/// Accesses the network header at the head of a descriptor chain.
+pub trait NetHeader: ByteValued {
+    /// Returns the common part of the header.
+    fn net_hdr(&self) -> &VirtioNetHdr;
+
+    /// Reads and validates the header from the head of a transmit descriptor chain, which
+    /// can only contain device-readable descriptors. Returns the header, and the buffers which
+    /// hold the packet data (as `(address, length)` pairs).
+    ///
+    /// # Arguments
+    /// * `chain` - The transmit descriptor chain.
+    fn read_from_chain<M: GuestAddressSpace>(
+        chain: &mut DescriptorChain<M>,
+    ) -> Result<(Self, Vec<(GuestAddress, u32)>)> {
+        let (parts, data) = split_chain(chain, size_of::<Self>(), false)?;
+        let mut header = Self::default();
+        let mut offset = 0;
+        for (addr, len) in parts {
+            chain
+                .memory()
+                .read_slice(&mut header.as_mut_slice()[offset..offset + len], addr)
+                .map_err(Error::GuestMemory)?;
+            offset += len;
+        }
+        header.net_hdr().validate()?;
+        Ok((header, data))
+    }
+
+    /// Writes the header to the head of a receive descriptor chain, which can only contain
+    /// device-writable descriptors. Returns the buffers which are available for the packet
+    /// data (as `(address, length)` pairs).
+    ///
+    /// # Arguments
+    /// * `chain` - The receive descriptor chain.
+    fn write_to_chain<M: GuestAddressSpace>(
+        &self,
+        chain: &mut DescriptorChain<M>,
+    ) -> Result<Vec<(GuestAddress, u32)>> {
+        let (parts, data) = split_chain(chain, size_of::<Self>(), true)?;
+        let mut offset = 0;
+        for (addr, len) in parts {
+            chain
+                .memory()
+                .write_slice(&self.as_slice()[offset..offset + len], addr)
+                .map_err(Error::GuestMemory)?;
+            offset += len;
+        }
+        Ok(data)
+    }
+}
+
+impl NetHeader for VirtioNetHdr {
+    fn net_hdr(&self) -> &VirtioNetHdr {
+        self
+    }
+}
+
+impl NetHeader for VirtioNetHdrMrgRxbuf {
+    fn net_hdr(&self) -> &VirtioNetHdr {
+        &self.hdr
+    }
+}
+
+// Consumes the descriptors of `chain`, and splits them into the memory areas which hold the
+// first `len` bytes (the header), and the ones that follow (the packet data).
+#[allow(clippy::type_complexity)]
+fn split_chain<M: GuestAddressSpace>(
+    chain: &mut DescriptorChain<M>,
+    mut len: usize,
+    writable: bool,
+) -> Result<(Vec<(GuestAddress, usize)>, Vec<(GuestAddress, u32)>)> {
+    let mut header = Vec::new();
+    let mut data = Vec::new();
+
+    for desc in chain.by_ref() {
+        if desc.is_write_only() != writable {
+            return Err(if writable {
+                Error::UnexpectedReadOnlyDescriptor
+            } else {
+                Error::UnexpectedWriteOnlyDescriptor
+            });
+        }
+
+        let count = min(len, desc.len() as usize);
+        if count > 0 {
+            header.push((desc.addr(), count));
+            len -= count;
+        }
+        // The cast is safe because `count` is not greater than the descriptor length.
+        let remaining = desc.len() - count as u32;
+        if remaining > 0 {
+            let addr = desc
+                .addr()
+                .checked_add(count as u64)
+                .ok_or(Error::GuestMemory(GuestMemoryError::InvalidGuestAddress(
+                    desc.addr(),
+                )))?;
+            data.push((addr, remaining));
+        }
+    }
+
+    if len > 0 {
+        return Err(Error::DescriptorChainTooShort);
+    }
+    Ok((header, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::GuestMemoryMmap;
+
+    use virtio_device::mock::adjacent_regions;
+    use virtio_queue::mock::{AttackPattern, VirtQueue};
+    use virtio_queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    impl PartialEq for Error {
+        fn eq(&self, other: &Self) -> bool {
+            format!("{}", self) == format!("{}", other)
+        }
+    }
+
+    fn gso_hdr(gso_type: u8) -> VirtioNetHdr {
+        VirtioNetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type,
+            hdr_len: 54,
+            gso_size: 1448,
+            csum_start: 34,
+            csum_offset: 16,
+        }
+    }
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(VirtioNetHdr::LEN, 10);
+        assert_eq!(VirtioNetHdrMrgRxbuf::LEN, 12);
+    }
+
+    #[test]
+    fn test_validate() {
+        let hdr = VirtioNetHdr::default();
+        assert!(hdr.validate().is_ok());
+        assert!(!hdr.needs_csum());
+        assert!(!hdr.is_gso());
+
+        for &gso_type in [
+            VIRTIO_NET_HDR_GSO_TCPV4,
+            VIRTIO_NET_HDR_GSO_TCPV6,
+            VIRTIO_NET_HDR_GSO_TCPV4 | VIRTIO_NET_HDR_GSO_ECN,
+            VIRTIO_NET_HDR_GSO_UDP,
+        ]
+        .iter()
+        {
+            let hdr = gso_hdr(gso_type);
+            assert!(hdr.validate().is_ok());
+            assert!(hdr.needs_csum());
+            assert!(hdr.is_gso());
+        }
+
+        for &gso_type in [
+            2,
+            VIRTIO_NET_HDR_GSO_ECN,
+            VIRTIO_NET_HDR_GSO_UDP | VIRTIO_NET_HDR_GSO_ECN,
+        ]
+        .iter()
+        {
+            assert_eq!(
+                gso_hdr(gso_type).validate().unwrap_err(),
+                Error::InvalidGsoType(gso_type)
+            );
+        }
+
+        let mut hdr = gso_hdr(VIRTIO_NET_HDR_GSO_TCPV4);
+        hdr.flags |= 0x10;
+        assert_eq!(hdr.validate().unwrap_err(), Error::InvalidFlags(0x11));
+
+        let mut hdr = gso_hdr(VIRTIO_NET_HDR_GSO_TCPV6);
+        hdr.hdr_len = 0;
+        assert_eq!(hdr.validate().unwrap_err(), Error::InvalidHeaderLength);
+
+        let mut hdr = gso_hdr(VIRTIO_NET_HDR_GSO_TCPV6);
+        hdr.gso_size = 0;
+        assert_eq!(hdr.validate().unwrap_err(), Error::InvalidGsoSize);
+    }
+
+    #[test]
+    fn test_read_from_chain() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let mut q = vq.create_queue(&mem);
+
+        let hdr = VirtioNetHdrMrgRxbuf::new(gso_hdr(VIRTIO_NET_HDR_GSO_TCPV4), 0);
+        mem.write_slice(&hdr.as_slice()[..4], GuestAddress(0x1000))
+            .unwrap();
+        mem.write_slice(&hdr.as_slice()[4..], GuestAddress(0x2000))
+            .unwrap();
+
+        // The header spans two descriptors, and the second one holds the start of the packet.
+        vq.dtable(0).set(0x1000, 4, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1).set(0x2000, 0x100, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable(2).set(0x3000, 0x200, 0, 0);
+        // A chain with a device-writable descriptor.
+        vq.dtable(3).set(0x1000, 12, VIRTQ_DESC_F_NEXT, 4);
+        vq.dtable(4).set(0x3000, 0x200, VIRTQ_DESC_F_WRITE, 0);
+        // A chain which is too short.
+        vq.dtable(5).set(0x1000, 4, 0, 0);
+        // A chain with an invalid header.
+        vq.dtable(6).set(0x4000, 12, 0, 0);
+        mem.write_obj(0xffu8, GuestAddress(0x4000)).unwrap();
+        for (i, &head) in [0, 3, 5, 6].iter().enumerate() {
+            vq.avail.ring(i as u16).store(head);
+        }
+        vq.avail.idx().store(4);
+
+        let mut chain = q.iter().unwrap().next().unwrap();
+        let (read_hdr, data) = VirtioNetHdrMrgRxbuf::read_from_chain(&mut chain).unwrap();
+        assert_eq!(read_hdr, hdr);
+        assert_eq!(
+            data,
+            vec![(GuestAddress(0x2008), 0xf8), (GuestAddress(0x3000), 0x200)]
+        );
+
+        let mut chain = q.iter().unwrap().next().unwrap();
+        assert_eq!(
+            VirtioNetHdrMrgRxbuf::read_from_chain(&mut chain).unwrap_err(),
+            Error::UnexpectedWriteOnlyDescriptor
+        );
+
+        let mut chain = q.iter().unwrap().next().unwrap();
+        assert_eq!(
+            VirtioNetHdr::read_from_chain(&mut chain).unwrap_err(),
+            Error::DescriptorChainTooShort
+        );
+
+        let mut chain = q.iter().unwrap().next().unwrap();
+        assert_eq!(
+            VirtioNetHdr::read_from_chain(&mut chain).unwrap_err(),
+            Error::InvalidFlags(0xff)
+        );
+    }
+
+    #[test]
+    fn test_write_to_chain() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let mut q = vq.create_queue(&mem);
+
+        vq.dtable(0)
+            .set(0x1000, 0x800, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 1);
+        vq.dtable(1).set(0x2000, 0x800, VIRTQ_DESC_F_WRITE, 0);
+        // The header doesn't fit in a device-readable descriptor.
+        vq.dtable(2).set(0x3000, 0x800, 0, 0);
+        vq.avail.ring(0).store(0);
+        vq.avail.ring(1).store(2);
+        vq.avail.idx().store(2);
+
+        let hdr = VirtioNetHdrMrgRxbuf::new(VirtioNetHdr::default(), 1);
+        let mut chain = q.iter().unwrap().next().unwrap();
+        let data = hdr.write_to_chain(&mut chain).unwrap();
+        assert_eq!(
+            data,
+            vec![(GuestAddress(0x100c), 0x7f4), (GuestAddress(0x2000), 0x800)]
+        );
+        assert_eq!(
+            mem.read_obj::<VirtioNetHdrMrgRxbuf>(GuestAddress(0x1000))
+                .unwrap(),
+            hdr
+        );
+
+        let mut chain = q.iter().unwrap().next().unwrap();
+        assert_eq!(
+            hdr.write_to_chain(&mut chain).unwrap_err(),
+            Error::UnexpectedReadOnlyDescriptor
+        );
+    }
+
+    #[test]
+    fn test_attack_patterns() {
+        let mem = adjacent_regions(0x10_0000);
+        for &pattern in AttackPattern::ALL.iter() {
+            let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+            vq.add_attack(pattern, 0, GuestAddress(0x1_0000));
+            let mut chain = vq.create_queue(&mem).iter().unwrap().next().unwrap();
+            // A loop of device-readable descriptors repeats the same buffer up to the queue size,
+            // which is still a valid header.
+            assert_eq!(
+                VirtioNetHdr::read_from_chain(&mut chain.clone()).is_ok(),
+                [AttackPattern::SelfReference].contains(&pattern),
+                "{:?}",
+                pattern
+            );
+            assert!(
+                VirtioNetHdr::default().write_to_chain(&mut chain).is_err(),
+                "{:?}",
+                pattern
+            );
+        }
+    }
+}
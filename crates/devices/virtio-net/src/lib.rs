@@ -0,0 +1,46 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A crate that provides network device specific components as described
+//! by the virtio specification.
+
+#![deny(missing_docs)]
+
+/// Contains the packet capture abstractions.
+pub mod capture;
+
+/// Contains the network device configuration space abstraction.
+pub mod config;
+
+/// Contains the control queue request abstraction.
+pub mod ctrl_queue;
+
+/// Contains virtio network constant definitions.
+pub mod defs;
+
+/// Contains a reference virtio network device implementation.
+pub mod device;
+
+/// Contains the virtio network header layout, and helpers for accessing it in descriptor chains.
+pub mod header;
+
+/// Contains helpers for negotiating the offload features of TAP backed network devices.
+pub mod offload;
+
+/// Contains the receive/transmit queue pair processing abstraction.
+pub mod queue_handler;
+
+/// Contains the TAP device abstraction.
+pub mod tap;
+
+/// Contains the in-kernel vhost-net backend for the queue pairs.
+pub mod vhost;
+
+/// Contains a virtio network device frontend which forwards the queue pairs to an external
+/// vhost-user-net backend.
+#[cfg(feature = "vhost-user")]
+pub mod vhost_user;
+
+/// Contains the AF_XDP socket packet backend.
+pub mod xdp;
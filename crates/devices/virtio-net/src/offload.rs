@@ -0,0 +1,258 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Offload feature negotiation for TAP backed network devices.
+//!
+//! The checksum and segmentation offloads can only be offered to the driver when the TAP
+//! device can honor them. This module provides the following abstractions:
+//!
+//! - [`TapCapabilities`](struct.TapCapabilities.html) which describes the offloads supported
+//!   by a TAP device, and maps them to the `VIRTIO_NET_F_*` feature bits that the network device
+//!   can advertise.
+//! - [`tap_offloads`](fn.tap_offloads.html) which maps the features acknowledged by the driver
+//!   to the `TUN_F_*` offload flags of the TAP device, and
+//!   [`configure_tap`](fn.configure_tap.html) which applies them (along with the network header
+//!   size) when the device is activated.
+//!
+//! The offloads flow in two directions. The `VIRTIO_NET_F_HOST_*` (and `VIRTIO_NET_F_CSUM`)
+//! features let the driver send partially checksummed or GSO packets, which the TAP device
+//! accepts as long as it uses network headers. The `VIRTIO_NET_F_GUEST_*` features let the
+//! device pass such packets to the driver, so the TAP device must be told (through
+//! `TUNSETOFFLOAD`) that it can deliver them.
+
+use std::io;
+use std::os::raw::{c_int, c_uint, c_ulong};
+use std::os::unix::io::AsRawFd;
+
+use vmm_sys_util::ioctl::{ioctl_with_ref, ioctl_with_val};
+
+use crate::defs::{
+    VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_ECN, VIRTIO_NET_F_GUEST_TSO4,
+    VIRTIO_NET_F_GUEST_TSO6, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_ECN, VIRTIO_NET_F_HOST_TSO4,
+    VIRTIO_NET_F_HOST_TSO6, VIRTIO_NET_F_HOST_UFO,
+};
+use crate::header::VirtioNetHdrMrgRxbuf;
+
+// TAP offload flags (from `linux/if_tun.h`).
+/// The TAP device can deliver packets with partial checksums.
+pub const TUN_F_CSUM: c_uint = 0x01;
+/// The TAP device can deliver TSOv4 packets.
+pub const TUN_F_TSO4: c_uint = 0x02;
+/// The TAP device can deliver TSOv6 packets.
+pub const TUN_F_TSO6: c_uint = 0x04;
+/// The TAP device can deliver TSO packets with ECN.
+pub const TUN_F_TSO_ECN: c_uint = 0x08;
+/// The TAP device can deliver UFO packets.
+pub const TUN_F_UFO: c_uint = 0x10;
+
+// The ioctls are declared in a private module, since the generated functions are public.
+mod ioctls {
+    use std::os::raw::{c_int, c_uint};
+
+    const TUNTAP: c_uint = 0x54;
+
+    vmm_sys_util::ioctl_iow_nr!(TUNSETOFFLOAD, TUNTAP, 0xd0, c_uint);
+    vmm_sys_util::ioctl_iow_nr!(TUNSETVNETHDRSZ, TUNTAP, 0xd8, c_int);
+}
+
+/// The offloads supported by a TAP device.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TapCapabilities {
+    /// The TAP device was opened with `IFF_VNET_HDR`, so packets are exchanged along with their
+    /// network header. None of the offloads can be used otherwise.
+    pub vnet_hdr: bool,
+    /// Packets with partial checksums are supported.
+    pub csum: bool,
+    /// TCP segmentation offload for IPv4 is supported.
+    pub tso4: bool,
+    /// TCP segmentation offload for IPv6 is supported.
+    pub tso6: bool,
+    /// TCP segmentation offload with ECN is supported.
+    pub tso_ecn: bool,
+    /// UDP fragmentation offload is supported.
+    pub ufo: bool,
+}
+
+impl TapCapabilities {
+    /// Returns the `VIRTIO_NET_F_*` offload features that can be offered to the driver.
+    ///
+    /// Segmentation offloads are only offered along with checksum offload, since the virtio
+    /// specification requires the driver to negotiate them that way.
+    pub fn features(&self) -> u64 {
+        if !self.vnet_hdr || !self.csum {
+            return 0;
+        }
+
+        let mut features = (1 << VIRTIO_NET_F_CSUM) | (1 << VIRTIO_NET_F_GUEST_CSUM);
+        if self.tso4 {
+            features |= (1 << VIRTIO_NET_F_HOST_TSO4) | (1 << VIRTIO_NET_F_GUEST_TSO4);
+        }
+        if self.tso6 {
+            features |= (1 << VIRTIO_NET_F_HOST_TSO6) | (1 << VIRTIO_NET_F_GUEST_TSO6);
+        }
+        if self.tso_ecn && (self.tso4 || self.tso6) {
+            features |= (1 << VIRTIO_NET_F_HOST_ECN) | (1 << VIRTIO_NET_F_GUEST_ECN);
+        }
+        if self.ufo {
+            features |= (1 << VIRTIO_NET_F_HOST_UFO) | (1 << VIRTIO_NET_F_GUEST_UFO);
+        }
+        features
+    }
+}
+
+/// Returns the `TUN_F_*` offload flags that correspond to the features acknowledged by the
+/// driver, i.e. the kinds of packets that the TAP device can pass to the driver.
+///
+/// # Arguments
+/// * `acked_features` - The features acknowledged by the driver.
+pub fn tap_offloads(acked_features: u64) -> c_uint {
+    let has_feature = |feature: u64| acked_features & (1 << feature) != 0;
+
+    // The segmentation offloads can't be enabled without checksum offload.
+    if !has_feature(VIRTIO_NET_F_GUEST_CSUM) {
+        return 0;
+    }
+    let mut flags = TUN_F_CSUM;
+    if has_feature(VIRTIO_NET_F_GUEST_TSO4) {
+        flags |= TUN_F_TSO4;
+    }
+    if has_feature(VIRTIO_NET_F_GUEST_TSO6) {
+        flags |= TUN_F_TSO6;
+    }
+    if has_feature(VIRTIO_NET_F_GUEST_ECN) {
+        flags |= TUN_F_TSO_ECN;
+    }
+    if has_feature(VIRTIO_NET_F_GUEST_UFO) {
+        flags |= TUN_F_UFO;
+    }
+    flags
+}
+
+/// Configures a TAP device opened with `IFF_VNET_HDR` for the features acknowledged by the
+/// driver. This has to be called when the network device is activated.
+///
+/// The network header size is set to the size of `VirtioNetHdrMrgRxbuf`, which is the header
+/// used by virtio 1.0 devices, and the offloads are set with
+/// [`tap_offloads`](fn.tap_offloads.html).
+///
+/// # Arguments
+/// * `tap` - The TAP device.
+/// * `acked_features` - The features acknowledged by the driver.
+pub fn configure_tap<T: AsRawFd>(tap: &T, acked_features: u64) -> io::Result<()> {
+    // The header size always fits in a `c_int`.
+    let hdr_size = VirtioNetHdrMrgRxbuf::LEN as c_int;
+    // Safe because the kernel only reads the header size, and we check the return value.
+    tap_ioctl(unsafe { ioctl_with_ref(tap, ioctls::TUNSETVNETHDRSZ(), &hdr_size) })?;
+
+    // `TUNSETOFFLOAD` takes the flags by value.
+    let offloads = c_ulong::from(tap_offloads(acked_features));
+    // Safe because the ioctl doesn't access memory, and we check the return value.
+    tap_ioctl(unsafe { ioctl_with_val(tap, ioctls::TUNSETOFFLOAD(), offloads) })
+}
+
+// Converts the return value of an ioctl to an `io::Result`.
+fn tap_ioctl(ret: c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_features() {
+        // No offloads without network headers.
+        let caps = TapCapabilities {
+            vnet_hdr: false,
+            csum: true,
+            tso4: true,
+            ..Default::default()
+        };
+        assert_eq!(caps.features(), 0);
+
+        // Segmentation offloads require checksum offload.
+        let caps = TapCapabilities {
+            vnet_hdr: true,
+            csum: false,
+            tso4: true,
+            ..Default::default()
+        };
+        assert_eq!(caps.features(), 0);
+
+        let caps = TapCapabilities {
+            vnet_hdr: true,
+            csum: true,
+            tso6: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            caps.features(),
+            (1 << VIRTIO_NET_F_CSUM)
+                | (1 << VIRTIO_NET_F_GUEST_CSUM)
+                | (1 << VIRTIO_NET_F_HOST_TSO6)
+                | (1 << VIRTIO_NET_F_GUEST_TSO6)
+        );
+
+        let caps = TapCapabilities {
+            vnet_hdr: true,
+            csum: true,
+            tso4: true,
+            tso6: true,
+            tso_ecn: true,
+            ufo: true,
+        };
+        let features = caps.features();
+        for &feature in [
+            VIRTIO_NET_F_HOST_TSO4,
+            VIRTIO_NET_F_HOST_TSO6,
+            VIRTIO_NET_F_HOST_ECN,
+            VIRTIO_NET_F_HOST_UFO,
+            VIRTIO_NET_F_GUEST_ECN,
+            VIRTIO_NET_F_GUEST_UFO,
+        ]
+        .iter()
+        {
+            assert_ne!(features & (1 << feature), 0);
+        }
+        // Everything that's offered is mapped back to the TAP offloads.
+        assert_eq!(
+            tap_offloads(features),
+            TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6 | TUN_F_TSO_ECN | TUN_F_UFO
+        );
+    }
+
+    #[test]
+    fn test_tap_offloads() {
+        assert_eq!(tap_offloads(0), 0);
+        // The host offloads don't affect the packets delivered by the TAP device.
+        assert_eq!(
+            tap_offloads((1 << VIRTIO_NET_F_CSUM) | (1 << VIRTIO_NET_F_HOST_TSO4)),
+            0
+        );
+        assert_eq!(
+            tap_offloads((1 << VIRTIO_NET_F_GUEST_TSO4) | (1 << VIRTIO_NET_F_GUEST_UFO)),
+            0
+        );
+        assert_eq!(
+            tap_offloads(
+                (1 << VIRTIO_NET_F_GUEST_CSUM)
+                    | (1 << VIRTIO_NET_F_GUEST_TSO4)
+                    | (1 << VIRTIO_NET_F_GUEST_UFO)
+            ),
+            TUN_F_CSUM | TUN_F_TSO4 | TUN_F_UFO
+        );
+    }
+
+    #[test]
+    fn test_configure_tap() {
+        // The ioctls are rejected for anything but a TAP device.
+        let file = TempFile::new().unwrap();
+        assert!(configure_tap(file.as_file(), 1 << VIRTIO_NET_F_GUEST_CSUM).is_err());
+    }
+}
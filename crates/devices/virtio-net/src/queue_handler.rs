@@ -0,0 +1,1029 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A receive/transmit queue pair processing abstraction.
+//!
+//! This module provides the following abstraction:
+//!
+//! - [`QueuePairHandler`](struct.QueuePairHandler.html) which moves packets between a receive
+//!   and transmit queue pair and a TAP device queue (or any other non-blocking packet backend),
+//!   and notifies the driver about the used buffers via a `SignalUsedQueue` implementation.
+//!
+//! Every queue pair has its own handler, and the handlers don't share any state, so the pairs
+//! can be processed independently of each other. Each direction can optionally be throttled
+//! with its own [`RateLimiter`](../../virtio_device/rate_limiter/struct.RateLimiter.html), and
+//! the exchanged frames can be copied to a [`PacketCapture`](../capture/trait.PacketCapture.html).
+
+use std::fmt::{self, Display};
+use std::io::{self, Read, Write};
+use std::{mem, result};
+
+use log::warn;
+
+use vm_memory::{ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryError};
+
+use virtio_device::rate_limiter::RateLimiter;
+use virtio_device::SignalUsedQueue;
+use virtio_queue::{self, DescriptorChain, Queue};
+
+use crate::capture::{Direction, SharedPacketCapture};
+use crate::header::{self, NetHeader, VirtioNetHdr, VirtioNetHdrMrgRxbuf};
+
+// The largest packet exchanged with the TAP device: a 64 KiB GSO packet, with an Ethernet
+// header (including a VLAN tag) and the network header.
+const MAX_PACKET_LEN: usize = VirtioNetHdrMrgRxbuf::LEN + 18 + 65_535;
+
+/// Errors encountered while processing a queue pair.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to access the network header.
+    Header(header::Error),
+    /// Invalid memory access.
+    GuestMemory(GuestMemoryError),
+    /// The packet doesn't fit in the buffers provided by the driver.
+    PacketTooLarge(usize),
+    /// Failed to access the queue.
+    Queue(virtio_queue::Error),
+    /// Failed to handle a rate limiter event.
+    RateLimiter(io::Error),
+    /// Failed to read a packet from the TAP device.
+    Tap(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Header(ref err) => write!(f, "failed to access the network header: {}", err),
+            GuestMemory(ref err) => write!(f, "error accessing guest memory: {}", err),
+            PacketTooLarge(len) => write!(f, "packet of {} bytes doesn't fit the buffers", len),
+            Queue(ref err) => write!(f, "failed to access the queue: {}", err),
+            RateLimiter(ref err) => write!(f, "failed to handle rate limiter event: {}", err),
+            Tap(ref err) => write!(f, "failed to read from the TAP device: {}", err),
+        }
+    }
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Processes the packets of a receive/transmit queue pair.
+///
+/// The packets sent by the driver are written to the TAP device, and the packets read from the
+/// TAP device are placed in the buffers of the receive queue. Each packet is preceded by a
+/// `VirtioNetHdrMrgRxbuf` header in guest memory, which is the header used by the virtio 1.0
+/// devices. When the TAP device exchanges packets along with their network header (i.e. it was
+/// opened with `IFF_VNET_HDR` and configured with `offload::configure_tap`), the header is
+/// passed through. Otherwise, it's stripped from the transmitted packets and zeroed for the
+/// received ones.
+///
+/// Each received packet is placed in a single descriptor chain, unless mergeable receive
+/// buffers are enabled (see `with_mergeable_rx_buffers`). A packet read from the TAP device when
+/// the receive queue doesn't have enough available buffers is kept until the driver provides
+/// more of them, which means `process_rx` has to be called both when the TAP device becomes
+/// readable, and when the driver notifies the receive queue.
+///
+/// # Example
+///
+/// ```rust
+/// # use virtio_net::queue_handler::QueuePairHandler;
+/// # use virtio_queue::Queue;
+/// # use vm_memory::{GuestAddress, GuestMemoryMmap};
+/// # use vmm_sys_util::eventfd::EventFd;
+/// # use std::io::Cursor;
+/// let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+/// // Any non-blocking packet backend can stand in for the TAP device.
+/// let tap = Cursor::new(Vec::new());
+/// let mut handler = QueuePairHandler::new(
+///     Queue::new(&mem, 256),
+///     Queue::new(&mem, 256),
+///     tap,
+///     EventFd::new(0).unwrap(),
+/// );
+///
+/// // When the driver notifies the device about the transmit queue:
+/// handler.process_tx().unwrap();
+/// ```
+#[derive(Debug)]
+pub struct QueuePairHandler<M: GuestAddressSpace, T: Read + Write, S: SignalUsedQueue> {
+    /// The receive queue.
+    rx_queue: Queue<M>,
+    /// The transmit queue.
+    tx_queue: Queue<M>,
+    /// The index of the queue pair, which determines the queue indices passed to
+    /// `driver_notify`.
+    pair_index: u16,
+    /// The TAP device queue.
+    tap: T,
+    /// Whether the TAP device exchanges packets along with their network header.
+    vnet_hdr: bool,
+    /// Whether a received packet can span multiple descriptor chains.
+    mrg_rxbuf: bool,
+    /// The object used for notifying the driver about used buffers.
+    driver_notify: S,
+    /// The buffer which holds the packets read from the TAP device.
+    rx_packet: Vec<u8>,
+    /// The length of the packet from `rx_packet` which is waiting for receive buffers (or for
+    /// the rate limiter), if any.
+    rx_pending: Option<usize>,
+    /// Whether the pending packet was already accounted for by the receive rate limiter.
+    rx_budgeted: bool,
+    /// The used elements of the chains which already hold a part of the pending packet, when
+    /// mergeable receive buffers are used.
+    rx_used: Vec<(u16, u32)>,
+    /// The number of bytes of the pending packet written to the `rx_used` chains.
+    rx_written: usize,
+    /// The buffers of the first `rx_used` chain, which hold the network header.
+    rx_hdr_buffers: Vec<(GuestAddress, u32)>,
+    /// The buffer used for assembling the transmitted packets.
+    tx_packet: Vec<u8>,
+    /// The optional rate limiter for the received packets.
+    rx_rate_limiter: Option<RateLimiter>,
+    /// The optional rate limiter for the transmitted packets.
+    tx_rate_limiter: Option<RateLimiter>,
+    /// The optional sink for the exchanged frames.
+    capture: Option<SharedPacketCapture>,
+}
+
+impl<M, T, S> QueuePairHandler<M, T, S>
+where
+    M: GuestAddressSpace,
+    T: Read + Write,
+    S: SignalUsedQueue,
+{
+    /// Creates a new `QueuePairHandler` for the queue pair with index 0, backed by a TAP device
+    /// which exchanges packets along with their network header.
+    ///
+    /// # Arguments
+    /// * `rx_queue` - The receive queue.
+    /// * `tx_queue` - The transmit queue.
+    /// * `tap` - The TAP device queue, which must be in non-blocking mode.
+    /// * `driver_notify` - The object used for notifying the driver.
+    pub fn new(rx_queue: Queue<M>, tx_queue: Queue<M>, tap: T, driver_notify: S) -> Self {
+        QueuePairHandler {
+            rx_queue,
+            tx_queue,
+            pair_index: 0,
+            tap,
+            vnet_hdr: true,
+            mrg_rxbuf: false,
+            driver_notify,
+            rx_packet: vec![0; MAX_PACKET_LEN],
+            rx_pending: None,
+            rx_budgeted: false,
+            rx_used: Vec::new(),
+            rx_written: 0,
+            rx_hdr_buffers: Vec::new(),
+            tx_packet: Vec::with_capacity(MAX_PACKET_LEN),
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            capture: None,
+        }
+    }
+
+    /// Sets the index of the queue pair, which is relevant when the device has multiple queue
+    /// pairs. The receive queue of the pair has the index `2 * pair_index`, and the transmit
+    /// queue has the index `2 * pair_index + 1`.
+    ///
+    /// # Arguments
+    /// * `pair_index` - The index of the queue pair.
+    pub fn with_pair_index(mut self, pair_index: u16) -> Self {
+        self.pair_index = pair_index;
+        self
+    }
+
+    /// Sets whether the TAP device exchanges packets along with their network header.
+    ///
+    /// # Arguments
+    /// * `vnet_hdr` - Whether the TAP device uses network headers.
+    pub fn with_vnet_hdr(mut self, vnet_hdr: bool) -> Self {
+        self.vnet_hdr = vnet_hdr;
+        self
+    }
+
+    /// Enables or disables mergeable receive buffers, which have to be used when the driver
+    /// negotiates `VIRTIO_NET_F_MRG_RXBUF`.
+    ///
+    /// With mergeable receive buffers, a packet which doesn't fit in the first available chain
+    /// is spread over as many chains as needed, and the `num_buffers` field of its header holds
+    /// the number of chains. The driver can then provide small buffers (i.e. one page each)
+    /// instead of buffers which fit the largest possible packet.
+    ///
+    /// # Arguments
+    /// * `mrg_rxbuf` - Whether received packets can span multiple chains.
+    pub fn with_mergeable_rx_buffers(mut self, mrg_rxbuf: bool) -> Self {
+        self.mrg_rxbuf = mrg_rxbuf;
+        self
+    }
+
+    /// Throttles the received packets with `rate_limiter`.
+    ///
+    /// Each packet consumes one operation and its length (including the network header) from
+    /// the rate limiter budget. When the budget is exhausted, the packet is kept until the rate
+    /// limiter file descriptor becomes readable, at which point `process_rx_rate_limiter_event`
+    /// has to be called.
+    ///
+    /// # Arguments
+    /// * `rate_limiter` - The rate limiter used for the received packets.
+    pub fn with_rx_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rx_rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Throttles the transmitted packets with `rate_limiter`.
+    ///
+    /// Each packet consumes one operation and its length (including the network header) from
+    /// the rate limiter budget. When the budget is exhausted, the packet is left in the transmit
+    /// queue until the rate limiter file descriptor becomes readable, at which point
+    /// `process_tx_rate_limiter_event` has to be called.
+    ///
+    /// # Arguments
+    /// * `rate_limiter` - The rate limiter used for the transmitted packets.
+    pub fn with_tx_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.tx_rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Copies the frames exchanged with the TAP device to `capture`, or stops copying them if
+    /// `capture` is `None`.
+    ///
+    /// The received frames are captured when they are read from the TAP device (even if they
+    /// are dropped afterwards), and the transmitted frames right before they are written to the
+    /// TAP device.
+    ///
+    /// # Arguments
+    /// * `capture` - The packet capture, which can be shared with other handlers.
+    pub fn set_packet_capture(&mut self, capture: Option<SharedPacketCapture>) {
+        self.capture = capture;
+    }
+
+    /// Returns a reference to the receive rate limiter, if any (i.e. for registering its file
+    /// descriptor with an event loop).
+    pub fn rx_rate_limiter(&self) -> Option<&RateLimiter> {
+        self.rx_rate_limiter.as_ref()
+    }
+
+    /// Returns a reference to the transmit rate limiter, if any.
+    pub fn tx_rate_limiter(&self) -> Option<&RateLimiter> {
+        self.tx_rate_limiter.as_ref()
+    }
+
+    /// Returns a mutable reference to the receive rate limiter, if any (i.e. for consuming its
+    /// timer events without processing the queue).
+    pub fn rx_rate_limiter_mut(&mut self) -> Option<&mut RateLimiter> {
+        self.rx_rate_limiter.as_mut()
+    }
+
+    /// Returns a mutable reference to the transmit rate limiter, if any.
+    pub fn tx_rate_limiter_mut(&mut self) -> Option<&mut RateLimiter> {
+        self.tx_rate_limiter.as_mut()
+    }
+
+    /// Returns a reference to the TAP device queue (i.e. for registering its file descriptor
+    /// with an event loop).
+    pub fn tap(&self) -> &T {
+        &self.tap
+    }
+
+    /// Returns a mutable reference to the TAP device queue.
+    pub fn tap_mut(&mut self) -> &mut T {
+        &mut self.tap
+    }
+
+    /// Consumes the handler and returns the TAP device queue.
+    pub fn into_tap(self) -> T {
+        self.tap
+    }
+
+    /// Returns a reference to the receive queue.
+    pub fn rx_queue(&self) -> &Queue<M> {
+        &self.rx_queue
+    }
+
+    /// Returns a reference to the transmit queue.
+    pub fn tx_queue(&self) -> &Queue<M> {
+        &self.tx_queue
+    }
+
+    /// Returns whether a packet read from the TAP device is waiting for receive buffers.
+    pub fn has_pending_rx(&self) -> bool {
+        self.rx_pending.is_some()
+    }
+
+    /// Sends the packets made available by the driver to the TAP device. This has to be called
+    /// when the driver notifies the device about the transmit queue.
+    ///
+    /// Packets which are malformed, or which the TAP device can't accept, are dropped.
+    pub fn process_tx(&mut self) -> Result<()> {
+        loop {
+            self.tx_queue.disable_notification()?;
+
+            while let Some(mut chain) = self.tx_queue.iter()?.next() {
+                if let Some(rate_limiter) = self.tx_rate_limiter.as_mut() {
+                    let len = chain.clone().map(|desc| u64::from(desc.len())).sum();
+                    if !rate_limiter.consume(1, len) {
+                        // Put the chain back; it will be processed once the rate limiter
+                        // allows it. Notifications stay disabled until then.
+                        self.tx_queue.go_to_previous_position();
+                        return Ok(());
+                    }
+                }
+                if let Err(e) = self.send_packet(&mut chain) {
+                    warn!("failed to send packet: {}", e);
+                }
+                // The driver doesn't expect anything to be written to the transmit buffers.
+                self.tx_queue.add_used(chain.head_index(), 0)?;
+                if self.tx_queue.needs_notification()? {
+                    self.driver_notify
+                        .signal_used_queue(2 * self.pair_index + 1);
+                }
+            }
+
+            if !self.tx_queue.enable_notification()? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Assembles the packet from a transmit chain, and writes it to the TAP device.
+    fn send_packet(&mut self, chain: &mut DescriptorChain<M>) -> Result<()> {
+        let (hdr, buffers) = VirtioNetHdrMrgRxbuf::read_from_chain(chain).map_err(Error::Header)?;
+
+        self.tx_packet.clear();
+        if self.vnet_hdr {
+            self.tx_packet.extend_from_slice(hdr.as_slice());
+        }
+        for (addr, len) in buffers {
+            let start = self.tx_packet.len();
+            let end = start + len as usize;
+            if end > MAX_PACKET_LEN {
+                return Err(Error::PacketTooLarge(end));
+            }
+            self.tx_packet.resize(end, 0);
+            chain
+                .memory()
+                .read_slice(&mut self.tx_packet[start..end], addr)
+                .map_err(Error::GuestMemory)?;
+        }
+
+        let hdr_len = if self.vnet_hdr {
+            VirtioNetHdrMrgRxbuf::LEN
+        } else {
+            0
+        };
+        self.capture_frame(Direction::Tx, &self.tx_packet[hdr_len..]);
+        self.tap.write(&self.tx_packet).map_err(Error::Tap)?;
+        Ok(())
+    }
+
+    /// Places the packets read from the TAP device in the buffers of the receive queue, until
+    /// there are no more packets, or no more buffers. This has to be called when the TAP device
+    /// becomes readable, and when the driver notifies the device about the receive queue.
+    pub fn process_rx(&mut self) -> Result<()> {
+        loop {
+            self.rx_queue.disable_notification()?;
+
+            loop {
+                let len = match self.rx_pending {
+                    Some(len) => len,
+                    None => match self.read_packet()? {
+                        Some(len) => len,
+                        None => break,
+                    },
+                };
+                if let Some(rate_limiter) = self.rx_rate_limiter.as_mut() {
+                    if !self.rx_budgeted && !rate_limiter.consume(1, len as u64) {
+                        // The packet is placed once the rate limiter allows it. Notifications
+                        // stay disabled until then.
+                        self.rx_pending = Some(len);
+                        return Ok(());
+                    }
+                    self.rx_budgeted = true;
+                }
+                if !self.place_packet(len)? {
+                    // Wait for the driver to provide more buffers.
+                    self.rx_pending = Some(len);
+                    break;
+                }
+                self.rx_pending = None;
+                self.rx_budgeted = false;
+            }
+
+            // The driver may have added buffers in the meantime, so there's a new chance of
+            // placing the pending packet.
+            if !self.rx_queue.enable_notification()? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Resumes placing the received packets after the receive rate limiter timer expired. This
+    /// has to be called when the receive rate limiter file descriptor becomes readable.
+    pub fn process_rx_rate_limiter_event(&mut self) -> Result<()> {
+        if let Some(rate_limiter) = self.rx_rate_limiter.as_mut() {
+            rate_limiter.event_handler().map_err(Error::RateLimiter)?;
+        }
+        self.process_rx()
+    }
+
+    /// Resumes sending the transmitted packets after the transmit rate limiter timer expired.
+    /// This has to be called when the transmit rate limiter file descriptor becomes readable.
+    pub fn process_tx_rate_limiter_event(&mut self) -> Result<()> {
+        if let Some(rate_limiter) = self.tx_rate_limiter.as_mut() {
+            rate_limiter.event_handler().map_err(Error::RateLimiter)?;
+        }
+        self.process_tx()
+    }
+
+    // Reads a packet from the TAP device into `rx_packet`, prepending an empty header if the
+    // TAP device doesn't provide one. Returns the length of the packet, or `None` if there's
+    // no packet to read.
+    fn read_packet(&mut self) -> Result<Option<usize>> {
+        let hdr_len = if self.vnet_hdr {
+            0
+        } else {
+            VirtioNetHdrMrgRxbuf::LEN
+        };
+        self.rx_packet[..hdr_len].iter_mut().for_each(|b| *b = 0);
+
+        match self.tap.read(&mut self.rx_packet[hdr_len..]) {
+            Ok(len) => {
+                let len = hdr_len + len;
+                if len > VirtioNetHdrMrgRxbuf::LEN {
+                    self.capture_frame(
+                        Direction::Rx,
+                        &self.rx_packet[VirtioNetHdrMrgRxbuf::LEN..len],
+                    );
+                }
+                Ok(Some(len))
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(Error::Tap(e)),
+        }
+    }
+
+    // Passes a copy of `frame` to the packet capture, if any.
+    fn capture_frame(&self, direction: Direction, frame: &[u8]) {
+        if let Some(capture) = self.capture.as_ref() {
+            // A poisoned lock only means that another handler panicked while capturing a frame.
+            let mut capture = capture.lock().unwrap_or_else(|e| e.into_inner());
+            capture.capture(self.pair_index, direction, frame);
+        }
+    }
+
+    // Places the first `len` bytes of `rx_packet` in the receive queue, and publishes the used
+    // chains. Returns `false` if there are not enough available buffers.
+    fn place_packet(&mut self, len: usize) -> Result<bool> {
+        let used = if self.mrg_rxbuf {
+            match self.receive_mergeable(len)? {
+                Some(used) => used,
+                None => return Ok(false),
+            }
+        } else {
+            let mut chain = match self.rx_queue.iter()?.next() {
+                Some(chain) => chain,
+                None => return Ok(false),
+            };
+            let used_len = self.receive_packet(&mut chain, len).unwrap_or_else(|e| {
+                warn!("failed to receive packet: {}", e);
+                0
+            });
+            vec![(chain.head_index(), used_len)]
+        };
+
+        // All the chains of a packet become visible to the driver at once, since it expects to
+        // find `num_buffers` consecutive used elements.
+        self.rx_queue.add_used_batch(&used)?;
+        if self.rx_queue.needs_notification()? {
+            self.driver_notify.signal_used_queue(2 * self.pair_index);
+        }
+        Ok(true)
+    }
+
+    // Writes the first `len` bytes of `rx_packet` to a receive chain. Returns the number of
+    // bytes written to guest memory.
+    fn receive_packet(&mut self, chain: &mut DescriptorChain<M>, len: usize) -> Result<u32> {
+        if len < VirtioNetHdrMrgRxbuf::LEN {
+            return Err(Error::PacketTooLarge(len));
+        }
+        let (hdr_bytes, data) = self.rx_packet[..len].split_at(VirtioNetHdrMrgRxbuf::LEN);
+
+        let mut hdr = VirtioNetHdrMrgRxbuf::default();
+        hdr.as_mut_slice().copy_from_slice(hdr_bytes);
+        // Each packet is placed in a single chain.
+        hdr.num_buffers = 1;
+        let buffers = hdr.write_to_chain(chain).map_err(Error::Header)?;
+
+        let capacity: usize = buffers.iter().map(|&(_, len)| len as usize).sum();
+        if capacity < data.len() {
+            return Err(Error::PacketTooLarge(len));
+        }
+        write_buffers(chain, &buffers, data)?;
+        // The packet length fits in an `u32`, since it's bounded by `MAX_PACKET_LEN`.
+        Ok(len as u32)
+    }
+
+    // Spreads the first `len` bytes of `rx_packet` over as many receive chains as needed, when
+    // `VIRTIO_NET_F_MRG_RXBUF` is negotiated. Returns the `(head_index, len)` pairs of the used
+    // chains, or `None` if there are not enough available buffers, in which case the chains
+    // filled so far are kept for the next attempt.
+    fn receive_mergeable(&mut self, len: usize) -> Result<Option<Vec<(u16, u32)>>> {
+        loop {
+            let mut chain = match self.rx_queue.iter()?.next() {
+                Some(chain) => chain,
+                None => return Ok(None),
+            };
+            match self.fill_mergeable_chain(&mut chain, len) {
+                Ok(written) => {
+                    self.rx_used.push((chain.head_index(), written));
+                    if self.rx_written == len {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    warn!("failed to receive packet: {}", e);
+                    // The packet is dropped, and the chains are returned to the driver.
+                    self.rx_used.push((chain.head_index(), 0));
+                    self.rx_used.iter_mut().for_each(|(_, len)| *len = 0);
+                    break;
+                }
+            }
+        }
+
+        self.rx_written = 0;
+        self.rx_hdr_buffers.clear();
+        Ok(Some(mem::take(&mut self.rx_used)))
+    }
+
+    // Writes the next part of the pending packet to a receive chain, and returns the number of
+    // bytes written. The header is written again once the last chain is filled, since that's
+    // when `num_buffers` is known.
+    fn fill_mergeable_chain(&mut self, chain: &mut DescriptorChain<M>, len: usize) -> Result<u32> {
+        let buffers = writable_buffers(chain)?;
+        let capacity: usize = buffers.iter().map(|&(_, len)| len as usize).sum();
+        if self.rx_used.is_empty() {
+            // The header can't be split across chains.
+            if capacity < VirtioNetHdrMrgRxbuf::LEN {
+                return Err(Error::PacketTooLarge(len));
+            }
+            self.rx_hdr_buffers = buffers.clone();
+        }
+
+        let written = capacity.min(len - self.rx_written);
+        let start = self.rx_written;
+        write_buffers(chain, &buffers, &self.rx_packet[start..start + written])?;
+        self.rx_written += written;
+
+        if self.rx_written == len {
+            // The number of chains is bounded by the queue size.
+            let num_buffers = (self.rx_used.len() as u16 + 1).to_le_bytes();
+            let offset = VirtioNetHdr::LEN;
+            self.rx_packet[offset..offset + num_buffers.len()].copy_from_slice(&num_buffers);
+            write_buffers(
+                chain,
+                &self.rx_hdr_buffers,
+                &self.rx_packet[..VirtioNetHdrMrgRxbuf::LEN],
+            )?;
+        }
+        // The written length fits in an `u32`, since it's bounded by `MAX_PACKET_LEN`.
+        Ok(written as u32)
+    }
+}
+
+// Returns the buffers of a receive chain, which can only contain device-writable descriptors.
+fn writable_buffers<M: GuestAddressSpace>(
+    chain: &mut DescriptorChain<M>,
+) -> Result<Vec<(GuestAddress, u32)>> {
+    chain
+        .map(|desc| {
+            if desc.is_write_only() {
+                Ok((desc.addr(), desc.len()))
+            } else {
+                Err(Error::Header(header::Error::UnexpectedReadOnlyDescriptor))
+            }
+        })
+        .collect()
+}
+
+// Scatters `data` to the guest memory `buffers` of a chain, which are large enough to hold it.
+fn write_buffers<M: GuestAddressSpace>(
+    chain: &DescriptorChain<M>,
+    buffers: &[(GuestAddress, u32)],
+    mut data: &[u8],
+) -> Result<()> {
+    for &(addr, len) in buffers {
+        if data.is_empty() {
+            break;
+        }
+        let (chunk, rest) = data.split_at(data.len().min(len as usize));
+        chain
+            .memory()
+            .write_slice(chunk, addr)
+            .map_err(Error::GuestMemory)?;
+        data = rest;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use vm_memory::{Address, GuestMemoryMmap};
+
+    use virtio_device::rate_limiter::TokenBucket;
+    use virtio_queue::mock::VirtQueue;
+    use virtio_queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    // A TAP device stand-in, which keeps the packets in memory.
+    #[derive(Debug, Default)]
+    pub(crate) struct TestTap {
+        // The packets which can be read by the device.
+        pub rx: VecDeque<Vec<u8>>,
+        // The packets written by the device.
+        pub tx: Vec<Vec<u8>>,
+    }
+
+    impl Read for TestTap {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let packet = self
+                .rx
+                .pop_front()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))?;
+            buf[..packet.len()].copy_from_slice(&packet);
+            Ok(packet.len())
+        }
+    }
+
+    impl Write for TestTap {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.tx.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Records the indices of the signalled queues.
+    #[derive(Debug, Default)]
+    struct TestSignal(RefCell<Vec<u16>>);
+
+    impl SignalUsedQueue for TestSignal {
+        fn signal_used_queue(&self, index: u16) {
+            self.0.borrow_mut().push(index);
+        }
+    }
+
+    // Returns the `(id, len)` pair of a used ring element.
+    pub(crate) fn used_elem(mem: &GuestMemoryMmap, vq: &VirtQueue, index: u16) -> (u32, u32) {
+        let addr = vq.used_start().unchecked_add(4 + 8 * u64::from(index));
+        (
+            mem.read_obj(addr).unwrap(),
+            mem.read_obj(addr.unchecked_add(4)).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_process_tx() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let rxq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let txq = VirtQueue::new(GuestAddress(0x1000), &mem, 16);
+        let mut handler = QueuePairHandler::new(
+            rxq.create_queue(&mem),
+            txq.create_queue(&mem),
+            TestTap::default(),
+            TestSignal::default(),
+        )
+        .with_pair_index(1);
+
+        let hdr = VirtioNetHdrMrgRxbuf::default();
+        mem.write_obj(hdr, GuestAddress(0x1_0000)).unwrap();
+        mem.write_slice(&[0xaa; 0x40], GuestAddress(0x2_0000))
+            .unwrap();
+        mem.write_slice(&[0xbb; 0x20], GuestAddress(0x3_0000))
+            .unwrap();
+        // A packet split across two buffers, following the header.
+        txq.dtable(0).set(
+            0x1_0000,
+            VirtioNetHdrMrgRxbuf::LEN as u32,
+            VIRTQ_DESC_F_NEXT,
+            1,
+        );
+        txq.dtable(1).set(0x2_0000, 0x40, VIRTQ_DESC_F_NEXT, 2);
+        txq.dtable(2).set(0x3_0000, 0x20, 0, 0);
+        // A malformed packet, which is dropped.
+        mem.write_obj(0xffu8, GuestAddress(0x4_0000)).unwrap();
+        txq.dtable(3)
+            .set(0x4_0000, VirtioNetHdrMrgRxbuf::LEN as u32, 0, 0);
+        txq.avail.ring(0).store(0);
+        txq.avail.ring(1).store(3);
+        txq.avail.idx().store(2);
+
+        handler.process_tx().unwrap();
+        assert_eq!(txq.used.idx().load(), 2);
+        assert_eq!(used_elem(&mem, &txq, 0), (0, 0));
+        assert_eq!(handler.tap().tx.len(), 1);
+        let packet = &handler.tap().tx[0];
+        assert_eq!(packet.len(), VirtioNetHdrMrgRxbuf::LEN + 0x60);
+        assert_eq!(packet[..VirtioNetHdrMrgRxbuf::LEN], *hdr.as_slice());
+        assert_eq!(packet[VirtioNetHdrMrgRxbuf::LEN..][..0x40], [0xaa; 0x40]);
+        assert_eq!(packet[VirtioNetHdrMrgRxbuf::LEN + 0x40..], [0xbb; 0x20]);
+        assert_eq!(*handler.driver_notify.0.borrow(), vec![3, 3]);
+
+        // The header is stripped when the TAP device doesn't use it.
+        let txq = VirtQueue::new(GuestAddress(0x2000), &mem, 16);
+        let mut handler = QueuePairHandler::new(
+            rxq.create_queue(&mem),
+            txq.create_queue(&mem),
+            TestTap::default(),
+            TestSignal::default(),
+        )
+        .with_vnet_hdr(false);
+        txq.dtable(0).set(
+            0x1_0000,
+            VirtioNetHdrMrgRxbuf::LEN as u32,
+            VIRTQ_DESC_F_NEXT,
+            1,
+        );
+        txq.dtable(1).set(0x2_0000, 0x40, VIRTQ_DESC_F_NEXT, 2);
+        txq.dtable(2).set(0x3_0000, 0x20, 0, 0);
+        txq.avail.ring(0).store(0);
+        txq.avail.idx().store(1);
+        handler.process_tx().unwrap();
+        assert_eq!(handler.tap().tx[0].len(), 0x60);
+    }
+
+    #[test]
+    fn test_packet_capture() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let rxq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let txq = VirtQueue::new(GuestAddress(0x1000), &mem, 16);
+        let mut tap = TestTap::default();
+        tap.rx.push_back(vec![0xcc; 0x30]);
+        let mut handler = QueuePairHandler::new(
+            rxq.create_queue(&mem),
+            txq.create_queue(&mem),
+            tap,
+            TestSignal::default(),
+        )
+        .with_pair_index(2)
+        .with_vnet_hdr(false);
+
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let sink = frames.clone();
+        handler.set_packet_capture(Some(Arc::new(Mutex::new(
+            move |pair, direction, frame: &[u8]| {
+                sink.lock().unwrap().push((pair, direction, frame.to_vec()))
+            },
+        ))));
+
+        mem.write_obj(VirtioNetHdrMrgRxbuf::default(), GuestAddress(0x1_0000))
+            .unwrap();
+        mem.write_slice(&[0xaa; 0x40], GuestAddress(0x2_0000))
+            .unwrap();
+        txq.dtable(0).set(
+            0x1_0000,
+            VirtioNetHdrMrgRxbuf::LEN as u32,
+            VIRTQ_DESC_F_NEXT,
+            1,
+        );
+        txq.dtable(1).set(0x2_0000, 0x40, 0, 0);
+        txq.avail.ring(0).store(0);
+        txq.avail.idx().store(1);
+        rxq.dtable(0).set(0x3_0000, 0x100, VIRTQ_DESC_F_WRITE, 0);
+        rxq.avail.ring(0).store(0);
+        rxq.avail.idx().store(1);
+
+        handler.process_tx().unwrap();
+        handler.process_rx().unwrap();
+        // The frames are captured without the network header.
+        assert_eq!(
+            *frames.lock().unwrap(),
+            vec![
+                (2, Direction::Tx, vec![0xaa; 0x40]),
+                (2, Direction::Rx, vec![0xcc; 0x30])
+            ]
+        );
+
+        // Nothing is captured once the capture is removed.
+        handler.set_packet_capture(None);
+        handler.tap_mut().rx.push_back(vec![0xdd; 0x30]);
+        handler.process_rx().unwrap();
+        assert_eq!(frames.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_process_rx() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let rxq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let txq = VirtQueue::new(GuestAddress(0x1000), &mem, 16);
+        let mut tap = TestTap::default();
+
+        let hdr = VirtioNetHdrMrgRxbuf::new(
+            VirtioNetHdr {
+                flags: 2,
+                ..Default::default()
+            },
+            0,
+        );
+        let mut packet = hdr.as_slice().to_vec();
+        packet.extend_from_slice(&[0xcc; 0x80]);
+        tap.rx.push_back(packet.clone());
+        tap.rx.push_back(packet);
+
+        let mut handler = QueuePairHandler::new(
+            rxq.create_queue(&mem),
+            txq.create_queue(&mem),
+            tap,
+            TestSignal::default(),
+        );
+
+        // There are no buffers yet, so the first packet is kept.
+        handler.process_rx().unwrap();
+        assert!(handler.has_pending_rx());
+        assert_eq!(handler.tap().rx.len(), 1);
+        assert_eq!(rxq.used.idx().load(), 0);
+
+        // The header and the first part of the packet share the first buffer.
+        rxq.dtable(0)
+            .set(0x1_0000, 0x40, VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT, 1);
+        rxq.dtable(1).set(0x2_0000, 0x100, VIRTQ_DESC_F_WRITE, 0);
+        // A chain which is too small for the second packet.
+        rxq.dtable(2).set(0x3_0000, 0x20, VIRTQ_DESC_F_WRITE, 0);
+        rxq.avail.ring(0).store(0);
+        rxq.avail.ring(1).store(2);
+        rxq.avail.idx().store(2);
+
+        handler.process_rx().unwrap();
+        assert!(!handler.has_pending_rx());
+        assert_eq!(rxq.used.idx().load(), 2);
+        assert_eq!(
+            used_elem(&mem, &rxq, 0),
+            (0, (VirtioNetHdrMrgRxbuf::LEN + 0x80) as u32)
+        );
+        assert_eq!(used_elem(&mem, &rxq, 1), (2, 0));
+
+        let written: VirtioNetHdrMrgRxbuf = mem.read_obj(GuestAddress(0x1_0000)).unwrap();
+        assert_eq!(written, VirtioNetHdrMrgRxbuf::new(hdr.hdr, 1));
+        let mut data = [0u8; 0x80];
+        mem.read_slice(&mut data[..0x34], GuestAddress(0x1_000c))
+            .unwrap();
+        mem.read_slice(&mut data[0x34..], GuestAddress(0x2_0000))
+            .unwrap();
+        assert_eq!(data, [0xcc; 0x80]);
+        assert_eq!(*handler.driver_notify.0.borrow(), vec![0, 0]);
+    }
+
+    #[test]
+    fn test_rate_limiting() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let rxq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let txq = VirtQueue::new(GuestAddress(0x1000), &mem, 16);
+        let mut tap = TestTap::default();
+        tap.rx.push_back(vec![0xcc; 0x40]);
+        tap.rx.push_back(vec![0xdd; 0x40]);
+
+        // One packet every 100 ms in each direction.
+        let rate_limiter =
+            || RateLimiter::new(TokenBucket::new(1, Duration::from_millis(100)), None).unwrap();
+        let mut handler = QueuePairHandler::new(
+            rxq.create_queue(&mem),
+            txq.create_queue(&mem),
+            tap,
+            TestSignal::default(),
+        )
+        .with_vnet_hdr(false)
+        .with_rx_rate_limiter(rate_limiter())
+        .with_tx_rate_limiter(rate_limiter());
+        assert!(handler.rx_rate_limiter().is_some());
+
+        for i in 0..2 {
+            let addr = 0x1_0000 + u64::from(i) * 0x1000;
+            txq.dtable(i).set(addr, 0x40, 0, 0);
+            txq.avail.ring(i).store(i);
+            rxq.dtable(i)
+                .set(addr + 0x8_0000, 0x100, VIRTQ_DESC_F_WRITE, 0);
+            rxq.avail.ring(i).store(i);
+        }
+        txq.avail.idx().store(2);
+        rxq.avail.idx().store(2);
+
+        // The second packet is left in the transmit queue.
+        handler.process_tx().unwrap();
+        assert_eq!(txq.used.idx().load(), 1);
+        assert_eq!(handler.tx_queue().next_avail(), 1);
+        assert_eq!(handler.tap().tx.len(), 1);
+        assert!(handler.tx_rate_limiter().unwrap().is_blocked());
+
+        // The second packet waits in the handler.
+        handler.process_rx().unwrap();
+        assert_eq!(rxq.used.idx().load(), 1);
+        assert!(handler.has_pending_rx());
+        assert!(handler.tap().rx.is_empty());
+
+        // Both packets go through once the buckets are replenished.
+        handler.process_tx_rate_limiter_event().unwrap();
+        assert_eq!(txq.used.idx().load(), 2);
+        assert_eq!(handler.tap().tx.len(), 2);
+        handler.process_rx_rate_limiter_event().unwrap();
+        assert_eq!(rxq.used.idx().load(), 2);
+        assert!(!handler.has_pending_rx());
+        assert_eq!(
+            used_elem(&mem, &rxq, 1),
+            (1, (VirtioNetHdrMrgRxbuf::LEN + 0x40) as u32)
+        );
+    }
+
+    #[test]
+    fn test_process_rx_mergeable() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let rxq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let txq = VirtQueue::new(GuestAddress(0x1000), &mem, 16);
+        let mut tap = TestTap::default();
+
+        let mut packet = VirtioNetHdrMrgRxbuf::default().as_slice().to_vec();
+        packet.extend_from_slice(&[0xee; 0x100]);
+        tap.rx.push_back(packet);
+
+        let mut handler = QueuePairHandler::new(
+            rxq.create_queue(&mem),
+            txq.create_queue(&mem),
+            tap,
+            TestSignal::default(),
+        )
+        .with_mergeable_rx_buffers(true);
+
+        for i in 0..3 {
+            rxq.dtable(i).set(
+                0x1_0000 + u64::from(i) * 0x1000,
+                0x80,
+                VIRTQ_DESC_F_WRITE,
+                0,
+            );
+            rxq.avail.ring(i).store(i);
+        }
+
+        // Two chains are not enough for the packet, so it waits for more buffers.
+        rxq.avail.idx().store(2);
+        handler.process_rx().unwrap();
+        assert!(handler.has_pending_rx());
+        assert_eq!(handler.rx_queue().next_avail(), 2);
+        assert_eq!(rxq.used.idx().load(), 0);
+
+        rxq.avail.idx().store(3);
+        handler.process_rx().unwrap();
+        assert!(!handler.has_pending_rx());
+        // All the chains are published at once, and the driver is notified only once.
+        assert_eq!(rxq.used.idx().load(), 3);
+        assert_eq!(used_elem(&mem, &rxq, 0), (0, 0x80));
+        assert_eq!(used_elem(&mem, &rxq, 1), (1, 0x80));
+        assert_eq!(
+            used_elem(&mem, &rxq, 2),
+            (2, VirtioNetHdrMrgRxbuf::LEN as u32)
+        );
+        assert_eq!(*handler.driver_notify.0.borrow(), vec![0]);
+
+        let written: VirtioNetHdrMrgRxbuf = mem.read_obj(GuestAddress(0x1_0000)).unwrap();
+        assert_eq!({ written.num_buffers }, 3);
+        let mut data = [0u8; 0x100];
+        mem.read_slice(&mut data[..0x74], GuestAddress(0x1_000c))
+            .unwrap();
+        mem.read_slice(&mut data[0x74..0xf4], GuestAddress(0x1_1000))
+            .unwrap();
+        mem.read_slice(&mut data[0xf4..], GuestAddress(0x1_2000))
+            .unwrap();
+        assert_eq!(data, [0xee; 0x100]);
+
+        // A read only descriptor causes the packet to be dropped.
+        let mut packet = VirtioNetHdrMrgRxbuf::default().as_slice().to_vec();
+        packet.extend_from_slice(&[0xee; 0x100]);
+        handler.tap_mut().rx.push_back(packet);
+        rxq.dtable(3).set(0x1_3000, 0x80, VIRTQ_DESC_F_WRITE, 0);
+        rxq.dtable(4).set(0x1_4000, 0x80, 0, 0);
+        rxq.avail.ring(3).store(3);
+        rxq.avail.ring(4).store(4);
+        rxq.avail.idx().store(5);
+        handler.process_rx().unwrap();
+        assert!(!handler.has_pending_rx());
+        assert_eq!(rxq.used.idx().load(), 5);
+        assert_eq!(used_elem(&mem, &rxq, 3), (3, 0));
+        assert_eq!(used_elem(&mem, &rxq, 4), (4, 0));
+    }
+}
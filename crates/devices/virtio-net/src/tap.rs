@@ -0,0 +1,253 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! TAP device abstraction.
+//!
+//! This module provides the [`Tap`](struct.Tap.html) abstraction, which wraps a queue of a TAP
+//! interface. The queues are opened in non-blocking mode with `IFF_VNET_HDR`, so each packet is
+//! exchanged along with its virtio network header, and with `IFF_MULTI_QUEUE`, so a separate
+//! queue can be attached to every receive/transmit queue pair of a network device.
+//!
+//! The MTU of the interface can be queried with [`Tap::mtu`](struct.Tap.html#method.mtu), i.e.
+//! to check that it matches the one advertised to the driver.
+
+use std::convert::TryFrom;
+use std::fmt::{self, Display};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::raw::{c_int, c_short, c_ulong};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::result;
+
+use vmm_sys_util::ioctl::ioctl_with_mut_ref;
+
+// The path of the TUN/TAP clone device.
+const TUN_PATH: &str = "/dev/net/tun";
+// The maximum length of an interface name, including the terminating null byte.
+const IFNAMSIZ: usize = 16;
+
+// TUN/TAP interface flags (from `linux/if_tun.h`).
+const IFF_TAP: c_short = 0x0002;
+const IFF_NO_PI: c_short = 0x1000;
+const IFF_VNET_HDR: c_short = 0x4000;
+const IFF_MULTI_QUEUE: c_short = 0x0100;
+
+// Gets the MTU of an interface (from `linux/sockios.h`). Socket ioctls don't follow the
+// `_IOC` encoding, so the request number is used as is.
+const SIOCGIFMTU: c_ulong = 0x8921;
+
+// The ioctls are declared in a private module, since the generated functions are public.
+mod ioctls {
+    use std::os::raw::{c_int, c_uint};
+
+    const TUNTAP: c_uint = 0x54;
+
+    vmm_sys_util::ioctl_iow_nr!(TUNSETIFF, TUNTAP, 202, c_int);
+}
+
+/// TAP device errors.
+#[derive(Debug)]
+pub enum Error {
+    /// The interface name is too long, or contains a null byte.
+    InvalidIfName,
+    /// The number of queues is 0.
+    InvalidNumQueues,
+    /// Failed to get the MTU of the TAP interface.
+    GetMtu(io::Error),
+    /// Failed to open the TUN/TAP clone device.
+    OpenTun(io::Error),
+    /// Failed to attach to the TAP interface.
+    SetIff(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            GetMtu(ref err) => write!(f, "failed to get the MTU of the TAP interface: {}", err),
+            InvalidIfName => write!(f, "invalid interface name"),
+            InvalidNumQueues => write!(f, "invalid number of queues"),
+            OpenTun(ref err) => write!(f, "failed to open {}: {}", TUN_PATH, err),
+            SetIff(ref err) => write!(f, "failed to attach to the TAP interface: {}", err),
+        }
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+// The `struct ifreq` layout used by `TUNSETIFF`, which only looks at the name and the flags.
+#[repr(C)]
+#[derive(Default)]
+struct IfReq {
+    ifr_name: [u8; IFNAMSIZ],
+    ifr_flags: c_short,
+    // The rest of the `ifr_ifru` union.
+    padding: [u8; 22],
+}
+
+// The `struct ifreq` layout used by `SIOCGIFMTU`.
+#[repr(C)]
+#[derive(Default)]
+struct IfReqMtu {
+    ifr_name: [u8; IFNAMSIZ],
+    ifr_mtu: c_int,
+    // The rest of the `ifr_ifru` union.
+    padding: [u8; 20],
+}
+
+/// A queue of a TAP interface.
+///
+/// Reads and writes transfer a single packet (preceded by its network header), and don't
+/// block: a read returns `io::ErrorKind::WouldBlock` when no packet is available.
+#[derive(Debug)]
+pub struct Tap {
+    file: File,
+    if_name: String,
+}
+
+impl Tap {
+    /// Opens `num_queues` queues of the TAP interface named `if_name`, which is created if it
+    /// doesn't exist yet. Each queue is meant to back one receive/transmit queue pair of a
+    /// network device.
+    ///
+    /// # Arguments
+    /// * `if_name` - The name of the TAP interface.
+    /// * `num_queues` - The number of queues.
+    pub fn open_queues(if_name: &str, num_queues: usize) -> Result<Vec<Tap>> {
+        if num_queues == 0 {
+            return Err(Error::InvalidNumQueues);
+        }
+        if if_name.len() >= IFNAMSIZ || if_name.as_bytes().contains(&0) {
+            return Err(Error::InvalidIfName);
+        }
+
+        let mut flags = IFF_TAP | IFF_NO_PI | IFF_VNET_HDR;
+        if num_queues > 1 {
+            flags |= IFF_MULTI_QUEUE;
+        }
+        (0..num_queues)
+            .map(|_| Self::open_queue(if_name, flags))
+            .collect()
+    }
+
+    /// Opens a single queue of the TAP interface named `if_name`.
+    ///
+    /// # Arguments
+    /// * `if_name` - The name of the TAP interface.
+    pub fn open(if_name: &str) -> Result<Tap> {
+        Self::open_queues(if_name, 1).map(|mut taps| taps.remove(0))
+    }
+
+    fn open_queue(if_name: &str, flags: c_short) -> Result<Tap> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open(TUN_PATH)
+            .map_err(Error::OpenTun)?;
+
+        let mut ifreq = IfReq {
+            ifr_flags: flags,
+            ..Default::default()
+        };
+        ifreq.ifr_name[..if_name.len()].copy_from_slice(if_name.as_bytes());
+        // Safe because the kernel only accesses the `ifreq` structure, which lives for the
+        // duration of the call, and we check the return value.
+        let ret = unsafe { ioctl_with_mut_ref(&file, ioctls::TUNSETIFF(), &mut ifreq) };
+        if ret < 0 {
+            return Err(Error::SetIff(io::Error::last_os_error()));
+        }
+
+        // The kernel returns the actual name, which matters when `if_name` is a pattern.
+        let len = ifreq
+            .ifr_name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(IFNAMSIZ);
+        Ok(Tap {
+            file,
+            if_name: String::from_utf8_lossy(&ifreq.ifr_name[..len]).into_owned(),
+        })
+    }
+
+    /// Returns the name of the TAP interface.
+    pub fn if_name(&self) -> &str {
+        &self.if_name
+    }
+
+    /// Returns the MTU of the TAP interface.
+    pub fn mtu(&self) -> Result<u16> {
+        // The interface ioctls have to be issued on a socket.
+        // Safe because we check the return value.
+        let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(Error::GetMtu(io::Error::last_os_error()));
+        }
+        // Safe because we just created the socket, and nothing else owns it.
+        let socket = unsafe { File::from_raw_fd(fd) };
+
+        let mut ifreq = IfReqMtu::default();
+        ifreq.ifr_name[..self.if_name.len()].copy_from_slice(self.if_name.as_bytes());
+        // Safe because the kernel only accesses the `ifreq` structure, which lives for the
+        // duration of the call, and we check the return value.
+        let ret = unsafe { ioctl_with_mut_ref(&socket, SIOCGIFMTU, &mut ifreq) };
+        if ret < 0 {
+            return Err(Error::GetMtu(io::Error::last_os_error()));
+        }
+        u16::try_from(ifreq.ifr_mtu)
+            .map_err(|_| Error::GetMtu(io::Error::from(io::ErrorKind::InvalidData)))
+    }
+}
+
+impl Read for Tap {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for Tap {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for Tap {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::mem::size_of;
+
+    #[test]
+    fn test_ifreq() {
+        // The kernel copies a whole `struct ifreq`.
+        assert_eq!(size_of::<IfReq>(), 40);
+        assert_eq!(size_of::<IfReqMtu>(), 40);
+    }
+
+    #[test]
+    fn test_open_invalid() {
+        assert!(matches!(
+            Tap::open_queues("tap0", 0),
+            Err(Error::InvalidNumQueues)
+        ));
+        assert!(matches!(
+            Tap::open("a-very-long-interface-name"),
+            Err(Error::InvalidIfName)
+        ));
+        assert!(matches!(Tap::open("tap\0"), Err(Error::InvalidIfName)));
+    }
+}
@@ -0,0 +1,382 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! In-kernel vhost-net backend for the receive/transmit queue pairs.
+//!
+//! The vhost-net driver moves packets between the queues of a pair and a TAP device queue
+//! without exiting to the VMM. This module provides the
+//! [`VhostNet`](struct.VhostNet.html) abstraction, which wraps a `/dev/vhost-net` file
+//! descriptor along with the kick and call `EventFd`s of the two queues it serves. A network
+//! device built with [`NetBuilder::with_vhost`](../device/struct.NetBuilder.html#method.with_vhost)
+//! hands its queue pairs over to such backends when it's activated, while the control queue
+//! and the configuration space are still handled by the device.
+//!
+//! The guest memory regions are passed to the kernel as host virtual addresses, so the memory
+//! has to stay mapped at the same address while the device is activated.
+
+use std::fmt::{self, Display};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::raw::c_int;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::result;
+
+use vm_memory::{
+    Address, ByteValued, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryError,
+    GuestMemoryRegion, MemoryRegionAddress,
+};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+use vmm_sys_util::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ptr, ioctl_with_ref};
+
+use virtio_queue::Queue;
+
+// The path of the vhost-net device.
+const VHOST_NET_PATH: &str = "/dev/vhost-net";
+
+// The default limit of memory regions accepted by `VHOST_SET_MEM_TABLE`.
+const MAX_MEMORY_REGIONS: usize = 64;
+
+/// The vhost feature bit which makes the kernel add and strip the network headers, for TAP
+/// devices opened without `IFF_VNET_HDR`.
+pub const VHOST_NET_F_VIRTIO_NET_HDR: u64 = 27;
+
+/// The index of the receive queue within a pair.
+pub const RX_QUEUE: usize = 0;
+/// The index of the transmit queue within a pair.
+pub const TX_QUEUE: usize = 1;
+
+// The ioctls are declared in a private module, since the generated functions are public.
+mod ioctls {
+    use std::os::raw::c_uint;
+
+    use super::{VringAddr, VringFile, VringState};
+
+    const VHOST: c_uint = 0xaf;
+
+    vmm_sys_util::ioctl_ior_nr!(VHOST_GET_FEATURES, VHOST, 0x00, u64);
+    vmm_sys_util::ioctl_iow_nr!(VHOST_SET_FEATURES, VHOST, 0x00, u64);
+    vmm_sys_util::ioctl_io_nr!(VHOST_SET_OWNER, VHOST, 0x01);
+    // The size of `struct vhost_memory` doesn't include the regions.
+    vmm_sys_util::ioctl_iow_nr!(VHOST_SET_MEM_TABLE, VHOST, 0x03, u64);
+    vmm_sys_util::ioctl_iow_nr!(VHOST_SET_VRING_NUM, VHOST, 0x10, VringState);
+    vmm_sys_util::ioctl_iow_nr!(VHOST_SET_VRING_ADDR, VHOST, 0x11, VringAddr);
+    vmm_sys_util::ioctl_iow_nr!(VHOST_SET_VRING_BASE, VHOST, 0x12, VringState);
+    vmm_sys_util::ioctl_iow_nr!(VHOST_SET_VRING_KICK, VHOST, 0x20, VringFile);
+    vmm_sys_util::ioctl_iow_nr!(VHOST_SET_VRING_CALL, VHOST, 0x21, VringFile);
+    vmm_sys_util::ioctl_iow_nr!(VHOST_NET_SET_BACKEND, VHOST, 0x30, VringFile);
+}
+
+/// vhost-net backend errors.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to create an `EventFd`.
+    EventFd(io::Error),
+    /// Invalid guest memory access.
+    GuestMemory(GuestMemoryError),
+    /// A vhost-net ioctl failed.
+    Ioctl(io::Error),
+    /// Failed to open the vhost-net device.
+    Open(io::Error),
+    /// The guest memory has too many regions.
+    TooManyMemoryRegions(usize),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            EventFd(ref err) => write!(f, "eventfd error: {}", err),
+            GuestMemory(ref err) => write!(f, "error accessing guest memory: {}", err),
+            Ioctl(ref err) => write!(f, "vhost-net ioctl failed: {}", err),
+            Open(ref err) => write!(f, "failed to open {}: {}", VHOST_NET_PATH, err),
+            TooManyMemoryRegions(count) => write!(f, "too many guest memory regions: {}", count),
+        }
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+// The argument of the ioctls which configure a queue with a single value.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VringState {
+    index: u32,
+    num: u32,
+}
+
+// Safe because VringState contains only plain data.
+unsafe impl ByteValued for VringState {}
+
+// The argument of `VHOST_SET_VRING_ADDR`, which holds host virtual addresses.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VringAddr {
+    index: u32,
+    flags: u32,
+    desc: u64,
+    used: u64,
+    avail: u64,
+    log: u64,
+}
+
+// Safe because VringAddr contains only plain data.
+unsafe impl ByteValued for VringAddr {}
+
+// The argument of the ioctls which pass a file descriptor for a queue.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VringFile {
+    index: u32,
+    fd: i32,
+}
+
+// Safe because VringFile contains only plain data.
+unsafe impl ByteValued for VringFile {}
+
+/// A vhost-net kernel device, which processes the receive and transmit queues of a pair.
+#[derive(Debug)]
+pub struct VhostNet {
+    file: File,
+    // Used by the driver (or the VMM) to notify the kernel about available buffers.
+    kick_evts: Vec<EventFd>,
+    // Used by the kernel to notify the VMM about used buffers.
+    call_evts: Vec<EventFd>,
+}
+
+impl VhostNet {
+    /// Opens the vhost-net device and takes ownership of it for the current process.
+    pub fn open() -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open(VHOST_NET_PATH)
+            .map_err(Error::Open)?;
+        // Safe because the ioctl doesn't access memory, and we check the return value.
+        vhost_ioctl(unsafe { ioctl(&file, ioctls::VHOST_SET_OWNER()) })?;
+
+        let new_eventfds = || {
+            (0..2)
+                .map(|_| EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd))
+                .collect::<Result<Vec<_>>>()
+        };
+        Ok(VhostNet {
+            file,
+            kick_evts: new_eventfds()?,
+            call_evts: new_eventfds()?,
+        })
+    }
+
+    /// Returns the features supported by the kernel.
+    pub fn features(&self) -> Result<u64> {
+        let mut features = 0u64;
+        // Safe because the kernel only writes a `u64`, and we check the return value.
+        vhost_ioctl(unsafe {
+            ioctl_with_mut_ref(&self.file, ioctls::VHOST_GET_FEATURES(), &mut features)
+        })?;
+        Ok(features)
+    }
+
+    /// Returns the `EventFd` which notifies the kernel about the buffers made available in the
+    /// receive (`RX_QUEUE`) or transmit (`TX_QUEUE`) queue. It can be registered as an
+    /// ioeventfd for the queue.
+    ///
+    /// # Arguments
+    /// * `queue` - The index of the queue within the pair.
+    pub fn kick_eventfd(&self, queue: usize) -> Option<&EventFd> {
+        self.kick_evts.get(queue)
+    }
+
+    /// Returns the `EventFd` which the kernel uses for signaling used buffers in the receive
+    /// (`RX_QUEUE`) or transmit (`TX_QUEUE`) queue.
+    ///
+    /// # Arguments
+    /// * `queue` - The index of the queue within the pair.
+    pub fn call_eventfd(&self, queue: usize) -> Option<&EventFd> {
+        self.call_evts.get(queue)
+    }
+
+    // Sends the acknowledged features, the guest memory and the configuration of the `rx` and
+    // `tx` queues to the kernel. The queues don't process any buffers until a TAP device is
+    // attached.
+    pub(crate) fn setup<M: GuestAddressSpace>(
+        &self,
+        features: u64,
+        rx: &Queue<M>,
+        tx: &Queue<M>,
+        mem: &M::M,
+    ) -> Result<()> {
+        // Safe because the kernel only reads a `u64`, and we check the return value.
+        vhost_ioctl(unsafe {
+            ioctl_with_ref(&self.file, ioctls::VHOST_SET_FEATURES(), &features)
+        })?;
+
+        let table = memory_table(mem)?;
+        // Safe because the table holds the number of regions it's made of, so the kernel
+        // doesn't read past its end, and we check the return value.
+        vhost_ioctl(unsafe {
+            ioctl_with_ptr(&self.file, ioctls::VHOST_SET_MEM_TABLE(), table.as_ptr())
+        })?;
+
+        let host_addr = |addr: GuestAddress| {
+            mem.get_host_address(addr)
+                .map(|ptr| ptr as u64)
+                .map_err(Error::GuestMemory)
+        };
+        for (index, queue) in [rx, tx].iter().enumerate() {
+            // The queue index is either 0 or 1.
+            let index = index as u32;
+            let num = VringState {
+                index,
+                num: u32::from(queue.actual_size()),
+            };
+            let addr = VringAddr {
+                index,
+                desc: host_addr(queue.desc_table)?,
+                used: host_addr(queue.used_ring)?,
+                avail: host_addr(queue.avail_ring)?,
+                ..Default::default()
+            };
+            let base = VringState {
+                index,
+                num: u32::from(queue.next_avail()),
+            };
+            self.set_vring(ioctls::VHOST_SET_VRING_NUM(), &num)?;
+            self.set_vring(ioctls::VHOST_SET_VRING_ADDR(), &addr)?;
+            self.set_vring(ioctls::VHOST_SET_VRING_BASE(), &base)?;
+
+            let evts = [
+                (ioctls::VHOST_SET_VRING_KICK(), &self.kick_evts),
+                (ioctls::VHOST_SET_VRING_CALL(), &self.call_evts),
+            ];
+            for (request, evts) in evts.iter() {
+                let file = VringFile {
+                    index,
+                    fd: evts[index as usize].as_raw_fd(),
+                };
+                self.set_vring(*request, &file)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Attaches a TAP device queue to both queues of the pair, which starts processing them.
+    // When `tap` is `None`, the queues are stopped instead.
+    pub(crate) fn set_backend<T: AsRawFd>(&self, tap: Option<&T>) -> Result<()> {
+        let fd: RawFd = tap.map_or(-1, AsRawFd::as_raw_fd);
+        for &index in [RX_QUEUE, TX_QUEUE].iter() {
+            let file = VringFile {
+                // The queue index is either 0 or 1.
+                index: index as u32,
+                fd,
+            };
+            self.set_vring(ioctls::VHOST_NET_SET_BACKEND(), &file)?;
+        }
+        Ok(())
+    }
+
+    fn set_vring<A: ByteValued>(&self, request: libc::c_ulong, arg: &A) -> Result<()> {
+        // Safe because the kernel only reads an object of the size encoded in `request`, which
+        // is the size of `A`, and we check the return value.
+        vhost_ioctl(unsafe { ioctl_with_ref(&self.file, request, arg) })
+    }
+}
+
+impl AsRawFd for VhostNet {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+// Builds the argument of `VHOST_SET_MEM_TABLE`: the number of regions (followed by 4 bytes of
+// padding), and then the guest address, the size, the host address and the flags (unused)
+// of each region.
+fn memory_table<G: GuestMemory>(mem: &G) -> Result<Vec<u64>> {
+    let count = mem.num_regions();
+    if count > MAX_MEMORY_REGIONS {
+        return Err(Error::TooManyMemoryRegions(count));
+    }
+
+    let mut table = vec![count as u64];
+    mem.with_regions_mut(|_, region| {
+        let host_addr = region
+            .get_host_address(MemoryRegionAddress(0))
+            .map_err(Error::GuestMemory)?;
+        table.extend_from_slice(&[
+            region.start_addr().raw_value(),
+            region.len(),
+            host_addr as u64,
+            0,
+        ]);
+        Ok(())
+    })?;
+    Ok(table)
+}
+
+// Converts the return value of an ioctl to a `Result`.
+fn vhost_ioctl(ret: c_int) -> Result<()> {
+    if ret < 0 {
+        return Err(Error::Ioctl(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::mem::size_of;
+
+    use vm_memory::GuestMemoryMmap;
+
+    #[test]
+    fn test_ioctl_layout() {
+        assert_eq!(size_of::<VringState>(), 8);
+        assert_eq!(size_of::<VringAddr>(), 40);
+        assert_eq!(size_of::<VringFile>(), 8);
+
+        // The request numbers from `linux/vhost.h`.
+        assert_eq!(ioctls::VHOST_GET_FEATURES(), 0x8008_af00);
+        assert_eq!(ioctls::VHOST_SET_OWNER(), 0xaf01);
+        assert_eq!(ioctls::VHOST_SET_MEM_TABLE(), 0x4008_af03);
+        assert_eq!(ioctls::VHOST_SET_VRING_ADDR(), 0x4028_af11);
+        assert_eq!(ioctls::VHOST_SET_VRING_BASE(), 0x4008_af12);
+        assert_eq!(ioctls::VHOST_NET_SET_BACKEND(), 0x4008_af30);
+    }
+
+    #[test]
+    fn test_memory_table() {
+        let mem = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x1_0000),
+            (GuestAddress(0x10_0000), 0x2_0000),
+        ])
+        .unwrap();
+
+        let table = memory_table(&mem).unwrap();
+        assert_eq!(table.len(), 1 + 2 * 4);
+        assert_eq!(table[0], 2);
+        assert_eq!(table[1..3], [0, 0x1_0000]);
+        assert_eq!(
+            table[3],
+            mem.get_host_address(GuestAddress(0)).unwrap() as u64
+        );
+        assert_eq!(table[5..7], [0x10_0000, 0x2_0000]);
+        assert_eq!(
+            table[7],
+            mem.get_host_address(GuestAddress(0x10_0000)).unwrap() as u64
+        );
+
+        let ranges: Vec<_> = (0..=MAX_MEMORY_REGIONS as u64)
+            .map(|i| (GuestAddress(i * 0x1_0000), 0x1000))
+            .collect();
+        let mem = GuestMemoryMmap::from_ranges(&ranges).unwrap();
+        assert!(matches!(
+            memory_table(&mem),
+            Err(Error::TooManyMemoryRegions(count)) if count == MAX_MEMORY_REGIONS + 1
+        ));
+    }
+}
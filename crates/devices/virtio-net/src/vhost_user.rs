@@ -0,0 +1,1069 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A virtio network device frontend for external vhost-user-net backends.
+//!
+//! This module provides the following abstractions:
+//!
+//! - [`VhostUserNet`](struct.VhostUserNet.html) which implements the `VirtioDevice` and
+//!   `VirtioMmioDevice` interfaces, and forwards the receive/transmit queue pairs over a Unix
+//!   socket to a vhost-user-net backend (such as a DPDK or Open vSwitch vhost-user port),
+//!   which accesses the guest memory directly.
+//! - [`VhostUserNetBuilder`](struct.VhostUserNetBuilder.html) which creates a `VhostUserNet`
+//!   device, based on the features offered by the backend.
+//!
+//! The queues are laid out the same way as for the [`Net`](../device/struct.Net.html) device,
+//! and the configuration space (the MAC address, the link status, the number of queue pairs and
+//! the MTU) is provided by the device itself, since most backends don't support
+//! `VHOST_USER_PROTOCOL_F_CONFIG`. The offloads and the other virtio features are the ones
+//! offered by the backend.
+//!
+//! By default, the control queue (which is only present for multiqueue devices) is processed
+//! by the device: when the driver selects the number of queue pairs it uses, the pairs are
+//! enabled or disabled in the backend. Backends which implement the control queue themselves
+//! (i.e. to support receive filtering) can process it instead, see
+//! [`VhostUserNetBuilder::with_ctrl_queue_forwarding`](struct.VhostUserNetBuilder.html#method.with_ctrl_queue_forwarding).
+//!
+//! The backend is notified through one kick `EventFd` for each forwarded queue. The VMM is
+//! expected to register them as ioeventfds (see
+//! [`VhostUserNet::kick_eventfd`](struct.VhostUserNet.html#method.kick_eventfd)), or to rely on
+//! the `VirtioMmioDevice::queue_notify` implementation, which triggers them as well and
+//! processes the control queue when it's handled by the device. The backend signals used
+//! buffers through one call `EventFd` for each forwarded queue, and the VMM has to call
+//! [`VhostUserNet::process_call_event`](struct.VhostUserNet.html#method.process_call_event)
+//! when one of them becomes readable.
+
+use std::fmt::{self, Display};
+use std::io;
+use std::mem;
+use std::os::unix::net::UnixStream;
+use std::result;
+
+use log::{error, warn};
+use vm_memory::GuestAddressSpace;
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+use virtio_device::vhost_user::{self, VhostUserFrontend};
+use virtio_device::{
+    SignalConfigChange, SignalUsedQueue, VirtioConfig, VirtioDeviceActions, VirtioDeviceCommon,
+    VirtioMmioDevice,
+};
+use virtio_queue::{self, Queue};
+
+use crate::config::{self, ConfigBuilder, ConfigSpace};
+use crate::ctrl_queue::CtrlRequest;
+use crate::defs::{
+    DEFAULT_QUEUE_SIZE, VIRTIO_ID_NET, VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN,
+    VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET, VIRTIO_NET_ERR, VIRTIO_NET_F_CTRL_GUEST_OFFLOADS,
+    VIRTIO_NET_F_CTRL_MAC_ADDR, VIRTIO_NET_F_CTRL_RX, VIRTIO_NET_F_CTRL_VLAN, VIRTIO_NET_F_CTRL_VQ,
+    VIRTIO_NET_F_GUEST_ANNOUNCE, VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ, VIRTIO_NET_F_MTU,
+    VIRTIO_NET_F_STATUS, VIRTIO_NET_OK, VIRTIO_NET_S_LINK_UP,
+};
+
+pub use virtio_device::vhost_user::{
+    VHOST_USER_F_PROTOCOL_FEATURES, VHOST_USER_PROTOCOL_F_MQ, VHOST_USER_PROTOCOL_F_REPLY_ACK,
+};
+
+// The features which depend on the control queue commands supported by the backend.
+const CTRL_FEATURES: u64 = (1 << VIRTIO_NET_F_CTRL_VQ)
+    | (1 << VIRTIO_NET_F_CTRL_RX)
+    | (1 << VIRTIO_NET_F_CTRL_VLAN)
+    | (1 << VIRTIO_NET_F_CTRL_GUEST_OFFLOADS)
+    | (1 << VIRTIO_NET_F_GUEST_ANNOUNCE)
+    | (1 << VIRTIO_NET_F_CTRL_MAC_ADDR);
+// The features which depend on the configuration space provided by the device.
+const CONFIG_FEATURES: u64 = (1 << VIRTIO_NET_F_MAC)
+    | (1 << VIRTIO_NET_F_STATUS)
+    | (1 << VIRTIO_NET_F_MQ)
+    | (1 << VIRTIO_NET_F_MTU);
+
+/// vhost-user network device errors.
+#[derive(Debug)]
+pub enum Error {
+    /// The device was already activated.
+    AlreadyActivated,
+    /// Invalid configuration space parameters.
+    Config(config::Error),
+    /// Failed to process the control queue.
+    CtrlQueue(virtio_queue::Error),
+    /// The control queue can't be forwarded, since the backend doesn't support it.
+    CtrlQueueUnsupported,
+    /// Failed to create or use an `EventFd`.
+    EventFd(io::Error),
+    /// The queues are not configured properly.
+    InvalidQueues,
+    /// The queue index is not valid.
+    InvalidQueueIndex(u16),
+    /// Failed to communicate with the backend.
+    VhostUser(vhost_user::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            AlreadyActivated => write!(f, "the device is already activated"),
+            Config(ref err) => write!(f, "invalid configuration: {}", err),
+            CtrlQueue(ref err) => write!(f, "failed to process the control queue: {}", err),
+            CtrlQueueUnsupported => write!(f, "the backend doesn't support the control queue"),
+            EventFd(ref err) => write!(f, "eventfd error: {}", err),
+            InvalidQueues => write!(f, "invalid queue configuration"),
+            InvalidQueueIndex(index) => write!(f, "invalid queue {}", index),
+            VhostUser(ref err) => write!(f, "vhost-user error: {}", err),
+        }
+    }
+}
+
+impl From<vhost_user::Error> for Error {
+    fn from(e: vhost_user::Error) -> Self {
+        Error::VhostUser(e)
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Configures and builds a `VhostUserNet` device.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # use std::os::unix::net::UnixStream;
+/// # use std::sync::Arc;
+/// # use virtio_net::vhost_user::VhostUserNetBuilder;
+/// # use vm_memory::{FileOffset, GuestAddress, GuestMemoryMmap};
+/// # use vmm_sys_util::eventfd::EventFd;
+/// # use vmm_sys_util::tempfile::TempFile;
+/// // The guest memory has to be shared with the backend.
+/// let file = TempFile::new().unwrap().into_file();
+/// file.set_len(0x10_0000).unwrap();
+/// let mem = Arc::new(
+///     GuestMemoryMmap::from_ranges_with_files(&[(
+///         GuestAddress(0),
+///         0x10_0000,
+///         Some(FileOffset::new(file, 0)),
+///     )])
+///     .unwrap(),
+/// );
+///
+/// let stream = UnixStream::connect("/tmp/vhost-user-net.sock").unwrap();
+/// let net = VhostUserNetBuilder::new(mem, stream, EventFd::new(0).unwrap())
+///     .with_queue_pairs(2)
+///     .with_mac([0x52, 0x54, 0x00, 0x12, 0x34, 0x56])
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug)]
+pub struct VhostUserNetBuilder<M: GuestAddressSpace, S: SignalUsedQueue> {
+    mem: M,
+    stream: UnixStream,
+    driver_notify: S,
+    pairs: u16,
+    queue_size: u16,
+    mac: Option<[u8; 6]>,
+    mtu: Option<u16>,
+    forward_ctrl_queue: bool,
+}
+
+impl<M, S> VhostUserNetBuilder<M, S>
+where
+    M: GuestAddressSpace + Clone,
+    S: SignalUsedQueue,
+{
+    /// Creates a new `VhostUserNetBuilder` for a device with a single queue pair.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory, whose regions have to be backed by files.
+    /// * `stream` - The socket connected to the vhost-user backend.
+    /// * `driver_notify` - The object used for notifying the driver about used buffers.
+    pub fn new(mem: M, stream: UnixStream, driver_notify: S) -> Self {
+        VhostUserNetBuilder {
+            mem,
+            stream,
+            driver_notify,
+            pairs: 1,
+            queue_size: DEFAULT_QUEUE_SIZE,
+            mac: None,
+            mtu: None,
+            forward_ctrl_queue: false,
+        }
+    }
+
+    /// Sets the number of receive/transmit queue pairs. When there's more than one pair, the
+    /// device offers `VIRTIO_NET_F_MQ` and the control queue, and the backend has to support
+    /// `VHOST_USER_PROTOCOL_F_MQ`.
+    ///
+    /// # Arguments
+    /// * `pairs` - The number of queue pairs.
+    pub fn with_queue_pairs(mut self, pairs: u16) -> Self {
+        self.pairs = pairs;
+        self
+    }
+
+    /// Sets the maximum size of the receive and transmit queues.
+    ///
+    /// # Arguments
+    /// * `queue_size` - The maximum queue size.
+    pub fn with_queue_size(mut self, queue_size: u16) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Sets the MAC address of the device. By default, the driver generates a random address.
+    ///
+    /// # Arguments
+    /// * `mac` - The unicast MAC address of the device.
+    pub fn with_mac(mut self, mac: [u8; 6]) -> Self {
+        self.mac = Some(mac);
+        self
+    }
+
+    /// Advertises the MTU of the backend port to the driver (through `VIRTIO_NET_F_MTU`), so
+    /// it can use jumbo frames. By default, the driver assumes an MTU of 1500 bytes.
+    ///
+    /// # Arguments
+    /// * `mtu` - The MTU of the backend port.
+    pub fn with_mtu(mut self, mtu: u16) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    /// Forwards the control queue to the backend, which has to offer `VIRTIO_NET_F_CTRL_VQ`,
+    /// instead of processing it in the device. The control queue features offered by the
+    /// backend (i.e. `VIRTIO_NET_F_CTRL_RX`) are then offered to the driver as well.
+    ///
+    /// # Arguments
+    /// * `forward` - Whether the control queue is processed by the backend.
+    pub fn with_ctrl_queue_forwarding(mut self, forward: bool) -> Self {
+        self.forward_ctrl_queue = forward;
+        self
+    }
+
+    /// Negotiates the protocol features with the backend and builds the `VhostUserNet` device.
+    pub fn build(self) -> Result<VhostUserNet<M, S>> {
+        let mut frontend = VhostUserFrontend::new(self.stream)?;
+        let pairs = self.pairs.max(1);
+
+        // The link is initially up.
+        let mut config = ConfigBuilder::new().with_link_status(true);
+        if let Some(mac) = self.mac {
+            config = config.with_mac(mac);
+        }
+        if let Some(mtu) = self.mtu {
+            config = config.with_mtu(mtu);
+        }
+        if pairs != 1 {
+            config = config.with_queue_pairs(pairs);
+        }
+
+        let backend_features = frontend.features();
+        let mut device_features =
+            (backend_features & !(CTRL_FEATURES | CONFIG_FEATURES)) | config.features();
+        if self.forward_ctrl_queue {
+            if backend_features & (1 << VIRTIO_NET_F_CTRL_VQ) == 0 {
+                return Err(Error::CtrlQueueUnsupported);
+            }
+            device_features |= backend_features & CTRL_FEATURES;
+        }
+        let config_space: Vec<u8> = config.build().map_err(Error::Config)?.into();
+
+        let mut num_queues = 2 * pairs;
+        if device_features & (1 << VIRTIO_NET_F_CTRL_VQ) != 0 {
+            num_queues += 1;
+        }
+        // The backend only sees the control queue when it's forwarded.
+        let backend_queues = if self.forward_ctrl_queue {
+            num_queues
+        } else {
+            2 * pairs
+        };
+        // A single queue pair is supported by all the backends.
+        if backend_queues > 2 {
+            frontend.check_queue_num(backend_queues)?;
+        }
+
+        let (mem, queue_size) = (self.mem, self.queue_size);
+        let queues = (0..num_queues)
+            .map(|_| Queue::new(mem.clone(), queue_size))
+            .collect();
+        let new_eventfds = || {
+            (0..backend_queues)
+                .map(|_| EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd))
+                .collect::<Result<Vec<_>>>()
+        };
+
+        Ok(VhostUserNet {
+            cfg: VirtioConfig::new(device_features, queues, config_space),
+            mem,
+            frontend,
+            max_pairs: pairs,
+            forward_ctrl_queue: self.forward_ctrl_queue,
+            kick_evts: new_eventfds()?,
+            call_evts: new_eventfds()?,
+            driver_notify: self.driver_notify,
+            ctrl_queue: None,
+            active_pairs: 0,
+            started_queues: Vec::new(),
+        })
+    }
+}
+
+/// A virtio network device whose queue pairs are processed by a vhost-user backend.
+#[derive(Debug, VirtioDeviceCommon)]
+#[virtio(device_type = VIRTIO_ID_NET)]
+pub struct VhostUserNet<M: GuestAddressSpace, S: SignalUsedQueue> {
+    #[virtio(config)]
+    cfg: VirtioConfig<M>,
+    mem: M,
+    frontend: VhostUserFrontend,
+    max_pairs: u16,
+    // Whether the control queue is processed by the backend rather than by the device.
+    forward_ctrl_queue: bool,
+    // Used by the driver (or the VMM) to notify the backend about available buffers, one for
+    // each queue forwarded to the backend.
+    kick_evts: Vec<EventFd>,
+    // Used by the backend to notify the VMM about used buffers.
+    call_evts: Vec<EventFd>,
+    driver_notify: S,
+    // The control queue, while the device is activated and processes it.
+    ctrl_queue: Option<Queue<M>>,
+    // The number of queue pairs enabled in the backend.
+    active_pairs: u16,
+    // The indices of the queues which were set up in the backend.
+    started_queues: Vec<u16>,
+}
+
+impl<M, S> VhostUserNet<M, S>
+where
+    M: GuestAddressSpace,
+    S: SignalUsedQueue,
+{
+    /// Returns whether the device is activated.
+    pub fn is_activated(&self) -> bool {
+        self.cfg.device_activated
+    }
+
+    /// Returns whether the link is up.
+    pub fn is_link_up(&self) -> bool {
+        self.status() & VIRTIO_NET_S_LINK_UP != 0
+    }
+
+    fn status(&self) -> u16 {
+        let offset = ConfigSpace::STATUS_OFFSET;
+        let config_space = &self.cfg.config_space;
+        u16::from_le_bytes([config_space[offset], config_space[offset + 1]])
+    }
+
+    /// Returns the maximum number of queue pairs.
+    pub fn max_queue_pairs(&self) -> u16 {
+        self.max_pairs
+    }
+
+    /// Returns the number of queue pairs enabled in the backend. When the control queue is
+    /// forwarded, these are all the pairs set up by the driver, and the backend is the one
+    /// which keeps track of the pairs actually in use.
+    pub fn active_queue_pairs(&self) -> u16 {
+        self.active_pairs
+    }
+
+    /// Returns the `EventFd` which notifies the backend about the buffers made available in the
+    /// queue with the specified index. It can be registered as an ioeventfd for the queue. There
+    /// is no such `EventFd` for the control queue, unless it's forwarded to the backend.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the queue.
+    pub fn kick_eventfd(&self, index: u16) -> Option<&EventFd> {
+        self.kick_evts.get(usize::from(index))
+    }
+
+    /// Returns the `EventFd` which the backend uses for signaling used buffers in the queue with
+    /// the specified index.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the queue.
+    pub fn call_eventfd(&self, index: u16) -> Option<&EventFd> {
+        self.call_evts.get(usize::from(index))
+    }
+
+    /// Updates the interrupt status and notifies the driver after the backend signaled used
+    /// buffers. This has to be called when the call `EventFd` of the queue becomes readable.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the queue.
+    pub fn process_call_event(&self, index: u16) -> Result<()> {
+        let call_evt = self
+            .call_eventfd(index)
+            .ok_or(Error::InvalidQueueIndex(index))?;
+        match call_evt.read() {
+            Ok(_) => {}
+            // Spurious wakeup, the event was already consumed.
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(Error::EventFd(e)),
+        }
+        self.cfg.signal_used_queue();
+        self.driver_notify.signal_used_queue(index);
+        Ok(())
+    }
+
+    // Returns the index of the control queue.
+    fn ctrl_queue_index(&self) -> u16 {
+        2 * self.max_pairs
+    }
+
+    /// Processes the commands available in the control queue, when it's not forwarded to the
+    /// backend. This has to be called when the driver notifies the device about the control
+    /// queue.
+    pub fn process_ctrl_queue(&mut self) -> Result<()> {
+        let index = self.ctrl_queue_index();
+        let mut queue = self
+            .ctrl_queue
+            .take()
+            .ok_or(Error::InvalidQueueIndex(index))?;
+        let result = self.process_ctrl_requests(&mut queue);
+        self.ctrl_queue = Some(queue);
+        result.map_err(Error::CtrlQueue)
+    }
+
+    fn process_ctrl_requests(
+        &mut self,
+        queue: &mut Queue<M>,
+    ) -> result::Result<(), virtio_queue::Error> {
+        while let Some(mut chain) = queue.iter()?.next() {
+            let len = match CtrlRequest::parse(&mut chain) {
+                Ok(request) => {
+                    let ack = self.handle_ctrl_request(&request);
+                    match request.complete(chain.memory(), ack) {
+                        Ok(()) => 1,
+                        Err(e) => {
+                            warn!("failed to complete control request: {}", e);
+                            0
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!("failed to parse control request: {}", e);
+                    0
+                }
+            };
+            queue.add_used(chain.head_index(), len)?;
+            if queue.needs_notification()? {
+                self.cfg.signal_used_queue();
+                self.driver_notify
+                    .signal_used_queue(self.ctrl_queue_index());
+            }
+        }
+        Ok(())
+    }
+
+    // Executes a control command, and returns its ack value.
+    fn handle_ctrl_request(&mut self, request: &CtrlRequest) -> u8 {
+        match (request.class(), request.cmd()) {
+            (VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET) => {
+                match request.data_obj::<u16>() {
+                    Some(pairs) if self.set_active_pairs(pairs) => VIRTIO_NET_OK,
+                    _ => VIRTIO_NET_ERR,
+                }
+            }
+            (class, cmd) => {
+                warn!("unsupported control command {}:{}", class, cmd);
+                VIRTIO_NET_ERR
+            }
+        }
+    }
+
+    // Enables the first `pairs` queue pairs, and returns whether the value is valid.
+    fn set_active_pairs(&mut self, pairs: u16) -> bool {
+        // All the pairs are set up when `VIRTIO_NET_F_MQ` is negotiated.
+        if self.cfg.driver_features & (1 << VIRTIO_NET_F_MQ) == 0
+            || pairs < VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN
+            || pairs > self.max_pairs
+        {
+            return false;
+        }
+        match self.enable_pairs(pairs) {
+            Ok(()) => true,
+            Err(e) => {
+                error!("failed to enable {} queue pairs: {}", pairs, e);
+                false
+            }
+        }
+    }
+
+    // Enables the first `pairs` queue pairs in the backend, and disables the other ones. Only
+    // the pairs whose state changes are updated.
+    fn enable_pairs(&mut self, pairs: u16) -> vhost_user::Result<()> {
+        let previous = self.active_pairs;
+        for pair in previous.min(pairs)..previous.max(pairs) {
+            let enable = pair < pairs;
+            self.frontend.set_vring_enable(2 * pair, enable)?;
+            self.frontend.set_vring_enable(2 * pair + 1, enable)?;
+        }
+        self.active_pairs = pairs;
+        Ok(())
+    }
+
+    // Returns whether the queues used by the driver are valid. The driver sets up either the
+    // first queue pair, or all of them when `VIRTIO_NET_F_MQ` is negotiated, in which case
+    // the number of pairs set up by the driver is also returned.
+    fn used_pairs(&self) -> Option<u16> {
+        let has_feature = |feature: u64| self.cfg.driver_features & (1 << feature) != 0;
+        let pairs = if has_feature(VIRTIO_NET_F_MQ) {
+            self.max_pairs
+        } else {
+            1
+        };
+
+        let ctrl_queue = if has_feature(VIRTIO_NET_F_CTRL_VQ) {
+            self.cfg.queues.get(usize::from(self.ctrl_queue_index()))
+        } else {
+            None
+        };
+        self.cfg.queues[..2 * usize::from(pairs)]
+            .iter()
+            .chain(ctrl_queue)
+            .all(Queue::is_valid)
+            .then_some(pairs)
+    }
+
+    // Sends the negotiated features, the guest memory and the configuration of the queues used
+    // by the driver to the backend. The queues are not enabled.
+    fn setup_backend(&mut self, pairs: u16) -> Result<()> {
+        let mut indices: Vec<u16> = (0..2 * pairs).collect();
+        if self.forward_ctrl_queue && self.cfg.driver_features & (1 << VIRTIO_NET_F_CTRL_VQ) != 0 {
+            indices.push(self.ctrl_queue_index());
+        }
+
+        // The features provided by the device itself are not acknowledged to the backend.
+        let mut features = self.cfg.driver_features & self.frontend.features();
+        if !self.forward_ctrl_queue {
+            features &= !CTRL_FEATURES;
+        }
+        let mem = self.mem.memory();
+        self.frontend.set_features(features)?;
+        self.frontend.set_mem_table(&*mem)?;
+        for index in indices {
+            let i = usize::from(index);
+            self.frontend.set_vring(
+                &*mem,
+                index,
+                &self.cfg.queues[i],
+                &self.kick_evts[i],
+                &self.call_evts[i],
+            )?;
+            self.started_queues.push(index);
+        }
+        Ok(())
+    }
+
+    // Disables and stops the queues which were set up in the backend.
+    fn stop_backend(&mut self) -> Result<()> {
+        for index in mem::take(&mut self.started_queues) {
+            self.frontend.set_vring_enable(index, false)?;
+            self.frontend.get_vring_base(index)?;
+        }
+        Ok(())
+    }
+
+    // Notifies the backend about the buffers made available in a queue, for VMMs which don't
+    // register the kick `EventFd`s as ioeventfds.
+    fn kick_backend(&self, index: u16) -> Result<()> {
+        self.kick_eventfd(index)
+            .ok_or(Error::InvalidQueueIndex(index))?
+            .write(1)
+            .map_err(Error::EventFd)
+    }
+}
+
+impl<M, S> VhostUserNet<M, S>
+where
+    M: GuestAddressSpace,
+    S: SignalUsedQueue + SignalConfigChange,
+{
+    /// Brings the link up or down while the device is running, i.e. when the backend port goes
+    /// down. The configuration generation is updated, and a configuration change interrupt is
+    /// raised when the device is activated. The link status is preserved across device resets.
+    ///
+    /// # Arguments
+    /// * `link_up` - Whether the link is up.
+    pub fn set_link_up(&mut self, link_up: bool) {
+        if self.is_link_up() == link_up {
+            return;
+        }
+
+        let status = if link_up {
+            self.status() | VIRTIO_NET_S_LINK_UP
+        } else {
+            self.status() & !VIRTIO_NET_S_LINK_UP
+        };
+        let offset = ConfigSpace::STATUS_OFFSET;
+        self.cfg.config_space[offset..offset + 2].copy_from_slice(&status.to_le_bytes());
+        self.cfg.config_generation = self.cfg.config_generation.wrapping_add(1);
+
+        if self.cfg.device_activated {
+            self.cfg.signal_config_change();
+            self.driver_notify.signal_config_change();
+        }
+    }
+}
+
+impl<M, S> VirtioDeviceActions for VhostUserNet<M, S>
+where
+    M: GuestAddressSpace + Clone,
+    S: SignalUsedQueue,
+{
+    type E = Error;
+
+    fn activate(&mut self) -> Result<()> {
+        if self.cfg.device_activated {
+            return Err(Error::AlreadyActivated);
+        }
+        let pairs = self.used_pairs().ok_or(Error::InvalidQueues)?;
+
+        self.setup_backend(pairs)?;
+        if self.forward_ctrl_queue {
+            for &index in self.started_queues.iter() {
+                self.frontend.set_vring_enable(index, true)?;
+            }
+            self.active_pairs = pairs;
+        } else {
+            if self.cfg.driver_features & (1 << VIRTIO_NET_F_CTRL_VQ) != 0 {
+                self.ctrl_queue =
+                    Some(self.cfg.queues[usize::from(self.ctrl_queue_index())].clone());
+            }
+            // Only the first pair is enabled until the driver asks for more.
+            self.enable_pairs(1)?;
+        }
+        self.cfg.device_activated = true;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        // The device is reset even if the backend fails, and the error is reported afterwards.
+        let stopped = self.stop_backend();
+        self.ctrl_queue = None;
+        self.active_pairs = 0;
+
+        let cfg = &mut self.cfg;
+        cfg.driver_features = 0;
+        cfg.device_features_select = 0;
+        cfg.driver_features_select = 0;
+        cfg.device_status = 0;
+        cfg.queue_select = 0;
+        cfg.queues.iter_mut().for_each(Queue::reset);
+        cfg.device_activated = false;
+        cfg.reset_interrupt_status();
+        stopped
+    }
+}
+
+impl<M, S> VirtioMmioDevice<M> for VhostUserNet<M, S>
+where
+    M: GuestAddressSpace + Clone + 'static,
+    S: SignalUsedQueue,
+{
+    fn queue_notify(&mut self, val: u32) {
+        // Queue indices always fit in an `u16`.
+        let index = val as u16;
+        let result = if !self.forward_ctrl_queue && index == self.ctrl_queue_index() {
+            self.process_ctrl_queue()
+        } else {
+            self.kick_backend(index)
+        };
+        if let Err(e) = result {
+            error!("failed to process queue {}: {}", val, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+
+    use vm_memory::{ByteValued, Bytes, FileOffset, GuestAddress, GuestMemoryMmap};
+    use vmm_sys_util::tempfile::TempFile;
+
+    use virtio_device::mock::{activate, recv_backend_message, BackendMessage};
+    use virtio_device::vhost_user::{
+        Header, VringState, SUPPORTED_PROTOCOL_FEATURES, VHOST_USER_GET_FEATURES,
+        VHOST_USER_GET_PROTOCOL_FEATURES, VHOST_USER_GET_QUEUE_NUM, VHOST_USER_GET_VRING_BASE,
+        VHOST_USER_NEED_REPLY, VHOST_USER_REPLY, VHOST_USER_SET_FEATURES,
+        VHOST_USER_SET_VRING_ADDR, VHOST_USER_SET_VRING_ENABLE, VHOST_USER_VERSION,
+    };
+    use virtio_device::{VirtioDevice, VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING};
+    use virtio_queue::mock::VirtQueue;
+    use virtio_queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    use crate::ctrl_queue::CtrlHeader;
+    use crate::defs::VIRTIO_NET_F_MRG_RXBUF;
+
+    type Mem = Arc<GuestMemoryMmap>;
+
+    const VIRTIO_F_VERSION_1: u64 = 32;
+    // The backend offers the control queue features, and a MAC address it doesn't provide.
+    const BACKEND_FEATURES: u64 = (1 << VIRTIO_F_VERSION_1)
+        | (1 << VHOST_USER_F_PROTOCOL_FEATURES)
+        | (1 << VIRTIO_NET_F_MRG_RXBUF)
+        | (1 << VIRTIO_NET_F_MAC)
+        | (1 << VIRTIO_NET_F_CTRL_VQ)
+        | (1 << VIRTIO_NET_F_CTRL_RX);
+
+    fn send_reply(stream: &mut UnixStream, request: u32, payload: &[u8]) {
+        let header = Header {
+            request,
+            flags: VHOST_USER_VERSION | VHOST_USER_REPLY,
+            size: payload.len() as u32,
+        };
+        stream.write_all(header.as_slice()).unwrap();
+        stream.write_all(payload).unwrap();
+    }
+
+    // Spawns a fake vhost-user-net backend with up to five queues, which answers the frontend
+    // requests and records all of them.
+    fn spawn_backend(
+        mut stream: UnixStream,
+        features: u64,
+        protocol_features: u64,
+    ) -> JoinHandle<Vec<BackendMessage>> {
+        thread::spawn(move || {
+            let mut messages = Vec::new();
+            while let Some(message) = recv_backend_message(&mut stream) {
+                let request = message.header.request;
+                match request {
+                    VHOST_USER_GET_FEATURES => {
+                        send_reply(&mut stream, request, features.as_slice())
+                    }
+                    VHOST_USER_GET_PROTOCOL_FEATURES => {
+                        send_reply(&mut stream, request, protocol_features.as_slice())
+                    }
+                    VHOST_USER_GET_QUEUE_NUM => send_reply(&mut stream, request, 5u64.as_slice()),
+                    VHOST_USER_GET_VRING_BASE => {
+                        let state = VringState {
+                            index: message.obj::<u32>(0),
+                            num: 0,
+                        };
+                        send_reply(&mut stream, request, state.as_slice());
+                    }
+                    _ => {}
+                }
+                if message.header.flags & VHOST_USER_NEED_REPLY != 0 {
+                    send_reply(&mut stream, request, 0u64.as_slice());
+                }
+                messages.push(message);
+            }
+            messages
+        })
+    }
+
+    fn shared_mem() -> Mem {
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x10_0000).unwrap();
+        Arc::new(
+            GuestMemoryMmap::from_ranges_with_files(&[(
+                GuestAddress(0),
+                0x10_0000,
+                Some(FileOffset::new(file, 0)),
+            )])
+            .unwrap(),
+        )
+    }
+
+    fn virt_queues(mem: &GuestMemoryMmap, num_queues: u64) -> Vec<VirtQueue<'_>> {
+        (0..num_queues)
+            .map(|i| VirtQueue::new(GuestAddress(i * 0x1000), mem, 16))
+            .collect()
+    }
+
+    // Returns the index and the state of the queues enabled or disabled in the backend.
+    fn vring_enables(messages: &[BackendMessage]) -> Vec<(u32, u32)> {
+        messages
+            .iter()
+            .filter(|m| m.header.request == VHOST_USER_SET_VRING_ENABLE)
+            .map(|m| (m.obj::<u32>(0), m.obj::<u32>(4)))
+            .collect()
+    }
+
+    #[test]
+    fn test_build() {
+        let mem = shared_mem();
+
+        // Multiple queue pairs require `VHOST_USER_PROTOCOL_F_MQ`.
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let handle = spawn_backend(backend, BACKEND_FEATURES, 0);
+        assert!(matches!(
+            VhostUserNetBuilder::new(mem.clone(), frontend, EventFd::new(0).unwrap())
+                .with_queue_pairs(2)
+                .build(),
+            Err(Error::VhostUser(vhost_user::Error::MissingProtocolFeature(
+                VHOST_USER_PROTOCOL_F_MQ
+            )))
+        ));
+        handle.join().unwrap();
+
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let handle = spawn_backend(backend, BACKEND_FEATURES, SUPPORTED_PROTOCOL_FEATURES);
+        assert!(matches!(
+            VhostUserNetBuilder::new(mem.clone(), frontend, EventFd::new(0).unwrap())
+                .with_queue_pairs(3)
+                .build(),
+            Err(Error::VhostUser(vhost_user::Error::TooManyQueues(6)))
+        ));
+        handle.join().unwrap();
+
+        // The control queue can only be forwarded to backends which support it.
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let features = BACKEND_FEATURES & !(1 << VIRTIO_NET_F_CTRL_VQ);
+        let handle = spawn_backend(backend, features, SUPPORTED_PROTOCOL_FEATURES);
+        assert!(matches!(
+            VhostUserNetBuilder::new(mem.clone(), frontend, EventFd::new(0).unwrap())
+                .with_ctrl_queue_forwarding(true)
+                .build(),
+            Err(Error::CtrlQueueUnsupported)
+        ));
+        handle.join().unwrap();
+
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let handle = spawn_backend(backend, BACKEND_FEATURES, SUPPORTED_PROTOCOL_FEATURES);
+        assert!(matches!(
+            VhostUserNetBuilder::new(mem.clone(), frontend, EventFd::new(0).unwrap())
+                .with_mac([0x01, 0, 0, 0, 0, 0])
+                .build(),
+            Err(Error::Config(config::Error::InvalidMac(_)))
+        ));
+        handle.join().unwrap();
+
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let handle = spawn_backend(backend, BACKEND_FEATURES, SUPPORTED_PROTOCOL_FEATURES);
+        let single = VhostUserNetBuilder::new(mem.clone(), frontend, EventFd::new(0).unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(VirtioDevice::device_type(&single), VIRTIO_ID_NET);
+        assert_eq!(single.num_queues(), 2);
+        // The control queue features of the backend, and the MAC address it doesn't provide,
+        // are not offered to the driver.
+        assert_eq!(
+            single.device_features(),
+            (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_NET_F_MRG_RXBUF) | (1 << VIRTIO_NET_F_STATUS)
+        );
+        assert!(single.is_link_up());
+        drop(single);
+        // A single queue pair doesn't require `VHOST_USER_PROTOCOL_F_MQ`.
+        let requests: Vec<u32> = handle
+            .join()
+            .unwrap()
+            .iter()
+            .map(|m| m.header.request)
+            .collect();
+        assert!(!requests.contains(&VHOST_USER_GET_QUEUE_NUM));
+
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let handle = spawn_backend(backend, BACKEND_FEATURES, SUPPORTED_PROTOCOL_FEATURES);
+        let mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        let net = VhostUserNetBuilder::new(mem, frontend, EventFd::new(0).unwrap())
+            .with_queue_pairs(2)
+            .with_queue_size(16)
+            .with_mac(mac)
+            .with_mtu(9000)
+            .build()
+            .unwrap();
+        assert_eq!(net.num_queues(), 5);
+        assert_eq!(net.max_queue_pairs(), 2);
+        assert_eq!(net.queue(4).unwrap().max_size(), 16);
+        let features = net.device_features();
+        for feature in [
+            VIRTIO_NET_F_MAC,
+            VIRTIO_NET_F_MTU,
+            VIRTIO_NET_F_MQ,
+            VIRTIO_NET_F_CTRL_VQ,
+        ]
+        .iter()
+        {
+            assert_ne!(features & (1 << feature), 0);
+        }
+        assert_eq!(features & (1 << VIRTIO_NET_F_CTRL_RX), 0);
+        let mut config_mac = [0u8; 6];
+        net.read_config(ConfigSpace::MAC_OFFSET, &mut config_mac);
+        assert_eq!(config_mac, mac);
+        let mut pairs = [0u8; 2];
+        net.read_config(ConfigSpace::MAX_VIRTQUEUE_PAIRS_OFFSET, &mut pairs);
+        assert_eq!(u16::from_le_bytes(pairs), 2);
+        // The control queue is processed by the device.
+        assert!(net.kick_eventfd(3).is_some());
+        assert!(net.kick_eventfd(4).is_none());
+        assert!(net.call_eventfd(4).is_none());
+        drop(net);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_local_ctrl_queue() {
+        let mem = shared_mem();
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let handle = spawn_backend(backend, BACKEND_FEATURES, SUPPORTED_PROTOCOL_FEATURES);
+        let mut net = VhostUserNetBuilder::new(mem.clone(), frontend, EventFd::new(0).unwrap())
+            .with_queue_pairs(2)
+            .with_queue_size(16)
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            VirtioDeviceActions::activate(&mut net),
+            Err(Error::InvalidQueues)
+        ));
+
+        let vqs = virt_queues(&mem, 5);
+        activate(&mut net, &vqs, 0);
+        assert!(net.is_activated());
+        assert_eq!(net.active_queue_pairs(), 1);
+
+        // The driver enables both pairs, then asks for an invalid number of pairs.
+        let ctrlq = &vqs[4];
+        let header = CtrlHeader {
+            class: VIRTIO_NET_CTRL_MQ,
+            cmd: VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET,
+        };
+        mem.write_obj(header, GuestAddress(0x2_0000)).unwrap();
+        mem.write_obj(2u16, GuestAddress(0x2_0002)).unwrap();
+        mem.write_obj(3u16, GuestAddress(0x2_0004)).unwrap();
+        ctrlq.dtable(0).set(0x2_0000, 4, VIRTQ_DESC_F_NEXT, 1);
+        ctrlq.dtable(1).set(0x3_0000, 1, VIRTQ_DESC_F_WRITE, 0);
+        ctrlq.dtable(2).set(0x2_0000, 2, VIRTQ_DESC_F_NEXT, 3);
+        ctrlq.dtable(3).set(0x2_0004, 2, VIRTQ_DESC_F_NEXT, 4);
+        ctrlq.dtable(4).set(0x3_0001, 1, VIRTQ_DESC_F_WRITE, 0);
+        ctrlq.avail.ring(0).store(0);
+        ctrlq.avail.ring(1).store(2);
+        ctrlq.avail.idx().store(2);
+        net.queue_notify(4);
+
+        assert_eq!(ctrlq.used.idx().load(), 2);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x3_0000)).unwrap(),
+            VIRTIO_NET_OK
+        );
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x3_0001)).unwrap(),
+            VIRTIO_NET_ERR
+        );
+        assert_eq!(net.active_queue_pairs(), 2);
+        assert_eq!(
+            net.interrupt_status().load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_VRING
+        );
+
+        // The other queue notifications are forwarded through the kick eventfds.
+        net.queue_notify(3);
+        assert_eq!(net.kick_eventfd(3).unwrap().read().unwrap(), 1);
+        net.call_eventfd(3).unwrap().write(1).unwrap();
+        net.process_call_event(3).unwrap();
+        assert!(matches!(
+            net.process_call_event(4),
+            Err(Error::InvalidQueueIndex(4))
+        ));
+
+        // The link status is provided by the device.
+        net.set_link_up(false);
+        assert!(!net.is_link_up());
+        assert_eq!(net.config_generation(), 1);
+        assert_ne!(
+            net.interrupt_status().load(Ordering::SeqCst) & VIRTIO_MMIO_INT_CONFIG,
+            0
+        );
+
+        net.ack_device_status(0);
+        assert!(!net.is_activated());
+        assert_eq!(net.active_queue_pairs(), 0);
+        assert!(matches!(
+            net.process_ctrl_queue(),
+            Err(Error::InvalidQueueIndex(4))
+        ));
+        drop(net);
+
+        let messages = handle.join().unwrap();
+        // The features provided by the device are not acknowledged to the backend.
+        let features = messages
+            .iter()
+            .find(|m| m.header.request == VHOST_USER_SET_FEATURES)
+            .unwrap();
+        assert_eq!(
+            features.obj::<u64>(0),
+            BACKEND_FEATURES & !(CTRL_FEATURES | (1 << VIRTIO_NET_F_MAC))
+        );
+        // The control queue is not set up in the backend.
+        let addrs: Vec<u32> = messages
+            .iter()
+            .filter(|m| m.header.request == VHOST_USER_SET_VRING_ADDR)
+            .map(|m| m.obj::<u32>(0))
+            .collect();
+        assert_eq!(addrs, vec![0, 1, 2, 3]);
+        // The second pair is enabled once the driver asks for it.
+        assert_eq!(
+            vring_enables(&messages),
+            vec![
+                (0, 1),
+                (1, 1),
+                (2, 1),
+                (3, 1),
+                (0, 0),
+                (1, 0),
+                (2, 0),
+                (3, 0)
+            ]
+        );
+        let stopped = messages
+            .iter()
+            .filter(|m| m.header.request == VHOST_USER_GET_VRING_BASE)
+            .count();
+        assert_eq!(stopped, 4);
+    }
+
+    #[test]
+    fn test_forwarded_ctrl_queue() {
+        let mem = shared_mem();
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let handle = spawn_backend(backend, BACKEND_FEATURES, SUPPORTED_PROTOCOL_FEATURES);
+        let mut net = VhostUserNetBuilder::new(mem.clone(), frontend, EventFd::new(0).unwrap())
+            .with_queue_size(16)
+            .with_ctrl_queue_forwarding(true)
+            .build()
+            .unwrap();
+        assert_eq!(net.num_queues(), 3);
+        assert_ne!(net.device_features() & (1 << VIRTIO_NET_F_CTRL_RX), 0);
+        assert!(net.kick_eventfd(2).is_some());
+
+        let vqs = virt_queues(&mem, 3);
+        activate(&mut net, &vqs, 0);
+        assert!(net.is_activated());
+        assert_eq!(net.active_queue_pairs(), 1);
+
+        // The control queue is processed by the backend.
+        net.queue_notify(2);
+        assert_eq!(net.kick_eventfd(2).unwrap().read().unwrap(), 1);
+        assert!(matches!(
+            net.process_ctrl_queue(),
+            Err(Error::InvalidQueueIndex(2))
+        ));
+
+        net.ack_device_status(0);
+        drop(net);
+
+        let messages = handle.join().unwrap();
+        let features = messages
+            .iter()
+            .find(|m| m.header.request == VHOST_USER_SET_FEATURES)
+            .unwrap();
+        assert_eq!(
+            features.obj::<u64>(0),
+            BACKEND_FEATURES & !(1 << VIRTIO_NET_F_MAC)
+        );
+        assert_eq!(
+            vring_enables(&messages),
+            vec![(0, 1), (1, 1), (2, 1), (0, 0), (1, 0), (2, 0)]
+        );
+    }
+}
@@ -0,0 +1,619 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! AF_XDP socket packet backend.
+//!
+//! This module provides the [`XdpSocket`](struct.XdpSocket.html) abstraction, which binds an
+//! AF_XDP socket to a queue of a network interface, and can be used instead of a TAP device
+//! queue to back a receive/transmit queue pair. Packets are exchanged through a UMEM area (a
+//! set of fixed size frames shared with the kernel) and four single producer/single consumer
+//! rings, without going through the kernel network stack.
+//!
+//! The kernel moves packets between the interface and the UMEM without copying them when the
+//! interface driver supports it (and falls back to copy mode otherwise). The packets are still
+//! copied between the UMEM and the guest buffers, since the descriptor chains provided by the
+//! driver are neither aligned to, nor sized as UMEM frames.
+//!
+//! AF_XDP frames don't carry virtio network headers, so the network device has to be configured
+//! without `TapCapabilities::vnet_hdr` (which is the default), and none of the offloads can be
+//! offered to the driver. The socket only receives the packets that an XDP program attached to
+//! the interface redirects to it (i.e. through an `XSKMAP`); loading such a program is up to the
+//! VMM.
+
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use std::os::raw::{c_int, c_void};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr::{self, NonNull};
+use std::result;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// The size of a UMEM frame, which is also the largest packet that can be exchanged.
+const FRAME_SIZE: usize = 4096;
+// The number of UMEM frames. Half of them are used for receiving, and the other half for
+// transmitting packets.
+const NUM_FRAMES: usize = 4096;
+// The number of entries of each ring.
+const RING_SIZE: u32 = 2048;
+
+// The maximum length of an interface name, including the terminating null byte.
+const IFNAMSIZ: usize = 16;
+
+// AF_XDP definitions (from `linux/if_xdp.h` and `linux/socket.h`).
+const AF_XDP: c_int = 44;
+const SOL_XDP: c_int = 283;
+const XDP_MMAP_OFFSETS: c_int = 1;
+const XDP_RX_RING: c_int = 2;
+const XDP_TX_RING: c_int = 3;
+const XDP_UMEM_REG: c_int = 4;
+const XDP_UMEM_FILL_RING: c_int = 5;
+const XDP_UMEM_COMPLETION_RING: c_int = 6;
+const XDP_USE_NEED_WAKEUP: u16 = 1 << 3;
+const XDP_RING_NEED_WAKEUP: u32 = 1 << 0;
+const XDP_PGOFF_RX_RING: i64 = 0;
+const XDP_PGOFF_TX_RING: i64 = 0x8000_0000;
+const XDP_UMEM_PGOFF_FILL_RING: i64 = 0x1_0000_0000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: i64 = 0x1_8000_0000;
+
+/// AF_XDP socket errors.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to bind the socket to the interface queue.
+    Bind(io::Error),
+    /// The interface name is too long, contains a null byte, or doesn't exist.
+    InvalidIfName,
+    /// Failed to set up one of the rings.
+    Ring(io::Error),
+    /// Failed to create the socket.
+    Socket(io::Error),
+    /// Failed to set up the UMEM area.
+    Umem(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Bind(ref err) => write!(f, "failed to bind the AF_XDP socket: {}", err),
+            InvalidIfName => write!(f, "invalid interface name"),
+            Ring(ref err) => write!(f, "failed to set up the AF_XDP rings: {}", err),
+            Socket(ref err) => write!(f, "failed to create the AF_XDP socket: {}", err),
+            Umem(ref err) => write!(f, "failed to set up the UMEM area: {}", err),
+        }
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+// The `struct sockaddr_xdp` layout.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct SockaddrXdp {
+    family: u16,
+    flags: u16,
+    ifindex: u32,
+    queue_id: u32,
+    shared_umem_fd: u32,
+}
+
+// The `struct xdp_umem_reg` layout.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct UmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+    padding: u32,
+}
+
+// The `struct xdp_ring_offset` layout, which holds the offsets of the ring fields within the
+// ring mapping.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct RingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+// The `struct xdp_mmap_offsets` layout.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct MmapOffsets {
+    rx: RingOffset,
+    tx: RingOffset,
+    fill: RingOffset,
+    completion: RingOffset,
+}
+
+// The `struct xdp_desc` layout, which describes a packet in the receive and transmit rings.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[repr(C)]
+struct XdpDesc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+// A memory mapping, which is unmapped when dropped.
+#[derive(Debug)]
+struct Mmap {
+    addr: NonNull<u8>,
+    len: usize,
+}
+
+impl Mmap {
+    // Maps `len` bytes of `fd` at `offset`, or anonymous memory if `fd` is `None`.
+    fn new(len: usize, fd: Option<RawFd>, offset: i64) -> io::Result<Self> {
+        let mut flags = libc::MAP_SHARED | libc::MAP_POPULATE;
+        if fd.is_none() {
+            flags |= libc::MAP_ANONYMOUS;
+        }
+        // Safe because we don't provide an address, so the kernel picks a new area, and we
+        // check the return value.
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+                fd.unwrap_or(-1),
+                offset,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        NonNull::new(addr as *mut u8)
+            .map(|addr| Mmap { addr, len })
+            .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // Safe because the area was mapped by `Mmap::new`, and nothing refers to it anymore.
+        unsafe { libc::munmap(self.addr.as_ptr() as *mut c_void, self.len) };
+    }
+}
+
+// A single producer/single consumer ring shared with the kernel. The same abstraction is used
+// for both sides: the device produces the fill and transmit entries, and consumes the
+// completion and receive entries.
+#[derive(Debug)]
+struct Ring<T> {
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    flags: *const AtomicU32,
+    descs: *mut T,
+    size: u32,
+}
+
+impl<T: Copy> Ring<T> {
+    // Creates a ring whose fields are located at the `offset`s from `base`.
+    //
+    // # Safety
+    //
+    // `base` has to point to a memory area which holds the ring fields described by `offset`,
+    // and which outlives the ring. `size` has to be a power of two.
+    unsafe fn new(base: *mut u8, offset: &RingOffset, size: u32) -> Self {
+        Ring {
+            producer: base.add(offset.producer as usize) as *const AtomicU32,
+            consumer: base.add(offset.consumer as usize) as *const AtomicU32,
+            flags: base.add(offset.flags as usize) as *const AtomicU32,
+            descs: base.add(offset.desc as usize) as *mut T,
+            size,
+        }
+    }
+
+    // Returns the length of a mapping which holds a ring with `size` entries.
+    fn mmap_len(offset: &RingOffset, size: u32) -> usize {
+        offset.desc as usize + size as usize * size_of::<T>()
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        // Safe because the ring fields outlive the ring.
+        unsafe { &*self.producer }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        // Safe because the ring fields outlive the ring.
+        unsafe { &*self.consumer }
+    }
+
+    // Returns whether the kernel has to be woken up to process the ring.
+    fn needs_wakeup(&self) -> bool {
+        // Safe because the ring fields outlive the ring.
+        unsafe { &*self.flags }.load(Ordering::Relaxed) & XDP_RING_NEED_WAKEUP != 0
+    }
+
+    // Adds an entry to the ring, and returns `false` if the ring is full.
+    fn push(&mut self, entry: T) -> bool {
+        let producer = self.producer().load(Ordering::Relaxed);
+        let consumer = self.consumer().load(Ordering::Acquire);
+        if producer.wrapping_sub(consumer) == self.size {
+            return false;
+        }
+        let index = (producer & (self.size - 1)) as usize;
+        // Safe because the index is within the ring, and the entry is not owned by the
+        // consumer until the producer index is updated.
+        unsafe { ptr::write_volatile(self.descs.add(index), entry) };
+        self.producer()
+            .store(producer.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    // Removes the next entry from the ring, if any.
+    fn pop(&mut self) -> Option<T> {
+        let consumer = self.consumer().load(Ordering::Relaxed);
+        let producer = self.producer().load(Ordering::Acquire);
+        if consumer == producer {
+            return None;
+        }
+        let index = (consumer & (self.size - 1)) as usize;
+        // Safe because the index is within the ring, and the entry was published by the
+        // producer.
+        let entry = unsafe { ptr::read_volatile(self.descs.add(index)) };
+        self.consumer()
+            .store(consumer.wrapping_add(1), Ordering::Release);
+        Some(entry)
+    }
+}
+
+/// An AF_XDP socket bound to a queue of a network interface.
+///
+/// Reads and writes transfer a single Ethernet frame (without a network header), and don't
+/// block: a read returns `io::ErrorKind::WouldBlock` when no packet is available, and so does a
+/// write when all the transmit frames are in flight.
+#[derive(Debug)]
+pub struct XdpSocket {
+    rx: Ring<XdpDesc>,
+    tx: Ring<XdpDesc>,
+    fill: Ring<u64>,
+    completion: Ring<u64>,
+    // The UMEM frames which can be used for transmitting packets.
+    free_frames: Vec<u64>,
+    // The ring mappings have to outlive the rings.
+    _ring_mmaps: Vec<Mmap>,
+    umem: Mmap,
+    file: File,
+}
+
+// Safe because the socket exclusively owns the UMEM area and the rings, so it can be moved to
+// another thread.
+unsafe impl Send for XdpSocket {}
+
+impl XdpSocket {
+    /// Creates an AF_XDP socket bound to the queue `queue_id` of the interface named
+    /// `if_name`. The socket is in non-blocking mode.
+    ///
+    /// # Arguments
+    /// * `if_name` - The name of the network interface.
+    /// * `queue_id` - The index of the interface queue.
+    pub fn open(if_name: &str, queue_id: u32) -> Result<Self> {
+        let ifindex = if_index(if_name)?;
+
+        // Safe because we check the return value.
+        let fd = unsafe {
+            libc::socket(
+                AF_XDP,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::Socket(io::Error::last_os_error()));
+        }
+        // Safe because we just created the socket, and nothing else owns it.
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        let umem = Mmap::new(FRAME_SIZE * NUM_FRAMES, None, 0).map_err(Error::Umem)?;
+        let umem_reg = UmemReg {
+            addr: umem.addr.as_ptr() as u64,
+            len: umem.len as u64,
+            chunk_size: FRAME_SIZE as u32,
+            ..Default::default()
+        };
+        set_sockopt(&file, XDP_UMEM_REG, &umem_reg).map_err(Error::Umem)?;
+        for &opt in [
+            XDP_UMEM_FILL_RING,
+            XDP_UMEM_COMPLETION_RING,
+            XDP_RX_RING,
+            XDP_TX_RING,
+        ]
+        .iter()
+        {
+            set_sockopt(&file, opt, &RING_SIZE).map_err(Error::Ring)?;
+        }
+
+        let mut offsets = MmapOffsets::default();
+        let mut len = size_of::<MmapOffsets>() as libc::socklen_t;
+        // Safe because the kernel writes at most `len` bytes to `offsets`, and we check the
+        // return value.
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                SOL_XDP,
+                XDP_MMAP_OFFSETS,
+                &mut offsets as *mut MmapOffsets as *mut c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::Ring(io::Error::last_os_error()));
+        }
+
+        let mut ring_mmaps = Vec::new();
+        let rx = map_ring(fd, &offsets.rx, XDP_PGOFF_RX_RING, &mut ring_mmaps)?;
+        let tx = map_ring(fd, &offsets.tx, XDP_PGOFF_TX_RING, &mut ring_mmaps)?;
+        let fill = map_ring(fd, &offsets.fill, XDP_UMEM_PGOFF_FILL_RING, &mut ring_mmaps)?;
+        let completion = map_ring(
+            fd,
+            &offsets.completion,
+            XDP_UMEM_PGOFF_COMPLETION_RING,
+            &mut ring_mmaps,
+        )?;
+
+        let mut socket = XdpSocket {
+            rx,
+            tx,
+            fill,
+            completion,
+            free_frames: Vec::new(),
+            _ring_mmaps: ring_mmaps,
+            umem,
+            file,
+        };
+        // The first half of the frames is handed to the kernel for receiving packets.
+        let frames = (0..NUM_FRAMES as u64).map(|i| i * FRAME_SIZE as u64);
+        for frame in frames.clone().take(NUM_FRAMES / 2) {
+            socket.fill.push(frame);
+        }
+        socket.free_frames = frames.skip(NUM_FRAMES / 2).collect();
+
+        let addr = SockaddrXdp {
+            family: AF_XDP as u16,
+            flags: XDP_USE_NEED_WAKEUP,
+            ifindex,
+            queue_id,
+            shared_umem_fd: 0,
+        };
+        // Safe because the kernel only reads the address, and we check the return value.
+        let ret = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const SockaddrXdp as *const libc::sockaddr,
+                size_of::<SockaddrXdp>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::Bind(io::Error::last_os_error()));
+        }
+        Ok(socket)
+    }
+
+    // Returns the UMEM frame which starts at `addr`.
+    fn frame(&mut self, addr: u64, len: usize) -> io::Result<&mut [u8]> {
+        let start = addr as usize;
+        if start + len > self.umem.len {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+        // Safe because the frame is within the UMEM area, and the kernel doesn't access it
+        // while it's owned by the device.
+        Ok(unsafe { std::slice::from_raw_parts_mut(self.umem.addr.as_ptr().add(start), len) })
+    }
+
+    // Wakes up the kernel, which doesn't process the fill and transmit rings by itself when
+    // they are flagged with `XDP_RING_NEED_WAKEUP`.
+    fn wakeup(&self) -> io::Result<()> {
+        // Safe because no buffers are passed to the kernel, and we check the return value.
+        let ret = unsafe {
+            libc::sendto(
+                self.file.as_raw_fd(),
+                ptr::null(),
+                0,
+                libc::MSG_DONTWAIT,
+                ptr::null(),
+                0,
+            )
+        };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            // The kernel is busy processing the rings anyway.
+            match err.raw_os_error() {
+                Some(libc::EAGAIN) | Some(libc::EBUSY) | Some(libc::ENOBUFS) => {}
+                _ => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for XdpSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let desc = match self.rx.pop() {
+            Some(desc) => desc,
+            None => {
+                if self.fill.needs_wakeup() {
+                    self.wakeup()?;
+                }
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+        };
+
+        let len = buf.len().min(desc.len as usize);
+        let result = self
+            .frame(desc.addr, len)
+            .map(|frame| buf[..len].copy_from_slice(frame));
+        // The frame is handed back to the kernel, which can't have more frames than the fill
+        // ring holds.
+        self.fill.push(desc.addr & !(FRAME_SIZE as u64 - 1));
+        result.map(|_| len)
+    }
+}
+
+impl Write for XdpSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > FRAME_SIZE {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        while let Some(addr) = self.completion.pop() {
+            self.free_frames.push(addr);
+        }
+        let addr = self
+            .free_frames
+            .pop()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))?;
+
+        self.frame(addr, buf.len())?.copy_from_slice(buf);
+        let desc = XdpDesc {
+            addr,
+            // The length is bounded by `FRAME_SIZE`.
+            len: buf.len() as u32,
+            options: 0,
+        };
+        // The transmit ring can hold all the transmit frames.
+        self.tx.push(desc);
+        if self.tx.needs_wakeup() {
+            self.wakeup()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for XdpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+// Maps the ring of the socket `fd` which is located at the page offset `pgoff`. The mapping is
+// added to `mmaps`, which has to outlive the ring.
+fn map_ring<T: Copy>(
+    fd: RawFd,
+    offset: &RingOffset,
+    pgoff: i64,
+    mmaps: &mut Vec<Mmap>,
+) -> Result<Ring<T>> {
+    let mmap =
+        Mmap::new(Ring::<T>::mmap_len(offset, RING_SIZE), Some(fd), pgoff).map_err(Error::Ring)?;
+    let base = mmap.addr.as_ptr();
+    mmaps.push(mmap);
+    // Safe because the mapping holds the ring fields described by `offset`, and it outlives
+    // the ring.
+    Ok(unsafe { Ring::new(base, offset, RING_SIZE) })
+}
+
+// Returns the index of the interface named `if_name`.
+fn if_index(if_name: &str) -> Result<u32> {
+    if if_name.len() >= IFNAMSIZ || if_name.as_bytes().contains(&0) {
+        return Err(Error::InvalidIfName);
+    }
+    let mut name = [0u8; IFNAMSIZ];
+    name[..if_name.len()].copy_from_slice(if_name.as_bytes());
+    // Safe because the name is null terminated, and the kernel only reads it.
+    match unsafe { libc::if_nametoindex(name.as_ptr() as *const libc::c_char) } {
+        0 => Err(Error::InvalidIfName),
+        index => Ok(index),
+    }
+}
+
+// Sets an AF_XDP socket option.
+fn set_sockopt<T>(file: &File, opt: c_int, value: &T) -> io::Result<()> {
+    // Safe because the kernel only reads `size_of::<T>()` bytes from `value`, and we check the
+    // return value.
+    let ret = unsafe {
+        libc::setsockopt(
+            file.as_raw_fd(),
+            SOL_XDP,
+            opt,
+            value as *const T as *const c_void,
+            size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(size_of::<SockaddrXdp>(), 16);
+        assert_eq!(size_of::<UmemReg>(), 32);
+        assert_eq!(size_of::<MmapOffsets>(), 128);
+        assert_eq!(size_of::<XdpDesc>(), 16);
+    }
+
+    #[test]
+    fn test_ring() {
+        // The producer, consumer and flags fields, followed by 4 entries.
+        let mut area = vec![0u64; 3 + 4 * 2];
+        let offset = RingOffset {
+            producer: 0,
+            consumer: 8,
+            flags: 16,
+            desc: 24,
+        };
+        assert_eq!(Ring::<XdpDesc>::mmap_len(&offset, 4), area.len() * 8);
+
+        let base = area.as_mut_ptr() as *mut u8;
+        // Safe because the area holds the ring and outlives it.
+        let mut ring = unsafe { Ring::<XdpDesc>::new(base, &offset, 4) };
+        assert_eq!(ring.pop(), None);
+        assert!(!ring.needs_wakeup());
+
+        let desc = |i: u64| XdpDesc {
+            addr: i * FRAME_SIZE as u64,
+            len: 0x40,
+            options: 0,
+        };
+        for i in 0..4 {
+            assert!(ring.push(desc(i)));
+        }
+        assert!(!ring.push(desc(4)));
+        assert_eq!(ring.pop(), Some(desc(0)));
+
+        // The indices wrap around the ring size.
+        assert!(ring.push(desc(4)));
+        for i in 1..5 {
+            assert_eq!(ring.pop(), Some(desc(i)));
+        }
+        assert_eq!(ring.pop(), None);
+        assert_eq!(area[0], 5);
+        assert_eq!(area[1], 5);
+
+        area[2] = u64::from(XDP_RING_NEED_WAKEUP);
+        // Safe because the area holds the ring and outlives it.
+        let ring = unsafe { Ring::<u64>::new(area.as_mut_ptr() as *mut u8, &offset, 4) };
+        assert!(ring.needs_wakeup());
+    }
+
+    #[test]
+    fn test_open_invalid() {
+        assert!(matches!(
+            XdpSocket::open("a-very-long-interface-name", 0),
+            Err(Error::InvalidIfName)
+        ));
+        assert!(matches!(
+            XdpSocket::open("bad\0name", 0),
+            Err(Error::InvalidIfName)
+        ));
+    }
+}
@@ -0,0 +1,36 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Vsock backend abstraction.
+//!
+//! This module provides the [`VsockBackend`](trait.VsockBackend.html) interface, which connects
+//! the vsock device to the host side of the connections. The device hands over the packets
+//! sent by the driver, and picks up the packets for the driver when there are receive buffers
+//! available. The [`UnixMuxer`](../muxer/struct.UnixMuxer.html) backend connects the guest to
+//! Unix domain sockets, and other backends can plug in different host transports.
+
+use crate::packet::Packet;
+
+/// The host side of the vsock connections.
+pub trait VsockBackend {
+    /// Handles a packet sent by the driver through the transmit queue. The source context
+    /// identifier of the packet is validated by the device.
+    ///
+    /// # Arguments
+    /// * `pkt` - The packet sent by the driver.
+    fn send_pkt(&mut self, pkt: &Packet);
+
+    /// Returns the next packet for the driver, if any, which is written to a receive buffer.
+    ///
+    /// # Arguments
+    /// * `max_data_len` - The largest amount of data that fits in the receive buffer.
+    fn recv_pkt(&mut self, max_data_len: usize) -> Option<Packet>;
+
+    /// Returns whether the backend has packets for the driver.
+    fn has_pending_rx(&self) -> bool;
+
+    /// Drops all the connections, i.e. when the device is reset. The driver doesn't expect any
+    /// packets for the connections which existed before.
+    fn reset(&mut self) {}
+}
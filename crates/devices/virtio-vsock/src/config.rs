@@ -0,0 +1,56 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Virtio vsock device configuration space abstraction.
+//!
+//! This module provides the [`ConfigSpace`](struct.ConfigSpace.html) abstraction, which mirrors
+//! the `virtio_vsock_config` structure from the virtio specification. It only holds the context
+//! identifier of the guest, which is set by the device.
+
+use std::mem::{offset_of, size_of};
+
+use vm_memory::ByteValued;
+
+/// The vsock device configuration space layout, as defined by the virtio specification.
+///
+/// All fields are expected to hold little endian values, which is always the case on the
+/// platforms that are currently supported (`x86_64` and `aarch64`).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C, packed)]
+pub struct ConfigSpace {
+    /// The context identifier of the guest, which is only written by the device.
+    pub guest_cid: u64,
+}
+
+// Safe because ConfigSpace only contains plain data, and there's no padding between (or after)
+// the fields since the structure is packed.
+unsafe impl ByteValued for ConfigSpace {}
+
+impl ConfigSpace {
+    /// The size of the vsock device configuration space.
+    pub const LEN: usize = size_of::<ConfigSpace>();
+    /// The offset of the `guest_cid` field.
+    pub const GUEST_CID_OFFSET: usize = offset_of!(ConfigSpace, guest_cid);
+}
+
+impl From<ConfigSpace> for Vec<u8> {
+    fn from(config: ConfigSpace) -> Self {
+        config.as_slice().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_space() {
+        assert_eq!(ConfigSpace::LEN, 8);
+        assert_eq!(ConfigSpace::GUEST_CID_OFFSET, 0);
+
+        let config = ConfigSpace { guest_cid: 3 };
+        let bytes: Vec<u8> = config.into();
+        assert_eq!(bytes, [3, 0, 0, 0, 0, 0, 0, 0]);
+    }
+}
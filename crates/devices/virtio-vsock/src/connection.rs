@@ -0,0 +1,558 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Vsock connection state machine.
+//!
+//! This module provides the [`Connection`](struct.Connection.html) abstraction, which tracks a
+//! stream connection between a guest port and a host stream (i.e. a Unix domain socket), and
+//! translates between the packets exchanged with the driver and the bytes exchanged with the
+//! stream.
+//!
+//! Flow control follows the credit based scheme from the virtio specification: both sides
+//! advertise the size of their receive buffer (`buf_alloc`) and the number of bytes they
+//! consumed from it (`fwd_cnt`) in every packet, and the sender never has more bytes in flight
+//! than what the receiver has room for. On the host side, the receive buffer holds the data
+//! sent by the driver which was not written to the stream yet.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+use log::warn;
+
+use crate::defs::{
+    VIRTIO_VSOCK_OP_CREDIT_REQUEST, VIRTIO_VSOCK_OP_CREDIT_UPDATE, VIRTIO_VSOCK_OP_REQUEST,
+    VIRTIO_VSOCK_OP_RESPONSE, VIRTIO_VSOCK_OP_RST, VIRTIO_VSOCK_OP_RW, VIRTIO_VSOCK_OP_SHUTDOWN,
+    VIRTIO_VSOCK_SHUTDOWN_RCV, VIRTIO_VSOCK_SHUTDOWN_SEND, VIRTIO_VSOCK_TYPE_STREAM,
+};
+use crate::packet::{Packet, PacketHeader};
+
+/// The size of the buffer which holds the data sent by the driver, until it's written to the
+/// host stream.
+pub const CONN_TX_BUF_SIZE: u32 = 256 * 1024;
+
+// The number of bytes written to the host stream since the last credit information sent to the
+// driver, after which a credit update is sent unprompted.
+const CONN_CREDIT_UPDATE_THRESHOLD: u32 = CONN_TX_BUF_SIZE / 2;
+
+/// The state of a vsock connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionState {
+    /// The host initiated the connection, and waits for the driver to accept it.
+    LocalInit,
+    /// The driver initiated the connection, and waits for the device to accept it.
+    PeerInit,
+    /// Data flows in both directions.
+    Established,
+    /// The host stream was closed, and the connection waits for the driver to reset it.
+    LocalClosed,
+    /// The driver shut down at least one of the directions of the connection.
+    PeerClosed {
+        /// The driver will not receive any more data.
+        rcv: bool,
+        /// The driver will not send any more data.
+        send: bool,
+    },
+    /// The connection was reset, and it can be dropped once it has no pending packets.
+    Killed,
+}
+
+/// A stream connection between a guest port and a host stream.
+///
+/// The host stream is expected to be in non-blocking mode. The owner of the connection calls
+/// [`notify_readable`](#method.notify_readable) and [`notify_writable`](#method.notify_writable)
+/// when the stream becomes readable or writable.
+#[derive(Debug)]
+pub struct Connection<S: Read + Write> {
+    stream: S,
+    state: ConnectionState,
+    local_cid: u64,
+    peer_cid: u64,
+    local_port: u32,
+    peer_port: u32,
+    // The control operations which have to be sent to the driver.
+    pending_ops: VecDeque<u16>,
+    // The data sent by the driver, which was not written to the stream yet.
+    tx_buf: VecDeque<u8>,
+    // The number of bytes from the driver written to the stream.
+    fwd_cnt: u32,
+    // The value of `fwd_cnt` sent to the driver last.
+    last_fwd_cnt: u32,
+    // The number of bytes sent to the driver.
+    rx_cnt: u32,
+    // The latest credit information received from the driver.
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    // Whether the stream may have data to read.
+    readable: bool,
+}
+
+impl<S: Read + Write> Connection<S> {
+    fn new(
+        stream: S,
+        state: ConnectionState,
+        local: (u64, u32),
+        peer: (u64, u32),
+        pending_op: u16,
+    ) -> Self {
+        Connection {
+            stream,
+            state,
+            local_cid: local.0,
+            peer_cid: peer.0,
+            local_port: local.1,
+            peer_port: peer.1,
+            pending_ops: vec![pending_op].into(),
+            tx_buf: VecDeque::new(),
+            fwd_cnt: 0,
+            last_fwd_cnt: 0,
+            rx_cnt: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+            readable: false,
+        }
+    }
+
+    /// Creates a connection initiated by the host, which sends a connection request to the
+    /// driver.
+    ///
+    /// # Arguments
+    /// * `stream` - The host stream.
+    /// * `local` - The context identifier and the port of the host end.
+    /// * `peer` - The context identifier and the port of the guest end.
+    pub fn new_local_init(stream: S, local: (u64, u32), peer: (u64, u32)) -> Self {
+        Self::new(
+            stream,
+            ConnectionState::LocalInit,
+            local,
+            peer,
+            VIRTIO_VSOCK_OP_REQUEST,
+        )
+    }
+
+    /// Creates a connection initiated by the driver, which accepts the connection request.
+    ///
+    /// # Arguments
+    /// * `stream` - The host stream.
+    /// * `request` - The header of the connection request sent by the driver.
+    pub fn new_peer_init(stream: S, request: &PacketHeader) -> Self {
+        let mut conn = Self::new(
+            stream,
+            ConnectionState::PeerInit,
+            (request.dst_cid, request.dst_port),
+            (request.src_cid, request.src_port),
+            VIRTIO_VSOCK_OP_RESPONSE,
+        );
+        conn.update_peer_credit(request);
+        conn
+    }
+
+    /// Returns the state of the connection.
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// Returns a reference to the host stream.
+    pub fn stream(&self) -> &S {
+        &self.stream
+    }
+
+    /// Returns a mutable reference to the host stream.
+    pub fn stream_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Returns whether the connection was reset, and doesn't have pending packets anymore.
+    pub fn is_done(&self) -> bool {
+        self.state == ConnectionState::Killed && self.pending_ops.is_empty()
+    }
+
+    /// Returns whether the connection has packets for the driver.
+    pub fn has_pending_rx(&self) -> bool {
+        !self.pending_ops.is_empty() || (self.readable && self.can_send_data())
+    }
+
+    /// Returns the next packet for the driver, with at most `max_data_len` bytes of data.
+    /// Control packets come first, and data is only read from the stream when the driver has
+    /// room for it.
+    ///
+    /// # Arguments
+    /// * `max_data_len` - The largest amount of data that fits in the receive buffer.
+    pub fn recv_pkt(&mut self, max_data_len: usize) -> Option<Packet> {
+        if let Some(op) = self.pending_ops.pop_front() {
+            if op == VIRTIO_VSOCK_OP_RESPONSE {
+                self.state = ConnectionState::Established;
+            }
+            return Some(Packet::new(self.header(op), Vec::new()));
+        }
+        if !self.readable || !self.can_send_data() {
+            return None;
+        }
+
+        let len = max_data_len.min(self.peer_credit() as usize);
+        // Reading into an empty buffer can't tell whether the stream was closed.
+        if len == 0 {
+            return None;
+        }
+        let mut data = vec![0; len];
+        match self.stream.read(&mut data) {
+            Ok(0) => {
+                // The host stream was closed, so the driver is told that no more data flows
+                // in either direction, and it's expected to reset the connection.
+                self.state = ConnectionState::LocalClosed;
+                self.readable = false;
+                let mut hdr = self.header(VIRTIO_VSOCK_OP_SHUTDOWN);
+                hdr.flags = VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND;
+                Some(Packet::new(hdr, Vec::new()))
+            }
+            Ok(count) => {
+                data.truncate(count);
+                // `count` is bounded by the peer credit.
+                self.rx_cnt = self.rx_cnt.wrapping_add(count as u32);
+                Some(Packet::new(self.header(VIRTIO_VSOCK_OP_RW), data))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.readable = false;
+                None
+            }
+            Err(e) => {
+                warn!("failed to read from vsock stream: {}", e);
+                self.kill();
+                self.recv_pkt(0)
+            }
+        }
+    }
+
+    /// Handles a packet sent by the driver for this connection.
+    ///
+    /// # Arguments
+    /// * `pkt` - The packet sent by the driver.
+    pub fn send_pkt(&mut self, pkt: &Packet) {
+        if self.state == ConnectionState::Killed {
+            return;
+        }
+        self.update_peer_credit(&pkt.hdr);
+
+        match (pkt.hdr.op, self.state) {
+            (VIRTIO_VSOCK_OP_RESPONSE, ConnectionState::LocalInit) => {
+                self.state = ConnectionState::Established;
+            }
+            (VIRTIO_VSOCK_OP_RW, ConnectionState::Established)
+            | (VIRTIO_VSOCK_OP_RW, ConnectionState::LocalClosed)
+            | (VIRTIO_VSOCK_OP_RW, ConnectionState::PeerClosed { send: false, .. }) => {
+                // The driver is not supposed to send more than what the buffer has room for.
+                if self.tx_buf.len() + pkt.data.len() > CONN_TX_BUF_SIZE as usize {
+                    warn!("vsock connection buffer overflow");
+                    self.kill();
+                    return;
+                }
+                self.tx_buf.extend(&pkt.data);
+                self.notify_writable();
+            }
+            (VIRTIO_VSOCK_OP_SHUTDOWN, ConnectionState::Established)
+            | (VIRTIO_VSOCK_OP_SHUTDOWN, ConnectionState::PeerClosed { .. }) => {
+                let (mut rcv, mut send) = match self.state {
+                    ConnectionState::PeerClosed { rcv, send } => (rcv, send),
+                    _ => (false, false),
+                };
+                rcv |= pkt.hdr.flags & VIRTIO_VSOCK_SHUTDOWN_RCV != 0;
+                send |= pkt.hdr.flags & VIRTIO_VSOCK_SHUTDOWN_SEND != 0;
+                if rcv && send {
+                    // The connection is reset once it's shut down in both directions.
+                    self.kill();
+                } else {
+                    self.state = ConnectionState::PeerClosed { rcv, send };
+                }
+            }
+            // The driver resets the connection after it was closed by the host.
+            (VIRTIO_VSOCK_OP_RST, _) => {
+                self.state = ConnectionState::Killed;
+                self.pending_ops.clear();
+            }
+            (VIRTIO_VSOCK_OP_CREDIT_UPDATE, ConnectionState::LocalInit) => self.kill(),
+            // The new credit information was already recorded.
+            (VIRTIO_VSOCK_OP_CREDIT_UPDATE, _) => (),
+            (VIRTIO_VSOCK_OP_CREDIT_REQUEST, ConnectionState::LocalInit) => self.kill(),
+            (VIRTIO_VSOCK_OP_CREDIT_REQUEST, _) => self.push_op(VIRTIO_VSOCK_OP_CREDIT_UPDATE),
+            (op, state) => {
+                warn!("unexpected vsock operation {} in state {:?}", op, state);
+                self.kill();
+            }
+        }
+    }
+
+    /// Records that the host stream has data to read.
+    pub fn notify_readable(&mut self) {
+        self.readable = true;
+    }
+
+    /// Writes the buffered data from the driver to the host stream, which can accept more
+    /// data.
+    pub fn notify_writable(&mut self) {
+        while !self.tx_buf.is_empty() {
+            let (data, _) = self.tx_buf.as_slices();
+            match self.stream.write(data) {
+                Ok(count) => {
+                    self.tx_buf.drain(..count);
+                    // `count` is bounded by the buffer size.
+                    self.fwd_cnt = self.fwd_cnt.wrapping_add(count as u32);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("failed to write to vsock stream: {}", e);
+                    self.kill();
+                    return;
+                }
+            }
+        }
+        if self.fwd_cnt.wrapping_sub(self.last_fwd_cnt) >= CONN_CREDIT_UPDATE_THRESHOLD {
+            self.push_op(VIRTIO_VSOCK_OP_CREDIT_UPDATE);
+        }
+    }
+
+    /// Resets the connection, i.e. when the host end goes away. A reset packet is sent to the
+    /// driver.
+    pub fn kill(&mut self) {
+        self.state = ConnectionState::Killed;
+        self.pending_ops.clear();
+        self.pending_ops.push_back(VIRTIO_VSOCK_OP_RST);
+    }
+
+    // Returns whether data can be sent to the driver.
+    fn can_send_data(&self) -> bool {
+        let open = match self.state {
+            ConnectionState::Established => true,
+            ConnectionState::PeerClosed { rcv, .. } => !rcv,
+            _ => false,
+        };
+        open && self.peer_credit() > 0
+    }
+
+    // Returns the number of bytes the driver has room for.
+    fn peer_credit(&self) -> u32 {
+        self.peer_buf_alloc
+            .saturating_sub(self.rx_cnt.wrapping_sub(self.peer_fwd_cnt))
+    }
+
+    fn update_peer_credit(&mut self, hdr: &PacketHeader) {
+        self.peer_buf_alloc = hdr.buf_alloc;
+        self.peer_fwd_cnt = hdr.fwd_cnt;
+    }
+
+    fn push_op(&mut self, op: u16) {
+        if !self.pending_ops.contains(&op) {
+            self.pending_ops.push_back(op);
+        }
+    }
+
+    // Returns a header for a packet sent to the driver, which carries the current credit
+    // information.
+    fn header(&mut self, op: u16) -> PacketHeader {
+        self.last_fwd_cnt = self.fwd_cnt;
+        PacketHeader {
+            src_cid: self.local_cid,
+            dst_cid: self.peer_cid,
+            src_port: self.local_port,
+            dst_port: self.peer_port,
+            type_: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            buf_alloc: CONN_TX_BUF_SIZE,
+            fwd_cnt: self.fwd_cnt,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixStream;
+
+    use crate::defs::VMADDR_CID_HOST;
+
+    const GUEST_CID: u64 = 3;
+
+    fn request(buf_alloc: u32) -> PacketHeader {
+        PacketHeader {
+            src_cid: GUEST_CID,
+            dst_cid: VMADDR_CID_HOST,
+            src_port: 1024,
+            dst_port: 52,
+            type_: VIRTIO_VSOCK_TYPE_STREAM,
+            op: VIRTIO_VSOCK_OP_REQUEST,
+            buf_alloc,
+            ..Default::default()
+        }
+    }
+
+    // Returns an established connection initiated by the driver, along with the other end of
+    // its stream.
+    fn peer_init(buf_alloc: u32) -> (Connection<UnixStream>, UnixStream) {
+        let (stream, peer) = UnixStream::pair().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut conn = Connection::new_peer_init(stream, &request(buf_alloc));
+        conn.recv_pkt(0).unwrap();
+        (conn, peer)
+    }
+
+    // Returns a packet sent by the driver for the connection.
+    fn guest_pkt(op: u16, flags: u32, data: &[u8]) -> Packet {
+        let hdr = PacketHeader {
+            src_cid: GUEST_CID,
+            dst_cid: VMADDR_CID_HOST,
+            src_port: 1024,
+            dst_port: 52,
+            type_: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            flags,
+            buf_alloc: 8,
+            ..Default::default()
+        };
+        Packet::new(hdr, data.to_vec())
+    }
+
+    #[test]
+    fn test_local_init() {
+        let (stream, mut peer) = UnixStream::pair().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut conn = Connection::new_local_init(stream, (VMADDR_CID_HOST, 1 << 30), (3, 80));
+        assert_eq!(conn.state(), ConnectionState::LocalInit);
+        assert!(conn.has_pending_rx());
+
+        let request = conn.recv_pkt(0).unwrap();
+        assert_eq!({ request.hdr.op }, VIRTIO_VSOCK_OP_REQUEST);
+        assert_eq!({ request.hdr.src_port }, 1 << 30);
+        assert_eq!({ request.hdr.dst_cid }, 3);
+        assert_eq!({ request.hdr.dst_port }, 80);
+        assert_eq!({ request.hdr.buf_alloc }, CONN_TX_BUF_SIZE);
+        assert!(!conn.has_pending_rx());
+
+        // No data flows before the driver accepts the connection.
+        peer.write_all(b"0123456789").unwrap();
+        conn.notify_readable();
+        assert!(conn.recv_pkt(100).is_none());
+
+        conn.send_pkt(&guest_pkt(VIRTIO_VSOCK_OP_RESPONSE, 0, &[]));
+        assert_eq!(conn.state(), ConnectionState::Established);
+
+        // The data is bounded by the credit of the driver.
+        let pkt = conn.recv_pkt(100).unwrap();
+        assert_eq!({ pkt.hdr.op }, VIRTIO_VSOCK_OP_RW);
+        assert_eq!(pkt.data, b"01234567");
+        assert!(!conn.has_pending_rx());
+
+        // The driver consumed some of the data.
+        let mut update = guest_pkt(VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, &[]);
+        update.hdr.fwd_cnt = 4;
+        conn.send_pkt(&update);
+        assert_eq!(conn.recv_pkt(1).unwrap().data, b"8");
+        assert_eq!(conn.recv_pkt(100).unwrap().data, b"9");
+        assert!(conn.recv_pkt(100).is_none());
+        assert!(!conn.has_pending_rx());
+    }
+
+    #[test]
+    fn test_peer_init() {
+        let (stream, mut peer) = UnixStream::pair().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut conn = Connection::new_peer_init(stream, &request(4));
+        assert_eq!(conn.state(), ConnectionState::PeerInit);
+        assert!(conn.has_pending_rx());
+
+        // The connection is established once the response is sent.
+        let response = conn.recv_pkt(0).unwrap();
+        assert_eq!({ response.hdr.op }, VIRTIO_VSOCK_OP_RESPONSE);
+        assert_eq!({ response.hdr.src_cid }, VMADDR_CID_HOST);
+        assert_eq!({ response.hdr.src_port }, 52);
+        assert_eq!({ response.hdr.dst_port }, 1024);
+        assert_eq!(conn.state(), ConnectionState::Established);
+
+        // The credit of the driver comes from the request.
+        peer.write_all(b"0123456789").unwrap();
+        conn.notify_readable();
+        assert_eq!(conn.recv_pkt(100).unwrap().data, b"0123");
+        assert!(!conn.has_pending_rx());
+
+        // Unexpected operations reset the connection.
+        conn.send_pkt(&guest_pkt(VIRTIO_VSOCK_OP_REQUEST, 0, &[]));
+        assert_eq!(conn.state(), ConnectionState::Killed);
+        assert_eq!({ conn.recv_pkt(0).unwrap().hdr.op }, VIRTIO_VSOCK_OP_RST);
+    }
+
+    #[test]
+    fn test_data() {
+        let (stream, mut peer) = UnixStream::pair().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut conn = Connection::new_local_init(stream, (VMADDR_CID_HOST, 1 << 30), (3, 80));
+        conn.recv_pkt(0).unwrap();
+        conn.send_pkt(&guest_pkt(VIRTIO_VSOCK_OP_RESPONSE, 0, &[]));
+
+        conn.send_pkt(&guest_pkt(VIRTIO_VSOCK_OP_RW, 0, b"hello"));
+        let mut buf = [0u8; 5];
+        peer.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // The driver asks for the credit information, which accounts for the written data.
+        conn.send_pkt(&guest_pkt(VIRTIO_VSOCK_OP_CREDIT_REQUEST, 0, &[]));
+        let update = conn.recv_pkt(0).unwrap();
+        assert_eq!({ update.hdr.op }, VIRTIO_VSOCK_OP_CREDIT_UPDATE);
+        assert_eq!({ update.hdr.fwd_cnt }, 5);
+
+        // The driver sends more than what the buffer has room for.
+        let data = vec![0u8; CONN_TX_BUF_SIZE as usize + 1];
+        conn.send_pkt(&guest_pkt(VIRTIO_VSOCK_OP_RW, 0, &data));
+        assert_eq!(conn.state(), ConnectionState::Killed);
+        assert_eq!({ conn.recv_pkt(0).unwrap().hdr.op }, VIRTIO_VSOCK_OP_RST);
+        assert!(conn.is_done());
+    }
+
+    #[test]
+    fn test_shutdown() {
+        // The host closes the stream.
+        let (mut conn, peer) = peer_init(0x1000);
+        drop(peer);
+        conn.notify_readable();
+        let shutdown = conn.recv_pkt(100).unwrap();
+        assert_eq!({ shutdown.hdr.op }, VIRTIO_VSOCK_OP_SHUTDOWN);
+        assert_eq!(
+            { shutdown.hdr.flags },
+            VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND
+        );
+        assert_eq!(conn.state(), ConnectionState::LocalClosed);
+        conn.send_pkt(&guest_pkt(VIRTIO_VSOCK_OP_RST, 0, &[]));
+        assert!(conn.is_done());
+
+        // The driver shuts down the connection one direction at a time.
+        let (mut conn, mut peer) = peer_init(0x1000);
+        conn.send_pkt(&guest_pkt(
+            VIRTIO_VSOCK_OP_SHUTDOWN,
+            VIRTIO_VSOCK_SHUTDOWN_RCV,
+            &[],
+        ));
+        assert_eq!(
+            conn.state(),
+            ConnectionState::PeerClosed {
+                rcv: true,
+                send: false
+            }
+        );
+        peer.write_all(b"data").unwrap();
+        conn.notify_readable();
+        assert!(!conn.has_pending_rx());
+        // The driver can still send data.
+        conn.send_pkt(&guest_pkt(VIRTIO_VSOCK_OP_RW, 0, b"abc"));
+        let mut buf = [0u8; 3];
+        peer.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"abc");
+
+        conn.send_pkt(&guest_pkt(
+            VIRTIO_VSOCK_OP_SHUTDOWN,
+            VIRTIO_VSOCK_SHUTDOWN_SEND,
+            &[],
+        ));
+        assert_eq!(conn.state(), ConnectionState::Killed);
+        assert_eq!({ conn.recv_pkt(0).unwrap().hdr.op }, VIRTIO_VSOCK_OP_RST);
+        assert!(conn.is_done());
+    }
+}
@@ -0,0 +1,55 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+// TODO: Move the generic device type definition to the vm-virtio crate proper.
+/// The virtio device type of vsock devices.
+pub const VIRTIO_ID_VSOCK: u32 = 19;
+
+/// The default (and maximum) size of the queues.
+pub const DEFAULT_QUEUE_SIZE: u16 = 256;
+
+// Queue indices.
+/// The index of the receive queue.
+pub const RX_QUEUE: u16 = 0;
+/// The index of the transmit queue.
+pub const TX_QUEUE: u16 = 1;
+/// The index of the event queue.
+pub const EVENT_QUEUE: u16 = 2;
+
+// Well known context identifiers (from `linux/vm_sockets.h`).
+/// The context identifier of the host.
+pub const VMADDR_CID_HOST: u64 = 2;
+
+// Socket types.
+/// Stream (connection oriented) socket.
+pub const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+
+// Packet operations.
+/// Invalid operation.
+pub const VIRTIO_VSOCK_OP_INVALID: u16 = 0;
+/// Connection request.
+pub const VIRTIO_VSOCK_OP_REQUEST: u16 = 1;
+/// Connection response.
+pub const VIRTIO_VSOCK_OP_RESPONSE: u16 = 2;
+/// Connection reset.
+pub const VIRTIO_VSOCK_OP_RST: u16 = 3;
+/// Connection shutdown, with the directions in `flags`.
+pub const VIRTIO_VSOCK_OP_SHUTDOWN: u16 = 4;
+/// Data transfer.
+pub const VIRTIO_VSOCK_OP_RW: u16 = 5;
+/// Credit update, sent when the buffer space of the sender changes.
+pub const VIRTIO_VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+/// Credit request, which asks the receiver for a credit update.
+pub const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+// Shutdown flags.
+/// The sender will not receive any more data.
+pub const VIRTIO_VSOCK_SHUTDOWN_RCV: u32 = 1;
+/// The sender will not send any more data.
+pub const VIRTIO_VSOCK_SHUTDOWN_SEND: u32 = 2;
+
+// Event identifiers.
+/// The communication was interrupted (i.e. by a migration), so the driver has to reset all the
+/// connections and fetch the guest context identifier again.
+pub const VIRTIO_VSOCK_EVENT_TRANSPORT_RESET: u32 = 0;
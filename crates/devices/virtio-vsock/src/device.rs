@@ -0,0 +1,594 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A reference virtio vsock device implementation.
+//!
+//! This module provides the following abstractions:
+//!
+//! - [`Vsock`](struct.Vsock.html) which ties together the generic device building blocks
+//!   (`VirtioConfig` and the `VirtioDevice`/`VirtioMmioDevice` interfaces) with the vsock
+//!   specific ones (the configuration space, the packets and the
+//!   [`VsockBackend`](../backend/trait.VsockBackend.html) interface).
+//! - [`VsockBuilder`](struct.VsockBuilder.html) which configures and creates a `Vsock` device.
+//!
+//! The packets sent by the driver through the transmit queue are handed over to the backend,
+//! and the packets produced by the backend are written to the buffers the driver makes
+//! available through the receive queue. The backend can produce packets without any driver
+//! activity (i.e. when a host stream has data), so the VMM is expected to call
+//! [`Vsock::process_rx_queue`](struct.Vsock.html#method.process_rx_queue) whenever that's the
+//! case. The event queue is only used for transport reset events (see
+//! [`Vsock::send_transport_reset`](struct.Vsock.html#method.send_transport_reset)), i.e. after
+//! the guest is migrated.
+//!
+//! The device doesn't register any events by itself: the VMM is expected to rely on the
+//! `VirtioMmioDevice::queue_notify` implementation (or call the `process_*` methods directly)
+//! when the driver notifies a queue.
+
+use std::fmt::{self, Display};
+use std::result;
+
+use log::{error, warn};
+
+use vm_memory::GuestAddressSpace;
+
+use virtio_device::{
+    set_interrupt_status, SignalUsedQueue, VirtioConfig, VirtioDeviceActions, VirtioDeviceCommon,
+    VirtioMmioDevice, VIRTIO_MMIO_INT_VRING,
+};
+use virtio_queue::{self, Queue};
+
+use crate::backend::VsockBackend;
+use crate::config::ConfigSpace;
+use crate::defs::{EVENT_QUEUE, RX_QUEUE, TX_QUEUE, VIRTIO_VSOCK_EVENT_TRANSPORT_RESET};
+use crate::packet::{rx_data_capacity, Packet, VsockEvent};
+
+pub use crate::defs::{DEFAULT_QUEUE_SIZE, VIRTIO_ID_VSOCK};
+
+// TODO: Move the generic feature definitions to the vm-virtio crate proper.
+const VIRTIO_F_VERSION_1: u64 = 32;
+const VIRTIO_F_RING_EVENT_IDX: u64 = 29;
+
+// The context identifiers below 3 are reserved, and so is the largest 32-bit one (the
+// `VMADDR_CID_ANY` wildcard). The upper 32 bits of the guest context identifier are reserved.
+const MIN_GUEST_CID: u64 = 3;
+const MAX_GUEST_CID: u64 = 0xffff_fffe;
+
+/// Vsock device errors.
+#[derive(Debug)]
+pub enum Error {
+    /// The device was already activated.
+    AlreadyActivated,
+    /// The guest context identifier is reserved.
+    InvalidCid(u64),
+    /// The queues are not configured properly.
+    InvalidQueues,
+    /// The queue index is not valid, or the device is not activated.
+    InvalidQueueIndex(u16),
+    /// Failed to access a queue.
+    Queue(virtio_queue::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            AlreadyActivated => write!(f, "the device is already activated"),
+            InvalidCid(cid) => write!(f, "invalid guest context identifier {}", cid),
+            InvalidQueues => write!(f, "invalid queue configuration"),
+            InvalidQueueIndex(index) => write!(f, "invalid or inactive queue {}", index),
+            Queue(ref err) => write!(f, "failed to access the queue: {}", err),
+        }
+    }
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Configures and builds a `Vsock` device.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # use std::sync::Arc;
+/// # use virtio_vsock::device::VsockBuilder;
+/// # use virtio_vsock::muxer::UnixMuxer;
+/// # use vm_memory::{GuestAddress, GuestMemoryMmap};
+/// # use vmm_sys_util::eventfd::EventFd;
+/// let mem = Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+/// let muxer = UnixMuxer::new(3, "/tmp/vsock.sock").unwrap();
+///
+/// let vsock = VsockBuilder::new(mem, 3, muxer, EventFd::new(0).unwrap())
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug)]
+pub struct VsockBuilder<M: GuestAddressSpace, B: VsockBackend, S: SignalUsedQueue> {
+    mem: M,
+    guest_cid: u64,
+    backend: B,
+    driver_notify: S,
+    queue_size: u16,
+}
+
+impl<M, B, S> VsockBuilder<M, B, S>
+where
+    M: GuestAddressSpace + Clone,
+    B: VsockBackend,
+    S: SignalUsedQueue,
+{
+    /// Creates a new `VsockBuilder`.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    /// * `guest_cid` - The context identifier of the guest.
+    /// * `backend` - The host side of the connections.
+    /// * `driver_notify` - The object used for notifying the driver about used buffers.
+    pub fn new(mem: M, guest_cid: u64, backend: B, driver_notify: S) -> Self {
+        VsockBuilder {
+            mem,
+            guest_cid,
+            backend,
+            driver_notify,
+            queue_size: DEFAULT_QUEUE_SIZE,
+        }
+    }
+
+    /// Sets the maximum size of the queues.
+    ///
+    /// # Arguments
+    /// * `queue_size` - The maximum queue size.
+    pub fn with_queue_size(mut self, queue_size: u16) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Builds the `Vsock` device.
+    pub fn build(self) -> Result<Vsock<M, B, S>> {
+        if !(MIN_GUEST_CID..=MAX_GUEST_CID).contains(&self.guest_cid) {
+            return Err(Error::InvalidCid(self.guest_cid));
+        }
+        let device_features = (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_RING_EVENT_IDX);
+        let queues = (0..3)
+            .map(|_| Queue::new(self.mem.clone(), self.queue_size))
+            .collect();
+        let config_space = ConfigSpace {
+            guest_cid: self.guest_cid,
+        };
+
+        Ok(Vsock {
+            cfg: VirtioConfig::new(device_features, queues, config_space.into()),
+            guest_cid: self.guest_cid,
+            backend: self.backend,
+            driver_notify: self.driver_notify,
+            pending_reset_event: false,
+        })
+    }
+}
+
+/// A virtio vsock device.
+#[derive(Debug, VirtioDeviceCommon)]
+#[virtio(device_type = VIRTIO_ID_VSOCK)]
+pub struct Vsock<M: GuestAddressSpace, B: VsockBackend, S: SignalUsedQueue> {
+    #[virtio(config)]
+    cfg: VirtioConfig<M>,
+    guest_cid: u64,
+    backend: B,
+    driver_notify: S,
+    // Whether a transport reset event waits for a buffer in the event queue.
+    pending_reset_event: bool,
+}
+
+impl<M, B, S> Vsock<M, B, S>
+where
+    M: GuestAddressSpace,
+    B: VsockBackend,
+    S: SignalUsedQueue,
+{
+    /// Returns whether the device is activated.
+    pub fn is_activated(&self) -> bool {
+        self.cfg.device_activated
+    }
+
+    /// Returns the context identifier of the guest.
+    pub fn guest_cid(&self) -> u64 {
+        self.guest_cid
+    }
+
+    /// Returns a reference to the backend.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Returns a mutable reference to the backend, i.e. for processing its events.
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    /// Writes the packets produced by the backend to the buffers from the receive queue. This
+    /// has to be called when the driver notifies the receive queue, and when the backend has
+    /// new packets for the driver.
+    pub fn process_rx_queue(&mut self) -> Result<()> {
+        if !self.cfg.device_activated {
+            return Err(Error::InvalidQueueIndex(RX_QUEUE));
+        }
+        let queue = &mut self.cfg.queues[usize::from(RX_QUEUE)];
+        let backend = &mut self.backend;
+        while backend.has_pending_rx() {
+            let mut chain = match queue.iter()?.next() {
+                Some(chain) => chain,
+                // The driver notifies the receive queue when it adds more buffers.
+                None => break,
+            };
+            let len = match rx_data_capacity(&chain) {
+                Ok(capacity) => match backend.recv_pkt(capacity) {
+                    Some(pkt) => pkt.write_to_chain(&mut chain).unwrap_or_else(|e| {
+                        warn!("failed to write vsock packet: {}", e);
+                        0
+                    }),
+                    // The buffer is kept for later.
+                    None => {
+                        queue.go_to_previous_position();
+                        break;
+                    }
+                },
+                Err(e) => {
+                    warn!("invalid vsock receive buffer: {}", e);
+                    0
+                }
+            };
+
+            queue.add_used(chain.head_index(), len)?;
+            if queue.needs_notification()? {
+                set_interrupt_status(&self.cfg.interrupt_status, VIRTIO_MMIO_INT_VRING);
+                self.driver_notify.signal_used_queue(RX_QUEUE);
+            }
+        }
+        Ok(())
+    }
+
+    /// Hands over the packets sent by the driver through the transmit queue to the backend.
+    /// This has to be called when the driver notifies the transmit queue.
+    pub fn process_tx_queue(&mut self) -> Result<()> {
+        if !self.cfg.device_activated {
+            return Err(Error::InvalidQueueIndex(TX_QUEUE));
+        }
+        let queue = &mut self.cfg.queues[usize::from(TX_QUEUE)];
+        while let Some(mut chain) = queue.iter()?.next() {
+            match Packet::read_from_chain(&mut chain) {
+                // The driver can only send packets on behalf of the guest.
+                Ok(pkt) if pkt.hdr.src_cid == self.guest_cid => self.backend.send_pkt(&pkt),
+                Ok(pkt) => warn!("dropping vsock packet from cid {}", { pkt.hdr.src_cid }),
+                Err(e) => warn!("failed to read vsock packet: {}", e),
+            }
+
+            // The driver doesn't expect anything to be written to the buffers.
+            queue.add_used(chain.head_index(), 0)?;
+            if queue.needs_notification()? {
+                set_interrupt_status(&self.cfg.interrupt_status, VIRTIO_MMIO_INT_VRING);
+                self.driver_notify.signal_used_queue(TX_QUEUE);
+            }
+        }
+
+        // The backend usually answers the packets from the driver (i.e. with a connection
+        // response or a credit update).
+        self.process_rx_queue()
+    }
+
+    /// Sends the pending transport reset event, if any. This has to be called when the driver
+    /// notifies the event queue.
+    pub fn process_event_queue(&mut self) -> Result<()> {
+        if !self.cfg.device_activated {
+            return Err(Error::InvalidQueueIndex(EVENT_QUEUE));
+        }
+        if !self.pending_reset_event {
+            return Ok(());
+        }
+
+        let queue = &mut self.cfg.queues[usize::from(EVENT_QUEUE)];
+        let mut chain = match queue.iter()?.next() {
+            Some(chain) => chain,
+            None => return Ok(()),
+        };
+        let event = VsockEvent {
+            id: VIRTIO_VSOCK_EVENT_TRANSPORT_RESET,
+        };
+        let len = event.write_to_chain(&mut chain).unwrap_or_else(|e| {
+            warn!("failed to write vsock event: {}", e);
+            0
+        });
+        self.pending_reset_event = false;
+
+        queue.add_used(chain.head_index(), len)?;
+        if queue.needs_notification()? {
+            self.cfg.signal_used_queue();
+            self.driver_notify.signal_used_queue(EVENT_QUEUE);
+        }
+        Ok(())
+    }
+
+    /// Tells the driver that the communication was interrupted (i.e. after the guest was
+    /// migrated), so it has to reset all its connections. The connections of the backend are
+    /// dropped as well. The event is sent when the driver makes a buffer available through the
+    /// event queue, if there's none at the moment.
+    pub fn send_transport_reset(&mut self) -> Result<()> {
+        self.backend.reset();
+        self.pending_reset_event = true;
+        self.process_event_queue()
+    }
+}
+
+impl<M, B, S> VirtioDeviceActions for Vsock<M, B, S>
+where
+    M: GuestAddressSpace,
+    B: VsockBackend,
+    S: SignalUsedQueue,
+{
+    type E = Error;
+
+    fn activate(&mut self) -> Result<()> {
+        if self.cfg.device_activated {
+            return Err(Error::AlreadyActivated);
+        }
+        if !self.cfg.queues.iter().all(Queue::is_valid) {
+            return Err(Error::InvalidQueues);
+        }
+        self.cfg.device_activated = true;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        // The driver starts over without any connections.
+        self.backend.reset();
+        self.pending_reset_event = false;
+
+        let cfg = &mut self.cfg;
+        cfg.driver_features = 0;
+        cfg.device_features_select = 0;
+        cfg.driver_features_select = 0;
+        cfg.device_status = 0;
+        cfg.queue_select = 0;
+        cfg.queues.iter_mut().for_each(Queue::reset);
+        cfg.device_activated = false;
+        cfg.reset_interrupt_status();
+        Ok(())
+    }
+}
+
+impl<M, B, S> VirtioMmioDevice<M> for Vsock<M, B, S>
+where
+    M: GuestAddressSpace + 'static,
+    B: VsockBackend,
+    S: SignalUsedQueue,
+{
+    fn queue_notify(&mut self, val: u32) {
+        // Queue indices always fit in an `u16`.
+        let result = match val as u16 {
+            RX_QUEUE => self.process_rx_queue(),
+            TX_QUEUE => self.process_tx_queue(),
+            EVENT_QUEUE => self.process_event_queue(),
+            index => Err(Error::InvalidQueueIndex(index)),
+        };
+        if let Err(e) = result {
+            error!("failed to process queue {}: {}", val, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::VecDeque;
+    use std::sync::Arc;
+
+    use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap};
+    use vmm_sys_util::eventfd::EventFd;
+
+    use virtio_device::mock::activate;
+    use virtio_device::VirtioDevice;
+    use virtio_queue::mock::VirtQueue;
+    use virtio_queue::VIRTQ_DESC_F_WRITE;
+
+    use crate::defs::{
+        VIRTIO_VSOCK_OP_REQUEST, VIRTIO_VSOCK_OP_RESPONSE, VIRTIO_VSOCK_TYPE_STREAM,
+        VMADDR_CID_HOST,
+    };
+    use crate::packet::PacketHeader;
+
+    type Mem = Arc<GuestMemoryMmap>;
+
+    const GUEST_CID: u64 = 3;
+
+    // Records the packets sent by the driver, and answers connection requests.
+    #[derive(Debug, Default)]
+    struct TestBackend {
+        sent: Vec<Packet>,
+        rx: VecDeque<Packet>,
+        resets: usize,
+    }
+
+    impl VsockBackend for TestBackend {
+        fn send_pkt(&mut self, pkt: &Packet) {
+            self.sent.push(pkt.clone());
+            if pkt.hdr.op == VIRTIO_VSOCK_OP_REQUEST {
+                self.rx
+                    .push_back(Packet::new(pkt.hdr.reply(VIRTIO_VSOCK_OP_RESPONSE), vec![]));
+            }
+        }
+
+        fn recv_pkt(&mut self, max_data_len: usize) -> Option<Packet> {
+            let mut pkt = self.rx.pop_front()?;
+            pkt.data.truncate(max_data_len);
+            Some(pkt)
+        }
+
+        fn has_pending_rx(&self) -> bool {
+            !self.rx.is_empty()
+        }
+
+        fn reset(&mut self) {
+            self.rx.clear();
+            self.resets += 1;
+        }
+    }
+
+    fn vsock(mem: &Mem) -> Vsock<Mem, TestBackend, EventFd> {
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        VsockBuilder::new(mem.clone(), GUEST_CID, TestBackend::default(), evt)
+            .with_queue_size(16)
+            .build()
+            .unwrap()
+    }
+
+    fn virt_queues(mem: &GuestMemoryMmap) -> Vec<VirtQueue<'_>> {
+        (0..3)
+            .map(|i| VirtQueue::new(GuestAddress(i * 0x1000), mem, 16))
+            .collect()
+    }
+
+    // Makes a buffer with a single descriptor available in `vq`, using the descriptor `index`.
+    fn add_buffer(vq: &VirtQueue, index: u16, addr: u64, len: u32, flags: u16) {
+        vq.dtable(index).set(addr, len, flags, 0);
+        let avail = vq.avail.idx().load();
+        vq.avail.ring(avail).store(index);
+        vq.avail.idx().store(avail + 1);
+    }
+
+    // Returns the length of the used element `index` from `vq`.
+    fn used_len(mem: &GuestMemoryMmap, vq: &VirtQueue, index: u64) -> u32 {
+        // The used ring starts after the flags and the index, and each element holds the head
+        // index followed by the length.
+        let addr = vq.used_start().unchecked_add(4 + index * 8 + 4);
+        mem.read_obj(addr).unwrap()
+    }
+
+    fn request(src_cid: u64) -> PacketHeader {
+        PacketHeader {
+            src_cid,
+            dst_cid: VMADDR_CID_HOST,
+            src_port: 1024,
+            dst_port: 52,
+            type_: VIRTIO_VSOCK_TYPE_STREAM,
+            op: VIRTIO_VSOCK_OP_REQUEST,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_build() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let b = vsock(&mem);
+        assert_eq!(VirtioDevice::device_type(&b), VIRTIO_ID_VSOCK);
+        assert_eq!(b.num_queues(), 3);
+        assert_ne!(b.device_features() & (1 << VIRTIO_F_VERSION_1), 0);
+        assert_eq!(b.guest_cid(), GUEST_CID);
+        let mut cid = [0u8; 8];
+        b.read_config(ConfigSpace::GUEST_CID_OFFSET, &mut cid);
+        assert_eq!(u64::from_le_bytes(cid), GUEST_CID);
+
+        for &cid in [0, VMADDR_CID_HOST, 0xffff_ffff, 1 << 32].iter() {
+            let evt = EventFd::new(0).unwrap();
+            assert!(matches!(
+                VsockBuilder::new(mem.clone(), cid, TestBackend::default(), evt).build(),
+                Err(Error::InvalidCid(c)) if c == cid
+            ));
+        }
+    }
+
+    #[test]
+    fn test_tx_rx() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let vqs = virt_queues(&mem);
+        let mut b = vsock(&mem);
+        assert!(matches!(
+            b.process_tx_queue(),
+            Err(Error::InvalidQueueIndex(TX_QUEUE))
+        ));
+        activate(&mut b, &vqs, 0);
+        assert!(b.is_activated());
+
+        // A connection request, and a packet with a spoofed source.
+        mem.write_obj(request(GUEST_CID), GuestAddress(0x1_0000))
+            .unwrap();
+        add_buffer(&vqs[1], 0, 0x1_0000, PacketHeader::LEN as u32, 0);
+        mem.write_obj(request(GUEST_CID + 1), GuestAddress(0x2_0000))
+            .unwrap();
+        add_buffer(&vqs[1], 1, 0x2_0000, PacketHeader::LEN as u32, 0);
+        // The response waits for a receive buffer.
+        b.queue_notify(u32::from(TX_QUEUE));
+        assert_eq!(b.backend().sent.len(), 1);
+        assert_eq!(vqs[1].used.idx().load(), 2);
+        assert!(b.backend().has_pending_rx());
+
+        // A receive buffer which is too short for the header is returned right away.
+        add_buffer(&vqs[0], 0, 0x3_0000, 8, VIRTQ_DESC_F_WRITE);
+        add_buffer(&vqs[0], 1, 0x4_0000, 0x1000, VIRTQ_DESC_F_WRITE);
+        add_buffer(&vqs[0], 2, 0x5_0000, 0x1000, VIRTQ_DESC_F_WRITE);
+        b.queue_notify(u32::from(RX_QUEUE));
+        assert_eq!(vqs[0].used.idx().load(), 2);
+        assert_eq!(used_len(&mem, &vqs[0], 0), 0);
+        assert_eq!(used_len(&mem, &vqs[0], 1), PacketHeader::LEN as u32);
+        let response: PacketHeader = mem.read_obj(GuestAddress(0x4_0000)).unwrap();
+        assert_eq!({ response.op }, VIRTIO_VSOCK_OP_RESPONSE);
+        assert_eq!({ response.dst_cid }, GUEST_CID);
+
+        // The backend produces data on its own, which is bounded by the buffer size.
+        let hdr = request(VMADDR_CID_HOST);
+        b.backend_mut()
+            .rx
+            .push_back(Packet::new(hdr, vec![0xab; 0x2000]));
+        b.process_rx_queue().unwrap();
+        assert_eq!(vqs[0].used.idx().load(), 3);
+        assert_eq!(used_len(&mem, &vqs[0], 2), 0x1000);
+        let hdr: PacketHeader = mem.read_obj(GuestAddress(0x5_0000)).unwrap();
+        assert_eq!({ hdr.len }, 0x1000 - PacketHeader::LEN as u32);
+
+        VirtioDeviceActions::reset(&mut b).unwrap();
+        assert!(!b.is_activated());
+        assert_eq!(b.backend().resets, 1);
+    }
+
+    #[test]
+    fn test_transport_reset() {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let vqs = virt_queues(&mem);
+        let mut b = vsock(&mem);
+        activate(&mut b, &vqs, 0);
+
+        // The event waits for a buffer.
+        b.backend_mut()
+            .rx
+            .push_back(Packet::new(request(VMADDR_CID_HOST), vec![]));
+        b.send_transport_reset().unwrap();
+        assert_eq!(b.backend().resets, 1);
+        assert!(!b.backend().has_pending_rx());
+        assert_eq!(vqs[2].used.idx().load(), 0);
+
+        mem.write_obj(0xffu32, GuestAddress(0x6_0000)).unwrap();
+        add_buffer(&vqs[2], 0, 0x6_0000, 4, VIRTQ_DESC_F_WRITE);
+        b.queue_notify(u32::from(EVENT_QUEUE));
+        assert_eq!(vqs[2].used.idx().load(), 1);
+        assert_eq!(used_len(&mem, &vqs[2], 0), 4);
+        assert_eq!(
+            mem.read_obj::<u32>(GuestAddress(0x6_0000)).unwrap(),
+            VIRTIO_VSOCK_EVENT_TRANSPORT_RESET
+        );
+        assert_eq!(b.driver_notify.read().unwrap(), 1);
+
+        // Only one event is sent.
+        add_buffer(&vqs[2], 1, 0x6_0000, 4, VIRTQ_DESC_F_WRITE);
+        b.process_event_queue().unwrap();
+        assert_eq!(vqs[2].used.idx().load(), 1);
+    }
+}
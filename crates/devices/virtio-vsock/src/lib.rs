@@ -0,0 +1,29 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A crate that provides vsock device specific components as described
+//! by the virtio specification.
+
+#![deny(missing_docs)]
+
+/// Contains the interface between the vsock device and the host side of the connections.
+pub mod backend;
+
+/// Contains the vsock device configuration space abstraction.
+pub mod config;
+
+/// Contains the vsock connection state machine.
+pub mod connection;
+
+/// Contains virtio vsock constant definitions.
+pub mod defs;
+
+/// Contains a reference virtio vsock device implementation.
+pub mod device;
+
+/// Contains a vsock backend which connects the guest to Unix domain sockets.
+pub mod muxer;
+
+/// Contains the vsock packet abstractions.
+pub mod packet;
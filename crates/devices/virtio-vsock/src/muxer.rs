@@ -0,0 +1,531 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A vsock backend which connects the guest to Unix domain sockets.
+//!
+//! The [`UnixMuxer`](struct.UnixMuxer.html) multiplexes the connections of a vsock device over
+//! Unix domain sockets on the host, which allows agents in the guest to talk to host
+//! applications without any networking:
+//!
+//! - Connections from the guest to port `P` of the host are forwarded to the Unix domain socket
+//!   at `<path>_P`, which has to be bound by a host application.
+//! - Host applications connect to the Unix domain socket at `<path>`, which is bound by the
+//!   muxer, and write `CONNECT P\n` to connect to port `P` of the guest. Once the driver accepts
+//!   the connection, the muxer writes `OK <host port>\n` back, and the data flows as is from
+//!   then on.
+//!
+//! The muxer is driven by an epoll file descriptor (see `AsRawFd`), which becomes readable when
+//! any of the sockets needs attention. The VMM is expected to call
+//! [`process_events`](struct.UnixMuxer.html#method.process_events) then, and process the
+//! receive queue of the device.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Display};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::result;
+
+use log::warn;
+use vmm_sys_util::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+
+use crate::backend::VsockBackend;
+use crate::connection::{Connection, ConnectionState};
+use crate::defs::{
+    VIRTIO_VSOCK_OP_REQUEST, VIRTIO_VSOCK_OP_RST, VIRTIO_VSOCK_TYPE_STREAM, VMADDR_CID_HOST,
+};
+use crate::packet::{Packet, PacketHeader};
+
+// The first port assigned to the host end of the connections initiated by the host, which is
+// above the ports commonly used by the guest.
+const FIRST_LOCAL_PORT: u32 = 1 << 30;
+// The longest `CONNECT` line accepted from host applications.
+const MAX_CONNECT_LINE_LEN: usize = 32;
+// The number of events processed at once.
+const EPOLL_EVENTS_LEN: usize = 32;
+
+/// Unix domain socket muxer errors.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to bind the listening socket.
+    Bind(io::Error),
+    /// Failed to set up the epoll file descriptor.
+    Epoll(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Bind(ref err) => write!(f, "failed to bind the listening socket: {}", err),
+            Epoll(ref err) => write!(f, "failed to set up epoll: {}", err),
+        }
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+// Identifies a connection by its host port and its guest port.
+type ConnectionKey = (u32, u32);
+
+// A host application which connected to the listening socket, but didn't send the `CONNECT`
+// line yet.
+#[derive(Debug)]
+struct Handshake {
+    stream: UnixStream,
+    line: Vec<u8>,
+}
+
+/// A vsock backend which connects the guest to Unix domain sockets.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # use virtio_vsock::muxer::UnixMuxer;
+/// let muxer = UnixMuxer::new(3, "/tmp/vsock.sock").unwrap();
+/// ```
+#[derive(Debug)]
+pub struct UnixMuxer {
+    guest_cid: u64,
+    path: PathBuf,
+    listener: UnixListener,
+    epoll: Epoll,
+    connections: HashMap<ConnectionKey, Connection<UnixStream>>,
+    // Maps the file descriptors of the connection streams to the connections.
+    conn_fds: HashMap<RawFd, ConnectionKey>,
+    handshakes: HashMap<RawFd, Handshake>,
+    // Reset packets for the driver, which answer packets for unknown connections.
+    rst_queue: VecDeque<PacketHeader>,
+    next_local_port: u32,
+}
+
+impl UnixMuxer {
+    /// Creates a new `UnixMuxer`, which binds the Unix domain socket at `path` for the host
+    /// applications that connect to the guest.
+    ///
+    /// # Arguments
+    /// * `guest_cid` - The context identifier of the guest.
+    /// * `path` - The path of the listening socket, and the prefix of the guest socket paths.
+    pub fn new<P: AsRef<Path>>(guest_cid: u64, path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let listener = UnixListener::bind(&path).map_err(Error::Bind)?;
+        listener.set_nonblocking(true).map_err(Error::Bind)?;
+        let epoll = Epoll::new().map_err(Error::Epoll)?;
+        let fd = listener.as_raw_fd();
+        epoll
+            .ctl(
+                ControlOperation::Add,
+                fd,
+                EpollEvent::new(EventSet::IN, fd as u64),
+            )
+            .map_err(Error::Epoll)?;
+
+        Ok(UnixMuxer {
+            guest_cid,
+            path,
+            listener,
+            epoll,
+            connections: HashMap::new(),
+            conn_fds: HashMap::new(),
+            handshakes: HashMap::new(),
+            rst_queue: VecDeque::new(),
+            next_local_port: FIRST_LOCAL_PORT,
+        })
+    }
+
+    /// Returns the number of open connections.
+    pub fn num_connections(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Handles the pending socket events, without blocking. This has to be called when the
+    /// epoll file descriptor of the muxer is readable.
+    pub fn process_events(&mut self) -> io::Result<()> {
+        let mut events = [EpollEvent::default(); EPOLL_EVENTS_LEN];
+        loop {
+            let count = self.epoll.wait(0, &mut events)?;
+            // Handling the events can make new file descriptors ready (i.e. the ones of the
+            // accepted streams), so the loop goes on until there are no more events.
+            if count == 0 {
+                return Ok(());
+            }
+            for event in &events[..count] {
+                self.handle_event(event.fd(), event.event_set());
+            }
+        }
+    }
+
+    fn handle_event(&mut self, fd: RawFd, event_set: EventSet) {
+        if fd == self.listener.as_raw_fd() {
+            self.accept();
+        } else if self.handshakes.contains_key(&fd) {
+            self.read_handshake(fd);
+        } else if let Some(key) = self.conn_fds.get(&fd) {
+            // The connection is always present for a registered file descriptor.
+            let conn = self.connections.get_mut(key).unwrap();
+            if event_set.intersects(
+                EventSet::IN | EventSet::READ_HANG_UP | EventSet::HANG_UP | EventSet::ERROR,
+            ) {
+                conn.notify_readable();
+            }
+            if event_set.contains(EventSet::OUT) {
+                conn.notify_writable();
+            }
+            let key = *key;
+            self.remove_if_done(key);
+        }
+    }
+
+    // Accepts the pending host applications.
+    fn accept(&mut self) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("failed to accept vsock host connection: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = self.register(&stream) {
+                warn!("failed to register vsock host connection: {}", e);
+                continue;
+            }
+            let handshake = Handshake {
+                stream,
+                line: Vec::new(),
+            };
+            self.handshakes
+                .insert(handshake.stream.as_raw_fd(), handshake);
+        }
+    }
+
+    // Reads the `CONNECT` line of a host application, and starts connecting to the guest.
+    fn read_handshake(&mut self, fd: RawFd) {
+        // The handshake is always present for a registered file descriptor.
+        let handshake = self.handshakes.get_mut(&fd).unwrap();
+        let mut byte = [0u8];
+        let port = loop {
+            match handshake.stream.read(&mut byte) {
+                Ok(1) if byte[0] == b'\n' => break parse_connect(&handshake.line),
+                Ok(1) if handshake.line.len() < MAX_CONNECT_LINE_LEN => {
+                    handshake.line.push(byte[0])
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                // The stream was closed, failed, or the line is too long.
+                _ => break None,
+            }
+        };
+
+        // The handshake is removed from the map either way.
+        let handshake = self.handshakes.remove(&fd).unwrap();
+        let peer_port = match port {
+            Some(port) => port,
+            None => {
+                warn!("invalid vsock host connection request");
+                return;
+            }
+        };
+        let local_port = self.alloc_local_port(peer_port);
+        let mut conn = Connection::new_local_init(
+            handshake.stream,
+            (VMADDR_CID_HOST, local_port),
+            (self.guest_cid, peer_port),
+        );
+        // The host application may have sent data right after the `CONNECT` line, which is not
+        // signaled again since the events are edge triggered.
+        conn.notify_readable();
+        self.conn_fds.insert(fd, (local_port, peer_port));
+        self.connections.insert((local_port, peer_port), conn);
+    }
+
+    // Returns a host port which is not in use by any connection to `peer_port`.
+    fn alloc_local_port(&mut self, peer_port: u32) -> u32 {
+        loop {
+            let port = self.next_local_port;
+            self.next_local_port = match port.wrapping_add(1) {
+                0 => FIRST_LOCAL_PORT,
+                next => next,
+            };
+            if !self.connections.contains_key(&(port, peer_port)) {
+                return port;
+            }
+        }
+    }
+
+    // Connects to the socket for the port requested by the driver.
+    fn connect(&mut self, hdr: &PacketHeader) {
+        let path = format!("{}_{}", self.path.display(), { hdr.dst_port });
+        let stream = match UnixStream::connect(&path)
+            .and_then(|stream| stream.set_nonblocking(true).map(|_| stream))
+        {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("failed to connect to {}: {}", path, e);
+                self.rst_queue.push_back(hdr.reply(VIRTIO_VSOCK_OP_RST));
+                return;
+            }
+        };
+        if let Err(e) = self.register(&stream) {
+            warn!("failed to register vsock connection: {}", e);
+            self.rst_queue.push_back(hdr.reply(VIRTIO_VSOCK_OP_RST));
+            return;
+        }
+        let key = (hdr.dst_port, hdr.src_port);
+        self.conn_fds.insert(stream.as_raw_fd(), key);
+        self.connections
+            .insert(key, Connection::new_peer_init(stream, hdr));
+    }
+
+    // Registers a stream for the readable and writable events, which are edge triggered since
+    // the connections keep track of the stream state.
+    fn register(&self, stream: &UnixStream) -> io::Result<()> {
+        stream.set_nonblocking(true)?;
+        let fd = stream.as_raw_fd();
+        let events =
+            EventSet::IN | EventSet::OUT | EventSet::READ_HANG_UP | EventSet::EDGE_TRIGGERED;
+        self.epoll.ctl(
+            ControlOperation::Add,
+            fd,
+            EpollEvent::new(events, fd as u64),
+        )
+    }
+
+    // Drops the connection if it was reset, and doesn't have pending packets.
+    fn remove_if_done(&mut self, key: ConnectionKey) {
+        if self.connections.get(&key).is_some_and(Connection::is_done) {
+            // Closing the stream also removes it from the epoll interest list.
+            if let Some(conn) = self.connections.remove(&key) {
+                self.conn_fds.remove(&conn.stream().as_raw_fd());
+            }
+        }
+    }
+}
+
+// Parses a `CONNECT <port>` line.
+fn parse_connect(line: &[u8]) -> Option<u32> {
+    let line = std::str::from_utf8(line).ok()?;
+    let port = line.trim_end_matches('\r').strip_prefix("CONNECT ")?;
+    port.trim().parse().ok()
+}
+
+impl VsockBackend for UnixMuxer {
+    fn send_pkt(&mut self, pkt: &Packet) {
+        let hdr = &pkt.hdr;
+        if hdr.dst_cid != VMADDR_CID_HOST {
+            warn!("dropping vsock packet for cid {}", { hdr.dst_cid });
+            return;
+        }
+        if hdr.type_ != VIRTIO_VSOCK_TYPE_STREAM {
+            if hdr.op != VIRTIO_VSOCK_OP_RST {
+                self.rst_queue.push_back(hdr.reply(VIRTIO_VSOCK_OP_RST));
+            }
+            return;
+        }
+
+        let key = (hdr.dst_port, hdr.src_port);
+        match self.connections.get_mut(&key) {
+            Some(conn) => {
+                let state = conn.state();
+                conn.send_pkt(pkt);
+                // Host applications are told when the driver accepts their connection.
+                if state == ConnectionState::LocalInit
+                    && conn.state() == ConnectionState::Established
+                {
+                    let ack = format!("OK {}\n", key.0);
+                    if let Err(e) = conn.stream_mut().write_all(ack.as_bytes()) {
+                        warn!("failed to acknowledge vsock host connection: {}", e);
+                        conn.kill();
+                    }
+                }
+                self.remove_if_done(key);
+            }
+            None if hdr.op == VIRTIO_VSOCK_OP_REQUEST => self.connect(hdr),
+            None if hdr.op != VIRTIO_VSOCK_OP_RST => {
+                self.rst_queue.push_back(hdr.reply(VIRTIO_VSOCK_OP_RST))
+            }
+            None => (),
+        }
+    }
+
+    fn recv_pkt(&mut self, max_data_len: usize) -> Option<Packet> {
+        if let Some(hdr) = self.rst_queue.pop_front() {
+            return Some(Packet::new(hdr, Vec::new()));
+        }
+
+        let keys: Vec<_> = self
+            .connections
+            .iter()
+            .filter(|(_, conn)| conn.has_pending_rx())
+            .map(|(key, _)| *key)
+            .collect();
+        for key in keys {
+            // The key was just collected from the map.
+            let pkt = self
+                .connections
+                .get_mut(&key)
+                .unwrap()
+                .recv_pkt(max_data_len);
+            self.remove_if_done(key);
+            if pkt.is_some() {
+                return pkt;
+            }
+        }
+        None
+    }
+
+    fn has_pending_rx(&self) -> bool {
+        !self.rst_queue.is_empty() || self.connections.values().any(Connection::has_pending_rx)
+    }
+
+    fn reset(&mut self) {
+        self.connections.clear();
+        self.conn_fds.clear();
+        self.rst_queue.clear();
+    }
+}
+
+impl AsRawFd for UnixMuxer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll.as_raw_fd()
+    }
+}
+
+impl Drop for UnixMuxer {
+    fn drop(&mut self) {
+        // The socket file is left behind when the listener is closed.
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vmm_sys_util::tempdir::TempDir;
+
+    use crate::defs::{VIRTIO_VSOCK_OP_RESPONSE, VIRTIO_VSOCK_OP_RW};
+
+    const GUEST_CID: u64 = 3;
+
+    fn guest_pkt(src_port: u32, dst_port: u32, op: u16, data: &[u8]) -> Packet {
+        let hdr = PacketHeader {
+            src_cid: GUEST_CID,
+            dst_cid: VMADDR_CID_HOST,
+            src_port,
+            dst_port,
+            type_: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            buf_alloc: 0x1000,
+            ..Default::default()
+        };
+        Packet::new(hdr, data.to_vec())
+    }
+
+    #[test]
+    fn test_parse_connect() {
+        assert_eq!(parse_connect(b"CONNECT 52"), Some(52));
+        assert_eq!(parse_connect(b"CONNECT 52\r"), Some(52));
+        assert_eq!(parse_connect(b"CONNECT"), None);
+        assert_eq!(parse_connect(b"CONNECT x"), None);
+        assert_eq!(parse_connect(b"LISTEN 52"), None);
+    }
+
+    #[test]
+    fn test_guest_connect() {
+        let dir = TempDir::new_with_prefix("/tmp/vsock").unwrap();
+        let path = dir.as_path().join("vsock");
+        let mut muxer = UnixMuxer::new(GUEST_CID, &path).unwrap();
+        let listener = UnixListener::bind(format!("{}_52", path.display())).unwrap();
+
+        // Nobody listens on port 53.
+        muxer.send_pkt(&guest_pkt(1024, 53, VIRTIO_VSOCK_OP_REQUEST, &[]));
+        assert!(muxer.has_pending_rx());
+        let rst = muxer.recv_pkt(0).unwrap();
+        assert_eq!({ rst.hdr.op }, VIRTIO_VSOCK_OP_RST);
+        assert_eq!({ rst.hdr.src_port }, 53);
+        assert_eq!({ rst.hdr.dst_port }, 1024);
+
+        muxer.send_pkt(&guest_pkt(1024, 52, VIRTIO_VSOCK_OP_REQUEST, &[]));
+        let (mut app, _) = listener.accept().unwrap();
+        assert_eq!(muxer.num_connections(), 1);
+        let response = muxer.recv_pkt(0).unwrap();
+        assert_eq!({ response.hdr.op }, VIRTIO_VSOCK_OP_RESPONSE);
+        assert!(!muxer.has_pending_rx());
+
+        muxer.send_pkt(&guest_pkt(1024, 52, VIRTIO_VSOCK_OP_RW, b"ping"));
+        let mut buf = [0u8; 4];
+        app.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        app.write_all(b"pong").unwrap();
+        muxer.process_events().unwrap();
+        assert!(muxer.has_pending_rx());
+        let pkt = muxer.recv_pkt(0x1000).unwrap();
+        assert_eq!({ pkt.hdr.op }, VIRTIO_VSOCK_OP_RW);
+        assert_eq!({ pkt.hdr.src_port }, 52);
+        assert_eq!(pkt.data, b"pong");
+        // The stream is considered readable until it runs out of data.
+        assert!(muxer.recv_pkt(0x1000).is_none());
+
+        // Packets for unknown connections are answered with resets.
+        muxer.send_pkt(&guest_pkt(1025, 52, VIRTIO_VSOCK_OP_RW, b"data"));
+        assert_eq!({ muxer.recv_pkt(0).unwrap().hdr.dst_port }, 1025);
+        muxer.send_pkt(&guest_pkt(1025, 52, VIRTIO_VSOCK_OP_RST, &[]));
+        assert!(!muxer.has_pending_rx());
+
+        muxer.send_pkt(&guest_pkt(1024, 52, VIRTIO_VSOCK_OP_RST, &[]));
+        assert_eq!(muxer.num_connections(), 0);
+    }
+
+    #[test]
+    fn test_host_connect() {
+        let dir = TempDir::new_with_prefix("/tmp/vsock").unwrap();
+        let path = dir.as_path().join("vsock");
+        let mut muxer = UnixMuxer::new(GUEST_CID, &path).unwrap();
+
+        // An invalid request is dropped.
+        let mut app = UnixStream::connect(&path).unwrap();
+        app.write_all(b"LISTEN 80\n").unwrap();
+        muxer.process_events().unwrap();
+        assert_eq!(muxer.num_connections(), 0);
+        assert_eq!(app.read(&mut [0u8; 4]).unwrap(), 0);
+
+        let mut app = UnixStream::connect(&path).unwrap();
+        app.write_all(b"CONNECT 80\n").unwrap();
+        muxer.process_events().unwrap();
+        assert_eq!(muxer.num_connections(), 1);
+        let request = muxer.recv_pkt(0).unwrap();
+        assert_eq!({ request.hdr.op }, VIRTIO_VSOCK_OP_REQUEST);
+        assert_eq!({ request.hdr.src_cid }, VMADDR_CID_HOST);
+        assert_eq!({ request.hdr.src_port }, FIRST_LOCAL_PORT);
+        assert_eq!({ request.hdr.dst_cid }, GUEST_CID);
+        assert_eq!({ request.hdr.dst_port }, 80);
+
+        muxer.send_pkt(&guest_pkt(
+            80,
+            FIRST_LOCAL_PORT,
+            VIRTIO_VSOCK_OP_RESPONSE,
+            &[],
+        ));
+        let ack = format!("OK {}\n", FIRST_LOCAL_PORT);
+        let mut buf = vec![0u8; ack.len()];
+        app.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, ack.as_bytes());
+
+        // The host application goes away.
+        drop(app);
+        muxer.process_events().unwrap();
+        assert!(muxer.recv_pkt(0x1000).is_some());
+        muxer.send_pkt(&guest_pkt(80, FIRST_LOCAL_PORT, VIRTIO_VSOCK_OP_RST, &[]));
+        assert_eq!(muxer.num_connections(), 0);
+
+        // The socket file is removed along with the muxer.
+        drop(muxer);
+        assert!(!path.exists());
+    }
+}
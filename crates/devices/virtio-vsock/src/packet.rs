@@ -0,0 +1,412 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Virtio vsock packet abstractions.
+//!
+//! Every buffer which goes through the receive or transmit queues of a vsock device holds a
+//! packet, which starts with a header that identifies the connection, the operation, and the
+//! buffer space of the sender. This module provides the following abstractions:
+//!
+//! - [`PacketHeader`](struct.PacketHeader.html) which is the `virtio_vsock_hdr` structure from
+//!   the virtio specification.
+//! - [`Packet`](struct.Packet.html) which holds a header and the data that follows it, and can
+//!   be read from a transmit descriptor chain, or written to a receive descriptor chain. The
+//!   header can span multiple descriptors, and it can share a descriptor with the data.
+//! - [`VsockEvent`](struct.VsockEvent.html) which is the `virtio_vsock_event` structure, sent
+//!   through the event queue.
+
+use std::cmp::min;
+use std::fmt::{self, Display};
+use std::mem::size_of;
+use std::result;
+
+use vm_memory::{ByteValued, GuestAddressSpace, GuestMemoryError};
+
+use virtio_queue::areas::{self, areas_len, read_areas, write_areas, Area};
+use virtio_queue::DescriptorChain;
+
+use crate::defs::VIRTIO_VSOCK_TYPE_STREAM;
+
+/// The largest amount of data accepted in a packet (the same limit as the Linux driver).
+pub const MAX_PKT_DATA_LEN: u32 = 64 * 1024;
+
+/// Vsock packet errors.
+#[derive(Debug)]
+pub enum Error {
+    /// The descriptor chain is too short to hold the packet.
+    DescriptorChainTooShort,
+    /// Invalid memory access.
+    GuestMemory(GuestMemoryError),
+    /// The packet carries more data than what the device accepts.
+    PacketTooLarge(u32),
+    /// Read only descriptor in a receive chain.
+    UnexpectedReadOnlyDescriptor,
+    /// Write only descriptor in a transmit chain.
+    UnexpectedWriteOnlyDescriptor,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            DescriptorChainTooShort => write!(f, "descriptor chain too short for the packet"),
+            GuestMemory(ref err) => write!(f, "error accessing guest memory: {}", err),
+            PacketTooLarge(len) => write!(f, "packet data too large: {} bytes", len),
+            UnexpectedReadOnlyDescriptor => write!(f, "unexpected read only descriptor"),
+            UnexpectedWriteOnlyDescriptor => write!(f, "unexpected write only descriptor"),
+        }
+    }
+}
+
+impl From<areas::Error> for Error {
+    fn from(e: areas::Error) -> Self {
+        match e {
+            areas::Error::TooShort => Error::DescriptorChainTooShort,
+            areas::Error::GuestMemory(e) => Error::GuestMemory(e),
+            areas::Error::UnexpectedReadOnlyDescriptor => Error::UnexpectedReadOnlyDescriptor,
+        }
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// The header of the packets exchanged through the vsock device queues (the
+/// `virtio_vsock_hdr` structure from the virtio specification).
+///
+/// All fields are expected to hold little endian values, which is always the case on the
+/// platforms that are currently supported (`x86_64` and `aarch64`).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C, packed)]
+pub struct PacketHeader {
+    /// The context identifier of the source.
+    pub src_cid: u64,
+    /// The context identifier of the destination.
+    pub dst_cid: u64,
+    /// The port of the source.
+    pub src_port: u32,
+    /// The port of the destination.
+    pub dst_port: u32,
+    /// The length of the data that follows the header.
+    pub len: u32,
+    /// The socket type.
+    pub type_: u16,
+    /// The operation.
+    pub op: u16,
+    /// Operation specific flags.
+    pub flags: u32,
+    /// The size of the receive buffer of the source.
+    pub buf_alloc: u32,
+    /// The number of bytes the source consumed from its receive buffer.
+    pub fwd_cnt: u32,
+}
+
+// Safe because PacketHeader only contains plain data, and there's no padding between (or
+// after) the fields since the structure is packed.
+unsafe impl ByteValued for PacketHeader {}
+
+impl PacketHeader {
+    /// The size of the packet header.
+    pub const LEN: usize = size_of::<PacketHeader>();
+
+    /// Returns a stream socket header without any data or credit information, which is
+    /// addressed back to the source of this header.
+    ///
+    /// # Arguments
+    /// * `op` - The operation of the reply.
+    pub fn reply(&self, op: u16) -> Self {
+        PacketHeader {
+            src_cid: self.dst_cid,
+            dst_cid: self.src_cid,
+            src_port: self.dst_port,
+            dst_port: self.src_port,
+            type_: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            ..Default::default()
+        }
+    }
+}
+
+/// A vsock packet, which consists of a header and the data that follows it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Packet {
+    /// The packet header.
+    pub hdr: PacketHeader,
+    /// The packet data, which is only present for `VIRTIO_VSOCK_OP_RW` packets.
+    pub data: Vec<u8>,
+}
+
+impl Packet {
+    /// Creates a new `Packet`, and updates the length of the header to match `data`.
+    ///
+    /// # Arguments
+    /// * `hdr` - The packet header.
+    /// * `data` - The packet data.
+    pub fn new(mut hdr: PacketHeader, data: Vec<u8>) -> Self {
+        // The data length is bounded by `MAX_PKT_DATA_LEN` for all the packets exchanged with
+        // the driver.
+        hdr.len = data.len() as u32;
+        Packet { hdr, data }
+    }
+
+    /// Reads a packet from a transmit descriptor chain, which can only contain device-readable
+    /// descriptors.
+    ///
+    /// # Arguments
+    /// * `chain` - The transmit descriptor chain.
+    pub fn read_from_chain<M: GuestAddressSpace>(chain: &mut DescriptorChain<M>) -> Result<Self> {
+        let areas = chain_areas(chain, false)?;
+        let mut hdr = PacketHeader::default();
+        read_areas(chain.memory(), &areas, 0, hdr.as_mut_slice())?;
+        if hdr.len > MAX_PKT_DATA_LEN {
+            return Err(Error::PacketTooLarge(hdr.len));
+        }
+        let mut data = vec![0; hdr.len as usize];
+        read_areas(chain.memory(), &areas, PacketHeader::LEN, &mut data)?;
+        Ok(Packet { hdr, data })
+    }
+
+    /// Writes the packet to a receive descriptor chain, which can only contain device-writable
+    /// descriptors. Returns the number of bytes written.
+    ///
+    /// # Arguments
+    /// * `chain` - The receive descriptor chain.
+    pub fn write_to_chain<M: GuestAddressSpace>(
+        &self,
+        chain: &mut DescriptorChain<M>,
+    ) -> Result<u32> {
+        let areas = chain_areas(chain, true)?;
+        let mut hdr = self.hdr;
+        hdr.len = self.data.len() as u32;
+        write_areas(chain.memory(), &areas, 0, hdr.as_slice())?;
+        write_areas(chain.memory(), &areas, PacketHeader::LEN, &self.data)?;
+        Ok((PacketHeader::LEN + self.data.len()) as u32)
+    }
+}
+
+/// Returns the amount of packet data that fits in a receive descriptor chain (after the
+/// header), without consuming the chain.
+///
+/// # Arguments
+/// * `chain` - The receive descriptor chain.
+pub fn rx_data_capacity<M: GuestAddressSpace>(chain: &DescriptorChain<M>) -> Result<usize> {
+    areas_len(&chain_areas(&mut chain.clone(), true)?)
+        .checked_sub(PacketHeader::LEN)
+        .map(|len| min(len, MAX_PKT_DATA_LEN as usize))
+        .ok_or(Error::DescriptorChainTooShort)
+}
+
+/// An event sent through the event queue (the `virtio_vsock_event` structure from the virtio
+/// specification).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C, packed)]
+pub struct VsockEvent {
+    /// The event identifier.
+    pub id: u32,
+}
+
+// Safe because VsockEvent only contains plain data.
+unsafe impl ByteValued for VsockEvent {}
+
+impl VsockEvent {
+    /// Writes the event to an event descriptor chain, which can only contain device-writable
+    /// descriptors. Returns the number of bytes written.
+    ///
+    /// # Arguments
+    /// * `chain` - The event descriptor chain.
+    pub fn write_to_chain<M: GuestAddressSpace>(
+        &self,
+        chain: &mut DescriptorChain<M>,
+    ) -> Result<u32> {
+        let areas = chain_areas(chain, true)?;
+        write_areas(chain.memory(), &areas, 0, self.as_slice())?;
+        Ok(size_of::<Self>() as u32)
+    }
+}
+
+// Consumes the descriptors of `chain`, which have to be device-writable (or device-readable),
+// and returns the memory areas they cover.
+fn chain_areas<M: GuestAddressSpace>(
+    chain: &mut DescriptorChain<M>,
+    writable: bool,
+) -> Result<Vec<Area>> {
+    chain
+        .map(|desc| {
+            if desc.is_write_only() == writable {
+                Ok((desc.addr(), desc.len() as usize))
+            } else if writable {
+                Err(Error::UnexpectedReadOnlyDescriptor)
+            } else {
+                Err(Error::UnexpectedWriteOnlyDescriptor)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::mem::offset_of;
+
+    use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+    use virtio_device::mock::adjacent_regions;
+    use virtio_queue::mock::{AttackPattern, VirtQueue};
+    use virtio_queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    use crate::defs::{VIRTIO_VSOCK_OP_RST, VIRTIO_VSOCK_OP_RW};
+
+    fn header() -> PacketHeader {
+        PacketHeader {
+            src_cid: 3,
+            dst_cid: 2,
+            src_port: 1024,
+            dst_port: 52,
+            type_: VIRTIO_VSOCK_TYPE_STREAM,
+            op: VIRTIO_VSOCK_OP_RW,
+            buf_alloc: 0x1000,
+            fwd_cnt: 0x10,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(PacketHeader::LEN, 44);
+        assert_eq!(offset_of!(PacketHeader, len), 24);
+        assert_eq!(offset_of!(PacketHeader, op), 30);
+        assert_eq!(offset_of!(PacketHeader, fwd_cnt), 40);
+        assert_eq!(size_of::<VsockEvent>(), 4);
+
+        let reply = header().reply(VIRTIO_VSOCK_OP_RST);
+        assert_eq!({ reply.src_cid }, 2);
+        assert_eq!({ reply.dst_cid }, 3);
+        assert_eq!({ reply.src_port }, 52);
+        assert_eq!({ reply.dst_port }, 1024);
+        assert_eq!({ reply.op }, VIRTIO_VSOCK_OP_RST);
+        assert_eq!({ reply.buf_alloc }, 0);
+    }
+
+    #[test]
+    fn test_read_from_chain() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let mut queue = vq.create_queue(&mem);
+
+        let pkt = Packet::new(header(), vec![0xab; 100]);
+        let mut bytes = pkt.hdr.as_slice().to_vec();
+        bytes.extend_from_slice(&pkt.data);
+        mem.write_slice(&bytes, GuestAddress(0x1_0000)).unwrap();
+
+        // The header is split across the first two descriptors, and shares the second one
+        // with the data.
+        vq.dtable(0).set(0x1_0000, 20, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1).set(0x1_0014, 124, 0, 0);
+        vq.avail.ring(0).store(0);
+        // A chain which is shorter than the packet data.
+        vq.dtable(2).set(0x1_0000, 50, 0, 0);
+        vq.avail.ring(1).store(2);
+        // A chain with a write only descriptor.
+        vq.dtable(3).set(0x1_0000, 144, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring(2).store(3);
+        vq.avail.idx().store(3);
+
+        let mut iter = queue.iter().unwrap();
+        assert_eq!(
+            Packet::read_from_chain(&mut iter.next().unwrap()).unwrap(),
+            pkt
+        );
+        assert!(matches!(
+            Packet::read_from_chain(&mut iter.next().unwrap()),
+            Err(Error::DescriptorChainTooShort)
+        ));
+        assert!(matches!(
+            Packet::read_from_chain(&mut iter.next().unwrap()),
+            Err(Error::UnexpectedWriteOnlyDescriptor)
+        ));
+
+        // The data length is validated before reading it.
+        let mut hdr = header();
+        hdr.len = MAX_PKT_DATA_LEN + 1;
+        mem.write_obj(hdr, GuestAddress(0x1_0000)).unwrap();
+        vq.avail.ring(3).store(0);
+        vq.avail.idx().store(4);
+        let mut chain = queue.iter().unwrap().next().unwrap();
+        assert!(matches!(
+            Packet::read_from_chain(&mut chain),
+            Err(Error::PacketTooLarge(len)) if len == MAX_PKT_DATA_LEN + 1
+        ));
+    }
+
+    #[test]
+    fn test_write_to_chain() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let mut queue = vq.create_queue(&mem);
+
+        vq.dtable(0)
+            .set(0x1_0000, 30, VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1).set(0x2_0000, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring(0).store(0);
+        vq.dtable(2).set(0x3_0000, 40, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring(1).store(2);
+        vq.dtable(3).set(0x4_0000, 0x100, 0, 0);
+        vq.avail.ring(2).store(3);
+        vq.avail.idx().store(3);
+
+        let mut chain = queue.iter().unwrap().next().unwrap();
+        assert_eq!(rx_data_capacity(&chain).unwrap(), 30 + 0x1000 - 44);
+        // The header length is updated when writing the packet.
+        let pkt = Packet {
+            hdr: header(),
+            data: vec![0xcd; 10],
+        };
+        assert_eq!(pkt.write_to_chain(&mut chain).unwrap(), 54);
+        let hdr: PacketHeader = mem.read_obj(GuestAddress(0x1_0000)).unwrap();
+        assert_eq!({ hdr.len }, 10);
+        assert_eq!({ hdr.src_port }, 1024);
+        let mut data = [0u8; 10];
+        mem.read_slice(&mut data, GuestAddress(0x2_000e)).unwrap();
+        assert_eq!(data, [0xcd; 10]);
+
+        let mut chain = queue.iter().unwrap().next().unwrap();
+        assert!(matches!(
+            rx_data_capacity(&chain),
+            Err(Error::DescriptorChainTooShort)
+        ));
+        assert!(matches!(
+            pkt.write_to_chain(&mut chain),
+            Err(Error::DescriptorChainTooShort)
+        ));
+
+        let mut chain = queue.iter().unwrap().next().unwrap();
+        assert!(matches!(
+            VsockEvent { id: 0 }.write_to_chain(&mut chain),
+            Err(Error::UnexpectedReadOnlyDescriptor)
+        ));
+    }
+
+    #[test]
+    fn test_attack_patterns() {
+        let mem = adjacent_regions(0x10_0000);
+        let pkt = Packet::new(header(), vec![0xab; 100]);
+        for &pattern in AttackPattern::ALL.iter() {
+            let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+            vq.add_attack(pattern, 0, GuestAddress(0x1_0000));
+            let mut chain = vq.create_queue(&mem).iter().unwrap().next().unwrap();
+            // A loop of device-readable descriptors repeats the same buffer up to the queue size,
+            // which is still a valid (empty) packet.
+            assert_eq!(
+                Packet::read_from_chain(&mut chain.clone()).is_ok(),
+                [AttackPattern::SelfReference].contains(&pattern),
+                "{:?}",
+                pattern
+            );
+            assert!(rx_data_capacity(&chain).is_err(), "{:?}", pattern);
+            assert!(pkt.write_to_chain(&mut chain).is_err(), "{:?}", pattern);
+        }
+    }
+}
@@ -0,0 +1,269 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A crate that provides derive macros for the building blocks of the `virtio-device` crate.
+//!
+//! This crate is not meant to be used directly; the macros are re-exported by `virtio-device`
+//! when its `derive` feature is enabled.
+
+#![deny(missing_docs)]
+
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Expr, Fields, Member, Result, Type};
+
+/// Derives the `VirtioDeviceType` implementation, along with the `Borrow<VirtioConfig<M>>` and
+/// `BorrowMut<VirtioConfig<M>>` ones, for a device object.
+///
+/// The device type is provided by the `#[virtio(device_type = ...)]` attribute of the structure,
+/// which accepts any expression (such as a constant), and the field which holds the
+/// `VirtioConfig` is marked with the `#[virtio(config)]` attribute. Together with a
+/// `VirtioDeviceActions` implementation, this enables the automatic `VirtioDevice`
+/// implementation for the device.
+///
+/// # Example
+///
+/// ```ignore
+/// #[derive(VirtioDeviceCommon)]
+/// #[virtio(device_type = VIRTIO_ID_BLOCK)]
+/// pub struct Block<M: GuestAddressSpace> {
+///     #[virtio(config)]
+///     cfg: VirtioConfig<M>,
+/// }
+/// ```
+#[proc_macro_derive(VirtioDeviceCommon, attributes(virtio))]
+pub fn derive_virtio_device_common(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+// Returns the expression of the `device_type` key from the `virtio` attributes of the
+// structure.
+fn device_type(input: &DeriveInput) -> Result<Expr> {
+    let mut device_type = None;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("virtio")) {
+        attr.parse_nested_meta(|meta| {
+            if !meta.path.is_ident("device_type") {
+                return Err(meta.error("expected `device_type = ...`"));
+            }
+            if device_type.is_some() {
+                return Err(meta.error("duplicate `device_type` attribute"));
+            }
+            device_type = Some(meta.value()?.parse()?);
+            Ok(())
+        })?;
+    }
+    device_type.ok_or_else(|| {
+        Error::new(
+            input.ident.span(),
+            "missing `#[virtio(device_type = ...)]` attribute",
+        )
+    })
+}
+
+// Returns the member and the type of the field marked with `#[virtio(config)]`.
+fn config_field(input: &DeriveInput) -> Result<(Member, Type)> {
+    let fields = match input.data {
+        Data::Struct(ref data) => &data.fields,
+        _ => {
+            return Err(Error::new(
+                input.ident.span(),
+                "`VirtioDeviceCommon` can only be derived for structures",
+            ))
+        }
+    };
+    let fields: Vec<_> = match fields {
+        Fields::Named(fields) => fields.named.iter().collect(),
+        Fields::Unnamed(fields) => fields.unnamed.iter().collect(),
+        Fields::Unit => Vec::new(),
+    };
+
+    let mut config = None;
+    for (index, field) in fields.into_iter().enumerate() {
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("virtio")) {
+            attr.parse_nested_meta(|meta| {
+                if !meta.path.is_ident("config") {
+                    return Err(meta.error("expected `config`"));
+                }
+                if config.is_some() {
+                    return Err(meta.error("multiple fields are marked with `config`"));
+                }
+                let member = match field.ident {
+                    Some(ref ident) => Member::Named(ident.clone()),
+                    None => Member::Unnamed(index.into()),
+                };
+                config = Some((member, field.ty.clone()));
+                Ok(())
+            })?;
+        }
+    }
+    config.ok_or_else(|| {
+        Error::new(
+            input.ident.span(),
+            "missing a field marked with `#[virtio(config)]`",
+        )
+    })
+}
+
+fn expand(input: DeriveInput) -> Result<TokenStream> {
+    let device_type = device_type(&input)?;
+    let (member, ty) = config_field(&input)?;
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::virtio_device::VirtioDeviceType for #name #ty_generics
+        #where_clause
+        {
+            fn device_type(&self) -> u32 {
+                #device_type
+            }
+        }
+
+        impl #impl_generics ::core::borrow::Borrow<#ty> for #name #ty_generics #where_clause {
+            fn borrow(&self) -> &#ty {
+                &self.#member
+            }
+        }
+
+        impl #impl_generics ::core::borrow::BorrowMut<#ty> for #name #ty_generics #where_clause {
+            fn borrow_mut(&mut self) -> &mut #ty {
+                &mut self.#member
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use syn::parse_quote;
+
+    fn expand_err(input: DeriveInput) -> String {
+        expand(input).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_expand() {
+        let input: DeriveInput = parse_quote! {
+            #[virtio(device_type = VIRTIO_ID_BLOCK)]
+            struct Block<M: GuestAddressSpace, B> where B: Backend {
+                backend: B,
+                #[virtio(config)]
+                cfg: VirtioConfig<M>,
+            }
+        };
+        let expected = quote! {
+            impl<M: GuestAddressSpace, B> ::virtio_device::VirtioDeviceType for Block<M, B>
+            where B: Backend
+            {
+                fn device_type(&self) -> u32 {
+                    VIRTIO_ID_BLOCK
+                }
+            }
+
+            impl<M: GuestAddressSpace, B> ::core::borrow::Borrow<VirtioConfig<M> > for Block<M, B>
+            where B: Backend
+            {
+                fn borrow(&self) -> &VirtioConfig<M> {
+                    &self.cfg
+                }
+            }
+
+            impl<M: GuestAddressSpace, B> ::core::borrow::BorrowMut<VirtioConfig<M> >
+                for Block<M, B>
+            where B: Backend
+            {
+                fn borrow_mut(&mut self) -> &mut VirtioConfig<M> {
+                    &mut self.cfg
+                }
+            }
+        };
+        assert_eq!(expand(input).unwrap().to_string(), expected.to_string());
+
+        // Tuple structures are supported as well.
+        let input: DeriveInput = parse_quote! {
+            #[virtio(device_type = 2)]
+            struct Dummy(u32, #[virtio(config)] VirtioConfig<Mem>);
+        };
+        let tokens = expand(input).unwrap().to_string();
+        assert!(tokens.contains(&quote!(&self.1).to_string()));
+    }
+
+    #[test]
+    fn test_errors() {
+        let input: DeriveInput = parse_quote! {
+            struct Dummy {
+                #[virtio(config)]
+                cfg: VirtioConfig<Mem>,
+            }
+        };
+        assert_eq!(
+            expand_err(input),
+            "missing `#[virtio(device_type = ...)]` attribute"
+        );
+
+        let input: DeriveInput = parse_quote! {
+            #[virtio(device_type = 2)]
+            #[virtio(device_type = 3)]
+            struct Dummy {
+                #[virtio(config)]
+                cfg: VirtioConfig<Mem>,
+            }
+        };
+        assert_eq!(expand_err(input), "duplicate `device_type` attribute");
+
+        let input: DeriveInput = parse_quote! {
+            #[virtio(device_id = 2)]
+            struct Dummy;
+        };
+        assert_eq!(expand_err(input), "expected `device_type = ...`");
+
+        let input: DeriveInput = parse_quote! {
+            #[virtio(device_type = 2)]
+            struct Dummy {
+                cfg: VirtioConfig<Mem>,
+            }
+        };
+        assert_eq!(
+            expand_err(input),
+            "missing a field marked with `#[virtio(config)]`"
+        );
+
+        let input: DeriveInput = parse_quote! {
+            #[virtio(device_type = 2)]
+            struct Dummy {
+                #[virtio(config)]
+                cfg: VirtioConfig<Mem>,
+                #[virtio(config)]
+                other: VirtioConfig<Mem>,
+            }
+        };
+        assert_eq!(
+            expand_err(input),
+            "multiple fields are marked with `config`"
+        );
+
+        let input: DeriveInput = parse_quote! {
+            #[virtio(device_type = 2)]
+            struct Dummy {
+                #[virtio(cfg)]
+                cfg: VirtioConfig<Mem>,
+            }
+        };
+        assert_eq!(expand_err(input), "expected `config`");
+
+        let input: DeriveInput = parse_quote! {
+            #[virtio(device_type = 2)]
+            enum Dummy {}
+        };
+        assert_eq!(
+            expand_err(input),
+            "`VirtioDeviceCommon` can only be derived for structures"
+        );
+    }
+}
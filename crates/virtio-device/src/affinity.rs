@@ -0,0 +1,145 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Placement of the queue processing workers on host CPUs.
+//!
+//! The devices don't spawn any threads by themselves; the VMM decides how their queues are
+//! processed. Multiqueue devices are usually processed by one worker thread per queue (or per
+//! queue pair, for network devices), and those workers have to run on the CPUs which are close
+//! to the backing storage or NIC to keep the processing NUMA-local.
+//!
+//! [`QueueAffinity`](struct.QueueAffinity.html) holds the host CPU of each queue, as configured
+//! by the user. The device builders accept one, and a worker is expected to call
+//! [`QueueAffinity::pin`](struct.QueueAffinity.html#method.pin) with the index of its queue
+//! before it starts processing it.
+
+use std::io;
+use std::mem;
+
+/// Pins the calling thread to the host CPU `cpu`.
+///
+/// # Arguments
+/// * `cpu` - The index of the host CPU.
+pub fn pin_current_thread(cpu: usize) -> io::Result<()> {
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+    // Safe because `cpu_set_t` is a plain bit mask, for which all zeroes is a valid value, and
+    // `cpu` was checked against its size.
+    let ret = unsafe {
+        let mut cpu_set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_SET(cpu, &mut cpu_set);
+        // The pid 0 stands for the calling thread.
+        libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &cpu_set)
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The host CPUs the workers of the queues of a device have to run on.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueueAffinity {
+    /// The CPU of each queue, indexed by queue, where `None` means the worker isn't pinned.
+    cpus: Vec<Option<usize>>,
+}
+
+impl QueueAffinity {
+    /// Creates an object which doesn't pin any worker.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Places the worker of a queue on a host CPU.
+    ///
+    /// # Arguments
+    /// * `queue` - The index of the queue (or of the queue pair, for network devices).
+    /// * `cpu` - The index of the host CPU.
+    pub fn with_cpu(mut self, queue: u16, cpu: usize) -> Self {
+        let queue = usize::from(queue);
+        if self.cpus.len() <= queue {
+            self.cpus.resize(queue + 1, None);
+        }
+        self.cpus[queue] = Some(cpu);
+        self
+    }
+
+    /// Returns the host CPU of the worker of a queue, if it's pinned.
+    ///
+    /// # Arguments
+    /// * `queue` - The index of the queue.
+    pub fn cpu(&self, queue: u16) -> Option<usize> {
+        self.cpus.get(usize::from(queue)).copied().flatten()
+    }
+
+    /// Returns the number of queues the object can place, which is one more than the largest
+    /// index of a pinned queue.
+    pub fn num_queues(&self) -> u16 {
+        // The cast is safe because the queues are indexed with `u16`s.
+        self.cpus.len() as u16
+    }
+
+    /// Pins the calling thread to the host CPU of a queue, and returns whether the queue has
+    /// one.
+    ///
+    /// # Arguments
+    /// * `queue` - The index of the queue the calling thread processes.
+    pub fn pin(&self, queue: u16) -> io::Result<bool> {
+        match self.cpu(queue) {
+            Some(cpu) => pin_current_thread(cpu).map(|()| true),
+            None => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    // Returns the CPUs the calling thread is allowed to run on.
+    fn current_cpus() -> Vec<usize> {
+        // Safe because the kernel writes at most `size_of::<cpu_set_t>()` bytes.
+        unsafe {
+            let mut cpu_set: libc::cpu_set_t = mem::zeroed();
+            assert_eq!(
+                libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut cpu_set),
+                0
+            );
+            (0..libc::CPU_SETSIZE as usize)
+                .filter(|&cpu| libc::CPU_ISSET(cpu, &cpu_set))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_queue_affinity() {
+        let affinity = QueueAffinity::new().with_cpu(2, 1).with_cpu(0, 3);
+        assert_eq!(affinity.cpu(0), Some(3));
+        assert_eq!(affinity.cpu(1), None);
+        assert_eq!(affinity.cpu(2), Some(1));
+        assert_eq!(affinity.cpu(3), None);
+        assert_eq!(affinity.num_queues(), 3);
+
+        assert_eq!(
+            pin_current_thread(libc::CPU_SETSIZE as usize)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+
+        // Pin a fresh thread to one of the CPUs the tests can run on.
+        let cpu = current_cpus()[0];
+        thread::spawn(move || {
+            let affinity = QueueAffinity::new().with_cpu(1, cpu);
+            assert!(!affinity.pin(0).unwrap());
+            assert!(affinity.pin(1).unwrap());
+            assert_eq!(current_cpus(), vec![cpu]);
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,546 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Byte stream backends for consoles and serial ports.
+//!
+//! This module provides the [`ByteStreamBackend`](trait.ByteStreamBackend.html) interface, which
+//! connects the ports of a console (or a similar character device) to the host, along with the
+//! following implementations:
+//!
+//! - [`FileStream`](struct.FileStream.html) which reads from (and writes to) files, such as the
+//!   standard input and output of the VMM, pipes, or log files.
+//! - [`PtyStream`](struct.PtyStream.html) which allocates a pseudo-terminal, and exposes the
+//!   path of its slave side to the users.
+//! - [`UnixSocketStream`](struct.UnixSocketStream.html) which connects to (or accepts a
+//!   connection on) a Unix domain socket.
+//!
+//! None of the operations block, so the device is expected to read from a backend when its
+//! input file descriptor becomes readable, which also happens when the other end hangs up.
+
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+/// A bidirectional stream of bytes, which connects a port to the host.
+pub trait ByteStreamBackend {
+    /// Returns the file descriptor which becomes readable when there's input to read (or when
+    /// the other end hangs up), such that it can be registered with the event loop of the VMM.
+    fn input_fd(&self) -> Option<RawFd>;
+
+    /// Returns whether a read would return input (or detect a hangup), without blocking.
+    fn read_ready(&mut self) -> io::Result<bool>;
+
+    /// Reads the available input, and returns the number of bytes read, which is zero when
+    /// there's no input. The stream is hung up when `is_hung_up` returns `true` after a read.
+    ///
+    /// # Arguments
+    /// * `buf` - The buffer for the input.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Writes output, and returns the number of bytes written, which is zero when the stream
+    /// can't take more output at the moment. Output written while the other end is not
+    /// connected may be discarded.
+    ///
+    /// # Arguments
+    /// * `buf` - The output.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Flushes the buffered output.
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Returns whether the other end of the stream hung up (or is not connected yet).
+    fn is_hung_up(&self) -> bool;
+}
+
+// Returns the events from `events` which are pending on `fd`, without blocking.
+fn poll_fd(fd: RawFd, events: libc::c_short) -> io::Result<libc::c_short> {
+    let mut pfd = libc::pollfd {
+        fd,
+        events,
+        revents: 0,
+    };
+    // Safe because we pass a single valid `pollfd` structure, and we check the return value.
+    if unsafe { libc::poll(&mut pfd, 1, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(pfd.revents)
+}
+
+// Returns whether `fd` has input to read, or was hung up.
+fn fd_read_ready(fd: RawFd) -> io::Result<bool> {
+    poll_fd(fd, libc::POLLIN).map(|revents| revents != 0)
+}
+
+// Converts the result of a non-blocking operation, such that running out of input (or of room
+// for output) is not an error.
+fn nonblocking(result: io::Result<usize>) -> io::Result<usize> {
+    match result {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+        result => result,
+    }
+}
+
+// Returns a new file which refers to the same open file description as `fd`.
+fn dup_file(fd: RawFd) -> io::Result<File> {
+    // Safe because we check the return value.
+    let new_fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if new_fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we own the new descriptor.
+    Ok(unsafe { File::from_raw_fd(new_fd) })
+}
+
+/// A byte stream backend which reads from (and writes to) files.
+#[derive(Debug)]
+pub struct FileStream {
+    input: Option<File>,
+    output: Option<File>,
+    hung_up: bool,
+}
+
+impl FileStream {
+    /// Creates a new `FileStream`.
+    ///
+    /// # Arguments
+    /// * `input` - The file from which the input is read, if any.
+    /// * `output` - The file to which the output is written, if any (the output is discarded
+    ///   otherwise).
+    pub fn new(input: Option<File>, output: Option<File>) -> Self {
+        FileStream {
+            hung_up: input.is_none(),
+            input,
+            output,
+        }
+    }
+
+    /// Creates a new `FileStream` which uses the standard input and output of the VMM.
+    pub fn stdio() -> io::Result<Self> {
+        Ok(Self::new(
+            Some(dup_file(libc::STDIN_FILENO)?),
+            Some(dup_file(libc::STDOUT_FILENO)?),
+        ))
+    }
+}
+
+impl ByteStreamBackend for FileStream {
+    fn input_fd(&self) -> Option<RawFd> {
+        self.input.as_ref().map(File::as_raw_fd)
+    }
+
+    fn read_ready(&mut self) -> io::Result<bool> {
+        match self.input {
+            Some(ref input) if !self.hung_up => fd_read_ready(input.as_raw_fd()),
+            _ => Ok(false),
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // The input is only read when it's ready, so reading doesn't block even when the file
+        // is not in non-blocking mode (which would affect the other users of the standard
+        // input, for example).
+        if buf.is_empty() || !self.read_ready()? {
+            return Ok(0);
+        }
+        match self.input.as_mut().map(|input| input.read(buf)) {
+            Some(Ok(0)) => {
+                self.hung_up = true;
+                Ok(0)
+            }
+            Some(result) => nonblocking(result),
+            None => Ok(0),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.output {
+            Some(ref mut output) => nonblocking(output.write(buf)),
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.output {
+            Some(ref mut output) => output.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn is_hung_up(&self) -> bool {
+        self.hung_up
+    }
+}
+
+/// A byte stream backend which allocates a pseudo-terminal. The users connect to the slave side
+/// of the terminal (e.g. with `screen` or `minicom`), which is in raw mode.
+///
+/// The stream is hung up while the slave side is not open. The master side stays readable in
+/// the meantime, so the VMM is expected to stop watching the input while the stream is hung
+/// up, and to check it again periodically.
+#[derive(Debug)]
+pub struct PtyStream {
+    master: File,
+    path: PathBuf,
+}
+
+impl PtyStream {
+    /// Creates a new `PtyStream`, with a newly allocated pseudo-terminal.
+    pub fn new() -> io::Result<Self> {
+        // Safe because we check the return value.
+        let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because we own the new descriptor.
+        let master = unsafe { File::from_raw_fd(fd) };
+
+        // Safe because the descriptor is valid, and we check the return values.
+        if unsafe { libc::grantpt(fd) } < 0 || unsafe { libc::unlockpt(fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut name = [0 as libc::c_char; 64];
+        // Safe because the buffer is valid for the given length, and we check the return value.
+        let ret = unsafe { libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) };
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+        // Safe because `ptsname_r` wrote a valid C string to the buffer.
+        let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+        let path = PathBuf::from(name.to_string_lossy().into_owned());
+
+        Self::set_raw_mode(&path)?;
+        // Safe because we check the return value.
+        if unsafe { libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PtyStream { master, path })
+    }
+
+    // Disables the line discipline processing (e.g. echo and line buffering) of the terminal.
+    fn set_raw_mode(path: &Path) -> io::Result<()> {
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(path)?;
+        let fd = slave.as_raw_fd();
+        // Safe because the kernel only writes a `termios` structure to `termios`, and we check
+        // the return values.
+        unsafe {
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(fd, &mut termios) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(fd, libc::TCSANOW, &termios) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the path of the slave side of the pseudo-terminal.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl ByteStreamBackend for PtyStream {
+    fn input_fd(&self) -> Option<RawFd> {
+        Some(self.master.as_raw_fd())
+    }
+
+    fn read_ready(&mut self) -> io::Result<bool> {
+        fd_read_ready(self.master.as_raw_fd())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.master.read(buf) {
+            // Reading fails with `EIO` while the slave side is not open.
+            Err(ref e) if e.raw_os_error() == Some(libc::EIO) => Ok(0),
+            result => nonblocking(result),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.master.write(buf) {
+            Err(ref e) if e.raw_os_error() == Some(libc::EIO) => Ok(buf.len()),
+            result => nonblocking(result),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn is_hung_up(&self) -> bool {
+        poll_fd(self.master.as_raw_fd(), 0).map_or(true, |revents| revents & libc::POLLHUP != 0)
+    }
+}
+
+/// A byte stream backend which uses a Unix domain socket. The stream either connects to a
+/// socket, or listens on one and accepts a single connection at a time; in the latter case, the
+/// users can reconnect after hanging up.
+#[derive(Debug)]
+pub struct UnixSocketStream {
+    listener: Option<UnixListener>,
+    stream: Option<UnixStream>,
+}
+
+impl UnixSocketStream {
+    /// Creates a new `UnixSocketStream` which is connected to a socket.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the socket.
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        stream.set_nonblocking(true)?;
+        Ok(UnixSocketStream {
+            listener: None,
+            stream: Some(stream),
+        })
+    }
+
+    /// Creates a new `UnixSocketStream` which listens on a socket. The stream is hung up until
+    /// a connection is accepted.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the socket, which must not exist.
+    pub fn listen<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(UnixSocketStream {
+            listener: Some(listener),
+            stream: None,
+        })
+    }
+
+    // Accepts a pending connection, if the stream is listening and not connected.
+    fn accept(&mut self) -> io::Result<()> {
+        if let (None, Some(listener)) = (&self.stream, &self.listener) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(true)?;
+                    self.stream = Some(stream);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ByteStreamBackend for UnixSocketStream {
+    fn input_fd(&self) -> Option<RawFd> {
+        match (&self.stream, &self.listener) {
+            (Some(stream), _) => Some(stream.as_raw_fd()),
+            (None, Some(listener)) => Some(listener.as_raw_fd()),
+            (None, None) => None,
+        }
+    }
+
+    fn read_ready(&mut self) -> io::Result<bool> {
+        self.accept()?;
+        match self.stream {
+            Some(ref stream) => fd_read_ready(stream.as_raw_fd()),
+            None => Ok(false),
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.accept()?;
+        let result = match self.stream {
+            Some(ref mut stream) if !buf.is_empty() => stream.read(buf),
+            _ => return Ok(0),
+        };
+        match result {
+            Ok(0) => {
+                self.stream = None;
+                Ok(0)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => {
+                self.stream = None;
+                Ok(0)
+            }
+            result => nonblocking(result),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.accept()?;
+        let result = match self.stream {
+            Some(ref mut stream) => stream.write(buf),
+            None => return Ok(buf.len()),
+        };
+        match result {
+            Err(ref e)
+                if e.kind() == io::ErrorKind::BrokenPipe
+                    || e.kind() == io::ErrorKind::ConnectionReset =>
+            {
+                self.stream = None;
+                Ok(buf.len())
+            }
+            result => nonblocking(result),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.stream {
+            Some(ref mut stream) => stream.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn is_hung_up(&self) -> bool {
+        self.stream.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Seek, SeekFrom};
+
+    use vmm_sys_util::tempdir::TempDir;
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn pipe() -> (File, File) {
+        let mut fds = [0; 2];
+        // Safe because the kernel only writes two descriptors to `fds`, and we check the
+        // return value.
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+        // Safe because we own the new descriptors.
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+    }
+
+    #[test]
+    fn test_file_stream() {
+        let (input, mut writer) = pipe();
+        let mut output = TempFile::new().unwrap().into_file();
+        let mut stream = FileStream::new(Some(input), Some(output.try_clone().unwrap()));
+        assert!(stream.input_fd().is_some());
+        assert!(!stream.is_hung_up());
+
+        let mut buf = [0u8; 16];
+        assert!(!stream.read_ready().unwrap());
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        assert!(!stream.is_hung_up());
+
+        writer.write_all(b"input").unwrap();
+        assert!(stream.read_ready().unwrap());
+        assert_eq!(stream.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"input");
+
+        assert_eq!(stream.write(b"output").unwrap(), 6);
+        stream.flush().unwrap();
+        let mut contents = String::new();
+        output.seek(SeekFrom::Start(0)).unwrap();
+        output.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "output");
+
+        drop(writer);
+        assert!(stream.read_ready().unwrap());
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        assert!(stream.is_hung_up());
+        assert!(!stream.read_ready().unwrap());
+
+        // Streams without input are always hung up, and discard their output when there's no
+        // output file either.
+        let mut stream = FileStream::new(None, None);
+        assert!(stream.is_hung_up() && stream.input_fd().is_none());
+        assert_eq!(stream.write(b"discarded").unwrap(), 9);
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_pty_stream() {
+        let mut stream = PtyStream::new().unwrap();
+        assert!(stream.path().starts_with("/dev/pts"));
+        assert!(stream.is_hung_up());
+        let mut buf = [0u8; 16];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+
+        let mut slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(stream.path())
+            .unwrap();
+        assert!(!stream.is_hung_up());
+        assert!(!stream.read_ready().unwrap());
+
+        // The terminal is in raw mode, so the input is neither buffered nor echoed.
+        slave.write_all(b"abc").unwrap();
+        assert!(stream.read_ready().unwrap());
+        assert_eq!(stream.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"abc");
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+
+        assert_eq!(stream.write(b"\nxyz").unwrap(), 4);
+        stream.flush().unwrap();
+        let mut out = [0u8; 4];
+        slave.read_exact(&mut out).unwrap();
+        assert_eq!(&out, b"\nxyz");
+
+        drop(slave);
+        assert!(stream.is_hung_up());
+    }
+
+    #[test]
+    fn test_unix_socket_stream() {
+        let dir = TempDir::new_with_prefix("/tmp/byte_stream").unwrap();
+        let path = dir.as_path().join("console.sock");
+        let mut stream = UnixSocketStream::listen(&path).unwrap();
+        assert!(stream.is_hung_up());
+        // The output is discarded while there's no connection.
+        assert_eq!(stream.write(b"lost").unwrap(), 4);
+        assert!(!stream.read_ready().unwrap());
+
+        for _ in 0..2 {
+            let mut client = UnixStream::connect(&path).unwrap();
+            client.write_all(b"hello").unwrap();
+            assert!(stream.read_ready().unwrap());
+            assert!(!stream.is_hung_up());
+
+            let mut buf = [0u8; 16];
+            assert_eq!(stream.read(&mut buf).unwrap(), 5);
+            assert_eq!(&buf[..5], b"hello");
+            assert_eq!(stream.read(&mut buf).unwrap(), 0);
+            assert!(!stream.is_hung_up());
+
+            assert_eq!(stream.write(b"world").unwrap(), 5);
+            let mut out = [0u8; 5];
+            client.read_exact(&mut out).unwrap();
+            assert_eq!(&out, b"world");
+
+            // The client hangs up, and the next one can connect.
+            drop(client);
+            assert_eq!(stream.read(&mut buf).unwrap(), 0);
+            assert!(stream.is_hung_up());
+        }
+
+        // The stream which connects to the socket can't reconnect.
+        let listener = UnixListener::bind(dir.as_path().join("vmm.sock")).unwrap();
+        let mut stream = UnixSocketStream::connect(dir.as_path().join("vmm.sock")).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        assert!(!stream.is_hung_up());
+        assert_eq!(
+            stream.input_fd(),
+            Some(stream.stream.as_ref().unwrap().as_raw_fd())
+        );
+        peer.write_all(b"x").unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(stream.read(&mut buf).unwrap(), 1);
+        drop(peer);
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        assert!(stream.is_hung_up());
+        assert_eq!(stream.input_fd(), None);
+    }
+}
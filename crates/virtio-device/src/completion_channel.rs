@@ -0,0 +1,423 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A lock-free channel which carries completions from the backend threads to a queue handler.
+//!
+//! Asynchronous backends complete the requests on their own (reactor) threads, while the queue
+//! is owned by the thread of its handler. Instead of sharing the queue behind a mutex, the
+//! backends send the used entries through a bounded multi-producer single-consumer channel,
+//! which doesn't take any lock:
+//!
+//! - [`CompletionSender`](struct.CompletionSender.html), which can be cloned for each backend
+//!   thread, pushes `(head_index, len)` pairs, and signals an `EventFd` when the handler has to
+//!   wake up. The pairs can carry a tag as well (i.e. the generation of the request), which the
+//!   handler uses to tell apart the completions of the requests it's no longer waiting for.
+//! - [`CompletionReceiver`](struct.CompletionReceiver.html), which is used by the handler. Its
+//!   file descriptor is expected to be registered with the event loop of the handler, which
+//!   calls [`CompletionReceiver::process`](struct.CompletionReceiver.html#method.process) when
+//!   it becomes readable, and notifies the driver when the call returns `true`.
+//!
+//! Each head index is in flight at most once, so a channel whose capacity is at least the size
+//! of the queue never fills up.
+
+use std::fmt::{self, Display};
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::result;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use vm_memory::GuestAddressSpace;
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+use virtio_queue::Queue;
+
+use crate::completion::CompletionBatcher;
+
+/// Completion channel errors.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to use the wakeup `EventFd`.
+    EventFd(io::Error),
+    /// The channel is full.
+    Full,
+    /// Failed to add the completions to the used ring.
+    Queue(virtio_queue::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            EventFd(ref err) => write!(f, "failed to use the wakeup eventfd: {}", err),
+            Full => write!(f, "the completion channel is full"),
+            Queue(ref err) => write!(f, "failed to add the completions to the queue: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+// A slot of the ring. `seq` tells who owns the slot: it's equal to the position of the next
+// send which can use the slot, or to that position plus one once the slot holds a completion
+// which can be received.
+struct Slot {
+    seq: AtomicUsize,
+    value: AtomicU64,
+}
+
+// The state shared by the two ends of the channel.
+struct Shared {
+    slots: Box<[Slot]>,
+    // The number of slots minus one (the number of slots is a power of two).
+    mask: usize,
+    // The position of the next send.
+    tail: AtomicUsize,
+    wakeup: EventFd,
+    // Whether the `EventFd` was signalled since the receiver last checked it.
+    wakeup_pending: AtomicBool,
+}
+
+impl fmt::Debug for Shared {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Shared")
+            .field("capacity", &self.slots.len())
+            .field("wakeup", &self.wakeup)
+            .finish()
+    }
+}
+
+/// Creates a completion channel which holds at least `capacity` completions, and returns its
+/// two ends.
+///
+/// # Arguments
+/// * `capacity` - The minimum number of completions the channel holds; it's rounded up to a
+///   power of two.
+pub fn channel(capacity: usize) -> Result<(CompletionSender, CompletionReceiver)> {
+    let capacity = capacity.max(1).next_power_of_two();
+    let slots = (0..capacity)
+        .map(|seq| Slot {
+            seq: AtomicUsize::new(seq),
+            value: AtomicU64::new(0),
+        })
+        .collect();
+    let shared = Arc::new(Shared {
+        slots,
+        mask: capacity - 1,
+        tail: AtomicUsize::new(0),
+        wakeup: EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd)?,
+        wakeup_pending: AtomicBool::new(false),
+    });
+    Ok((
+        CompletionSender {
+            shared: shared.clone(),
+        },
+        CompletionReceiver { shared, head: 0 },
+    ))
+}
+
+/// The end of a completion channel used by the backend threads.
+#[derive(Clone, Debug)]
+pub struct CompletionSender {
+    shared: Arc<Shared>,
+}
+
+impl CompletionSender {
+    /// Sends a used entry to the handler, and wakes it up if needed.
+    ///
+    /// # Arguments
+    /// * `head_index` - The head index of the descriptor chain.
+    /// * `len` - The number of bytes written to the chain.
+    pub fn send(&self, head_index: u16, len: u32) -> Result<()> {
+        self.send_tagged(head_index, len, 0)
+    }
+
+    /// Sends a used entry to the handler along with a tag, and wakes it up if needed.
+    ///
+    /// # Arguments
+    /// * `head_index` - The head index of the descriptor chain.
+    /// * `len` - The number of bytes written to the chain.
+    /// * `tag` - The tag the handler receives with the entry.
+    pub fn send_tagged(&self, head_index: u16, len: u32, tag: u16) -> Result<()> {
+        let shared = &*self.shared;
+        let mut pos = shared.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &shared.slots[pos & shared.mask];
+            let seq = slot.seq.load(Ordering::Acquire);
+            if seq == pos {
+                // The slot is free; claim it before writing the completion.
+                match shared.tail.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let value =
+                            (u64::from(tag) << 48) | (u64::from(head_index) << 32) | u64::from(len);
+                        slot.value.store(value, Ordering::Relaxed);
+                        slot.seq.store(pos.wrapping_add(1), Ordering::Release);
+                        break;
+                    }
+                    Err(current) => pos = current,
+                }
+            } else if (seq.wrapping_sub(pos) as isize) < 0 {
+                // The slot still holds a completion from the previous lap.
+                return Err(Error::Full);
+            } else {
+                // Another sender claimed the slot in the meantime.
+                pos = shared.tail.load(Ordering::Relaxed);
+            }
+        }
+
+        // Only the first completion after the receiver checked the channel signals it. The RMWs
+        // on the flag always see its latest value, so `AcqRel` makes sure that either the
+        // receiver's swap sees this completion, or this swap sees the flag cleared.
+        if !shared.wakeup_pending.swap(true, Ordering::AcqRel) {
+            shared.wakeup.write(1).map_err(Error::EventFd)?;
+        }
+        Ok(())
+    }
+}
+
+/// The end of a completion channel used by the queue handler.
+#[derive(Debug)]
+pub struct CompletionReceiver {
+    shared: Arc<Shared>,
+    // The position of the next receive.
+    head: usize,
+}
+
+impl CompletionReceiver {
+    /// Returns the next used entry, as a `(head_index, len)` pair, if any.
+    pub fn try_recv(&mut self) -> Option<(u16, u32)> {
+        self.try_recv_tagged()
+            .map(|(head_index, len, _)| (head_index, len))
+    }
+
+    /// Returns the next used entry, as a `(head_index, len, tag)` triple, if any. The tag is 0
+    /// for the entries sent with `CompletionSender::send`.
+    pub fn try_recv_tagged(&mut self) -> Option<(u16, u32, u16)> {
+        let shared = &*self.shared;
+        let slot = &shared.slots[self.head & shared.mask];
+        if slot.seq.load(Ordering::Acquire) != self.head.wrapping_add(1) {
+            return None;
+        }
+        let value = slot.value.load(Ordering::Relaxed);
+        // Hand the slot over to the send which comes one lap later.
+        slot.seq
+            .store(self.head.wrapping_add(shared.mask + 1), Ordering::Release);
+        self.head = self.head.wrapping_add(1);
+        Some(((value >> 32) as u16, value as u32, (value >> 48) as u16))
+    }
+
+    /// Consumes the wakeup signal. The completions which were sent before have to be received
+    /// afterwards with `try_recv`, since they don't signal the handler again.
+    pub fn clear_wakeup(&mut self) -> Result<()> {
+        match self.shared.wakeup.read() {
+            Err(e) if e.kind() != io::ErrorKind::WouldBlock => return Err(Error::EventFd(e)),
+            _ => {}
+        }
+        // The completions sent after this point signal the `EventFd` again, so none of them
+        // is left behind until the next wakeup.
+        self.shared.wakeup_pending.swap(false, Ordering::AcqRel);
+        Ok(())
+    }
+
+    /// Blocks until the wakeup is signalled, or `timeout` expires. Returns whether the wakeup
+    /// was signalled (it's not consumed).
+    ///
+    /// # Arguments
+    /// * `timeout` - How long to wait for (the precision is one millisecond).
+    pub fn wait(&self, timeout: Duration) -> Result<bool> {
+        let mut pollfd = libc::pollfd {
+            fd: self.shared.wakeup.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        // Safe because `pollfd` is valid for the duration of the call.
+        match unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } {
+            ret if ret < 0 => Err(Error::EventFd(io::Error::last_os_error())),
+            ret => Ok(ret > 0),
+        }
+    }
+
+    /// Consumes the wakeup signal, adds all the received completions to the used ring of
+    /// `queue` through `batcher`, and returns whether the driver has to be notified.
+    ///
+    /// # Arguments
+    /// * `queue` - The queue the completions belong to.
+    /// * `batcher` - The batcher which publishes the completions.
+    pub fn process<M: GuestAddressSpace>(
+        &mut self,
+        queue: &mut Queue<M>,
+        batcher: &mut CompletionBatcher,
+    ) -> Result<bool> {
+        self.clear_wakeup()?;
+        let mut notify = false;
+        while let Some((head_index, len)) = self.try_recv() {
+            notify |= batcher
+                .add_used(queue, head_index, len)
+                .map_err(Error::Queue)?;
+        }
+        Ok(batcher.flush(queue).map_err(Error::Queue)? || notify)
+    }
+}
+
+impl AsRawFd for CompletionReceiver {
+    fn as_raw_fd(&self) -> RawFd {
+        self.shared.wakeup.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    use vm_memory::{GuestAddress, GuestMemoryMmap};
+
+    use virtio_queue::mock::VirtQueue;
+
+    #[test]
+    fn test_channel() {
+        let (sender, mut receiver) = channel(3).unwrap();
+        assert!(receiver.try_recv().is_none());
+
+        // The capacity is rounded up to a power of two.
+        for head_index in 0..4 {
+            sender
+                .send(head_index, u32::MAX - u32::from(head_index))
+                .unwrap();
+        }
+        assert!(matches!(sender.send(4, 0), Err(Error::Full)));
+        assert_eq!(receiver.try_recv(), Some((0, u32::MAX)));
+        sender.send(4, 0).unwrap();
+        let received = (0..4)
+            .map(|_| receiver.try_recv().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            received,
+            vec![
+                (1, u32::MAX - 1),
+                (2, u32::MAX - 2),
+                (3, u32::MAX - 3),
+                (4, 0)
+            ]
+        );
+        assert!(receiver.try_recv().is_none());
+
+        // The tags are carried along with the used entries.
+        sender.send_tagged(u16::MAX, u32::MAX, u16::MAX).unwrap();
+        sender.send(1, 0x10).unwrap();
+        assert_eq!(
+            receiver.try_recv_tagged(),
+            Some((u16::MAX, u32::MAX, u16::MAX))
+        );
+        assert_eq!(receiver.try_recv_tagged(), Some((1, 0x10, 0)));
+
+        // The handler is only woken up once until it checks the channel.
+        assert_eq!(receiver.shared.wakeup.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_wait() {
+        let (sender, mut receiver) = channel(4).unwrap();
+        assert!(!receiver.wait(Duration::from_millis(10)).unwrap());
+
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            sender.send(1, 0x10).unwrap();
+            sender
+        });
+        assert!(receiver.wait(Duration::from_secs(10)).unwrap());
+        let sender = handle.join().unwrap();
+        // The wakeup is not consumed by `wait`.
+        assert!(receiver.wait(Duration::ZERO).unwrap());
+        receiver.clear_wakeup().unwrap();
+        assert!(!receiver.wait(Duration::ZERO).unwrap());
+
+        // The completions sent before the wakeup was cleared don't signal it again.
+        sender.send(2, 0x20).unwrap();
+        receiver.clear_wakeup().unwrap();
+        assert_eq!(receiver.try_recv(), Some((1, 0x10)));
+        assert_eq!(receiver.try_recv(), Some((2, 0x20)));
+        assert!(!receiver.wait(Duration::ZERO).unwrap());
+        sender.send(3, 0x30).unwrap();
+        assert!(receiver.wait(Duration::ZERO).unwrap());
+    }
+
+    #[test]
+    fn test_multiple_senders() {
+        const SENDERS: u16 = 4;
+        const COMPLETIONS: u16 = 1000;
+
+        let (sender, mut receiver) = channel(16).unwrap();
+        let handles = (0..SENDERS)
+            .map(|i| {
+                let sender = sender.clone();
+                thread::spawn(move || {
+                    for j in 0..COMPLETIONS {
+                        while let Err(Error::Full) = sender.send(i, u32::from(j)) {
+                            thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // The completions of each sender are received in order.
+        let mut next = vec![0u32; usize::from(SENDERS)];
+        let mut received = 0;
+        while received < SENDERS * COMPLETIONS {
+            match receiver.try_recv() {
+                Some((i, len)) => {
+                    assert_eq!(next[usize::from(i)], len);
+                    next[usize::from(i)] += 1;
+                    received += 1;
+                }
+                None => thread::yield_now(),
+            }
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert!(receiver.try_recv().is_none());
+    }
+
+    #[test]
+    fn test_process() {
+        let mem = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), mem, 16);
+        let mut queue = vq.create_queue(mem);
+        let mut batcher = CompletionBatcher::new();
+        let (sender, mut receiver) = channel(16).unwrap();
+
+        // Nothing to do without completions.
+        assert!(!receiver.process(&mut queue, &mut batcher).unwrap());
+
+        let handle = thread::spawn(move || {
+            for head_index in 0..3 {
+                sender.send(head_index, 0x10).unwrap();
+            }
+        });
+        handle.join().unwrap();
+        assert!(receiver.process(&mut queue, &mut batcher).unwrap());
+        assert_eq!(vq.used.idx().load(), 3);
+
+        // The wakeup was consumed.
+        assert!(!receiver.wait(Duration::ZERO).unwrap());
+        assert_eq!(
+            receiver.shared.wakeup.read().unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+}
@@ -0,0 +1,385 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Conformance tests for the split queue and the MMIO transport.
+//!
+//! Each test covers a section of the virtio 1.1 standard, and quotes the normative statements
+//! it checks right above the assertions. The statements which are not met yet have their own
+//! ignored tests, and the ones which can't be checked here are listed below, so the gaps in the
+//! coverage stay visible:
+//!
+//! - 2.1.2, 4.2.2.1: resetting the device status, the interrupt status and the queues is left
+//!   to the `reset` method of each device.
+//! - 2.6.5.1: not accessing the buffers in the wrong direction is up to each device.
+//! - 4.2.2.1: changing `ConfigGeneration` when the configuration space changes is up to each
+//!   device.
+
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap};
+
+use virtio_queue::mock::VirtQueue;
+use virtio_queue::{
+    Descriptor, DirtyTracker, InvalidQueueReason, Queue, VIRTQ_DESC_F_INDIRECT, VIRTQ_DESC_F_NEXT,
+    VIRTQ_DESC_F_WRITE,
+};
+
+use crate::status::{ACKNOWLEDGE, DRIVER, DRIVER_OK, FAILED, FEATURES_OK};
+use crate::virtio_config::tests::Dummy;
+use crate::{VirtioDevice, VirtioMmioDevice};
+
+// The `flags` values of the rings.
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+const VIRTQ_USED_F_NO_NOTIFY: u16 = 1;
+
+fn guest_memory() -> GuestMemoryMmap {
+    GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap()
+}
+
+fn read_reg(device: &Dummy, offset: u64) -> u32 {
+    let mut data = [0u8; 4];
+    device.read(offset, &mut data);
+    u32::from_le_bytes(data)
+}
+
+fn write_reg(device: &mut Dummy, offset: u64, value: u32) {
+    device.write(offset, &value.to_le_bytes());
+}
+
+// Records the writes of the queue to guest memory, in order.
+#[derive(Debug, Default)]
+struct WriteTracker {
+    writes: Mutex<Vec<(GuestAddress, usize)>>,
+}
+
+impl DirtyTracker for WriteTracker {
+    fn mark_dirty(&self, addr: GuestAddress, len: usize) {
+        self.writes.lock().unwrap().push((addr, len));
+    }
+}
+
+// 2.1.2 Device Requirements: Device Status Field
+#[test]
+fn test_device_status() {
+    let mut d = Dummy::new(1, 1 << 32, Vec::new());
+
+    // "The device MUST NOT consume buffers or send any used buffer notifications to the driver
+    // before DRIVER_OK."
+    for status in [ACKNOWLEDGE, ACKNOWLEDGE | DRIVER].iter() {
+        write_reg(&mut d, 0x70, u32::from(*status));
+        assert_eq!(d.device_status(), *status);
+    }
+    d.set_driver_features(1, 1);
+    write_reg(&mut d, 0x70, u32::from(ACKNOWLEDGE | DRIVER | FEATURES_OK));
+    assert_eq!(d.activate_count, 0);
+    write_reg(
+        &mut d,
+        0x70,
+        u32::from(ACKNOWLEDGE | DRIVER | FEATURES_OK | DRIVER_OK),
+    );
+    assert_eq!(d.activate_count, 1);
+    assert_eq!(
+        d.device_status(),
+        ACKNOWLEDGE | DRIVER | FEATURES_OK | DRIVER_OK
+    );
+
+    // The steps of the initialization (3.1.1) can't be skipped.
+    let mut d = Dummy::new(1, 1 << 32, Vec::new());
+    write_reg(&mut d, 0x70, u32::from(ACKNOWLEDGE | DRIVER | FEATURES_OK));
+    assert_eq!(d.device_status(), 0);
+    write_reg(&mut d, 0x70, u32::from(ACKNOWLEDGE));
+    write_reg(&mut d, 0x70, u32::from(ACKNOWLEDGE | DRIVER_OK));
+    assert_eq!(d.device_status(), ACKNOWLEDGE);
+    assert_eq!(d.activate_count, 0);
+
+    // "The driver [...] MUST set the FAILED bit" when it gives up, which the device keeps.
+    write_reg(&mut d, 0x70, u32::from(ACKNOWLEDGE | FAILED));
+    assert_eq!(d.device_status(), ACKNOWLEDGE | FAILED);
+}
+
+// 2.2.2 Device Requirements: Feature Bits
+#[test]
+fn test_feature_bits() {
+    let mut d = Dummy::new(1, (1 << 32) | 1, Vec::new());
+    write_reg(&mut d, 0x70, u32::from(ACKNOWLEDGE));
+    write_reg(&mut d, 0x70, u32::from(ACKNOWLEDGE | DRIVER));
+
+    // "The device SHOULD accept any valid subset of features the driver accepts, otherwise it
+    // MUST fail to set the FEATURES_OK device status bit when the driver writes it."
+    write_reg(&mut d, 0x24, 0);
+    write_reg(&mut d, 0x20, 3);
+    write_reg(&mut d, 0x70, u32::from(ACKNOWLEDGE | DRIVER | FEATURES_OK));
+    assert_eq!(d.device_status() & FEATURES_OK, 0);
+
+    write_reg(&mut d, 0x20, 1);
+    write_reg(&mut d, 0x70, u32::from(ACKNOWLEDGE | DRIVER | FEATURES_OK));
+    assert_ne!(d.device_status() & FEATURES_OK, 0);
+}
+
+// 2.6 Split Virtqueues
+#[test]
+fn test_split_queue_size() {
+    let mem = guest_memory();
+    let mut q = Queue::new(&mem, 0x100);
+    q.ready = true;
+
+    // "Queue Size value is always a power of 2. The maximum Queue Size value is 32768." The
+    // device also refuses the sizes larger than its own maximum.
+    for size in [0u16, 3, 0x81, 0x200, 0x8000].iter() {
+        q.size = *size;
+        assert_eq!(q.validate(), Err(InvalidQueueReason::InvalidSize(*size)));
+    }
+    for size in [1u16, 0x80, 0x100].iter() {
+        q.size = *size;
+        assert_eq!(q.validate(), Ok(()));
+    }
+}
+
+// 2.6.1 Driver Requirements: Virtqueues
+#[test]
+fn test_split_queue_alignment() {
+    let mem = guest_memory();
+    let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+    let mut q = vq.create_queue(&mem);
+    assert_eq!(q.validate(), Ok(()));
+
+    // "The driver MUST ensure that the physical address of the first byte of each virtqueue
+    // part is a multiple of the specified alignment value": 16 for the descriptor table, 2 for
+    // the available ring, and 4 for the used ring. The device refuses the other queues.
+    q.desc_table = GuestAddress(0x8008);
+    assert_eq!(
+        q.validate(),
+        Err(InvalidQueueReason::DescTableMisaligned(q.desc_table))
+    );
+    q.desc_table = GuestAddress(0x8010);
+    q.avail_ring = GuestAddress(0x9001);
+    assert_eq!(
+        q.validate(),
+        Err(InvalidQueueReason::AvailRingMisaligned(q.avail_ring))
+    );
+    q.avail_ring = GuestAddress(0x9002);
+    q.used_ring = GuestAddress(0xa002);
+    assert_eq!(
+        q.validate(),
+        Err(InvalidQueueReason::UsedRingMisaligned(q.used_ring))
+    );
+    q.used_ring = GuestAddress(0xa004);
+    assert_eq!(q.validate(), Ok(()));
+}
+
+// 2.6.5.3.2 Device Requirements: Indirect Descriptors
+#[test]
+fn test_split_queue_indirect_descriptors() {
+    let mem = guest_memory();
+    let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+    let mut q = vq.create_queue(&mem);
+
+    // "The device MUST ignore the write-only flag (flags&VIRTQ_DESC_F_WRITE) in the descriptor
+    // that refers to an indirect table."
+    // "The device MUST handle the case of zero or more normal chained descriptors followed by a
+    // single descriptor with flags&VIRTQ_DESC_F_INDIRECT."
+    vq.dtable(0).set(0x1000, 0x10, VIRTQ_DESC_F_NEXT, 1);
+    vq.dtable(1)
+        .set(0x2000, 0x20, VIRTQ_DESC_F_INDIRECT | VIRTQ_DESC_F_WRITE, 0);
+    mem.write_obj(
+        Descriptor::new(0x3000, 0x10, VIRTQ_DESC_F_NEXT, 1),
+        GuestAddress(0x2000),
+    )
+    .unwrap();
+    mem.write_obj(
+        Descriptor::new(0x4000, 0x10, VIRTQ_DESC_F_WRITE, 0),
+        GuestAddress(0x2010),
+    )
+    .unwrap();
+    vq.avail.ring(0).store(0);
+    vq.avail.idx().store(1);
+
+    let chain = q.iter().unwrap().next().unwrap();
+    let descs = chain
+        .map(|d| (d.addr().raw_value(), d.is_write_only()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        descs,
+        vec![(0x1000, false), (0x3000, false), (0x4000, true)]
+    );
+
+    // "The driver MUST NOT set the VIRTQ_DESC_F_INDIRECT flag within an indirect descriptor"
+    // (2.6.5.3.1); the device stops at such a descriptor.
+    mem.write_obj(
+        Descriptor::new(0x2000, 0x20, VIRTQ_DESC_F_INDIRECT, 0),
+        GuestAddress(0x2010),
+    )
+    .unwrap();
+    let chain = q.chain_at(0).unwrap();
+    assert_eq!(chain.count(), 2);
+}
+
+// 2.6.7.2 Device Requirements: Used Buffer Notification Suppression
+#[test]
+fn test_split_queue_used_buffer_notification_suppression() {
+    let mem = guest_memory();
+    let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+    let mut q = vq.create_queue(&mem);
+
+    // "If the VIRTIO_F_EVENT_IDX feature bit is not negotiated: [...] If flags is 0, the device
+    // MUST send a notification."
+    vq.avail.event().store(0x10);
+    for _ in 0..2 {
+        q.add_used(0, 0).unwrap();
+        assert!(q.needs_notification().unwrap());
+    }
+
+    // "Otherwise, if the VIRTIO_F_EVENT_IDX feature bit is negotiated: The device MUST ignore
+    // the lower bit of flags. [...] If the idx field in the used ring (which determined where
+    // that descriptor index was placed) was equal to used_event, the device MUST send a
+    // notification. Otherwise the device SHOULD NOT send a notification."
+    q.set_event_idx(true);
+    vq.avail.flags().store(VIRTQ_AVAIL_F_NO_INTERRUPT);
+    vq.avail.event().store(3);
+    q.add_used(0, 0).unwrap();
+    // The device always notifies the driver about the first used buffer.
+    assert!(q.needs_notification().unwrap());
+    q.add_used(0, 0).unwrap();
+    assert!(q.needs_notification().unwrap());
+    q.add_used(0, 0).unwrap();
+    assert!(!q.needs_notification().unwrap());
+    assert_eq!(q.next_used(), 5);
+}
+
+// 2.6.7.2 Device Requirements: Used Buffer Notification Suppression
+#[test]
+#[ignore = "VIRTQ_AVAIL_F_NO_INTERRUPT is not supported yet"]
+fn test_split_queue_no_interrupt_flag() {
+    let mem = guest_memory();
+    let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+    let mut q = vq.create_queue(&mem);
+
+    // "If the VIRTIO_F_EVENT_IDX feature bit is not negotiated: [...] If flags is 1, the device
+    // SHOULD NOT send a notification."
+    vq.avail.flags().store(VIRTQ_AVAIL_F_NO_INTERRUPT);
+    q.add_used(0, 0).unwrap();
+    assert!(!q.needs_notification().unwrap());
+}
+
+// 2.6.8.2 Device Requirements: The Virtqueue Used Ring
+#[test]
+fn test_split_queue_used_ring() {
+    let mem = guest_memory();
+    let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+    let mut q = vq.create_queue(&mem);
+    let tracker = Arc::new(WriteTracker::default());
+    q.set_dirty_tracker(Some(tracker.clone()));
+
+    // "The device MUST set len prior to updating the used idx."
+    q.add_used(3, 0x100).unwrap();
+    let used = vq.used_start();
+    assert_eq!(
+        *tracker.writes.lock().unwrap(),
+        vec![(used.unchecked_add(4), 8), (used.unchecked_add(2), 2)]
+    );
+    assert_eq!(vq.used.ring(0).load().len(), 0x100);
+    assert_eq!(vq.used.idx().load(), 1);
+}
+
+// 2.6.10.2 Device Requirements: Available Buffer Notification Suppression
+#[test]
+fn test_split_queue_available_buffer_notification_suppression() {
+    let mem = guest_memory();
+    let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+    let mut q = vq.create_queue(&mem);
+
+    // "If the VIRTIO_F_EVENT_IDX feature bit is not negotiated: The device MUST set flags to 0
+    // or 1. The device MAY set flags to 1 to advise the driver that notifications are not
+    // needed."
+    q.disable_notification().unwrap();
+    assert_eq!(vq.used.flags().load(), VIRTQ_USED_F_NO_NOTIFY);
+    assert!(!q.enable_notification().unwrap());
+    assert_eq!(vq.used.flags().load(), 0);
+
+    // "Otherwise, if the VIRTIO_F_EVENT_IDX feature bit is negotiated: The device MUST set
+    // flags to 0. The device MAY use avail_event to advise the driver that notifications are
+    // unnecessary until the driver writes entry with an index specified by avail_event into the
+    // available ring (equivalently, until idx in the available ring will reach the value
+    // avail_event + 1)."
+    q.set_event_idx(true);
+    q.set_next_avail(7);
+    q.disable_notification().unwrap();
+    assert_eq!(vq.used.flags().load(), 0);
+    assert!(q.enable_notification().unwrap());
+    assert_eq!(vq.used.flags().load(), 0);
+    assert_eq!(vq.used.event().load(), 7);
+}
+
+// 4.2.2.1 Device Requirements: MMIO Device Register Layout
+#[test]
+fn test_mmio_device_registers() {
+    let mut d = Dummy::new(1, 0, Vec::new());
+
+    // "The device MUST return 0x74726976 in MagicValue."
+    assert_eq!(read_reg(&d, 0x00), 0x7472_6976);
+    // "The device MUST return value 0x2 in Version."
+    assert_eq!(read_reg(&d, 0x04), 2);
+
+    // "The device MUST present each event by setting the corresponding bit in InterruptStatus
+    // from the moment it takes place, until the driver acknowledges the interrupt by writing a
+    // corresponding bit mask to the InterruptACK register. Bits which do not represent events
+    // which took place MUST be zero."
+    d.set_device_status(ACKNOWLEDGE | DRIVER | FEATURES_OK | DRIVER_OK);
+    assert_eq!(read_reg(&d, 0x60), 0);
+    d.interrupt_status().fetch_or(1, Ordering::Release);
+    assert_eq!(read_reg(&d, 0x60), 1);
+    d.interrupt_status().fetch_or(2, Ordering::Release);
+    write_reg(&mut d, 0x64, 1);
+    assert_eq!(read_reg(&d, 0x60), 2);
+    write_reg(&mut d, 0x64, 2);
+    assert_eq!(read_reg(&d, 0x60), 0);
+}
+
+// 4.2.2.2 Driver Requirements: MMIO Device Register Layout
+#[test]
+fn test_mmio_driver_accesses() {
+    let mut d = Dummy::new(1, 0, vec![1, 2, 3, 4]);
+    write_reg(&mut d, 0x70, u32::from(ACKNOWLEDGE));
+
+    // "The driver MUST NOT access memory locations not described in the table [...], MUST NOT
+    // write to the read-only registers (direction R) and MUST NOT read from the write-only
+    // registers (direction W)." The device ignores such accesses.
+    write_reg(&mut d, 0x00, 0);
+    assert_eq!(read_reg(&d, 0x00), 0x7472_6976);
+    for offset in [0x18u64, 0x50, 0x64, 0xf0].iter() {
+        let mut data = [0xffu8; 4];
+        d.read(*offset, &mut data);
+        assert_eq!(data, [0xff; 4]);
+    }
+    write_reg(&mut d, 0x18, 1);
+    assert_eq!(d.device_status(), ACKNOWLEDGE);
+
+    // "The driver MUST only use 32 bit wide and aligned reads and writes to access the control
+    // registers described in table 4.1." The device ignores the other ones.
+    let mut data = [0xffu8; 2];
+    d.read(0x70, &mut data);
+    assert_eq!(data, [0xff; 2]);
+    d.write(0x70, &[0, 0]);
+    assert_eq!(d.device_status(), ACKNOWLEDGE);
+    assert_eq!(d.reset_count, 0);
+
+    // The configuration space can be accessed with any width.
+    let mut data = [0u8; 2];
+    d.read(0x101, &mut data);
+    assert_eq!(data, [2, 3]);
+}
+
+// 4.2.3.2 Virtqueue Configuration
+#[test]
+fn test_mmio_queue_num_max() {
+    let mut d = Dummy::new(1, 0, Vec::new());
+
+    // "Read maximum queue size (number of elements) from QueueNumMax. If the returned value is
+    // zero (0x0) the queue is not available."
+    assert_eq!(read_reg(&d, 0x34), 256);
+    write_reg(&mut d, 0x30, 1);
+    assert_eq!(read_reg(&d, 0x34), 0);
+    assert_eq!(read_reg(&d, 0x44), 0);
+}
@@ -0,0 +1,371 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A deterministic log of the queue events, for reproducing timing dependent bugs.
+//!
+//! The order in which the driver kicks a queue, the device completes the requests, and the
+//! device decides whether to interrupt the driver depends on the timing of the guest and of the
+//! worker threads, so bugs in the notification suppression logic are hard to reproduce. An
+//! [`EventLog`](struct.EventLog.html) records these events with a sequence number:
+//!
+//! - the worker pool (see `QueueWorkerPool::with_event_log`) records a kick each time it hands
+//!   an event of a queue to its subscriber;
+//! - the completion batcher (see `CompletionBatcher::with_event_log`) records the completions it
+//!   publishes, and the interrupt decision it makes for them.
+//!
+//! The log is written as a text trace, with one event on each line:
+//!
+//! ```text
+//! # The sequence number, the queue index, and the event.
+//! 0 1 kick
+//! 1 1 used 3 512
+//! 2 1 notify 1
+//! ```
+//!
+//! [`replay`](fn.replay.html) performs the events of a trace against the queues again, in the
+//! recorded order, and checks that the queues make the same interrupt decisions.
+
+use std::fmt::{self, Display};
+use std::result;
+use std::sync::{Arc, Mutex};
+
+use vm_memory::GuestAddressSpace;
+
+use virtio_queue::Queue;
+
+/// Event log errors.
+#[derive(Debug)]
+pub enum Error {
+    /// The event with the contained sequence number refers to a queue which doesn't exist.
+    InvalidQueueIndex(u64),
+    /// The line with the contained number (starting from 1) is not a valid trace entry.
+    InvalidTrace(usize),
+    /// The queue made a different interrupt decision than the recorded one.
+    NotificationMismatch {
+        /// The sequence number of the interrupt decision.
+        seq: u64,
+        /// Whether the recorded decision was to notify the driver.
+        expected: bool,
+    },
+    /// Failed to replay the event with the contained sequence number.
+    Queue(u64, virtio_queue::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            InvalidQueueIndex(seq) => write!(f, "event {} refers to an invalid queue", seq),
+            InvalidTrace(line) => write!(f, "invalid trace entry at line {}", line),
+            NotificationMismatch { seq, expected } => write!(
+                f,
+                "event {} expected the driver to be {}notified",
+                seq,
+                if *expected { "" } else { "not " }
+            ),
+            Queue(seq, ref err) => write!(f, "failed to replay event {}: {}", seq, err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// A queue event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    /// An event of the queue was handed to its processing (usually a driver notification).
+    Kick,
+    /// A descriptor chain was added to the used ring.
+    Completion {
+        /// The head index of the chain.
+        head_index: u16,
+        /// The number of bytes written to the chain.
+        len: u32,
+    },
+    /// The device decided whether to notify the driver about the completions so far.
+    Interrupt(bool),
+}
+
+/// A queue event, as recorded by an [`EventLog`](struct.EventLog.html).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// The sequence number of the event, which is unique within the log.
+    pub seq: u64,
+    /// The index of the queue.
+    pub queue: u16,
+    /// What happened.
+    pub kind: EventKind,
+}
+
+impl Event {
+    // Parses a line of a trace, in the format written by `Display`.
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split_whitespace();
+        let seq = fields.next()?.parse().ok()?;
+        let queue = fields.next()?.parse().ok()?;
+        let kind = match fields.next()? {
+            "kick" => EventKind::Kick,
+            "used" => EventKind::Completion {
+                head_index: fields.next()?.parse().ok()?,
+                len: fields.next()?.parse().ok()?,
+            },
+            "notify" => match fields.next()? {
+                "0" => EventKind::Interrupt(false),
+                "1" => EventKind::Interrupt(true),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        if fields.next().is_some() {
+            return None;
+        }
+        Some(Event { seq, queue, kind })
+    }
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} ", self.seq, self.queue)?;
+        match self.kind {
+            EventKind::Kick => write!(f, "kick"),
+            EventKind::Completion { head_index, len } => write!(f, "used {} {}", head_index, len),
+            EventKind::Interrupt(notify) => write!(f, "notify {}", u8::from(notify)),
+        }
+    }
+}
+
+// The events recorded so far.
+#[derive(Debug, Default)]
+struct LogState {
+    next_seq: u64,
+    events: Vec<Event>,
+}
+
+/// Records the queue events of a device, from any thread. Clones of the log share the same
+/// events.
+#[derive(Clone, Debug, Default)]
+pub struct EventLog {
+    state: Arc<Mutex<LogState>>,
+}
+
+impl EventLog {
+    /// Creates an empty log.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records an event, and returns its sequence number. The sequence numbers follow the
+    /// order in which the events are recorded.
+    ///
+    /// # Arguments
+    /// * `queue` - The index of the queue.
+    /// * `kind` - What happened.
+    pub fn record(&self, queue: u16, kind: EventKind) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.events.push(Event { seq, queue, kind });
+        seq
+    }
+
+    /// Returns the events recorded so far.
+    pub fn events(&self) -> Vec<Event> {
+        self.state.lock().unwrap().events.clone()
+    }
+
+    /// Returns the events recorded so far, and removes them from the log. The sequence numbers
+    /// of the next events continue from the last one.
+    pub fn take(&self) -> Vec<Event> {
+        std::mem::take(&mut self.state.lock().unwrap().events)
+    }
+
+    /// Returns the events recorded so far as a trace, with one event on each line.
+    pub fn trace(&self) -> String {
+        self.state
+            .lock()
+            .unwrap()
+            .events
+            .iter()
+            .map(|event| format!("{}\n", event))
+            .collect()
+    }
+}
+
+/// Parses a trace, which holds an event on each line, as written by
+/// [`EventLog::trace`](struct.EventLog.html#method.trace). The empty lines, and the ones
+/// starting with `#`, are ignored.
+///
+/// # Arguments
+/// * `trace` - The contents of the trace.
+pub fn parse_trace(trace: &str) -> Result<Vec<Event>> {
+    trace
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| Event::parse(line).ok_or(Error::InvalidTrace(i + 1)))
+        .collect()
+}
+
+/// Replays recorded events against the queues of a device, one at a time and in order: the
+/// completions are added to the used rings, and each interrupt decision is made again and
+/// compared with the recorded one. Stops at the first decision which doesn't match.
+///
+/// The kicks are handed to `kick`, which plays the part of the guest at that point (i.e. makes
+/// the buffers available, or updates the used event index), or the part of the handler (i.e.
+/// pops the chains from the available ring).
+///
+/// # Arguments
+/// * `events` - The events, in the order they were recorded.
+/// * `queues` - The queues of the device, indexed by queue.
+/// * `kick` - Handles the kicks, given the index of the queue and the queue.
+pub fn replay<M, F>(events: &[Event], queues: &mut [Queue<M>], mut kick: F) -> Result<()>
+where
+    M: GuestAddressSpace,
+    F: FnMut(u16, &mut Queue<M>),
+{
+    for event in events {
+        let queue = queues
+            .get_mut(usize::from(event.queue))
+            .ok_or(Error::InvalidQueueIndex(event.seq))?;
+        match event.kind {
+            EventKind::Kick => kick(event.queue, queue),
+            EventKind::Completion { head_index, len } => queue
+                .add_used(head_index, len)
+                .map_err(|e| Error::Queue(event.seq, e))?,
+            EventKind::Interrupt(expected) => {
+                let notify = queue
+                    .needs_notification()
+                    .map_err(|e| Error::Queue(event.seq, e))?;
+                if notify != expected {
+                    return Err(Error::NotificationMismatch {
+                        seq: event.seq,
+                        expected,
+                    });
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::{GuestAddress, GuestMemoryMmap};
+
+    use virtio_queue::mock::VirtQueue;
+
+    use crate::completion::CompletionBatcher;
+
+    #[test]
+    fn test_trace() {
+        let log = EventLog::new();
+        assert_eq!(log.record(1, EventKind::Kick), 0);
+        log.record(
+            1,
+            EventKind::Completion {
+                head_index: 3,
+                len: 512,
+            },
+        );
+        log.record(1, EventKind::Interrupt(true));
+
+        let trace = log.trace();
+        assert_eq!(trace, "0 1 kick\n1 1 used 3 512\n2 1 notify 1\n");
+        let events = parse_trace(&format!("# A comment.\n\n{}", trace)).unwrap();
+        assert_eq!(events, log.events());
+        assert_eq!(
+            events[1],
+            Event {
+                seq: 1,
+                queue: 1,
+                kind: EventKind::Completion {
+                    head_index: 3,
+                    len: 512
+                },
+            }
+        );
+
+        // The sequence numbers continue after the events are taken.
+        assert_eq!(log.take(), events);
+        assert!(log.events().is_empty());
+        assert_eq!(log.record(0, EventKind::Interrupt(false)), 3);
+
+        assert!(matches!(
+            parse_trace("0 0 kick\n1 0 kick 1\n"),
+            Err(Error::InvalidTrace(2))
+        ));
+        for line in ["0 0", "x 0 kick", "0 0 used 1", "0 0 notify 2", "0 0 irq 1"].iter() {
+            assert!(matches!(parse_trace(line), Err(Error::InvalidTrace(1))));
+        }
+    }
+
+    #[test]
+    fn test_replay() {
+        // The driver asks for a notification after the first used entry, and then after the
+        // sixth one.
+        let used_events = [0, 5];
+
+        let mem = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), mem, 16);
+        let mut queue = vq.create_queue(mem);
+        queue.set_event_idx(true);
+        let log = EventLog::new();
+        let mut batcher = CompletionBatcher::new().with_event_log(log.clone(), 0);
+        for (i, used_event) in used_events.iter().enumerate() {
+            log.record(0, EventKind::Kick);
+            vq.avail.event().store(*used_event);
+            for head_index in 0..2 {
+                batcher
+                    .add_used(&mut queue, i as u16 * 2 + head_index, 0x10)
+                    .unwrap();
+            }
+            batcher.flush(&mut queue).unwrap();
+        }
+        let events = parse_trace(&log.trace()).unwrap();
+        assert_eq!(events.len(), 8);
+        assert_eq!(events[3].kind, EventKind::Interrupt(true));
+        assert_eq!(events[7].kind, EventKind::Interrupt(false));
+
+        // Replays the events on a new queue, with the driver setting the used event index
+        // at each kick.
+        let replay_with = |used_events: [u16; 2]| {
+            let mem = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+            let vq = VirtQueue::new(GuestAddress(0), mem, 16);
+            let mut queue = vq.create_queue(mem);
+            queue.set_event_idx(true);
+            let mut kicks = used_events.iter();
+            let result = replay(&events, std::slice::from_mut(&mut queue), |index, _| {
+                assert_eq!(index, 0);
+                vq.avail.event().store(*kicks.next().unwrap());
+            });
+            (result, vq.used.idx().load())
+        };
+        let (result, used_idx) = replay_with(used_events);
+        assert!(result.is_ok());
+        assert_eq!(used_idx, 4);
+
+        // A driver which behaves differently is caught.
+        let (result, _) = replay_with([0, 2]);
+        assert!(matches!(
+            result,
+            Err(Error::NotificationMismatch {
+                seq: 7,
+                expected: false
+            })
+        ));
+
+        let mut queues: [Queue<&GuestMemoryMmap>; 0] = [];
+        assert!(matches!(
+            replay(&events, &mut queues, |_, _| {}),
+            Err(Error::InvalidQueueIndex(0))
+        ));
+    }
+}
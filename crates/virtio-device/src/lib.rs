@@ -7,23 +7,91 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
 //! A crate that offers building blocks for virtio devices.
+//!
+//! The `derive` feature provides the `VirtioDeviceCommon` derive macro, which generates the
+//! `VirtioDeviceType`, `Borrow<VirtioConfig>` and `BorrowMut<VirtioConfig>` implementations
+//! of device objects, the `vhost-user` feature provides both sides of the vhost-user protocol,
+//! and the `vhost-kernel` feature provides the wrappers of the in-kernel vhost ioctls.
+//!
+//! The `std` feature is enabled by default. Without it, the device and configuration traits,
+//! `VirtioConfig`, the MMIO transport and the device states are still available, while the
+//! helpers built on top of host facilities (i.e. the `EventFd` notifications, the worker
+//! threads, the byte streams and `persist::save_to`) are not. The crate doesn't support
+//! `no_std` targets, as `vm-memory` depends on `std`.
 
 #![deny(missing_docs)]
 
+extern crate alloc;
+
+/// Contains the configuration which places the queue processing workers on host CPUs.
+#[cfg(feature = "std")]
+pub mod affinity;
+/// Contains the byte stream backends for consoles and serial ports.
+#[cfg(feature = "std")]
+pub mod byte_stream;
+/// Contains a helper which coalesces the notifications of batched completions.
+#[cfg(feature = "std")]
+pub mod completion;
+/// Contains a lock-free channel which carries completions from the backend threads to a queue
+/// handler.
+#[cfg(feature = "std")]
+pub mod completion_channel;
+#[cfg(test)]
+mod conformance;
+/// Contains a log of the queue events, which can be replayed against the queues.
+#[cfg(feature = "std")]
+pub mod event_log;
 mod mmio;
+/// Contains a simulated guest driver, for testing devices through the MMIO transport, and a
+/// self-test device.
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+/// Contains the abstractions for saving the state of devices and restoring them.
+pub mod persist;
+/// Contains a token bucket based rate limiter for queue processing.
+#[cfg(feature = "std")]
+pub mod rate_limiter;
+/// Contains a registry of device constructors, which creates devices from their descriptions.
+#[cfg(feature = "std")]
+pub mod registry;
+/// Contains the wrappers of the in-kernel vhost ioctls.
+#[cfg(feature = "vhost-kernel")]
+pub mod vhost_kernel;
+/// Contains the frontend side of the vhost-user protocol.
+#[cfg(feature = "vhost-user")]
+pub mod vhost_user;
+/// Contains the backend side of the vhost-user protocol, for writing vhost-user daemons.
+#[cfg(feature = "vhost-user")]
+pub mod vhost_user_backend;
 mod virtio_config;
-
-use vm_memory::GuestAddressSpace;
-
-use std::result;
-use std::sync::atomic::AtomicU8;
-use std::sync::Arc;
-
+/// Contains the queue configuration structures shared by the vhost and vhost-user frontends and
+/// backends, and their conversions from and to the queue state.
+#[cfg(any(feature = "vhost-kernel", feature = "vhost-user"))]
+pub mod vring;
+/// Contains a pool of worker threads which process the queues of multiqueue devices.
+#[cfg(feature = "std")]
+pub mod worker_pool;
+
+use vm_memory::{GuestAddress, GuestAddressSpace};
+
+use alloc::sync::Arc;
+use core::result;
+use core::sync::atomic::AtomicU8;
+
+#[cfg(feature = "std")]
+use log::error;
 use log::warn;
 use virtio_queue::Queue;
+#[cfg(feature = "std")]
+use vmm_sys_util::eventfd::EventFd;
 
 pub use mmio::VirtioMmioDevice;
-pub use virtio_config::{VirtioConfig, VirtioDeviceActions, VirtioDeviceType};
+pub use virtio_config::{
+    set_interrupt_status, VirtioConfig, VirtioDeviceActions, VirtioDeviceType,
+    VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING,
+};
+#[cfg(feature = "derive")]
+pub use virtio_device_derive::VirtioDeviceCommon;
 
 // TODO: Bring this (and other feature definitions) to the vm-virtio crate proper.
 // Using a local const temporarily until then.
@@ -71,6 +139,19 @@ pub mod status {
 // forward since different customers most likely have different expectation around levels,
 // messages, formatting, etc.
 
+/// A shared memory region of a device, which is a range of guest physical memory the device
+/// and the driver can both access without going through the queues (i.e. the DAX window of a
+/// virtio-fs device). Regions are identified by a device specific `id`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SharedMemoryRegion {
+    /// The identifier of the region.
+    pub id: u8,
+    /// The guest physical address where the region starts.
+    pub addr: GuestAddress,
+    /// The length of the region in bytes.
+    pub len: u64,
+}
+
 /// A common interface for Virtio devices, shared by all transports. The methods present here
 /// are mainly concerned with enabling the initial discovery/configuration and related interactions
 /// between a device and the driver over the transport protocol. Once a device is activated, queue
@@ -203,6 +284,11 @@ pub trait VirtioDevice<M: GuestAddressSpace> {
     /// Write to the configuration space associated with the device at `offset`, using
     /// input from `data`.
     fn write_config(&mut self, offset: usize, data: &[u8]);
+
+    /// Return the shared memory regions of the device. Most devices don't have any.
+    fn shm_regions(&self) -> &[SharedMemoryRegion] {
+        &[]
+    }
 }
 
 /// Virtio transports such as MMIO and PCI use a two step mechanism to read or write various parts
@@ -237,6 +323,128 @@ pub trait WithDriverSelect<M: GuestAddressSpace>: VirtioDevice<M> {
 
     /// Set the index of the currently selected page for driver features acknowledgement.
     fn set_driver_features_select(&mut self, value: u32);
+
+    /// Return the identifier of the shared memory region currently selected by the driver.
+    fn shm_select(&self) -> u32;
+
+    /// Set the identifier of the shared memory region currently selected by the driver.
+    fn set_shm_select(&mut self, value: u32);
+
+    /// Return the currently selected shared memory region, or `None` for an invalid selection.
+    fn selected_shm_region(&self) -> Option<SharedMemoryRegion> {
+        let id = self.shm_select();
+        self.shm_regions()
+            .iter()
+            .find(|region| u32::from(region.id) == id)
+            .copied()
+    }
+}
+
+/// Quiesces devices, i.e. before taking a snapshot of the VM or resizing the guest memory.
+///
+/// While a device is paused, it doesn't consume buffers from its queues, and doesn't write to
+/// guest memory. Driver notifications and backend events received in the meantime are not
+/// lost, the buffers made available by the driver are processed on `resume`.
+pub trait VirtioDevicePause {
+    /// Type of the error that can be returned by `pause` and `resume`.
+    type E;
+
+    /// Stops processing the queues. When this method returns successfully, all the requests
+    /// which were in flight (i.e. executed by workers of the device) are completed.
+    ///
+    /// # Arguments
+    /// * `flush` - Whether to also flush the backends, such that the data written by the
+    ///   completed requests reaches persistent storage.
+    fn pause(&mut self, flush: bool) -> result::Result<(), Self::E>;
+
+    /// Resumes processing the queues, starting with the buffers which were made available while
+    /// the device was paused.
+    fn resume(&mut self) -> result::Result<(), Self::E>;
+
+    /// Returns whether the device is paused.
+    fn is_paused(&self) -> bool;
+}
+
+/// Trait for objects which can notify the driver that buffers have been added to the used ring
+/// of a queue (i.e. by injecting an interrupt). Queue handlers are usually generic over this
+/// interface, so they don't have to know about the transport or interrupt delivery details.
+pub trait SignalUsedQueue {
+    /// Notifies the driver about new used buffers in the queue with the specified index.
+    fn signal_used_queue(&self, index: u16);
+
+    /// Returns the `EventFd` which has to be registered as an irqfd for the notifications to
+    /// reach the driver, if any.
+    #[cfg(feature = "std")]
+    fn irqfd(&self) -> Option<&EventFd> {
+        None
+    }
+}
+
+// Most simple setups use an `EventFd` registered as an irqfd for each device, in which case
+// the queue index is not relevant.
+#[cfg(feature = "std")]
+impl SignalUsedQueue for EventFd {
+    fn signal_used_queue(&self, index: u16) {
+        if let Err(e) = self.write(1) {
+            error!("failed to signal used queue {}: {}", index, e);
+        }
+    }
+
+    fn irqfd(&self) -> Option<&EventFd> {
+        Some(self)
+    }
+}
+
+/// Registers the notification `EventFd`s of devices with the hypervisor (i.e. as KVM
+/// ioeventfds and irqfds). It is implemented by the VMM, which knows the transport details
+/// such as the notification address of each device.
+#[cfg(feature = "std")]
+pub trait EventsContext {
+    /// Type of the error that can be returned when registering an `EventFd`.
+    type E;
+
+    /// Registers an `EventFd` which is signaled when the driver notifies a queue.
+    ///
+    /// # Arguments
+    /// * `queue` - The index of the queue.
+    /// * `fd` - The `EventFd` to register.
+    fn register_ioeventfd(&mut self, queue: u16, fd: &EventFd) -> result::Result<(), Self::E>;
+
+    /// Registers an `EventFd` which injects the device interrupt when written.
+    ///
+    /// # Arguments
+    /// * `fd` - The `EventFd` to register.
+    fn register_irqfd(&mut self, fd: &EventFd) -> result::Result<(), Self::E>;
+}
+
+/// Devices which own notification `EventFd`s that have to be registered with the hypervisor.
+///
+/// The registrations don't survive restoring a snapshot, or recreating the VM file descriptor,
+/// so `reattach` has to be called after `VirtioDevicePersist::restore` (and after recreating
+/// the VM) for the device to receive driver notifications and to deliver its interrupts.
+#[cfg(feature = "std")]
+pub trait VirtioDeviceEvents {
+    /// Registers all the notification `EventFd`s of the device.
+    ///
+    /// # Arguments
+    /// * `events_ctx` - The context used for registering the `EventFd`s.
+    fn reattach<C: EventsContext>(&self, events_ctx: &mut C) -> result::Result<(), C::E>;
+}
+
+/// Trait for objects which can notify the driver that the device configuration space has
+/// changed (i.e. by injecting an interrupt).
+pub trait SignalConfigChange {
+    /// Notifies the driver about a configuration space change.
+    fn signal_config_change(&self);
+}
+
+#[cfg(feature = "std")]
+impl SignalConfigChange for EventFd {
+    fn signal_config_change(&self) {
+        if let Err(e) = self.write(1) {
+            error!("failed to signal configuration change: {}", e);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -246,6 +454,25 @@ mod tests {
 
     use super::*;
 
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn test_signal_used_queue() {
+        let evt = EventFd::new(0).unwrap();
+        evt.signal_used_queue(0);
+        evt.signal_used_queue(1);
+        assert_eq!(evt.read().unwrap(), 2);
+
+        assert_eq!(evt.irqfd().unwrap().as_raw_fd(), evt.as_raw_fd());
+    }
+
+    #[test]
+    fn test_signal_config_change() {
+        let evt = EventFd::new(0).unwrap();
+        evt.signal_config_change();
+        assert_eq!(evt.read().unwrap(), 1);
+    }
+
     #[test]
     fn test_ack_device_status() {
         // We're using the `Dummy` struct that gets a `VirtioDevice` implementation
@@ -284,7 +511,7 @@ mod tests {
             assert_eq!(d.cfg.device_features & (1 << VIRTIO_F_RING_EVENT_IDX), 0);
 
             for q in d.cfg.queues.iter() {
-                assert_eq!(q.event_idx_enabled, false);
+                assert!(!q.event_idx_enabled);
             }
 
             // Revert status.
@@ -302,7 +529,7 @@ mod tests {
             assert_eq!(d.cfg.device_status, status);
 
             for q in d.cfg.queues.iter() {
-                assert_eq!(q.event_idx_enabled, true);
+                assert!(q.event_idx_enabled);
             }
         }
 
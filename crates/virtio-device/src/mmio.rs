@@ -6,8 +6,8 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
-use std::convert::TryInto;
-use std::sync::atomic::Ordering;
+use core::convert::TryInto;
+use core::sync::atomic::Ordering;
 
 use log::warn;
 use vm_memory::{GuestAddress, GuestAddressSpace};
@@ -27,6 +27,9 @@ const MMIO_VERSION: u32 = 2;
 // like the standard doesn't say anything regarding an actual VENDOR_ID value for MMIO devices.
 const VENDOR_ID: u32 = 0;
 
+// Length reported for a shared memory region identifier which is not used by the device.
+const SHM_LEN_NO_REGION: u64 = !0;
+
 // Helper function that runs the provided closure to mutate the currently selected queue of
 // a `VirtioDevice`, provided the status check is successful.
 // TODO: This function and its uses will likely have to be updated when we start offering
@@ -102,8 +105,20 @@ pub trait VirtioMmioDevice<M: GuestAddressSpace>: WithDriverSelect<M> {
                         .map(|q| q.ready)
                         .unwrap_or(false)
                         .into(),
-                    0x60 => self.interrupt_status().load(Ordering::SeqCst).into(),
+                    // Pairs with the `Release` RMW in `set_interrupt_status`.
+                    0x60 => self.interrupt_status().load(Ordering::Acquire).into(),
                     0x70 => self.device_status().into(),
+                    0xb0 => self
+                        .selected_shm_region()
+                        .map_or(SHM_LEN_NO_REGION, |r| r.len) as u32,
+                    0xb4 => {
+                        (self
+                            .selected_shm_region()
+                            .map_or(SHM_LEN_NO_REGION, |r| r.len)
+                            >> 32) as u32
+                    }
+                    0xb8 => self.selected_shm_region().map_or(0, |r| r.addr.0) as u32,
+                    0xbc => (self.selected_shm_region().map_or(0, |r| r.addr.0) >> 32) as u32,
                     0xfc => self.config_generation().into(),
                     _ => {
                         warn!("unknown virtio mmio register read: 0x{:x}", offset);
@@ -164,8 +179,10 @@ pub trait VirtioMmioDevice<M: GuestAddressSpace>: WithDriverSelect<M> {
                     0x50 => self.queue_notify(v),
                     0x64 => {
                         if self.check_device_status(status::DRIVER_OK, 0) {
+                            // Acknowledging doesn't publish anything, and the RMW can't lose
+                            // the bits which are set concurrently.
                             self.interrupt_status()
-                                .fetch_and(!(v as u8), Ordering::SeqCst);
+                                .fetch_and(!(v as u8), Ordering::Relaxed);
                         }
                     }
                     0x70 => self.ack_device_status(v as u8),
@@ -175,6 +192,7 @@ pub trait VirtioMmioDevice<M: GuestAddressSpace>: WithDriverSelect<M> {
                     0x94 => update_queue_field(self, |q| set_high(&mut q.avail_ring, v)),
                     0xa0 => update_queue_field(self, |q| set_low(&mut q.used_ring, v)),
                     0xa4 => update_queue_field(self, |q| set_high(&mut q.used_ring, v)),
+                    0xac => self.set_shm_select(v),
                     _ => {
                         warn!("unknown virtio mmio register write: 0x{:x}", offset);
                     }
@@ -206,6 +224,7 @@ pub trait VirtioMmioDevice<M: GuestAddressSpace>: WithDriverSelect<M> {
 mod tests {
     use crate::status;
     use crate::virtio_config::tests::Dummy;
+    use crate::SharedMemoryRegion;
 
     use super::*;
     use vm_memory::ByteValued;
@@ -244,12 +263,12 @@ mod tests {
         assert_eq!(mmio_read(&d, 0x10), 0);
 
         // Attempt to write some feature acknowledged by the driver.
-        d.write(0x20, &driver_features.as_slice());
+        d.write(0x20, driver_features.as_slice());
         // Nothing happens because the device status is no appropriate.
         assert_eq!(d.cfg.driver_features, 0);
 
         d.cfg.device_status = status::DRIVER;
-        d.write(0x20, &driver_features.as_slice());
+        d.write(0x20, driver_features.as_slice());
         assert_eq!(d.cfg.driver_features, driver_features as u64);
 
         d.write(0x24, &1u32.to_le_bytes());
@@ -327,6 +346,22 @@ mod tests {
 
         assert_eq!(mmio_read(&d, 0x70) as u8, new_status);
 
+        // There are no shared memory regions by default.
+        assert_eq!(mmio_read(&d, 0xb0), 0xffff_ffff);
+        assert_eq!(mmio_read(&d, 0xb4), 0xffff_ffff);
+        d.cfg.shm_regions.push(SharedMemoryRegion {
+            id: 1,
+            addr: GuestAddress((5 << 32) + 0x1000),
+            len: (1 << 32) + 0x2000,
+        });
+        // The region with id 0 is still missing.
+        assert_eq!(mmio_read(&d, 0xb0), 0xffff_ffff);
+        d.write(0xac, &1u32.to_le_bytes());
+        assert_eq!(mmio_read(&d, 0xb0), 0x2000);
+        assert_eq!(mmio_read(&d, 0xb4), 1);
+        assert_eq!(mmio_read(&d, 0xb8), 0x1000);
+        assert_eq!(mmio_read(&d, 0xbc), 5);
+
         // The config generation is 0 by default.
         assert_eq!(mmio_read(&d, 0xfc) as u8, 0);
         d.cfg.config_generation = 5;
@@ -362,3 +397,61 @@ mod tests {
         }
     }
 }
+
+// A model of the interrupt status handshake between the device, which raises an interrupt
+// after adding used buffers, and the vCPU which handles it through the `0x60` and `0x64`
+// registers, checked with loom. It replays the accesses of `read` and `write` on loom atomics,
+// so it has to be kept in sync with them. Run it with:
+//
+// RUSTFLAGS="--cfg loom" cargo test -p virtio-device --release loom
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use loom::sync::atomic::{AtomicU16, AtomicU8, Ordering};
+    use loom::sync::Arc;
+    use loom::thread;
+
+    const INT_VRING: u8 = 1;
+    const INT_CONFIG: u8 = 2;
+
+    fn check_interrupt_status(order: Ordering) {
+        loom::model(move || {
+            let interrupt_status = Arc::new(AtomicU8::new(INT_CONFIG));
+            let used_idx = Arc::new(AtomicU16::new(0));
+
+            let device = thread::spawn({
+                let interrupt_status = interrupt_status.clone();
+                let used_idx = used_idx.clone();
+                move || {
+                    used_idx.store(1, Ordering::Relaxed);
+                    interrupt_status.fetch_or(INT_VRING, order);
+                }
+            });
+
+            // The vCPU handles the pending configuration change interrupt.
+            let status = interrupt_status.load(Ordering::Acquire);
+            if status & INT_VRING != 0 {
+                // The used buffers are visible along with the interrupt status bit.
+                assert_eq!(used_idx.load(Ordering::Relaxed), 1);
+            }
+            interrupt_status.fetch_and(!status, Ordering::Relaxed);
+            device.join().unwrap();
+
+            // Acknowledging the interrupts which were read doesn't lose the new ones.
+            let pending = interrupt_status.load(Ordering::Relaxed);
+            assert_eq!(pending & INT_CONFIG, 0);
+            assert!(status & INT_VRING != 0 || pending & INT_VRING != 0);
+        });
+    }
+
+    #[test]
+    fn test_loom_interrupt_status() {
+        check_interrupt_status(Ordering::Release);
+    }
+
+    // Raising the interrupt has to publish the used buffers.
+    #[test]
+    #[should_panic]
+    fn test_loom_interrupt_status_relaxed() {
+        check_interrupt_status(Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,1353 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A simulated guest driver, for testing devices end to end without a VM.
+//!
+//! [`MmioDriver`](struct.MmioDriver.html) only talks to a device through its MMIO registers,
+//! as the driver of a guest would: it goes through the initialization steps and the feature
+//! negotiation, lays out the queues in guest memory, makes buffers available and notifies the
+//! device, and then consumes the used buffers and acknowledges the interrupts.
+//!
+//! [`MmioRecorder`](struct.MmioRecorder.html) sits between a driver and a device instead, and
+//! records the accesses to the MMIO space of the device as a text trace, which can be replayed
+//! later against another instance of the device. This turns the access patterns of a particular
+//! guest (i.e. the probe order of its kernel) into deterministic regression tests:
+//!
+//! ```text
+//! # Each line holds the direction, the offset and the data of an access.
+//! r 0x0 76697274
+//! w 0x70 01000000
+//! ```
+//!
+//! [`check_golden`](fn.check_golden.html) compares text (i.e. a
+//! [`register_dump`](struct.MmioDriver.html#method.register_dump), or the
+//! [`hex_dump`](fn.hex_dump.html) of an encoded state) with a golden file checked in next to the
+//! tests, so accidental changes of the register layouts and of the state encodings are caught.
+//! Setting the `UPDATE_GOLDEN` environment variable rewrites the golden files instead.
+//!
+//! [`EchoDevice`](struct.EchoDevice.html) is a trivial device on the other side, which copies
+//! the readable buffers of each chain into its writable ones. It doesn't depend on any backend,
+//! so the driver, the fuzz targets and the transport can be validated against it on their own.
+//!
+//! The unit tests of the devices share a few fixtures as well:
+//! [`activate`](fn.activate.html) goes through the device initialization steps by calling the
+//! device methods directly, [`adjacent_regions`](fn.adjacent_regions.html) builds guest memory
+//! whose buffers can straddle the boundary between two regions, and, with the `vhost-user`
+//! feature, [`recv_backend_message`](fn.recv_backend_message.html) plays the backend side of a
+//! vhost-user connection.
+//!
+//! The module is available with the `mock` feature.
+
+use std::borrow::{Borrow, BorrowMut};
+use std::env;
+use std::fmt::{self, Display};
+use std::fs;
+#[cfg(feature = "vhost-user")]
+use std::fs::File;
+#[cfg(feature = "vhost-user")]
+use std::io::Read;
+use std::marker::PhantomData;
+#[cfg(feature = "vhost-user")]
+use std::mem::size_of;
+#[cfg(feature = "vhost-user")]
+use std::os::unix::io::FromRawFd;
+#[cfg(feature = "vhost-user")]
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::result;
+use std::sync::Mutex;
+
+#[cfg(feature = "vhost-user")]
+use libc::iovec;
+use log::{error, warn};
+#[cfg(feature = "vhost-user")]
+use vm_memory::ByteValued;
+use vm_memory::{Address, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryMmap};
+#[cfg(feature = "vhost-user")]
+use vmm_sys_util::sock_ctrl_msg::ScmSocket;
+
+use virtio_queue::mock::VirtQueue;
+use virtio_queue::{Descriptor, DescriptorChain, Queue, VIRTQ_DESC_F_NEXT};
+
+use crate::status::{ACKNOWLEDGE, DEVICE_NEEDS_RESET, DRIVER, DRIVER_OK, FAILED, FEATURES_OK};
+#[cfg(feature = "vhost-user")]
+use crate::vhost_user::{Header, MAX_MEMORY_REGIONS};
+use crate::{
+    VirtioConfig, VirtioDeviceActions, VirtioDeviceType, VirtioMmioDevice, WithDriverSelect,
+    VIRTIO_F_RING_EVENT_IDX,
+};
+
+// The MMIO registers used by the driver.
+const MAGIC_VALUE: u64 = 0x00;
+const VERSION: u64 = 0x04;
+const DEVICE_ID: u64 = 0x08;
+const VENDOR_ID: u64 = 0x0c;
+const DEVICE_FEATURES: u64 = 0x10;
+const DEVICE_FEATURES_SEL: u64 = 0x14;
+const DRIVER_FEATURES: u64 = 0x20;
+const DRIVER_FEATURES_SEL: u64 = 0x24;
+const QUEUE_SEL: u64 = 0x30;
+const QUEUE_NUM_MAX: u64 = 0x34;
+const QUEUE_NUM: u64 = 0x38;
+const QUEUE_READY: u64 = 0x44;
+const QUEUE_NOTIFY: u64 = 0x50;
+const INTERRUPT_STATUS: u64 = 0x60;
+const INTERRUPT_ACK: u64 = 0x64;
+const STATUS: u64 = 0x70;
+const QUEUE_DESC_LOW: u64 = 0x80;
+const QUEUE_DESC_HIGH: u64 = 0x84;
+const QUEUE_AVAIL_LOW: u64 = 0x90;
+const QUEUE_AVAIL_HIGH: u64 = 0x94;
+const QUEUE_USED_LOW: u64 = 0xa0;
+const QUEUE_USED_HIGH: u64 = 0xa4;
+const SHM_SEL: u64 = 0xac;
+const SHM_LEN_LOW: u64 = 0xb0;
+const SHM_LEN_HIGH: u64 = 0xb4;
+const SHM_BASE_LOW: u64 = 0xb8;
+const SHM_BASE_HIGH: u64 = 0xbc;
+const CONFIG_GENERATION: u64 = 0xfc;
+const CONFIG: u64 = 0x100;
+
+const MMIO_MAGIC_VALUE: u32 = 0x7472_6976;
+const MMIO_VERSION: u32 = 2;
+
+// The queues are placed at page aligned addresses.
+const QUEUE_ALIGN: u64 = 0x1000;
+
+const VIRTIO_F_VERSION_1: u64 = 32;
+// The size of the chunks `EchoDevice` copies at once.
+const ECHO_CHUNK_SIZE: u32 = 256;
+// The number of shared memory regions a register dump looks for.
+const MAX_SHM_REGIONS: u32 = 256;
+
+/// The environment variable which makes [`check_golden`](fn.check_golden.html) write the golden
+/// files instead of comparing against them.
+pub const UPDATE_GOLDEN_ENV: &str = "UPDATE_GOLDEN";
+
+/// The device type of [`EchoDevice`](struct.EchoDevice.html), which is not assigned to any
+/// device by the virtio standard.
+pub const ECHO_DEVICE_TYPE: u32 = 0xffff;
+
+/// Driver errors.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The device failed to initialize, and set the contained status.
+    DeviceFailed(u8),
+    /// The device didn't accept the negotiated features.
+    FeaturesRejected,
+    /// The magic value register holds the contained value instead of `virt`.
+    InvalidMagicValue(u32),
+    /// The queue with the contained index is not available.
+    InvalidQueueIndex(u16),
+    /// The queue size is larger than the maximum size of the contained queue.
+    InvalidQueueSize(u16),
+    /// The device uses the contained version of the MMIO transport, instead of version 2.
+    InvalidVersion(u32),
+    /// The line with the contained number (starting from 1) is not a valid trace entry.
+    InvalidTrace(usize),
+    /// There are not enough free descriptors for the chain.
+    QueueFull,
+    /// A replayed read returned different data than the one recorded in the trace.
+    UnexpectedRead {
+        /// The index of the read in the trace.
+        index: usize,
+        /// The data returned by the device.
+        data: Vec<u8>,
+    },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            DeviceFailed(status) => write!(f, "the device failed with status 0x{:x}", status),
+            FeaturesRejected => write!(f, "the device rejected the features"),
+            InvalidMagicValue(value) => write!(f, "invalid magic value: 0x{:x}", value),
+            InvalidQueueIndex(index) => write!(f, "invalid queue index: {}", index),
+            InvalidQueueSize(index) => write!(f, "invalid size for queue {}", index),
+            InvalidVersion(version) => write!(f, "invalid mmio version: {}", version),
+            InvalidTrace(line) => write!(f, "invalid trace entry at line {}", line),
+            QueueFull => write!(f, "not enough free descriptors"),
+            UnexpectedRead { index, data } => {
+                write!(f, "access {} of the trace read unexpected data: ", index)?;
+                write_hex(f, data)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+// The driver side of a queue.
+struct DriverQueue<'a> {
+    vq: VirtQueue<'a>,
+    // The descriptors which are not part of an available chain.
+    free: Vec<u16>,
+    // The descriptors of each available chain, indexed by head.
+    chains: Vec<Vec<u16>>,
+    // The index of the next used ring entry the driver consumes.
+    last_used: u16,
+}
+
+/// A guest driver which configures and uses a device through its MMIO registers.
+pub struct MmioDriver<'a, M, D> {
+    device: D,
+    mem: &'a GuestMemoryMmap,
+    // Where the next queue is laid out.
+    next_queue_addr: GuestAddress,
+    queues: Vec<DriverQueue<'a>>,
+    features: u64,
+    _marker: PhantomData<M>,
+}
+
+impl<'a, M, D> MmioDriver<'a, M, D>
+where
+    M: GuestAddressSpace,
+    D: VirtioMmioDevice<M>,
+{
+    /// Creates a driver for `device`, which is not initialized yet.
+    ///
+    /// # Arguments
+    /// * `device` - The device, which has to use `mem` as its guest memory.
+    /// * `mem` - The guest memory.
+    /// * `queues_addr` - The guest physical address where the queues are laid out, one after
+    ///   the other, which has to be distinct from the buffers passed to the device.
+    pub fn new(device: D, mem: &'a GuestMemoryMmap, queues_addr: GuestAddress) -> Self {
+        MmioDriver {
+            device,
+            mem,
+            next_queue_addr: queues_addr,
+            queues: Vec::new(),
+            features: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the device.
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// Returns the device, i.e. for processing its queues from the test.
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    /// Returns the features negotiated by `initialize`.
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    /// Returns the driver side of a queue, as laid out by `initialize`.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the queue.
+    pub fn virt_queue(&self, index: u16) -> Option<&VirtQueue<'a>> {
+        self.queues.get(usize::from(index)).map(|q| &q.vq)
+    }
+
+    /// Reads a 32-bit register of the device.
+    ///
+    /// # Arguments
+    /// * `offset` - The offset of the register within the MMIO space of the device.
+    pub fn read_reg(&self, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        self.device.read(offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    /// Writes a 32-bit register of the device.
+    ///
+    /// # Arguments
+    /// * `offset` - The offset of the register within the MMIO space of the device.
+    /// * `value` - The value of the register.
+    pub fn write_reg(&mut self, offset: u64, value: u32) {
+        self.device.write(offset, &value.to_le_bytes());
+    }
+
+    /// Reads from the configuration space of the device.
+    ///
+    /// # Arguments
+    /// * `offset` - The offset within the configuration space.
+    /// * `data` - The buffer which receives the contents of the configuration space.
+    pub fn read_config(&self, offset: u64, data: &mut [u8]) {
+        self.device.read(CONFIG + offset, data);
+    }
+
+    /// Writes to the configuration space of the device.
+    ///
+    /// # Arguments
+    /// * `offset` - The offset within the configuration space.
+    /// * `data` - The new contents of the configuration space.
+    pub fn write_config(&mut self, offset: u64, data: &[u8]) {
+        self.device.write(CONFIG + offset, data);
+    }
+
+    /// Returns the device status.
+    pub fn status(&self) -> u8 {
+        self.read_reg(STATUS) as u8
+    }
+
+    /// Resets the device, which forgets about the queues.
+    pub fn reset(&mut self) {
+        self.write_reg(STATUS, 0);
+        self.queues.clear();
+        self.features = 0;
+    }
+
+    // Sets a status bit, and checks that the device didn't fail.
+    fn set_status(&mut self, bit: u8) -> Result<()> {
+        let status = self.status() | bit;
+        self.write_reg(STATUS, u32::from(status));
+        match self.status() {
+            status if status & (FAILED | DEVICE_NEEDS_RESET) != 0 => {
+                Err(Error::DeviceFailed(status))
+            }
+            status if status & bit == 0 => Err(Error::DeviceFailed(status)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Resets the device and goes through the initialization steps of the virtio standard: it
+    /// negotiates the features, sets up the queues, and sets the `DRIVER_OK` status. Returns
+    /// the negotiated features, which are the features supported by both the driver and the
+    /// device.
+    ///
+    /// # Arguments
+    /// * `features` - The features supported by the driver.
+    /// * `num_queues` - The number of queues the driver uses, starting with queue 0.
+    /// * `queue_size` - The size of the queues, which has to be a power of two.
+    pub fn initialize(&mut self, features: u64, num_queues: u16, queue_size: u16) -> Result<u64> {
+        let magic = self.read_reg(MAGIC_VALUE);
+        if magic != MMIO_MAGIC_VALUE {
+            return Err(Error::InvalidMagicValue(magic));
+        }
+        let version = self.read_reg(VERSION);
+        if version != MMIO_VERSION {
+            return Err(Error::InvalidVersion(version));
+        }
+
+        self.reset();
+        self.set_status(ACKNOWLEDGE)?;
+        self.set_status(DRIVER)?;
+
+        self.write_reg(DEVICE_FEATURES_SEL, 0);
+        let mut device_features = u64::from(self.read_reg(DEVICE_FEATURES));
+        self.write_reg(DEVICE_FEATURES_SEL, 1);
+        device_features |= u64::from(self.read_reg(DEVICE_FEATURES)) << 32;
+        let features = features & device_features;
+        self.write_reg(DRIVER_FEATURES_SEL, 0);
+        self.write_reg(DRIVER_FEATURES, features as u32);
+        self.write_reg(DRIVER_FEATURES_SEL, 1);
+        self.write_reg(DRIVER_FEATURES, (features >> 32) as u32);
+        self.set_status(FEATURES_OK)
+            .map_err(|_| Error::FeaturesRejected)?;
+
+        for index in 0..num_queues {
+            self.setup_queue(index, queue_size)?;
+        }
+        self.set_status(DRIVER_OK)?;
+        self.features = features;
+        Ok(features)
+    }
+
+    // Lays out a queue in guest memory, and configures the device to use it.
+    fn setup_queue(&mut self, index: u16, size: u16) -> Result<()> {
+        self.write_reg(QUEUE_SEL, u32::from(index));
+        match self.read_reg(QUEUE_NUM_MAX) {
+            0 => return Err(Error::InvalidQueueIndex(index)),
+            max if u32::from(size) > max || !size.is_power_of_two() => {
+                return Err(Error::InvalidQueueSize(index))
+            }
+            _ => {}
+        }
+
+        let vq = VirtQueue::new(self.next_queue_addr, self.mem, size);
+        let end = vq.end().raw_value();
+        self.next_queue_addr = GuestAddress((end + QUEUE_ALIGN - 1) & !(QUEUE_ALIGN - 1));
+
+        self.write_reg(QUEUE_NUM, u32::from(size));
+        let desc = vq.dtable_start().raw_value();
+        self.write_reg(QUEUE_DESC_LOW, desc as u32);
+        self.write_reg(QUEUE_DESC_HIGH, (desc >> 32) as u32);
+        let avail = vq.avail_start().raw_value();
+        self.write_reg(QUEUE_AVAIL_LOW, avail as u32);
+        self.write_reg(QUEUE_AVAIL_HIGH, (avail >> 32) as u32);
+        let used = vq.used_start().raw_value();
+        self.write_reg(QUEUE_USED_LOW, used as u32);
+        self.write_reg(QUEUE_USED_HIGH, (used >> 32) as u32);
+        self.write_reg(QUEUE_READY, 1);
+
+        self.queues.push(DriverQueue {
+            vq,
+            // The descriptors are handed out starting with the lowest indices.
+            free: (0..size).rev().collect(),
+            chains: vec![Vec::new(); usize::from(size)],
+            last_used: 0,
+        });
+        Ok(())
+    }
+
+    fn queue_mut(&mut self, index: u16) -> Result<&mut DriverQueue<'a>> {
+        self.queues
+            .get_mut(usize::from(index))
+            .ok_or(Error::InvalidQueueIndex(index))
+    }
+
+    /// Makes a chain of buffers available in a queue, and returns the index of its head. The
+    /// device is not notified; see [`kick`](#method.kick).
+    ///
+    /// # Arguments
+    /// * `queue` - The index of the queue.
+    /// * `descs` - The buffers of the chain, in order. The `NEXT` flags and the `next` fields
+    ///   are set by the driver, while the other flags are kept.
+    pub fn add_buffers(&mut self, queue: u16, descs: &[Descriptor]) -> Result<u16> {
+        let q = self.queue_mut(queue)?;
+        if descs.is_empty() || descs.len() > q.free.len() {
+            return Err(Error::QueueFull);
+        }
+
+        let indices = q.free.split_off(q.free.len() - descs.len());
+        // `split_off` keeps the order of the free list, which is reversed.
+        let indices = indices.into_iter().rev().collect::<Vec<_>>();
+        for (i, (&index, desc)) in indices.iter().zip(descs.iter()).enumerate() {
+            let (flags, next) = match indices.get(i + 1) {
+                Some(&next) => (desc.flags() | VIRTQ_DESC_F_NEXT, next),
+                None => (desc.flags() & !VIRTQ_DESC_F_NEXT, 0),
+            };
+            q.vq.dtable(index)
+                .set(desc.addr().raw_value(), desc.len(), flags, next);
+        }
+
+        let head = indices[0];
+        let avail_idx = q.vq.avail.idx().load();
+        q.vq.avail.ring(avail_idx % q.vq.size()).store(head);
+        q.vq.avail.idx().store(avail_idx.wrapping_add(1));
+        q.chains[usize::from(head)] = indices;
+        Ok(head)
+    }
+
+    /// Notifies the device that a queue has new available buffers.
+    ///
+    /// # Arguments
+    /// * `queue` - The index of the queue.
+    pub fn kick(&mut self, queue: u16) {
+        self.write_reg(QUEUE_NOTIFY, u32::from(queue));
+    }
+
+    /// Returns the next used buffer of a queue, as a `(head_index, len)` pair, if any. The
+    /// descriptors of the chain become free again.
+    ///
+    /// # Arguments
+    /// * `queue` - The index of the queue.
+    pub fn pop_used(&mut self, queue: u16) -> Option<(u16, u32)> {
+        let event_idx = self.features & (1 << VIRTIO_F_RING_EVENT_IDX) != 0;
+        let q = self.queue_mut(queue).ok()?;
+        if q.vq.used.idx().load() == q.last_used {
+            return None;
+        }
+
+        let elem = q.vq.used.ring(q.last_used % q.vq.size()).load();
+        q.last_used = q.last_used.wrapping_add(1);
+        if event_idx {
+            // Ask for a notification as soon as the next buffer is used.
+            q.vq.avail.event().store(q.last_used);
+        }
+
+        let head = elem.id() as u16;
+        let chain = q.chains.get_mut(usize::from(head))?;
+        let mut indices = std::mem::take(chain);
+        indices.reverse();
+        q.free.append(&mut indices);
+        Some((head, elem.len()))
+    }
+
+    /// Acknowledges the pending interrupts, and returns their causes, as read from the interrupt
+    /// status register (i.e. bit 0 for used buffers).
+    pub fn ack_interrupt(&mut self) -> u32 {
+        let status = self.read_reg(INTERRUPT_STATUS);
+        if status != 0 {
+            self.write_reg(INTERRUPT_ACK, status);
+        }
+        status
+    }
+
+    /// Reads back the registers of the device, and returns them as text, one register per line,
+    /// followed by a hex dump of the first `config_len` bytes of the configuration space. The
+    /// registers behind a selector are read for both feature pages, and for every queue and
+    /// shared memory region the device has; the selectors are left at 0.
+    ///
+    /// # Arguments
+    /// * `config_len` - The length of the configuration space of the device.
+    pub fn register_dump(&mut self, config_len: usize) -> String {
+        let mut dump = String::new();
+        let mut line =
+            |name: String, value: u32| dump.push_str(&format!("{:<24}0x{:08x}\n", name, value));
+
+        for &(name, offset) in [
+            ("magic_value", MAGIC_VALUE),
+            ("version", VERSION),
+            ("device_id", DEVICE_ID),
+            ("vendor_id", VENDOR_ID),
+        ]
+        .iter()
+        {
+            line(name.to_string(), self.read_reg(offset));
+        }
+        for page in 0..2 {
+            self.write_reg(DEVICE_FEATURES_SEL, page);
+            line(
+                format!("device_features[{}]", page),
+                self.read_reg(DEVICE_FEATURES),
+            );
+        }
+        // The queues which don't exist have a maximum size of 0.
+        for index in 0..=u32::from(u16::MAX) {
+            self.write_reg(QUEUE_SEL, index);
+            let max_size = self.read_reg(QUEUE_NUM_MAX);
+            if max_size == 0 {
+                break;
+            }
+            line(format!("queue_num_max[{}]", index), max_size);
+            line(
+                format!("queue_ready[{}]", index),
+                self.read_reg(QUEUE_READY),
+            );
+        }
+        line(
+            "interrupt_status".to_string(),
+            self.read_reg(INTERRUPT_STATUS),
+        );
+        line("status".to_string(), self.read_reg(STATUS));
+        // The regions which don't exist have a length of `u64::MAX`.
+        for id in 0..MAX_SHM_REGIONS {
+            self.write_reg(SHM_SEL, id);
+            let len = (self.read_reg(SHM_LEN_LOW), self.read_reg(SHM_LEN_HIGH));
+            if len == (u32::MAX, u32::MAX) {
+                break;
+            }
+            line(format!("shm_len_low[{}]", id), len.0);
+            line(format!("shm_len_high[{}]", id), len.1);
+            line(format!("shm_base_low[{}]", id), self.read_reg(SHM_BASE_LOW));
+            line(
+                format!("shm_base_high[{}]", id),
+                self.read_reg(SHM_BASE_HIGH),
+            );
+        }
+        line(
+            "config_generation".to_string(),
+            self.read_reg(CONFIG_GENERATION),
+        );
+
+        for &offset in [DEVICE_FEATURES_SEL, QUEUE_SEL, SHM_SEL].iter() {
+            self.write_reg(offset, 0);
+        }
+
+        let mut config = vec![0u8; config_len];
+        self.read_config(0, &mut config);
+        dump.push_str("config\n");
+        dump.push_str(&hex_dump(&config));
+        dump
+    }
+}
+
+/// Formats `bytes` as text, 16 bytes per line, each line starting with the offset of its first
+/// byte. Golden files hold binary encodings in this form, so their changes can be reviewed.
+///
+/// # Arguments
+/// * `bytes` - The bytes to format.
+pub fn hex_dump(bytes: &[u8]) -> String {
+    bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let hex: Vec<_> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("{:04x}: {}\n", i * 16, hex.join(" "))
+        })
+        .collect()
+}
+
+/// Compares `actual` with the contents of the golden file at `path`, and panics with the first
+/// line which differs. When the `UPDATE_GOLDEN` environment variable is set, the golden file is
+/// written with `actual` instead, such that intentional changes to a layout or an encoding show
+/// up as a diff of the checked-in file.
+///
+/// # Arguments
+/// * `path` - The path of the golden file, usually relative to `CARGO_MANIFEST_DIR`.
+/// * `actual` - The text the golden file is expected to hold.
+pub fn check_golden<P: AsRef<Path>>(path: P, actual: &str) {
+    let path = path.as_ref();
+    if env::var_os(UPDATE_GOLDEN_ENV).is_some() {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).unwrap();
+        }
+        fs::write(path, actual).unwrap();
+        return;
+    }
+
+    let expected = fs::read_to_string(path).unwrap_or_else(|e| {
+        panic!(
+            "failed to read golden file {}: {} (set {} to create it)",
+            path.display(),
+            e,
+            UPDATE_GOLDEN_ENV
+        )
+    });
+    if expected == actual {
+        return;
+    }
+    let (line, expected_line, actual_line) = expected
+        .lines()
+        .map(Some)
+        .chain(std::iter::repeat(None))
+        .zip(actual.lines().map(Some).chain(std::iter::repeat(None)))
+        .enumerate()
+        .find(|(_, (e, a))| e != a)
+        .map(|(i, (e, a))| {
+            (
+                i + 1,
+                e.unwrap_or("<end of file>"),
+                a.unwrap_or("<end of file>"),
+            )
+        })
+        // The texts only differ in the trailing newline.
+        .unwrap_or((expected.lines().count(), "", ""));
+    panic!(
+        "{} doesn't match at line {}:\n  expected: {}\n  actual:   {}\n(set {} to update it)",
+        path.display(),
+        line,
+        expected_line,
+        actual_line,
+        UPDATE_GOLDEN_ENV
+    );
+}
+
+/// The direction of an MMIO access.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// The driver reads from the device.
+    Read,
+    /// The driver writes to the device.
+    Write,
+}
+
+/// An access to the MMIO space of a device, which is formatted as a line of a trace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MmioAccess {
+    /// The direction of the access.
+    pub direction: Direction,
+    /// The offset within the MMIO space of the device.
+    pub offset: u64,
+    /// The data returned by the device for reads, or written by the driver.
+    pub data: Vec<u8>,
+}
+
+impl MmioAccess {
+    // Parses a line of a trace, in the format written by `Display`.
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split_whitespace();
+        let direction = match fields.next()? {
+            "r" => Direction::Read,
+            "w" => Direction::Write,
+            _ => return None,
+        };
+        let offset = u64::from_str_radix(fields.next()?.strip_prefix("0x")?, 16).ok()?;
+        let hex = fields.next()?;
+        if fields.next().is_some() || hex.len() % 2 != 0 {
+            return None;
+        }
+        let data = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<_>>>()?;
+        Some(MmioAccess {
+            direction,
+            offset,
+            data,
+        })
+    }
+}
+
+impl Display for MmioAccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let direction = match self.direction {
+            Direction::Read => 'r',
+            Direction::Write => 'w',
+        };
+        write!(f, "{} 0x{:x} ", direction, self.offset)?;
+        write_hex(f, &self.data)
+    }
+}
+
+fn write_hex(f: &mut fmt::Formatter, data: &[u8]) -> fmt::Result {
+    data.iter().try_for_each(|b| write!(f, "{:02x}", b))
+}
+
+/// Parses a trace, which holds an access on each line, as written by
+/// [`MmioRecorder::trace`](struct.MmioRecorder.html#method.trace). The empty lines, and the
+/// ones starting with `#`, are ignored.
+///
+/// # Arguments
+/// * `trace` - The contents of the trace.
+pub fn parse_trace(trace: &str) -> Result<Vec<MmioAccess>> {
+    trace
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| MmioAccess::parse(line).ok_or(Error::InvalidTrace(i + 1)))
+        .collect()
+}
+
+/// Forwards the MMIO accesses of a driver to a device, and records them.
+pub struct MmioRecorder<M, D> {
+    device: D,
+    accesses: Mutex<Vec<MmioAccess>>,
+    _marker: PhantomData<M>,
+}
+
+impl<M, D> MmioRecorder<M, D>
+where
+    M: GuestAddressSpace,
+    D: VirtioMmioDevice<M>,
+{
+    /// Creates a recorder for the accesses to `device`, which didn't record any access yet.
+    ///
+    /// # Arguments
+    /// * `device` - The device.
+    pub fn new(device: D) -> Self {
+        MmioRecorder {
+            device,
+            accesses: Mutex::new(Vec::new()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the device.
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// Returns the device, i.e. for processing its queues from the test.
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    /// Returns the accesses recorded so far.
+    pub fn accesses(&self) -> Vec<MmioAccess> {
+        self.accesses.lock().unwrap().clone()
+    }
+
+    /// Returns the accesses recorded so far as a trace, with one access on each line.
+    pub fn trace(&self) -> String {
+        self.accesses
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|access| format!("{}\n", access))
+            .collect()
+    }
+
+    /// Reads from the MMIO space of the device, and records the access.
+    ///
+    /// # Arguments
+    /// * `offset` - The offset within the MMIO space of the device.
+    /// * `data` - The buffer which receives the data.
+    pub fn read(&self, offset: u64, data: &mut [u8]) {
+        self.device.read(offset, data);
+        self.accesses.lock().unwrap().push(MmioAccess {
+            direction: Direction::Read,
+            offset,
+            data: data.to_vec(),
+        });
+    }
+
+    /// Writes to the MMIO space of the device, and records the access.
+    ///
+    /// # Arguments
+    /// * `offset` - The offset within the MMIO space of the device.
+    /// * `data` - The data to write.
+    pub fn write(&mut self, offset: u64, data: &[u8]) {
+        self.device.write(offset, data);
+        self.accesses.lock().unwrap().push(MmioAccess {
+            direction: Direction::Write,
+            offset,
+            data: data.to_vec(),
+        });
+    }
+
+    /// Performs the accesses of a trace in order, and checks that the reads return the same
+    /// data as when the trace was recorded. Stops at the first read which doesn't.
+    ///
+    /// # Arguments
+    /// * `accesses` - The accesses, i.e. as returned by [`parse_trace`](fn.parse_trace.html).
+    pub fn replay(&mut self, accesses: &[MmioAccess]) -> Result<()> {
+        for (index, access) in accesses.iter().enumerate() {
+            match access.direction {
+                Direction::Read => {
+                    let mut data = vec![0u8; access.data.len()];
+                    self.read(access.offset, &mut data);
+                    if data != access.data {
+                        return Err(Error::UnexpectedRead { index, data });
+                    }
+                }
+                Direction::Write => self.write(access.offset, &access.data),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A self-test device with a single queue, which copies the readable buffers of each chain the
+/// driver makes available into the writable buffers of the same chain, and returns the chain
+/// with the number of bytes copied. It offers `VIRTIO_F_VERSION_1` and
+/// `VIRTIO_F_RING_EVENT_IDX`, and has no configuration space.
+///
+/// The queue is processed when the driver notifies it through the MMIO transport, or with
+/// [`process_queue`](#method.process_queue).
+#[derive(Debug)]
+pub struct EchoDevice<M: GuestAddressSpace> {
+    cfg: VirtioConfig<M>,
+    echoed: u64,
+}
+
+impl<M: GuestAddressSpace> EchoDevice<M> {
+    /// Creates a device which is not activated yet.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    /// * `max_queue_size` - The maximum size of the queue.
+    pub fn new(mem: M, max_queue_size: u16) -> Self {
+        let features = (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_RING_EVENT_IDX);
+        EchoDevice {
+            cfg: VirtioConfig::new(features, vec![Queue::new(mem, max_queue_size)], Vec::new()),
+            echoed: 0,
+        }
+    }
+
+    /// Returns the number of chains returned to the driver since the device was created.
+    pub fn echoed(&self) -> u64 {
+        self.echoed
+    }
+
+    /// Echoes all the available chains, and returns whether the driver has to be notified.
+    /// Nothing is processed before the device is activated.
+    pub fn process_queue(&mut self) -> result::Result<bool, virtio_queue::Error> {
+        if !self.cfg.device_activated {
+            return Ok(false);
+        }
+
+        let queue = &mut self.cfg.queues[0];
+        let mut used = false;
+        loop {
+            queue.disable_notification()?;
+            loop {
+                let chain = match queue.iter()?.next() {
+                    Some(chain) => chain,
+                    None => break,
+                };
+                let head_index = chain.head_index();
+                let len = echo(chain);
+                queue.add_used(head_index, len)?;
+                self.echoed += 1;
+                used = true;
+            }
+            if !queue.enable_notification()? {
+                break;
+            }
+        }
+        Ok(used && queue.needs_notification()?)
+    }
+}
+
+// Copies the readable buffers of `chain` into its writable buffers, and returns the number of
+// bytes copied. The copy stops when either side runs out, or at the first buffer which is not
+// in guest memory.
+fn echo<M: GuestAddressSpace>(chain: DescriptorChain<M>) -> u32 {
+    let mem = chain.memory();
+    let mut writable = chain.clone().writable();
+    let (mut dst, mut dst_len) = (GuestAddress(0), 0);
+    let mut buf = [0u8; ECHO_CHUNK_SIZE as usize];
+    let mut copied = 0u32;
+
+    for desc in chain.clone().readable() {
+        let (mut src, mut src_len) = (desc.addr(), desc.len());
+        while src_len > 0 {
+            while dst_len == 0 {
+                match writable.next() {
+                    Some(desc) => (dst, dst_len) = (desc.addr(), desc.len()),
+                    None => return copied,
+                }
+            }
+            let len = src_len.min(dst_len).min(ECHO_CHUNK_SIZE);
+            let buf = &mut buf[..len as usize];
+            if mem.read_slice(buf, src).is_err() || mem.write_slice(buf, dst).is_err() {
+                return copied;
+            }
+            copied = copied.wrapping_add(len);
+            src_len -= len;
+            dst_len -= len;
+            match (
+                src.checked_add(u64::from(len)),
+                dst.checked_add(u64::from(len)),
+            ) {
+                (Some(next_src), Some(next_dst)) => (src, dst) = (next_src, next_dst),
+                _ => return copied,
+            }
+        }
+    }
+    copied
+}
+
+impl<M: GuestAddressSpace> VirtioDeviceType for EchoDevice<M> {
+    fn device_type(&self) -> u32 {
+        ECHO_DEVICE_TYPE
+    }
+}
+
+impl<M: GuestAddressSpace> Borrow<VirtioConfig<M>> for EchoDevice<M> {
+    fn borrow(&self) -> &VirtioConfig<M> {
+        &self.cfg
+    }
+}
+
+impl<M: GuestAddressSpace> BorrowMut<VirtioConfig<M>> for EchoDevice<M> {
+    fn borrow_mut(&mut self) -> &mut VirtioConfig<M> {
+        &mut self.cfg
+    }
+}
+
+impl<M: GuestAddressSpace> VirtioDeviceActions for EchoDevice<M> {
+    // The activation only fails when the driver didn't set up a valid queue.
+    type E = ();
+
+    fn activate(&mut self) -> result::Result<(), ()> {
+        if self.cfg.device_activated || !self.cfg.queues_valid() {
+            return Err(());
+        }
+        self.cfg.device_activated = true;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> result::Result<(), ()> {
+        let cfg = &mut self.cfg;
+        cfg.driver_features = 0;
+        cfg.device_features_select = 0;
+        cfg.driver_features_select = 0;
+        cfg.device_status = 0;
+        cfg.queue_select = 0;
+        cfg.queues.iter_mut().for_each(Queue::reset);
+        cfg.device_activated = false;
+        cfg.reset_interrupt_status();
+        Ok(())
+    }
+}
+
+impl<M: GuestAddressSpace + 'static> VirtioMmioDevice<M> for EchoDevice<M> {
+    fn queue_notify(&mut self, val: u32) {
+        if val != 0 {
+            warn!("invalid queue notification: {}", val);
+            return;
+        }
+        match self.process_queue() {
+            Ok(true) => {
+                self.cfg.signal_used_queue();
+            }
+            Ok(false) => {}
+            Err(e) => error!("failed to process the echo queue: {}", e),
+        }
+    }
+}
+
+/// Goes through the device initialization steps by calling the methods of `device` directly,
+/// accepting the offered features except for `excluded_features`, and setting up the queues of
+/// the device from `vqs`, in order. The device is activated once the driver sets `DRIVER_OK`.
+///
+/// # Arguments
+/// * `device` - The device to initialize.
+/// * `vqs` - The queues laid out in guest memory, one for each queue of the device to set up.
+/// * `excluded_features` - The offered features which are not accepted.
+pub fn activate<M, D>(device: &mut D, vqs: &[VirtQueue], excluded_features: u64)
+where
+    M: GuestAddressSpace,
+    D: WithDriverSelect<M>,
+{
+    device.ack_device_status(ACKNOWLEDGE);
+    device.ack_device_status(ACKNOWLEDGE | DRIVER);
+    let features = device.device_features() & !excluded_features;
+    device.set_driver_features(0, features as u32);
+    device.set_driver_features(1, (features >> 32) as u32);
+    device.ack_device_status(ACKNOWLEDGE | DRIVER | FEATURES_OK);
+
+    for (i, vq) in vqs.iter().enumerate() {
+        device.set_queue_select(i as u16);
+        let queue = device.selected_queue_mut().unwrap();
+        queue.size = vq.size();
+        queue.desc_table = vq.dtable_start();
+        queue.avail_ring = vq.avail_start();
+        queue.used_ring = vq.used_start();
+        queue.ready = true;
+    }
+    device.ack_device_status(ACKNOWLEDGE | DRIVER | FEATURES_OK | DRIVER_OK);
+}
+
+/// Returns guest memory made of two adjacent regions of `region_size` bytes, starting at
+/// address 0, so the buffers can straddle the boundary between them.
+///
+/// # Arguments
+/// * `region_size` - The size of each region.
+pub fn adjacent_regions(region_size: usize) -> GuestMemoryMmap {
+    GuestMemoryMmap::from_ranges(&[
+        (GuestAddress(0), region_size),
+        (GuestAddress(region_size as u64), region_size),
+    ])
+    .unwrap()
+}
+
+/// A message received on the backend side of a vhost-user connection.
+#[cfg(feature = "vhost-user")]
+#[derive(Debug)]
+pub struct BackendMessage {
+    /// The header of the message.
+    pub header: Header,
+    /// The payload which follows the header.
+    pub payload: Vec<u8>,
+    /// The file descriptors passed along with the message.
+    pub fds: Vec<File>,
+}
+
+#[cfg(feature = "vhost-user")]
+impl BackendMessage {
+    /// Returns the object at `offset` within the payload.
+    ///
+    /// # Arguments
+    /// * `offset` - The offset of the object within the payload.
+    pub fn obj<T: ByteValued>(&self, offset: usize) -> T {
+        let bytes = &self.payload[offset..offset + size_of::<T>()];
+        // Safe because `bytes` holds `size_of::<T>()` bytes, and a `ByteValued` object can be
+        // initialized with arbitrary data, at any alignment.
+        unsafe { (bytes.as_ptr() as *const T).read_unaligned() }
+    }
+}
+
+/// Receives a message on the backend side of a vhost-user connection, or returns `None` when
+/// the frontend disconnects.
+///
+/// # Arguments
+/// * `stream` - The backend end of the connection.
+#[cfg(feature = "vhost-user")]
+pub fn recv_backend_message(stream: &mut UnixStream) -> Option<BackendMessage> {
+    let mut header = Header::default();
+    let mut fds = [-1; MAX_MEMORY_REGIONS];
+    let mut iovecs = [iovec {
+        iov_base: header.as_mut_slice().as_mut_ptr() as *mut libc::c_void,
+        iov_len: size_of::<Header>(),
+    }];
+    // Safe because the iovec points to the header, which can hold arbitrary data.
+    let (len, fd_count) = unsafe { stream.recv_with_fds(&mut iovecs, &mut fds) }.unwrap();
+    if len == 0 {
+        return None;
+    }
+    stream
+        .read_exact(&mut header.as_mut_slice()[len..])
+        .unwrap();
+    let mut payload = vec![0; header.size as usize];
+    stream.read_exact(&mut payload).unwrap();
+    let fds = fds[..fd_count]
+        .iter()
+        // Safe because the received file descriptors are owned by the backend.
+        .map(|&fd| unsafe { File::from_raw_fd(fd) })
+        .collect();
+    Some(BackendMessage {
+        header,
+        payload,
+        fds,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use virtio_queue::VIRTQ_DESC_F_WRITE;
+    use vmm_sys_util::tempfile::TempFile;
+
+    use crate::virtio_config::tests::{Dummy, DummyMem};
+    use crate::{
+        set_interrupt_status, SharedMemoryRegion, VirtioDevice, WithDriverSelect,
+        VIRTIO_MMIO_INT_VRING,
+    };
+
+    const FEATURES: u64 = (1 << 32) | (1 << VIRTIO_F_RING_EVENT_IDX) | 1;
+
+    fn driver(mem: &GuestMemoryMmap) -> MmioDriver<'_, DummyMem, Dummy> {
+        MmioDriver::new(
+            Dummy::new(3, FEATURES, vec![1, 2, 3, 4]),
+            mem,
+            GuestAddress(0),
+        )
+    }
+
+    #[test]
+    fn test_initialize() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+
+        // `Dummy` only has one queue, with a maximum size of 256.
+        assert_eq!(
+            driver(&mem).initialize(FEATURES, 2, 16),
+            Err(Error::InvalidQueueIndex(1))
+        );
+        assert_eq!(
+            driver(&mem).initialize(FEATURES, 1, 512),
+            Err(Error::InvalidQueueSize(0))
+        );
+
+        // Only the features supported by both sides are negotiated.
+        let mut driver = driver(&mem);
+        let features = (1 << 32) | (1 << VIRTIO_F_RING_EVENT_IDX);
+        assert_eq!(driver.initialize(features | 2, 1, 16), Ok(features));
+        assert_eq!(driver.features(), features);
+        assert_eq!(
+            driver.status(),
+            ACKNOWLEDGE | DRIVER | FEATURES_OK | DRIVER_OK
+        );
+
+        let device = driver.device();
+        assert_eq!(device.activate_count, 1);
+        assert_eq!(device.driver_features(), features);
+        let queue = device.queue(0).unwrap();
+        let vq = driver.virt_queue(0).unwrap();
+        assert!(queue.ready && queue.event_idx_enabled);
+        assert_eq!(queue.size, 16);
+        assert_eq!(queue.desc_table, vq.dtable_start());
+        assert_eq!(queue.avail_ring, vq.avail_start());
+        assert_eq!(queue.used_ring, vq.used_start());
+        assert!(driver.virt_queue(1).is_none());
+
+        let mut config = [0u8; 4];
+        driver.read_config(1, &mut config[..3]);
+        assert_eq!(config, [2, 3, 4, 0]);
+
+        // `initialize` starts with a reset as well.
+        driver.reset();
+        assert_eq!(driver.device().reset_count, 2);
+        assert!(driver.virt_queue(0).is_none());
+    }
+
+    #[test]
+    fn test_buffers() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let mut driver = driver(&mem);
+        driver.initialize(FEATURES, 1, 4).unwrap();
+
+        let descs = [
+            Descriptor::new(0x1_0000, 0x10, VIRTQ_DESC_F_NEXT, 0),
+            Descriptor::new(0x2_0000, 0x10, VIRTQ_DESC_F_WRITE, 3),
+        ];
+        assert_eq!(driver.add_buffers(0, &descs), Ok(0));
+        assert_eq!(driver.add_buffers(0, &descs), Ok(2));
+        assert_eq!(driver.add_buffers(0, &descs[..1]), Err(Error::QueueFull));
+        assert_eq!(
+            driver.add_buffers(1, &descs),
+            Err(Error::InvalidQueueIndex(1))
+        );
+        driver.kick(0);
+        assert_eq!(driver.device().last_queue_notify, 0);
+
+        // The `NEXT` flags and the `next` fields link the descriptors of each chain.
+        let vq = driver.virt_queue(0).unwrap();
+        assert_eq!(vq.avail.idx().load(), 2);
+        assert_eq!(vq.dtable(0).flags().load(), VIRTQ_DESC_F_NEXT);
+        assert_eq!(vq.dtable(0).next().load(), 1);
+        assert_eq!(vq.dtable(1).flags().load(), VIRTQ_DESC_F_WRITE);
+        assert_eq!(vq.dtable(1).next().load(), 0);
+
+        // Play the role of the device, which only uses the second chain.
+        let mut queue = vq.create_queue(&mem);
+        let head = queue.iter().unwrap().nth(1).unwrap().head_index();
+        queue.add_used(head, 0x10).unwrap();
+        set_interrupt_status(driver.device().interrupt_status(), VIRTIO_MMIO_INT_VRING);
+
+        assert_eq!(driver.ack_interrupt(), 1);
+        assert_eq!(driver.ack_interrupt(), 0);
+        assert_eq!(driver.pop_used(0), Some((2, 0x10)));
+        assert_eq!(driver.pop_used(0), None);
+        // The driver asks to be notified about the next used buffer.
+        assert_eq!(driver.virt_queue(0).unwrap().avail.event().load(), 1);
+
+        // The descriptors of the used chain are free again.
+        assert_eq!(driver.add_buffers(0, &descs), Ok(2));
+    }
+
+    #[test]
+    fn test_record_replay() {
+        // The beginning of the probe sequence of a driver.
+        let mut recorder = MmioRecorder::new(Dummy::new(3, FEATURES, vec![1, 2, 3, 4]));
+        let mut data = [0u8; 4];
+        recorder.read(MAGIC_VALUE, &mut data);
+        recorder.write(STATUS, &[ACKNOWLEDGE, 0, 0, 0]);
+        recorder.write(DEVICE_FEATURES_SEL, &[1, 0, 0, 0]);
+        recorder.read(DEVICE_FEATURES, &mut data);
+        recorder.read(CONFIG + 1, &mut data[..2]);
+
+        let trace = recorder.trace();
+        assert_eq!(
+            trace,
+            "r 0x0 76697274\n\
+             w 0x70 01000000\n\
+             w 0x14 01000000\n\
+             r 0x10 01000000\n\
+             r 0x101 0203\n"
+        );
+        let accesses = parse_trace(&format!("# A comment.\n\n{}", trace)).unwrap();
+        assert_eq!(accesses, recorder.accesses());
+        assert_eq!(
+            accesses[4],
+            MmioAccess {
+                direction: Direction::Read,
+                offset: 0x101,
+                data: vec![2, 3],
+            }
+        );
+
+        // The replay leaves a new device in the same state.
+        let mut replay = MmioRecorder::new(Dummy::new(3, FEATURES, vec![1, 2, 3, 4]));
+        assert_eq!(replay.replay(&accesses), Ok(()));
+        assert_eq!(replay.device().device_status(), ACKNOWLEDGE);
+        assert_eq!(replay.accesses(), accesses);
+
+        // A device which behaves differently is caught.
+        let mut replay = MmioRecorder::new(Dummy::new(3, 1, vec![1, 2, 3, 4]));
+        assert_eq!(
+            replay.replay(&accesses),
+            Err(Error::UnexpectedRead {
+                index: 3,
+                data: vec![0, 0, 0, 0]
+            })
+        );
+
+        assert_eq!(
+            parse_trace("r 0x0 00\nr 0x0 0\n"),
+            Err(Error::InvalidTrace(2))
+        );
+        for line in ["x 0x0 00", "r 0 00", "r 0x0", "r 0x0 zz", "r 0x0 00 00"].iter() {
+            assert_eq!(parse_trace(line), Err(Error::InvalidTrace(1)));
+        }
+    }
+
+    #[test]
+    fn test_echo_device() {
+        let mem = Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let mut driver = MmioDriver::new(EchoDevice::new(mem.clone(), 16), &mem, GuestAddress(0));
+        assert_eq!(driver.read_reg(0x08), ECHO_DEVICE_TYPE);
+
+        // Nothing is processed before the activation.
+        assert!(!driver.device_mut().process_queue().unwrap());
+        assert_eq!(driver.initialize(FEATURES, 1, 16), Ok(FEATURES & !1));
+
+        let data = (0..0x18).collect::<Vec<u8>>();
+        mem.write_slice(&data, GuestAddress(0x1_0000)).unwrap();
+        // The readable buffers are spread over the writable ones, whatever their sizes.
+        let descs = [
+            Descriptor::new(0x1_0000, 0x10, 0, 0),
+            Descriptor::new(0x1_0010, 0x8, 0, 0),
+            Descriptor::new(0x2_0000, 0x4, VIRTQ_DESC_F_WRITE, 0),
+            Descriptor::new(0x3_0000, 0x20, VIRTQ_DESC_F_WRITE, 0),
+        ];
+        let head = driver.add_buffers(0, &descs).unwrap();
+        driver.kick(0);
+        assert_eq!(driver.ack_interrupt(), 1);
+        assert_eq!(driver.pop_used(0), Some((head, 0x18)));
+        let mut echoed = vec![0u8; 0x18];
+        mem.read_slice(&mut echoed[..4], GuestAddress(0x2_0000))
+            .unwrap();
+        mem.read_slice(&mut echoed[4..], GuestAddress(0x3_0000))
+            .unwrap();
+        assert_eq!(echoed, data);
+
+        // Only as much as fits in the writable buffers is copied.
+        let head = driver.add_buffers(0, &descs[1..3]).unwrap();
+        // The copy stops at the first buffer outside guest memory.
+        let invalid = [
+            Descriptor::new(0x1_0000, 0x10, 0, 0),
+            Descriptor::new(0x10_0000, 0x10, 0, 0),
+            Descriptor::new(0x2_0000, 0x20, VIRTQ_DESC_F_WRITE, 0),
+        ];
+        let invalid_head = driver.add_buffers(0, &invalid).unwrap();
+        driver.kick(0);
+        assert_eq!(driver.ack_interrupt(), 1);
+        assert_eq!(driver.pop_used(0), Some((head, 0x4)));
+        assert_eq!(driver.pop_used(0), Some((invalid_head, 0x10)));
+        assert_eq!(driver.device().echoed(), 3);
+
+        // Other queues don't exist.
+        driver.kick(1);
+        assert_eq!(driver.ack_interrupt(), 0);
+
+        driver.reset();
+        assert!(!driver.device().queue(0).unwrap().ready);
+        assert!(!driver.device_mut().process_queue().unwrap());
+    }
+
+    #[test]
+    fn test_golden() {
+        let mem = Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+        let mut echo = MmioDriver::new(EchoDevice::new(mem.clone(), 16), &mem, GuestAddress(0));
+        echo.initialize(u64::MAX, 1, 16).unwrap();
+        let dump = echo.register_dump(0);
+        check_golden(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/golden/echo_registers.txt"),
+            &dump,
+        );
+        // The selectors are reset by the dump.
+        assert_eq!(echo.device().device_features_select(), 0);
+        assert_eq!(echo.device().queue_select(), 0);
+
+        // The shared memory regions and the configuration space are part of the dump.
+        let mut driver = driver(&mem);
+        driver
+            .device_mut()
+            .cfg
+            .shm_regions
+            .push(SharedMemoryRegion {
+                id: 0,
+                addr: GuestAddress(0x1_0000_0000),
+                len: 0x2000,
+            });
+        let dump = driver.register_dump(4);
+        assert!(dump.contains("shm_base_high[0]        0x00000001\n"));
+        assert!(dump.ends_with("config\n0000: 01 02 03 04\n"));
+
+        assert_eq!(
+            hex_dump(&(0..20).collect::<Vec<u8>>()),
+            "0000: 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f\n0010: 10 11 12 13\n"
+        );
+    }
+
+    #[test]
+    fn test_check_golden() {
+        // The golden files would be written instead of compared.
+        if env::var_os(UPDATE_GOLDEN_ENV).is_some() {
+            return;
+        }
+
+        let file = TempFile::new().unwrap();
+        fs::write(file.as_path(), "a\nb\n").unwrap();
+        check_golden(file.as_path(), "a\nb\n");
+        let err = std::panic::catch_unwind(|| check_golden(file.as_path(), "a\nc\n")).unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert!(
+            msg.contains("line 2:\n  expected: b\n  actual:   c"),
+            "{}",
+            msg
+        );
+        assert!(std::panic::catch_unwind(|| check_golden(file.as_path(), "a\n")).is_err());
+    }
+}
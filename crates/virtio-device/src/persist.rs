@@ -0,0 +1,836 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Snapshot and restore of whole devices.
+//!
+//! The state of a device is split in two parts: the generic one, which covers `VirtioConfig`
+//! and the queues (see [`VirtioConfigState`](struct.VirtioConfigState.html)), and the device
+//! specific one, which is defined by each device. The
+//! [`VirtioDevicePersist`](trait.VirtioDevicePersist.html) trait saves both of them in a
+//! [`VirtioDeviceState`](struct.VirtioDeviceState.html), which also records the version of the
+//! device specific state, and creates a device from a saved state. The resources which are not
+//! part of the state (i.e. the guest memory, the backend or the interrupt notifier) are passed
+//! to the device when it's restored.
+//!
+//! The states only contain plain data, and the VMM is free to serialize them in whatever format
+//! it uses for snapshots. Alternatively, the states whose device specific part implements
+//! [`StateCodec`](trait.StateCodec.html) can be sent over a migration channel (or stored in a
+//! file) with [`save_to`](fn.save_to.html), and read back with [`load_from`](fn.load_from.html).
+//! Each state is framed with a magic number, the version of the device specific state, and the
+//! length of the payload that follows. Device specific states which implement
+//! [`VersionedState`](trait.VersionedState.html) can also be written with the layout of an older
+//! version, and states written by older releases are upgraded when they're read.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::fmt::{self, Display};
+use core::result;
+use core::sync::atomic::{AtomicU8, Ordering};
+#[cfg(feature = "std")]
+use std::convert::TryFrom;
+#[cfg(feature = "std")]
+use std::io::{self, Read, Write};
+
+use vm_memory::{GuestAddress, GuestAddressSpace};
+
+use virtio_queue::{Queue, QueueState};
+
+use crate::{SharedMemoryRegion, VirtioConfig};
+
+/// The state of the generic part of a device, without the guest memory the queues access.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VirtioConfigState {
+    /// The set of features exposed by the device.
+    pub device_features: u64,
+    /// The set of features acknowledged by the driver.
+    pub driver_features: u64,
+    /// Index of the current device features page.
+    pub device_features_select: u32,
+    /// Index of the current driver acknowledgement device features page.
+    pub driver_features_select: u32,
+    /// Device status flags.
+    pub device_status: u8,
+    /// Index of the queue currently selected by the driver.
+    pub queue_select: u16,
+    /// The states of the queues associated with the device.
+    pub queues: Vec<QueueState>,
+    /// Configuration space generation number.
+    pub config_generation: u8,
+    /// Contents of the device configuration space.
+    pub config_space: Vec<u8>,
+    /// Represents whether the device has been activated or not.
+    pub device_activated: bool,
+    /// Device interrupt status.
+    pub interrupt_status: u8,
+    /// Identifier of the shared memory region currently selected by the driver.
+    pub shm_select: u32,
+    /// Shared memory regions of the device.
+    pub shm_regions: Vec<SharedMemoryRegion>,
+}
+
+impl<M: GuestAddressSpace> VirtioConfig<M> {
+    /// Returns the state of the configuration and of the queues.
+    pub fn state(&self) -> VirtioConfigState {
+        VirtioConfigState {
+            device_features: self.device_features,
+            driver_features: self.driver_features,
+            device_features_select: self.device_features_select,
+            driver_features_select: self.driver_features_select,
+            device_status: self.device_status,
+            queue_select: self.queue_select,
+            queues: self.queues.iter().map(Queue::state).collect(),
+            config_generation: self.config_generation,
+            config_space: self.config_space.clone(),
+            device_activated: self.device_activated,
+            // Pairs with the `Release` RMW in `set_interrupt_status`, so the saved queues aren't
+            // older than the interrupt status.
+            interrupt_status: self.interrupt_status.load(Ordering::Acquire),
+            shm_select: self.shm_select,
+            shm_regions: self.shm_regions.clone(),
+        }
+    }
+}
+
+impl<M: GuestAddressSpace + Clone> VirtioConfig<M> {
+    /// Creates a `VirtioConfig` object from a saved state.
+    ///
+    /// The negotiated features, the driver selections, the device status and the configuration
+    /// space are restored as they were, so the driver continues from the same initialization
+    /// step (or keeps using the activated device) without going through the handshake again.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory the queues access.
+    /// * `state` - The saved state.
+    pub fn from_state(mem: M, state: &VirtioConfigState) -> Self {
+        let queues = state
+            .queues
+            .iter()
+            .map(|queue_state| {
+                let mut queue = Queue::new(mem.clone(), queue_state.max_size);
+                queue.set_state(queue_state);
+                queue
+            })
+            .collect();
+
+        VirtioConfig {
+            device_features: state.device_features,
+            driver_features: state.driver_features,
+            device_features_select: state.device_features_select,
+            driver_features_select: state.driver_features_select,
+            device_status: state.device_status,
+            queue_select: state.queue_select,
+            queues,
+            config_generation: state.config_generation,
+            config_space: state.config_space.clone(),
+            device_activated: state.device_activated,
+            interrupt_status: Arc::new(AtomicU8::new(state.interrupt_status)),
+            shm_select: state.shm_select,
+            shm_regions: state.shm_regions.clone(),
+        }
+    }
+}
+
+/// The saved state of a device.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VirtioDeviceState<D> {
+    /// The version of the device specific state.
+    pub version: u16,
+    /// The state of the generic part of the device.
+    pub config: VirtioConfigState,
+    /// The device specific state.
+    pub device: D,
+}
+
+/// Saves the state of a device, and creates devices from saved states.
+pub trait VirtioDevicePersist: Sized {
+    /// The device specific state.
+    type State;
+    /// The resources which are not part of the state, and are required for creating the device.
+    type ConstructorArgs;
+    /// Type of the error that can be returned by `restore`.
+    type E;
+
+    /// The version of the device specific state, which is increased whenever its layout
+    /// changes.
+    const STATE_VERSION: u16;
+
+    /// Returns the state of the device. The device is expected to be quiesced beforehand (i.e.
+    /// with `VirtioDevicePause::pause`), such that no requests are in flight.
+    fn save(&self) -> VirtioDeviceState<Self::State>;
+
+    /// Creates a device from a saved state. The device is activated when it was activated at
+    /// the time the state was saved, and it starts processing the queues where it left off.
+    /// The notification `EventFd`s of the device have to be registered again afterwards (see
+    /// `VirtioDeviceEvents::reattach`).
+    ///
+    /// # Arguments
+    /// * `args` - The resources which are not part of the state.
+    /// * `state` - The saved state.
+    fn restore(
+        args: Self::ConstructorArgs,
+        state: &VirtioDeviceState<Self::State>,
+    ) -> result::Result<Self, Self::E>;
+}
+
+/// The magic number which starts every state written by `save_to` ("VSTA" in little endian).
+pub const STATE_MAGIC: u32 = 0x4154_5356;
+
+/// The maximum length of a state payload accepted by `load_from`, which bounds the memory
+/// allocated for states received from an untrusted source.
+pub const MAX_STATE_LEN: u32 = 16 << 20;
+
+/// Errors encountered while streaming device states.
+#[derive(Debug)]
+pub enum Error {
+    /// The stream doesn't start with `STATE_MAGIC`.
+    InvalidMagic(u32),
+    /// The payload is truncated, or contains invalid values.
+    InvalidPayload,
+    /// Failed to read or write the stream.
+    #[cfg(feature = "std")]
+    Io(io::Error),
+    /// The payload is longer than `MAX_STATE_LEN`.
+    StateTooLarge(u64),
+    /// The state can't be converted from or to the layout of the specified version.
+    UnsupportedVersion(u16),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            InvalidMagic(magic) => write!(f, "invalid state magic number 0x{:x}", magic),
+            InvalidPayload => write!(f, "invalid state payload"),
+            #[cfg(feature = "std")]
+            Io(ref err) => write!(f, "failed to access the state stream: {}", err),
+            StateTooLarge(len) => write!(f, "state payload too large: {} bytes", len),
+            UnsupportedVersion(version) => write!(f, "unsupported state version {}", version),
+        }
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Converts states to a byte representation, and back.
+///
+/// Integers are encoded in little endian, and variable length values (i.e. vectors) are
+/// prefixed with their length, so the encoding doesn't depend on the host. Device specific
+/// states implement this trait by encoding their fields in order.
+pub trait StateCodec: Sized {
+    /// Appends the encoded value to `buf`.
+    ///
+    /// # Arguments
+    /// * `buf` - The buffer that holds the encoded state.
+    fn encode(&self, buf: &mut Vec<u8>);
+
+    /// Decodes a value from the start of `buf`, which is advanced past it. Returns `None` when
+    /// `buf` is too short, or holds an invalid value.
+    ///
+    /// # Arguments
+    /// * `buf` - The remaining part of the encoded state.
+    fn decode(buf: &mut &[u8]) -> Option<Self>;
+}
+
+macro_rules! impl_state_codec_int {
+    ($($t:ty),*) => {
+        $(
+            impl StateCodec for $t {
+                fn encode(&self, buf: &mut Vec<u8>) {
+                    buf.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn decode(buf: &mut &[u8]) -> Option<Self> {
+                    let (bytes, rest) = split_at_checked(buf, core::mem::size_of::<$t>())?;
+                    *buf = rest;
+                    // The slice has the size of the integer.
+                    Some(<$t>::from_le_bytes(bytes.try_into().ok()?))
+                }
+            }
+        )*
+    };
+}
+
+impl_state_codec_int!(u8, u16, u32, u64);
+
+// Splits `buf` at `mid`, or returns `None` if it's shorter than that.
+fn split_at_checked(buf: &[u8], mid: usize) -> Option<(&[u8], &[u8])> {
+    if mid > buf.len() {
+        return None;
+    }
+    Some(buf.split_at(mid))
+}
+
+impl StateCodec for bool {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        u8::from(*self).encode(buf);
+    }
+
+    fn decode(buf: &mut &[u8]) -> Option<Self> {
+        match u8::decode(buf)? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl StateCodec for GuestAddress {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.0.encode(buf);
+    }
+
+    fn decode(buf: &mut &[u8]) -> Option<Self> {
+        u64::decode(buf).map(GuestAddress)
+    }
+}
+
+impl<const N: usize> StateCodec for [u8; N] {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self);
+    }
+
+    fn decode(buf: &mut &[u8]) -> Option<Self> {
+        let (bytes, rest) = split_at_checked(buf, N)?;
+        *buf = rest;
+        bytes.try_into().ok()
+    }
+}
+
+impl<T: StateCodec> StateCodec for Option<T> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.is_some().encode(buf);
+        if let Some(value) = self {
+            value.encode(buf);
+        }
+    }
+
+    fn decode(buf: &mut &[u8]) -> Option<Self> {
+        if bool::decode(buf)? {
+            T::decode(buf).map(Some)
+        } else {
+            Some(None)
+        }
+    }
+}
+
+impl<T: StateCodec> StateCodec for Vec<T> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        // States never hold anywhere near `u32::MAX` elements.
+        (self.len() as u32).encode(buf);
+        self.iter().for_each(|value| value.encode(buf));
+    }
+
+    fn decode(buf: &mut &[u8]) -> Option<Self> {
+        let len = u32::decode(buf)? as usize;
+        // Every element takes at least one byte, so a corrupted length can't make us allocate
+        // more than the size of the payload.
+        let mut values = Vec::with_capacity(len.min(buf.len()));
+        for _ in 0..len {
+            values.push(T::decode(buf)?);
+        }
+        Some(values)
+    }
+}
+
+impl StateCodec for QueueState {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.max_size.encode(buf);
+        self.size.encode(buf);
+        self.ready.encode(buf);
+        self.desc_table.encode(buf);
+        self.avail_ring.encode(buf);
+        self.used_ring.encode(buf);
+        self.next_avail.encode(buf);
+        self.next_used.encode(buf);
+        self.event_idx_enabled.encode(buf);
+    }
+
+    fn decode(buf: &mut &[u8]) -> Option<Self> {
+        Some(QueueState {
+            max_size: StateCodec::decode(buf)?,
+            size: StateCodec::decode(buf)?,
+            ready: StateCodec::decode(buf)?,
+            desc_table: StateCodec::decode(buf)?,
+            avail_ring: StateCodec::decode(buf)?,
+            used_ring: StateCodec::decode(buf)?,
+            next_avail: StateCodec::decode(buf)?,
+            next_used: StateCodec::decode(buf)?,
+            event_idx_enabled: StateCodec::decode(buf)?,
+        })
+    }
+}
+
+impl StateCodec for SharedMemoryRegion {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.id.encode(buf);
+        self.addr.encode(buf);
+        self.len.encode(buf);
+    }
+
+    fn decode(buf: &mut &[u8]) -> Option<Self> {
+        Some(SharedMemoryRegion {
+            id: StateCodec::decode(buf)?,
+            addr: StateCodec::decode(buf)?,
+            len: StateCodec::decode(buf)?,
+        })
+    }
+}
+
+impl StateCodec for VirtioConfigState {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.device_features.encode(buf);
+        self.driver_features.encode(buf);
+        self.device_features_select.encode(buf);
+        self.driver_features_select.encode(buf);
+        self.device_status.encode(buf);
+        self.queue_select.encode(buf);
+        self.queues.encode(buf);
+        self.config_generation.encode(buf);
+        self.config_space.encode(buf);
+        self.device_activated.encode(buf);
+        self.interrupt_status.encode(buf);
+        self.shm_select.encode(buf);
+        self.shm_regions.encode(buf);
+    }
+
+    fn decode(buf: &mut &[u8]) -> Option<Self> {
+        Some(VirtioConfigState {
+            device_features: StateCodec::decode(buf)?,
+            driver_features: StateCodec::decode(buf)?,
+            device_features_select: StateCodec::decode(buf)?,
+            driver_features_select: StateCodec::decode(buf)?,
+            device_status: StateCodec::decode(buf)?,
+            queue_select: StateCodec::decode(buf)?,
+            queues: StateCodec::decode(buf)?,
+            config_generation: StateCodec::decode(buf)?,
+            config_space: StateCodec::decode(buf)?,
+            device_activated: StateCodec::decode(buf)?,
+            interrupt_status: StateCodec::decode(buf)?,
+            shm_select: StateCodec::decode(buf)?,
+            shm_regions: StateCodec::decode(buf)?,
+        })
+    }
+}
+
+/// Device specific states whose layout changed across releases, which can be converted from
+/// and to the layouts of the older versions.
+///
+/// Each layout change increases `VERSION`, and the type of the previous layout is kept around
+/// (i.e. as `BlockStateV1` next to `BlockState`) together with the conversions between them,
+/// such that snapshots taken with older releases can still be restored, and states can be
+/// migrated to hosts which run older releases.
+pub trait VersionedState: StateCodec {
+    /// The version of the current layout.
+    const VERSION: u16;
+
+    /// Decodes a state which was encoded with the layout of `version`, and upgrades it to the
+    /// current layout.
+    ///
+    /// # Arguments
+    /// * `version` - The version of the encoded state.
+    /// * `buf` - The remaining part of the encoded state.
+    fn decode_version(version: u16, buf: &mut &[u8]) -> Result<Self> {
+        if version != Self::VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        Self::decode(buf).ok_or(Error::InvalidPayload)
+    }
+
+    /// Downgrades the state to the layout of `version`, and appends its encoding to `buf`.
+    ///
+    /// # Arguments
+    /// * `version` - The version of the layout, which is not newer than the current one.
+    /// * `buf` - The buffer that holds the encoded state.
+    fn encode_version(&self, version: u16, buf: &mut Vec<u8>) -> Result<()> {
+        if version != Self::VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        self.encode(buf);
+        Ok(())
+    }
+}
+
+// Writes the frame of a state with the specified version and encoded payload.
+#[cfg(feature = "std")]
+fn write_frame<W: Write>(writer: &mut W, version: u16, payload: &[u8]) -> Result<()> {
+    let len = u32::try_from(payload.len())
+        .ok()
+        .filter(|&len| len <= MAX_STATE_LEN)
+        .ok_or(Error::StateTooLarge(payload.len() as u64))?;
+
+    let mut header = Vec::with_capacity(10);
+    STATE_MAGIC.encode(&mut header);
+    version.encode(&mut header);
+    len.encode(&mut header);
+    writer.write_all(&header).map_err(Error::Io)?;
+    writer.write_all(payload).map_err(Error::Io)?;
+    writer.flush().map_err(Error::Io)
+}
+
+// Reads the frame of a state, and returns its version and encoded payload.
+#[cfg(feature = "std")]
+fn read_frame<R: Read>(reader: &mut R) -> Result<(u16, Vec<u8>)> {
+    let mut header = [0u8; 10];
+    reader.read_exact(&mut header).map_err(Error::Io)?;
+    let mut buf = &header[..];
+    // The header has the size of the decoded fields.
+    let magic = u32::decode(&mut buf).ok_or(Error::InvalidPayload)?;
+    if magic != STATE_MAGIC {
+        return Err(Error::InvalidMagic(magic));
+    }
+    let version = u16::decode(&mut buf).ok_or(Error::InvalidPayload)?;
+    let len = u32::decode(&mut buf).ok_or(Error::InvalidPayload)?;
+    if len > MAX_STATE_LEN {
+        return Err(Error::StateTooLarge(u64::from(len)));
+    }
+
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload).map_err(Error::Io)?;
+    Ok((version, payload))
+}
+
+/// Writes a device state to `writer` (i.e. a migration socket), framed as follows: the
+/// `STATE_MAGIC` number (`u32`), the version of the device specific state (`u16`), the length
+/// of the payload (`u32`), and the payload, which holds the encoded generic state followed by
+/// the encoded device specific state. All the values are little endian.
+///
+/// # Arguments
+/// * `writer` - The destination of the state.
+/// * `state` - The saved state of the device.
+#[cfg(feature = "std")]
+pub fn save_to<W: Write, D: StateCodec>(
+    writer: &mut W,
+    state: &VirtioDeviceState<D>,
+) -> Result<()> {
+    let mut payload = Vec::new();
+    state.config.encode(&mut payload);
+    state.device.encode(&mut payload);
+    write_frame(writer, state.version, &payload)
+}
+
+/// Writes a device state to `writer` like `save_to` does, after downgrading its device specific
+/// part to the layout of `version` (i.e. for migrating the device to a host which runs an older
+/// release).
+///
+/// # Arguments
+/// * `writer` - The destination of the state.
+/// * `state` - The saved state of the device.
+/// * `version` - The version of the layout the state is written with.
+#[cfg(feature = "std")]
+pub fn save_to_version<W: Write, D: VersionedState>(
+    writer: &mut W,
+    state: &VirtioDeviceState<D>,
+    version: u16,
+) -> Result<()> {
+    let mut payload = Vec::new();
+    state.config.encode(&mut payload);
+    state.device.encode_version(version, &mut payload)?;
+    write_frame(writer, version, &payload)
+}
+
+/// Reads a device state written by `save_to` from `reader`. Exactly the bytes of the state are
+/// consumed, so multiple states can be sent over the same stream.
+///
+/// The version of the device specific state is returned as part of the state, and it's up to
+/// `VirtioDevicePersist::restore` to check it.
+///
+/// # Arguments
+/// * `reader` - The source of the state.
+#[cfg(feature = "std")]
+pub fn load_from<R: Read, D: StateCodec>(reader: &mut R) -> Result<VirtioDeviceState<D>> {
+    let (version, payload) = read_frame(reader)?;
+    let mut buf = &payload[..];
+    let config = VirtioConfigState::decode(&mut buf).ok_or(Error::InvalidPayload)?;
+    let device = D::decode(&mut buf).ok_or(Error::InvalidPayload)?;
+    if !buf.is_empty() {
+        return Err(Error::InvalidPayload);
+    }
+    Ok(VirtioDeviceState {
+        version,
+        config,
+        device,
+    })
+}
+
+/// Reads a device state from `reader` like `load_from` does, and upgrades its device specific
+/// part to the current layout, such that states written by older releases can be restored.
+/// The returned state has the current version.
+///
+/// # Arguments
+/// * `reader` - The source of the state.
+#[cfg(feature = "std")]
+pub fn load_from_versioned<R: Read, D: VersionedState>(
+    reader: &mut R,
+) -> Result<VirtioDeviceState<D>> {
+    let (version, payload) = read_frame(reader)?;
+    let mut buf = &payload[..];
+    let config = VirtioConfigState::decode(&mut buf).ok_or(Error::InvalidPayload)?;
+    let device = D::decode_version(version, &mut buf)?;
+    if !buf.is_empty() {
+        return Err(Error::InvalidPayload);
+    }
+    Ok(VirtioDeviceState {
+        version: D::VERSION,
+        config,
+        device,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::GuestMemoryMmap;
+
+    use crate::mock::{check_golden, hex_dump};
+    use crate::status::{ACKNOWLEDGE, DRIVER, DRIVER_OK, FEATURES_OK};
+    use crate::virtio_config::tests::Dummy;
+    use crate::{VirtioDevice, WithDriverSelect, VIRTIO_F_RING_EVENT_IDX};
+
+    #[test]
+    fn test_config_state() {
+        let mut d = Dummy::new(2, 7, vec![1, 2, 3, 4]);
+        let cfg = &mut d.cfg;
+        cfg.driver_features = 3;
+        cfg.device_status = 0xf;
+        cfg.config_generation = 2;
+        cfg.device_activated = true;
+        cfg.interrupt_status.store(1, Ordering::SeqCst);
+        cfg.shm_regions.push(SharedMemoryRegion {
+            id: 1,
+            addr: GuestAddress(0x1_0000_0000),
+            len: 0x1000,
+        });
+        let queue = &mut cfg.queues[0];
+        queue.size = 16;
+        queue.desc_table = GuestAddress(0x1000);
+        queue.ready = true;
+        queue.set_next_avail(4);
+
+        let state = cfg.state();
+        assert_eq!(state.queues.len(), 1);
+        assert_eq!(state.queues[0].next_avail, 4);
+        assert_eq!(state.interrupt_status, 1);
+
+        let mem = Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1_0000)]).unwrap());
+        let restored = VirtioConfig::from_state(mem, &state);
+        assert_eq!(restored.state(), state);
+        assert_eq!(restored.queues[0].max_size(), 256);
+        assert_eq!(restored.queues[0].next_avail(), 4);
+        // The interrupt status is not shared with the saved device.
+        assert!(!Arc::ptr_eq(
+            &restored.interrupt_status,
+            &d.cfg.interrupt_status
+        ));
+    }
+
+    #[test]
+    fn test_restore_negotiation() {
+        let features = (1 << VIRTIO_F_RING_EVENT_IDX) | 0x5;
+        let mut d = Dummy::new(2, features, vec![1, 2, 3, 4]);
+        d.ack_device_status(ACKNOWLEDGE);
+        d.ack_device_status(ACKNOWLEDGE | DRIVER);
+        d.set_driver_features(0, (features & !0x4) as u32);
+        d.set_device_features_select(1);
+        d.set_driver_features_select(1);
+        d.ack_device_status(ACKNOWLEDGE | DRIVER | FEATURES_OK);
+        d.write_config(0, &[5]);
+        let state = d.cfg.state();
+
+        // The handshake continues from `FEATURES_OK` on the restored device.
+        let mut restored = Dummy::new(2, 0, Vec::new());
+        let mem = Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1_0000)]).unwrap());
+        restored.cfg = VirtioConfig::from_state(mem, &state);
+        assert_eq!(restored.device_features(), features);
+        assert_eq!(restored.driver_features(), features & !0x4);
+        assert_eq!(restored.device_features_select(), 1);
+        assert_eq!(restored.driver_features_select(), 1);
+        assert!(restored.cfg.queues[0].event_idx_enabled);
+        let mut data = [0u8; 4];
+        restored.read_config(0, &mut data);
+        assert_eq!(data, [5, 2, 3, 4]);
+
+        restored.ack_device_status(ACKNOWLEDGE | DRIVER | FEATURES_OK | DRIVER_OK);
+        assert_eq!(restored.activate_count, 1);
+        assert_eq!(
+            restored.device_status(),
+            ACKNOWLEDGE | DRIVER | FEATURES_OK | DRIVER_OK
+        );
+    }
+
+    #[test]
+    fn test_stream_state() {
+        let mut d = Dummy::new(2, 7, vec![1, 2, 3, 4]);
+        d.cfg.device_activated = true;
+        d.cfg.shm_regions.push(SharedMemoryRegion {
+            id: 1,
+            addr: GuestAddress(0x1_0000_0000),
+            len: 0x1000,
+        });
+        let queue = &mut d.cfg.queues[0];
+        queue.size = 16;
+        queue.desc_table = GuestAddress(0x1000);
+        queue.set_next_avail(4);
+        let state = VirtioDeviceState {
+            version: 3,
+            config: d.cfg.state(),
+            device: vec![Some([0xaa, 0xbb]), None],
+        };
+
+        // Multiple states can be sent over the same stream.
+        let mut stream = Vec::new();
+        save_to(&mut stream, &state).unwrap();
+        let len = stream.len();
+        save_to(&mut stream, &state).unwrap();
+        assert_eq!(&stream[..4], b"VSTA");
+        assert_eq!(stream[4..6], [3, 0]);
+        assert_eq!(
+            u32::from_le_bytes(stream[6..10].try_into().unwrap()) as usize,
+            len - 10
+        );
+        let mut reader = &stream[..];
+        assert_eq!(
+            load_from::<_, Vec<Option<[u8; 2]>>>(&mut reader).unwrap(),
+            state
+        );
+        assert_eq!(
+            load_from::<_, Vec<Option<[u8; 2]>>>(&mut reader).unwrap(),
+            state
+        );
+        assert!(reader.is_empty());
+
+        // The device specific state doesn't match the payload.
+        assert!(matches!(
+            load_from::<_, Vec<u64>>(&mut &stream[..len]),
+            Err(Error::InvalidPayload)
+        ));
+        assert!(matches!(
+            load_from::<_, u8>(&mut &stream[..len]),
+            Err(Error::InvalidPayload)
+        ));
+        // The stream is truncated.
+        assert!(matches!(
+            load_from::<_, Vec<Option<[u8; 2]>>>(&mut &stream[..len - 1]),
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+
+        let mut invalid = stream[..len].to_vec();
+        invalid[0] = 0;
+        assert!(matches!(
+            load_from::<_, Vec<u8>>(&mut &invalid[..]),
+            Err(Error::InvalidMagic(0x4154_5300))
+        ));
+        invalid[..4].copy_from_slice(&STATE_MAGIC.to_le_bytes());
+        invalid[6..10].copy_from_slice(&(MAX_STATE_LEN + 1).to_le_bytes());
+        assert!(matches!(
+            load_from::<_, Vec<u8>>(&mut &invalid[..]),
+            Err(Error::StateTooLarge(len)) if len == u64::from(MAX_STATE_LEN) + 1
+        ));
+        // The last byte of the payload is the tag of the second element, which is not a valid
+        // boolean anymore.
+        invalid[6..10].copy_from_slice(&((len - 10) as u32).to_le_bytes());
+        invalid[len - 1] = 2;
+        assert!(matches!(
+            load_from::<_, Vec<Option<[u8; 2]>>>(&mut &invalid[..]),
+            Err(Error::InvalidPayload)
+        ));
+    }
+
+    // A state which only has one layout.
+    impl VersionedState for u32 {
+        const VERSION: u16 = 2;
+    }
+
+    #[test]
+    fn test_versioned_state() {
+        let state = VirtioDeviceState {
+            version: 2,
+            config: VirtioConfigState::default(),
+            device: 0xaabb_ccddu32,
+        };
+        let mut stream = Vec::new();
+        save_to_version(&mut stream, &state, 2).unwrap();
+        assert_eq!(
+            load_from_versioned::<_, u32>(&mut &stream[..]).unwrap(),
+            state
+        );
+
+        // Only the current layout is supported by default.
+        assert!(matches!(
+            save_to_version(&mut stream, &state, 1),
+            Err(Error::UnsupportedVersion(1))
+        ));
+        stream.clear();
+        save_to(
+            &mut stream,
+            &VirtioDeviceState {
+                version: 1,
+                ..state
+            },
+        )
+        .unwrap();
+        assert!(matches!(
+            load_from_versioned::<_, u32>(&mut &stream[..]),
+            Err(Error::UnsupportedVersion(1))
+        ));
+    }
+
+    #[test]
+    fn test_golden_encoding() {
+        // Every field holds a distinct value, so a field which moves shows up in the dump.
+        let state = VirtioDeviceState {
+            version: 7,
+            config: VirtioConfigState {
+                device_features: 0x1_0000_0007,
+                driver_features: 0x1_0000_0005,
+                device_features_select: 1,
+                driver_features_select: 2,
+                device_status: 0xf,
+                queue_select: 1,
+                queues: vec![
+                    QueueState {
+                        max_size: 256,
+                        size: 16,
+                        ready: true,
+                        desc_table: GuestAddress(0x1000),
+                        avail_ring: GuestAddress(0x1100),
+                        used_ring: GuestAddress(0x1200),
+                        next_avail: 3,
+                        next_used: 2,
+                        event_idx_enabled: true,
+                    },
+                    QueueState {
+                        max_size: 128,
+                        ..Default::default()
+                    },
+                ],
+                config_generation: 4,
+                config_space: vec![0xa0, 0xa1, 0xa2],
+                device_activated: true,
+                interrupt_status: 1,
+                shm_select: 3,
+                shm_regions: vec![SharedMemoryRegion {
+                    id: 5,
+                    addr: GuestAddress(0x2_0000_0000),
+                    len: 0x4000,
+                }],
+            },
+            device: vec![Some([0xaa, 0xbb]), None],
+        };
+
+        let mut stream = Vec::new();
+        save_to(&mut stream, &state).unwrap();
+        check_golden(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/golden/device_state.txt"),
+            &hex_dump(&stream),
+        );
+        assert_eq!(
+            load_from::<_, Vec<Option<[u8; 2]>>>(&mut &stream[..]).unwrap(),
+            state
+        );
+    }
+}
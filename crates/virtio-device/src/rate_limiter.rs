@@ -0,0 +1,340 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Token bucket based rate limiting for device queue processing.
+//!
+//! This module provides the following abstractions:
+//!
+//! - [`TokenBucket`](struct.TokenBucket.html) which holds a budget of tokens (i.e. operations or
+//!   bytes) that is continuously replenished, up to the size of the bucket, over `refill_time`.
+//! - [`RateLimiter`](struct.RateLimiter.html) which combines an optional operations bucket with
+//!   an optional bandwidth bucket. When a request can't be budgeted, the rate limiter arms an
+//!   internal timer which signals (via its file descriptor) when the processing can resume.
+//!
+//! A queue handler which can't budget the next request is expected to leave it in the queue
+//! (i.e. with `Queue::go_to_previous_position`), and to process it again once the timer
+//! expires.
+//!
+//! rust-vmm doesn't publish a rate limiter crate: the one used by Firecracker (which the
+//! handler prototype referred to) lives in the Firecracker tree, and depends on its own utility
+//! crates. This module follows the same model (an operations bucket, a bandwidth bucket, and a
+//! timer file descriptor registered with the event loop of the handler), without the one-time
+//! burst budget, which the devices don't need.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+use vmm_sys_util::timerfd::TimerFd;
+
+// The interval after which the processing of the throttled requests is retried.
+const REFILL_TIMER_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A token bucket, which is refilled at a constant rate.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    /// The maximum number of tokens in the bucket.
+    size: u64,
+    /// The time it takes for an empty bucket to be completely refilled.
+    refill_time: Duration,
+    /// The number of tokens currently available.
+    budget: u64,
+    /// The last time the budget was replenished.
+    last_update: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a new full `TokenBucket`, or returns `None` if any of the parameters is zero
+    /// (which is the equivalent of not limiting the associated resource).
+    ///
+    /// # Arguments
+    /// * `size` - The maximum number of tokens in the bucket.
+    /// * `refill_time` - The time it takes for an empty bucket to be completely refilled.
+    pub fn new(size: u64, refill_time: Duration) -> Option<Self> {
+        if size == 0 || refill_time.as_nanos() == 0 {
+            return None;
+        }
+        Some(TokenBucket {
+            size,
+            refill_time,
+            budget: size,
+            last_update: Instant::now(),
+        })
+    }
+
+    /// Returns the maximum number of tokens in the bucket.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the number of tokens currently available.
+    pub fn budget(&mut self) -> u64 {
+        self.replenish();
+        self.budget
+    }
+
+    // Adds the tokens generated since the last update to the budget.
+    fn replenish(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_update).as_nanos();
+        let refill_time = self.refill_time.as_nanos();
+        // `elapsed * size` doesn't overflow an `u128` for any realistic value of `elapsed`.
+        let tokens = elapsed.saturating_mul(u128::from(self.size)) / refill_time;
+        if tokens == 0 {
+            return;
+        }
+        if tokens >= u128::from(self.size - self.budget) {
+            self.budget = self.size;
+            self.last_update = now;
+        } else {
+            // The cast is safe since `tokens` is smaller than `size`.
+            self.budget += tokens as u64;
+            // Only account for the time that actually produced tokens, so we don't lose the
+            // fractional part.
+            let used = tokens * refill_time / u128::from(self.size);
+            self.last_update += Duration::from_nanos(used as u64);
+        }
+    }
+
+    // Returns whether `tokens` can be consumed from the bucket. A request that's larger than
+    // the bucket itself is allowed through when the bucket is full, otherwise it would never be
+    // processed.
+    fn can_consume(&mut self, tokens: u64) -> bool {
+        self.replenish();
+        tokens <= self.budget || self.budget == self.size
+    }
+
+    // Consumes `tokens` from the bucket; `can_consume` has to be checked first.
+    fn consume(&mut self, tokens: u64) {
+        self.budget = self.budget.saturating_sub(tokens);
+    }
+}
+
+/// Limits the rate of processed requests and/or bytes.
+///
+/// # Example
+///
+/// ```rust
+/// # use std::time::Duration;
+/// # use virtio_device::rate_limiter::{RateLimiter, TokenBucket};
+/// // At most 1000 requests and 1 MiB per second.
+/// let mut rate_limiter = RateLimiter::new(
+///     TokenBucket::new(1000, Duration::from_secs(1)),
+///     TokenBucket::new(0x10_0000, Duration::from_secs(1)),
+/// )
+/// .unwrap();
+/// assert!(rate_limiter.consume(1, 0x1000));
+/// ```
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// The bucket which limits the number of operations.
+    ops: Option<TokenBucket>,
+    /// The bucket which limits the number of bytes.
+    bandwidth: Option<TokenBucket>,
+    /// Timer which expires when the processing of throttled requests can be retried.
+    timer_fd: TimerFd,
+    /// Whether the rate limiter is waiting for the buckets to be replenished.
+    blocked: bool,
+}
+
+impl RateLimiter {
+    /// Creates a new `RateLimiter`.
+    ///
+    /// # Arguments
+    /// * `ops` - The bucket for the number of operations, or `None` for no limit.
+    /// * `bandwidth` - The bucket for the number of bytes, or `None` for no limit.
+    pub fn new(ops: Option<TokenBucket>, bandwidth: Option<TokenBucket>) -> io::Result<Self> {
+        Ok(RateLimiter {
+            ops,
+            bandwidth,
+            timer_fd: TimerFd::new()?,
+            blocked: false,
+        })
+    }
+
+    /// Returns whether processing is suspended until the timer expires.
+    pub fn is_blocked(&self) -> bool {
+        self.blocked
+    }
+
+    /// Tries to consume `ops` operations and `bytes` bytes, and returns whether the budget was
+    /// enough for both. Nothing is consumed when `false` is returned, in which case the timer
+    /// is armed and the caller should retry after it expires.
+    ///
+    /// # Arguments
+    /// * `ops` - The number of operations to consume.
+    /// * `bytes` - The number of bytes to consume.
+    pub fn consume(&mut self, ops: u64, bytes: u64) -> bool {
+        if self.blocked {
+            return false;
+        }
+
+        let ops_ok = self.ops.as_mut().map_or(true, |b| b.can_consume(ops));
+        let bytes_ok = self
+            .bandwidth
+            .as_mut()
+            .map_or(true, |b| b.can_consume(bytes));
+        if !ops_ok || !bytes_ok {
+            // If arming the timer fails, we'd rather not block the queue forever.
+            self.blocked = self.timer_fd.reset(REFILL_TIMER_INTERVAL, None).is_ok();
+            return !self.blocked;
+        }
+
+        if let Some(bucket) = self.ops.as_mut() {
+            bucket.consume(ops);
+        }
+        if let Some(bucket) = self.bandwidth.as_mut() {
+            bucket.consume(bytes);
+        }
+        true
+    }
+
+    /// Handles the expiration of the timer, after which requests can be processed again.
+    ///
+    /// This has to be called when the file descriptor of the rate limiter becomes readable.
+    pub fn event_handler(&mut self) -> io::Result<()> {
+        self.timer_fd.wait().map_err(io::Error::from)?;
+        self.blocked = false;
+        Ok(())
+    }
+}
+
+impl AsRawFd for RateLimiter {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer_fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_token_bucket() {
+        assert!(TokenBucket::new(0, Duration::from_secs(1)).is_none());
+        assert!(TokenBucket::new(1, Duration::from_secs(0)).is_none());
+
+        let mut bucket = TokenBucket::new(100, Duration::from_millis(100)).unwrap();
+        assert_eq!(bucket.size(), 100);
+        assert_eq!(bucket.budget(), 100);
+        assert!(bucket.can_consume(100));
+        bucket.consume(60);
+        assert!(bucket.budget() <= 41);
+        assert!(!bucket.can_consume(50));
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(bucket.budget(), 100);
+
+        // Requests larger than the bucket are allowed through when the bucket is full.
+        assert!(bucket.can_consume(150));
+        bucket.consume(150);
+        assert!(!bucket.can_consume(150));
+    }
+
+    #[test]
+    fn test_refill() {
+        let refill_time = Duration::from_secs(100);
+        let mut bucket = TokenBucket::new(100, refill_time).unwrap();
+        bucket.consume(100);
+        assert_eq!(bucket.budget(), 0);
+
+        // The tokens are generated proportionally to the elapsed time.
+        bucket.last_update -= refill_time / 4;
+        assert_eq!(bucket.budget(), 25);
+        assert!(bucket.can_consume(25));
+        assert!(!bucket.can_consume(26));
+
+        // The time which didn't produce a whole token is carried over to the next refill.
+        bucket.consume(25);
+        bucket.last_update -= refill_time / 200;
+        assert_eq!(bucket.budget(), 0);
+        bucket.last_update -= refill_time / 200;
+        assert_eq!(bucket.budget(), 1);
+
+        // The budget never exceeds the size of the bucket.
+        bucket.last_update -= refill_time * 10;
+        assert_eq!(bucket.budget(), 100);
+        // An idle bucket doesn't build up time for later refills.
+        bucket.consume(100);
+        assert_eq!(bucket.budget(), 0);
+    }
+
+    #[test]
+    fn test_burst() {
+        let mut rate_limiter =
+            RateLimiter::new(TokenBucket::new(10, Duration::from_secs(100)), None).unwrap();
+        // A full bucket lets a burst of its size through at once.
+        for _ in 0..10 {
+            assert!(rate_limiter.consume(1, 0));
+        }
+        assert!(!rate_limiter.consume(1, 0));
+        assert!(rate_limiter.is_blocked());
+
+        // A single request which is larger than the bucket goes through when the bucket is
+        // full, and leaves it empty.
+        let mut rate_limiter =
+            RateLimiter::new(None, TokenBucket::new(0x1000, Duration::from_secs(100))).unwrap();
+        assert!(rate_limiter.consume(1, 0x4000));
+        assert_eq!(rate_limiter.bandwidth.as_mut().unwrap().budget(), 0);
+        assert!(!rate_limiter.consume(1, 1));
+    }
+
+    #[test]
+    fn test_timer_expiry() {
+        let mut rate_limiter =
+            RateLimiter::new(TokenBucket::new(1, Duration::from_millis(50)), None).unwrap();
+        let mut pollfd = libc::pollfd {
+            fd: rate_limiter.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Safe because `pollfd` is valid for the duration of the call.
+        let mut poll = |timeout_ms| unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+
+        // The timer is only armed when a request is throttled.
+        assert!(rate_limiter.consume(1, 0));
+        assert_eq!(poll(0), 0);
+        let start = Instant::now();
+        assert!(!rate_limiter.consume(1, 0));
+        assert_eq!(poll(0), 0);
+
+        // The file descriptor becomes readable when the timer expires, and the requests go
+        // through once the expiration is handled.
+        assert_eq!(poll(1000), 1);
+        assert!(start.elapsed() >= REFILL_TIMER_INTERVAL);
+        assert!(!rate_limiter.consume(1, 0));
+        rate_limiter.event_handler().unwrap();
+        assert!(!rate_limiter.is_blocked());
+        assert_eq!(poll(0), 0);
+        assert!(rate_limiter.consume(1, 0));
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut rate_limiter = RateLimiter::new(None, None).unwrap();
+        for _ in 0..100 {
+            assert!(rate_limiter.consume(1, u64::MAX));
+        }
+
+        let mut rate_limiter = RateLimiter::new(
+            TokenBucket::new(2, Duration::from_secs(100)),
+            TokenBucket::new(0x1000, Duration::from_secs(100)),
+        )
+        .unwrap();
+        assert!(rate_limiter.consume(1, 0x800));
+        // Not enough bytes; the operations budget must not be consumed either.
+        assert!(!rate_limiter.consume(1, 0x1000));
+        assert!(rate_limiter.is_blocked());
+        assert_eq!(rate_limiter.ops.as_mut().unwrap().budget(), 1);
+        // Nothing goes through while blocked.
+        assert!(!rate_limiter.consume(0, 0));
+
+        rate_limiter.event_handler().unwrap();
+        assert!(!rate_limiter.is_blocked());
+        assert!(rate_limiter.consume(1, 0x800));
+        assert!(!rate_limiter.consume(1, 0));
+    }
+}
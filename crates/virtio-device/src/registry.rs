@@ -0,0 +1,370 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Declarative device instantiation.
+//!
+//! This module provides the following abstractions:
+//!
+//! - [`DeviceDescription`](struct.DeviceDescription.html) which describes a device (its type,
+//!   the path of its backend, the offered features, the queue sizes and any device specific
+//!   parameters), and can be filled in from the configuration of the VMM.
+//! - [`DeviceRegistry`](struct.DeviceRegistry.html) which maps device types to constructors,
+//!   and creates the devices which correspond to the descriptions.
+//!
+//! The registry is generic over the type of the created devices (for example, a boxed
+//! `VirtioMmioDevice` trait object, or an enum of the devices supported by the VMM) and over
+//! the type of the errors returned by the constructors.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::path::PathBuf;
+use std::result;
+
+use vm_memory::GuestAddressSpace;
+
+use crate::VirtioConfig;
+
+/// Device registry errors.
+#[derive(Debug)]
+pub enum Error<E> {
+    /// A constructor failed to create a device.
+    Constructor(u32, E),
+    /// A constructor is already registered for the device type.
+    DuplicateDeviceType(u32),
+    /// There's no constructor registered for the device type.
+    UnknownDeviceType(u32),
+}
+
+impl<E: Display> Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Constructor(device_type, ref err) => {
+                write!(
+                    f,
+                    "failed to create device of type {}: {}",
+                    device_type, err
+                )
+            }
+            DuplicateDeviceType(device_type) => {
+                write!(f, "device type {} is already registered", device_type)
+            }
+            UnknownDeviceType(device_type) => write!(f, "unknown device type {}", device_type),
+        }
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T, E> = result::Result<T, Error<E>>;
+
+/// The description of a device, which is passed to the constructor registered for its type.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceDescription {
+    /// The virtio device type.
+    pub device_type: u32,
+    /// The path of the backend (e.g. a disk image, a socket or a shared directory), if the
+    /// device has one.
+    pub backend_path: Option<PathBuf>,
+    /// The features the device is allowed to offer, or `None` to offer all the features
+    /// supported by the device.
+    pub features: Option<u64>,
+    /// The maximum sizes of the queues, in queue order; the device defaults are used for the
+    /// queues without an entry.
+    pub queue_sizes: Vec<u16>,
+    /// Device specific parameters.
+    pub params: BTreeMap<String, String>,
+}
+
+impl DeviceDescription {
+    /// Creates a new `DeviceDescription` for a device which uses the default configuration.
+    ///
+    /// # Arguments
+    /// * `device_type` - The virtio device type.
+    pub fn new(device_type: u32) -> Self {
+        DeviceDescription {
+            device_type,
+            ..Default::default()
+        }
+    }
+
+    /// Sets the path of the backend.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the backend.
+    pub fn with_backend_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.backend_path = Some(path.into());
+        self
+    }
+
+    /// Restricts the features the device is allowed to offer.
+    ///
+    /// # Arguments
+    /// * `features` - The allowed features.
+    pub fn with_features(mut self, features: u64) -> Self {
+        self.features = Some(features);
+        self
+    }
+
+    /// Sets the maximum sizes of the queues.
+    ///
+    /// # Arguments
+    /// * `queue_sizes` - The maximum queue sizes, in queue order.
+    pub fn with_queue_sizes(mut self, queue_sizes: Vec<u16>) -> Self {
+        self.queue_sizes = queue_sizes;
+        self
+    }
+
+    /// Sets a device specific parameter.
+    ///
+    /// # Arguments
+    /// * `key` - The name of the parameter.
+    /// * `value` - The value of the parameter.
+    pub fn with_param<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.params.insert(key.into(), value.into());
+        self
+    }
+
+    /// Returns the value of a device specific parameter.
+    ///
+    /// # Arguments
+    /// * `key` - The name of the parameter.
+    pub fn param(&self, key: &str) -> Option<&str> {
+        self.params.get(key).map(String::as_str)
+    }
+
+    /// Returns the maximum size of a queue, if it's set by the description.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the queue.
+    pub fn queue_size(&self, index: usize) -> Option<u16> {
+        self.queue_sizes.get(index).copied()
+    }
+
+    /// Restricts the features offered by a device to the allowed ones. This is meant to be
+    /// called by constructors once the device is created.
+    ///
+    /// # Arguments
+    /// * `cfg` - The configuration of the device.
+    pub fn restrict_features<M: GuestAddressSpace>(&self, cfg: &mut VirtioConfig<M>) {
+        if let Some(features) = self.features {
+            cfg.device_features &= features;
+        }
+    }
+}
+
+// The constructor of a device type.
+type Constructor<T, E> = Box<dyn Fn(&DeviceDescription) -> result::Result<T, E> + Send + Sync>;
+
+/// A registry of device constructors, keyed by device type.
+///
+/// # Example
+///
+/// ```rust
+/// # use virtio_device::registry::{DeviceDescription, DeviceRegistry};
+/// #[derive(Debug, PartialEq)]
+/// struct Device {
+///     device_type: u32,
+///     path: String,
+/// }
+///
+/// let mut registry = DeviceRegistry::<Device, String>::new();
+/// registry
+///     .register(2, |desc| {
+///         let path = desc.backend_path.as_ref().ok_or("missing disk image")?;
+///         Ok(Device {
+///             device_type: 2,
+///             path: path.display().to_string(),
+///         })
+///     })
+///     .unwrap();
+///
+/// let desc = DeviceDescription::new(2).with_backend_path("/tmp/disk.img");
+/// assert_eq!(registry.create(&desc).unwrap().path, "/tmp/disk.img");
+/// assert!(registry.create(&DeviceDescription::new(2)).is_err());
+/// ```
+pub struct DeviceRegistry<T, E> {
+    constructors: BTreeMap<u32, Constructor<T, E>>,
+}
+
+impl<T, E> Default for DeviceRegistry<T, E> {
+    fn default() -> Self {
+        DeviceRegistry {
+            constructors: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T, E> fmt::Debug for DeviceRegistry<T, E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DeviceRegistry")
+            .field("device_types", &self.constructors.keys())
+            .finish()
+    }
+}
+
+impl<T, E> DeviceRegistry<T, E> {
+    /// Creates a new empty `DeviceRegistry`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the constructor of a device type.
+    ///
+    /// # Arguments
+    /// * `device_type` - The virtio device type.
+    /// * `constructor` - The constructor, which creates a device from its description.
+    pub fn register<F>(&mut self, device_type: u32, constructor: F) -> Result<(), E>
+    where
+        F: Fn(&DeviceDescription) -> result::Result<T, E> + Send + Sync + 'static,
+    {
+        if self.constructors.contains_key(&device_type) {
+            return Err(Error::DuplicateDeviceType(device_type));
+        }
+        self.constructors.insert(device_type, Box::new(constructor));
+        Ok(())
+    }
+
+    /// Returns whether a constructor is registered for a device type.
+    ///
+    /// # Arguments
+    /// * `device_type` - The virtio device type.
+    pub fn is_registered(&self, device_type: u32) -> bool {
+        self.constructors.contains_key(&device_type)
+    }
+
+    /// Returns the registered device types, in ascending order.
+    pub fn device_types(&self) -> Vec<u32> {
+        self.constructors.keys().copied().collect()
+    }
+
+    /// Creates the device which corresponds to a description.
+    ///
+    /// # Arguments
+    /// * `desc` - The description of the device.
+    pub fn create(&self, desc: &DeviceDescription) -> Result<T, E> {
+        let constructor = self
+            .constructors
+            .get(&desc.device_type)
+            .ok_or(Error::UnknownDeviceType(desc.device_type))?;
+        constructor(desc).map_err(|e| Error::Constructor(desc.device_type, e))
+    }
+
+    /// Creates the devices which correspond to a list of descriptions, in order. No devices are
+    /// returned if any of them can't be created.
+    ///
+    /// # Arguments
+    /// * `descs` - The descriptions of the devices.
+    pub fn create_all(&self, descs: &[DeviceDescription]) -> Result<Vec<T>, E> {
+        descs.iter().map(|desc| self.create(desc)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use vm_memory::{GuestAddress, GuestMemoryMmap};
+
+    use virtio_queue::Queue;
+
+    type Mem = Arc<GuestMemoryMmap>;
+
+    // A device which records the parts of the description it was created from.
+    #[derive(Debug)]
+    struct TestDevice {
+        cfg: VirtioConfig<Mem>,
+        tag: String,
+    }
+
+    fn registry() -> DeviceRegistry<TestDevice, String> {
+        let mem: Mem =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap());
+        let mut registry = DeviceRegistry::new();
+        registry
+            .register(9, move |desc| {
+                let tag = desc.param("tag").ok_or("missing tag")?;
+                let queues = vec![Queue::new(mem.clone(), desc.queue_size(0).unwrap_or(128))];
+                let mut cfg = VirtioConfig::new(0b1111, queues, Vec::new());
+                desc.restrict_features(&mut cfg);
+                Ok(TestDevice {
+                    cfg,
+                    tag: tag.to_owned(),
+                })
+            })
+            .unwrap();
+        registry
+    }
+
+    #[test]
+    fn test_register() {
+        let mut registry = registry();
+        assert!(registry.is_registered(9));
+        assert!(!registry.is_registered(2));
+
+        registry
+            .register(2, |_| Err("unsupported".to_owned()))
+            .unwrap();
+        assert_eq!(registry.device_types(), [2, 9]);
+        assert!(matches!(
+            registry.register(9, |_| Err(String::new())),
+            Err(Error::DuplicateDeviceType(9))
+        ));
+        assert_eq!(
+            format!("{:?}", registry),
+            "DeviceRegistry { device_types: [2, 9] }"
+        );
+    }
+
+    #[test]
+    fn test_create() {
+        let registry = registry();
+
+        let desc = DeviceDescription::new(9)
+            .with_features(0b0110)
+            .with_queue_sizes(vec![64])
+            .with_param("tag", "share");
+        let device = registry.create(&desc).unwrap();
+        assert_eq!(device.tag, "share");
+        assert_eq!(device.cfg.device_features, 0b0110);
+        assert_eq!(device.cfg.queues[0].max_size(), 64);
+
+        // The device defaults are used when the description doesn't override them.
+        let desc = DeviceDescription::new(9).with_param("tag", "other");
+        let device = registry.create(&desc).unwrap();
+        assert_eq!(device.cfg.device_features, 0b1111);
+        assert_eq!(device.cfg.queues[0].max_size(), 128);
+
+        let err = registry.create(&DeviceDescription::new(9)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "failed to create device of type 9: missing tag"
+        );
+        let err = registry.create(&DeviceDescription::new(3)).unwrap_err();
+        assert!(matches!(err, Error::UnknownDeviceType(3)));
+    }
+
+    #[test]
+    fn test_create_all() {
+        let registry = registry();
+        let descs = [
+            DeviceDescription::new(9).with_param("tag", "a"),
+            DeviceDescription::new(9).with_param("tag", "b"),
+        ];
+        let devices = registry.create_all(&descs).unwrap();
+        let tags: Vec<_> = devices.iter().map(|d| d.tag.as_str()).collect();
+        assert_eq!(tags, ["a", "b"]);
+
+        let descs = [
+            DeviceDescription::new(9).with_param("tag", "a"),
+            DeviceDescription::new(5),
+        ];
+        assert!(matches!(
+            registry.create_all(&descs),
+            Err(Error::UnknownDeviceType(5))
+        ));
+    }
+}
@@ -0,0 +1,449 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! In-kernel vhost integration.
+//!
+//! This module provides the [`VhostKernel`](struct.VhostKernel.html) abstraction, which wraps
+//! a `/dev/vhost-*` file descriptor (e.g. `/dev/vhost-net` or `/dev/vhost-vsock`), and lets a
+//! device offload the processing of its queues to the kernel. Just like with vhost-user, the
+//! device is expected to:
+//!
+//! - expose (a subset of) the features supported by the kernel (see
+//!   [`VhostKernel::features`](struct.VhostKernel.html#method.features));
+//! - send the negotiated features, the guest memory regions and the queue configuration to the
+//!   kernel when it's activated, with
+//!   [`VhostKernel::start`](struct.VhostKernel.html#method.start), followed by the device
+//!   specific setup (e.g. [`VhostKernel::set_backend`](struct.VhostKernel.html#method.set_backend)
+//!   for vhost-net).
+//!
+//! The kernel accesses the guest memory through the mappings of the current process, so the
+//! regions don't have to be backed by files.
+
+use std::convert::TryFrom;
+use std::fmt::{self, Display};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::raw::c_int;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::result;
+
+use vm_memory::{
+    Address, GuestAddressSpace, GuestMemory, GuestMemoryError, GuestMemoryRegion,
+    MemoryRegionAddress,
+};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ref};
+
+use virtio_queue::Queue;
+
+use crate::vring::{VringAddr, VringState};
+
+/// The maximum number of memory regions accepted by the kernel (the default value of the
+/// `max_mem_regions` parameter of the `vhost` module).
+pub const MAX_MEMORY_REGIONS: usize = 64;
+
+// The ioctls are declared in a private module, since the generated functions are public
+// (see `include/uapi/linux/vhost.h`).
+mod ioctls {
+    use std::os::raw::c_uint;
+
+    use super::{VhostVringFile, VringAddr, VringState};
+
+    const VHOST: c_uint = 0xAF;
+
+    vmm_sys_util::ioctl_ior_nr!(VHOST_GET_FEATURES, VHOST, 0x00, u64);
+    vmm_sys_util::ioctl_iow_nr!(VHOST_SET_FEATURES, VHOST, 0x00, u64);
+    vmm_sys_util::ioctl_io_nr!(VHOST_SET_OWNER, VHOST, 0x01);
+    // The size of `struct vhost_memory` doesn't include the flexible array of regions.
+    vmm_sys_util::ioctl_iow_nr!(VHOST_SET_MEM_TABLE, VHOST, 0x03, u64);
+    vmm_sys_util::ioctl_iow_nr!(VHOST_SET_VRING_NUM, VHOST, 0x10, VringState);
+    vmm_sys_util::ioctl_iow_nr!(VHOST_SET_VRING_ADDR, VHOST, 0x11, VringAddr);
+    vmm_sys_util::ioctl_iow_nr!(VHOST_SET_VRING_BASE, VHOST, 0x12, VringState);
+    vmm_sys_util::ioctl_iowr_nr!(VHOST_GET_VRING_BASE, VHOST, 0x12, VringState);
+    vmm_sys_util::ioctl_iow_nr!(VHOST_SET_VRING_KICK, VHOST, 0x20, VhostVringFile);
+    vmm_sys_util::ioctl_iow_nr!(VHOST_SET_VRING_CALL, VHOST, 0x21, VhostVringFile);
+    vmm_sys_util::ioctl_iow_nr!(VHOST_NET_SET_BACKEND, VHOST, 0x30, VhostVringFile);
+}
+use ioctls::*;
+
+/// In-kernel vhost errors.
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid guest memory access.
+    GuestMemory(GuestMemoryError),
+    /// The number of kick or call `EventFd`s doesn't match the number of queues.
+    InvalidEventFds,
+    /// A vhost ioctl failed.
+    Ioctl(io::Error),
+    /// Failed to open the vhost device.
+    Open(io::Error),
+    /// The guest memory has too many regions.
+    TooManyMemoryRegions(usize),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            GuestMemory(ref err) => write!(f, "error accessing guest memory: {}", err),
+            InvalidEventFds => write!(f, "the eventfds don't match the queues"),
+            Ioctl(ref err) => write!(f, "vhost ioctl failed: {}", err),
+            Open(ref err) => write!(f, "failed to open the vhost device: {}", err),
+            TooManyMemoryRegions(count) => write!(f, "too many guest memory regions: {}", count),
+        }
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+// The `struct vhost_vring_file` argument, which passes a file descriptor for a queue.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+struct VhostVringFile {
+    index: u32,
+    fd: c_int,
+}
+
+// A `struct vhost_memory_region`.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+struct VhostMemoryRegion {
+    guest_phys_addr: u64,
+    memory_size: u64,
+    userspace_addr: u64,
+    flags_padding: u64,
+}
+
+// The `struct vhost_memory` argument, with room for the maximum number of regions. The kernel
+// only reads the first `nregions` regions.
+#[repr(C)]
+struct VhostMemory {
+    nregions: u32,
+    padding: u32,
+    regions: [VhostMemoryRegion; MAX_MEMORY_REGIONS],
+}
+
+impl VhostMemory {
+    fn new<G: GuestMemory>(mem: &G) -> Result<Self> {
+        let count = mem.num_regions();
+        if count > MAX_MEMORY_REGIONS {
+            return Err(Error::TooManyMemoryRegions(count));
+        }
+
+        let mut table = VhostMemory {
+            // The number of regions was checked above, so it fits in an `u32`.
+            nregions: count as u32,
+            padding: 0,
+            regions: [VhostMemoryRegion::default(); MAX_MEMORY_REGIONS],
+        };
+        mem.with_regions_mut(|i, region| {
+            let host_addr = region
+                .get_host_address(MemoryRegionAddress(0))
+                .map_err(Error::GuestMemory)?;
+            table.regions[i] = VhostMemoryRegion {
+                guest_phys_addr: region.start_addr().raw_value(),
+                memory_size: region.len(),
+                userspace_addr: host_addr as u64,
+                flags_padding: 0,
+            };
+            Ok(())
+        })?;
+        Ok(table)
+    }
+}
+
+/// A handle to an in-kernel vhost device.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # use std::sync::Arc;
+/// # use virtio_device::vhost_kernel::VhostKernel;
+/// # use virtio_queue::Queue;
+/// # use vm_memory::{GuestAddress, GuestMemoryMmap};
+/// # use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+/// # let tap = std::fs::File::open("/dev/null").unwrap();
+/// let mem = Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+/// let mut vhost = VhostKernel::open("/dev/vhost-net").unwrap();
+///
+/// // Once the driver configured the queues and acknowledged the features.
+/// let queues = vec![Queue::new(mem.clone(), 256), Queue::new(mem.clone(), 256)];
+/// let kick_evts = vec![EventFd::new(EFD_NONBLOCK).unwrap(), EventFd::new(EFD_NONBLOCK).unwrap()];
+/// let call_evts = vec![EventFd::new(EFD_NONBLOCK).unwrap(), EventFd::new(EFD_NONBLOCK).unwrap()];
+/// vhost
+///     .start(&*mem, vhost.features(), &queues, &kick_evts, &call_evts)
+///     .unwrap();
+/// // The packets of both queues are exchanged with the TAP interface.
+/// vhost.set_backend(0, Some(&tap)).unwrap();
+/// vhost.set_backend(1, Some(&tap)).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct VhostKernel {
+    file: File,
+    // The virtio features supported by the kernel.
+    features: u64,
+}
+
+impl VhostKernel {
+    /// Creates a new `VhostKernel`, which claims the vhost device for the current process.
+    ///
+    /// # Arguments
+    /// * `file` - An open `/dev/vhost-*` file.
+    pub fn new(file: File) -> Result<Self> {
+        let mut vhost = VhostKernel { file, features: 0 };
+        // Safe because the ioctl doesn't take an argument, and we check the return value.
+        vhost.check(unsafe { ioctl(&vhost.file, VHOST_SET_OWNER()) })?;
+
+        let mut features = 0u64;
+        // Safe because the kernel only writes an `u64` to `features`, and we check the return
+        // value.
+        vhost.check(unsafe {
+            ioctl_with_mut_ref(&vhost.file, VHOST_GET_FEATURES(), &mut features)
+        })?;
+        vhost.features = features;
+        Ok(vhost)
+    }
+
+    /// Opens a vhost device, and creates a new `VhostKernel`.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the vhost device (e.g. `/dev/vhost-net`).
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_CLOEXEC | libc::O_NONBLOCK)
+            .open(path)
+            .map_err(Error::Open)?;
+        Self::new(file)
+    }
+
+    /// Returns the virtio features supported by the kernel.
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    // Turns the return value of an ioctl into a `Result`.
+    fn check(&self, ret: c_int) -> Result<()> {
+        if ret < 0 {
+            return Err(Error::Ioctl(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// Sets the virtio features negotiated with the driver.
+    ///
+    /// # Arguments
+    /// * `features` - The negotiated virtio features.
+    pub fn set_features(&mut self, features: u64) -> Result<()> {
+        // Safe because the kernel only reads an `u64` from `features`, and we check the return
+        // value.
+        self.check(unsafe { ioctl_with_ref(&self.file, VHOST_SET_FEATURES(), &features) })
+    }
+
+    /// Sends the guest memory regions to the kernel.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    pub fn set_mem_table<G: GuestMemory>(&mut self, mem: &G) -> Result<()> {
+        let table = VhostMemory::new(mem)?;
+        // Safe because the kernel only reads the header and the first `nregions` regions of
+        // the table, and we check the return value.
+        self.check(unsafe { ioctl_with_ref(&self.file, VHOST_SET_MEM_TABLE(), &table) })
+    }
+
+    // Runs an ioctl which configures a queue with a single value.
+    fn set_vring_state(&mut self, req: std::os::raw::c_ulong, state: &VringState) -> Result<()> {
+        // Safe because the kernel only reads a `VringState` from `state`, and we check the
+        // return value.
+        self.check(unsafe { ioctl_with_ref(&self.file, req, state) })
+    }
+
+    // Runs an ioctl which passes a file descriptor for a queue, or -1 to remove it.
+    fn set_vring_file(
+        &mut self,
+        req: std::os::raw::c_ulong,
+        index: u16,
+        fd: Option<RawFd>,
+    ) -> Result<()> {
+        let file = VhostVringFile {
+            index: u32::from(index),
+            fd: fd.unwrap_or(-1),
+        };
+        // Safe because the kernel only reads a `VhostVringFile` from `file`, and we check the
+        // return value.
+        self.check(unsafe { ioctl_with_ref(&self.file, req, &file) })
+    }
+
+    /// Sends the configuration of a queue to the kernel: the size, the addresses of the
+    /// descriptor table and the rings, the next available ring index, and the kick and call
+    /// file descriptors.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory, which is used for translating the queue addresses.
+    /// * `index` - The index of the queue.
+    /// * `queue` - The queue, as configured by the driver.
+    /// * `kick_evt` - The `EventFd` which notifies the kernel about available buffers.
+    /// * `call_evt` - The `EventFd` which the kernel uses for signaling used buffers.
+    pub fn set_vring<G: GuestMemory, M: GuestAddressSpace>(
+        &mut self,
+        mem: &G,
+        index: u16,
+        queue: &Queue<M>,
+        kick_evt: &EventFd,
+        call_evt: &EventFd,
+    ) -> Result<()> {
+        let state = queue.state();
+        let addr = VringAddr::try_from((mem, index, &state)).map_err(Error::GuestMemory)?;
+
+        self.set_vring_state(VHOST_SET_VRING_NUM(), &VringState::size(index, &state))?;
+        // Safe because the kernel only reads a `VringAddr` from `addr`, and we check the
+        // return value.
+        self.check(unsafe { ioctl_with_ref(&self.file, VHOST_SET_VRING_ADDR(), &addr) })?;
+        self.set_vring_state(VHOST_SET_VRING_BASE(), &VringState::from((index, &state)))?;
+        self.set_vring_file(VHOST_SET_VRING_KICK(), index, Some(kick_evt.as_raw_fd()))?;
+        self.set_vring_file(VHOST_SET_VRING_CALL(), index, Some(call_evt.as_raw_fd()))
+    }
+
+    /// Returns the next available ring index of a queue. The kernel only stops processing the
+    /// queue once its device specific backend is removed.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the queue.
+    pub fn get_vring_base(&mut self, index: u16) -> Result<u16> {
+        let mut state = VringState {
+            index: u32::from(index),
+            num: 0,
+        };
+        // Safe because the kernel only reads and writes a `VringState`, and we check the
+        // return value.
+        self.check(unsafe { ioctl_with_mut_ref(&self.file, VHOST_GET_VRING_BASE(), &mut state) })?;
+        Ok(state.base())
+    }
+
+    /// Sends the negotiated features, the guest memory regions and the configuration of all
+    /// the queues to the kernel. This is meant to be called when the device is activated.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    /// * `features` - The virtio features negotiated with the driver.
+    /// * `queues` - The queues of the device.
+    /// * `kick_evts` - The kick `EventFd` of each queue.
+    /// * `call_evts` - The call `EventFd` of each queue.
+    pub fn start<G: GuestMemory, M: GuestAddressSpace>(
+        &mut self,
+        mem: &G,
+        features: u64,
+        queues: &[Queue<M>],
+        kick_evts: &[EventFd],
+        call_evts: &[EventFd],
+    ) -> Result<()> {
+        if kick_evts.len() != queues.len() || call_evts.len() != queues.len() {
+            return Err(Error::InvalidEventFds);
+        }
+        self.set_features(features)?;
+        self.set_mem_table(mem)?;
+
+        for (i, queue) in queues.iter().enumerate() {
+            // The number of queues always fits in an `u16`.
+            self.set_vring(mem, i as u16, queue, &kick_evts[i], &call_evts[i])?;
+        }
+        Ok(())
+    }
+
+    /// Attaches the file descriptor which the kernel exchanges the packets of a queue with
+    /// (e.g. a TAP interface), or detaches it, which stops the queue. This is specific to
+    /// `/dev/vhost-net`.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the queue.
+    /// * `backend` - The backend file, or `None` to detach the current one.
+    pub fn set_backend<F: AsRawFd>(&mut self, index: u16, backend: Option<&F>) -> Result<()> {
+        self.set_vring_file(
+            VHOST_NET_SET_BACKEND(),
+            index,
+            backend.map(AsRawFd::as_raw_fd),
+        )
+    }
+}
+
+impl AsRawFd for VhostKernel {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::mem::size_of;
+
+    use vm_memory::{GuestAddress, GuestMemoryMmap};
+
+    #[test]
+    fn test_ioctl_numbers() {
+        // The values from the kernel headers.
+        assert_eq!(VHOST_GET_FEATURES(), 0x8008_af00);
+        assert_eq!(VHOST_SET_FEATURES(), 0x4008_af00);
+        assert_eq!(VHOST_SET_OWNER(), 0xaf01);
+        assert_eq!(VHOST_SET_MEM_TABLE(), 0x4008_af03);
+        assert_eq!(VHOST_SET_VRING_NUM(), 0x4008_af10);
+        assert_eq!(VHOST_SET_VRING_ADDR(), 0x4028_af11);
+        assert_eq!(VHOST_SET_VRING_BASE(), 0x4008_af12);
+        assert_eq!(VHOST_GET_VRING_BASE(), 0xc008_af12);
+        assert_eq!(VHOST_SET_VRING_KICK(), 0x4008_af20);
+        assert_eq!(VHOST_SET_VRING_CALL(), 0x4008_af21);
+        assert_eq!(VHOST_NET_SET_BACKEND(), 0x4008_af30);
+        assert_eq!(size_of::<VhostMemoryRegion>(), 32);
+    }
+
+    #[test]
+    fn test_memory_table() {
+        let mem = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x1000),
+            (GuestAddress(0x10_0000), 0x2000),
+        ])
+        .unwrap();
+        let table = VhostMemory::new(&mem).unwrap();
+        assert_eq!(table.nregions, 2);
+        assert_eq!(
+            table.regions[1],
+            VhostMemoryRegion {
+                guest_phys_addr: 0x10_0000,
+                memory_size: 0x2000,
+                userspace_addr: mem.get_host_address(GuestAddress(0x10_0000)).unwrap() as u64,
+                flags_padding: 0,
+            }
+        );
+        assert_eq!(table.regions[2], VhostMemoryRegion::default());
+
+        let ranges: Vec<_> = (0..=MAX_MEMORY_REGIONS as u64)
+            .map(|i| (GuestAddress(i * 0x1000), 0x1000))
+            .collect();
+        let mem = GuestMemoryMmap::from_ranges(&ranges).unwrap();
+        assert!(matches!(
+            VhostMemory::new(&mem),
+            Err(Error::TooManyMemoryRegions(65))
+        ));
+    }
+
+    #[test]
+    fn test_errors() {
+        // The vhost ioctls are rejected by other files.
+        let file = File::open("/dev/null").unwrap();
+        match VhostKernel::new(file) {
+            Err(Error::Ioctl(e)) => assert_eq!(e.raw_os_error(), Some(libc::ENOTTY)),
+            _ => panic!("unexpected result"),
+        }
+        assert!(matches!(
+            VhostKernel::open("/nonexistent/vhost-net"),
+            Err(Error::Open(_))
+        ));
+    }
+}
@@ -0,0 +1,2148 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! The frontend side of the vhost-user protocol.
+//!
+//! This module provides the [`VhostUserFrontend`](struct.VhostUserFrontend.html) abstraction,
+//! which lets a device delegate its datapath to an external backend process (such as SPDK,
+//! DPDK or virtiofsd) over a Unix socket. The frontend negotiates the virtio and protocol
+//! features with the backend when it's created, and the device then:
+//!
+//! - exposes the features offered by the backend (see
+//!   [`VhostUserFrontend::features`](struct.VhostUserFrontend.html#method.features)), possibly
+//!   along with the configuration space fetched from the backend;
+//! - sends the negotiated features, the guest memory regions and the queue configuration to the
+//!   backend when it's activated, with
+//!   [`VhostUserFrontend::start`](struct.VhostUserFrontend.html#method.start);
+//! - stops the queues of the backend when it's reset, with
+//!   [`VhostUserFrontend::stop`](struct.VhostUserFrontend.html#method.stop).
+//!
+//! The backend accesses the guest memory directly, so all the regions must be backed by files
+//! (i.e. created with `GuestMemoryMmap::from_ranges_with_files`). The queues are kicked and
+//! signaled through `EventFd`s owned by the device, whose file descriptors are passed to the
+//! backend as well.
+//!
+//! When the backend closes the connection (i.e. because it crashed or it was restarted, see
+//! [`VhostUserFrontend::is_disconnected`](struct.VhostUserFrontend.html#method.is_disconnected)),
+//! the device keeps its state, and the frontend can
+//! [`reconnect`](struct.VhostUserFrontend.html#method.reconnect) to the new backend, and
+//! [`resume`](struct.VhostUserFrontend.html#method.resume) the queues, so the guest doesn't have
+//! to be rebooted.
+//!
+//! The devices take part in live migration as well: the backend logs the guest memory it writes
+//! in a [`DirtyLog`](struct.DirtyLog.html) shared with the frontend between
+//! [`start_logging`](struct.VhostUserFrontend.html#method.start_logging) and
+//! [`stop_logging`](struct.VhostUserFrontend.html#method.stop_logging), and on the destination
+//! the backend resolves its own page faults during postcopy migration, between
+//! [`postcopy_advise`](struct.VhostUserFrontend.html#method.postcopy_advise) and
+//! [`postcopy_end`](struct.VhostUserFrontend.html#method.postcopy_end).
+//!
+//! Devices which handle requests from the backend (i.e. the DAX window mappings of vhost-user-fs
+//! devices) negotiate the slave channel as well, and receive the requests through a
+//! [`SlaveChannel`](struct.SlaveChannel.html).
+//!
+//! The message layouts are public, so they can be used for implementing (or testing) the
+//! backend side of the protocol.
+
+use std::convert::TryFrom;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::result;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use libc::iovec;
+use vm_memory::mmap::Error as MmapError;
+use vm_memory::{
+    Address, ByteValued, FileOffset, GuestAddress, GuestAddressSpace, GuestMemory,
+    GuestMemoryError, GuestMemoryRegion, MemoryRegionAddress, MmapRegion,
+};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::sock_ctrl_msg::ScmSocket;
+
+use virtio_queue::{DirtyTracker, Queue};
+
+pub use crate::vring::{VringAddr, VringState, VHOST_VRING_F_LOG};
+
+// The vhost-user requests (from the `VhostUserRequest` enumeration of the specification).
+/// Returns the virtio features supported by the backend.
+pub const VHOST_USER_GET_FEATURES: u32 = 1;
+/// Sets the virtio features negotiated with the driver.
+pub const VHOST_USER_SET_FEATURES: u32 = 2;
+/// Claims the backend for the frontend which sent the request.
+pub const VHOST_USER_SET_OWNER: u32 = 3;
+/// Sets the guest memory regions, along with the file descriptors backing them.
+pub const VHOST_USER_SET_MEM_TABLE: u32 = 5;
+/// Sets the dirty page log, along with the file descriptor backing it.
+pub const VHOST_USER_SET_LOG_BASE: u32 = 6;
+/// Sets the file descriptor the backend uses for signaling updates of the dirty page log.
+pub const VHOST_USER_SET_LOG_FD: u32 = 7;
+/// Sets the size of a queue.
+pub const VHOST_USER_SET_VRING_NUM: u32 = 8;
+/// Sets the addresses of the descriptor table and the rings of a queue.
+pub const VHOST_USER_SET_VRING_ADDR: u32 = 9;
+/// Sets the next available ring index of a queue.
+pub const VHOST_USER_SET_VRING_BASE: u32 = 10;
+/// Stops a queue, and returns its next available ring index.
+pub const VHOST_USER_GET_VRING_BASE: u32 = 11;
+/// Sets the file descriptor used for kicking a queue.
+pub const VHOST_USER_SET_VRING_KICK: u32 = 12;
+/// Sets the file descriptor used for signaling the used buffers of a queue.
+pub const VHOST_USER_SET_VRING_CALL: u32 = 13;
+/// Returns the protocol features supported by the backend.
+pub const VHOST_USER_GET_PROTOCOL_FEATURES: u32 = 15;
+/// Sets the protocol features used by the frontend.
+pub const VHOST_USER_SET_PROTOCOL_FEATURES: u32 = 16;
+/// Returns the maximum number of queues supported by the backend.
+pub const VHOST_USER_GET_QUEUE_NUM: u32 = 17;
+/// Enables or disables a queue.
+pub const VHOST_USER_SET_VRING_ENABLE: u32 = 18;
+/// Sets the socket the backend uses for sending requests to the frontend.
+pub const VHOST_USER_SET_SLAVE_REQ_FD: u32 = 21;
+/// Returns the contents of the configuration space.
+pub const VHOST_USER_GET_CONFIG: u32 = 24;
+/// Writes to the configuration space.
+pub const VHOST_USER_SET_CONFIG: u32 = 25;
+/// Makes the backend open a userfaultfd for postcopy migration, and return it.
+pub const VHOST_USER_POSTCOPY_ADVISE: u32 = 28;
+/// Makes the backend register the guest memory with its userfaultfd.
+pub const VHOST_USER_POSTCOPY_LISTEN: u32 = 29;
+/// Makes the backend stop handling page faults, once postcopy migration completed.
+pub const VHOST_USER_POSTCOPY_END: u32 = 30;
+/// Returns the shared memory area where the backend records the in-flight descriptors.
+pub const VHOST_USER_GET_INFLIGHT_FD: u32 = 31;
+/// Sets the shared memory area where the backend records the in-flight descriptors.
+pub const VHOST_USER_SET_INFLIGHT_FD: u32 = 32;
+/// Returns the maximum number of memory regions supported by the backend.
+pub const VHOST_USER_GET_MAX_MEM_SLOTS: u32 = 36;
+/// Adds a guest memory region, along with the file descriptor backing it.
+pub const VHOST_USER_ADD_MEM_REG: u32 = 37;
+/// Removes a guest memory region.
+pub const VHOST_USER_REM_MEM_REG: u32 = 38;
+
+// Message header flags.
+/// The version of the protocol, which is set in the flags of all the messages.
+pub const VHOST_USER_VERSION: u32 = 0x1;
+/// Set in the flags of the replies.
+pub const VHOST_USER_REPLY: u32 = 0x4;
+/// Set in the flags of the requests which have to be acknowledged, when
+/// `VHOST_USER_PROTOCOL_F_REPLY_ACK` is negotiated.
+pub const VHOST_USER_NEED_REPLY: u32 = 0x8;
+
+/// The virtio feature bit which makes the backend log its guest memory writes.
+pub const VHOST_F_LOG_ALL: u64 = 26;
+/// The virtio feature bit which signals support for the vhost-user protocol features.
+pub const VHOST_USER_F_PROTOCOL_FEATURES: u64 = 30;
+
+/// The protocol feature bit for multiple queues.
+pub const VHOST_USER_PROTOCOL_F_MQ: u64 = 0;
+/// The protocol feature bit for sharing the dirty page log through a file descriptor.
+pub const VHOST_USER_PROTOCOL_F_LOG_SHMFD: u64 = 1;
+/// The protocol feature bit for acknowledging requests that don't have a reply.
+pub const VHOST_USER_PROTOCOL_F_REPLY_ACK: u64 = 3;
+/// The protocol feature bit for postcopy migration, where the backend handles its own page
+/// faults.
+pub const VHOST_USER_PROTOCOL_F_PAGEFAULT: u64 = 8;
+/// The protocol feature bit for accessing the configuration space.
+pub const VHOST_USER_PROTOCOL_F_CONFIG: u64 = 9;
+/// The protocol feature bit for the channel the backend uses for sending requests to the
+/// frontend.
+pub const VHOST_USER_PROTOCOL_F_SLAVE_REQ: u64 = 10;
+/// The protocol feature bit for passing file descriptors along with the backend requests.
+pub const VHOST_USER_PROTOCOL_F_SLAVE_SEND_FD: u64 = 11;
+/// The protocol feature bit for the shared memory area where the backend records the in-flight
+/// descriptors, so they can be resubmitted after the backend restarts.
+pub const VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD: u64 = 12;
+/// The protocol feature bit for adding and removing guest memory regions one by one.
+pub const VHOST_USER_PROTOCOL_F_CONFIGURE_MEM_SLOTS: u64 = 15;
+
+/// The protocol features supported by the frontend.
+pub const SUPPORTED_PROTOCOL_FEATURES: u64 = (1 << VHOST_USER_PROTOCOL_F_MQ)
+    | (1 << VHOST_USER_PROTOCOL_F_LOG_SHMFD)
+    | (1 << VHOST_USER_PROTOCOL_F_REPLY_ACK)
+    | (1 << VHOST_USER_PROTOCOL_F_PAGEFAULT)
+    | (1 << VHOST_USER_PROTOCOL_F_CONFIG)
+    | (1 << VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD)
+    | (1 << VHOST_USER_PROTOCOL_F_CONFIGURE_MEM_SLOTS);
+/// The protocol features for the slave channel, which are only negotiated for the devices
+/// which handle backend requests (see
+/// [`VhostUserFrontend::new_with_protocol_features`](struct.VhostUserFrontend.html#method.new_with_protocol_features)).
+pub const SLAVE_PROTOCOL_FEATURES: u64 =
+    (1 << VHOST_USER_PROTOCOL_F_SLAVE_REQ) | (1 << VHOST_USER_PROTOCOL_F_SLAVE_SEND_FD);
+
+/// The maximum number of memory regions in a `VHOST_USER_SET_MEM_TABLE` request.
+pub const MAX_MEMORY_REGIONS: usize = 8;
+/// The size of the guest memory pages tracked by each bit of the dirty page log.
+pub const VHOST_LOG_PAGE: u64 = 0x1000;
+/// Set in the payload of `VHOST_USER_SET_VRING_{KICK,CALL}` when no file descriptor is sent.
+pub const VHOST_USER_VRING_NOFD_MASK: u64 = 0x100;
+// The maximum size of a reply payload accepted from the backend.
+const MAX_REPLY_SIZE: u32 = 0x1000;
+// The maximum size of a request payload accepted from the backend on the slave channel.
+const MAX_SLAVE_REQUEST_SIZE: u32 = 0x1000;
+
+/// vhost-user frontend errors.
+#[derive(Debug)]
+pub enum Error {
+    /// The backend failed to execute a request.
+    BackendFailure(u32),
+    /// Failed to create the dirty page log.
+    DirtyLog(io::Error),
+    /// Failed to write a kick `EventFd`.
+    EventFd(io::Error),
+    /// Invalid guest memory access.
+    GuestMemory(GuestMemoryError),
+    /// The backend the frontend reconnected to doesn't support all the features negotiated
+    /// with the previous one.
+    IncompatibleBackend,
+    /// The number of kick or call `EventFd`s doesn't match the number of queues.
+    InvalidEventFds,
+    /// The backend sent an invalid reply to a request.
+    InvalidReply(u32),
+    /// The backend sent an invalid request on the slave channel.
+    InvalidSlaveRequest(u32),
+    /// Failed to map the dirty page log.
+    MemoryMap(MmapError),
+    /// The backend doesn't support a required virtio feature.
+    MissingFeature(u64),
+    /// The backend doesn't support a required protocol feature.
+    MissingProtocolFeature(u64),
+    /// Failed to communicate with the backend.
+    Socket(io::Error),
+    /// The guest memory has too many regions.
+    TooManyMemoryRegions(usize),
+    /// The backend doesn't support the requested number of queues.
+    TooManyQueues(u16),
+    /// A guest memory region is not backed by a file, so it can't be shared with the backend.
+    UnsharedMemoryRegion(GuestAddress),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            BackendFailure(request) => write!(f, "the backend failed request {}", request),
+            DirtyLog(ref err) => write!(f, "failed to create the dirty page log: {}", err),
+            EventFd(ref err) => write!(f, "eventfd error: {}", err),
+            GuestMemory(ref err) => write!(f, "error accessing guest memory: {}", err),
+            IncompatibleBackend => write!(f, "the backend doesn't support the negotiated features"),
+            InvalidEventFds => write!(f, "the eventfds don't match the queues"),
+            InvalidReply(request) => write!(f, "invalid reply for request {}", request),
+            InvalidSlaveRequest(request) => write!(f, "invalid backend request {}", request),
+            MemoryMap(ref err) => write!(f, "failed to map the dirty page log: {}", err),
+            MissingFeature(feature) => {
+                write!(f, "the backend doesn't support feature {}", feature)
+            }
+            MissingProtocolFeature(feature) => {
+                write!(
+                    f,
+                    "the backend doesn't support protocol feature {}",
+                    feature
+                )
+            }
+            Socket(ref err) => write!(f, "vhost-user socket error: {}", err),
+            TooManyMemoryRegions(count) => write!(f, "too many guest memory regions: {}", count),
+            TooManyQueues(count) => write!(f, "the backend doesn't support {} queues", count),
+            UnsharedMemoryRegion(addr) => write!(
+                f,
+                "guest memory region at 0x{:x} is not backed by a file",
+                addr.raw_value()
+            ),
+        }
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// The header of the vhost-user messages.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct Header {
+    /// The request code.
+    pub request: u32,
+    /// The protocol version and the message flags.
+    pub flags: u32,
+    /// The size of the payload which follows the header.
+    pub size: u32,
+}
+
+// Safe because Header contains only plain data.
+unsafe impl ByteValued for Header {}
+
+/// A region from the payload of `VHOST_USER_SET_MEM_TABLE`, which starts with the number of
+/// regions as an `u64`, or the payload of `VHOST_USER_{ADD,REM}_MEM_REG`, which starts with 8
+/// bytes of padding.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct MemoryRegion {
+    /// The guest physical address of the region.
+    pub guest_phys_addr: u64,
+    /// The size of the region.
+    pub memory_size: u64,
+    /// The frontend virtual address of the region.
+    pub userspace_addr: u64,
+    /// The offset of the region in the file which backs it.
+    pub mmap_offset: u64,
+}
+
+// Safe because MemoryRegion contains only plain data.
+unsafe impl ByteValued for MemoryRegion {}
+
+/// The start of the `VHOST_USER_{GET,SET}_CONFIG` payloads, which is followed by the contents.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct ConfigHeader {
+    /// The offset of the accessed range in the configuration space.
+    pub offset: u32,
+    /// The size of the accessed range.
+    pub size: u32,
+    /// The access flags.
+    pub flags: u32,
+}
+
+// Safe because ConfigHeader contains only plain data.
+unsafe impl ByteValued for ConfigHeader {}
+
+/// The payload of `VHOST_USER_{GET,SET}_INFLIGHT_FD`, which describes the shared memory area
+/// where the backend records the in-flight descriptors.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct Inflight {
+    /// The size of the area.
+    pub mmap_size: u64,
+    /// The offset of the area in the file which backs it.
+    pub mmap_offset: u64,
+    /// The number of queues with an in-flight descriptors region.
+    pub num_queues: u16,
+    /// The size of the queues.
+    pub queue_size: u16,
+    /// Padding.
+    pub padding: u32,
+}
+
+// Safe because Inflight contains only plain data.
+unsafe impl ByteValued for Inflight {}
+
+/// The payload of `VHOST_USER_SET_LOG_BASE`, which describes the dirty page log.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct Log {
+    /// The size of the log.
+    pub mmap_size: u64,
+    /// The offset of the log in the file which backs it.
+    pub mmap_offset: u64,
+}
+
+// Safe because Log contains only plain data.
+unsafe impl ByteValued for Log {}
+
+/// The dirty page log, a bitmap shared with the backend, where each bit tracks whether the
+/// backend wrote to a `VHOST_LOG_PAGE` sized page of the guest memory.
+#[derive(Debug)]
+pub struct DirtyLog {
+    file: File,
+    mapping: MmapRegion,
+}
+
+impl DirtyLog {
+    /// Creates an empty log, which covers all the regions of the guest memory.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    pub fn new<G: GuestMemory>(mem: &G) -> Result<Self> {
+        let pages = mem.last_addr().raw_value() / VHOST_LOG_PAGE + 1;
+        // The log is accessed as `u64` words, which hold the bits of 64 pages each.
+        let size = (pages.div_ceil(64) * 8) as usize;
+
+        // Safe because the name is a valid C string, and the return value is checked.
+        let fd = unsafe {
+            libc::memfd_create(
+                b"vhost-user-log\0".as_ptr() as *const libc::c_char,
+                libc::MFD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::DirtyLog(io::Error::last_os_error()));
+        }
+        // Safe because the file descriptor was just created, and it's not owned by anything
+        // else.
+        let file = unsafe { File::from_raw_fd(fd) };
+        // The file is filled with zeroes, so all the pages start clean.
+        file.set_len(size as u64).map_err(Error::DirtyLog)?;
+        let mapping = MmapRegion::from_file(
+            FileOffset::new(file.try_clone().map_err(Error::DirtyLog)?, 0),
+            size,
+        )
+        .map_err(|e| Error::MemoryMap(MmapError::MmapRegion(e)))?;
+        Ok(DirtyLog { file, mapping })
+    }
+
+    /// Returns the description of the log, as sent to the backend.
+    pub fn log(&self) -> Log {
+        Log {
+            mmap_size: self.mapping.size() as u64,
+            mmap_offset: 0,
+        }
+    }
+
+    /// Returns the guest physical addresses of the pages written since the last call, and marks
+    /// them as clean.
+    pub fn take_dirty_pages(&self) -> Vec<GuestAddress> {
+        let mut pages = Vec::new();
+        for word in 0..self.mapping.size() / 8 {
+            // Safe because the mapping is page aligned and its size is a multiple of 8, so the
+            // word is aligned and in bounds. The backend only accesses the log atomically.
+            let bits = unsafe { &*(self.mapping.as_ptr().add(word * 8) as *const AtomicU64) };
+            // The log is a bitmap of bytes, so the first page is the lowest bit of the first
+            // byte.
+            let mut bits = u64::from_le(bits.swap(0, Ordering::AcqRel));
+            while bits != 0 {
+                let bit = u64::from(bits.trailing_zeros());
+                pages.push(GuestAddress((word as u64 * 64 + bit) * VHOST_LOG_PAGE));
+                bits &= bits - 1;
+            }
+        }
+        pages
+    }
+}
+
+impl DirtyTracker for DirtyLog {
+    // Marks the written pages in the log, as the backend does, so the VMM can track the writes
+    // of the devices it emulates in the same place. Pages outside the log are ignored.
+    fn mark_dirty(&self, addr: GuestAddress, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let first = addr.raw_value() / VHOST_LOG_PAGE;
+        let last = addr.raw_value().saturating_add(len as u64 - 1) / VHOST_LOG_PAGE;
+        let pages = (self.mapping.size() * 8) as u64;
+        for page in first..=last.min(pages - 1) {
+            // Safe because the mapping is page aligned, and the word is in bounds since
+            // `page` is smaller than the number of pages the log covers.
+            let bits = unsafe {
+                &*(self.mapping.as_ptr().add((page / 64) as usize * 8) as *const AtomicU64)
+            };
+            bits.fetch_or((1u64 << (page % 64)).to_le(), Ordering::AcqRel);
+        }
+    }
+}
+
+impl AsRawFd for DirtyLog {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// Returns the regions of the guest memory, as described to the backend, along with the file
+/// descriptors which back them (e.g. memfds or hugetlbfs files). The file descriptors are
+/// owned by the guest memory.
+///
+/// # Arguments
+/// * `mem` - The guest memory, whose regions have to be backed by files.
+pub fn memory_regions<G: GuestMemory>(mem: &G) -> Result<Vec<(MemoryRegion, RawFd)>> {
+    let mut regions = Vec::with_capacity(mem.num_regions());
+    mem.with_regions_mut(|_, region| {
+        let file_offset = region
+            .file_offset()
+            .ok_or_else(|| Error::UnsharedMemoryRegion(region.start_addr()))?;
+        let host_addr = region
+            .get_host_address(MemoryRegionAddress(0))
+            .map_err(Error::GuestMemory)?;
+        let memory_region = MemoryRegion {
+            guest_phys_addr: region.start_addr().raw_value(),
+            memory_size: region.len(),
+            userspace_addr: host_addr as u64,
+            mmap_offset: file_offset.start(),
+        };
+        regions.push((memory_region, file_offset.file().as_raw_fd()));
+        Ok(())
+    })?;
+    Ok(regions)
+}
+
+/// The frontend side of a vhost-user connection.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # use std::sync::Arc;
+/// # use virtio_device::vhost_user::{VhostUserFrontend, VHOST_USER_PROTOCOL_F_CONFIG};
+/// # use virtio_queue::Queue;
+/// # use vm_memory::{FileOffset, GuestAddress, GuestMemoryMmap};
+/// # use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+/// # use vmm_sys_util::tempfile::TempFile;
+/// // The guest memory has to be shared with the backend.
+/// let file = TempFile::new().unwrap().into_file();
+/// file.set_len(0x10_0000).unwrap();
+/// let mem = Arc::new(
+///     GuestMemoryMmap::from_ranges_with_files(&[(
+///         GuestAddress(0),
+///         0x10_0000,
+///         Some(FileOffset::new(file, 0)),
+///     )])
+///     .unwrap(),
+/// );
+///
+/// let mut frontend = VhostUserFrontend::connect("/tmp/vhost-user.sock").unwrap();
+/// frontend
+///     .require_protocol_feature(VHOST_USER_PROTOCOL_F_CONFIG)
+///     .unwrap();
+/// let config_space = frontend.get_config(8).unwrap();
+///
+/// // Once the driver configured the queue and acknowledged the features.
+/// let queues = vec![Queue::new(mem.clone(), 256)];
+/// let kick_evts = vec![EventFd::new(EFD_NONBLOCK).unwrap()];
+/// let call_evts = vec![EventFd::new(EFD_NONBLOCK).unwrap()];
+/// frontend
+///     .start(&*mem, frontend.features(), &queues, &kick_evts, &call_evts)
+///     .unwrap();
+/// ```
+#[derive(Debug)]
+pub struct VhostUserFrontend {
+    stream: UnixStream,
+    // The virtio features supported by the backend.
+    features: u64,
+    // The negotiated protocol features.
+    protocol_features: u64,
+    // Whether the backend logs its guest memory writes.
+    logging: bool,
+    // The guest memory regions, as mapped by the backend, once it listens for page faults.
+    postcopy_regions: Option<Vec<MemoryRegion>>,
+}
+
+impl VhostUserFrontend {
+    /// Creates a new `VhostUserFrontend`, which claims the backend and negotiates the protocol
+    /// features supported by both sides.
+    ///
+    /// # Arguments
+    /// * `stream` - The socket connected to the backend.
+    pub fn new(stream: UnixStream) -> Result<Self> {
+        Self::new_with_protocol_features(stream, 0)
+    }
+
+    /// Creates a new `VhostUserFrontend`, which negotiates the specified protocol features as
+    /// well, on top of `SUPPORTED_PROTOCOL_FEATURES`, when the backend supports them (i.e.
+    /// `SLAVE_PROTOCOL_FEATURES` for the devices which handle backend requests).
+    ///
+    /// # Arguments
+    /// * `stream` - The socket connected to the backend.
+    /// * `protocol_features` - The additional protocol features.
+    pub fn new_with_protocol_features(stream: UnixStream, protocol_features: u64) -> Result<Self> {
+        let mut frontend = VhostUserFrontend {
+            stream,
+            features: 0,
+            protocol_features: 0,
+            logging: false,
+            postcopy_regions: None,
+        };
+        frontend.send(VHOST_USER_SET_OWNER, 0, &[], &[])?;
+
+        frontend.features = frontend.get_u64(VHOST_USER_GET_FEATURES)?;
+        if frontend.features & (1 << VHOST_USER_F_PROTOCOL_FEATURES) != 0 {
+            let protocol_features = frontend.get_u64(VHOST_USER_GET_PROTOCOL_FEATURES)?
+                & (SUPPORTED_PROTOCOL_FEATURES | protocol_features);
+            // The acknowledgements can't be used before `VHOST_USER_PROTOCOL_F_REPLY_ACK` is
+            // negotiated.
+            frontend.send(
+                VHOST_USER_SET_PROTOCOL_FEATURES,
+                0,
+                protocol_features.as_slice(),
+                &[],
+            )?;
+            frontend.protocol_features = protocol_features;
+        }
+        Ok(frontend)
+    }
+
+    /// Connects to a backend, and creates a new `VhostUserFrontend`.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the socket the backend listens on.
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::new(UnixStream::connect(path).map_err(Error::Socket)?)
+    }
+
+    /// Returns whether the backend closed the connection (i.e. because it crashed or it was
+    /// restarted). This is meant to be checked when the socket (see `AsRawFd`) reports a hangup
+    /// or becomes readable outside of a request, such as when it's monitored with epoll.
+    pub fn is_disconnected(&self) -> bool {
+        let mut pollfd = libc::pollfd {
+            fd: self.stream.as_raw_fd(),
+            events: libc::POLLRDHUP,
+            revents: 0,
+        };
+        // Safe because the pollfd is valid for the duration of the call, and the return value
+        // is checked.
+        let ret = unsafe { libc::poll(&mut pollfd, 1, 0) };
+        ret > 0 && pollfd.revents & (libc::POLLRDHUP | libc::POLLHUP | libc::POLLERR) != 0
+    }
+
+    /// Replaces the connection with one to a new backend (i.e. after the previous one crashed),
+    /// and negotiates the protocol features again. The new backend has to support all the
+    /// features negotiated with the previous one, since they were already exposed to the
+    /// driver. The queues have to be started again afterwards, with
+    /// [`resume`](struct.VhostUserFrontend.html#method.resume).
+    ///
+    /// # Arguments
+    /// * `stream` - The socket connected to the new backend.
+    pub fn reconnect(&mut self, stream: UnixStream) -> Result<()> {
+        let frontend = Self::new_with_protocol_features(stream, self.protocol_features)?;
+        if frontend.features & self.features != self.features
+            || frontend.protocol_features & self.protocol_features != self.protocol_features
+        {
+            return Err(Error::IncompatibleBackend);
+        }
+        *self = frontend;
+        Ok(())
+    }
+
+    /// Returns the virtio features supported by the backend, without
+    /// `VHOST_USER_F_PROTOCOL_FEATURES` and `VHOST_F_LOG_ALL`, which are not meant for the
+    /// driver.
+    pub fn features(&self) -> u64 {
+        self.features & !(1 << VHOST_USER_F_PROTOCOL_FEATURES) & !(1 << VHOST_F_LOG_ALL)
+    }
+
+    /// Returns the negotiated protocol features.
+    pub fn protocol_features(&self) -> u64 {
+        self.protocol_features
+    }
+
+    /// Returns an error if a protocol feature was not negotiated.
+    ///
+    /// # Arguments
+    /// * `feature` - The protocol feature bit.
+    pub fn require_protocol_feature(&self, feature: u64) -> Result<()> {
+        if self.protocol_features & (1 << feature) == 0 {
+            return Err(Error::MissingProtocolFeature(feature));
+        }
+        Ok(())
+    }
+
+    /// Returns the maximum number of queues supported by the backend, which requires
+    /// `VHOST_USER_PROTOCOL_F_MQ`.
+    pub fn get_queue_num(&mut self) -> Result<u64> {
+        self.require_protocol_feature(VHOST_USER_PROTOCOL_F_MQ)?;
+        self.get_u64(VHOST_USER_GET_QUEUE_NUM)
+    }
+
+    /// Returns an error if the backend doesn't support the specified number of queues.
+    ///
+    /// # Arguments
+    /// * `num_queues` - The number of queues used by the device.
+    pub fn check_queue_num(&mut self, num_queues: u16) -> Result<()> {
+        if num_queues <= 1 {
+            return Ok(());
+        }
+        if self.get_queue_num()? < u64::from(num_queues) {
+            return Err(Error::TooManyQueues(num_queues));
+        }
+        Ok(())
+    }
+
+    // Sends a request with the specified payload and file descriptors.
+    fn send(&mut self, request: u32, flags: u32, payload: &[u8], fds: &[RawFd]) -> Result<()> {
+        let header = Header {
+            request,
+            flags: VHOST_USER_VERSION | flags,
+            // Payloads are always small, so the length fits in an `u32`.
+            size: payload.len() as u32,
+        };
+        let mut message = header.as_slice().to_vec();
+        message.extend_from_slice(payload);
+
+        if fds.is_empty() {
+            return self.stream.write_all(&message).map_err(Error::Socket);
+        }
+        let sent = self
+            .stream
+            .send_with_fds(&[&message[..]], fds)
+            .map_err(|e| Error::Socket(io::Error::from_raw_os_error(e.errno())))?;
+        // The file descriptors are attached to the first chunk, so the rest of the message
+        // can be sent separately.
+        self.stream
+            .write_all(&message[sent..])
+            .map_err(Error::Socket)
+    }
+
+    // Receives the reply to `request` and returns its payload.
+    fn recv(&mut self, request: u32) -> Result<Vec<u8>> {
+        self.recv_with_files(request).map(|(payload, _)| payload)
+    }
+
+    // Receives the reply to `request` and returns its payload, along with the files sent by
+    // the backend.
+    fn recv_with_files(&mut self, request: u32) -> Result<(Vec<u8>, Vec<File>)> {
+        let mut header = Header::default();
+        let mut fds = [-1; MAX_MEMORY_REGIONS];
+        let mut iovecs = [iovec {
+            iov_base: header.as_mut_slice().as_mut_ptr() as *mut libc::c_void,
+            iov_len: size_of::<Header>(),
+        }];
+        // Safe because the iovec points to the header, which can hold arbitrary data.
+        let (len, fd_count) = unsafe { self.stream.recv_with_fds(&mut iovecs, &mut fds) }
+            .map_err(|e| Error::Socket(io::Error::from_raw_os_error(e.errno())))?;
+        let files = fds[..fd_count]
+            .iter()
+            // Safe because the received file descriptors are owned by the frontend.
+            .map(|&fd| unsafe { File::from_raw_fd(fd) })
+            .collect();
+        if len == 0 {
+            return Err(Error::Socket(io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        self.stream
+            .read_exact(&mut header.as_mut_slice()[len..])
+            .map_err(Error::Socket)?;
+        if header.request != request
+            || header.flags & VHOST_USER_REPLY == 0
+            || header.size > MAX_REPLY_SIZE
+        {
+            return Err(Error::InvalidReply(request));
+        }
+        let mut payload = vec![0; header.size as usize];
+        self.stream
+            .read_exact(&mut payload)
+            .map_err(Error::Socket)?;
+        Ok((payload, files))
+    }
+
+    // Receives the reply to `request`, which consists of a `T` object.
+    fn recv_obj<T: ByteValued + Default>(&mut self, request: u32) -> Result<T> {
+        let payload = self.recv(request)?;
+        if payload.len() != size_of::<T>() {
+            return Err(Error::InvalidReply(request));
+        }
+        let mut obj = T::default();
+        obj.as_mut_slice().copy_from_slice(&payload);
+        Ok(obj)
+    }
+
+    // Sends a request which doesn't have a reply, and waits for the acknowledgement when
+    // `VHOST_USER_PROTOCOL_F_REPLY_ACK` was negotiated.
+    fn set(&mut self, request: u32, payload: &[u8], fds: &[RawFd]) -> Result<()> {
+        if self.protocol_features & (1 << VHOST_USER_PROTOCOL_F_REPLY_ACK) == 0 {
+            return self.send(request, 0, payload, fds);
+        }
+        self.send(request, VHOST_USER_NEED_REPLY, payload, fds)?;
+        match self.recv_obj::<u64>(request)? {
+            0 => Ok(()),
+            _ => Err(Error::BackendFailure(request)),
+        }
+    }
+
+    // Sends a request without payload, and returns the `u64` value from the reply.
+    fn get_u64(&mut self, request: u32) -> Result<u64> {
+        self.send(request, 0, &[], &[])?;
+        self.recv_obj(request)
+    }
+
+    /// Sets the virtio features negotiated with the driver. `VHOST_USER_F_PROTOCOL_FEATURES`
+    /// is acknowledged as well when the backend supports it, and `VHOST_F_LOG_ALL` while the
+    /// backend logs its guest memory writes.
+    ///
+    /// # Arguments
+    /// * `features` - The negotiated virtio features.
+    pub fn set_features(&mut self, features: u64) -> Result<()> {
+        let mut features = features | (self.features & (1 << VHOST_USER_F_PROTOCOL_FEATURES));
+        if self.logging {
+            features |= 1 << VHOST_F_LOG_ALL;
+        }
+        self.set(VHOST_USER_SET_FEATURES, features.as_slice(), &[])
+    }
+
+    /// Sends the guest memory regions, which have to be backed by files, to the backend. While
+    /// the backend listens for page faults (see
+    /// [`postcopy_listen`](struct.VhostUserFrontend.html#method.postcopy_listen)), it replies
+    /// with the addresses it mapped the regions at, which are returned by
+    /// [`postcopy_regions`](struct.VhostUserFrontend.html#method.postcopy_regions).
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    pub fn set_mem_table<G: GuestMemory>(&mut self, mem: &G) -> Result<()> {
+        let count = mem.num_regions();
+        if count > MAX_MEMORY_REGIONS {
+            return Err(Error::TooManyMemoryRegions(count));
+        }
+
+        // The payload starts with the number of regions, followed by 4 bytes of padding.
+        let mut payload = (count as u64).as_slice().to_vec();
+        let mut fds = Vec::with_capacity(count);
+        for (region, fd) in memory_regions(mem)? {
+            payload.extend_from_slice(region.as_slice());
+            fds.push(fd);
+        }
+        if self.postcopy_regions.is_none() {
+            return self.set(VHOST_USER_SET_MEM_TABLE, &payload, &fds);
+        }
+
+        // The acknowledgement only comes after the frontend confirms it received the regions
+        // mapped by the backend.
+        let reply_ack = self.protocol_features & (1 << VHOST_USER_PROTOCOL_F_REPLY_ACK) != 0;
+        let flags = if reply_ack { VHOST_USER_NEED_REPLY } else { 0 };
+        self.send(VHOST_USER_SET_MEM_TABLE, flags, &payload, &fds)?;
+        let reply = self.recv(VHOST_USER_SET_MEM_TABLE)?;
+        if reply.len() != payload.len() || reply[..8] != payload[..8] {
+            return Err(Error::InvalidReply(VHOST_USER_SET_MEM_TABLE));
+        }
+        let regions = reply[8..]
+            .chunks(size_of::<MemoryRegion>())
+            .map(|chunk| *MemoryRegion::from_slice(chunk).unwrap())
+            .collect();
+        self.postcopy_regions = Some(regions);
+
+        self.send(VHOST_USER_SET_MEM_TABLE, 0, 0u64.as_slice(), &[])?;
+        if reply_ack && self.recv_obj::<u64>(VHOST_USER_SET_MEM_TABLE)? != 0 {
+            return Err(Error::BackendFailure(VHOST_USER_SET_MEM_TABLE));
+        }
+        Ok(())
+    }
+
+    /// Returns the maximum number of guest memory regions supported by the backend, which
+    /// requires `VHOST_USER_PROTOCOL_F_CONFIGURE_MEM_SLOTS`.
+    pub fn get_max_mem_slots(&mut self) -> Result<u64> {
+        self.require_protocol_feature(VHOST_USER_PROTOCOL_F_CONFIGURE_MEM_SLOTS)?;
+        self.get_u64(VHOST_USER_GET_MAX_MEM_SLOTS)
+    }
+
+    /// Adds a guest memory region to the ones used by the backend, which requires
+    /// `VHOST_USER_PROTOCOL_F_CONFIGURE_MEM_SLOTS`.
+    ///
+    /// # Arguments
+    /// * `region` - The description of the region.
+    /// * `fd` - The file descriptor which backs the region.
+    pub fn add_mem_region(&mut self, region: &MemoryRegion, fd: RawFd) -> Result<()> {
+        self.require_protocol_feature(VHOST_USER_PROTOCOL_F_CONFIGURE_MEM_SLOTS)?;
+        let mut payload = 0u64.as_slice().to_vec();
+        payload.extend_from_slice(region.as_slice());
+        self.set(VHOST_USER_ADD_MEM_REG, &payload, &[fd])
+    }
+
+    /// Removes a guest memory region from the ones used by the backend, which requires
+    /// `VHOST_USER_PROTOCOL_F_CONFIGURE_MEM_SLOTS`.
+    ///
+    /// # Arguments
+    /// * `region` - The description of the region, as it was added.
+    pub fn remove_mem_region(&mut self, region: &MemoryRegion) -> Result<()> {
+        self.require_protocol_feature(VHOST_USER_PROTOCOL_F_CONFIGURE_MEM_SLOTS)?;
+        let mut payload = 0u64.as_slice().to_vec();
+        payload.extend_from_slice(region.as_slice());
+        self.set(VHOST_USER_REM_MEM_REG, &payload, &[])
+    }
+
+    /// Updates the guest memory regions used by the backend after memory was plugged or
+    /// unplugged (i.e. by virtio-mem). Only the regions which changed are sent when
+    /// `VHOST_USER_PROTOCOL_F_CONFIGURE_MEM_SLOTS` is negotiated, and the whole table is sent
+    /// again otherwise.
+    ///
+    /// # Arguments
+    /// * `old` - The guest memory the backend currently uses.
+    /// * `new` - The updated guest memory.
+    pub fn update_mem_table<G: GuestMemory, H: GuestMemory>(
+        &mut self,
+        old: &G,
+        new: &H,
+    ) -> Result<()> {
+        if self.protocol_features & (1 << VHOST_USER_PROTOCOL_F_CONFIGURE_MEM_SLOTS) == 0 {
+            return self.set_mem_table(new);
+        }
+        let old_regions = memory_regions(old)?;
+        let new_regions = memory_regions(new)?;
+        if new_regions.len() as u64 > self.get_max_mem_slots()? {
+            return Err(Error::TooManyMemoryRegions(new_regions.len()));
+        }
+
+        // The regions are removed first, since the new ones might overlap them.
+        for (region, _) in old_regions.iter() {
+            if !new_regions.iter().any(|(r, _)| r == region) {
+                self.remove_mem_region(region)?;
+            }
+        }
+        for (region, fd) in new_regions.iter() {
+            if !old_regions.iter().any(|(r, _)| r == region) {
+                self.add_mem_region(region, *fd)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Sends a request which configures a queue with a single value.
+    fn set_vring_state(&mut self, request: u32, index: u16, num: u32) -> Result<()> {
+        let state = VringState {
+            index: u32::from(index),
+            num,
+        };
+        self.set(request, state.as_slice(), &[])
+    }
+
+    // Sends the kick or call file descriptor of a queue.
+    fn set_vring_fd(&mut self, request: u32, index: u16, fd: Option<RawFd>) -> Result<()> {
+        let mut value = u64::from(index);
+        if fd.is_none() {
+            value |= VHOST_USER_VRING_NOFD_MASK;
+        }
+        let fds: Vec<RawFd> = fd.into_iter().collect();
+        self.set(request, value.as_slice(), &fds)
+    }
+
+    /// Sends the configuration of a queue to the backend: the size, the addresses of the
+    /// descriptor table and the rings, the next available ring index, and the kick and call
+    /// file descriptors. The queue is not enabled.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory, which is used for translating the queue addresses.
+    /// * `index` - The index of the queue.
+    /// * `queue` - The queue, as configured by the driver.
+    /// * `kick_evt` - The `EventFd` which notifies the backend about available buffers.
+    /// * `call_evt` - The `EventFd` which the backend uses for signaling used buffers.
+    pub fn set_vring<G: GuestMemory, M: GuestAddressSpace>(
+        &mut self,
+        mem: &G,
+        index: u16,
+        queue: &Queue<M>,
+        kick_evt: &EventFd,
+        call_evt: &EventFd,
+    ) -> Result<()> {
+        let base = queue.next_avail();
+        self.set_vring_with_base(mem, index, queue, base, kick_evt, call_evt)
+    }
+
+    // Sends the configuration of a queue, which continues from the `base` available ring index.
+    fn set_vring_with_base<G: GuestMemory, M: GuestAddressSpace>(
+        &mut self,
+        mem: &G,
+        index: u16,
+        queue: &Queue<M>,
+        base: u16,
+        kick_evt: &EventFd,
+        call_evt: &EventFd,
+    ) -> Result<()> {
+        let state = queue.state();
+        let size = VringState::size(index, &state);
+        self.set(VHOST_USER_SET_VRING_NUM, size.as_slice(), &[])?;
+        self.set_vring_addr(mem, index, queue)?;
+        self.set_vring_state(VHOST_USER_SET_VRING_BASE, index, u32::from(base))?;
+        self.set_vring_fd(VHOST_USER_SET_VRING_KICK, index, Some(kick_evt.as_raw_fd()))?;
+        self.set_vring_fd(VHOST_USER_SET_VRING_CALL, index, Some(call_evt.as_raw_fd()))
+    }
+
+    // Sends the addresses of a queue, and whether the writes to its used ring are logged.
+    fn set_vring_addr<G: GuestMemory, M: GuestAddressSpace>(
+        &mut self,
+        mem: &G,
+        index: u16,
+        queue: &Queue<M>,
+    ) -> Result<()> {
+        let state = queue.state();
+        let mut addr = VringAddr::try_from((mem, index, &state)).map_err(Error::GuestMemory)?;
+        if self.logging {
+            addr.set_log(&state);
+        }
+        self.set(VHOST_USER_SET_VRING_ADDR, addr.as_slice(), &[])
+    }
+
+    /// Enables or disables a queue. The queues start disabled when
+    /// `VHOST_USER_F_PROTOCOL_FEATURES` is negotiated, and enabled otherwise.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the queue.
+    /// * `enable` - Whether the queue is enabled.
+    pub fn set_vring_enable(&mut self, index: u16, enable: bool) -> Result<()> {
+        if self.features & (1 << VHOST_USER_F_PROTOCOL_FEATURES) == 0 {
+            return Ok(());
+        }
+        self.set_vring_state(VHOST_USER_SET_VRING_ENABLE, index, u32::from(enable))
+    }
+
+    /// Stops a queue, and returns its next available ring index.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the queue.
+    pub fn get_vring_base(&mut self, index: u16) -> Result<u16> {
+        let state = VringState {
+            index: u32::from(index),
+            num: 0,
+        };
+        self.send(VHOST_USER_GET_VRING_BASE, 0, state.as_slice(), &[])?;
+        let reply = self.recv_obj::<VringState>(VHOST_USER_GET_VRING_BASE)?;
+        if reply.index != u32::from(index) {
+            return Err(Error::InvalidReply(VHOST_USER_GET_VRING_BASE));
+        }
+        // The ring indices are 16 bits wide.
+        Ok(reply.num as u16)
+    }
+
+    /// Sends the negotiated features, the guest memory regions and the configuration of all
+    /// the queues to the backend, and enables the queues. This is meant to be called when the
+    /// device is activated.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory, whose regions have to be backed by files.
+    /// * `features` - The virtio features negotiated with the driver.
+    /// * `queues` - The queues of the device.
+    /// * `kick_evts` - The kick `EventFd` of each queue.
+    /// * `call_evts` - The call `EventFd` of each queue.
+    pub fn start<G: GuestMemory, M: GuestAddressSpace>(
+        &mut self,
+        mem: &G,
+        features: u64,
+        queues: &[Queue<M>],
+        kick_evts: &[EventFd],
+        call_evts: &[EventFd],
+    ) -> Result<()> {
+        let bases = queues.iter().map(|queue| Ok(queue.next_avail()));
+        self.start_with_bases(mem, features, queues, bases, kick_evts, call_evts)
+    }
+
+    /// Sends the configuration of the device to a backend the frontend reconnected to (see
+    /// [`reconnect`](struct.VhostUserFrontend.html#method.reconnect)), and enables the queues,
+    /// like [`start`](struct.VhostUserFrontend.html#method.start). The queue indices of the
+    /// previous backend are lost, so the queues continue from the index of their used ring,
+    /// which means the buffers the previous backend didn't use are processed again. The queues
+    /// are kicked as well, since the driver might have made buffers available in the meantime.
+    /// When the
+    /// device has an in-flight descriptors area (see
+    /// [`get_inflight_fd`](struct.VhostUserFrontend.html#method.get_inflight_fd)), it has to be
+    /// passed to the backend with
+    /// [`set_inflight_fd`](struct.VhostUserFrontend.html#method.set_inflight_fd) beforehand,
+    /// so the backend resubmits the in-flight buffers itself.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory, whose regions have to be backed by files.
+    /// * `features` - The virtio features negotiated with the driver.
+    /// * `queues` - The queues of the device.
+    /// * `kick_evts` - The kick `EventFd` of each queue.
+    /// * `call_evts` - The call `EventFd` of each queue.
+    pub fn resume<G: GuestMemory, M: GuestAddressSpace>(
+        &mut self,
+        mem: &G,
+        features: u64,
+        queues: &[Queue<M>],
+        kick_evts: &[EventFd],
+        call_evts: &[EventFd],
+    ) -> Result<()> {
+        let bases = queues.iter().enumerate().map(|(i, queue)| {
+            // The number of queues always fits in an `u16`.
+            VringState::from_used_ring(mem, i as u16, &queue.state())
+                .map(|base| base.base())
+                .map_err(Error::GuestMemory)
+        });
+        self.start_with_bases(mem, features, queues, bases, kick_evts, call_evts)?;
+        for kick_evt in kick_evts {
+            kick_evt.write(1).map_err(Error::EventFd)?;
+        }
+        Ok(())
+    }
+
+    // Starts the queues, each one continuing from the available ring index in `bases`.
+    fn start_with_bases<G, M, I>(
+        &mut self,
+        mem: &G,
+        features: u64,
+        queues: &[Queue<M>],
+        bases: I,
+        kick_evts: &[EventFd],
+        call_evts: &[EventFd],
+    ) -> Result<()>
+    where
+        G: GuestMemory,
+        M: GuestAddressSpace,
+        I: Iterator<Item = Result<u16>>,
+    {
+        if kick_evts.len() != queues.len() || call_evts.len() != queues.len() {
+            return Err(Error::InvalidEventFds);
+        }
+        self.set_features(features)?;
+        self.set_mem_table(mem)?;
+
+        for (i, (queue, base)) in queues.iter().zip(bases).enumerate() {
+            // The number of queues always fits in an `u16`.
+            let index = i as u16;
+            self.set_vring_with_base(mem, index, queue, base?, &kick_evts[i], &call_evts[i])?;
+            self.set_vring_enable(index, true)?;
+        }
+        Ok(())
+    }
+
+    /// Disables and stops the first `num_queues` queues of the backend, and returns their next
+    /// available ring indices. This is meant to be called when the device is reset.
+    ///
+    /// # Arguments
+    /// * `num_queues` - The number of queues of the device.
+    pub fn stop(&mut self, num_queues: u16) -> Result<Vec<u16>> {
+        (0..num_queues)
+            .map(|index| {
+                self.set_vring_enable(index, false)?;
+                self.get_vring_base(index)
+            })
+            .collect()
+    }
+
+    /// Returns the first `len` bytes of the configuration space of the backend, which requires
+    /// `VHOST_USER_PROTOCOL_F_CONFIG`.
+    ///
+    /// # Arguments
+    /// * `len` - The length of the configuration space.
+    pub fn get_config(&mut self, len: usize) -> Result<Vec<u8>> {
+        self.require_protocol_feature(VHOST_USER_PROTOCOL_F_CONFIG)?;
+        let header = ConfigHeader {
+            offset: 0,
+            // The configuration space is small, so its length fits in an `u32`.
+            size: len as u32,
+            flags: 0,
+        };
+        let mut payload = header.as_slice().to_vec();
+        payload.resize(payload.len() + len, 0);
+        self.send(VHOST_USER_GET_CONFIG, 0, &payload, &[])?;
+
+        let reply = self.recv(VHOST_USER_GET_CONFIG)?;
+        if reply.len() != payload.len() {
+            return Err(Error::InvalidReply(VHOST_USER_GET_CONFIG));
+        }
+        Ok(reply[size_of::<ConfigHeader>()..].to_vec())
+    }
+
+    /// Writes to the configuration space of the backend, which requires
+    /// `VHOST_USER_PROTOCOL_F_CONFIG`.
+    ///
+    /// # Arguments
+    /// * `offset` - The offset of the written range in the configuration space.
+    /// * `data` - The contents of the range.
+    pub fn set_config(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        self.require_protocol_feature(VHOST_USER_PROTOCOL_F_CONFIG)?;
+        let header = ConfigHeader {
+            // Configuration space accesses are small, so these fit in an `u32`.
+            offset: offset as u32,
+            size: data.len() as u32,
+            flags: 0,
+        };
+        let mut payload = header.as_slice().to_vec();
+        payload.extend_from_slice(data);
+        self.set(VHOST_USER_SET_CONFIG, &payload, &[])
+    }
+
+    /// Asks the backend for a shared memory area where it records the in-flight descriptors,
+    /// which requires `VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD`. The frontend is expected to keep
+    /// the area around, and to pass it to the backend with
+    /// [`set_inflight_fd`](struct.VhostUserFrontend.html#method.set_inflight_fd) after the
+    /// backend restarts (i.e. when reconnecting), such that the requests which were in flight
+    /// when the backend stopped are resubmitted.
+    ///
+    /// # Arguments
+    /// * `num_queues` - The number of queues of the device.
+    /// * `queue_size` - The size of the queues.
+    pub fn get_inflight_fd(
+        &mut self,
+        num_queues: u16,
+        queue_size: u16,
+    ) -> Result<(Inflight, File)> {
+        self.require_protocol_feature(VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD)?;
+        let inflight = Inflight {
+            num_queues,
+            queue_size,
+            ..Default::default()
+        };
+        self.send(VHOST_USER_GET_INFLIGHT_FD, 0, inflight.as_slice(), &[])?;
+        let (payload, mut files) = self.recv_with_files(VHOST_USER_GET_INFLIGHT_FD)?;
+        let file = match files.pop() {
+            Some(file) if payload.len() == size_of::<Inflight>() => file,
+            _ => return Err(Error::InvalidReply(VHOST_USER_GET_INFLIGHT_FD)),
+        };
+        let mut inflight = Inflight::default();
+        inflight.as_mut_slice().copy_from_slice(&payload);
+        Ok((inflight, file))
+    }
+
+    /// Passes the shared memory area returned by
+    /// [`get_inflight_fd`](struct.VhostUserFrontend.html#method.get_inflight_fd) to the
+    /// backend. This has to be done before the queues are started.
+    ///
+    /// # Arguments
+    /// * `inflight` - The description of the area.
+    /// * `file` - The file which backs the area.
+    pub fn set_inflight_fd(&mut self, inflight: &Inflight, file: &File) -> Result<()> {
+        self.require_protocol_feature(VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD)?;
+        self.set(
+            VHOST_USER_SET_INFLIGHT_FD,
+            inflight.as_slice(),
+            &[file.as_raw_fd()],
+        )
+    }
+
+    /// Shares the dirty page log with the backend, which requires
+    /// `VHOST_USER_PROTOCOL_F_LOG_SHMFD`. The backend only writes to the log once logging is
+    /// enabled, which is done by
+    /// [`start_logging`](struct.VhostUserFrontend.html#method.start_logging).
+    ///
+    /// # Arguments
+    /// * `log` - The dirty page log.
+    pub fn set_log_base(&mut self, log: &DirtyLog) -> Result<()> {
+        self.require_protocol_feature(VHOST_USER_PROTOCOL_F_LOG_SHMFD)?;
+        self.send(
+            VHOST_USER_SET_LOG_BASE,
+            0,
+            log.log().as_slice(),
+            &[log.as_raw_fd()],
+        )?;
+        // The backend replies once it mapped the log, either with an empty payload or with a
+        // status.
+        let reply = self.recv(VHOST_USER_SET_LOG_BASE)?;
+        match reply.len() {
+            0 => Ok(()),
+            8 if reply.iter().all(|&b| b == 0) => Ok(()),
+            8 => Err(Error::BackendFailure(VHOST_USER_SET_LOG_BASE)),
+            _ => Err(Error::InvalidReply(VHOST_USER_SET_LOG_BASE)),
+        }
+    }
+
+    /// Sets the `EventFd` the backend writes to after it updates the dirty page log.
+    ///
+    /// # Arguments
+    /// * `log_evt` - The `EventFd` which notifies the frontend about log updates.
+    pub fn set_log_fd(&mut self, log_evt: &EventFd) -> Result<()> {
+        self.set(VHOST_USER_SET_LOG_FD, &[], &[log_evt.as_raw_fd()])
+    }
+
+    /// Makes the backend log its guest memory writes in the dirty page log, which requires
+    /// `VHOST_F_LOG_ALL` and `VHOST_USER_PROTOCOL_F_LOG_SHMFD`. This is meant to be called on
+    /// the source of a live migration, while the device is activated, and the pages returned by
+    /// [`DirtyLog::take_dirty_pages`](struct.DirtyLog.html#method.take_dirty_pages) have to be
+    /// sent to the destination along with the ones written by the VMM.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    /// * `features` - The virtio features negotiated with the driver.
+    /// * `queues` - The queues of the device.
+    /// * `log` - The dirty page log.
+    pub fn start_logging<G: GuestMemory, M: GuestAddressSpace>(
+        &mut self,
+        mem: &G,
+        features: u64,
+        queues: &[Queue<M>],
+        log: &DirtyLog,
+    ) -> Result<()> {
+        if self.features & (1 << VHOST_F_LOG_ALL) == 0 {
+            return Err(Error::MissingFeature(VHOST_F_LOG_ALL));
+        }
+        self.set_log_base(log)?;
+        self.set_logging(mem, features, queues, true)
+    }
+
+    /// Makes the backend stop logging its guest memory writes (i.e. when the migration is
+    /// canceled).
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    /// * `features` - The virtio features negotiated with the driver.
+    /// * `queues` - The queues of the device.
+    pub fn stop_logging<G: GuestMemory, M: GuestAddressSpace>(
+        &mut self,
+        mem: &G,
+        features: u64,
+        queues: &[Queue<M>],
+    ) -> Result<()> {
+        self.set_logging(mem, features, queues, false)
+    }
+
+    // Toggles `VHOST_F_LOG_ALL` and the used ring logging of all the queues.
+    fn set_logging<G: GuestMemory, M: GuestAddressSpace>(
+        &mut self,
+        mem: &G,
+        features: u64,
+        queues: &[Queue<M>],
+        logging: bool,
+    ) -> Result<()> {
+        self.logging = logging;
+        self.set_features(features)?;
+        for (i, queue) in queues.iter().enumerate() {
+            // The number of queues always fits in an `u16`.
+            self.set_vring_addr(mem, i as u16, queue)?;
+        }
+        Ok(())
+    }
+
+    /// Makes the backend open a userfaultfd for postcopy migration, and returns it, which
+    /// requires `VHOST_USER_PROTOCOL_F_PAGEFAULT`. This is meant to be called on the
+    /// destination of a live migration, before the guest memory is populated, and the VMM is
+    /// expected to resolve the faults reported through the userfaultfd by placing the pages
+    /// received from the source.
+    pub fn postcopy_advise(&mut self) -> Result<File> {
+        self.require_protocol_feature(VHOST_USER_PROTOCOL_F_PAGEFAULT)?;
+        self.send(VHOST_USER_POSTCOPY_ADVISE, 0, &[], &[])?;
+        let (_, mut files) = self.recv_with_files(VHOST_USER_POSTCOPY_ADVISE)?;
+        files
+            .pop()
+            .ok_or(Error::InvalidReply(VHOST_USER_POSTCOPY_ADVISE))
+    }
+
+    /// Makes the backend register the guest memory with its userfaultfd, which requires
+    /// `VHOST_USER_PROTOCOL_F_PAGEFAULT`. The guest memory regions have to be sent again
+    /// afterwards (i.e. when the device is activated), and the backend replies with the
+    /// addresses it mapped them at.
+    pub fn postcopy_listen(&mut self) -> Result<()> {
+        self.require_protocol_feature(VHOST_USER_PROTOCOL_F_PAGEFAULT)?;
+        self.set(VHOST_USER_POSTCOPY_LISTEN, &[], &[])?;
+        self.postcopy_regions = Some(Vec::new());
+        Ok(())
+    }
+
+    /// Returns the guest memory regions, as mapped by the backend, with the addresses the
+    /// faults reported through the userfaultfd refer to. This is `None` unless the backend
+    /// listens for page faults.
+    pub fn postcopy_regions(&self) -> Option<&[MemoryRegion]> {
+        self.postcopy_regions.as_deref()
+    }
+
+    /// Makes the backend stop handling page faults and close its userfaultfd, once all the
+    /// pages were received from the source, which requires `VHOST_USER_PROTOCOL_F_PAGEFAULT`.
+    pub fn postcopy_end(&mut self) -> Result<()> {
+        self.require_protocol_feature(VHOST_USER_PROTOCOL_F_PAGEFAULT)?;
+        self.send(VHOST_USER_POSTCOPY_END, 0, &[], &[])?;
+        self.postcopy_regions = None;
+        match self.recv_obj::<u64>(VHOST_USER_POSTCOPY_END)? {
+            0 => Ok(()),
+            _ => Err(Error::BackendFailure(VHOST_USER_POSTCOPY_END)),
+        }
+    }
+
+    /// Creates the slave channel, through which the backend sends requests to the frontend
+    /// (i.e. for mapping file ranges in a shared memory region), which requires
+    /// `VHOST_USER_PROTOCOL_F_SLAVE_REQ`.
+    pub fn create_slave_channel(&mut self) -> Result<SlaveChannel> {
+        self.require_protocol_feature(VHOST_USER_PROTOCOL_F_SLAVE_REQ)?;
+        let (stream, backend_stream) = UnixStream::pair().map_err(Error::Socket)?;
+        self.set(
+            VHOST_USER_SET_SLAVE_REQ_FD,
+            &[],
+            &[backend_stream.as_raw_fd()],
+        )?;
+        Ok(SlaveChannel { stream })
+    }
+}
+
+impl AsRawFd for VhostUserFrontend {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+/// A request sent by the backend through the slave channel.
+#[derive(Debug)]
+pub struct SlaveRequest {
+    /// The request code, which is device specific.
+    pub request: u32,
+    /// The protocol version and the message flags.
+    pub flags: u32,
+    /// The payload of the request.
+    pub payload: Vec<u8>,
+    /// The files sent along with the request.
+    pub files: Vec<File>,
+}
+
+impl SlaveRequest {
+    /// Returns whether the backend waits for the result of the request.
+    pub fn needs_reply(&self) -> bool {
+        self.flags & VHOST_USER_NEED_REPLY != 0
+    }
+
+    /// Returns the payload as an object of type `T`, or `None` if the payload doesn't have the
+    /// right size.
+    pub fn payload_obj<T: ByteValued>(&self) -> Option<T> {
+        if self.payload.len() != size_of::<T>() {
+            return None;
+        }
+        let mut obj = T::default();
+        obj.as_mut_slice().copy_from_slice(&self.payload);
+        Some(obj)
+    }
+}
+
+/// The frontend side of the slave channel, through which the backend sends requests to the
+/// frontend. It's created by
+/// [`VhostUserFrontend::create_slave_channel`](struct.VhostUserFrontend.html#method.create_slave_channel),
+/// and the VMM is expected to wait for it to become readable (i.e. with epoll), then to
+/// receive and handle the request, and to send back its result.
+#[derive(Debug)]
+pub struct SlaveChannel {
+    stream: UnixStream,
+}
+
+impl SlaveChannel {
+    /// Receives the next request from the backend. This blocks until a request is available.
+    pub fn recv_request(&mut self) -> Result<SlaveRequest> {
+        let mut header = Header::default();
+        let mut fds = [-1; MAX_MEMORY_REGIONS];
+        let mut iovecs = [iovec {
+            iov_base: header.as_mut_slice().as_mut_ptr() as *mut libc::c_void,
+            iov_len: size_of::<Header>(),
+        }];
+        // Safe because the iovec points to the header, which can hold arbitrary data.
+        let (len, fd_count) = unsafe { self.stream.recv_with_fds(&mut iovecs, &mut fds) }
+            .map_err(|e| Error::Socket(io::Error::from_raw_os_error(e.errno())))?;
+        let files = fds[..fd_count]
+            .iter()
+            // Safe because the received file descriptors are owned by the frontend.
+            .map(|&fd| unsafe { File::from_raw_fd(fd) })
+            .collect();
+        if len == 0 {
+            return Err(Error::Socket(io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        self.stream
+            .read_exact(&mut header.as_mut_slice()[len..])
+            .map_err(Error::Socket)?;
+        if header.size > MAX_SLAVE_REQUEST_SIZE {
+            return Err(Error::InvalidSlaveRequest(header.request));
+        }
+        let mut payload = vec![0; header.size as usize];
+        self.stream
+            .read_exact(&mut payload)
+            .map_err(Error::Socket)?;
+
+        Ok(SlaveRequest {
+            request: header.request,
+            flags: header.flags,
+            payload,
+            files,
+        })
+    }
+
+    /// Sends the result of a request to the backend, if it waits for one.
+    ///
+    /// # Arguments
+    /// * `request` - The request received from the backend.
+    /// * `result` - The result of the request, which is zero on success.
+    pub fn send_reply(&mut self, request: &SlaveRequest, result: u64) -> Result<()> {
+        if !request.needs_reply() {
+            return Ok(());
+        }
+        let header = Header {
+            request: request.request,
+            flags: VHOST_USER_VERSION | VHOST_USER_REPLY,
+            size: size_of::<u64>() as u32,
+        };
+        let mut reply = header.as_slice().to_vec();
+        reply.extend_from_slice(result.as_slice());
+        self.stream.write_all(&reply).map_err(Error::Socket)
+    }
+}
+
+impl AsRawFd for SlaveChannel {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::io::IntoRawFd;
+    use std::sync::{Arc, Mutex};
+    use std::thread::{self, JoinHandle};
+
+    use vm_memory::{Bytes, GuestMemoryMmap, VolatileMemory};
+    use vmm_sys_util::eventfd::EFD_NONBLOCK;
+    use vmm_sys_util::tempfile::TempFile;
+
+    use crate::mock::{recv_backend_message, BackendMessage};
+
+    type Mem = Arc<GuestMemoryMmap>;
+
+    const BACKEND_FEATURES: u64 =
+        (1 << 32) | (1 << VHOST_USER_F_PROTOCOL_FEATURES) | (1 << VHOST_F_LOG_ALL) | (1 << 5);
+    // The fake backend maps the guest memory regions at this offset from their guest physical
+    // address during postcopy migration.
+    const POSTCOPY_MAP_OFFSET: u64 = 0x7000_0000;
+
+    // The behavior of the fake backend.
+    #[derive(Clone, Debug)]
+    struct Backend {
+        features: u64,
+        protocol_features: u64,
+        // The value of the acknowledgements, which signals a failure when nonzero.
+        ack: u64,
+        config: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Default for Backend {
+        fn default() -> Self {
+            Backend {
+                features: BACKEND_FEATURES,
+                protocol_features: SUPPORTED_PROTOCOL_FEATURES,
+                ack: 0,
+                config: Arc::new(Mutex::new(vec![1, 2, 3, 4])),
+            }
+        }
+    }
+
+    fn send_reply(stream: &mut UnixStream, request: u32, payload: &[u8]) {
+        let header = Header {
+            request,
+            flags: VHOST_USER_VERSION | VHOST_USER_REPLY,
+            size: payload.len() as u32,
+        };
+        let mut reply = header.as_slice().to_vec();
+        reply.extend_from_slice(payload);
+        stream.write_all(&reply).unwrap();
+    }
+
+    // Spawns a fake backend, which answers the frontend requests and records all of them. A
+    // request is sent on the slave channel as soon as it's created, and its reply is recorded
+    // after the frontend disconnects.
+    fn spawn_backend(mut stream: UnixStream, backend: Backend) -> JoinHandle<Vec<BackendMessage>> {
+        thread::spawn(move || {
+            let mut messages = Vec::new();
+            let mut slave = None;
+            let mut postcopy = false;
+            while let Some(message) = recv_backend_message(&mut stream) {
+                let request = message.header.request;
+                let mut follow_up = None;
+                match request {
+                    VHOST_USER_SET_SLAVE_REQ_FD => {
+                        let fd = message.fds[0].try_clone().unwrap().into_raw_fd();
+                        // Safe because the file descriptor is a socket owned by the backend.
+                        let slave_stream = unsafe { UnixStream::from_raw_fd(fd) };
+                        let header = Header {
+                            request: 6,
+                            flags: VHOST_USER_VERSION | VHOST_USER_NEED_REPLY,
+                            size: 4,
+                        };
+                        let mut slave_request = header.as_slice().to_vec();
+                        slave_request.extend_from_slice(&[1, 2, 3, 4]);
+                        slave_stream
+                            .send_with_fds(&[&slave_request[..]], &[slave_stream.as_raw_fd()])
+                            .unwrap();
+                        slave = Some(slave_stream);
+                    }
+                    VHOST_USER_GET_FEATURES => {
+                        send_reply(&mut stream, request, backend.features.as_slice())
+                    }
+                    VHOST_USER_GET_PROTOCOL_FEATURES => {
+                        send_reply(&mut stream, request, backend.protocol_features.as_slice())
+                    }
+                    VHOST_USER_GET_QUEUE_NUM | VHOST_USER_GET_MAX_MEM_SLOTS => {
+                        send_reply(&mut stream, request, 2u64.as_slice())
+                    }
+                    VHOST_USER_GET_CONFIG => {
+                        let mut reply = message.payload[..size_of::<ConfigHeader>()].to_vec();
+                        reply.extend_from_slice(&backend.config.lock().unwrap());
+                        send_reply(&mut stream, request, &reply);
+                    }
+                    VHOST_USER_SET_CONFIG => {
+                        let header: ConfigHeader = message.obj(0);
+                        let offset = header.offset as usize;
+                        let data = &message.payload[size_of::<ConfigHeader>()..];
+                        backend.config.lock().unwrap()[offset..offset + data.len()]
+                            .copy_from_slice(data);
+                    }
+                    VHOST_USER_GET_VRING_BASE => {
+                        let state = VringState {
+                            index: message.obj::<VringState>(0).index,
+                            num: 3,
+                        };
+                        send_reply(&mut stream, request, state.as_slice());
+                    }
+                    VHOST_USER_SET_LOG_BASE => {
+                        // Marks the second page of each region as written.
+                        let log: Log = message.obj(0);
+                        let mapping = MmapRegion::from_file(
+                            FileOffset::new(message.fds[0].try_clone().unwrap(), 0),
+                            log.mmap_size as usize,
+                        )
+                        .unwrap();
+                        let bitmap = mapping.get_slice(0, mapping.size()).unwrap();
+                        bitmap.store(0x2u8, 0, Ordering::Release).unwrap();
+                        bitmap.store(0x2u8, 0x20, Ordering::Release).unwrap();
+                        send_reply(&mut stream, request, &[]);
+                    }
+                    VHOST_USER_POSTCOPY_ADVISE => {
+                        let userfaultfd = TempFile::new().unwrap().into_file();
+                        let header = Header {
+                            request,
+                            flags: VHOST_USER_VERSION | VHOST_USER_REPLY,
+                            size: 0,
+                        };
+                        stream
+                            .send_with_fds(&[header.as_slice()], &[userfaultfd.as_raw_fd()])
+                            .unwrap();
+                    }
+                    VHOST_USER_POSTCOPY_LISTEN => postcopy = true,
+                    VHOST_USER_POSTCOPY_END => {
+                        postcopy = false;
+                        send_reply(&mut stream, request, 0u64.as_slice());
+                    }
+                    VHOST_USER_SET_MEM_TABLE if postcopy => {
+                        let mut reply = message.payload.clone();
+                        for chunk in reply[8..].chunks_mut(size_of::<MemoryRegion>()) {
+                            let region = MemoryRegion::from_mut_slice(chunk).unwrap();
+                            region.userspace_addr = region.guest_phys_addr + POSTCOPY_MAP_OFFSET;
+                        }
+                        send_reply(&mut stream, request, &reply);
+                        // The frontend confirms it received the mapped regions.
+                        follow_up = recv_backend_message(&mut stream);
+                    }
+                    _ => {}
+                }
+                if message.header.flags & VHOST_USER_NEED_REPLY != 0 {
+                    send_reply(&mut stream, request, backend.ack.as_slice());
+                }
+                messages.push(message);
+                messages.extend(follow_up);
+            }
+            if let Some(reply) = slave.as_mut().and_then(recv_backend_message) {
+                messages.push(reply);
+            }
+            messages
+        })
+    }
+
+    fn connect(backend: Backend) -> (VhostUserFrontend, JoinHandle<Vec<BackendMessage>>) {
+        let (frontend, stream) = UnixStream::pair().unwrap();
+        let handle = spawn_backend(stream, backend);
+        (VhostUserFrontend::new(frontend).unwrap(), handle)
+    }
+
+    fn shared_mem() -> Mem {
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x2_0000).unwrap();
+        Arc::new(
+            GuestMemoryMmap::from_ranges_with_files(&[
+                (
+                    GuestAddress(0),
+                    0x1_0000,
+                    Some(FileOffset::new(file.try_clone().unwrap(), 0)),
+                ),
+                (
+                    GuestAddress(0x10_0000),
+                    0x1_0000,
+                    Some(FileOffset::new(file, 0x1_0000)),
+                ),
+            ])
+            .unwrap(),
+        )
+    }
+
+    fn requests(messages: &[BackendMessage]) -> Vec<u32> {
+        messages.iter().map(|m| m.header.request).collect()
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let backend = Backend {
+            protocol_features: (1 << VHOST_USER_PROTOCOL_F_MQ) | (1 << 2),
+            ..Default::default()
+        };
+        let (mut frontend, handle) = connect(backend);
+        assert_eq!(
+            frontend.features(),
+            BACKEND_FEATURES & !(1 << VHOST_USER_F_PROTOCOL_FEATURES) & !(1 << VHOST_F_LOG_ALL)
+        );
+        // Only the protocol features supported by both sides are negotiated.
+        assert_eq!(frontend.protocol_features(), 1 << VHOST_USER_PROTOCOL_F_MQ);
+        assert!(frontend
+            .require_protocol_feature(VHOST_USER_PROTOCOL_F_MQ)
+            .is_ok());
+        assert!(matches!(
+            frontend.get_config(4),
+            Err(Error::MissingProtocolFeature(VHOST_USER_PROTOCOL_F_CONFIG))
+        ));
+        frontend.check_queue_num(1).unwrap();
+        frontend.check_queue_num(2).unwrap();
+        assert!(matches!(
+            frontend.check_queue_num(3),
+            Err(Error::TooManyQueues(3))
+        ));
+        drop(frontend);
+
+        let messages = handle.join().unwrap();
+        assert_eq!(
+            requests(&messages),
+            [
+                VHOST_USER_SET_OWNER,
+                VHOST_USER_GET_FEATURES,
+                VHOST_USER_GET_PROTOCOL_FEATURES,
+                VHOST_USER_SET_PROTOCOL_FEATURES,
+                VHOST_USER_GET_QUEUE_NUM,
+                VHOST_USER_GET_QUEUE_NUM,
+            ]
+        );
+        assert_eq!(messages[3].obj::<u64>(0), 1 << VHOST_USER_PROTOCOL_F_MQ);
+
+        // The protocol features are not negotiated when the backend doesn't support them.
+        let backend = Backend {
+            features: 1 << 32,
+            ..Default::default()
+        };
+        let (mut frontend, handle) = connect(backend);
+        assert_eq!(frontend.protocol_features(), 0);
+        assert!(matches!(
+            frontend.check_queue_num(2),
+            Err(Error::MissingProtocolFeature(VHOST_USER_PROTOCOL_F_MQ))
+        ));
+        frontend.set_features(1 << 32).unwrap();
+        frontend.set_vring_enable(0, true).unwrap();
+        drop(frontend);
+
+        let messages = handle.join().unwrap();
+        assert_eq!(
+            requests(&messages),
+            [
+                VHOST_USER_SET_OWNER,
+                VHOST_USER_GET_FEATURES,
+                VHOST_USER_SET_FEATURES
+            ]
+        );
+        assert_eq!(messages[2].obj::<u64>(0), 1 << 32);
+    }
+
+    #[test]
+    fn test_slave_channel() {
+        let backend = Backend {
+            protocol_features: SUPPORTED_PROTOCOL_FEATURES | SLAVE_PROTOCOL_FEATURES,
+            ..Default::default()
+        };
+
+        // The slave channel features are only negotiated when the device asks for them.
+        let (mut frontend, handle) = connect(backend.clone());
+        assert_eq!(frontend.protocol_features(), SUPPORTED_PROTOCOL_FEATURES);
+        assert!(matches!(
+            frontend.create_slave_channel(),
+            Err(Error::MissingProtocolFeature(
+                VHOST_USER_PROTOCOL_F_SLAVE_REQ
+            ))
+        ));
+        drop(frontend);
+        handle.join().unwrap();
+
+        let (stream, backend_stream) = UnixStream::pair().unwrap();
+        let handle = spawn_backend(backend_stream, backend);
+        let mut frontend =
+            VhostUserFrontend::new_with_protocol_features(stream, SLAVE_PROTOCOL_FEATURES).unwrap();
+        assert_eq!(
+            frontend.protocol_features(),
+            SUPPORTED_PROTOCOL_FEATURES | SLAVE_PROTOCOL_FEATURES
+        );
+        let mut channel = frontend.create_slave_channel().unwrap();
+        let request = channel.recv_request().unwrap();
+        assert_eq!(request.request, 6);
+        assert!(request.needs_reply());
+        assert_eq!(request.payload, vec![1, 2, 3, 4]);
+        assert_eq!(request.payload_obj::<u32>(), Some(0x0403_0201));
+        assert_eq!(request.payload_obj::<u64>(), None);
+        assert_eq!(request.files.len(), 1);
+        channel.send_reply(&request, 1).unwrap();
+        // The received file is the other end of the channel.
+        drop(request);
+        drop(frontend);
+
+        let messages = handle.join().unwrap();
+        let slave_req_fd = &messages[messages.len() - 2];
+        assert_eq!(slave_req_fd.header.request, VHOST_USER_SET_SLAVE_REQ_FD);
+        assert_eq!(slave_req_fd.fds.len(), 1);
+        let reply = messages.last().unwrap();
+        assert_eq!(reply.header.request, 6);
+        assert_eq!(reply.header.flags, VHOST_USER_VERSION | VHOST_USER_REPLY);
+        assert_eq!(reply.obj::<u64>(0), 1);
+
+        // The backend closed its end of the channel.
+        drop(messages);
+        assert!(matches!(
+            channel.recv_request(),
+            Err(Error::Socket(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[test]
+    fn test_config() {
+        let backend = Backend::default();
+        let config = backend.config.clone();
+        let (mut frontend, handle) = connect(backend);
+        assert_eq!(frontend.get_config(4).unwrap(), [1, 2, 3, 4]);
+        frontend.set_config(2, &[5, 6]).unwrap();
+        assert_eq!(*config.lock().unwrap(), [1, 2, 5, 6]);
+        // The backend has to return the whole range.
+        assert!(matches!(
+            frontend.get_config(8),
+            Err(Error::InvalidReply(VHOST_USER_GET_CONFIG))
+        ));
+        drop(frontend);
+
+        let messages = handle.join().unwrap();
+        let set_config = messages
+            .iter()
+            .find(|m| m.header.request == VHOST_USER_SET_CONFIG)
+            .unwrap();
+        // The request is acknowledged when `VHOST_USER_PROTOCOL_F_REPLY_ACK` is negotiated.
+        assert_ne!(set_config.header.flags & VHOST_USER_NEED_REPLY, 0);
+        assert_eq!(
+            set_config.obj::<ConfigHeader>(0),
+            ConfigHeader {
+                offset: 2,
+                size: 2,
+                flags: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_start_stop() {
+        let mem = shared_mem();
+        let (mut frontend, handle) = connect(Backend::default());
+
+        let mut queues = vec![Queue::new(mem.clone(), 16), Queue::new(mem.clone(), 16)];
+        for (i, queue) in queues.iter_mut().enumerate() {
+            let base = GuestAddress(i as u64 * 0x1000);
+            queue.size = 8;
+            queue.desc_table = base;
+            queue.avail_ring = base.unchecked_add(0x100);
+            queue.used_ring = GuestAddress(0x10_0000).unchecked_add(i as u64 * 0x1000);
+            queue.ready = true;
+        }
+        let new_eventfds = |count| {
+            (0..count)
+                .map(|_| EventFd::new(EFD_NONBLOCK).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let (kick_evts, call_evts) = (new_eventfds(2), new_eventfds(2));
+        assert!(matches!(
+            frontend.start(&*mem, 1 << 32, &queues, &kick_evts[..1], &call_evts),
+            Err(Error::InvalidEventFds)
+        ));
+        frontend
+            .start(&*mem, 1 << 32, &queues, &kick_evts, &call_evts)
+            .unwrap();
+        assert_eq!(frontend.stop(2).unwrap(), [3, 3]);
+        drop(frontend);
+
+        let messages = handle.join().unwrap();
+        let messages = &messages[4..];
+        let mut expected = vec![VHOST_USER_SET_FEATURES, VHOST_USER_SET_MEM_TABLE];
+        for _ in 0..2 {
+            expected.extend_from_slice(&[
+                VHOST_USER_SET_VRING_NUM,
+                VHOST_USER_SET_VRING_ADDR,
+                VHOST_USER_SET_VRING_BASE,
+                VHOST_USER_SET_VRING_KICK,
+                VHOST_USER_SET_VRING_CALL,
+                VHOST_USER_SET_VRING_ENABLE,
+            ]);
+        }
+        for _ in 0..2 {
+            expected.extend_from_slice(&[VHOST_USER_SET_VRING_ENABLE, VHOST_USER_GET_VRING_BASE]);
+        }
+        assert_eq!(requests(messages), expected);
+
+        // The protocol features bit is acknowledged along with the driver features.
+        assert_eq!(
+            messages[0].obj::<u64>(0),
+            (1 << 32) | (1 << VHOST_USER_F_PROTOCOL_FEATURES)
+        );
+
+        let mem_table = &messages[1];
+        assert_eq!(mem_table.fds.len(), 2);
+        assert_eq!(mem_table.obj::<u64>(0), 2);
+        let host_addr = |addr| mem.get_host_address(addr).unwrap() as u64;
+        assert_eq!(
+            mem_table.obj::<MemoryRegion>(8 + size_of::<MemoryRegion>()),
+            MemoryRegion {
+                guest_phys_addr: 0x10_0000,
+                memory_size: 0x1_0000,
+                userspace_addr: host_addr(GuestAddress(0x10_0000)),
+                mmap_offset: 0x1_0000,
+            }
+        );
+
+        let vring = &messages[8..14];
+        assert_eq!(
+            vring[0].obj::<VringState>(0),
+            VringState { index: 1, num: 8 }
+        );
+        assert_eq!(
+            vring[1].obj::<VringAddr>(0),
+            VringAddr {
+                index: 1,
+                desc: host_addr(GuestAddress(0x1000)),
+                used: host_addr(GuestAddress(0x10_1000)),
+                avail: host_addr(GuestAddress(0x1100)),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            vring[2].obj::<VringState>(0),
+            VringState { index: 1, num: 0 }
+        );
+        assert_eq!(vring[3].obj::<u64>(0), 1);
+        assert_eq!(vring[3].fds.len(), 1);
+        assert_eq!(vring[4].fds.len(), 1);
+        assert_eq!(
+            vring[5].obj::<VringState>(0),
+            VringState { index: 1, num: 1 }
+        );
+        assert_eq!(
+            messages[16].obj::<VringState>(0),
+            VringState { index: 1, num: 0 }
+        );
+    }
+
+    #[test]
+    fn test_dirty_log() {
+        let mem = shared_mem();
+        let (mut frontend, handle) = connect(Backend::default());
+        let mut queue = Queue::new(mem.clone(), 16);
+        queue.used_ring = GuestAddress(0x10_0000);
+        let queues = vec![queue];
+
+        let log = DirtyLog::new(&*mem).unwrap();
+        // 272 pages, rounded up to a multiple of 64.
+        assert_eq!(
+            log.log(),
+            Log {
+                mmap_size: 40,
+                mmap_offset: 0
+            }
+        );
+        assert!(log.take_dirty_pages().is_empty());
+        frontend
+            .start_logging(&*mem, 1 << 32, &queues, &log)
+            .unwrap();
+        assert_eq!(
+            log.take_dirty_pages(),
+            [GuestAddress(0x1000), GuestAddress(0x10_1000)]
+        );
+        assert!(log.take_dirty_pages().is_empty());
+        // The writes of the VMM are tracked in the same log.
+        log.mark_dirty(GuestAddress(0xfff), 2);
+        log.mark_dirty(GuestAddress(0x10_0000), 0);
+        log.mark_dirty(GuestAddress(0x200_0000), 1);
+        assert_eq!(
+            log.take_dirty_pages(),
+            [GuestAddress(0), GuestAddress(0x1000)]
+        );
+        let log_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        frontend.set_log_fd(&log_evt).unwrap();
+        frontend.stop_logging(&*mem, 1 << 32, &queues).unwrap();
+        drop(frontend);
+
+        let messages = handle.join().unwrap();
+        let messages = &messages[4..];
+        assert_eq!(
+            requests(messages),
+            [
+                VHOST_USER_SET_LOG_BASE,
+                VHOST_USER_SET_FEATURES,
+                VHOST_USER_SET_VRING_ADDR,
+                VHOST_USER_SET_LOG_FD,
+                VHOST_USER_SET_FEATURES,
+                VHOST_USER_SET_VRING_ADDR,
+            ]
+        );
+        assert_eq!(messages[0].obj::<Log>(0), log.log());
+        assert_eq!(messages[0].fds.len(), 1);
+        assert_eq!(
+            messages[1].obj::<u64>(0),
+            (1 << 32) | (1 << VHOST_USER_F_PROTOCOL_FEATURES) | (1 << VHOST_F_LOG_ALL)
+        );
+        let addr = messages[2].obj::<VringAddr>(0);
+        assert_eq!(addr.flags, 1 << VHOST_VRING_F_LOG);
+        assert_eq!(addr.log, 0x10_0000);
+        assert_eq!(messages[3].fds.len(), 1);
+        assert_eq!(
+            messages[4].obj::<u64>(0),
+            (1 << 32) | (1 << VHOST_USER_F_PROTOCOL_FEATURES)
+        );
+        assert_eq!(messages[5].obj::<VringAddr>(0).flags, 0);
+
+        // Logging requires both the virtio and the protocol feature.
+        let backend = Backend {
+            features: 1 << VHOST_USER_F_PROTOCOL_FEATURES,
+            ..Default::default()
+        };
+        let (mut frontend, handle) = connect(backend);
+        assert!(matches!(
+            frontend.start_logging(&*mem, 0, &queues, &log),
+            Err(Error::MissingFeature(VHOST_F_LOG_ALL))
+        ));
+        drop(frontend);
+        handle.join().unwrap();
+
+        let backend = Backend {
+            protocol_features: 0,
+            ..Default::default()
+        };
+        let (mut frontend, handle) = connect(backend);
+        assert!(matches!(
+            frontend.start_logging(&*mem, 0, &queues, &log),
+            Err(Error::MissingProtocolFeature(
+                VHOST_USER_PROTOCOL_F_LOG_SHMFD
+            ))
+        ));
+        drop(frontend);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_postcopy() {
+        let mem = shared_mem();
+        let (mut frontend, handle) = connect(Backend::default());
+        assert!(frontend.postcopy_regions().is_none());
+        frontend.postcopy_advise().unwrap();
+        frontend.postcopy_listen().unwrap();
+        assert_eq!(frontend.postcopy_regions(), Some(&[][..]));
+
+        frontend.set_mem_table(&*mem).unwrap();
+        let regions = frontend.postcopy_regions().unwrap();
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[1].guest_phys_addr, 0x10_0000);
+        assert_eq!(regions[1].userspace_addr, 0x7010_0000);
+        frontend.postcopy_end().unwrap();
+        assert!(frontend.postcopy_regions().is_none());
+        // The regions are not returned outside of postcopy migration.
+        frontend.set_mem_table(&*mem).unwrap();
+        drop(frontend);
+
+        let messages = handle.join().unwrap();
+        let messages = &messages[4..];
+        assert_eq!(
+            requests(messages),
+            [
+                VHOST_USER_POSTCOPY_ADVISE,
+                VHOST_USER_POSTCOPY_LISTEN,
+                VHOST_USER_SET_MEM_TABLE,
+                VHOST_USER_SET_MEM_TABLE,
+                VHOST_USER_POSTCOPY_END,
+                VHOST_USER_SET_MEM_TABLE,
+            ]
+        );
+        // The table is acknowledged after the frontend confirms it received the regions.
+        assert_ne!(messages[2].header.flags & VHOST_USER_NEED_REPLY, 0);
+        assert_eq!(messages[3].header.flags & VHOST_USER_NEED_REPLY, 0);
+        assert_eq!(messages[3].payload, 0u64.as_slice());
+
+        let backend = Backend {
+            protocol_features: SUPPORTED_PROTOCOL_FEATURES
+                & !(1 << VHOST_USER_PROTOCOL_F_PAGEFAULT),
+            ..Default::default()
+        };
+        let (mut frontend, handle) = connect(backend);
+        assert!(matches!(
+            frontend.postcopy_advise(),
+            Err(Error::MissingProtocolFeature(
+                VHOST_USER_PROTOCOL_F_PAGEFAULT
+            ))
+        ));
+        drop(frontend);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_update_mem_table() {
+        let mem = shared_mem();
+        let (partial, _) = mem
+            .remove_region(GuestAddress(0x10_0000), 0x1_0000)
+            .unwrap();
+        let regions = memory_regions(&*mem).unwrap();
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[1].0.guest_phys_addr, 0x10_0000);
+
+        let (mut frontend, handle) = connect(Backend::default());
+        frontend.update_mem_table(&partial, &*mem).unwrap();
+        frontend.update_mem_table(&*mem, &partial).unwrap();
+        frontend
+            .update_mem_table(&partial, &GuestMemoryMmap::new())
+            .unwrap();
+        drop(frontend);
+
+        let messages = handle.join().unwrap();
+        let messages = &messages[4..];
+        assert_eq!(
+            requests(messages),
+            [
+                VHOST_USER_GET_MAX_MEM_SLOTS,
+                VHOST_USER_ADD_MEM_REG,
+                VHOST_USER_GET_MAX_MEM_SLOTS,
+                VHOST_USER_REM_MEM_REG,
+                VHOST_USER_GET_MAX_MEM_SLOTS,
+                VHOST_USER_REM_MEM_REG,
+            ]
+        );
+        // Only the added regions come with a file descriptor.
+        assert_eq!(messages[1].fds.len(), 1);
+        assert_eq!(messages[1].obj::<MemoryRegion>(8), regions[1].0);
+        assert!(messages[3].fds.is_empty());
+        assert_eq!(messages[3].obj::<MemoryRegion>(8), regions[1].0);
+        assert_eq!(messages[5].obj::<MemoryRegion>(8), regions[0].0);
+
+        // The whole table is sent when the regions can't be updated one by one.
+        let backend = Backend {
+            protocol_features: 0,
+            ..Default::default()
+        };
+        let (mut frontend, handle) = connect(backend);
+        assert!(matches!(
+            frontend.add_mem_region(&regions[1].0, regions[1].1),
+            Err(Error::MissingProtocolFeature(
+                VHOST_USER_PROTOCOL_F_CONFIGURE_MEM_SLOTS
+            ))
+        ));
+        frontend.update_mem_table(&partial, &*mem).unwrap();
+        drop(frontend);
+        let messages = handle.join().unwrap();
+        assert_eq!(
+            messages.last().unwrap().header.request,
+            VHOST_USER_SET_MEM_TABLE
+        );
+        assert_eq!(messages.last().unwrap().obj::<u64>(0), 2);
+    }
+
+    #[test]
+    fn test_errors() {
+        // The backend rejects the requests.
+        let backend = Backend {
+            ack: 1,
+            ..Default::default()
+        };
+        let (mut frontend, handle) = connect(backend);
+        assert!(matches!(
+            frontend.set_features(0),
+            Err(Error::BackendFailure(VHOST_USER_SET_FEATURES))
+        ));
+
+        // The guest memory has to be backed by files.
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0x1000), 0x1000)]).unwrap();
+        assert!(matches!(
+            frontend.set_mem_table(&mem),
+            Err(Error::UnsharedMemoryRegion(GuestAddress(0x1000)))
+        ));
+        let ranges: Vec<_> = (0..=MAX_MEMORY_REGIONS as u64)
+            .map(|i| (GuestAddress(i * 0x1000), 0x1000))
+            .collect();
+        let mem = GuestMemoryMmap::from_ranges(&ranges).unwrap();
+        assert!(matches!(
+            frontend.set_mem_table(&mem),
+            Err(Error::TooManyMemoryRegions(9))
+        ));
+        drop(frontend);
+        handle.join().unwrap();
+
+        // The backend replies to a different request.
+        let (frontend, mut stream) = UnixStream::pair().unwrap();
+        let handle = thread::spawn(move || {
+            recv_backend_message(&mut stream).unwrap();
+            recv_backend_message(&mut stream).unwrap();
+            send_reply(&mut stream, VHOST_USER_GET_QUEUE_NUM, &0u64.to_le_bytes());
+        });
+        assert!(matches!(
+            VhostUserFrontend::new(frontend),
+            Err(Error::InvalidReply(VHOST_USER_GET_FEATURES))
+        ));
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,1807 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! The backend side of the vhost-user protocol.
+//!
+//! This module provides the building blocks of standalone vhost-user daemons (e.g. for block,
+//! network or file system devices):
+//!
+//! - [`VhostUserListener`](struct.VhostUserListener.html) which accepts the connections of the
+//!   frontends (i.e. the VMMs) on a Unix socket.
+//! - [`VhostUserBackend`](trait.VhostUserBackend.html) which is implemented by the device
+//!   logic, and processes the queues.
+//! - [`VhostUserDaemon`](struct.VhostUserDaemon.html) which handles the requests of a frontend.
+//!   It maps the guest memory regions received with `VHOST_USER_SET_MEM_TABLE`, reconstructs
+//!   the `Queue` objects from the `VHOST_USER_SET_VRING_*` requests, and calls the backend when
+//!   a queue is kicked.
+//!
+//! The daemon is driven by an epoll file descriptor (see `AsRawFd`), which becomes readable when
+//! the frontend sends a request or kicks a queue. The daemon can either run its own event loop
+//! with [`run`](struct.VhostUserDaemon.html#method.run), or be integrated into an existing one
+//! with [`process_events`](struct.VhostUserDaemon.html#method.process_events).
+//!
+//! Devices which opt in for `VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD` process their queues through
+//! an [`InflightQueue`](struct.InflightQueue.html), which records the in-flight descriptors in a
+//! memory area shared with the frontend. When the daemon is restarted (i.e. after a crash), the
+//! frontend passes the area to the new daemon, which resubmits the descriptors that were never
+//! returned to the driver.
+
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use std::num::Wrapping;
+use std::ops::Range;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::result;
+use std::sync::atomic::{fence, Ordering};
+use std::sync::Arc;
+
+use libc::iovec;
+use log::warn;
+use vm_memory::mmap::Error as MmapError;
+use vm_memory::{
+    AtomicAccess, ByteValued, Bytes, FileOffset, GuestAddress, GuestAddressSpace,
+    GuestMemoryAtomic, GuestMemoryMmap, GuestRegionMmap, MmapRegion, VolatileMemory, VolatileSlice,
+};
+use vmm_sys_util::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::sock_ctrl_msg::ScmSocket;
+
+use virtio_queue::{DescriptorChain, Error as QueueError, Queue};
+
+use crate::vhost_user::{
+    ConfigHeader, Header, Inflight, MemoryRegion, VringAddr, VringState, MAX_MEMORY_REGIONS,
+    VHOST_USER_ADD_MEM_REG, VHOST_USER_F_PROTOCOL_FEATURES, VHOST_USER_GET_CONFIG,
+    VHOST_USER_GET_FEATURES, VHOST_USER_GET_INFLIGHT_FD, VHOST_USER_GET_MAX_MEM_SLOTS,
+    VHOST_USER_GET_PROTOCOL_FEATURES, VHOST_USER_GET_QUEUE_NUM, VHOST_USER_GET_VRING_BASE,
+    VHOST_USER_NEED_REPLY, VHOST_USER_PROTOCOL_F_CONFIG, VHOST_USER_PROTOCOL_F_CONFIGURE_MEM_SLOTS,
+    VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD, VHOST_USER_PROTOCOL_F_MQ,
+    VHOST_USER_PROTOCOL_F_REPLY_ACK, VHOST_USER_REM_MEM_REG, VHOST_USER_REPLY,
+    VHOST_USER_SET_CONFIG, VHOST_USER_SET_FEATURES, VHOST_USER_SET_INFLIGHT_FD,
+    VHOST_USER_SET_MEM_TABLE, VHOST_USER_SET_OWNER, VHOST_USER_SET_PROTOCOL_FEATURES,
+    VHOST_USER_SET_VRING_ADDR, VHOST_USER_SET_VRING_BASE, VHOST_USER_SET_VRING_CALL,
+    VHOST_USER_SET_VRING_ENABLE, VHOST_USER_SET_VRING_KICK, VHOST_USER_SET_VRING_NUM,
+    VHOST_USER_VERSION, VHOST_USER_VRING_NOFD_MASK,
+};
+use crate::VIRTIO_F_RING_EVENT_IDX;
+
+/// The protocol features which are always supported by the backend side.
+pub const BACKEND_PROTOCOL_FEATURES: u64 = (1 << VHOST_USER_PROTOCOL_F_MQ)
+    | (1 << VHOST_USER_PROTOCOL_F_REPLY_ACK)
+    | (1 << VHOST_USER_PROTOCOL_F_CONFIGURE_MEM_SLOTS);
+/// The maximum number of guest memory regions supported by the backend side, when they are
+/// added one by one with `VHOST_USER_ADD_MEM_REG`.
+pub const MAX_MEM_SLOTS: u64 = 32;
+/// The protocol features which are supported when the device opts in for them (see
+/// [`VhostUserBackend::protocol_features`](trait.VhostUserBackend.html#method.protocol_features)).
+pub const OPTIONAL_PROTOCOL_FEATURES: u64 =
+    (1 << VHOST_USER_PROTOCOL_F_CONFIG) | (1 << VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD);
+
+// The largest request payload accepted from the frontend.
+const MAX_REQUEST_SIZE: u32 = 0x1000;
+// The largest configuration space access (`VHOST_USER_MAX_CONFIG_SIZE` in the specification).
+const MAX_CONFIG_SIZE: u32 = 0x100;
+// The epoll token of the socket; the kick file descriptors use the index of their queue.
+const SOCKET_TOKEN: u64 = u64::MAX;
+// The number of events processed at once.
+const EPOLL_EVENTS_LEN: usize = 32;
+
+// The layout of the in-flight descriptors region of a split queue (`QueueRegionSplit` in the
+// specification). The 16 bytes header is followed by the state of each descriptor of the queue
+// (`DescStateSplit`), which is 16 bytes long as well, and the regions are 64 bytes aligned.
+const INFLIGHT_VERSION: u16 = 1;
+const INFLIGHT_VERSION_OFFSET: usize = 8;
+const INFLIGHT_DESC_NUM_OFFSET: usize = 10;
+const INFLIGHT_LAST_BATCH_HEAD_OFFSET: usize = 12;
+const INFLIGHT_USED_IDX_OFFSET: usize = 14;
+const INFLIGHT_DESC_OFFSET: usize = 16;
+const INFLIGHT_DESC_SIZE: usize = 16;
+const INFLIGHT_DESC_COUNTER_OFFSET: usize = 8;
+const INFLIGHT_REGION_ALIGNMENT: usize = 64;
+
+/// vhost-user backend errors.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to bind the listening socket.
+    Bind(io::Error),
+    /// Failed to set up or wait on the epoll file descriptor.
+    Epoll(io::Error),
+    /// Failed to read a kick `EventFd` or to write a call `EventFd`.
+    EventFd(io::Error),
+    /// Failed to set up the in-flight descriptors area.
+    Inflight(io::Error),
+    /// The frontend sent a malformed request.
+    InvalidRequest(u32),
+    /// A request refers to a queue which doesn't exist.
+    InvalidQueueIndex(u32),
+    /// The frontend set a queue size which is not supported by the backend.
+    InvalidQueueSize(u32),
+    /// A queue address is not within the guest memory regions.
+    InvalidRingAddress(u64),
+    /// Failed to map the guest memory regions.
+    MemoryMap(MmapError),
+    /// Failed to access a queue.
+    Queue(QueueError),
+    /// Failed to communicate with the frontend.
+    Socket(io::Error),
+    /// The request is not supported by the backend.
+    UnsupportedRequest(u32),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Bind(ref err) => write!(f, "failed to bind the listening socket: {}", err),
+            Epoll(ref err) => write!(f, "epoll error: {}", err),
+            EventFd(ref err) => write!(f, "eventfd error: {}", err),
+            Inflight(ref err) => {
+                write!(
+                    f,
+                    "failed to set up the in-flight descriptors area: {}",
+                    err
+                )
+            }
+            InvalidRequest(request) => write!(f, "malformed request {}", request),
+            InvalidQueueIndex(index) => write!(f, "invalid queue index: {}", index),
+            InvalidQueueSize(size) => write!(f, "invalid queue size: {}", size),
+            InvalidRingAddress(addr) => {
+                write!(f, "queue address 0x{:x} is not in guest memory", addr)
+            }
+            MemoryMap(ref err) => write!(f, "failed to map guest memory: {}", err),
+            Queue(ref err) => write!(f, "failed to access queue: {}", err),
+            Socket(ref err) => write!(f, "vhost-user socket error: {}", err),
+            UnsupportedRequest(request) => write!(f, "unsupported request {}", request),
+        }
+    }
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// The guest memory of a daemon, which is replaced when the frontend sends a new memory table.
+pub type VringMemory = GuestMemoryAtomic<GuestMemoryMmap>;
+
+/// The device logic of a vhost-user daemon.
+pub trait VhostUserBackend {
+    /// Returns the number of queues of the device.
+    fn num_queues(&self) -> u16;
+
+    /// Returns the maximum size of the queues.
+    fn max_queue_size(&self) -> u16;
+
+    /// Returns the virtio features supported by the device.
+    fn features(&self) -> u64;
+
+    /// Called when the frontend sets the virtio features negotiated with the driver.
+    ///
+    /// # Arguments
+    /// * `features` - The negotiated virtio features.
+    fn set_features(&mut self, _features: u64) {}
+
+    /// Returns the optional protocol features supported by the device, out of
+    /// [`OPTIONAL_PROTOCOL_FEATURES`](constant.OPTIONAL_PROTOCOL_FEATURES.html), as a mask
+    /// of feature bits. The configuration space is only accessible by the frontend when
+    /// `VHOST_USER_PROTOCOL_F_CONFIG` is supported, and devices which support
+    /// `VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD` have to implement
+    /// [`process_queue_inflight`](trait.VhostUserBackend.html#method.process_queue_inflight).
+    fn protocol_features(&self) -> u64 {
+        0
+    }
+
+    /// Reads from the configuration space of the device, on behalf of the frontend.
+    ///
+    /// # Arguments
+    /// * `offset` - The offset of the read range in the configuration space.
+    /// * `data` - The buffer which receives the contents of the range.
+    fn read_config(&self, _offset: usize, _data: &mut [u8]) {}
+
+    /// Writes to the configuration space of the device, on behalf of the frontend.
+    ///
+    /// # Arguments
+    /// * `offset` - The offset of the written range in the configuration space.
+    /// * `data` - The contents of the range.
+    fn write_config(&mut self, _offset: usize, _data: &[u8]) {}
+
+    /// Processes the available buffers of a started and enabled queue, after the queue was
+    /// kicked. Returns whether the driver has to be notified about the used buffers.
+    ///
+    /// The daemon disables the queue notifications around each call, and calls the backend
+    /// again while it consumes buffers and more of them are available once the notifications
+    /// are enabled, so the backend doesn't have to drain the available ring by itself.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the queue.
+    /// * `queue` - The queue, which accesses the guest memory mapped by the daemon.
+    fn process_queue(&mut self, index: u16, queue: &mut Queue<VringMemory>) -> bool;
+
+    /// Processes the available buffers of a queue like
+    /// [`process_queue`](trait.VhostUserBackend.html#method.process_queue), while recording
+    /// the in-flight descriptors, which requires consuming the buffers with
+    /// [`InflightQueue::pop`](struct.InflightQueue.html#method.pop) and
+    /// [`InflightQueue::add_used`](struct.InflightQueue.html#method.add_used). The default
+    /// implementation calls `process_queue`, so nothing is recorded.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the queue.
+    /// * `queue` - The queue, which accesses the guest memory mapped by the daemon.
+    fn process_queue_inflight(&mut self, index: u16, queue: &mut InflightQueue) -> bool {
+        self.process_queue(index, queue.queue_mut())
+    }
+}
+
+// Returns the size of the in-flight descriptors region of a queue.
+fn inflight_region_size(queue_size: u16) -> usize {
+    let size = INFLIGHT_DESC_OFFSET + usize::from(queue_size) * INFLIGHT_DESC_SIZE;
+    (size + INFLIGHT_REGION_ALIGNMENT - 1) & !(INFLIGHT_REGION_ALIGNMENT - 1)
+}
+
+// The state of the in-flight descriptors of a queue, which is kept by the daemon.
+#[derive(Debug, Default)]
+struct InflightState {
+    // The counter of the next popped descriptor chain, which orders the in-flight chains.
+    counter: u64,
+    // The chains which were in flight when the queue was started, and are popped again before
+    // the available ring is processed, from the end.
+    resubmit: Vec<u16>,
+}
+
+// The in-flight descriptors region of a queue, along with the state kept by the daemon.
+#[derive(Debug)]
+struct InflightTracker<'a> {
+    region: VolatileSlice<'a>,
+    state: &'a mut InflightState,
+}
+
+impl InflightTracker<'_> {
+    // The offsets in the region are checked when the queue is started, so the accesses only
+    // fail for descriptor indices which are out of bounds.
+    fn load<T: AtomicAccess>(&self, offset: usize) -> result::Result<T, QueueError> {
+        self.region
+            .load(offset, Ordering::Acquire)
+            .map_err(|_| QueueError::InvalidDescriptorIndex)
+    }
+
+    fn store<T: AtomicAccess>(&self, val: T, offset: usize) -> result::Result<(), QueueError> {
+        self.region
+            .store(val, offset, Ordering::Release)
+            .map_err(|_| QueueError::InvalidDescriptorIndex)
+    }
+
+    fn desc_offset(head_index: u16) -> usize {
+        INFLIGHT_DESC_OFFSET + usize::from(head_index) * INFLIGHT_DESC_SIZE
+    }
+
+    // Rebuilds the state of a queue which is started, and moves its indices past the
+    // in-flight chains, which are resubmitted in the order they were popped.
+    fn recover(&mut self, queue: &mut Queue<VringMemory>) -> result::Result<(), QueueError> {
+        self.state.resubmit.clear();
+        if self.load::<u16>(INFLIGHT_VERSION_OFFSET)? != INFLIGHT_VERSION {
+            // The region was just created by the daemon.
+            self.store(INFLIGHT_VERSION, INFLIGHT_VERSION_OFFSET)?;
+            self.state.counter = 0;
+            return Ok(());
+        }
+
+        let used_idx = queue.used_idx(Ordering::Acquire)?.0;
+        if self.load::<u16>(INFLIGHT_USED_IDX_OFFSET)? != used_idx {
+            // The daemon stopped after updating the used ring, but before clearing the last
+            // used chain.
+            let head = self.load::<u16>(INFLIGHT_LAST_BATCH_HEAD_OFFSET)?;
+            self.store(0u8, Self::desc_offset(head))?;
+            // As in `InflightQueue::add_used`, the stores only have to be ordered for the
+            // daemon which reads the region after a crash (there's no concurrent reader).
+            fence(Ordering::Release);
+            self.store(used_idx, INFLIGHT_USED_IDX_OFFSET)?;
+        }
+
+        let mut inflight = Vec::new();
+        for head in 0..queue.actual_size() {
+            let offset = Self::desc_offset(head);
+            if self.load::<u8>(offset)? != 0 {
+                let counter = self.load::<u64>(offset + INFLIGHT_DESC_COUNTER_OFFSET)?;
+                inflight.push((counter, head));
+            }
+        }
+        // The oldest chain is at the end, so it's popped first.
+        inflight.sort_unstable_by(|a, b| b.cmp(a));
+        self.state.counter = inflight.first().map_or(0, |&(counter, _)| counter + 1);
+        self.state.resubmit = inflight.into_iter().map(|(_, head)| head).collect();
+
+        // The number of in-flight chains is bounded by the queue size.
+        let next_avail = Wrapping(used_idx) + Wrapping(self.state.resubmit.len() as u16);
+        queue.set_next_avail(next_avail.0);
+        queue.set_next_used(used_idx);
+        Ok(())
+    }
+
+    fn set_inflight(&mut self, head_index: u16) -> result::Result<(), QueueError> {
+        let offset = Self::desc_offset(head_index);
+        self.store(self.state.counter, offset + INFLIGHT_DESC_COUNTER_OFFSET)?;
+        self.store(1u8, offset)?;
+        self.state.counter += 1;
+        Ok(())
+    }
+}
+
+/// A queue of a vhost-user daemon, which records the in-flight descriptor chains in the memory
+/// area shared with the frontend, when `VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD` is negotiated
+/// (see [`VhostUserBackend::process_queue_inflight`](trait.VhostUserBackend.html#method.process_queue_inflight)).
+/// The chains are marked as in flight when they are popped, and cleared when they are added to
+/// the used ring.
+#[derive(Debug)]
+pub struct InflightQueue<'a> {
+    queue: &'a mut Queue<VringMemory>,
+    tracker: Option<InflightTracker<'a>>,
+}
+
+impl<'a> InflightQueue<'a> {
+    /// Creates an `InflightQueue` which doesn't record the in-flight descriptor chains (i.e.
+    /// for implementing `process_queue` on top of `process_queue_inflight`).
+    ///
+    /// # Arguments
+    /// * `queue` - The wrapped queue.
+    pub fn new(queue: &'a mut Queue<VringMemory>) -> Self {
+        InflightQueue {
+            queue,
+            tracker: None,
+        }
+    }
+
+    /// Returns whether the in-flight descriptor chains are recorded.
+    pub fn is_tracked(&self) -> bool {
+        self.tracker.is_some()
+    }
+
+    /// Returns a reference to the wrapped queue.
+    pub fn queue(&self) -> &Queue<VringMemory> {
+        self.queue
+    }
+
+    /// Returns a mutable reference to the wrapped queue. The chains consumed directly through
+    /// the queue are not recorded.
+    pub fn queue_mut(&mut self) -> &mut Queue<VringMemory> {
+        self.queue
+    }
+
+    /// Returns the next descriptor chain to process, and marks it as in flight. The chains
+    /// which were in flight when the daemon was restarted are returned first.
+    pub fn pop(&mut self) -> result::Result<Option<DescriptorChain<VringMemory>>, QueueError> {
+        if let Some(head) = self.tracker.as_mut().and_then(|t| t.state.resubmit.pop()) {
+            return self.queue.chain_at(head).map(Some);
+        }
+        let chain = match self.queue.iter()?.next() {
+            Some(chain) => chain,
+            None => return Ok(None),
+        };
+        if let Some(tracker) = self.tracker.as_mut() {
+            tracker.set_inflight(chain.head_index())?;
+        }
+        Ok(Some(chain))
+    }
+
+    /// Puts a descriptor chain into the used ring, and clears its in-flight mark.
+    ///
+    /// # Arguments
+    /// * `head_index` - The index of the head descriptor of the chain.
+    /// * `len` - The number of bytes written to the chain.
+    pub fn add_used(&mut self, head_index: u16, len: u32) -> result::Result<(), QueueError> {
+        if head_index >= self.queue.actual_size() {
+            return Err(QueueError::InvalidDescriptorIndex);
+        }
+        if let Some(tracker) = self.tracker.as_ref() {
+            tracker.store(head_index, INFLIGHT_LAST_BATCH_HEAD_OFFSET)?;
+        }
+        self.queue.add_used(head_index, len)?;
+        if let Some(tracker) = self.tracker.as_ref() {
+            // Only the order of the stores matters to a daemon which takes over the region after
+            // a crash, so release fences are enough. There's no acquire side: the new daemon
+            // reads the region (in `InflightTracker::recover`) after this one exited.
+            fence(Ordering::Release);
+            tracker.store(0u8, InflightTracker::desc_offset(head_index))?;
+            // The chain is cleared before the used index it's accounted in is recorded.
+            fence(Ordering::Release);
+            tracker.store(self.queue.next_used(), INFLIGHT_USED_IDX_OFFSET)?;
+        }
+        Ok(())
+    }
+}
+
+// The in-flight descriptors area of the queues, which is shared with the frontend.
+#[derive(Debug)]
+struct InflightArea {
+    mapping: MmapRegion,
+    num_queues: u16,
+    queue_size: u16,
+}
+
+impl InflightArea {
+    // Maps the area described by `inflight`, which is backed by `file`.
+    fn new(file: File, inflight: &Inflight) -> Result<Self> {
+        let size = usize::from(inflight.num_queues) * inflight_region_size(inflight.queue_size);
+        let mapping = MmapRegion::from_file(FileOffset::new(file, inflight.mmap_offset), size)
+            .map_err(|e| Error::MemoryMap(MmapError::MmapRegion(e)))?;
+        Ok(InflightArea {
+            mapping,
+            num_queues: inflight.num_queues,
+            queue_size: inflight.queue_size,
+        })
+    }
+
+    // Returns the region of a queue, or `None` if the queue doesn't have one.
+    fn queue_region(&self, index: u16) -> Option<VolatileSlice<'_>> {
+        if index >= self.num_queues {
+            return None;
+        }
+        let size = inflight_region_size(self.queue_size);
+        self.mapping.get_slice(usize::from(index) * size, size).ok()
+    }
+}
+
+/// Accepts the connections of vhost-user frontends on a Unix socket, which is removed when
+/// the listener is dropped.
+#[derive(Debug)]
+pub struct VhostUserListener {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl VhostUserListener {
+    /// Creates a new `VhostUserListener`.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the socket.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let listener = UnixListener::bind(&path).map_err(Error::Bind)?;
+        Ok(VhostUserListener { listener, path })
+    }
+
+    /// Waits for a frontend to connect, and returns the connected socket.
+    pub fn accept(&self) -> Result<UnixStream> {
+        self.listener
+            .accept()
+            .map(|(stream, _)| stream)
+            .map_err(Error::Socket)
+    }
+}
+
+impl AsRawFd for VhostUserListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Drop for VhostUserListener {
+    fn drop(&mut self) {
+        // The socket file is left behind when the listener is closed.
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// A request received from the frontend.
+#[derive(Debug)]
+struct Request {
+    header: Header,
+    payload: Vec<u8>,
+    files: Vec<File>,
+}
+
+impl Request {
+    // Returns the `T` object at `offset` in the payload.
+    fn obj<T: ByteValued + Default>(&self, offset: usize) -> Result<T> {
+        let bytes = self
+            .payload
+            .get(offset..offset + size_of::<T>())
+            .ok_or(Error::InvalidRequest(self.header.request))?;
+        let mut obj = T::default();
+        obj.as_mut_slice().copy_from_slice(bytes);
+        Ok(obj)
+    }
+}
+
+// The state of a queue of the daemon.
+#[derive(Debug)]
+struct Vring {
+    queue: Queue<VringMemory>,
+    kick: Option<EventFd>,
+    call: Option<EventFd>,
+    // Whether the queue was enabled by the frontend.
+    enabled: bool,
+    inflight: InflightState,
+}
+
+/// Handles the requests of a vhost-user frontend, and drives a
+/// [`VhostUserBackend`](trait.VhostUserBackend.html).
+///
+/// The queues are started when the frontend sets their kick file descriptor, and stopped by
+/// `VHOST_USER_GET_VRING_BASE`. When `VHOST_USER_F_PROTOCOL_FEATURES` is negotiated, the
+/// queues also have to be enabled with `VHOST_USER_SET_VRING_ENABLE`.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # use virtio_device::vhost_user_backend::{
+/// #     VhostUserBackend, VhostUserDaemon, VhostUserListener, VringMemory,
+/// # };
+/// # use virtio_queue::Queue;
+/// // A device which completes all the buffers without touching them.
+/// struct Sink;
+///
+/// impl VhostUserBackend for Sink {
+///     fn num_queues(&self) -> u16 {
+///         1
+///     }
+///
+///     fn max_queue_size(&self) -> u16 {
+///         256
+///     }
+///
+///     fn features(&self) -> u64 {
+///         1 << 32
+///     }
+///
+///     fn process_queue(&mut self, _index: u16, queue: &mut Queue<VringMemory>) -> bool {
+///         let heads: Vec<u16> = match queue.iter() {
+///             Ok(iter) => iter.map(|chain| chain.head_index()).collect(),
+///             Err(_) => return false,
+///         };
+///         for head in heads {
+///             queue.add_used(head, 0).unwrap();
+///         }
+///         queue.needs_notification().unwrap_or(true)
+///     }
+/// }
+///
+/// let listener = VhostUserListener::new("/tmp/vhost-user-sink.sock").unwrap();
+/// loop {
+///     let stream = listener.accept().unwrap();
+///     let mut daemon = VhostUserDaemon::new(stream, Sink).unwrap();
+///     if let Err(e) = daemon.run() {
+///         eprintln!("frontend error: {}", e);
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct VhostUserDaemon<B> {
+    stream: UnixStream,
+    backend: B,
+    mem: VringMemory,
+    // The guest memory regions, as sent by the frontend, which are used for translating the
+    // frontend addresses of the queues.
+    regions: Vec<MemoryRegion>,
+    vrings: Vec<Vring>,
+    inflight: Option<InflightArea>,
+    epoll: Epoll,
+    acked_features: u64,
+    acked_protocol_features: u64,
+}
+
+impl<B: VhostUserBackend> VhostUserDaemon<B> {
+    /// Creates a new `VhostUserDaemon`.
+    ///
+    /// # Arguments
+    /// * `stream` - The socket connected to the frontend.
+    /// * `backend` - The device logic.
+    pub fn new(stream: UnixStream, backend: B) -> Result<Self> {
+        let mem = GuestMemoryAtomic::new(GuestMemoryMmap::new());
+        let vrings = (0..backend.num_queues())
+            .map(|_| Vring {
+                queue: Queue::new(mem.clone(), backend.max_queue_size()),
+                kick: None,
+                call: None,
+                enabled: false,
+                inflight: InflightState::default(),
+            })
+            .collect();
+
+        let epoll = Epoll::new().map_err(Error::Epoll)?;
+        epoll
+            .ctl(
+                ControlOperation::Add,
+                stream.as_raw_fd(),
+                EpollEvent::new(EventSet::IN, SOCKET_TOKEN),
+            )
+            .map_err(Error::Epoll)?;
+
+        Ok(VhostUserDaemon {
+            stream,
+            backend,
+            mem,
+            regions: Vec::new(),
+            vrings,
+            inflight: None,
+            epoll,
+            acked_features: 0,
+            acked_protocol_features: 0,
+        })
+    }
+
+    /// Returns a reference to the backend.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Returns a mutable reference to the backend.
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    /// Returns the guest memory mapped by the daemon.
+    pub fn memory(&self) -> &VringMemory {
+        &self.mem
+    }
+
+    /// Returns the virtio features acknowledged by the frontend.
+    pub fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    /// Handles the requests and the kicks until the frontend disconnects.
+    pub fn run(&mut self) -> Result<()> {
+        while self.process_events(-1)? {}
+        Ok(())
+    }
+
+    /// Waits for the requests and the kicks, and handles them. Returns `false` once the
+    /// frontend disconnects.
+    ///
+    /// # Arguments
+    /// * `timeout` - The maximum number of milliseconds to wait, or `-1` to wait indefinitely.
+    pub fn process_events(&mut self, timeout: i32) -> Result<bool> {
+        let mut events = [EpollEvent::default(); EPOLL_EVENTS_LEN];
+        let count = match self.epoll.wait(timeout, &mut events) {
+            Ok(count) => count,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => return Ok(true),
+            Err(e) => return Err(Error::Epoll(e)),
+        };
+
+        for event in &events[..count] {
+            match event.data() {
+                SOCKET_TOKEN => {
+                    if !self.handle_request()? {
+                        return Ok(false);
+                    }
+                }
+                // The kick tokens are queue indices, which fit in an `u16`.
+                index => self.handle_kick(index as u16)?,
+            }
+        }
+        Ok(true)
+    }
+
+    // Receives the next request, or returns `None` if the frontend disconnected.
+    fn recv_request(&mut self) -> Result<Option<Request>> {
+        let mut header = Header::default();
+        let mut fds = [-1; MAX_MEMORY_REGIONS];
+        let mut iovecs = [iovec {
+            iov_base: header.as_mut_slice().as_mut_ptr() as *mut libc::c_void,
+            iov_len: size_of::<Header>(),
+        }];
+        // Safe because the iovec points to the header, which can hold arbitrary data.
+        let (len, fd_count) = unsafe { self.stream.recv_with_fds(&mut iovecs, &mut fds) }
+            .map_err(|e| Error::Socket(io::Error::from_raw_os_error(e.errno())))?;
+        let files = fds[..fd_count]
+            .iter()
+            // Safe because the received file descriptors are owned by the daemon.
+            .map(|&fd| unsafe { File::from_raw_fd(fd) })
+            .collect();
+        if len == 0 {
+            return Ok(None);
+        }
+
+        self.stream
+            .read_exact(&mut header.as_mut_slice()[len..])
+            .map_err(Error::Socket)?;
+        if header.size > MAX_REQUEST_SIZE {
+            return Err(Error::InvalidRequest(header.request));
+        }
+        let mut payload = vec![0; header.size as usize];
+        self.stream
+            .read_exact(&mut payload)
+            .map_err(Error::Socket)?;
+
+        Ok(Some(Request {
+            header,
+            payload,
+            files,
+        }))
+    }
+
+    fn send_reply(&mut self, request: u32, payload: &[u8]) -> Result<()> {
+        self.send_reply_with_fds(request, payload, &[])
+    }
+
+    fn send_reply_with_fds(&mut self, request: u32, payload: &[u8], fds: &[RawFd]) -> Result<()> {
+        let header = Header {
+            request,
+            flags: VHOST_USER_VERSION | VHOST_USER_REPLY,
+            // Replies are always small, so the length fits in an `u32`.
+            size: payload.len() as u32,
+        };
+        let mut reply = header.as_slice().to_vec();
+        reply.extend_from_slice(payload);
+
+        if fds.is_empty() {
+            return self.stream.write_all(&reply).map_err(Error::Socket);
+        }
+        let sent = self
+            .stream
+            .send_with_fds(&[&reply[..]], fds)
+            .map_err(|e| Error::Socket(io::Error::from_raw_os_error(e.errno())))?;
+        // The file descriptors are attached to the first chunk, so the rest of the reply can
+        // be sent separately.
+        self.stream.write_all(&reply[sent..]).map_err(Error::Socket)
+    }
+
+    // Handles the next request of the frontend, and returns `false` if it disconnected.
+    fn handle_request(&mut self) -> Result<bool> {
+        let request = match self.recv_request()? {
+            Some(request) => request,
+            None => return Ok(false),
+        };
+        let code = request.header.request;
+        let result = self.execute(&request);
+
+        // The requests which have a reply are not acknowledged. An empty reply is sent when
+        // they fail, so the frontend doesn't wait for it.
+        let has_reply = matches!(
+            code,
+            VHOST_USER_GET_FEATURES
+                | VHOST_USER_GET_PROTOCOL_FEATURES
+                | VHOST_USER_GET_QUEUE_NUM
+                | VHOST_USER_GET_VRING_BASE
+                | VHOST_USER_GET_CONFIG
+                | VHOST_USER_GET_MAX_MEM_SLOTS
+                | VHOST_USER_GET_INFLIGHT_FD
+        );
+        if has_reply && result.is_err() {
+            self.send_reply(code, &[])?;
+        } else if !has_reply
+            && request.header.flags & VHOST_USER_NEED_REPLY != 0
+            && self.acked_protocol_features & (1 << VHOST_USER_PROTOCOL_F_REPLY_ACK) != 0
+        {
+            let ack: u64 = if result.is_ok() { 0 } else { 1 };
+            self.send_reply(code, ack.as_slice())?;
+        }
+        result.map(|_| true)
+    }
+
+    // Executes a request, and sends the reply if it has one.
+    fn execute(&mut self, request: &Request) -> Result<()> {
+        let code = request.header.request;
+        match code {
+            VHOST_USER_SET_OWNER => Ok(()),
+            VHOST_USER_GET_FEATURES => {
+                let features = self.backend.features() | (1 << VHOST_USER_F_PROTOCOL_FEATURES);
+                self.send_reply(code, features.as_slice())
+            }
+            VHOST_USER_SET_FEATURES => self.set_features(request.obj(0)?),
+            VHOST_USER_GET_PROTOCOL_FEATURES => {
+                let protocol_features = self.protocol_features();
+                self.send_reply(code, protocol_features.as_slice())
+            }
+            VHOST_USER_SET_PROTOCOL_FEATURES => {
+                self.acked_protocol_features = request.obj::<u64>(0)? & self.protocol_features();
+                Ok(())
+            }
+            VHOST_USER_GET_QUEUE_NUM => {
+                let num_queues = u64::from(self.backend.num_queues());
+                self.send_reply(code, num_queues.as_slice())
+            }
+            VHOST_USER_SET_MEM_TABLE => self.set_mem_table(request),
+            VHOST_USER_GET_MAX_MEM_SLOTS => self.send_reply(code, MAX_MEM_SLOTS.as_slice()),
+            VHOST_USER_ADD_MEM_REG => self.add_mem_region(request),
+            VHOST_USER_REM_MEM_REG => self.remove_mem_region(request),
+            VHOST_USER_SET_VRING_NUM => {
+                let state: VringState = request.obj(0)?;
+                let max_size = self.backend.max_queue_size();
+                let vring = self.vring(state.index)?;
+                if state.num == 0 || state.num > u32::from(max_size) {
+                    return Err(Error::InvalidQueueSize(state.num));
+                }
+                // The size was checked above, so it fits in an `u16`.
+                vring.queue.size = state.num as u16;
+                Ok(())
+            }
+            VHOST_USER_SET_VRING_ADDR => {
+                let addr: VringAddr = request.obj(0)?;
+                let mut state = self.vring(addr.index)?.queue.state();
+                addr.apply(&mut state, |addr| self.translate(addr))?;
+                self.vring(addr.index)?.queue.set_state(&state);
+                Ok(())
+            }
+            VHOST_USER_SET_VRING_BASE => {
+                let state: VringState = request.obj(0)?;
+                let queue = &mut self.vring(state.index)?.queue;
+                let mut queue_state = queue.state();
+                state.apply_base(&mut queue_state);
+                queue.set_state(&queue_state);
+                Ok(())
+            }
+            VHOST_USER_GET_VRING_BASE => {
+                let index = request.obj::<VringState>(0)?.index;
+                let num = self.stop_vring(index)?;
+                self.send_reply(code, VringState { index, num }.as_slice())
+            }
+            VHOST_USER_SET_VRING_KICK => {
+                let (index, kick) = Self::vring_fd(request)?;
+                // Polling the available ring is not supported.
+                let kick = kick.ok_or(Error::InvalidRequest(code))?;
+                self.start_vring(index, kick)
+            }
+            VHOST_USER_SET_VRING_CALL => {
+                let (index, call) = Self::vring_fd(request)?;
+                self.vring(index)?.call = call;
+                Ok(())
+            }
+            VHOST_USER_SET_VRING_ENABLE => {
+                let state: VringState = request.obj(0)?;
+                let vring = self.vring(state.index)?;
+                vring.enabled = state.num != 0;
+                Self::kick_resubmitted(vring)
+            }
+            VHOST_USER_GET_CONFIG => {
+                let (header, range) = self.config_range(request)?;
+                let mut reply = header.as_slice().to_vec();
+                let mut data = vec![0; range.len()];
+                self.backend.read_config(header.offset as usize, &mut data);
+                reply.extend_from_slice(&data);
+                self.send_reply(code, &reply)
+            }
+            VHOST_USER_SET_CONFIG => {
+                let (header, range) = self.config_range(request)?;
+                self.backend
+                    .write_config(header.offset as usize, &request.payload[range]);
+                Ok(())
+            }
+            VHOST_USER_GET_INFLIGHT_FD => self.get_inflight_fd(request),
+            VHOST_USER_SET_INFLIGHT_FD => self.set_inflight_fd(request),
+            _ => Err(Error::UnsupportedRequest(code)),
+        }
+    }
+
+    // Returns the protocol features supported by the daemon and the device.
+    fn protocol_features(&self) -> u64 {
+        BACKEND_PROTOCOL_FEATURES | (self.backend.protocol_features() & OPTIONAL_PROTOCOL_FEATURES)
+    }
+
+    // Validates a `VHOST_USER_{GET,SET}_CONFIG` request, and returns its header along with the
+    // range of the payload which holds the contents.
+    fn config_range(&self, request: &Request) -> Result<(ConfigHeader, Range<usize>)> {
+        let code = request.header.request;
+        if self.acked_protocol_features & (1 << VHOST_USER_PROTOCOL_F_CONFIG) == 0 {
+            return Err(Error::UnsupportedRequest(code));
+        }
+        let header: ConfigHeader = request.obj(0)?;
+        let start = size_of::<ConfigHeader>();
+        if header.size > MAX_CONFIG_SIZE || request.payload.len() != start + header.size as usize {
+            return Err(Error::InvalidRequest(code));
+        }
+        Ok((header, start..request.payload.len()))
+    }
+
+    fn vring(&mut self, index: u32) -> Result<&mut Vring> {
+        self.vrings
+            .get_mut(index as usize)
+            .ok_or(Error::InvalidQueueIndex(index))
+    }
+
+    // Returns the queue index and the file descriptor of `VHOST_USER_SET_VRING_{KICK,CALL}`.
+    fn vring_fd(request: &Request) -> Result<(u32, Option<EventFd>)> {
+        let value: u64 = request.obj(0)?;
+        let mut files = request.files.iter();
+        let fd = if value & VHOST_USER_VRING_NOFD_MASK == 0 {
+            let file = files
+                .next()
+                .ok_or(Error::InvalidRequest(request.header.request))?;
+            let file = file.try_clone().map_err(Error::Socket)?;
+            // Safe because the received file descriptor is owned by the daemon.
+            Some(unsafe { EventFd::from_raw_fd(file.into_raw_fd()) })
+        } else {
+            None
+        };
+        // The queue index is stored in the low byte.
+        Ok(((value & 0xff) as u32, fd))
+    }
+
+    fn set_features(&mut self, features: u64) -> Result<()> {
+        self.acked_features = features;
+        for vring in self.vrings.iter_mut() {
+            vring
+                .queue
+                .set_event_idx(features & (1 << VIRTIO_F_RING_EVENT_IDX) != 0);
+        }
+        self.backend
+            .set_features(features & !(1 << VHOST_USER_F_PROTOCOL_FEATURES));
+        Ok(())
+    }
+
+    fn set_mem_table(&mut self, request: &Request) -> Result<()> {
+        let code = request.header.request;
+        // The payload starts with the number of regions, followed by 4 bytes of padding.
+        let count = request.obj::<u64>(0)? as usize;
+        if count == 0 || count > MAX_MEMORY_REGIONS || request.files.len() != count {
+            return Err(Error::InvalidRequest(code));
+        }
+
+        let mut regions = Vec::with_capacity(count);
+        let mut ranges = Vec::with_capacity(count);
+        for (i, file) in request.files.iter().enumerate() {
+            let region: MemoryRegion = request.obj(8 + i * size_of::<MemoryRegion>())?;
+            let file = file.try_clone().map_err(Error::Socket)?;
+            ranges.push((
+                GuestAddress(region.guest_phys_addr),
+                region.memory_size as usize,
+                Some(FileOffset::new(file, region.mmap_offset)),
+            ));
+            regions.push(region);
+        }
+        // The regions have to be sorted by guest address.
+        ranges.sort_by_key(|range| range.0);
+
+        let mem = GuestMemoryMmap::from_ranges_with_files(ranges).map_err(Error::MemoryMap)?;
+        // The queues refer to the same `GuestMemoryAtomic`, so they see the new regions.
+        self.mem.lock().unwrap().replace(mem);
+        self.regions = regions;
+        Ok(())
+    }
+
+    fn add_mem_region(&mut self, request: &Request) -> Result<()> {
+        let code = request.header.request;
+        // The region follows 8 bytes of padding.
+        let region: MemoryRegion = request.obj(8)?;
+        if request.files.len() != 1 || self.regions.len() as u64 >= MAX_MEM_SLOTS {
+            return Err(Error::InvalidRequest(code));
+        }
+
+        let file = request.files[0].try_clone().map_err(Error::Socket)?;
+        let mapping = MmapRegion::from_file(
+            FileOffset::new(file, region.mmap_offset),
+            region.memory_size as usize,
+        )
+        .map_err(|e| Error::MemoryMap(MmapError::MmapRegion(e)))?;
+        let guest_region = GuestRegionMmap::new(mapping, GuestAddress(region.guest_phys_addr))
+            .map_err(Error::MemoryMap)?;
+        let mem = self
+            .mem
+            .memory()
+            .insert_region(Arc::new(guest_region))
+            .map_err(Error::MemoryMap)?;
+        self.mem.lock().unwrap().replace(mem);
+        self.regions.push(region);
+        Ok(())
+    }
+
+    fn remove_mem_region(&mut self, request: &Request) -> Result<()> {
+        let region: MemoryRegion = request.obj(8)?;
+        let index = self
+            .regions
+            .iter()
+            .position(|r| {
+                r.guest_phys_addr == region.guest_phys_addr
+                    && r.memory_size == region.memory_size
+                    && r.userspace_addr == region.userspace_addr
+            })
+            .ok_or(Error::InvalidRequest(request.header.request))?;
+
+        let (mem, _) = self
+            .mem
+            .memory()
+            .remove_region(GuestAddress(region.guest_phys_addr), region.memory_size)
+            .map_err(Error::MemoryMap)?;
+        self.mem.lock().unwrap().replace(mem);
+        self.regions.remove(index);
+        Ok(())
+    }
+
+    // Validates a `VHOST_USER_{GET,SET}_INFLIGHT_FD` request, and returns its payload.
+    fn inflight_request(&self, request: &Request) -> Result<Inflight> {
+        let code = request.header.request;
+        if self.acked_protocol_features & (1 << VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD) == 0 {
+            return Err(Error::UnsupportedRequest(code));
+        }
+        let inflight: Inflight = request.obj(0)?;
+        if inflight.num_queues == 0 || inflight.queue_size == 0 {
+            return Err(Error::InvalidRequest(code));
+        }
+        Ok(inflight)
+    }
+
+    // Creates the in-flight descriptors area, and sends it to the frontend.
+    fn get_inflight_fd(&mut self, request: &Request) -> Result<()> {
+        let mut inflight = self.inflight_request(request)?;
+        let size = usize::from(inflight.num_queues) * inflight_region_size(inflight.queue_size);
+
+        // Safe because the name is a valid C string, and the return value is checked.
+        let fd = unsafe {
+            libc::memfd_create(
+                b"vhost-user-inflight\0".as_ptr() as *const libc::c_char,
+                libc::MFD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::Inflight(io::Error::last_os_error()));
+        }
+        // Safe because the file descriptor was just created, and it's not owned by anything
+        // else.
+        let file = unsafe { File::from_raw_fd(fd) };
+        // The file is filled with zeroes, so all the descriptors start out of flight.
+        file.set_len(size as u64).map_err(Error::Inflight)?;
+
+        inflight.mmap_size = size as u64;
+        inflight.mmap_offset = 0;
+        let area = InflightArea::new(file.try_clone().map_err(Error::Inflight)?, &inflight)?;
+        for index in 0..inflight.num_queues {
+            if let Some(region) = area.queue_region(index) {
+                region
+                    .store(
+                        inflight.queue_size,
+                        INFLIGHT_DESC_NUM_OFFSET,
+                        Ordering::Release,
+                    )
+                    .map_err(|_| Error::InvalidRequest(request.header.request))?;
+            }
+        }
+        self.send_reply_with_fds(
+            request.header.request,
+            inflight.as_slice(),
+            &[file.as_raw_fd()],
+        )?;
+        self.inflight = Some(area);
+        Ok(())
+    }
+
+    // Maps the in-flight descriptors area received from the frontend, which is used when the
+    // queues are started.
+    fn set_inflight_fd(&mut self, request: &Request) -> Result<()> {
+        let code = request.header.request;
+        let inflight = self.inflight_request(request)?;
+        let size = usize::from(inflight.num_queues) * inflight_region_size(inflight.queue_size);
+        if request.files.len() != 1 || inflight.mmap_size < size as u64 {
+            return Err(Error::InvalidRequest(code));
+        }
+        let file = request.files[0].try_clone().map_err(Error::Inflight)?;
+        self.inflight = Some(InflightArea::new(file, &inflight)?);
+        Ok(())
+    }
+
+    // Kicks a queue which has resubmitted chains once it's enabled, so they are processed
+    // even if the driver doesn't make new buffers available.
+    fn kick_resubmitted(vring: &Vring) -> Result<()> {
+        if !vring.enabled || vring.inflight.resubmit.is_empty() {
+            return Ok(());
+        }
+        match vring.kick.as_ref() {
+            Some(kick) => kick.write(1).map_err(Error::EventFd),
+            None => Ok(()),
+        }
+    }
+
+    // Translates a frontend virtual address to a guest physical address.
+    fn translate(&self, addr: u64) -> Result<GuestAddress> {
+        self.regions
+            .iter()
+            .find(|r| addr >= r.userspace_addr && addr - r.userspace_addr < r.memory_size)
+            .map(|r| GuestAddress(r.guest_phys_addr + (addr - r.userspace_addr)))
+            .ok_or(Error::InvalidRingAddress(addr))
+    }
+
+    fn start_vring(&mut self, index: u32, kick: EventFd) -> Result<()> {
+        let protocol_features = self.acked_features & (1 << VHOST_USER_F_PROTOCOL_FEATURES) != 0;
+        let epoll = &self.epoll;
+        let vring = self
+            .vrings
+            .get_mut(index as usize)
+            .ok_or(Error::InvalidQueueIndex(index))?;
+
+        if let Some(old) = vring.kick.take() {
+            epoll
+                .ctl(
+                    ControlOperation::Delete,
+                    old.as_raw_fd(),
+                    EpollEvent::default(),
+                )
+                .map_err(Error::Epoll)?;
+        }
+        epoll
+            .ctl(
+                ControlOperation::Add,
+                kick.as_raw_fd(),
+                EpollEvent::new(EventSet::IN, u64::from(index)),
+            )
+            .map_err(Error::Epoll)?;
+        vring.kick = Some(kick);
+        vring.queue.ready = true;
+        // The queues are enabled as soon as they are started, unless the frontend enables them
+        // explicitly.
+        if !protocol_features {
+            vring.enabled = true;
+        }
+
+        // The queue indices are 8 bits wide in `VHOST_USER_SET_VRING_KICK`.
+        let tracker = Self::inflight_tracker(
+            &self.inflight,
+            index as u16,
+            &vring.queue,
+            &mut vring.inflight,
+        );
+        if let Some(mut tracker) = tracker {
+            tracker.recover(&mut vring.queue).map_err(Error::Queue)?;
+        }
+        Self::kick_resubmitted(vring)
+    }
+
+    // Returns the in-flight descriptors tracker of a queue, if the frontend set up the area,
+    // and the region of the queue can hold all its descriptors.
+    fn inflight_tracker<'a>(
+        area: &'a Option<InflightArea>,
+        index: u16,
+        queue: &Queue<VringMemory>,
+        state: &'a mut InflightState,
+    ) -> Option<InflightTracker<'a>> {
+        let area = area.as_ref()?;
+        if !queue.is_valid() || queue.actual_size() > area.queue_size {
+            return None;
+        }
+        let region = area.queue_region(index)?;
+        Some(InflightTracker { region, state })
+    }
+
+    // Stops a queue and returns its next available ring index.
+    fn stop_vring(&mut self, index: u32) -> Result<u32> {
+        let epoll = &self.epoll;
+        let vring = self
+            .vrings
+            .get_mut(index as usize)
+            .ok_or(Error::InvalidQueueIndex(index))?;
+
+        if let Some(kick) = vring.kick.take() {
+            epoll
+                .ctl(
+                    ControlOperation::Delete,
+                    kick.as_raw_fd(),
+                    EpollEvent::default(),
+                )
+                .map_err(Error::Epoll)?;
+        }
+        vring.queue.ready = false;
+        vring.enabled = false;
+        Ok(u32::from(vring.queue.next_avail()))
+    }
+
+    fn handle_kick(&mut self, index: u16) -> Result<()> {
+        let vring = match self.vrings.get_mut(usize::from(index)) {
+            Some(vring) => vring,
+            None => return Ok(()),
+        };
+        if let Some(kick) = vring.kick.as_ref() {
+            // Reading the counter consumes all the kicks since the last read, which are handled
+            // at once. The frontend might have shared a non-blocking `EventFd`, which the other
+            // kicks (i.e. from `kick_resubmitted`) could have drained already.
+            match kick.read() {
+                Err(e) if e.kind() != io::ErrorKind::WouldBlock => return Err(Error::EventFd(e)),
+                _ => {}
+            }
+        }
+        if !vring.enabled {
+            return Ok(());
+        }
+        if !vring.queue.is_valid() {
+            warn!("vhost-user queue {} is kicked, but it's not valid", index);
+            return Ok(());
+        }
+
+        // The driver doesn't kick the queue for the buffers it makes available while they're
+        // processed, so the processing goes on until the available ring is empty after the
+        // notifications are enabled again. It also stops when the backend doesn't consume any
+        // buffers (i.e. when it's throttled), since the backend is kicked again later on.
+        let mut notify = false;
+        loop {
+            vring.queue.disable_notification().map_err(Error::Queue)?;
+            let next_avail = vring.queue.next_avail();
+            let tracker =
+                Self::inflight_tracker(&self.inflight, index, &vring.queue, &mut vring.inflight);
+            let mut queue = InflightQueue {
+                queue: &mut vring.queue,
+                tracker,
+            };
+            notify |= self.backend.process_queue_inflight(index, &mut queue);
+            let progress = vring.queue.next_avail() != next_avail;
+            if !vring.queue.enable_notification().map_err(Error::Queue)? || !progress {
+                break;
+            }
+        }
+        if notify {
+            if let Some(call) = vring.call.as_ref() {
+                call.write(1).map_err(Error::EventFd)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<B> AsRawFd for VhostUserDaemon<B> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::FileExt;
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+
+    use vm_memory::{Address, Bytes, GuestMemory};
+    use vmm_sys_util::tempdir::TempDir;
+    use vmm_sys_util::tempfile::TempFile;
+
+    use crate::vhost_user::{self, VhostUserFrontend};
+
+    // A device which completes the buffers, and records the kicks. The configuration space is
+    // accessible by the frontend when it's not empty. When `budget` is set, each call completes
+    // at most that many buffers.
+    #[derive(Debug, Default)]
+    struct TestBackend {
+        features: u64,
+        kicks: Vec<u16>,
+        heads: Vec<u16>,
+        config: Vec<u8>,
+        budget: Option<usize>,
+    }
+
+    impl VhostUserBackend for TestBackend {
+        fn num_queues(&self) -> u16 {
+            2
+        }
+
+        fn max_queue_size(&self) -> u16 {
+            16
+        }
+
+        fn features(&self) -> u64 {
+            (1 << 32) | (1 << 5)
+        }
+
+        fn set_features(&mut self, features: u64) {
+            self.features = features;
+        }
+
+        fn protocol_features(&self) -> u64 {
+            if self.config.is_empty() {
+                return 0;
+            }
+            // The unsupported features are ignored.
+            (1 << VHOST_USER_PROTOCOL_F_CONFIG) | (1 << 20)
+        }
+
+        fn read_config(&self, offset: usize, data: &mut [u8]) {
+            data.copy_from_slice(&self.config[offset..offset + data.len()]);
+        }
+
+        fn write_config(&mut self, offset: usize, data: &[u8]) {
+            self.config[offset..offset + data.len()].copy_from_slice(data);
+        }
+
+        fn process_queue(&mut self, index: u16, queue: &mut Queue<VringMemory>) -> bool {
+            self.kicks.push(index);
+            let budget = self.budget.unwrap_or(usize::MAX);
+            let heads: Vec<u16> = queue
+                .iter()
+                .unwrap()
+                .take(budget)
+                .map(|c| c.head_index())
+                .collect();
+            for &head in heads.iter() {
+                queue.add_used(head, 0x10).unwrap();
+            }
+            self.heads.extend(heads);
+            queue.needs_notification().unwrap()
+        }
+    }
+
+    // A device which records the in-flight descriptors, and only completes the first `limit`
+    // chains, leaving the rest in flight.
+    #[derive(Debug, Default)]
+    struct InflightBackend {
+        limit: usize,
+        heads: Vec<u16>,
+    }
+
+    impl VhostUserBackend for InflightBackend {
+        fn num_queues(&self) -> u16 {
+            1
+        }
+
+        fn max_queue_size(&self) -> u16 {
+            16
+        }
+
+        fn features(&self) -> u64 {
+            1 << 32
+        }
+
+        fn protocol_features(&self) -> u64 {
+            1 << VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD
+        }
+
+        fn process_queue(&mut self, index: u16, queue: &mut Queue<VringMemory>) -> bool {
+            self.process_queue_inflight(index, &mut InflightQueue::new(queue))
+        }
+
+        fn process_queue_inflight(&mut self, _index: u16, queue: &mut InflightQueue) -> bool {
+            assert!(queue.is_tracked());
+            while let Some(chain) = queue.pop().unwrap() {
+                self.heads.push(chain.head_index());
+                if self.heads.len() <= self.limit {
+                    queue.add_used(chain.head_index(), 0x10).unwrap();
+                }
+            }
+            true
+        }
+    }
+
+    type Mem = Arc<GuestMemoryMmap>;
+
+    fn shared_mem() -> Mem {
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x2_0000).unwrap();
+        Arc::new(
+            GuestMemoryMmap::from_ranges_with_files(&[
+                (
+                    GuestAddress(0),
+                    0x1_0000,
+                    Some(FileOffset::new(file.try_clone().unwrap(), 0)),
+                ),
+                (
+                    GuestAddress(0x10_0000),
+                    0x1_0000,
+                    Some(FileOffset::new(file, 0x1_0000)),
+                ),
+            ])
+            .unwrap(),
+        )
+    }
+
+    fn spawn_daemon<B: VhostUserBackend + Send + 'static>(
+        stream: UnixStream,
+        backend: B,
+    ) -> JoinHandle<Result<B>> {
+        thread::spawn(move || {
+            let mut daemon = VhostUserDaemon::new(stream, backend)?;
+            daemon.run()?;
+            Ok(daemon.backend)
+        })
+    }
+
+    // Sets up the queues in guest memory, with the descriptor tables in the first region and
+    // the used rings in the second one.
+    fn queues(mem: &Mem) -> Vec<Queue<Mem>> {
+        (0..2)
+            .map(|i| {
+                let mut queue = Queue::new(mem.clone(), 16);
+                let base = GuestAddress(i * 0x1000);
+                queue.size = 8;
+                queue.desc_table = base;
+                queue.avail_ring = base.unchecked_add(0x100);
+                queue.used_ring = GuestAddress(0x10_0000).unchecked_add(i * 0x1000);
+                queue.ready = true;
+                queue
+            })
+            .collect()
+    }
+
+    // Makes the descriptor `head` available in the queue, as the `idx`th buffer.
+    fn add_avail(mem: &Mem, queue: &Queue<Mem>, head: u16, idx: u16) {
+        let desc = queue.desc_table.unchecked_add(u64::from(head) * 16);
+        mem.write_obj(0x8000u64, desc).unwrap();
+        mem.write_obj(0x100u32, desc.unchecked_add(8)).unwrap();
+        let slot = u64::from(idx % queue.size);
+        mem.write_obj(head, queue.avail_ring.unchecked_add(4 + slot * 2))
+            .unwrap();
+        mem.write_obj(idx + 1, queue.avail_ring.unchecked_add(2))
+            .unwrap();
+    }
+
+    fn eventfds(count: usize) -> Vec<EventFd> {
+        // The call eventfds are blocking, so the test can wait for the used buffers.
+        (0..count).map(|_| EventFd::new(0).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_daemon() {
+        let mem = shared_mem();
+        let (stream, daemon_stream) = UnixStream::pair().unwrap();
+        let handle = spawn_daemon(daemon_stream, TestBackend::default());
+
+        let mut frontend = VhostUserFrontend::new(stream).unwrap();
+        assert_eq!(frontend.features(), (1 << 32) | (1 << 5));
+        assert_eq!(frontend.protocol_features(), BACKEND_PROTOCOL_FEATURES);
+        frontend.check_queue_num(2).unwrap();
+
+        let mut queues = queues(&mem);
+        queues[1].set_next_avail(3);
+        let (kick_evts, call_evts) = (eventfds(2), eventfds(2));
+        frontend
+            .start(&*mem, 1 << 32, &queues, &kick_evts, &call_evts)
+            .unwrap();
+
+        add_avail(&mem, &queues[0], 5, 0);
+        kick_evts[0].write(1).unwrap();
+        assert_eq!(call_evts[0].read().unwrap(), 1);
+        let used_ring = queues[0].used_ring;
+        assert_eq!(mem.read_obj::<u16>(used_ring.unchecked_add(2)).unwrap(), 1);
+        assert_eq!(mem.read_obj::<u32>(used_ring.unchecked_add(4)).unwrap(), 5);
+        assert_eq!(
+            mem.read_obj::<u32>(used_ring.unchecked_add(8)).unwrap(),
+            0x10
+        );
+
+        // The second queue continues from the index set by the frontend.
+        add_avail(&mem, &queues[1], 2, 3);
+        kick_evts[1].write(1).unwrap();
+        assert_eq!(call_evts[1].read().unwrap(), 1);
+        let used_ring = queues[1].used_ring;
+        assert_eq!(mem.read_obj::<u16>(used_ring.unchecked_add(2)).unwrap(), 4);
+        assert_eq!(
+            mem.read_obj::<u32>(used_ring.unchecked_add(4 + 3 * 8))
+                .unwrap(),
+            2
+        );
+
+        assert_eq!(frontend.stop(2).unwrap(), [1, 4]);
+        // The stopped queues are not processed anymore.
+        add_avail(&mem, &queues[0], 6, 1);
+        kick_evts[0].write(1).unwrap();
+        drop(frontend);
+
+        let backend = handle.join().unwrap().unwrap();
+        assert_eq!(backend.features, 1 << 32);
+        assert_eq!(backend.kicks, [0, 1]);
+        assert_eq!(backend.heads, [5, 2]);
+    }
+
+    #[test]
+    fn test_kick_drain() {
+        let mem = shared_mem();
+        let (stream, daemon_stream) = UnixStream::pair().unwrap();
+        let backend = TestBackend {
+            budget: Some(1),
+            ..Default::default()
+        };
+        let handle = spawn_daemon(daemon_stream, backend);
+
+        let mut frontend = VhostUserFrontend::new(stream).unwrap();
+        let queues = queues(&mem);
+        let (kick_evts, call_evts) = (eventfds(2), eventfds(2));
+        frontend
+            .start(&*mem, 1 << 32, &queues, &kick_evts, &call_evts)
+            .unwrap();
+
+        // A single kick for all the buffers, which the backend completes one at a time.
+        for (idx, head) in [1, 3, 5].iter().enumerate() {
+            add_avail(&mem, &queues[0], *head, idx as u16);
+        }
+        kick_evts[0].write(1).unwrap();
+        assert_eq!(call_evts[0].read().unwrap(), 1);
+        let used_ring = queues[0].used_ring;
+        assert_eq!(mem.read_obj::<u16>(used_ring.unchecked_add(2)).unwrap(), 3);
+        drop(frontend);
+
+        let backend = handle.join().unwrap().unwrap();
+        // The processing stops once the available ring is empty.
+        assert_eq!(backend.kicks, [0; 3]);
+        assert_eq!(backend.heads, [1, 3, 5]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mem = shared_mem();
+
+        // Requests for queues which don't exist are rejected.
+        let (stream, daemon_stream) = UnixStream::pair().unwrap();
+        let handle = spawn_daemon(daemon_stream, TestBackend::default());
+        let mut frontend = VhostUserFrontend::new(stream).unwrap();
+        assert!(matches!(
+            frontend.check_queue_num(3),
+            Err(vhost_user::Error::TooManyQueues(3))
+        ));
+        frontend.set_features(1 << 32).unwrap();
+        assert!(matches!(
+            frontend.set_vring_enable(2, true),
+            Err(vhost_user::Error::BackendFailure(
+                VHOST_USER_SET_VRING_ENABLE
+            ))
+        ));
+        assert!(matches!(
+            handle.join().unwrap(),
+            Err(Error::InvalidQueueIndex(2))
+        ));
+
+        // The queues have to be within the guest memory regions.
+        let (stream, daemon_stream) = UnixStream::pair().unwrap();
+        let handle = spawn_daemon(daemon_stream, TestBackend::default());
+        let mut frontend = VhostUserFrontend::new(stream).unwrap();
+        frontend.set_mem_table(&*mem).unwrap();
+        let private = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1_0000)]).unwrap();
+        let (kick_evts, call_evts) = (eventfds(1), eventfds(1));
+        let queue = Queue::new(Arc::new(private.clone()), 16);
+        assert!(frontend
+            .set_vring(&private, 0, &queue, &kick_evts[0], &call_evts[0])
+            .is_err());
+        let addr = private.get_host_address(GuestAddress(0)).unwrap() as u64;
+        match handle.join().unwrap() {
+            Err(Error::InvalidRingAddress(a)) => assert_eq!(a, addr),
+            _ => panic!("unexpected result"),
+        }
+
+        // The queue sizes are limited by the backend.
+        let (stream, daemon_stream) = UnixStream::pair().unwrap();
+        let handle = spawn_daemon(daemon_stream, TestBackend::default());
+        let mut frontend = VhostUserFrontend::new(stream).unwrap();
+        let mut queue = Queue::new(mem.clone(), 32);
+        queue.size = 32;
+        assert!(frontend
+            .set_vring(&*mem, 0, &queue, &kick_evts[0], &call_evts[0])
+            .is_err());
+        assert!(matches!(
+            handle.join().unwrap(),
+            Err(Error::InvalidQueueSize(32))
+        ));
+    }
+
+    #[test]
+    fn test_mem_regions() {
+        let mem = shared_mem();
+        // The guest memory without the region of the used rings, which shares the mapping of
+        // the first region.
+        let (partial, _) = mem
+            .remove_region(GuestAddress(0x10_0000), 0x1_0000)
+            .unwrap();
+        let (stream, daemon_stream) = UnixStream::pair().unwrap();
+        let handle = spawn_daemon(daemon_stream, TestBackend::default());
+
+        let mut frontend = VhostUserFrontend::new(stream).unwrap();
+        assert_eq!(frontend.get_max_mem_slots().unwrap(), MAX_MEM_SLOTS);
+        frontend.set_features(1 << 32).unwrap();
+        frontend.set_mem_table(&partial).unwrap();
+        // The region is plugged.
+        frontend.update_mem_table(&partial, &*mem).unwrap();
+
+        let queues = queues(&mem);
+        let (kick_evts, call_evts) = (eventfds(1), eventfds(1));
+        frontend
+            .set_vring(&*mem, 0, &queues[0], &kick_evts[0], &call_evts[0])
+            .unwrap();
+        frontend.set_vring_enable(0, true).unwrap();
+        add_avail(&mem, &queues[0], 1, 0);
+        kick_evts[0].write(1).unwrap();
+        assert_eq!(call_evts[0].read().unwrap(), 1);
+        let used_ring = queues[0].used_ring;
+        assert_eq!(mem.read_obj::<u16>(used_ring.unchecked_add(2)).unwrap(), 1);
+
+        // The region is unplugged, so the used ring is not accessible anymore.
+        frontend.update_mem_table(&*mem, &partial).unwrap();
+        let addr = mem.get_host_address(used_ring).unwrap() as u64;
+        assert!(frontend
+            .set_vring(&*mem, 1, &queues[1], &kick_evts[0], &call_evts[0])
+            .is_err());
+        match handle.join().unwrap() {
+            Err(Error::InvalidRingAddress(a)) => assert_eq!(a, addr + 0x1000),
+            _ => panic!("unexpected result"),
+        }
+    }
+
+    #[test]
+    fn test_config() {
+        let backend = TestBackend {
+            config: vec![1, 2, 3, 4],
+            ..Default::default()
+        };
+        let (stream, daemon_stream) = UnixStream::pair().unwrap();
+        let handle = spawn_daemon(daemon_stream, backend);
+
+        let mut frontend = VhostUserFrontend::new(stream).unwrap();
+        assert_eq!(
+            frontend.protocol_features(),
+            BACKEND_PROTOCOL_FEATURES | (1 << VHOST_USER_PROTOCOL_F_CONFIG)
+        );
+        assert_eq!(frontend.get_queue_num().unwrap(), 2);
+        assert_eq!(frontend.get_config(4).unwrap(), [1, 2, 3, 4]);
+        frontend.set_config(1, &[5, 6]).unwrap();
+        assert_eq!(frontend.get_config(4).unwrap(), [1, 5, 6, 4]);
+        // The daemon replies without contents when the access is too large.
+        assert!(matches!(
+            frontend.get_config(MAX_CONFIG_SIZE as usize + 1),
+            Err(vhost_user::Error::InvalidReply(VHOST_USER_GET_CONFIG))
+        ));
+        assert!(matches!(
+            handle.join().unwrap(),
+            Err(Error::InvalidRequest(VHOST_USER_GET_CONFIG))
+        ));
+
+        // The configuration space is not accessible when the device doesn't opt in.
+        let (stream, daemon_stream) = UnixStream::pair().unwrap();
+        let handle = spawn_daemon(daemon_stream, TestBackend::default());
+        let mut frontend = VhostUserFrontend::new(stream).unwrap();
+        assert_eq!(frontend.protocol_features(), BACKEND_PROTOCOL_FEATURES);
+        assert!(matches!(
+            frontend.get_config(4),
+            Err(vhost_user::Error::MissingProtocolFeature(
+                VHOST_USER_PROTOCOL_F_CONFIG
+            ))
+        ));
+        drop(frontend);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_inflight() {
+        let mem = shared_mem();
+        let queues = queues(&mem);
+        let used_ring = queues[0].used_ring;
+        let (kick_evts, call_evts) = (eventfds(1), eventfds(1));
+
+        let (stream, daemon_stream) = UnixStream::pair().unwrap();
+        let backend = InflightBackend {
+            limit: 1,
+            ..Default::default()
+        };
+        let handle = spawn_daemon(daemon_stream, backend);
+        let mut frontend = VhostUserFrontend::new(stream).unwrap();
+        assert_eq!(
+            frontend.protocol_features(),
+            BACKEND_PROTOCOL_FEATURES | (1 << VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD)
+        );
+        let (inflight, file) = frontend.get_inflight_fd(1, 16).unwrap();
+        assert_eq!(inflight.num_queues, 1);
+        assert_eq!(inflight.queue_size, 16);
+        assert_eq!(inflight.mmap_size, 320);
+        assert_eq!(file.metadata().unwrap().len(), 320);
+        frontend
+            .start(&*mem, 1 << 32, &queues[..1], &kick_evts, &call_evts)
+            .unwrap();
+        for (idx, &head) in [5, 6, 7].iter().enumerate() {
+            add_avail(&mem, &queues[0], head, idx as u16);
+        }
+        kick_evts[0].write(1).unwrap();
+        assert_eq!(call_evts[0].read().unwrap(), 1);
+        assert_eq!(mem.read_obj::<u16>(used_ring.unchecked_add(2)).unwrap(), 1);
+        drop(frontend);
+        assert_eq!(handle.join().unwrap().unwrap().heads, [5, 6, 7]);
+
+        // The daemon stopped right after adding the second chain to the used ring.
+        mem.write_obj(6u32, used_ring.unchecked_add(4 + 8)).unwrap();
+        mem.write_obj(2u16, used_ring.unchecked_add(2)).unwrap();
+        file.write_at(&6u16.to_le_bytes(), INFLIGHT_LAST_BATCH_HEAD_OFFSET as u64)
+            .unwrap();
+
+        // The new daemon resubmits the last chain, without waiting for a kick.
+        let (stream, daemon_stream) = UnixStream::pair().unwrap();
+        let backend = InflightBackend {
+            limit: usize::MAX,
+            ..Default::default()
+        };
+        let handle = spawn_daemon(daemon_stream, backend);
+        let mut frontend = VhostUserFrontend::new(stream).unwrap();
+        frontend.set_inflight_fd(&inflight, &file).unwrap();
+        frontend
+            .start(&*mem, 1 << 32, &queues[..1], &kick_evts, &call_evts)
+            .unwrap();
+        assert_eq!(call_evts[0].read().unwrap(), 1);
+        assert_eq!(mem.read_obj::<u16>(used_ring.unchecked_add(2)).unwrap(), 3);
+        assert_eq!(
+            mem.read_obj::<u32>(used_ring.unchecked_add(4 + 2 * 8))
+                .unwrap(),
+            7
+        );
+
+        // The new buffers follow the resubmitted ones.
+        add_avail(&mem, &queues[0], 2, 3);
+        kick_evts[0].write(1).unwrap();
+        assert_eq!(call_evts[0].read().unwrap(), 1);
+        assert_eq!(mem.read_obj::<u16>(used_ring.unchecked_add(2)).unwrap(), 4);
+        assert_eq!(frontend.stop(1).unwrap(), [4]);
+        drop(frontend);
+        assert_eq!(handle.join().unwrap().unwrap().heads, [7, 2]);
+
+        // The area is only available when the device opts in.
+        let (stream, daemon_stream) = UnixStream::pair().unwrap();
+        let handle = spawn_daemon(daemon_stream, TestBackend::default());
+        let mut frontend = VhostUserFrontend::new(stream).unwrap();
+        assert!(matches!(
+            frontend.get_inflight_fd(1, 16),
+            Err(vhost_user::Error::MissingProtocolFeature(
+                VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD
+            ))
+        ));
+        drop(frontend);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let mem = shared_mem();
+        let queues = queues(&mem);
+        let used_ring = queues[0].used_ring;
+        let (kick_evts, call_evts) = (eventfds(1), eventfds(1));
+
+        let (stream, daemon_stream) = UnixStream::pair().unwrap();
+        // Shutting down the socket of the daemon stops it, like a crash would.
+        let crash = daemon_stream.try_clone().unwrap();
+        let handle = spawn_daemon(daemon_stream, TestBackend::default());
+        let mut frontend = VhostUserFrontend::new(stream).unwrap();
+        frontend
+            .start(&*mem, 1 << 32, &queues[..1], &kick_evts, &call_evts)
+            .unwrap();
+        add_avail(&mem, &queues[0], 5, 0);
+        kick_evts[0].write(1).unwrap();
+        assert_eq!(call_evts[0].read().unwrap(), 1);
+        assert!(!frontend.is_disconnected());
+
+        crash.shutdown(std::net::Shutdown::Both).unwrap();
+        assert_eq!(handle.join().unwrap().unwrap().heads, [5]);
+        assert!(frontend.is_disconnected());
+        // The driver keeps making buffers available while the backend is gone.
+        add_avail(&mem, &queues[0], 6, 1);
+        kick_evts[0].write(1).unwrap();
+
+        // The new backend has to support the features exposed to the driver.
+        let (stream, daemon_stream) = UnixStream::pair().unwrap();
+        let handle = spawn_daemon(daemon_stream, InflightBackend::default());
+        assert!(matches!(
+            frontend.reconnect(stream),
+            Err(vhost_user::Error::IncompatibleBackend)
+        ));
+        handle.join().unwrap().unwrap();
+        assert!(frontend.is_disconnected());
+
+        // The queue continues after the last used buffer.
+        let (stream, daemon_stream) = UnixStream::pair().unwrap();
+        let handle = spawn_daemon(daemon_stream, TestBackend::default());
+        frontend.reconnect(stream).unwrap();
+        assert!(!frontend.is_disconnected());
+        frontend
+            .resume(&*mem, 1 << 32, &queues[..1], &kick_evts, &call_evts)
+            .unwrap();
+        assert_eq!(call_evts[0].read().unwrap(), 1);
+        assert_eq!(mem.read_obj::<u16>(used_ring.unchecked_add(2)).unwrap(), 2);
+        assert_eq!(
+            mem.read_obj::<u32>(used_ring.unchecked_add(4 + 8)).unwrap(),
+            6
+        );
+        assert_eq!(frontend.stop(1).unwrap(), [2]);
+        drop(frontend);
+        assert_eq!(handle.join().unwrap().unwrap().heads, [6]);
+    }
+
+    #[test]
+    fn test_listener() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("vhost-user.sock");
+
+        let listener = VhostUserListener::new(&path).unwrap();
+        assert!(matches!(VhostUserListener::new(&path), Err(Error::Bind(_))));
+        let frontend_path = path.clone();
+        let handle = thread::spawn(move || {
+            let frontend = VhostUserFrontend::connect(frontend_path).unwrap();
+            frontend.features()
+        });
+        let stream = listener.accept().unwrap();
+        let mut daemon = VhostUserDaemon::new(stream, TestBackend::default()).unwrap();
+        daemon.run().unwrap();
+        assert_eq!(handle.join().unwrap(), (1 << 32) | (1 << 5));
+        assert_eq!(daemon.acked_features(), 0);
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+}
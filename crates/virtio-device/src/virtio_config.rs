@@ -2,18 +2,39 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
-use std::borrow::BorrowMut;
-use std::cmp;
-use std::result;
-use std::sync::atomic::AtomicU8;
-use std::sync::Arc;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::borrow::BorrowMut;
+use core::cmp;
+use core::result;
+use core::sync::atomic::{AtomicU8, Ordering};
 
 use log::error;
 use vm_memory::GuestAddressSpace;
 
-use crate::{VirtioDevice, WithDriverSelect};
+use crate::{SharedMemoryRegion, VirtioDevice, WithDriverSelect};
 use virtio_queue::Queue;
 
+/// Interrupt status bit which reports that the device used buffers of at least one of its
+/// queues.
+pub const VIRTIO_MMIO_INT_VRING: u8 = 0x01;
+/// Interrupt status bit which reports that the configuration space of the device changed.
+pub const VIRTIO_MMIO_INT_CONFIG: u8 = 0x02;
+
+/// Sets `bits` in the interrupt status of a device, once the used rings or the configuration
+/// space they report on are updated (see `VirtioConfig::signal_used_queue`, for devices which
+/// only keep a reference to the interrupt status).
+///
+/// # Arguments
+/// * `interrupt_status` - The interrupt status of the device.
+/// * `bits` - The bits to set (`VIRTIO_MMIO_INT_VRING` and/or `VIRTIO_MMIO_INT_CONFIG`).
+pub fn set_interrupt_status(interrupt_status: &AtomicU8, bits: u8) {
+    // `Release` pairs with the `Acquire` load in `VirtioMmioDevice::read`, so a driver which
+    // sees a bit also sees the used ring or configuration space updates it reports. Setting the
+    // bits with an RMW doesn't lose the ones which are cleared concurrently by the driver.
+    interrupt_status.fetch_or(bits, Ordering::Release);
+}
+
 /// An object that provides a common virtio device configuration representation. It is not part
 /// of the main `vm-virtio` set of interfaces, but rather can be used as a helper object in
 /// conjunction with the `WithVirtioConfig` trait (provided in the same module), to enable the
@@ -44,7 +65,15 @@ pub struct VirtioConfig<M: GuestAddressSpace> {
     /// Represents whether the device has been activated or not.
     pub device_activated: bool,
     /// Device interrupt status.
+    ///
+    /// The devices update it with `signal_used_queue`, `signal_config_change` and
+    /// `reset_interrupt_status` (or `set_interrupt_status`), which use the orderings the
+    /// driver side relies on.
     pub interrupt_status: Arc<AtomicU8>,
+    /// Identifier of the shared memory region currently selected by the driver.
+    pub shm_select: u32,
+    /// Shared memory regions of the device.
+    pub shm_regions: Vec<SharedMemoryRegion>,
 }
 
 impl<M: GuestAddressSpace> VirtioConfig<M> {
@@ -62,6 +91,8 @@ impl<M: GuestAddressSpace> VirtioConfig<M> {
             config_space,
             device_activated: false,
             interrupt_status: Arc::new(AtomicU8::new(0)),
+            shm_select: 0,
+            shm_regions: Vec::new(),
         }
     }
 
@@ -72,6 +103,25 @@ impl<M: GuestAddressSpace> VirtioConfig<M> {
     pub fn queues_valid(&self) -> bool {
         self.queues.iter().all(Queue::is_valid)
     }
+
+    /// Reports that the device used buffers of its queues through the interrupt status. The
+    /// driver still has to be notified (i.e. with an interrupt).
+    pub fn signal_used_queue(&self) {
+        set_interrupt_status(&self.interrupt_status, VIRTIO_MMIO_INT_VRING);
+    }
+
+    /// Reports that the configuration space changed through the interrupt status. The driver
+    /// still has to be notified (i.e. with an interrupt).
+    pub fn signal_config_change(&self) {
+        set_interrupt_status(&self.interrupt_status, VIRTIO_MMIO_INT_CONFIG);
+    }
+
+    /// Clears the interrupt status when the device is reset.
+    pub fn reset_interrupt_status(&self) {
+        // Clearing the bits doesn't publish anything for the `Acquire` load in
+        // `VirtioMmioDevice::read`, so `Relaxed` is enough.
+        self.interrupt_status.store(0, Ordering::Relaxed);
+    }
 }
 
 /// Helper trait that can be implemented for objects which represent virtio devices. Together
@@ -195,6 +245,10 @@ where
         // Cannot fail because the lengths are identical and we do bounds checking beforehand.
         config_space[offset..end].copy_from_slice(&data[..write_len]);
     }
+
+    fn shm_regions(&self) -> &[SharedMemoryRegion] {
+        &self.borrow().shm_regions
+    }
 }
 
 impl<M, T> WithDriverSelect<M> for T
@@ -226,6 +280,14 @@ where
     fn set_driver_features_select(&mut self, value: u32) {
         self.borrow_mut().driver_features_select = value;
     }
+
+    fn shm_select(&self) -> u32 {
+        self.borrow().shm_select
+    }
+
+    fn set_shm_select(&mut self, value: u32) {
+        self.borrow_mut().shm_select = value;
+    }
 }
 
 #[cfg(test)]
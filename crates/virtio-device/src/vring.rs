@@ -0,0 +1,270 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! The queue configuration structures shared by the in-kernel vhost ioctls and the vhost-user
+//! protocol, together with the conversions from and to a [`QueueState`].
+//!
+//! A queue is handed over to a vhost backend with its size, the addresses of the descriptor
+//! table and the rings, and the base, which is the available ring index the backend continues
+//! from. The conversions follow the same rules on both sides:
+//!
+//! - the frontend sends the host virtual addresses of the rings, and the backend translates
+//!   them back to guest physical addresses;
+//! - the frontend sends the next available ring index of the queue as the base, and the backend
+//!   considers all the buffers made available before the base as used, so both its ring indices
+//!   start from the base;
+//! - when the backend stopped without returning the base (i.e. it crashed), the frontend
+//!   recovers it from the index of the used ring, since the buffers made available after it
+//!   were not completed.
+//!
+//! [`QueueState`]: ../../virtio_queue/struct.QueueState.html
+
+use std::convert::TryFrom;
+use std::result;
+use std::sync::atomic::Ordering;
+
+use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError};
+
+use virtio_queue::QueueState;
+
+/// The bit of the `VringAddr` flags which makes the backend log the writes to the used ring
+/// (i.e. during live migration).
+pub const VHOST_VRING_F_LOG: u32 = 0;
+
+/// The `struct vhost_vring_state` payload, which configures a queue with a single value.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct VringState {
+    /// The index of the queue.
+    pub index: u32,
+    /// The value (i.e. the size or the next available ring index).
+    pub num: u32,
+}
+
+// Safe because VringState contains only plain data.
+unsafe impl ByteValued for VringState {}
+
+impl VringState {
+    /// Returns the size of a queue, as sent with `VHOST_SET_VRING_NUM`.
+    ///
+    /// # Arguments
+    /// * `index` - The index of the queue.
+    /// * `state` - The state of the queue.
+    pub fn size(index: u16, state: &QueueState) -> Self {
+        VringState {
+            index: u32::from(index),
+            num: u32::from(state.actual_size()),
+        }
+    }
+
+    /// Returns the base of a queue which stopped without returning it, recovered from the index
+    /// of its used ring.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    /// * `index` - The index of the queue.
+    /// * `state` - The state of the queue.
+    pub fn from_used_ring<G: GuestMemory>(
+        mem: &G,
+        index: u16,
+        state: &QueueState,
+    ) -> result::Result<Self, GuestMemoryError> {
+        // The `idx` field follows the `flags` of the used ring.
+        let used_idx: u16 = mem.load(state.used_ring.unchecked_add(2), Ordering::Acquire)?;
+        Ok(VringState {
+            index: u32::from(index),
+            num: u32::from(used_idx),
+        })
+    }
+
+    /// Returns the base as a ring index, since the ring indices are 16 bits wide.
+    pub fn base(&self) -> u16 {
+        self.num as u16
+    }
+
+    /// Makes a queue continue from the base. All the buffers made available before the base
+    /// are already used, so the used ring index starts from the same value.
+    ///
+    /// # Arguments
+    /// * `state` - The state of the queue.
+    pub fn apply_base(&self, state: &mut QueueState) {
+        state.next_avail = self.base();
+        state.next_used = self.base();
+    }
+}
+
+impl From<(u16, &QueueState)> for VringState {
+    // Returns the base of a queue, as sent with `VHOST_SET_VRING_BASE`.
+    fn from((index, state): (u16, &QueueState)) -> Self {
+        VringState {
+            index: u32::from(index),
+            num: u32::from(state.next_avail),
+        }
+    }
+}
+
+/// The `struct vhost_vring_addr` payload, which holds the host virtual addresses of a queue.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct VringAddr {
+    /// The index of the queue.
+    pub index: u32,
+    /// The queue flags (i.e. whether used ring writes are logged).
+    pub flags: u32,
+    /// The address of the descriptor table.
+    pub desc: u64,
+    /// The address of the used ring.
+    pub used: u64,
+    /// The address of the available ring.
+    pub avail: u64,
+    /// The guest physical address of the used ring, for logging writes.
+    pub log: u64,
+}
+
+// Safe because VringAddr contains only plain data.
+unsafe impl ByteValued for VringAddr {}
+
+impl VringAddr {
+    /// Makes the backend log the writes to the used ring of a queue in the dirty page log.
+    ///
+    /// # Arguments
+    /// * `state` - The state of the queue.
+    pub fn set_log(&mut self, state: &QueueState) {
+        self.flags |= 1 << VHOST_VRING_F_LOG;
+        self.log = state.used_ring.raw_value();
+    }
+
+    /// Translates the addresses back to guest physical addresses, and sets them in the state
+    /// of a queue. The state is left unchanged if any translation fails.
+    ///
+    /// # Arguments
+    /// * `state` - The state of the queue.
+    /// * `translate` - Translates a host virtual address to a guest physical address.
+    pub fn apply<F, E>(&self, state: &mut QueueState, translate: F) -> result::Result<(), E>
+    where
+        F: Fn(u64) -> result::Result<GuestAddress, E>,
+    {
+        let desc_table = translate(self.desc)?;
+        let avail_ring = translate(self.avail)?;
+        let used_ring = translate(self.used)?;
+        state.desc_table = desc_table;
+        state.avail_ring = avail_ring;
+        state.used_ring = used_ring;
+        Ok(())
+    }
+}
+
+impl<G: GuestMemory> TryFrom<(&G, u16, &QueueState)> for VringAddr {
+    type Error = GuestMemoryError;
+
+    // Translates the addresses of a queue to the host virtual addresses of the guest memory
+    // mappings.
+    fn try_from((mem, index, state): (&G, u16, &QueueState)) -> result::Result<Self, Self::Error> {
+        let host_addr = |addr: GuestAddress| mem.get_host_address(addr).map(|ptr| ptr as u64);
+        Ok(VringAddr {
+            index: u32::from(index),
+            desc: host_addr(state.desc_table)?,
+            used: host_addr(state.used_ring)?,
+            avail: host_addr(state.avail_ring)?,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::GuestMemoryMmap;
+
+    fn queue_state() -> QueueState {
+        QueueState {
+            max_size: 16,
+            size: 8,
+            ready: true,
+            desc_table: GuestAddress(0x1000),
+            avail_ring: GuestAddress(0x2000),
+            used_ring: GuestAddress(0x3000),
+            next_avail: 5,
+            next_used: 3,
+            event_idx_enabled: false,
+        }
+    }
+
+    #[test]
+    fn test_vring_state() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut state = queue_state();
+
+        assert_eq!(VringState::size(2, &state), VringState { index: 2, num: 8 });
+        let base = VringState::from((2, &state));
+        assert_eq!(base, VringState { index: 2, num: 5 });
+
+        let mut other = QueueState::default();
+        base.apply_base(&mut other);
+        assert_eq!((other.next_avail, other.next_used), (5, 5));
+
+        // Only the low 16 bits are a ring index.
+        VringState {
+            index: 0,
+            num: 0x1_0007,
+        }
+        .apply_base(&mut other);
+        assert_eq!((other.next_avail, other.next_used), (7, 7));
+
+        mem.store(3u16, GuestAddress(0x3002), Ordering::Release)
+            .unwrap();
+        let recovered = VringState::from_used_ring(&mem, 2, &state).unwrap();
+        assert_eq!(recovered, VringState { index: 2, num: 3 });
+
+        state.used_ring = GuestAddress(0x10000);
+        assert!(VringState::from_used_ring(&mem, 2, &state).is_err());
+    }
+
+    #[test]
+    fn test_vring_addr() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut state = queue_state();
+        let base = mem.get_host_address(GuestAddress(0)).unwrap() as u64;
+
+        let addr = VringAddr::try_from((&mem, 1, &state)).unwrap();
+        assert_eq!(
+            addr,
+            VringAddr {
+                index: 1,
+                flags: 0,
+                desc: base + 0x1000,
+                used: base + 0x3000,
+                avail: base + 0x2000,
+                log: 0,
+            }
+        );
+
+        let translate = |addr: u64| {
+            addr.checked_sub(base)
+                .filter(|offset| *offset < 0x10000)
+                .map(GuestAddress)
+                .ok_or(addr)
+        };
+        let mut other = QueueState::default();
+        addr.apply(&mut other, translate).unwrap();
+        assert_eq!(
+            (other.desc_table, other.avail_ring, other.used_ring),
+            (state.desc_table, state.avail_ring, state.used_ring)
+        );
+
+        let mut logged = addr;
+        logged.set_log(&state);
+        assert_eq!(logged.flags, 1 << VHOST_VRING_F_LOG);
+        assert_eq!(logged.log, 0x3000);
+
+        let invalid = VringAddr { used: 0, ..addr };
+        let mut unchanged = QueueState::default();
+        assert_eq!(invalid.apply(&mut unchanged, translate), Err(0));
+        assert_eq!(unchanged, QueueState::default());
+
+        state.avail_ring = GuestAddress(0x10000);
+        assert!(VringAddr::try_from((&mem, 1, &state)).is_err());
+    }
+}
@@ -0,0 +1,478 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A pool of worker threads which process the queues of multiqueue devices.
+//!
+//! Processing each queue on its own thread doesn't scale past a handful of queues per device:
+//! the threads mostly sleep, and they compete for the same host CPUs. A
+//! [`QueueWorkerPool`](struct.QueueWorkerPool.html) spreads the queues of a device over a fixed
+//! number of threads instead, each of them waiting on the events of its queues with `epoll`.
+//!
+//! The queues are represented by [`QueueSubscriber`](trait.QueueSubscriber.html) objects (i.e.
+//! wrappers of the queue handlers), which the pool takes over when the device is activated, and
+//! hands back when it's reset, so the device can get to the queue state again. The pool also
+//! counts the wakeups and the events of each worker, which helps with sizing it, and can record
+//! the events it hands to the subscribers in an
+//! [`EventLog`](../event_log/struct.EventLog.html), for replaying them later.
+
+use std::fmt::{self, Display};
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::result;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use log::error;
+use vmm_sys_util::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+use crate::event_log::{EventKind, EventLog};
+
+// The epoll token of the `EventFd` which stops a worker; the other tokens index the
+// registrations of the worker.
+const STOP_TOKEN: u64 = u64::MAX;
+// The maximum number of events handled by a worker after a single wakeup.
+const EPOLL_EVENTS_LEN: usize = 32;
+
+/// Worker pool errors.
+#[derive(Debug)]
+pub enum Error {
+    /// The queues are already processed by the pool.
+    AlreadyActive,
+    /// Failed to set up the epoll file descriptor of a worker.
+    Epoll(io::Error),
+    /// Failed to create or signal the `EventFd` which stops a worker.
+    EventFd(io::Error),
+    /// The pool needs at least one worker.
+    InvalidWorkerCount,
+    /// Failed to spawn a worker thread.
+    Spawn(io::Error),
+    /// A worker thread panicked, so its queues are lost.
+    WorkerPanicked,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            AlreadyActive => write!(f, "the queues are already processed by the pool"),
+            Epoll(ref err) => write!(f, "failed to set up the worker epoll: {}", err),
+            EventFd(ref err) => write!(f, "failed to use the worker stop eventfd: {}", err),
+            InvalidWorkerCount => write!(f, "the pool needs at least one worker"),
+            Spawn(ref err) => write!(f, "failed to spawn a worker thread: {}", err),
+            WorkerPanicked => write!(f, "a worker thread panicked"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// The processing of a queue, as driven by a worker of a
+/// [`QueueWorkerPool`](struct.QueueWorkerPool.html).
+pub trait QueueSubscriber: Send {
+    /// Returns the file descriptors the queue processing waits on (i.e. the ioeventfd of the
+    /// queue, and the timer of its rate limiter).
+    fn fds(&self) -> Vec<RawFd>;
+
+    /// Handles an event of one of the file descriptors returned by `fds`. The file descriptors
+    /// are level triggered, so the event has to be consumed (i.e. by reading the `EventFd`).
+    ///
+    /// # Arguments
+    /// * `fd` - The file descriptor which became readable.
+    fn process(&mut self, fd: RawFd);
+}
+
+/// The statistics of a worker thread.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WorkerStats {
+    /// The indices of the queues processed by the worker.
+    pub queues: Vec<u16>,
+    /// The number of times the worker woke up.
+    pub wakeups: u64,
+    /// The number of events handled by the worker.
+    pub events: u64,
+}
+
+// The counters updated by a worker thread.
+#[derive(Debug, Default)]
+struct WorkerCounters {
+    wakeups: AtomicU64,
+    events: AtomicU64,
+}
+
+// The subscribers of a worker, along with the indices of their queues.
+type Subscribers = Vec<(u16, Box<dyn QueueSubscriber>)>;
+
+// A running worker thread.
+struct Worker {
+    queues: Vec<u16>,
+    stop: EventFd,
+    counters: Arc<WorkerCounters>,
+    handle: JoinHandle<Subscribers>,
+}
+
+impl fmt::Debug for Worker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Worker")
+            .field("queues", &self.queues)
+            .field("counters", &self.counters)
+            .finish()
+    }
+}
+
+// Waits for the events of `subscribers` until the stop `EventFd` is signalled, and returns the
+// subscribers. `tokens` maps the epoll tokens to the subscribers and their file descriptors.
+fn run_worker(
+    epoll: Epoll,
+    mut subscribers: Subscribers,
+    tokens: Vec<(usize, RawFd)>,
+    counters: &WorkerCounters,
+    event_log: Option<EventLog>,
+) -> Subscribers {
+    let mut events = vec![EpollEvent::default(); EPOLL_EVENTS_LEN];
+    loop {
+        let count = match epoll.wait(-1, &mut events) {
+            Ok(count) => count,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                error!("queue worker failed to wait for events: {}", e);
+                return subscribers;
+            }
+        };
+        counters.wakeups.fetch_add(1, Ordering::Relaxed);
+
+        for event in &events[..count] {
+            let token = event.data();
+            if token == STOP_TOKEN {
+                return subscribers;
+            }
+            // The tokens were assigned by `spawn_worker`, so they are valid indices.
+            let (subscriber, fd) = tokens[token as usize];
+            let (queue, subscriber) = &mut subscribers[subscriber];
+            if let Some(event_log) = event_log.as_ref() {
+                event_log.record(*queue, EventKind::Kick);
+            }
+            subscriber.process(fd);
+            counters.events.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Processes the queues of a device on a fixed number of worker threads.
+///
+/// The queue with index `i` is processed by the worker with index `i % num_workers`, and no
+/// more workers than queues are started.
+#[derive(Debug)]
+pub struct QueueWorkerPool {
+    /// The maximum number of worker threads.
+    num_workers: usize,
+    /// The running workers, which are only present while the device is active.
+    workers: Vec<Worker>,
+    /// The log of the events handed to the subscribers, if any.
+    event_log: Option<EventLog>,
+}
+
+impl QueueWorkerPool {
+    /// Creates a pool which processes the queues with up to `num_workers` threads.
+    ///
+    /// # Arguments
+    /// * `num_workers` - The maximum number of worker threads (at least 1).
+    pub fn new(num_workers: usize) -> Result<Self> {
+        if num_workers == 0 {
+            return Err(Error::InvalidWorkerCount);
+        }
+        Ok(QueueWorkerPool {
+            num_workers,
+            workers: Vec::new(),
+            event_log: None,
+        })
+    }
+
+    /// Records a kick in `event_log` each time a worker hands an event to a subscriber, before
+    /// the subscriber processes it.
+    ///
+    /// # Arguments
+    /// * `event_log` - The log of the queue events.
+    pub fn with_event_log(mut self, event_log: EventLog) -> Self {
+        self.event_log = Some(event_log);
+        self
+    }
+
+    /// Returns the maximum number of worker threads.
+    pub fn num_workers(&self) -> usize {
+        self.num_workers
+    }
+
+    /// Returns whether the pool processes queues.
+    pub fn is_active(&self) -> bool {
+        !self.workers.is_empty()
+    }
+
+    /// Starts processing the queues of a device (i.e. when the device is activated).
+    ///
+    /// # Arguments
+    /// * `subscribers` - The processing of each queue, indexed by queue.
+    pub fn activate(&mut self, subscribers: Vec<Box<dyn QueueSubscriber>>) -> Result<()> {
+        if self.is_active() {
+            return Err(Error::AlreadyActive);
+        }
+        let num_workers = self.num_workers.min(subscribers.len());
+        let mut assigned: Vec<Subscribers> = (0..num_workers).map(|_| Vec::new()).collect();
+        for (index, subscriber) in subscribers.into_iter().enumerate() {
+            // The cast is safe because the queues are indexed with `u16`s.
+            assigned[index % num_workers].push((index as u16, subscriber));
+        }
+
+        for (index, subscribers) in assigned.into_iter().enumerate() {
+            if let Err(e) = self.spawn_worker(index, subscribers) {
+                // Don't leave a part of the queues running.
+                let _ = self.reset();
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    // Registers the file descriptors of `subscribers` with a new epoll instance, and starts a
+    // worker thread which waits on it.
+    fn spawn_worker(&mut self, index: usize, subscribers: Subscribers) -> Result<()> {
+        let epoll = Epoll::new().map_err(Error::Epoll)?;
+        let stop = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd)?;
+        epoll
+            .ctl(
+                ControlOperation::Add,
+                stop.as_raw_fd(),
+                EpollEvent::new(EventSet::IN, STOP_TOKEN),
+            )
+            .map_err(Error::Epoll)?;
+
+        let mut tokens = Vec::new();
+        for (subscriber, (_, s)) in subscribers.iter().enumerate() {
+            for fd in s.fds() {
+                epoll
+                    .ctl(
+                        ControlOperation::Add,
+                        fd,
+                        EpollEvent::new(EventSet::IN, tokens.len() as u64),
+                    )
+                    .map_err(Error::Epoll)?;
+                tokens.push((subscriber, fd));
+            }
+        }
+
+        let queues = subscribers.iter().map(|(queue, _)| *queue).collect();
+        let counters = Arc::new(WorkerCounters::default());
+        let handle = thread::Builder::new()
+            .name(format!("queue_worker_{}", index))
+            .spawn({
+                let counters = counters.clone();
+                let event_log = self.event_log.clone();
+                move || run_worker(epoll, subscribers, tokens, &counters, event_log)
+            })
+            .map_err(Error::Spawn)?;
+        self.workers.push(Worker {
+            queues,
+            stop,
+            counters,
+            handle,
+        });
+        Ok(())
+    }
+
+    /// Stops processing the queues (i.e. when the device is reset), and returns the processing
+    /// of each queue, indexed by queue. Nothing is returned when the pool isn't active.
+    pub fn reset(&mut self) -> Result<Vec<Box<dyn QueueSubscriber>>> {
+        // Signal all the workers before waiting for any of them.
+        for worker in self.workers.iter() {
+            worker.stop.write(1).map_err(Error::EventFd)?;
+        }
+        let mut subscribers = Vec::new();
+        let mut panicked = false;
+        for worker in self.workers.drain(..) {
+            match worker.handle.join() {
+                Ok(worker_subscribers) => subscribers.extend(worker_subscribers),
+                Err(_) => panicked = true,
+            }
+        }
+        if panicked {
+            return Err(Error::WorkerPanicked);
+        }
+        subscribers.sort_by_key(|(queue, _)| *queue);
+        Ok(subscribers
+            .into_iter()
+            .map(|(_, subscriber)| subscriber)
+            .collect())
+    }
+
+    /// Returns the statistics of each running worker.
+    pub fn stats(&self) -> Vec<WorkerStats> {
+        self.workers
+            .iter()
+            .map(|worker| WorkerStats {
+                queues: worker.queues.clone(),
+                wakeups: worker.counters.wakeups.load(Ordering::Relaxed),
+                events: worker.counters.events.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+impl Drop for QueueWorkerPool {
+    fn drop(&mut self) {
+        if let Err(e) = self.reset() {
+            error!("failed to stop the queue workers: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    // Counts the kicks of a queue.
+    struct TestSubscriber {
+        kick: EventFd,
+        kicks: Arc<AtomicU64>,
+    }
+
+    impl QueueSubscriber for TestSubscriber {
+        fn fds(&self) -> Vec<RawFd> {
+            vec![self.kick.as_raw_fd()]
+        }
+
+        fn process(&mut self, fd: RawFd) {
+            assert_eq!(fd, self.kick.as_raw_fd());
+            let kicks = self.kick.read().unwrap();
+            self.kicks.fetch_add(kicks, Ordering::SeqCst);
+        }
+    }
+
+    // Waits until `f` returns `true`, for up to a few seconds.
+    fn wait_for<F: Fn() -> bool>(f: F) {
+        let start = Instant::now();
+        while !f() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_worker_pool() {
+        assert!(matches!(
+            QueueWorkerPool::new(0),
+            Err(Error::InvalidWorkerCount)
+        ));
+
+        let mut pool = QueueWorkerPool::new(2).unwrap();
+        assert_eq!(pool.num_workers(), 2);
+        assert!(!pool.is_active());
+        assert!(pool.reset().unwrap().is_empty());
+
+        let kicks = (0..5)
+            .map(|_| Arc::new(AtomicU64::new(0)))
+            .collect::<Vec<_>>();
+        let kick_evts = (0..5)
+            .map(|_| EventFd::new(EFD_NONBLOCK).unwrap())
+            .collect::<Vec<_>>();
+        let subscribers = || -> Vec<Box<dyn QueueSubscriber>> {
+            kick_evts
+                .iter()
+                .zip(kicks.iter())
+                .map(|(kick, kicks)| {
+                    Box::new(TestSubscriber {
+                        kick: kick.try_clone().unwrap(),
+                        kicks: kicks.clone(),
+                    }) as Box<dyn QueueSubscriber>
+                })
+                .collect()
+        };
+
+        pool.activate(subscribers()).unwrap();
+        assert!(pool.is_active());
+        assert!(matches!(
+            pool.activate(subscribers()),
+            Err(Error::AlreadyActive)
+        ));
+        let queues = pool
+            .stats()
+            .into_iter()
+            .map(|stats| stats.queues)
+            .collect::<Vec<_>>();
+        assert_eq!(queues, vec![vec![0, 2, 4], vec![1, 3]]);
+
+        for (i, kick) in kick_evts.iter().enumerate() {
+            kick.write(i as u64 + 1).unwrap();
+        }
+        for (i, kicks) in kicks.iter().enumerate() {
+            wait_for(|| kicks.load(Ordering::SeqCst) == i as u64 + 1);
+        }
+        let stats = pool.stats();
+        assert!(stats.iter().all(|stats| stats.wakeups >= 1));
+        assert_eq!(stats.iter().map(|stats| stats.events).sum::<u64>(), 5);
+
+        // The subscribers are handed back in queue order, and not processed anymore.
+        let mut subscribers = pool.reset().unwrap();
+        assert!(!pool.is_active());
+        assert!(pool.stats().is_empty());
+        for kick in kick_evts.iter() {
+            kick.write(1).unwrap();
+        }
+        thread::sleep(Duration::from_millis(10));
+        for (i, subscriber) in subscribers.iter_mut().enumerate() {
+            assert_eq!(kicks[i].load(Ordering::SeqCst), i as u64 + 1);
+            let fd = subscriber.fds()[0];
+            subscriber.process(fd);
+            assert_eq!(kicks[i].load(Ordering::SeqCst), i as u64 + 2);
+        }
+
+        // There are no more workers than queues, and the pool can be activated again.
+        let mut pool = QueueWorkerPool::new(8).unwrap();
+        pool.activate(subscribers).unwrap();
+        assert_eq!(pool.stats().len(), 5);
+        kick_evts[0].write(1).unwrap();
+        wait_for(|| kicks[0].load(Ordering::SeqCst) == 3);
+    }
+
+    #[test]
+    fn test_event_log() {
+        let log = EventLog::new();
+        let mut pool = QueueWorkerPool::new(1).unwrap().with_event_log(log.clone());
+        let kicks = Arc::new(AtomicU64::new(0));
+        let kick_evts = (0..2)
+            .map(|_| EventFd::new(EFD_NONBLOCK).unwrap())
+            .collect::<Vec<_>>();
+        let subscribers = kick_evts
+            .iter()
+            .map(|kick| {
+                Box::new(TestSubscriber {
+                    kick: kick.try_clone().unwrap(),
+                    kicks: kicks.clone(),
+                }) as Box<dyn QueueSubscriber>
+            })
+            .collect();
+        pool.activate(subscribers).unwrap();
+
+        // Each event is recorded before it's processed, along with the index of its queue.
+        kick_evts[1].write(1).unwrap();
+        wait_for(|| kicks.load(Ordering::SeqCst) == 1);
+        kick_evts[0].write(1).unwrap();
+        wait_for(|| kicks.load(Ordering::SeqCst) == 2);
+        let queues = log
+            .events()
+            .iter()
+            .map(|event| {
+                assert_eq!(event.kind, EventKind::Kick);
+                event.queue
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(queues, vec![1, 0]);
+    }
+}
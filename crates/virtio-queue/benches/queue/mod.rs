@@ -85,4 +85,22 @@ pub fn benchmark_queue(c: &mut Criterion) {
             q.add_used(123, 0x1000).unwrap();
         }
     });
+
+    for event_idx in [false, true].iter().copied() {
+        bench_queue(
+            c,
+            &format!("add used and check notification (event_idx={})", event_idx),
+            || {
+                let mut q = empty_queue();
+                q.set_event_idx(event_idx);
+                q
+            },
+            |mut q| {
+                for _ in 0..128 {
+                    q.add_used(123, 0x1000).unwrap();
+                    black_box(q.needs_notification().unwrap());
+                }
+            },
+        );
+    }
 }
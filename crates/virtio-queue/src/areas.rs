@@ -0,0 +1,284 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! Helpers which access the guest memory covered by a descriptor chain as a contiguous buffer.
+//!
+//! The requests of most devices are laid out by the driver in a sequence of descriptors, which
+//! can be split at any offset. The memory areas covered by the descriptors are collected (i.e.
+//! with [`split_chain`](fn.split_chain.html)), and the helpers read or write the bytes at a
+//! given offset of their concatenation.
+
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::fmt::{self, Display};
+use core::ops::Range;
+
+use vm_memory::{
+    Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryError,
+};
+
+use crate::DescriptorChain;
+
+/// Errors which occur when accessing the areas.
+#[derive(Debug)]
+pub enum Error {
+    /// The areas are too short for the access.
+    TooShort,
+    /// Invalid memory access.
+    GuestMemory(GuestMemoryError),
+    /// A device-readable descriptor follows a device-writable one.
+    UnexpectedReadOnlyDescriptor,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            TooShort => write!(f, "the descriptor chain is too short"),
+            GuestMemory(ref err) => write!(f, "invalid guest memory access: {}", err),
+            UnexpectedReadOnlyDescriptor => write!(f, "unexpected device-readable descriptor"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+/// A guest memory area, i.e. the one covered by a descriptor.
+pub type Area = (GuestAddress, usize);
+
+/// Returns the total length of `areas`.
+pub fn areas_len(areas: &[Area]) -> usize {
+    areas.iter().map(|(_, len)| len).sum()
+}
+
+// Splits the `len` bytes which start at `offset` in the concatenation of `areas` into the
+// guest memory segments that hold them, as `(address, buffer range)` pairs.
+fn segments(
+    areas: &[Area],
+    mut offset: usize,
+    len: usize,
+) -> Result<Vec<(GuestAddress, Range<usize>)>, Error> {
+    let mut segments = Vec::new();
+    let mut pos = 0;
+    for &(addr, area_len) in areas {
+        if pos == len {
+            break;
+        }
+        if offset >= area_len {
+            offset -= area_len;
+            continue;
+        }
+        let count = min(area_len - offset, len - pos);
+        let start = addr.checked_add(offset as u64).ok_or(Error::GuestMemory(
+            GuestMemoryError::InvalidGuestAddress(addr),
+        ))?;
+        segments.push((start, pos..pos + count));
+        pos += count;
+        offset = 0;
+    }
+
+    if pos < len {
+        return Err(Error::TooShort);
+    }
+    Ok(segments)
+}
+
+/// Fills `buf` with the bytes which start at `offset` in the concatenation of `areas`.
+///
+/// # Arguments
+/// * `mem` - The guest memory.
+/// * `areas` - The memory areas, in order.
+/// * `offset` - The offset of the first byte in the concatenation of `areas`.
+/// * `buf` - The buffer to fill.
+pub fn read_areas<M: GuestMemory>(
+    mem: &M,
+    areas: &[Area],
+    offset: usize,
+    buf: &mut [u8],
+) -> Result<(), Error> {
+    for (addr, range) in segments(areas, offset, buf.len())? {
+        mem.read_slice(&mut buf[range], addr)
+            .map_err(Error::GuestMemory)?;
+    }
+    Ok(())
+}
+
+/// Writes `buf` at `offset` in the concatenation of `areas`.
+///
+/// # Arguments
+/// * `mem` - The guest memory.
+/// * `areas` - The memory areas, in order.
+/// * `offset` - The offset of the first byte in the concatenation of `areas`.
+/// * `buf` - The bytes to write.
+pub fn write_areas<M: GuestMemory>(
+    mem: &M,
+    areas: &[Area],
+    offset: usize,
+    buf: &[u8],
+) -> Result<(), Error> {
+    for (addr, range) in segments(areas, offset, buf.len())? {
+        mem.write_slice(&buf[range], addr)
+            .map_err(Error::GuestMemory)?;
+    }
+    Ok(())
+}
+
+/// Reads an object which starts at `offset` in the concatenation of `areas`.
+///
+/// # Arguments
+/// * `mem` - The guest memory.
+/// * `areas` - The memory areas, in order.
+/// * `offset` - The offset of the object in the concatenation of `areas`.
+pub fn read_obj<M: GuestMemory, T: ByteValued>(
+    mem: &M,
+    areas: &[Area],
+    offset: usize,
+) -> Result<T, Error> {
+    let mut obj = T::default();
+    read_areas(mem, areas, offset, obj.as_mut_slice())?;
+    Ok(obj)
+}
+
+/// Consumes the descriptors of `chain`, and returns the areas covered by the device-readable
+/// descriptors, followed by the ones covered by the device-writable descriptors (which have to
+/// come after the device-readable ones).
+///
+/// # Arguments
+/// * `chain` - The descriptor chain.
+pub fn split_chain<M: GuestAddressSpace>(
+    chain: &mut DescriptorChain<M>,
+) -> Result<(Vec<Area>, Vec<Area>), Error> {
+    let mut readable = Vec::new();
+    let mut writable = Vec::new();
+    for desc in chain.by_ref() {
+        let area = (desc.addr(), desc.len() as usize);
+        if desc.is_write_only() {
+            writable.push(area);
+        } else if writable.is_empty() {
+            readable.push(area);
+        } else {
+            return Err(Error::UnexpectedReadOnlyDescriptor);
+        }
+    }
+    Ok((readable, writable))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::GuestMemoryMmap;
+
+    use crate::mock::VirtQueue;
+    use crate::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    #[test]
+    fn test_read_write_areas() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1_0000)]).unwrap();
+        let areas = [
+            (GuestAddress(0x1000), 4),
+            (GuestAddress(0x2000), 0),
+            (GuestAddress(0x3000), 8),
+        ];
+        assert_eq!(areas_len(&areas), 12);
+        assert_eq!(areas_len(&[]), 0);
+
+        // The buffer is split between the areas, and the empty ones are skipped.
+        write_areas(&mem, &areas, 2, &[1, 2, 3, 4, 5]).unwrap();
+        assert_eq!(
+            mem.read_obj::<[u8; 2]>(GuestAddress(0x1002)).unwrap(),
+            [1, 2]
+        );
+        assert_eq!(
+            mem.read_obj::<[u8; 4]>(GuestAddress(0x3000)).unwrap(),
+            [3, 4, 5, 0]
+        );
+
+        let mut buf = [0u8; 4];
+        read_areas(&mem, &areas, 3, &mut buf).unwrap();
+        assert_eq!(buf, [2, 3, 4, 5]);
+        assert_eq!(read_obj::<_, u16>(&mem, &areas, 3).unwrap(), 0x0302);
+
+        // The accesses which end exactly at the end of the areas are valid.
+        write_areas(&mem, &areas, 11, &[6]).unwrap();
+        read_areas(&mem, &areas, 11, &mut buf[..1]).unwrap();
+        assert_eq!(buf[0], 6);
+        read_areas(&mem, &areas, 12, &mut []).unwrap();
+
+        assert!(matches!(
+            read_areas(&mem, &areas, 10, &mut buf),
+            Err(Error::TooShort)
+        ));
+        assert!(matches!(
+            write_areas(&mem, &areas, 12, &[0]),
+            Err(Error::TooShort)
+        ));
+        assert!(matches!(
+            read_obj::<_, u64>(&mem, &areas[..1], 0),
+            Err(Error::TooShort)
+        ));
+
+        // The areas which don't fit in the guest memory, or in the address space.
+        let areas = [(GuestAddress(0xfff0), 0x20)];
+        assert!(matches!(
+            write_areas(&mem, &areas, 0, &[0; 0x20]),
+            Err(Error::GuestMemory(_))
+        ));
+        let areas = [(GuestAddress(u64::MAX), 4)];
+        assert!(matches!(
+            read_areas(&mem, &areas, 2, &mut buf[..1]),
+            Err(Error::GuestMemory(GuestMemoryError::InvalidGuestAddress(
+                GuestAddress(u64::MAX)
+            )))
+        ));
+    }
+
+    #[test]
+    fn test_split_chain() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let mut queue = vq.create_queue(&mem);
+
+        vq.dtable(0).set(0x1_0000, 0x10, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1).set(0x2_0000, 0x20, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable(2)
+            .set(0x3_0000, 0x30, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 3);
+        vq.dtable(3).set(0x4_0000, 0x40, VIRTQ_DESC_F_WRITE, 0);
+        // A device-readable descriptor after a device-writable one.
+        vq.dtable(4)
+            .set(0x5_0000, 0x50, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 5);
+        vq.dtable(5).set(0x6_0000, 0x60, 0, 0);
+        vq.avail.ring(0).store(0);
+        vq.avail.ring(1).store(4);
+        vq.avail.idx().store(2);
+
+        let mut iter = queue.iter().unwrap();
+        let mut chain = iter.next().unwrap();
+        let (readable, writable) = split_chain(&mut chain).unwrap();
+        assert_eq!(
+            readable,
+            [
+                (GuestAddress(0x1_0000), 0x10),
+                (GuestAddress(0x2_0000), 0x20)
+            ]
+        );
+        assert_eq!(
+            writable,
+            [
+                (GuestAddress(0x3_0000), 0x30),
+                (GuestAddress(0x4_0000), 0x40)
+            ]
+        );
+        // The chain is consumed.
+        assert!(chain.next().is_none());
+
+        let mut chain = iter.next().unwrap();
+        assert!(matches!(
+            split_chain(&mut chain),
+            Err(Error::UnexpectedReadOnlyDescriptor)
+        ));
+    }
+}
@@ -11,17 +11,43 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 //! A crate that exposes the virtio queue API.
+//!
+//! The `std` feature is enabled by default. Without it, the crate doesn't depend on
+//! `vmm-sys-util`, `Error` doesn't implement `std::error::Error`, and the errors caused by the
+//! driver are no longer logged from within the iterators. The crate doesn't support `no_std`
+//! targets, as `vm-memory` depends on `std`.
 
 #![deny(missing_docs)]
 
-use std::cmp::min;
-use std::fmt::{self, Debug, Display};
-use std::mem::size_of;
-use std::num::Wrapping;
-use std::sync::atomic::{fence, Ordering};
+extern crate alloc;
+
+#[macro_use]
+mod log_limit;
+pub mod areas;
+/// Contains a mock queue, which plays the role of the driver in unit tests.
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+
+/// Contains proptest strategies for the descriptor tables and the available rings.
+#[cfg(any(test, feature = "strategies"))]
+pub mod strategies;
+
+// The mock queue used to be called like this.
+#[cfg(feature = "test-utils")]
+#[doc(hidden)]
+pub use mock as test_utils;
+
+use alloc::sync::Arc;
+use core::cmp::min;
+use core::fmt::{self, Debug, Display};
+use core::mem::{align_of, size_of};
+use core::num::Wrapping;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
 
 use vm_memory::{
-    Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryError,
+    Address, AtomicAccess, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemory,
+    GuestMemoryError, VolatileRef, VolatileSlice,
 };
 
 use log::error;
@@ -51,6 +77,9 @@ const VIRTQ_AVAIL_RING_HEADER_SIZE: u64 = 4;
 // VIRTQ_AVAIL_RING_META_SIZE + VIRTQ_AVAIL_ELEMENT_SIZE * queue_size
 const VIRTQ_AVAIL_RING_META_SIZE: u64 = VIRTQ_AVAIL_RING_HEADER_SIZE + 2;
 
+// The maximum number of available ring entries `AvailIter` reads at once.
+const AVAIL_BATCH_SIZE: usize = 32;
+
 // The Virtio Spec 1.0 defines the alignment of VirtIO descriptor is 16 bytes,
 // which fulfills the explicit constraint of GuestMemory::read_obj().
 const VIRTQ_DESCRIPTOR_SIZE: usize = 16;
@@ -84,8 +113,65 @@ impl Display for Error {
     }
 }
 
+#[cfg(feature = "std")]
 impl std::error::Error for Error {}
 
+/// The reasons a virtio queue configuration is not valid.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InvalidQueueReason {
+    /// The available ring isn't aligned to 2 bytes.
+    AvailRingMisaligned(GuestAddress),
+    /// The available ring (start address and size) goes out of the guest memory.
+    AvailRingOutOfBounds(GuestAddress, u64),
+    /// The descriptor table isn't aligned to 16 bytes.
+    DescTableMisaligned(GuestAddress),
+    /// The descriptor table (start address and size) goes out of the guest memory.
+    DescTableOutOfBounds(GuestAddress, u64),
+    /// The queue size is zero, not a power of two, or larger than the maximum size.
+    InvalidSize(u16),
+    /// The queue is not marked ready by the driver.
+    NotReady,
+    /// The used ring isn't aligned to 4 bytes.
+    UsedRingMisaligned(GuestAddress),
+    /// The used ring (start address and size) goes out of the guest memory.
+    UsedRingOutOfBounds(GuestAddress, u64),
+}
+
+impl Display for InvalidQueueReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::InvalidQueueReason::*;
+
+        let out_of_bounds = |f: &mut fmt::Formatter, area: &str, start: &GuestAddress, size| {
+            write!(
+                f,
+                "virtio queue {} goes out of bounds: start:0x{:08x} size:0x{:08x}",
+                area,
+                start.raw_value(),
+                size
+            )
+        };
+        match self {
+            AvailRingMisaligned(_) => {
+                write!(f, "virtio queue available ring breaks alignment contraints")
+            }
+            AvailRingOutOfBounds(start, size) => out_of_bounds(f, "available ring", start, size),
+            DescTableMisaligned(_) => {
+                write!(
+                    f,
+                    "virtio queue descriptor table breaks alignment contraints"
+                )
+            }
+            DescTableOutOfBounds(start, size) => out_of_bounds(f, "descriptor table", start, size),
+            InvalidSize(size) => write!(f, "virtio queue with invalid size: {}", size),
+            NotReady => write!(f, "attempt to use virtio queue that is not marked ready"),
+            UsedRingMisaligned(_) => {
+                write!(f, "virtio queue used ring breaks alignment contraints")
+            }
+            UsedRingOutOfBounds(start, size) => out_of_bounds(f, "used ring", start, size),
+        }
+    }
+}
+
 /// A virtio descriptor constraints with C representation
 #[repr(C)]
 #[derive(Default, Clone, Copy, Debug)]
@@ -151,10 +237,12 @@ impl Descriptor {
 unsafe impl ByteValued for Descriptor {}
 
 /// A virtio descriptor chain.
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct DescriptorChain<M: GuestAddressSpace> {
     mem: M::T,
     desc_table: GuestAddress,
+    // The host address of the descriptor table within `mem`, if it was resolved by the queue.
+    desc_table_host: Option<usize>,
     queue_size: u16,
     head_index: u16,
     next_index: u16,
@@ -162,30 +250,54 @@ pub struct DescriptorChain<M: GuestAddressSpace> {
     is_indirect: bool,
 }
 
+// We can't derive Clone, because rustc would require M: Clone instead of relying on the
+// M::T: Clone constraint from GuestAddressSpace.
+impl<M: GuestAddressSpace> Clone for DescriptorChain<M> {
+    fn clone(&self) -> Self {
+        DescriptorChain {
+            mem: self.mem.clone(),
+            desc_table: self.desc_table,
+            desc_table_host: self.desc_table_host,
+            queue_size: self.queue_size,
+            head_index: self.head_index,
+            next_index: self.next_index,
+            ttl: self.ttl,
+            is_indirect: self.is_indirect,
+        }
+    }
+}
+
 impl<M: GuestAddressSpace> DescriptorChain<M> {
-    fn with_ttl(
+    /// Create a new `DescriptorChain` instance.
+    #[cfg(any(test, feature = "mock"))]
+    fn new(mem: M::T, desc_table: GuestAddress, queue_size: u16, head_index: u16) -> Self {
+        Self::with_mapping(mem, desc_table, None, queue_size, head_index)
+    }
+
+    // Creates a chain which reads the descriptors through the host mapping of the descriptor
+    // table. The mapping has to be resolved from the same memory snapshot as `mem`; when the
+    // queue doesn't provide one, the table is resolved here, once for the whole chain.
+    fn with_mapping(
         mem: M::T,
         desc_table: GuestAddress,
+        desc_table_host: Option<usize>,
         queue_size: u16,
-        ttl: u16,
         head_index: u16,
     ) -> Self {
+        let desc_table_host =
+            desc_table_host.or_else(|| map_desc_table(&*mem, desc_table, queue_size));
         DescriptorChain {
             mem,
             desc_table,
+            desc_table_host,
             queue_size,
             head_index,
             next_index: head_index,
-            ttl,
+            ttl: queue_size,
             is_indirect: false,
         }
     }
 
-    /// Create a new `DescriptorChain` instance.
-    fn new(mem: M::T, desc_table: GuestAddress, queue_size: u16, head_index: u16) -> Self {
-        Self::with_ttl(mem, desc_table, queue_size, queue_size, head_index)
-    }
-
     /// Get the descriptor index of the chain header
     pub fn head_index(&self) -> u16 {
         self.head_index
@@ -194,7 +306,7 @@ impl<M: GuestAddressSpace> DescriptorChain<M> {
     /// Return a `GuestMemory` object that can be used to access the buffers
     /// pointed to by the descriptor chain.
     pub fn memory(&self) -> &M::M {
-        &*self.mem
+        &self.mem
     }
 
     /// Returns an iterator that only yields the readable descriptors in the chain.
@@ -224,19 +336,42 @@ impl<M: GuestAddressSpace> DescriptorChain<M> {
         // Check the target indirect descriptor table is correctly aligned.
         if desc.addr().raw_value() & (VIRTQ_DESCRIPTOR_SIZE as u64 - 1) != 0
             || (desc.len as usize) & (VIRTQ_DESCRIPTOR_SIZE - 1) != 0
-            || table_len > usize::from(std::u16::MAX)
+            || table_len > usize::from(u16::MAX)
         {
             return Err(Error::InvalidIndirectDescriptorTable);
         }
 
         self.desc_table = desc.addr();
         self.queue_size = table_len as u16;
+        self.desc_table_host = map_desc_table(&*self.mem, self.desc_table, self.queue_size);
         self.next_index = 0;
         self.ttl = self.queue_size;
         self.is_indirect = true;
 
         Ok(())
     }
+
+    // Reads the descriptor at `index` from the current descriptor table. The caller has to
+    // make sure `index` is smaller than `queue_size`.
+    fn load_descriptor(&self, index: u16) -> Option<Descriptor> {
+        // It's ok to use `unchecked_add` here because the index does not exceed the queue
+        // size, and the descriptor table location is expected to have been validated before
+        // (for example, before activating a device). Moreover, this cannot lead to unsafety
+        // because the actual memory accesses are always checked.
+        let offset = index as usize * size_of::<Descriptor>();
+        match self.desc_table_host {
+            // Safe because the mapping covers `queue_size` descriptors, is aligned for
+            // `Descriptor`, and stays valid while we hold the memory snapshot it was resolved
+            // from.
+            Some(host_addr) => Some(unsafe {
+                VolatileRef::<Descriptor>::new((host_addr + offset) as *mut u8).load()
+            }),
+            None => {
+                let desc_addr = self.desc_table.unchecked_add(offset as u64);
+                self.mem.read_obj::<Descriptor>(desc_addr).ok()
+            }
+        }
+    }
 }
 
 impl<M: GuestAddressSpace> Iterator for DescriptorChain<M> {
@@ -248,35 +383,29 @@ impl<M: GuestAddressSpace> Iterator for DescriptorChain<M> {
     /// [`AvailIter`](struct.AvailIter.html), which is the head of the next
     /// _available_ descriptor chain.
     fn next(&mut self) -> Option<Self::Item> {
-        if self.ttl == 0 || self.next_index >= self.queue_size {
-            return None;
-        }
+        loop {
+            if self.ttl == 0 || self.next_index >= self.queue_size {
+                return None;
+            }
 
-        // It's ok to use `unchecked_add` here because we previously verify the index does not
-        // exceed the queue size, and the descriptor table location is expected to have been
-        // validate before (for example, before activating a device). Moreover, this cannot
-        // lead to unsafety because the actual memory accesses are always checked.
-        let desc_addr = self
-            .desc_table
-            .unchecked_add(self.next_index as u64 * size_of::<Descriptor>() as u64);
+            let desc = self.load_descriptor(self.next_index)?;
 
-        let desc = self.mem.read_obj::<Descriptor>(desc_addr).ok()?;
+            if desc.is_indirect() {
+                self.process_indirect_descriptor(desc).ok()?;
+                continue;
+            }
 
-        if desc.is_indirect() {
-            self.process_indirect_descriptor(desc).ok()?;
-            return self.next();
-        }
+            if desc.has_next() {
+                self.next_index = desc.next();
+                // It's ok to decrement `self.ttl` here because we check at the start of the
+                // loop that it's greater than 0.
+                self.ttl -= 1;
+            } else {
+                self.ttl = 0;
+            }
 
-        if desc.has_next() {
-            self.next_index = desc.next();
-            // It's ok to decrement `self.ttl` here because we check at the start of the method
-            // that it's greater than 0.
-            self.ttl -= 1;
-        } else {
-            self.ttl = 0;
+            return Some(desc);
         }
-
-        Some(desc)
     }
 }
 
@@ -327,44 +456,90 @@ where
 #[derive(Debug)]
 pub struct AvailIter<'b, M: GuestAddressSpace> {
     mem: M::T,
+    // The mapping of the queue within `mem`, if any.
+    mapping: Option<&'b QueueMapping<M>>,
     desc_table: GuestAddress,
     avail_ring: GuestAddress,
     last_index: Wrapping<u16>,
     queue_size: u16,
     next_avail: &'b mut Wrapping<u16>,
+    // The heads fetched from the available ring which were not returned yet, starting with
+    // the one at `next_avail`.
+    heads: [u16; AVAIL_BATCH_SIZE],
+    heads_pos: usize,
+    heads_len: usize,
+    // The error which stopped the iteration, if any.
+    error: Option<Error>,
+}
+
+impl<M: GuestAddressSpace> AvailIter<'_, M> {
+    /// Returns the error which stopped the iteration before reaching the last available
+    /// descriptor chain, if any. The iterator doesn't return anything after an error.
+    pub fn error(&self) -> Option<&Error> {
+        self.error.as_ref()
+    }
+
+    // Reads the available ring entries from `next_avail` up to `last_index` at once, without
+    // wrapping around the end of the ring, and without exceeding `AVAIL_BATCH_SIZE` entries.
+    fn fetch_heads(&mut self) -> Result<(), GuestMemoryError> {
+        let start = self.next_avail.0 % self.queue_size;
+        let pending = (self.last_index - *self.next_avail).0;
+        let count = usize::from(min(pending, self.queue_size - start)).min(AVAIL_BATCH_SIZE);
+        // This computation cannot overflow because all the values involved are actually
+        // `u16`s cast to `u64`.
+        let offset = VIRTQ_AVAIL_RING_HEADER_SIZE + u64::from(start) * VIRTQ_AVAIL_ELEMENT_SIZE;
+
+        let mut buf = [0u8; AVAIL_BATCH_SIZE * VIRTQ_AVAIL_ELEMENT_SIZE as usize];
+        let buf = &mut buf[..count * VIRTQ_AVAIL_ELEMENT_SIZE as usize];
+        let avail_ring = RingRef {
+            mem: &*self.mem,
+            addr: self.avail_ring,
+            slice: self.mapping.map(QueueMapping::avail_ring),
+        };
+        avail_ring.read_slice(buf, offset).inspect_err(|_| {
+            let addr = self.avail_ring.unchecked_add(offset);
+            error_ratelimited!("Failed to read from memory {:x}", addr.raw_value())
+        })?;
+
+        for (head, entry) in self.heads.iter_mut().zip(buf.chunks_exact(2)) {
+            *head = u16::from_ne_bytes([entry[0], entry[1]]);
+        }
+        self.heads_pos = 0;
+        self.heads_len = count;
+        Ok(())
+    }
 }
 
 impl<'b, M: GuestAddressSpace> Iterator for AvailIter<'b, M> {
     type Item = DescriptorChain<M>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if *self.next_avail == self.last_index {
+        if *self.next_avail == self.last_index || self.error.is_some() {
             return None;
         }
 
-        // This computation cannot overflow because all the values involved are actually
-        // `u16`s cast to `u64`.
-        let offset = VIRTQ_AVAIL_RING_HEADER_SIZE
-            + (self.next_avail.0 % self.queue_size) as u64 * VIRTQ_AVAIL_ELEMENT_SIZE;
-
-        // The logic in `Queue::is_valid` ensures it's ok to use `unchecked_add` as long
-        // as the index is within bounds. We do not currently enforce that a queue is only used
-        // after checking `is_valid`, but rather expect the device implementations to do so
-        // before activation. The standard also forbids drivers to change queue parameters
-        // while the device is "running". A warp-around cannot lead to unsafe memory accesses
-        // because the memory model performs its own validations.
-        let addr = self.avail_ring.unchecked_add(offset);
-        let head_index: u16 = self
-            .mem
-            .read_obj(addr)
-            .map_err(|_| error!("Failed to read from memory {:x}", addr.raw_value()))
-            .ok()?;
+        // The heads are fetched in batches to save memory accesses when the driver made
+        // multiple chains available. The logic in `Queue::is_valid` ensures it's ok to use
+        // `unchecked_add` for the ring entries. We do not currently enforce that a queue is
+        // only used after checking `is_valid`, but rather expect the device implementations to
+        // do so before activation. The standard also forbids drivers to change queue
+        // parameters while the device is "running". A warp-around cannot lead to unsafe memory
+        // accesses because the memory model performs its own validations.
+        if self.heads_pos == self.heads_len {
+            if let Err(e) = self.fetch_heads() {
+                self.error = Some(Error::GuestMemory(e));
+                return None;
+            }
+        }
+        let head_index = self.heads[self.heads_pos];
+        self.heads_pos += 1;
 
         *self.next_avail += Wrapping(1);
 
-        Some(DescriptorChain::new(
+        Some(DescriptorChain::with_mapping(
             self.mem.clone(),
             self.desc_table,
+            self.mapping.map(|mapping| mapping.desc_table_host),
             self.queue_size,
             head_index,
         ))
@@ -379,6 +554,7 @@ pub struct VirtqUsedElem {
     len: u32,
 }
 
+#[allow(clippy::len_without_is_empty)]
 impl VirtqUsedElem {
     /// Create a new `VirtqUsedElem` instance.
     pub fn new(id: u16, len: u32) -> Self {
@@ -387,10 +563,245 @@ impl VirtqUsedElem {
             len,
         }
     }
+
+    /// Returns the head index of the used descriptor chain.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Returns the number of bytes written to the descriptor chain.
+    pub fn len(&self) -> u32 {
+        self.len
+    }
 }
 
 unsafe impl ByteValued for VirtqUsedElem {}
 
+/// The configuration and the ring indices of a queue, without the guest memory it accesses.
+///
+/// This is the state which is handed over when the queue is processed somewhere else (i.e. by a
+/// vhost backend), or saved and restored (i.e. for snapshots).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QueueState {
+    /// The maximal size in elements offered by the device
+    pub max_size: u16,
+
+    /// The queue size in elements the driver selected
+    pub size: u16,
+
+    /// Indicates if the queue is finished with configuration
+    pub ready: bool,
+
+    /// Guest physical address of the descriptor table
+    pub desc_table: GuestAddress,
+
+    /// Guest physical address of the available ring
+    pub avail_ring: GuestAddress,
+
+    /// Guest physical address of the used ring
+    pub used_ring: GuestAddress,
+
+    /// The index of the next descriptor chain head in the available ring
+    pub next_avail: u16,
+
+    /// The index of the next element in the used ring
+    pub next_used: u16,
+
+    /// VIRTIO_F_RING_EVENT_IDX negotiated
+    pub event_idx_enabled: bool,
+}
+
+impl QueueState {
+    /// Returns the actual size of the queue, as the driver may not set up a queue as big as the
+    /// device allows.
+    pub fn actual_size(&self) -> u16 {
+        min(self.size, self.max_size)
+    }
+}
+
+/// Records the ranges of guest memory written by the device, such that they are transferred
+/// again during live migration.
+///
+/// The queue reports the writes to the used ring, and the devices report the writes to the
+/// buffers of the requests they complete.
+pub trait DirtyTracker: Debug + Send + Sync {
+    /// Marks the pages of guest memory which overlap a written range as dirty.
+    ///
+    /// # Arguments
+    /// * `addr` - The start of the range.
+    /// * `len` - The length of the range.
+    fn mark_dirty(&self, addr: GuestAddress, len: usize);
+}
+
+// The host mappings of the descriptor table and of the rings, which are resolved once for a
+// memory snapshot, such that the hot path accesses them through volatile slices instead of
+// translating guest addresses every time.
+struct QueueMapping<M: GuestAddressSpace> {
+    // Keeps the mappings alive, and identifies the memory snapshot they belong to.
+    mem: M::T,
+    desc_table: GuestAddress,
+    avail_ring: GuestAddress,
+    used_ring: GuestAddress,
+    queue_size: u16,
+    desc_table_host: usize,
+    avail_ring_host: usize,
+    used_ring_host: usize,
+}
+
+impl<M: GuestAddressSpace> QueueMapping<M> {
+    // Returns `None` when the queue is empty, or when one of its areas is not contained in a
+    // single memory region, or has a host address which is not aligned for its elements.
+    fn new(mem: M::T, queue: &Queue<M>) -> Option<Self> {
+        let queue_size = queue.actual_size();
+        if queue_size == 0 {
+            return None;
+        }
+        let host_addr = |addr: GuestAddress, len: usize, align: usize| {
+            mem.get_slice(addr, len)
+                .ok()
+                .map(|slice| slice.as_ptr() as usize)
+                .filter(|host_addr| host_addr % align == 0)
+        };
+        let desc_table_host = host_addr(
+            queue.desc_table,
+            desc_table_len(queue_size),
+            align_of::<Descriptor>(),
+        )?;
+        let avail_ring_host = host_addr(queue.avail_ring, avail_ring_len(queue_size), 2)?;
+        let used_ring_host = host_addr(queue.used_ring, used_ring_len(queue_size), 4)?;
+        Some(QueueMapping {
+            mem,
+            desc_table: queue.desc_table,
+            avail_ring: queue.avail_ring,
+            used_ring: queue.used_ring,
+            queue_size,
+            desc_table_host,
+            avail_ring_host,
+            used_ring_host,
+        })
+    }
+
+    // The snapshots are compared by address, which can't be reused while we hold `self.mem`.
+    fn matches(&self, mem: &M::M, queue: &Queue<M>) -> bool {
+        ptr::eq::<M::M>(&*self.mem, mem)
+            && self.desc_table == queue.desc_table
+            && self.avail_ring == queue.avail_ring
+            && self.used_ring == queue.used_ring
+            && self.queue_size == queue.actual_size()
+    }
+
+    fn avail_ring(&self) -> VolatileSlice<'_> {
+        // Safe because the mapping covers the whole ring, and stays valid while we hold the
+        // memory snapshot it was resolved from.
+        unsafe {
+            VolatileSlice::new(
+                self.avail_ring_host as *mut u8,
+                avail_ring_len(self.queue_size),
+            )
+        }
+    }
+
+    fn used_ring(&self) -> VolatileSlice<'_> {
+        // Safe for the same reasons as above.
+        unsafe {
+            VolatileSlice::new(
+                self.used_ring_host as *mut u8,
+                used_ring_len(self.queue_size),
+            )
+        }
+    }
+}
+
+// We can't derive Clone and Debug, because rustc would require M: Clone and M::T: Debug.
+impl<M: GuestAddressSpace> Clone for QueueMapping<M> {
+    fn clone(&self) -> Self {
+        QueueMapping {
+            mem: self.mem.clone(),
+            ..*self
+        }
+    }
+}
+
+impl<M: GuestAddressSpace> Debug for QueueMapping<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QueueMapping")
+            .field("desc_table", &self.desc_table)
+            .field("avail_ring", &self.avail_ring)
+            .field("used_ring", &self.used_ring)
+            .field("queue_size", &self.queue_size)
+            .finish()
+    }
+}
+
+// Returns the host address of a descriptor table with `queue_size` entries, or `None` when the
+// table is not contained in a single memory region, or its host address is not aligned for
+// `Descriptor`. The address stays valid while the caller holds `mem`.
+fn map_desc_table<M: GuestMemory + ?Sized>(
+    mem: &M,
+    desc_table: GuestAddress,
+    queue_size: u16,
+) -> Option<usize> {
+    mem.get_slice(desc_table, desc_table_len(queue_size))
+        .ok()
+        .map(|slice| slice.as_ptr() as usize)
+        .filter(|host_addr| host_addr % align_of::<Descriptor>() == 0)
+}
+
+fn desc_table_len(queue_size: u16) -> usize {
+    size_of::<Descriptor>() * usize::from(queue_size)
+}
+
+fn avail_ring_len(queue_size: u16) -> usize {
+    (VIRTQ_AVAIL_RING_META_SIZE + VIRTQ_AVAIL_ELEMENT_SIZE * u64::from(queue_size)) as usize
+}
+
+fn used_ring_len(queue_size: u16) -> usize {
+    (VIRTQ_USED_RING_META_SIZE + VIRTQ_USED_ELEMENT_SIZE * u64::from(queue_size)) as usize
+}
+
+// Accesses the fields of a ring through its host mapping if there is one, and through the
+// guest memory otherwise. The offsets are relative to the start of the ring.
+struct RingRef<'a, T: GuestMemory> {
+    mem: &'a T,
+    addr: GuestAddress,
+    slice: Option<VolatileSlice<'a>>,
+}
+
+impl<T: GuestMemory> RingRef<'_, T> {
+    fn read_slice(&self, buf: &mut [u8], offset: u64) -> Result<(), GuestMemoryError> {
+        match self.slice {
+            Some(ref slice) => slice.read_slice(buf, offset as usize).map_err(Into::into),
+            None => self.mem.read_slice(buf, self.addr.unchecked_add(offset)),
+        }
+    }
+
+    fn write_obj<O: ByteValued>(&self, val: O, offset: u64) -> Result<(), GuestMemoryError> {
+        match self.slice {
+            Some(ref slice) => slice.write_obj(val, offset as usize).map_err(Into::into),
+            None => self.mem.write_obj(val, self.addr.unchecked_add(offset)),
+        }
+    }
+
+    fn load<O: AtomicAccess>(&self, offset: u64, order: Ordering) -> Result<O, GuestMemoryError> {
+        match self.slice {
+            Some(ref slice) => slice.load(offset as usize, order).map_err(Into::into),
+            None => self.mem.load(self.addr.unchecked_add(offset), order),
+        }
+    }
+
+    fn store<O: AtomicAccess>(
+        &self,
+        val: O,
+        offset: u64,
+        order: Ordering,
+    ) -> Result<(), GuestMemoryError> {
+        match self.slice {
+            Some(ref slice) => slice.store(val, offset as usize, order).map_err(Into::into),
+            None => self.mem.store(val, self.addr.unchecked_add(offset), order),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 /// A virtio queue's parameters.
 pub struct Queue<M: GuestAddressSpace> {
@@ -422,6 +833,13 @@ pub struct Queue<M: GuestAddressSpace> {
 
     /// Guest physical address of the used ring
     pub used_ring: GuestAddress,
+
+    /// Tracks the writes to the used ring, if any
+    dirty_tracker: Option<Arc<dyn DirtyTracker>>,
+
+    /// The host mappings of the descriptor table and of the rings, resolved when the ready
+    /// queue is first iterated or used, and again after the memory or the configuration change
+    mapping: Option<QueueMapping<M>>,
 }
 
 impl<M: GuestAddressSpace> Queue<M> {
@@ -439,6 +857,8 @@ impl<M: GuestAddressSpace> Queue<M> {
             next_used: Wrapping(0),
             event_idx_enabled: false,
             signalled_used: None,
+            dirty_tracker: None,
+            mapping: None,
         }
     }
 
@@ -464,6 +884,59 @@ impl<M: GuestAddressSpace> Queue<M> {
         self.next_used = Wrapping(0);
         self.signalled_used = None;
         self.event_idx_enabled = false;
+        self.mapping = None;
+    }
+
+    /// Sets the object which tracks the writes of the queue to guest memory. The tracker is
+    /// not part of the queue state, and it's kept across resets.
+    ///
+    /// # Arguments
+    /// * `dirty_tracker` - The tracker, or `None` to stop tracking writes.
+    pub fn set_dirty_tracker(&mut self, dirty_tracker: Option<Arc<dyn DirtyTracker>>) {
+        self.dirty_tracker = dirty_tracker;
+    }
+
+    /// Returns the object which tracks the writes of the queue to guest memory, if any.
+    pub fn dirty_tracker(&self) -> Option<&Arc<dyn DirtyTracker>> {
+        self.dirty_tracker.as_ref()
+    }
+
+    // Reports a write of `len` bytes at `addr` to the dirty tracker.
+    fn mark_dirty(&self, addr: GuestAddress, len: usize) {
+        if let Some(tracker) = self.dirty_tracker.as_ref() {
+            tracker.mark_dirty(addr, len);
+        }
+    }
+
+    /// Returns the configuration and the ring indices of the queue.
+    pub fn state(&self) -> QueueState {
+        QueueState {
+            max_size: self.max_size,
+            size: self.size,
+            ready: self.ready,
+            desc_table: self.desc_table,
+            avail_ring: self.avail_ring,
+            used_ring: self.used_ring,
+            next_avail: self.next_avail.0,
+            next_used: self.next_used.0,
+            event_idx_enabled: self.event_idx_enabled,
+        }
+    }
+
+    /// Restores the configuration and the ring indices of the queue. The driver is notified
+    /// about the next used buffer, since the state doesn't track the last notification.
+    pub fn set_state(&mut self, state: &QueueState) {
+        self.max_size = state.max_size;
+        self.size = state.size;
+        self.ready = state.ready;
+        self.desc_table = state.desc_table;
+        self.avail_ring = state.avail_ring;
+        self.used_ring = state.used_ring;
+        self.next_avail = Wrapping(state.next_avail);
+        self.next_used = Wrapping(state.next_used);
+        self.event_idx_enabled = state.event_idx_enabled;
+        self.signalled_used = None;
+        self.mapping = None;
     }
 
     /// Enable/disable the VIRTIO_F_RING_EVENT_IDX feature.
@@ -474,6 +947,20 @@ impl<M: GuestAddressSpace> Queue<M> {
 
     /// Check if the virtio queue configuration is valid.
     pub fn is_valid(&self) -> bool {
+        match self.validate() {
+            Ok(()) => true,
+            Err(reason) => {
+                error!("{}", reason);
+                false
+            }
+        }
+    }
+
+    /// Checks the virtio queue configuration, and returns the reason it's not valid, if any
+    /// (i.e. for deciding whether a queue restored from a snapshot can still be used).
+    pub fn validate(&self) -> Result<(), InvalidQueueReason> {
+        use self::InvalidQueueReason::*;
+
         let mem = self.mem.memory();
         let queue_size = self.actual_size() as u64;
         let desc_table = self.desc_table;
@@ -482,122 +969,196 @@ impl<M: GuestAddressSpace> Queue<M> {
         let avail_ring_size = VIRTQ_AVAIL_RING_META_SIZE + VIRTQ_AVAIL_ELEMENT_SIZE * queue_size;
         let used_ring = self.used_ring;
         let used_ring_size = VIRTQ_USED_RING_META_SIZE + VIRTQ_USED_ELEMENT_SIZE * queue_size;
+        let out_of_bounds = |start: GuestAddress, size: u64| {
+            start
+                .checked_add(size)
+                .map_or(true, |v| !mem.address_in_range(v))
+        };
+
         if !self.ready {
-            error!("attempt to use virtio queue that is not marked ready");
-            false
+            Err(NotReady)
         } else if self.size > self.max_size || self.size == 0 || (self.size & (self.size - 1)) != 0
         {
-            error!("virtio queue with invalid size: {}", self.size);
-            false
-        } else if desc_table
-            .checked_add(desc_table_size)
-            .map_or(true, |v| !mem.address_in_range(v))
-        {
-            error!(
-                "virtio queue descriptor table goes out of bounds: start:0x{:08x} size:0x{:08x}",
-                desc_table.raw_value(),
-                desc_table_size
-            );
-            false
-        } else if avail_ring
-            .checked_add(avail_ring_size)
-            .map_or(true, |v| !mem.address_in_range(v))
-        {
-            error!(
-                "virtio queue available ring goes out of bounds: start:0x{:08x} size:0x{:08x}",
-                avail_ring.raw_value(),
-                avail_ring_size
-            );
-            false
-        } else if used_ring
-            .checked_add(used_ring_size)
-            .map_or(true, |v| !mem.address_in_range(v))
-        {
-            error!(
-                "virtio queue used ring goes out of bounds: start:0x{:08x} size:0x{:08x}",
-                used_ring.raw_value(),
-                used_ring_size
-            );
-            false
+            Err(InvalidSize(self.size))
+        } else if out_of_bounds(desc_table, desc_table_size) {
+            Err(DescTableOutOfBounds(desc_table, desc_table_size))
+        } else if out_of_bounds(avail_ring, avail_ring_size) {
+            Err(AvailRingOutOfBounds(avail_ring, avail_ring_size))
+        } else if out_of_bounds(used_ring, used_ring_size) {
+            Err(UsedRingOutOfBounds(used_ring, used_ring_size))
         } else if desc_table.mask(0xf) != 0 {
-            error!("virtio queue descriptor table breaks alignment contraints");
-            false
+            Err(DescTableMisaligned(desc_table))
         } else if avail_ring.mask(0x1) != 0 {
-            error!("virtio queue available ring breaks alignment contraints");
-            false
+            Err(AvailRingMisaligned(avail_ring))
         } else if used_ring.mask(0x3) != 0 {
-            error!("virtio queue used ring breaks alignment contraints");
-            false
+            Err(UsedRingMisaligned(used_ring))
         } else {
-            true
+            Ok(())
         }
     }
 
     /// Reads the `idx` field from the available ring.
     pub fn avail_idx(&self, order: Ordering) -> Result<Wrapping<u16>, Error> {
-        let addr = self.avail_ring.unchecked_add(2);
-        self.mem
-            .memory()
-            .load(addr, order)
+        let mem = self.mem.memory();
+        self.avail_ring_ref(&mem)
+            .load(2, order)
+            .map(Wrapping)
+            .map_err(Error::GuestMemory)
+    }
+
+    /// Reads the `idx` field from the used ring.
+    pub fn used_idx(&self, order: Ordering) -> Result<Wrapping<u16>, Error> {
+        let mem = self.mem.memory();
+        self.used_ring_ref(&mem)
+            .load(2, order)
             .map(Wrapping)
             .map_err(Error::GuestMemory)
     }
 
+    /// Returns the descriptor chain which starts at `head_index`, without consuming anything
+    /// from the available ring. This is useful for resubmitting chains which were popped
+    /// before, but never returned to the driver (i.e. after a device backend restarts).
+    pub fn chain_at(&self, head_index: u16) -> Result<DescriptorChain<M>, Error> {
+        if head_index >= self.actual_size() {
+            return Err(Error::InvalidDescriptorIndex);
+        }
+
+        let mem = self.mem.memory();
+        let desc_table_host = self.mapping(&mem).map(|mapping| mapping.desc_table_host);
+        Ok(DescriptorChain::with_mapping(
+            mem,
+            self.desc_table,
+            desc_table_host,
+            self.actual_size(),
+            head_index,
+        ))
+    }
+
+    // Returns the mapping of the queue, if it was resolved for `mem` and the current queue
+    // configuration.
+    fn mapping(&self, mem: &M::M) -> Option<&QueueMapping<M>> {
+        self.mapping
+            .as_ref()
+            .filter(|mapping| mapping.matches(mem, self))
+    }
+
+    // Resolves the mapping of the queue again if it doesn't belong to `mem` and the current
+    // queue configuration. Queues which are not ready are not mapped.
+    fn update_mapping(&mut self, mem: &M::T) {
+        if !self.ready {
+            self.mapping = None;
+        } else if self.mapping(mem).is_none() {
+            self.mapping = QueueMapping::new(mem.clone(), self);
+        }
+    }
+
+    fn avail_ring_ref<'a>(&'a self, mem: &'a M::M) -> RingRef<'a, M::M> {
+        RingRef {
+            mem,
+            addr: self.avail_ring,
+            slice: self.mapping(mem).map(QueueMapping::avail_ring),
+        }
+    }
+
+    fn used_ring_ref<'a>(&'a self, mem: &'a M::M) -> RingRef<'a, M::M> {
+        RingRef {
+            mem,
+            addr: self.used_ring,
+            slice: self.mapping(mem).map(QueueMapping::used_ring),
+        }
+    }
+
     /// A consuming iterator over all available descriptor chain heads offered by the driver.
     pub fn iter(&mut self) -> Result<AvailIter<'_, M>, Error> {
-        self.avail_idx(Ordering::Acquire).map(move |idx| AvailIter {
-            mem: self.mem.memory(),
+        let mem = self.mem.memory();
+        self.update_mapping(&mem);
+        // Pairs with the driver's release store of `idx` (a write barrier before the update in
+        // the virtio spec), so the entries it publishes are visible to the iterator.
+        let idx = self
+            .avail_ring_ref(&mem)
+            .load(2, Ordering::Acquire)
+            .map(Wrapping)
+            .map_err(Error::GuestMemory)?;
+        Ok(AvailIter {
+            mem,
+            mapping: self.mapping.as_ref(),
             desc_table: self.desc_table,
             avail_ring: self.avail_ring,
             last_index: idx,
             queue_size: self.actual_size(),
             next_avail: &mut self.next_avail,
+            heads: [0; AVAIL_BATCH_SIZE],
+            heads_pos: 0,
+            heads_len: 0,
+            error: None,
         })
     }
 
     /// Puts an available descriptor head into the used ring for use by the guest.
     pub fn add_used(&mut self, head_index: u16, len: u32) -> Result<(), Error> {
-        if head_index >= self.actual_size() {
-            error!(
-                "attempted to add out of bounds descriptor to used ring: {}",
-                head_index
-            );
+        self.add_used_batch(&[(head_index, len)])
+    }
+
+    /// Puts multiple available descriptor heads into the used ring, as `(head_index, len)`
+    /// pairs, and makes them visible to the guest at once. This is required when the driver
+    /// expects a group of used elements to be published together (i.e. the buffers of a packet
+    /// which spans multiple descriptor chains).
+    pub fn add_used_batch(&mut self, elems: &[(u16, u32)]) -> Result<(), Error> {
+        if elems
+            .iter()
+            .any(|&(head_index, _)| head_index >= self.actual_size())
+        {
             return Err(Error::InvalidDescriptorIndex);
         }
 
         let mem = self.mem.memory();
-        let next_used_index = u64::from(self.next_used.0 % self.actual_size());
-        let addr = self.used_ring.unchecked_add(4 + next_used_index * 8);
-        mem.write_obj(VirtqUsedElem::new(head_index, len), addr)
-            .map_err(Error::GuestMemory)?;
-
-        self.next_used += Wrapping(1);
+        self.update_mapping(&mem);
+        let used_ring = self.used_ring_ref(&mem);
+        let mut next_used = self.next_used;
+        for &(head_index, len) in elems {
+            let next_used_index = u64::from(next_used.0 % self.actual_size());
+            let offset = VIRTQ_USED_RING_HEADER_SIZE + next_used_index * VIRTQ_USED_ELEMENT_SIZE;
+            used_ring
+                .write_obj(VirtqUsedElem::new(head_index, len), offset)
+                .map_err(Error::GuestMemory)?;
+            self.mark_dirty(
+                self.used_ring.unchecked_add(offset),
+                size_of::<VirtqUsedElem>(),
+            );
+            next_used += Wrapping(1);
+        }
 
-        mem.store(
-            self.next_used.0,
-            self.used_ring.unchecked_add(2),
-            Ordering::Release,
-        )
-        .map_err(Error::GuestMemory)
+        // Pairs with the driver's acquire load of the used `idx` (the read barrier after it in
+        // the virtio spec), so the elements written above are visible before the index.
+        used_ring
+            .store(next_used.0, 2, Ordering::Release)
+            .map_err(Error::GuestMemory)?;
+        self.mark_dirty(self.used_ring.unchecked_add(2), size_of::<u16>());
+        self.next_used = next_used;
+        Ok(())
     }
 
     // Helper method that writes `val` to the `avail_event` field of the used ring, using
     // the provided ordering.
     fn set_avail_event(&self, val: u16, order: Ordering) -> Result<(), Error> {
-        let offset = (4 + self.actual_size() * 8) as u64;
-        let addr = self.used_ring.unchecked_add(offset);
-        self.mem
-            .memory()
-            .store(val, addr, order)
-            .map_err(Error::GuestMemory)
+        let offset =
+            VIRTQ_USED_RING_HEADER_SIZE + u64::from(self.actual_size()) * VIRTQ_USED_ELEMENT_SIZE;
+        let mem = self.mem.memory();
+        self.used_ring_ref(&mem)
+            .store(val, offset, order)
+            .map_err(Error::GuestMemory)?;
+        self.mark_dirty(self.used_ring.unchecked_add(offset), size_of::<u16>());
+        Ok(())
     }
 
     // Set the value of the `flags` field of the used ring, applying the specified ordering.
     fn set_used_flags(&mut self, val: u16, order: Ordering) -> Result<(), Error> {
-        self.mem
-            .memory()
-            .store(val, self.used_ring, order)
-            .map_err(Error::GuestMemory)
+        let mem = self.mem.memory();
+        self.used_ring_ref(&mem)
+            .store(val, 0, order)
+            .map_err(Error::GuestMemory)?;
+        self.mark_dirty(self.used_ring, size_of::<u16>());
+        Ok(())
     }
 
     // Write the appropriate values to enable or disable notifications from the driver. Every
@@ -622,10 +1183,6 @@ impl<M: GuestAddressSpace> Queue<M> {
         Ok(())
     }
 
-    /// Enable notification events from the guest driver. Returns true if one or more descriptors
-    /// can be consumed from the available ring after notifications were enabled (and thus it's
-    /// possible there will be no corresponding notification).
-
     // TODO: Turn this into a doc comment/example.
     // With the current implementation, a common way of consuming entries from the available ring
     // while also leveraging notification suppression is to use a loop, for example:
@@ -646,10 +1203,16 @@ impl<M: GuestAddressSpace> Queue<M> {
     //         break;
     //     }
     // }
+    /// Enable notification events from the guest driver. Returns true if one or more descriptors
+    /// can be consumed from the available ring after notifications were enabled (and thus it's
+    /// possible there will be no corresponding notification).
     #[inline]
     pub fn enable_notification(&mut self) -> Result<bool, Error> {
         self.set_notification(true)?;
-        // Ensures the following read is not reordered before any previous write operation.
+        // Ensures the following read is not reordered before any previous write operation. The
+        // driver does the opposite (it writes `idx`, then reads the notification suppression
+        // fields), and only a full fence orders a store before a later load, so this can't be
+        // relaxed to an acquire/release fence.
         fence(Ordering::SeqCst);
 
         // We double check here to avoid the situation where the available ring has been updated
@@ -658,6 +1221,8 @@ impl<M: GuestAddressSpace> Queue<M> {
         // entries. There are situations where we intentionally avoid processing everything in the
         // available ring (which will cause this method to return `true`), but in that case we'll
         // probably not re-enable notifications as we already know there are pending entries.
+        // The fence above orders the load, and the entries are read through `iter`, whose
+        // `Acquire` load synchronizes with the driver, so `Relaxed` is enough here.
         self.avail_idx(Ordering::Relaxed)
             .map(|idx| idx != self.next_avail)
     }
@@ -679,14 +1244,11 @@ impl<M: GuestAddressSpace> Queue<M> {
     /// with the device, but they serve as useful optimizations. So we only ensure access to the
     /// virtq_avail.used_event is atomic, but do not need to synchronize with other memory accesses.
     fn used_event(&self, order: Ordering) -> Result<Wrapping<u16>, Error> {
-        // Safe because we have validated the queue and access guest memory through GuestMemory
-        // interfaces.
+        let offset =
+            VIRTQ_AVAIL_RING_HEADER_SIZE + u64::from(self.actual_size()) * VIRTQ_AVAIL_ELEMENT_SIZE;
         let mem = self.mem.memory();
-        let used_event_addr = self
-            .avail_ring
-            .unchecked_add((4 + self.actual_size() * 2) as u64);
-
-        mem.load(used_event_addr, order)
+        self.avail_ring_ref(&mem)
+            .load(offset, order)
             .map(Wrapping)
             .map_err(Error::GuestMemory)
     }
@@ -700,12 +1262,15 @@ impl<M: GuestAddressSpace> Queue<M> {
     pub fn needs_notification(&mut self) -> Result<bool, Error> {
         let used_idx = self.next_used;
 
-        // Complete all the writes in add_used() before reading the event.
+        // Complete all the writes in add_used() before reading the event. Like in
+        // `enable_notification`, this orders a store before a load, which needs a full fence.
         fence(Ordering::SeqCst);
 
         // The VRING_AVAIL_F_NO_INTERRUPT flag isn't supported yet.
         if self.event_idx_enabled {
             if let Some(old_idx) = self.signalled_used.replace(used_idx) {
+                // Ordered by the fence above. Nothing is read based on `used_event`, so there's
+                // no driver release store to pair with (see `used_event`).
                 let used_event = self.used_event(Ordering::Relaxed)?;
                 // This check looks at `used_idx`, `used_event`, and `old_idx` as if they are on
                 // an axis that wraps around. If `used_idx - used_used - Wrapping(1)` is greater
@@ -737,245 +1302,15 @@ impl<M: GuestAddressSpace> Queue<M> {
     pub fn set_next_avail(&mut self, next_avail: u16) {
         self.next_avail = Wrapping(next_avail);
     }
-}
 
-#[allow(missing_docs)]
-#[cfg(feature = "test-utils")]
-pub mod test_utils {
-    use super::*;
+    /// Returns the index for the next descriptor in the used ring.
+    pub fn next_used(&self) -> u16 {
+        self.next_used.0
+    }
 
-    use std::marker::PhantomData;
-    use std::mem;
-
-    use vm_memory::{
-        GuestAddress, GuestMemoryMmap, GuestMemoryRegion, GuestUsize, VolatileMemory, VolatileRef,
-        VolatileSlice,
-    };
-
-    impl Descriptor {
-        // Only available to unit tests within the local crate.
-        pub fn new(addr: u64, len: u32, flags: u16, next: u16) -> Self {
-            Descriptor {
-                addr,
-                len,
-                flags,
-                next,
-            }
-        }
-    }
-
-    // Represents a virtio descriptor in guest memory.
-    pub struct VirtqDesc<'a> {
-        desc: VolatileSlice<'a>,
-    }
-
-    /// Extracts the displacement of a field in a struct
-    #[macro_export]
-    macro_rules! offset_of {
-        ($ty:ty, $field:ident) => {
-            unsafe { &(*std::ptr::null::<$ty>()).$field as *const _ as usize }
-        };
-    }
-
-    #[allow(clippy::len_without_is_empty)]
-    impl<'a> VirtqDesc<'a> {
-        pub fn new(dtable: &'a VolatileSlice<'a>, i: u16) -> Self {
-            let desc = dtable
-                .get_slice((i as usize) * Self::dtable_len(1), Self::dtable_len(1))
-                .unwrap();
-            VirtqDesc { desc }
-        }
-
-        pub fn addr(&self) -> VolatileRef<u64> {
-            self.desc.get_ref(offset_of!(Descriptor, addr)).unwrap()
-        }
-
-        pub fn len(&self) -> VolatileRef<u32> {
-            self.desc.get_ref(offset_of!(Descriptor, len)).unwrap()
-        }
-
-        pub fn flags(&self) -> VolatileRef<u16> {
-            self.desc.get_ref(offset_of!(Descriptor, flags)).unwrap()
-        }
-
-        pub fn next(&self) -> VolatileRef<u16> {
-            self.desc.get_ref(offset_of!(Descriptor, next)).unwrap()
-        }
-
-        pub fn set(&self, addr: u64, len: u32, flags: u16, next: u16) {
-            self.addr().store(addr);
-            self.len().store(len);
-            self.flags().store(flags);
-            self.next().store(next);
-        }
-
-        pub fn dtable_len(nelem: u16) -> usize {
-            16 * nelem as usize
-        }
-    }
-
-    // Represents a virtio queue ring. The only difference between the used and available rings,
-    // is the ring element type.
-    pub struct VirtqRing<'a, T> {
-        ring: VolatileSlice<'a>,
-        start: GuestAddress,
-        qsize: u16,
-        _marker: PhantomData<*const T>,
-    }
-
-    impl<'a, T> VirtqRing<'a, T>
-    where
-        T: vm_memory::ByteValued,
-    {
-        fn new(
-            start: GuestAddress,
-            mem: &'a GuestMemoryMmap,
-            qsize: u16,
-            alignment: GuestUsize,
-        ) -> Self {
-            assert_eq!(start.0 & (alignment - 1), 0);
-
-            let (region, addr) = mem.to_region_addr(start).unwrap();
-            let size = Self::ring_len(qsize);
-            let ring = region.get_slice(addr, size).unwrap();
-
-            let result = VirtqRing {
-                ring,
-                start,
-                qsize,
-                _marker: PhantomData,
-            };
-
-            result.flags().store(0);
-            result.idx().store(0);
-            result.event().store(0);
-            result
-        }
-
-        pub fn start(&self) -> GuestAddress {
-            self.start
-        }
-
-        pub fn end(&self) -> GuestAddress {
-            self.start.unchecked_add(self.ring.len() as GuestUsize)
-        }
-
-        pub fn flags(&self) -> VolatileRef<u16> {
-            self.ring.get_ref(0).unwrap()
-        }
-
-        pub fn idx(&self) -> VolatileRef<u16> {
-            self.ring.get_ref(2).unwrap()
-        }
-
-        fn ring_offset(i: u16) -> usize {
-            4 + mem::size_of::<T>() * (i as usize)
-        }
-
-        pub fn ring(&self, i: u16) -> VolatileRef<T> {
-            assert!(i < self.qsize);
-            self.ring.get_ref(Self::ring_offset(i)).unwrap()
-        }
-
-        pub fn event(&self) -> VolatileRef<u16> {
-            self.ring.get_ref(Self::ring_offset(self.qsize)).unwrap()
-        }
-
-        fn ring_len(qsize: u16) -> usize {
-            Self::ring_offset(qsize) + 2
-        }
-    }
-
-    pub type VirtqAvail<'a> = VirtqRing<'a, u16>;
-    pub type VirtqUsed<'a> = VirtqRing<'a, VirtqUsedElem>;
-
-    trait GuestAddressExt {
-        fn align_up(&self, x: GuestUsize) -> GuestAddress;
-    }
-    impl GuestAddressExt for GuestAddress {
-        fn align_up(&self, x: GuestUsize) -> GuestAddress {
-            Self((self.0 + (x - 1)) & !(x - 1))
-        }
-    }
-
-    pub struct VirtQueue<'a> {
-        start: GuestAddress,
-        dtable: VolatileSlice<'a>,
-        pub avail: VirtqAvail<'a>,
-        pub used: VirtqUsed<'a>,
-    }
-
-    impl<'a> VirtQueue<'a> {
-        // We try to make sure things are aligned properly :-s
-        pub fn new(start: GuestAddress, mem: &'a GuestMemoryMmap, qsize: u16) -> Self {
-            // power of 2?
-            assert!(qsize > 0 && qsize & (qsize - 1) == 0);
-
-            let (region, addr) = mem.to_region_addr(start).unwrap();
-            let dtable = region
-                .get_slice(addr, VirtqDesc::dtable_len(qsize))
-                .unwrap();
-
-            const AVAIL_ALIGN: GuestUsize = 2;
-
-            let avail_addr = start
-                .unchecked_add(VirtqDesc::dtable_len(qsize) as GuestUsize)
-                .align_up(AVAIL_ALIGN);
-            let avail = VirtqAvail::new(avail_addr, mem, qsize, AVAIL_ALIGN);
-
-            const USED_ALIGN: GuestUsize = 4;
-
-            let used_addr = avail.end().align_up(USED_ALIGN);
-            let used = VirtqUsed::new(used_addr, mem, qsize, USED_ALIGN);
-
-            VirtQueue {
-                start,
-                dtable,
-                avail,
-                used,
-            }
-        }
-
-        pub fn size(&self) -> u16 {
-            (self.dtable.len() / VirtqDesc::dtable_len(1)) as u16
-        }
-
-        pub fn dtable(&self, i: u16) -> VirtqDesc {
-            VirtqDesc::new(&self.dtable, i)
-        }
-
-        pub fn dtable_start(&self) -> GuestAddress {
-            self.start
-        }
-
-        pub fn avail_start(&self) -> GuestAddress {
-            self.avail.start()
-        }
-
-        pub fn used_start(&self) -> GuestAddress {
-            self.used.start()
-        }
-
-        // Creates a new Queue, using the underlying memory regions represented by the VirtQueue.
-        pub fn create_queue(&self, mem: &'a GuestMemoryMmap) -> Queue<&'a GuestMemoryMmap> {
-            let mut q = Queue::new(mem, self.size());
-
-            q.size = self.size();
-            q.ready = true;
-            q.desc_table = self.dtable_start();
-            q.avail_ring = self.avail_start();
-            q.used_ring = self.used_start();
-
-            q
-        }
-
-        pub fn start(&self) -> GuestAddress {
-            self.dtable_start()
-        }
-
-        pub fn end(&self) -> GuestAddress {
-            self.used.end()
-        }
+    /// Sets the index for the next descriptor in the used ring.
+    pub fn set_next_used(&mut self, next_used: u16) {
+        self.next_used = Wrapping(next_used);
     }
 }
 
@@ -983,10 +1318,17 @@ pub mod test_utils {
 mod tests {
     use super::*;
 
-    use test_utils::*;
+    use std::mem::offset_of;
+
+    use mock::*;
+    use strategies::*;
 
+    use proptest::prelude::*;
     use vm_memory::{GuestAddress, GuestMemoryMmap, GuestMemoryRegion, MemoryRegionAddress};
 
+    // The size of the guest memory of the property tests.
+    const PROP_MEM_SIZE: u64 = 0x1_0000;
+
     #[test]
     pub fn test_offset() {
         assert_eq!(offset_of!(Descriptor, addr), 0);
@@ -1069,8 +1411,10 @@ mod tests {
 
         let mut c: DescriptorChain<&GuestMemoryMmap> = DescriptorChain::new(m, vq.start(), 16, 0);
 
-        // The chain logic hasn't parsed the indirect descriptor yet.
+        // The chain logic hasn't parsed the indirect descriptor yet, but the descriptor table
+        // was resolved when the chain was created.
         assert!(!c.is_indirect);
+        assert!(c.desc_table_host.is_some());
 
         let region = m.find_region(GuestAddress(0)).unwrap();
         let dtable = region
@@ -1081,7 +1425,7 @@ mod tests {
         for j in 0..4 {
             let desc = VirtqDesc::new(&dtable, j);
             if j < 3 {
-                desc.set(0x1000, 0x1000, VIRTQ_DESC_F_NEXT, (j + 1) as u16);
+                desc.set(0x1000, 0x1000, VIRTQ_DESC_F_NEXT, j + 1);
             } else {
                 desc.set(0x1000, 0x1000, 0, 0_u16);
             }
@@ -1099,6 +1443,8 @@ mod tests {
         for j in 0..4 {
             let desc = c.next().unwrap();
             assert!(c.is_indirect);
+            // The indirect table is read through its host mapping as well.
+            assert_eq!(c.desc_table_host, Some(dtable.as_ptr() as usize));
             if j < 3 {
                 assert_eq!(desc.flags(), VIRTQ_DESC_F_NEXT);
                 assert_eq!(desc.next, j + 1);
@@ -1137,6 +1483,232 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_validate() {
+        use super::InvalidQueueReason::*;
+
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        assert_eq!(q.validate(), Ok(()));
+
+        q.ready = false;
+        assert_eq!(q.validate(), Err(NotReady));
+        q.ready = true;
+
+        q.size = 11;
+        assert_eq!(q.validate(), Err(InvalidSize(11)));
+        q.size = q.max_size;
+
+        // Out of bounds areas are reported before misaligned ones.
+        q.desc_table = GuestAddress(0xffff_ffff);
+        q.used_ring = GuestAddress(0x1001);
+        assert_eq!(
+            q.validate(),
+            Err(DescTableOutOfBounds(GuestAddress(0xffff_ffff), 256))
+        );
+        q.desc_table = GuestAddress(0x1001);
+        assert_eq!(q.validate(), Err(DescTableMisaligned(GuestAddress(0x1001))));
+        q.desc_table = vq.dtable_start();
+        assert_eq!(q.validate(), Err(UsedRingMisaligned(GuestAddress(0x1001))));
+        q.used_ring = GuestAddress(0xfff0);
+        assert_eq!(
+            q.validate(),
+            Err(UsedRingOutOfBounds(GuestAddress(0xfff0), 6 + 8 * 16))
+        );
+        q.used_ring = vq.used_start();
+
+        q.avail_ring = GuestAddress(0x1001);
+        assert_eq!(q.validate(), Err(AvailRingMisaligned(GuestAddress(0x1001))));
+        q.avail_ring = GuestAddress(0xfff0);
+        assert_eq!(
+            q.validate(),
+            Err(AvailRingOutOfBounds(GuestAddress(0xfff0), 6 + 2 * 16))
+        );
+        q.avail_ring = vq.avail_start();
+
+        assert!(q.validate().is_ok());
+    }
+
+    #[test]
+    fn test_queue_mapping() {
+        use vm_memory::GuestMemoryAtomic;
+
+        let m = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &m, 16);
+        let mem = GuestMemoryAtomic::new(m.clone());
+        let mut q = Queue::new(mem.clone(), 16);
+        q.desc_table = vq.dtable_start();
+        q.avail_ring = vq.avail_start();
+        q.used_ring = vq.used_start();
+
+        vq.dtable(0).set(0x1000, 0x100, 0, 0);
+        vq.avail.ring(0).store(0);
+        vq.avail.idx().store(1);
+
+        // The queue is only mapped once it's ready.
+        assert_eq!(
+            q.iter().unwrap().next().unwrap().next().unwrap().addr().0,
+            0x1000
+        );
+        assert!(q.mapping.is_none());
+
+        q.ready = true;
+        q.set_next_avail(0);
+        assert_eq!(
+            q.iter().unwrap().next().unwrap().next().unwrap().addr().0,
+            0x1000
+        );
+        assert_eq!(q.mapping.as_ref().unwrap().desc_table, vq.dtable_start());
+        assert_eq!(
+            q.chain_at(0).unwrap().desc_table_host,
+            Some(q.mapping.as_ref().unwrap().desc_table_host)
+        );
+
+        // The mapping is resolved again when the table moves.
+        m.write_obj(Descriptor::new(0x2000, 0x200, 0, 0), GuestAddress(0x8000))
+            .unwrap();
+        q.desc_table = GuestAddress(0x8000);
+        vq.avail.ring(1).store(0);
+        vq.avail.idx().store(2);
+        assert_eq!(
+            q.iter().unwrap().next().unwrap().next().unwrap().addr().0,
+            0x2000
+        );
+        assert_eq!(q.mapping.as_ref().unwrap().desc_table, GuestAddress(0x8000));
+
+        // And when the guest memory changes.
+        let other = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        other
+            .write_obj(Descriptor::new(0x3000, 0x300, 0, 0), GuestAddress(0x8000))
+            .unwrap();
+        other
+            .write_obj(0u16, vq.avail_start().unchecked_add(4))
+            .unwrap();
+        other
+            .write_obj(1u16, vq.avail_start().unchecked_add(2))
+            .unwrap();
+        mem.lock().unwrap().replace(other.clone());
+        q.set_next_avail(0);
+        assert_eq!(
+            q.iter().unwrap().next().unwrap().next().unwrap().addr().0,
+            0x3000
+        );
+        assert_eq!(q.chain_at(0).unwrap().next().unwrap().addr().0, 0x3000);
+        // The used ring is written through the new mapping as well.
+        let next_used = q.next_used();
+        q.add_used(0, 0x10).unwrap();
+        assert_eq!(
+            other
+                .read_obj::<u16>(vq.used_start().unchecked_add(2))
+                .unwrap(),
+            next_used + 1
+        );
+        assert_eq!(vq.used.idx().load(), 0);
+
+        // An unaligned table is read without a mapping.
+        q.desc_table = GuestAddress(0x8001);
+        q.set_next_avail(0);
+        assert!(q.iter().unwrap().next().is_some());
+        assert!(q.mapping.is_none());
+    }
+
+    #[test]
+    fn test_avail_iter_batches() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        // The pending entries wrap around the end of the ring, and exceed its size.
+        q.set_next_avail(10);
+        for i in 0..16 {
+            vq.dtable(i).set(0x1000 * u64::from(i), 0x100, 0, 0);
+            vq.avail.ring(i).store(15 - i);
+        }
+        vq.avail.idx().store(30);
+        let heads: Vec<u16> = q.iter().unwrap().map(|chain| chain.head_index()).collect();
+        let expected: Vec<u16> = (10..30).map(|i| 15 - i % 16).collect();
+        assert_eq!(heads, expected);
+        assert_eq!(q.next_avail(), 30);
+
+        // The entries made available after the iterator is created are not returned.
+        let mut iter = q.iter().unwrap();
+        vq.avail.idx().store(31);
+        assert!(iter.next().is_none());
+        assert_eq!(q.iter().unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_avail_iter_error() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        // Only the header of the available ring is within the guest memory.
+        q.avail_ring = GuestAddress(0x10000 - 4);
+        m.write_obj(1u16, q.avail_ring.unchecked_add(2)).unwrap();
+
+        let mut iter = q.iter().unwrap();
+        assert!(iter.error().is_none());
+        assert!(iter.next().is_none());
+        match iter.error() {
+            Some(Error::GuestMemory(_)) => (),
+            e => panic!("unexpected error: {:?}", e),
+        }
+        // The iteration stops for good after an error.
+        assert!(iter.next().is_none());
+        assert_eq!(q.next_avail(), 0);
+    }
+
+    #[test]
+    fn test_memory_faults() {
+        let m = &FaultyMemory::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m.memory(), 16);
+        let mut q = vq.create_queue(m);
+        for i in 0..2 {
+            vq.dtable(i).set(0x1000 * u64::from(i + 1), 0x100, 0, 0);
+            vq.avail.ring(i).store(i);
+        }
+        vq.avail.idx().store(2);
+
+        // The index of the available ring can't be read.
+        m.fail_after(0);
+        match q.iter() {
+            Err(Error::GuestMemory(_)) => (),
+            Err(e) => panic!("unexpected error: {:?}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+        m.clear();
+
+        // Neither can its entries; nothing is consumed.
+        m.fail_range(vq.avail_start().unchecked_add(4), 4, Access::Read);
+        let mut iter = q.iter().unwrap();
+        assert!(iter.next().is_none());
+        assert!(matches!(iter.error(), Some(Error::GuestMemory(_))));
+        assert_eq!(q.next_avail(), 0);
+        m.clear();
+
+        // A descriptor which can't be read ends its chain.
+        m.fail_range(vq.dtable_start().unchecked_add(16), 16, Access::Read);
+        let mut iter = q.iter().unwrap();
+        assert_eq!(iter.next().unwrap().count(), 1);
+        assert_eq!(iter.next().unwrap().count(), 0);
+        assert!(iter.error().is_none());
+        assert_eq!(q.next_avail(), 2);
+        m.clear();
+
+        // The used ring can't be written; the element isn't published.
+        m.fail_range(vq.used_start().unchecked_add(2), 2, Access::Write);
+        assert!(matches!(q.add_used(0, 0x100), Err(Error::GuestMemory(_))));
+        assert_eq!(q.next_used(), 0);
+        assert_eq!(vq.used.idx().load(), 0);
+        assert_eq!(m.injected(), 4);
+        m.clear();
+        q.add_used(0, 0x100).unwrap();
+        assert_eq!(vq.used.idx().load(), 1);
+    }
+
     #[test]
     fn test_queue_and_iterator() {
         let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
@@ -1200,12 +1772,8 @@ mod tests {
 
         {
             for j in 0..5 {
-                vq.dtable(j).set(
-                    0x1000 * (j + 1) as u64,
-                    0x1000,
-                    VIRTQ_DESC_F_NEXT,
-                    (j + 1) as u16,
-                );
+                vq.dtable(j)
+                    .set(0x1000 * (j + 1) as u64, 0x1000, VIRTQ_DESC_F_NEXT, j + 1);
             }
 
             // the chains are (0, 1) and (2, 3, 4)
@@ -1262,12 +1830,8 @@ mod tests {
         assert!(q.is_valid());
 
         for j in 0..7 {
-            vq.dtable(j).set(
-                0x1000 * (j + 1) as u64,
-                0x1000,
-                VIRTQ_DESC_F_NEXT,
-                (j + 1) as u16,
-            );
+            vq.dtable(j)
+                .set(0x1000 * (j + 1) as u64, 0x1000, VIRTQ_DESC_F_NEXT, j + 1);
         }
 
         // the chains are (0, 1), (2, 3, 4) and (5, 6)
@@ -1320,6 +1884,83 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_descriptor_chain_builder() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut builder = DescriptorChainBuilder::new(GuestAddress(0), m, 4);
+
+        // The `NEXT` flags and the `next` fields are set according to the order of the
+        // descriptors, while the other flags are kept.
+        let descs = [
+            Descriptor::new(0x1000, 0x10, 0, 3),
+            Descriptor::new(0x2000, 0x20, VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT, 0),
+        ];
+        let chain = builder.build(&descs);
+        assert_eq!(chain.head_index(), 0);
+        let read = chain
+            .map(|d| (d.addr().0, d.len(), d.flags(), d.next()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            read,
+            vec![
+                (0x1000, 0x10, VIRTQ_DESC_F_NEXT, 1),
+                (0x2000, 0x20, VIRTQ_DESC_F_WRITE, 0)
+            ]
+        );
+
+        // The built chains aren't available, unlike the added ones.
+        let head = builder.add_chain(&descs[..1]);
+        assert_eq!(head, 2);
+        assert_eq!(builder.virt_queue().avail.idx().load(), 1);
+        let mut q = builder.create_queue();
+        let mut i = q.iter().unwrap();
+        let c = i.next().unwrap();
+        assert_eq!(c.head_index(), 2);
+        assert_eq!(c.count(), 1);
+        assert!(i.next().is_none());
+    }
+
+    #[test]
+    fn test_attack_patterns() {
+        // Two regions, so the chains can straddle the boundary between them.
+        let m = &GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x10000),
+            (GuestAddress(0x10000), 0x10000),
+        ])
+        .unwrap();
+
+        // The chains end after at most `qsize` descriptors, and the ones which can't be
+        // followed are cut short.
+        let expected = [16, 16, 3, 3, 3, 2, 0, 2, 0];
+        for (pattern, &count) in AttackPattern::ALL.iter().zip(expected.iter()) {
+            let mut builder = DescriptorChainBuilder::new(GuestAddress(0), m, 16);
+            assert_eq!(
+                builder.build_attack(*pattern).count(),
+                count,
+                "{:?}",
+                pattern
+            );
+        }
+
+        // All the patterns fit in the same table, and their chains are available in order.
+        let vq = VirtQueue::new(GuestAddress(0), m, 32);
+        let mut heads = Vec::new();
+        for (i, pattern) in AttackPattern::ALL.iter().enumerate() {
+            let first = i as u16 * AttackPattern::MAX_DESCRIPTORS;
+            heads.push(vq.add_attack(*pattern, first, GuestAddress(0x1000)));
+        }
+        assert_eq!(heads.last(), Some(&32));
+        let mut q = vq.create_queue(m);
+        let chains = q.iter().unwrap().collect::<Vec<_>>();
+        assert_eq!(
+            chains.iter().map(|c| c.head_index()).collect::<Vec<_>>(),
+            heads
+        );
+        for c in chains {
+            assert!(c.count() <= 32);
+        }
+    }
+
     #[test]
     fn test_add_used() {
         let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
@@ -1339,6 +1980,151 @@ mod tests {
         let x = vq.used.ring(0).load();
         assert_eq!(x.id, 1);
         assert_eq!(x.len, 0x1000);
+
+        // The used ring index can be restored, e.g. when the queue is handed over to another
+        // process.
+        q.set_next_used(5);
+        q.add_used(2, 0x200).unwrap();
+        assert_eq!(q.next_used(), 6);
+        assert_eq!(vq.used.idx().load(), 6);
+        assert_eq!(vq.used.ring(5).load().id, 2);
+    }
+
+    #[test]
+    fn test_queue_state() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let mut q = vq.create_queue(m);
+        q.size = 8;
+        q.set_next_avail(3);
+        q.set_next_used(2);
+        q.set_event_idx(true);
+        let state = q.state();
+        assert_eq!(state.actual_size(), 8);
+        assert_eq!(state.desc_table, vq.dtable_start());
+        assert_eq!(state.avail_ring, vq.avail_start());
+        assert_eq!(state.used_ring, vq.used_start());
+        assert_eq!((state.next_avail, state.next_used), (3, 2));
+        assert!(state.ready && state.event_idx_enabled);
+
+        let mut other = Queue::new(m, 4);
+        assert_ne!(other.state(), state);
+        other.set_state(&state);
+        assert_eq!(other.state(), state);
+        assert_eq!(other.max_size(), 16);
+        assert!(other.is_valid());
+
+        q.reset();
+        assert_eq!(q.state().next_avail, 0);
+        q.set_state(&state);
+        assert_eq!(q.next_avail(), 3);
+        assert_eq!(q.next_used(), 2);
+    }
+
+    #[test]
+    fn test_chain_at() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let mut q = vq.create_queue(m);
+        vq.dtable(3).set(0x1000, 0x1000, VIRTQ_DESC_F_NEXT, 4);
+        vq.dtable(4).set(0x2000, 0x1000, 0, 0);
+        vq.avail.ring(0).store(3);
+        vq.avail.idx().store(1);
+
+        // The chain can be retrieved without consuming it.
+        let mut c = q.chain_at(3).unwrap();
+        assert_eq!(c.head_index(), 3);
+        assert_eq!(c.next().unwrap().addr(), GuestAddress(0x1000));
+        assert_eq!(c.next().unwrap().addr(), GuestAddress(0x2000));
+        assert!(c.next().is_none());
+        assert_eq!(q.next_avail(), 0);
+        assert!(q.chain_at(16).is_err());
+
+        assert_eq!(q.used_idx(Ordering::Acquire).unwrap(), Wrapping(0));
+        q.add_used(3, 0x1000).unwrap();
+        assert_eq!(q.used_idx(Ordering::Acquire).unwrap(), Wrapping(1));
+    }
+
+    #[test]
+    fn test_add_used_batch() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let mut q = vq.create_queue(m);
+        q.add_used(0, 0x10).unwrap();
+
+        // Nothing is published if any of the indices is too large.
+        assert!(q.add_used_batch(&[(1, 0x20), (16, 0x30)]).is_err());
+        assert_eq!(q.next_used, Wrapping(1));
+        assert_eq!(vq.used.idx().load(), 1);
+
+        q.add_used_batch(&[(1, 0x20), (5, 0x30), (3, 0x40)])
+            .unwrap();
+        assert_eq!(q.next_used, Wrapping(4));
+        assert_eq!(vq.used.idx().load(), 4);
+        for (i, &(id, len)) in [(1, 0x20), (5, 0x30), (3, 0x40)].iter().enumerate() {
+            let x = vq.used.ring(i as u16 + 1).load();
+            assert_eq!(x.id, id);
+            assert_eq!(x.len, len);
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct TestTracker {
+        ranges: std::sync::Mutex<Vec<(GuestAddress, usize)>>,
+    }
+
+    impl DirtyTracker for TestTracker {
+        fn mark_dirty(&self, addr: GuestAddress, len: usize) {
+            self.ranges.lock().unwrap().push((addr, len));
+        }
+    }
+
+    #[test]
+    fn test_dirty_tracker() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let used = vq.used_start();
+
+        let mut q = vq.create_queue(m);
+        let tracker = Arc::new(TestTracker::default());
+        q.set_dirty_tracker(Some(tracker.clone()));
+        assert!(q.dirty_tracker().is_some());
+
+        q.add_used_batch(&[(1, 0x20), (5, 0x30)]).unwrap();
+        assert_eq!(
+            *tracker.ranges.lock().unwrap(),
+            [
+                (used.unchecked_add(4), 8),
+                (used.unchecked_add(12), 8),
+                (used.unchecked_add(2), 2)
+            ]
+        );
+        tracker.ranges.lock().unwrap().clear();
+
+        // Nothing is written, and thus tracked, for invalid indices.
+        assert!(q.add_used(16, 0).is_err());
+        assert!(tracker.ranges.lock().unwrap().is_empty());
+
+        q.disable_notification().unwrap();
+        q.set_event_idx(true);
+        q.enable_notification().unwrap();
+        assert_eq!(
+            *tracker.ranges.lock().unwrap(),
+            [(used, 2), (used.unchecked_add(4 + 16 * 8), 2)]
+        );
+        tracker.ranges.lock().unwrap().clear();
+
+        // The tracker is kept across resets, and can be removed.
+        q.reset();
+        assert!(q.dirty_tracker().is_some());
+        q.set_dirty_tracker(None);
+        q.used_ring = used;
+        q.ready = true;
+        q.add_used(0, 0).unwrap();
+        assert!(tracker.ranges.lock().unwrap().is_empty());
     }
 
     #[test]
@@ -1351,7 +2137,7 @@ mod tests {
         q.ready = true;
         q.reset();
         assert_eq!(q.size, 16);
-        assert_eq!(q.ready, false);
+        assert!(!q.ready);
     }
 
     #[test]
@@ -1359,13 +2145,13 @@ mod tests {
         let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
         let qsize = 16;
         let vq = VirtQueue::new(GuestAddress(0), m, qsize);
-        let mut q = vq.create_queue(&m);
+        let mut q = vq.create_queue(m);
         let avail_addr = vq.avail_start();
 
         // It should always return true when EVENT_IDX isn't enabled.
         for i in 0..qsize {
             q.next_used = Wrapping(i);
-            assert_eq!(q.needs_notification().unwrap(), true);
+            assert!(q.needs_notification().unwrap());
         }
 
         m.write_obj::<u16>(4, avail_addr.unchecked_add(4 + qsize as u64 * 2))
@@ -1386,26 +2172,26 @@ mod tests {
             .unwrap();
 
         // Returns `false` because `signalled_used` already passed this value.
-        assert_eq!(q.needs_notification().unwrap(), false);
+        assert!(!q.needs_notification().unwrap());
 
         m.write_obj::<u16>(15, avail_addr.unchecked_add(4 + 16 * 2))
             .unwrap();
-        assert_eq!(q.needs_notification().unwrap(), false);
+        assert!(!q.needs_notification().unwrap());
         q.next_used = Wrapping(15);
-        assert_eq!(q.needs_notification().unwrap(), false);
+        assert!(!q.needs_notification().unwrap());
         q.next_used = Wrapping(0);
-        assert_eq!(q.needs_notification().unwrap(), true);
-        assert_eq!(q.needs_notification().unwrap(), false);
+        assert!(q.needs_notification().unwrap());
+        assert!(!q.needs_notification().unwrap());
     }
 
     #[test]
     fn test_enable_disable_notification() {
         let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
         let vq = VirtQueue::new(GuestAddress(0), m, 16);
-        let mut q = vq.create_queue(&m);
+        let mut q = vq.create_queue(m);
         let used_addr = vq.used_start();
 
-        assert_eq!(q.event_idx_enabled, false);
+        assert!(!q.event_idx_enabled);
 
         q.enable_notification().unwrap();
         let v = m.read_obj::<u16>(used_addr).unwrap();
@@ -1423,14 +2209,528 @@ mod tests {
         let avail_addr = vq.avail_start();
         m.write_obj::<u16>(2, avail_addr.unchecked_add(2)).unwrap();
 
-        assert_eq!(q.enable_notification().unwrap(), true);
+        assert!(q.enable_notification().unwrap());
         q.next_avail = Wrapping(2);
-        assert_eq!(q.enable_notification().unwrap(), false);
+        assert!(!q.enable_notification().unwrap());
 
         m.write_obj::<u16>(8, avail_addr.unchecked_add(2)).unwrap();
 
-        assert_eq!(q.enable_notification().unwrap(), true);
+        assert!(q.enable_notification().unwrap());
         q.next_avail = Wrapping(8);
-        assert_eq!(q.enable_notification().unwrap(), false);
+        assert!(!q.enable_notification().unwrap());
+    }
+
+    proptest! {
+        #[test]
+        fn test_prop_valid_chains(
+            (size, chains) in queue_size(256)
+                .prop_flat_map(|size| (Just(size), chains(size, PROP_MEM_SIZE)))
+        ) {
+            let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), PROP_MEM_SIZE as usize)])
+                .unwrap();
+            let mut builder = DescriptorChainBuilder::new(GuestAddress(0), m, size);
+            for chain in chains.iter() {
+                builder.add_chain(chain);
+            }
+
+            // The device sees exactly the chains the driver made available.
+            let mut q = builder.create_queue();
+            let read = q
+                .iter()
+                .unwrap()
+                .map(|chain| {
+                    chain
+                        .map(|d| (d.addr(), d.len(), d.is_write_only()))
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            let expected = chains
+                .iter()
+                .map(|chain| {
+                    chain
+                        .iter()
+                        .map(|d| (d.addr(), d.len(), d.is_write_only()))
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            prop_assert_eq!(read, expected);
+        }
+
+        #[test]
+        fn test_prop_iteration_terminates(
+            layout in queue_size(256).prop_flat_map(|size| queue_layout(size, PROP_MEM_SIZE))
+        ) {
+            let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), PROP_MEM_SIZE as usize)])
+                .unwrap();
+            let vq = VirtQueue::new(GuestAddress(0), m, layout.size);
+            layout.write(&vq);
+
+            let mut q = vq.create_queue(m);
+            let mut chains = 0;
+            for chain in q.iter().unwrap() {
+                // A chain visits each entry of the descriptor table at most once, and then each
+                // entry of at most one indirect table.
+                let max_len = usize::from(layout.size) + usize::from(u16::MAX);
+                prop_assert!(chain.clone().count() <= max_len);
+                prop_assert!(chain.clone().readable().count() <= max_len);
+                prop_assert!(chain.writable().count() <= max_len);
+                chains += 1;
+            }
+            prop_assert_eq!(chains, layout.heads.len());
+            prop_assert_eq!(q.next_avail(), layout.heads.len() as u16);
+        }
+
+        #[test]
+        fn test_prop_add_used_within_used_ring(
+            layout in queue_size(256).prop_flat_map(|size| queue_layout(size, PROP_MEM_SIZE)),
+            next_used in any::<u16>(),
+            event_idx in any::<bool>(),
+            elems in prop::collection::vec((any::<u16>(), any::<u32>()), 0..64),
+        ) {
+            let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), PROP_MEM_SIZE as usize)])
+                .unwrap();
+            let vq = VirtQueue::new(GuestAddress(0), m, layout.size);
+            layout.write(&vq);
+            let mut q = vq.create_queue(m);
+            q.set_next_used(next_used);
+            q.set_event_idx(event_idx);
+
+            let mut before = vec![0u8; PROP_MEM_SIZE as usize];
+            m.read_slice(&mut before, GuestAddress(0)).unwrap();
+            for (head_index, len) in elems {
+                let _ = q.add_used(head_index, len);
+            }
+            let _ = q.enable_notification();
+            let _ = q.needs_notification();
+            let mut after = vec![0u8; PROP_MEM_SIZE as usize];
+            m.read_slice(&mut after, GuestAddress(0)).unwrap();
+
+            let used = vq.used_start().0 as usize..vq.end().0 as usize;
+            for (addr, (b, a)) in before.iter().zip(after.iter()).enumerate() {
+                if !used.contains(&addr) {
+                    prop_assert_eq!(b, a, "write outside the used ring at {:#x}", addr);
+                }
+            }
+        }
+    }
+}
+
+// Models of the notification suppression protocol, checked with loom under all the allowed
+// interleavings and memory reorderings. The guest memory accesses of `Queue` can't run under
+// loom, so the models replay the same accesses, with the same orderings, on loom atomics; they
+// have to be kept in sync with `enable_notification` and `needs_notification`. Run them with:
+//
+// RUSTFLAGS="--cfg loom" cargo test -p virtio-queue --release loom
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::VIRTQ_USED_F_NO_NOTIFY;
+
+    use loom::sync::atomic::{fence, AtomicBool, AtomicU16, Ordering};
+    use loom::sync::Arc;
+    use loom::thread;
+
+    // The fields of the rings which take part in the protocol.
+    #[derive(Default)]
+    struct Rings {
+        avail_idx: AtomicU16,
+        used_idx: AtomicU16,
+        // The `flags` field of the used ring.
+        used_flags: AtomicU16,
+        // The `avail_event` field of the used ring.
+        avail_event: AtomicU16,
+        // The `used_event` field of the available ring.
+        used_event: AtomicU16,
+    }
+
+    // The condition of the virtio standard for sending a notification when `EVENT_IDX` is
+    // negotiated, as used by both sides.
+    fn need_event(event: u16, new: u16, old: u16) -> bool {
+        new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+    }
+
+    // The device consumes the available ring until it's empty, like the loop described next to
+    // `enable_notification`, and returns the index where it stopped.
+    fn device_process(rings: &Rings, event_idx: bool, order: Ordering) -> u16 {
+        loop {
+            // `disable_notification`.
+            if !event_idx {
+                rings
+                    .used_flags
+                    .store(VIRTQ_USED_F_NO_NOTIFY, Ordering::Relaxed);
+            }
+            // `iter`, which processes everything.
+            let next_avail = rings.avail_idx.load(Ordering::Acquire);
+            // `enable_notification`.
+            if event_idx {
+                rings.avail_event.store(next_avail, Ordering::Relaxed);
+            } else {
+                rings.used_flags.store(0, Ordering::Relaxed);
+            }
+            fence(order);
+            if rings.avail_idx.load(Ordering::Relaxed) == next_avail {
+                return next_avail;
+            }
+        }
+    }
+
+    // The driver makes a buffer available, and returns whether it notifies the device.
+    fn driver_kick(rings: &Rings, event_idx: bool, order: Ordering) -> bool {
+        rings.avail_idx.store(1, Ordering::Release);
+        fence(order);
+        if event_idx {
+            need_event(rings.avail_event.load(Ordering::Relaxed), 1, 0)
+        } else {
+            rings.used_flags.load(Ordering::Relaxed) & VIRTQ_USED_F_NO_NOTIFY == 0
+        }
+    }
+
+    // Checks that a buffer made available while the device re-enables notifications is either
+    // seen by the device, or causes a notification.
+    fn check_avail(event_idx: bool, order: Ordering) {
+        loom::model(move || {
+            let rings = Arc::new(Rings::default());
+            // With `EVENT_IDX`, the notifications stay suppressed after the one which started
+            // the processing, until the device sets `avail_event` again.
+            rings.avail_event.store(u16::MAX / 2, Ordering::Relaxed);
+            let kicked = Arc::new(AtomicBool::new(false));
+
+            let driver = thread::spawn({
+                let rings = rings.clone();
+                let kicked = kicked.clone();
+                move || kicked.store(driver_kick(&rings, event_idx, order), Ordering::Relaxed)
+            });
+            let next_avail = device_process(&rings, event_idx, order);
+            driver.join().unwrap();
+
+            assert!(next_avail == 1 || kicked.load(Ordering::Relaxed));
+        });
+    }
+
+    #[test]
+    fn test_loom_avail_flags() {
+        check_avail(false, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_loom_avail_event_idx() {
+        check_avail(true, Ordering::SeqCst);
+    }
+
+    // The full fences are needed: they order a store before a later load.
+    #[test]
+    #[should_panic]
+    fn test_loom_avail_weak_fence() {
+        check_avail(true, Ordering::AcqRel);
+    }
+
+    // Checks that a buffer used while the driver re-enables interrupts is either seen by the
+    // driver, or causes an interrupt.
+    fn check_used(order: Ordering) {
+        loom::model(move || {
+            let rings = Arc::new(Rings::default());
+            // The driver suppressed the interrupts, until it's done with the used buffers.
+            rings.used_event.store(u16::MAX / 2, Ordering::Relaxed);
+
+            let device = thread::spawn({
+                let rings = rings.clone();
+                move || {
+                    // `add_used`, and then `needs_notification`, which last signalled the
+                    // driver when the used ring was empty.
+                    rings.used_idx.store(1, Ordering::Release);
+                    fence(order);
+                    need_event(rings.used_event.load(Ordering::Relaxed), 1, 0)
+                }
+            });
+            // The driver re-enables the interrupts, and checks the used ring once more.
+            rings.used_event.store(0, Ordering::Relaxed);
+            fence(order);
+            let used_idx = rings.used_idx.load(Ordering::Acquire);
+            let interrupted = device.join().unwrap();
+
+            assert!(used_idx == 1 || interrupted);
+        });
+    }
+
+    #[test]
+    fn test_loom_used_event_idx() {
+        check_used(Ordering::SeqCst);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_loom_used_weak_fence() {
+        check_used(Ordering::AcqRel);
+    }
+}
+
+// Proof harnesses for the Kani model checker, which explore all the possible contents of a
+// small guest memory (i.e. all the descriptor tables and rings a malicious driver can write),
+// up to the unwinding bounds of the loops.
+#[cfg(kani)]
+mod verification {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::iter::{self, Once};
+    use std::sync::Mutex;
+
+    use vm_memory::guest_memory::GuestMemoryIterator;
+    use vm_memory::{GuestMemoryRegion, GuestUsize, MemoryRegionAddress};
+
+    // The size of the guest memory, which fits a queue of `MAX_QUEUE_SIZE` entries.
+    const MEM_SIZE: usize = 0x100;
+    const MAX_QUEUE_SIZE: u16 = 4;
+
+    // A single region of guest memory starting at address 0, with arbitrary contents. It's
+    // backed by a heap allocation, since the model checker doesn't support `mmap`.
+    struct ProofRegion {
+        addr: *mut u8,
+    }
+
+    impl ProofRegion {
+        fn new() -> Self {
+            let bytes: &'static mut [u8; MEM_SIZE] = Box::leak(Box::new(kani::any()));
+            ProofRegion {
+                addr: bytes.as_mut_ptr(),
+            }
+        }
+
+        fn slice(&self) -> VolatileSlice<'_> {
+            // Safe because the allocation is `MEM_SIZE` bytes long, and is never freed.
+            unsafe { VolatileSlice::new(self.addr, MEM_SIZE) }
+        }
+    }
+
+    impl Bytes<MemoryRegionAddress> for ProofRegion {
+        type E = GuestMemoryError;
+
+        fn write(&self, buf: &[u8], addr: MemoryRegionAddress) -> Result<usize, Self::E> {
+            Ok(self.slice().write(buf, addr.raw_value() as usize)?)
+        }
+
+        fn read(&self, buf: &mut [u8], addr: MemoryRegionAddress) -> Result<usize, Self::E> {
+            Ok(self.slice().read(buf, addr.raw_value() as usize)?)
+        }
+
+        fn write_slice(&self, buf: &[u8], addr: MemoryRegionAddress) -> Result<(), Self::E> {
+            Ok(self.slice().write_slice(buf, addr.raw_value() as usize)?)
+        }
+
+        fn read_slice(&self, buf: &mut [u8], addr: MemoryRegionAddress) -> Result<(), Self::E> {
+            Ok(self.slice().read_slice(buf, addr.raw_value() as usize)?)
+        }
+
+        fn read_from<F: Read>(
+            &self,
+            addr: MemoryRegionAddress,
+            src: &mut F,
+            count: usize,
+        ) -> Result<usize, Self::E> {
+            Ok(self
+                .slice()
+                .read_from(addr.raw_value() as usize, src, count)?)
+        }
+
+        fn read_exact_from<F: Read>(
+            &self,
+            addr: MemoryRegionAddress,
+            src: &mut F,
+            count: usize,
+        ) -> Result<(), Self::E> {
+            Ok(self
+                .slice()
+                .read_exact_from(addr.raw_value() as usize, src, count)?)
+        }
+
+        fn write_to<F: Write>(
+            &self,
+            addr: MemoryRegionAddress,
+            dst: &mut F,
+            count: usize,
+        ) -> Result<usize, Self::E> {
+            Ok(self
+                .slice()
+                .write_to(addr.raw_value() as usize, dst, count)?)
+        }
+
+        fn write_all_to<F: Write>(
+            &self,
+            addr: MemoryRegionAddress,
+            dst: &mut F,
+            count: usize,
+        ) -> Result<(), Self::E> {
+            Ok(self
+                .slice()
+                .write_all_to(addr.raw_value() as usize, dst, count)?)
+        }
+
+        fn store<T: AtomicAccess>(
+            &self,
+            val: T,
+            addr: MemoryRegionAddress,
+            order: Ordering,
+        ) -> Result<(), Self::E> {
+            Ok(self.slice().store(val, addr.raw_value() as usize, order)?)
+        }
+
+        fn load<T: AtomicAccess>(
+            &self,
+            addr: MemoryRegionAddress,
+            order: Ordering,
+        ) -> Result<T, Self::E> {
+            Ok(self.slice().load(addr.raw_value() as usize, order)?)
+        }
+    }
+
+    impl GuestMemoryRegion for ProofRegion {
+        fn len(&self) -> GuestUsize {
+            MEM_SIZE as GuestUsize
+        }
+
+        fn start_addr(&self) -> GuestAddress {
+            GuestAddress(0)
+        }
+
+        fn get_slice(
+            &self,
+            offset: MemoryRegionAddress,
+            count: usize,
+        ) -> Result<VolatileSlice<'_>, GuestMemoryError> {
+            Ok(self.slice().subslice(offset.raw_value() as usize, count)?)
+        }
+    }
+
+    struct ProofMemory {
+        region: ProofRegion,
+    }
+
+    impl ProofMemory {
+        fn new() -> Self {
+            ProofMemory {
+                region: ProofRegion::new(),
+            }
+        }
+    }
+
+    impl<'a> GuestMemoryIterator<'a, ProofRegion> for ProofMemory {
+        type Iter = Once<&'a ProofRegion>;
+    }
+
+    impl GuestMemory for ProofMemory {
+        type R = ProofRegion;
+        type I = Self;
+
+        fn num_regions(&self) -> usize {
+            1
+        }
+
+        fn find_region(&self, addr: GuestAddress) -> Option<&ProofRegion> {
+            self.region.to_region_addr(addr).map(|_| &self.region)
+        }
+
+        fn iter(&self) -> Once<&ProofRegion> {
+            iter::once(&self.region)
+        }
+    }
+
+    // Records the ranges of guest memory the queue writes to.
+    #[derive(Debug, Default)]
+    struct WriteTracker {
+        ranges: Mutex<Vec<(GuestAddress, usize)>>,
+    }
+
+    impl DirtyTracker for WriteTracker {
+        fn mark_dirty(&self, addr: GuestAddress, len: usize) {
+            self.ranges.lock().unwrap().push((addr, len));
+        }
+    }
+
+    // Returns an arbitrary queue size, which is a power of two up to `MAX_QUEUE_SIZE`.
+    fn queue_size() -> u16 {
+        let shift: u32 = kani::any();
+        kani::assume(shift <= MAX_QUEUE_SIZE.trailing_zeros());
+        1 << shift
+    }
+
+    // Returns the descriptors of a table with `queue_size` entries at address 0.
+    fn descriptors(mem: &ProofMemory, queue_size: u16) -> impl Iterator<Item = Descriptor> + '_ {
+        (0..queue_size).map(move |i| {
+            mem.read_obj(GuestAddress(u64::from(i) * VIRTQ_DESCRIPTOR_SIZE as u64))
+                .unwrap()
+        })
+    }
+
+    // A chain made of direct descriptors yields at most one descriptor per table entry, even
+    // when the `next` fields form a cycle.
+    #[kani::proof]
+    #[kani::unwind(17)]
+    fn verify_direct_chain_is_bounded() {
+        let mem = ProofMemory::new();
+        let queue_size = queue_size();
+        for desc in descriptors(&mem, queue_size) {
+            kani::assume(!desc.is_indirect());
+        }
+
+        let chain = DescriptorChain::<&ProofMemory>::with_mapping(
+            &mem,
+            GuestAddress(0),
+            None,
+            queue_size,
+            kani::any(),
+        );
+        assert!(chain.count() <= usize::from(queue_size));
+    }
+
+    // A chain can switch to a single indirect table, which it walks at most once, whatever the
+    // descriptors before the switch and the contents of the indirect table are.
+    #[kani::proof]
+    #[kani::unwind(17)]
+    fn verify_indirect_chain_is_bounded() {
+        let mem = ProofMemory::new();
+        let queue_size = queue_size();
+        // Longer indirect tables are bounded the same way, but would make the proof slower.
+        for desc in descriptors(&mem, queue_size) {
+            kani::assume(
+                !desc.is_indirect()
+                    || desc.len() as usize <= usize::from(MAX_QUEUE_SIZE) * VIRTQ_DESCRIPTOR_SIZE,
+            );
+        }
+
+        let chain = DescriptorChain::<&ProofMemory>::with_mapping(
+            &mem,
+            GuestAddress(0),
+            None,
+            queue_size,
+            kani::any(),
+        );
+        assert!(chain.count() < usize::from(queue_size) + usize::from(MAX_QUEUE_SIZE));
+    }
+
+    // The queue only writes to the used ring, within the bounds checked by `Queue::validate`,
+    // whatever the configuration of a valid queue and the indexes of the used entries are.
+    #[kani::proof]
+    #[kani::unwind(17)]
+    fn verify_used_ring_writes_are_bounded() {
+        let mem = ProofMemory::new();
+        let mut queue = Queue::new(&mem, MAX_QUEUE_SIZE);
+        queue.size = queue_size();
+        queue.ready = true;
+        queue.desc_table = GuestAddress(kani::any());
+        queue.avail_ring = GuestAddress(kani::any());
+        queue.used_ring = GuestAddress(kani::any());
+        kani::assume(queue.is_valid());
+        queue.set_next_used(kani::any());
+        queue.set_event_idx(kani::any());
+        let tracker = Arc::new(WriteTracker::default());
+        queue.set_dirty_tracker(Some(tracker.clone()));
+
+        let _ = queue.add_used(kani::any(), kani::any());
+        let _ = queue.enable_notification();
+
+        let start = queue.used_ring.raw_value();
+        let end = start + used_ring_len(queue.actual_size()) as u64;
+        for &(addr, len) in tracker.ranges.lock().unwrap().iter() {
+            assert!(addr.raw_value() >= start && addr.raw_value() + len as u64 <= end);
+        }
     }
 }
@@ -0,0 +1,107 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! Rate limiting for the messages a guest can trigger at will.
+//!
+//! The queue errors caused by a misbehaving driver are returned to the device, but some of them
+//! are also logged because they can't be reported in-band (i.e. from inside an iterator). A
+//! malicious guest can trigger those millions of times per second, so each call site logs at
+//! most once per `LOG_INTERVAL_MS`, and reports how many messages it suppressed in between.
+
+#![cfg_attr(not(feature = "std"), allow(dead_code))]
+
+#[cfg(feature = "std")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "std")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The minimum interval between two messages logged from the same call site.
+const LOG_INTERVAL_MS: u64 = 1000;
+
+// Decides whether a message can be logged, and counts the suppressed ones.
+#[cfg(feature = "std")]
+pub(crate) struct LogLimiter {
+    // The time (in milliseconds since the epoch) after which the next message can be logged.
+    next_log_ms: AtomicU64,
+    suppressed: AtomicU64,
+}
+
+#[cfg(feature = "std")]
+impl LogLimiter {
+    pub(crate) const fn new() -> Self {
+        LogLimiter {
+            next_log_ms: AtomicU64::new(0),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    // Returns the number of messages suppressed since the last one was logged, if the current
+    // message can be logged, or `None` if it's suppressed as well.
+    pub(crate) fn check(&self) -> Option<u64> {
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or(0);
+        let next_log_ms = self.next_log_ms.load(Ordering::Relaxed);
+        if now_ms >= next_log_ms
+            && self
+                .next_log_ms
+                .compare_exchange(
+                    next_log_ms,
+                    now_ms + LOG_INTERVAL_MS,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+        {
+            return Some(self.suppressed.swap(0, Ordering::Relaxed));
+        }
+        self.suppressed.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+}
+
+// Logs an error, unless the same call site logged one less than `LOG_INTERVAL_MS` ago.
+#[cfg(feature = "std")]
+macro_rules! error_ratelimited {
+    ($($arg:tt)+) => {{
+        static LIMITER: $crate::log_limit::LogLimiter = $crate::log_limit::LogLimiter::new();
+        match LIMITER.check() {
+            Some(0) => log::error!($($arg)+),
+            Some(suppressed) => log::error!(
+                "{} ({} similar messages suppressed)",
+                format_args!($($arg)+),
+                suppressed
+            ),
+            None => {}
+        }
+    }};
+}
+
+// Without `std` there's no clock to rate limit the messages against, so they are dropped.
+#[cfg(not(feature = "std"))]
+macro_rules! error_ratelimited {
+    ($($arg:tt)+) => {{
+        let _ = format_args!($($arg)+);
+    }};
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_limiter() {
+        let limiter = LogLimiter::new();
+
+        assert_eq!(limiter.check(), Some(0));
+        assert_eq!(limiter.check(), None);
+        assert_eq!(limiter.check(), None);
+
+        // Once the interval passes, the next message reports the suppressed ones.
+        limiter.next_log_ms.store(0, Ordering::Relaxed);
+        assert_eq!(limiter.check(), Some(2));
+        assert_eq!(limiter.check(), None);
+    }
+}
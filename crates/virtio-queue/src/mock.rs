@@ -0,0 +1,931 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// Portions Copyright 2017 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE-BSD-3-Clause file.
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! A mock virtio queue, which plays the role of the driver in unit tests.
+//!
+//! [`VirtQueue`](struct.VirtQueue.html) lays out the descriptor table and the available and
+//! used rings of a split queue in guest memory, and gives direct access to their fields, so a
+//! test can make descriptor chains available and check what the device wrote to the used ring.
+//! [`VirtQueue::create_queue`](struct.VirtQueue.html#method.create_queue) returns the device
+//! side of the same queue.
+//!
+//! ```rust
+//! # use virtio_queue::mock::VirtQueue;
+//! # use virtio_queue::VIRTQ_DESC_F_WRITE;
+//! # use vm_memory::{GuestAddress, GuestMemoryMmap};
+//! let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+//! let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+//!
+//! // The driver makes a single buffer available.
+//! vq.dtable(0).set(0x1000, 0x100, VIRTQ_DESC_F_WRITE, 0);
+//! vq.avail.ring(0).store(0);
+//! vq.avail.idx().store(1);
+//!
+//! // The device uses it.
+//! let mut queue = vq.create_queue(&mem);
+//! let head_index = queue.iter().unwrap().next().unwrap().head_index();
+//! queue.add_used(head_index, 0x100).unwrap();
+//! assert_eq!(vq.used.idx().load(), 1);
+//! ```
+//!
+//! [`DescriptorChainBuilder`](struct.DescriptorChainBuilder.html) builds on top of it, for the
+//! tests which only need well-formed chains, i.e. to check how a device parses its requests.
+//!
+//! [`AttackPattern`](enum.AttackPattern.html) lists the known ways a malicious driver can
+//! corrupt the descriptors (loops, buffers which alias the rings, addresses at the edges of
+//! guest memory, ...), which both of them can write to the descriptor table.
+//!
+//! [`FaultyMemory`](struct.FaultyMemory.html) is a guest memory which can be programmed to
+//! fail some of the accesses, so the tests can exercise the error paths of the queue and of the
+//! devices deterministically.
+//!
+//! The module is available with the `mock` feature, which device crates are expected to enable
+//! for their dev-dependency on this crate.
+
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::mem::{self, offset_of};
+use std::slice;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use vm_memory::guest_memory::{Error as GuestMemoryError, GuestMemoryIterator};
+use vm_memory::{
+    Address, AtomicAccess, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryMmap,
+    GuestMemoryRegion, GuestRegionMmap, GuestUsize, MemoryRegionAddress, MmapRegion,
+    VolatileMemory, VolatileRef, VolatileSlice,
+};
+
+use crate::{
+    Descriptor, DescriptorChain, Queue, VirtqUsedElem, VIRTQ_DESC_F_INDIRECT, VIRTQ_DESC_F_NEXT,
+    VIRTQ_DESC_F_WRITE,
+};
+
+impl Descriptor {
+    /// Creates a descriptor (i.e. for writing it to an indirect descriptor table).
+    ///
+    /// # Arguments
+    /// * `addr` - The guest physical address of the buffer.
+    /// * `len` - The length of the buffer.
+    /// * `flags` - The descriptor flags (`VIRTQ_DESC_F_*`).
+    /// * `next` - The index of the next descriptor in the chain.
+    pub fn new(addr: u64, len: u32, flags: u16, next: u16) -> Self {
+        Descriptor {
+            addr,
+            len,
+            flags,
+            next,
+        }
+    }
+}
+
+/// A descriptor of a descriptor table in guest memory.
+pub struct VirtqDesc<'a> {
+    desc: VolatileSlice<'a>,
+}
+
+#[allow(clippy::len_without_is_empty)]
+impl<'a> VirtqDesc<'a> {
+    /// Returns the descriptor with index `i` of a descriptor table.
+    ///
+    /// # Arguments
+    /// * `dtable` - The memory of the descriptor table.
+    /// * `i` - The index of the descriptor.
+    pub fn new(dtable: &'a VolatileSlice<'a>, i: u16) -> Self {
+        let desc = dtable
+            .get_slice((i as usize) * Self::dtable_len(1), Self::dtable_len(1))
+            .unwrap();
+        VirtqDesc { desc }
+    }
+
+    /// Returns the `addr` field of the descriptor.
+    pub fn addr(&self) -> VolatileRef<'_, u64> {
+        self.desc.get_ref(offset_of!(Descriptor, addr)).unwrap()
+    }
+
+    /// Returns the `len` field of the descriptor.
+    pub fn len(&self) -> VolatileRef<'_, u32> {
+        self.desc.get_ref(offset_of!(Descriptor, len)).unwrap()
+    }
+
+    /// Returns the `flags` field of the descriptor.
+    pub fn flags(&self) -> VolatileRef<'_, u16> {
+        self.desc.get_ref(offset_of!(Descriptor, flags)).unwrap()
+    }
+
+    /// Returns the `next` field of the descriptor.
+    pub fn next(&self) -> VolatileRef<'_, u16> {
+        self.desc.get_ref(offset_of!(Descriptor, next)).unwrap()
+    }
+
+    /// Writes all the fields of the descriptor.
+    ///
+    /// # Arguments
+    /// * `addr` - The guest physical address of the buffer.
+    /// * `len` - The length of the buffer.
+    /// * `flags` - The descriptor flags (`VIRTQ_DESC_F_*`).
+    /// * `next` - The index of the next descriptor in the chain.
+    pub fn set(&self, addr: u64, len: u32, flags: u16, next: u16) {
+        self.addr().store(addr);
+        self.len().store(len);
+        self.flags().store(flags);
+        self.next().store(next);
+    }
+
+    /// Returns the size of a descriptor table with `nelem` descriptors.
+    ///
+    /// # Arguments
+    /// * `nelem` - The number of descriptors.
+    pub fn dtable_len(nelem: u16) -> usize {
+        16 * nelem as usize
+    }
+}
+
+/// A virtio queue ring in guest memory. The only difference between the used and available
+/// rings is the ring element type.
+pub struct VirtqRing<'a, T> {
+    ring: VolatileSlice<'a>,
+    start: GuestAddress,
+    qsize: u16,
+    _marker: PhantomData<*const T>,
+}
+
+impl<'a, T> VirtqRing<'a, T>
+where
+    T: vm_memory::ByteValued,
+{
+    fn new(
+        start: GuestAddress,
+        mem: &'a GuestMemoryMmap,
+        qsize: u16,
+        alignment: GuestUsize,
+    ) -> Self {
+        assert_eq!(start.0 & (alignment - 1), 0);
+
+        let (region, addr) = mem.to_region_addr(start).unwrap();
+        let size = Self::ring_len(qsize);
+        let ring = region.get_slice(addr, size).unwrap();
+
+        let result = VirtqRing {
+            ring,
+            start,
+            qsize,
+            _marker: PhantomData,
+        };
+
+        result.flags().store(0);
+        result.idx().store(0);
+        result.event().store(0);
+        result
+    }
+
+    /// Returns the guest physical address of the ring.
+    pub fn start(&self) -> GuestAddress {
+        self.start
+    }
+
+    /// Returns the guest physical address right after the ring.
+    pub fn end(&self) -> GuestAddress {
+        self.start.unchecked_add(self.ring.len() as GuestUsize)
+    }
+
+    /// Returns the `flags` field of the ring.
+    pub fn flags(&self) -> VolatileRef<'_, u16> {
+        self.ring.get_ref(0).unwrap()
+    }
+
+    /// Returns the `idx` field of the ring.
+    pub fn idx(&self) -> VolatileRef<'_, u16> {
+        self.ring.get_ref(2).unwrap()
+    }
+
+    fn ring_offset(i: u16) -> usize {
+        4 + mem::size_of::<T>() * (i as usize)
+    }
+
+    /// Returns the element with index `i` of the ring.
+    ///
+    /// # Arguments
+    /// * `i` - The index of the element, which has to be smaller than the queue size.
+    pub fn ring(&self, i: u16) -> VolatileRef<'_, T> {
+        assert!(i < self.qsize);
+        self.ring.get_ref(Self::ring_offset(i)).unwrap()
+    }
+
+    /// Returns the event field of the ring (`used_event` for the available ring, and
+    /// `avail_event` for the used ring).
+    pub fn event(&self) -> VolatileRef<'_, u16> {
+        self.ring.get_ref(Self::ring_offset(self.qsize)).unwrap()
+    }
+
+    fn ring_len(qsize: u16) -> usize {
+        Self::ring_offset(qsize) + 2
+    }
+}
+
+/// The available ring, whose elements are head indices.
+pub type VirtqAvail<'a> = VirtqRing<'a, u16>;
+/// The used ring, whose elements are `(head index, length)` pairs.
+pub type VirtqUsed<'a> = VirtqRing<'a, VirtqUsedElem>;
+
+// Rounds `addr` up to a multiple of `x`, which is a power of two.
+fn align_up(addr: GuestAddress, x: GuestUsize) -> GuestAddress {
+    GuestAddress((addr.0 + (x - 1)) & !(x - 1))
+}
+
+/// A split virtio queue laid out in guest memory, as seen by the driver.
+pub struct VirtQueue<'a> {
+    mem: &'a GuestMemoryMmap,
+    start: GuestAddress,
+    dtable: VolatileSlice<'a>,
+    /// The available ring.
+    pub avail: VirtqAvail<'a>,
+    /// The used ring.
+    pub used: VirtqUsed<'a>,
+}
+
+impl<'a> VirtQueue<'a> {
+    /// Lays out a queue in guest memory: the descriptor table starts at `start`, and it's
+    /// followed by the available ring and then by the used ring, each of them aligned as
+    /// required by the specification. The rings are zeroed.
+    ///
+    /// # Arguments
+    /// * `start` - The guest physical address of the descriptor table.
+    /// * `mem` - The guest memory, which has to hold the whole queue in a single region.
+    /// * `qsize` - The size of the queue, which has to be a power of two.
+    pub fn new(start: GuestAddress, mem: &'a GuestMemoryMmap, qsize: u16) -> Self {
+        assert!(qsize > 0 && qsize.is_power_of_two());
+
+        let (region, addr) = mem.to_region_addr(start).unwrap();
+        let dtable = region
+            .get_slice(addr, VirtqDesc::dtable_len(qsize))
+            .unwrap();
+
+        const AVAIL_ALIGN: GuestUsize = 2;
+
+        let avail_addr = align_up(
+            start.unchecked_add(VirtqDesc::dtable_len(qsize) as GuestUsize),
+            AVAIL_ALIGN,
+        );
+        let avail = VirtqAvail::new(avail_addr, mem, qsize, AVAIL_ALIGN);
+
+        const USED_ALIGN: GuestUsize = 4;
+
+        let used_addr = align_up(avail.end(), USED_ALIGN);
+        let used = VirtqUsed::new(used_addr, mem, qsize, USED_ALIGN);
+
+        VirtQueue {
+            mem,
+            start,
+            dtable,
+            avail,
+            used,
+        }
+    }
+
+    /// Returns the size of the queue.
+    pub fn size(&self) -> u16 {
+        (self.dtable.len() / VirtqDesc::dtable_len(1)) as u16
+    }
+
+    /// Returns the descriptor with index `i` of the descriptor table.
+    ///
+    /// # Arguments
+    /// * `i` - The index of the descriptor.
+    pub fn dtable(&self, i: u16) -> VirtqDesc<'_> {
+        VirtqDesc::new(&self.dtable, i)
+    }
+
+    /// Returns the guest physical address of the descriptor table.
+    pub fn dtable_start(&self) -> GuestAddress {
+        self.start
+    }
+
+    /// Returns the guest physical address of the available ring.
+    pub fn avail_start(&self) -> GuestAddress {
+        self.avail.start()
+    }
+
+    /// Returns the guest physical address of the used ring.
+    pub fn used_start(&self) -> GuestAddress {
+        self.used.start()
+    }
+
+    /// Creates the device side of the queue, which is ready to be used.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory the queue was laid out in, or another view of the same memory
+    ///   (i.e. a [`FaultyMemory`](struct.FaultyMemory.html)).
+    pub fn create_queue<M: GuestAddressSpace>(&self, mem: M) -> Queue<M> {
+        let mut q = Queue::new(mem, self.size());
+
+        q.size = self.size();
+        q.ready = true;
+        q.desc_table = self.dtable_start();
+        q.avail_ring = self.avail_start();
+        q.used_ring = self.used_start();
+
+        q
+    }
+
+    /// Returns the guest physical address where the queue starts.
+    pub fn start(&self) -> GuestAddress {
+        self.dtable_start()
+    }
+
+    /// Returns the guest physical address right after the queue.
+    pub fn end(&self) -> GuestAddress {
+        self.used.end()
+    }
+
+    // Writes the descriptors of `pattern` to the descriptor table, starting with the one at
+    // index `first`, and returns the head index of the chain together with the number of
+    // descriptors it takes up.
+    fn write_attack(&self, pattern: AttackPattern, first: u16, buf: GuestAddress) -> (u16, u16) {
+        let qsize = self.size();
+        let buf = buf.0;
+        let descs = match pattern {
+            AttackPattern::SelfReference => vec![(buf, 0x10, VIRTQ_DESC_F_NEXT, first)],
+            AttackPattern::Loop => vec![
+                (buf, 0x10, VIRTQ_DESC_F_NEXT, first + 1),
+                (
+                    buf + 0x10,
+                    0x10,
+                    VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT,
+                    first,
+                ),
+            ],
+            AttackPattern::RingAliasing => vec![
+                (
+                    self.dtable_start().0,
+                    VirtqDesc::dtable_len(qsize) as u32,
+                    VIRTQ_DESC_F_NEXT,
+                    first + 1,
+                ),
+                (
+                    self.avail.start().0,
+                    (self.avail.end().0 - self.avail.start().0) as u32,
+                    VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT,
+                    first + 2,
+                ),
+                (
+                    self.used.start().0,
+                    (self.used.end().0 - self.used.start().0) as u32,
+                    VIRTQ_DESC_F_WRITE,
+                    0,
+                ),
+            ],
+            AttackPattern::ZeroLength => vec![
+                (buf, 0, VIRTQ_DESC_F_NEXT, first + 1),
+                (buf, 0, VIRTQ_DESC_F_NEXT, first + 2),
+                (buf, 0, VIRTQ_DESC_F_WRITE, 0),
+            ],
+            AttackPattern::RegionBoundary => {
+                let first_end = self.mem.iter().next().unwrap().last_addr().0 + 1;
+                let last_end = self.mem.last_addr().0 + 1;
+                vec![
+                    (first_end - 8, 0x10, VIRTQ_DESC_F_NEXT, first + 1),
+                    (
+                        last_end - 8,
+                        0x10,
+                        VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT,
+                        first + 2,
+                    ),
+                    (last_end, 0x10, VIRTQ_DESC_F_WRITE, 0),
+                ]
+            }
+            AttackPattern::AddressOverflow => vec![
+                (u64::MAX - 7, 0x10, VIRTQ_DESC_F_NEXT, first + 1),
+                (buf, u32::MAX, VIRTQ_DESC_F_WRITE, 0),
+            ],
+            AttackPattern::IndirectSelfReference => {
+                // The indirect table follows the buffer, and its first entry refers to the
+                // table itself.
+                let table = buf + 0x100;
+                let entries = [
+                    Descriptor::new(table, 0x20, VIRTQ_DESC_F_INDIRECT | VIRTQ_DESC_F_NEXT, 1),
+                    Descriptor::new(buf, 0x10, VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT, 0),
+                ];
+                for (i, entry) in entries.iter().enumerate() {
+                    let addr = GuestAddress(table + (i * mem::size_of::<Descriptor>()) as u64);
+                    self.mem.write_obj(*entry, addr).unwrap();
+                }
+                vec![(table, 0x20, VIRTQ_DESC_F_INDIRECT, 0)]
+            }
+            AttackPattern::NextOutOfRange => vec![
+                (buf, 0x10, VIRTQ_DESC_F_NEXT, first + 1),
+                (
+                    buf + 0x10,
+                    0x10,
+                    VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT,
+                    u16::MAX,
+                ),
+            ],
+            AttackPattern::HeadOutOfRange => return (qsize, 0),
+        };
+
+        assert!(
+            usize::from(first) + descs.len() <= usize::from(qsize),
+            "descriptor table full"
+        );
+        for (i, &(addr, len, flags, next)) in descs.iter().enumerate() {
+            self.dtable(first + i as u16).set(addr, len, flags, next);
+        }
+        (first, descs.len() as u16)
+    }
+
+    /// Writes the descriptors of a malicious chain to the descriptor table, adds the chain to
+    /// the available ring, and returns its head index (which is out of range for
+    /// [`AttackPattern::HeadOutOfRange`](enum.AttackPattern.html#variant.HeadOutOfRange)).
+    ///
+    /// # Arguments
+    /// * `pattern` - The kind of corruption.
+    /// * `first` - The index of the first descriptor the chain can take up; the patterns use
+    ///   at most `AttackPattern::MAX_DESCRIPTORS` descriptors.
+    /// * `buf` - The guest physical address of a page which holds the buffers of the chain.
+    pub fn add_attack(&self, pattern: AttackPattern, first: u16, buf: GuestAddress) -> u16 {
+        let (head, _) = self.write_attack(pattern, first, buf);
+        let avail_idx = self.avail.idx().load();
+        self.avail.ring(avail_idx % self.size()).store(head);
+        self.avail.idx().store(avail_idx.wrapping_add(1));
+        head
+    }
+}
+
+/// A known pattern of descriptor corruption, as a malicious driver would write it.
+///
+/// The devices have to handle each of these without panicking or looping forever, and the
+/// tests of the request parsers are expected to go through all of them (see
+/// [`AttackPattern::ALL`](#associatedconstant.ALL)).
+///
+/// ```rust
+/// # use virtio_queue::mock::{AttackPattern, DescriptorChainBuilder};
+/// # use vm_memory::{GuestAddress, GuestMemoryMmap};
+/// let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+/// for pattern in AttackPattern::ALL.iter() {
+///     let mut builder = DescriptorChainBuilder::new(GuestAddress(0), &mem, 16);
+///     let chain = builder.build_attack(*pattern);
+///     assert!(chain.count() <= 16);
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttackPattern {
+    /// A descriptor whose `next` field refers to itself.
+    SelfReference,
+    /// Two descriptors which refer to each other.
+    Loop,
+    /// Buffers which cover the descriptor table (device-readable), and the available and used
+    /// rings (device-writable).
+    RingAliasing,
+    /// A chain of zero-length buffers.
+    ZeroLength,
+    /// Buffers which straddle the end of the first memory region and the end of guest memory,
+    /// and one which starts right after guest memory.
+    RegionBoundary,
+    /// Buffers whose end overflows the guest address space, or which go past the end of
+    /// guest memory.
+    AddressOverflow,
+    /// An indirect table which refers to itself through a nested indirect descriptor.
+    IndirectSelfReference,
+    /// A `next` field outside the descriptor table.
+    NextOutOfRange,
+    /// A head index outside the descriptor table.
+    HeadOutOfRange,
+}
+
+impl AttackPattern {
+    /// All the patterns.
+    pub const ALL: [AttackPattern; 9] = [
+        AttackPattern::SelfReference,
+        AttackPattern::Loop,
+        AttackPattern::RingAliasing,
+        AttackPattern::ZeroLength,
+        AttackPattern::RegionBoundary,
+        AttackPattern::AddressOverflow,
+        AttackPattern::IndirectSelfReference,
+        AttackPattern::NextOutOfRange,
+        AttackPattern::HeadOutOfRange,
+    ];
+
+    /// The maximum number of descriptors a pattern takes up in the descriptor table.
+    pub const MAX_DESCRIPTORS: u16 = 3;
+}
+
+/// Builds descriptor chains in guest memory, as a driver would, for testing the code which
+/// parses device requests.
+///
+/// The builder lays out a [`VirtQueue`](struct.VirtQueue.html) and writes each chain to the
+/// free descriptors which follow the previous chain. It sets the `NEXT` flag and the `next`
+/// field of the descriptors to link them in order, and keeps their other fields as given.
+///
+/// ```rust
+/// # use virtio_queue::mock::DescriptorChainBuilder;
+/// # use virtio_queue::{Descriptor, VIRTQ_DESC_F_WRITE};
+/// # use vm_memory::{GuestAddress, GuestMemoryMmap};
+/// let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+/// let mut builder = DescriptorChainBuilder::new(GuestAddress(0), &mem, 16);
+///
+/// let chain = builder.build(&[
+///     Descriptor::new(0x1000, 0x10, 0, 0),
+///     Descriptor::new(0x2000, 0x100, VIRTQ_DESC_F_WRITE, 0),
+/// ]);
+/// assert_eq!(chain.count(), 2);
+/// ```
+pub struct DescriptorChainBuilder<'a> {
+    mem: &'a GuestMemoryMmap,
+    vq: VirtQueue<'a>,
+    // The index of the first descriptor which isn't part of a chain yet.
+    next_desc: u16,
+}
+
+impl<'a> DescriptorChainBuilder<'a> {
+    /// Lays out a queue in guest memory, with the descriptor table at `start`.
+    ///
+    /// # Arguments
+    /// * `start` - The guest physical address of the queue, which has to be below the buffers
+    ///   of the chains (i.e. at address 0, with the buffers starting at `0x10_0000`).
+    /// * `mem` - The guest memory, which has to hold the whole queue in a single region.
+    /// * `qsize` - The size of the queue, which bounds the total number of descriptors of the
+    ///   chains and has to be a power of two.
+    pub fn new(start: GuestAddress, mem: &'a GuestMemoryMmap, qsize: u16) -> Self {
+        DescriptorChainBuilder {
+            mem,
+            vq: VirtQueue::new(start, mem, qsize),
+            next_desc: 0,
+        }
+    }
+
+    /// Returns the queue the chains are written to.
+    pub fn virt_queue(&self) -> &VirtQueue<'a> {
+        &self.vq
+    }
+
+    // Writes `descs` as a chain to the descriptor table, and returns its head index.
+    fn write_chain(&mut self, descs: &[Descriptor]) -> u16 {
+        assert!(!descs.is_empty(), "empty descriptor chain");
+        let head = self.next_desc;
+        assert!(
+            usize::from(head) + descs.len() <= usize::from(self.vq.size()),
+            "descriptor table full"
+        );
+
+        for (i, desc) in descs.iter().enumerate() {
+            let index = head + i as u16;
+            let (flags, next) = if i == descs.len() - 1 {
+                (desc.flags() & !VIRTQ_DESC_F_NEXT, 0)
+            } else {
+                (desc.flags() | VIRTQ_DESC_F_NEXT, index + 1)
+            };
+            self.vq
+                .dtable(index)
+                .set(desc.addr().0, desc.len(), flags, next);
+        }
+        self.next_desc += descs.len() as u16;
+        head
+    }
+
+    /// Writes a chain made of `descs` to the descriptor table and returns it, without making it
+    /// available to the device.
+    ///
+    /// # Arguments
+    /// * `descs` - The descriptors of the chain, in order; their `next` fields are ignored.
+    pub fn build(&mut self, descs: &[Descriptor]) -> DescriptorChain<&'a GuestMemoryMmap> {
+        let head = self.write_chain(descs);
+        DescriptorChain::new(self.mem, self.vq.dtable_start(), self.vq.size(), head)
+    }
+
+    /// Writes a chain made of `descs` to the descriptor table, adds it to the available ring,
+    /// and returns its head index. The device side of the queue, as returned by
+    /// [`create_queue`](#method.create_queue), yields the chains in the order they were added.
+    ///
+    /// # Arguments
+    /// * `descs` - The descriptors of the chain, in order; their `next` fields are ignored.
+    pub fn add_chain(&mut self, descs: &[Descriptor]) -> u16 {
+        let head = self.write_chain(descs);
+        let avail_idx = self.vq.avail.idx().load();
+        self.vq.avail.ring(avail_idx % self.vq.size()).store(head);
+        self.vq.avail.idx().store(avail_idx.wrapping_add(1));
+        head
+    }
+
+    /// Writes a malicious chain to the descriptor table and returns it, without making it
+    /// available to the device. The buffers of the chain are in the page which follows the
+    /// queue, so the guest memory has to extend at least one page past the queue.
+    ///
+    /// # Arguments
+    /// * `pattern` - The kind of corruption.
+    pub fn build_attack(&mut self, pattern: AttackPattern) -> DescriptorChain<&'a GuestMemoryMmap> {
+        let buf = align_up(self.vq.end(), 0x1000);
+        let (head, count) = self.vq.write_attack(pattern, self.next_desc, buf);
+        self.next_desc += count;
+        DescriptorChain::new(self.mem, self.vq.dtable_start(), self.vq.size(), head)
+    }
+
+    /// Creates the device side of the queue, which is ready to be used.
+    pub fn create_queue(&self) -> Queue<&'a GuestMemoryMmap> {
+        self.vq.create_queue(self.mem)
+    }
+}
+
+/// The kind of guest memory accesses a fault applies to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// The accesses which read guest memory.
+    Read,
+    /// The accesses which write guest memory.
+    Write,
+    /// All the accesses.
+    Any,
+}
+
+impl Access {
+    fn matches(self, access: Access) -> bool {
+        self == Access::Any || self == access
+    }
+}
+
+// The faults programmed for a `FaultyMemory`, which are shared with its regions.
+#[derive(Debug, Default)]
+struct Faults {
+    // The `[start, end)` ranges of guest addresses which can't be accessed.
+    ranges: Vec<(GuestAddress, GuestAddress, Access)>,
+    // The number of accesses which succeed before all the others fail, if any.
+    fail_after: Option<usize>,
+    accesses: usize,
+    injected: usize,
+}
+
+impl Faults {
+    // Counts an access of `len` bytes at `addr`, and returns an error if it has to fail.
+    fn check(
+        &mut self,
+        addr: GuestAddress,
+        len: usize,
+        access: Access,
+    ) -> Result<(), GuestMemoryError> {
+        self.accesses += 1;
+        let end = addr.unchecked_add(len as u64);
+        let fail = self.fail_after.is_some_and(|count| self.accesses > count)
+            || self
+                .ranges
+                .iter()
+                .any(|&(start, stop, kind)| kind.matches(access) && addr < stop && start < end);
+        if fail {
+            self.injected += 1;
+            return Err(GuestMemoryError::IOError(io::Error::other(
+                "injected guest memory fault",
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// A memory region of a [`FaultyMemory`](struct.FaultyMemory.html).
+///
+/// The region doesn't expose its host mapping, so all the accesses go through the `Bytes`
+/// methods, where the faults are injected.
+#[derive(Clone, Debug)]
+pub struct FaultyRegion {
+    region: Arc<GuestRegionMmap>,
+    faults: Arc<Mutex<Faults>>,
+}
+
+impl FaultyRegion {
+    fn check(
+        &self,
+        addr: MemoryRegionAddress,
+        len: usize,
+        access: Access,
+    ) -> Result<(), GuestMemoryError> {
+        let addr = self.start_addr().unchecked_add(addr.raw_value());
+        self.faults.lock().unwrap().check(addr, len, access)
+    }
+}
+
+impl Bytes<MemoryRegionAddress> for FaultyRegion {
+    type E = GuestMemoryError;
+
+    fn write(&self, buf: &[u8], addr: MemoryRegionAddress) -> Result<usize, Self::E> {
+        self.check(addr, buf.len(), Access::Write)?;
+        self.region.write(buf, addr)
+    }
+
+    fn read(&self, buf: &mut [u8], addr: MemoryRegionAddress) -> Result<usize, Self::E> {
+        self.check(addr, buf.len(), Access::Read)?;
+        self.region.read(buf, addr)
+    }
+
+    fn write_slice(&self, buf: &[u8], addr: MemoryRegionAddress) -> Result<(), Self::E> {
+        self.check(addr, buf.len(), Access::Write)?;
+        self.region.write_slice(buf, addr)
+    }
+
+    fn read_slice(&self, buf: &mut [u8], addr: MemoryRegionAddress) -> Result<(), Self::E> {
+        self.check(addr, buf.len(), Access::Read)?;
+        self.region.read_slice(buf, addr)
+    }
+
+    fn read_from<F: Read>(
+        &self,
+        addr: MemoryRegionAddress,
+        src: &mut F,
+        count: usize,
+    ) -> Result<usize, Self::E> {
+        self.check(addr, count, Access::Write)?;
+        self.region.read_from(addr, src, count)
+    }
+
+    fn read_exact_from<F: Read>(
+        &self,
+        addr: MemoryRegionAddress,
+        src: &mut F,
+        count: usize,
+    ) -> Result<(), Self::E> {
+        self.check(addr, count, Access::Write)?;
+        self.region.read_exact_from(addr, src, count)
+    }
+
+    fn write_to<F: Write>(
+        &self,
+        addr: MemoryRegionAddress,
+        dst: &mut F,
+        count: usize,
+    ) -> Result<usize, Self::E> {
+        self.check(addr, count, Access::Read)?;
+        self.region.write_to(addr, dst, count)
+    }
+
+    fn write_all_to<F: Write>(
+        &self,
+        addr: MemoryRegionAddress,
+        dst: &mut F,
+        count: usize,
+    ) -> Result<(), Self::E> {
+        self.check(addr, count, Access::Read)?;
+        self.region.write_all_to(addr, dst, count)
+    }
+
+    fn store<T: AtomicAccess>(
+        &self,
+        val: T,
+        addr: MemoryRegionAddress,
+        order: Ordering,
+    ) -> Result<(), Self::E> {
+        self.check(addr, mem::size_of::<T>(), Access::Write)?;
+        self.region.store(val, addr, order)
+    }
+
+    fn load<T: AtomicAccess>(
+        &self,
+        addr: MemoryRegionAddress,
+        order: Ordering,
+    ) -> Result<T, Self::E> {
+        self.check(addr, mem::size_of::<T>(), Access::Read)?;
+        self.region.load(addr, order)
+    }
+}
+
+impl GuestMemoryRegion for FaultyRegion {
+    fn len(&self) -> GuestUsize {
+        self.region.len()
+    }
+
+    fn start_addr(&self) -> GuestAddress {
+        self.region.start_addr()
+    }
+}
+
+/// A guest memory which fails the accesses it was programmed to, for testing the error paths.
+///
+/// The memory is backed by anonymous mappings, like a `GuestMemoryMmap`, which is also available
+/// through [`memory`](#method.memory) for setting up the tests (i.e. as the memory of a
+/// [`VirtQueue`](struct.VirtQueue.html)) and checking their results without hitting any fault.
+/// The accesses through the `FaultyMemory` itself fail when:
+/// - they overlap a range passed to [`fail_range`](#method.fail_range), or
+/// - they come after the number of accesses passed to [`fail_after`](#method.fail_after).
+///
+/// Each call to a `Bytes` method of a region counts as an access, and the accesses which span
+/// multiple regions count once for each of them. The regions don't have host addresses, so the
+/// users of the memory can't bypass the faults (i.e. the queue always goes through the checked
+/// accesses).
+///
+/// ```rust
+/// # use virtio_queue::mock::{Access, FaultyMemory, VirtQueue};
+/// # use vm_memory::GuestAddress;
+/// let mem = FaultyMemory::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+/// let vq = VirtQueue::new(GuestAddress(0), mem.memory(), 16);
+/// let mut queue = vq.create_queue(&mem);
+///
+/// // The device can't write to the used ring.
+/// mem.fail_range(vq.used_start(), 0x100, Access::Write);
+/// assert!(queue.add_used(0, 0x100).is_err());
+/// assert_eq!(vq.used.idx().load(), 0);
+/// ```
+#[derive(Clone, Debug)]
+pub struct FaultyMemory {
+    mem: GuestMemoryMmap,
+    regions: Vec<FaultyRegion>,
+    faults: Arc<Mutex<Faults>>,
+}
+
+impl FaultyMemory {
+    /// Allocates anonymous memory for the regions, without programming any fault.
+    ///
+    /// # Arguments
+    /// * `ranges` - The `(address, size)` pairs of the regions, sorted by address.
+    pub fn from_ranges(ranges: &[(GuestAddress, usize)]) -> Result<Self, vm_memory::Error> {
+        let regions = ranges
+            .iter()
+            .map(|&(addr, size)| {
+                MmapRegion::new(size)
+                    .map_err(vm_memory::Error::MmapRegion)
+                    .and_then(|region| GuestRegionMmap::new(region, addr))
+                    .map(Arc::new)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mem = GuestMemoryMmap::from_arc_regions(regions.clone())?;
+        let faults = Arc::new(Mutex::new(Faults::default()));
+        let regions = regions
+            .into_iter()
+            .map(|region| FaultyRegion {
+                region,
+                faults: faults.clone(),
+            })
+            .collect();
+        Ok(FaultyMemory {
+            mem,
+            regions,
+            faults,
+        })
+    }
+
+    /// Returns a view of the same memory which never fails.
+    pub fn memory(&self) -> &GuestMemoryMmap {
+        &self.mem
+    }
+
+    /// Fails the accesses of the given kind which overlap a range of guest memory.
+    ///
+    /// # Arguments
+    /// * `addr` - The start of the range.
+    /// * `len` - The length of the range.
+    /// * `access` - The kind of accesses which fail.
+    pub fn fail_range(&self, addr: GuestAddress, len: usize, access: Access) {
+        let end = addr.unchecked_add(len as u64);
+        self.faults.lock().unwrap().ranges.push((addr, end, access));
+    }
+
+    /// Fails all the accesses after the next `count` ones.
+    ///
+    /// # Arguments
+    /// * `count` - The number of accesses which still succeed.
+    pub fn fail_after(&self, count: usize) {
+        let mut faults = self.faults.lock().unwrap();
+        faults.fail_after = Some(faults.accesses + count);
+    }
+
+    /// Removes all the programmed faults.
+    pub fn clear(&self) {
+        let mut faults = self.faults.lock().unwrap();
+        faults.ranges.clear();
+        faults.fail_after = None;
+    }
+
+    /// Returns the number of accesses so far, including the ones which failed.
+    pub fn accesses(&self) -> usize {
+        self.faults.lock().unwrap().accesses
+    }
+
+    /// Returns the number of accesses which failed because of a programmed fault.
+    pub fn injected(&self) -> usize {
+        self.faults.lock().unwrap().injected
+    }
+}
+
+impl<'a> GuestMemoryIterator<'a, FaultyRegion> for FaultyMemory {
+    type Iter = slice::Iter<'a, FaultyRegion>;
+}
+
+impl GuestMemory for FaultyMemory {
+    type R = FaultyRegion;
+    type I = Self;
+
+    fn num_regions(&self) -> usize {
+        self.regions.len()
+    }
+
+    fn find_region(&self, addr: GuestAddress) -> Option<&FaultyRegion> {
+        self.regions
+            .iter()
+            .find(|region| region.to_region_addr(addr).is_some())
+    }
+
+    fn iter(&self) -> slice::Iter<'_, FaultyRegion> {
+        self.regions.iter()
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! [proptest](https://docs.rs/proptest) strategies for the driver side of a queue.
+//!
+//! The strategies come in two flavors:
+//!
+//! - [`chains`](fn.chains.html) generates well-formed descriptor chains, which can be written
+//!   to guest memory with a
+//!   [`DescriptorChainBuilder`](../mock/struct.DescriptorChainBuilder.html), for checking how a
+//!   device handles the requests of a correct driver.
+//! - [`queue_layout`](fn.queue_layout.html) generates whatever a driver can write to the
+//!   descriptor table and to the available ring: chains with loops, out of range `next` fields
+//!   and head indices, indirect tables which point anywhere, and so on. These are meant for
+//!   checking that the device side doesn't misbehave, whatever the guest does.
+//!
+//! ```rust
+//! # use proptest::prelude::*;
+//! # use virtio_queue::mock::VirtQueue;
+//! # use virtio_queue::strategies::{queue_layout, queue_size};
+//! # use vm_memory::{GuestAddress, GuestMemoryMmap};
+//! proptest!(|(layout in queue_size(16).prop_flat_map(|size| queue_layout(size, 0x10000)))| {
+//!     let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+//!     let vq = VirtQueue::new(GuestAddress(0), &mem, layout.size);
+//!     layout.write(&vq);
+//!
+//!     let mut queue = vq.create_queue(&mem);
+//!     let chains = queue.iter().unwrap().count();
+//!     prop_assert!(chains <= layout.heads.len());
+//! });
+//! ```
+//!
+//! The module is available with the `strategies` feature.
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+use crate::mock::VirtQueue;
+use crate::{Descriptor, VIRTQ_DESC_F_INDIRECT, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+// The largest buffer length the valid descriptors use, which keeps them small compared to the
+// guest memory of the tests.
+const MAX_BUFFER_LEN: u64 = 0x1000;
+
+/// Returns a strategy for the valid queue sizes, which are powers of two, up to `max`.
+///
+/// # Arguments
+/// * `max` - The largest queue size, which has to be a power of two.
+pub fn queue_size(max: u16) -> impl Strategy<Value = u16> {
+    assert!(max.is_power_of_two());
+    (0..=max.trailing_zeros()).prop_map(|shift| 1 << shift)
+}
+
+/// Returns a strategy for the descriptors of well-formed chains, which point to buffers within
+/// the first `mem_size` bytes of guest memory. The `next` field and the `NEXT` flag are left
+/// clear, and the descriptors are device-writable or not.
+///
+/// # Arguments
+/// * `mem_size` - The size of the guest memory the buffers are placed in.
+pub fn valid_descriptor(mem_size: u64) -> impl Strategy<Value = Descriptor> {
+    assert!(mem_size > 0);
+    (0..mem_size, any::<bool>()).prop_flat_map(move |(addr, write)| {
+        let max_len = (mem_size - addr).min(MAX_BUFFER_LEN);
+        let flags = if write { VIRTQ_DESC_F_WRITE } else { 0 };
+        (1..=max_len).prop_map(move |len| Descriptor::new(addr, len as u32, flags, 0))
+    })
+}
+
+/// Returns a strategy for arbitrary descriptors. Their buffers are placed within the first
+/// `mem_size` bytes of guest memory most of the time, and they mix all the descriptor flags.
+///
+/// # Arguments
+/// * `mem_size` - The size of the guest memory the buffers are usually placed in.
+pub fn descriptor(mem_size: u64) -> impl Strategy<Value = Descriptor> {
+    assert!(mem_size > 0);
+    let addr = prop_oneof![
+        3 => 0..mem_size,
+        // Indirect tables have to be aligned to the descriptor size.
+        3 => (0..mem_size / 16).prop_map(|i| i * 16),
+        1 => any::<u64>(),
+    ];
+    let len = prop_oneof![
+        3 => 0..=MAX_BUFFER_LEN as u32,
+        // Indirect tables have to be a multiple of the descriptor size.
+        3 => (0..=MAX_BUFFER_LEN as u32 / 16).prop_map(|n| n * 16),
+        1 => any::<u32>(),
+    ];
+    let flags = prop_oneof![
+        4 => 0..=VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_INDIRECT,
+        1 => any::<u16>(),
+    ];
+    (addr, len, flags, any::<u16>())
+        .prop_map(|(addr, len, flags, next)| Descriptor::new(addr, len, flags, next))
+}
+
+/// Returns a strategy for sets of well-formed descriptor chains, made of
+/// [`valid_descriptor`](fn.valid_descriptor.html)s, which fit a queue of size `queue_size`
+/// together.
+///
+/// # Arguments
+/// * `queue_size` - The size of the queue, which bounds the total number of descriptors.
+/// * `mem_size` - The size of the guest memory the buffers are placed in.
+pub fn chains(queue_size: u16, mem_size: u64) -> impl Strategy<Value = Vec<Vec<Descriptor>>> {
+    let queue_size = usize::from(queue_size);
+    vec(
+        vec(valid_descriptor(mem_size), 1..=queue_size),
+        0..=queue_size,
+    )
+    .prop_map(move |mut chains| {
+        // Drop the chains which don't fit the descriptor table anymore.
+        let mut total = 0;
+        chains.retain(|chain| {
+            total += chain.len();
+            total <= queue_size
+        });
+        chains
+    })
+}
+
+/// The contents of the descriptor table and of the available ring of a queue.
+#[derive(Clone, Debug)]
+pub struct QueueLayout {
+    /// The size of the queue.
+    pub size: u16,
+    /// The descriptor table, which holds `size` descriptors.
+    pub descs: Vec<Descriptor>,
+    /// The head indices which are made available, in order, at most `size` of them.
+    pub heads: Vec<u16>,
+}
+
+impl QueueLayout {
+    /// Writes the descriptor table and the available ring to a queue of the same size, and
+    /// makes all the heads available.
+    ///
+    /// # Arguments
+    /// * `vq` - The queue, whose used ring and available ring index are expected to be zeroed.
+    pub fn write(&self, vq: &VirtQueue<'_>) {
+        assert_eq!(vq.size(), self.size);
+        for (i, desc) in self.descs.iter().enumerate() {
+            vq.dtable(i as u16)
+                .set(desc.addr().0, desc.len(), desc.flags(), desc.next());
+        }
+        for (i, &head) in self.heads.iter().enumerate() {
+            vq.avail.ring(i as u16).store(head);
+        }
+        vq.avail.idx().store(self.heads.len() as u16);
+    }
+}
+
+/// Returns a strategy for arbitrary driver areas of a queue of size `queue_size`, made of
+/// [`descriptor`](fn.descriptor.html)s and of any head indices.
+///
+/// # Arguments
+/// * `queue_size` - The size of the queue.
+/// * `mem_size` - The size of the guest memory the buffers are usually placed in.
+pub fn queue_layout(queue_size: u16, mem_size: u64) -> impl Strategy<Value = QueueLayout> {
+    let size = usize::from(queue_size);
+    let head = prop_oneof![
+        3 => 0..queue_size,
+        1 => any::<u16>(),
+    ];
+    (vec(descriptor(mem_size), size), vec(head, 0..=size)).prop_map(move |(descs, heads)| {
+        QueueLayout {
+            size: queue_size,
+            descs,
+            heads,
+        }
+    })
+}
@@ -0,0 +1,33 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+// Parses all the block requests which the input makes available; the request headers and the
+// discard or write zeroes segments are read from the input as well.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use virtio_blk::request::Request;
+use vm_virtio_fuzz::{guest_memory, queue};
+
+fuzz_target!(|data: &[u8]| {
+    let mem = guest_memory(data);
+    let mut queue = match queue(&mem, data) {
+        Some(queue) => queue,
+        None => return,
+    };
+
+    if let Ok(iter) = queue.iter() {
+        for mut chain in iter {
+            if let Ok(request) = Request::parse(&mut chain) {
+                // The checks a device does before executing the request, which use the values
+                // read from the header.
+                let _ = request.check_capacity(u64::MAX);
+                let _ = request.check_segments(u32::MAX);
+                let _ = request.data_slices(&mem);
+            }
+        }
+    }
+});
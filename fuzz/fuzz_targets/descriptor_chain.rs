@@ -0,0 +1,43 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+// Walks all the descriptor chains which the input makes available, and returns them to the
+// driver.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use vm_virtio_fuzz::{guest_memory, queue};
+
+fuzz_target!(|data: &[u8]| {
+    let mem = guest_memory(data);
+    let mut queue = match queue(&mem, data) {
+        Some(queue) => queue,
+        None => return,
+    };
+
+    let mut used = Vec::new();
+    if let Ok(iter) = queue.iter() {
+        for chain in iter {
+            // Whatever the descriptors hold, including loops and nested indirect tables, the
+            // iteration has to end.
+            let len = chain
+                .clone()
+                .map(|desc| desc.len())
+                .fold(0u32, u32::wrapping_add);
+            let _ = chain.clone().readable().count();
+            let _ = chain.clone().writable().count();
+            used.push((chain.head_index(), len));
+        }
+    }
+
+    for (head_index, len) in used {
+        if queue.add_used(head_index, len).is_err() {
+            break;
+        }
+    }
+    let _ = queue.needs_notification();
+    let _ = queue.enable_notification();
+});
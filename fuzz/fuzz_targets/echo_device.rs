@@ -0,0 +1,34 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+// Drives the echo device through its MMIO registers, with the accesses read from the input as
+// well: each access takes 6 bytes, the register (in units of 4 bytes), the direction, and the
+// value which is written. The input configures the queue and notifies the device, so the
+// transport and the queue are exercised without any real backend.
+
+#![no_main]
+
+use std::convert::TryInto;
+use std::sync::Arc;
+
+use libfuzzer_sys::fuzz_target;
+
+use virtio_device::mock::EchoDevice;
+use virtio_device::VirtioMmioDevice;
+use vm_virtio_fuzz::{guest_memory, MAX_QUEUE_SIZE};
+
+fuzz_target!(|data: &[u8]| {
+    let mem = Arc::new(guest_memory(data));
+    let mut device = EchoDevice::new(mem, MAX_QUEUE_SIZE);
+
+    for access in data.chunks_exact(6) {
+        let offset = u64::from(access[0]) * 4;
+        let mut value: [u8; 4] = access[2..].try_into().unwrap();
+        if access[1] & 1 != 0 {
+            device.write(offset, &value);
+        } else {
+            device.read(offset, &mut value);
+        }
+    }
+});
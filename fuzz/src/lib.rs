@@ -0,0 +1,57 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Helpers shared by the fuzz targets.
+//!
+//! The targets use the fuzzer input as the contents of guest memory, so the descriptor table,
+//! the rings and the buffers they point to are all controlled by the input, as they are by the
+//! driver of a real guest. The queue is always placed at the same addresses, and the first
+//! bytes of the input select its configuration.
+
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+use virtio_queue::Queue;
+
+/// The size of the guest memory, which holds the start of the fuzzer input.
+pub const MEM_SIZE: usize = 0x1_0000;
+
+/// The largest queue size the targets use.
+pub const MAX_QUEUE_SIZE: u16 = 256;
+
+// The addresses of the queue areas, which fit the largest queue.
+const DESC_TABLE_ADDR: u64 = 0x0;
+const AVAIL_RING_ADDR: u64 = 0x1000;
+const USED_RING_ADDR: u64 = 0x2000;
+
+/// Creates the guest memory, and writes the fuzzer input to it, starting at address 0.
+///
+/// # Arguments
+/// * `data` - The fuzzer input, which is truncated to the size of the memory.
+pub fn guest_memory(data: &[u8]) -> GuestMemoryMmap {
+    let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+    let len = data.len().min(MEM_SIZE);
+    mem.write_slice(&data[..len], GuestAddress(0)).unwrap();
+    mem
+}
+
+/// Creates a ready queue in `mem`, or returns `None` if the input is too short. The first byte
+/// of the input selects the size of the queue, and the second one whether `EVENT_IDX` is
+/// negotiated.
+///
+/// # Arguments
+/// * `mem` - The guest memory, as returned by [`guest_memory`](fn.guest_memory.html).
+/// * `data` - The fuzzer input.
+pub fn queue<'a>(mem: &'a GuestMemoryMmap, data: &[u8]) -> Option<Queue<&'a GuestMemoryMmap>> {
+    let (&size, &flags) = (data.first()?, data.get(1)?);
+
+    let mut queue = Queue::new(mem, MAX_QUEUE_SIZE);
+    // Sizes from 1 to `MAX_QUEUE_SIZE`, all powers of two.
+    queue.size = 1 << (size % 9);
+    queue.desc_table = GuestAddress(DESC_TABLE_ADDR);
+    queue.avail_ring = GuestAddress(AVAIL_RING_ADDR);
+    queue.used_ring = GuestAddress(USED_RING_ADDR);
+    queue.set_event_idx(flags & 1 != 0);
+    queue.ready = true;
+    Some(queue)
+}